@@ -0,0 +1,50 @@
+//! Building the expressions of a function's worth of symbolic execution, and dropping them,
+//! with boxes against an expression pool reused from one function to the next.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use vivisect::{
+    arena::{ExprPool, Node},
+    symbolic::{BinOp, Expr},
+};
+
+const INSNS: usize = 2000;
+
+// A stack pointer walked up and down, and values computed from loads through it
+fn boxed() -> Vec<Expr> {
+    let mut sp = Expr::Reg(4);
+    let mut values = Vec::with_capacity(INSNS);
+    for i in 0..INSNS as u64 {
+        sp = Expr::Binary(BinOp::Add, Box::new(sp), Box::new(Expr::Const(i & 0xf)));
+        let load = Expr::Load(Box::new(sp.clone()));
+        values.push(Expr::Binary(
+            BinOp::Xor,
+            Box::new(load),
+            Box::new(Expr::Reg(0)),
+        ));
+    }
+    values
+}
+
+fn pooled(pool: &mut ExprPool) -> usize {
+    pool.clear();
+    let mut sp = pool.node_id(Node::Reg(4));
+    let eax = pool.node_id(Node::Reg(0));
+    for i in 0..INSNS as u64 {
+        let c = pool.node_id(Node::Const(i & 0xf));
+        sp = pool.node_id(Node::Binary(BinOp::Add, sp, c));
+        let load = pool.node_id(Node::Load(sp));
+        pool.node_id(Node::Binary(BinOp::Xor, load, eax));
+    }
+    pool.len()
+}
+
+fn arena(c: &mut Criterion) {
+    c.bench_function("boxed expressions", |b| b.iter(|| black_box(boxed())));
+    let mut pool = ExprPool::new();
+    c.bench_function("pooled expressions", |b| {
+        b.iter(|| black_box(pooled(&mut pool)))
+    });
+}
+
+criterion_group!(benches, arena);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Print the ROP and JOP gadgets of a binary.
+//!
+//! `cargo run --example gadgets --features gadgets -- <binary> [max instructions] [effect]`,
+//! the effect being one of pop, move, arithmetic, read, write or pivot.
+use std::env;
+use vivisect::workspace::VivWorkspace;
+
+pub fn main() {
+    let mut args = env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("usage: gadgets <binary> [max instructions] [effect]");
+        std::process::exit(2);
+    };
+    let max_insns = args.next().and_then(|n| n.parse().ok()).unwrap_or(5);
+    let effect = args.next();
+    let mut workspace = VivWorkspace::new("", false);
+    workspace.load_from_file(&path, None, None);
+    for gadget in workspace.find_gadgets(max_insns) {
+        if effect
+            .as_deref()
+            .is_some_and(|effect| !gadget.effects.iter().any(|e| e.to_string() == effect))
+        {
+            continue;
+        }
+        let effects: Vec<String> = gadget.effects.iter().map(|e| e.to_string()).collect();
+        println!(
+            "{} [{}] x{}",
+            gadget,
+            effects.join(", "),
+            gadget.duplicates.len() + 1
+        );
+    }
+}
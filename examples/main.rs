@@ -1,7 +1,7 @@
 use log::Level;
 use simple_logger::init_with_level;
 use std::env::current_dir;
-use std::rc::Rc;
+use std::sync::Arc;
 use vivisect::analysis::{EntryPointsAnalyzer, RelocationsAnalyzer};
 use vivisect::workspace::VivWorkspace;
 
@@ -11,8 +11,8 @@ pub fn main() {
     let sample_path = "data/test-decode-to-stack.exe";
     let mut workspace = VivWorkspace::new("", false);
     workspace.load_from_file(sample_path, None, None);
-    workspace.add_analyzer(Rc::new(RelocationsAnalyzer::new()));
-    workspace.add_analyzer(Rc::new(EntryPointsAnalyzer::new()));
+    workspace.add_analyzer(Arc::new(RelocationsAnalyzer::new()));
+    workspace.add_analyzer(Arc::new(EntryPointsAnalyzer::new()));
     workspace.analyze(sample_path);
     // println!("{:?}", workspace);
 }
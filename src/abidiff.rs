@@ -0,0 +1,426 @@
+//! Export and import set comparison between two versions of a binary, for tracking ABI breakage
+//! across library updates without having to diff the code itself.
+//!
+//! The exports and imports of a PE, ELF or Mach-O binary are collected into an [`AbiSet`], which
+//! can then be compared against the set of another version of the same binary.
+
+use crate::{
+    elf::{self, sym},
+    error,
+    mach::{self, exports::EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION},
+    pe::{self, export::ExportAddressTableEntry, options::ParseOptions},
+    Object,
+};
+use alloc::collections::BTreeMap;
+use core::fmt;
+use scroll::Pread;
+
+/// An exported or imported symbol, with the attributes which matter to the ABI
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AbiSymbol {
+    /// The symbol name; PE exports which only have an ordinal are named `#<ordinal>`
+    pub name: String,
+    /// The library an import is bound to, or the library an export is re-exported from
+    pub library: Option<String>,
+    /// The PE export/import ordinal
+    pub ordinal: Option<u32>,
+    /// The ELF symbol version
+    pub version: Option<String>,
+    pub weak: bool,
+    pub reexport: bool,
+}
+
+impl AbiSymbol {
+    fn new(name: &str) -> Self {
+        AbiSymbol {
+            name: name.to_string(),
+            library: None,
+            ordinal: None,
+            version: None,
+            weak: false,
+            reexport: false,
+        }
+    }
+
+    /// Symbols are matched between versions by name and symbol version (an ELF library can
+    /// export several versions of a symbol), imports also by library
+    fn key(&self, with_library: bool) -> (String, Option<String>, Option<String>) {
+        let library = if with_library {
+            self.library.as_ref().map(|lib| lib.to_lowercase())
+        } else {
+            None
+        };
+        (self.name.clone(), self.version.clone(), library)
+    }
+
+    /// The symbol with its library lowercased, as libraries are compared
+    fn normalized(&self) -> AbiSymbol {
+        AbiSymbol {
+            library: self.library.as_ref().map(|lib| lib.to_lowercase()),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for AbiSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(ref version) = self.version {
+            write!(f, "@{}", version)?;
+        }
+        if let Some(ordinal) = self.ordinal {
+            write!(f, " (ordinal {})", ordinal)?;
+        }
+        if let Some(ref library) = self.library {
+            write!(f, " [{}]", library)?;
+        }
+        if self.weak {
+            write!(f, " weak")?;
+        }
+        if self.reexport {
+            write!(f, " reexport")?;
+        }
+        Ok(())
+    }
+}
+
+/// The exports and imports of a binary
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AbiSet {
+    pub exports: Vec<AbiSymbol>,
+    pub imports: Vec<AbiSymbol>,
+}
+
+impl AbiSet {
+    /// Collect the exports and imports of `object`, which was parsed from `bytes`
+    pub fn from_object(object: &Object, bytes: &[u8]) -> error::Result<Self> {
+        match object {
+            Object::PE(pe) => Ok(from_pe(pe, bytes)),
+            Object::Elf(elf) => Ok(from_elf(elf)),
+            Object::Mach(mach::Mach::Binary(macho)) => from_macho(macho),
+            Object::Mach(mach::Mach::Fat(_)) => Err(error::Error::Malformed(
+                "Pick an architecture out of the fat binary first".to_string(),
+            )),
+            Object::Archive(_) | Object::Unknown(_) => Err(error::Error::Malformed(
+                "Only PE, ELF and Mach-O binaries have exports and imports".to_string(),
+            )),
+        }
+    }
+
+    /// Parse `bytes` and collect its exports and imports
+    pub fn parse(bytes: &[u8]) -> error::Result<Self> {
+        AbiSet::from_object(&Object::parse(bytes)?, bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbiChange {
+    Added(AbiSymbol),
+    Removed(AbiSymbol),
+    /// The symbol exists in both versions, but its attributes differ
+    Changed {
+        old: AbiSymbol,
+        new: AbiSymbol,
+    },
+}
+
+impl AbiChange {
+    /// Removing or changing an export breaks the existing users of a library
+    pub fn is_breaking(&self) -> bool {
+        !matches!(self, AbiChange::Added(_))
+    }
+}
+
+impl fmt::Display for AbiChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AbiChange::Added(sym) => write!(f, "+ {}", sym),
+            AbiChange::Removed(sym) => write!(f, "- {}", sym),
+            AbiChange::Changed { old, new } => write!(f, "~ {} -> {}", old, new),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AbiDiff {
+    pub exports: Vec<AbiChange>,
+    pub imports: Vec<AbiChange>,
+}
+
+impl AbiDiff {
+    pub fn is_empty(&self) -> bool {
+        self.exports.is_empty() && self.imports.is_empty()
+    }
+
+    /// Whether any export was removed or changed
+    pub fn breaks_abi(&self) -> bool {
+        self.exports.iter().any(|change| change.is_breaking())
+    }
+}
+
+/// Compare the exports and imports of two versions of a binary
+pub fn diff(old: &AbiSet, new: &AbiSet) -> AbiDiff {
+    AbiDiff {
+        exports: diff_symbols(&old.exports, &new.exports, false),
+        imports: diff_symbols(&old.imports, &new.imports, true),
+    }
+}
+
+/// Parse two versions of a binary and compare their exports and imports
+pub fn diff_bytes(old: &[u8], new: &[u8]) -> error::Result<AbiDiff> {
+    Ok(diff(&AbiSet::parse(old)?, &AbiSet::parse(new)?))
+}
+
+fn diff_symbols(old: &[AbiSymbol], new: &[AbiSymbol], with_library: bool) -> Vec<AbiChange> {
+    let old = old
+        .iter()
+        .map(|sym| (sym.key(with_library), sym))
+        .collect::<BTreeMap<_, _>>();
+    let new = new
+        .iter()
+        .map(|sym| (sym.key(with_library), sym))
+        .collect::<BTreeMap<_, _>>();
+    let mut changes = Vec::new();
+    for (key, old_sym) in old.iter() {
+        match new.get(key) {
+            None => changes.push(AbiChange::Removed((*old_sym).clone())),
+            Some(new_sym) if old_sym.normalized() != new_sym.normalized() => {
+                changes.push(AbiChange::Changed {
+                    old: (*old_sym).clone(),
+                    new: (*new_sym).clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+    for (key, new_sym) in new.iter() {
+        if !old.contains_key(key) {
+            changes.push(AbiChange::Added((*new_sym).clone()));
+        }
+    }
+    changes
+}
+
+fn from_pe(pe: &pe::PE, bytes: &[u8]) -> AbiSet {
+    let mut set = AbiSet::default();
+    if let Some(ref export_data) = pe.export_data {
+        let base = export_data.export_directory_table.ordinal_base;
+        let (sections, file_alignment) = (&pe.sections, pe_file_alignment(pe));
+        let opts = ParseOptions::default();
+        let mut named = vec![false; export_data.export_address_table.len()];
+        for (idx, ptr) in export_data.export_name_pointer_table.iter().enumerate() {
+            let name = pe::utils::find_offset(*ptr as usize, sections, file_alignment, &opts)
+                .and_then(|offset| bytes.pread::<&str>(offset).ok());
+            let slot = export_data.export_ordinal_table.get(idx).copied();
+            if let (Some(name), Some(slot)) = (name, slot) {
+                let mut sym = AbiSymbol::new(name);
+                sym.ordinal = Some(base + u32::from(slot));
+                if let Some(entry) = export_data.export_address_table.get(slot as usize) {
+                    sym.reexport = matches!(entry, ExportAddressTableEntry::ForwarderRVA(_));
+                    named[slot as usize] = true;
+                }
+                set.exports.push(sym);
+            }
+        }
+        // exports which can only be imported by ordinal
+        for (slot, entry) in export_data.export_address_table.iter().enumerate() {
+            if named[slot] || matches!(entry, ExportAddressTableEntry::ExportRVA(0)) {
+                continue;
+            }
+            let ordinal = base + slot as u32;
+            let mut sym = AbiSymbol::new(&format!("#{}", ordinal));
+            sym.ordinal = Some(ordinal);
+            sym.reexport = matches!(entry, ExportAddressTableEntry::ForwarderRVA(_));
+            set.exports.push(sym);
+        }
+        // fill in where the forwarded exports go
+        for export in pe.exports.iter() {
+            if let (Some(name), Some(ref reexport)) = (export.name, &export.reexport) {
+                let lib = match reexport {
+                    pe::export::Reexport::DLLName { lib, .. } => lib,
+                    pe::export::Reexport::DLLOrdinal { lib, .. } => lib,
+                };
+                if let Some(sym) = set.exports.iter_mut().find(|sym| sym.name == name) {
+                    sym.library = Some(lib.to_string());
+                }
+            }
+        }
+    }
+    for import in pe.imports.iter() {
+        let mut sym = AbiSymbol::new(&import.name);
+        sym.library = Some(import.dll.to_string());
+        if import.ordinal != 0 {
+            sym.ordinal = Some(u32::from(import.ordinal));
+        }
+        set.imports.push(sym);
+    }
+    set
+}
+
+fn pe_file_alignment(pe: &pe::PE) -> u32 {
+    pe.header
+        .optional_header
+        .map(|opt| opt.windows_fields.file_alignment)
+        .unwrap_or(0x200)
+}
+
+fn from_elf(elf: &elf::Elf) -> AbiSet {
+    let mut set = AbiSet::default();
+    // version index -> (version name, library needing it)
+    let mut versions = BTreeMap::new();
+    if let Some(ref verdef) = elf.verdef {
+        for def in verdef.iter() {
+            if let Some(aux) = def.iter().next() {
+                if let Some(name) = elf.dynstrtab.get_at(aux.vda_name) {
+                    versions.insert(def.vd_ndx, (name, None));
+                }
+            }
+        }
+    }
+    if let Some(ref verneed) = elf.verneed {
+        for need in verneed.iter() {
+            let file = elf.dynstrtab.get_at(need.vn_file);
+            for aux in need.iter() {
+                if let Some(name) = elf.dynstrtab.get_at(aux.vna_name) {
+                    versions.insert(aux.vna_other & elf::symver::VERSYM_VERSION, (name, file));
+                }
+            }
+        }
+    }
+    for (idx, dynsym) in elf.dynsyms.iter().enumerate() {
+        let bind = dynsym.st_bind();
+        if bind != sym::STB_GLOBAL && bind != sym::STB_WEAK {
+            continue;
+        }
+        if sym::st_visibility(dynsym.st_other) == sym::STV_HIDDEN {
+            continue;
+        }
+        let name = match elf.dynstrtab.get_at(dynsym.st_name) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        let mut abi_sym = AbiSymbol::new(name);
+        abi_sym.weak = bind == sym::STB_WEAK;
+        let version = elf
+            .versym
+            .as_ref()
+            .and_then(|versym| versym.get_at(idx))
+            .and_then(|versym| versions.get(&versym.version()));
+        if let Some((version, file)) = version {
+            abi_sym.version = Some(version.to_string());
+            abi_sym.library = file.map(|file| file.to_string());
+        }
+        if dynsym.st_shndx == 0 {
+            set.imports.push(abi_sym);
+        } else {
+            set.exports.push(abi_sym);
+        }
+    }
+    set
+}
+
+fn from_macho(macho: &mach::MachO) -> error::Result<AbiSet> {
+    let mut set = AbiSet::default();
+    for export in macho.exports()? {
+        let mut sym = AbiSymbol::new(&export.name);
+        match export.info {
+            mach::exports::ExportInfo::Regular { flags, .. }
+            | mach::exports::ExportInfo::Stub { flags, .. } => {
+                sym.weak = flags & EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION != 0;
+            }
+            mach::exports::ExportInfo::Reexport { lib, flags, .. } => {
+                sym.weak = flags & EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION != 0;
+                sym.reexport = true;
+                sym.library = Some(lib.to_string());
+            }
+        }
+        set.exports.push(sym);
+    }
+    for import in macho.imports()? {
+        let mut sym = AbiSymbol::new(import.name);
+        sym.library = import.dylib.name().map(str::to_string);
+        sym.weak = import.is_weak;
+        // lazy and non lazy bindings of the same symbol are the same import
+        if !set.imports.contains(&sym) {
+            set.imports.push(sym);
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(name: &str, version: Option<&str>) -> AbiSymbol {
+        let mut sym = AbiSymbol::new(name);
+        sym.version = version.map(|v| v.to_string());
+        sym
+    }
+
+    #[test]
+    fn export_changes() {
+        let old = AbiSet {
+            exports: vec![
+                export("open_thing", Some("V1")),
+                export("close_thing", Some("V1")),
+            ],
+            imports: vec![],
+        };
+        let mut weak_open = export("open_thing", Some("V1"));
+        weak_open.weak = true;
+        let new = AbiSet {
+            exports: vec![weak_open, export("reset_thing", Some("V2"))],
+            imports: vec![],
+        };
+        let result = diff(&old, &new);
+        assert_eq!(result.exports.len(), 3);
+        assert!(result.breaks_abi());
+        assert!(result
+            .exports
+            .contains(&AbiChange::Removed(export("close_thing", Some("V1")))));
+        assert!(result
+            .exports
+            .contains(&AbiChange::Added(export("reset_thing", Some("V2")))));
+        assert_eq!(result.exports[0].to_string(), "- close_thing@V1");
+        assert_eq!(
+            result.exports[1].to_string(),
+            "~ open_thing@V1 -> open_thing@V1 weak"
+        );
+    }
+
+    #[test]
+    fn imports_match_by_library() {
+        let mut old = AbiSymbol::new("CreateFileW");
+        old.library = Some("KERNEL32.dll".to_string());
+        let mut new = old.clone();
+        new.library = Some("kernel32.dll".to_string());
+        let old = AbiSet {
+            exports: vec![],
+            imports: vec![old],
+        };
+        let unchanged = AbiSet {
+            exports: vec![],
+            imports: vec![new.clone()],
+        };
+        // only the spelling of the dll changed
+        assert!(diff(&old, &unchanged).is_empty());
+        new.library = Some("kernelbase.dll".to_string());
+        let moved = AbiSet {
+            exports: vec![],
+            imports: vec![new],
+        };
+        let result = diff(&old, &moved);
+        assert_eq!(result.imports.len(), 2);
+        assert!(!result.breaks_abi());
+    }
+
+    #[test]
+    fn parse_containers() {
+        let crt1: Vec<u8> = include!("../assets/crt1.rs");
+        let set = AbiSet::parse(&crt1).unwrap();
+        assert!(diff(&set, &set).is_empty());
+        let archive: Vec<u8> = include!("../assets/crt1a.rs");
+        assert!(AbiSet::parse(&archive).is_err());
+    }
+}
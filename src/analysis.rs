@@ -6,16 +6,20 @@ use crate::{
         LOC_UNI, L_LTYPE, REF_CODE, RTYPE_BASEOFF, RTYPE_BASERELOC,
     },
     emulator::OpCode,
+    journal::Event,
     workspace::VivWorkspace,
 };
 use log::debug;
 use std::{
-    collections::HashMap,
-    fmt::{Debug, Formatter},
-    rc::Rc,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Debug, Display, Formatter},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
 };
 
-pub fn analyze_function(mut workspace: VivWorkspace, funcva: i32) {
+pub fn analyze_function(workspace: &mut VivWorkspace, funcva: i32) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("function", va = format_args!("{:#x}", funcva)).entered();
     let mut blocks = Vec::new();
     let mut done: HashMap<i32, bool> = HashMap::new();
     let mut mnem: HashMap<String, i32> = HashMap::new();
@@ -129,21 +133,30 @@ pub fn analyze_function(mut workspace: VivWorkspace, funcva: i32) {
         let tmpcb = workspace.get_code_block(bva);
         // Sometimes codeblocks can be deleted if owned by multiple functions.
         if !old_blocks.contains_key(&bva) || tmpcb.is_none() {
-            workspace.add_code_block(bva, bsize, funcva);
+            workspace.add_code_block(bva, *bsize, funcva);
         } else if &bsize != old_blocks.get(&bva).unwrap() {
             workspace.del_code_block(bva);
-            workspace.add_code_block(bva, bsize, funcva);
+            workspace.add_code_block(bva, *bsize, funcva);
         }
         bcnt += 1;
     }
     workspace.set_function_meta(funcva, "Size", size);
     workspace.set_function_meta(funcva, "BlockCount", bcnt);
     workspace.set_function_meta(funcva, "InstructionCount", opcount);
+    workspace.record_analysis(1, opcount as u64);
     // workspace.set_function_meta(funcva, "MnemDist", mnem.get(0).unwrap());
 }
 
-pub trait Analyzer {
-    fn analyze(&self, workspace: VivWorkspace);
+/// An analysis pass. Passes are shared with every thread holding the workspace.
+pub trait Analyzer: Send + Sync {
+    /// Analyze the workspace, making what the pass finds in it
+    fn analyze(&self, workspace: &mut VivWorkspace);
+
+    /// The name the pass is reported under in [`AnalysisStats`]
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
 }
 
 impl Debug for dyn Analyzer + 'static {
@@ -152,27 +165,173 @@ impl Debug for dyn Analyzer + 'static {
     }
 }
 
+/// What one analysis pass cost
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PassStats {
+    pub runs: u64,
+    pub elapsed: Duration,
+    pub functions: u64,
+    pub instructions: u64,
+}
+
+/// Time spent and work done by each analysis pass, to find the pass which takes minutes on a
+/// pathological binary. Work recorded outside of a pass is put down to [`AnalysisStats::OTHER`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AnalysisStats {
+    pub passes: BTreeMap<String, PassStats>,
+}
+
+impl AnalysisStats {
+    pub const OTHER: &'static str = "(other)";
+
+    pub fn pass(&self, name: &str) -> Option<&PassStats> {
+        self.passes.get(name)
+    }
+
+    pub fn total(&self) -> PassStats {
+        let mut total = PassStats::default();
+        for stats in self.passes.values() {
+            total.runs += stats.runs;
+            total.elapsed += stats.elapsed;
+            total.functions += stats.functions;
+            total.instructions += stats.instructions;
+        }
+        total
+    }
+
+    /// The passes, most expensive first
+    pub fn by_time(&self) -> Vec<(&str, &PassStats)> {
+        let mut passes = self
+            .passes
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats))
+            .collect::<Vec<_>>();
+        passes.sort_by(|a, b| b.1.elapsed.cmp(&a.1.elapsed).then(a.0.cmp(b.0)));
+        passes
+    }
+}
+
+impl Display for AnalysisStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<32} {:>6} {:>12} {:>10} {:>12}",
+            "Pass", "Runs", "Time (ms)", "Functions", "Instructions"
+        )?;
+        for (name, stats) in self.by_time() {
+            writeln!(
+                f,
+                "{:<32} {:>6} {:>12.3} {:>10} {:>12}",
+                name,
+                stats.runs,
+                stats.elapsed.as_secs_f64() * 1000.0,
+                stats.functions,
+                stats.instructions
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct StatsRecorder {
+    current: Option<String>,
+    stats: AnalysisStats,
+}
+
 #[derive(Clone, Debug)]
 pub struct AnalysisModTracker {
-    analyzers: Vec<Rc<dyn Analyzer>>,
+    analyzers: Vec<Arc<dyn Analyzer>>,
+    // Shared with the copy of the tracker running the passes on its workspace
+    recorder: Arc<Mutex<StatsRecorder>>,
 }
 
 impl AnalysisModTracker {
     pub fn new() -> Self {
         AnalysisModTracker {
             analyzers: Vec::new(),
+            recorder: Arc::new(Mutex::new(StatsRecorder::default())),
         }
     }
 
-    pub fn register_analyzer(&mut self, analyzer: Rc<dyn Analyzer>) {
+    pub fn register_analyzer(&mut self, analyzer: Arc<dyn Analyzer>) {
         self.analyzers.push(analyzer);
     }
 
-    pub fn start_analysis(&self, workspace: VivWorkspace) {
-        for analyzer in self.analyzers.clone() {
-            analyzer.analyze(workspace.clone());
+    /// The names of the passes, in the order they run
+    pub fn names(&self) -> Vec<String> {
+        self.analyzers
+            .iter()
+            .map(|analyzer| analyzer.name().to_string())
+            .collect()
+    }
+
+    pub fn start_analysis(&self, workspace: &mut VivWorkspace) {
+        for index in 0..self.analyzers.len() {
+            self.run_pass(index, workspace);
         }
     }
+
+    /// Run the pass at `index` (in the order they run) on `workspace`, recording what it cost.
+    /// Returns false if there is no such pass.
+    pub fn run_pass(&self, index: usize, workspace: &mut VivWorkspace) -> bool {
+        let analyzer = match self.analyzers.get(index) {
+            Some(analyzer) => analyzer.clone(),
+            None => return false,
+        };
+        let name = analyzer.name().to_string();
+        self.recorder().current = Some(name.clone());
+        workspace.record(|| Event::Pass(name.clone()));
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("pass", name = name.as_str()).entered();
+        let start = Instant::now();
+        let confidence = workspace.get_analysis_confidence();
+        analyzer.analyze(workspace);
+        // how sure the pass was is its own business
+        workspace.set_analysis_confidence(confidence);
+        let elapsed = start.elapsed();
+        #[cfg(feature = "tracing")]
+        {
+            tracing::debug!(elapsed = ?elapsed, "pass done");
+            drop(span);
+        }
+        debug!("Analysis pass {} took {:?}", name, elapsed);
+        let mut recorder = self.recorder();
+        recorder.current = None;
+        let stats = recorder.stats.passes.entry(name).or_default();
+        stats.runs += 1;
+        stats.elapsed += elapsed;
+        true
+    }
+
+    fn recorder(&self) -> std::sync::MutexGuard<'_, StatsRecorder> {
+        self.recorder.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Put functions analyzed and instructions decoded down to the running pass
+    pub fn record(&self, functions: u64, instructions: u64) {
+        let mut recorder = self.recorder();
+        let name = recorder
+            .current
+            .clone()
+            .unwrap_or_else(|| AnalysisStats::OTHER.to_string());
+        let stats = recorder.stats.passes.entry(name).or_default();
+        stats.functions += functions;
+        stats.instructions += instructions;
+    }
+
+    pub fn stats(&self) -> AnalysisStats {
+        self.recorder().stats.clone()
+    }
+
+    /// The name of the running pass, if any
+    pub fn current_pass(&self) -> Option<String> {
+        self.recorder().current.clone()
+    }
+
+    pub fn reset_stats(&self) {
+        self.recorder().stats = AnalysisStats::default();
+    }
 }
 
 impl Default for AnalysisModTracker {
@@ -196,7 +355,7 @@ impl EntryPointsAnalyzer {
 }
 
 impl Analyzer for EntryPointsAnalyzer {
-    fn analyze(&self, mut workspace: VivWorkspace) {
+    fn analyze(&self, workspace: &mut VivWorkspace) {
         workspace.process_entry_points();
     }
 }
@@ -216,7 +375,7 @@ impl RelocationsAnalyzer {
 }
 
 impl Analyzer for RelocationsAnalyzer {
-    fn analyze(&self, mut workspace: VivWorkspace) {
+    fn analyze(&self, workspace: &mut VivWorkspace) {
         println!("INside RELOCATIONS ANALYZER.");
         for (fname, vaoff, rtype, data, size) in workspace.get_relocations() {
             let imgbase = workspace.get_file_meta(fname.as_str(), "imagebase");
@@ -245,7 +404,7 @@ impl StringConstantAnalyzer {
 }
 
 impl Analyzer for StringConstantAnalyzer {
-    fn analyze(&self, mut workspace: VivWorkspace) {
+    fn analyze(&self, workspace: &mut VivWorkspace) {
         debug!(
             "Analyzing string constants, {:?}",
             workspace.get_functions()
@@ -305,3 +464,36 @@ impl Analyzer for StringConstantAnalyzer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counting;
+
+    impl Analyzer for Counting {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            workspace.record_analysis(2, 40);
+        }
+    }
+
+    #[test]
+    fn pass_stats() {
+        let mut workspace = VivWorkspace::new("", false);
+        workspace.add_analyzer(Arc::new(Counting));
+        workspace.add_analyzer(Arc::new(EntryPointsAnalyzer::new()));
+        workspace.record_analysis(1, 3);
+        workspace.run_analyzers();
+        workspace.run_analyzers();
+        let stats = workspace.get_analysis_stats();
+        let counting = stats.pass("Counting").unwrap();
+        assert_eq!(
+            (counting.runs, counting.functions, counting.instructions),
+            (2, 4, 80)
+        );
+        assert_eq!(stats.pass("EntryPointsAnalyzer").unwrap().runs, 2);
+        assert_eq!(stats.pass(AnalysisStats::OTHER).unwrap().functions, 1);
+        assert_eq!(stats.total().instructions, 83);
+        assert!(stats.to_string().contains("Counting"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Anti-debugging, anti-VM and anti-emulation tricks.
+//!
+//! Malware checks whether it is being watched before it does anything worth watching. The
+//! common checks leave one of two traces:
+//!
+//! * a call to an API that answers the question, `IsDebuggerPresent`,
+//!   `CheckRemoteDebuggerPresent`, `NtQueryInformationProcess` for the debug port, or
+//!   `ptrace(PTRACE_TRACEME)`, which fails when a debugger is attached already;
+//! * a short instruction sequence: reading `BeingDebugged` out of the PEB (`fs:[0x30]` or
+//!   `gs:[0x60]`, then the byte at offset 2), two `rdtsc`s close together timing the code in
+//!   between, `cpuid` asking for the hypervisor leaf `0x40000000` or testing the hypervisor bit
+//!   of leaf 1, and `int 2d` or the two byte `int 3`, which behave differently under a
+//!   debugger.
+//!
+//! [`detect`] looks for both in a workspace, the sequences only in the bytes of functions on
+//! x86. Each [`Detection`] carries its [`Technique`], which maps to a MITRE ATT&CK technique,
+//! and [`crate::findings::Report::add_anti_analysis`] reports them.
+
+use crate::{envi::Arch, memory::Memory, workspace::VivWorkspace};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Technique {
+    /// A call to an API reporting a debugger
+    DebuggerApi,
+    PebBeingDebugged,
+    /// `ptrace(PTRACE_TRACEME)`
+    Ptrace,
+    /// Timing code with `rdtsc` or the tick count
+    Timing,
+    /// Looking for a hypervisor with `cpuid`
+    Hypervisor,
+    /// `int 2d` or `int 3` by its two byte encoding
+    DebugInterrupt,
+}
+
+impl Technique {
+    /// The rule id of the technique in a findings report
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Technique::DebuggerApi => "debugger-api",
+            Technique::PebBeingDebugged => "peb-being-debugged",
+            Technique::Ptrace => "ptrace-traceme",
+            Technique::Timing => "timing-check",
+            Technique::Hypervisor => "hypervisor-check",
+            Technique::DebugInterrupt => "debug-interrupt",
+        }
+    }
+
+    /// The MITRE ATT&CK technique
+    pub fn attack_id(&self) -> &'static str {
+        match self {
+            Technique::DebuggerApi
+            | Technique::PebBeingDebugged
+            | Technique::Ptrace
+            | Technique::DebugInterrupt => "T1622",
+            Technique::Timing => "T1497.003",
+            Technique::Hypervisor => "T1497.001",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Technique::DebuggerApi => "Asks the system whether a debugger is attached",
+            Technique::PebBeingDebugged => "Reads the BeingDebugged flag of the PEB",
+            Technique::Ptrace => "Traces itself so that no debugger can attach",
+            Technique::Timing => "Times its own code to notice single stepping or emulation",
+            Technique::Hypervisor => "Asks the CPU whether it runs under a hypervisor",
+            Technique::DebugInterrupt => "Raises a breakpoint which a debugger swallows",
+        }
+    }
+}
+
+impl fmt::Display for Technique {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.rule())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Detection {
+    pub va: u64,
+    pub technique: Technique,
+    /// What gave it away, `call IsDebuggerPresent`
+    pub detail: String,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} [{}] {}",
+            self.va,
+            self.technique,
+            self.technique.attack_id(),
+            self.detail
+        )
+    }
+}
+
+const DEBUGGER_APIS: [&str; 8] = [
+    "IsDebuggerPresent",
+    "CheckRemoteDebuggerPresent",
+    "NtQueryInformationProcess",
+    "ZwQueryInformationProcess",
+    "NtSetInformationThread",
+    "ZwSetInformationThread",
+    "OutputDebugStringA",
+    "OutputDebugStringW",
+];
+
+/// APIs reading a clock, a check when a function reads it twice
+const TIMING_APIS: [&str; 3] = ["GetTickCount", "QueryPerformanceCounter", "timeGetTime"];
+
+/// How far apart two `rdtsc`s can be and still time the code between them
+const RDTSC_WINDOW: usize = 0x80;
+
+/// The length of the `fs:[0x30]` or `gs:[0x60]` load at `bytes`, if it is one
+fn peb_load(arch: Arch, bytes: &[u8]) -> Option<usize> {
+    match (arch, bytes) {
+        (Arch::I386, [0x64, 0xa1, 0x30, 0, 0, 0, ..]) => Some(6),
+        (Arch::I386, [0x64, 0x8b, modrm, 0x30, 0, 0, 0, ..]) if modrm & 0xc7 == 0x05 => Some(7),
+        (Arch::Amd64, [0x65, 0x48 | 0x4c, 0x8b, modrm, 0x25, 0x60, 0, 0, 0, ..])
+            if modrm & 0xc7 == 0x04 =>
+        {
+            Some(9)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `bytes` read a byte at offset 2 from a register: `movzx`, `mov`, or `cmp` with an
+/// 8 bit displacement
+fn reads_offset_2(bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|i| {
+        let (modrm, rest) = match &bytes[i..] {
+            [0x0f, 0xb6, modrm, rest @ ..] | [0x8a | 0x80 | 0x38, modrm, rest @ ..] => {
+                (*modrm, rest)
+            }
+            _ => return false,
+        };
+        modrm & 0xc0 == 0x40 && modrm & 0x07 != 0x04 && rest.first() == Some(&2)
+    })
+}
+
+/// Whether the `cpuid` at `at` asks about a hypervisor: `mov eax, 0x40000000` just before it,
+/// or a test of bit 31 of `ecx` just after
+fn hypervisor_query(bytes: &[u8], at: usize) -> bool {
+    let before = &bytes[at.saturating_sub(16)..at];
+    let after = &bytes[at + 2..(at + 32).min(bytes.len())];
+    let leaf = before.windows(5).any(|w| w == [0xb8, 0, 0, 0, 0x40]);
+    let bit = after.windows(4).any(|w| w == [0x0f, 0xba, 0xe1, 0x1f])
+        || after.windows(6).any(|w| w == [0xf7, 0xc1, 0, 0, 0, 0x80])
+        || after.windows(6).any(|w| w == [0x81, 0xe1, 0, 0, 0, 0x80]);
+    leaf || bit
+}
+
+/// The tricks in the code `bytes` at `va`. On x86 a match may be the middle of another
+/// instruction, so give this the bytes of a function rather than a whole section.
+pub fn scan(arch: Arch, va: u64, bytes: &[u8]) -> Vec<Detection> {
+    if !matches!(arch, Arch::I386 | Arch::Amd64) {
+        return Vec::new();
+    }
+    let mut found = Vec::new();
+    let mut detect = |offset: usize, technique, detail: &str| {
+        found.push(Detection {
+            va: va + offset as u64,
+            technique,
+            detail: detail.to_string(),
+        })
+    };
+    let mut last_rdtsc: Option<usize> = None;
+    for i in 0..bytes.len() {
+        let rest = &bytes[i..];
+        if let Some(len) = peb_load(arch, rest) {
+            let after = &rest[len..rest.len().min(len + 16)];
+            if reads_offset_2(after) {
+                detect(
+                    i,
+                    Technique::PebBeingDebugged,
+                    "PEB load, then the byte at +2",
+                );
+            }
+        }
+        match rest {
+            [0x0f, 0x31, ..] => {
+                if let Some(first) = last_rdtsc.filter(|first| i - first <= RDTSC_WINDOW) {
+                    detect(first, Technique::Timing, "rdtsc twice");
+                    last_rdtsc = None;
+                } else {
+                    last_rdtsc = Some(i);
+                }
+            }
+            [0x0f, 0xa2, ..] if hypervisor_query(bytes, i) => {
+                detect(i, Technique::Hypervisor, "cpuid hypervisor query");
+            }
+            [0xcd, 0x2d, ..] => detect(i, Technique::DebugInterrupt, "int 2d"),
+            [0xcd, 0x03, ..] => detect(i, Technique::DebugInterrupt, "int 3 as cd 03"),
+            _ => {}
+        }
+    }
+    found
+}
+
+fn calls(workspace: &VivWorkspace, name: &str, technique: Technique) -> Vec<Detection> {
+    workspace
+        .get_callers_of_import(name)
+        .into_iter()
+        .map(|va| Detection {
+            va: va as u32 as u64,
+            technique,
+            detail: format!("call {}", name),
+        })
+        .collect()
+}
+
+/// The tricks in a workspace: calls to the APIs, and the instruction sequences in the bytes of
+/// its functions
+pub fn detect(workspace: &VivWorkspace) -> Vec<Detection> {
+    let mut found = Vec::new();
+    for name in DEBUGGER_APIS {
+        found.extend(calls(workspace, name, Technique::DebuggerApi));
+    }
+    found.extend(calls(workspace, "ptrace", Technique::Ptrace));
+    for name in TIMING_APIS {
+        // Reading the clock once is a timestamp; twice in one function is a measurement
+        let calls = calls(workspace, name, Technique::Timing);
+        for (i, detection) in calls.iter().enumerate() {
+            let func = workspace.get_function(detection.va as i32);
+            let again = calls[i + 1..]
+                .iter()
+                .any(|other| func.is_some() && workspace.get_function(other.va as i32) == func);
+            if again {
+                found.push(detection.clone());
+            }
+        }
+    }
+    if let Some(arch) = Arch::from_envi(workspace.arch) {
+        for fva in workspace.get_functions() {
+            for (start, size) in workspace.get_function_bounds(fva).unwrap_or_default() {
+                if let Some(bytes) = workspace.read_memory(start, size) {
+                    found.extend(scan(arch, start as u32 as u64, &bytes));
+                }
+            }
+        }
+    }
+    found.sort();
+    found.dedup();
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_I386, MM_EXEC, MM_READ, REF_CODE},
+        storage::Annotations,
+    };
+
+    #[test]
+    fn tricks() {
+        let mut code = vec![0x90; 0x80];
+        // mov eax, fs:[0x30]; movzx eax, byte [eax+2]
+        code[0..10].copy_from_slice(&[0x64, 0xa1, 0x30, 0, 0, 0, 0x0f, 0xb6, 0x40, 0x02]);
+        // rdtsc; nop; rdtsc
+        code[0x10..0x15].copy_from_slice(&[0x0f, 0x31, 0x90, 0x0f, 0x31]);
+        // mov eax, 0x40000000; cpuid
+        code[0x20..0x27].copy_from_slice(&[0xb8, 0, 0, 0, 0x40, 0x0f, 0xa2]);
+        // int 2d
+        code[0x30..0x32].copy_from_slice(&[0xcd, 0x2d]);
+        // a lone cpuid and rdtsc are fine
+        code[0x40..0x42].copy_from_slice(&[0x0f, 0xa2]);
+        code[0x70..0x72].copy_from_slice(&[0x0f, 0x31]);
+
+        let mut ann = Annotations::new();
+        ann.functions.insert(0x1000, 0x80);
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        ws.apply_annotations(&ann);
+        ws.set_function_bounds(0x1000, vec![(0x1000, 0x80)]);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code, None);
+        ws.make_import(0x3000, "kernel32", "IsDebuggerPresent");
+        ws.make_import(0x3004, "kernel32", "GetTickCount");
+        ws.add_xref(0x1050, 0x3000, REF_CODE, 0);
+        ws.add_xref(0x1058, 0x3004, REF_CODE, 0);
+
+        let found: Vec<(u64, Technique)> =
+            detect(&ws).iter().map(|d| (d.va, d.technique)).collect();
+        assert_eq!(
+            found,
+            [
+                (0x1000, Technique::PebBeingDebugged),
+                (0x1010, Technique::Timing),
+                (0x1025, Technique::Hypervisor),
+                (0x1030, Technique::DebugInterrupt),
+                (0x1050, Technique::DebuggerApi),
+            ]
+        );
+        assert_eq!(
+            detect(&ws)[4].to_string(),
+            "0x1050: debugger-api [T1622] call IsDebuggerPresent"
+        );
+    }
+}
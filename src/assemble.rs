@@ -0,0 +1,413 @@
+//! Assembling patches from text, so a patch can be written as `jmp 0x401000` rather than
+//! encoded by hand.
+//!
+//! [`Assembler`] is the hook: anything turning a line of assembly at an address into bytes,
+//! such as a binding to an external assembler. With the `assembler` feature, [`IcedAssembler`]
+//! is one for i386 and amd64 on top of the iced-x86 encoder, and
+//! [`VivWorkspace::patch_asm`](crate::workspace::VivWorkspace::patch_asm) patches the workspace
+//! with it; [`VivWorkspace::patch_with`](crate::workspace::VivWorkspace::patch_with) takes any
+//! other.
+
+use crate::envi::Arch;
+use std::io;
+
+/// Turns assembly text into machine code
+pub trait Assembler {
+    /// The bytes of `text`, one or more instructions separated by `;` or newlines, placed at
+    /// `va`
+    fn assemble(&self, arch: Arch, va: u64, text: &str) -> io::Result<Vec<u8>>;
+}
+
+/// An Intel syntax assembler for i386 and amd64 on the iced-x86 encoder.
+///
+/// Operands are registers, immediates (decimal, `0x` or `h` suffixed hex, maybe negative) and
+/// memory operands like `dword ptr fs:[eax+ecx*4+0x10]`. A memory operand without a size takes
+/// the size of the register operand, or the pointer size if there is none. Branch targets are
+/// absolute, and the shortest encoding is picked.
+#[cfg(feature = "assembler")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IcedAssembler;
+
+#[cfg(feature = "assembler")]
+mod iced {
+    use iced_x86::{
+        Code, Encoder, EncodingKind, Instruction, MemoryOperand, OpCodeOperandKind as K, OpKind,
+        Register,
+    };
+    use std::io;
+
+    fn invalid(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    /// Mnemonics iced knows under another name
+    const ALIASES: [(&str, &str); 20] = [
+        ("jz", "je"),
+        ("jnz", "jne"),
+        ("jc", "jb"),
+        ("jnae", "jb"),
+        ("jnc", "jae"),
+        ("jnb", "jae"),
+        ("jna", "jbe"),
+        ("jnbe", "ja"),
+        ("jpe", "jp"),
+        ("jpo", "jnp"),
+        ("jnge", "jl"),
+        ("jnl", "jge"),
+        ("jng", "jle"),
+        ("jnle", "jg"),
+        ("setz", "sete"),
+        ("setnz", "setne"),
+        ("cmovz", "cmove"),
+        ("cmovnz", "cmovne"),
+        ("sal", "shl"),
+        ("retn", "ret"),
+    ];
+
+    #[derive(Clone, Copy, Debug)]
+    enum Operand {
+        Reg(Register),
+        Imm(i64),
+        /// A memory operand and its size in bytes, if given
+        Mem(MemoryOperand, Option<usize>),
+    }
+
+    fn register(name: &str) -> Option<Register> {
+        Register::values()
+            .skip(1)
+            .find(|reg| format!("{:?}", reg).eq_ignore_ascii_case(name))
+    }
+
+    fn immediate(text: &str) -> Option<i64> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(text) => (true, text),
+            None => (false, text),
+        };
+        let value = if let Some(hex) = text.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16).ok()?
+        } else if let Some(hex) = text.strip_suffix('h') {
+            u64::from_str_radix(hex, 16).ok()?
+        } else {
+            text.parse::<u64>().ok()?
+        } as i64;
+        Some(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        })
+    }
+
+    fn memory(text: &str, bitness: u32) -> Option<Operand> {
+        let mut size = None;
+        let mut rest = text;
+        for (name, bytes) in [("byte", 1), ("word", 2), ("dword", 4), ("qword", 8)] {
+            if let Some(after) = rest.strip_prefix(name) {
+                if after.starts_with([' ', '[']) {
+                    size = Some(bytes);
+                    rest = after.trim_start();
+                    rest = rest.strip_prefix("ptr").unwrap_or(rest).trim_start();
+                    break;
+                }
+            }
+        }
+        let (outer, inner) = rest.strip_suffix(']')?.split_once('[')?;
+        let mut segment = match outer.trim().strip_suffix(':') {
+            Some(seg) => Some(register(seg.trim())?),
+            None if outer.trim().is_empty() => None,
+            None => return None,
+        };
+        let mut inner = inner.trim();
+        if let Some((seg, after)) = inner.split_once(':') {
+            segment = Some(register(seg.trim())?);
+            inner = after.trim();
+        }
+        let (mut base, mut index, mut scale, mut disp) = (Register::None, Register::None, 1, 0i64);
+        for term in inner.replace('-', "+-").split('+') {
+            let term = term.trim();
+            if term.is_empty() {
+                continue;
+            }
+            if let Some((reg, factor)) = term.split_once('*') {
+                index = register(reg.trim())?;
+                scale = immediate(factor.trim())? as u32;
+            } else if let Some(reg) = register(term) {
+                if base == Register::None {
+                    base = reg;
+                } else {
+                    index = reg;
+                }
+            } else {
+                disp = disp.wrapping_add(immediate(term)?);
+            }
+        }
+        let displ_size = if base == Register::None && index == Register::None {
+            bitness / 8
+        } else if disp == 0 {
+            0
+        } else if i8::try_from(disp).is_ok() {
+            1
+        } else {
+            bitness / 8
+        };
+        let segment = segment.unwrap_or(Register::None);
+        let mem = MemoryOperand::new(base, index, scale, disp, displ_size, false, segment);
+        Some(Operand::Mem(mem, size))
+    }
+
+    fn operand(text: &str, bitness: u32) -> Option<Operand> {
+        if text.ends_with(']') {
+            return memory(text, bitness);
+        }
+        register(text)
+            .map(Operand::Reg)
+            .or_else(|| immediate(text).map(Operand::Imm))
+    }
+
+    /// The size of the general purpose registers an operand kind takes, if it takes one
+    fn gpr_size(kind: K) -> Option<usize> {
+        match kind {
+            K::r8_reg | K::r8_opcode | K::r8_or_mem => Some(1),
+            K::r16_reg | K::r16_reg_mem | K::r16_rm | K::r16_opcode | K::r16_or_mem => Some(2),
+            K::r32_reg | K::r32_reg_mem | K::r32_rm | K::r32_opcode | K::r32_or_mem => Some(4),
+            K::r64_reg | K::r64_reg_mem | K::r64_rm | K::r64_opcode | K::r64_or_mem => Some(8),
+            _ => None,
+        }
+    }
+
+    fn fixed_register(kind: K) -> Option<Register> {
+        Some(match kind {
+            K::al => Register::AL,
+            K::cl => Register::CL,
+            K::ax => Register::AX,
+            K::dx => Register::DX,
+            K::eax => Register::EAX,
+            K::rax => Register::RAX,
+            K::es => Register::ES,
+            K::cs => Register::CS,
+            K::ss => Register::SS,
+            K::ds => Register::DS,
+            K::fs => Register::FS,
+            K::gs => Register::GS,
+            _ => return None,
+        })
+    }
+
+    fn gpr_of(reg: Register) -> Option<usize> {
+        if reg.is_gpr8() {
+            Some(1)
+        } else if reg.is_gpr16() {
+            Some(2)
+        } else if reg.is_gpr32() {
+            Some(4)
+        } else if reg.is_gpr64() {
+            Some(8)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of immediate operand an operand kind takes, if the value fits it
+    fn immediate_kind(kind: K, value: i64) -> Option<OpKind> {
+        let fits = |min: i64, max: i64| min <= value && value <= max;
+        match kind {
+            K::imm8 if fits(i8::MIN as i64, u8::MAX as i64) => Some(OpKind::Immediate8),
+            K::imm8_const_1 if value == 1 => Some(OpKind::Immediate8),
+            K::imm8sex16 if fits(i8::MIN as i64, i8::MAX as i64) => Some(OpKind::Immediate8to16),
+            K::imm8sex32 if fits(i8::MIN as i64, i8::MAX as i64) => Some(OpKind::Immediate8to32),
+            K::imm8sex64 if fits(i8::MIN as i64, i8::MAX as i64) => Some(OpKind::Immediate8to64),
+            K::imm16 if fits(i16::MIN as i64, u16::MAX as i64) => Some(OpKind::Immediate16),
+            K::imm32 if fits(i32::MIN as i64, u32::MAX as i64) => Some(OpKind::Immediate32),
+            K::imm32sex64 if fits(i32::MIN as i64, i32::MAX as i64) => {
+                Some(OpKind::Immediate32to64)
+            }
+            K::imm64 => Some(OpKind::Immediate64),
+            _ => None,
+        }
+    }
+
+    fn is_branch(kind: K, bitness: u32) -> bool {
+        match bitness {
+            32 => matches!(kind, K::br32_1 | K::br32_4),
+            _ => matches!(kind, K::br64_1 | K::br64_4),
+        }
+    }
+
+    /// The instruction `code` makes of the operands, if they fit it
+    fn instruction(code: Code, ops: &[Operand], bitness: u32) -> Option<Instruction> {
+        let info = code.op_code();
+        let kinds = info.op_kinds();
+        if kinds.len() != ops.len() {
+            return None;
+        }
+        if let ([kind], [Operand::Imm(target)]) = (kinds, ops) {
+            if is_branch(*kind, bitness) {
+                return Instruction::with_branch(code, *target as u64).ok();
+            }
+        }
+        let sized = ops.iter().find_map(|op| match op {
+            Operand::Reg(reg) => gpr_of(*reg),
+            _ => None,
+        });
+        let mut instr = Instruction::default();
+        instr.set_code(code);
+        for (i, (kind, op)) in kinds.iter().zip(ops.iter()).enumerate() {
+            let i = i as u32;
+            match *op {
+                Operand::Reg(reg) => {
+                    let fits = match fixed_register(*kind) {
+                        Some(fixed) => fixed == reg,
+                        None if *kind == K::seg_reg => reg.is_segment_register(),
+                        None => gpr_size(*kind).is_some() && gpr_size(*kind) == gpr_of(reg),
+                    };
+                    if !fits {
+                        return None;
+                    }
+                    instr.set_op_kind(i, OpKind::Register);
+                    instr.set_op_register(i, reg);
+                }
+                Operand::Imm(value) => {
+                    let op_kind = immediate_kind(*kind, value)?;
+                    instr.set_op_kind(i, op_kind);
+                    instr.try_set_immediate_i64(i, value).ok()?;
+                }
+                Operand::Mem(mem, size) => {
+                    match (*kind, size.or(sized)) {
+                        (K::mem, _) => {}
+                        (kind, size) => {
+                            let size = size.unwrap_or(bitness as usize / 8);
+                            if gpr_size(kind)? != size
+                                || !matches!(
+                                    kind,
+                                    K::r8_or_mem | K::r16_or_mem | K::r32_or_mem | K::r64_or_mem
+                                )
+                            {
+                                return None;
+                            }
+                        }
+                    }
+                    instr.set_op_kind(i, OpKind::Memory);
+                    instr.set_memory_base(mem.base);
+                    instr.set_memory_index(mem.index);
+                    instr.set_memory_index_scale(mem.scale);
+                    instr.set_memory_displ_size(mem.displ_size);
+                    instr.set_memory_displacement64(mem.displacement as u64);
+                    instr.set_segment_prefix(mem.segment_prefix);
+                }
+            }
+        }
+        Some(instr)
+    }
+
+    /// The shortest encoding of one instruction at `va`
+    pub(super) fn assemble_one(line: &str, bitness: u32, va: u64) -> io::Result<Vec<u8>> {
+        let line = line.trim().to_ascii_lowercase();
+        let (mnemonic, rest) = line.split_once(' ').unwrap_or((&line, ""));
+        let mnemonic = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == mnemonic)
+            .map_or(mnemonic, |(_, name)| name);
+        let ops = rest
+            .split(',')
+            .map(str::trim)
+            .filter(|op| !op.is_empty())
+            .map(|op| operand(op, bitness).ok_or_else(|| invalid(format!("bad operand: {}", op))))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut best: Option<Vec<u8>> = None;
+        for code in Code::values() {
+            let info = code.op_code();
+            if !info.is_instruction()
+                || info.encoding() != EncodingKind::Legacy
+                || !format!("{:?}", code.mnemonic()).eq_ignore_ascii_case(mnemonic)
+                || !(if bitness == 64 {
+                    info.mode64()
+                } else {
+                    info.mode32()
+                })
+            {
+                continue;
+            }
+            let Some(instr) = instruction(code, &ops, bitness) else {
+                continue;
+            };
+            let mut encoder = Encoder::new(bitness);
+            if encoder.encode(&instr, va).is_err() {
+                continue;
+            }
+            let bytes = encoder.take_buffer();
+            if best.as_ref().is_none_or(|best| bytes.len() < best.len()) {
+                best = Some(bytes);
+            }
+        }
+        best.ok_or_else(|| invalid(format!("cannot assemble: {}", line)))
+    }
+}
+
+#[cfg(feature = "assembler")]
+impl Assembler for IcedAssembler {
+    fn assemble(&self, arch: Arch, va: u64, text: &str) -> io::Result<Vec<u8>> {
+        let bitness = match arch {
+            Arch::I386 => 32,
+            Arch::Amd64 => 64,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("no assembler for {}", arch.name()),
+                ))
+            }
+        };
+        let mut bytes = Vec::new();
+        for line in text
+            .split([';', '\n'])
+            .filter(|line| !line.trim().is_empty())
+        {
+            let va = va.wrapping_add(bytes.len() as u64);
+            bytes.extend(iced::assemble_one(line, bitness, va)?);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_I386, MM_EXEC, MM_READ},
+        memory::Memory,
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn patch_asm() {
+        let asm = IcedAssembler;
+        let bytes = |arch, va, text| asm.assemble(arch, va, text).unwrap();
+        assert_eq!(
+            bytes(Arch::I386, 0x1000, "push dword ptr [ebp+8]"),
+            [0xff, 0x75, 0x08]
+        );
+        assert_eq!(
+            bytes(Arch::I386, 0x1000, "jnz 0x1100"),
+            [0x0f, 0x85, 0xfa, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes(Arch::Amd64, 0x1000, "call 0x1000"),
+            [0xe8, 0xfb, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            bytes(Arch::Amd64, 0, "mov rax, 0x1122334455667788"),
+            [0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+        assert!(asm.assemble(Arch::I386, 0, "mov eax, [ebx").is_err());
+        assert!(asm.assemble(Arch::A64, 0, "ret").is_err());
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        ws.add_memory_map(0x401000, MM_READ | MM_EXEC, "a.exe", vec![0x90; 0x10], None);
+        assert_eq!(ws.patch_asm(0x401002, "jmp 0x401000").unwrap(), 2);
+        assert_eq!(ws.patch_asm(0x401004, "mov eax, 1; ret").unwrap(), 6);
+        assert_eq!(
+            ws.read_memory(0x401000, 12).unwrap(),
+            [0x90, 0x90, 0xeb, 0xfc, 0xb8, 1, 0, 0, 0, 0xc3, 0x90, 0x90]
+        );
+        assert!(ws.patch_asm(0x40100e, "mov eax, 1").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! MITRE ATT&CK technique ids for what the detection passes find.
+//!
+//! Findings carry the ids of the techniques they are evidence of, `T1622` or `T1071.001`, so
+//! threat intel systems can file them without reading the message. The anti-analysis pass
+//! knows its techniques, capability rules name theirs in their `att&ck` meta, and the tags of
+//! imported APIs (see [`crate::tags`]) map onto them through [`TAG_TECHNIQUES`].
+
+/// The technique of each import tag, the most specific tag first
+pub const TAG_TECHNIQUES: &[(&str, &str)] = &[
+    ("network/dns", "T1071.004"),
+    ("network/http", "T1071.001"),
+    ("network", "T1071"),
+    ("crypto", "T1027"),
+    ("anti-debug", "T1622"),
+    ("process", "T1106"),
+    ("injection", "T1055"),
+    ("registry", "T1112"),
+    ("hooking", "T1056.004"),
+    ("keylogging", "T1056.001"),
+];
+
+/// Whether `id` is a technique or sub-technique id, `T1234` or `T1234.001`
+pub fn is_technique(id: &str) -> bool {
+    let Some(digits) = id.strip_prefix('T') else {
+        return false;
+    };
+    let (main, sub) = match digits.split_once('.') {
+        Some((main, sub)) => (main, Some(sub)),
+        None => (digits, None),
+    };
+    let numeric = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    numeric(main, 4) && sub.is_none_or(|sub| numeric(sub, 3))
+}
+
+/// The technique id of an ATT&CK entry as capa rules write them,
+/// `Defense Evasion::Obfuscated Files or Information [T1027]`, or of a bare id
+pub fn technique_id(entry: &str) -> Option<&str> {
+    let entry = entry.trim();
+    let id = match entry.rfind('[') {
+        Some(start) => entry[start + 1..].strip_suffix(']')?,
+        None => entry,
+    };
+    is_technique(id).then_some(id)
+}
+
+/// The technique of a tag, or of the nearest tag above it with one
+pub fn tag_technique(tag: &str) -> Option<&'static str> {
+    TAG_TECHNIQUES
+        .iter()
+        .find(|(parent, _)| crate::tags::is_under(tag, parent))
+        .map(|(_, id)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn technique_ids() {
+        assert_eq!(
+            technique_id("Defense Evasion::Obfuscated Files or Information [T1027.002]"),
+            Some("T1027.002")
+        );
+        assert_eq!(technique_id("T1622"), Some("T1622"));
+        assert_eq!(technique_id("Execution::Shared Modules [1129]"), None);
+        assert!(!is_technique("T12345"));
+        assert_eq!(tag_technique("network/http"), Some("T1071.001"));
+        assert_eq!(tag_technique("network/smtp"), Some("T1071"));
+        assert_eq!(tag_technique("crypto/aes"), Some("T1027"));
+        assert_eq!(tag_technique("networking"), None);
+    }
+}
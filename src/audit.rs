@@ -0,0 +1,652 @@
+//! A security audit of the calls a binary makes.
+//!
+//! A code review of a binary starts from the calls most likely to be bugs, and [`audit`] finds
+//! them in the lifted functions:
+//!
+//! * printf-family calls whose format isn't a constant string ([`FORMAT_APIS`]). A format
+//!   worked out at run time or kept in writable memory may be the attacker's, and one from
+//!   input certainly is;
+//! * calls to string APIs with no bound on what they write, `strcpy`, `sprintf` and `gets`
+//!   ([`UNBOUNDED_APIS`]);
+//! * stack allocations sized by input (see [`crate::taint`]): calls to `alloca`, and the stack
+//!   pointer moved by a register the input controls, which is what an inlined `alloca` is;
+//! * heap allocations sized by a product which can overflow, `malloc(n * size)` or
+//!   `operator new[]` ([`ALLOC_APIS`]), with no check on the way to the call: no branch on
+//!   overflow and no compare of a factor against a constant. A factor whose known bits keep the
+//!   product in range needs no check.
+//!
+//! Each [`Issue`] carries the call as far as its arguments are known (see [`crate::callargs`])
+//! as evidence, and [`crate::findings::Report::add_audit`] reports them.
+
+use crate::{
+    callargs::{call_site, Api, Arg, Value},
+    envi::flags::Condition,
+    envi::registers::RegisterModel,
+    findings::Level,
+    pic::AddressSpace,
+    symbolic::{call_argument, BinOp, Expr, FlagOp, Function, Stmt, Terminator, UnOp},
+    syscalls::{expr_before, function_at},
+    taint::{input_calls, Taint},
+    vsa::{value_before, ValueSet},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Check {
+    FormatString,
+    UnboundedCopy,
+    TaintedAlloca,
+    AllocOverflow,
+}
+
+impl Check {
+    /// The rule id of the check in a findings report
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Check::FormatString => "format-string",
+            Check::UnboundedCopy => "unbounded-copy",
+            Check::TaintedAlloca => "tainted-alloca",
+            Check::AllocOverflow => "alloc-overflow",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Check::FormatString => "A printf-family call whose format is not a constant string",
+            Check::UnboundedCopy => "A string API with no bound on what it writes",
+            Check::TaintedAlloca => "A stack allocation whose size comes from input",
+            Check::AllocOverflow => "An allocation sized by a product not checked for overflow",
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.rule())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    /// The call or the instruction at fault
+    pub va: u64,
+    pub check: Check,
+    pub level: Level,
+    /// The function it is in
+    pub function: u64,
+    /// What gave it away, `sprintf(?, "%s/%s", ...)`
+    pub evidence: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} [{}] in {:#x}: {}",
+            self.va, self.check, self.level, self.function, self.evidence
+        )
+    }
+}
+
+use Arg::{Int, Str, WStr};
+
+const fn api<'a>(name: &'a str, args: &'a [Arg]) -> Api<'a> {
+    Api { name, args }
+}
+
+/// The printf family, the format being the last argument listed
+pub const FORMAT_APIS: &[Api<'static>] = &[
+    api("printf", &[Str]),
+    api("vprintf", &[Str]),
+    api("fprintf", &[Int, Str]),
+    api("vfprintf", &[Int, Str]),
+    api("dprintf", &[Int, Str]),
+    api("sprintf", &[Int, Str]),
+    api("vsprintf", &[Int, Str]),
+    api("snprintf", &[Int, Int, Str]),
+    api("vsnprintf", &[Int, Int, Str]),
+    api("_snprintf", &[Int, Int, Str]),
+    api("syslog", &[Int, Str]),
+    api("err", &[Int, Str]),
+    api("warn", &[Str]),
+    api("wprintf", &[WStr]),
+    api("fwprintf", &[Int, WStr]),
+    api("wsprintfA", &[Int, Str]),
+    api("wsprintfW", &[Int, WStr]),
+];
+
+/// String APIs writing as much as they are given
+pub const UNBOUNDED_APIS: &[Api<'static>] = &[
+    api("gets", &[Int]),
+    api("_getws", &[Int]),
+    api("strcpy", &[Int, Str]),
+    api("strcat", &[Int, Str]),
+    api("wcscpy", &[Int, WStr]),
+    api("wcscat", &[Int, WStr]),
+    api("lstrcpyA", &[Int, Str]),
+    api("lstrcpyW", &[Int, WStr]),
+    api("lstrcatA", &[Int, Str]),
+    api("lstrcatW", &[Int, WStr]),
+    api("sprintf", &[Int, Str]),
+    api("vsprintf", &[Int, Str]),
+    api("wsprintfA", &[Int, Str]),
+    api("wsprintfW", &[Int, WStr]),
+];
+
+/// Stack allocators, taking the size as their first argument
+const ALLOCA_APIS: [&str; 3] = ["alloca", "_alloca", "__builtin_alloca"];
+
+/// Allocators and the argument which is the size
+pub const ALLOC_APIS: &[(&str, usize)] = &[
+    ("malloc", 0),
+    ("realloc", 1),
+    ("HeapAlloc", 2),
+    ("HeapReAlloc", 3),
+    ("LocalAlloc", 1),
+    ("GlobalAlloc", 1),
+    ("VirtualAlloc", 1),
+    // operator new and new[]
+    ("_Znwm", 0),
+    ("_Znwj", 0),
+    ("_Znam", 0),
+    ("_Znaj", 0),
+    ("??2@YAPAXI@Z", 0),
+    ("??2@YAPEAX_K@Z", 0),
+    ("??_U@YAPAXI@Z", 0),
+    ("??_U@YAPEAX_K@Z", 0),
+];
+
+/// The largest value `expr` can have
+fn max_value(expr: &Expr, width: u32) -> u128 {
+    let mask = u64::MAX >> (64 - width);
+    u128::from(!expr.known_bits(width).zeros & mask)
+}
+
+/// The values multiplied in `size` where the product may not fit: both sides of a product of
+/// two unknowns, the unknown side of a product or left shift by a constant
+fn factors(size: &Expr, width: u32, found: &mut Vec<Expr>) {
+    let limit = 1u128 << width;
+    match size {
+        Expr::Binary(BinOp::Mul, a, b) => match (a.as_const(), b.as_const()) {
+            (Some(_), Some(_)) => {}
+            (Some(c), None) | (None, Some(c)) => {
+                let x = if a.as_const().is_some() { b } else { a };
+                if c > 1 && max_value(x, width) * u128::from(c) >= limit {
+                    found.push((**x).clone());
+                }
+            }
+            (None, None) => {
+                if max_value(a, width) * max_value(b, width) >= limit {
+                    found.extend([(**a).clone(), (**b).clone()]);
+                }
+            }
+        },
+        Expr::Binary(BinOp::Shl, a, b) if a.as_const().is_none() => {
+            let shift = b.as_const().filter(|shift| (1..64).contains(shift));
+            if shift.is_some_and(|shift| max_value(a, width) << shift >= limit) {
+                found.push((**a).clone());
+            }
+        }
+        Expr::Binary(_, a, b) => {
+            factors(a, width, found);
+            factors(b, width, found);
+        }
+        Expr::Unary(_, a) => factors(a, width, found),
+        Expr::Const(_) | Expr::Reg(_) | Expr::Load(_) => {}
+    }
+}
+
+/// The registers, as full registers, and the loads an expression is made of
+fn leaves(model: &RegisterModel, expr: &Expr, found: &mut BTreeSet<Expr>) {
+    match expr {
+        Expr::Const(_) => {}
+        Expr::Reg(reg) => {
+            found.insert(Expr::Reg(model.full(*reg)));
+        }
+        Expr::Load(_) => {
+            found.insert(expr.clone());
+        }
+        Expr::Unary(_, a) => leaves(model, a, found),
+        Expr::Binary(_, a, b) => {
+            leaves(model, a, found);
+            leaves(model, b, found);
+        }
+    }
+}
+
+/// Whether a branch on the way to `block` tests for overflow, or compares something `factors`
+/// are made of against a constant
+fn guarded(func: &Function, model: &RegisterModel, block: u64, factors: &[Expr]) -> bool {
+    let mut made_of = BTreeSet::new();
+    for factor in factors {
+        leaves(model, factor, &mut made_of);
+    }
+    let preds = func.predecessors();
+    let states = func.block_states();
+    let mut seen = BTreeSet::from([block]);
+    let mut queue: Vec<u64> = preds.get(&block).cloned().unwrap_or_default();
+    while let Some(va) = queue.pop() {
+        if !seen.insert(va) {
+            continue;
+        }
+        queue.extend(preds.get(&va).into_iter().flatten());
+        let Some(Terminator::Branch { cond, .. }) = func.blocks.get(&va).map(|b| &b.end) else {
+            continue;
+        };
+        if matches!(cond, Condition::Overflow | Condition::NoOverflow) {
+            return true;
+        }
+        let Some((FlagOp::Sub, a, b)) = states.get(&va).and_then(|state| state.flag_source())
+        else {
+            continue;
+        };
+        let other = match (a.as_const(), b.as_const()) {
+            (Some(_), None) => b,
+            (None, Some(_)) => a,
+            _ => continue,
+        };
+        let mut compared = BTreeSet::new();
+        leaves(model, other, &mut compared);
+        if !compared.is_disjoint(&made_of) {
+            return true;
+        }
+    }
+    false
+}
+
+/// An expression as text, with register names
+fn render(model: &RegisterModel, expr: &Expr) -> String {
+    // operands of an operation in parentheses where they are operations themselves
+    let operand = |a: &Expr| match a {
+        Expr::Binary(..) => format!("({})", render(model, a)),
+        _ => render(model, a),
+    };
+    match expr {
+        Expr::Const(c) => format!("{:#x}", c),
+        Expr::Reg(reg) => model.name(*reg).to_string(),
+        Expr::Load(addr) => format!("[{}]", render(model, addr)),
+        Expr::Unary(UnOp::Not, a) => format!("~{}", operand(a)),
+        Expr::Unary(UnOp::Neg, a) => format!("-{}", operand(a)),
+        Expr::Binary(op, a, b) => {
+            let op = match op {
+                BinOp::Add => "+",
+                BinOp::Sub => "-",
+                BinOp::Mul => "*",
+                BinOp::And => "&",
+                BinOp::Or => "|",
+                BinOp::Xor => "^",
+                BinOp::Shl => "<<",
+                BinOp::Shr => ">>",
+                BinOp::Sar => ">>>",
+            };
+            format!("{} {} {}", operand(a), op, operand(b))
+        }
+    }
+}
+
+/// Where a format not constant comes from, if anywhere of note
+fn format_origin(
+    func: &Function,
+    space: &AddressSpace<'_>,
+    taint: &Taint,
+    call: u64,
+    location: &Expr,
+) -> Option<(Level, String)> {
+    if taint.is_tainted_before(func, call, location) {
+        return Some((Level::Error, "comes from input".to_string()));
+    }
+    match value_before(func, space, call, location) {
+        ValueSet::Values(values) if !values.is_empty() => {
+            let writable = values
+                .iter()
+                .find(|va| space.bytes(**va, 1).is_none_or(|(_, writable)| writable))?;
+            Some((
+                Level::Warning,
+                format!("is in writable or unmapped memory at {:#x}", writable),
+            ))
+        }
+        _ => Some((Level::Warning, "is not known statically".to_string())),
+    }
+}
+
+/// The taint of `func`, worked out the first time it is asked for
+fn taint_of<'a>(
+    taints: &'a mut BTreeMap<u64, Taint>,
+    func: &Function,
+    sources: &BTreeSet<u64>,
+) -> &'a Taint {
+    taints
+        .entry(func.entry)
+        .or_insert_with(|| Taint::new(func, sources))
+}
+
+/// Audit the calls in the lifted `functions`. Calls go by the workspace's xrefs to the imports;
+/// `space` is the memory the functions run in and `windows` whether the binary follows the
+/// Windows calling convention. Input comes from the calls to [`crate::taint::INPUT_APIS`].
+pub fn audit(
+    workspace: &VivWorkspace,
+    functions: &BTreeMap<u64, Function>,
+    space: &AddressSpace<'_>,
+    windows: bool,
+) -> Vec<Issue> {
+    let sources = input_calls(workspace);
+    let mut taints: BTreeMap<u64, Taint> = BTreeMap::new();
+    let calls = |name: &str| -> Vec<(u64, &Function)> {
+        workspace
+            .get_callers_of_import(name)
+            .into_iter()
+            .filter_map(|call| {
+                let call = call as u32 as u64;
+                function_at(functions, call).map(|func| (call, func))
+            })
+            .collect()
+    };
+    let mut issues = Vec::new();
+
+    for api in FORMAT_APIS {
+        let index = api.args.len() - 1;
+        for (call, func) in calls(api.name) {
+            let Some(location) = call_argument(func.arch, windows, index) else {
+                continue;
+            };
+            let taint = taint_of(&mut taints, func, &sources);
+            let Some((level, origin)) = format_origin(func, space, taint, call, &location) else {
+                continue;
+            };
+            let site = call_site(func, space, call, windows, api);
+            issues.push(Issue {
+                va: call,
+                check: Check::FormatString,
+                level,
+                function: func.entry,
+                evidence: format!("{}: the format {}", site, origin),
+            });
+        }
+    }
+
+    for api in UNBOUNDED_APIS {
+        for (call, func) in calls(api.name) {
+            let site = call_site(func, space, call, windows, api);
+            let (level, why) = match site.args.last() {
+                _ if api.args.len() == 1 => (Level::Error, "reads a line of any length"),
+                Some(Value::Str { .. }) => (Level::Note, "copies a constant string"),
+                _ => (Level::Warning, "copies a string of any length"),
+            };
+            issues.push(Issue {
+                va: call,
+                check: Check::UnboundedCopy,
+                level,
+                function: func.entry,
+                evidence: format!("{}: {} with no bound", site, why),
+            });
+        }
+    }
+
+    for name in ALLOCA_APIS {
+        for (call, func) in calls(name) {
+            let Some(size) = call_argument(func.arch, windows, 0) else {
+                continue;
+            };
+            if taint_of(&mut taints, func, &sources).is_tainted_before(func, call, &size) {
+                issues.push(Issue {
+                    va: call,
+                    check: Check::TaintedAlloca,
+                    level: Level::Error,
+                    function: func.entry,
+                    evidence: format!("{}(size from input)", name),
+                });
+            }
+        }
+    }
+    for (name, index) in ALLOC_APIS {
+        for (call, func) in calls(name) {
+            let Some(location) = call_argument(func.arch, windows, *index) else {
+                continue;
+            };
+            let Some(size) = expr_before(func, call, &location) else {
+                continue;
+            };
+            let width = func.arch.pointer_size() as u32 * 8;
+            let mut found = Vec::new();
+            factors(&size, width, &mut found);
+            let model = RegisterModel::new(func.arch);
+            let Some((block, _)) = func.blocks.range(..=call).next_back() else {
+                continue;
+            };
+            if found.is_empty() || guarded(func, &model, *block, &found) {
+                continue;
+            }
+            let tainted =
+                taint_of(&mut taints, func, &sources).is_tainted_before(func, call, &location);
+            let (level, from) = match tainted {
+                true => (Level::Error, ", from input,"),
+                false => (Level::Warning, ""),
+            };
+            issues.push(Issue {
+                va: call,
+                check: Check::AllocOverflow,
+                level,
+                function: func.entry,
+                evidence: format!(
+                    "{}({}): the size{} is a product not checked for overflow",
+                    name,
+                    render(&model, &size),
+                    from
+                ),
+            });
+        }
+    }
+
+    // an inlined alloca moves the stack pointer by a register
+    for func in functions.values() {
+        let model = RegisterModel::new(func.arch);
+        let sp = model.full(model.sp());
+        for block in func.blocks.values() {
+            for insn in block.insns.iter() {
+                let moved = insn.stmts.iter().find_map(|stmt| match stmt {
+                    Stmt::Set(reg, value) if model.full(*reg) == sp => {
+                        let mut regs = BTreeSet::new();
+                        value.registers(&mut regs);
+                        regs.iter()
+                            .any(|reg| model.full(*reg) != sp)
+                            .then_some(value)
+                    }
+                    _ => None,
+                });
+                let Some(value) = moved else {
+                    continue;
+                };
+                let taint = taint_of(&mut taints, func, &sources);
+                if taint.is_tainted_before(func, insn.va, value) {
+                    issues.push(Issue {
+                        va: insn.va,
+                        check: Check::TaintedAlloca,
+                        level: Level::Error,
+                        function: func.entry,
+                        evidence: "stack pointer moved by a size from input".to_string(),
+                    });
+                }
+            }
+        }
+    }
+    issues.sort_by_key(|issue| (issue.va, issue.check));
+    issues.dedup();
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::REF_CODE,
+        envi::Arch,
+        symbolic::{BinOp, Block, Insn, Terminator},
+    };
+
+    #[test]
+    fn audit_findings() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let (rax, rdi, rsi, rsp) = (reg("rax"), reg("rdi"), reg("rsi"), model.sp());
+        let set = |reg, value| Stmt::Set(reg, Expr::Const(value));
+        let insn = |va, stmts| Insn { va, stmts };
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        // printf("%d\n"); printf([0x3000]); strcpy(?, "abc"); gets(?);
+        // n = atoi(?); rsp -= n; printf(n)
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![set(rdi, 0x2000)]),
+                insn(0x1004, vec![Stmt::Unknown]),
+                insn(0x1008, vec![set(rdi, 0x3000)]),
+                insn(0x100c, vec![Stmt::Unknown]),
+                insn(0x1010, vec![set(rsi, 0x2004)]),
+                insn(0x1014, vec![Stmt::Unknown]),
+                insn(0x1018, vec![Stmt::Unknown]),
+                insn(0x101c, vec![Stmt::Unknown]),
+                insn(
+                    0x1020,
+                    vec![Stmt::Set(
+                        rsp,
+                        Expr::binary(BinOp::Sub, Expr::Reg(rsp), Expr::Reg(rax), 64),
+                    )],
+                ),
+                insn(0x1024, vec![Stmt::Set(rdi, Expr::Reg(rax))]),
+                insn(0x1028, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x1028,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0x1000, func)]);
+        let rodata = b"%d\n\0abc\0";
+        let data = [0u8; 8];
+        let code = [0u8; 0x100];
+        let mut space = AddressSpace::new();
+        space.add_map(0x1000, &code, false);
+        space.add_map(0x2000, rodata, false);
+        space.add_map(0x3000, &data, true);
+
+        let mut ws = VivWorkspace::new("", false);
+        for (slot, name, calls) in [
+            (0x4000, "printf", &[0x1004, 0x100c, 0x1028][..]),
+            (0x4008, "strcpy", &[0x1014]),
+            (0x4010, "gets", &[0x1018]),
+            (0x4018, "atoi", &[0x101c]),
+        ] {
+            ws.make_import(slot, "libc", name);
+            for call in calls {
+                ws.add_xref(*call, slot, REF_CODE, 0);
+            }
+        }
+
+        let issues = audit(&ws, &functions, &space, false);
+        let summary: Vec<_> = issues
+            .iter()
+            .map(|issue| (issue.va, issue.check, issue.level))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x100c, Check::FormatString, Level::Warning),
+                (0x1014, Check::UnboundedCopy, Level::Note),
+                (0x1018, Check::UnboundedCopy, Level::Error),
+                (0x1020, Check::TaintedAlloca, Level::Error),
+                (0x1028, Check::FormatString, Level::Error),
+            ]
+        );
+        assert_eq!(
+            issues[0].evidence,
+            "printf(0x3000): the format is in writable or unmapped memory at 0x3000"
+        );
+        assert_eq!(
+            issues[1].evidence,
+            r#"strcpy(?, "abc"): copies a constant string with no bound"#
+        );
+        assert_eq!(
+            issues[4].evidence,
+            "printf(...): the format comes from input"
+        );
+    }
+
+    #[test]
+    fn allocation_overflow() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let (rax, rbx, rdi, rsi) = (reg("rax"), reg("rbx"), reg("rdi"), reg("rsi"));
+        let insn = |va, stmts| Insn { va, stmts };
+        let times = |a: Expr, op, b| Stmt::Set(rdi, Expr::binary(op, a, Expr::Const(b), 64));
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        // n = atoi(?); malloc(n * 0x18); malloc((rsi & 0xffff) * 8)
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Unknown]),
+                insn(0x1005, vec![Stmt::Set(rbx, Expr::Reg(rax))]),
+                insn(0x1008, vec![times(Expr::Reg(rbx), BinOp::Mul, 0x18)]),
+                insn(0x100c, vec![Stmt::Unknown]),
+                insn(
+                    0x1010,
+                    vec![times(
+                        Expr::binary(BinOp::And, Expr::Reg(rsi), Expr::Const(0xffff), 64),
+                        BinOp::Mul,
+                        8,
+                    )],
+                ),
+                insn(0x1014, vec![Stmt::Unknown]),
+                insn(
+                    0x1018,
+                    vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(rbx), Expr::Const(0x100))],
+                ),
+            ],
+            end_va: 0x101c,
+            end: Terminator::Branch {
+                cond: Condition::NoCarryNoZero,
+                taken: 0x1030,
+                fallthrough: 0x1020,
+            },
+        });
+        // if n <= 0x100: malloc(n << 4)
+        func.add_block(Block {
+            va: 0x1020,
+            insns: vec![
+                insn(0x1020, vec![times(Expr::Reg(rbx), BinOp::Shl, 4)]),
+                insn(0x1024, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x1024,
+            end: Terminator::Jump(0x1030),
+        });
+        func.add_block(Block {
+            va: 0x1030,
+            insns: vec![insn(0x1030, vec![Stmt::Unknown])],
+            end_va: 0x1030,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0x1000, func)]);
+        let code = [0u8; 0x100];
+        let mut space = AddressSpace::new();
+        space.add_map(0x1000, &code, false);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x4000, "libc", "atoi");
+        ws.make_import(0x4008, "libc", "malloc");
+        ws.add_xref(0x1000, 0x4000, REF_CODE, 0);
+        for call in [0x100c, 0x1014, 0x1024] {
+            ws.add_xref(call, 0x4008, REF_CODE, 0);
+        }
+
+        let issues = audit(&ws, &functions, &space, false);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(
+            (issues[0].va, issues[0].check, issues[0].level),
+            (0x100c, Check::AllocOverflow, Level::Error)
+        );
+        assert_eq!(
+            issues[0].evidence,
+            "malloc(rax * 0x18): the size, from input, is a product not checked for overflow"
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! The audit log of a workspace: a record of each analysis run, for forensic reports.
+//!
+//! `VivWorkspace::analyze` puts down an [`AuditRecord`] for every file it analyzes: when the
+//! run started and finished, the file's name, size and SHA-256, the version of the crate, the
+//! options the analysis ran with (the workspace metadata and the passes, in order), what each
+//! pass cost, and how many functions, imports, names and xrefs the workspace ended up with.
+//!
+//! The log is saved with the workspace annotations (see [`crate::storage`]), so it goes where
+//! the workspace goes, and [`AuditLog::to_json`] and its `Display` export it to attach to a
+//! report. Times are UTC.
+
+use crate::{
+    analysis::{AnalysisStats, PassStats},
+    utils::{hex, json_string, sha256},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// One analysis run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the run started and finished, in seconds since the Unix epoch
+    pub started: u64,
+    pub finished: u64,
+    pub file: String,
+    pub size: u64,
+    /// The SHA-256 of the file, in hex
+    pub sha256: String,
+    /// The version of the crate which ran the analysis
+    pub version: String,
+    pub options: BTreeMap<String, String>,
+    /// What each pass cost in this run
+    pub passes: AnalysisStats,
+    /// How many functions, imports, exports, names, xrefs and relocations there were after it
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl AuditRecord {
+    /// Start the record of analyzing `file`, made of `bytes`
+    pub fn new(file: &str, bytes: &[u8]) -> Self {
+        AuditRecord {
+            started: now(),
+            file: file.to_string(),
+            size: bytes.len() as u64,
+            sha256: hex(&sha256(bytes)),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ..AuditRecord::default()
+        }
+    }
+
+    /// Finish the record once the passes have run, `before` being the pass statistics of the
+    /// workspace from before they did
+    pub fn finish(mut self, workspace: &VivWorkspace, before: &AnalysisStats) -> Self {
+        self.finished = now();
+        self.options = workspace.get_meta_dict();
+        self.options.insert(
+            "analyzers".to_string(),
+            workspace.get_analyzer_names().join(","),
+        );
+        for (name, after) in workspace.get_analysis_stats().passes {
+            let earlier = before.pass(&name).cloned().unwrap_or_default();
+            let stats = PassStats {
+                runs: after.runs - earlier.runs,
+                elapsed: after.elapsed.saturating_sub(earlier.elapsed),
+                functions: after.functions - earlier.functions,
+                instructions: after.instructions - earlier.instructions,
+            };
+            if stats != PassStats::default() {
+                self.passes.passes.insert(name, stats);
+            }
+        }
+        let counts = [
+            ("functions", workspace.get_functions().len()),
+            ("imports", workspace.get_imports().len()),
+            ("exports", workspace.get_exports().len()),
+            ("names", workspace.get_names().len()),
+            ("xrefs", workspace.get_xrefs(None).len()),
+            ("relocations", workspace.get_relocations().len()),
+        ];
+        self.counts = counts
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count as u64))
+            .collect();
+        self
+    }
+
+    /// The record as `<field> <value>` lines, for the annotation snapshot
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = vec![
+            format!("started {}", self.started),
+            format!("finished {}", self.finished),
+            format!("file {}", self.file),
+            format!("size {}", self.size),
+            format!("sha256 {}", self.sha256),
+            format!("version {}", self.version),
+        ];
+        for (key, value) in self.options.iter() {
+            fields.push(format!("option {} {}", key, value));
+        }
+        for (name, stats) in self.passes.passes.iter() {
+            fields.push(format!(
+                "pass {} {} {} {} {}",
+                stats.runs,
+                stats.elapsed.as_micros(),
+                stats.functions,
+                stats.instructions,
+                name
+            ));
+        }
+        for (name, count) in self.counts.iter() {
+            fields.push(format!("count {} {}", name, count));
+        }
+        fields
+    }
+
+    /// Take in a line of [`AuditRecord::fields`]
+    pub fn read_field(&mut self, field: &str) -> Result<(), String> {
+        let (key, value) = field.split_once(' ').unwrap_or((field, ""));
+        let number = |s: &str| {
+            s.parse::<u64>()
+                .map_err(|_| format!("Invalid number in audit record: {}", s))
+        };
+        match key {
+            "started" => self.started = number(value)?,
+            "finished" => self.finished = number(value)?,
+            "file" => self.file = value.to_string(),
+            "size" => self.size = number(value)?,
+            "sha256" => self.sha256 = value.to_string(),
+            "version" => self.version = value.to_string(),
+            "option" => {
+                let (key, value) = value.split_once(' ').unwrap_or((value, ""));
+                self.options.insert(key.to_string(), value.to_string());
+            }
+            "pass" => {
+                let mut parts = value.splitn(5, ' ');
+                let mut next = || number(parts.next().unwrap_or_default());
+                let stats = PassStats {
+                    runs: next()?,
+                    elapsed: Duration::from_micros(next()?),
+                    functions: next()?,
+                    instructions: next()?,
+                };
+                let name = parts.next().ok_or("Truncated audit record")?;
+                self.passes.passes.insert(name.to_string(), stats);
+            }
+            "count" => {
+                let (name, count) = value.split_once(' ').ok_or("Truncated audit record")?;
+                self.counts.insert(name.to_string(), number(count)?);
+            }
+            _ => return Err(format!("Unknown audit record field: {}", key)),
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let map = |entries: Vec<String>| format!("{{{}}}", entries.join(", "));
+        let options = self
+            .options
+            .iter()
+            .map(|(key, value)| format!("{}: {}", json_string(key), json_string(value)))
+            .collect();
+        let passes = self
+            .passes
+            .passes
+            .iter()
+            .map(|(name, stats)| {
+                format!(
+                    "{}: {{\"runs\": {}, \"seconds\": {:.6}, \"functions\": {}, \
+                     \"instructions\": {}}}",
+                    json_string(name),
+                    stats.runs,
+                    stats.elapsed.as_secs_f64(),
+                    stats.functions,
+                    stats.instructions
+                )
+            })
+            .collect();
+        let counts = self
+            .counts
+            .iter()
+            .map(|(name, count)| format!("{}: {}", json_string(name), count))
+            .collect();
+        format!(
+            "{{\"started\": \"{}\", \"finished\": \"{}\", \"file\": {}, \"size\": {}, \
+             \"sha256\": \"{}\", \"version\": {}, \"options\": {}, \"passes\": {}, \
+             \"counts\": {}}}",
+            utc(self.started),
+            utc(self.finished),
+            json_string(&self.file),
+            self.size,
+            self.sha256,
+            json_string(&self.version),
+            map(options),
+            map(passes),
+            map(counts)
+        )
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} to {}: {} ({} bytes, sha256 {}), vivisect {}",
+            utc(self.started),
+            utc(self.finished),
+            self.file,
+            self.size,
+            self.sha256,
+            self.version
+        )?;
+        for (key, value) in self.options.iter() {
+            writeln!(f, "  option {} = {}", key, value)?;
+        }
+        for (name, stats) in self.passes.passes.iter() {
+            writeln!(
+                f,
+                "  pass {}: {:?}, {} runs, {} functions, {} instructions",
+                name, stats.elapsed, stats.runs, stats.functions, stats.instructions
+            )?;
+        }
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        writeln!(f, "  found {}", counts.join(", "))
+    }
+}
+
+/// The analysis runs of a workspace, oldest first
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditLog {
+    pub records: Vec<AuditRecord>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        AuditLog::default()
+    }
+
+    pub fn push(&mut self, record: AuditRecord) {
+        self.records.push(record);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        let records: Vec<String> = self
+            .records
+            .iter()
+            .map(|record| format!("  {}", record.to_json()))
+            .collect();
+        format!("[\n{}\n]\n", records.join(",\n"))
+    }
+}
+
+impl fmt::Display for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in self.records.iter() {
+            write!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+/// The seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// A Unix time as an RFC 3339 UTC time, `2024-02-29T12:00:00Z`
+pub fn utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // the civil date of a day count, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Annotations;
+    use std::io::BufReader;
+
+    #[test]
+    fn records_and_saves() {
+        assert_eq!(utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc(1709208000), "2024-02-29T12:00:00Z");
+
+        let ws = VivWorkspace::new("", false);
+        let before = ws.get_analysis_stats();
+        ws.record_analysis(3, 120);
+        let record = AuditRecord::new("sample.exe", b"abc").finish(&ws, &before);
+        assert_eq!(record.passes.pass(AnalysisStats::OTHER).unwrap().functions, 3);
+        assert_eq!(record.counts["functions"], 0);
+        assert!(record.started <= record.finished);
+        assert!(record.to_json().contains("\"file\": \"sample.exe\", \"size\": 3"));
+
+        let mut ann = Annotations::new();
+        ann.audit.push(record);
+        let mut saved = Vec::new();
+        ann.write(&mut saved).unwrap();
+        let loaded = Annotations::read(BufReader::new(saved.as_slice())).unwrap();
+        assert_eq!(loaded, ann);
+    }
+
+    #[test]
+    fn rejects_out_of_order_records() {
+        let mut ann = Annotations::new();
+        for file in ["a.out", "b.out"] {
+            ann.audit.push(AuditRecord {
+                file: file.to_string(),
+                ..Default::default()
+            });
+        }
+        let mut saved = Vec::new();
+        ann.write(&mut saved).unwrap();
+        let text = String::from_utf8(saved).unwrap();
+        let read = |text: &str| Annotations::read(BufReader::new(text.as_bytes()));
+        assert_eq!(read(&text).unwrap(), ann);
+
+        // the second record before the first, and the first again after the second
+        let (first, second): (Vec<&str>, Vec<&str>) = text
+            .lines()
+            .partition(|line| !line.starts_with("audit 0x1 "));
+        let (header, first) = first.split_at(1);
+        let swapped = [header, &second, first].concat().join("\n");
+        assert!(read(&swapped).is_err());
+        let interleaved = [header, first, &second, &first[1..2]].concat().join("\n");
+        assert!(read(&interleaved).is_err());
+    }
+}
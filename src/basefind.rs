@@ -0,0 +1,223 @@
+//! Load address inference for raw firmware images.
+//!
+//! A raw or SREC image of bare metal firmware says nothing about where it runs, but its code is
+//! full of absolute pointers into itself. [`infer_base`] tries the bases those pointers suggest
+//! and ranks them by how much of the image lines up:
+//!
+//! * string references: pointer sized words that land on the start of a string of the image
+//!   once it's loaded at the base. Each pointer votes for the bases putting one of the strings
+//!   at it, at the alignment asked for, and the bases with the most votes are the candidates.
+//! * a vector table: on Cortex-M the image starts with the initial stack pointer and the
+//!   handlers, Thumb addresses with the low bit set, which should all land in the image. The
+//!   bases putting the reset handler in the image are candidates too.
+//! * absolute pointers: the words of the image pointing into it at the base, the weakest signal
+//!   and only there to break ties.
+//!
+//! Load the image at the best candidate, then check the strings its code refers to make sense.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// 4 or 8
+    pub pointer_size: usize,
+    pub big_endian: bool,
+    /// Candidate bases are multiples of this
+    pub alignment: u64,
+    /// The shortest run of printable bytes counted as a string
+    pub min_string: usize,
+    /// How many candidates to return
+    pub candidates: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            pointer_size: 4,
+            big_endian: false,
+            alignment: 0x1000,
+            min_string: 4,
+            candidates: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub base: u64,
+    /// Words pointing at the start of a string
+    pub strings: usize,
+    /// Entries of the vector table at the start of the image landing in it
+    pub vectors: usize,
+    /// Words pointing anywhere in the image
+    pub pointers: usize,
+}
+
+impl Candidate {
+    /// The rank of the candidate, higher is better. A vector table entry is worth a few string
+    /// references, as there are only a handful of them.
+    pub fn score(&self) -> usize {
+        self.strings + 4 * self.vectors
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} string references, {} vectors, {} pointers",
+            self.base, self.strings, self.vectors, self.pointers
+        )
+    }
+}
+
+/// The offsets of the strings of `bytes`: printable runs at least `min_len` long ending in a
+/// NUL, the way C compilers lay them out
+pub fn string_offsets(bytes: &[u8], min_len: usize) -> Vec<u64> {
+    let printable = |b: u8| b.is_ascii_graphic() || matches!(b, b' ' | b'\t' | b'\r' | b'\n');
+    let mut offsets = Vec::new();
+    let mut start = None;
+    for (offset, byte) in bytes.iter().enumerate() {
+        match (start, printable(*byte)) {
+            (None, true) => start = Some(offset),
+            (Some(s), false) => {
+                if *byte == 0 && offset - s >= min_len {
+                    offsets.push(s as u64);
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    offsets
+}
+
+/// The pointer sized words of `bytes`, at pointer alignment
+fn words(bytes: &[u8], options: &Options) -> Vec<u64> {
+    let size = options.pointer_size;
+    bytes
+        .chunks_exact(size)
+        .map(|chunk| {
+            let mut word = [0u8; 8];
+            if options.big_endian {
+                word[8 - size..].copy_from_slice(chunk);
+                u64::from_be_bytes(word)
+            } else {
+                word[..size].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            }
+        })
+        .collect()
+}
+
+/// The Cortex-M vector table entries counted: the handlers from reset to SysTick
+const VECTORS: std::ops::Range<usize> = 1..16;
+
+fn vector_hits(words: &[u64], base: u64, size: u64) -> usize {
+    words
+        .get(VECTORS)
+        .unwrap_or_default()
+        .iter()
+        .filter(|handler| **handler & 1 == 1 && (**handler & !1).wrapping_sub(base) < size)
+        .count()
+}
+
+/// The likely load addresses of the raw image `bytes`, best first
+pub fn infer_base(bytes: &[u8], options: &Options) -> Vec<Candidate> {
+    let alignment = options.alignment.max(1);
+    let size = bytes.len() as u64;
+    let words = words(bytes, options);
+    let strings = string_offsets(bytes, options.min_string);
+
+    // a pointer p can only point at a string s from a base p - s with the alignment
+    let mut by_low: HashMap<u64, Vec<u64>> = HashMap::new();
+    for offset in strings.iter() {
+        by_low.entry(offset % alignment).or_default().push(*offset);
+    }
+    let mut votes: HashMap<u64, usize> = HashMap::new();
+    let pointers: BTreeSet<u64> = words.iter().copied().collect();
+    for pointer in pointers.iter() {
+        for offset in by_low.get(&(pointer % alignment)).into_iter().flatten() {
+            if let Some(base) = pointer.checked_sub(*offset) {
+                *votes.entry(base).or_default() += 1;
+            }
+        }
+    }
+    let mut bases: Vec<(u64, usize)> = votes.into_iter().collect();
+    bases.sort_by_key(|(base, votes)| (Reverse(*votes), *base));
+    let mut bases: Vec<u64> = bases
+        .into_iter()
+        .take(options.candidates * 4)
+        .map(|(base, _)| base)
+        .collect();
+    if let Some(reset) = words.get(VECTORS.start).filter(|reset| **reset & 1 == 1) {
+        let reset = reset & !1;
+        let low = reset.saturating_sub(size.saturating_sub(1));
+        let first = low.div_ceil(alignment) * alignment;
+        bases.extend((first..=reset).step_by(alignment as usize).take(1024));
+    }
+    bases.sort_unstable();
+    bases.dedup();
+
+    // counted once per word, like the string votes
+    let mut candidates: Vec<Candidate> = bases
+        .into_iter()
+        .map(|base| Candidate {
+            base,
+            strings: strings
+                .iter()
+                .filter(|offset| pointers.contains(&(base + **offset)))
+                .count(),
+            vectors: vector_hits(&words, base, size),
+            pointers: words
+                .iter()
+                .filter(|word| word.wrapping_sub(base) < size)
+                .count(),
+        })
+        .filter(|candidate| candidate.score() > 0)
+        .collect();
+    candidates.sort_by_key(|c| (Reverse((c.score(), c.pointers)), c.base));
+    candidates.truncate(options.candidates);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_inference() {
+        assert_eq!(string_offsets(b"\x01abcd\0ab\0xyzw", 4), [1]);
+
+        // a Cortex-M image for 0x0800_0000: the vector table, a table of pointers to strings,
+        // then the strings
+        let base = 0x0800_0000u32;
+        let mut image = vec![0u8; 0x100];
+        image[..4].copy_from_slice(&0x2000_8000u32.to_le_bytes());
+        for i in 1..8 {
+            let handler = base + 0x40 + 2 * i as u32 + 1;
+            image[i * 4..i * 4 + 4].copy_from_slice(&handler.to_le_bytes());
+        }
+        let names = ["reset", "hard fault", "usart rx", "usart tx"];
+        let mut offset = 0x80;
+        for (i, name) in names.iter().enumerate() {
+            let at = 0x60 + i * 4;
+            image[at..at + 4].copy_from_slice(&(base + offset as u32).to_le_bytes());
+            image[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+            offset += name.len() + 1;
+        }
+
+        let candidates = infer_base(&image, &Options::default());
+        let best = candidates[0];
+        assert_eq!(best.base, base as u64);
+        assert_eq!((best.strings, best.vectors), (4, 7));
+        assert_eq!(best.pointers, 11);
+        assert!(candidates[1..]
+            .iter()
+            .all(|candidate| candidate.score() < best.score()));
+    }
+}
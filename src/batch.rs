@@ -0,0 +1,542 @@
+//! Headless bulk triage: every file under a directory, and inside the containers found there,
+//! classified, loaded and analyzed in parallel, one [`Record`] streamed back per file.
+//!
+//! [`run`] walks the tree in name order. Containers are opened rather than analyzed, up to
+//! [`Profile::depth`] levels down, and what's inside goes through the same way:
+//!
+//! * zip files, which APKs, IPAs and JARs are, their stored and (with the `gzip` feature)
+//!   deflated members,
+//! * Unix `ar` archives, their members,
+//! * firmware images, the executables [`crate::carve::executables`] finds in them.
+//!
+//! A member's path is the container's, `!`, and its name in the container, so
+//! `app.apk!lib/arm64-v8a/libfoo.so`. Executables are loaded into a workspace of their own and
+//! the [`Profile::analyzers`] run on it; a file which fails to load, even by panicking, gets a
+//! [`Outcome::Failed`] record and the run goes on.
+
+use crate::{
+    analysis::Analyzer,
+    archive::Archive,
+    carve,
+    envi::Arch,
+    peek_bytes,
+    utils::{hex, sha256},
+    workspace::VivWorkspace,
+    Hint, Object,
+};
+use std::{
+    fmt, fs, io,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+const ZIP_LOCAL: &[u8] = b"PK\x03\x04";
+const ZIP_CENTRAL: &[u8] = b"PK\x01\x02";
+const ZIP_END: &[u8] = b"PK\x05\x06";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    Elf,
+    Pe,
+    MachO,
+    /// A Unix `ar` archive
+    Archive,
+    /// A zip file, APKs and IPAs among them
+    Zip,
+    /// Compressed streams or a filesystem, see [`crate::carve`]
+    Firmware,
+    Other,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Elf => "elf",
+            Kind::Pe => "pe",
+            Kind::MachO => "macho",
+            Kind::Archive => "archive",
+            Kind::Zip => "zip",
+            Kind::Firmware => "firmware",
+            Kind::Other => "other",
+        })
+    }
+}
+
+impl Kind {
+    pub fn is_executable(&self) -> bool {
+        matches!(self, Kind::Elf | Kind::Pe | Kind::MachO)
+    }
+}
+
+/// What a file is, by its first bytes
+pub fn classify(bytes: &[u8]) -> Kind {
+    if bytes.starts_with(ZIP_LOCAL) {
+        return Kind::Zip;
+    }
+    let hint = bytes
+        .get(..16)
+        .and_then(|head| peek_bytes(head.try_into().ok()?).ok());
+    match hint {
+        Some(Hint::Elf(_)) => Kind::Elf,
+        Some(Hint::PE) => Kind::Pe,
+        Some(Hint::Mach(_)) | Some(Hint::MachFat(_)) => Kind::MachO,
+        Some(Hint::Archive) => Kind::Archive,
+        _ if carve::scan(&bytes[..bytes.len().min(0x10000)])
+            .first()
+            .is_some_and(|(offset, _)| *offset == 0) =>
+        {
+            Kind::Firmware
+        }
+        _ => Kind::Other,
+    }
+}
+
+/// How to analyze each file of a batch
+#[derive(Clone)]
+pub struct Profile {
+    /// The passes run on each executable, in order; none just loads them
+    pub analyzers: Vec<Arc<dyn Analyzer>>,
+    /// How many files are analyzed at once
+    pub threads: usize,
+    /// How many levels of containers are opened
+    pub depth: usize,
+    /// Files larger than this are skipped
+    pub max_size: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            analyzers: Vec::new(),
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            depth: 3,
+            max_size: carve::DEFAULT_LIMIT as u64,
+        }
+    }
+}
+
+/// What analysis found in an executable
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub arch: Option<Arch>,
+    pub functions: usize,
+    pub imports: usize,
+    pub exports: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Analyzed(Summary),
+    /// A container, and how many files were taken out of it
+    Opened(usize),
+    /// Not analyzed, and why
+    Skipped(String),
+    Failed(String),
+}
+
+/// The result for one file of a batch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub path: String,
+    pub kind: Kind,
+    pub size: usize,
+    /// SHA-256 of the contents, in hex
+    pub sha256: String,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Record {
+    /// A tab separated line: path, kind, size, hash, then the outcome
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            self.path, self.kind, self.size, self.sha256
+        )?;
+        match &self.outcome {
+            Outcome::Analyzed(summary) => write!(
+                f,
+                "analyzed\t{}\t{} functions\t{} imports\t{} exports",
+                summary.arch.map_or("?", |arch| arch.name()),
+                summary.functions,
+                summary.imports,
+                summary.exports
+            ),
+            Outcome::Opened(count) => write!(f, "opened\t{} files", count),
+            Outcome::Skipped(why) => write!(f, "skipped\t{}", why),
+            Outcome::Failed(why) => write!(f, "failed\t{}", why),
+        }
+    }
+}
+
+/// The files of the zip archive `bytes`, by name. Deflated ones are only opened with the `gzip`
+/// feature, and none get bigger than `limit`.
+pub fn zip_members(bytes: &[u8], limit: usize) -> Option<Vec<(String, Vec<u8>)>> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|at| bytes[*at..].starts_with(ZIP_END))?;
+    let count = u16_at(end + 10)? as usize;
+    let mut at = u32_at(end + 16)? as usize;
+    let mut members = Vec::new();
+    for _ in 0..count {
+        if !bytes.get(at..)?.starts_with(ZIP_CENTRAL) {
+            return None;
+        }
+        let method = u16_at(at + 10)?;
+        let packed = u32_at(at + 20)? as usize;
+        let name_len = u16_at(at + 28)? as usize;
+        let extra_len = u16_at(at + 30)? as usize;
+        let comment_len = u16_at(at + 32)? as usize;
+        let local = u32_at(at + 42)? as usize;
+        let name = String::from_utf8_lossy(bytes.get(at + 46..at + 46 + name_len)?).to_string();
+        at += 46 + name_len + extra_len + comment_len;
+        if name.ends_with('/') || !bytes.get(local..)?.starts_with(ZIP_LOCAL) {
+            continue;
+        }
+        let start = local + 30 + u16_at(local + 26)? as usize + u16_at(local + 28)? as usize;
+        let Some(data) = bytes.get(start..start + packed) else {
+            continue;
+        };
+        let data = match method {
+            0 if data.len() <= limit => data.to_vec(),
+            #[cfg(feature = "gzip")]
+            8 => match carve::read_limited(flate2::read::DeflateDecoder::new(data), limit) {
+                Ok(data) => data,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        members.push((name, data));
+    }
+    Some(members)
+}
+
+/// The members of the `ar` archive `bytes`, by name
+fn archive_members(bytes: &[u8]) -> Option<Vec<(String, Vec<u8>)>> {
+    let archive = Archive::parse(bytes).ok()?;
+    Some(
+        archive
+            .members()
+            .into_iter()
+            .filter_map(|name| {
+                Some((
+                    name.to_string(),
+                    archive.extract(name, bytes).ok()?.to_vec(),
+                ))
+            })
+            .collect(),
+    )
+}
+
+/// The files taken out of a container
+fn open(kind: Kind, bytes: &[u8], limit: usize) -> Option<Vec<(String, Vec<u8>)>> {
+    match kind {
+        Kind::Zip => zip_members(bytes, limit),
+        Kind::Archive => archive_members(bytes),
+        Kind::Firmware => Some(
+            carve::executables(bytes, 1)
+                .into_iter()
+                .map(|carved| (carved.path, carved.bytes))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// A file waiting for analysis: on disk already, or taken out of a container
+struct Job {
+    path: String,
+    file: Option<PathBuf>,
+    bytes: Vec<u8>,
+    kind: Kind,
+}
+
+fn record(path: &str, kind: Kind, bytes: &[u8], outcome: Outcome) -> Record {
+    Record {
+        path: path.to_string(),
+        kind,
+        size: bytes.len(),
+        sha256: hex(&sha256(bytes)),
+        outcome,
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string())
+}
+
+/// Load and analyze one executable, from its own file if it has one and from a scratch file
+/// in `scratch` otherwise
+fn analyze(job: &Job, profile: &Profile, scratch: &Path, seq: usize) -> Outcome {
+    // the loader takes anything it can't parse for Intel hex
+    if let Err(err) = Object::parse(&job.bytes) {
+        return Outcome::Failed(err.to_string());
+    }
+    let (path, temporary) = match &job.file {
+        Some(file) => (file.clone(), false),
+        None => {
+            let name = job.path.rsplit(['/', '!']).next().unwrap_or("member");
+            let dir = scratch.join(seq.to_string());
+            let path = dir.join(name);
+            if let Err(err) = fs::create_dir_all(&dir).and_then(|_| fs::write(&path, &job.bytes)) {
+                return Outcome::Failed(err.to_string());
+            }
+            (path, true)
+        }
+    };
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let mut workspace = VivWorkspace::new("", false);
+        workspace.load_from_file(&path.to_string_lossy(), None, None);
+        for analyzer in profile.analyzers.iter() {
+            workspace.add_analyzer(analyzer.clone());
+        }
+        workspace.run_analyzers();
+        Summary {
+            arch: Arch::from_envi(workspace.arch),
+            functions: workspace.get_functions().len(),
+            imports: workspace.get_imports().len(),
+            exports: workspace.get_exports().len(),
+        }
+    }));
+    if temporary {
+        let _ = fs::remove_dir_all(path.parent().unwrap_or(scratch));
+    }
+    match result {
+        Ok(summary) => Outcome::Analyzed(summary),
+        Err(panic) => Outcome::Failed(panic_message(panic)),
+    }
+}
+
+/// Queue `bytes` for analysis, or open it and queue what's inside, recording containers and
+/// skipped files straight away
+fn dispatch(
+    job: Job,
+    depth: usize,
+    profile: &Profile,
+    jobs: &mpsc::SyncSender<Job>,
+    records: &mpsc::Sender<Record>,
+) {
+    if job.kind.is_executable() {
+        let _ = jobs.send(job);
+        return;
+    }
+    let members = match depth {
+        0 => None,
+        _ => open(job.kind, &job.bytes, profile.max_size as usize),
+    };
+    let Some(members) = members else {
+        let why = match job.kind {
+            Kind::Other => "not an executable",
+            _ => "not opened",
+        };
+        let _ = records.send(record(
+            &job.path,
+            job.kind,
+            &job.bytes,
+            Outcome::Skipped(why.to_string()),
+        ));
+        return;
+    };
+    let _ = records.send(record(
+        &job.path,
+        job.kind,
+        &job.bytes,
+        Outcome::Opened(members.len()),
+    ));
+    for (name, bytes) in members {
+        let member = Job {
+            path: format!("{}!{}", job.path, name),
+            file: None,
+            kind: classify(&bytes),
+            bytes,
+        };
+        dispatch(member, depth - 1, profile, jobs, records);
+    }
+}
+
+/// The files under `root` (or `root` itself), in name order
+fn walk(root: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !root.is_dir() {
+        files.push(root.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(root)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        walk(&entry, files)?;
+    }
+    Ok(())
+}
+
+/// Analyze every file under `root` with `profile`, handing each record to `sink` as soon as it
+/// is ready, in no particular order. Returns how many records there were.
+pub fn run(root: &Path, profile: &Profile, mut sink: impl FnMut(Record)) -> io::Result<usize> {
+    let mut files = Vec::new();
+    walk(root, &mut files)?;
+    let scratch = std::env::temp_dir().join(format!("vivisect-batch-{}", std::process::id()));
+    let threads = profile.threads.max(1);
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(threads * 2);
+    let job_rx = Mutex::new(job_rx);
+    let (record_tx, record_rx) = mpsc::channel::<Record>();
+    let seq = AtomicUsize::new(0);
+    let mut count = 0;
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (job_rx, record_tx, seq, scratch) = (&job_rx, record_tx.clone(), &seq, &scratch);
+            scope.spawn(move || loop {
+                let next = job_rx.lock().map(|rx| rx.recv());
+                let Ok(Ok(job)) = next else {
+                    return;
+                };
+                let seq = seq.fetch_add(1, Ordering::Relaxed);
+                let outcome = analyze(&job, profile, scratch, seq);
+                let _ = record_tx.send(record(&job.path, job.kind, &job.bytes, outcome));
+            });
+        }
+        let walker_records = record_tx.clone();
+        scope.spawn(move || {
+            for file in files {
+                let path = file.to_string_lossy().to_string();
+                let size = fs::metadata(&file).map_or(0, |meta| meta.len());
+                if size > profile.max_size {
+                    let _ = walker_records.send(Record {
+                        path,
+                        kind: Kind::Other,
+                        size: size as usize,
+                        sha256: String::new(),
+                        outcome: Outcome::Skipped("too large".to_string()),
+                    });
+                    continue;
+                }
+                let bytes = match fs::read(&file) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        let outcome = Outcome::Failed(err.to_string());
+                        let _ = walker_records.send(record(&path, Kind::Other, &[], outcome));
+                        continue;
+                    }
+                };
+                let job = Job {
+                    path,
+                    file: Some(file),
+                    kind: classify(&bytes),
+                    bytes,
+                };
+                dispatch(job, profile.depth, profile, &job_tx, &walker_records);
+            }
+        });
+        drop(record_tx);
+        for record in record_rx {
+            count += 1;
+            sink(record);
+        }
+    });
+    let _ = fs::remove_dir_all(&scratch);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zip archive of stored files
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (name, data) in files {
+            let local = out.len() as u32;
+            let header = |sig: &[u8], out: &mut Vec<u8>, central: bool| {
+                out.extend(sig);
+                if central {
+                    out.extend([20, 0]);
+                }
+                out.extend([20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                out.extend((data.len() as u32).to_le_bytes());
+                out.extend((data.len() as u32).to_le_bytes());
+                out.extend((name.len() as u16).to_le_bytes());
+                out.extend([0, 0]);
+                if central {
+                    out.extend([0; 10]);
+                    out.extend(local.to_le_bytes());
+                }
+                out.extend(name.as_bytes());
+            };
+            header(ZIP_LOCAL, &mut out, false);
+            out.extend(*data);
+            header(ZIP_CENTRAL, &mut central, true);
+        }
+        let offset = out.len() as u32;
+        out.extend(&central);
+        out.extend(ZIP_END);
+        out.extend([0, 0, 0, 0]);
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((central.len() as u32).to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn triage_tree() {
+        let elf = b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0truncated";
+        let apk = zip(&[
+            ("lib/x86/libfoo.so", elf),
+            ("AndroidManifest.xml", b"<manifest/>"),
+        ]);
+        assert_eq!(classify(&apk), Kind::Zip);
+        assert_eq!(classify(elf), Kind::Elf);
+        assert_eq!(zip_members(&apk, 1 << 20).unwrap().len(), 2);
+
+        let dir = std::env::temp_dir().join(format!("vivisect-batch-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("apps")).unwrap();
+        fs::write(dir.join("apps/app.apk"), &apk).unwrap();
+        fs::write(dir.join("readme.txt"), b"hello").unwrap();
+        let profile = Profile {
+            threads: 2,
+            ..Profile::default()
+        };
+        let mut records = Vec::new();
+        let count = run(&dir, &profile, |record| records.push(record)).unwrap();
+        records.sort_by(|a, b| a.path.cmp(&b.path));
+        let prefix = dir.to_string_lossy().to_string();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.path.trim_start_matches(&prefix), r.kind, &r.outcome))
+            .collect();
+        assert_eq!(count, 4);
+        assert_eq!(
+            summary[0],
+            ("/apps/app.apk", Kind::Zip, &Outcome::Opened(2))
+        );
+        assert_eq!(
+            summary[1],
+            (
+                "/apps/app.apk!AndroidManifest.xml",
+                Kind::Other,
+                &Outcome::Skipped("not an executable".to_string())
+            )
+        );
+        // truncated, so loading it fails, but the run goes on
+        assert_eq!(summary[2].0, "/apps/app.apk!lib/x86/libfoo.so");
+        assert!(matches!(summary[2].2, Outcome::Failed(_)));
+        assert_eq!(summary[3].1, Kind::Other);
+        assert!(records[3]
+            .to_string()
+            .ends_with("\tskipped\tnot an executable"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
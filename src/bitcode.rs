@@ -0,0 +1,341 @@
+//! Embedded bitcode: the `__LLVM,__bundle` section of a Mach-O built with `-fembed-bitcode`.
+//!
+//! The section is a xar archive. Its table of contents is zlib compressed XML, with a `Ld`
+//! subdocument recording how the binary was linked (the SDK, the dylibs and the linker
+//! options) and a `<file>` per object, each a bitcode module or a Mach-O object with the clang
+//! options it was compiled with. The files' data is on the heap after the table of contents.
+//! A binary built with `-fembed-bitcode-marker` has a one byte section instead, which says
+//! bitcode was asked for without carrying any.
+//!
+//! The table of contents and zlib compressed files need the `gzip` feature.
+
+use crate::{
+    carve::DEFAULT_LIMIT,
+    interop::{xml_tags, xml_unescape},
+    mach::MachO,
+};
+use std::io;
+
+const XAR_MAGIC: &[u8] = b"xar!";
+const XAR_HEADER_SIZE: usize = 28;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Inflate the zlib stream at the start of `bytes`, up to `limit` bytes of output
+fn inflate(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "gzip")]
+    {
+        crate::carve::read_limited(flate2::read::ZlibDecoder::new(bytes), limit).map_err(invalid)
+    }
+    #[cfg(not(feature = "gzip"))]
+    {
+        let _ = (bytes, limit);
+        Err(invalid(
+            "zlib support isn't built in, enable the `gzip` feature".to_string(),
+        ))
+    }
+}
+
+/// A file of a xar archive, as its table of contents has it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XarFile {
+    pub id: u64,
+    pub name: String,
+    /// `Bitcode`, `Object`, `Bundle` or `LTO` for an embedded bitcode member
+    pub file_type: Option<String>,
+    /// Where its data is, from the start of the heap
+    pub offset: u64,
+    /// The size of its data in the archive
+    pub length: u64,
+    /// The size of its data extracted
+    pub size: u64,
+    /// The MIME type its data is encoded as, `application/x-gzip` being zlib
+    pub encoding: Option<String>,
+    /// The compiler options it was built with
+    pub options: Vec<String>,
+}
+
+/// The bitcode archive of a binary
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bitcode<'a> {
+    pub version: Option<String>,
+    pub architecture: Option<String>,
+    pub platform: Option<String>,
+    pub sdk_version: Option<String>,
+    /// The dylibs linked against, paths in the SDK as `{SDKPATH}/usr/lib/libSystem.B.dylib`
+    pub dylibs: Vec<String>,
+    pub link_options: Vec<String>,
+    pub files: Vec<XarFile>,
+    heap: &'a [u8],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EmbeddedBitcode<'a> {
+    /// Built with `-fembed-bitcode-marker`: no bitcode, only the section
+    Marker,
+    Archive(Bitcode<'a>),
+}
+
+impl<'a> Bitcode<'a> {
+    /// Parse the xar archive `bytes`
+    pub fn parse(bytes: &'a [u8]) -> io::Result<Self> {
+        if !bytes.starts_with(XAR_MAGIC) || bytes.len() < XAR_HEADER_SIZE {
+            return Err(invalid("Not a xar archive".to_string()));
+        }
+        let header_size = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let toc_len = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) as usize;
+        let toc_size = u64::from_be_bytes(bytes[16..24].try_into().unwrap()) as usize;
+        let toc_end = header_size.saturating_add(toc_len);
+        let toc = bytes
+            .get(header_size..toc_end)
+            .ok_or_else(|| invalid("xar table of contents past the end".to_string()))?;
+        let toc = inflate(toc, toc_size.min(DEFAULT_LIMIT))?;
+        let toc = std::str::from_utf8(&toc).map_err(|err| invalid(err.to_string()))?;
+        let mut bitcode = Bitcode::from_toc(toc)?;
+        bitcode.heap = &bytes[toc_end..];
+        Ok(bitcode)
+    }
+
+    /// The archive described by the XML table of contents `toc`, without its data
+    pub fn from_toc(toc: &str) -> io::Result<Self> {
+        let tags = xml_tags(toc)?;
+        let mut bitcode = Bitcode::default();
+        // the elements open around each tag, innermost last
+        let mut open: Vec<&str> = Vec::new();
+        for (i, tag) in tags.iter().enumerate() {
+            if let Some(name) = tag.name.strip_prefix('/') {
+                if let Some(at) = open.iter().rposition(|open| *open == name) {
+                    open.truncate(at);
+                }
+                continue;
+            }
+            if tag.name == "file" {
+                bitcode.files.push(XarFile {
+                    id: tag.attr("id").and_then(|id| id.parse().ok()).unwrap_or(0),
+                    ..XarFile::default()
+                });
+            }
+            if tag.name == "encoding" && open.last() == Some(&"data") {
+                if let Some(file) = bitcode.files.last_mut() {
+                    file.encoding = tag.attr("style").map(str::to_string);
+                }
+            }
+            if tag.empty {
+                continue;
+            }
+            open.push(tag.name);
+            // only leaves have text worth keeping
+            let leaf = tags
+                .get(i + 1)
+                .is_some_and(|next| next.name.strip_prefix('/') == Some(tag.name));
+            if !leaf {
+                continue;
+            }
+            let text = xml_unescape(tag.text).trim().to_string();
+            let parent = open.len().checked_sub(2).map(|at| open[at]);
+            let number = || text.parse::<u64>().unwrap_or(0);
+            // the file being read, not the table of contents' own checksum
+            let file = bitcode.files.last_mut().filter(|_| open.contains(&"file"));
+            match (parent, tag.name, file) {
+                (Some("subdoc"), "version", _) => bitcode.version = Some(text),
+                (Some("subdoc"), "architecture", _) => bitcode.architecture = Some(text),
+                (Some("subdoc"), "platform", _) => bitcode.platform = Some(text),
+                (Some("subdoc"), "sdkversion", _) => bitcode.sdk_version = Some(text),
+                (Some("dylibs"), "lib", _) => bitcode.dylibs.push(text),
+                (Some("link-options"), "option", _) => bitcode.link_options.push(text),
+                (Some("file"), "name", Some(file)) => file.name = text,
+                (Some("file"), "file-type", Some(file)) => file.file_type = Some(text),
+                (Some("data"), "offset", Some(file)) => file.offset = number(),
+                (Some("data"), "length", Some(file)) => file.length = number(),
+                (Some("data"), "size", Some(file)) => file.size = number(),
+                (Some("clang" | "swift"), "cmd", Some(file)) => file.options.push(text),
+                _ => {}
+            }
+        }
+        Ok(bitcode)
+    }
+
+    /// The bitcode modules
+    pub fn modules(&self) -> impl Iterator<Item = &XarFile> {
+        self.files
+            .iter()
+            .filter(|file| file.file_type.as_deref() == Some("Bitcode"))
+    }
+
+    /// The data of `file`, decompressed
+    pub fn extract(&self, file: &XarFile) -> io::Result<Vec<u8>> {
+        let start = file.offset as usize;
+        let data = self
+            .heap
+            .get(start..start.saturating_add(file.length as usize))
+            .ok_or_else(|| invalid(format!("xar file {} past the end", file.name)))?;
+        match file.encoding.as_deref() {
+            None | Some("application/octet-stream") => Ok(data.to_vec()),
+            Some("application/x-gzip") => inflate(data, (file.size as usize).min(DEFAULT_LIMIT)),
+            Some(encoding) => Err(invalid(format!(
+                "xar file {} is {}, which isn't supported",
+                file.name, encoding
+            ))),
+        }
+    }
+}
+
+/// The bitcode embedded in `macho`, None if it has no `__LLVM,__bundle` section
+pub fn embedded_bitcode<'a>(macho: &MachO<'a>) -> Option<io::Result<EmbeddedBitcode<'a>>> {
+    let section = macho.section_data("__LLVM", "__bundle")?;
+    if section.len() <= 1 {
+        return Some(Ok(EmbeddedBitcode::Marker));
+    }
+    Some(Bitcode::parse(section).map(EmbeddedBitcode::Archive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plist::Plist;
+
+    const TOC: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar>
+ <subdoc subdoc_name="Ld">
+  <version>1.0</version>
+  <architecture>arm64</architecture>
+  <platform>iOS</platform>
+  <sdkversion>14.0</sdkversion>
+  <dylibs>
+   <lib>{SDKPATH}/usr/lib/libSystem.B.dylib</lib>
+  </dylibs>
+  <link-options>
+   <option>-execute</option>
+   <option>-dead_strip</option>
+  </link-options>
+ </subdoc>
+ <toc>
+  <checksum style="sha1"><offset>0</offset><size>20</size></checksum>
+  <file id="1">
+   <name>1</name>
+   <type>file</type>
+   <data>
+    <length>4</length>
+    <encoding style="application/octet-stream"/>
+    <offset>20</offset>
+    <size>4</size>
+   </data>
+   <file-type>Bitcode</file-type>
+   <clang>
+    <cmd>-triple</cmd>
+    <cmd>arm64-apple-ios14.0.0</cmd>
+   </clang>
+  </file>
+ </toc>
+</xar>"#;
+
+    /// A 64 bit Mach-O with only the given sections, each in a segment of its own
+    fn macho(sections: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        let name = |name: &str| {
+            let mut bytes = [0u8; 16];
+            bytes[..name.len()].copy_from_slice(name.as_bytes());
+            bytes
+        };
+        let cmds = sections.len() * 152;
+        let mut data_at = 32 + cmds;
+        let mut bytes = Vec::new();
+        for word in [
+            0xfeed_facfu32,
+            0x0100_000c,
+            0,
+            2,
+            sections.len() as u32,
+            cmds as u32,
+            0,
+            0,
+        ] {
+            bytes.extend(word.to_le_bytes());
+        }
+        for (segname, sectname, data) in sections {
+            let len = data.len() as u64;
+            bytes.extend(0x19u32.to_le_bytes());
+            bytes.extend(152u32.to_le_bytes());
+            bytes.extend(name(segname));
+            for value in [0u64, len, data_at as u64, len] {
+                bytes.extend(value.to_le_bytes());
+            }
+            for value in [5u32, 5, 1, 0] {
+                bytes.extend(value.to_le_bytes());
+            }
+            bytes.extend(name(sectname));
+            bytes.extend(name(segname));
+            bytes.extend(0u64.to_le_bytes());
+            bytes.extend(len.to_le_bytes());
+            for value in [data_at as u32, 0, 0, 0, 0, 0, 0, 0] {
+                bytes.extend(value.to_le_bytes());
+            }
+            data_at += data.len();
+        }
+        for (_, _, data) in sections {
+            bytes.extend(*data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn bitcode_and_info_plist() {
+        let bitcode = Bitcode::from_toc(TOC).unwrap();
+        assert_eq!(bitcode.platform.as_deref(), Some("iOS"));
+        assert_eq!(bitcode.sdk_version.as_deref(), Some("14.0"));
+        assert_eq!(bitcode.dylibs, ["{SDKPATH}/usr/lib/libSystem.B.dylib"]);
+        assert_eq!(bitcode.link_options, ["-execute", "-dead_strip"]);
+        let modules: Vec<&XarFile> = bitcode.modules().collect();
+        assert_eq!(modules.len(), 1);
+        assert_eq!((modules[0].offset, modules[0].length), (20, 4));
+        assert_eq!(modules[0].options, ["-triple", "arm64-apple-ios14.0.0"]);
+        assert_eq!(
+            modules[0].encoding.as_deref(),
+            Some("application/octet-stream")
+        );
+
+        let plist = b"<plist><dict><key>CFBundleIdentifier</key><string>com.example.tool</string></dict></plist>\0\0";
+        let bytes = macho(&[
+            ("__TEXT", "__info_plist", plist),
+            ("__LLVM", "__bundle", &[0]),
+        ]);
+        let binary = MachO::parse(&bytes, 0).unwrap();
+        let info = Plist::from_macho(&binary).unwrap().unwrap();
+        assert_eq!(
+            info.get("CFBundleIdentifier").and_then(Plist::as_str),
+            Some("com.example.tool")
+        );
+        assert!(matches!(
+            embedded_bitcode(&binary),
+            Some(Ok(EmbeddedBitcode::Marker))
+        ));
+
+        #[cfg(feature = "gzip")]
+        {
+            use std::io::Write;
+
+            let mut zlib =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            zlib.write_all(TOC.as_bytes()).unwrap();
+            let toc = zlib.finish().unwrap();
+            let mut xar = XAR_MAGIC.to_vec();
+            xar.extend(28u16.to_be_bytes());
+            xar.extend(1u16.to_be_bytes());
+            xar.extend((toc.len() as u64).to_be_bytes());
+            xar.extend((TOC.len() as u64).to_be_bytes());
+            xar.extend(1u32.to_be_bytes());
+            xar.extend(toc);
+            xar.extend([0; 20]);
+            xar.extend(b"BC\xc0\xde");
+            let bytes = macho(&[("__LLVM", "__bundle", &xar)]);
+            let binary = MachO::parse(&bytes, 0).unwrap();
+            let Some(Ok(EmbeddedBitcode::Archive(bitcode))) = embedded_bitcode(&binary) else {
+                panic!("no bitcode archive");
+            };
+            assert_eq!(bitcode.architecture.as_deref(), Some("arm64"));
+            let module = bitcode.modules().next().unwrap();
+            assert_eq!(bitcode.extract(module).unwrap(), b"BC\xc0\xde");
+        }
+    }
+}
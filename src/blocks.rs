@@ -0,0 +1,371 @@
+//! Objective-C block literals and Swift closures.
+//!
+//! A block literal is an `isa` pointer to `_NSConcreteGlobalBlock` or `_NSConcreteStackBlock`,
+//! 32 bits of flags, 32 reserved, and pointers to the invoke function and the descriptor. The
+//! invoke function is only ever called through the literal, so the code reaching it is missed
+//! without them. Global blocks are found in the data, where the `isa` is bound to the runtime's
+//! class or points at it; stack blocks in the functions with IL, which store the class and the
+//! invoke function at the offsets of a literal.
+//!
+//! A Swift closure is a pair of a function and a context the function captured its variables
+//! in, which `swift_allocObject` allocates. Passed on, the two go in two arguments one after
+//! the other, the function first.
+//!
+//! [`add_closures`] adds the invoke functions as entry points, named if they have no name, with
+//! a pointer xref from where the closure is made.
+
+use crate::{
+    constants::{MM_EXEC, MM_READ, REF_CODE, REF_PTR},
+    envi::{registers::RegisterModel, Arch},
+    memory::Memory,
+    symbolic::{call_argument, return_value, BinOp, Expr, State, Stmt},
+    taint::callee_saved,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The flag of a block literal made at compile time
+pub const BLOCK_IS_GLOBAL: u32 = 1 << 28;
+/// The arguments looked through for a Swift closure
+const MAX_ARGS: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClosureKind {
+    GlobalBlock,
+    StackBlock,
+    Swift,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Closure {
+    pub kind: ClosureKind,
+    /// Where it is made: the literal of a global block, the instruction storing the `isa` of a
+    /// stack block, or the call a Swift closure is passed to
+    pub site: u64,
+    pub invoke: u64,
+    /// The block descriptor
+    pub descriptor: Option<u64>,
+}
+
+impl Closure {
+    /// The name its invoke function gets, if it has none
+    pub fn invoke_name(&self) -> String {
+        match self.kind {
+            ClosureKind::Swift => format!("closure_{:x}", self.invoke),
+            _ => format!("block_invoke_{:x}", self.invoke),
+        }
+    }
+}
+
+/// The symbol an import or a name is for, without the library and the leading underscores
+fn symbol(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name).trim_start_matches('_')
+}
+
+/// The addresses named `symbol`, imports included
+fn named(workspace: &VivWorkspace, name: &str) -> BTreeSet<u64> {
+    workspace
+        .get_names()
+        .into_iter()
+        .filter(|(_, found)| symbol(found) == name)
+        .map(|(va, _)| va as u32 as u64)
+        .collect()
+}
+
+/// The names of the functions the call at `va` calls, through an import trampoline or not
+fn callees(workspace: &VivWorkspace, va: u64) -> Vec<String> {
+    let stubs: BTreeMap<i32, i32> = workspace.get_import_stubs().into_iter().collect();
+    workspace
+        .get_xrefs_from(va as i32, Some(REF_CODE))
+        .into_iter()
+        .filter_map(|(_, to, _, _)| {
+            let to = stubs.get(&to).copied().unwrap_or(to);
+            workspace.get_name(to, false)
+        })
+        .collect()
+}
+
+struct Finder<'a> {
+    workspace: &'a VivWorkspace,
+    ptr: u64,
+    code: Vec<(u64, u64)>,
+}
+
+impl Finder<'_> {
+    fn word(&self, va: u64, size: u64) -> Option<u64> {
+        let bytes = self.workspace.read_memory(va as i32, size as i32)?;
+        let mut word = [0u8; 8];
+        word[..size as usize].copy_from_slice(bytes.get(..size as usize)?);
+        Some(u64::from_le_bytes(word))
+    }
+
+    fn is_code(&self, va: u64) -> bool {
+        self.code
+            .iter()
+            .any(|(start, size)| *start <= va && va < start + size)
+    }
+
+    /// The global block literals in data which isn't code
+    fn global_blocks(&self, found: &mut Vec<Closure>) {
+        let classes = named(self.workspace, "NSConcreteGlobalBlock");
+        if classes.is_empty() {
+            return;
+        }
+        for (start, perms, bytes) in self.workspace.get_maps() {
+            if perms & MM_READ == 0 || perms & MM_EXEC != 0 {
+                continue;
+            }
+            let start = start as u32 as u64;
+            for offset in (0..bytes.len() as u64).step_by(self.ptr as usize) {
+                let va = start + offset;
+                // bound to the class, or pointing at it
+                let bound = classes.contains(&va);
+                if !bound && !self.word(va, self.ptr).is_some_and(|isa| classes.contains(&isa)) {
+                    continue;
+                }
+                let Some(flags) = self.word(va + self.ptr, 4) else {
+                    continue;
+                };
+                let invoke = self.word(va + self.ptr + 8, self.ptr);
+                if let Some(invoke) = invoke.filter(|invoke| self.is_code(*invoke)) {
+                    if flags as u32 & BLOCK_IS_GLOBAL != 0 {
+                        found.push(Closure {
+                            kind: ClosureKind::GlobalBlock,
+                            site: va,
+                            invoke,
+                            descriptor: self.word(va + 2 * self.ptr + 8, self.ptr),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// The stack blocks made and the Swift closures passed on in the functions with IL
+    fn in_code(&self, found: &mut Vec<Closure>) {
+        let stack = named(self.workspace, "NSConcreteStackBlock");
+        for fva in self.workspace.get_functions_with_il() {
+            let func = self.workspace.get_function_il(fva).unwrap();
+            let saved = callee_saved(&RegisterModel::new(func.arch));
+            let ret = return_value(func.arch);
+            for va in func.reverse_postorder() {
+                let mut state = State::new(func.arch);
+                // the stores made in the run, with the instruction making them
+                let mut stores: BTreeMap<Expr, (u64, Expr)> = BTreeMap::new();
+                // the registers holding a context swift_allocObject returned
+                let mut contexts: BTreeSet<Expr> = BTreeSet::new();
+                for insn in func.blocks[&va].insns.iter() {
+                    for stmt in insn.stmts.iter() {
+                        match stmt {
+                            Stmt::Store(addr, value) => {
+                                let (addr, value) = (state.eval(addr), state.eval(value));
+                                stores.insert(addr.clone(), (insn.va, value));
+                                self.stack_block(&stack, &stores, &addr, state.width(), found);
+                            }
+                            Stmt::Unknown => {
+                                let callees = callees(self.workspace, insn.va);
+                                let alloc = callees
+                                    .iter()
+                                    .any(|name| symbol(name) == "swift_allocObject");
+                                if !alloc {
+                                    self.swift(func.arch, insn.va, &state, &contexts, found);
+                                }
+                                contexts = saved
+                                    .iter()
+                                    .filter(|reg| contexts.contains(&state.reg(**reg)))
+                                    .map(|reg| Expr::Reg(*reg))
+                                    .collect();
+                                contexts.extend(ret.clone().filter(|_| alloc));
+                                stores.clear();
+                            }
+                            _ => {}
+                        }
+                        state.exec(stmt);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A stack block whose `isa` or invoke function was just stored at `addr`
+    fn stack_block(
+        &self,
+        classes: &BTreeSet<u64>,
+        stores: &BTreeMap<Expr, (u64, Expr)>,
+        addr: &Expr,
+        width: u32,
+        found: &mut Vec<Closure>,
+    ) {
+        let at = |base: &Expr, offset: u64| {
+            let addr = Expr::binary(BinOp::Add, base.clone(), Expr::Const(offset), width);
+            stores.get(&addr)
+        };
+        let invoke_at = self.ptr + 8;
+        let base = Expr::binary(BinOp::Sub, addr.clone(), Expr::Const(invoke_at), width);
+        for base in [addr, &base] {
+            let (Some((site, isa)), Some((_, invoke))) = (at(base, 0), at(base, invoke_at)) else {
+                continue;
+            };
+            // stored as the address, or loaded from the slot bound to it
+            let class = match isa {
+                Expr::Const(isa) => classes.contains(isa),
+                Expr::Load(slot) => slot.as_const().is_some_and(|slot| classes.contains(&slot)),
+                _ => false,
+            };
+            let Some(invoke) = invoke.as_const().filter(|invoke| self.is_code(*invoke)) else {
+                continue;
+            };
+            if class && !found.iter().any(|closure| closure.site == *site) {
+                let descriptor = at(base, invoke_at + self.ptr).and_then(|(_, d)| d.as_const());
+                found.push(Closure {
+                    kind: ClosureKind::StackBlock,
+                    site: *site,
+                    invoke,
+                    descriptor,
+                });
+            }
+        }
+    }
+
+    /// A Swift closure passed to the call at `call`, with a context of `contexts`
+    fn swift(
+        &self,
+        arch: Arch,
+        call: u64,
+        state: &State,
+        contexts: &BTreeSet<Expr>,
+        found: &mut Vec<Closure>,
+    ) {
+        let args: Vec<Expr> = (0..MAX_ARGS)
+            .map_while(|index| call_argument(arch, false, index))
+            .map(|arg| state.eval(&arg))
+            .collect();
+        for pair in args.windows(2) {
+            let Some(invoke) = pair[0].as_const().filter(|invoke| self.is_code(*invoke)) else {
+                continue;
+            };
+            if contexts.contains(&pair[1]) {
+                found.push(Closure {
+                    kind: ClosureKind::Swift,
+                    site: call,
+                    invoke,
+                    descriptor: None,
+                });
+            }
+        }
+    }
+}
+
+/// The block literals and Swift closures of `workspace`, for code of `arch`
+pub fn find_closures(workspace: &VivWorkspace, arch: Arch) -> Vec<Closure> {
+    let finder = Finder {
+        workspace,
+        ptr: arch.pointer_size() as u64,
+        code: workspace
+            .get_executable_maps()
+            .into_iter()
+            .map(|(va, bytes)| (va as u32 as u64, bytes.len() as u64))
+            .collect(),
+    };
+    let mut found = Vec::new();
+    finder.global_blocks(&mut found);
+    finder.in_code(&mut found);
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Add the invoke functions of the closures of `workspace` as entry points, named if they have
+/// no name, with a pointer xref from where each closure is made
+pub fn add_closures(workspace: &mut VivWorkspace) -> Vec<Closure> {
+    let Some(arch) = Arch::from_envi(workspace.get_mem_architecture()) else {
+        return Vec::new();
+    };
+    let found = find_closures(workspace, arch);
+    for closure in found.iter() {
+        let invoke = closure.invoke as i32;
+        workspace.add_xref(closure.site as i32, invoke, REF_PTR, 0);
+        if workspace.get_name(invoke, false).is_none() {
+            workspace.make_name(invoke, closure.invoke_name(), true, true);
+        }
+        workspace.add_entry_point(invoke);
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_AMD64, BR_PROC},
+        symbolic::{Block, Function, Insn, Terminator},
+    };
+
+    #[test]
+    fn finds_closures() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let (rax, rbx, rdi, rsi) = (reg("rax"), reg("rbx"), reg("rdi"), reg("rsi"));
+        let sp = Expr::Reg(model.sp());
+        let local = |offset| Expr::binary(BinOp::Add, sp.clone(), Expr::Const(offset), 64);
+        let insn = |va, stmt| Insn {
+            va,
+            stmts: vec![stmt],
+        };
+
+        // a global block at 0x3010, its isa bound to the class
+        let mut data = vec![0u8; 0x100];
+        data[0x18..0x1c].copy_from_slice(&(BLOCK_IS_GLOBAL | 1 << 29).to_le_bytes());
+        data[0x20..0x28].copy_from_slice(&0x1100u64.to_le_bytes());
+        data[0x28..0x30].copy_from_slice(&0x3080u64.to_le_bytes());
+
+        // mov rax, [_NSConcreteStackBlock@GOT]; mov [rsp+0x10], rax;
+        // mov qword [rsp+0x20], invoke; call swift_allocObject; mov rbx, rax; call bar;
+        // mov rdi, closure; mov rsi, rbx; call baz
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, Stmt::Set(rax, Expr::Load(Box::new(Expr::Const(0x3000))))),
+                insn(0x1007, Stmt::Store(local(0x10), Expr::Reg(rax))),
+                insn(0x100c, Stmt::Store(local(0x20), Expr::Const(0x1200))),
+                insn(0x1015, Stmt::Unknown),
+                insn(0x101a, Stmt::Set(rbx, Expr::Reg(rax))),
+                insn(0x101d, Stmt::Unknown),
+                insn(0x1022, Stmt::Set(rdi, Expr::Const(0x1300))),
+                insn(0x1029, Stmt::Set(rsi, Expr::Reg(rbx))),
+                insn(0x102c, Stmt::Unknown),
+            ],
+            end_va: 0x1031,
+            end: Terminator::Return,
+        });
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_AMD64 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a", vec![0xc3; 0x400], None);
+        ws.add_memory_map(0x3000, MM_READ, "a", data, None);
+        ws.make_import(0x3000, "libSystem", "_NSConcreteStackBlock");
+        ws.make_import(0x3010, "libSystem", "_NSConcreteGlobalBlock");
+        ws.make_import(0x3040, "libswiftCore", "swift_allocObject");
+        ws.add_xref(0x1015, 0x3040, REF_CODE, BR_PROC);
+        ws.set_function_il(0x1000, func);
+
+        let found = add_closures(&mut ws);
+        let closure = |kind, site, invoke, descriptor| Closure {
+            kind,
+            site,
+            invoke,
+            descriptor,
+        };
+        assert_eq!(
+            found,
+            [
+                closure(ClosureKind::GlobalBlock, 0x3010, 0x1100, Some(0x3080)),
+                closure(ClosureKind::StackBlock, 0x1007, 0x1200, None),
+                closure(ClosureKind::Swift, 0x102c, 0x1300, None),
+            ]
+        );
+        assert_eq!(ws.get_name(0x1200, false).unwrap(), "block_invoke_1200");
+        assert_eq!(ws.get_xrefs_to(0x1300, Some(REF_PTR))[0].0, 0x102c);
+    }
+}
@@ -0,0 +1,454 @@
+//! iOS apps, from an `.ipa` package or an `.app` bundle directory.
+//!
+//! An app bundle holds its main executable, named by the `CFBundleExecutable` of its
+//! `Info.plist`, the frameworks and dylibs it embeds under `Frameworks/`, and its app
+//! extensions under `PlugIns/*.appex`, each a bundle of its own. An `.ipa` is a zip file with
+//! the bundle under `Payload/`. [`Bundle`] finds all the Mach-O images, with the bundle IDs of
+//! the bundles they are the executables of and the entitlements they are signed with, and
+//! [`load`] puts them all in one workspace, each slid clear of the others, and returns which
+//! image loads which.
+
+use crate::{
+    batch::zip_members,
+    carve::DEFAULT_LIMIT,
+    mach::{load_command::CommandVariant, Mach, MachO},
+    memory::Memory,
+    parser::parse_macho,
+    plist::Plist,
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the images of a bundle after the first one go, at least
+const IMAGE_ALIGN: u32 = 0x100_0000;
+/// The code signature blob of the entitlements, an XML property list
+const CSMAGIC_EMBEDDED_ENTITLEMENTS: u32 = 0xfade_7171;
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Main,
+    Framework,
+    /// An app extension, a `.appex` bundle
+    Extension,
+    /// A dylib not in a framework
+    Library,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Main => "main",
+            Role::Framework => "framework",
+            Role::Extension => "extension",
+            Role::Library => "library",
+        })
+    }
+}
+
+/// A Mach-O image of a bundle
+#[derive(Clone, Debug)]
+pub struct Image {
+    /// The path in the app bundle, `Frameworks/Foo.framework/Foo`
+    pub path: String,
+    pub role: Role,
+    /// The bundle ID of the bundle the image is the executable of
+    pub bundle_id: Option<String>,
+    /// The install name of a dylib, `@rpath/Foo.framework/Foo`
+    pub install_name: Option<String>,
+    /// The dylibs it loads, by install name
+    pub libs: Vec<String>,
+    pub entitlements: Option<Plist>,
+    /// The image, the first one of a fat binary
+    pub bytes: Vec<u8>,
+}
+
+impl Image {
+    /// The application identifier the image is signed for, `TEAMID.com.example.App`
+    pub fn application_identifier(&self) -> Option<&str> {
+        let entitlements = self.entitlements.as_ref()?;
+        entitlements
+            .get("application-identifier")
+            .or_else(|| entitlements.get("com.apple.application-identifier"))
+            .and_then(Plist::as_str)
+    }
+
+    /// Whether the image is signed for the bundle ID of its bundle; None unless it has both
+    pub fn entitlements_match(&self) -> Option<bool> {
+        let bundle_id = self.bundle_id.as_deref()?;
+        let (_team, app) = self.application_identifier()?.split_once('.')?;
+        Some(app == bundle_id || app == "*")
+    }
+}
+
+/// An edge of the dependency graph of a bundle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    /// The loading image, by index
+    pub from: usize,
+    /// The install name loaded
+    pub lib: String,
+    /// The image of the bundle it is, if any; None for a system library
+    pub to: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bundle {
+    pub bundle_id: Option<String>,
+    pub info: Plist,
+    /// The main executable, then the frameworks, libraries and extensions
+    pub images: Vec<Image>,
+}
+
+/// The first image of a fat binary, or `bytes` itself
+fn thin(bytes: &[u8]) -> Option<&[u8]> {
+    match Mach::parse(bytes).ok()? {
+        Mach::Binary(_) => Some(bytes),
+        Mach::Fat(fat) => Some(fat.arches().ok()?.first()?.slice(bytes)),
+    }
+}
+
+/// The entitlements in the code signature of `macho`
+pub(crate) fn entitlements(macho: &MachO, bytes: &[u8]) -> Option<Plist> {
+    Plist::parse(signature_blob(macho, bytes, CSMAGIC_EMBEDDED_ENTITLEMENTS)?.get(8..)?).ok()
+}
+
+/// The blob of the code signature of `macho` starting with `magic`
+pub(crate) fn signature_blob<'a>(macho: &MachO, bytes: &'a [u8], magic: u32) -> Option<&'a [u8]> {
+    let sig = macho
+        .load_commands
+        .iter()
+        .find_map(|lc| match &lc.command {
+            CommandVariant::CodeSignature(sig) => Some(sig),
+            _ => None,
+        })?;
+    let start = sig.dataoff as usize;
+    let blob = bytes.get(start..start + sig.datasize as usize)?;
+    let u32_at = |bytes: &[u8], at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+    };
+    if u32_at(blob, 0)? != CSMAGIC_EMBEDDED_SIGNATURE {
+        return None;
+    }
+    (0..u32_at(blob, 8)? as usize).find_map(|i| {
+        let offset = u32_at(blob, 16 + i * 8)? as usize;
+        let entry = blob.get(offset..)?;
+        if u32_at(entry, 0)? != magic {
+            return None;
+        }
+        entry.get(..u32_at(entry, 4)? as usize)
+    })
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Bundle {
+    /// The app bundle of an `.ipa` package
+    pub fn from_ipa(bytes: &[u8]) -> io::Result<Bundle> {
+        let members = zip_members(bytes, DEFAULT_LIMIT)
+            .ok_or_else(|| invalid("Not a zip file".to_string()))?;
+        let mut app = None;
+        let mut files = BTreeMap::new();
+        for (name, data) in members {
+            let Some(rest) = name.strip_prefix("Payload/") else {
+                continue;
+            };
+            let Some((dir, path)) = rest.split_once(".app/") else {
+                continue;
+            };
+            if *app.get_or_insert_with(|| dir.to_string()) == dir {
+                files.insert(path.to_string(), data);
+            }
+        }
+        Bundle::from_files(files)
+    }
+
+    /// An `.app` bundle directory
+    pub fn from_dir(path: &Path) -> io::Result<Bundle> {
+        fn walk(root: &Path, dir: &Path, files: &mut BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    walk(root, &path, files)?;
+                } else if let Ok(rel) = path.strip_prefix(root) {
+                    let rel = rel.to_string_lossy().replace('\\', "/");
+                    files.insert(rel, fs::read(&path)?);
+                }
+            }
+            Ok(())
+        }
+        let mut files = BTreeMap::new();
+        walk(path, path, &mut files)?;
+        Bundle::from_files(files)
+    }
+
+    /// A bundle from its files, by path in the bundle
+    pub fn from_files(files: BTreeMap<String, Vec<u8>>) -> io::Result<Bundle> {
+        let plist = |dir: &str| -> Option<Plist> {
+            Plist::parse(files.get(&format!("{}Info.plist", dir))?).ok()
+        };
+        let info = plist("").ok_or_else(|| invalid("No Info.plist".to_string()))?;
+        let string =
+            |info: &Plist, key: &str| info.get(key).and_then(Plist::as_str).map(str::to_string);
+        let mut images = Vec::new();
+        let mut add = |path: String, role: Role, bundle_id: Option<String>| {
+            let Some(bytes) = files.get(&path).and_then(|bytes| thin(bytes)) else {
+                return;
+            };
+            let Ok(macho) = MachO::parse(bytes, 0) else {
+                return;
+            };
+            images.push(Image {
+                role,
+                bundle_id,
+                install_name: macho.name.map(str::to_string),
+                libs: macho
+                    .libs
+                    .iter()
+                    .skip(1)
+                    .map(|lib| lib.to_string())
+                    .collect(),
+                entitlements: entitlements(&macho, bytes),
+                bytes: bytes.to_vec(),
+                path,
+            });
+        };
+        let executable = string(&info, "CFBundleExecutable")
+            .ok_or_else(|| invalid("No CFBundleExecutable".to_string()))?;
+        add(executable, Role::Main, string(&info, "CFBundleIdentifier"));
+        // the bundles within, by the directory holding their Info.plist
+        let bundles: Vec<&str> = files
+            .keys()
+            .filter_map(|path| path.strip_suffix("Info.plist"))
+            .filter(|dir| dir.matches('/').count() == 2)
+            .collect();
+        for dir in bundles.iter() {
+            let role = if dir.starts_with("Frameworks/") && dir.ends_with(".framework/") {
+                Role::Framework
+            } else if dir.starts_with("PlugIns/") && dir.ends_with(".appex/") {
+                Role::Extension
+            } else {
+                continue;
+            };
+            let info = plist(dir);
+            let name = info
+                .as_ref()
+                .and_then(|info| string(info, "CFBundleExecutable"))
+                .unwrap_or_else(|| {
+                    let bundle = dir
+                        .trim_end_matches('/')
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default();
+                    bundle.split('.').next().unwrap_or_default().to_string()
+                });
+            let bundle_id = info
+                .as_ref()
+                .and_then(|info| string(info, "CFBundleIdentifier"));
+            add(format!("{}{}", dir, name), role, bundle_id);
+        }
+        for path in files.keys() {
+            if path.starts_with("Frameworks/")
+                && path.matches('/').count() == 1
+                && path.ends_with(".dylib")
+            {
+                add(path.clone(), Role::Library, None);
+            }
+        }
+        if images.first().is_none_or(|image| image.role != Role::Main) {
+            return Err(invalid(
+                "The main executable isn't a Mach-O image".to_string(),
+            ));
+        }
+        Ok(Bundle {
+            bundle_id: string(&info, "CFBundleIdentifier"),
+            info,
+            images,
+        })
+    }
+
+    pub fn main(&self) -> &Image {
+        &self.images[0]
+    }
+
+    /// The image `lib`, an install name, is, if in the bundle: an image with that install name,
+    /// or one at the path after `@rpath/`, `@executable_path/` or `@loader_path/` under
+    /// `Frameworks/` or the bundle itself
+    pub fn resolve(&self, lib: &str) -> Option<usize> {
+        if let Some(at) = self
+            .images
+            .iter()
+            .position(|image| image.install_name.as_deref() == Some(lib))
+        {
+            return Some(at);
+        }
+        let rel = ["@rpath/", "@executable_path/", "@loader_path/"]
+            .iter()
+            .find_map(|prefix| lib.strip_prefix(prefix))?;
+        let rel = rel
+            .trim_start_matches("../")
+            .trim_start_matches("Frameworks/");
+        self.images
+            .iter()
+            .position(|image| image.path == rel || image.path == format!("Frameworks/{}", rel))
+    }
+
+    /// What each image loads
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        self.images
+            .iter()
+            .enumerate()
+            .flat_map(|(from, image)| {
+                image.libs.iter().map(move |lib| Dependency {
+                    from,
+                    lib: lib.clone(),
+                    to: self.resolve(lib),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A bundle loaded into a workspace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Loaded {
+    /// The workspace file name of each image
+    pub files: Vec<String>,
+    /// Which file loads which, a system library by its install name
+    pub edges: Vec<(String, String)>,
+}
+
+/// Where the next image goes: past every map, aligned
+fn next_base(workspace: &mut VivWorkspace) -> i32 {
+    let end = workspace
+        .get_memory_maps()
+        .iter()
+        .map(|(va, size, _, _)| (*va as u32).saturating_add(*size as u32))
+        .max()
+        .unwrap_or(0);
+    end.next_multiple_of(IMAGE_ALIGN) as i32
+}
+
+/// Load every image of `bundle` into `workspace`, writing them to `dir` to load them from. The
+/// main executable stays where it was linked, the others follow it.
+pub fn load(workspace: &mut VivWorkspace, bundle: &Bundle, dir: &Path) -> io::Result<Loaded> {
+    fs::create_dir_all(dir)?;
+    let mut fnames = Vec::new();
+    for (i, image) in bundle.images.iter().enumerate() {
+        // the workspace names files by their stem, which must be unique
+        let stem = image.path.rsplit('/').next().unwrap_or("image");
+        let stem = stem.split('.').next().unwrap_or(stem);
+        let path: PathBuf = match fnames.contains(&stem.to_string()) {
+            true => dir.join(format!("{}-{}", stem, i)),
+            false => dir.join(stem),
+        };
+        fs::write(&path, &image.bytes)?;
+        let macho = MachO::parse(&image.bytes, 0).map_err(|err| invalid(err.to_string()))?;
+        let base = (i != 0).then(|| next_base(workspace));
+        let fname = parse_macho(
+            workspace,
+            &path.to_string_lossy(),
+            &image.bytes,
+            &macho,
+            base,
+        );
+        if let Some(id) = image.bundle_id.clone() {
+            workspace.set_meta(&format!("BundleId:{}", fname), Some(id));
+        }
+        fnames.push(fname);
+    }
+    let edges = bundle
+        .dependencies()
+        .into_iter()
+        .map(|dep| {
+            let to = dep.to.map_or(dep.lib, |to| fnames[to].clone());
+            (fnames[dep.from].clone(), to)
+        })
+        .collect();
+    Ok(Loaded {
+        files: fnames,
+        edges,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(executable: &str, id: &str) -> Vec<u8> {
+        format!(
+            "<plist><dict><key>CFBundleExecutable</key><string>{}</string>\
+             <key>CFBundleIdentifier</key><string>{}</string></dict></plist>",
+            executable, id
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn bundle_images() {
+        let mut files = BTreeMap::new();
+        files.insert("Info.plist".to_string(), info("App", "com.ex.app"));
+        files.insert("App".to_string(), b"not mach-o".to_vec());
+        assert!(Bundle::from_files(files.clone()).is_err());
+        assert!(Bundle::from_files(BTreeMap::new()).is_err());
+
+        let image = Image {
+            path: "PlugIns/Share.appex/Share".to_string(),
+            role: Role::Extension,
+            bundle_id: Some("com.ex.app.share".to_string()),
+            install_name: None,
+            libs: vec!["@rpath/Kit.framework/Kit".to_string()],
+            entitlements: Plist::parse(
+                b"<plist><dict><key>application-identifier</key>\
+                  <string>ABCDE12345.com.ex.app.share</string></dict></plist>",
+            )
+            .ok(),
+            bytes: Vec::new(),
+        };
+        assert_eq!(
+            image.application_identifier(),
+            Some("ABCDE12345.com.ex.app.share")
+        );
+        assert_eq!(image.entitlements_match(), Some(true));
+        let framework = Image {
+            path: "Frameworks/Kit.framework/Kit".to_string(),
+            role: Role::Framework,
+            bundle_id: Some("com.ex.kit".to_string()),
+            install_name: Some("@rpath/Kit.framework/Kit".to_string()),
+            libs: vec!["/usr/lib/libSystem.B.dylib".to_string()],
+            entitlements: None,
+            bytes: Vec::new(),
+        };
+        assert_eq!(framework.entitlements_match(), None);
+        let bundle = Bundle {
+            bundle_id: Some("com.ex.app".to_string()),
+            info: Plist::parse(&info("App", "com.ex.app")).unwrap(),
+            images: vec![image, framework],
+        };
+        assert_eq!(
+            bundle.resolve("@executable_path/Frameworks/Kit.framework/Kit"),
+            Some(1)
+        );
+        assert_eq!(
+            bundle.dependencies(),
+            [
+                Dependency {
+                    from: 0,
+                    lib: "@rpath/Kit.framework/Kit".to_string(),
+                    to: Some(1)
+                },
+                Dependency {
+                    from: 1,
+                    lib: "/usr/lib/libSystem.B.dylib".to_string(),
+                    to: None
+                }
+            ]
+        );
+    }
+}
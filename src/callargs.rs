@@ -0,0 +1,364 @@
+//! The argument values at the calls to APIs of interest, for triage.
+//!
+//! Which file a program opens, where it connects to and what it runs are often right there in
+//! the code, as constants and string literals passed to the API. [`recover`] goes through the
+//! calls to each [`Api`] of a list, [`APIS`] or one's own, and works out the value of each
+//! argument the function makes a constant (see [`crate::vsa::value_before`]): a number, a few
+//! possible numbers, or for string arguments the string pointed at. Arguments it can't tell are
+//! [`Value::Unknown`].
+//!
+//! A [`CallSite`] prints as the call would read, `CreateFileW("C:\\x.dll", 0x80000000, ...)`,
+//! and [`annotate`] puts that on the call as a comment.
+
+use crate::{
+    pic::{load, AddressSpace},
+    symbolic::{call_argument, Function},
+    syscalls::function_at,
+    vsa::{value_before, ValueSet},
+    workspace::VivWorkspace,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// The longest string read, in characters
+const MAX_STRING: usize = 1024;
+/// The most values listed for an argument with several
+const MAX_LISTED: usize = 8;
+
+/// What an argument is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arg {
+    /// A number, flag or handle
+    Int,
+    /// A pointer to a NUL terminated string
+    Str,
+    /// A pointer to a NUL terminated UTF-16 string
+    WStr,
+}
+
+/// An API whose calls are of interest, by the bare name it is imported by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Api<'a> {
+    pub name: &'a str,
+    pub args: &'a [Arg],
+}
+
+const fn api<'a>(name: &'a str, args: &'a [Arg]) -> Api<'a> {
+    Api { name, args }
+}
+
+use Arg::{Int, Str, WStr};
+
+/// APIs whose arguments tell what a program touches
+pub const APIS: &[Api<'static>] = &[
+    api("CreateFileA", &[Str, Int, Int, Int, Int, Int]),
+    api("CreateFileW", &[WStr, Int, Int, Int, Int, Int]),
+    api("DeleteFileA", &[Str]),
+    api("DeleteFileW", &[WStr]),
+    api("LoadLibraryA", &[Str]),
+    api("LoadLibraryW", &[WStr]),
+    api("LoadLibraryExA", &[Str, Int, Int]),
+    api("LoadLibraryExW", &[WStr, Int, Int]),
+    api("RegOpenKeyExA", &[Int, Str, Int, Int]),
+    api("RegOpenKeyExW", &[Int, WStr, Int, Int]),
+    api("RegSetValueExA", &[Int, Str, Int, Int]),
+    api("RegSetValueExW", &[Int, WStr, Int, Int]),
+    api("CreateMutexA", &[Int, Int, Str]),
+    api("CreateMutexW", &[Int, Int, WStr]),
+    api("WinExec", &[Str, Int]),
+    api("ShellExecuteA", &[Int, Str, Str, Str, Str, Int]),
+    api("ShellExecuteW", &[Int, WStr, WStr, WStr, WStr, Int]),
+    api("InternetOpenUrlA", &[Int, Str, Str, Int, Int]),
+    api("InternetOpenUrlW", &[Int, WStr, WStr, Int, Int]),
+    api("URLDownloadToFileA", &[Int, Str, Str, Int]),
+    api("URLDownloadToFileW", &[Int, WStr, WStr, Int]),
+    api("VirtualAlloc", &[Int, Int, Int, Int]),
+    api("VirtualProtect", &[Int, Int, Int]),
+    api("socket", &[Int, Int, Int]),
+    api("connect", &[Int, Int, Int]),
+    api("bind", &[Int, Int, Int]),
+    api("gethostbyname", &[Str]),
+    api("getaddrinfo", &[Str, Str]),
+    api("open", &[Str, Int, Int]),
+    api("fopen", &[Str, Str]),
+    api("unlink", &[Str]),
+    api("dlopen", &[Str, Int]),
+    api("system", &[Str]),
+    api("popen", &[Str, Str]),
+    api("execve", &[Str]),
+    api("execl", &[Str, Str]),
+    api("execlp", &[Str, Str]),
+    api("execv", &[Str]),
+    api("execvp", &[Str]),
+    api("mprotect", &[Int, Int, Int]),
+    api("DeviceIoControl", &[Int, Int, Int, Int, Int, Int, Int, Int]),
+    api("ioctl", &[Int, Int, Int]),
+    api("setsockopt", &[Int, Int, Int, Int, Int]),
+    api("shutdown", &[Int, Int]),
+    api("syscall", &[Int]),
+    api("strerror", &[Int]),
+];
+
+/// The value an argument is passed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(u64),
+    /// One of several numbers, such as flags set on either side of a branch
+    OneOf(Vec<u64>),
+    /// A string, and where it is
+    Str {
+        va: u64,
+        text: String,
+    },
+    /// A pointer for a string argument which doesn't point at one
+    Pointer(u64),
+    Unknown,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) | Value::Pointer(value) => write!(f, "{:#x}", value),
+            Value::OneOf(values) => {
+                let values: Vec<String> = values.iter().map(|v| format!("{:#x}", v)).collect();
+                write!(f, "{{{}}}", values.join(" | "))
+            }
+            Value::Str { text, .. } => write!(f, "{:?}", text),
+            Value::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallSite {
+    pub call: u64,
+    pub api: String,
+    pub args: Vec<Value>,
+}
+
+impl CallSite {
+    /// Whether any argument was worked out
+    pub fn is_known(&self) -> bool {
+        self.args.iter().any(|arg| *arg != Value::Unknown)
+    }
+
+    /// The call as it would read, with the numbers `name` gives a name to, by argument index
+    /// and value, written as that name
+    pub fn render(&self, name: &dyn Fn(usize, u64) -> Option<String>) -> String {
+        let known = self
+            .args
+            .iter()
+            .rposition(|arg| *arg != Value::Unknown)
+            .map_or(0, |last| last + 1);
+        let mut args: Vec<String> = self.args[..known]
+            .iter()
+            .enumerate()
+            .map(|(index, arg)| {
+                let number = |value: &u64| name(index, *value).unwrap_or(format!("{:#x}", value));
+                match arg {
+                    Value::Int(value) => number(value),
+                    Value::OneOf(values) => {
+                        let values: Vec<String> = values.iter().map(number).collect();
+                        format!("{{{}}}", values.join(" | "))
+                    }
+                    arg => arg.to_string(),
+                }
+            })
+            .collect();
+        if known < self.args.len() {
+            args.push("...".to_string());
+        }
+        format!("{}({})", self.api, args.join(", "))
+    }
+}
+
+impl fmt::Display for CallSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(&|_, _| None))
+    }
+}
+
+/// The string at `va`, in units of `size` bytes
+fn read_string(space: &AddressSpace<'_>, va: u64, size: usize) -> Option<String> {
+    let mut units = Vec::new();
+    for i in 0..MAX_STRING as u64 {
+        let (bytes, _) = space.bytes(va + i * size as u64, size)?;
+        let unit = load(bytes, space.is_big_endian());
+        if unit == 0 {
+            break;
+        }
+        units.push(unit as u16);
+    }
+    let text = match size {
+        1 => units.iter().map(|unit| *unit as u8 as char).collect(),
+        _ => String::from_utf16(&units).ok()?,
+    };
+    let printable = text
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace());
+    (!text.is_empty() && units.len() < MAX_STRING && printable).then_some(text)
+}
+
+/// The value of argument `index` of `kind` at `call`
+fn argument(
+    func: &Function,
+    space: &AddressSpace<'_>,
+    call: u64,
+    windows: bool,
+    index: usize,
+    kind: Arg,
+) -> Value {
+    let Some(location) = call_argument(func.arch, windows, index) else {
+        return Value::Unknown;
+    };
+    let values = match value_before(func, space, call, &location) {
+        ValueSet::Values(values) if !values.is_empty() => values,
+        _ => return Value::Unknown,
+    };
+    match (kind, values.len()) {
+        (Arg::Int, 1) => Value::Int(*values.first().unwrap()),
+        (Arg::Int, n) if n <= MAX_LISTED => Value::OneOf(values.into_iter().collect()),
+        (Arg::Str | Arg::WStr, 1) => {
+            let va = *values.first().unwrap();
+            let size = if kind == Arg::WStr { 2 } else { 1 };
+            match read_string(space, va, size) {
+                Some(text) => Value::Str { va, text },
+                None => Value::Pointer(va),
+            }
+        }
+        _ => Value::Unknown,
+    }
+}
+
+/// The argument values at the call to `api` at `call` in `func`
+pub fn call_site(
+    func: &Function,
+    space: &AddressSpace<'_>,
+    call: u64,
+    windows: bool,
+    api: &Api<'_>,
+) -> CallSite {
+    let args = api
+        .args
+        .iter()
+        .enumerate()
+        .map(|(index, kind)| argument(func, space, call, windows, index, *kind))
+        .collect();
+    CallSite {
+        call,
+        api: api.name.to_string(),
+        args,
+    }
+}
+
+/// The argument values at each call to the `apis`. Calls go by the workspace's xrefs to the
+/// imports, and are looked for in the lifted `functions`; `space` is the memory they run in and
+/// `windows` whether the binary follows the Windows calling convention.
+pub fn recover(
+    workspace: &VivWorkspace,
+    functions: &BTreeMap<u64, Function>,
+    space: &AddressSpace<'_>,
+    windows: bool,
+    apis: &[Api<'_>],
+) -> Vec<CallSite> {
+    let mut sites = Vec::new();
+    for api in apis {
+        for call in workspace.get_callers_of_import(api.name) {
+            let call = call as u32 as u64;
+            sites.push(match function_at(functions, call) {
+                Some(func) => call_site(func, space, call, windows, api),
+                None => CallSite {
+                    call,
+                    api: api.name.to_string(),
+                    args: vec![Value::Unknown; api.args.len()],
+                },
+            });
+        }
+    }
+    sites.sort_by(|a, b| (a.call, &a.api).cmp(&(b.call, &b.api)));
+    sites
+}
+
+/// Comment the call with its arguments, where any are known
+pub fn annotate(workspace: &mut VivWorkspace, site: &CallSite) {
+    if site.is_known() {
+        workspace.set_comment(site.call as i32, &site.to_string(), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::REF_CODE,
+        envi::{registers::RegisterModel, Arch},
+        symbolic::{Block, Expr, Insn, Stmt, Terminator},
+    };
+
+    #[test]
+    fn call_arguments() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let set = |name, value| Stmt::Set(reg(name), Expr::Const(value));
+        let insn = |va, stmts| Insn { va, stmts };
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        // CreateFileW(L"C:\\x.dll", GENERIC_READ, 0, ?), system("id"), system(?)
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![set("rcx", 0x2000), set("rdx", 0x8000_0000)]),
+                insn(0x1008, vec![set("r8", 0)]),
+                insn(0x100c, vec![Stmt::Unknown]),
+                insn(0x1011, vec![set("rcx", 0x2020)]),
+                insn(0x1015, vec![Stmt::Unknown]),
+                insn(0x101a, vec![Stmt::Set(reg("rcx"), Expr::Reg(reg("rax")))]),
+                insn(0x101d, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x1022,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0x1000, func)]);
+        let mut rodata: Vec<u8> = "C:\\x.dll\0"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        rodata.resize(0x20, 0);
+        rodata.extend(b"id\0");
+        let code = [0u8; 0x100];
+        let mut space = AddressSpace::new();
+        space.add_map(0x1000, &code, false);
+        space.add_map(0x2000, &rodata, false);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x4000, "kernel32", "CreateFileW");
+        ws.make_import(0x4008, "msvcrt", "system");
+        ws.add_xref(0x100c, 0x4000, REF_CODE, 0);
+        ws.add_xref(0x1015, 0x4008, REF_CODE, 0);
+        ws.add_xref(0x101d, 0x4008, REF_CODE, 0);
+
+        let sites = recover(&ws, &functions, &space, true, APIS);
+        let printed: Vec<String> = sites.iter().map(CallSite::to_string).collect();
+        assert_eq!(
+            printed,
+            [
+                r#"CreateFileW("C:\\x.dll", 0x80000000, 0x0, ...)"#,
+                r#"system("id")"#,
+                "system(...)",
+            ]
+        );
+        assert_eq!(
+            sites[1].args,
+            [Value::Str {
+                va: 0x2020,
+                text: "id".to_string()
+            }]
+        );
+        for site in sites.iter() {
+            annotate(&mut ws, site);
+        }
+        assert_eq!(
+            ws.get_comments().get(&0x1015).map(String::as_str),
+            Some(r#"system("id")"#)
+        );
+        assert!(!ws.get_comments().contains_key(&0x101d));
+    }
+}
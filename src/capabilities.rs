@@ -0,0 +1,1634 @@
+//! Capability rules in the format of capa, matched against an analyzed workspace.
+//!
+//! A [`Rule`] is parsed from the YAML of a capa rule: its `meta` gives the name, namespace,
+//! scope and ATT&CK and MBC ids, and its `features` a tree of `and`, `or`, `not`, `optional`,
+//! `N or more`, `count(...)` and `basic block:` / `instruction:` subscopes over features:
+//!
+//! * `api`, a function or import called, with or without its library and its `A`/`W` suffix,
+//! * `string` (exact, or a `/regex/i`), `substring` and `bytes`, the data the code refers to,
+//! * `number`, `offset` and `mnemonic`, of the instructions,
+//! * `characteristic`: `loop`, `tight loop`, `recursive call`, `calls from`, `calls to`,
+//!   `nzxor` and `indirect call`,
+//! * `section`, `import` and `export`, and `os`, `arch` and `format`, of the file,
+//! * `match`, another rule by name, or every rule of a namespace.
+//!
+//! Features this crate doesn't extract (the .NET ones, for instance) parse but never match, so
+//! a rule set can be loaded whole with [`RuleSet::load_dir`]. Instruction features come from
+//! iced-x86 when the crate is built with it, for i386 and amd64; without it, rules asking for
+//! them only match through their other branches.
+//!
+//! [`find_capabilities`] evaluates a [`RuleSet`] against every function, code block and
+//! instruction of a workspace and against the file as a whole; what matches in a block or an
+//! instruction matches in its function too, and what matches in a function in the file.
+
+use crate::{
+    constants::{BR_PROC, LOC_OP, LOC_STRING, LOC_UNI, REF_CODE, REF_DATA, REF_PTR},
+    envi::Arch,
+    listing::{trim_nul, utf16},
+    memory::Memory,
+    metrics::ranges,
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs, io,
+    path::Path,
+};
+
+/// How many bytes of referenced data a `bytes` feature can match
+const MAX_BYTES: i32 = 0x100;
+
+/// What a rule is matched against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Scope {
+    File,
+    Function,
+    BasicBlock,
+    Instruction,
+}
+
+impl Scope {
+    fn parse(text: &str) -> Result<Self, String> {
+        match text {
+            "file" => Ok(Scope::File),
+            "function" => Ok(Scope::Function),
+            "basic block" => Ok(Scope::BasicBlock),
+            "instruction" => Ok(Scope::Instruction),
+            _ => Err(format!("unsupported scope: {}", text)),
+        }
+    }
+}
+
+/// How a text feature matches
+#[derive(Clone, Debug)]
+pub enum Text {
+    Exact(String),
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Text {
+    fn parse(value: &str) -> Result<Self, String> {
+        if let Some(rest) = value.strip_prefix('/') {
+            if let Some(end) = rest.rfind('/') {
+                let flags = &rest[end + 1..];
+                if flags.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Regex::new(&rest[..end], flags.contains('i')).map(Text::Regex);
+                }
+            }
+        }
+        Ok(Text::Exact(value.to_string()))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Text::Exact(want) => text == want,
+            Text::Substring(want) => text.contains(want.as_str()),
+            Text::Regex(re) => re.is_match(text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Feature {
+    Api(String),
+    String(Text),
+    Bytes(Vec<u8>),
+    Number(i64),
+    Offset(i64),
+    Mnemonic(String),
+    Characteristic(String),
+    Section(String),
+    Import(String),
+    Export(String),
+    Os(String),
+    Arch(String),
+    Format(String),
+    /// A rule by name, or the rules of a namespace
+    Match(String),
+    /// A feature this crate doesn't extract, by its key
+    Unsupported(String),
+}
+
+impl Feature {
+    fn parse(key: &str, value: &str) -> Result<Self, String> {
+        // `number/x32: ...` and the like name a flavour of the feature
+        let key = key.split('/').next().unwrap_or(key);
+        let described = || value.split(" = ").next().unwrap_or(value).trim();
+        Ok(match key {
+            "api" => Feature::Api(described().to_string()),
+            "string" => Feature::String(Text::parse(value)?),
+            "substring" => Feature::String(Text::Substring(value.to_string())),
+            "bytes" => Feature::Bytes(parse_bytes(described())?),
+            "number" => Feature::Number(parse_number(described())?),
+            "offset" => Feature::Offset(parse_number(described())?),
+            "mnemonic" => Feature::Mnemonic(value.to_lowercase()),
+            "characteristic" => Feature::Characteristic(value.to_string()),
+            "section" => Feature::Section(value.to_string()),
+            "import" => Feature::Import(described().to_string()),
+            "export" => Feature::Export(value.to_string()),
+            "os" => Feature::Os(value.to_string()),
+            "arch" => Feature::Arch(value.to_string()),
+            "format" => Feature::Format(value.to_string()),
+            "match" => Feature::Match(value.to_string()),
+            _ => Feature::Unsupported(key.to_string()),
+        })
+    }
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).map(|v| v as i64),
+        None => digits.parse::<i64>(),
+    }
+    .map_err(|_| format!("bad number: {}", text))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let hex: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    // checked first, so the pairs below are whole characters
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("bad bytes: {}", text));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("bad bytes: {}", text)))
+        .collect()
+}
+
+/// The statement of a rule
+#[derive(Clone, Debug)]
+pub enum Node {
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Not(Box<Node>),
+    /// At least this many of the children; `optional` is none of them
+    Some(usize, Vec<Node>),
+    Feature(Feature),
+    /// A feature found at least `min` and at most `max` times
+    Count(Feature, usize, Option<usize>),
+    /// The child matches in one of the blocks or instructions of what the rule is matched against
+    Within(Scope, Box<Node>),
+}
+
+impl Node {
+    fn parse(value: &Yaml) -> Result<Self, String> {
+        let Yaml::Map(pairs) = value else {
+            return Err("a feature is not a mapping".to_string());
+        };
+        let (key, value) = pairs
+            .iter()
+            .find(|(key, _)| key != "description")
+            .ok_or("a feature is only a description")?;
+        let children = || -> Result<Vec<Node>, String> {
+            match value {
+                Yaml::List(items) => items
+                    .iter()
+                    .filter(|item| !item.is_description())
+                    .map(Node::parse)
+                    .collect(),
+                _ => Err(format!("{} takes a list", key)),
+            }
+        };
+        let one = |mut children: Vec<Node>| match children.len() {
+            1 => children.remove(0),
+            _ => Node::And(children),
+        };
+        Ok(match key.as_str() {
+            "and" => Node::And(children()?),
+            "or" => Node::Or(children()?),
+            "not" => Node::Not(Box::new(one(children()?))),
+            "optional" => Node::Some(0, children()?),
+            "basic block" | "instruction" => {
+                Node::Within(Scope::parse(key)?, Box::new(one(children()?)))
+            }
+            key if key.ends_with(" or more") => {
+                let count = key.trim_end_matches(" or more");
+                let count = count.parse().map_err(|_| format!("bad count: {}", key))?;
+                Node::Some(count, children()?)
+            }
+            key if key.starts_with("count(") && key.ends_with(')') => {
+                let inner = &key[6..key.len() - 1];
+                let (fkey, fvalue) = match inner.split_once('(') {
+                    Some((fkey, rest)) => (fkey, rest.strip_suffix(')').unwrap_or(rest)),
+                    None => ("characteristic", inner),
+                };
+                let feature = Feature::parse(fkey, fvalue)?;
+                let (min, max) = parse_count(value.as_str().unwrap_or_default())?;
+                Node::Count(feature, min, max)
+            }
+            key => Node::Feature(Feature::parse(key, value.as_str().unwrap_or_default())?),
+        })
+    }
+
+    /// The rule names and namespaces the node matches on
+    fn matches_on<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Node::And(children) | Node::Or(children) | Node::Some(_, children) => {
+                children.iter().for_each(|child| child.matches_on(out))
+            }
+            Node::Not(child) | Node::Within(_, child) => child.matches_on(out),
+            Node::Feature(Feature::Match(name)) | Node::Count(Feature::Match(name), ..) => {
+                out.push(name)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `N`, `N or more`, `N or fewer` or `(N, M)`
+fn parse_count(text: &str) -> Result<(usize, Option<usize>), String> {
+    let number = |text: &str| {
+        parse_number(text.trim())
+            .ok()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or(format!("bad count: {}", text))
+    };
+    if let Some(n) = text.strip_suffix(" or more") {
+        Ok((number(n)?, None))
+    } else if let Some(n) = text.strip_suffix(" or fewer") {
+        Ok((0, Some(number(n)?)))
+    } else if let Some(range) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        let (min, max) = range
+            .split_once(',')
+            .ok_or(format!("bad count: {}", text))?;
+        Ok((number(min)?, Some(number(max)?)))
+    } else {
+        let n = number(text)?;
+        Ok((n, Some(n)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub namespace: Option<String>,
+    pub scope: Scope,
+    /// ATT&CK techniques, as `Tactic::Technique [Id]`
+    pub attack: Vec<String>,
+    /// Malware Behavior Catalog behaviors
+    pub mbc: Vec<String>,
+    pub node: Node,
+}
+
+impl Rule {
+    /// Parse the YAML of a capa rule
+    pub fn parse(text: &str) -> Result<Rule, String> {
+        let doc = Yaml::parse(text)?;
+        let rule = doc.get("rule").ok_or("no rule")?;
+        let meta = rule.get("meta").ok_or("no meta")?;
+        let name = meta
+            .get("name")
+            .and_then(Yaml::as_str)
+            .ok_or("no name")?
+            .to_string();
+        let scope = match meta.get("scopes") {
+            Some(scopes) => scopes.get("static").and_then(Yaml::as_str),
+            None => meta.get("scope").and_then(Yaml::as_str),
+        };
+        let scope = match scope {
+            Some(scope) => Scope::parse(scope).map_err(|e| format!("{}: {}", name, e))?,
+            None => Scope::Function,
+        };
+        let strings = |key: &str| match meta.get(key) {
+            Some(Yaml::List(items)) => items
+                .iter()
+                .filter_map(|item| item.as_str().map(str::to_string))
+                .collect(),
+            _ => vec![],
+        };
+        let features = match rule.get("features") {
+            Some(Yaml::List(items)) => items
+                .iter()
+                .filter(|item| !item.is_description())
+                .map(Node::parse)
+                .collect::<Result<Vec<_>, _>>(),
+            _ => Err("no features".to_string()),
+        }
+        .map_err(|e| format!("{}: {}", name, e))?;
+        let node = match features.len() {
+            1 => features.into_iter().next().unwrap(),
+            _ => Node::And(features),
+        };
+        Ok(Rule {
+            namespace: meta
+                .get("namespace")
+                .and_then(Yaml::as_str)
+                .map(str::to_string),
+            attack: strings("att&ck"),
+            mbc: strings("mbc"),
+            name,
+            scope,
+            node,
+        })
+    }
+
+    /// Whether `match: target` refers to this rule
+    fn is(&self, target: &str) -> bool {
+        self.name == target
+            || self.namespace.as_deref().is_some_and(|ns| {
+                ns == target
+                    || ns
+                        .strip_prefix(target)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+}
+
+/// Rules in the order they can be evaluated in, every rule after those it matches on
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> Result<Self, String> {
+        let mut ordered: Vec<Rule> = Vec::with_capacity(rules.len());
+        let mut pending = rules;
+        while !pending.is_empty() {
+            let ready = |rule: &Rule| {
+                let mut targets = Vec::new();
+                rule.node.matches_on(&mut targets);
+                targets.iter().all(|target| {
+                    !pending
+                        .iter()
+                        .any(|other| other.name != rule.name && other.is(target))
+                })
+            };
+            let (now, later): (Vec<Rule>, Vec<Rule>) =
+                pending.iter().cloned().partition(|rule| ready(rule));
+            if now.is_empty() {
+                let names: Vec<&str> = later.iter().map(|rule| rule.name.as_str()).collect();
+                return Err(format!("rules match on each other: {}", names.join(", ")));
+            }
+            ordered.extend(now);
+            pending = later;
+        }
+        Ok(RuleSet { rules: ordered })
+    }
+
+    /// Load every `.yml` and `.yaml` rule under `dir`, with the errors of those which don't
+    /// parse
+    pub fn load_dir(dir: &Path) -> io::Result<(RuleSet, Vec<String>)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path
+                    .extension()
+                    .is_some_and(|ext| ext == "yml" || ext == "yaml")
+                {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        let mut rules = Vec::new();
+        let mut errors = Vec::new();
+        for path in files {
+            match Rule::parse(&fs::read_to_string(&path)?) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+        let set = RuleSet::new(rules).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((set, errors))
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.name == name)
+    }
+}
+
+/// The rules which matched, by name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Everything which matched anywhere in the binary
+    pub file: BTreeSet<String>,
+    pub functions: BTreeMap<i32, BTreeSet<String>>,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for name in self.file.iter() {
+            writeln!(f, "{}", name)?;
+            for (fva, names) in self.functions.iter() {
+                if names.contains(name) {
+                    writeln!(f, "    {:#x}", fva)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Match `rules` against every function, block and instruction of `workspace`, and against
+/// the file as a whole
+pub fn find_capabilities(workspace: &VivWorkspace, rules: &RuleSet) -> Capabilities {
+    let scan = Scan::new(workspace, rules);
+    let scoped = |scope: Scope| -> Vec<&Rule> {
+        rules
+            .rules
+            .iter()
+            .filter(|rule| rule.scope == scope)
+            .collect()
+    };
+    let (in_insns, in_blocks) = (scoped(Scope::Instruction), scoped(Scope::BasicBlock));
+    let (in_functions, in_file) = (scoped(Scope::Function), scoped(Scope::File));
+    let mut caps = Capabilities::default();
+    for fva in workspace.get_functions() {
+        let mut matched = Vec::new();
+        for (unit_rules, scope) in [
+            (&in_insns, Scope::Instruction),
+            (&in_blocks, Scope::BasicBlock),
+        ] {
+            if unit_rules.is_empty() {
+                continue;
+            }
+            for unit in scan.units(scope, &ranges(workspace, fva)) {
+                let unit = [unit];
+                let mut features = scan.features(&unit, None);
+                scan.evaluate(unit_rules, &unit, &mut features);
+                matched.extend(features.matched);
+            }
+        }
+        let bounds = ranges(workspace, fva);
+        let mut features = scan.features(&bounds, Some(fva));
+        features.matched = matched;
+        scan.evaluate(&in_functions, &bounds, &mut features);
+        if !features.matched.is_empty() {
+            caps.file.extend(features.matched.iter().cloned());
+            caps.functions
+                .insert(fva, features.matched.into_iter().collect());
+        }
+    }
+    let mut features = scan.file_features();
+    features.matched = caps.file.iter().cloned().collect();
+    scan.evaluate(&in_file, &[], &mut features);
+    caps.file.extend(features.matched);
+    caps
+}
+
+/// What is found in a function, block, instruction or file
+#[derive(Default)]
+struct Features {
+    apis: Vec<String>,
+    strings: Vec<String>,
+    data: Vec<Vec<u8>>,
+    numbers: Vec<i64>,
+    offsets: Vec<i64>,
+    mnemonics: Vec<String>,
+    characteristics: Vec<&'static str>,
+    sections: Vec<String>,
+    imports: Vec<String>,
+    exports: Vec<String>,
+    /// The rules matched so far
+    matched: Vec<String>,
+}
+
+/// What extracting features needs, worked out once
+struct Scan<'a> {
+    workspace: &'a VivWorkspace,
+    /// Every xref, by where it is from
+    xrefs: Vec<(i32, i32, i32, i32)>,
+    stubs: HashMap<i32, i32>,
+    imports: HashSet<i32>,
+    os: String,
+    arch: String,
+    format: String,
+    rules: &'a RuleSet,
+}
+
+impl<'a> Scan<'a> {
+    fn new(workspace: &'a VivWorkspace, rules: &'a RuleSet) -> Self {
+        let mut xrefs = workspace.get_xrefs(None);
+        xrefs.sort_unstable();
+        let os = match workspace.get_meta("Platform").unwrap_or_default().as_str() {
+            "darwin" => "macos".to_string(),
+            os => os.to_lowercase(),
+        };
+        Scan {
+            workspace,
+            xrefs,
+            stubs: workspace.get_import_stubs().into_iter().collect(),
+            imports: workspace
+                .get_imports()
+                .into_iter()
+                .map(|(va, _)| va)
+                .collect(),
+            os,
+            arch: Arch::from_envi(workspace.arch)
+                .map(|arch| arch.name().to_string())
+                .unwrap_or_default(),
+            format: workspace.get_meta("Format").unwrap_or_default(),
+            rules,
+        }
+    }
+
+    /// The blocks or instructions within `ranges`
+    fn units(&self, scope: Scope, ranges: &[(i32, i32)]) -> Vec<(i32, i32)> {
+        let inside = |va: i32| {
+            ranges
+                .iter()
+                .any(|(start, size)| *start <= va && (va as i64) < *start as i64 + *size as i64)
+        };
+        match scope {
+            Scope::BasicBlock => {
+                let fvas: BTreeSet<i32> = ranges
+                    .iter()
+                    .filter_map(|(va, _)| self.workspace.get_function(*va))
+                    .collect();
+                let mut blocks: Vec<(i32, i32)> = fvas
+                    .iter()
+                    .flat_map(|fva| self.workspace.get_function_blocks(*fva))
+                    .filter(|(va, ..)| inside(*va))
+                    .map(|(va, size, ..)| (va, size))
+                    .collect();
+                blocks.sort_unstable();
+                blocks.dedup();
+                blocks
+            }
+            Scope::Instruction => self.ops(ranges),
+            _ => ranges.to_vec(),
+        }
+    }
+
+    /// The (va, size) of the instructions within `ranges`
+    fn ops(&self, ranges: &[(i32, i32)]) -> Vec<(i32, i32)> {
+        let mut ops = Vec::new();
+        for (start, size) in ranges {
+            let mut va = *start;
+            while (va as i64) < *start as i64 + *size as i64 {
+                match self.workspace.get_location(va) {
+                    Some((lva, lsize, ltype, _)) if lva == va && lsize > 0 => {
+                        if ltype == LOC_OP {
+                            ops.push((va, lsize));
+                        }
+                        va = va.wrapping_add(lsize);
+                    }
+                    _ => va = va.wrapping_add(1),
+                }
+            }
+        }
+        ops
+    }
+
+    fn text(&self, va: i32) -> Option<String> {
+        let (lva, size, ltype, _) = self.workspace.get_location(va)?;
+        let bytes = self.workspace.read_memory(lva, size)?;
+        match ltype {
+            LOC_STRING => Some(String::from_utf8_lossy(trim_nul(&bytes)).into_owned()),
+            LOC_UNI => Some(utf16(&bytes)),
+            _ => None,
+        }
+    }
+
+    /// The features of code within `ranges`, of the function at `fva` if they are a function's
+    fn features(&self, ranges: &[(i32, i32)], fva: Option<i32>) -> Features {
+        let workspace = self.workspace;
+        let mut features = Features::default();
+        let inside = |va: i32| {
+            ranges
+                .iter()
+                .any(|(start, size)| *start <= va && (va as i64) < *start as i64 + *size as i64)
+        };
+        for (start, size) in ranges {
+            let end = *start as i64 + *size as i64;
+            let first = self.xrefs.partition_point(|xref| xref.0 < *start);
+            for (from, to, rtype, rflags) in self.xrefs[first..]
+                .iter()
+                .take_while(|xref| (xref.0 as i64) < end)
+            {
+                let name = || {
+                    let to = self.stubs.get(to).copied().unwrap_or(*to);
+                    workspace.get_name(to, false)
+                };
+                match *rtype {
+                    REF_CODE if rflags & BR_PROC != 0 => {
+                        features.characteristics.push("calls from");
+                        if Some(*to) == fva {
+                            features.characteristics.push("recursive call");
+                        }
+                        features.apis.extend(name());
+                    }
+                    REF_CODE => {
+                        if to <= from && inside(*to) {
+                            features.characteristics.push("loop");
+                            let block = workspace.get_code_block(*from).map(|cb| cb.0);
+                            if block == Some(*to) {
+                                features.characteristics.push("tight loop");
+                            }
+                        }
+                        if self.imports.contains(to) {
+                            features.apis.extend(name());
+                        }
+                    }
+                    REF_DATA | REF_PTR if self.imports.contains(to) => features.apis.extend(name()),
+                    REF_DATA | REF_PTR => {
+                        if let Some(text) = self.text(*to) {
+                            features.strings.push(text);
+                        } else if let Some(bytes) = workspace.read_memory(*to, MAX_BYTES) {
+                            features.data.push(bytes);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(fva) = fva {
+            let callers = workspace
+                .get_xrefs_to(fva, Some(REF_CODE))
+                .iter()
+                .filter(|(.., rflags)| rflags & BR_PROC != 0)
+                .count();
+            features
+                .characteristics
+                .extend(std::iter::repeat_n("calls to", callers));
+        }
+        let mut insns = Insns::new(workspace);
+        if let Some(insns) = insns.as_mut() {
+            for (va, size) in self.ops(ranges) {
+                if let Some(bytes) = workspace.read_memory(va, size) {
+                    insns.features(va, &bytes, &mut features);
+                }
+            }
+        }
+        features
+    }
+
+    fn file_features(&self) -> Features {
+        let workspace = self.workspace;
+        let mut features = Features {
+            imports: workspace
+                .get_imports()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect(),
+            exports: workspace
+                .get_exports()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect(),
+            sections: workspace
+                .get_segments()
+                .into_iter()
+                .map(|(.., name, _)| name)
+                .collect(),
+            ..Features::default()
+        };
+        for ltype in [LOC_STRING, LOC_UNI] {
+            for (va, ..) in workspace.get_locations(Some(ltype), None) {
+                features.strings.extend(self.text(va));
+            }
+        }
+        features
+    }
+
+    /// Add the rules which match `features`, of the code within `ranges`, to what it matched,
+    /// in order
+    fn evaluate(&self, rules: &[&Rule], ranges: &[(i32, i32)], features: &mut Features) {
+        for rule in rules {
+            if self.eval(&rule.node, ranges, features) && !features.matched.contains(&rule.name) {
+                features.matched.push(rule.name.clone());
+            }
+        }
+    }
+
+    fn eval(&self, node: &Node, ranges: &[(i32, i32)], features: &Features) -> bool {
+        match node {
+            Node::And(children) => children
+                .iter()
+                .all(|child| self.eval(child, ranges, features)),
+            Node::Or(children) => children
+                .iter()
+                .any(|child| self.eval(child, ranges, features)),
+            Node::Not(child) => !self.eval(child, ranges, features),
+            Node::Some(count, children) => {
+                children
+                    .iter()
+                    .filter(|child| self.eval(child, ranges, features))
+                    .count()
+                    >= *count
+            }
+            Node::Feature(feature) => self.count(feature, features) > 0,
+            Node::Count(feature, min, max) => {
+                let count = self.count(feature, features);
+                count >= *min && max.is_none_or(|max| count <= max)
+            }
+            Node::Within(scope, child) => self.units(*scope, ranges).into_iter().any(|unit| {
+                let unit = [unit];
+                self.eval(child, &unit, &self.features(&unit, None))
+            }),
+        }
+    }
+
+    fn count(&self, feature: &Feature, features: &Features) -> usize {
+        let global =
+            |have: &str, want: &str| usize::from(want == "any" || have.eq_ignore_ascii_case(want));
+        match feature {
+            Feature::Api(want) => features
+                .apis
+                .iter()
+                .filter(|have| api_matches(want, have))
+                .count(),
+            Feature::String(text) => features.strings.iter().filter(|s| text.matches(s)).count(),
+            Feature::Bytes(want) => features
+                .data
+                .iter()
+                .filter(|data| data.starts_with(want))
+                .count(),
+            Feature::Number(want) => features.numbers.iter().filter(|n| *n == want).count(),
+            Feature::Offset(want) => features.offsets.iter().filter(|n| *n == want).count(),
+            Feature::Mnemonic(want) => features.mnemonics.iter().filter(|m| *m == want).count(),
+            Feature::Characteristic(want) => features
+                .characteristics
+                .iter()
+                .filter(|c| **c == want.as_str())
+                .count(),
+            Feature::Section(want) => features.sections.iter().filter(|s| *s == want).count(),
+            Feature::Import(want) => features
+                .imports
+                .iter()
+                .filter(|have| api_matches(want, have))
+                .count(),
+            Feature::Export(want) => features.exports.iter().filter(|e| *e == want).count(),
+            Feature::Os(want) => global(&self.os, want),
+            Feature::Arch(want) => global(&self.arch, want),
+            Feature::Format(want) => global(&self.format, want),
+            Feature::Match(target) => features
+                .matched
+                .iter()
+                .filter(|name| {
+                    *name == target || self.rules.get(name).is_some_and(|rule| rule.is(target))
+                })
+                .count(),
+            Feature::Unsupported(_) => 0,
+        }
+    }
+}
+
+/// Whether a called or imported `have`, as `library.name` or `name`, is the `want` of a rule:
+/// the library is optional and matches in any case, and an `A` or `W` suffix may be left off
+fn api_matches(want: &str, have: &str) -> bool {
+    let split = |name: &str| match name.rsplit_once('.') {
+        Some((lib, name)) => (Some(lib.to_lowercase()), name.to_string()),
+        None => (None, name.to_string()),
+    };
+    let ((want_lib, want_name), (have_lib, have_name)) = (split(want), split(have));
+    if want_lib.is_some() && have_lib.is_some() {
+        let strip = |lib: String| lib.trim_end_matches(".dll").to_string();
+        if want_lib.map(strip) != have_lib.map(strip) {
+            return false;
+        }
+    }
+    have_name == want_name
+        || have_name
+            .strip_prefix(want_name.as_str())
+            .is_some_and(|suffix| suffix == "A" || suffix == "W")
+}
+
+#[cfg(feature = "iced-x86")]
+struct Insns {
+    bitness: u32,
+}
+
+#[cfg(feature = "iced-x86")]
+impl Insns {
+    fn new(workspace: &VivWorkspace) -> Option<Self> {
+        let bitness = match Arch::from_envi(workspace.arch)? {
+            Arch::I386 => 32,
+            Arch::Amd64 => 64,
+            _ => return None,
+        };
+        Some(Insns { bitness })
+    }
+
+    fn features(&mut self, va: i32, bytes: &[u8], features: &mut Features) {
+        use iced_x86::{Mnemonic, OpKind, Register};
+
+        let mut decoder = iced_x86::Decoder::with_ip(
+            self.bitness,
+            bytes,
+            va as u32 as u64,
+            iced_x86::DecoderOptions::NONE,
+        );
+        let insn = decoder.decode();
+        if insn.is_invalid() {
+            return;
+        }
+        features
+            .mnemonics
+            .push(format!("{:?}", insn.mnemonic()).to_lowercase());
+        for op in 0..insn.op_count() {
+            match insn.op_kind(op) {
+                OpKind::Immediate8
+                | OpKind::Immediate16
+                | OpKind::Immediate32
+                | OpKind::Immediate64
+                | OpKind::Immediate8to16
+                | OpKind::Immediate8to32
+                | OpKind::Immediate8to64
+                | OpKind::Immediate32to64 => features.numbers.push(insn.immediate(op) as i64),
+                OpKind::Memory
+                    if insn.memory_base() != Register::None && !insn.is_ip_rel_memory_operand() =>
+                {
+                    let disp = match self.bitness {
+                        64 => insn.memory_displacement64() as i64,
+                        _ => insn.memory_displacement32() as i32 as i64,
+                    };
+                    if disp != 0 {
+                        features.offsets.push(disp);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let xors = [
+            Mnemonic::Xor,
+            Mnemonic::Pxor,
+            Mnemonic::Xorps,
+            Mnemonic::Xorpd,
+        ];
+        if xors.contains(&insn.mnemonic())
+            && !(insn.op_kind(0) == OpKind::Register
+                && insn.op_kind(1) == OpKind::Register
+                && insn.op0_register() == insn.op1_register())
+        {
+            features.characteristics.push("nzxor");
+        }
+        if insn.mnemonic() == Mnemonic::Call && insn.op_kind(0) == OpKind::Register {
+            features.characteristics.push("indirect call");
+        }
+        if insn.mnemonic() == Mnemonic::Call
+            && insn.op_kind(0) == OpKind::Memory
+            && !insn.is_ip_rel_memory_operand()
+            && insn.memory_base() != Register::None
+        {
+            features.characteristics.push("indirect call");
+        }
+    }
+}
+
+/// Without a decoder there are no instruction features
+#[cfg(not(feature = "iced-x86"))]
+struct Insns;
+
+#[cfg(not(feature = "iced-x86"))]
+impl Insns {
+    fn new(_workspace: &VivWorkspace) -> Option<Self> {
+        None
+    }
+
+    fn features(&mut self, _va: i32, _bytes: &[u8], _features: &mut Features) {}
+}
+
+/// The YAML capa rules are written in: block mappings and sequences of plain or quoted scalars
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Yaml {
+    Str(String),
+    List(Vec<Yaml>),
+    Map(Vec<(String, Yaml)>),
+}
+
+impl Yaml {
+    fn parse(text: &str) -> Result<Yaml, String> {
+        let lines: Vec<(usize, &str)> = text
+            .lines()
+            .map(|line| (line.len() - line.trim_start().len(), line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#') && *line != "---")
+            .collect();
+        let mut pos = 0;
+        let Some((indent, _)) = lines.first() else {
+            return Ok(Yaml::Map(vec![]));
+        };
+        Yaml::block(&lines, &mut pos, *indent)
+    }
+
+    fn block(lines: &[(usize, &str)], pos: &mut usize, indent: usize) -> Result<Yaml, String> {
+        if lines[*pos].1.starts_with('-') {
+            let mut items = Vec::new();
+            while *pos < lines.len() && lines[*pos].0 == indent && lines[*pos].1.starts_with('-') {
+                let item = lines[*pos].1[1..].trim_start();
+                // the item's own column, where more keys of a mapping item line up
+                let column = indent + (lines[*pos].1.len() - item.len());
+                if item.is_empty() {
+                    *pos += 1;
+                    match lines.get(*pos) {
+                        Some((next, _)) if *next > indent => {
+                            let next = *next;
+                            items.push(Yaml::block(lines, pos, next)?)
+                        }
+                        _ => items.push(Yaml::Str(String::new())),
+                    }
+                } else if let Some((key, value)) = split_key(item) {
+                    *pos += 1;
+                    let mut pairs = vec![(key, Yaml::value(lines, pos, value, indent)?)];
+                    while *pos < lines.len()
+                        && lines[*pos].0 == column
+                        && !lines[*pos].1.starts_with('-')
+                    {
+                        let (key, value) = split_key(lines[*pos].1)
+                            .ok_or(format!("expected a key: {}", lines[*pos].1))?;
+                        *pos += 1;
+                        pairs.push((key, Yaml::value(lines, pos, value, column)?));
+                    }
+                    items.push(Yaml::Map(pairs));
+                } else {
+                    items.push(Yaml::Str(unquote(item)?));
+                    *pos += 1;
+                }
+            }
+            return Ok(Yaml::List(items));
+        }
+        let mut pairs = Vec::new();
+        while *pos < lines.len() && lines[*pos].0 == indent && !lines[*pos].1.starts_with('-') {
+            let (key, value) =
+                split_key(lines[*pos].1).ok_or(format!("expected a key: {}", lines[*pos].1))?;
+            *pos += 1;
+            pairs.push((key, Yaml::value(lines, pos, value, indent)?));
+        }
+        Ok(Yaml::Map(pairs))
+    }
+
+    /// The value of a key at `indent`: inline, or the block below it
+    fn value(
+        lines: &[(usize, &str)],
+        pos: &mut usize,
+        inline: &str,
+        indent: usize,
+    ) -> Result<Yaml, String> {
+        if !inline.is_empty() {
+            return Ok(Yaml::Str(unquote(inline)?));
+        }
+        match lines.get(*pos) {
+            Some((next, line)) if *next > indent || (*next == indent && line.starts_with('-')) => {
+                let next = *next;
+                Yaml::block(lines, pos, next)
+            }
+            _ => Ok(Yaml::Str(String::new())),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Yaml> {
+        match self {
+            Yaml::Map(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Yaml::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn is_description(&self) -> bool {
+        matches!(self, Yaml::Map(pairs) if pairs.len() == 1 && pairs[0].0 == "description")
+    }
+}
+
+/// `key: value` or `key:`, where the key may itself hold `: ` inside parentheses
+fn split_key(line: &str) -> Option<(String, &str)> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' | '\'' if quote == Some(c) => quote = None,
+            '"' | '\'' if quote.is_none() && i == 0 => quote = Some(c),
+            '(' if quote.is_none() => depth += 1,
+            ')' if quote.is_none() => depth -= 1,
+            ':' if quote.is_none() && depth == 0 => {
+                let rest = &line[i + 1..];
+                if rest.is_empty() || rest.starts_with(' ') {
+                    let key = unquote(line[..i].trim()).ok()?;
+                    return Some((key, rest.trim()));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(text: &str) -> Result<String, String> {
+    if text.len() >= 2 && text.starts_with('\'') && text.ends_with('\'') {
+        return Ok(text[1..text.len() - 1].replace("''", "'"));
+    }
+    if !(text.len() >= 2 && text.starts_with('"') && text.ends_with('"')) {
+        return Ok(text.to_string());
+    }
+    let mut out = String::new();
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some(c @ ('"' | '\\' | '/')) => out.push(c),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let code =
+                    u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape in {}", text))?;
+                out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+            }
+            _ => return Err(format!("bad escape in {}", text)),
+        }
+    }
+    Ok(out)
+}
+
+/// A regex of the syntax capa rules use: literals and escapes, `.`, classes, anchors, groups
+/// with alternatives, and `*`, `+`, `?` and `{n,m}`. It is compiled to a Thompson NFA and run
+/// as a Pike VM, in time linear in the text, so no pattern can make matching blow up.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    icase: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Re {
+    Set(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Vec<Vec<Re>>),
+    Repeat(Box<Re>, usize, Option<usize>),
+}
+
+/// An instruction of a compiled regex
+#[derive(Clone, Debug)]
+enum Inst {
+    /// One character in (or, negated, outside) the ranges
+    Set(Vec<(char, char)>, bool),
+    Start,
+    End,
+    /// Go on at both, the first preferred
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+/// How many instructions a regex compiles to at most, counted repeats expanded
+const MAX_PROGRAM: usize = 0x1000;
+/// How many characters of a text a regex is matched against
+const MAX_TEXT: usize = 0x10000;
+
+impl Regex {
+    pub fn new(pattern: &str, icase: bool) -> Result<Self, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut pos = 0;
+        let alts = Regex::alternatives(&chars, &mut pos)?;
+        if pos != chars.len() {
+            return Err(format!("unbalanced ) in /{}/", pattern));
+        }
+        let mut prog = Vec::new();
+        Regex::compile_alts(&alts, &mut prog)?;
+        prog.push(Inst::Match);
+        Ok(Regex { prog, icase })
+    }
+
+    fn alternatives(chars: &[char], pos: &mut usize) -> Result<Vec<Vec<Re>>, String> {
+        let mut alts = vec![vec![]];
+        while *pos < chars.len() {
+            let c = chars[*pos];
+            *pos += 1;
+            let atom = match c {
+                '|' => {
+                    alts.push(vec![]);
+                    continue;
+                }
+                ')' => {
+                    *pos -= 1;
+                    break;
+                }
+                '(' => {
+                    if chars[*pos..].starts_with(&['?', ':']) {
+                        *pos += 2;
+                    }
+                    let inner = Regex::alternatives(chars, pos)?;
+                    if chars.get(*pos) != Some(&')') {
+                        return Err("unbalanced (".to_string());
+                    }
+                    *pos += 1;
+                    Re::Group(inner)
+                }
+                '^' => Re::Start,
+                '$' => Re::End,
+                '.' => Re::Set(vec![], true),
+                '[' => Regex::class(chars, pos)?,
+                '\\' => {
+                    let c = *chars.get(*pos).ok_or("trailing \\")?;
+                    *pos += 1;
+                    Regex::escape(c, chars, pos)?
+                }
+                c => Re::Set(vec![(c, c)], false),
+            };
+            let atom = match chars.get(*pos) {
+                Some('*') => Re::Repeat(Box::new(atom), 0, None),
+                Some('+') => Re::Repeat(Box::new(atom), 1, None),
+                Some('?') => Re::Repeat(Box::new(atom), 0, Some(1)),
+                Some('{') => match Regex::bounds(chars, pos) {
+                    Some((min, max)) => Re::Repeat(Box::new(atom), min, max),
+                    None => {
+                        alts.last_mut().unwrap().push(atom);
+                        continue;
+                    }
+                },
+                _ => {
+                    alts.last_mut().unwrap().push(atom);
+                    continue;
+                }
+            };
+            *pos += 1;
+            // lazy quantifiers match the same, just not as soon
+            if chars.get(*pos) == Some(&'?') {
+                *pos += 1;
+            }
+            alts.last_mut().unwrap().push(atom);
+        }
+        Ok(alts)
+    }
+
+    /// `{n}`, `{n,}` or `{n,m}` at `pos`, leaving `pos` on its last character
+    fn bounds(chars: &[char], pos: &mut usize) -> Option<(usize, Option<usize>)> {
+        let end = chars[*pos..].iter().position(|c| *c == '}')? + *pos;
+        let text: String = chars[*pos + 1..end].iter().collect();
+        let (min, max) = match text.split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+            None => {
+                let n = text.parse().ok()?;
+                (n, Some(n))
+            }
+        };
+        *pos = end;
+        Some((min, max))
+    }
+
+    fn escape(c: char, chars: &[char], pos: &mut usize) -> Result<Re, String> {
+        let digits = vec![('0', '9')];
+        let word = vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+        let space = vec![(' ', ' '), ('\t', '\r')];
+        Ok(match c {
+            'd' => Re::Set(digits, false),
+            'D' => Re::Set(digits, true),
+            'w' => Re::Set(word, false),
+            'W' => Re::Set(word, true),
+            's' => Re::Set(space, false),
+            'S' => Re::Set(space, true),
+            'b' | 'B' | 'A' | 'Z' => return Err(format!("unsupported escape \\{}", c)),
+            c => {
+                let c = Regex::literal(c, chars, pos)?;
+                Re::Set(vec![(c, c)], false)
+            }
+        })
+    }
+
+    /// The character an escape other than a class stands for
+    fn literal(c: char, chars: &[char], pos: &mut usize) -> Result<char, String> {
+        Ok(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'x' => {
+                let hex: String = chars.get(*pos..*pos + 2).ok_or("bad \\x")?.iter().collect();
+                *pos += 2;
+                char::from_u32(u32::from_str_radix(&hex, 16).map_err(|_| "bad \\x")?)
+                    .ok_or("bad \\x")?
+            }
+            c => c,
+        })
+    }
+
+    fn class(chars: &[char], pos: &mut usize) -> Result<Re, String> {
+        let negated = chars.get(*pos) == Some(&'^');
+        if negated {
+            *pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = *chars.get(*pos).ok_or("unbalanced [")?;
+            *pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let e = *chars.get(*pos).ok_or("unbalanced [")?;
+                *pos += 1;
+                match Regex::escape(e, chars, pos)? {
+                    Re::Set(set, false)
+                        if set.len() > 1 || e.is_ascii_alphabetic() && set[0].0 != set[0].1 =>
+                    {
+                        ranges.extend(set);
+                        continue;
+                    }
+                    Re::Set(set, false) => set[0].0,
+                    _ => return Err(format!("unsupported escape \\{} in a class", e)),
+                }
+            } else {
+                c
+            };
+            if chars.get(*pos) == Some(&'-') && chars.get(*pos + 1).is_some_and(|c| *c != ']') {
+                let mut hi = chars[*pos + 1];
+                *pos += 2;
+                if hi == '\\' {
+                    let e = *chars.get(*pos).ok_or("unbalanced [")?;
+                    *pos += 1;
+                    hi = Regex::literal(e, chars, pos)?;
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Re::Set(ranges, negated))
+    }
+
+    fn emit(prog: &mut Vec<Inst>, inst: Inst) -> Result<usize, String> {
+        if prog.len() >= MAX_PROGRAM {
+            return Err("regex too large".to_string());
+        }
+        prog.push(inst);
+        Ok(prog.len() - 1)
+    }
+
+    fn compile_alts(alts: &[Vec<Re>], prog: &mut Vec<Inst>) -> Result<(), String> {
+        let mut jumps = Vec::new();
+        for (i, alt) in alts.iter().enumerate() {
+            let split = match i + 1 < alts.len() {
+                true => Some(Regex::emit(prog, Inst::Split(0, 0))?),
+                false => None,
+            };
+            for re in alt {
+                Regex::compile(re, prog)?;
+            }
+            if let Some(split) = split {
+                jumps.push(Regex::emit(prog, Inst::Jmp(0))?);
+                prog[split] = Inst::Split(split + 1, prog.len());
+            }
+        }
+        for jump in jumps {
+            prog[jump] = Inst::Jmp(prog.len());
+        }
+        Ok(())
+    }
+
+    fn compile(re: &Re, prog: &mut Vec<Inst>) -> Result<(), String> {
+        match re {
+            Re::Set(ranges, negated) => {
+                Regex::emit(prog, Inst::Set(ranges.clone(), *negated))?;
+            }
+            Re::Start => {
+                Regex::emit(prog, Inst::Start)?;
+            }
+            Re::End => {
+                Regex::emit(prog, Inst::End)?;
+            }
+            Re::Group(alts) => Regex::compile_alts(alts, prog)?,
+            Re::Repeat(inner, min, max) => {
+                for _ in 0..*min {
+                    Regex::compile(inner, prog)?;
+                }
+                match max {
+                    None => {
+                        let split = Regex::emit(prog, Inst::Split(0, 0))?;
+                        Regex::compile(inner, prog)?;
+                        Regex::emit(prog, Inst::Jmp(split))?;
+                        prog[split] = Inst::Split(split + 1, prog.len());
+                    }
+                    Some(max) => {
+                        // each optional copy skips past all of them
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(Regex::emit(prog, Inst::Split(0, 0))?);
+                            Regex::compile(inner, prog)?;
+                        }
+                        for split in splits {
+                            prog[split] = Inst::Split(split + 1, prog.len());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the regex matches anywhere in the first [`MAX_TEXT`] characters of `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().take(MAX_TEXT).collect();
+        let mut current = Threads::new(self.prog.len());
+        let mut next = Threads::new(self.prog.len());
+        for pos in 0..=text.len() {
+            // a match may start anywhere
+            if self.add(&mut current, 0, pos, &text) {
+                return true;
+            }
+            let Some(c) = text.get(pos) else {
+                break;
+            };
+            next.clear();
+            for i in 0..current.pcs.len() {
+                let pc = current.pcs[i];
+                if let Inst::Set(ranges, negated) = &self.prog[pc] {
+                    if self.has(ranges, *c) != *negated
+                        && self.add(&mut next, pc + 1, pos + 1, &text)
+                    {
+                        return true;
+                    }
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+
+    fn has(&self, ranges: &[(char, char)], c: char) -> bool {
+        let has = |c: char| ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi);
+        has(c) || self.icase && (has(c.to_ascii_lowercase()) || has(c.to_ascii_uppercase()))
+    }
+
+    /// Add the thread at `pc` and those it reaches without reading, at `pos`; whether one of
+    /// them matches
+    fn add(&self, threads: &mut Threads, pc: usize, pos: usize, text: &[char]) -> bool {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc) {
+                continue;
+            }
+            match self.prog[pc] {
+                Inst::Set(..) => {}
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == text.len() => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Split(first, second) => stack.extend([second, first]),
+                Inst::Jmp(to) => stack.push(to),
+                Inst::Match => return true,
+            }
+        }
+        false
+    }
+}
+
+/// The program counters of the threads at one position, each at most once
+struct Threads {
+    pcs: Vec<usize>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            pcs: Vec::with_capacity(len),
+            seen: vec![false; len],
+        }
+    }
+
+    fn insert(&mut self, pc: usize) -> bool {
+        if self.seen[pc] {
+            return false;
+        }
+        self.seen[pc] = true;
+        self.pcs.push(pc);
+        true
+    }
+
+    fn clear(&mut self) {
+        for pc in self.pcs.drain(..) {
+            self.seen[pc] = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_I386, MM_READ, MM_RWX};
+
+    const CREATE_FILE: &str = r#"
+rule:
+  meta:
+    name: write file on Windows
+    namespace: host-interaction/file-system/write
+    authors:
+      - someone@example.com
+    scopes:
+      static: function
+      dynamic: call
+    att&ck:
+      - Execution::Shared Modules [T1129]
+  features:
+    - and:
+      - os: windows
+      - or:
+        - api: kernel32.CreateFile
+        - api: NtCreateFile
+      - description: the path
+      - string: /\.(log|tmp)$/i
+      - optional:
+        - number: 0x40000000 = GENERIC_WRITE
+      - count(characteristic(calls from)): 2 or more
+"#;
+
+    const LOGGER: &str = r#"
+rule:
+  meta:
+    name: log to a file
+    scope: file
+  features:
+    - and:
+      - match: host-interaction/file-system
+      - section: .text
+      - not:
+        - export: DllMain
+"#;
+
+    #[test]
+    fn parses_yaml() {
+        let doc = Yaml::parse(
+            "---\n# a comment\nrule:\n  'quoted: key': \"a\\tb\\x41\"\n  it's: 'don''t'\n  empty:\n  list:\n  - plain\n  -\n    nested: 1\n  - key: 2\n    more: 3\n  - count(api(a: b)): 2\n",
+        )
+        .unwrap();
+        let rule = doc.get("rule").unwrap();
+        assert_eq!(
+            rule.get("quoted: key").and_then(Yaml::as_str),
+            Some("a\tbA")
+        );
+        assert_eq!(rule.get("it's").and_then(Yaml::as_str), Some("don't"));
+        assert_eq!(rule.get("empty").and_then(Yaml::as_str), Some(""));
+        let Some(Yaml::List(items)) = rule.get("list") else {
+            panic!("no list");
+        };
+        assert_eq!(items[0], Yaml::Str("plain".to_string()));
+        assert_eq!(items[1].get("nested").and_then(Yaml::as_str), Some("1"));
+        // more keys of a mapping item line up with its first
+        assert_eq!(items[2].get("more").and_then(Yaml::as_str), Some("3"));
+        assert_eq!(
+            items[3].get("count(api(a: b))").and_then(Yaml::as_str),
+            Some("2")
+        );
+        // a list may sit at the same indent as its key
+        let doc = Yaml::parse("features:\n- a\n- b\nafter: c\n").unwrap();
+        assert!(matches!(doc.get("features"), Some(Yaml::List(items)) if items.len() == 2));
+        assert_eq!(doc.get("after").and_then(Yaml::as_str), Some("c"));
+        assert!(Yaml::parse("key: \"bad \\q\"").is_err());
+        assert!(Yaml::parse("not a key\n").is_err());
+        assert_eq!(Yaml::parse("# nothing\n").unwrap(), Yaml::Map(vec![]));
+    }
+
+    #[test]
+    fn regex_constructs() {
+        let matches =
+            |pattern: &str, text: &str| Regex::new(pattern, false).unwrap().is_match(text);
+        // literals match anywhere unless anchored
+        assert!(matches("abc", "xxabcxx") && !matches("abd", "abc"));
+        assert!(matches("^ab", "abc") && !matches("^bc", "abc"));
+        assert!(matches("bc$", "abc") && !matches("ab$", "abc"));
+        assert!(matches("^$", "") && !matches("^$", "a"));
+        // escapes and classes
+        assert!(matches(r"\d\D\w\W\s\S", "1a_ \tx") && !matches(r"\d", "abc"));
+        assert!(matches(r"a\.b\x41\t", "a.bA\t") && !matches(r"a\.b", "axb"));
+        assert!(matches("a.c", "abc") && !matches("a.c", "ac"));
+        assert!(matches("^[a-c_]+$", "ab_c") && !matches("^[a-c]+$", "abd"));
+        assert!(matches("^[^a-c]$", "d") && !matches("^[^a-c]$", "b"));
+        assert!(matches(r"^[\d.-]+$", "1.2-3") && matches("^[]a]$", "]"));
+        // groups, alternatives and repeats
+        assert!(matches("^(?:ab|cd)+$", "abcdab") && !matches("^(ab|cd)+$", "abc"));
+        assert!(matches("^a|b$", "axx") && matches("^a|b$", "xxb"));
+        assert!(matches("^ab*c$", "ac") && matches("^ab*c$", "abbbc"));
+        assert!(matches("^ab+c$", "abc") && !matches("^ab+c$", "ac"));
+        assert!(matches("^ab?c$", "ac") && !matches("^ab?c$", "abbc"));
+        assert!(matches("^a{2}$", "aa") && !matches("^a{2}$", "aaa"));
+        assert!(matches("^a{2,}$", "aaaa") && !matches("^a{2,}$", "a"));
+        assert!(matches("^a{1,2}$", "aa") && !matches("^a{1,2}$", "aaa"));
+        assert!(matches("^a*?b$", "aab") && matches("^a{x}$", "a{x}"));
+        assert!(matches("^(a*)*$", "aaa") && matches("^(|a)+$", "aa"));
+        // case folding
+        let re = Regex::new(r"^[a-z]\w*\.(?:log|t.p){1,2}$", true).unwrap();
+        assert!(re.is_match("Debug.LOG") && re.is_match("x.tmptmp"));
+        assert!(!re.is_match("1x.log") && !re.is_match("x.logs"));
+        for bad in ["(a", "a)", "[a", r"\b", "a\\", r"\x4", "a{100000}"] {
+            assert!(Regex::new(bad, false).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn regex_is_linear() {
+        let re = Regex::new("^(a|aa)*$", false).unwrap();
+        assert!(!re.is_match(&format!("{}b", "a".repeat(0x1000))));
+        let re = Regex::new(r".*\.exe", false).unwrap();
+        assert!(!re.is_match(&"a".repeat(200_000)));
+        assert!(re.is_match(&format!("{}.exe", "a".repeat(0x1000))));
+    }
+
+    #[test]
+    fn counts_features() {
+        let rule = |features: &str| {
+            Rule::parse(&format!(
+                "rule:\n  meta:\n    name: test\n  features:\n{}",
+                features
+            ))
+            .unwrap()
+        };
+        let ws = VivWorkspace::new("", false);
+        let rules = RuleSet::default();
+        let scan = Scan::new(&ws, &rules);
+        let features = Features {
+            apis: vec![
+                "kernel32.CreateFileW".to_string(),
+                "CreateFileA".to_string(),
+            ],
+            characteristics: vec!["calls from"; 3],
+            numbers: vec![1, 2],
+            ..Features::default()
+        };
+        let eval = |features_yaml: &str| scan.eval(&rule(features_yaml).node, &[], &features);
+        assert!(eval("    - count(api(CreateFile)): 2\n"));
+        assert!(!eval("    - count(api(CreateFile)): 3\n"));
+        assert!(eval("    - count(calls from): 2 or more\n"));
+        assert!(!eval(
+            "    - count(characteristic(calls from)): 2 or fewer\n"
+        ));
+        assert!(eval("    - count(characteristic(calls from)): (1, 3)\n"));
+        assert!(!eval("    - count(number(3)): 1 or more\n"));
+        assert!(eval(
+            "    - 2 or more:\n      - number: 1\n      - number: 2\n      - number: 3\n"
+        ));
+        assert!(!eval(
+            "    - 2 or more:\n      - number: 1\n      - number: 3\n"
+        ));
+        assert!(eval("    - optional:\n      - number: 3\n"));
+        assert!(Rule::parse(
+            "rule:\n  meta:\n    name: x\n  features:\n    - x or more:\n      - number: 1\n"
+        )
+        .is_err());
+        assert!(Rule::parse(
+            "rule:\n  meta:\n    name: x\n  features:\n    - count(number(1)): (1 2)\n"
+        )
+        .is_err());
+        // an even number of bytes, but not of hex digits
+        assert!(
+            Rule::parse("rule:\n  meta:\n    name: x\n  features:\n    - bytes: a\u{e9}1\n")
+                .is_err()
+        );
+        assert_eq!(parse_bytes("4d 5a"), Ok(vec![0x4d, 0x5a]));
+    }
+
+    #[test]
+    fn finds_capabilities() {
+        let rule = Rule::parse(CREATE_FILE).unwrap();
+        assert_eq!(rule.scope, Scope::Function);
+        assert_eq!(rule.attack, ["Execution::Shared Modules [T1129]"]);
+        // file scoped rules go last, after the rules they match on
+        let rules = RuleSet::new(vec![Rule::parse(LOGGER).unwrap(), rule]).unwrap();
+        assert_eq!(rules.rules()[1].name, "log to a file");
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_meta("Platform", Some("windows".to_string()));
+        ws.arch = ARCH_I386 as u32;
+        let fname = ws.add_file("Cargo.toml", 0x1000, vec![]);
+        ws.add_segment(0x1000, 0x100, ".text", fname.clone());
+        ws.add_memory_map(0x1000, MM_RWX, &fname, vec![0x90; 0x100], None);
+        ws.add_memory_map(0x3000, MM_READ, &fname, b"C:\\out.Log\0".to_vec(), None);
+        ws.add_location(0x3000, 11, LOC_STRING, Some(vec![]));
+        ws.make_import(0x2000, "kernel32", "CreateFileW");
+        ws.add_function(0x1000, vec![(0x1000, 0x40)]);
+        ws.add_function(0x1040, vec![(0x1040, 0x40)]);
+        ws.add_xref(0x1004, 0x3000, REF_DATA, 0);
+        ws.add_xref(0x1010, 0x2000, REF_CODE, BR_PROC);
+        ws.add_xref(0x1020, 0x1040, REF_CODE, BR_PROC);
+        // one call is not enough
+        ws.add_xref(0x1044, 0x3000, REF_DATA, 0);
+        ws.add_xref(0x1050, 0x2000, REF_CODE, BR_PROC);
+
+        let caps = find_capabilities(&ws, &rules);
+        assert_eq!(caps.functions.len(), 1);
+        assert!(caps.functions[&0x1000].contains("write file on Windows"));
+        assert_eq!(
+            caps.file.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["log to a file", "write file on Windows"]
+        );
+    }
+}
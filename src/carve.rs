@@ -0,0 +1,462 @@
+//! Carving through the compression and filesystems of firmware update images.
+//!
+//! An update image is rarely the code itself: it's a gzip'd cpio initramfs, a squashfs root
+//! filesystem, an xz'd kernel after a uImage header. [`scan`] finds the compressed streams and
+//! container filesystems in a blob by their magic, [`decompress`] opens the streams, and
+//! [`executables`] walks down through both to the ELF, PE and Mach-O files inside, for the
+//! loader to take from there.
+//!
+//! Each decompressor is behind a feature of its own, `gzip`, `xz`, `lz4` and `zstd`, or all of
+//! them with `compression`; without it the stream is found but not opened. squashfs is only
+//! detected, its superblock read for the size and compression; its files need unsquashfs.
+
+use std::{fmt, ops::Range};
+
+/// How large a decompressed stream may get, so a bomb can't take the process down
+pub const DEFAULT_LIMIT: usize = 256 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    /// The LZ4 frame format
+    Lz4,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+impl Compression {
+    const ALL: [Compression; 4] = [
+        Compression::Gzip,
+        Compression::Xz,
+        Compression::Lz4,
+        Compression::Zstd,
+    ];
+
+    pub fn magic(&self) -> &'static [u8] {
+        match self {
+            // the deflate method byte too, which cuts down on false hits
+            Compression::Gzip => &[0x1f, 0x8b, 0x08],
+            Compression::Xz => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            Compression::Lz4 => &[0x04, 0x22, 0x4d, 0x18],
+            Compression::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+
+    /// The compression `bytes` start with
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        Compression::ALL
+            .into_iter()
+            .find(|compression| bytes.starts_with(compression.magic()))
+    }
+}
+
+/// Read `reader` to the end or to `limit` bytes, whichever comes first
+#[allow(dead_code)]
+pub(crate) fn read_limited<R: std::io::Read>(reader: R, limit: usize) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    reader
+        .take(limit as u64)
+        .read_to_end(&mut out)
+        .map_err(|err| err.to_string())?;
+    Ok(out)
+}
+
+/// A writer taking up to `limit` bytes and failing past them, for decompressors which write
+/// their output rather than being read
+#[cfg(feature = "xz")]
+struct LimitedWriter {
+    out: Vec<u8>,
+    limit: usize,
+}
+
+#[cfg(feature = "xz")]
+impl std::io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.limit - self.out.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(std::io::Error::other("decompression limit reached"));
+        }
+        self.out.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompress the stream at the start of `bytes`, up to `limit` bytes of output. Bytes after
+/// the stream are ignored. Fails if the stream is corrupt or its decompressor isn't built in.
+pub fn decompress(compression: Compression, bytes: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => read_limited(flate2::read::GzDecoder::new(bytes), limit),
+        #[cfg(feature = "xz")]
+        Compression::Xz => {
+            let mut input = bytes;
+            let mut out = LimitedWriter {
+                out: Vec::new(),
+                limit,
+            };
+            match lzma_rs::xz_decompress(&mut input, &mut out) {
+                // past the limit, what came before it is the output, as for the other streams
+                Err(_) if out.out.len() == limit => Ok(out.out),
+                result => result.map(|_| out.out).map_err(|err| err.to_string()),
+            }
+        }
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => read_limited(lz4_flex::frame::FrameDecoder::new(bytes), limit),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let decoder =
+                ruzstd::decoding::StreamingDecoder::new(bytes).map_err(|err| err.to_string())?;
+            read_limited(decoder, limit)
+        }
+        #[allow(unreachable_patterns)]
+        compression => {
+            let _ = (bytes, limit);
+            Err(format!(
+                "{} support isn't built in, enable the `{}` feature",
+                compression, compression
+            ))
+        }
+    }
+}
+
+/// A file of a cpio archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpioEntry {
+    pub name: String,
+    pub mode: u32,
+    /// Where its data is in the archive
+    pub data: Range<usize>,
+}
+
+impl CpioEntry {
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+}
+
+const CPIO_NEWC: &[u8] = b"070701";
+const CPIO_CRC: &[u8] = b"070702";
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// The entries of a `newc` cpio archive, the format of Linux initramfs images, up to the
+/// trailer. None if `bytes` doesn't start with one.
+pub fn cpio_entries(bytes: &[u8]) -> Option<Vec<CpioEntry>> {
+    if !bytes.starts_with(CPIO_NEWC) && !bytes.starts_with(CPIO_CRC) {
+        return None;
+    }
+    let field = |header: &[u8], index: usize| {
+        let digits = std::str::from_utf8(&header[6 + index * 8..6 + index * 8 + 8]).ok()?;
+        u32::from_str_radix(digits, 16).ok()
+    };
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = bytes.get(offset..offset + CPIO_HEADER)?;
+        if !header.starts_with(CPIO_NEWC) && !header.starts_with(CPIO_CRC) {
+            return None;
+        }
+        let mode = field(header, 1)?;
+        let size = field(header, 6)? as usize;
+        let name_size = field(header, 11)? as usize;
+        let name = bytes.get(offset + CPIO_HEADER..offset + CPIO_HEADER + name_size)?;
+        let name = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(name)).to_string();
+        let start = (offset + CPIO_HEADER + name_size).next_multiple_of(4);
+        if name == CPIO_TRAILER {
+            return Some(entries);
+        }
+        let end = start.checked_add(size).filter(|end| *end <= bytes.len())?;
+        entries.push(CpioEntry {
+            name,
+            mode,
+            data: start..end,
+        });
+        offset = end.next_multiple_of(4);
+    }
+}
+
+/// The superblock of a squashfs filesystem
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Squashfs {
+    pub inodes: u32,
+    pub block_size: u32,
+    /// The compression of its blocks, `gzip`, `xz`, `zstd` and so on
+    pub compression: &'static str,
+    pub version: (u16, u16),
+    /// The size of the filesystem in bytes
+    pub bytes_used: u64,
+}
+
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Read the squashfs superblock at the start of `bytes`, a version 4 little endian one
+pub fn squashfs(bytes: &[u8]) -> Option<Squashfs> {
+    if !bytes.starts_with(SQUASHFS_MAGIC) || bytes.len() < 96 {
+        return None;
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let version = (u16_at(28), u16_at(30));
+    let block_size = u32_at(12);
+    // the block size is a power of two and block_log its log
+    if version.0 != 4
+        || !block_size.is_power_of_two()
+        || block_size.trailing_zeros() != u16_at(22) as u32
+    {
+        return None;
+    }
+    let compression = match u16_at(20) {
+        1 => "gzip",
+        2 => "lzma",
+        3 => "lzo",
+        4 => "xz",
+        5 => "lz4",
+        6 => "zstd",
+        _ => "unknown",
+    };
+    Some(Squashfs {
+        inodes: u32_at(4),
+        block_size,
+        compression,
+        version,
+        bytes_used: u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Found {
+    Compressed(Compression),
+    Cpio,
+    Squashfs(Squashfs),
+}
+
+impl fmt::Display for Found {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Found::Compressed(compression) => write!(f, "{}", compression),
+            Found::Cpio => f.write_str("cpio"),
+            Found::Squashfs(fs) => {
+                write!(f, "squashfs ({}, {} bytes)", fs.compression, fs.bytes_used)
+            }
+        }
+    }
+}
+
+/// The compressed streams and filesystems in `bytes`, by offset. Stream magics are short and a
+/// hit may be chance; [`executables`] finds out by opening them.
+pub fn scan(bytes: &[u8]) -> Vec<(usize, Found)> {
+    let mut found = Vec::new();
+    for offset in 0..bytes.len() {
+        let rest = &bytes[offset..];
+        if let Some(compression) = Compression::detect(rest) {
+            found.push((offset, Found::Compressed(compression)));
+        } else if rest.starts_with(CPIO_NEWC) || rest.starts_with(CPIO_CRC) {
+            // only an archive's first header, not those of every file after it
+            if found.last().is_none_or(|(_, last)| *last != Found::Cpio)
+                && cpio_entries(rest).is_some()
+            {
+                found.push((offset, Found::Cpio));
+            }
+        } else if let Some(fs) = squashfs(rest) {
+            found.push((offset, Found::Squashfs(fs)));
+        }
+    }
+    found
+}
+
+/// An executable file format, by its magic
+pub fn is_executable(bytes: &[u8]) -> bool {
+    const MAGICS: [&[u8]; 6] = [
+        b"\x7fELF",
+        b"MZ",
+        &[0xfe, 0xed, 0xfa, 0xce],
+        &[0xfe, 0xed, 0xfa, 0xcf],
+        &[0xce, 0xfa, 0xed, 0xfe],
+        &[0xcf, 0xfa, 0xed, 0xfe],
+    ];
+    MAGICS.iter().any(|magic| bytes.starts_with(magic))
+}
+
+/// An executable found inside a blob
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Carved {
+    /// How it was reached: `0x40:gzip/cpio:bin/busybox` is the file `bin/busybox` of the cpio
+    /// archive in the gzip stream at 0x40 of the blob
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Every executable inside `bytes`, through compressed streams and cpio archives up to `depth`
+/// levels down. Streams that don't open, for a false hit or a decompressor that isn't built
+/// in, are skipped.
+pub fn executables(bytes: &[u8], depth: usize) -> Vec<Carved> {
+    let mut carved = Vec::new();
+    carve_into(bytes, String::new(), depth, &mut carved);
+    carved
+}
+
+fn carve_into(bytes: &[u8], path: String, depth: usize, carved: &mut Vec<Carved>) {
+    if depth == 0 {
+        return;
+    }
+    for (offset, found) in scan(bytes) {
+        let rest = &bytes[offset..];
+        match found {
+            Found::Compressed(compression) => {
+                let Ok(inner) = decompress(compression, rest, DEFAULT_LIMIT) else {
+                    continue;
+                };
+                let path = format!("{}{:#x}:{}/", path, offset, compression);
+                if is_executable(&inner) {
+                    carved.push(Carved {
+                        path: path.trim_end_matches('/').to_string(),
+                        bytes: inner.clone(),
+                    });
+                }
+                carve_into(&inner, path, depth - 1, carved);
+            }
+            Found::Cpio => {
+                for entry in cpio_entries(rest).unwrap_or_default() {
+                    let data = &rest[entry.data.clone()];
+                    if entry.is_file() && is_executable(data) {
+                        carved.push(Carved {
+                            path: format!("{}{:#x}:cpio:{}", path, offset, entry.name),
+                            bytes: data.to_vec(),
+                        });
+                    }
+                }
+            }
+            Found::Squashfs(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    fn cpio(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let trailer = (CPIO_TRAILER, 0, &[][..]);
+        for (name, mode, data) in files.iter().copied().chain([trailer]) {
+            out.extend(CPIO_NEWC);
+            for value in [
+                0,
+                mode,
+                0,
+                0,
+                1,
+                0,
+                data.len() as u32,
+                0,
+                0,
+                0,
+                0,
+                name.len() as u32 + 1,
+                0,
+            ] {
+                out.extend(format!("{:08x}", value).as_bytes());
+            }
+            out.extend(name.as_bytes());
+            out.push(0);
+            out.resize(out.len().next_multiple_of(4), 0);
+            out.extend(data);
+            out.resize(out.len().next_multiple_of(4), 0);
+        }
+        out
+    }
+
+    #[test]
+    fn carve_firmware() {
+        let elf = b"\x7fELF\x01\x01\x01";
+        let archive = cpio(&[
+            ("bin", 0o040755, b""),
+            ("bin/busybox", 0o100755, elf),
+            ("etc/passwd", 0o100644, b"root:x:0:0"),
+        ]);
+        let entries = cpio_entries(&archive).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(&archive[entries[1].data.clone()], elf);
+
+        let mut superblock = vec![0u8; 96];
+        superblock[..4].copy_from_slice(SQUASHFS_MAGIC);
+        superblock[12..16].copy_from_slice(&0x20000u32.to_le_bytes());
+        superblock[20] = 4;
+        superblock[22] = 17;
+        superblock[28] = 4;
+        superblock[40] = 0x60;
+
+        // a gzip stream of a single stored deflate block holding the archive
+        let mut gzip = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff, 0x01];
+        let len = archive.len() as u16;
+        gzip.extend(len.to_le_bytes());
+        gzip.extend((!len).to_le_bytes());
+        gzip.extend(&archive);
+        gzip.extend(crc32(&archive).to_le_bytes());
+        gzip.extend((archive.len() as u32).to_le_bytes());
+
+        let mut blob = vec![0xffu8; 0x40];
+        blob.extend(&gzip);
+        blob.extend(&superblock);
+        let found = scan(&blob);
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0], (0x40, Found::Compressed(Compression::Gzip)));
+        // a stored block leaves the archive readable in the stream
+        assert_eq!(found[1], (0x4f, Found::Cpio));
+        assert_eq!(found[2].0, 0x40 + gzip.len());
+        assert_eq!(found[2].1.to_string(), "squashfs (xz, 96 bytes)");
+
+        let paths: Vec<String> = executables(&blob, 4)
+            .into_iter()
+            .inspect(|carved| assert_eq!(carved.bytes, elf))
+            .map(|carved| carved.path)
+            .collect();
+        if cfg!(feature = "gzip") {
+            assert_eq!(
+                paths,
+                ["0x40:gzip/0x0:cpio:bin/busybox", "0x4f:cpio:bin/busybox"]
+            );
+        } else {
+            assert_eq!(paths, ["0x4f:cpio:bin/busybox"]);
+            assert!(decompress(Compression::Gzip, &gzip, DEFAULT_LIMIT).is_err());
+        }
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_limit() {
+        let data = vec![0u8; 1 << 20];
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut data.as_slice(), &mut xz).unwrap();
+        assert_eq!(decompress(Compression::Xz, &xz, 100).unwrap(), &data[..100]);
+        assert_eq!(decompress(Compression::Xz, &xz, DEFAULT_LIMIT).unwrap(), data);
+    }
+}
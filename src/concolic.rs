@@ -0,0 +1,272 @@
+//! Concolic exploration of lifted functions.
+//!
+//! [`explore`] runs a function on the IL interpreter (see [`crate::ilemu`]) from concrete
+//! registers and memory, some of the registers being inputs. A run goes down one path, and the
+//! symbolic engine gives what that path requires of the inputs (see
+//! [`solver::path_constraints`]), every other register held at the value it ran with. Negating
+//! what one branch requires and solving gives inputs which go the other way at it, and those
+//! are run in turn. This is a generational search: the run a flip leads to only flips the
+//! branches after that one, so no path is asked for twice.
+//!
+//! Loads are free inputs to the solver, so the inputs it comes up with are guesses until run;
+//! the runs are what say where execution goes. [`reach`] explores until a run gets to a block.
+
+use crate::{
+    envi::registers::{RegId, RegisterContext},
+    ilemu::{Fault, Interpreter, Ram, Stop},
+    solver::{self, path_constraints, Answer, Constraint},
+    symbolic::{Expr, Function, Terminator},
+};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How many runs to make at most
+    pub max_runs: usize,
+    /// How many blocks a run goes through before it is stopped
+    pub max_blocks: usize,
+    /// The conflicts each solver query may run into
+    pub budget: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_runs: 64,
+            max_blocks: 10_000,
+            budget: solver::DEFAULT_BUDGET,
+        }
+    }
+}
+
+/// One run of the function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Run {
+    /// The values the inputs started with
+    pub inputs: BTreeMap<RegId, u64>,
+    /// The blocks gone through
+    pub path: Vec<u64>,
+    pub stop: Result<Stop, Fault>,
+}
+
+/// Run `func` from `registers` and `memory` down as many paths as the inputs can lead to
+pub fn explore(
+    func: &Function,
+    registers: &RegisterContext,
+    memory: &Ram,
+    inputs: &[RegId],
+    options: &Options,
+) -> Vec<Run> {
+    search(func, registers, memory, inputs, options, |_| false)
+}
+
+/// Look for values of the inputs which get `func` to the block at `target`
+pub fn reach(
+    func: &Function,
+    registers: &RegisterContext,
+    memory: &Ram,
+    inputs: &[RegId],
+    target: u64,
+    options: &Options,
+) -> Option<Run> {
+    let reached = |run: &Run| run.path.contains(&target);
+    search(func, registers, memory, inputs, options, reached)
+        .into_iter()
+        .find(reached)
+}
+
+fn search(
+    func: &Function,
+    registers: &RegisterContext,
+    memory: &Ram,
+    inputs: &[RegId],
+    options: &Options,
+    done: impl Fn(&Run) -> bool,
+) -> Vec<Run> {
+    let model = registers.model();
+    let width = func.arch.pointer_size() as u32 * 8;
+    let mask = u64::MAX >> (64 - width);
+    // The program counter is left out, it reads differently in each instruction
+    let pins: Vec<Constraint> = model
+        .full_registers()
+        .filter(|(reg, register)| {
+            !inputs.contains(reg) && *reg != model.pc() && register.width <= width
+        })
+        .map(|(reg, _)| {
+            let value = registers.get(reg) as u64 & mask;
+            Constraint::Equal(Expr::Reg(reg), Expr::Const(value))
+        })
+        .collect();
+    let run = |values: BTreeMap<RegId, u64>| {
+        let mut registers = registers.clone();
+        for (reg, value) in &values {
+            registers.set(*reg, (*value).into());
+        }
+        let mut memory = memory.clone();
+        let mut path = Vec::new();
+        let stop = Interpreter::new(&mut registers, &mut memory).trace(
+            func,
+            options.max_blocks,
+            &mut path,
+        );
+        Run {
+            inputs: values,
+            path,
+            stop,
+        }
+    };
+
+    let initial = inputs
+        .iter()
+        .map(|reg| (*reg, registers.get(*reg) as u64 & mask))
+        .collect();
+    // The inputs to run, and the first branch of the path the run may flip
+    let mut pending = VecDeque::from([(initial, 0)]);
+    let mut tried = BTreeSet::new();
+    let mut runs = Vec::new();
+    while let Some((values, bound)) = pending.pop_front() {
+        if runs.len() >= options.max_runs {
+            break;
+        }
+        let run = run(values);
+        for j in 1..run.path.len() {
+            tried.insert(run.path[..=j].to_vec());
+        }
+        for j in bound..run.path.len().saturating_sub(1) {
+            let Terminator::Branch {
+                taken, fallthrough, ..
+            } = func.blocks[&run.path[j]].end
+            else {
+                continue;
+            };
+            let other = if run.path[j + 1] == taken {
+                fallthrough
+            } else {
+                taken
+            };
+            let mut flipped = run.path[..=j].to_vec();
+            flipped.push(other);
+            if !tried.insert(flipped.clone()) {
+                continue;
+            }
+            let (Some(before), Some(mut constraints)) = (
+                path_constraints(func, &run.path[..=j]),
+                path_constraints(func, &flipped),
+            ) else {
+                continue;
+            };
+            // A branch on flags set before the function isn't up to the inputs
+            if constraints.len() == before.len() {
+                continue;
+            }
+            constraints.extend(pins.iter().cloned());
+            if let Answer::Sat(solution) = solver::solve(func.arch, &constraints, options.budget) {
+                let values = inputs
+                    .iter()
+                    .map(|reg| {
+                        let value = solution.registers.get(reg).unwrap_or(&run.inputs[reg]);
+                        (*reg, *value)
+                    })
+                    .collect();
+                pending.push_back((values, j + 1));
+            }
+        }
+        let stop = done(&run);
+        runs.push(run);
+        if stop {
+            break;
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::{flags::Condition, Arch},
+        symbolic::{BinOp, Block, FlagOp, Insn, Stmt},
+    };
+
+    #[test]
+    fn guarded_block() {
+        // imul eax, eax, 3; add eax, ebx; cmp eax, 0x100; jne out
+        // cmp ecx, 7; jae out
+        // mov dword [0x3000], 1
+        let registers = RegisterContext::new(Arch::I386);
+        let model = registers.model();
+        let (eax, ebx, ecx) = (
+            model.by_name("eax").unwrap(),
+            model.by_name("ebx").unwrap(),
+            model.by_name("ecx").unwrap(),
+        );
+        let mut registers = registers.clone();
+        registers.set(ebx, 1);
+        registers.set(ecx, 100);
+        let insn = |va, stmts| Insn { va, stmts };
+        let block = |va: u64, insns, end| Block {
+            va,
+            insns,
+            end_va: va + 8,
+            end,
+        };
+        let scaled = Expr::binary(BinOp::Mul, Expr::Reg(eax), Expr::Const(3), 32);
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![
+                insn(
+                    0x1000,
+                    vec![Stmt::Set(
+                        eax,
+                        Expr::binary(BinOp::Add, scaled, Expr::Reg(ebx), 32),
+                    )],
+                ),
+                insn(
+                    0x1005,
+                    vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(0x100))],
+                ),
+            ],
+            Terminator::Branch {
+                cond: Condition::NotZero,
+                taken: 0x1200,
+                fallthrough: 0x1100,
+            },
+        ));
+        func.add_block(block(
+            0x1100,
+            vec![insn(
+                0x1100,
+                vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(ecx), Expr::Const(7))],
+            )],
+            Terminator::Branch {
+                cond: Condition::NoCarry,
+                taken: 0x1200,
+                fallthrough: 0x1300,
+            },
+        ));
+        func.add_block(block(0x1200, vec![], Terminator::Return));
+        func.add_block(block(
+            0x1300,
+            vec![insn(
+                0x1300,
+                vec![Stmt::Store(Expr::Const(0x3000), Expr::Const(1))],
+            )],
+            Terminator::Return,
+        ));
+        let mut memory = Ram::new();
+        memory.map(0x3000, vec![0; 4]);
+
+        let options = Options::default();
+        let run = reach(&func, &registers, &memory, &[eax, ecx], 0x1300, &options).unwrap();
+        assert_eq!(run.path, [0x1000, 0x1100, 0x1300]);
+        assert_eq!(run.inputs[&eax], 0x55);
+        assert!(run.inputs[&ecx] < 7);
+        assert_eq!(run.stop, Ok(Stop::Return { va: 0x1308 }));
+
+        let runs = explore(&func, &registers, &memory, &[eax, ecx], &options);
+        let paths: BTreeSet<_> = runs.iter().map(|run| run.path.clone()).collect();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(paths.len(), 3);
+    }
+}
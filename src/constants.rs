@@ -88,11 +88,13 @@ pub const API_ARG_START: i32 = 4;
 pub const REF_CODE: i32 = 1; // A branch/call
 pub const REF_DATA: i32 = 2; // A memory dereference
 pub const REF_PTR: i32 = 3; // A pointer immediate (may be in operand *or* part of LOC_PTR)
+pub const REF_CONST: i32 = 4; // A watched immediate constant, the "to" being its value
 
 pub enum RefTypeNames {
     RefCode,
     RefData,
     RefPtr,
+    RefConst,
 }
 
 //NOTE: The flag values for RefCode are the envi.BR_FOO flags
@@ -163,6 +165,11 @@ pub const ARCH_THUMB16: i32 = 4 << 16;
 pub const ARCH_THUMB: i32 = 5 << 16;
 pub const ARCH_MSP430: i32 = 6 << 16;
 pub const ARCH_H8: i32 = 7 << 16;
+pub const ARCH_A64: i32 = 8 << 16;
+pub const ARCH_SPARC: i32 = 9 << 16;
+pub const ARCH_SPARC64: i32 = 10 << 16;
+pub const ARCH_S390X: i32 = 11 << 16;
+pub const ARCH_I8086: i32 = 12 << 16;
 pub const ARCH_MASK: u32 = 0xffff0000; // Masked; into IF_FOO and BR_FOO values
 
 // pub const ARCH_NAMES: Vec<(i32, &str)> = vec![
@@ -173,7 +180,12 @@ pub const ARCH_MASK: u32 = 0xffff0000; // Masked; into IF_FOO and BR_FOO values
 //     (ARCH_THUMB16, "thumb16"),
 //     (ARCH_THUMB, "thumb"),
 //     (ARCH_MSP430, "msp430"),
-//     (ARCH_H8, "h8")
+//     (ARCH_H8, "h8"),
+//     (ARCH_A64, "a64"),
+//     (ARCH_SPARC, "sparc"),
+//     (ARCH_SPARC64, "sparc64"),
+//     (ARCH_S390X, "s390x"),
+//     (ARCH_I8086, "i8086")
 // ];
 //
 // pub const ARCH_BY_NAME: Vec<(&str, i32)> = vec![
@@ -187,7 +199,14 @@ pub const ARCH_MASK: u32 = 0xffff0000; // Masked; into IF_FOO and BR_FOO values
 //     ("thumb", ARCH_THUMB),
 //     ("thumb2", ARCH_THUMB),
 //     ("msp430", ARCH_MSP430),
-//     ("h8", ARCH_H8)
+//     ("h8", ARCH_H8),
+//     ("a64", ARCH_A64),
+//     ("aarch64", ARCH_A64),
+//     ("sparc", ARCH_SPARC),
+//     ("sparcv9", ARCH_SPARC64),
+//     ("sparc64", ARCH_SPARC64),
+//     ("s390x", ARCH_S390X),
+//     ("i8086", ARCH_I8086)
 // ];
 
 // Instruction flags (The first 8 bits are reserved for arch independant use std::collections::HashMap;
@@ -242,6 +261,7 @@ pub const EXP_DATA: i32 = 1;
 pub const RTYPE_BASERELOC: i32 = 0; // VA contains a pointer to a va (and is assumed fixed up by parser)
 pub const RTYPE_BASEOFF: i32 = 1; // Add Base and Offset to a pointer at a memory location
 pub const RTYPE_BASEPTR: i32 = 2; // Like BASEOFF, but treated as a Pointer, not part of an instruction/assets.
+pub const RTYPE_FIXUP: i32 = 3; // Bytes patched at link or load time which aren't a pointer, eg. a pc relative displacement
 
 pub const REBASE_TYPES: (i32, i32) = (RTYPE_BASEOFF, RTYPE_BASEPTR);
 
@@ -0,0 +1,619 @@
+//! Symbolic names for the constants of disassembly and reports.
+//!
+//! `0x22e004` is a Windows IOCTL code, `-0x16` returned from a Linux kernel routine is
+//! `-EINVAL` and `socket(2, 1, 6)` is `socket(AF_INET, SOCK_STREAM, IPPROTO_TCP)`, but only where
+//! the constant is used as one. A [`ConstantDecoder`] names a value given the [`Site`] using it:
+//! the target OS and architecture, and whether the value is an argument of a call to an API, a
+//! compare operand, a returned value or a system call number. [`Decoders`] holds the decoders in
+//! use, [`Decoders::standard`] those of this module and any added with [`Decoders::add`], the
+//! first to name a value winning.
+//!
+//! [`annotate`] comments the compares and returns of the lifted functions of a workspace, the
+//! recovered call sites (see [`crate::callargs`]) and the system call sites (see
+//! [`crate::syscalls`]) with the names, which the listing and reports show.
+
+use crate::{
+    callargs::CallSite,
+    driver::Ioctl,
+    envi::Arch,
+    symbolic::{return_value, Expr, FlagOp, Stmt, Terminator},
+    syscalls::{syscall_name, Inventory, Via},
+    workspace::VivWorkspace,
+};
+use std::collections::BTreeMap;
+
+/// How a constant is used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Use<'a> {
+    /// Argument `index` of a call to `api`, by the bare name it is imported by
+    Argument { api: &'a str, index: usize },
+    /// The second operand of a compare
+    Compare,
+    /// The value a function returns
+    Return,
+    /// The number of a system call instruction
+    Syscall,
+}
+
+/// Where a constant is used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Site<'a> {
+    /// The lowercased `Platform` of the workspace, `windows`, `linux` or `darwin`
+    pub os: &'a str,
+    pub arch: Option<Arch>,
+    pub usage: Use<'a>,
+}
+
+impl Site<'_> {
+    fn is_windows(&self) -> bool {
+        self.os == "windows"
+    }
+
+    fn is_darwin(&self) -> bool {
+        matches!(self.os, "darwin" | "macos" | "ios")
+    }
+
+    fn argument(&self) -> Option<(&str, usize)> {
+        match self.usage {
+            Use::Argument { api, index } => Some((api, index)),
+            _ => None,
+        }
+    }
+}
+
+pub trait ConstantDecoder {
+    fn name(&self) -> &str;
+
+    /// The symbolic form of `value` used at `site`, if this decoder knows it
+    fn decode(&self, value: u64, site: &Site<'_>) -> Option<String>;
+}
+
+/// IOCTL codes: `CTL_CODE(...)` on Windows, `_IOR(...)` and the terminal requests on Linux
+pub struct IoctlDecoder;
+
+const LINUX_IOCTLS: &[(u64, &str)] = &[
+    (0x5401, "TCGETS"),
+    (0x5402, "TCSETS"),
+    (0x540b, "TCFLSH"),
+    (0x540e, "TIOCSCTTY"),
+    (0x540f, "TIOCGPGRP"),
+    (0x5410, "TIOCSPGRP"),
+    (0x5413, "TIOCGWINSZ"),
+    (0x5414, "TIOCSWINSZ"),
+    (0x541b, "FIONREAD"),
+    (0x5421, "FIONBIO"),
+    (0x5451, "FIOCLEX"),
+    (0x5452, "FIOASYNC"),
+];
+
+impl ConstantDecoder for IoctlDecoder {
+    fn name(&self) -> &str {
+        "ioctl"
+    }
+
+    fn decode(&self, value: u64, site: &Site<'_>) -> Option<String> {
+        let windows = match site.usage {
+            Use::Argument {
+                api: "DeviceIoControl",
+                index: 1,
+            }
+            | Use::Argument {
+                api: "NtDeviceIoControlFile" | "ZwDeviceIoControlFile" | "NtFsControlFile",
+                index: 5,
+            } => true,
+            Use::Compare => site.is_windows() && Ioctl::plausible(value),
+            Use::Argument { api: "ioctl", index: 1 } if !site.is_windows() => {
+                return linux_ioctl(value);
+            }
+            _ => false,
+        };
+        (windows && value <= u32::MAX as u64).then(|| Ioctl(value as u32).to_string())
+    }
+}
+
+/// A Linux ioctl request: one of [`LINUX_IOCTLS`], or an `_IOC` encoding with its direction,
+/// type character, number and argument size
+fn linux_ioctl(value: u64) -> Option<String> {
+    if let Some((_, name)) = LINUX_IOCTLS.iter().find(|(code, _)| *code == value) {
+        return Some(name.to_string());
+    }
+    let value = u32::try_from(value).ok()?;
+    let kind = (value >> 8) as u8;
+    let size = (value >> 16) & 0x3fff;
+    let macro_name = match value >> 30 {
+        1 => "_IOW",
+        2 => "_IOR",
+        3 => "_IOWR",
+        _ => return None,
+    };
+    (kind.is_ascii_graphic() && size != 0).then(|| {
+        format!(
+            "{}('{}', {:#x}, {:#x})",
+            macro_name, kind as char, value & 0xff, size
+        )
+    })
+}
+
+/// `errno` values: the argument of `strerror`, and the negated codes system calls and kernel
+/// routines return, as compared and returned
+pub struct ErrnoDecoder;
+
+/// The codes Linux and Darwin share
+const ERRNO: &[(u64, &str)] = &[
+    (1, "EPERM"),
+    (2, "ENOENT"),
+    (3, "ESRCH"),
+    (4, "EINTR"),
+    (5, "EIO"),
+    (6, "ENXIO"),
+    (7, "E2BIG"),
+    (8, "ENOEXEC"),
+    (9, "EBADF"),
+    (10, "ECHILD"),
+    (12, "ENOMEM"),
+    (13, "EACCES"),
+    (14, "EFAULT"),
+    (15, "ENOTBLK"),
+    (16, "EBUSY"),
+    (17, "EEXIST"),
+    (18, "EXDEV"),
+    (19, "ENODEV"),
+    (20, "ENOTDIR"),
+    (21, "EISDIR"),
+    (22, "EINVAL"),
+    (23, "ENFILE"),
+    (24, "EMFILE"),
+    (25, "ENOTTY"),
+    (26, "ETXTBSY"),
+    (27, "EFBIG"),
+    (28, "ENOSPC"),
+    (29, "ESPIPE"),
+    (30, "EROFS"),
+    (31, "EMLINK"),
+    (32, "EPIPE"),
+    (33, "EDOM"),
+    (34, "ERANGE"),
+];
+
+const LINUX_ERRNO: &[(u64, &str)] = &[
+    (11, "EAGAIN"),
+    (35, "EDEADLK"),
+    (36, "ENAMETOOLONG"),
+    (37, "ENOLCK"),
+    (38, "ENOSYS"),
+    (39, "ENOTEMPTY"),
+    (40, "ELOOP"),
+    (95, "EOPNOTSUPP"),
+    (97, "EAFNOSUPPORT"),
+    (98, "EADDRINUSE"),
+    (99, "EADDRNOTAVAIL"),
+    (104, "ECONNRESET"),
+    (110, "ETIMEDOUT"),
+    (111, "ECONNREFUSED"),
+    (115, "EINPROGRESS"),
+];
+
+const DARWIN_ERRNO: &[(u64, &str)] = &[
+    (11, "EDEADLK"),
+    (35, "EAGAIN"),
+    (36, "EINPROGRESS"),
+    (47, "EAFNOSUPPORT"),
+    (48, "EADDRINUSE"),
+    (49, "EADDRNOTAVAIL"),
+    (54, "ECONNRESET"),
+    (60, "ETIMEDOUT"),
+    (61, "ECONNREFUSED"),
+    (62, "ELOOP"),
+    (63, "ENAMETOOLONG"),
+    (66, "ENOTEMPTY"),
+    (77, "ENOLCK"),
+    (78, "ENOSYS"),
+    (102, "EOPNOTSUPP"),
+];
+
+/// The largest code a system call returns negated
+const MAX_ERRNO: u64 = 4095;
+
+impl ErrnoDecoder {
+    fn errno(code: u64, site: &Site<'_>) -> Option<&'static str> {
+        let extra = if site.is_darwin() {
+            DARWIN_ERRNO
+        } else {
+            LINUX_ERRNO
+        };
+        lookup(ERRNO, code).or_else(|| lookup(extra, code))
+    }
+}
+
+impl ConstantDecoder for ErrnoDecoder {
+    fn name(&self) -> &str {
+        "errno"
+    }
+
+    fn decode(&self, value: u64, site: &Site<'_>) -> Option<String> {
+        if site.is_windows() {
+            return None;
+        }
+        match site.usage {
+            Use::Argument {
+                api: "strerror",
+                index: 0,
+            } => ErrnoDecoder::errno(value, site).map(str::to_string),
+            Use::Compare | Use::Return => {
+                // negative at 64 or at 32 bits
+                let code = [value.wrapping_neg(), (value as u32).wrapping_neg() as u64]
+                    .into_iter()
+                    .find(|code| (1..=MAX_ERRNO).contains(code))?;
+                ErrnoDecoder::errno(code, site).map(|name| format!("-{}", name))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The address families, socket types, protocols, option levels and shutdown modes of the
+/// socket API
+pub struct SocketDecoder;
+
+const IPPROTO: &[(u64, &str)] = &[
+    (0, "IPPROTO_IP"),
+    (1, "IPPROTO_ICMP"),
+    (6, "IPPROTO_TCP"),
+    (17, "IPPROTO_UDP"),
+    (41, "IPPROTO_IPV6"),
+    (58, "IPPROTO_ICMPV6"),
+    (255, "IPPROTO_RAW"),
+];
+
+const SOCK: &[(u64, &str)] = &[
+    (1, "SOCK_STREAM"),
+    (2, "SOCK_DGRAM"),
+    (3, "SOCK_RAW"),
+    (4, "SOCK_RDM"),
+    (5, "SOCK_SEQPACKET"),
+];
+
+/// The flags Linux takes in the type of a socket
+const LINUX_SOCK_FLAGS: &[(u64, &str)] = &[(0x800, "SOCK_NONBLOCK"), (0x8_0000, "SOCK_CLOEXEC")];
+
+impl SocketDecoder {
+    fn family(value: u64, site: &Site<'_>) -> Option<&'static str> {
+        let inet6 = if site.is_windows() {
+            23
+        } else if site.is_darwin() {
+            30
+        } else {
+            10
+        };
+        match value {
+            0 => Some("AF_UNSPEC"),
+            1 => Some("AF_UNIX"),
+            2 => Some("AF_INET"),
+            _ if value == inet6 => Some("AF_INET6"),
+            16 if site.os == "linux" => Some("AF_NETLINK"),
+            17 if site.os == "linux" => Some("AF_PACKET"),
+            _ => None,
+        }
+    }
+
+    fn socket_type(value: u64, site: &Site<'_>) -> Option<String> {
+        let mut names = vec![lookup(SOCK, value & 0xf)?];
+        let mut rest = value & !0xf;
+        if site.os == "linux" {
+            for (flag, name) in LINUX_SOCK_FLAGS {
+                if rest & flag != 0 {
+                    names.push(name);
+                    rest &= !flag;
+                }
+            }
+        }
+        (rest == 0).then(|| names.join(" | "))
+    }
+}
+
+impl ConstantDecoder for SocketDecoder {
+    fn name(&self) -> &str {
+        "socket"
+    }
+
+    fn decode(&self, value: u64, site: &Site<'_>) -> Option<String> {
+        let sol_socket = if site.os == "linux" { 1 } else { 0xffff };
+        match site.argument()? {
+            ("socket" | "WSASocketA" | "WSASocketW", 0) => {
+                SocketDecoder::family(value, site).map(str::to_string)
+            }
+            ("socket" | "WSASocketA" | "WSASocketW", 1) => SocketDecoder::socket_type(value, site),
+            ("socket" | "WSASocketA" | "WSASocketW", 2) => {
+                lookup(IPPROTO, value).map(str::to_string)
+            }
+            ("setsockopt" | "getsockopt", 1) if value == sol_socket => {
+                Some("SOL_SOCKET".to_string())
+            }
+            ("setsockopt" | "getsockopt", 1) => lookup(IPPROTO, value).map(str::to_string),
+            ("shutdown", 1) => {
+                let names = if site.is_windows() {
+                    ["SD_RECEIVE", "SD_SEND", "SD_BOTH"]
+                } else {
+                    ["SHUT_RD", "SHUT_WR", "SHUT_RDWR"]
+                };
+                names.get(value as usize).map(|name| name.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// System call numbers, by the tables of [`crate::syscalls`], at system call instructions and
+/// as the first argument of the libc `syscall()`
+pub struct SyscallDecoder;
+
+impl ConstantDecoder for SyscallDecoder {
+    fn name(&self) -> &str {
+        "syscall"
+    }
+
+    fn decode(&self, value: u64, site: &Site<'_>) -> Option<String> {
+        match site.usage {
+            Use::Syscall | Use::Argument {
+                api: "syscall",
+                index: 0,
+            } => syscall_name(site.arch?, value).map(|name| format!("SYS_{}", name)),
+            _ => None,
+        }
+    }
+}
+
+fn lookup(table: &[(u64, &'static str)], value: u64) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(code, _)| *code == value)
+        .map(|(_, name)| *name)
+}
+
+/// The decoders in use
+#[derive(Default)]
+pub struct Decoders {
+    decoders: Vec<Box<dyn ConstantDecoder>>,
+}
+
+impl Decoders {
+    pub fn new() -> Self {
+        Decoders::default()
+    }
+
+    /// The decoders of this module
+    pub fn standard() -> Self {
+        let mut decoders = Decoders::new();
+        decoders.add(Box::new(IoctlDecoder));
+        decoders.add(Box::new(ErrnoDecoder));
+        decoders.add(Box::new(SocketDecoder));
+        decoders.add(Box::new(SyscallDecoder));
+        decoders
+    }
+
+    pub fn add(&mut self, decoder: Box<dyn ConstantDecoder>) {
+        self.decoders.push(decoder);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.decoders.iter().map(|decoder| decoder.name()).collect()
+    }
+
+    /// The name the first decoder knowing `value` at `site` gives it
+    pub fn decode(&self, value: u64, site: &Site<'_>) -> Option<String> {
+        self.decoders
+            .iter()
+            .find_map(|decoder| decoder.decode(value, site))
+    }
+
+    /// `call` as it would read with its constants decoded
+    pub fn render_call(&self, call: &CallSite, os: &str, arch: Option<Arch>) -> String {
+        call.render(&|index, value| {
+            let usage = Use::Argument {
+                api: &call.api,
+                index,
+            };
+            self.decode(value, &Site { os, arch, usage })
+        })
+    }
+}
+
+/// The lowercased `Platform` of the workspace
+pub fn platform(workspace: &VivWorkspace) -> String {
+    workspace
+        .get_meta("Platform")
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// The compares with a constant and the constants returned in the lifted functions of the
+/// workspace, as (instruction, value, use)
+pub fn code_constants(workspace: &VivWorkspace) -> Vec<(u64, u64, Use<'static>)> {
+    let mut found = Vec::new();
+    for fva in workspace.get_functions_with_il() {
+        let Some(func) = workspace.get_function_il(fva) else {
+            continue;
+        };
+        let result = match return_value(func.arch) {
+            Some(Expr::Reg(reg)) => Some(reg),
+            _ => None,
+        };
+        for block in func.blocks.values() {
+            for insn in block.insns.iter() {
+                for stmt in insn.stmts.iter() {
+                    if let Stmt::Flags(FlagOp::Sub, _, Expr::Const(value)) = stmt {
+                        found.push((insn.va, *value, Use::Compare));
+                    }
+                }
+            }
+            if block.end != Terminator::Return {
+                continue;
+            }
+            // the last value the block sets the return register to
+            let returned = block.insns.iter().rev().find_map(|insn| {
+                insn.stmts.iter().rev().find_map(|stmt| match stmt {
+                    Stmt::Set(reg, value) if Some(*reg) == result => Some((insn.va, value)),
+                    _ => None,
+                })
+            });
+            if let Some((va, Expr::Const(value))) = returned {
+                found.push((va, *value, Use::Return));
+            }
+        }
+    }
+    found.sort_by_key(|(va, value, _)| (*va, *value));
+    found
+}
+
+/// Comment the workspace with the decoded constants of the compares and returns of its lifted
+/// functions, of the `calls` and of the system call instructions of `syscalls`, giving back the
+/// comments by address. Comments already there are kept, except the plain rendering
+/// [`crate::callargs::annotate`] puts on a call.
+pub fn annotate(
+    workspace: &mut VivWorkspace,
+    decoders: &Decoders,
+    calls: &[CallSite],
+    syscalls: &Inventory,
+) -> BTreeMap<u64, String> {
+    let os = platform(workspace);
+    let arch = syscalls.arch.or(Arch::from_envi(workspace.arch));
+    let mut comments: BTreeMap<u64, String> = BTreeMap::new();
+    let mut uses = code_constants(workspace);
+    uses.extend(syscalls.sites.iter().filter_map(|site| match (&site.via, site.number) {
+        (Via::Instruction, Some(number)) => Some((site.va, number, Use::Syscall)),
+        _ => None,
+    }));
+    for (va, value, usage) in uses {
+        let site = Site {
+            os: &os,
+            arch,
+            usage,
+        };
+        if let Some(name) = decoders.decode(value, &site) {
+            comments
+                .entry(va)
+                .and_modify(|comment| {
+                    comment.push_str(", ");
+                    comment.push_str(&name);
+                })
+                .or_insert(name);
+        }
+    }
+    for (va, comment) in comments.iter() {
+        workspace.set_comment(*va as i32, comment, true);
+    }
+    for call in calls {
+        let rendered = decoders.render_call(call, &os, arch);
+        let plain = call.to_string();
+        let existing = workspace.get_comments().get(&(call.call as i32)).cloned();
+        if rendered != plain && existing.is_none_or(|comment| comment == plain) {
+            workspace.set_comment(call.call as i32, &rendered, false);
+            comments.insert(call.call, rendered);
+        }
+    }
+    comments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callargs::Value,
+        envi::registers::RegisterModel,
+        symbolic::{Block, Function, Insn},
+        syscalls::SyscallSite,
+    };
+
+    #[test]
+    fn decodes_constants() {
+        let decoders = Decoders::standard();
+        let site = |os, usage| Site {
+            os,
+            arch: Some(Arch::Amd64),
+            usage,
+        };
+        let arg = |api, index| Use::Argument { api, index };
+        let decode = |value, site: Site<'_>| decoders.decode(value, &site);
+        assert_eq!(
+            decode(0x22_2003, site("windows", arg("DeviceIoControl", 1))).as_deref(),
+            Some("CTL_CODE(0x22, 0x800, METHOD_NEITHER, FILE_ANY_ACCESS)")
+        );
+        assert_eq!(
+            decode(0x8008_5401, site("linux", arg("ioctl", 1))).as_deref(),
+            Some("_IOR('T', 0x1, 0x8)")
+        );
+        assert_eq!(
+            decode(0x5413, site("linux", arg("ioctl", 1))).as_deref(),
+            Some("TIOCGWINSZ")
+        );
+        assert_eq!(
+            decode(0xffff_ffea, site("linux", Use::Return)).as_deref(),
+            Some("-EINVAL")
+        );
+        assert_eq!(
+            decode(35, site("darwin", arg("strerror", 0))).as_deref(),
+            Some("EAGAIN")
+        );
+        assert_eq!(decode(0xffff_ffea, site("windows", Use::Return)), None);
+        assert_eq!(
+            decode(0x8_0001, site("linux", arg("socket", 1))).as_deref(),
+            Some("SOCK_STREAM | SOCK_CLOEXEC")
+        );
+        assert_eq!(
+            decode(0xffff, site("windows", arg("setsockopt", 1))).as_deref(),
+            Some("SOL_SOCKET")
+        );
+        assert_eq!(
+            decode(59, site("linux", Use::Syscall)).as_deref(),
+            Some("SYS_execve")
+        );
+
+        let call = CallSite {
+            call: 0x1010,
+            api: "socket".to_string(),
+            args: vec![Value::Int(10), Value::Int(2), Value::Unknown],
+        };
+        assert_eq!(
+            decoders.render_call(&call, "linux", None),
+            "socket(AF_INET6, SOCK_DGRAM, ...)"
+        );
+        assert_eq!(
+            decoders.render_call(&call, "windows", None),
+            "socket(0xa, SOCK_DGRAM, ...)"
+        );
+
+        // a kernel routine returning -EFAULT, and a syscall instruction
+        let rax = RegisterModel::new(Arch::Amd64).by_name("rax").unwrap();
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![Insn {
+                va: 0x1000,
+                stmts: vec![Stmt::Set(rax, Expr::Const(0xffff_ffff_ffff_fff2))],
+            }],
+            end_va: 0x1007,
+            end: Terminator::Return,
+        });
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_meta("Platform", Some("linux".to_string()));
+        ws.add_function(0x1000, vec![]);
+        ws.set_function_il(0x1000, func);
+        ws.set_comment(0x1010, &call.to_string(), false);
+        let syscalls = Inventory {
+            arch: Some(Arch::Amd64),
+            sites: vec![SyscallSite {
+                va: 0x1020,
+                number: Some(60),
+                via: Via::Instruction,
+            }],
+        };
+        let comments = annotate(&mut ws, &decoders, &[call], &syscalls);
+        assert_eq!(comments.len(), 3);
+        assert_eq!(ws.get_comment(0x1000), "-EFAULT");
+        assert_eq!(ws.get_comment(0x1010), "socket(AF_INET6, SOCK_DGRAM, ...)");
+        assert_eq!(
+            comments.get(&0x1020).map(String::as_str),
+            Some("SYS_exit")
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Cortex-M firmware: the vector table and named peripherals.
+//!
+//! A Cortex-M image starts with its vector table, the initial stack pointer and then the
+//! handlers, Thumb addresses with the low bit set: reset, the faults, SVCall, PendSV, SysTick
+//! and the interrupts of the part. [`load`] maps a raw image at its base as Thumb code and seeds
+//! a named function at every handler, `Reset_Handler` as the entry point.
+//!
+//! The code of such firmware talks to its peripherals through fixed addresses. A
+//! [`PeripheralMap`], from the SVD file of the part or built by hand, names them, `USART1.DR`
+//! rather than `0x40011004`: [`annotate`] comments the references the workspace has to them and
+//! [`mmio_accesses`] finds the loads and stores of a lifted function that hit them.
+
+use crate::{
+    constants::{ARCH_THUMB, MM_RWX, REF_CODE},
+    memory::Memory,
+    symbolic::{Expr, Function, State, Stmt},
+    workspace::VivWorkspace,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// The system exceptions, by vector number. Numbers from 16 are the interrupts of the part.
+pub const EXCEPTION_NAMES: [Option<&str>; 16] = [
+    None,
+    Some("Reset"),
+    Some("NMI"),
+    Some("HardFault"),
+    Some("MemManage"),
+    Some("BusFault"),
+    Some("UsageFault"),
+    None,
+    None,
+    None,
+    None,
+    Some("SVC"),
+    Some("DebugMon"),
+    None,
+    Some("PendSV"),
+    Some("SysTick"),
+];
+
+/// At most 240 interrupts after the system exceptions
+pub const MAX_VECTORS: usize = 16 + 240;
+
+/// The CMSIS name of the handler of vector `number`
+pub fn handler_name(number: usize) -> String {
+    match EXCEPTION_NAMES.get(number) {
+        Some(Some(name)) => format!("{}_Handler", name),
+        Some(None) => format!("Reserved{}_Handler", number),
+        None => format!("IRQ{}_Handler", number - 16),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VectorTable {
+    pub initial_sp: u32,
+    /// The handlers set, by vector number, Thumb bit and all
+    pub handlers: BTreeMap<usize, u32>,
+}
+
+impl VectorTable {
+    /// Parse the vector table at the start of `bytes`. It ends before the first entry that's
+    /// neither empty nor a Thumb address, or before `max` entries; None if the reset vector
+    /// isn't a Thumb address.
+    pub fn parse(bytes: &[u8], max: usize) -> Option<Self> {
+        let mut words = bytes
+            .chunks_exact(4)
+            .take(max.min(MAX_VECTORS))
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        let initial_sp = words.next()?;
+        let mut handlers = BTreeMap::new();
+        for (number, handler) in words.enumerate().map(|(i, word)| (i + 1, word)) {
+            // the reserved slots of the system exceptions are often used as a checksum
+            if number < 16 && EXCEPTION_NAMES[number].is_none() {
+                continue;
+            }
+            match handler {
+                0 => {}
+                handler if handler & 1 == 1 => {
+                    handlers.insert(number, handler);
+                }
+                _ => break,
+            }
+        }
+        handlers.get(&1)?;
+        Some(VectorTable {
+            initial_sp,
+            handlers,
+        })
+    }
+
+    /// The address the core starts running at
+    pub fn reset(&self) -> u32 {
+        self.handlers[&1] & !1
+    }
+}
+
+/// Load a raw Cortex-M image at `base` into the workspace and seed its handlers. Returns the
+/// name of the file, as the parsers do, or None if the image doesn't start with a vector table.
+pub fn load(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    bytes: Vec<u8>,
+    base: u32,
+) -> Option<(String, VectorTable)> {
+    let table = VectorTable::parse(&bytes, MAX_VECTORS)?;
+    workspace.set_meta("Architecture", Some(ARCH_THUMB.to_string()));
+    workspace.set_meta("Platform", Some("cortex-m".to_string()));
+    workspace.set_meta("Format", Some("blob".to_string()));
+    workspace.set_mem_architecture(ARCH_THUMB as u32);
+    workspace.set_pointer_size(4);
+    let size = bytes.len() as i32;
+    let fname = workspace.add_file(filename, base as i32, bytes.clone());
+    workspace.add_memory_map(base as i32, MM_RWX, &fname, bytes, None);
+    workspace.add_segment(base as i32, size, "flash", fname.clone());
+    seed_handlers(workspace, &table);
+    Some((fname, table))
+}
+
+/// Add an entry point at every handler in the workspace, named after the first vector using
+/// it. Handlers shared by several vectors (a `Default_Handler`) get a comment listing them.
+pub fn seed_handlers(workspace: &mut VivWorkspace, table: &VectorTable) {
+    let mut vectors: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    for (number, handler) in table.handlers.iter() {
+        vectors.entry(handler & !1).or_default().push(*number);
+    }
+    for (va, numbers) in vectors {
+        let va = va as i32;
+        if !workspace.is_valid_pointer(va) {
+            continue;
+        }
+        workspace.add_entry_point(va);
+        if workspace.get_name(va, false).is_none() {
+            let name = if numbers.len() == 1 {
+                handler_name(numbers[0])
+            } else {
+                "Default_Handler".to_string()
+            };
+            workspace.make_name(va, name, false, true);
+        }
+        if numbers.len() > 1 {
+            let names: Vec<String> = numbers.into_iter().map(handler_name).collect();
+            workspace.set_comment(va, &format!("vectors: {}", names.join(", ")), false);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub name: String,
+    pub offset: u32,
+    /// In bytes
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peripheral {
+    pub name: String,
+    pub base: u32,
+    /// The size of its address block, in bytes
+    pub size: u32,
+    pub registers: Vec<Register>,
+}
+
+/// The peripherals of a part, by base address
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeripheralMap {
+    peripherals: BTreeMap<u32, Peripheral>,
+}
+
+impl PeripheralMap {
+    pub fn new() -> Self {
+        PeripheralMap::default()
+    }
+
+    pub fn add(&mut self, peripheral: Peripheral) {
+        self.peripherals.insert(peripheral.base, peripheral);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peripherals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peripherals.is_empty()
+    }
+
+    /// The peripheral whose address block holds `addr`
+    pub fn peripheral(&self, addr: u32) -> Option<&Peripheral> {
+        self.peripherals
+            .range(..=addr)
+            .next_back()
+            .map(|(_, peripheral)| peripheral)
+            .filter(|peripheral| addr - peripheral.base < peripheral.size)
+    }
+
+    /// The name of `addr`: `USART1.DR` for a register, `USART1+0x1c` elsewhere in a peripheral
+    pub fn name(&self, addr: u32) -> Option<String> {
+        let peripheral = self.peripheral(addr)?;
+        let offset = addr - peripheral.base;
+        let name = match peripheral
+            .registers
+            .iter()
+            .find(|reg| offset >= reg.offset && offset - reg.offset < reg.size.max(1))
+        {
+            Some(reg) if reg.offset == offset => format!("{}.{}", peripheral.name, reg.name),
+            Some(reg) => format!(
+                "{}.{}+{:#x}",
+                peripheral.name,
+                reg.name,
+                offset - reg.offset
+            ),
+            None => format!("{}+{:#x}", peripheral.name, offset),
+        };
+        Some(name)
+    }
+
+    /// Read the peripherals of a CMSIS SVD file: their names, base addresses, address blocks
+    /// and registers. Clusters, arrays and `derivedFrom` aren't followed; a derived peripheral
+    /// keeps its own registers, usually none.
+    pub fn from_svd(svd: &str) -> Result<Self, String> {
+        let mut map = PeripheralMap::new();
+        let peripherals = elements(svd, "peripherals")
+            .into_iter()
+            .next()
+            .ok_or("No peripherals in the SVD file")?;
+        for body in elements(peripherals, "peripheral") {
+            let (own, registers) = match body.find("<registers>") {
+                Some(at) => body.split_at(at),
+                None => (body, ""),
+            };
+            let name = text(own, "name").ok_or("Peripheral without a name")?;
+            let base = text(own, "baseAddress")
+                .and_then(parse_number)
+                .ok_or_else(|| format!("Peripheral {} without a base address", name))?;
+            let size = elements(own, "addressBlock")
+                .into_iter()
+                .filter_map(|block| {
+                    let offset = text(block, "offset").and_then(parse_number)?;
+                    let size = text(block, "size").and_then(parse_number)?;
+                    Some(offset + size)
+                })
+                .max();
+            let mut regs = Vec::new();
+            for reg in elements(registers, "register") {
+                let reg_name = text(reg, "name")
+                    .ok_or_else(|| format!("Register of {} without a name", name))?;
+                let offset = text(reg, "addressOffset")
+                    .and_then(parse_number)
+                    .ok_or_else(|| format!("Register {}.{} without an offset", name, reg_name))?;
+                let bits = text(reg, "size").and_then(parse_number).unwrap_or(32);
+                regs.push(Register {
+                    name: reg_name.to_string(),
+                    offset,
+                    size: bits.div_ceil(8),
+                });
+            }
+            regs.sort_by_key(|reg| reg.offset);
+            let size = size
+                .or_else(|| regs.iter().map(|reg| reg.offset + reg.size).max())
+                .unwrap_or(0x400);
+            map.add(Peripheral {
+                name: name.to_string(),
+                base,
+                size,
+                registers: regs,
+            });
+        }
+        Ok(map)
+    }
+}
+
+/// The bodies of the `<tag>` elements of `xml`, outermost first. Enough XML for SVD files, whose
+/// elements carry nothing we need in attributes.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}", tag), format!("</{}>", tag));
+    let mut bodies = Vec::new();
+    let mut rest = xml;
+    while let Some(at) = rest.find(&open) {
+        let after = &rest[at + open.len()..];
+        // <register> but not <registers>
+        if !after.starts_with(['>', ' ', '\t', '\r', '\n']) {
+            rest = after;
+            continue;
+        }
+        let Some(gt) = after.find('>') else {
+            break;
+        };
+        let body = &after[gt + 1..];
+        let Some(end) = body.find(&close) else {
+            break;
+        };
+        bodies.push(&body[..end]);
+        rest = &body[end + close.len()..];
+    }
+    bodies
+}
+
+fn text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    elements(xml, tag).into_iter().next().map(str::trim)
+}
+
+/// A number of an SVD file: decimal, `0x` hex or `#` binary
+fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix('#') {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Comment every reference of the workspace into a peripheral with its name. Returns how many
+/// were commented.
+pub fn annotate(workspace: &mut VivWorkspace, map: &PeripheralMap) -> usize {
+    let mut count = 0;
+    for (from, to, rtype, _) in workspace.get_xrefs(None) {
+        if rtype == REF_CODE {
+            continue;
+        }
+        if let Some(name) = map.name(to as u32) {
+            workspace.set_comment(from, &name, false);
+            count += 1;
+        }
+    }
+    count
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmioAccess {
+    pub va: u64,
+    pub addr: u32,
+    pub name: String,
+    pub write: bool,
+}
+
+impl fmt::Display for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = if self.write { "write" } else { "read" };
+        write!(f, "{:#x}: {} {}", self.va, op, self.name)
+    }
+}
+
+/// The loads and stores of a lifted function at constant addresses in a peripheral, in block
+/// order. Addresses are worked out as far as the block and its single predecessor go, which
+/// covers the base in a register plus an offset that firmware code is made of.
+pub fn mmio_accesses(func: &Function, map: &PeripheralMap) -> Vec<MmioAccess> {
+    let preds = func.predecessors();
+    let states = func.block_states();
+    let mut accesses = Vec::new();
+    for va in func.reverse_postorder() {
+        let mut state = match preds.get(&va).map(Vec::as_slice) {
+            Some([pred]) if *pred != va => states
+                .get(pred)
+                .cloned()
+                .unwrap_or_else(|| State::new(func.arch)),
+            _ => State::new(func.arch),
+        };
+        for insn in func.blocks[&va].insns.iter() {
+            for stmt in insn.stmts.iter() {
+                let mut addrs = Vec::new();
+                match stmt {
+                    Stmt::Set(_, value) => value.loads(&mut addrs),
+                    Stmt::Store(addr, value) => {
+                        addr.loads(&mut addrs);
+                        value.loads(&mut addrs);
+                    }
+                    Stmt::Flags(_, a, b) => {
+                        a.loads(&mut addrs);
+                        b.loads(&mut addrs);
+                    }
+                    Stmt::Unknown => {}
+                }
+                let mut found: Vec<(Expr, bool)> =
+                    addrs.into_iter().map(|addr| (addr, false)).collect();
+                if let Stmt::Store(addr, _) = stmt {
+                    found.push((addr.clone(), true));
+                }
+                for (addr, write) in found {
+                    let Some(addr) = state.eval(&addr).as_const() else {
+                        continue;
+                    };
+                    let Ok(addr) = u32::try_from(addr) else {
+                        continue;
+                    };
+                    if let Some(name) = map.name(addr) {
+                        accesses.push(MmioAccess {
+                            va: insn.va,
+                            addr,
+                            name,
+                            write,
+                        });
+                    }
+                }
+                state.exec(stmt);
+            }
+        }
+    }
+    accesses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::{registers::RegisterModel, Arch},
+        symbolic::{BinOp, Block, Insn, Terminator},
+    };
+
+    const SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<device schemaVersion="1.1">
+  <name>STM32F4</name>
+  <peripherals>
+    <peripheral>
+      <name>USART1</name>
+      <baseAddress>0x40011000</baseAddress>
+      <addressBlock><offset>0x0</offset><size>0x400</size><usage>registers</usage></addressBlock>
+      <registers>
+        <register><name>SR</name><addressOffset>0x0</addressOffset><size>0x20</size></register>
+        <register><name>DR</name><addressOffset>0x4</addressOffset><size>32</size></register>
+      </registers>
+    </peripheral>
+    <peripheral derivedFrom="USART1">
+      <name>USART2</name>
+      <baseAddress>0x40004400</baseAddress>
+    </peripheral>
+  </peripherals>
+</device>"#;
+
+    #[test]
+    fn cortexm_firmware() {
+        // stack, reset at 0x40, NMI and HardFault sharing a handler, a checksum in the reserved
+        // slot 7, then IRQ0
+        let mut image = vec![0u8; 0x60];
+        for (i, word) in [
+            0x2000_8000u32,
+            0x0800_0041,
+            0x0800_0051,
+            0x0800_0051,
+            0,
+            0,
+            0,
+            0xdead_beef,
+        ]
+        .iter()
+        .enumerate()
+        {
+            image[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        image[16 * 4..16 * 4 + 4].copy_from_slice(&0x0800_0059u32.to_le_bytes());
+        let table = VectorTable::parse(&image, MAX_VECTORS).unwrap();
+        assert_eq!(table.initial_sp, 0x2000_8000);
+        assert_eq!(table.reset(), 0x0800_0040);
+        assert_eq!(
+            table.handlers.keys().copied().collect::<Vec<_>>(),
+            [1, 2, 3, 16]
+        );
+        assert_eq!(handler_name(16), "IRQ0_Handler");
+
+        let mut ws = VivWorkspace::new("", false);
+        load(&mut ws, "Cargo.toml", image, 0x0800_0000).unwrap();
+        assert_eq!(
+            ws.get_name(0x0800_0040, false).as_deref(),
+            Some("Reset_Handler")
+        );
+        assert_eq!(
+            ws.get_name(0x0800_0050, false).as_deref(),
+            Some("Default_Handler")
+        );
+        assert!(ws
+            .get_va_set_rows("EntryPoints")
+            .unwrap()
+            .contains(&0x0800_0040));
+
+        let map = PeripheralMap::from_svd(SVD).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.name(0x4001_1004).as_deref(), Some("USART1.DR"));
+        assert_eq!(map.name(0x4001_1006).as_deref(), Some("USART1.DR+0x2"));
+        assert_eq!(map.name(0x4001_1100).as_deref(), Some("USART1+0x100"));
+        assert_eq!(map.name(0x4000_4400).as_deref(), Some("USART2+0x0"));
+        assert_eq!(map.name(0x4001_1400), None);
+
+        // ldr r0, =USART1; ldr r1, [r0, #0]; str r1, [r0, #4]
+        let regs = RegisterModel::new(Arch::Thumb);
+        let (r0, r1) = (regs.by_name("r0").unwrap(), regs.by_name("r1").unwrap());
+        let r0_plus = |offset| Expr::binary(BinOp::Add, Expr::Reg(r0), Expr::Const(offset), 32);
+        let mut func = Function::new(Arch::Thumb, 0x0800_0040);
+        func.add_block(Block {
+            va: 0x0800_0040,
+            insns: vec![
+                Insn {
+                    va: 0x0800_0040,
+                    stmts: vec![Stmt::Set(r0, Expr::Const(0x4001_1000))],
+                },
+                Insn {
+                    va: 0x0800_0042,
+                    stmts: vec![Stmt::Set(r1, Expr::Load(Box::new(Expr::Reg(r0))))],
+                },
+                Insn {
+                    va: 0x0800_0044,
+                    stmts: vec![Stmt::Store(r0_plus(4), Expr::Reg(r1))],
+                },
+            ],
+            end_va: 0x0800_0044,
+            end: Terminator::Return,
+        });
+        let accesses: Vec<String> = mmio_accesses(&func, &map)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            accesses,
+            ["0x8000042: read USART1.SR", "0x8000044: write USART1.DR"]
+        );
+    }
+}
@@ -0,0 +1,598 @@
+//! Coverage from execution traces.
+//!
+//! A trace says where a run of the program went. [`parse_drcov`] reads the coverage files of
+//! DynamoRIO's drcov, and of the tools writing its format (Lighthouse, TinyInst, frida), in
+//! their binary and text flavors; [`parse_address_log`] reads logs of one address per line, as
+//! a branch log or Intel PT decoded by `perf script -F ip` gives. A [`Trace`] keeps addresses
+//! relative to the modules it names, if it names any.
+//!
+//! [`cover`] maps a trace onto a workspace, rebasing each module onto the file of the same name
+//! (the workspace may have loaded it elsewhere), and counts the hits by the block covered,
+//! the blocks being those of the lifted functions, or else the workspace's code blocks.
+//! [`mark`] lists the blocks covered in the `Coverage` va set, and [`Coverage::untouched`]
+//! gives the functions no run went into, for confirming dead code. [`Coverage::diff`] sets
+//! two coverages side by side, giving the regions of each function only one of them went
+//! through, as for triaging a crash against a run that didn't crash.
+
+use crate::{symbolic::Function, workspace::VivWorkspace};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A module a trace names
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Module {
+    pub id: u16,
+    pub base: u64,
+    pub end: u64,
+    pub path: String,
+}
+
+impl Module {
+    /// The name the workspace would know the module's file by: the file name without its
+    /// extension, from a Unix or Windows path
+    pub fn name(&self) -> &str {
+        let file = self.path.rsplit(['/', '\\']).next().unwrap_or(&self.path);
+        file.split_once('.').map_or(file, |(stem, _)| stem)
+    }
+}
+
+/// A basic block a run went through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hit {
+    /// The index of the module in [`Trace::modules`], or None for an absolute address
+    pub module: Option<usize>,
+    /// The address, from the base of the module if there is one
+    pub offset: u64,
+    /// How many bytes were run, if known
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub modules: Vec<Module>,
+    pub hits: Vec<Hit>,
+}
+
+fn number(text: &str) -> Option<u64> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => u64::from_str_radix(text, 16).ok(),
+    }
+}
+
+/// The next line of `bytes` from `at`, moving `at` past it
+fn line<'a>(bytes: &'a [u8], at: &mut usize) -> Option<&'a str> {
+    let rest = bytes.get(*at..).filter(|rest| !rest.is_empty())?;
+    let len = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+    *at += (len + 1).min(rest.len());
+    std::str::from_utf8(&rest[..len]).ok().map(str::trim_end)
+}
+
+/// Parse a drcov file. The module table is read by its `Columns` header where it has one,
+/// as version 1 files don't; the block table is either binary (`u32` offset, `u16` size,
+/// `u16` module id each) or text (`module[  1]: 0x1234, 8`).
+pub fn parse_drcov(bytes: &[u8]) -> Result<Trace, String> {
+    let mut at = 0;
+    let header = line(bytes, &mut at).unwrap_or_default();
+    if !header.starts_with("DRCOV VERSION") {
+        return Err("not a drcov file".to_string());
+    }
+    let mut trace = Trace::default();
+    let mut ids = BTreeMap::new();
+    let mut columns = vec!["id", "base", "end", "entry", "path"];
+    let mut count = None;
+    while let Some(text) = line(bytes, &mut at) {
+        if let Some(table) = text.strip_prefix("Module Table:") {
+            let n = table.rsplit([' ', ',']).next().unwrap_or_default();
+            count = Some(n.trim().parse::<usize>().map_err(|err| err.to_string())?);
+        } else if let Some(names) = text.strip_prefix("Columns:") {
+            columns = names.split(',').map(str::trim).collect();
+        } else if let Some(table) = text.strip_prefix("BB Table:") {
+            let n: usize = table
+                .split_whitespace()
+                .next()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| format!("bad block table: {}", table))?;
+            let end = n
+                .checked_mul(8)
+                .and_then(|len| at.checked_add(len))
+                .ok_or_else(|| format!("bad block table: {}", table))?;
+            let binary = bytes.get(at..end);
+            let text_table = bytes
+                .get(at..)
+                .is_some_and(|rest| rest.starts_with(b"module"));
+            match binary {
+                Some(entries) if !text_table => {
+                    for entry in entries.chunks_exact(8) {
+                        let offset = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                        let size = u16::from_le_bytes([entry[4], entry[5]]);
+                        let id = u16::from_le_bytes([entry[6], entry[7]]);
+                        trace.hits.push(Hit {
+                            module: ids.get(&id).copied(),
+                            offset: offset as u64,
+                            size: size as u32,
+                        });
+                    }
+                }
+                _ => {
+                    while let Some(text) = line(bytes, &mut at) {
+                        let Some((module, rest)) = text.split_once("]:") else {
+                            continue;
+                        };
+                        let id = module.trim_start_matches("module[").trim().parse::<u16>();
+                        let (offset, size) = rest.split_once(',').unwrap_or((rest, "0"));
+                        trace.hits.push(Hit {
+                            module: id.ok().and_then(|id| ids.get(&id).copied()),
+                            offset: number(offset).ok_or_else(|| format!("bad block: {}", text))?,
+                            size: size.trim().parse().unwrap_or(0),
+                        });
+                    }
+                }
+            }
+            return Ok(trace);
+        } else if count.is_some_and(|count| trace.modules.len() < count) {
+            let fields: Vec<&str> = text.splitn(columns.len(), ',').map(str::trim).collect();
+            let field = |names: &[&str]| {
+                names
+                    .iter()
+                    .find_map(|name| columns.iter().position(|column| column == name))
+                    .and_then(|i| fields.get(i).copied())
+            };
+            let id = field(&["id"]).and_then(|id| id.parse().ok());
+            let base = field(&["base", "start"]).and_then(number);
+            let end = field(&["end"]).and_then(number);
+            let (Some(id), Some(base), Some(end)) = (id, base, end) else {
+                return Err(format!("bad module: {}", text));
+            };
+            ids.insert(id, trace.modules.len());
+            trace.modules.push(Module {
+                id,
+                base,
+                end,
+                path: field(&["path"]).unwrap_or_default().to_string(),
+            });
+        }
+    }
+    Err("no block table".to_string())
+}
+
+/// Parse a log of one address per line, in hex with or without `0x`, or as `module+offset`.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_address_log(text: &str) -> Result<Trace, String> {
+    let mut trace = Trace::default();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (module, offset) = match line.split_once('+') {
+            Some((name, offset)) => {
+                let index = match trace.modules.iter().position(|m| m.path == name) {
+                    Some(index) => index,
+                    None => {
+                        trace.modules.push(Module {
+                            id: trace.modules.len() as u16,
+                            base: 0,
+                            end: u64::MAX,
+                            path: name.to_string(),
+                        });
+                        trace.modules.len() - 1
+                    }
+                };
+                (Some(index), offset)
+            }
+            None => (None, line),
+        };
+        let offset = number(offset).ok_or_else(|| format!("bad address: {}", line))?;
+        trace.hits.push(Hit {
+            module,
+            offset,
+            size: 0,
+        });
+    }
+    Ok(trace)
+}
+
+/// A trace mapped onto a workspace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// Hits by the block covered
+    pub blocks: BTreeMap<u64, usize>,
+    /// Hits in executable memory of the workspace which no known block holds, by address
+    pub loose: BTreeMap<u64, usize>,
+    /// How many hits are in modules or memory the workspace doesn't have
+    pub unmapped: usize,
+}
+
+impl Coverage {
+    /// The functions of `functions` none of whose blocks were covered
+    pub fn untouched<'a>(&self, functions: &'a BTreeMap<u64, Function>) -> Vec<&'a Function> {
+        functions
+            .values()
+            .filter(|func| !func.blocks.keys().any(|va| self.blocks.contains_key(va)))
+            .collect()
+    }
+
+    /// How many blocks of `func` were covered
+    pub fn blocks_of(&self, func: &Function) -> usize {
+        func.blocks
+            .keys()
+            .filter(|va| self.blocks.contains_key(va))
+            .count()
+    }
+}
+
+/// The start of the block of `functions`, or else of the workspace, holding `va`
+fn block_at(workspace: &VivWorkspace, functions: &BTreeMap<u64, Function>, va: u64) -> Option<u64> {
+    functions
+        .values()
+        .find_map(|func| {
+            let (start, block) = func.blocks.range(..=va).next_back()?;
+            (va <= block.end_va).then_some(*start)
+        })
+        .or_else(|| {
+            workspace
+                .get_code_block(va as i32)
+                .map(|(bva, ..)| bva as u32 as u64)
+        })
+}
+
+/// Map `trace` onto `workspace`, counting hits by the blocks of `functions` or of the
+/// workspace. A module of the trace is rebased onto the workspace's file of the same name; a
+/// hit of no module is taken as an address in the workspace.
+pub fn cover(
+    workspace: &VivWorkspace,
+    functions: &BTreeMap<u64, Function>,
+    trace: &Trace,
+) -> Coverage {
+    let bases: BTreeMap<String, u64> = workspace
+        .get_files()
+        .into_iter()
+        .map(|file| {
+            let base = workspace.get_file_meta(&file, "imagebase") as u32 as u64;
+            (file.to_lowercase(), base)
+        })
+        .collect();
+    let executable: Vec<(u64, u64)> = workspace
+        .get_executable_maps()
+        .iter()
+        .map(|(va, bytes)| (*va as u32 as u64, bytes.len() as u64))
+        .collect();
+    let is_code = |va: u64| {
+        executable
+            .iter()
+            .any(|(start, size)| *start <= va && va - start < *size)
+    };
+    let mut coverage = Coverage::default();
+    for hit in trace.hits.iter() {
+        let va = match hit.module.map(|i| &trace.modules[i]) {
+            Some(module) => match bases.get(&module.name().to_lowercase()) {
+                Some(base) => base.wrapping_add(hit.offset),
+                None => {
+                    coverage.unmapped += 1;
+                    continue;
+                }
+            },
+            None => hit.offset,
+        };
+        if !is_code(va) {
+            coverage.unmapped += 1;
+            continue;
+        }
+        let mut covered = BTreeSet::new();
+        covered.extend(block_at(workspace, functions, va));
+        // a block of the tracer may run through several of ours
+        let end = va + hit.size as u64;
+        for func in functions.values() {
+            covered.extend(func.blocks.range(va..end).map(|(start, _)| *start));
+        }
+        if covered.is_empty() {
+            *coverage.loose.entry(va).or_default() += 1;
+        }
+        for block in covered {
+            *coverage.blocks.entry(block).or_default() += 1;
+        }
+    }
+    coverage
+}
+
+/// List the blocks covered in the `Coverage` va set of the workspace
+pub fn mark(workspace: &mut VivWorkspace, coverage: &Coverage) {
+    let mut rows = workspace.get_va_set_rows("Coverage").unwrap_or_default();
+    for va in coverage.blocks.keys() {
+        let va = *va as i32;
+        if !rows.contains(&va) {
+            rows.push(va);
+        }
+    }
+    workspace.set_va_set_row("Coverage", rows);
+}
+
+/// Blocks covered by one of two coverages and not the other, which the CFG of a function
+/// links together, or a lone block outside the functions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub function: Option<u64>,
+    pub blocks: Vec<u64>,
+}
+
+/// How two coverages differ in one function
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionDiff {
+    pub function: u64,
+    /// The blocks only the first coverage went through
+    pub only_a: Vec<u64>,
+    /// The blocks only the second coverage went through
+    pub only_b: Vec<u64>,
+    /// How many blocks both went through
+    pub both: usize,
+}
+
+impl FunctionDiff {
+    /// Whether only the first coverage went into the function
+    pub fn entered_only_by_a(&self) -> bool {
+        self.both == 0 && self.only_b.is_empty() && !self.only_a.is_empty()
+    }
+
+    /// Whether only the second coverage went into the function
+    pub fn entered_only_by_b(&self) -> bool {
+        self.both == 0 && self.only_a.is_empty() && !self.only_b.is_empty()
+    }
+}
+
+/// The difference of two coverages, say of a crashing input and a benign one
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// The functions the coverages differ in
+    pub functions: Vec<FunctionDiff>,
+    /// The regions only the first coverage went through
+    pub only_a: Vec<Region>,
+    /// The regions only the second coverage went through
+    pub only_b: Vec<Region>,
+}
+
+/// Split `unique`, the blocks of `func` covered by one side only, into the regions linked
+/// by the edges of its CFG
+fn regions(func: &Function, unique: &BTreeSet<u64>) -> Vec<Region> {
+    let mut seen = BTreeSet::new();
+    let mut found = Vec::new();
+    let preds = func.predecessors();
+    for va in unique.iter() {
+        if !seen.insert(*va) {
+            continue;
+        }
+        let mut blocks = vec![*va];
+        let mut todo = vec![*va];
+        while let Some(block) = todo.pop() {
+            let linked = func
+                .successors(block)
+                .into_iter()
+                .chain(preds.get(&block).into_iter().flatten().copied());
+            for next in linked {
+                if unique.contains(&next) && seen.insert(next) {
+                    blocks.push(next);
+                    todo.push(next);
+                }
+            }
+        }
+        blocks.sort_unstable();
+        found.push(Region {
+            function: Some(func.entry),
+            blocks,
+        });
+    }
+    found
+}
+
+impl Coverage {
+    /// Add the hits of `other`, as for several runs of one input, or of inputs alike
+    pub fn merge(&mut self, other: &Coverage) {
+        for (va, hits) in other.blocks.iter() {
+            *self.blocks.entry(*va).or_default() += hits;
+        }
+        for (va, hits) in other.loose.iter() {
+            *self.loose.entry(*va).or_default() += hits;
+        }
+        self.unmapped += other.unmapped;
+    }
+
+    /// Compare with `other` block by block, within the functions of `functions` and the
+    /// blocks they don't hold
+    pub fn diff(&self, other: &Coverage, functions: &BTreeMap<u64, Function>) -> Diff {
+        let mut diff = Diff::default();
+        let mut held = BTreeSet::new();
+        for func in functions.values() {
+            let mut only_a = BTreeSet::new();
+            let mut only_b = BTreeSet::new();
+            let mut both = 0;
+            for va in func.blocks.keys() {
+                held.insert(*va);
+                match (self.blocks.contains_key(va), other.blocks.contains_key(va)) {
+                    (true, true) => both += 1,
+                    (true, false) => {
+                        only_a.insert(*va);
+                    }
+                    (false, true) => {
+                        only_b.insert(*va);
+                    }
+                    (false, false) => {}
+                }
+            }
+            if only_a.is_empty() && only_b.is_empty() {
+                continue;
+            }
+            diff.only_a.extend(regions(func, &only_a));
+            diff.only_b.extend(regions(func, &only_b));
+            diff.functions.push(FunctionDiff {
+                function: func.entry,
+                only_a: only_a.into_iter().collect(),
+                only_b: only_b.into_iter().collect(),
+                both,
+            });
+        }
+        let lone = |a: &Coverage, b: &Coverage| -> Vec<Region> {
+            a.blocks
+                .keys()
+                .filter(|va| !held.contains(*va) && !b.blocks.contains_key(*va))
+                .map(|va| Region {
+                    function: None,
+                    blocks: vec![*va],
+                })
+                .collect()
+        };
+        diff.only_a.extend(lone(self, other));
+        diff.only_b.extend(lone(other, self));
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_I386, MM_EXEC, MM_READ},
+        envi::{flags::Condition, Arch},
+        memory::Memory,
+        symbolic::{Block, Terminator},
+    };
+
+    #[test]
+    fn drcov_and_logs() {
+        let mut file = b"DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n\
+            Module Table: version 2, count 2\n\
+            Columns: id, base, end, entry, checksum, timestamp, path\n\
+             0, 0x10000000, 0x10002000, 0x0, 0x0, 0x0, C:\\bin\\Cargo.exe\n\
+             1, 0x70000000, 0x70001000, 0x0, 0x0, 0x0, C:\\Windows\\ntdll.dll\n\
+            BB Table: 3 bbs\n"
+            .to_vec();
+        for (offset, size, id) in [(0x1000u32, 8u16, 0u16), (0x1010, 4, 0), (0x10, 2, 1)] {
+            file.extend_from_slice(&offset.to_le_bytes());
+            file.extend_from_slice(&size.to_le_bytes());
+            file.extend_from_slice(&id.to_le_bytes());
+        }
+        let trace = parse_drcov(&file).unwrap();
+        assert_eq!(trace.modules[0].name(), "Cargo");
+        assert_eq!(
+            trace.hits[1],
+            Hit {
+                module: Some(0),
+                offset: 0x1010,
+                size: 4
+            }
+        );
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        // modules are matched to workspace files by name
+        let fname = ws.add_file("Cargo.toml", 0x40_0000, Vec::new());
+        ws.add_memory_map(
+            0x40_1000,
+            MM_READ | MM_EXEC,
+            &fname,
+            vec![0x90; 0x100],
+            None,
+        );
+        let mut func = Function::new(Arch::I386, 0x40_1000);
+        for (va, end_va) in [(0x40_1000, 0x40_1004), (0x40_1006, 0x40_1008)] {
+            func.add_block(Block {
+                va,
+                insns: Vec::new(),
+                end_va,
+                end: Terminator::Return,
+            });
+        }
+        let functions = BTreeMap::from([(0x40_1000, func)]);
+        let coverage = cover(&ws, &functions, &trace);
+        assert_eq!(
+            coverage.blocks,
+            BTreeMap::from([(0x40_1000, 1), (0x40_1006, 1)])
+        );
+        assert_eq!(coverage.loose, BTreeMap::from([(0x40_1010, 1)]));
+        assert_eq!(coverage.unmapped, 1);
+        assert!(coverage.untouched(&functions).is_empty());
+        mark(&mut ws, &coverage);
+        assert_eq!(
+            ws.get_va_set_rows("Coverage"),
+            Some(vec![0x40_1000, 0x40_1006])
+        );
+
+        let log = parse_address_log("# perf script -F ip\n401006\ncargo+0x1000\n").unwrap();
+        let coverage = cover(&ws, &functions, &log);
+        assert_eq!(
+            coverage.blocks,
+            BTreeMap::from([(0x40_1000, 1), (0x40_1006, 1)])
+        );
+        let text = b"DRCOV VERSION: 2\nModule Table: 1\n0, 0x400000, 0x500000, 0x0, cargo\n\
+            BB Table: 1 bbs\nmodule[  0]: 0x1006, 2\n";
+        let coverage = cover(&ws, &functions, &parse_drcov(text).unwrap());
+        assert_eq!(coverage.blocks, BTreeMap::from([(0x40_1006, 1)]));
+
+        // a block count whose table size overflows is a format error, not a panic
+        let huge = format!("DRCOV VERSION: 2\nBB Table: {} bbs\n", usize::MAX / 4);
+        assert_eq!(
+            parse_drcov(huge.as_bytes()),
+            Err(format!("bad block table:  {} bbs", usize::MAX / 4))
+        );
+    }
+
+    #[test]
+    fn diffs() {
+        // 0x1000 branches to 0x1010, which goes on to 0x1018, or to 0x1020
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        for (va, end) in [
+            (
+                0x1000,
+                Terminator::Branch {
+                    cond: Condition::Zero,
+                    taken: 0x1010,
+                    fallthrough: 0x1020,
+                },
+            ),
+            (0x1010, Terminator::Jump(0x1018)),
+            (0x1018, Terminator::Return),
+            (0x1020, Terminator::Return),
+        ] {
+            func.add_block(Block {
+                va,
+                insns: Vec::new(),
+                end_va: va + 4,
+                end,
+            });
+        }
+        let functions = BTreeMap::from([(0x1000, func)]);
+        let covered = |blocks: &[u64]| Coverage {
+            blocks: blocks.iter().map(|va| (*va, 1)).collect(),
+            ..Default::default()
+        };
+        let mut crash = covered(&[0x1000, 0x1010]);
+        crash.merge(&covered(&[0x1000, 0x1018, 0x5000]));
+        assert_eq!(crash.blocks[&0x1000], 2);
+        let benign = covered(&[0x1000, 0x1020]);
+
+        let diff = crash.diff(&benign, &functions);
+        assert_eq!(
+            diff.functions,
+            [FunctionDiff {
+                function: 0x1000,
+                only_a: vec![0x1010, 0x1018],
+                only_b: vec![0x1020],
+                both: 1,
+            }]
+        );
+        assert_eq!(
+            diff.only_a,
+            [
+                Region {
+                    function: Some(0x1000),
+                    blocks: vec![0x1010, 0x1018],
+                },
+                Region {
+                    function: None,
+                    blocks: vec![0x5000],
+                },
+            ]
+        );
+        assert_eq!(diff.only_b.len(), 1);
+        assert!(!diff.functions[0].entered_only_by_a());
+        assert!(crash.diff(&crash, &functions).functions.is_empty());
+    }
+}
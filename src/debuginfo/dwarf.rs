@@ -0,0 +1,1362 @@
+//! DWARF, versions 2 to 5, read down to what function import needs: each subprogram with code,
+//! its ranges and entry, the names of its parameters, and the subroutines inlined into it, with
+//! the line programs of `.debug_line` for source lines.
+//!
+//! Names are followed through `DW_AT_abstract_origin` and `DW_AT_specification`, so an
+//! out-of-line instance of an inline function or a method defined outside its class gets the
+//! name of its declaration. Type units, split DWARF and location lists are not read.
+
+use super::{Function, Inlined, LineRow, Lines};
+use crate::{
+    container::{Container, Ctx},
+    elf::{
+        compression_header::{CompressionHeader, ELFCOMPRESS_ZLIB},
+        section_header::SHF_COMPRESSED,
+        Elf,
+    },
+    error::{Error, Result},
+    mach::MachO,
+};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+};
+
+pub const DW_TAG_FORMAL_PARAMETER: u64 = 0x05;
+pub const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+pub const DW_TAG_INLINED_SUBROUTINE: u64 = 0x1d;
+pub const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+
+pub const DW_AT_NAME: u64 = 0x03;
+pub const DW_AT_STMT_LIST: u64 = 0x10;
+pub const DW_AT_LOW_PC: u64 = 0x11;
+pub const DW_AT_HIGH_PC: u64 = 0x12;
+pub const DW_AT_COMP_DIR: u64 = 0x1b;
+pub const DW_AT_ABSTRACT_ORIGIN: u64 = 0x31;
+pub const DW_AT_SPECIFICATION: u64 = 0x47;
+pub const DW_AT_ENTRY_PC: u64 = 0x52;
+pub const DW_AT_RANGES: u64 = 0x55;
+pub const DW_AT_CALL_FILE: u64 = 0x58;
+pub const DW_AT_CALL_LINE: u64 = 0x59;
+pub const DW_AT_CALL_COLUMN: u64 = 0x57;
+pub const DW_AT_LINKAGE_NAME: u64 = 0x6e;
+pub const DW_AT_STR_OFFSETS_BASE: u64 = 0x72;
+pub const DW_AT_ADDR_BASE: u64 = 0x73;
+pub const DW_AT_RNGLISTS_BASE: u64 = 0x74;
+pub const DW_AT_MIPS_LINKAGE_NAME: u64 = 0x2007;
+
+pub const DW_FORM_ADDR: u64 = 0x01;
+pub const DW_FORM_BLOCK2: u64 = 0x03;
+pub const DW_FORM_BLOCK4: u64 = 0x04;
+pub const DW_FORM_DATA2: u64 = 0x05;
+pub const DW_FORM_DATA4: u64 = 0x06;
+pub const DW_FORM_DATA8: u64 = 0x07;
+pub const DW_FORM_STRING: u64 = 0x08;
+pub const DW_FORM_BLOCK: u64 = 0x09;
+pub const DW_FORM_BLOCK1: u64 = 0x0a;
+pub const DW_FORM_DATA1: u64 = 0x0b;
+pub const DW_FORM_FLAG: u64 = 0x0c;
+pub const DW_FORM_SDATA: u64 = 0x0d;
+pub const DW_FORM_STRP: u64 = 0x0e;
+pub const DW_FORM_UDATA: u64 = 0x0f;
+pub const DW_FORM_REF_ADDR: u64 = 0x10;
+pub const DW_FORM_REF1: u64 = 0x11;
+pub const DW_FORM_REF2: u64 = 0x12;
+pub const DW_FORM_REF4: u64 = 0x13;
+pub const DW_FORM_REF8: u64 = 0x14;
+pub const DW_FORM_REF_UDATA: u64 = 0x15;
+pub const DW_FORM_INDIRECT: u64 = 0x16;
+pub const DW_FORM_SEC_OFFSET: u64 = 0x17;
+pub const DW_FORM_EXPRLOC: u64 = 0x18;
+pub const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+pub const DW_FORM_STRX: u64 = 0x1a;
+pub const DW_FORM_ADDRX: u64 = 0x1b;
+pub const DW_FORM_REF_SUP4: u64 = 0x1c;
+pub const DW_FORM_STRP_SUP: u64 = 0x1d;
+pub const DW_FORM_DATA16: u64 = 0x1e;
+pub const DW_FORM_LINE_STRP: u64 = 0x1f;
+pub const DW_FORM_REF_SIG8: u64 = 0x20;
+pub const DW_FORM_IMPLICIT_CONST: u64 = 0x21;
+pub const DW_FORM_LOCLISTX: u64 = 0x22;
+pub const DW_FORM_RNGLISTX: u64 = 0x23;
+pub const DW_FORM_REF_SUP8: u64 = 0x24;
+pub const DW_FORM_STRX1: u64 = 0x25;
+pub const DW_FORM_STRX4: u64 = 0x28;
+pub const DW_FORM_ADDRX1: u64 = 0x29;
+pub const DW_FORM_ADDRX4: u64 = 0x2c;
+pub const DW_FORM_GNU_ADDR_INDEX: u64 = 0x1f01;
+pub const DW_FORM_GNU_STR_INDEX: u64 = 0x1f02;
+pub const DW_FORM_GNU_REF_ALT: u64 = 0x1f20;
+pub const DW_FORM_GNU_STRP_ALT: u64 = 0x1f21;
+
+const DW_UT_TYPE: u8 = 0x02;
+const DW_UT_SKELETON: u8 = 0x04;
+const DW_UT_SPLIT_COMPILE: u8 = 0x05;
+const DW_UT_SPLIT_TYPE: u8 = 0x06;
+
+pub const DW_LNS_COPY: u8 = 0x01;
+pub const DW_LNS_ADVANCE_PC: u8 = 0x02;
+pub const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+pub const DW_LNS_SET_FILE: u8 = 0x04;
+pub const DW_LNS_SET_COLUMN: u8 = 0x05;
+pub const DW_LNS_CONST_ADD_PC: u8 = 0x08;
+pub const DW_LNS_FIXED_ADVANCE_PC: u8 = 0x09;
+pub const DW_LNE_END_SEQUENCE: u8 = 0x01;
+pub const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+const DW_LNCT_PATH: u64 = 0x1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 0x2;
+
+const DW_RLE_END_OF_LIST: u8 = 0;
+const DW_RLE_BASE_ADDRESSX: u8 = 1;
+const DW_RLE_STARTX_ENDX: u8 = 2;
+const DW_RLE_STARTX_LENGTH: u8 = 3;
+const DW_RLE_OFFSET_PAIR: u8 = 4;
+const DW_RLE_BASE_ADDRESS: u8 = 5;
+const DW_RLE_START_END: u8 = 6;
+const DW_RLE_START_LENGTH: u8 = 7;
+
+/// The DWARF sections of a binary, decompressed where they were compressed
+#[derive(Clone, Debug, Default)]
+pub struct Sections<'a> {
+    pub info: Cow<'a, [u8]>,
+    pub abbrev: Cow<'a, [u8]>,
+    pub line: Cow<'a, [u8]>,
+    pub str: Cow<'a, [u8]>,
+    pub line_str: Cow<'a, [u8]>,
+    pub ranges: Cow<'a, [u8]>,
+    pub rnglists: Cow<'a, [u8]>,
+    pub addr: Cow<'a, [u8]>,
+    pub str_offsets: Cow<'a, [u8]>,
+    pub little_endian: bool,
+}
+
+impl<'a> Sections<'a> {
+    fn set(&mut self, name: &str, data: Cow<'a, [u8]>) {
+        let section = match name {
+            "info" => &mut self.info,
+            "abbrev" => &mut self.abbrev,
+            "line" => &mut self.line,
+            "str" => &mut self.str,
+            "line_str" => &mut self.line_str,
+            "ranges" => &mut self.ranges,
+            "rnglists" => &mut self.rnglists,
+            "addr" => &mut self.addr,
+            // Mach-O section names stop at 16 characters
+            "str_offsets" | "str_offs" => &mut self.str_offsets,
+            _ => return,
+        };
+        *section = data;
+    }
+
+    /// The `.debug_*` sections of an ELF file; `SHF_COMPRESSED` and `.zdebug_*` ones need the
+    /// `gzip` feature, and are left out without it
+    pub fn from_elf(elf: &Elf, bytes: &'a [u8]) -> Self {
+        let mut sections = Sections {
+            little_endian: elf.little_endian,
+            ..Sections::default()
+        };
+        let container = if elf.is_64 {
+            Container::Big
+        } else {
+            Container::Little
+        };
+        let ctx = Ctx::new(container, scroll::Endian::from(elf.little_endian));
+        for shdr in &elf.section_headers {
+            let Some(name) = elf.shdr_strtab.get_at(shdr.sh_name) else {
+                continue;
+            };
+            let start = shdr.sh_offset as usize;
+            let Some(data) = start
+                .checked_add(shdr.sh_size as usize)
+                .and_then(|end| bytes.get(start..end))
+            else {
+                continue;
+            };
+            if let Some(name) = name.strip_prefix(".debug_") {
+                if shdr.sh_flags & SHF_COMPRESSED as u64 == 0 {
+                    sections.set(name, Cow::Borrowed(data));
+                    continue;
+                }
+                let Ok(chdr) = CompressionHeader::parse(bytes, start, ctx) else {
+                    continue;
+                };
+                let stream = &data[CompressionHeader::size(ctx).min(data.len())..];
+                if chdr.ch_type == ELFCOMPRESS_ZLIB {
+                    if let Some(data) = inflate(stream, chdr.ch_size as usize) {
+                        sections.set(name, Cow::Owned(data));
+                    }
+                }
+            } else if let Some(name) = name.strip_prefix(".zdebug_") {
+                // "ZLIB", the size as 8 bytes big endian, then the stream
+                if data.starts_with(b"ZLIB") && data.len() >= 12 {
+                    let size = u64::from_be_bytes(data[4..12].try_into().unwrap());
+                    if let Some(data) = inflate(&data[12..], size as usize) {
+                        sections.set(name, Cow::Owned(data));
+                    }
+                }
+            }
+        }
+        sections
+    }
+
+    /// The `__DWARF,__debug_*` sections of a Mach-O file or dSYM
+    pub fn from_macho(macho: &MachO<'a>) -> Self {
+        let mut sections = Sections {
+            little_endian: macho.little_endian,
+            ..Sections::default()
+        };
+        for name in [
+            "info", "abbrev", "line", "str", "line_str", "ranges", "rnglists", "addr", "str_offs",
+        ] {
+            if let Some(data) = macho.section_data("__DWARF", &format!("__debug_{}", name)) {
+                sections.set(name, Cow::Borrowed(data));
+            }
+        }
+        sections
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info.is_empty()
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn inflate(bytes: &[u8], size: usize) -> Option<Vec<u8>> {
+    crate::carve::read_limited(flate2::read::ZlibDecoder::new(bytes), size).ok()
+}
+
+#[cfg(not(feature = "gzip"))]
+fn inflate(_bytes: &[u8], _size: usize) -> Option<Vec<u8>> {
+    None
+}
+
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize, little_endian: bool) -> Self {
+        Reader {
+            data,
+            pos,
+            little_endian,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| Error::Malformed(format!("DWARF truncated at {:#x}", self.pos)))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        let bytes = self.bytes(len)?;
+        let fold = |value: u64, byte: &u8| value << 8 | *byte as u64;
+        Ok(if self.little_endian {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        })
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    fn uleb(&mut self) -> Result<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<&'a [u8]> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        let len = rest.iter().position(|b| *b == 0).ok_or_else(|| {
+            Error::Malformed(format!("Unterminated DWARF string at {:#x}", self.pos))
+        })?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+}
+
+/// The string at `offset` of a string section
+fn cstr_at(data: &[u8], offset: u64) -> Option<String> {
+    let rest = data.get(offset as usize..)?;
+    let len = rest.iter().position(|b| *b == 0)?;
+    Some(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+struct Abbrev {
+    tag: u64,
+    children: bool,
+    /// (attribute, form, implicit constant)
+    attrs: Vec<(u64, u64, i64)>,
+}
+
+fn abbrevs(data: &[u8], offset: usize) -> Result<HashMap<u64, Abbrev>> {
+    let mut reader = Reader::new(data, offset, true);
+    let mut abbrevs = HashMap::new();
+    loop {
+        let code = reader.uleb()?;
+        if code == 0 {
+            return Ok(abbrevs);
+        }
+        let tag = reader.uleb()?;
+        let children = reader.u8()? != 0;
+        let mut attrs = Vec::new();
+        loop {
+            let (attr, form) = (reader.uleb()?, reader.uleb()?);
+            if attr == 0 && form == 0 {
+                break;
+            }
+            let implicit = match form {
+                DW_FORM_IMPLICIT_CONST => reader.sleb()?,
+                _ => 0,
+            };
+            attrs.push((attr, form, implicit));
+        }
+        abbrevs.insert(
+            code,
+            Abbrev {
+                tag,
+                children,
+                attrs,
+            },
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value<'a> {
+    Addr(u64),
+    Addrx(u64),
+    Const(u64),
+    Signed(i64),
+    Str(&'a [u8]),
+    Strp(u64),
+    LineStrp(u64),
+    Strx(u64),
+    /// A reference, from the start of `.debug_info`
+    Ref(u64),
+    SecOffset(u64),
+    Rnglistx(u64),
+    Flag(bool),
+    Other,
+}
+
+impl Value<'_> {
+    fn offset(&self) -> Option<u64> {
+        match *self {
+            Value::SecOffset(offset) | Value::Const(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    fn constant(&self) -> Option<u64> {
+        match *self {
+            Value::Const(value) => Some(value),
+            Value::Signed(value) => u64::try_from(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Unit {
+    /// Where the unit starts in `.debug_info`
+    offset: u64,
+    version: u16,
+    addr_size: usize,
+    offset_size: usize,
+    str_offsets_base: u64,
+    addr_base: u64,
+    rnglists_base: u64,
+    base_address: u64,
+}
+
+impl Unit {
+    fn read_value<'a>(
+        &self,
+        reader: &mut Reader<'a>,
+        form: u64,
+        implicit: i64,
+    ) -> Result<Value<'a>> {
+        let offset_size = self.offset_size;
+        let block = |reader: &mut Reader<'a>, len: u64| -> Result<Value<'a>> {
+            reader.bytes(len as usize)?;
+            Ok(Value::Other)
+        };
+        Ok(match form {
+            DW_FORM_ADDR => Value::Addr(reader.uint(self.addr_size)?),
+            DW_FORM_BLOCK1 => {
+                let len = reader.uint(1)?;
+                return block(reader, len);
+            }
+            DW_FORM_BLOCK2 => {
+                let len = reader.uint(2)?;
+                return block(reader, len);
+            }
+            DW_FORM_BLOCK4 => {
+                let len = reader.uint(4)?;
+                return block(reader, len);
+            }
+            DW_FORM_BLOCK | DW_FORM_EXPRLOC => {
+                let len = reader.uleb()?;
+                return block(reader, len);
+            }
+            DW_FORM_DATA1 => Value::Const(reader.uint(1)?),
+            DW_FORM_DATA2 => Value::Const(reader.uint(2)?),
+            DW_FORM_DATA4 => Value::Const(reader.uint(4)?),
+            DW_FORM_DATA8 => Value::Const(reader.uint(8)?),
+            DW_FORM_DATA16 => return block(reader, 16),
+            DW_FORM_STRING => Value::Str(reader.cstr()?),
+            DW_FORM_FLAG => Value::Flag(reader.u8()? != 0),
+            DW_FORM_FLAG_PRESENT => Value::Flag(true),
+            DW_FORM_SDATA => Value::Signed(reader.sleb()?),
+            DW_FORM_UDATA => Value::Const(reader.uleb()?),
+            DW_FORM_IMPLICIT_CONST => Value::Signed(implicit),
+            DW_FORM_STRP => Value::Strp(reader.uint(offset_size)?),
+            DW_FORM_LINE_STRP => Value::LineStrp(reader.uint(offset_size)?),
+            DW_FORM_STRP_SUP | DW_FORM_GNU_STRP_ALT | DW_FORM_GNU_REF_ALT => {
+                return block(reader, offset_size as u64)
+            }
+            DW_FORM_REF_ADDR if self.version <= 2 => Value::Ref(reader.uint(self.addr_size)?),
+            DW_FORM_REF_ADDR => Value::Ref(reader.uint(offset_size)?),
+            DW_FORM_REF1 => self.unit_ref(reader.uint(1)?, reader)?,
+            DW_FORM_REF2 => self.unit_ref(reader.uint(2)?, reader)?,
+            DW_FORM_REF4 => self.unit_ref(reader.uint(4)?, reader)?,
+            DW_FORM_REF8 => self.unit_ref(reader.uint(8)?, reader)?,
+            DW_FORM_REF_UDATA => self.unit_ref(reader.uleb()?, reader)?,
+            DW_FORM_REF_SIG8 | DW_FORM_REF_SUP8 => return block(reader, 8),
+            DW_FORM_REF_SUP4 => return block(reader, 4),
+            DW_FORM_SEC_OFFSET => Value::SecOffset(reader.uint(offset_size)?),
+            DW_FORM_INDIRECT => {
+                let form = reader.uleb()?;
+                return self.read_value(reader, form, implicit);
+            }
+            DW_FORM_STRX | DW_FORM_GNU_STR_INDEX => Value::Strx(reader.uleb()?),
+            DW_FORM_STRX1..=DW_FORM_STRX4 => {
+                Value::Strx(reader.uint((form - DW_FORM_STRX1 + 1) as usize)?)
+            }
+            DW_FORM_ADDRX | DW_FORM_GNU_ADDR_INDEX => Value::Addrx(reader.uleb()?),
+            DW_FORM_ADDRX1..=DW_FORM_ADDRX4 => {
+                Value::Addrx(reader.uint((form - DW_FORM_ADDRX1 + 1) as usize)?)
+            }
+            DW_FORM_LOCLISTX => {
+                reader.uleb()?;
+                Value::Other
+            }
+            DW_FORM_RNGLISTX => Value::Rnglistx(reader.uleb()?),
+            form => {
+                return Err(Error::Malformed(format!(
+                    "Unknown DWARF form {:#x} at {:#x}",
+                    form, reader.pos
+                )))
+            }
+        })
+    }
+
+    /// A reference `offset` bytes into the unit
+    fn unit_ref(&self, offset: u64, reader: &Reader) -> Result<Value<'static>> {
+        self.offset
+            .checked_add(offset)
+            .map(Value::Ref)
+            .ok_or_else(|| {
+                Error::Malformed(format!(
+                    "DWARF reference {:#x} out of range at {:#x}",
+                    offset, reader.pos
+                ))
+            })
+    }
+
+    fn string(&self, sections: &Sections, value: Value) -> Option<String> {
+        match value {
+            Value::Str(s) => Some(String::from_utf8_lossy(s).into_owned()),
+            Value::Strp(offset) => cstr_at(&sections.str, offset),
+            Value::LineStrp(offset) => cstr_at(&sections.line_str, offset),
+            Value::Strx(index) => {
+                let at = index
+                    .checked_mul(self.offset_size as u64)?
+                    .checked_add(self.str_offsets_base)?;
+                let mut reader =
+                    Reader::new(&sections.str_offsets, at as usize, sections.little_endian);
+                cstr_at(&sections.str, reader.uint(self.offset_size).ok()?)
+            }
+            _ => None,
+        }
+    }
+
+    fn address(&self, sections: &Sections, value: Value) -> Option<u64> {
+        match value {
+            Value::Addr(addr) => Some(addr),
+            Value::Addrx(index) => {
+                let at = index
+                    .checked_mul(self.addr_size as u64)?
+                    .checked_add(self.addr_base)?;
+                let mut reader = Reader::new(&sections.addr, at as usize, sections.little_endian);
+                reader.uint(self.addr_size).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `addr` is no address: what linkers leave for code they dropped
+    fn is_tombstone(&self, addr: u64) -> bool {
+        let max = u64::MAX >> (64 - 8 * self.addr_size as u32);
+        addr == 0 || addr >= max - 1
+    }
+
+    /// The (start, size) ranges of the ranges list at `offset`
+    fn ranges(&self, sections: &Sections, value: Value) -> Option<Vec<(u64, u64)>> {
+        let mut ranges = Vec::new();
+        let le = sections.little_endian;
+        let size = self.addr_size;
+        if self.version < 5 {
+            let mut reader = Reader::new(&sections.ranges, value.offset()? as usize, le);
+            let mut base = self.base_address;
+            let max = u64::MAX >> (64 - 8 * size as u32);
+            loop {
+                let (start, end) = (reader.uint(size).ok()?, reader.uint(size).ok()?);
+                match (start, end) {
+                    (0, 0) => return Some(ranges),
+                    (start, end) if start == max => base = end,
+                    (start, end) => {
+                        ranges.push((base.checked_add(start)?, end.saturating_sub(start)))
+                    }
+                }
+            }
+        }
+        let offset = match value {
+            Value::Rnglistx(index) => {
+                let at = index
+                    .checked_mul(self.offset_size as u64)?
+                    .checked_add(self.rnglists_base)?;
+                let mut reader = Reader::new(&sections.rnglists, at as usize, le);
+                self.rnglists_base
+                    .checked_add(reader.uint(self.offset_size).ok()?)?
+            }
+            value => value.offset()?,
+        };
+        let mut reader = Reader::new(&sections.rnglists, offset as usize, le);
+        let mut base = self.base_address;
+        let addrx = |index: u64| self.address(sections, Value::Addrx(index));
+        loop {
+            match reader.u8().ok()? {
+                DW_RLE_END_OF_LIST => return Some(ranges),
+                DW_RLE_BASE_ADDRESSX => base = addrx(reader.uleb().ok()?)?,
+                DW_RLE_STARTX_ENDX => {
+                    let start = addrx(reader.uleb().ok()?)?;
+                    let end = addrx(reader.uleb().ok()?)?;
+                    ranges.push((start, end.saturating_sub(start)));
+                }
+                DW_RLE_STARTX_LENGTH => {
+                    let start = addrx(reader.uleb().ok()?)?;
+                    ranges.push((start, reader.uleb().ok()?));
+                }
+                DW_RLE_OFFSET_PAIR => {
+                    let (start, end) = (reader.uleb().ok()?, reader.uleb().ok()?);
+                    ranges.push((base.checked_add(start)?, end.saturating_sub(start)));
+                }
+                DW_RLE_BASE_ADDRESS => base = reader.uint(size).ok()?,
+                DW_RLE_START_END => {
+                    let (start, end) = (reader.uint(size).ok()?, reader.uint(size).ok()?);
+                    ranges.push((start, end.saturating_sub(start)));
+                }
+                DW_RLE_START_LENGTH => {
+                    let start = reader.uint(size).ok()?;
+                    ranges.push((start, reader.uleb().ok()?));
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// The attributes of a DIE function import looks at
+#[derive(Default)]
+struct Attrs<'a> {
+    name: Option<Value<'a>>,
+    linkage_name: Option<Value<'a>>,
+    /// The abstract origin or the specification
+    origin: Option<u64>,
+    low_pc: Option<Value<'a>>,
+    high_pc: Option<Value<'a>>,
+    entry_pc: Option<Value<'a>>,
+    ranges: Option<Value<'a>>,
+    stmt_list: Option<Value<'a>>,
+    comp_dir: Option<Value<'a>>,
+    call_file: Option<Value<'a>>,
+    call_line: Option<Value<'a>>,
+    call_column: Option<Value<'a>>,
+}
+
+impl Attrs<'_> {
+    /// The (start, size) ranges of the code of the DIE, without those the linker dropped
+    fn ranges(&self, unit: &Unit, sections: &Sections) -> Vec<(u64, u64)> {
+        let ranges = if let Some(ranges) = self.ranges {
+            unit.ranges(sections, ranges).unwrap_or_default()
+        } else if let Some(low) = self.low_pc.and_then(|low| unit.address(sections, low)) {
+            let size = match self.high_pc {
+                Some(Value::Const(size)) => size,
+                Some(Value::Signed(size)) => size as u64,
+                Some(high) => unit
+                    .address(sections, high)
+                    .map_or(0, |high| high.saturating_sub(low)),
+                None => 0,
+            };
+            vec![(low, size)]
+        } else {
+            Vec::new()
+        };
+        ranges
+            .into_iter()
+            .filter(|(start, size)| *size != 0 && !unit.is_tombstone(*start))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Named {
+    name: Option<String>,
+    linkage_name: Option<String>,
+    origin: Option<u64>,
+}
+
+enum Scope {
+    Function(usize),
+    Inline,
+    Other,
+}
+
+struct RawFunction {
+    die: u64,
+    entry: u64,
+    ranges: Vec<(u64, u64)>,
+    params: Vec<u64>,
+    /// The DIE each inlined function's name comes from, the name yet to be resolved
+    inlined: Vec<(u64, Inlined)>,
+}
+
+/// The name and linkage name of the DIE at `offset`, following its origins
+fn resolve(names: &HashMap<u64, Named>, offset: u64) -> (Option<String>, Option<String>) {
+    let (mut name, mut linkage_name) = (None, None);
+    let mut at = Some(offset);
+    // origins chain: an inlined instance, its abstract instance, its declaration
+    for _ in 0..8 {
+        let Some(named) = at.and_then(|at| names.get(&at)) else {
+            break;
+        };
+        name = name.or_else(|| named.name.clone());
+        linkage_name = linkage_name.or_else(|| named.linkage_name.clone());
+        if name.is_some() && linkage_name.is_some() {
+            break;
+        }
+        at = named.origin;
+    }
+    (name, linkage_name)
+}
+
+/// The functions with code in the DWARF of `sections`
+pub fn functions(sections: &Sections) -> Result<Vec<Function>> {
+    Ok(read(sections)?.0)
+}
+
+/// The functions with code in the DWARF of `sections` and the rows of the line programs of its
+/// compilation units
+pub fn read(sections: &Sections) -> Result<(Vec<Function>, Lines)> {
+    let info: &[u8] = &sections.info;
+    let le = sections.little_endian;
+    let mut abbrev_tables: HashMap<u64, HashMap<u64, Abbrev>> = HashMap::new();
+    let mut names: HashMap<u64, Named> = HashMap::new();
+    let mut raw: Vec<RawFunction> = Vec::new();
+    let mut lines = Lines::default();
+    let mut offset = 0usize;
+    while offset < info.len() {
+        let mut reader = Reader::new(info, offset, le);
+        let (length, offset_size) = match reader.uint(4)? {
+            0xffff_ffff => (reader.uint(8)?, 8),
+            length => (length, 4),
+        };
+        let end = reader.pos.saturating_add(length as usize).min(info.len());
+        let version = reader.uint(2)? as u16;
+        let (unit_type, addr_size, abbrev_offset) = if version >= 5 {
+            let unit_type = reader.u8()?;
+            let addr_size = reader.u8()? as usize;
+            (unit_type, addr_size, reader.uint(offset_size)?)
+        } else {
+            let abbrev_offset = reader.uint(offset_size)?;
+            (0, reader.u8()? as usize, abbrev_offset)
+        };
+        if !(2..=5).contains(&version) || !(1..=8).contains(&addr_size) {
+            return Err(Error::Malformed(format!(
+                "Unsupported DWARF unit at {:#x}: version {}, address size {}",
+                offset, version, addr_size
+            )));
+        }
+        let unit_offset = offset as u64;
+        offset = end;
+        match unit_type {
+            DW_UT_TYPE | DW_UT_SPLIT_TYPE | DW_UT_SPLIT_COMPILE => continue,
+            DW_UT_SKELETON => {
+                reader.bytes(8)?;
+            }
+            _ => {}
+        }
+        let table = match abbrev_tables.entry(abbrev_offset) {
+            Entry::Occupied(table) => table.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(abbrevs(&sections.abbrev, abbrev_offset as usize)?)
+            }
+        };
+        let mut unit = Unit {
+            offset: unit_offset,
+            version,
+            addr_size,
+            offset_size,
+            str_offsets_base: 8,
+            addr_base: 8,
+            rnglists_base: 12,
+            base_address: 0,
+        };
+        let mut stack: Vec<Scope> = Vec::new();
+        let mut root = true;
+        // the files of the unit's line program, by index
+        let mut files: Vec<String> = Vec::new();
+        while reader.pos < end {
+            let die = reader.pos as u64;
+            let code = reader.uleb()?;
+            if code == 0 {
+                stack.pop();
+                if stack.is_empty() {
+                    break;
+                }
+                continue;
+            }
+            let abbrev = table.get(&code).ok_or_else(|| {
+                Error::Malformed(format!("Unknown DWARF abbreviation {} at {:#x}", code, die))
+            })?;
+            let mut attrs = Attrs::default();
+            for (attr, form, implicit) in &abbrev.attrs {
+                let value = unit.read_value(&mut reader, *form, *implicit)?;
+                match *attr {
+                    DW_AT_NAME => attrs.name = Some(value),
+                    DW_AT_LINKAGE_NAME | DW_AT_MIPS_LINKAGE_NAME => {
+                        attrs.linkage_name = Some(value)
+                    }
+                    DW_AT_ABSTRACT_ORIGIN | DW_AT_SPECIFICATION => {
+                        if let Value::Ref(origin) = value {
+                            attrs.origin = Some(origin);
+                        }
+                    }
+                    DW_AT_LOW_PC => attrs.low_pc = Some(value),
+                    DW_AT_HIGH_PC => attrs.high_pc = Some(value),
+                    DW_AT_ENTRY_PC => attrs.entry_pc = Some(value),
+                    DW_AT_RANGES => attrs.ranges = Some(value),
+                    DW_AT_CALL_FILE => attrs.call_file = Some(value),
+                    DW_AT_CALL_LINE => attrs.call_line = Some(value),
+                    DW_AT_CALL_COLUMN => attrs.call_column = Some(value),
+                    DW_AT_STMT_LIST if root => attrs.stmt_list = Some(value),
+                    DW_AT_COMP_DIR if root => attrs.comp_dir = Some(value),
+                    DW_AT_STR_OFFSETS_BASE if root => {
+                        unit.str_offsets_base = value.offset().unwrap_or(8)
+                    }
+                    DW_AT_ADDR_BASE if root => unit.addr_base = value.offset().unwrap_or(8),
+                    DW_AT_RNGLISTS_BASE if root => {
+                        unit.rnglists_base = value.offset().unwrap_or(12)
+                    }
+                    _ => {}
+                }
+            }
+            if root {
+                root = false;
+                unit.base_address = attrs
+                    .low_pc
+                    .and_then(|low| unit.address(sections, low))
+                    .unwrap_or(0);
+                let stmt_list = attrs.stmt_list.and_then(|value| value.offset());
+                if let Some(stmt_list) = stmt_list.filter(|_| !sections.line.is_empty()) {
+                    let comp_dir = attrs
+                        .comp_dir
+                        .and_then(|dir| unit.string(sections, dir))
+                        .unwrap_or_default();
+                    let program = line_program(sections, &unit, stmt_list, &comp_dir)?;
+                    let base = lines.files.len();
+                    lines
+                        .rows
+                        .extend(program.rows.into_iter().map(|row| LineRow {
+                            file: base + row.file,
+                            ..row
+                        }));
+                    lines.files.extend(program.files.iter().cloned());
+                    files = program.files;
+                }
+            }
+            if attrs.name.is_some() || attrs.linkage_name.is_some() || attrs.origin.is_some() {
+                names.insert(
+                    die,
+                    Named {
+                        name: attrs.name.and_then(|name| unit.string(sections, name)),
+                        linkage_name: attrs
+                            .linkage_name
+                            .and_then(|name| unit.string(sections, name)),
+                        origin: attrs.origin,
+                    },
+                );
+            }
+            let scope = match abbrev.tag {
+                DW_TAG_SUBPROGRAM => {
+                    let ranges = attrs.ranges(&unit, sections);
+                    match ranges.first() {
+                        Some(first) => {
+                            let entry = attrs
+                                .entry_pc
+                                .and_then(|entry| unit.address(sections, entry))
+                                .or_else(|| {
+                                    attrs.low_pc.and_then(|low| unit.address(sections, low))
+                                })
+                                .unwrap_or(first.0);
+                            raw.push(RawFunction {
+                                die,
+                                entry,
+                                ranges,
+                                params: Vec::new(),
+                                inlined: Vec::new(),
+                            });
+                            Scope::Function(raw.len() - 1)
+                        }
+                        None => Scope::Other,
+                    }
+                }
+                DW_TAG_INLINED_SUBROUTINE => {
+                    let ranges = attrs.ranges(&unit, sections);
+                    let depth = stack
+                        .iter()
+                        .rev()
+                        .take_while(|scope| !matches!(scope, Scope::Function(_)))
+                        .filter(|scope| matches!(scope, Scope::Inline))
+                        .count();
+                    let function = stack.iter().rev().find_map(|scope| match scope {
+                        Scope::Function(index) => Some(*index),
+                        _ => None,
+                    });
+                    if let Some(index) = function.filter(|_| !ranges.is_empty()) {
+                        raw[index].inlined.push((
+                            die,
+                            Inlined {
+                                name: None,
+                                ranges,
+                                depth,
+                                call_file: attrs
+                                    .call_file
+                                    .and_then(|file| file.constant())
+                                    .and_then(|file| files.get(file as usize).cloned()),
+                                call_line: attrs
+                                    .call_line
+                                    .and_then(|line| line.constant())
+                                    .unwrap_or(0) as u32,
+                                call_column: attrs
+                                    .call_column
+                                    .and_then(|column| column.constant())
+                                    .unwrap_or(0)
+                                    as u32,
+                                lines: Vec::new(),
+                            },
+                        ));
+                    }
+                    Scope::Inline
+                }
+                DW_TAG_FORMAL_PARAMETER => {
+                    if let Some(Scope::Function(index)) = stack.last() {
+                        raw[*index].params.push(die);
+                    }
+                    Scope::Other
+                }
+                _ => Scope::Other,
+            };
+            if abbrev.children {
+                stack.push(scope);
+            } else if stack.is_empty() {
+                break;
+            }
+        }
+    }
+    lines.sort();
+    let functions = raw
+        .into_iter()
+        .map(|function| {
+            let (name, linkage_name) = resolve(&names, function.die);
+            Function {
+                name,
+                linkage_name,
+                entry: function.entry,
+                ranges: function.ranges,
+                params: function
+                    .params
+                    .iter()
+                    .map(|param| resolve(&names, *param).0.unwrap_or_default())
+                    .collect(),
+                inlined: function
+                    .inlined
+                    .into_iter()
+                    .map(|(die, inlined)| Inlined {
+                        name: resolve(&names, die).0,
+                        ..inlined
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok((functions, lines))
+}
+
+/// `dir` and `name` joined, unless `name` is absolute
+fn join_path(dir: &str, name: &str) -> String {
+    let absolute = name.starts_with('/')
+        || name.starts_with('\\')
+        || name.as_bytes().get(1..3) == Some(b":\\")
+        || name.as_bytes().get(1..3) == Some(b":/");
+    if absolute || dir.is_empty() {
+        name.to_string()
+    } else if dir.ends_with('/') || dir.ends_with('\\') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// The files and rows of the line program at `offset` of `.debug_line`, for `unit`
+fn line_program(sections: &Sections, unit: &Unit, offset: u64, comp_dir: &str) -> Result<Lines> {
+    let data: &[u8] = &sections.line;
+    let mut reader = Reader::new(data, offset as usize, sections.little_endian);
+    let (length, offset_size) = match reader.uint(4)? {
+        0xffff_ffff => (reader.uint(8)?, 8),
+        length => (length, 4),
+    };
+    let end = reader.pos.saturating_add(length as usize).min(data.len());
+    let version = reader.uint(2)? as u16;
+    if !(2..=5).contains(&version) {
+        return Err(Error::Malformed(format!(
+            "Unsupported DWARF line program at {:#x}: version {}",
+            offset, version
+        )));
+    }
+    if version >= 5 {
+        // address size and segment selector size
+        reader.bytes(2)?;
+    }
+    let header_length = reader.uint(offset_size)? as usize;
+    let program = reader.pos.saturating_add(header_length);
+    let min_inst_length = reader.u8()? as u64;
+    if version >= 4 {
+        // maximum operations per instruction, only for VLIW
+        reader.u8()?;
+    }
+    // default is_stmt: rows which aren't statements are kept all the same
+    reader.u8()?;
+    let line_base = reader.u8()? as i8 as i64;
+    let line_range = reader.u8()?.max(1);
+    let opcode_base = reader.u8()?;
+    let opcode_lengths = reader.bytes(opcode_base.saturating_sub(1) as usize)?;
+
+    let header = Unit {
+        version,
+        offset_size,
+        ..*unit
+    };
+    let mut dirs: Vec<String> = Vec::new();
+    let mut files: Vec<String> = Vec::new();
+    if version >= 5 {
+        // directory 0 is the compilation directory
+        for list in 0..2 {
+            let formats = (0..reader.u8()?)
+                .map(|_| Ok((reader.uleb()?, reader.uleb()?)))
+                .collect::<Result<Vec<_>>>()?;
+            for _ in 0..reader.uleb()? {
+                let (mut path, mut dir) = (String::new(), 0);
+                for (content, form) in &formats {
+                    let value = header.read_value(&mut reader, *form, 0)?;
+                    match *content {
+                        DW_LNCT_PATH => path = header.string(sections, value).unwrap_or_default(),
+                        DW_LNCT_DIRECTORY_INDEX => dir = value.constant().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+                if list == 0 {
+                    let base = dirs.first().map_or(comp_dir, String::as_str);
+                    dirs.push(join_path(base, &path));
+                } else {
+                    let dir = dirs.get(dir as usize).map_or("", String::as_str);
+                    files.push(join_path(dir, &path));
+                }
+            }
+        }
+    } else {
+        // directory 0 is the compilation directory, and files count from 1
+        dirs.push(comp_dir.to_string());
+        loop {
+            let dir = reader.cstr()?;
+            if dir.is_empty() {
+                break;
+            }
+            dirs.push(join_path(comp_dir, &String::from_utf8_lossy(dir)));
+        }
+        files.push(String::new());
+        loop {
+            let name = reader.cstr()?;
+            if name.is_empty() {
+                break;
+            }
+            let dir = reader.uleb()?;
+            // modification time and size
+            reader.uleb()?;
+            reader.uleb()?;
+            let dir = dirs.get(dir as usize).map_or("", String::as_str);
+            files.push(join_path(dir, &String::from_utf8_lossy(name)));
+        }
+    }
+
+    reader.pos = program;
+    let mut rows: Vec<LineRow> = Vec::new();
+    let mut sequence: Vec<LineRow> = Vec::new();
+    let initial = LineRow {
+        address: 0,
+        file: 1,
+        line: 1,
+        column: 0,
+        end_sequence: false,
+    };
+    let mut row = initial;
+    while reader.pos < end {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = (opcode - opcode_base) as u64;
+            row.address += adjusted / line_range as u64 * min_inst_length;
+            row.line = (row.line as i64 + line_base + (adjusted % line_range as u64) as i64) as u32;
+            sequence.push(row);
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = reader.uleb()? as usize;
+                let next = reader.pos.saturating_add(len);
+                match reader.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        sequence.push(LineRow {
+                            end_sequence: true,
+                            ..row
+                        });
+                        // sequences of code the linker dropped start at the tombstone
+                        if !unit.is_tombstone(sequence[0].address) {
+                            rows.append(&mut sequence);
+                        }
+                        sequence.clear();
+                        row = initial;
+                    }
+                    DW_LNE_SET_ADDRESS => row.address = reader.uint(len.saturating_sub(1))?,
+                    _ => {}
+                }
+                reader.pos = next;
+            }
+            DW_LNS_COPY => sequence.push(row),
+            DW_LNS_ADVANCE_PC => row.address += reader.uleb()? * min_inst_length,
+            DW_LNS_ADVANCE_LINE => row.line = (row.line as i64 + reader.sleb()?) as u32,
+            DW_LNS_SET_FILE => row.file = reader.uleb()? as usize,
+            DW_LNS_SET_COLUMN => row.column = reader.uleb()? as u32,
+            DW_LNS_CONST_ADD_PC => {
+                row.address += (255 - opcode_base) as u64 / line_range as u64 * min_inst_length
+            }
+            DW_LNS_FIXED_ADVANCE_PC => row.address += reader.uint(2)?,
+            // the rest only change state rows don't keep, skipped by their operand count
+            _ => {
+                for _ in 0..opcode_lengths[opcode as usize - 1] {
+                    reader.uleb()?;
+                }
+            }
+        }
+    }
+    Ok(Lines { files, rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debuginfo::{DebugInfo, Source};
+
+    fn uleb(mut value: u64, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    #[test]
+    fn references_out_of_range() {
+        let mut abbrev = Vec::new();
+        for (code, form) in [(1, DW_FORM_REF8), (2, DW_FORM_REF_UDATA)] {
+            uleb(code, &mut abbrev);
+            uleb(DW_TAG_SUBPROGRAM, &mut abbrev);
+            abbrev.push(0);
+            uleb(DW_AT_ABSTRACT_ORIGIN, &mut abbrev);
+            uleb(form, &mut abbrev);
+            abbrev.extend([0, 0]);
+        }
+        abbrev.push(0);
+        // version 4, abbrev offset 0, 8 byte addresses; the references are relative to the
+        // second unit, which doesn't start at 0
+        let unit = |die: &[u8]| {
+            let mut unit = ((7 + die.len()) as u32).to_le_bytes().to_vec();
+            unit.extend(4u16.to_le_bytes());
+            unit.extend(0u32.to_le_bytes());
+            unit.push(8);
+            unit.extend(die);
+            unit
+        };
+        let mut ref8 = vec![1];
+        ref8.extend(u64::MAX.to_le_bytes());
+        let mut udata = vec![2];
+        uleb(u64::MAX, &mut udata);
+        for die in [ref8, udata] {
+            let mut info = unit(&[0]);
+            info.extend(unit(&die));
+            let sections = Sections {
+                info: Cow::Owned(info),
+                abbrev: Cow::Owned(abbrev.clone()),
+                little_endian: true,
+                ..Sections::default()
+            };
+            assert!(read(&sections).is_err());
+        }
+    }
+
+    #[test]
+    fn dwarf4_functions() {
+        let mut abbrev = Vec::new();
+        // code, tag, whether it has children, attributes and forms
+        type Spec = (u64, u64, u8, &'static [(u64, u64)]);
+        let table: [Spec; 5] = [
+            (
+                1,
+                DW_TAG_COMPILE_UNIT,
+                1,
+                &[
+                    (DW_AT_NAME, DW_FORM_STRING),
+                    (DW_AT_LOW_PC, DW_FORM_ADDR),
+                    (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+                    (DW_AT_COMP_DIR, DW_FORM_STRING),
+                ],
+            ),
+            // the abstract instance of an inline function
+            (2, DW_TAG_SUBPROGRAM, 1, &[(DW_AT_NAME, DW_FORM_STRP)]),
+            (
+                3,
+                DW_TAG_FORMAL_PARAMETER,
+                0,
+                &[(DW_AT_NAME, DW_FORM_STRING)],
+            ),
+            (
+                4,
+                DW_TAG_SUBPROGRAM,
+                1,
+                &[
+                    (DW_AT_NAME, DW_FORM_STRING),
+                    (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+                    (DW_AT_LOW_PC, DW_FORM_ADDR),
+                    (DW_AT_HIGH_PC, DW_FORM_DATA4),
+                ],
+            ),
+            (
+                5,
+                DW_TAG_INLINED_SUBROUTINE,
+                0,
+                &[
+                    (DW_AT_ABSTRACT_ORIGIN, DW_FORM_REF4),
+                    (DW_AT_RANGES, DW_FORM_SEC_OFFSET),
+                    (DW_AT_CALL_FILE, DW_FORM_DATA1),
+                    (DW_AT_CALL_LINE, DW_FORM_DATA1),
+                ],
+            ),
+        ];
+        for (code, tag, children, attrs) in table {
+            uleb(code, &mut abbrev);
+            uleb(tag, &mut abbrev);
+            abbrev.push(children);
+            for (attr, form) in attrs {
+                uleb(*attr, &mut abbrev);
+                uleb(*form, &mut abbrev);
+            }
+            abbrev.extend([0, 0]);
+        }
+        abbrev.push(0);
+
+        // header: length, version 4, abbrev offset 0, 8 byte addresses
+        let mut info = vec![0; 4];
+        info.extend(4u16.to_le_bytes());
+        info.extend(0u32.to_le_bytes());
+        info.push(8);
+        info.push(1);
+        info.extend(b"a.c\0");
+        info.extend(0x1000u64.to_le_bytes());
+        info.extend(0u32.to_le_bytes());
+        info.extend(b"/work\0");
+        let inline = info.len() as u32;
+        info.push(2);
+        info.extend(1u32.to_le_bytes());
+        info.push(3);
+        info.extend(b"n\0");
+        info.push(0);
+        info.push(4);
+        info.extend(b"main\0_main\0");
+        info.extend(0x1010u64.to_le_bytes());
+        info.extend(0x40u32.to_le_bytes());
+        info.push(3);
+        info.extend(b"argc\0");
+        info.push(3);
+        info.extend(b"argv\0");
+        info.push(5);
+        info.extend(inline.to_le_bytes());
+        info.extend(0u32.to_le_bytes());
+        info.extend([1, 11]);
+        info.extend([0, 0]);
+        let len = info.len() as u32 - 4;
+        info[..4].copy_from_slice(&len.to_le_bytes());
+
+        // relative to the unit's base, 0x1000
+        let mut ranges = Vec::new();
+        for value in [0x18u64, 0x20, 0x30, 0x38, 0, 0] {
+            ranges.extend(value.to_le_bytes());
+        }
+        // version 4, line base -5, line range 14, opcode base 13; a.c and inc/sq.h
+        let mut header = vec![1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend(b"inc\0\0a.c\0\0\0\0sq.h\0\x01\0\0\0");
+        let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+        program.extend(0x1010u64.to_le_bytes());
+        // a.c:10, sq.h:3 at 0x1018, a.c:11 at 0x1020, then a.c:12 at 0x1030
+        program.extend([DW_LNS_ADVANCE_LINE, 9, DW_LNS_COPY]);
+        program.extend([
+            DW_LNS_SET_FILE,
+            2,
+            DW_LNS_ADVANCE_PC,
+            8,
+            DW_LNS_ADVANCE_LINE,
+            0x79,
+        ]);
+        program.push(DW_LNS_COPY);
+        program.extend([
+            DW_LNS_SET_FILE,
+            1,
+            DW_LNS_ADVANCE_PC,
+            8,
+            DW_LNS_ADVANCE_LINE,
+            8,
+        ]);
+        program.extend([
+            DW_LNS_COPY,
+            243,
+            DW_LNS_ADVANCE_PC,
+            0x20,
+            0,
+            1,
+            DW_LNE_END_SEQUENCE,
+        ]);
+        let mut line = ((header.len() + program.len() + 6) as u32)
+            .to_le_bytes()
+            .to_vec();
+        line.extend(4u16.to_le_bytes());
+        line.extend((header.len() as u32).to_le_bytes());
+        line.extend(header);
+        line.extend(program);
+
+        let sections = Sections {
+            info: Cow::Owned(info),
+            abbrev: Cow::Owned(abbrev),
+            line: Cow::Owned(line),
+            str: Cow::Borrowed(b"\0square\0"),
+            ranges: Cow::Owned(ranges),
+            little_endian: true,
+            ..Sections::default()
+        };
+        let (functions, lines) = read(&sections).unwrap();
+        assert_eq!(functions.len(), 1);
+        let main = &functions[0];
+        assert_eq!(main.name.as_deref(), Some("main"));
+        assert_eq!(main.linkage_name.as_deref(), Some("_main"));
+        assert_eq!(
+            (main.entry, main.ranges.clone()),
+            (0x1010, vec![(0x1010, 0x40)])
+        );
+        assert_eq!(main.params, ["argc", "argv"]);
+        assert_eq!(main.inlined.len(), 1);
+        assert_eq!(main.inlined[0].name.as_deref(), Some("square"));
+        assert_eq!(main.inlined[0].ranges, [(0x1018, 8), (0x1030, 8)]);
+
+        assert_eq!(lines.files, ["", "/work/a.c", "/work/inc/sq.h"]);
+        let rows: Vec<_> = lines
+            .rows
+            .iter()
+            .map(|row| (row.address, row.file, row.line, row.end_sequence))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (0x1010, 1, 10, false),
+                (0x1018, 2, 3, false),
+                (0x1020, 1, 11, false),
+                (0x1030, 1, 12, false),
+                (0x1050, 1, 12, true)
+            ]
+        );
+        let debuginfo = DebugInfo {
+            source: Source::Dwarf,
+            functions,
+            lines,
+        };
+        let frames: Vec<String> = debuginfo
+            .line_for_va(0x101a)
+            .iter()
+            .map(|frame| frame.to_string())
+            .collect();
+        assert_eq!(
+            frames,
+            ["square at /work/inc/sq.h:3", "main at /work/a.c:11"]
+        );
+        assert!(debuginfo.line_for_va(0x1050).is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+//! Function boundaries from debug info, DWARF in ELF and Mach-O files or a PDB beside a PE,
+//! imported into a workspace in place of what code flow analysis guessed.
+//!
+//! The ranges of a function in debug info are what the compiler emitted, so [`apply`] sets them
+//! as the function's bounds and forgets any function analysis found inside them, such as the
+//! cold part of a function split off by the compiler. Each imported function is marked with
+//! [`SOURCE_META`], so later imports and the user can tell it from a heuristic one. Inlined
+//! functions become comments at the start of their ranges, parameters the function's arguments.
+//!
+//! [`DebugInfo::line_for_va`] answers what addr2line does: the source line of an address from
+//! the line tables, with a frame for each function inlined there.
+
+pub mod dwarf;
+pub mod pdb;
+
+use crate::{error::Result, mach::Mach, workspace::VivWorkspace, Object};
+use std::fmt;
+
+/// The function meta key recording where a function's bounds came from, a [`Source`]
+pub const SOURCE_META: &str = "DebugInfo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Dwarf = 1,
+    Pdb = 2,
+}
+
+/// A function in debug info, its addresses as the debug info has them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Function {
+    pub name: Option<String>,
+    /// The mangled name
+    pub linkage_name: Option<String>,
+    pub entry: u64,
+    /// The (address, size) ranges of its code
+    pub ranges: Vec<(u64, u64)>,
+    /// The names of its parameters in order
+    pub params: Vec<String>,
+    pub inlined: Vec<Inlined>,
+}
+
+/// A function inlined into another
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inlined {
+    pub name: Option<String>,
+    pub ranges: Vec<(u64, u64)>,
+    /// How many inlined functions it is nested in
+    pub depth: usize,
+    /// Where it was called from, in the function or inlined function it is nested in
+    pub call_file: Option<String>,
+    pub call_line: u32,
+    pub call_column: u32,
+    /// The lines of its own code, sorted by address, where the debug info gives them per
+    /// inlined function rather than in the line table (PDB)
+    pub lines: Vec<LineRow>,
+}
+
+/// A row of a line table: the source line of the code from `address` up to the next row
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u64,
+    /// An index into [`Lines::files`]
+    pub file: usize,
+    pub line: u32,
+    pub column: u32,
+    /// The first address past a sequence of rows, rather than a row
+    pub end_sequence: bool,
+}
+
+/// The line tables of a binary, their rows sorted by address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lines {
+    pub files: Vec<String>,
+    pub rows: Vec<LineRow>,
+}
+
+impl Lines {
+    /// Sort the rows by address, the end of a sequence before a row starting at the same
+    /// address
+    pub fn sort(&mut self) {
+        self.rows
+            .sort_by_key(|row| (row.address, !row.end_sequence));
+    }
+
+    /// The row holding `address`
+    pub fn find(&self, address: u64) -> Option<&LineRow> {
+        let index = self.rows.partition_point(|row| row.address <= address);
+        self.rows
+            .get(index.checked_sub(1)?)
+            .filter(|row| !row.end_sequence)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    /// 0 if not known
+    pub column: u32,
+}
+
+/// A function an address is in, inlined or not, and where in its source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at ", self.function.as_deref().unwrap_or("??"))?;
+        match &self.location {
+            Some(location) => write!(f, "{}:{}", location.file, location.line),
+            None => write!(f, "??:0"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub source: Source,
+    pub functions: Vec<Function>,
+    pub lines: Lines,
+}
+
+impl DebugInfo {
+    /// The DWARF of an ELF or Mach-O file, none if it has no debug info
+    pub fn from_object(bytes: &[u8]) -> Result<Option<DebugInfo>> {
+        let sections = match Object::parse(bytes)? {
+            Object::Elf(elf) => dwarf::Sections::from_elf(&elf, bytes),
+            Object::Mach(Mach::Binary(macho)) => dwarf::Sections::from_macho(&macho),
+            _ => return Ok(None),
+        };
+        if sections.is_empty() {
+            return Ok(None);
+        }
+        let (functions, lines) = dwarf::read(&sections)?;
+        Ok(Some(DebugInfo {
+            source: Source::Dwarf,
+            functions,
+            lines,
+        }))
+    }
+
+    /// The functions of a PDB, their addresses RVAs
+    pub fn from_pdb(bytes: &[u8]) -> Result<DebugInfo> {
+        let (functions, lines) = pdb::read(bytes)?;
+        Ok(DebugInfo {
+            source: Source::Pdb,
+            functions,
+            lines,
+        })
+    }
+
+    /// The frames at `va`, an address as the debug info has them, the innermost inlined
+    /// function first and the function holding them last, like `addr2line -i`. Empty if
+    /// neither a function nor a line holds `va`.
+    pub fn line_for_va(&self, va: u64) -> Vec<Frame> {
+        let contains = |ranges: &[(u64, u64)]| {
+            ranges
+                .iter()
+                .any(|(start, size)| (*start..start.saturating_add(*size)).contains(&va))
+        };
+        let row = self.lines.find(va).map(|row| Location {
+            file: self.lines.files.get(row.file).cloned().unwrap_or_default(),
+            line: row.line,
+            column: row.column,
+        });
+        let Some(function) = self.functions.iter().find(|f| contains(&f.ranges)) else {
+            return row
+                .map(|location| Frame {
+                    function: None,
+                    location: Some(location),
+                })
+                .into_iter()
+                .collect();
+        };
+        let mut inlined: Vec<&Inlined> = function
+            .inlined
+            .iter()
+            .filter(|inlined| contains(&inlined.ranges))
+            .collect();
+        inlined.sort_by_key(|inlined| std::cmp::Reverse(inlined.depth));
+        // DWARF line tables give the line in the innermost inlined function, and each inlined
+        // function where it was called from; PDB line tables give the line in the function, and
+        // each inlined function its own lines
+        let (mut location, outer) = match self.source {
+            Source::Dwarf => (row, None),
+            Source::Pdb => (None, row),
+        };
+        let mut frames = Vec::new();
+        for inlined in inlined {
+            let index = inlined.lines.partition_point(|row| row.address <= va);
+            let own = index.checked_sub(1).map(|index| {
+                let row = &inlined.lines[index];
+                Location {
+                    file: self.lines.files.get(row.file).cloned().unwrap_or_default(),
+                    line: row.line,
+                    column: row.column,
+                }
+            });
+            frames.push(Frame {
+                function: inlined.name.clone(),
+                location: own.or(location),
+            });
+            location = inlined.call_file.clone().map(|file| Location {
+                file,
+                line: inlined.call_line,
+                column: inlined.call_column,
+            });
+        }
+        frames.push(Frame {
+            function: function.name.clone().or(function.linkage_name.clone()),
+            location: outer.or(location),
+        });
+        frames
+    }
+}
+
+/// Import the functions of `info` into `workspace`, `base` added to their addresses. Returns
+/// how many were imported.
+pub fn apply(workspace: &mut VivWorkspace, info: &DebugInfo, base: i64) -> usize {
+    let va = |address: u64| (address as i64 + base) as i32;
+    let mut imported = 0;
+    for function in &info.functions {
+        let fva = va(function.entry);
+        let ranges: Vec<(i32, i32)> = function
+            .ranges
+            .iter()
+            .map(|(start, size)| (va(*start), *size as i32))
+            .collect();
+        if workspace.is_function(fva) {
+            if !ranges.is_empty() {
+                workspace.set_function_bounds(fva, ranges.clone());
+            }
+        } else if !workspace.add_function(fva, ranges.clone()) {
+            continue;
+        }
+        // functions analysis found within these bounds are pieces of this one
+        for (start, size) in &ranges {
+            let inside: Vec<i32> = workspace
+                .get_functions()
+                .into_iter()
+                .filter(|other| {
+                    *other != fva && (*start..start.saturating_add(*size)).contains(other)
+                })
+                .filter(|other| {
+                    !workspace
+                        .get_function_meta_dict(*other)
+                        .contains_key(SOURCE_META)
+                })
+                .collect();
+            for other in inside {
+                workspace.del_function(other);
+            }
+        }
+        workspace.set_function_meta(fva, SOURCE_META, info.source as i32);
+        if let Some(name) = function.name.as_ref().or(function.linkage_name.as_ref()) {
+            workspace.make_name(fva, name.clone(), false, true);
+        }
+        if !function.params.is_empty() {
+            let args = function
+                .params
+                .iter()
+                .map(|name| (String::new(), name.clone()))
+                .collect();
+            workspace.set_function_args(fva, args);
+        }
+        for inlined in &function.inlined {
+            let (Some(name), Some((start, _))) = (&inlined.name, inlined.ranges.first()) else {
+                continue;
+            };
+            workspace.set_comment(va(*start), &format!("inlined {}", name), false);
+        }
+        imported += 1;
+    }
+    imported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_overrides_heuristics() {
+        let mut ws = VivWorkspace::new("", false);
+        // analysis split the function in two
+        ws.add_function(0x1000, vec![(0x1000, 0x10)]);
+        ws.add_function(0x1010, vec![(0x1010, 0x10)]);
+        let info = DebugInfo {
+            source: Source::Pdb,
+            functions: vec![Function {
+                name: Some("main".into()),
+                entry: 0,
+                ranges: vec![(0, 0x20)],
+                params: vec!["argc".into(), "argv".into()],
+                inlined: vec![Inlined {
+                    name: Some("square".into()),
+                    ranges: vec![(0x8, 4)],
+                    ..Inlined::default()
+                }],
+                ..Function::default()
+            }],
+            lines: Lines::default(),
+        };
+        assert_eq!(apply(&mut ws, &info, 0x1000), 1);
+        assert!(ws.is_function(0x1000));
+        assert!(!ws.is_function(0x1010));
+        assert_eq!(ws.get_function_bounds(0x1000), Some(vec![(0x1000, 0x20)]));
+        assert_eq!(
+            ws.get_function_meta_dict(0x1000).get(SOURCE_META),
+            Some(&(Source::Pdb as i32))
+        );
+        assert_eq!(ws.get_name(0x1000, false).as_deref(), Some("main"));
+        let args: Vec<String> = ws
+            .get_function_args(0x1000)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(args, ["argc", "argv"]);
+        assert_eq!(ws.get_comment(0x1008), "inlined square");
+    }
+}
@@ -0,0 +1,901 @@
+//! PDB, the debug info of MSVC and lld-link, read down to the functions of its module symbol
+//! streams: `S_GPROC32` and `S_LPROC32` with their code, the parameters that follow them, and
+//! the `S_INLINESITE`s within them. Public function symbols fill in functions which have no
+//! module symbols, as in a stripped PDB. The C13 line tables after each module's symbols give
+//! the source lines, their file names in the `/names` stream.
+//!
+//! A PDB is an MSF file: a set of streams, each spread over blocks listed in the stream
+//! directory. The DBI stream (3) lists the modules and points at the copy of the section
+//! headers used to turn `section:offset` into RVAs; the TPI (2) and IPI (4) streams hold the
+//! function types, which give the parameter count, and the names of inlined functions.
+
+use super::{Function, Inlined, LineRow, Lines};
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+
+const MSF_MAGIC: &[u8] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+
+const PDB_STREAM: usize = 1;
+const TPI_STREAM: usize = 2;
+const DBI_STREAM: usize = 3;
+const IPI_STREAM: usize = 4;
+const NIL_STREAM: u16 = 0xffff;
+/// The index of the section header stream in the optional debug header
+const DBG_SECTION_HDR: usize = 5;
+
+pub const S_END: u16 = 0x0006;
+pub const S_THUNK32: u16 = 0x1102;
+pub const S_BLOCK32: u16 = 0x1103;
+pub const S_WITH32: u16 = 0x1104;
+pub const S_BPREL32: u16 = 0x110b;
+pub const S_PUB32: u16 = 0x110e;
+pub const S_LPROC32: u16 = 0x110f;
+pub const S_GPROC32: u16 = 0x1110;
+pub const S_REGREL32: u16 = 0x1111;
+pub const S_SEPCODE: u16 = 0x1132;
+pub const S_LOCAL: u16 = 0x113e;
+pub const S_LPROC32_ID: u16 = 0x1146;
+pub const S_GPROC32_ID: u16 = 0x1147;
+pub const S_INLINESITE: u16 = 0x114d;
+pub const S_INLINESITE_END: u16 = 0x114e;
+pub const S_PROC_ID_END: u16 = 0x114f;
+pub const S_LPROC32_DPC: u16 = 0x1155;
+pub const S_LPROC32_DPC_ID: u16 = 0x1156;
+pub const S_INLINESITE2: u16 = 0x115d;
+
+pub const DEBUG_S_LINES: u32 = 0xf2;
+pub const DEBUG_S_FILECHKSMS: u32 = 0xf4;
+pub const DEBUG_S_INLINEELINES: u32 = 0xf6;
+/// `DEBUG_S_INLINEELINES` signature of entries listing extra files
+const CV_INLINEE_SOURCE_LINE_SIGNATURE_EX: u32 = 0x1;
+/// `DEBUG_S_LINES` flag of a table with columns
+const CV_LINES_HAVE_COLUMNS: u16 = 0x0001;
+
+pub const LF_PROCEDURE: u16 = 0x1008;
+pub const LF_MFUNCTION: u16 = 0x1009;
+pub const LF_FUNC_ID: u16 = 0x1601;
+pub const LF_MFUNC_ID: u16 = 0x1602;
+
+/// `S_LOCAL` flag of a parameter
+const LOCAL_IS_PARAM: u16 = 0x0001;
+/// `S_PUB32` flag of a function
+const PUB_FUNCTION: u32 = 0x0002;
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn cstr_at(bytes: &[u8], at: usize) -> Option<String> {
+    let rest = bytes.get(at..)?;
+    let len = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+    Some(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+fn truncated(what: &str) -> Error {
+    Error::Malformed(format!("PDB {} truncated", what))
+}
+
+/// The streams of an MSF file
+pub struct Msf<'a> {
+    bytes: &'a [u8],
+    block_size: usize,
+    /// The blocks and size of each stream
+    streams: Vec<(Vec<u32>, usize)>,
+}
+
+impl<'a> Msf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if !bytes.starts_with(MSF_MAGIC) {
+            return Err(Error::Malformed("Not an MSF 7.00 file".into()));
+        }
+        let header = |at| u32_at(bytes, at).ok_or_else(|| truncated("superblock"));
+        let block_size = header(32)? as usize;
+        if !matches!(block_size, 512 | 1024 | 2048 | 4096) {
+            return Err(Error::Malformed(format!("MSF block size {}", block_size)));
+        }
+        let directory_size = header(44)? as usize;
+        let block_map = header(52)? as usize;
+        let mut msf = Msf {
+            bytes,
+            block_size,
+            streams: Vec::new(),
+        };
+        // the block map lists the blocks of the directory
+        let map = msf.block(block_map)?;
+        let blocks = (0..directory_size.div_ceil(block_size))
+            .map(|i| u32_at(map, i * 4).ok_or_else(|| truncated("block map")))
+            .collect::<Result<Vec<u32>>>()?;
+        let directory = msf.read(&blocks, directory_size)?;
+        let count = u32_at(&directory, 0).ok_or_else(|| truncated("directory"))? as usize;
+        let mut at = 4 + count * 4;
+        for i in 0..count {
+            let size = match u32_at(&directory, 4 + i * 4).ok_or_else(|| truncated("directory"))? {
+                0xffff_ffff => 0,
+                size => size as usize,
+            };
+            let blocks = (0..size.div_ceil(block_size))
+                .map(|j| u32_at(&directory, at + j * 4).ok_or_else(|| truncated("directory")))
+                .collect::<Result<Vec<u32>>>()?;
+            at += blocks.len() * 4;
+            msf.streams.push((blocks, size));
+        }
+        Ok(msf)
+    }
+
+    fn block(&self, index: usize) -> Result<&'a [u8]> {
+        let start = index * self.block_size;
+        self.bytes
+            .get(start..start + self.block_size)
+            .ok_or_else(|| Error::Malformed(format!("MSF block {} past the end", index)))
+    }
+
+    fn read(&self, blocks: &[u32], size: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(size);
+        for block in blocks {
+            data.extend(self.block(*block as usize)?);
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// The contents of stream `index`, empty for a stream the file doesn't have
+    pub fn stream(&self, index: usize) -> Result<Vec<u8>> {
+        match self.streams.get(index) {
+            Some((blocks, size)) => self.read(blocks, *size),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// The GUID and age in the PDB stream, which match the CodeView record of the PE
+pub fn signature(msf: &Msf) -> Result<([u8; 16], u32)> {
+    let stream = msf.stream(PDB_STREAM)?;
+    let guid = stream.get(12..28).ok_or_else(|| truncated("PDB stream"))?;
+    let age = u32_at(&stream, 8).ok_or_else(|| truncated("PDB stream"))?;
+    Ok((guid.try_into().unwrap(), age))
+}
+
+/// The index of the stream the PDB stream's named stream map gives `name`
+fn named_stream(msf: &Msf, name: &str) -> Result<Option<usize>> {
+    let stream = msf.stream(PDB_STREAM)?;
+    let Some(size) = u32_at(&stream, 28) else {
+        return Ok(None);
+    };
+    let strings = stream.get(32..32 + size as usize).unwrap_or_default();
+    let mut at = 32 + size as usize;
+    let count = u32_at(&stream, at).unwrap_or(0);
+    // the capacity, then the present and deleted bit vectors
+    at += 8;
+    for _ in 0..2 {
+        at += 4 + u32_at(&stream, at).unwrap_or(0) as usize * 4;
+    }
+    for i in 0..count as usize {
+        let (Some(key), Some(index)) =
+            (u32_at(&stream, at + i * 8), u32_at(&stream, at + i * 8 + 4))
+        else {
+            break;
+        };
+        if cstr_at(strings, key as usize).as_deref() == Some(name) {
+            return Ok(Some(index as usize));
+        }
+    }
+    Ok(None)
+}
+
+/// The records of a TPI or IPI stream by type index, as kind and data
+struct Types {
+    begin: u32,
+    records: Vec<(u16, Vec<u8>)>,
+}
+
+impl Types {
+    fn parse(stream: &[u8]) -> Types {
+        let begin = u32_at(stream, 8).unwrap_or(0x1000);
+        let mut at = u32_at(stream, 4).unwrap_or(56) as usize;
+        let mut records = Vec::new();
+        while let (Some(len), Some(kind)) = (u16_at(stream, at), u16_at(stream, at + 2)) {
+            let Some(data) = stream.get(at + 4..at + 2 + len as usize) else {
+                break;
+            };
+            records.push((kind, data.to_vec()));
+            at += 2 + len as usize;
+        }
+        Types { begin, records }
+    }
+
+    fn get(&self, index: u32) -> Option<(u16, &[u8])> {
+        let (kind, data) = self.records.get(index.checked_sub(self.begin)? as usize)?;
+        Some((*kind, data))
+    }
+
+    /// The name of an `LF_FUNC_ID` or `LF_MFUNC_ID`
+    fn func_id_name(&self, index: u32) -> Option<String> {
+        match self.get(index)? {
+            (LF_FUNC_ID | LF_MFUNC_ID, data) => cstr_at(data, 8),
+            _ => None,
+        }
+    }
+
+    /// The function type of an `LF_FUNC_ID` or `LF_MFUNC_ID`
+    fn func_id_type(&self, index: u32) -> Option<u32> {
+        match self.get(index)? {
+            (LF_FUNC_ID | LF_MFUNC_ID, data) => u32_at(data, 4),
+            _ => None,
+        }
+    }
+
+    /// How many parameters a procedure type has, `this` included
+    fn param_count(&self, index: u32) -> Option<usize> {
+        match self.get(index)? {
+            (LF_PROCEDURE, data) => Some(u16_at(data, 6)? as usize),
+            (LF_MFUNCTION, data) => {
+                let this = (u32_at(data, 8)? != 0) as usize;
+                Some(u16_at(data, 14)? as usize + this)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The RVAs of the sections, from the copy of the section headers the DBI stream points to
+fn section_rvas(msf: &Msf, dbi: &[u8]) -> Result<Vec<u32>> {
+    let size = |at| u32_at(dbi, at).unwrap_or(0) as usize;
+    let optional = 64 + size(24) + size(28) + size(32) + size(36) + size(40) + size(52);
+    let stream = u16_at(dbi, optional + DBG_SECTION_HDR * 2)
+        .filter(|stream| *stream != NIL_STREAM)
+        .ok_or_else(|| Error::Malformed("PDB without section headers".into()))?;
+    let headers = msf.stream(stream as usize)?;
+    Ok(headers
+        .chunks_exact(40)
+        .map(|header| u32_at(header, 12).unwrap())
+        .collect())
+}
+
+struct Module {
+    stream: u16,
+    symbols: usize,
+    c11_lines: usize,
+    c13_lines: usize,
+}
+
+/// The modules with a stream
+fn modules(dbi: &[u8]) -> Vec<Module> {
+    let end = (64 + u32_at(dbi, 24).unwrap_or(0) as usize).min(dbi.len());
+    let mut modules = Vec::new();
+    let mut at = 64;
+    while at + 64 <= end {
+        let size = |offset| u32_at(dbi, at + offset).unwrap_or(0) as usize;
+        let Some(stream) = u16_at(dbi, at + 34) else {
+            break;
+        };
+        if stream != NIL_STREAM {
+            modules.push(Module {
+                stream,
+                symbols: size(36),
+                c11_lines: size(40),
+                c13_lines: size(44),
+            });
+        }
+        // the module name and object file name follow, then padding to 4 bytes
+        let mut names = at + 64;
+        for _ in 0..2 {
+            names += dbi[names.min(end)..end]
+                .iter()
+                .position(|b| *b == 0)
+                .map_or(end, |len| len + 1);
+        }
+        at = names.next_multiple_of(4);
+    }
+    modules
+}
+
+/// A compressed unsigned integer of the binary annotations
+fn annotation_uint(bytes: &[u8], at: &mut usize) -> Option<u32> {
+    let byte = |i: usize| bytes.get(*at + i).map(|b| *b as u32);
+    let b0 = byte(0)?;
+    let (value, len) = if b0 & 0x80 == 0 {
+        (b0, 1)
+    } else if b0 & 0xc0 == 0x80 {
+        ((b0 & 0x3f) << 8 | byte(1)?, 2)
+    } else if b0 & 0xe0 == 0xc0 {
+        (
+            (b0 & 0x1f) << 24 | byte(1)? << 16 | byte(2)? << 8 | byte(3)?,
+            4,
+        )
+    } else {
+        return None;
+    };
+    *at += len;
+    Some(value)
+}
+
+/// A compressed signed integer of the binary annotations, its sign in the low bit
+fn annotation_int(value: u32) -> i32 {
+    match value & 1 {
+        0 => (value >> 1) as i32,
+        _ => -((value >> 1) as i32),
+    }
+}
+
+/// The (offset, file, line) a line of an inline site starts at, the file an offset into the file
+/// checksums if it changed and the line relative to the first line of the inlined function
+type LineStart = (u64, Option<u32>, i32);
+
+/// What the binary annotations of an inline site say of its code, its offsets from the start of
+/// the function it is inlined into
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Annotations {
+    /// The (offset, size) ranges of its code
+    ranges: Vec<(u64, u64)>,
+    lines: Vec<LineStart>,
+}
+
+fn annotations(bytes: &[u8]) -> Annotations {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let mut lines: Vec<LineStart> = Vec::new();
+    let (mut at, mut offset) = (0, 0u64);
+    let (mut file, mut line) = (None, 0i32);
+    let mut open: Option<u64> = None;
+    let close = |ranges: &mut Vec<(u64, u64)>, start: u64, end: u64| match ranges.last_mut() {
+        Some(last) if last.0 + last.1 == start => last.1 += end - start,
+        _ if end > start => ranges.push((start, end - start)),
+        _ => {}
+    };
+    // a line starts wherever the code offset moves to
+    let start_line = |lines: &mut Vec<LineStart>, row: LineStart| match lines.last_mut() {
+        Some(last) if last.0 == row.0 => *last = row,
+        _ => lines.push(row),
+    };
+    while let Some(op) = annotation_uint(bytes, &mut at) {
+        let Some(operand) = annotation_uint(bytes, &mut at) else {
+            break;
+        };
+        let moved = match op {
+            // code offset, change code offset, change code offset and line offset
+            1 => Some(operand as u64),
+            3 => Some(offset + operand as u64),
+            11 => {
+                line = line.wrapping_add(annotation_int(operand >> 4));
+                Some(offset + (operand & 0xf) as u64)
+            }
+            // change code length
+            4 => {
+                let start = open.take().unwrap_or(offset);
+                close(&mut ranges, start, offset + operand as u64);
+                offset += operand as u64;
+                None
+            }
+            // change file, change line offset
+            5 => {
+                file = Some(operand);
+                None
+            }
+            6 => {
+                line = line.wrapping_add(annotation_int(operand));
+                None
+            }
+            // change code length and code offset
+            12 => {
+                let Some(delta) = annotation_uint(bytes, &mut at) else {
+                    break;
+                };
+                if let Some(start) = open.take() {
+                    close(&mut ranges, start, offset + delta as u64);
+                }
+                offset += delta as u64;
+                start_line(&mut lines, (offset, file, line));
+                close(&mut ranges, offset, offset + operand as u64);
+                offset += operand as u64;
+                None
+            }
+            // the invalid opcode pads the end
+            0 => break,
+            _ => None,
+        };
+        if let Some(moved) = moved {
+            if let Some(start) = open.replace(moved) {
+                close(&mut ranges, start, moved);
+            }
+            offset = moved;
+            start_line(&mut lines, (moved, file, line));
+        }
+    }
+    Annotations { ranges, lines }
+}
+
+enum Scope {
+    Function(usize),
+    Inline,
+    Other,
+}
+
+/// The subsections of C13 line info, as kind and data
+fn subsections(c13: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut at = 0;
+    std::iter::from_fn(move || {
+        let (kind, len) = (u32_at(c13, at)?, u32_at(c13, at + 4)? as usize);
+        let data = c13.get(at + 8..at + 8 + len)?;
+        at = (at + 8 + len).next_multiple_of(4);
+        Some((kind, data))
+    })
+}
+
+/// The functions of the PDB `bytes`, their addresses RVAs
+pub fn functions(bytes: &[u8]) -> Result<Vec<Function>> {
+    Ok(read(bytes)?.0)
+}
+
+/// The functions and line tables of the PDB `bytes`, their addresses RVAs
+pub fn read(bytes: &[u8]) -> Result<(Vec<Function>, Lines)> {
+    let msf = Msf::parse(bytes)?;
+    let dbi = msf.stream(DBI_STREAM)?;
+    if dbi.len() < 64 {
+        return Err(truncated("DBI stream"));
+    }
+    let sections = section_rvas(&msf, &dbi)?;
+    let tpi = Types::parse(&msf.stream(TPI_STREAM)?);
+    let ipi = Types::parse(&msf.stream(IPI_STREAM)?);
+    let rva = |segment: u16, offset: u32| -> Option<u64> {
+        let section = sections.get((segment as usize).checked_sub(1)?)?;
+        Some(*section as u64 + offset as u64)
+    };
+
+    let mut functions: Vec<Function> = Vec::new();
+    // the parameters each function may still have, from its type
+    let mut params_left: Vec<Option<usize>> = Vec::new();
+    let names = match named_stream(&msf, "/names")? {
+        // after the signature, version and size
+        Some(stream) => msf.stream(stream)?.get(12..).unwrap_or_default().to_vec(),
+        None => Vec::new(),
+    };
+    let mut lines = Lines::default();
+    let mut files: HashMap<String, usize> = HashMap::new();
+    for module in modules(&dbi) {
+        let stream = msf.stream(module.stream as usize)?;
+        let symbols = &stream[..module.symbols.min(stream.len())];
+        let c13 = stream
+            .get(module.symbols + module.c11_lines..)
+            .map(|c13| &c13[..module.c13_lines.min(c13.len())])
+            .unwrap_or_default();
+
+        // the files by their offset in the checksums, then the lines
+        let mut checksums: HashMap<u32, usize> = HashMap::new();
+        for (_, data) in subsections(c13).filter(|(kind, _)| *kind == DEBUG_S_FILECHKSMS) {
+            let mut at = 0;
+            while let (Some(name), Some(len)) = (u32_at(data, at), data.get(at + 4)) {
+                let name = cstr_at(&names, name as usize).unwrap_or_default();
+                let next = files.len();
+                let file = *files.entry(name.clone()).or_insert_with(|| {
+                    lines.files.push(name);
+                    next
+                });
+                checksums.insert(at as u32, file);
+                at = (at + 6 + *len as usize).next_multiple_of(4);
+            }
+        }
+        for (_, data) in subsections(c13).filter(|(kind, _)| *kind == DEBUG_S_LINES) {
+            let (Some(offset), Some(segment), Some(flags), Some(size)) = (
+                u32_at(data, 0),
+                u16_at(data, 4),
+                u16_at(data, 6),
+                u32_at(data, 8),
+            ) else {
+                continue;
+            };
+            let Some(start) = rva(segment, offset) else {
+                continue;
+            };
+            let mut at = 12;
+            while let (Some(file), Some(count)) = (u32_at(data, at), u32_at(data, at + 4)) {
+                let (count, block) = (count as usize, u32_at(data, at + 8).unwrap_or(0) as usize);
+                let columns = at + 12 + count * 8;
+                for i in 0..count {
+                    let (Some(line_offset), Some(line)) =
+                        (u32_at(data, at + 12 + i * 8), u32_at(data, at + 16 + i * 8))
+                    else {
+                        break;
+                    };
+                    let column = match flags & CV_LINES_HAVE_COLUMNS {
+                        0 => 0,
+                        _ => u16_at(data, columns + i * 4).unwrap_or(0),
+                    };
+                    lines.rows.push(LineRow {
+                        address: start + line_offset as u64,
+                        file: checksums.get(&file).copied().unwrap_or(0),
+                        line: line & 0x00ff_ffff,
+                        column: column as u32,
+                        end_sequence: false,
+                    });
+                }
+                at += block.max(12);
+            }
+            lines.rows.push(LineRow {
+                address: start.saturating_add(size as u64),
+                end_sequence: true,
+                ..LineRow::default()
+            });
+        }
+        // the file checksum and first line of each inlined function
+        let mut inlinees: HashMap<u32, (u32, u32)> = HashMap::new();
+        for (_, data) in subsections(c13).filter(|(kind, _)| *kind == DEBUG_S_INLINEELINES) {
+            let extra = u32_at(data, 0) == Some(CV_INLINEE_SOURCE_LINE_SIGNATURE_EX);
+            let mut at = 4;
+            while let (Some(inlinee), Some(file), Some(line)) =
+                (u32_at(data, at), u32_at(data, at + 4), u32_at(data, at + 8))
+            {
+                inlinees.insert(inlinee, (file, line));
+                at += 12;
+                if extra {
+                    at += 4 + 4 * u32_at(data, at).unwrap_or(0) as usize;
+                }
+            }
+        }
+
+        // after the signature
+        let mut at = 4;
+        let mut stack: Vec<Scope> = Vec::new();
+        while let (Some(len), Some(kind)) = (u16_at(symbols, at), u16_at(symbols, at + 2)) {
+            let Some(data) = symbols.get(at + 4..at + 2 + len as usize) else {
+                break;
+            };
+            at += 2 + len as usize;
+            let function = match stack.last() {
+                Some(Scope::Function(index)) => Some(*index),
+                _ => None,
+            };
+            match kind {
+                S_GPROC32 | S_LPROC32 | S_GPROC32_ID | S_LPROC32_ID | S_LPROC32_DPC
+                | S_LPROC32_DPC_ID => {
+                    let (Some(size), Some(offset), Some(segment)) =
+                        (u32_at(data, 12), u32_at(data, 28), u16_at(data, 32))
+                    else {
+                        continue;
+                    };
+                    let Some(entry) = rva(segment, offset) else {
+                        stack.push(Scope::Other);
+                        continue;
+                    };
+                    let ty = u32_at(data, 24).unwrap_or(0);
+                    let ty = match kind {
+                        S_GPROC32_ID | S_LPROC32_ID | S_LPROC32_DPC_ID => ipi.func_id_type(ty),
+                        _ => Some(ty),
+                    };
+                    functions.push(Function {
+                        name: cstr_at(data, 35),
+                        entry,
+                        ranges: vec![(entry, size as u64)],
+                        ..Function::default()
+                    });
+                    params_left.push(ty.and_then(|ty| tpi.param_count(ty)));
+                    stack.push(Scope::Function(functions.len() - 1));
+                }
+                S_INLINESITE | S_INLINESITE2 => {
+                    let enclosing = stack.iter().rev().find_map(|scope| match scope {
+                        Scope::Function(index) => Some(*index),
+                        _ => None,
+                    });
+                    let depth = stack
+                        .iter()
+                        .rev()
+                        .take_while(|scope| !matches!(scope, Scope::Function(_)))
+                        .filter(|scope| matches!(scope, Scope::Inline))
+                        .count();
+                    let binary = data.get(if kind == S_INLINESITE2 { 16 } else { 12 }..);
+                    if let (Some(index), Some(inlinee)) = (enclosing, u32_at(data, 8)) {
+                        let base = functions[index].entry;
+                        let annotations = annotations(binary.unwrap_or_default());
+                        // without its first line, the line offsets mean nothing
+                        let rows = match inlinees.get(&inlinee) {
+                            Some((first_file, first_line)) => annotations
+                                .lines
+                                .into_iter()
+                                .map(|(offset, file, line)| LineRow {
+                                    address: base.saturating_add(offset),
+                                    file: checksums
+                                        .get(&file.unwrap_or(*first_file))
+                                        .copied()
+                                        .unwrap_or(0),
+                                    line: first_line.wrapping_add_signed(line),
+                                    ..LineRow::default()
+                                })
+                                .collect(),
+                            None => Vec::new(),
+                        };
+                        functions[index].inlined.push(Inlined {
+                            name: ipi.func_id_name(inlinee),
+                            ranges: annotations
+                                .ranges
+                                .into_iter()
+                                .map(|(offset, size)| (base.saturating_add(offset), size))
+                                .collect(),
+                            depth,
+                            lines: rows,
+                            ..Inlined::default()
+                        });
+                    }
+                    stack.push(Scope::Inline);
+                }
+                S_BLOCK32 | S_THUNK32 | S_WITH32 | S_SEPCODE => stack.push(Scope::Other),
+                S_END | S_PROC_ID_END | S_INLINESITE_END => {
+                    stack.pop();
+                }
+                S_REGREL32 | S_BPREL32 | S_LOCAL => {
+                    let Some(index) = function else {
+                        continue;
+                    };
+                    let (name, is_param) = match kind {
+                        S_REGREL32 => (cstr_at(data, 10), None),
+                        S_BPREL32 => (cstr_at(data, 8), None),
+                        _ => (
+                            cstr_at(data, 6),
+                            u16_at(data, 4).map(|flags| flags & LOCAL_IS_PARAM != 0),
+                        ),
+                    };
+                    // the parameters come first; the type says how many there are
+                    let is_param = is_param.unwrap_or_else(|| match &mut params_left[index] {
+                        Some(0) => false,
+                        Some(left) => {
+                            *left -= 1;
+                            true
+                        }
+                        None => false,
+                    });
+                    if is_param {
+                        functions[index].params.push(name.unwrap_or_default());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // public functions without module symbols
+    let known: HashSet<u64> = functions.iter().map(|function| function.entry).collect();
+    let mut publics: HashMap<u64, String> = HashMap::new();
+    let symbol_records = u16_at(&dbi, 20).filter(|stream| *stream != NIL_STREAM);
+    let records = match symbol_records {
+        Some(stream) => msf.stream(stream as usize)?,
+        None => Vec::new(),
+    };
+    let mut at = 0;
+    while let (Some(len), Some(kind)) = (u16_at(&records, at), u16_at(&records, at + 2)) {
+        let Some(data) = records.get(at + 4..at + 2 + len as usize) else {
+            break;
+        };
+        at += 2 + len as usize;
+        let (Some(flags), Some(offset), Some(segment)) =
+            (u32_at(data, 0), u32_at(data, 4), u16_at(data, 8))
+        else {
+            continue;
+        };
+        if kind != S_PUB32 || flags & PUB_FUNCTION == 0 {
+            continue;
+        }
+        if let (Some(entry), Some(name)) = (rva(segment, offset), cstr_at(data, 10)) {
+            if !known.contains(&entry) {
+                publics.entry(entry).or_insert(name);
+            }
+        }
+    }
+    let mut publics: Vec<(u64, String)> = publics.into_iter().collect();
+    publics.sort_unstable();
+    functions.extend(publics.into_iter().map(|(entry, name)| Function {
+        linkage_name: Some(name),
+        entry,
+        ..Function::default()
+    }));
+    lines.sort();
+    Ok((functions, lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An MSF file with 512 byte blocks holding `streams`
+    fn msf(streams: &[Vec<u8>]) -> Vec<u8> {
+        let block = 512;
+        let mut blocks: Vec<Vec<u8>> = vec![Vec::new(); 3];
+        let mut directory = (streams.len() as u32).to_le_bytes().to_vec();
+        for stream in streams {
+            directory.extend((stream.len() as u32).to_le_bytes());
+        }
+        for stream in streams {
+            for chunk in stream.chunks(block) {
+                directory.extend((blocks.len() as u32).to_le_bytes());
+                blocks.push(chunk.to_vec());
+            }
+        }
+        // the directory fits a block, listed by the block map in block 2
+        let directory_block = blocks.len() as u32;
+        blocks[2] = directory_block.to_le_bytes().to_vec();
+        let mut superblock = MSF_MAGIC.to_vec();
+        for value in [
+            block as u32,
+            1,
+            directory_block + 1,
+            directory.len() as u32,
+            0,
+            2,
+        ] {
+            superblock.extend(value.to_le_bytes());
+        }
+        blocks[0] = superblock;
+        blocks.push(directory);
+        blocks
+            .into_iter()
+            .flat_map(|mut data| {
+                data.resize(block, 0);
+                data
+            })
+            .collect()
+    }
+
+    fn record(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(data.len().next_multiple_of(4), 0);
+        let mut record = ((data.len() + 2) as u16).to_le_bytes().to_vec();
+        record.extend(kind.to_le_bytes());
+        record.extend(data);
+        record
+    }
+
+    #[test]
+    fn pdb_functions() {
+        // types: a procedure of two parameters; ids: the inlined function
+        let mut tpi = vec![0; 56];
+        tpi[4..8].copy_from_slice(&56u32.to_le_bytes());
+        tpi[8..12].copy_from_slice(&0x1000u32.to_le_bytes());
+        let mut ipi = tpi.clone();
+        tpi.extend(record(
+            LF_PROCEDURE,
+            &[0x74, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0],
+        ));
+        let mut func_id = vec![0; 8];
+        func_id.extend(b"square\0");
+        ipi.extend(record(LF_FUNC_ID, &func_id));
+
+        // the proc, two register relative parameters and a local, an inline site
+        let mut symbols = 4u32.to_le_bytes().to_vec();
+        let mut proc = vec![0; 12];
+        proc.extend(0x40u32.to_le_bytes());
+        proc.extend([0; 8]);
+        proc.extend(0x1000u32.to_le_bytes());
+        proc.extend(0x10u32.to_le_bytes());
+        proc.extend(1u16.to_le_bytes());
+        proc.push(0);
+        proc.extend(b"main\0");
+        symbols.extend(record(S_GPROC32, &proc));
+        for name in ["argc", "argv", "local"] {
+            let mut regrel = vec![0; 10];
+            regrel.extend(name.as_bytes());
+            regrel.push(0);
+            symbols.extend(record(S_REGREL32, &regrel));
+        }
+        let mut site = vec![0; 8];
+        site.extend(0x1000u32.to_le_bytes());
+        // code offset 8 a line down, code length 2, a line up, code length 2
+        site.extend([11, 0x28, 4, 2, 11, 0x30, 4, 2]);
+        symbols.extend(record(S_INLINESITE, &site));
+        symbols.extend(record(S_INLINESITE_END, &[]));
+        symbols.extend(record(S_END, &[]));
+
+        let mut publics = Vec::new();
+        let mut public = PUB_FUNCTION.to_le_bytes().to_vec();
+        public.extend(0x80u32.to_le_bytes());
+        public.extend(1u16.to_le_bytes());
+        public.extend(b"_helper@4\0");
+        publics.extend(record(S_PUB32, &public));
+
+        // a .text section header at 0x1000
+        let mut headers = b".text\0\0\0".to_vec();
+        headers.extend(0x100u32.to_le_bytes());
+        headers.extend(0x1000u32.to_le_bytes());
+        headers.resize(40, 0);
+
+        // main.c and square.h, then lines 10 and 12 of main
+        let mut c13 = DEBUG_S_FILECHKSMS.to_le_bytes().to_vec();
+        c13.extend(14u32.to_le_bytes());
+        c13.extend([1, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+        c13.extend(DEBUG_S_LINES.to_le_bytes());
+        c13.extend(40u32.to_le_bytes());
+        // offset, segment 1 without columns, size; a block of main.c with two lines
+        c13.extend(0x10u32.to_le_bytes());
+        c13.extend([1, 0, 0, 0]);
+        for value in [0x40u32, 0, 2, 28, 0, 10 | 0x8000_0000, 8, 12] {
+            c13.extend(value.to_le_bytes());
+        }
+        // square starts at line 3 of square.h
+        for value in [DEBUG_S_INLINEELINES, 16, 0, 0x1000, 8, 3] {
+            c13.extend(value.to_le_bytes());
+        }
+
+        let mut module = vec![0; 64];
+        module[34..36].copy_from_slice(&5u16.to_le_bytes());
+        module[36..40].copy_from_slice(&(symbols.len() as u32).to_le_bytes());
+        module[44..48].copy_from_slice(&(c13.len() as u32).to_le_bytes());
+        module.extend(b"main.obj\0main.obj\0");
+        module.resize(module.len().next_multiple_of(4), 0);
+        let mut dbi = vec![0; 64];
+        dbi[20..22].copy_from_slice(&6u16.to_le_bytes());
+        dbi[24..28].copy_from_slice(&(module.len() as u32).to_le_bytes());
+        dbi[48..52].copy_from_slice(&22u32.to_le_bytes());
+        dbi.extend(module);
+        let mut optional = [NIL_STREAM; 11];
+        optional[DBG_SECTION_HDR] = 7;
+        dbi.extend(optional.iter().flat_map(|stream| stream.to_le_bytes()));
+
+        let mut info = vec![0; 28];
+        info[8..12].copy_from_slice(&3u32.to_le_bytes());
+        info[12..28].copy_from_slice(&[0xab; 16]);
+        // the named stream map: "/names" is stream 8
+        info.extend(7u32.to_le_bytes());
+        info.extend(b"/names\0");
+        for value in [1u32, 1, 1, 1, 0, 0, 8] {
+            info.extend(value.to_le_bytes());
+        }
+        let mut names = vec![0xfe, 0xef, 0xfe, 0xef, 1, 0, 0, 0, 17, 0, 0, 0];
+        names.extend(b"\0main.c\0square.h\0");
+        symbols.extend(c13);
+        let pdb = msf(&[
+            vec![],
+            info,
+            tpi,
+            dbi,
+            ipi,
+            symbols,
+            publics,
+            headers,
+            names,
+        ]);
+
+        assert_eq!(
+            signature(&Msf::parse(&pdb).unwrap()).unwrap(),
+            ([0xab; 16], 3)
+        );
+        let (functions, lines) = read(&pdb).unwrap();
+        assert_eq!(functions.len(), 2);
+        let main = &functions[0];
+        assert_eq!(main.name.as_deref(), Some("main"));
+        assert_eq!(
+            (main.entry, main.ranges.clone()),
+            (0x1010, vec![(0x1010, 0x40)])
+        );
+        assert_eq!(main.params, ["argc", "argv"]);
+        assert_eq!(main.inlined.len(), 1);
+        assert_eq!(main.inlined[0].name.as_deref(), Some("square"));
+        assert_eq!(main.inlined[0].ranges, [(0x1018, 4)]);
+        assert_eq!(functions[1].linkage_name.as_deref(), Some("_helper@4"));
+        assert_eq!(functions[1].entry, 0x1080);
+
+        assert_eq!(lines.files, ["main.c", "square.h"]);
+        assert_eq!(lines.find(0x100f), None);
+        assert_eq!(lines.find(0x1012).map(|row| row.line), Some(10));
+        assert_eq!(lines.find(0x1019).map(|row| row.line), Some(12));
+        assert_eq!(lines.find(0x1050), None);
+        // the line table holds the call site of an inlined function, its annotations its lines
+        let info = crate::debuginfo::DebugInfo::from_pdb(&pdb).unwrap();
+        let frames = |va: u64| -> Vec<String> {
+            info.line_for_va(va)
+                .iter()
+                .map(|frame| frame.to_string())
+                .collect()
+        };
+        assert_eq!(
+            frames(0x1019),
+            ["square at square.h:4", "main at main.c:12"]
+        );
+        assert_eq!(
+            frames(0x101b),
+            ["square at square.h:3", "main at main.c:12"]
+        );
+    }
+}
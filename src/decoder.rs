@@ -0,0 +1,203 @@
+//! Decoding instructions without a workspace.
+//!
+//! [`decode`] decodes the instruction at the start of a byte slice for a [`Mode`], and
+//! [`instructions`] sweeps a slice into instructions, neither of them allocating. An
+//! [`Instruction`] says how long it is and where the flow goes after it, which is what a tracer
+//! stepping over code or a patcher finding instruction boundaries needs.
+//!
+//! x86 in its three modes is decoded by iced-x86, with any of the features bringing it in
+//! (`assembler`, `realmode`, `gadgets`); [`Instruction::x86`] has the full instruction, to
+//! format or look at the operands of. Without those features x86 doesn't decode. SPARC and
+//! s390x are decoded by the stub decoders of [`crate::stubdis`], as far as code flow goes.
+
+use crate::{
+    constants::{ARCH_AMD64, ARCH_I386, ARCH_I8086, ARCH_MASK, ARCH_S390X, ARCH_SPARC, ARCH_SPARC64},
+    stubdis,
+};
+
+pub use crate::stubdis::Flow;
+
+/// What to decode the bytes as
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    X86_16,
+    X86_32,
+    X86_64,
+    Sparc,
+    Sparc64,
+    S390x,
+}
+
+impl Mode {
+    /// The mode of an `ARCH_*` value, which may be part of a set of instruction flags
+    pub fn from_arch(arch: i32) -> Option<Self> {
+        match arch & ARCH_MASK as i32 {
+            ARCH_I8086 => Some(Mode::X86_16),
+            ARCH_I386 => Some(Mode::X86_32),
+            ARCH_AMD64 => Some(Mode::X86_64),
+            ARCH_SPARC => Some(Mode::Sparc),
+            ARCH_SPARC64 => Some(Mode::Sparc64),
+            ARCH_S390X => Some(Mode::S390x),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub va: u64,
+    pub size: usize,
+    pub flow: Flow,
+    /// The instruction after this one runs before the branch is taken
+    pub delay_slot: bool,
+    #[cfg(feature = "iced-x86")]
+    x86: Option<iced_x86::Instruction>,
+}
+
+impl Instruction {
+    /// The iced-x86 instruction, for x86
+    #[cfg(feature = "iced-x86")]
+    pub fn x86(&self) -> Option<&iced_x86::Instruction> {
+        self.x86.as_ref()
+    }
+}
+
+impl From<stubdis::StubInsn> for Instruction {
+    fn from(insn: stubdis::StubInsn) -> Self {
+        Instruction {
+            va: insn.va,
+            size: insn.size,
+            flow: insn.flow,
+            delay_slot: insn.delay_slot,
+            #[cfg(feature = "iced-x86")]
+            x86: None,
+        }
+    }
+}
+
+/// Decode the instruction at the start of `bytes`, which are at `va`. None if it doesn't
+/// decode, is cut short by the end of `bytes`, or the mode has no decoder in this build.
+pub fn decode(bytes: &[u8], va: u64, mode: Mode) -> Option<Instruction> {
+    match mode {
+        Mode::X86_16 => x86(bytes, va, 16),
+        Mode::X86_32 => x86(bytes, va, 32),
+        Mode::X86_64 => x86(bytes, va, 64),
+        Mode::Sparc => stubdis::decode(ARCH_SPARC, va, bytes).map(Instruction::from),
+        Mode::Sparc64 => stubdis::decode(ARCH_SPARC64, va, bytes).map(Instruction::from),
+        Mode::S390x => stubdis::decode(ARCH_S390X, va, bytes).map(Instruction::from),
+    }
+}
+
+/// The instructions of `bytes` one after the other, up to the first which doesn't decode
+pub fn instructions(bytes: &[u8], va: u64, mode: Mode) -> Instructions<'_> {
+    Instructions {
+        bytes,
+        va,
+        mode,
+        offset: 0,
+    }
+}
+
+/// A linear sweep of instructions, see [`instructions`]
+#[derive(Clone, Debug)]
+pub struct Instructions<'a> {
+    bytes: &'a [u8],
+    va: u64,
+    mode: Mode,
+    offset: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        let rest = self.bytes.get(self.offset..)?;
+        let insn = decode(rest, self.va.wrapping_add(self.offset as u64), self.mode)?;
+        self.offset += insn.size;
+        Some(insn)
+    }
+}
+
+#[cfg(feature = "iced-x86")]
+fn x86(bytes: &[u8], va: u64, bitness: u32) -> Option<Instruction> {
+    use iced_x86::{Decoder, DecoderOptions, FlowControl};
+
+    let mut decoder = Decoder::with_ip(bitness, bytes, va, DecoderOptions::NONE);
+    let insn = decoder.decode();
+    if insn.is_invalid() {
+        return None;
+    }
+    let near = || (!insn.is_jmp_far() && !insn.is_call_far()).then(|| insn.near_branch_target());
+    let flow = match insn.flow_control() {
+        FlowControl::UnconditionalBranch => Flow::Branch {
+            target: near(),
+            conditional: false,
+        },
+        FlowControl::ConditionalBranch => Flow::Branch {
+            target: near(),
+            conditional: true,
+        },
+        FlowControl::Call => Flow::Call(near()),
+        FlowControl::IndirectBranch => Flow::Branch {
+            target: None,
+            conditional: false,
+        },
+        FlowControl::IndirectCall => Flow::Call(None),
+        FlowControl::Return => Flow::Return,
+        FlowControl::Exception => Flow::Halt,
+        _ => Flow::Fall,
+    };
+    Some(Instruction {
+        va,
+        size: insn.len(),
+        flow,
+        delay_slot: false,
+        x86: Some(insn),
+    })
+}
+
+#[cfg(not(feature = "iced-x86"))]
+fn x86(_bytes: &[u8], _va: u64, _bitness: u32) -> Option<Instruction> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_without_workspace() {
+        // sparc: call +0x10; nop (in the delay slot); retl
+        let code = [
+            0x40, 0, 0, 4, 0x01, 0, 0, 0, 0x81, 0xc3, 0xe0, 0x08,
+        ];
+        let insns: Vec<Instruction> = instructions(&code, 0x1000, Mode::Sparc).collect();
+        assert_eq!(insns.len(), 3);
+        assert_eq!(insns[0].flow, Flow::Call(Some(0x1010)));
+        assert!(insns[0].delay_slot);
+        assert_eq!(insns[2].flow, Flow::Return);
+        assert_eq!(Mode::from_arch(ARCH_S390X), Some(Mode::S390x));
+
+        // x86-64: jne +2; call rel32; ud2
+        let code = [0x75, 0x02, 0xe8, 0, 0, 0, 0, 0x0f, 0x0b];
+        let insns: Vec<Instruction> = instructions(&code, 0x400000, Mode::X86_64).collect();
+        if cfg!(feature = "iced-x86") {
+            let flows: Vec<Flow> = insns.iter().map(|insn| insn.flow).collect();
+            assert_eq!(
+                flows,
+                [
+                    Flow::Branch {
+                        target: Some(0x400004),
+                        conditional: true
+                    },
+                    Flow::Call(Some(0x400007)),
+                    Flow::Halt
+                ]
+            );
+            assert_eq!(decode(&code[2..5], 0, Mode::X86_64), None);
+        } else {
+            assert!(insns.is_empty());
+        }
+    }
+}
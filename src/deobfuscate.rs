@@ -0,0 +1,398 @@
+//! Opaque predicate and junk code detection for obfuscated code.
+//!
+//! [`analyze`] runs each block of a lifted [`Function`] symbolically and reports:
+//!
+//! * conditional branches which always go the same way (opaque predicates), along with the
+//!   edge which is never taken and the blocks only reachable through such edges,
+//! * runs of instructions which leave registers, flags and memory as they found them (`push
+//!   eax; pop eax`, `add eax, 5; sub eax, 5`, `xchg` pairs),
+//! * instructions whose only effect is a register write overwritten before it is read.
+//!
+//! A block with a single predecessor starts from the state its predecessor ended with, so a
+//! predicate set up in one block and tested in the next is still found. [`simplify`] then
+//! rewrites the function without the dead edges, the dead blocks and the junk, and
+//! [`annotate`] comments the findings in a workspace.
+
+use crate::{
+    envi::registers::RegId,
+    symbolic::{Function, Insn, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::collections::BTreeSet;
+
+/// The longest run of instructions checked for having no effect
+const MAX_JUNK_RUN: usize = 16;
+
+/// A conditional branch which always goes the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpaquePredicate {
+    pub block: u64,
+    /// The address of the branch
+    pub va: u64,
+    /// Whether the branch is always taken, rather than never
+    pub taken: bool,
+    /// The successor which is never reached through the branch
+    pub dead: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JunkKind {
+    /// The instructions leave everything as they found it
+    NoEffect,
+    /// The instruction writes a register which is overwritten before it is read
+    DeadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Junk {
+    pub block: u64,
+    /// The addresses of the instructions
+    pub insns: Vec<u64>,
+    pub kind: JunkKind,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub opaque: Vec<OpaquePredicate>,
+    pub junk: Vec<Junk>,
+    /// Blocks which are only reachable through the dead edges of opaque predicates
+    pub dead_blocks: Vec<u64>,
+}
+
+impl Report {
+    /// The edges which are never taken, as (from block, to block)
+    pub fn dead_edges(&self) -> Vec<(u64, u64)> {
+        self.opaque.iter().map(|p| (p.block, p.dead)).collect()
+    }
+}
+
+pub fn analyze(func: &Function) -> Report {
+    let mut report = Report::default();
+    let states = func.block_states();
+    for (va, state) in &states {
+        let block = &func.blocks[va];
+        if let Terminator::Branch {
+            cond,
+            taken,
+            fallthrough,
+        } = block.end
+        {
+            if taken != fallthrough {
+                if let Some(always) = state.condition(cond) {
+                    report.opaque.push(OpaquePredicate {
+                        block: *va,
+                        va: block.end_va,
+                        taken: always,
+                        dead: if always { fallthrough } else { taken },
+                    });
+                }
+            }
+        }
+        find_junk(func, *va, &mut report.junk);
+    }
+    let live = reachable(func, &report.opaque);
+    report.dead_blocks = func
+        .reverse_postorder()
+        .into_iter()
+        .filter(|va| !live.contains(va))
+        .collect();
+    report
+}
+
+/// The blocks reachable from the entry without taking a dead edge
+fn reachable(func: &Function, opaque: &[OpaquePredicate]) -> BTreeSet<u64> {
+    let dead: BTreeSet<(u64, u64)> = opaque.iter().map(|p| (p.block, p.dead)).collect();
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![func.entry];
+    while let Some(va) = todo.pop() {
+        let Some(block) = func.blocks.get(&va) else {
+            continue;
+        };
+        if !seen.insert(va) {
+            continue;
+        }
+        for succ in block.end.successors() {
+            if !dead.contains(&(va, succ)) {
+                todo.push(succ);
+            }
+        }
+    }
+    seen
+}
+
+fn find_junk(func: &Function, va: u64, junk: &mut Vec<Junk>) {
+    let block = &func.blocks[&va];
+    let insns = &block.insns;
+    let mut in_run = vec![false; insns.len()];
+    let mut start = 0;
+    while start < insns.len() {
+        let mut state = State::new(func.arch);
+        let mut longest = None;
+        let mut sets_flags = false;
+        for (end, insn) in insns.iter().enumerate().skip(start).take(MAX_JUNK_RUN) {
+            if insn.stmts.contains(&Stmt::Unknown) {
+                break;
+            }
+            sets_flags |= insn.stmts.iter().any(|s| matches!(s, Stmt::Flags(..)));
+            state.exec_insn(insn);
+            let no_effect = state.registers_unchanged()
+                && state
+                    .stores()
+                    .iter()
+                    .all(|(addr, _)| state.below_stack(addr))
+                && (!sets_flags || flags_dead(func, va, end + 1));
+            if no_effect {
+                longest = Some(end);
+            }
+        }
+        match longest {
+            Some(end) => {
+                junk.push(Junk {
+                    block: va,
+                    insns: insns[start..=end].iter().map(|insn| insn.va).collect(),
+                    kind: JunkKind::NoEffect,
+                });
+                in_run[start..=end].fill(true);
+                start = end + 1;
+            }
+            None => start += 1,
+        }
+    }
+    for (idx, insn) in insns.iter().enumerate() {
+        if in_run[idx] || insn.stmts.is_empty() {
+            continue;
+        }
+        let written: Option<BTreeSet<_>> = insn
+            .stmts
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Set(reg, _) => Some(*reg),
+                _ => None,
+            })
+            .collect();
+        let dead =
+            written.is_some_and(|regs| regs.iter().all(|reg| overwritten(&insns[idx + 1..], *reg)));
+        if dead {
+            junk.push(Junk {
+                block: va,
+                insns: vec![insn.va],
+                kind: JunkKind::DeadWrite,
+            });
+        }
+    }
+}
+
+/// Whether the flags are set again before anything can read them, from the instruction at
+/// `from` on
+fn flags_dead(func: &Function, va: u64, from: usize) -> bool {
+    let block = &func.blocks[&va];
+    for insn in &block.insns[from..] {
+        for stmt in &insn.stmts {
+            match stmt {
+                Stmt::Unknown => return false,
+                Stmt::Flags(..) => return true,
+                _ => {}
+            }
+        }
+    }
+    block.end == Terminator::Return
+}
+
+/// Whether `reg` is written by the instructions before it is read. A register live at the end
+/// of the block is assumed to be read.
+fn overwritten(insns: &[Insn], reg: RegId) -> bool {
+    for insn in insns {
+        for stmt in &insn.stmts {
+            match stmt.reads() {
+                None => return false,
+                Some(reads) if reads.contains(&reg) => return false,
+                _ => {}
+            }
+            if matches!(stmt, Stmt::Set(r, _) if *r == reg) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Rewrite the function without the findings of `report`: opaque predicates become jumps, the
+/// dead blocks and the junk instructions are removed
+pub fn simplify(func: &mut Function, report: &Report) {
+    for predicate in &report.opaque {
+        if let Some(block) = func.blocks.get_mut(&predicate.block) {
+            if let Terminator::Branch {
+                taken, fallthrough, ..
+            } = block.end
+            {
+                let live = if predicate.taken { taken } else { fallthrough };
+                block.end = Terminator::Jump(live);
+            }
+        }
+    }
+    for va in &report.dead_blocks {
+        func.blocks.remove(va);
+    }
+    for junk in &report.junk {
+        if let Some(block) = func.blocks.get_mut(&junk.block) {
+            block.insns.retain(|insn| !junk.insns.contains(&insn.va));
+        }
+    }
+}
+
+/// Comment the findings of `report` in the workspace, leaving existing comments alone
+pub fn annotate(workspace: &mut VivWorkspace, report: &Report) {
+    for predicate in &report.opaque {
+        let comment = if predicate.taken {
+            "opaque predicate: always taken"
+        } else {
+            "opaque predicate: never taken"
+        };
+        workspace.set_comment(predicate.va as i32, comment, true);
+    }
+    for junk in &report.junk {
+        let comment = match junk.kind {
+            JunkKind::NoEffect => "junk code: no effect",
+            JunkKind::DeadWrite => "junk code: dead write",
+        };
+        workspace.set_comment(junk.insns[0] as i32, comment, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envi::{flags::Condition, registers::RegisterModel, Arch};
+    use crate::symbolic::{BinOp, Block, Expr, FlagOp};
+
+    fn insn(va: u64, stmts: Vec<Stmt>) -> Insn {
+        Insn { va, stmts }
+    }
+
+    #[test]
+    fn opaque_and_junk() {
+        let model = RegisterModel::new(Arch::I386);
+        let reg = |name| model.by_name(name).unwrap();
+        let (eax, ecx, edx, esp) = (reg("eax"), reg("ecx"), reg("edx"), reg("esp"));
+        let r = Expr::Reg;
+        let add = |a, c| Expr::Binary(BinOp::Add, Box::new(a), Box::new(Expr::Const(c)));
+        let mut func = Function::new(Arch::I386, 0x1000);
+        // ecx = eax * (eax + 1) is even, so the jnz is never taken
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Set(ecx, add(r(eax), 1))]),
+                insn(
+                    0x1003,
+                    vec![Stmt::Set(
+                        ecx,
+                        Expr::Binary(BinOp::Mul, Box::new(r(ecx)), Box::new(r(eax))),
+                    )],
+                ),
+                insn(
+                    0x1006,
+                    vec![Stmt::Flags(FlagOp::And, r(ecx), Expr::Const(1))],
+                ),
+            ],
+            end_va: 0x100c,
+            end: Terminator::Branch {
+                cond: Condition::NotZero,
+                taken: 0x1100,
+                fallthrough: 0x1010,
+            },
+        });
+        func.add_block(Block {
+            va: 0x1010,
+            insns: vec![
+                // push eax; pop eax
+                insn(
+                    0x1010,
+                    vec![
+                        Stmt::Set(esp, add(r(esp), 0xffff_fffc)),
+                        Stmt::Store(r(esp), r(eax)),
+                    ],
+                ),
+                insn(
+                    0x1011,
+                    vec![
+                        Stmt::Set(eax, Expr::Load(Box::new(r(esp)))),
+                        Stmt::Set(esp, add(r(esp), 4)),
+                    ],
+                ),
+                insn(0x1012, vec![Stmt::Set(edx, Expr::Const(1))]),
+                insn(0x1017, vec![Stmt::Set(edx, Expr::Const(2))]),
+            ],
+            end_va: 0x101c,
+            end: Terminator::Return,
+        });
+        func.add_block(Block {
+            va: 0x1100,
+            insns: vec![insn(0x1100, vec![Stmt::Unknown])],
+            end_va: 0x1101,
+            end: Terminator::Return,
+        });
+
+        let report = analyze(&func);
+        assert_eq!(
+            report.opaque,
+            [OpaquePredicate {
+                block: 0x1000,
+                va: 0x100c,
+                taken: false,
+                dead: 0x1100,
+            }]
+        );
+        assert_eq!(report.dead_edges(), [(0x1000, 0x1100)]);
+        assert_eq!(report.dead_blocks, [0x1100]);
+        assert_eq!(report.junk.len(), 2);
+        assert_eq!(report.junk[0].insns, [0x1010, 0x1011]);
+        assert_eq!(report.junk[0].kind, JunkKind::NoEffect);
+        assert_eq!(report.junk[1].insns, [0x1012]);
+        assert_eq!(report.junk[1].kind, JunkKind::DeadWrite);
+
+        simplify(&mut func, &report);
+        assert_eq!(func.blocks.len(), 2);
+        assert_eq!(func.blocks[&0x1000].end, Terminator::Jump(0x1010));
+        assert_eq!(func.blocks[&0x1010].insns.len(), 1);
+    }
+
+    #[test]
+    fn predicate_across_blocks() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let rax = model.by_name("rax").unwrap();
+        let mut func = Function::new(Arch::Amd64, 0);
+        func.add_block(Block {
+            va: 0,
+            insns: vec![insn(0, vec![Stmt::Set(rax, Expr::Const(5))])],
+            end_va: 5,
+            end: Terminator::Jump(0x10),
+        });
+        func.add_block(Block {
+            va: 0x10,
+            insns: vec![insn(
+                0x10,
+                vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(rax), Expr::Const(5))],
+            )],
+            end_va: 0x14,
+            end: Terminator::Branch {
+                cond: Condition::Zero,
+                taken: 0x20,
+                fallthrough: 0x16,
+            },
+        });
+        for va in [0x16, 0x20] {
+            func.add_block(Block {
+                va,
+                insns: vec![],
+                end_va: va,
+                end: Terminator::Return,
+            });
+        }
+        let report = analyze(&func);
+        assert_eq!(report.opaque.len(), 1);
+        assert!(report.opaque[0].taken);
+        assert_eq!(report.dead_blocks, [0x16]);
+        // the flags are read by the branch, so the compare isn't junk
+        assert!(report.junk.is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+//! Inline hooks at function entries.
+//!
+//! A detour overwrites the first bytes of a function with a jump to the hook, which calls back
+//! into a trampoline holding the bytes it overwrote. [`detour`] recognises the jumps hooking
+//! libraries write: `jmp rel32` and `jmp rel8`, `jmp [mem]` (with the pointer right after it on
+//! amd64), `push imm; ret`, `mov reg, imm; jmp reg`, and `ldr x16, #8; br x16` on A64. A
+//! hotpatchable Windows function starts with a two byte `mov edi, edi` after five bytes of
+//! padding, and is hooked by making those a `jmp rel32` and the `mov` a `jmp` back to it.
+//!
+//! [`inventory`] looks at the entry of every function and export of a workspace of a memory
+//! image, and resolves where each detour goes. Compilers write some of these jumps too, an
+//! incremental linking thunk is a `jmp rel32`, so a hook is told by going somewhere else:
+//! into another module, or into memory of no module at all, as injected code is.
+
+use crate::{envi::Arch, memory::Memory, resolve::Resolved, workspace::VivWorkspace};
+use std::{collections::BTreeSet, fmt};
+
+/// The most bytes a detour takes
+const MAX_DETOUR: u64 = 16;
+
+/// The jump a detour is made of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    JmpRel8,
+    JmpRel32,
+    /// `jmp [mem]`
+    JmpIndirect,
+    /// `push imm32; ret`, on amd64 with a `mov dword [rsp + 4], imm32` for the high half
+    PushRet,
+    /// `mov reg, imm; jmp reg`
+    MovJmp,
+    /// A `jmp` back into the padding before the function, which jumps on
+    Hotpatch,
+    /// `ldr xN, #8; br xN` followed by the address
+    LdrBr,
+}
+
+/// A detour at a function entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Detour {
+    pub pattern: Pattern,
+    /// The bytes from the entry the detour takes
+    pub size: u64,
+    /// Where it jumps, if that can be read
+    pub target: Option<u64>,
+}
+
+/// A function entry hooked, or jumping on anyway
+#[derive(Clone, Debug)]
+pub struct Hook {
+    pub va: u64,
+    /// The function's or export's name, if it has one
+    pub name: Option<String>,
+    /// The file the function belongs to
+    pub module: Option<String>,
+    pub detour: Detour,
+    /// The function or module `detour.target` is in
+    pub target: Option<Resolved>,
+}
+
+impl Hook {
+    /// Whether the detour leaves the module of the function: for another module, or for memory
+    /// of none
+    pub fn is_foreign(&self) -> bool {
+        match &self.target {
+            Some(target) => Some(&target.module) != self.module.as_ref(),
+            None => self.detour.target.is_some(),
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.va)?;
+        if let Some(name) = &self.name {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ": {:?}", self.detour.pattern)?;
+        match (&self.target, self.detour.target) {
+            (Some(target), Some(va)) => {
+                write!(f, " to {:#x} {} ({})", va, target, target.module)
+            }
+            (None, Some(va)) => write!(f, " to {:#x} outside the modules", va),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+/// The detour the code at `va` starts with, reading the pointers it jumps through from `memory`
+pub fn detour(memory: &VivWorkspace, arch: Arch, va: u64) -> Option<Detour> {
+    let bytes = read(memory, va, MAX_DETOUR)?;
+    let pointer = |va: u64| {
+        let size = arch.pointer_size();
+        let bytes = read(memory, va, size as u64)?;
+        let mut word = [0u8; 8];
+        word[..size].copy_from_slice(bytes.get(..size)?);
+        Some(u64::from_le_bytes(word))
+    };
+    let found = |pattern, size, target| {
+        Some(Detour {
+            pattern,
+            size,
+            target,
+        })
+    };
+    let rel32 = |at: usize| i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as i64;
+    let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as u64;
+    let quad = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    match arch {
+        Arch::I386 | Arch::Amd64 => {
+            let amd64 = arch == Arch::Amd64;
+            match bytes.as_slice() {
+                // jmp $-5 into the padding, which holds a jmp rel32
+                [0xeb, 0xf9, ..] => {
+                    let pad = read(memory, va.wrapping_sub(5), 5)?;
+                    if pad[0] != 0xe9 {
+                        return None;
+                    }
+                    let rel = i32::from_le_bytes(pad[1..5].try_into().unwrap()) as i64;
+                    found(Pattern::Hotpatch, 2, Some(va.wrapping_add(rel as u64)))
+                }
+                [0xeb, rel, ..] => found(
+                    Pattern::JmpRel8,
+                    2,
+                    Some(va.wrapping_add(2).wrapping_add(*rel as i8 as u64)),
+                ),
+                [0xe9, ..] => found(
+                    Pattern::JmpRel32,
+                    5,
+                    Some(va.wrapping_add(5).wrapping_add(rel32(1) as u64)),
+                ),
+                [0xff, 0x25, ..] => {
+                    let slot = if amd64 {
+                        va.wrapping_add(6).wrapping_add(rel32(2) as u64)
+                    } else {
+                        word(2)
+                    };
+                    // the pointer of `jmp [rip]` is part of the detour
+                    let size = if amd64 && rel32(2) == 0 { 14 } else { 6 };
+                    found(Pattern::JmpIndirect, size, pointer(slot))
+                }
+                [0x68, _, _, _, _, 0xc3, ..] => found(Pattern::PushRet, 6, Some(word(1))),
+                [0x68, _, _, _, _, 0xc7, 0x44, 0x24, 0x04, _, _, _, _, 0xc3, ..] if amd64 => {
+                    found(Pattern::PushRet, 14, Some(word(9) << 32 | word(1)))
+                }
+                // mov rax / r11, imm64; jmp rax / r11
+                [0x48, 0xb8, _, _, _, _, _, _, _, _, 0xff, 0xe0, ..] if amd64 => {
+                    found(Pattern::MovJmp, 12, Some(quad(2)))
+                }
+                [0x49, 0xbb, _, _, _, _, _, _, _, _, 0x41, 0xff, 0xe3, ..] if amd64 => {
+                    found(Pattern::MovJmp, 13, Some(quad(2)))
+                }
+                [0xb8, _, _, _, _, 0xff, 0xe0, ..] if !amd64 => {
+                    found(Pattern::MovJmp, 7, Some(word(1)))
+                }
+                _ => None,
+            }
+        }
+        Arch::A64 => {
+            let ldr = word(0);
+            let br = word(4);
+            // ldr xN, #8; br xN
+            if ldr & 0xffff_ffe0 == 0x5800_0040 && br == 0xd61f_0000 | (ldr & 0x1f) << 5 {
+                found(Pattern::LdrBr, 16, Some(quad(8)))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The detours at the entries of the functions and exports of `workspace`, by VA
+pub fn inventory(workspace: &VivWorkspace) -> Vec<Hook> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let maps: Vec<(u64, u64)> = workspace
+        .get_executable_maps()
+        .into_iter()
+        .map(|(va, bytes)| (va as u32 as u64, bytes.len() as u64))
+        .collect();
+    let entries: BTreeSet<u64> = workspace
+        .get_functions()
+        .into_iter()
+        .chain(workspace.get_exports().into_iter().map(|(va, _)| va))
+        .map(|va| va as u32 as u64)
+        .filter(|va| maps.iter().any(|(mva, size)| mva <= va && *va < mva + size))
+        .collect();
+    let index = workspace.build_symbol_index();
+    entries
+        .into_iter()
+        .filter_map(|va| {
+            let detour = detour(workspace, arch, va)?;
+            Some(Hook {
+                va,
+                name: workspace.get_name(va as i32, false),
+                module: workspace.get_file_by_va(va as i32),
+                detour,
+                target: detour.target.and_then(|target| index.lookup(target)),
+            })
+        })
+        .collect()
+}
+
+/// The `size` bytes at `va`, or as many of them as are mapped up to the end of the map
+fn read(memory: &VivWorkspace, va: u64, size: u64) -> Option<Vec<u8>> {
+    (1..=size).rev().find_map(|size| {
+        let mut bytes = memory.read_memory(va as i32, size as i32)?;
+        bytes.resize(MAX_DETOUR as usize, 0);
+        Some(bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_AMD64, MM_EXEC, MM_READ};
+
+    #[test]
+    fn finds_detours() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.arch = ARCH_AMD64 as u32;
+        let mut code = vec![0xcc; 0x100];
+        // hotpatched at 0x1005: its padding jumps out of the module
+        code[0..5].copy_from_slice(&[0xe9, 0xfb, 0xef, 0xff, 0x7f]);
+        code[5..7].copy_from_slice(&[0xeb, 0xf9]);
+        // jmp [rip]; dq 0x2000 at 0x1010, into the other module
+        code[0x10..0x16].copy_from_slice(&[0xff, 0x25, 0, 0, 0, 0]);
+        code[0x16..0x1e].copy_from_slice(&0x2000u64.to_le_bytes());
+        // a thunk to 0x1005 at 0x1020, and an intact function at 0x1030
+        code[0x20..0x25].copy_from_slice(&[0xe9, 0xe0, 0xff, 0xff, 0xff]);
+        code[0x30..0x33].copy_from_slice(&[0x55, 0x48, 0x89]);
+        // push 0x20000000; mov dword [rsp + 4], 0x7f; ret at 0x1040
+        code[0x40..0x4e].copy_from_slice(&[
+            0x68, 0, 0, 0, 0x20, 0xc7, 0x44, 0x24, 0x04, 0x7f, 0, 0, 0, 0xc3,
+        ]);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "app", code, None);
+        ws.add_segment(0x1000, 0x100, ".text", "app".to_string());
+        ws.add_memory_map(0x2000, MM_READ | MM_EXEC, "hook", vec![0xc3; 0x10], None);
+        ws.add_segment(0x2000, 0x10, ".text", "hook".to_string());
+        ws.make_name(0x2000, "hook.Detour".to_string(), false, true);
+        for va in [0x1005, 0x1010, 0x1020, 0x1030, 0x1040] {
+            ws.add_function(va, vec![(va, 1)]);
+        }
+        ws.add_export(0x1010, "Frob", "app");
+
+        let hooks = inventory(&ws);
+        let found: Vec<(u64, Pattern, Option<u64>, bool)> = hooks
+            .iter()
+            .map(|hook| {
+                (
+                    hook.va,
+                    hook.detour.pattern,
+                    hook.detour.target,
+                    hook.is_foreign(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (0x1005, Pattern::Hotpatch, Some(0x80000000), true),
+                (0x1010, Pattern::JmpIndirect, Some(0x2000), true),
+                (0x1020, Pattern::JmpRel32, Some(0x1005), false),
+                (0x1040, Pattern::PushRet, Some(0x7f_2000_0000), true),
+            ]
+        );
+        assert_eq!(hooks[1].detour.size, 14);
+        assert_eq!(
+            hooks[1].to_string(),
+            "0x1010 app.Frob: JmpIndirect to 0x2000 hook.Detour (hook)"
+        );
+        assert_eq!(
+            hooks[0].to_string(),
+            "0x1005: Hotpatch to 0x80000000 outside the modules"
+        );
+    }
+}
@@ -0,0 +1,639 @@
+//! Devirtualizing calls through vtables, by the classes RTTI names and the vtables constructors
+//! store.
+//!
+//! The vtables are the `LOC_VFTABLE` locations of a workspace and the constants lifted code
+//! stores at the start of an object, where they are followed by pointers to code. RTTI names
+//! the class of a vtable and its bases: the MSVC complete object locator in the slot before
+//! it, or the Itanium `type_info` there. A constructor is a function storing a vtable at its
+//! `this`, and a virtual function one in a vtable slot, taking `this` of that class or one
+//! derived from it.
+//!
+//! [`devirtualize`] goes through the functions with IL and the targets of their indirect calls,
+//! as the lifter gives them (`call [eax + 8]` as a load of `eax + 8`), and resolves the calls
+//! loading the target from a vtable the object points at. The object's class is known exactly
+//! when the function stored the vtable or called a constructor on it; a call on `this` in a
+//! virtual function goes to any of the implementations of the class and its derived classes.
+//! Objects are followed across calls in the registers the calling convention preserves.
+
+use crate::{
+    constants::{BR_PROC, LOC_VFTABLE, REF_CODE},
+    envi::{
+        registers::{RegId, RegisterModel},
+        Arch,
+    },
+    memory::Memory,
+    osprofile,
+    structs::param,
+    symbolic::{call_argument, BinOp, Expr, Function, State, Stmt},
+    taint::callee_saved,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The most slots read from a vtable
+pub const MAX_SLOTS: usize = 512;
+/// How far up a chain of Itanium base classes is followed
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vtable {
+    pub va: u64,
+    /// The functions in its slots
+    pub slots: Vec<u64>,
+    /// The class RTTI names
+    pub class: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Class {
+    pub name: String,
+    pub vtables: Vec<u64>,
+    /// All the classes it derives from, directly or not
+    pub bases: BTreeSet<String>,
+}
+
+/// The classes RTTI describes, by name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hierarchy {
+    pub classes: BTreeMap<String, Class>,
+}
+
+impl Hierarchy {
+    /// The class `name` and the classes deriving from it
+    pub fn derived(&self, name: &str) -> Vec<&Class> {
+        self.classes
+            .values()
+            .filter(|class| class.name == name || class.bases.contains(name))
+            .collect()
+    }
+
+    /// Whether `base` is `class` or one of its bases
+    pub fn is_base(&self, base: &str, class: &str) -> bool {
+        base == class
+            || self
+                .classes
+                .get(class)
+                .is_some_and(|class| class.bases.contains(base))
+    }
+}
+
+/// A call devirtualized
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Devirtualized {
+    pub call: u64,
+    /// The offset of the slot in the vtable
+    pub slot: u64,
+    pub class: Option<String>,
+    /// Whether the object is of `class` for sure, rather than of it or a class derived from it
+    pub exact: bool,
+    /// The functions the call may go to
+    pub targets: Vec<u64>,
+}
+
+/// Pointers and strings read from the memory of a workspace
+struct Image<'a> {
+    workspace: &'a VivWorkspace,
+    ptr: u64,
+    code: Vec<(u64, u64)>,
+}
+
+impl<'a> Image<'a> {
+    fn new(workspace: &'a VivWorkspace, arch: Arch) -> Self {
+        Image {
+            workspace,
+            ptr: arch.pointer_size() as u64,
+            code: workspace
+                .get_executable_maps()
+                .into_iter()
+                .map(|(va, bytes)| (va as u32 as u64, bytes.len() as u64))
+                .collect(),
+        }
+    }
+
+    fn word(&self, va: u64, size: u64) -> Option<u64> {
+        let bytes = self.workspace.read_memory(va as i32, size as i32)?;
+        let mut word = [0u8; 8];
+        word[..size as usize].copy_from_slice(bytes.get(..size as usize)?);
+        Some(u64::from_le_bytes(word))
+    }
+
+    fn pointer(&self, va: u64) -> Option<u64> {
+        self.word(va, self.ptr)
+    }
+
+    fn is_code(&self, va: u64) -> bool {
+        self.code
+            .iter()
+            .any(|(start, size)| *start <= va && va < start + size)
+    }
+
+    fn cstring(&self, va: u64) -> Option<String> {
+        let mut bytes = Vec::new();
+        while bytes.len() < 256 {
+            let byte = self.word(va + bytes.len() as u64, 1)? as u8;
+            if byte == 0 {
+                return String::from_utf8(bytes).ok();
+            }
+            bytes.push(byte);
+        }
+        None
+    }
+
+    /// The functions in the slots of the vtable at `va`, up to the first which isn't code or
+    /// the start of another of `vtables`
+    fn slots(&self, va: u64, vtables: &BTreeSet<u64>) -> Vec<u64> {
+        let mut slots = Vec::new();
+        while slots.len() < MAX_SLOTS {
+            let at = va + slots.len() as u64 * self.ptr;
+            if !slots.is_empty() && vtables.contains(&at) {
+                break;
+            }
+            match self.pointer(at) {
+                Some(target) if self.is_code(target) => slots.push(target),
+                _ => break,
+            }
+        }
+        slots
+    }
+
+    /// The class of the vtable at `va` and its bases, by MSVC or Itanium RTTI
+    fn rtti(&self, va: u64) -> Option<(String, BTreeSet<String>)> {
+        let info = self.pointer(va.checked_sub(self.ptr)?)?;
+        self.msvc(info).or_else(|| self.itanium(info))
+    }
+
+    /// A complete object locator: absolute pointers on 32 bits, image relative ones on 64
+    fn msvc(&self, col: u64) -> Option<(String, BTreeSet<String>)> {
+        let at = |va: u64| -> Option<u64> {
+            let value = self.word(va, 4)?;
+            match self.word(col, 4)? {
+                0 => Some(value),
+                // the locator holds its own offset from the image base
+                1 => Some(col - self.word(col + 20, 4)? + value),
+                _ => None,
+            }
+        };
+        let name = |td: u64| -> Option<String> {
+            let name = self.cstring(td + 2 * self.ptr)?;
+            let name = name
+                .strip_prefix(".?AV")
+                .or_else(|| name.strip_prefix(".?AU"))?
+                .strip_suffix("@@")?;
+            Some(name.rsplit('@').collect::<Vec<_>>().join("::"))
+        };
+        let class = name(at(col + 12)?)?;
+        let hierarchy = at(col + 16)?;
+        let count = self.word(hierarchy + 8, 4)?.min(MAX_SLOTS as u64);
+        let array = at(hierarchy + 12)?;
+        // the first base class descriptor is the class itself
+        let bases = (1..count)
+            .filter_map(|i| name(at(at(array + 4 * i)?)?))
+            .collect();
+        Some((class, bases))
+    }
+
+    /// A `type_info`: its vtable, its mangled name, and for a single base the base's
+    /// `type_info`, or for several a count and the bases
+    fn itanium(&self, info: u64) -> Option<(String, BTreeSet<String>)> {
+        let name = |info: u64| demangle(&self.cstring(self.pointer(info + self.ptr)?)?);
+        let class = name(info)?;
+        let mut bases = BTreeSet::new();
+        let mut work = vec![(info, 0)];
+        while let Some((info, depth)) = work.pop() {
+            if depth >= MAX_DEPTH {
+                continue;
+            }
+            let after = info + 2 * self.ptr;
+            if let Some(base) = self.pointer(after).filter(|base| name(*base).is_some()) {
+                bases.extend(name(base));
+                work.push((base, depth + 1));
+                continue;
+            }
+            let count = self.word(after + 4, 4).unwrap_or(0).min(MAX_SLOTS as u64);
+            for i in 0..count {
+                let Some(base) = self.pointer(after + 8 + i * 2 * self.ptr) else {
+                    break;
+                };
+                if let Some(name) = name(base) {
+                    bases.insert(name);
+                    work.push((base, depth + 1));
+                }
+            }
+        }
+        Some((class, bases))
+    }
+}
+
+/// A class name of the Itanium ABI, `3Foo` or `N2ns3FooE`
+fn demangle(mangled: &str) -> Option<String> {
+    let nested = mangled.strip_prefix('N').and_then(|n| n.strip_suffix('E'));
+    let mut rest = nested.unwrap_or(mangled);
+    let mut parts = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let len: usize = rest[..digits].parse().ok()?;
+        let part = rest.get(digits..digits + len)?;
+        parts.push(part);
+        rest = &rest[digits + len..];
+    }
+    (!parts.is_empty() && (nested.is_some() || parts.len() == 1)).then(|| parts.join("::"))
+}
+
+/// Where a method gets `this` at a call: `ecx` for MSVC on i386, else the first argument
+fn this_arg(arch: Arch, windows: bool) -> Option<Expr> {
+    match arch {
+        Arch::I386 if windows => RegisterModel::new(arch).by_name("ecx").map(Expr::Reg),
+        _ => call_argument(arch, windows, 0),
+    }
+}
+
+/// Where a method has `this` as it starts
+fn this_param(arch: Arch, windows: bool) -> Option<Expr> {
+    match arch {
+        Arch::I386 if windows => this_arg(arch, windows),
+        _ => param(arch, windows, 0),
+    }
+}
+
+/// The target each indirect call loads, before the call, by the VA of the call
+pub type Calls = BTreeMap<u64, Expr>;
+
+/// What is known of the pointers in a run of blocks
+#[derive(Clone, Default)]
+struct Objects {
+    /// The vtable each object points at
+    vtables: BTreeMap<Expr, u64>,
+    /// The pointers which are `this` of the function
+    this: BTreeSet<Expr>,
+}
+
+impl Objects {
+    /// What is still known after a call, which keeps `saved`
+    fn after_call(&self, state: &State, saved: &BTreeSet<RegId>) -> Objects {
+        let mut after = Objects::default();
+        for reg in saved.iter() {
+            let value = state.reg(*reg);
+            if let Some(vtable) = self.vtables.get(&value) {
+                after.vtables.insert(Expr::Reg(*reg), *vtable);
+            }
+            if self.this.contains(&value) {
+                after.this.insert(Expr::Reg(*reg));
+            }
+        }
+        after
+    }
+}
+
+/// What the functions do with vtables
+#[derive(Default)]
+struct Context {
+    windows: bool,
+    ptr: u64,
+    vtables: BTreeMap<u64, Vtable>,
+    hierarchy: Hierarchy,
+    /// The vtable each constructor stores
+    ctors: BTreeMap<u64, u64>,
+    /// The class of `this` of each virtual function
+    methods: BTreeMap<u64, String>,
+    /// The constants stored at the start of an object
+    stored: BTreeSet<u64>,
+    /// The last constant each function stores at its `this`
+    this_stores: BTreeMap<u64, u64>,
+    found: Vec<Devirtualized>,
+}
+
+impl Context {
+    /// Go through `func`, resolving the indirect calls of `calls` into `found`
+    fn walk(&mut self, workspace: &VivWorkspace, fva: u64, func: &Function, calls: &Calls) {
+        let model = RegisterModel::new(func.arch);
+        let saved = callee_saved(&model);
+        let sp = Expr::Reg(model.sp());
+        let preds = func.predecessors();
+        let mut ends: BTreeMap<u64, (State, Objects)> = BTreeMap::new();
+        for va in func.reverse_postorder() {
+            let (mut state, mut objects) = match preds.get(&va).map(Vec::as_slice) {
+                Some([pred]) if *pred != va && ends.contains_key(pred) => ends[pred].clone(),
+                _ => {
+                    let mut objects = Objects::default();
+                    if va == func.entry {
+                        objects.this.extend(this_param(func.arch, self.windows));
+                    }
+                    (State::new(func.arch), objects)
+                }
+            };
+            for insn in func.blocks[&va].insns.iter() {
+                for stmt in insn.stmts.iter() {
+                    match stmt {
+                        Stmt::Store(addr, value) => {
+                            let (addr, value) = (state.eval(addr), state.eval(value));
+                            let object = matches!(addr, Expr::Reg(_) | Expr::Load(_));
+                            if let (true, Some(vtable)) = (object && addr != sp, value.as_const()) {
+                                self.stored.insert(vtable);
+                                if objects.this.contains(&addr) {
+                                    self.this_stores.insert(fva, vtable);
+                                }
+                                if self.vtables.contains_key(&vtable) {
+                                    objects.vtables.insert(addr, vtable);
+                                }
+                            }
+                        }
+                        Stmt::Unknown => {
+                            if let Some(target) = calls.get(&insn.va) {
+                                self.resolve(fva, insn.va, &state, &objects, target);
+                            }
+                            self.constructed(workspace, insn.va, func.arch, &state, &mut objects);
+                            objects = objects.after_call(&state, &saved);
+                        }
+                        _ => {}
+                    }
+                    state.exec(stmt);
+                }
+            }
+            ends.insert(va, (state, objects));
+        }
+    }
+
+    /// Note the object a call to a constructor makes
+    fn constructed(
+        &self,
+        workspace: &VivWorkspace,
+        call: u64,
+        arch: Arch,
+        state: &State,
+        objects: &mut Objects,
+    ) {
+        let Some(this) = this_arg(arch, self.windows) else {
+            return;
+        };
+        for (_, callee, _, rflags) in workspace.get_xrefs_from(call as i32, Some(REF_CODE)) {
+            if rflags & BR_PROC == 0 {
+                continue;
+            }
+            if let Some(vtable) = self.ctors.get(&(callee as u32 as u64)) {
+                objects.vtables.insert(state.eval(&this), *vtable);
+            }
+        }
+    }
+
+    /// Resolve the call at `call` to `target`, a load from a vtable slot
+    fn resolve(&mut self, fva: u64, call: u64, state: &State, objects: &Objects, target: &Expr) {
+        let Expr::Load(slot) = state.eval(target) else {
+            return;
+        };
+        let (vptr, offset) = match *slot {
+            Expr::Binary(BinOp::Add, vptr, offset) => (*vptr, offset.as_const()),
+            vptr => (vptr, Some(0)),
+        };
+        let Some(offset) = offset else {
+            return;
+        };
+        let (vtables, class, exact) = match &vptr {
+            // the function stored the vtable itself
+            Expr::Const(vtable) if self.vtables.contains_key(vtable) => {
+                (vec![*vtable], self.vtables[vtable].class.clone(), true)
+            }
+            Expr::Load(object) => {
+                if let Some(vtable) = objects.vtables.get(object.as_ref()) {
+                    (vec![*vtable], self.vtables[vtable].class.clone(), true)
+                } else if let (true, Some(class)) =
+                    (objects.this.contains(object.as_ref()), self.methods.get(&fva))
+                {
+                    let vtables = self
+                        .hierarchy
+                        .derived(class)
+                        .into_iter()
+                        .flat_map(|class| class.vtables.iter().copied())
+                        .collect();
+                    (vtables, Some(class.clone()), false)
+                } else {
+                    return;
+                }
+            }
+            _ => return,
+        };
+        let ptr = self.ptr;
+        let mut targets: Vec<u64> = vtables
+            .iter()
+            .filter_map(|vtable| self.vtables[vtable].slots.get((offset / ptr) as usize))
+            .copied()
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        if offset % ptr != 0 || targets.is_empty() {
+            return;
+        }
+        self.found.push(Devirtualized {
+            call,
+            slot: offset,
+            class,
+            exact,
+            targets,
+        });
+    }
+}
+
+/// The calls of `calls` in the functions with IL which go through a vtable of a known class,
+/// to the functions they may go to
+pub fn devirtualize(workspace: &VivWorkspace, calls: &Calls) -> Vec<Devirtualized> {
+    let fvas = workspace.get_functions_with_il();
+    let Some(arch) = fvas.first().map(|fva| workspace.get_function_il(*fva).unwrap().arch) else {
+        return Vec::new();
+    };
+    let image = Image::new(workspace, arch);
+    let mut context = Context {
+        windows: osprofile::is_windows(workspace),
+        ptr: image.ptr,
+        ..Context::default()
+    };
+    // the first pass finds the constants stored in objects, and those constructors store
+    for fva in fvas.iter() {
+        let func = workspace.get_function_il(*fva).unwrap();
+        context.walk(workspace, *fva as u32 as u64, func, &Calls::new());
+    }
+
+    let mut candidates: BTreeSet<u64> = workspace
+        .get_locations(Some(LOC_VFTABLE), None)
+        .into_iter()
+        .map(|loc| loc.0 as u32 as u64)
+        .collect();
+    candidates.append(&mut context.stored);
+    for va in candidates.iter() {
+        let slots = image.slots(*va, &candidates);
+        if slots.is_empty() {
+            continue;
+        }
+        let rtti = image.rtti(*va);
+        if let Some((name, bases)) = rtti.as_ref() {
+            let class = context.hierarchy.classes.entry(name.clone()).or_default();
+            class.name = name.clone();
+            class.vtables.push(*va);
+            class.bases.extend(bases.iter().cloned());
+        }
+        let class = rtti.map(|(name, _)| name);
+        context.vtables.insert(*va, Vtable { va: *va, slots, class });
+    }
+    context.ctors = context
+        .this_stores
+        .iter()
+        .filter(|(_, vtable)| context.vtables.contains_key(vtable))
+        .map(|(ctor, vtable)| (*ctor, *vtable))
+        .collect();
+
+    // a virtual function takes `this` of the least derived class with it in a vtable
+    let mut owners: BTreeMap<u64, BTreeSet<&str>> = BTreeMap::new();
+    for vtable in context.vtables.values() {
+        if let Some(class) = vtable.class.as_deref() {
+            for slot in vtable.slots.iter() {
+                owners.entry(*slot).or_default().insert(class);
+            }
+        }
+    }
+    let hierarchy = &context.hierarchy;
+    let methods = owners
+        .iter()
+        .filter_map(|(method, classes)| {
+            let base = classes
+                .iter()
+                .find(|base| classes.iter().all(|class| hierarchy.is_base(base, class)))?;
+            Some((*method, base.to_string()))
+        })
+        .collect();
+    context.methods = methods;
+
+    for fva in fvas.iter() {
+        let func = workspace.get_function_il(*fva).unwrap();
+        context.walk(workspace, *fva as u32 as u64, func, calls);
+    }
+    context.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ},
+        symbolic::{Block, Insn, Terminator},
+    };
+
+    #[test]
+    fn devirtualizes_calls() {
+        let model = RegisterModel::new(Arch::I386);
+        let reg = |name| model.by_name(name).unwrap();
+        let (eax, ecx, esi) = (reg("eax"), reg("ecx"), reg("esi"));
+        let load = |expr| Expr::Load(Box::new(expr));
+        let slot = |base, offset| {
+            load(Expr::binary(BinOp::Add, Expr::Reg(base), Expr::Const(offset), 32))
+        };
+        let function = |entry, insns: Vec<(u64, Stmt)>| {
+            let mut func = Function::new(Arch::I386, entry);
+            func.add_block(Block {
+                va: entry,
+                insns: insns
+                    .into_iter()
+                    .map(|(va, stmt)| Insn {
+                        va,
+                        stmts: vec![stmt],
+                    })
+                    .collect(),
+                end_va: entry + 0x20,
+                end: Terminator::Return,
+            });
+            func
+        };
+
+        // MSVC RTTI of Base and of Derived deriving from it: the type descriptors, base class
+        // descriptors and arrays, hierarchy descriptors and complete object locators
+        let mut data = vec![0u8; 0x500];
+        let mut put = |va: usize, value: u32| {
+            data[va - 0x3000..va - 0x2ffc].copy_from_slice(&value.to_le_bytes())
+        };
+        let layout = [
+            (0x3200, 0x3100),
+            (0x3220, 0x3140),
+            (0x3240, 0x3200),
+            (0x3250, 0x3220),
+            (0x3254, 0x3200),
+            (0x3268, 1),
+            (0x326c, 0x3240),
+            (0x3288, 2),
+            (0x328c, 0x3250),
+            (0x330c, 0x3100),
+            (0x3310, 0x3260),
+            (0x332c, 0x3140),
+            (0x3330, 0x3280),
+            // the vtables, each after its locator
+            (0x3400, 0x3300),
+            (0x3404, 0x1100),
+            (0x3408, 0x1110),
+            (0x3410, 0x3320),
+            (0x3414, 0x1100),
+            (0x3418, 0x1120),
+        ];
+        for (va, value) in layout {
+            put(va, value);
+        }
+        data[0x108..0x113].copy_from_slice(b".?AVBase@@\0");
+        data[0x148..0x156].copy_from_slice(b".?AVDerived@@\0");
+
+        // main: mov ecx, esi; call Derived::Derived; mov eax, [esi]; mov ecx, esi; call [eax+4]
+        let main = function(
+            0x1000,
+            vec![
+                (0x1000, Stmt::Set(ecx, Expr::Reg(esi))),
+                (0x1002, Stmt::Unknown),
+                (0x1007, Stmt::Set(eax, load(Expr::Reg(esi)))),
+                (0x1009, Stmt::Set(ecx, Expr::Reg(esi))),
+                (0x100b, Stmt::Unknown),
+            ],
+        );
+        // Derived::Derived: mov dword [ecx], Derived::vftable
+        let store = Stmt::Store(Expr::Reg(ecx), Expr::Const(0x3414));
+        let ctor = function(0x1300, vec![(0x1300, store)]);
+        // Base::f: mov eax, [ecx]; call [eax+4]
+        let method = function(
+            0x1110,
+            vec![
+                (0x1110, Stmt::Set(eax, load(Expr::Reg(ecx)))),
+                (0x1112, Stmt::Unknown),
+            ],
+        );
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_meta("Platform", Some("windows".to_string()));
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a.exe", vec![0x90; 0x400], None);
+        ws.add_memory_map(0x3000, MM_READ, "a.exe", data, None);
+        ws.add_location(0x3404, 8, LOC_VFTABLE, Some(Vec::new()));
+        ws.add_xref(0x1002, 0x1300, REF_CODE, BR_PROC);
+        ws.set_function_il(0x1000, main);
+        ws.set_function_il(0x1110, method);
+        ws.set_function_il(0x1300, ctor);
+        let calls = Calls::from([(0x100b, slot(eax, 4)), (0x1112, slot(eax, 4))]);
+
+        let found = ws.devirtualize_calls(&calls);
+        assert_eq!(
+            found,
+            [
+                Devirtualized {
+                    call: 0x100b,
+                    slot: 4,
+                    class: Some("Derived".to_string()),
+                    exact: true,
+                    targets: vec![0x1120],
+                },
+                Devirtualized {
+                    call: 0x1112,
+                    slot: 4,
+                    class: Some("Base".to_string()),
+                    exact: false,
+                    targets: vec![0x1110, 0x1120],
+                },
+            ]
+        );
+        let xrefs = ws.get_xrefs_from(0x1112, Some(REF_CODE));
+        assert_eq!(xrefs.len(), 2);
+        assert_eq!(
+            ws.get_comment(0x100b),
+            "virtual call to Derived slot 0x4: 0x1120"
+        );
+    }
+}
@@ -0,0 +1,496 @@
+//! Android packages: the DEX bytecode and native libraries of an APK, and the JNI bindings
+//! between them.
+//!
+//! An APK is a zip file holding `classes.dex` (and `classes2.dex` and so on for multidex
+//! apps) and the native libraries under `lib/<abi>/`. [`Dex`] reads enough of a DEX file to
+//! list its classes and their methods, with each method's access flags, so the `native` ones
+//! are known. Their implementations are looked up by the JNI naming convention,
+//! `Java_<class>_<method>` or, for overloads, `Java_<class>_<method>__<arguments>`, among the
+//! exports of the native libraries; [`bindings`] pairs up the two sides. Methods registered at
+//! run time with `RegisterNatives` have no such export, and stay unbound.
+
+use crate::{
+    batch::zip_members,
+    carve::DEFAULT_LIMIT,
+    elf::Elf,
+    error::{self, Error},
+};
+use scroll::{Pread, LE};
+use std::fmt;
+
+pub const DEX_MAGIC: &[u8] = b"dex\n";
+/// The access flag of methods implemented in native code
+pub const ACC_NATIVE: u32 = 0x100;
+
+const HEADER_SIZE: usize = 0x70;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DexMethod {
+    pub name: String,
+    /// The argument types, as type descriptors
+    pub params: Vec<String>,
+    pub ret: String,
+    pub access_flags: u32,
+}
+
+impl DexMethod {
+    pub fn is_native(&self) -> bool {
+        self.access_flags & ACC_NATIVE != 0
+    }
+
+    /// The method descriptor, `(ILjava/lang/String;)V`
+    pub fn descriptor(&self) -> String {
+        format!("({}){}", self.params.concat(), self.ret)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DexClass {
+    /// The type descriptor, `Lcom/example/Foo;`
+    pub descriptor: String,
+    pub superclass: Option<String>,
+    pub access_flags: u32,
+    /// The direct methods, then the virtual ones
+    pub methods: Vec<DexMethod>,
+}
+
+impl DexClass {
+    /// The class name, `com.example.Foo`
+    pub fn name(&self) -> String {
+        class_name(&self.descriptor)
+    }
+}
+
+/// `com.example.Foo` from `Lcom/example/Foo;`
+fn class_name(descriptor: &str) -> String {
+    descriptor
+        .strip_prefix('L')
+        .and_then(|name| name.strip_suffix(';'))
+        .unwrap_or(descriptor)
+        .replace('/', ".")
+}
+
+/// The classes of a DEX file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dex {
+    pub version: String,
+    pub strings: Vec<String>,
+    pub classes: Vec<DexClass>,
+}
+
+fn uleb128(bytes: &[u8], offset: &mut usize) -> error::Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte: u8 = bytes.pread(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::Malformed(format!(
+        "uleb128 too long at {:#x}",
+        offset
+    )))
+}
+
+impl Dex {
+    pub fn parse(bytes: &[u8]) -> error::Result<Dex> {
+        if !bytes.starts_with(DEX_MAGIC) || bytes.len() < HEADER_SIZE {
+            return Err(Error::Malformed("not a DEX file".to_string()));
+        }
+        let version = String::from_utf8_lossy(&bytes[4..7]).to_string();
+        // a table of `entry` byte entries, which can't hold more of them than the file does
+        let table = |at: usize, entry: usize| -> error::Result<(usize, usize)> {
+            let size: u32 = bytes.pread_with(at, LE)?;
+            let offset: u32 = bytes.pread_with(at + 4, LE)?;
+            if size as usize > bytes.len() / entry {
+                return Err(Error::Malformed(format!(
+                    "table at {:#x} of {} entries",
+                    at, size
+                )));
+            }
+            Ok((size as usize, offset as usize))
+        };
+        let u32_at =
+            |at: usize| -> error::Result<usize> { Ok(bytes.pread_with::<u32>(at, LE)? as usize) };
+        let u16_at =
+            |at: usize| -> error::Result<usize> { Ok(bytes.pread_with::<u16>(at, LE)? as usize) };
+        let index = |table: &[String], idx: usize| -> error::Result<String> {
+            table
+                .get(idx)
+                .cloned()
+                .ok_or_else(|| Error::Malformed(format!("index {} out of range", idx)))
+        };
+
+        let (count, offset) = table(0x38, 4)?;
+        let mut strings = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut at = u32_at(offset + i * 4)?;
+            // the length is in UTF-16 units; the bytes end with a nul
+            uleb128(bytes, &mut at)?;
+            let data = bytes.get(at..).unwrap_or_default();
+            let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+            // modified UTF-8, which is UTF-8 for everything but nuls and surrogates
+            strings.push(String::from_utf8_lossy(&data[..end]).to_string());
+        }
+
+        let (count, offset) = table(0x40, 4)?;
+        let types = (0..count)
+            .map(|i| index(&strings, u32_at(offset + i * 4)?))
+            .collect::<error::Result<Vec<_>>>()?;
+
+        let (count, offset) = table(0x48, 12)?;
+        let mut protos = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + i * 12;
+            let ret = index(&types, u32_at(at + 4)?)?;
+            let params_at = u32_at(at + 8)?;
+            let mut params = Vec::new();
+            if params_at != 0 {
+                for j in 0..u32_at(params_at)? {
+                    params.push(index(&types, u16_at(params_at + 4 + j * 2)?)?);
+                }
+            }
+            protos.push((params, ret));
+        }
+
+        let (count, offset) = table(0x58, 8)?;
+        let mut methods = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + i * 8;
+            let proto = u16_at(at + 2)?;
+            let name = index(&strings, u32_at(at + 4)?)?;
+            let (params, ret) = protos
+                .get(proto)
+                .cloned()
+                .ok_or_else(|| Error::Malformed(format!("prototype {} out of range", proto)))?;
+            methods.push(DexMethod {
+                name,
+                params,
+                ret,
+                access_flags: 0,
+            });
+        }
+
+        let (count, offset) = table(0x60, 32)?;
+        let mut classes = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + i * 32;
+            let descriptor = index(&types, u32_at(at)?)?;
+            let access_flags = u32_at(at + 4)? as u32;
+            let superclass = match u32_at(at + 8)? {
+                0xffff_ffff => None,
+                idx => Some(index(&types, idx)?),
+            };
+            let mut class_methods = Vec::new();
+            let mut at = u32_at(at + 24)?;
+            if at != 0 {
+                let static_fields = uleb128(bytes, &mut at)?;
+                let instance_fields = uleb128(bytes, &mut at)?;
+                let direct = uleb128(bytes, &mut at)?;
+                let virtual_ = uleb128(bytes, &mut at)?;
+                let fields = static_fields
+                    .checked_add(instance_fields)
+                    .and_then(|fields| fields.checked_mul(2))
+                    .ok_or_else(|| {
+                        Error::Malformed(format!(
+                            "{} static and {} instance fields",
+                            static_fields, instance_fields
+                        ))
+                    })?;
+                for _ in 0..fields {
+                    uleb128(bytes, &mut at)?;
+                }
+                // method indices are deltas from the previous one, starting over for the
+                // virtual methods
+                for count in [direct, virtual_] {
+                    let mut idx = 0;
+                    for _ in 0..count {
+                        idx += uleb128(bytes, &mut at)? as usize;
+                        let flags = uleb128(bytes, &mut at)?;
+                        uleb128(bytes, &mut at)?;
+                        let mut method = methods.get(idx).cloned().ok_or_else(|| {
+                            Error::Malformed(format!("method {} out of range", idx))
+                        })?;
+                        method.access_flags = flags;
+                        class_methods.push(method);
+                    }
+                }
+            }
+            classes.push(DexClass {
+                descriptor,
+                superclass,
+                access_flags,
+                methods: class_methods,
+            });
+        }
+        Ok(Dex {
+            version,
+            strings,
+            classes,
+        })
+    }
+}
+
+/// A name escaped for a JNI symbol
+fn jni_mangle(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        match c {
+            '/' | '.' => out.push('_'),
+            '_' => out.push_str("_1"),
+            ';' => out.push_str("_2"),
+            '[' => out.push_str("_3"),
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            c => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("_0{:04x}", unit));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// The symbols the JNI looks a native method up by: the short one, then the one with the
+/// argument types for overloaded methods
+pub fn jni_names(class_descriptor: &str, method: &DexMethod) -> [String; 2] {
+    let short = format!(
+        "Java_{}_{}",
+        jni_mangle(&class_name(class_descriptor)),
+        jni_mangle(&method.name)
+    );
+    let long = format!("{}__{}", short, jni_mangle(&method.params.concat()));
+    [short, long]
+}
+
+/// The contents of an APK
+#[derive(Clone, Debug, Default)]
+pub struct Apk {
+    /// The DEX files, by name
+    pub dex: Vec<(String, Vec<u8>)>,
+    /// The native libraries: ABI, path in the package and contents
+    pub libs: Vec<(String, String, Vec<u8>)>,
+}
+
+impl Apk {
+    /// Whether `bytes` is a zip file with a `classes.dex` or an `AndroidManifest.xml`
+    pub fn is_apk(bytes: &[u8]) -> bool {
+        Apk::parse(bytes).is_some_and(|apk| !apk.dex.is_empty() || !apk.libs.is_empty())
+    }
+
+    /// The DEX files and native libraries of the package, or None if it isn't a zip file
+    pub fn parse(bytes: &[u8]) -> Option<Apk> {
+        let mut apk = Apk::default();
+        for (name, data) in zip_members(bytes, DEFAULT_LIMIT)? {
+            let parts: Vec<&str> = name.split('/').collect();
+            match parts.as_slice() {
+                [file] if file.starts_with("classes") && file.ends_with(".dex") => {
+                    apk.dex.push((name, data))
+                }
+                ["lib", abi, file] if file.ends_with(".so") => {
+                    apk.libs.push((abi.to_string(), name, data))
+                }
+                _ => {}
+            }
+        }
+        apk.dex.sort_by(|a, b| a.0.cmp(&b.0));
+        Some(apk)
+    }
+
+    /// The classes of every DEX file; ones which don't parse are left out
+    pub fn classes(&self) -> Vec<DexClass> {
+        self.dex
+            .iter()
+            .filter_map(|(_, bytes)| Dex::parse(bytes).ok())
+            .flat_map(|dex| dex.classes)
+            .collect()
+    }
+}
+
+/// A native method and the export implementing it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    /// The class, `com.example.Foo`
+    pub class: String,
+    pub method: String,
+    pub descriptor: String,
+    /// The library exporting it, by path in the package, and the symbol; None if it is
+    /// registered at run time
+    pub export: Option<(String, String)>,
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}{} -> ", self.class, self.method, self.descriptor)?;
+        match &self.export {
+            Some((lib, symbol)) => write!(f, "{}!{}", lib, symbol),
+            None => f.write_str("unbound"),
+        }
+    }
+}
+
+/// The `Java_` exports of an ELF shared library
+fn jni_exports(bytes: &[u8]) -> Vec<String> {
+    let Ok(elf) = Elf::parse(bytes) else {
+        return Vec::new();
+    };
+    elf.dynsyms
+        .iter()
+        .filter(|sym| sym.st_value != 0)
+        .filter_map(|sym| elf.dynstrtab.get_at(sym.st_name))
+        .filter(|name| name.starts_with("Java_"))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// The native methods declared in the DEX files of `apk`, with the exports implementing them.
+/// Each library is searched, ABI by ABI; the first export found wins.
+pub fn bindings(apk: &Apk) -> Vec<Binding> {
+    let exports: Vec<(&str, Vec<String>)> = apk
+        .libs
+        .iter()
+        .map(|(_, path, bytes)| (path.as_str(), jni_exports(bytes)))
+        .collect();
+    let mut bindings = Vec::new();
+    for class in apk.classes() {
+        for method in class.methods.iter().filter(|method| method.is_native()) {
+            let names = jni_names(&class.descriptor, method);
+            let export = exports.iter().find_map(|(path, symbols)| {
+                names
+                    .iter()
+                    .find(|name| symbols.contains(name))
+                    .map(|name| (path.to_string(), name.clone()))
+            });
+            bindings.push(Binding {
+                class: class.name(),
+                method: method.name.clone(),
+                descriptor: method.descriptor(),
+                export,
+            });
+        }
+    }
+    bindings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DEX file of one class, `Lcom/ex/My_App;`, with a native `check(I)Z` and a plain
+    /// `run()V`
+    fn dex() -> Vec<u8> {
+        let strings = [
+            "()V",
+            "(I)Z",
+            "I",
+            "Lcom/ex/My_App;",
+            "V",
+            "Z",
+            "check",
+            "run",
+        ];
+        let mut out = vec![0u8; HEADER_SIZE];
+        out[..8].copy_from_slice(b"dex\n035\0");
+        let put = |out: &mut Vec<u8>, at: usize, value: u32| {
+            out[at..at + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        // the offset of what comes next, at `at`
+        let here = |out: &mut Vec<u8>, at: usize| {
+            let offset = out.len() as u32;
+            put(out, at, offset)
+        };
+        // string ids, then the string data
+        put(&mut out, 0x38, strings.len() as u32);
+        here(&mut out, 0x3c);
+        let data = out.len() + strings.len() * 4;
+        let mut blob = Vec::new();
+        for s in strings {
+            out.extend(((data + blob.len()) as u32).to_le_bytes());
+            blob.push(s.len() as u8);
+            blob.extend(s.as_bytes());
+            blob.push(0);
+        }
+        out.extend(blob);
+        // types: I, Lcom/ex/My_App;, V, Z
+        put(&mut out, 0x40, 4);
+        here(&mut out, 0x44);
+        for s in [2u32, 3, 4, 5] {
+            out.extend(s.to_le_bytes());
+        }
+        // a type list of I
+        let params = out.len() as u32;
+        out.extend([1, 0, 0, 0, 0, 0, 0, 0]);
+        // protos: ()V, (I)Z
+        put(&mut out, 0x48, 2);
+        here(&mut out, 0x4c);
+        for (shorty, ret, params) in [(0u32, 2u32, 0u32), (1, 3, params)] {
+            for value in [shorty, ret, params] {
+                out.extend(value.to_le_bytes());
+            }
+        }
+        // methods: check, run
+        put(&mut out, 0x58, 2);
+        here(&mut out, 0x5c);
+        out.extend([1, 0, 1, 0, 6, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+        // the class data: no fields, check direct and native, run virtual
+        let class_data = out.len() as u32;
+        out.extend([0, 0, 1, 1, 0, 0x89, 0x02, 0, 1, 0x01, 0]);
+        put(&mut out, 0x60, 1);
+        here(&mut out, 0x64);
+        for value in [1u32, 1, 0xffff_ffff, 0, 0xffff_ffff, 0, class_data, 0] {
+            out.extend(value.to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn rejects_huge_counts() {
+        let mut bytes = dex();
+        bytes[0x38..0x3c].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        assert!(Dex::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn native_methods() {
+        let parsed = Dex::parse(&dex()).unwrap();
+        assert_eq!(parsed.version, "035");
+        let class = &parsed.classes[0];
+        assert_eq!(class.name(), "com.ex.My_App");
+        assert_eq!(class.superclass, None);
+        let summary: Vec<_> = class
+            .methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.descriptor(), m.is_native()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("check", "(I)Z".to_string(), true),
+                ("run", "()V".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            jni_names(&class.descriptor, &class.methods[0]),
+            [
+                "Java_com_ex_My_1App_check".to_string(),
+                "Java_com_ex_My_1App_check__I".to_string()
+            ]
+        );
+        assert_eq!(jni_mangle("a[Lx;é"), "a_3Lx_2_000e9");
+
+        let apk = Apk {
+            dex: vec![("classes.dex".to_string(), dex())],
+            libs: Vec::new(),
+        };
+        assert_eq!(
+            bindings(&apk)
+                .iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>(),
+            ["com.ex.My_App.check(I)Z -> unbound"]
+        );
+    }
+}
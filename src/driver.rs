@@ -0,0 +1,503 @@
+//! Windows kernel driver analysis.
+//!
+//! A driver's life starts in `DriverEntry`, which gets the `DRIVER_OBJECT` as its first
+//! argument and fills in the `MajorFunction` table of it with the routines handling each kind
+//! of IRP, creates its device (`IoCreateDevice` on a `\Device\` name) and usually a symbolic
+//! link for user mode to open it by (`\DosDevices\` or `\??\`). User mode then talks to the
+//! `IRP_MJ_DEVICE_CONTROL` routine with `DeviceIoControl`, which compares the IOCTL code against
+//! each one it handles.
+//!
+//! [`analyze`] recovers all of that from the lifted functions of a driver: the real
+//! `DriverEntry` behind the `GsDriverEntry` stub the compiler wraps it in, the dispatch routines
+//! from the stores into the `MajorFunction` table, the IOCTL codes the device control routine
+//! compares against, and the device and link names from the strings of the image.
+//! [`annotate`] names the routines and comments the IOCTL comparisons in the workspace, and
+//! keeps the [`DriverInfo`] on it (see `VivWorkspace::get_driver_info`).
+
+use crate::{
+    constants::{LOC_STRING, LOC_UNI},
+    envi::{registers::RegisterModel, Arch},
+    memory::Memory,
+    symbolic::{BinOp, Expr, FlagOp, Function, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// The IRP major function codes, by index into the `MajorFunction` table
+pub const IRP_MJ_NAMES: [&str; 28] = [
+    "IRP_MJ_CREATE",
+    "IRP_MJ_CREATE_NAMED_PIPE",
+    "IRP_MJ_CLOSE",
+    "IRP_MJ_READ",
+    "IRP_MJ_WRITE",
+    "IRP_MJ_QUERY_INFORMATION",
+    "IRP_MJ_SET_INFORMATION",
+    "IRP_MJ_QUERY_EA",
+    "IRP_MJ_SET_EA",
+    "IRP_MJ_FLUSH_BUFFERS",
+    "IRP_MJ_QUERY_VOLUME_INFORMATION",
+    "IRP_MJ_SET_VOLUME_INFORMATION",
+    "IRP_MJ_DIRECTORY_CONTROL",
+    "IRP_MJ_FILE_SYSTEM_CONTROL",
+    "IRP_MJ_DEVICE_CONTROL",
+    "IRP_MJ_INTERNAL_DEVICE_CONTROL",
+    "IRP_MJ_SHUTDOWN",
+    "IRP_MJ_LOCK_CONTROL",
+    "IRP_MJ_CLEANUP",
+    "IRP_MJ_CREATE_MAILSLOT",
+    "IRP_MJ_QUERY_SECURITY",
+    "IRP_MJ_SET_SECURITY",
+    "IRP_MJ_POWER",
+    "IRP_MJ_SYSTEM_CONTROL",
+    "IRP_MJ_DEVICE_CHANGE",
+    "IRP_MJ_QUERY_QUOTA",
+    "IRP_MJ_SET_QUOTA",
+    "IRP_MJ_PNP",
+];
+pub const IRP_MJ_DEVICE_CONTROL: u8 = 0x0e;
+pub const IRP_MJ_INTERNAL_DEVICE_CONTROL: u8 = 0x0f;
+
+/// The name of the dispatch routine for a major function, `DispatchDeviceControl`
+pub fn dispatch_name(major: u8) -> String {
+    let Some(name) = IRP_MJ_NAMES.get(major as usize) else {
+        return format!("Dispatch{:02x}", major);
+    };
+    let mut out = String::from("Dispatch");
+    for word in name.trim_start_matches("IRP_MJ_").split('_') {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.push(first);
+            out.extend(chars.map(|c| c.to_ascii_lowercase()));
+        }
+    }
+    out
+}
+
+const METHODS: [&str; 4] = [
+    "METHOD_BUFFERED",
+    "METHOD_IN_DIRECT",
+    "METHOD_OUT_DIRECT",
+    "METHOD_NEITHER",
+];
+const ACCESSES: [&str; 4] = [
+    "FILE_ANY_ACCESS",
+    "FILE_READ_ACCESS",
+    "FILE_WRITE_ACCESS",
+    "FILE_READ_ACCESS | FILE_WRITE_ACCESS",
+];
+
+/// An IOCTL code, as `CTL_CODE(device_type, function, method, access)` makes them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ioctl(pub u32);
+
+impl Ioctl {
+    pub fn device_type(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    pub fn access(&self) -> u8 {
+        ((self.0 >> 14) & 3) as u8
+    }
+
+    pub fn function(&self) -> u16 {
+        ((self.0 >> 2) & 0xfff) as u16
+    }
+
+    pub fn method(&self) -> u8 {
+        (self.0 & 3) as u8
+    }
+
+    /// Whether a constant could be an IOCTL code: a device type Microsoft defines (below 0x100)
+    /// or a vendor one (0x8000 and up)
+    pub fn plausible(value: u64) -> bool {
+        let device_type = value >> 16;
+        value <= u32::MAX as u64 && device_type != 0 && !(0x100..0x8000).contains(&device_type)
+    }
+}
+
+impl fmt::Display for Ioctl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CTL_CODE({:#x}, {:#x}, {}, {})",
+            self.device_type(),
+            self.function(),
+            METHODS[self.method() as usize],
+            ACCESSES[self.access() as usize]
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DriverInfo {
+    /// The `DriverEntry` routine, past any `GsDriverEntry` stub
+    pub entry: u64,
+    /// The dispatch routine of each major function set
+    pub dispatch: BTreeMap<u8, u64>,
+    pub unload: Option<u64>,
+    /// The IOCTL codes the device control routines handle, with where each is compared
+    pub ioctls: BTreeMap<Ioctl, Vec<u64>>,
+    /// Device object names, `\Device\Foo`
+    pub devices: Vec<String>,
+    /// Symbolic link names, `\DosDevices\Foo` or `\??\Foo`
+    pub symbolic_links: Vec<String>,
+}
+
+/// The `DRIVER_OBJECT` argument as an expression of the state at the entry
+fn driver_object(arch: Arch) -> Option<Expr> {
+    let regs = RegisterModel::new(arch);
+    match arch {
+        Arch::Amd64 => regs.by_name("rcx").map(Expr::Reg),
+        Arch::A64 => regs.by_name("x0").map(Expr::Reg),
+        Arch::ArmV7 | Arch::Thumb | Arch::Thumb16 => regs.by_name("r0").map(Expr::Reg),
+        Arch::I386 => Some(Expr::Load(Box::new(Expr::binary(
+            BinOp::Add,
+            Expr::Reg(regs.sp()),
+            Expr::Const(4),
+            32,
+        )))),
+        Arch::Msp430 | Arch::H8 => None,
+    }
+}
+
+/// The offset of `addr` from `base`, for `base` and `base + c`
+fn offset_from(addr: &Expr, base: &Expr) -> Option<u64> {
+    match addr {
+        _ if addr == base => Some(0),
+        Expr::Binary(BinOp::Add, a, c) if **a == *base => c.as_const(),
+        _ => None,
+    }
+}
+
+/// The function a `GsDriverEntry` style stub jumps to after its setup, or the entry itself
+pub fn driver_entry(func: &Function) -> u64 {
+    let blocks = func.reverse_postorder();
+    let tail_calls: Vec<u64> = blocks
+        .iter()
+        .filter_map(|va| match func.blocks[va].end {
+            Terminator::TailCall(to) => Some(to),
+            _ => None,
+        })
+        .collect();
+    let returns = blocks
+        .iter()
+        .any(|va| func.blocks[va].end == Terminator::Return);
+    match tail_calls.as_slice() {
+        [to] if !returns => *to,
+        _ => func.entry,
+    }
+}
+
+/// The dispatch routines and the unload routine `DriverEntry` stores in the driver object
+pub fn dispatch_table(func: &Function) -> (BTreeMap<u8, u64>, Option<u64>) {
+    let mut dispatch = BTreeMap::new();
+    let mut unload = None;
+    let Some(base) = driver_object(func.arch) else {
+        return (dispatch, unload);
+    };
+    let psize = func.arch.pointer_size() as u64;
+    // DriverUnload, with the MajorFunction table right after it
+    let unload_offset = if psize == 8 { 0x68 } else { 0x34 };
+    let table_offset = unload_offset + psize;
+    for state in func.block_states().values() {
+        for (addr, value) in state.stores() {
+            let (Some(offset), Some(target)) = (offset_from(addr, &base), value.as_const()) else {
+                continue;
+            };
+            if offset == unload_offset {
+                unload = Some(target);
+            } else if offset >= table_offset && (offset - table_offset).is_multiple_of(psize) {
+                let major = (offset - table_offset) / psize;
+                if major < IRP_MJ_NAMES.len() as u64 {
+                    dispatch.insert(major as u8, target);
+                }
+            }
+        }
+    }
+    (dispatch, unload)
+}
+
+/// The IOCTL codes a device control routine compares against, with where. Both a compare with
+/// the code and the subtraction a compiler normalises a switch on the codes with count.
+pub fn ioctl_codes(func: &Function) -> BTreeMap<Ioctl, Vec<u64>> {
+    let mut ioctls: BTreeMap<Ioctl, Vec<u64>> = BTreeMap::new();
+    let mut add = |value: u64, va: u64| {
+        if Ioctl::plausible(value) {
+            let sites = ioctls.entry(Ioctl(value as u32)).or_default();
+            if !sites.contains(&va) {
+                sites.push(va);
+            }
+        }
+    };
+    for block in func.blocks.values() {
+        let mut state = State::new(func.arch);
+        for insn in block.insns.iter() {
+            state.exec_insn(insn);
+            for stmt in insn.stmts.iter() {
+                match stmt {
+                    Stmt::Flags(FlagOp::Sub, _, b) => {
+                        if let Some(value) = state.eval(b).as_const() {
+                            add(value, insn.va);
+                        }
+                    }
+                    Stmt::Set(_, Expr::Binary(BinOp::Sub, _, c)) => {
+                        if let Some(value) = c.as_const() {
+                            add(value, insn.va);
+                        }
+                    }
+                    // sub eax, code lifted as eax + -code
+                    Stmt::Set(_, Expr::Binary(BinOp::Add, _, c)) => {
+                        if let Some(negated) = c.as_const() {
+                            add(negated.wrapping_neg() & 0xffff_ffff, insn.va);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    ioctls
+}
+
+/// The text of a string location: UTF-16LE for a unicode one
+pub(crate) fn string_at(workspace: &VivWorkspace, va: i32, size: i32, unicode: bool) -> Option<String> {
+    let bytes = workspace.read_memory(va, size)?;
+    let text = if unicode {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    Some(text.trim_end_matches('\0').to_string())
+}
+
+/// The device and symbolic link names among the strings of the workspace
+pub fn device_names(workspace: &VivWorkspace) -> (Vec<String>, Vec<String>) {
+    let (mut devices, mut links) = (Vec::new(), Vec::new());
+    for ltype in [LOC_UNI, LOC_STRING] {
+        for (va, size, ..) in workspace.get_locations(Some(ltype), None) {
+            let Some(text) = string_at(workspace, va, size, ltype == LOC_UNI) else {
+                continue;
+            };
+            let lower = text.to_ascii_lowercase();
+            let list = if lower.starts_with("\\device\\") {
+                &mut devices
+            } else if ["\\dosdevices\\", "\\??\\", "\\global??\\"]
+                .iter()
+                .any(|prefix| lower.starts_with(prefix))
+            {
+                &mut links
+            } else {
+                continue;
+            };
+            if !list.contains(&text) {
+                list.push(text);
+            }
+        }
+    }
+    (devices, links)
+}
+
+/// Analyze a driver from its lifted functions, by entry. `entry` is the entry point of the
+/// image; the functions it leads to which weren't lifted are left out of the analysis.
+pub fn analyze(
+    workspace: &VivWorkspace,
+    entry: u64,
+    functions: &BTreeMap<u64, Function>,
+) -> DriverInfo {
+    let entry = functions.get(&entry).map_or(entry, driver_entry);
+    let (dispatch, unload) = functions
+        .get(&entry)
+        .map(dispatch_table)
+        .unwrap_or_default();
+    let mut ioctls: BTreeMap<Ioctl, Vec<u64>> = BTreeMap::new();
+    for major in [IRP_MJ_DEVICE_CONTROL, IRP_MJ_INTERNAL_DEVICE_CONTROL] {
+        let Some(func) = dispatch.get(&major).and_then(|va| functions.get(va)) else {
+            continue;
+        };
+        for (ioctl, sites) in ioctl_codes(func) {
+            let known = ioctls.entry(ioctl).or_default();
+            for va in sites {
+                if !known.contains(&va) {
+                    known.push(va);
+                }
+            }
+        }
+    }
+    let (devices, symbolic_links) = device_names(workspace);
+    DriverInfo {
+        entry,
+        dispatch,
+        unload,
+        ioctls,
+        devices,
+        symbolic_links,
+    }
+}
+
+/// Name the driver's routines where nobody named them yet, comment the dispatch routines and
+/// IOCTL comparisons, and keep the driver info on the workspace
+pub fn annotate(workspace: &mut VivWorkspace, info: &DriverInfo) {
+    let name = |workspace: &mut VivWorkspace, va: u64, name: String| {
+        let va = va as i32;
+        if workspace.get_name(va, false).is_none() || workspace.is_auto_name(va) {
+            workspace.make_name(va, name, false, true);
+        }
+    };
+    name(workspace, info.entry, "DriverEntry".to_string());
+    if let Some(unload) = info.unload {
+        name(workspace, unload, "DriverUnload".to_string());
+    }
+    let mut handlers: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    for (major, handler) in info.dispatch.iter() {
+        handlers.entry(*handler).or_default().push(*major);
+    }
+    for (handler, majors) in handlers {
+        name(workspace, handler, dispatch_name(majors[0]));
+        let majors: Vec<&str> = majors
+            .iter()
+            .map(|major| IRP_MJ_NAMES[*major as usize])
+            .collect();
+        workspace.set_comment(handler as i32, &majors.join(", "), true);
+    }
+    for (ioctl, sites) in info.ioctls.iter() {
+        for va in sites {
+            let comment = format!("IOCTL {:#x}: {}", ioctl.0, ioctl);
+            workspace.set_comment(*va as i32, &comment, true);
+        }
+    }
+    workspace.set_driver_info(Some(info.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::MM_READ,
+        envi::flags::Condition,
+        symbolic::{Block, Insn},
+    };
+
+    fn block(va: u64, stmts: Vec<Stmt>, end: Terminator) -> Block {
+        Block {
+            va,
+            insns: stmts
+                .into_iter()
+                .enumerate()
+                .map(|(i, stmt)| Insn {
+                    va: va + 4 * i as u64,
+                    stmts: vec![stmt],
+                })
+                .collect(),
+            end_va: va + 0x20,
+            end,
+        }
+    }
+
+    #[test]
+    fn driver_analysis() {
+        let regs = RegisterModel::new(Arch::Amd64);
+        let (rcx, rdx, eax) = (
+            regs.by_name("rcx").unwrap(),
+            regs.by_name("rdx").unwrap(),
+            regs.by_name("eax").unwrap(),
+        );
+        let field = |offset| Expr::binary(BinOp::Add, Expr::Reg(rcx), Expr::Const(offset), 64);
+        let mut functions = BTreeMap::new();
+
+        // GsDriverEntry: jmp DriverEntry
+        let mut stub = Function::new(Arch::Amd64, 0x1000);
+        stub.add_block(block(0x1000, vec![], Terminator::TailCall(0x1100)));
+        functions.insert(0x1000, stub);
+
+        // DriverEntry: DriverUnload = 0x1200; MajorFunction[CREATE] = MajorFunction[CLOSE] =
+        // 0x1300; MajorFunction[DEVICE_CONTROL] = 0x1400
+        let mut entry = Function::new(Arch::Amd64, 0x1100);
+        entry.add_block(block(
+            0x1100,
+            vec![
+                Stmt::Set(rdx, Expr::Const(0x1300)),
+                Stmt::Store(field(0x68), Expr::Const(0x1200)),
+                Stmt::Store(field(0x70), Expr::Reg(rdx)),
+                Stmt::Store(field(0x80), Expr::Reg(rdx)),
+                Stmt::Store(field(0xe0), Expr::Const(0x1400)),
+            ],
+            Terminator::Return,
+        ));
+        functions.insert(0x1100, entry);
+
+        // DispatchDeviceControl: sub eax, 0x222000; je; cmp eax, 0x222004; je
+        let mut control = Function::new(Arch::Amd64, 0x1400);
+        control.add_block(block(
+            0x1400,
+            vec![Stmt::Set(
+                eax,
+                Expr::Binary(
+                    BinOp::Add,
+                    Box::new(Expr::Reg(eax)),
+                    Box::new(Expr::Const(0xffdde000)),
+                ),
+            )],
+            Terminator::Branch {
+                cond: Condition::Zero,
+                taken: 0x1500,
+                fallthrough: 0x1420,
+            },
+        ));
+        control.add_block(block(
+            0x1420,
+            vec![
+                Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(0x222004)),
+                Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(8)),
+            ],
+            Terminator::Return,
+        ));
+        functions.insert(0x1400, control);
+
+        let mut ws = VivWorkspace::new("", false);
+        let mut strings = Vec::new();
+        for text in ["\\Device\\Vuln", "\\DosDevices\\Vuln"] {
+            strings.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+            strings.extend([0, 0]);
+        }
+        ws.add_memory_map(0x4000, MM_READ, "test", strings, None);
+        ws.add_location(0x4000, 26, LOC_UNI, Some(vec![]));
+        ws.add_location(0x401a, 34, LOC_UNI, Some(vec![]));
+
+        let info = analyze(&ws, 0x1000, &functions);
+        assert_eq!(info.entry, 0x1100);
+        assert_eq!(info.unload, Some(0x1200));
+        assert_eq!(
+            info.dispatch,
+            BTreeMap::from([(0, 0x1300), (2, 0x1300), (0xe, 0x1400)])
+        );
+        assert_eq!(
+            info.ioctls,
+            BTreeMap::from([
+                (Ioctl(0x222000), vec![0x1400]),
+                (Ioctl(0x222004), vec![0x1420])
+            ])
+        );
+        assert_eq!(
+            Ioctl(0x222004).to_string(),
+            "CTL_CODE(0x22, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS)"
+        );
+        assert_eq!(info.devices, ["\\Device\\Vuln"]);
+        assert_eq!(info.symbolic_links, ["\\DosDevices\\Vuln"]);
+
+        ws.make_name(0x1300, "my_create".to_string(), false, false);
+        annotate(&mut ws, &info);
+        assert_eq!(ws.get_name(0x1100, false).as_deref(), Some("DriverEntry"));
+        assert_eq!(
+            ws.get_name(0x1400, false).as_deref(),
+            Some("DispatchDeviceControl")
+        );
+        assert_eq!(ws.get_name(0x1300, false).as_deref(), Some("my_create"));
+        assert_eq!(ws.get_comment(0x1300), "IRP_MJ_CREATE, IRP_MJ_CLOSE");
+        assert!(ws.get_comment(0x1420).starts_with("IOCTL 0x222004"));
+        assert_eq!(ws.get_driver_info(), Some(&info));
+    }
+}
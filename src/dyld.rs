@@ -0,0 +1,578 @@
+//! Which definition dyld binds each import of a process to.
+//!
+//! A process is its main executable and the dylibs it loads, in load order, libraries inserted
+//! with `DYLD_INSERT_LIBRARIES` right after the executable. dyld looks for the definition of an
+//! import of an image with a two-level namespace in the dylib the import names, then in the
+//! dylibs that one re-exports; with a flat namespace, or `DYLD_FORCE_FLAT_NAMESPACE`, in every
+//! image in load order. A weak definition is coalesced: whoever binds to it gets the first
+//! non-weak definition of the symbol in load order, or the first weak one if all of them are.
+//! The `__interpose` tuples of an image put its replacements in place of the functions they
+//! name, for the binds of every image but itself. A weak import nothing defines is bound to
+//! null, and any other import nothing defines stops the launch.
+//!
+//! [`Process::bindings`] sums up every bind of every image, lazy or not and weak or not, with
+//! what it resolves to, and [`Process::resolve`] answers for one symbol of one image.
+
+use crate::{
+    error,
+    mach::{
+        exports::{ExportInfo, EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION},
+        header::{MH_FORCE_FLAT, MH_TWOLEVEL},
+        imports::Dylib,
+        load_command::CommandVariant,
+        symbols::N_WEAK_DEF,
+        MachO,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// Re-exports chained deeper than this are taken for a cycle
+const MAX_REEXPORT_DEPTH: usize = 16;
+
+/// Where an image looks for the definition of an import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    /// A dylib, by install name
+    Library(String),
+    SelfImage,
+    MainExecutable,
+    /// Every image in load order
+    Flat,
+    /// The first definition in load order, non-weak ones first
+    Weak,
+}
+
+impl From<Dylib<'_>> for Lookup {
+    fn from(dylib: Dylib<'_>) -> Self {
+        match dylib {
+            Dylib::Ordinary(name) => Lookup::Library(name.to_string()),
+            Dylib::SelfModule => Lookup::SelfImage,
+            Dylib::MainExecutable => Lookup::MainExecutable,
+            Dylib::FlatLookup => Lookup::Flat,
+            Dylib::WeakLookup => Lookup::Weak,
+        }
+    }
+}
+
+/// What an image exports under a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Definition {
+    /// At an offset from the Mach header of the image
+    Offset { offset: u64, weak: bool },
+    /// The symbol `name` of the dylib `library`
+    Reexport { library: String, name: String },
+}
+
+/// A pointer dyld binds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bind {
+    pub name: String,
+    pub lookup: Lookup,
+    /// Bound on the first call through its stub rather than at launch
+    pub lazy: bool,
+    /// Left null if nothing defines it
+    pub weak_import: bool,
+    /// The VA of the pointer
+    pub address: u64,
+}
+
+/// An `__interpose` tuple: the function at `replacement`, an offset from the Mach header of the
+/// image, stands in for whatever `replacee` is bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpose {
+    pub replacement: u64,
+    /// The symbol at `replacement`, if the image names it
+    pub name: Option<String>,
+    pub replacee: Bind,
+}
+
+/// What dyld needs of a Mach-O image to bind it and to bind to it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DyldImage {
+    /// The install name of a dylib, or the path the image is loaded from
+    pub path: String,
+    pub two_level: bool,
+    /// The dylibs it loads, in library ordinal order
+    pub libraries: Vec<String>,
+    /// Those of `libraries` it re-exports
+    pub reexports: Vec<String>,
+    pub exports: BTreeMap<String, Definition>,
+    pub binds: Vec<Bind>,
+    pub interposes: Vec<Interpose>,
+}
+
+impl DyldImage {
+    /// An image with a two-level namespace, and nothing in it yet
+    pub fn new(path: &str) -> Self {
+        DyldImage {
+            path: path.to_string(),
+            two_level: true,
+            ..Default::default()
+        }
+    }
+
+    /// The image of the Mach-O loaded from `path`. Its exports are those of its export trie,
+    /// or of its symbol table in an image from before there were tries.
+    pub fn from_macho(path: &str, macho: &MachO) -> error::Result<Self> {
+        let flags = macho.header.flags;
+        let mut image = DyldImage::new(macho.name.unwrap_or(path));
+        image.two_level = flags & MH_TWOLEVEL != 0 && flags & MH_FORCE_FLAT == 0;
+        image.libraries = macho.libs.iter().skip(1).map(|lib| lib.to_string()).collect();
+        let mut ordinal = 0;
+        for lc in macho.load_commands.iter() {
+            match lc.command {
+                CommandVariant::ReexportDylib(_) => {
+                    image.reexports.extend(image.libraries.get(ordinal).cloned());
+                    ordinal += 1;
+                }
+                CommandVariant::LoadDylib(_)
+                | CommandVariant::LoadUpwardDylib(_)
+                | CommandVariant::LoadWeakDylib(_)
+                | CommandVariant::LazyLoadDylib(_) => ordinal += 1,
+                _ => {}
+            }
+        }
+
+        let image_base = macho
+            .segments
+            .iter()
+            .find(|seg| seg.name().ok() == Some("__TEXT"))
+            .map_or(0, |seg| seg.vmaddr);
+        for export in macho.exports()? {
+            let definition = match export.info {
+                ExportInfo::Regular { address, flags } => Definition::Offset {
+                    offset: address,
+                    weak: flags & EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION != 0,
+                },
+                ExportInfo::Stub {
+                    stub_offset, flags, ..
+                } => Definition::Offset {
+                    offset: stub_offset.into(),
+                    weak: flags & EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION != 0,
+                },
+                ExportInfo::Reexport {
+                    lib,
+                    lib_symbol_name,
+                    ..
+                } => Definition::Reexport {
+                    library: lib.to_string(),
+                    name: lib_symbol_name
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or(&export.name)
+                        .to_string(),
+                },
+            };
+            image.exports.insert(export.name, definition);
+        }
+        let from_symtab = image.exports.is_empty();
+        let mut names = HashMap::new();
+        for (name, nlist) in macho.symbols().flatten() {
+            if nlist.is_stab() || nlist.is_undefined() {
+                continue;
+            }
+            names.entry(nlist.n_value).or_insert(name);
+            if from_symtab && nlist.is_global() {
+                image.exports.insert(
+                    name.to_string(),
+                    Definition::Offset {
+                        offset: nlist.n_value.wrapping_sub(image_base),
+                        weak: nlist.n_desc & N_WEAK_DEF != 0,
+                    },
+                );
+            }
+        }
+
+        for import in macho.imports()? {
+            image.binds.push(Bind {
+                name: import.name.to_string(),
+                lookup: import.dylib.into(),
+                lazy: import.is_lazy,
+                weak_import: import.is_weak,
+                address: import.address,
+            });
+        }
+        // With chained fixups, the binds are in the chains, and the pointers to the image are
+        // encoded in place
+        let mut rebases = HashMap::new();
+        if let Some(fixups) = macho.chained_fixups()? {
+            for (va, pointer) in fixups.fixups.iter() {
+                match fixups.import(pointer) {
+                    Some(import) => {
+                        let Some(dylib) = import.dylib else {
+                            continue;
+                        };
+                        image.binds.push(Bind {
+                            name: import.name.to_string(),
+                            lookup: dylib.into(),
+                            lazy: false,
+                            weak_import: import.weak,
+                            address: *va,
+                        });
+                    }
+                    None => {
+                        rebases.extend(pointer.target(image_base).map(|target| (*va, target)));
+                    }
+                }
+            }
+        }
+
+        let ptr_size = if macho.is_64 { 8 } else { 4 };
+        for segment in macho.segments.iter() {
+            for (section, data) in segment.sections()? {
+                if section.name()? != "__interpose" {
+                    continue;
+                }
+                for (idx, tuple) in data.chunks_exact(2 * ptr_size).enumerate() {
+                    let va = section.addr + (idx * 2 * ptr_size) as u64;
+                    let replacement = match rebases.get(&va) {
+                        Some(target) => *target,
+                        None if ptr_size == 8 => u64::from_le_bytes(tuple[..8].try_into().unwrap()),
+                        None => u64::from(u32::from_le_bytes(tuple[..4].try_into().unwrap())),
+                    };
+                    let replacee = va + ptr_size as u64;
+                    let Some(replacee) = image.binds.iter().find(|bind| bind.address == replacee)
+                    else {
+                        continue;
+                    };
+                    image.interposes.push(Interpose {
+                        replacement: replacement.wrapping_sub(image_base),
+                        name: names.get(&replacement).map(|name| name.to_string()),
+                        replacee: replacee.clone(),
+                    });
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// A definition a bind resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// The image defining it
+    pub image: String,
+    pub symbol: String,
+    /// From the Mach header of the image
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The definition found where the bind says to look
+    Bound(Target),
+    /// The first definition in load order, for a weak definition or a weak lookup
+    Coalesced(Target),
+    /// The replacement an interposing image puts in place of the definition
+    Interposed(Target),
+    /// Nothing defines the weak import, which stays null
+    Null,
+    /// Nothing defines the import, and dyld stops the launch
+    Missing,
+}
+
+impl Resolution {
+    pub fn target(&self) -> Option<&Target> {
+        match self {
+            Resolution::Bound(target)
+            | Resolution::Coalesced(target)
+            | Resolution::Interposed(target) => Some(target),
+            Resolution::Null | Resolution::Missing => None,
+        }
+    }
+}
+
+/// A bind of an image and what it resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub image: String,
+    pub bind: Bind,
+    pub resolution: Resolution,
+}
+
+/// The images of a process, to bind as dyld would
+#[derive(Debug, Clone, Default)]
+pub struct Process {
+    /// In load order, the main executable first
+    pub images: Vec<DyldImage>,
+    /// As with `DYLD_FORCE_FLAT_NAMESPACE`
+    pub force_flat: bool,
+}
+
+impl Process {
+    pub fn new() -> Self {
+        Process::default()
+    }
+
+    /// Load `image` after the ones loaded before
+    pub fn add(&mut self, image: DyldImage) {
+        self.images.push(image);
+    }
+
+    /// What the import of `symbol` of the image `image` resolves to, or if it doesn't import
+    /// `symbol`, what a flat lookup from it does. None if no such image is loaded.
+    pub fn resolve(&self, image: &str, symbol: &str) -> Option<Resolution> {
+        let idx = self.index(image)?;
+        let flat = Bind {
+            name: symbol.to_string(),
+            lookup: Lookup::Flat,
+            lazy: false,
+            weak_import: false,
+            address: 0,
+        };
+        let bind = self.images[idx]
+            .binds
+            .iter()
+            .find(|bind| bind.name == symbol)
+            .unwrap_or(&flat);
+        Some(self.bind(idx, bind))
+    }
+
+    /// Every bind of every image, in load order, with what it resolves to
+    pub fn bindings(&self) -> Vec<Binding> {
+        self.images
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, image)| {
+                image.binds.iter().map(move |bind| Binding {
+                    image: image.path.clone(),
+                    bind: bind.clone(),
+                    resolution: self.bind(idx, bind),
+                })
+            })
+            .collect()
+    }
+
+    fn bind(&self, from: usize, bind: &Bind) -> Resolution {
+        let resolution = self.bind_uninterposed(from, bind);
+        let Some(target) = resolution.target() else {
+            return resolution;
+        };
+        for (idx, image) in self.images.iter().enumerate() {
+            if idx == from {
+                continue;
+            }
+            for interpose in image.interposes.iter() {
+                if self.bind_uninterposed(idx, &interpose.replacee).target() == Some(target) {
+                    return Resolution::Interposed(Target {
+                        image: image.path.clone(),
+                        symbol: interpose
+                            .name
+                            .clone()
+                            .unwrap_or_else(|| interpose.replacee.name.clone()),
+                        offset: interpose.replacement,
+                    });
+                }
+            }
+        }
+        resolution
+    }
+
+    fn bind_uninterposed(&self, from: usize, bind: &Bind) -> Resolution {
+        let name = bind.name.as_str();
+        let flat = self.force_flat || !self.images[from].two_level;
+        let found = match &bind.lookup {
+            Lookup::Weak => None,
+            _ if flat => self.flat(name),
+            Lookup::Flat => self.flat(name),
+            Lookup::Library(library) => self
+                .index(library)
+                .and_then(|idx| self.define(idx, name, 0)),
+            Lookup::SelfImage => self.define(from, name, 0),
+            Lookup::MainExecutable => self.define(0, name, 0),
+        };
+        let coalesce = match &found {
+            Some((_, weak)) => *weak,
+            None => bind.lookup == Lookup::Weak,
+        };
+        if coalesce {
+            if let Some(target) = self.coalesced(name) {
+                return Resolution::Coalesced(target);
+            }
+        }
+        match found {
+            Some((target, _)) => Resolution::Bound(target),
+            None if bind.weak_import => Resolution::Null,
+            None => Resolution::Missing,
+        }
+    }
+
+    /// The definition of `name` in the image `idx` or what it re-exports, and whether it's weak
+    fn define(&self, idx: usize, name: &str, depth: usize) -> Option<(Target, bool)> {
+        let image = self.images.get(idx)?;
+        if depth > MAX_REEXPORT_DEPTH {
+            return None;
+        }
+        match image.exports.get(name) {
+            Some(Definition::Offset { offset, weak }) => Some((
+                Target {
+                    image: image.path.clone(),
+                    symbol: name.to_string(),
+                    offset: *offset,
+                },
+                *weak,
+            )),
+            Some(Definition::Reexport { library, name }) => self
+                .index(library)
+                .and_then(|idx| self.define(idx, name, depth + 1)),
+            None => image
+                .reexports
+                .iter()
+                .filter_map(|library| self.index(library))
+                .find_map(|idx| self.define(idx, name, depth + 1)),
+        }
+    }
+
+    fn flat(&self, name: &str) -> Option<(Target, bool)> {
+        (0..self.images.len()).find_map(|idx| self.define(idx, name, 0))
+    }
+
+    /// The first non-weak definition of `name` in load order, or else the first weak one
+    fn coalesced(&self, name: &str) -> Option<Target> {
+        let mut weak = None;
+        for image in self.images.iter() {
+            if let Some(Definition::Offset { offset, weak: is_weak }) = image.exports.get(name) {
+                let target = Target {
+                    image: image.path.clone(),
+                    symbol: name.to_string(),
+                    offset: *offset,
+                };
+                if !is_weak {
+                    return Some(target);
+                }
+                weak.get_or_insert(target);
+            }
+        }
+        weak
+    }
+
+    /// The image loaded as `path`, or failing that, under the same file name, for the
+    /// `@rpath/` and `@executable_path/` names dylibs are loaded by
+    fn index(&self, path: &str) -> Option<usize> {
+        let file_name = |path: &str| Path::new(path).file_name().map(|name| name.to_owned());
+        self.images
+            .iter()
+            .position(|image| image.path == path)
+            .or_else(|| {
+                let name = file_name(path)?;
+                self.images
+                    .iter()
+                    .position(|image| file_name(&image.path).as_ref() == Some(&name))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind(name: &str, lookup: Lookup) -> Bind {
+        Bind {
+            name: name.to_string(),
+            lookup,
+            lazy: true,
+            weak_import: false,
+            address: 0x4000,
+        }
+    }
+
+    fn image(path: &str, exports: &[(&str, u64, bool)]) -> DyldImage {
+        let mut image = DyldImage::new(path);
+        for (name, offset, weak) in exports {
+            let definition = Definition::Offset {
+                offset: *offset,
+                weak: *weak,
+            };
+            image.exports.insert(name.to_string(), definition);
+        }
+        image
+    }
+
+    fn target(image: &str, symbol: &str, offset: u64) -> Target {
+        Target {
+            image: image.to_string(),
+            symbol: symbol.to_string(),
+            offset,
+        }
+    }
+
+    #[test]
+    fn binds_like_dyld() {
+        let libsystem = "/usr/lib/libSystem.B.dylib";
+        let library = |name: &str| Lookup::Library(name.to_string());
+        let mut main = image("/bin/app", &[]);
+        main.binds = vec![
+            bind("_malloc", library(libsystem)),
+            bind("_foo", library("@rpath/libfoo.dylib")),
+            bind("_shared", library("@rpath/libbar.dylib")),
+            bind("__Znwm", Lookup::Weak),
+            Bind {
+                weak_import: true,
+                ..bind("_gone", library("@rpath/libfoo.dylib"))
+            },
+            bind("_missing", library("@rpath/libfoo.dylib")),
+        ];
+        let mut insert = image("/tmp/insert.dylib", &[("_my_malloc", 0x100, false)]);
+        insert.binds = vec![bind("_malloc", library(libsystem))];
+        insert.interposes = vec![Interpose {
+            replacement: 0x100,
+            name: Some("_my_malloc".to_string()),
+            replacee: insert.binds[0].clone(),
+        }];
+        let mut system = image(libsystem, &[("_exit", 0x10, false)]);
+        system.reexports = vec!["/usr/lib/system/libsystem_malloc.dylib".to_string()];
+
+        let mut process = Process::new();
+        process.add(main);
+        process.add(insert);
+        process.add(system);
+        process.add(image(
+            "/usr/lib/system/libsystem_malloc.dylib",
+            &[("_malloc", 0x2000, false)],
+        ));
+        process.add(image(
+            "/app/libfoo.dylib",
+            &[("_foo", 0x10, false), ("__Znwm", 0x20, true), ("_shared", 0x30, false)],
+        ));
+        process.add(image(
+            "/app/libbar.dylib",
+            &[("__Znwm", 0x40, false), ("_shared", 0x50, false)],
+        ));
+
+        let resolutions: Vec<Resolution> = process
+            .bindings()
+            .into_iter()
+            .map(|binding| binding.resolution)
+            .collect();
+        assert_eq!(
+            resolutions,
+            [
+                Resolution::Interposed(target("/tmp/insert.dylib", "_my_malloc", 0x100)),
+                Resolution::Bound(target("/app/libfoo.dylib", "_foo", 0x10)),
+                Resolution::Bound(target("/app/libbar.dylib", "_shared", 0x50)),
+                Resolution::Coalesced(target("/app/libbar.dylib", "__Znwm", 0x40)),
+                Resolution::Null,
+                Resolution::Missing,
+                // the interposing image binds to the real thing
+                Resolution::Bound(target(
+                    "/usr/lib/system/libsystem_malloc.dylib",
+                    "_malloc",
+                    0x2000
+                )),
+            ]
+        );
+
+        process.force_flat = true;
+        assert_eq!(
+            process.resolve("app", "_shared"),
+            Some(Resolution::Bound(target("/app/libfoo.dylib", "_shared", 0x30)))
+        );
+        assert_eq!(
+            process.resolve("/bin/app", "_exit"),
+            Some(Resolution::Bound(target(libsystem, "_exit", 0x10)))
+        );
+        assert_eq!(process.resolve("nowhere", "_exit"), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! APIs resolved at run time through `GetProcAddress` and `dlsym`.
+//!
+//! Code hiding what it imports looks its APIs up by name instead, and keeps the pointer in a
+//! global or calls through it right away. [`recover`] works out the name asked for at each call
+//! to a resolver: from the argument's value if the function makes it a constant pointer (see
+//! [`crate::vsa::value_before`]), and otherwise by running the function on the IL interpreter
+//! (see [`crate::ilemu`]) up to the call, from its entry and then from the start of the block
+//! holding the call, and reading the string the argument points at then. That covers strings
+//! built on the stack and strings decoded in place before the call.
+//!
+//! [`annotate`] comments each call with the name, and where the code stores the result to a
+//! global straight after the call, makes that global a synthetic import of [`DYNAMIC_LIBRARY`],
+//! so calls through it count as calls to the API.
+
+use crate::{
+    envi::{registers::RegisterContext, Arch},
+    ilemu::{Interpreter, Ram, Stop},
+    pic::AddressSpace,
+    symbolic::{call_argument, return_value, Expr, Function, State, Stmt},
+    syscalls::function_at,
+    vsa::{value_before, ValueSet},
+    workspace::VivWorkspace,
+};
+use std::collections::BTreeMap;
+
+/// The library synthetic imports are made from
+pub const DYNAMIC_LIBRARY: &str = "dynamic";
+
+/// The functions resolving an API by name, taking it as their second argument, and whether
+/// they follow the Windows ABI
+const RESOLVERS: [(&str, bool); 3] = [
+    ("GetProcAddress", true),
+    ("dlsym", false),
+    ("dlvsym", false),
+];
+
+/// The longest API name read
+const MAX_NAME: usize = 256;
+/// How many blocks are run to get to a call
+const MAX_BLOCKS: usize = 10_000;
+const STACK_BASE: u64 = 0x7f00_0000;
+const STACK_SIZE: usize = 0x10_0000;
+
+/// How the name was worked out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Derivation {
+    /// From a constant pointer
+    Static,
+    /// By running the code up to the call
+    Emulated,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    /// The call to the resolver
+    pub call: u64,
+    pub resolver: String,
+    /// The API asked for
+    pub name: String,
+    pub derivation: Derivation,
+    /// The global the result is stored to
+    pub slot: Option<u64>,
+}
+
+/// The printable, NUL terminated name at `va`
+fn read_name(mut read: impl FnMut(u64) -> Option<u8>, va: u64) -> Option<String> {
+    let mut name = String::new();
+    for i in 0..MAX_NAME as u64 {
+        match read(va + i)? {
+            0 if !name.is_empty() => return Some(name),
+            byte if byte.is_ascii_graphic() => name.push(byte as char),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The name the argument points at once the code runs from the block at `from` up to the call
+fn emulate(func: &Function, memory: &Ram, from: u64, call: u64, argument: &Expr) -> Option<String> {
+    let mut registers = RegisterContext::new(func.arch);
+    registers.set_sp(STACK_BASE + STACK_SIZE as u64 / 2);
+    let mut memory = memory.clone();
+    let mut interpreter = Interpreter::new(&mut registers, &mut memory);
+    match interpreter.run_to(func, from, call, MAX_BLOCKS) {
+        Ok(Stop::Reached { .. }) => {}
+        _ => return None,
+    }
+    let pointer = interpreter.eval(argument).ok()?;
+    read_name(|va| memory.read(va, 1).map(|byte| byte as u8), pointer)
+}
+
+/// The global the return value is stored to by the instructions after the call in its block
+fn result_slot(func: &Function, call: u64, result: &Expr) -> Option<u64> {
+    let (_, block) = func.blocks.range(..=call).next_back()?;
+    let mut state = State::new(func.arch);
+    for insn in block.insns.iter().skip_while(|insn| insn.va <= call) {
+        for stmt in insn.stmts.iter() {
+            if let Stmt::Store(addr, value) = stmt {
+                if state.eval(value) == *result {
+                    return state.eval(addr).as_const();
+                }
+            }
+            state.exec(stmt);
+            if state.eval(result) != *result {
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Work out the API each call to a resolver asks for. Calls go by the workspace's xrefs to the
+/// imports, and are looked for in the lifted `functions`; `space` is the memory they run in.
+pub fn recover(
+    workspace: &VivWorkspace,
+    arch: Arch,
+    functions: &BTreeMap<u64, Function>,
+    space: &AddressSpace<'_>,
+) -> Vec<Resolution> {
+    let mut memory = Ram::new();
+    memory.set_big_endian(space.is_big_endian());
+    for (va, bytes, _) in space.maps() {
+        memory.map(va, bytes.to_vec());
+    }
+    memory.map(STACK_BASE, vec![0; STACK_SIZE]);
+    let result = return_value(arch);
+    let mut found = Vec::new();
+    for (resolver, windows) in RESOLVERS {
+        let Some(argument) = call_argument(arch, windows, 1) else {
+            continue;
+        };
+        for call in workspace.get_callers_of_import(resolver) {
+            let call = call as u32 as u64;
+            let Some(func) = function_at(functions, call) else {
+                continue;
+            };
+            let constant = match value_before(func, space, call, &argument) {
+                ValueSet::Values(values) if values.len() == 1 => values.first().copied(),
+                _ => None,
+            };
+            let (name, derivation) = match constant
+                .and_then(|pointer| read_name(|va| space.bytes(va, 1).map(|(b, _)| b[0]), pointer))
+            {
+                Some(name) => (name, Derivation::Static),
+                None => {
+                    let block = func.blocks.range(..=call).next_back().map(|(va, _)| *va);
+                    let name = [Some(func.entry), block]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|from| emulate(func, &memory, from, call, &argument));
+                    let Some(name) = name else {
+                        continue;
+                    };
+                    (name, Derivation::Emulated)
+                }
+            };
+            found.push(Resolution {
+                call,
+                resolver: resolver.to_string(),
+                name,
+                derivation,
+                slot: result.as_ref().and_then(|r| result_slot(func, call, r)),
+            });
+        }
+    }
+    found.sort_by_key(|resolution| resolution.call);
+    found
+}
+
+/// Record a resolution in the workspace: a comment at the call, and the global the result is
+/// stored to as an import
+pub fn annotate(workspace: &mut VivWorkspace, resolution: &Resolution) {
+    let comment = format!("{}: {}", resolution.resolver, resolution.name);
+    workspace.set_comment(resolution.call as i32, &comment, true);
+    if let Some(slot) = resolution.slot {
+        workspace.make_import(slot as i32, DYNAMIC_LIBRARY, &resolution.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::REF_CODE,
+        envi::registers::RegisterModel,
+        symbolic::{BinOp, Block, Insn, Terminator},
+    };
+
+    #[test]
+    fn resolved_names() {
+        let model = RegisterModel::new(Arch::I386);
+        let (esp, eax) = (model.sp(), model.by_name("eax").unwrap());
+        let sp = |offset: u64| Expr::binary(BinOp::Add, Expr::Reg(esp), Expr::Const(offset), 32);
+        let insn = |va, stmts| Insn { va, stmts };
+        let push_args = |va, name: Expr| {
+            insn(
+                va,
+                vec![
+                    Stmt::Set(esp, sp(0xffff_fff8)),
+                    Stmt::Store(sp(4), name),
+                    Stmt::Store(sp(0), Expr::Const(0)),
+                ],
+            )
+        };
+        let mut func = Function::new(Arch::I386, 0x1000);
+        // push "Sleep"; push 0; call GetProcAddress; mov [0x3000], eax
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                push_args(0x1000, Expr::Const(0x2000)),
+                insn(0x100a, vec![Stmt::Unknown]),
+                insn(
+                    0x100f,
+                    vec![Stmt::Store(Expr::Const(0x3000), Expr::Reg(eax))],
+                ),
+            ],
+            end_va: 0x1014,
+            end: Terminator::Jump(0x1100),
+        });
+        // "VirtualAlloc" built on the stack, then passed by address
+        let word = |s: &[u8; 4]| Expr::Const(u32::from_le_bytes(*s) as u64);
+        func.add_block(Block {
+            va: 0x1100,
+            insns: vec![
+                insn(
+                    0x1100,
+                    vec![
+                        Stmt::Set(esp, sp(0xffff_fff0)),
+                        Stmt::Store(sp(0), word(b"Virt")),
+                        Stmt::Store(sp(4), word(b"ualA")),
+                        Stmt::Store(sp(8), word(b"lloc")),
+                        Stmt::Store(sp(12), Expr::Const(0)),
+                        Stmt::Set(eax, sp(0)),
+                    ],
+                ),
+                push_args(0x1120, Expr::Reg(eax)),
+                insn(0x1128, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x112d,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0x1000, func)]);
+        let rodata = b"Sleep\0";
+        let data = [0u8; 4];
+        let code = [0u8; 0x200];
+        let mut space = AddressSpace::new();
+        space.add_map(0x1000, &code, false);
+        space.add_map(0x2000, rodata, false);
+        space.add_map(0x3000, &data, true);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x4000, "kernel32", "GetProcAddress");
+        ws.add_xref(0x100a, 0x4000, REF_CODE, 0);
+        ws.add_xref(0x1128, 0x4000, REF_CODE, 0);
+
+        let found = recover(&ws, Arch::I386, &functions, &space);
+        let summary: Vec<_> = found
+            .iter()
+            .map(|r| (r.call, r.name.as_str(), r.derivation, r.slot))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x100a, "Sleep", Derivation::Static, Some(0x3000)),
+                (0x1128, "VirtualAlloc", Derivation::Emulated, None),
+            ]
+        );
+        for resolution in found.iter() {
+            annotate(&mut ws, resolution);
+        }
+        assert!(ws
+            .get_imports()
+            .contains(&(0x3000, "dynamic.Sleep".to_string())));
+        assert_eq!(
+            ws.get_comments().get(&0x1128).map(String::as_str),
+            Some("GetProcAddress: VirtualAlloc")
+        );
+    }
+}
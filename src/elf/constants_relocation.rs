@@ -905,6 +905,199 @@ pub const R_RISCV_SET16: u32 = 55;
 /// Local label subtraction
 pub const R_RISCV_SET32: u32 = 56;
 
+///////////////////
+// SPARC
+///////////////////
+/// No reloc
+pub const R_SPARC_NONE: u32 = 0;
+pub const R_SPARC_8: u32 = 1;
+pub const R_SPARC_16: u32 = 2;
+/// Direct 32 bit
+pub const R_SPARC_32: u32 = 3;
+pub const R_SPARC_DISP8: u32 = 4;
+pub const R_SPARC_DISP16: u32 = 5;
+pub const R_SPARC_DISP32: u32 = 6;
+/// PC relative 30 bit shifted, of a call
+pub const R_SPARC_WDISP30: u32 = 7;
+/// PC relative 22 bit shifted, of a branch
+pub const R_SPARC_WDISP22: u32 = 8;
+/// High 22 bit
+pub const R_SPARC_HI22: u32 = 9;
+pub const R_SPARC_22: u32 = 10;
+pub const R_SPARC_13: u32 = 11;
+/// Truncated 10 bit
+pub const R_SPARC_LO10: u32 = 12;
+pub const R_SPARC_GOT10: u32 = 13;
+pub const R_SPARC_GOT13: u32 = 14;
+pub const R_SPARC_GOT22: u32 = 15;
+pub const R_SPARC_PC10: u32 = 16;
+pub const R_SPARC_PC22: u32 = 17;
+pub const R_SPARC_WPLT30: u32 = 18;
+/// Copy symbol at runtime
+pub const R_SPARC_COPY: u32 = 19;
+/// Create GOT entry
+pub const R_SPARC_GLOB_DAT: u32 = 20;
+/// Create PLT entry
+pub const R_SPARC_JMP_SLOT: u32 = 21;
+/// Adjust by program base
+pub const R_SPARC_RELATIVE: u32 = 22;
+pub const R_SPARC_UA32: u32 = 23;
+pub const R_SPARC_PLT32: u32 = 24;
+pub const R_SPARC_HIPLT22: u32 = 25;
+pub const R_SPARC_LOPLT10: u32 = 26;
+pub const R_SPARC_PCPLT32: u32 = 27;
+pub const R_SPARC_PCPLT22: u32 = 28;
+pub const R_SPARC_PCPLT10: u32 = 29;
+pub const R_SPARC_10: u32 = 30;
+pub const R_SPARC_11: u32 = 31;
+/// Direct 64 bit
+pub const R_SPARC_64: u32 = 32;
+/// 10 bit with secondary 13 bit addend, kept in the high 24 bits of the type
+pub const R_SPARC_OLO10: u32 = 33;
+pub const R_SPARC_HH22: u32 = 34;
+pub const R_SPARC_HM10: u32 = 35;
+pub const R_SPARC_LM22: u32 = 36;
+pub const R_SPARC_PC_HH22: u32 = 37;
+pub const R_SPARC_PC_HM10: u32 = 38;
+pub const R_SPARC_PC_LM22: u32 = 39;
+pub const R_SPARC_WDISP16: u32 = 40;
+pub const R_SPARC_WDISP19: u32 = 41;
+pub const R_SPARC_GLOB_JMP: u32 = 42;
+pub const R_SPARC_7: u32 = 43;
+pub const R_SPARC_5: u32 = 44;
+pub const R_SPARC_6: u32 = 45;
+pub const R_SPARC_DISP64: u32 = 46;
+pub const R_SPARC_PLT64: u32 = 47;
+pub const R_SPARC_HIX22: u32 = 48;
+pub const R_SPARC_LOX10: u32 = 49;
+pub const R_SPARC_H44: u32 = 50;
+pub const R_SPARC_M44: u32 = 51;
+pub const R_SPARC_L44: u32 = 52;
+/// Global register usage
+pub const R_SPARC_REGISTER: u32 = 53;
+pub const R_SPARC_UA64: u32 = 54;
+pub const R_SPARC_UA16: u32 = 55;
+pub const R_SPARC_TLS_GD_HI22: u32 = 56;
+pub const R_SPARC_TLS_GD_LO10: u32 = 57;
+pub const R_SPARC_TLS_GD_ADD: u32 = 58;
+pub const R_SPARC_TLS_GD_CALL: u32 = 59;
+pub const R_SPARC_TLS_LDM_HI22: u32 = 60;
+pub const R_SPARC_TLS_LDM_LO10: u32 = 61;
+pub const R_SPARC_TLS_LDM_ADD: u32 = 62;
+pub const R_SPARC_TLS_LDM_CALL: u32 = 63;
+pub const R_SPARC_TLS_LDO_HIX22: u32 = 64;
+pub const R_SPARC_TLS_LDO_LOX10: u32 = 65;
+pub const R_SPARC_TLS_LDO_ADD: u32 = 66;
+pub const R_SPARC_TLS_IE_HI22: u32 = 67;
+pub const R_SPARC_TLS_IE_LO10: u32 = 68;
+pub const R_SPARC_TLS_IE_LD: u32 = 69;
+pub const R_SPARC_TLS_IE_LDX: u32 = 70;
+pub const R_SPARC_TLS_IE_ADD: u32 = 71;
+pub const R_SPARC_TLS_LE_HIX22: u32 = 72;
+pub const R_SPARC_TLS_LE_LOX10: u32 = 73;
+pub const R_SPARC_TLS_DTPMOD32: u32 = 74;
+pub const R_SPARC_TLS_DTPMOD64: u32 = 75;
+pub const R_SPARC_TLS_DTPOFF32: u32 = 76;
+pub const R_SPARC_TLS_DTPOFF64: u32 = 77;
+pub const R_SPARC_TLS_TPOFF32: u32 = 78;
+pub const R_SPARC_TLS_TPOFF64: u32 = 79;
+pub const R_SPARC_GOTDATA_HIX22: u32 = 80;
+pub const R_SPARC_GOTDATA_LOX10: u32 = 81;
+pub const R_SPARC_GOTDATA_OP_HIX22: u32 = 82;
+pub const R_SPARC_GOTDATA_OP_LOX10: u32 = 83;
+pub const R_SPARC_GOTDATA_OP: u32 = 84;
+pub const R_SPARC_H34: u32 = 85;
+pub const R_SPARC_SIZE32: u32 = 86;
+pub const R_SPARC_SIZE64: u32 = 87;
+pub const R_SPARC_WDISP10: u32 = 88;
+pub const R_SPARC_JMP_IREL: u32 = 248;
+pub const R_SPARC_IRELATIVE: u32 = 249;
+pub const R_SPARC_GNU_VTINHERIT: u32 = 250;
+pub const R_SPARC_GNU_VTENTRY: u32 = 251;
+pub const R_SPARC_REV32: u32 = 252;
+
+///////////////////
+// s390 and s390x
+///////////////////
+/// No reloc
+pub const R_390_NONE: u32 = 0;
+pub const R_390_8: u32 = 1;
+pub const R_390_12: u32 = 2;
+pub const R_390_16: u32 = 3;
+/// Direct 32 bit
+pub const R_390_32: u32 = 4;
+/// PC relative 32 bit
+pub const R_390_PC32: u32 = 5;
+pub const R_390_GOT12: u32 = 6;
+pub const R_390_GOT32: u32 = 7;
+pub const R_390_PLT32: u32 = 8;
+/// Copy symbol at runtime
+pub const R_390_COPY: u32 = 9;
+/// Create GOT entry
+pub const R_390_GLOB_DAT: u32 = 10;
+/// Create PLT entry
+pub const R_390_JMP_SLOT: u32 = 11;
+/// Adjust by program base
+pub const R_390_RELATIVE: u32 = 12;
+pub const R_390_GOTOFF32: u32 = 13;
+pub const R_390_GOTPC: u32 = 14;
+pub const R_390_GOT16: u32 = 15;
+pub const R_390_PC16: u32 = 16;
+pub const R_390_PC16DBL: u32 = 17;
+pub const R_390_PLT16DBL: u32 = 18;
+/// PC relative 32 bit shifted by 1
+pub const R_390_PC32DBL: u32 = 19;
+/// 32 bit PC rel. PLT shifted by 1
+pub const R_390_PLT32DBL: u32 = 20;
+pub const R_390_GOTPCDBL: u32 = 21;
+/// Direct 64 bit
+pub const R_390_64: u32 = 22;
+pub const R_390_PC64: u32 = 23;
+pub const R_390_GOT64: u32 = 24;
+pub const R_390_PLT64: u32 = 25;
+/// 32 bit PC rel. to GOT entry shifted by 1
+pub const R_390_GOTENT: u32 = 26;
+pub const R_390_GOTOFF16: u32 = 27;
+pub const R_390_GOTOFF64: u32 = 28;
+pub const R_390_GOTPLT12: u32 = 29;
+pub const R_390_GOTPLT16: u32 = 30;
+pub const R_390_GOTPLT32: u32 = 31;
+pub const R_390_GOTPLT64: u32 = 32;
+pub const R_390_GOTPLTENT: u32 = 33;
+pub const R_390_PLTOFF16: u32 = 34;
+pub const R_390_PLTOFF32: u32 = 35;
+pub const R_390_PLTOFF64: u32 = 36;
+pub const R_390_TLS_LOAD: u32 = 37;
+pub const R_390_TLS_GDCALL: u32 = 38;
+pub const R_390_TLS_LDCALL: u32 = 39;
+pub const R_390_TLS_GD32: u32 = 40;
+pub const R_390_TLS_GD64: u32 = 41;
+pub const R_390_TLS_GOTIE12: u32 = 42;
+pub const R_390_TLS_GOTIE32: u32 = 43;
+pub const R_390_TLS_GOTIE64: u32 = 44;
+pub const R_390_TLS_LDM32: u32 = 45;
+pub const R_390_TLS_LDM64: u32 = 46;
+pub const R_390_TLS_IE32: u32 = 47;
+pub const R_390_TLS_IE64: u32 = 48;
+pub const R_390_TLS_IEENT: u32 = 49;
+pub const R_390_TLS_LE32: u32 = 50;
+pub const R_390_TLS_LE64: u32 = 51;
+pub const R_390_TLS_LDO32: u32 = 52;
+pub const R_390_TLS_LDO64: u32 = 53;
+pub const R_390_TLS_DTPMOD: u32 = 54;
+pub const R_390_TLS_DTPOFF: u32 = 55;
+pub const R_390_TLS_TPOFF: u32 = 56;
+pub const R_390_20: u32 = 57;
+pub const R_390_GOT20: u32 = 58;
+pub const R_390_GOTPLT20: u32 = 59;
+pub const R_390_TLS_GOTIE20: u32 = 60;
+/// Indirect, the result of calling the function at B + A
+pub const R_390_IRELATIVE: u32 = 61;
+pub const R_390_PC12DBL: u32 = 62;
+pub const R_390_PLT12DBL: u32 = 63;
+pub const R_390_PC24DBL: u32 = 64;
+pub const R_390_PLT24DBL: u32 = 65;
+
 #[inline]
 pub fn r_to_str(typ: u32, machine: u16) -> &'static str {
     use crate::elf::header::*;
@@ -1412,6 +1605,174 @@ pub fn r_to_str(typ: u32, machine: u16) -> &'static str {
         R_RISCV_SET32 => "R_RISCV_SET32",
         _ => "R_UNKNOWN_RISCV",
         }},
+        // SPARC; SPARC V9 keeps extra addend bits above the low 8 bits of the type
+        EM_SPARC | EM_SPARC32PLUS | EM_SPARCV9 => { match typ & 0xff {
+        R_SPARC_NONE => "R_SPARC_NONE",
+        R_SPARC_8 => "R_SPARC_8",
+        R_SPARC_16 => "R_SPARC_16",
+        R_SPARC_32 => "R_SPARC_32",
+        R_SPARC_DISP8 => "R_SPARC_DISP8",
+        R_SPARC_DISP16 => "R_SPARC_DISP16",
+        R_SPARC_DISP32 => "R_SPARC_DISP32",
+        R_SPARC_WDISP30 => "R_SPARC_WDISP30",
+        R_SPARC_WDISP22 => "R_SPARC_WDISP22",
+        R_SPARC_HI22 => "R_SPARC_HI22",
+        R_SPARC_22 => "R_SPARC_22",
+        R_SPARC_13 => "R_SPARC_13",
+        R_SPARC_LO10 => "R_SPARC_LO10",
+        R_SPARC_GOT10 => "R_SPARC_GOT10",
+        R_SPARC_GOT13 => "R_SPARC_GOT13",
+        R_SPARC_GOT22 => "R_SPARC_GOT22",
+        R_SPARC_PC10 => "R_SPARC_PC10",
+        R_SPARC_PC22 => "R_SPARC_PC22",
+        R_SPARC_WPLT30 => "R_SPARC_WPLT30",
+        R_SPARC_COPY => "R_SPARC_COPY",
+        R_SPARC_GLOB_DAT => "R_SPARC_GLOB_DAT",
+        R_SPARC_JMP_SLOT => "R_SPARC_JMP_SLOT",
+        R_SPARC_RELATIVE => "R_SPARC_RELATIVE",
+        R_SPARC_UA32 => "R_SPARC_UA32",
+        R_SPARC_PLT32 => "R_SPARC_PLT32",
+        R_SPARC_HIPLT22 => "R_SPARC_HIPLT22",
+        R_SPARC_LOPLT10 => "R_SPARC_LOPLT10",
+        R_SPARC_PCPLT32 => "R_SPARC_PCPLT32",
+        R_SPARC_PCPLT22 => "R_SPARC_PCPLT22",
+        R_SPARC_PCPLT10 => "R_SPARC_PCPLT10",
+        R_SPARC_10 => "R_SPARC_10",
+        R_SPARC_11 => "R_SPARC_11",
+        R_SPARC_64 => "R_SPARC_64",
+        R_SPARC_OLO10 => "R_SPARC_OLO10",
+        R_SPARC_HH22 => "R_SPARC_HH22",
+        R_SPARC_HM10 => "R_SPARC_HM10",
+        R_SPARC_LM22 => "R_SPARC_LM22",
+        R_SPARC_PC_HH22 => "R_SPARC_PC_HH22",
+        R_SPARC_PC_HM10 => "R_SPARC_PC_HM10",
+        R_SPARC_PC_LM22 => "R_SPARC_PC_LM22",
+        R_SPARC_WDISP16 => "R_SPARC_WDISP16",
+        R_SPARC_WDISP19 => "R_SPARC_WDISP19",
+        R_SPARC_GLOB_JMP => "R_SPARC_GLOB_JMP",
+        R_SPARC_7 => "R_SPARC_7",
+        R_SPARC_5 => "R_SPARC_5",
+        R_SPARC_6 => "R_SPARC_6",
+        R_SPARC_DISP64 => "R_SPARC_DISP64",
+        R_SPARC_PLT64 => "R_SPARC_PLT64",
+        R_SPARC_HIX22 => "R_SPARC_HIX22",
+        R_SPARC_LOX10 => "R_SPARC_LOX10",
+        R_SPARC_H44 => "R_SPARC_H44",
+        R_SPARC_M44 => "R_SPARC_M44",
+        R_SPARC_L44 => "R_SPARC_L44",
+        R_SPARC_REGISTER => "R_SPARC_REGISTER",
+        R_SPARC_UA64 => "R_SPARC_UA64",
+        R_SPARC_UA16 => "R_SPARC_UA16",
+        R_SPARC_TLS_GD_HI22 => "R_SPARC_TLS_GD_HI22",
+        R_SPARC_TLS_GD_LO10 => "R_SPARC_TLS_GD_LO10",
+        R_SPARC_TLS_GD_ADD => "R_SPARC_TLS_GD_ADD",
+        R_SPARC_TLS_GD_CALL => "R_SPARC_TLS_GD_CALL",
+        R_SPARC_TLS_LDM_HI22 => "R_SPARC_TLS_LDM_HI22",
+        R_SPARC_TLS_LDM_LO10 => "R_SPARC_TLS_LDM_LO10",
+        R_SPARC_TLS_LDM_ADD => "R_SPARC_TLS_LDM_ADD",
+        R_SPARC_TLS_LDM_CALL => "R_SPARC_TLS_LDM_CALL",
+        R_SPARC_TLS_LDO_HIX22 => "R_SPARC_TLS_LDO_HIX22",
+        R_SPARC_TLS_LDO_LOX10 => "R_SPARC_TLS_LDO_LOX10",
+        R_SPARC_TLS_LDO_ADD => "R_SPARC_TLS_LDO_ADD",
+        R_SPARC_TLS_IE_HI22 => "R_SPARC_TLS_IE_HI22",
+        R_SPARC_TLS_IE_LO10 => "R_SPARC_TLS_IE_LO10",
+        R_SPARC_TLS_IE_LD => "R_SPARC_TLS_IE_LD",
+        R_SPARC_TLS_IE_LDX => "R_SPARC_TLS_IE_LDX",
+        R_SPARC_TLS_IE_ADD => "R_SPARC_TLS_IE_ADD",
+        R_SPARC_TLS_LE_HIX22 => "R_SPARC_TLS_LE_HIX22",
+        R_SPARC_TLS_LE_LOX10 => "R_SPARC_TLS_LE_LOX10",
+        R_SPARC_TLS_DTPMOD32 => "R_SPARC_TLS_DTPMOD32",
+        R_SPARC_TLS_DTPMOD64 => "R_SPARC_TLS_DTPMOD64",
+        R_SPARC_TLS_DTPOFF32 => "R_SPARC_TLS_DTPOFF32",
+        R_SPARC_TLS_DTPOFF64 => "R_SPARC_TLS_DTPOFF64",
+        R_SPARC_TLS_TPOFF32 => "R_SPARC_TLS_TPOFF32",
+        R_SPARC_TLS_TPOFF64 => "R_SPARC_TLS_TPOFF64",
+        R_SPARC_GOTDATA_HIX22 => "R_SPARC_GOTDATA_HIX22",
+        R_SPARC_GOTDATA_LOX10 => "R_SPARC_GOTDATA_LOX10",
+        R_SPARC_GOTDATA_OP_HIX22 => "R_SPARC_GOTDATA_OP_HIX22",
+        R_SPARC_GOTDATA_OP_LOX10 => "R_SPARC_GOTDATA_OP_LOX10",
+        R_SPARC_GOTDATA_OP => "R_SPARC_GOTDATA_OP",
+        R_SPARC_H34 => "R_SPARC_H34",
+        R_SPARC_SIZE32 => "R_SPARC_SIZE32",
+        R_SPARC_SIZE64 => "R_SPARC_SIZE64",
+        R_SPARC_WDISP10 => "R_SPARC_WDISP10",
+        R_SPARC_JMP_IREL => "R_SPARC_JMP_IREL",
+        R_SPARC_IRELATIVE => "R_SPARC_IRELATIVE",
+        R_SPARC_GNU_VTINHERIT => "R_SPARC_GNU_VTINHERIT",
+        R_SPARC_GNU_VTENTRY => "R_SPARC_GNU_VTENTRY",
+        R_SPARC_REV32 => "R_SPARC_REV32",
+        _ => "R_UNKNOWN_SPARC",
+        }},
+        // s390 and s390x
+        EM_S390 => { match typ {
+        R_390_NONE => "R_390_NONE",
+        R_390_8 => "R_390_8",
+        R_390_12 => "R_390_12",
+        R_390_16 => "R_390_16",
+        R_390_32 => "R_390_32",
+        R_390_PC32 => "R_390_PC32",
+        R_390_GOT12 => "R_390_GOT12",
+        R_390_GOT32 => "R_390_GOT32",
+        R_390_PLT32 => "R_390_PLT32",
+        R_390_COPY => "R_390_COPY",
+        R_390_GLOB_DAT => "R_390_GLOB_DAT",
+        R_390_JMP_SLOT => "R_390_JMP_SLOT",
+        R_390_RELATIVE => "R_390_RELATIVE",
+        R_390_GOTOFF32 => "R_390_GOTOFF32",
+        R_390_GOTPC => "R_390_GOTPC",
+        R_390_GOT16 => "R_390_GOT16",
+        R_390_PC16 => "R_390_PC16",
+        R_390_PC16DBL => "R_390_PC16DBL",
+        R_390_PLT16DBL => "R_390_PLT16DBL",
+        R_390_PC32DBL => "R_390_PC32DBL",
+        R_390_PLT32DBL => "R_390_PLT32DBL",
+        R_390_GOTPCDBL => "R_390_GOTPCDBL",
+        R_390_64 => "R_390_64",
+        R_390_PC64 => "R_390_PC64",
+        R_390_GOT64 => "R_390_GOT64",
+        R_390_PLT64 => "R_390_PLT64",
+        R_390_GOTENT => "R_390_GOTENT",
+        R_390_GOTOFF16 => "R_390_GOTOFF16",
+        R_390_GOTOFF64 => "R_390_GOTOFF64",
+        R_390_GOTPLT12 => "R_390_GOTPLT12",
+        R_390_GOTPLT16 => "R_390_GOTPLT16",
+        R_390_GOTPLT32 => "R_390_GOTPLT32",
+        R_390_GOTPLT64 => "R_390_GOTPLT64",
+        R_390_GOTPLTENT => "R_390_GOTPLTENT",
+        R_390_PLTOFF16 => "R_390_PLTOFF16",
+        R_390_PLTOFF32 => "R_390_PLTOFF32",
+        R_390_PLTOFF64 => "R_390_PLTOFF64",
+        R_390_TLS_LOAD => "R_390_TLS_LOAD",
+        R_390_TLS_GDCALL => "R_390_TLS_GDCALL",
+        R_390_TLS_LDCALL => "R_390_TLS_LDCALL",
+        R_390_TLS_GD32 => "R_390_TLS_GD32",
+        R_390_TLS_GD64 => "R_390_TLS_GD64",
+        R_390_TLS_GOTIE12 => "R_390_TLS_GOTIE12",
+        R_390_TLS_GOTIE32 => "R_390_TLS_GOTIE32",
+        R_390_TLS_GOTIE64 => "R_390_TLS_GOTIE64",
+        R_390_TLS_LDM32 => "R_390_TLS_LDM32",
+        R_390_TLS_LDM64 => "R_390_TLS_LDM64",
+        R_390_TLS_IE32 => "R_390_TLS_IE32",
+        R_390_TLS_IE64 => "R_390_TLS_IE64",
+        R_390_TLS_IEENT => "R_390_TLS_IEENT",
+        R_390_TLS_LE32 => "R_390_TLS_LE32",
+        R_390_TLS_LE64 => "R_390_TLS_LE64",
+        R_390_TLS_LDO32 => "R_390_TLS_LDO32",
+        R_390_TLS_LDO64 => "R_390_TLS_LDO64",
+        R_390_TLS_DTPMOD => "R_390_TLS_DTPMOD",
+        R_390_TLS_DTPOFF => "R_390_TLS_DTPOFF",
+        R_390_TLS_TPOFF => "R_390_TLS_TPOFF",
+        R_390_20 => "R_390_20",
+        R_390_GOT20 => "R_390_GOT20",
+        R_390_GOTPLT20 => "R_390_GOTPLT20",
+        R_390_TLS_GOTIE20 => "R_390_TLS_GOTIE20",
+        R_390_IRELATIVE => "R_390_IRELATIVE",
+        R_390_PC12DBL => "R_390_PC12DBL",
+        R_390_PLT12DBL => "R_390_PLT12DBL",
+        R_390_PC24DBL => "R_390_PC24DBL",
+        R_390_PLT24DBL => "R_390_PLT24DBL",
+        _ => "R_UNKNOWN_390",
+        }},
         _ => "R_UNKNOWN",
     }
 }
@@ -55,6 +55,10 @@ pub mod reloc;
 pub mod note;
 #[cfg(all(any(feature = "elf32", feature = "elf64"), feature = "alloc"))]
 pub mod symver;
+#[cfg(all(feature = "elf32", feature = "elf64", feature = "endian_fd"))]
+pub mod unified_symbols;
+#[cfg(all(feature = "elf32", feature = "elf64", feature = "endian_fd"))]
+pub mod writer;
 
 macro_rules! if_sylvan {
     ($($i:item)*) => ($(
@@ -178,6 +182,17 @@ if_sylvan! {
                 })
             }
         }
+        /// Return the contents of the `NT_GNU_BUILD_ID` note, if any; looks in the note program headers first and falls back to the note sections
+        pub fn build_id(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+            // a note which can't be read is read again on every call, so stop at it
+            let find = |notes: note::NoteIterator<'a>| {
+                notes.map_while(Result::ok)
+                    .find(|note| note.n_type == note::NT_GNU_BUILD_ID && note.name == "GNU")
+                    .map(|note| note.desc)
+            };
+            self.iter_note_headers(data).and_then(find)
+                .or_else(|| self.iter_note_sections(data, None).and_then(find))
+        }
         /// Try to iterate notes in SHT_NOTE sections; returns `None` if there aren't any note sections in this binary
         ///
         /// If a section_name is given, only the section with the according name is iterated.
@@ -286,7 +301,7 @@ if_sylvan! {
                 }
 
                 if section_idx >= section_headers.len() {
-                    // FIXME: warn! here
+                    anomaly!("string table section {} out of {} sections, using an empty one", section_idx, section_headers.len());
                     Ok(Strtab::default())
                 } else {
                     let shdr = &section_headers[section_idx];
@@ -325,8 +340,10 @@ if_sylvan! {
                                           0x0)?;
 
                 if dyn_info.soname != 0 {
-                    // FIXME: warn! here
                     soname = dynstrtab.get_at(dyn_info.soname);
+                    if soname.is_none() {
+                        anomaly!("DT_SONAME offset {:#x} is past the dynamic string table", dyn_info.soname);
+                    }
                 }
                 if dyn_info.needed_count > 0 {
                     libraries = dynamic.get_libraries(&dynstrtab);
@@ -554,6 +571,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn build_id_stops_at_bad_note() {
+        let mut crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        // the name of the note in the first section runs past the end of the file
+        crt1[0x40..0x44].copy_from_slice(&[0xff; 4]);
+        let binary = Elf::parse(&crt1).unwrap();
+        assert!(binary.iter_note_sections(&crt1, None).unwrap().next().unwrap().is_err());
+        assert_eq!(binary.build_id(&crt1), None);
+    }
+
     // See https://github.com/m4b/goblin/issues/257
     #[test]
     #[allow(unused)]
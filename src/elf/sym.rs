@@ -45,6 +45,8 @@ pub const STT_HIOS: u8 = 12;
 pub const STT_LOPROC: u8 = 13;
 /// End of processor-specific.
 pub const STT_HIPROC: u8 = 15;
+/// SPARC global register use; the value is the register number, not an address.
+pub const STT_SPARC_REGISTER: u8 = 13;
 
 /// === Sym visibility ===
 /// Default: Visibility is specified by the symbol's binding type
@@ -0,0 +1,396 @@
+//! A single view of the static (`.symtab`) and dynamic (`.dynsym`) symbols of an ELF binary,
+//! which is what symbolization almost always wants: every defined symbol once, sorted by
+//! address, with its version and where it was found.
+
+use crate::elf::{header, section_header, sym, Elf};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// Which symbol table(s) a symbol was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolSource {
+    Symtab,
+    Dynsym,
+    Both,
+}
+
+/// The ARM and AArch64 mapping symbols, which mark the start of a run of instructions or data
+/// rather than naming anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSymbol {
+    /// `$a`, A32 instructions
+    Arm,
+    /// `$t`, T32 instructions
+    Thumb,
+    /// `$x`, A64 instructions
+    A64,
+    /// `$d`, data
+    Data,
+}
+
+impl MappingSymbol {
+    /// Recognize a mapping symbol name, which may carry a `.suffix` (e.g. `$d.1`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let kind = name.split('.').next().unwrap_or(name);
+        match kind {
+            "$a" => Some(MappingSymbol::Arm),
+            "$t" => Some(MappingSymbol::Thumb),
+            "$x" => Some(MappingSymbol::A64),
+            "$d" => Some(MappingSymbol::Data),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnifiedSymbol<'a> {
+    /// The name without any `@VERSION` suffix
+    pub name: &'a str,
+    /// The address of the symbol; the Thumb bit of ARM function symbols is cleared
+    pub address: u64,
+    pub size: u64,
+    pub bind: u8,
+    pub typ: u8,
+    pub visibility: u8,
+    pub shndx: usize,
+    /// The symbol version, from the version sections or from a `name@VERSION` static symbol
+    pub version: Option<&'a str>,
+    /// Whether this is a non default version (`name@VERSION` rather than `name@@VERSION`)
+    pub hidden_version: bool,
+    /// Whether this is an ARM Thumb function
+    pub thumb: bool,
+    pub mapping: Option<MappingSymbol>,
+    pub source: SymbolSource,
+}
+
+/// Split `name@VERSION` and `name@@VERSION` into the name, the version and whether the version
+/// is hidden (i.e. not the default one)
+pub fn split_version(name: &str) -> (&str, Option<&str>, bool) {
+    match name.find('@') {
+        Some(at) => match name[at..].strip_prefix("@@") {
+            Some(version) => (&name[..at], Some(version), false),
+            None => (&name[..at], Some(&name[at + 1..]), true),
+        },
+        None => (name, None, false),
+    }
+}
+
+fn bind_rank(bind: u8) -> u8 {
+    match bind {
+        sym::STB_GLOBAL => 0,
+        sym::STB_WEAK => 1,
+        sym::STB_LOCAL => 3,
+        _ => 2,
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// Merge the static and dynamic symbol tables into one list of the defined symbols, sorted
+    /// by address. A symbol present in both tables is listed once, with the version from the
+    /// dynamic table and the size and type of whichever table has them. Symbols at the same
+    /// address are ordered global, weak, then local.
+    pub fn symbols_unified(&self) -> Vec<UnifiedSymbol<'a>> {
+        let arm = self.header.e_machine == header::EM_ARM;
+        let mapping_symbols = arm || self.header.e_machine == header::EM_AARCH64;
+        let mut versions = BTreeMap::new();
+        if let Some(ref verdef) = self.verdef {
+            for def in verdef.iter() {
+                if let Some(name) = def
+                    .iter()
+                    .next()
+                    .and_then(|aux| self.dynstrtab.get_at(aux.vda_name))
+                {
+                    versions.insert(def.vd_ndx, name);
+                }
+            }
+        }
+
+        let mut symbols: BTreeMap<(u64, &'a str, Option<&'a str>), UnifiedSymbol<'a>> =
+            BTreeMap::new();
+        let tables = [
+            (&self.syms, &self.strtab, SymbolSource::Symtab),
+            (&self.dynsyms, &self.dynstrtab, SymbolSource::Dynsym),
+        ];
+        for (symtab, strtab, source) in tables {
+            for (idx, symbol) in symtab.iter().enumerate() {
+                let typ = symbol.st_type();
+                if symbol.st_shndx == section_header::SHN_UNDEF as usize
+                    || typ == sym::STT_SECTION
+                    || typ == sym::STT_FILE
+                {
+                    continue;
+                }
+                let (name, mut version, mut hidden_version) = match strtab.get_at(symbol.st_name) {
+                    Some(name) if !name.is_empty() => split_version(name),
+                    _ => continue,
+                };
+                if source == SymbolSource::Dynsym {
+                    let versym = self.versym.as_ref().and_then(|versym| versym.get_at(idx));
+                    if let Some(versym) = versym {
+                        if let Some(name) = versions.get(&versym.version()) {
+                            version = Some(*name);
+                            hidden_version = versym.is_hidden();
+                        }
+                    }
+                }
+                let mapping = if mapping_symbols {
+                    MappingSymbol::from_name(name)
+                } else {
+                    None
+                };
+                let thumb = arm && typ == sym::STT_FUNC && symbol.st_value & 1 == 1;
+                let address = if thumb {
+                    symbol.st_value & !1
+                } else {
+                    symbol.st_value
+                };
+                let unified = UnifiedSymbol {
+                    name,
+                    address,
+                    size: symbol.st_size,
+                    bind: symbol.st_bind(),
+                    typ,
+                    visibility: symbol.st_visibility(),
+                    shndx: symbol.st_shndx,
+                    version,
+                    hidden_version,
+                    thumb,
+                    mapping,
+                    source,
+                };
+                // the unversioned static symbol of a versioned dynamic one is the same symbol
+                let existing = symbols
+                    .remove(&(address, name, version))
+                    .or_else(|| symbols.remove(&(address, name, None)));
+                let merged = match existing {
+                    Some(existing) if existing.source != source => merge(existing, unified),
+                    Some(existing) => existing,
+                    None => unified,
+                };
+                symbols.insert((address, name, merged.version), merged);
+            }
+        }
+        let mut symbols = symbols.into_values().collect::<Vec<_>>();
+        symbols.sort_by(|a, b| {
+            a.address
+                .cmp(&b.address)
+                .then(bind_rank(a.bind).cmp(&bind_rank(b.bind)))
+                .then(a.name.cmp(b.name))
+        });
+        symbols
+    }
+}
+
+/// Merge the dynamic symbol `dynamic` into the static symbol `existing`
+fn merge<'a>(mut existing: UnifiedSymbol<'a>, dynamic: UnifiedSymbol<'a>) -> UnifiedSymbol<'a> {
+    existing.source = SymbolSource::Both;
+    if dynamic.version.is_some() {
+        existing.version = dynamic.version;
+        existing.hidden_version = dynamic.hidden_version;
+    }
+    if existing.size == 0 {
+        existing.size = dynamic.size;
+    }
+    if existing.typ == sym::STT_NOTYPE {
+        existing.typ = dynamic.typ;
+    }
+    // the dynamic table has the binding the dynamic linker actually sees
+    existing.bind = dynamic.bind;
+    existing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container::{Container, Ctx},
+        elf::{
+            section_header::SectionHeader,
+            sym::Symtab,
+            symver::{VerdefSection, VersymSection},
+            Header,
+        },
+        strtab::Strtab,
+    };
+
+    #[test]
+    fn version_suffixes() {
+        assert_eq!(split_version("memcpy"), ("memcpy", None, false));
+        assert_eq!(
+            split_version("memcpy@@GLIBC_2.14"),
+            ("memcpy", Some("GLIBC_2.14"), false)
+        );
+        assert_eq!(
+            split_version("memcpy@GLIBC_2.2.5"),
+            ("memcpy", Some("GLIBC_2.2.5"), true)
+        );
+        assert_eq!(MappingSymbol::from_name("$d.12"), Some(MappingSymbol::Data));
+        assert_eq!(MappingSymbol::from_name("$dollar"), None);
+    }
+
+    #[test]
+    fn unified_view() {
+        let crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        let elf = Elf::parse(&crt1).unwrap();
+        let symbols = elf.symbols_unified();
+        assert!(!symbols.is_empty());
+        assert!(symbols.windows(2).all(|w| w[0].address <= w[1].address));
+        assert!(symbols.iter().all(|s| s.source == SymbolSource::Symtab));
+        assert!(symbols.iter().any(|s| s.name == "_start"));
+        // section symbols and imports are not part of the view
+        assert!(symbols
+            .iter()
+            .all(|s| s.typ != sym::STT_SECTION && s.shndx != 0));
+    }
+
+    /// The symbol tables of a Thumb library and the section headers of each, in the order
+    /// `.strtab`, `.symtab`, `.dynstr`, `.dynsym`, `.gnu.version`, `.gnu.version_d`: a static
+    /// `main`, mapping symbols and a local Thumb function, and a dynamic `main` at version
+    /// `LIBFOO_1.0` and `memcpy` at the hidden version `LIBFOO_2.0`
+    fn thumb_library() -> (Vec<u8>, Vec<SectionHeader>) {
+        fn symbols(out: &mut Vec<u8>, symbols: &[(u32, u32, u32, u8)]) {
+            out.resize(out.len() + 16, 0);
+            for &(st_name, st_value, st_size, st_info) in symbols {
+                for value in [st_name, st_value, st_size] {
+                    out.extend(value.to_le_bytes());
+                }
+                out.extend([st_info, 0, 1, 0]);
+            }
+        }
+        let func = |bind: u8| bind << 4 | sym::STT_FUNC;
+        let mut out = Vec::new();
+        let mut starts = vec![0];
+        out.extend(b"\0main\0$t\0$d.1\0helper\0");
+        starts.push(out.len());
+        symbols(
+            &mut out,
+            &[
+                (1, 0x8001, 0x10, func(sym::STB_GLOBAL)),
+                (6, 0x8000, 0, sym::STB_LOCAL << 4),
+                (9, 0x8010, 0, sym::STB_LOCAL << 4),
+                (14, 0x8021, 4, func(sym::STB_LOCAL)),
+            ],
+        );
+        starts.push(out.len());
+        out.extend(b"\0main\0memcpy\0libfoo.so\0LIBFOO_1.0\0LIBFOO_2.0\0");
+        starts.push(out.len());
+        symbols(
+            &mut out,
+            &[
+                (1, 0x8001, 0, func(sym::STB_GLOBAL)),
+                (6, 0x8030, 8, func(sym::STB_GLOBAL)),
+            ],
+        );
+        starts.push(out.len());
+        for version in [0u16, 2, 0x8003] {
+            out.extend(version.to_le_bytes());
+        }
+        // the base version, which names the file, then the two versions of symbols
+        starts.push(out.len());
+        for (ndx, vda_name) in [(1u16, 13u32), (2, 23), (3, 34)] {
+            let vd_next = if ndx == 3 { 0 } else { 28u32 };
+            for value in [1, u16::from(ndx == 1), ndx, 1] {
+                out.extend(value.to_le_bytes());
+            }
+            for value in [0, 20, vd_next, vda_name, 0] {
+                out.extend(value.to_le_bytes());
+            }
+        }
+        starts.push(out.len());
+        let types = [
+            section_header::SHT_STRTAB,
+            section_header::SHT_SYMTAB,
+            section_header::SHT_STRTAB,
+            section_header::SHT_DYNSYM,
+            section_header::SHT_GNU_VERSYM,
+            section_header::SHT_GNU_VERDEF,
+        ];
+        let shdrs = types
+            .iter()
+            .zip(starts.windows(2))
+            .map(|(&sh_type, range)| SectionHeader {
+                sh_type,
+                sh_offset: range[0] as u64,
+                sh_size: (range[1] - range[0]) as u64,
+                sh_info: if sh_type == section_header::SHT_GNU_VERDEF {
+                    3
+                } else {
+                    0
+                },
+                ..Default::default()
+            })
+            .collect();
+        (out, shdrs)
+    }
+
+    #[test]
+    fn merged_versioned_thumb() {
+        let (bytes, shdrs) = thumb_library();
+        let ctx = Ctx::new(Container::Little, scroll::LE);
+        let strtab = |shdr: &SectionHeader| {
+            Strtab::parse(&bytes, shdr.sh_offset as usize, shdr.sh_size as usize, 0).unwrap()
+        };
+        let symtab = |shdr: &SectionHeader| {
+            Symtab::parse(
+                &bytes,
+                shdr.sh_offset as usize,
+                shdr.sh_size as usize / 16,
+                ctx,
+            )
+            .unwrap()
+        };
+        let mut header = Header::new(ctx);
+        header.e_machine = header::EM_ARM;
+        let mut elf = Elf::lazy_parse(header).unwrap();
+        elf.strtab = strtab(&shdrs[0]);
+        elf.syms = symtab(&shdrs[1]);
+        elf.dynstrtab = strtab(&shdrs[2]);
+        elf.dynsyms = symtab(&shdrs[3]);
+        elf.versym = VersymSection::parse(&bytes, &shdrs, ctx).unwrap();
+        elf.verdef = VerdefSection::parse(&bytes, &shdrs, ctx).unwrap();
+
+        let symbols = elf.symbols_unified();
+        let found = symbols
+            .iter()
+            .map(|s| (s.name, s.address, s.size, s.source))
+            .collect::<Vec<_>>();
+        // the Thumb bit is off the addresses, and the global sorts before the local
+        assert_eq!(
+            found,
+            [
+                ("main", 0x8000, 0x10, SymbolSource::Both),
+                ("$t", 0x8000, 0, SymbolSource::Symtab),
+                ("$d.1", 0x8010, 0, SymbolSource::Symtab),
+                ("helper", 0x8020, 4, SymbolSource::Symtab),
+                ("memcpy", 0x8030, 8, SymbolSource::Dynsym),
+            ]
+        );
+        let versions = symbols
+            .iter()
+            .map(|s| (s.version, s.hidden_version))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            [
+                (Some("LIBFOO_1.0"), false),
+                (None, false),
+                (None, false),
+                (None, false),
+                (Some("LIBFOO_2.0"), true),
+            ]
+        );
+        let kinds = symbols
+            .iter()
+            .map(|s| (s.thumb, s.mapping))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                (true, None),
+                (false, Some(MappingSymbol::Thumb)),
+                (false, Some(MappingSymbol::Data)),
+                (true, None),
+                (false, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,933 @@
+//! Rewriting of ELF binaries, e.g. to give a stripped binary back the names recovered by analysis.
+//!
+//! The writer never moves anything the loader depends on: the rebuilt tables and a new section
+//! header table are appended to the end of the file and the ELF header is pointed at them. The
+//! old tables stay behind as unreferenced bytes. Data appended after the end of the binary
+//! proper (e.g. the zip archive of a self-extractor) is kept after the new tables, unless
+//! [`ElfWriter::set_appended_data`] strips it. Transforms registered for a section run on its
+//! final contents; a section which is not loaded is moved to the end of the file if it grows.
+//!
+//! Editing the dynamic section (`DT_NEEDED`, `DT_RUNPATH`, `DT_SONAME`) works the same way. The
+//! dynamic string table and the dynamic array are rewritten in place when they still fit, and
+//! are otherwise moved to a new writable `PT_LOAD` segment at the end of the file. That segment
+//! takes the program header slot of the `PT_NOTE` (or a `PT_NULL`) entry, since the program
+//! header table can't grow without moving what follows it.
+
+use crate::container::{Container, Ctx};
+use crate::elf::{
+    dynamic::{self, Dyn},
+    program_header::{self, ProgramHeader},
+    section_header::{self, SectionHeader},
+    sym::{self, Sym},
+    Elf,
+};
+use crate::error;
+use crate::overlay::{self, AppendedData, Artifact};
+use crate::transform::{SectionRef, SectionTransform, SectionTransforms};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use scroll::{
+    ctx::{IntoCtx, SizeWith},
+    Pwrite,
+};
+
+/// A `.symtab` entry as the writer sees it, with its name instead of a string table offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolEntry {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    /// One of the `STB_*` bindings
+    pub bind: u8,
+    /// One of the `STT_*` types
+    pub typ: u8,
+    pub other: u8,
+    /// The index of the section the symbol is defined in, or one of the reserved `SHN_*` indices
+    pub shndx: usize,
+}
+
+impl SymbolEntry {
+    pub fn is_local(&self) -> bool {
+        self.bind == sym::STB_LOCAL
+    }
+
+    fn to_sym(&self, st_name: usize) -> Sym {
+        Sym {
+            st_name,
+            st_info: (self.bind << 4) | (self.typ & 0xf),
+            st_other: self.other,
+            st_shndx: self.shndx,
+            st_value: self.value,
+            st_size: self.size,
+        }
+    }
+}
+
+/// Edits the symbol table of an ELF binary and emits the rewritten file
+pub struct ElfWriter<'a> {
+    bytes: &'a [u8],
+    elf: Elf<'a>,
+    symbols: Vec<SymbolEntry>,
+    transforms: SectionTransforms,
+    needed: Vec<String>,
+    runpath: Option<String>,
+    soname: Option<String>,
+    appended: AppendedData,
+}
+
+impl<'a> ElfWriter<'a> {
+    pub fn new(bytes: &'a [u8]) -> error::Result<Self> {
+        let elf = Elf::parse(bytes)?;
+        let symbols = elf
+            .syms
+            .iter()
+            .skip(1)
+            .map(|sym| SymbolEntry {
+                name: elf.strtab.get_at(sym.st_name).unwrap_or("").to_string(),
+                value: sym.st_value,
+                size: sym.st_size,
+                bind: sym.st_bind(),
+                typ: sym.st_type(),
+                other: sym.st_other,
+                shndx: sym.st_shndx,
+            })
+            .collect();
+        Ok(ElfWriter {
+            bytes,
+            elf,
+            symbols,
+            transforms: SectionTransforms::new(),
+            needed: Vec::new(),
+            runpath: None,
+            soname: None,
+            appended: AppendedData::default(),
+        })
+    }
+
+    /// The binary as it was parsed, before any edits
+    pub fn elf(&self) -> &Elf<'a> {
+        &self.elf
+    }
+
+    /// The symbols which will be written, without the leading null symbol
+    pub fn symbols(&self) -> &[SymbolEntry] {
+        &self.symbols
+    }
+
+    pub fn add_symbol(&mut self, symbol: SymbolEntry) {
+        self.symbols.push(symbol);
+    }
+
+    /// Add a global function symbol, defined in whichever section contains `address`
+    pub fn add_function(&mut self, name: &str, address: u64, size: u64) {
+        let shndx = self.section_for_address(address);
+        self.add_symbol(SymbolEntry {
+            name: name.to_string(),
+            value: address,
+            size,
+            bind: sym::STB_GLOBAL,
+            typ: sym::STT_FUNC,
+            other: sym::STV_DEFAULT,
+            shndx,
+        });
+    }
+
+    /// Rename every symbol called `old`, returning how many were renamed
+    pub fn rename_symbol(&mut self, old: &str, new: &str) -> usize {
+        let mut count = 0;
+        for symbol in self.symbols.iter_mut().filter(|s| s.name == old) {
+            symbol.name = new.to_string();
+            count += 1;
+        }
+        count
+    }
+
+    /// Remove every symbol called `name`, returning how many were removed
+    pub fn strip_symbol(&mut self, name: &str) -> usize {
+        let before = self.symbols.len();
+        self.symbols.retain(|s| s.name != name);
+        before - self.symbols.len()
+    }
+
+    /// Keep only the symbols for which `f` returns true
+    pub fn retain_symbols<F: FnMut(&SymbolEntry) -> bool>(&mut self, f: F) {
+        self.symbols.retain(f);
+    }
+
+    /// Run `transform` on the contents of the section called `section` when the binary is built
+    pub fn add_section_transform<T: SectionTransform + 'static>(
+        &mut self,
+        section: &str,
+        transform: T,
+    ) {
+        self.transforms.register(section, transform);
+    }
+
+    /// Add a `DT_NEEDED` entry for `library` after the existing ones, returning false if the
+    /// binary already depends on it
+    pub fn add_needed(&mut self, library: &str) -> bool {
+        if self.elf.libraries.contains(&library) || self.needed.iter().any(|l| l == library) {
+            return false;
+        }
+        self.needed.push(library.to_string());
+        true
+    }
+
+    /// Set the `DT_RUNPATH` of the binary to `runpath`, a colon separated list of directories.
+    /// Like patchelf, an existing `DT_RPATH` is turned into a `DT_RUNPATH`.
+    pub fn set_runpath(&mut self, runpath: &str) {
+        self.runpath = Some(runpath.to_string());
+    }
+
+    /// Set the `DT_SONAME` of the binary, adding the entry if there is none
+    pub fn set_soname(&mut self, soname: &str) {
+        self.soname = Some(soname.to_string());
+    }
+
+    /// The data appended after the end of the binary, in file order
+    pub fn appended(&self) -> Vec<Artifact> {
+        overlay::artifacts(self.bytes, self.content_end(), None)
+    }
+
+    /// Choose which kinds of appended data are kept in the built binary
+    pub fn set_appended_data(&mut self, appended: AppendedData) {
+        self.appended = appended;
+    }
+
+    /// The end of the last header, segment or section in the file
+    fn content_end(&self) -> usize {
+        let header = &self.elf.header;
+        let phdrs =
+            header.e_phoff as usize + usize::from(header.e_phnum) * usize::from(header.e_phentsize);
+        let shdrs =
+            header.e_shoff as usize + usize::from(header.e_shnum) * usize::from(header.e_shentsize);
+        let segments = self
+            .elf
+            .program_headers
+            .iter()
+            .map(|phdr| phdr.file_range().end);
+        let sections = self
+            .elf
+            .section_headers
+            .iter()
+            .filter_map(|shdr| shdr.file_range().map(|range| range.end));
+        segments
+            .chain(sections)
+            .fold(phdrs.max(shdrs), usize::max)
+            .min(self.bytes.len())
+    }
+
+    /// The index of the allocated section containing `address`, or `SHN_ABS` if there is none
+    pub fn section_for_address(&self, address: u64) -> usize {
+        self.elf
+            .section_headers
+            .iter()
+            .position(|shdr| {
+                shdr.sh_flags & u64::from(section_header::SHF_ALLOC) != 0
+                    && shdr.vm_range().contains(&(address as usize))
+            })
+            .unwrap_or(section_header::SHN_ABS as usize)
+    }
+
+    /// Emit the binary with the edited symbol table
+    pub fn build(&mut self) -> error::Result<Vec<u8>> {
+        let ctx = self.elf.ctx;
+        let artifacts = self.appended();
+        let mut out = self.bytes[..self.content_end()].to_vec();
+        let mut shdrs = self.elf.section_headers.clone();
+        if shdrs.is_empty() {
+            shdrs.push(SectionHeader::default());
+        }
+        let mut shstrndx = usize::from(self.elf.header.e_shstrndx);
+        let mut shstrtab = match shdrs.get(shstrndx) {
+            Some(shdr) if shstrndx != 0 && shdr.sh_type == section_header::SHT_STRTAB => self
+                .bytes
+                .get(shdr.file_range().unwrap_or_default())
+                .ok_or_else(|| error::Error::Malformed("Section name table is truncated".into()))?
+                .to_vec(),
+            _ => {
+                shstrndx = 0;
+                vec![0]
+            }
+        };
+        let shstrtab_len = shstrtab.len();
+        self.rewrite_dynamic(&mut out, &mut shdrs)?;
+
+        // locals must come before every global, sh_info holds the index of the first global
+        let mut ordered = self
+            .symbols
+            .iter()
+            .filter(|s| s.is_local())
+            .collect::<Vec<_>>();
+        let first_global = ordered.len() + 1;
+        ordered.extend(self.symbols.iter().filter(|s| !s.is_local()));
+
+        let mut strtab = vec![0u8];
+        let mut offsets = BTreeMap::new();
+        let mut symtab = vec![0u8; Sym::size_with(&ctx) * (ordered.len() + 1)];
+        let mut offset = Sym::size_with(&ctx);
+        for symbol in ordered {
+            let st_name = if symbol.name.is_empty() {
+                0
+            } else {
+                *offsets.entry(symbol.name.as_str()).or_insert_with(|| {
+                    let at = strtab.len();
+                    strtab.extend_from_slice(symbol.name.as_bytes());
+                    strtab.push(0);
+                    at
+                })
+            };
+            symtab.gwrite_with(symbol.to_sym(st_name), &mut offset, ctx)?;
+        }
+
+        let align = match ctx.container {
+            Container::Little => 4,
+            Container::Big => 8,
+        };
+        let symtab_idx = shdrs
+            .iter()
+            .position(|shdr| shdr.sh_type == section_header::SHT_SYMTAB);
+        let strtab_idx = match symtab_idx.map(|idx| shdrs[idx].sh_link as usize) {
+            Some(link) if link != 0 && link != shstrndx && link < shdrs.len() => link,
+            _ => {
+                let name = section_name(&mut shstrtab, ".strtab");
+                shdrs.push(SectionHeader {
+                    sh_name: name,
+                    sh_type: section_header::SHT_STRTAB,
+                    sh_addralign: 1,
+                    ..Default::default()
+                });
+                shdrs.len() - 1
+            }
+        };
+        let symtab_idx = match symtab_idx {
+            Some(idx) => idx,
+            None => {
+                let name = section_name(&mut shstrtab, ".symtab");
+                shdrs.push(SectionHeader {
+                    sh_name: name,
+                    sh_type: section_header::SHT_SYMTAB,
+                    ..Default::default()
+                });
+                shdrs.len() - 1
+            }
+        };
+        if shstrndx == 0 {
+            let name = section_name(&mut shstrtab, ".shstrtab");
+            shdrs.push(SectionHeader {
+                sh_name: name,
+                sh_type: section_header::SHT_STRTAB,
+                sh_addralign: 1,
+                ..Default::default()
+            });
+            shstrndx = shdrs.len() - 1;
+        }
+
+        let strtab_offset = append(&mut out, &strtab, 1);
+        let shdr = &mut shdrs[strtab_idx];
+        shdr.sh_offset = strtab_offset as u64;
+        shdr.sh_size = strtab.len() as u64;
+
+        let symtab_offset = append(&mut out, &symtab, align);
+        let shdr = &mut shdrs[symtab_idx];
+        shdr.sh_offset = symtab_offset as u64;
+        shdr.sh_size = symtab.len() as u64;
+        shdr.sh_link = strtab_idx as u32;
+        shdr.sh_info = first_global as u32;
+        shdr.sh_entsize = Sym::size_with(&ctx) as u64;
+        shdr.sh_addralign = align as u64;
+
+        if shstrtab.len() != shstrtab_len || self.elf.header.e_shstrndx == 0 {
+            let shstrtab_offset = append(&mut out, &shstrtab, 1);
+            let shdr = &mut shdrs[shstrndx];
+            shdr.sh_offset = shstrtab_offset as u64;
+            shdr.sh_size = shstrtab.len() as u64;
+        }
+
+        for shdr in shdrs.iter_mut() {
+            let range = match shdr.file_range() {
+                Some(range) => range,
+                None => continue,
+            };
+            let section = SectionRef {
+                name: name_at(&shstrtab, shdr.sh_name),
+                address: shdr.sh_addr,
+                offset: range.start,
+                loaded: shdr.sh_flags & u64::from(section_header::SHF_ALLOC) != 0,
+                file: &out,
+            };
+            let data = match self.transforms.apply(&section, range.clone())? {
+                Some(data) => data,
+                None => continue,
+            };
+            if data.len() <= range.len() {
+                out[range.start..range.start + data.len()].copy_from_slice(&data);
+                out[range.start + data.len()..range.end].fill(0);
+            } else {
+                let align = (shdr.sh_addralign as usize).max(1);
+                shdr.sh_offset = append(&mut out, &data, align) as u64;
+            }
+            shdr.sh_size = data.len() as u64;
+        }
+
+        write_section_headers(&mut out, self.elf.header, shdrs, shstrndx, ctx)?;
+        overlay::append_artifacts(&mut out, self.bytes, &artifacts, &self.appended)?;
+        Ok(out)
+    }
+
+    /// Apply the edits of the dynamic section, moving the dynamic string table and the dynamic
+    /// array to a new segment if they outgrow their current place
+    fn rewrite_dynamic(&self, out: &mut Vec<u8>, shdrs: &mut [SectionHeader]) -> error::Result<()> {
+        if self.needed.is_empty() && self.runpath.is_none() && self.soname.is_none() {
+            return Ok(());
+        }
+        let ctx = self.elf.ctx;
+        let dynamic =
+            self.elf.dynamic.as_ref().ok_or_else(|| {
+                error::Error::Malformed("Binary is not dynamically linked".into())
+            })?;
+        let info = &dynamic.info;
+        let mut dynstr = self
+            .bytes
+            .get(info.strtab..info.strtab + info.strsz)
+            .ok_or_else(|| error::Error::Malformed("Dynamic string table is truncated".into()))?
+            .to_vec();
+        let mut dyns = dynamic
+            .dyns
+            .iter()
+            .filter(|d| d.d_tag != dynamic::DT_NULL)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // new entries go after the last DT_NEEDED, so the existing search order is kept
+        let mut at = dyns
+            .iter()
+            .rposition(|d| d.d_tag == dynamic::DT_NEEDED)
+            .map_or(0, |idx| idx + 1);
+        for library in self.needed.iter() {
+            let d_val = dynamic_string(&mut dynstr, library);
+            dyns.insert(
+                at,
+                Dyn {
+                    d_tag: dynamic::DT_NEEDED,
+                    d_val,
+                },
+            );
+            at += 1;
+        }
+        if let Some(ref soname) = self.soname {
+            let d_val = dynamic_string(&mut dynstr, soname);
+            match dyns.iter_mut().find(|d| d.d_tag == dynamic::DT_SONAME) {
+                Some(entry) => entry.d_val = d_val,
+                None => dyns.insert(
+                    at,
+                    Dyn {
+                        d_tag: dynamic::DT_SONAME,
+                        d_val,
+                    },
+                ),
+            }
+        }
+        if let Some(ref runpath) = self.runpath {
+            let d_val = dynamic_string(&mut dynstr, runpath);
+            let existing = dyns
+                .iter()
+                .position(|d| d.d_tag == dynamic::DT_RUNPATH)
+                .or_else(|| dyns.iter().position(|d| d.d_tag == dynamic::DT_RPATH));
+            let entry = Dyn {
+                d_tag: dynamic::DT_RUNPATH,
+                d_val,
+            };
+            match existing {
+                Some(idx) => dyns[idx] = entry,
+                None => dyns.push(entry),
+            }
+        }
+        dyns.push(Dyn::default());
+
+        let phdrs = &self.elf.program_headers;
+        let dynamic_idx = phdrs
+            .iter()
+            .position(|phdr| phdr.p_type == program_header::PT_DYNAMIC)
+            .ok_or_else(|| error::Error::Malformed("Binary has no PT_DYNAMIC segment".into()))?;
+        let dyn_size = Dyn::size_with(&ctx);
+        let move_dynstr = dynstr.len() > info.strsz;
+        let move_dynamic = dyns.len() * dyn_size > phdrs[dynamic_idx].p_filesz as usize;
+
+        let mut phdrs = phdrs.clone();
+        let old_dynstr = info.strtab;
+        let old_dynamic = phdrs[dynamic_idx].clone();
+        let mut dynstr_at = (old_dynstr, 0);
+        let mut dynamic_at = (old_dynamic.p_offset as usize, old_dynamic.p_vaddr);
+        if move_dynstr || move_dynamic {
+            // the new segment goes past the end of the file and of the address space in use
+            let page = phdrs
+                .iter()
+                .filter(|phdr| phdr.p_type == program_header::PT_LOAD)
+                .map(|phdr| phdr.p_align)
+                .max()
+                .unwrap_or(0)
+                .max(0x1000);
+            let vaddr = phdrs
+                .iter()
+                .filter(|phdr| phdr.p_type == program_header::PT_LOAD)
+                .map(|phdr| phdr.p_vaddr + phdr.p_memsz)
+                .max()
+                .unwrap_or(0)
+                .div_ceil(page)
+                * page;
+            let mut segment = Vec::new();
+            if move_dynstr {
+                dynstr_at = (0, vaddr);
+                segment.extend_from_slice(&dynstr);
+                segment.resize(segment.len().div_ceil(8) * 8, 0);
+            }
+            if move_dynamic {
+                dynamic_at = (segment.len(), vaddr + segment.len() as u64);
+                segment.resize(segment.len() + dyns.len() * dyn_size, 0);
+            }
+            let offset = append(out, &segment, page as usize);
+            if move_dynstr {
+                dynstr_at.0 = offset;
+            }
+            if move_dynamic {
+                dynamic_at.0 += offset;
+            }
+
+            let slot = phdrs
+                .iter()
+                .position(|phdr| phdr.p_type == program_header::PT_NOTE)
+                .or_else(|| {
+                    phdrs
+                        .iter()
+                        .position(|phdr| phdr.p_type == program_header::PT_NULL)
+                })
+                .ok_or_else(|| {
+                    error::Error::Malformed("No room for a new program header".into())
+                })?;
+            phdrs.remove(slot);
+            // loadable segments must stay sorted by address
+            let last_load = phdrs
+                .iter()
+                .rposition(|phdr| phdr.p_type == program_header::PT_LOAD)
+                .map_or(0, |idx| idx + 1);
+            phdrs.insert(
+                last_load,
+                ProgramHeader {
+                    p_type: program_header::PT_LOAD,
+                    p_flags: program_header::PF_R | program_header::PF_W,
+                    p_offset: offset as u64,
+                    p_vaddr: vaddr,
+                    p_paddr: vaddr,
+                    p_filesz: segment.len() as u64,
+                    p_memsz: segment.len() as u64,
+                    p_align: page,
+                },
+            );
+        }
+
+        for entry in dyns.iter_mut() {
+            match entry.d_tag {
+                dynamic::DT_STRTAB if move_dynstr => entry.d_val = dynstr_at.1,
+                dynamic::DT_STRSZ => entry.d_val = dynstr.len() as u64,
+                _ => (),
+            }
+        }
+        out[dynstr_at.0..dynstr_at.0 + dynstr.len()].copy_from_slice(&dynstr);
+        let capacity = if move_dynamic {
+            dyns.len() * dyn_size
+        } else {
+            old_dynamic.p_filesz as usize
+        };
+        // unused slots of the old array read as DT_NULL
+        out[dynamic_at.0..dynamic_at.0 + capacity].fill(0);
+        let mut offset = dynamic_at.0;
+        for entry in dyns {
+            out.gwrite_with(entry, &mut offset, ctx)?;
+        }
+
+        if move_dynamic {
+            if let Some(phdr) = phdrs
+                .iter_mut()
+                .find(|phdr| phdr.p_type == program_header::PT_DYNAMIC)
+            {
+                phdr.p_offset = dynamic_at.0 as u64;
+                phdr.p_vaddr = dynamic_at.1;
+                phdr.p_paddr = dynamic_at.1;
+                phdr.p_filesz = capacity as u64;
+                phdr.p_memsz = capacity as u64;
+            }
+        }
+        let mut offset = self.elf.header.e_phoff as usize;
+        for phdr in phdrs {
+            out.gwrite_with(phdr, &mut offset, ctx)?;
+        }
+
+        for shdr in shdrs.iter_mut() {
+            if shdr.sh_type == section_header::SHT_DYNAMIC && move_dynamic {
+                shdr.sh_offset = dynamic_at.0 as u64;
+                shdr.sh_addr = dynamic_at.1;
+                shdr.sh_size = capacity as u64;
+            } else if move_dynstr
+                && shdr.sh_type == section_header::SHT_STRTAB
+                && shdr.sh_offset as usize == old_dynstr
+                && shdr.sh_flags & u64::from(section_header::SHF_ALLOC) != 0
+            {
+                shdr.sh_offset = dynstr_at.0 as u64;
+                shdr.sh_addr = dynstr_at.1;
+                shdr.sh_size = dynstr.len() as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The offset of `string` in the dynamic string table, adding it to the end if it isn't there
+fn dynamic_string(dynstr: &mut Vec<u8>, string: &str) -> u64 {
+    let mut needle = string.as_bytes().to_vec();
+    needle.push(0);
+    // a string may also be the tail of a longer one
+    if let Some(at) = dynstr.windows(needle.len()).position(|w| w == needle) {
+        return at as u64;
+    }
+    let at = dynstr.len();
+    dynstr.extend_from_slice(&needle);
+    at as u64
+}
+
+/// Add `name` to the section name table and return its offset
+fn section_name(shstrtab: &mut Vec<u8>, name: &str) -> usize {
+    let at = shstrtab.len();
+    shstrtab.extend_from_slice(name.as_bytes());
+    shstrtab.push(0);
+    at
+}
+
+/// The NUL terminated string at `offset` of a string table
+fn name_at(table: &[u8], offset: usize) -> &str {
+    let bytes = table.get(offset..).unwrap_or_default();
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).unwrap_or("")
+}
+
+/// Append `data` to `out` at the next `align` boundary and return the offset it was written at
+fn append(out: &mut Vec<u8>, data: &[u8], align: usize) -> usize {
+    let offset = out.len().div_ceil(align) * align;
+    out.resize(offset, 0);
+    out.extend_from_slice(data);
+    offset
+}
+
+/// Append `shdrs` as the new section header table and point the ELF header at it
+fn write_section_headers(
+    out: &mut Vec<u8>,
+    mut header: crate::elf::Header,
+    shdrs: Vec<SectionHeader>,
+    shstrndx: usize,
+    ctx: Ctx,
+) -> error::Result<()> {
+    let align = match ctx.container {
+        Container::Little => 4,
+        Container::Big => 8,
+    };
+    let mut table = vec![0u8; SectionHeader::size(ctx) * shdrs.len()];
+    let mut offset = 0;
+    for shdr in shdrs.iter() {
+        table.gwrite_with(shdr.clone(), &mut offset, ctx)?;
+    }
+    header.e_shoff = append(out, &table, align) as u64;
+    header.e_shnum = shdrs.len() as u16;
+    header.e_shstrndx = shstrndx as u16;
+    header.e_shentsize = SectionHeader::size(ctx) as u16;
+    header.into_ctx(out, ctx);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{header, Header};
+    use scroll::LE;
+
+    const DYNSTR: &[u8] = b"\0libc.so.6\0libfoo.so.1\0$ORIGIN\0";
+
+    /// A shared library with room for two more dynamic entries and no room for more strings
+    fn library() -> Vec<u8> {
+        let ctx = Ctx::new(Container::Big, LE);
+        let shstrtab = b"\0.dynstr\0.dynamic\0.shstrtab\0";
+        let mut out = vec![0u8; 0x1c0];
+        out[0x100..0x100 + DYNSTR.len()].copy_from_slice(DYNSTR);
+        let dyns = [
+            (dynamic::DT_NEEDED, 1),
+            (dynamic::DT_SONAME, 11),
+            (dynamic::DT_STRTAB, 0x100),
+            (dynamic::DT_STRSZ, DYNSTR.len() as u64),
+        ];
+        for (i, (d_tag, d_val)) in dyns.into_iter().enumerate() {
+            out.pwrite_with(Dyn { d_tag, d_val }, 0x120 + i * 16, ctx)
+                .unwrap();
+        }
+        out[0x190..0x190 + shstrtab.len()].copy_from_slice(shstrtab);
+
+        let mut header = Header::new(ctx);
+        header.e_type = header::ET_DYN;
+        header.e_machine = header::EM_X86_64;
+        header.e_phoff = 64;
+        header.e_phnum = 3;
+        header.e_shoff = out.len() as u64;
+        header.e_shnum = 4;
+        header.e_shstrndx = 3;
+        header.into_ctx(&mut out, ctx);
+        let phdrs = [
+            ProgramHeader {
+                p_type: program_header::PT_LOAD,
+                p_flags: program_header::PF_R | program_header::PF_W,
+                p_filesz: 0x1c0,
+                p_memsz: 0x1c0,
+                p_align: 0x1000,
+                ..Default::default()
+            },
+            ProgramHeader {
+                p_type: program_header::PT_DYNAMIC,
+                p_offset: 0x120,
+                p_vaddr: 0x120,
+                p_filesz: 0x60,
+                p_memsz: 0x60,
+                p_align: 8,
+                ..Default::default()
+            },
+            ProgramHeader {
+                p_type: program_header::PT_NOTE,
+                p_offset: 0x180,
+                p_vaddr: 0x180,
+                p_filesz: 0x10,
+                p_memsz: 0x10,
+                p_align: 4,
+                ..Default::default()
+            },
+        ];
+        for (i, phdr) in phdrs.into_iter().enumerate() {
+            out.pwrite_with(phdr, 64 + i * 56, ctx).unwrap();
+        }
+        let alloc = u64::from(section_header::SHF_ALLOC);
+        let shdrs = [
+            SectionHeader::default(),
+            SectionHeader {
+                sh_name: 1,
+                sh_type: section_header::SHT_STRTAB,
+                sh_flags: alloc,
+                sh_addr: 0x100,
+                sh_offset: 0x100,
+                sh_size: DYNSTR.len() as u64,
+                sh_addralign: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                sh_name: 9,
+                sh_type: section_header::SHT_DYNAMIC,
+                sh_flags: alloc | u64::from(section_header::SHF_WRITE),
+                sh_addr: 0x120,
+                sh_offset: 0x120,
+                sh_size: 0x60,
+                sh_link: 1,
+                sh_entsize: 16,
+                sh_addralign: 8,
+                ..Default::default()
+            },
+            SectionHeader {
+                sh_name: 18,
+                sh_type: section_header::SHT_STRTAB,
+                sh_offset: 0x190,
+                sh_size: shstrtab.len() as u64,
+                sh_addralign: 1,
+                ..Default::default()
+            },
+        ];
+        out.resize(0x1c0 + 4 * 64, 0);
+        for (i, shdr) in shdrs.into_iter().enumerate() {
+            out.pwrite_with(shdr, 0x1c0 + i * 64, ctx).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn rename_and_add_symbols() {
+        let crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        let mut writer = ElfWriter::new(&crt1).unwrap();
+        let count = writer.symbols().len();
+        assert_eq!(writer.rename_symbol("_start", "entry_point"), 1);
+        writer.add_function("recovered_fn", 0x10, 4);
+        assert_eq!(writer.strip_symbol("__libc_csu_fini"), 1);
+        let out = writer.build().unwrap();
+
+        let elf = Elf::parse(&out).unwrap();
+        assert_eq!(elf.syms.len(), count + 1);
+        let names = elf
+            .syms
+            .iter()
+            .map(|sym| elf.strtab.get_at(sym.st_name).unwrap())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"entry_point"));
+        assert!(names.contains(&"recovered_fn"));
+        assert!(!names.contains(&"_start"));
+        assert!(!names.contains(&"__libc_csu_fini"));
+        // every local precedes the first global
+        let symtab = elf
+            .section_headers
+            .iter()
+            .find(|shdr| shdr.sh_type == section_header::SHT_SYMTAB)
+            .unwrap();
+        let first_global = symtab.sh_info as usize;
+        assert!(elf
+            .syms
+            .iter()
+            .take(first_global)
+            .all(|s| s.st_bind() == sym::STB_LOCAL));
+        assert!(elf
+            .syms
+            .iter()
+            .skip(first_global)
+            .all(|s| s.st_bind() != sym::STB_LOCAL));
+        // the original contents are untouched
+        assert_eq!(out[64..crt1.len()], crt1[64..]);
+    }
+
+    #[test]
+    fn strip_everything() {
+        let crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        let mut writer = ElfWriter::new(&crt1).unwrap();
+        writer.retain_symbols(|_| false);
+        let out = writer.build().unwrap();
+        let elf = Elf::parse(&out).unwrap();
+        assert_eq!(elf.syms.len(), 1);
+        assert_eq!(
+            elf.section_headers.len(),
+            writer.elf().section_headers.len()
+        );
+    }
+
+    #[test]
+    fn section_transforms() {
+        let crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        let mut writer = ElfWriter::new(&crt1).unwrap();
+        writer.add_section_transform(".comment", |section: &SectionRef, data: &mut Vec<u8>| {
+            assert!(!section.loaded);
+            data.extend_from_slice(b" (rewritten)\0");
+            Ok(())
+        });
+        writer.add_section_transform(".data", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.iter_mut().for_each(|b| *b ^= 0x5a);
+            Ok(())
+        });
+        let out = writer.build().unwrap();
+        let elf = Elf::parse(&out).unwrap();
+        let section = |name: &str| {
+            elf.section_headers
+                .iter()
+                .find(|shdr| elf.shdr_strtab.get_at(shdr.sh_name) == Some(name))
+                .unwrap()
+        };
+        // the grown comment moved past the original contents, the data stayed in place
+        let comment = section(".comment");
+        assert!(comment.sh_offset as usize >= crt1.len());
+        assert!(out[comment.file_range().unwrap()].ends_with(b" (rewritten)\0"));
+        let data = section(".data").file_range().unwrap();
+        assert_eq!(
+            out[data.clone()],
+            crt1[data].iter().map(|b| b ^ 0x5a).collect::<Vec<_>>()[..]
+        );
+
+        let mut writer = ElfWriter::new(&crt1).unwrap();
+        writer.add_section_transform(".text", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.push(0x90);
+            Ok(())
+        });
+        assert!(writer.build().is_err());
+    }
+
+    #[test]
+    fn edit_dynamic_in_place() {
+        let bytes = library();
+        let mut writer = ElfWriter::new(&bytes).unwrap();
+        assert_eq!(writer.elf().soname, Some("libfoo.so.1"));
+        // both strings are already in the table, the first one as the tail of a longer one
+        writer.set_soname("foo.so.1");
+        writer.set_runpath("$ORIGIN");
+        let out = writer.build().unwrap();
+        let elf = Elf::parse(&out).unwrap();
+        assert_eq!(elf.soname, Some("foo.so.1"));
+        assert_eq!(elf.runpaths, ["$ORIGIN"]);
+        assert_eq!(elf.libraries, ["libc.so.6"]);
+        assert_eq!(elf.program_headers.len(), 3);
+        assert_eq!(elf.dynamic.unwrap().dyns.len(), 6);
+        assert_eq!(out[0x100..0x120], bytes[0x100..0x120]);
+    }
+
+    #[test]
+    fn appended_data() {
+        let mut bytes = library();
+        let end = bytes.len();
+        bytes.extend_from_slice(b"appended payload");
+        let mut writer = ElfWriter::new(&bytes).unwrap();
+        assert_eq!(
+            writer.appended(),
+            [Artifact {
+                kind: overlay::ArtifactKind::Overlay,
+                range: end..bytes.len(),
+            }]
+        );
+        // the payload stays last, after the new tables
+        writer.set_runpath("/opt/foo/lib");
+        let out = writer.build().unwrap();
+        assert!(out.ends_with(b"appended payload"));
+        assert!(Elf::parse(&out).unwrap().header.e_shoff as usize > end);
+
+        writer.set_appended_data(AppendedData::strip_all());
+        let out = writer.build().unwrap();
+        assert!(!out.ends_with(b"appended payload"));
+        assert_eq!(Elf::parse(&out).unwrap().runpaths, ["/opt/foo/lib"]);
+    }
+
+    #[test]
+    fn move_dynamic() {
+        let bytes = library();
+        let mut writer = ElfWriter::new(&bytes).unwrap();
+        assert!(writer.add_needed("libm.so.6"));
+        assert!(writer.add_needed("libdl.so.2"));
+        assert!(!writer.add_needed("libc.so.6"));
+        writer.set_runpath("/opt/foo/lib");
+        let out = writer.build().unwrap();
+        let elf = Elf::parse(&out).unwrap();
+        assert_eq!(elf.libraries, ["libc.so.6", "libm.so.6", "libdl.so.2"]);
+        assert_eq!(elf.runpaths, ["/opt/foo/lib"]);
+        assert_eq!(elf.soname, Some("libfoo.so.1"));
+
+        // the note made way for a segment past the original one
+        let phdrs = &elf.program_headers;
+        assert_eq!(phdrs.len(), 3);
+        assert!(phdrs.iter().all(|p| p.p_type != program_header::PT_NOTE));
+        let segment = &phdrs[1];
+        assert_eq!(segment.p_type, program_header::PT_LOAD);
+        assert_eq!(segment.p_vaddr, 0x1000);
+        assert_eq!(segment.p_offset % 0x1000, 0);
+        let dynamic = &phdrs[2];
+        assert_eq!(dynamic.p_type, program_header::PT_DYNAMIC);
+        assert!(segment.vm_range().contains(&(dynamic.p_vaddr as usize)));
+        assert_eq!(dynamic.p_filesz, 8 * 16);
+
+        // the sections follow the tables
+        let dynstr = &elf.section_headers[1];
+        assert_eq!(dynstr.sh_addr, 0x1000);
+        assert_eq!(out[dynstr.file_range().unwrap()][..DYNSTR.len()], *DYNSTR);
+        assert_eq!(elf.section_headers[2].sh_addr, dynamic.p_vaddr);
+        assert_eq!(elf.section_headers[2].sh_offset, dynamic.p_offset);
+
+        let crt1: Vec<u8> = include!("../../assets/crt1.rs");
+        let mut writer = ElfWriter::new(&crt1).unwrap();
+        writer.set_soname("libcrt1.so");
+        assert!(writer.build().is_err());
+    }
+}
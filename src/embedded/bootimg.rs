@@ -0,0 +1,155 @@
+//! Android boot images: a header page, then the kernel, the ramdisk and the second stage, each
+//! starting on a page of its own.
+//!
+//! Versions 0 to 2 give the page size and the load addresses; versions 3 and 4 fix the page at
+//! 4096 bytes and leave the addresses to the bootloader.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use scroll::{Pread, LE};
+
+use super::{Payload, PayloadKind};
+use crate::error::{Error, Result};
+
+pub const MAGIC: &[u8; 8] = b"ANDROID!";
+/// The page size of version 3 and later
+pub const PAGE_SIZE_V3: u32 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootImage {
+    pub header_version: u32,
+    pub page_size: u32,
+    pub kernel_size: u32,
+    pub kernel_addr: Option<u32>,
+    pub ramdisk_size: u32,
+    pub ramdisk_addr: Option<u32>,
+    pub second_size: u32,
+    pub second_addr: Option<u32>,
+    pub os_version: u32,
+    pub name: String,
+    pub cmdline: String,
+}
+
+fn c_string(bytes: &[u8], range: core::ops::Range<usize>) -> String {
+    let bytes = bytes.get(range).unwrap_or_default();
+    let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+impl BootImage {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(Error::Malformed("Not an Android boot image".into()));
+        }
+        let header_version: u32 = bytes.pread_with(40, LE)?;
+        if header_version >= 3 {
+            return Ok(BootImage {
+                header_version,
+                page_size: PAGE_SIZE_V3,
+                kernel_size: bytes.pread_with(8, LE)?,
+                kernel_addr: None,
+                ramdisk_size: bytes.pread_with(12, LE)?,
+                ramdisk_addr: None,
+                second_size: 0,
+                second_addr: None,
+                os_version: bytes.pread_with(16, LE)?,
+                name: String::new(),
+                cmdline: c_string(bytes, 44..44 + 1536),
+            });
+        }
+        let page_size: u32 = bytes.pread_with(36, LE)?;
+        if page_size == 0 || !page_size.is_power_of_two() {
+            return Err(Error::Malformed(format!(
+                "Bad boot image page size: {:#x}",
+                page_size
+            )));
+        }
+        Ok(BootImage {
+            header_version,
+            page_size,
+            kernel_size: bytes.pread_with(8, LE)?,
+            kernel_addr: Some(bytes.pread_with(12, LE)?),
+            ramdisk_size: bytes.pread_with(16, LE)?,
+            ramdisk_addr: Some(bytes.pread_with(20, LE)?),
+            second_size: bytes.pread_with(24, LE)?,
+            second_addr: Some(bytes.pread_with(28, LE)?),
+            os_version: bytes.pread_with(44, LE)?,
+            name: c_string(bytes, 48..64),
+            cmdline: c_string(bytes, 64..64 + 512),
+        })
+    }
+
+    /// The kernel, ramdisk and second stage the image holds, in that order. The ranges are
+    /// where the header puts them; [`Payload::data`] tells whether the bytes are all there.
+    pub fn payloads(&self) -> Vec<Payload> {
+        let page = self.page_size as usize;
+        let mut offset = page;
+        let mut payloads = Vec::new();
+        for (name, kind, size, addr) in [
+            (
+                "kernel",
+                PayloadKind::Kernel,
+                self.kernel_size,
+                self.kernel_addr,
+            ),
+            (
+                "ramdisk",
+                PayloadKind::Ramdisk,
+                self.ramdisk_size,
+                self.ramdisk_addr,
+            ),
+            (
+                "second",
+                PayloadKind::Other,
+                self.second_size,
+                self.second_addr,
+            ),
+        ] {
+            let size = size as usize;
+            if size == 0 {
+                continue;
+            }
+            let mut payload = Payload::new(name, kind, offset..offset + size);
+            payload.load = addr.map(u64::from);
+            if kind == PayloadKind::Kernel {
+                payload.entry = payload.load;
+            }
+            payloads.push(payload);
+            offset += size.next_multiple_of(page);
+        }
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_image_v0() {
+        let mut bytes = vec![0u8; 0x800];
+        bytes[..8].copy_from_slice(MAGIC);
+        for (offset, value) in [
+            (8, 0x900u32),
+            (12, 0x1000_8000),
+            (16, 0x10),
+            (20, 0x1100_0000),
+            (36, 0x800),
+        ] {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes[64..64 + 12].copy_from_slice(b"console=ttyS");
+        bytes.resize(0x800 * 3 + 0x10, 0);
+
+        let image = BootImage::parse(&bytes).unwrap();
+        assert_eq!(image.cmdline, "console=ttyS");
+        let payloads = image.payloads();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].range, 0x800..0x1100);
+        assert_eq!(payloads[0].entry, Some(0x1000_8000));
+        // the kernel takes two pages
+        assert_eq!(payloads[1].range, 0x1800..0x1810);
+        assert!(payloads[1].data(&bytes).is_some());
+        assert_eq!(super::super::payloads(&bytes).unwrap(), payloads);
+    }
+}
@@ -0,0 +1,376 @@
+//! Flattened device trees, and the FIT images U-Boot builds out of them.
+//!
+//! A device tree blob is a big endian header, the reserved memory map, a structure block of
+//! tokens (begin node, property, end node) and a strings block with the property names. A FIT
+//! image is a device tree whose `/images` node has a subnode per payload, its bytes in a `data`
+//! property or, for images built with `mkimage -E`, after the tree at `data-offset`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use scroll::{Pread, BE};
+
+use super::{check_range, Payload, PayloadKind};
+use crate::error::{Error, Result};
+
+pub const MAGIC: u32 = 0xd00d_feed;
+pub const SIZEOF_HEADER: usize = 40;
+
+pub const FDT_BEGIN_NODE: u32 = 1;
+pub const FDT_END_NODE: u32 = 2;
+pub const FDT_PROP: u32 = 3;
+pub const FDT_NOP: u32 = 4;
+pub const FDT_END: u32 = 9;
+
+/// How deep nodes nest before the tree is taken as malformed
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+    /// Where the value is in the blob
+    pub offset: usize,
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        self.value
+            .pread_with(0, BE)
+            .ok()
+            .filter(|_| self.value.len() == 4)
+    }
+
+    /// A one or two cell number, as addresses are
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.value.pread_with::<u32>(0, BE).ok().map(u64::from),
+            8 => self.value.pread_with(0, BE).ok(),
+            _ => None,
+        }
+    }
+
+    /// A string value, without its NUL
+    pub fn as_str(&self) -> Option<&'a str> {
+        let value = self.value.strip_suffix(&[0])?;
+        core::str::from_utf8(value).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node<'a> {
+    /// The unit name, `memory@80000000`; empty for the root
+    pub name: &'a str,
+    pub properties: Vec<Property<'a>>,
+    pub children: Vec<Node<'a>>,
+}
+
+impl<'a> Node<'a> {
+    pub fn property(&self, name: &str) -> Option<&Property<'a>> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node<'a>> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fdt<'a> {
+    pub total_size: u32,
+    pub version: u32,
+    pub boot_cpuid: u32,
+    /// The reserved memory map, as address and size
+    pub reserved: Vec<(u64, u64)>,
+    pub root: Node<'a>,
+}
+
+fn padded(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+impl<'a> Fdt<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let magic: u32 = bytes.pread_with(0, BE)?;
+        if magic != MAGIC {
+            return Err(Error::BadMagic(magic as u64));
+        }
+        let total_size: u32 = bytes.pread_with(4, BE)?;
+        let off_struct = bytes.pread_with::<u32>(8, BE)? as usize;
+        let off_strings = bytes.pread_with::<u32>(12, BE)? as usize;
+        let off_rsvmap = bytes.pread_with::<u32>(16, BE)? as usize;
+        let version: u32 = bytes.pread_with(20, BE)?;
+        let boot_cpuid: u32 = bytes.pread_with(28, BE)?;
+        let size_strings = bytes.pread_with::<u32>(32, BE)? as usize;
+        let strings = check_range(off_strings, size_strings, bytes.len(), "FDT strings")?;
+        let strings = &bytes[strings];
+
+        let mut reserved = Vec::new();
+        let mut offset = off_rsvmap;
+        loop {
+            let address: u64 = bytes.gread_with(&mut offset, BE)?;
+            let size: u64 = bytes.gread_with(&mut offset, BE)?;
+            if address == 0 && size == 0 {
+                break;
+            }
+            reserved.push((address, size));
+        }
+
+        // the nodes being built, innermost last
+        let mut stack: Vec<Node<'a>> = Vec::new();
+        let mut root = None;
+        let mut offset = off_struct;
+        loop {
+            let token: u32 = bytes.gread_with(&mut offset, BE)?;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = bytes.pread::<&str>(offset)?;
+                    offset = padded(offset + name.len() + 1);
+                    if stack.len() == MAX_DEPTH {
+                        return Err(Error::Malformed("FDT nodes nest too deep".into()));
+                    }
+                    stack.push(Node {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    let node = stack
+                        .pop()
+                        .ok_or_else(|| Error::Malformed("Unbalanced FDT end of node".into()))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = bytes.gread_with::<u32>(&mut offset, BE)? as usize;
+                    let name_offset = bytes.gread_with::<u32>(&mut offset, BE)? as usize;
+                    let name = strings.pread::<&str>(name_offset)?;
+                    let value = check_range(offset, len, bytes.len(), "FDT property")?;
+                    let node = stack
+                        .last_mut()
+                        .ok_or_else(|| Error::Malformed("FDT property outside of a node".into()))?;
+                    node.properties.push(Property {
+                        name,
+                        value: &bytes[value],
+                        offset,
+                    });
+                    offset = padded(offset + len);
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                token => {
+                    return Err(Error::Malformed(format!(
+                        "Bad FDT token {:#x} at {:#x}",
+                        token,
+                        offset - 4
+                    )))
+                }
+            }
+        }
+        let root = root.ok_or_else(|| Error::Malformed("FDT without a root node".into()))?;
+        Ok(Fdt {
+            total_size,
+            version,
+            boot_cpuid,
+            reserved,
+            root,
+        })
+    }
+
+    /// The node at `path`, `/chosen` or `/images/kernel`
+    pub fn find(&self, path: &str) -> Option<&Node<'a>> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(&self.root, |node, part| node.child(part))
+    }
+
+    /// The initrd the bootloader put in `/chosen`, as start and end addresses
+    pub fn initrd(&self) -> Option<(u64, u64)> {
+        let chosen = self.find("/chosen")?;
+        let start = chosen.property("linux,initrd-start")?.as_u64()?;
+        let end = chosen.property("linux,initrd-end")?.as_u64()?;
+        Some((start, end))
+    }
+
+    /// The images of a FIT, empty for a plain device tree. `bytes` is the whole FIT: data kept
+    /// outside of the tree follows it.
+    pub fn fit_payloads(&self, bytes: &[u8]) -> Result<Vec<Payload>> {
+        let Some(images) = self.find("/images") else {
+            return Ok(Vec::new());
+        };
+        // external data starts after the tree, rounded up to 4 bytes
+        let external = padded(self.total_size as usize);
+        let mut payloads = Vec::new();
+        for image in images.children.iter() {
+            let range = match (
+                image.property("data"),
+                image.property("data-offset").and_then(Property::as_u32),
+                image.property("data-position").and_then(Property::as_u32),
+                image.property("data-size").and_then(Property::as_u32),
+            ) {
+                (Some(data), ..) => data.offset..data.offset + data.value.len(),
+                (None, Some(offset), _, Some(size)) => check_range(
+                    external + offset as usize,
+                    size as usize,
+                    bytes.len(),
+                    "FIT image data",
+                )?,
+                (None, None, Some(position), Some(size)) => check_range(
+                    position as usize,
+                    size as usize,
+                    bytes.len(),
+                    "FIT image data",
+                )?,
+                _ => continue,
+            };
+            let kind = match image.property("type").and_then(Property::as_str) {
+                Some("kernel") | Some("kernel_noload") => PayloadKind::Kernel,
+                Some("ramdisk") => PayloadKind::Ramdisk,
+                Some("flat_dt") => PayloadKind::DeviceTree,
+                _ => PayloadKind::Other,
+            };
+            let mut payload = Payload::new(image.name, kind, range);
+            payload.load = image.property("load").and_then(Property::as_u64);
+            payload.entry = image.property("entry").and_then(Property::as_u64);
+            let string = |name| {
+                image
+                    .property(name)
+                    .and_then(Property::as_str)
+                    .map(ToString::to_string)
+            };
+            payload.compression = string("compression");
+            payload.arch = string("arch");
+            payloads.push(payload);
+        }
+        Ok(payloads)
+    }
+}
+
+/// The name of a node, without its unit address
+pub fn node_name(name: &str) -> String {
+    name.split('@').next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nodes in the order they start, with their properties, `)` ending the last one open
+    type Nodes<'a> = [(&'a str, &'a [(&'a str, &'a [u8])])];
+
+    /// Build a blob, property names interned as they come
+    fn blob(nodes: &Nodes) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings: Vec<u8> = Vec::new();
+        let word = |out: &mut Vec<u8>, v: u32| out.extend(v.to_be_bytes());
+        for (name, props) in nodes {
+            match *name {
+                ")" => word(&mut structure, FDT_END_NODE),
+                name => {
+                    word(&mut structure, FDT_BEGIN_NODE);
+                    structure.extend(name.as_bytes());
+                    structure.push(0);
+                    structure.resize(padded(structure.len()), 0);
+                    for (prop, value) in props.iter() {
+                        word(&mut structure, FDT_PROP);
+                        word(&mut structure, value.len() as u32);
+                        word(&mut structure, strings.len() as u32);
+                        strings.extend(prop.as_bytes());
+                        strings.push(0);
+                        structure.extend(*value);
+                        structure.resize(padded(structure.len()), 0);
+                    }
+                }
+            }
+        }
+        word(&mut structure, FDT_END);
+        let off_rsvmap = SIZEOF_HEADER;
+        let off_struct = off_rsvmap + 16;
+        let off_strings = off_struct + structure.len();
+        let total = off_strings + strings.len();
+        let mut out = Vec::new();
+        for value in [
+            MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            word(&mut out, value);
+        }
+        out.extend([0; 16]);
+        out.extend(structure);
+        out.extend(strings);
+        out
+    }
+
+    #[test]
+    fn fit_image() {
+        let kernel = [0x1fu8, 0x20, 0x03, 0xd5, 0xc0];
+        let bytes = blob(&[
+            ("", &[("description", b"test\0")]),
+            (
+                "chosen",
+                &[
+                    ("linux,initrd-start", &0x4800_0000u32.to_be_bytes()),
+                    ("linux,initrd-end", &0x4810_0000u32.to_be_bytes()),
+                ],
+            ),
+            (")", &[]),
+            ("images", &[]),
+            (
+                "kernel-1",
+                &[
+                    ("data", &kernel),
+                    ("type", b"kernel\0"),
+                    ("arch", b"arm64\0"),
+                    ("compression", b"none\0"),
+                    ("load", &0x4008_0000u32.to_be_bytes()),
+                    ("entry", &0x4008_0000u32.to_be_bytes()),
+                ],
+            ),
+            (")", &[]),
+            (
+                "fdt-1",
+                &[
+                    ("type", b"flat_dt\0"),
+                    ("data-offset", &[0, 0, 0, 0]),
+                    ("data-size", &[0, 0, 0, 4]),
+                ],
+            ),
+            (")", &[]),
+            (")", &[]),
+            (")", &[]),
+        ]);
+        let mut with_external = bytes.clone();
+        with_external.resize(padded(bytes.len()), 0);
+        with_external.extend([0xd0, 0x0d, 0xfe, 0xed]);
+
+        let fdt = Fdt::parse(&with_external).unwrap();
+        assert_eq!(
+            fdt.root.property("description").unwrap().as_str(),
+            Some("test")
+        );
+        assert_eq!(fdt.initrd(), Some((0x4800_0000, 0x4810_0000)));
+        assert_eq!(node_name("memory@80000000"), "memory");
+
+        let payloads = super::super::payloads(&with_external).unwrap();
+        assert_eq!(payloads.len(), 2);
+        let k = &payloads[0];
+        assert_eq!((k.name.as_str(), k.kind), ("kernel-1", PayloadKind::Kernel));
+        assert_eq!(k.data(&with_external), Some(&kernel[..]));
+        assert_eq!(k.entry, Some(0x4008_0000));
+        assert_eq!(k.arch.as_deref(), Some("arm64"));
+        assert_eq!(payloads[1].kind, PayloadKind::DeviceTree);
+        assert_eq!(
+            payloads[1].data(&with_external),
+            Some(&MAGIC.to_be_bytes()[..])
+        );
+    }
+}
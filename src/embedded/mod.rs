@@ -0,0 +1,118 @@
+//! Parsers for the wrappers embedded code ships in: flattened device trees and the FIT images
+//! built on them, legacy U-Boot uImages, and Android boot images.
+//!
+//! None of them is code itself. What they give is the [`Payload`]s inside, the kernel, the
+//! ramdisk, the device tree, as ranges of the wrapper's bytes with the load address and entry
+//! point the bootloader would use, ready to map into a workspace with [`load_payload`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::error::{Error, Result};
+
+pub mod bootimg;
+pub mod dtb;
+pub mod uimage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    Kernel,
+    Ramdisk,
+    DeviceTree,
+    /// The second stage bootloader of an Android boot image, firmware in a FIT, and the like
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something a wrapper holds, by where it is in the wrapper's bytes
+pub struct Payload {
+    pub name: String,
+    pub kind: PayloadKind,
+    pub range: Range<usize>,
+    /// Where the bootloader puts it, if the wrapper says
+    pub load: Option<u64>,
+    /// Where the bootloader jumps to, for a kernel
+    pub entry: Option<u64>,
+    /// The compression the payload is stored with, if any (`gzip`, `lzma`)
+    pub compression: Option<String>,
+    /// The architecture the wrapper names (`arm64`, `x86`)
+    pub arch: Option<String>,
+}
+
+impl Payload {
+    fn new(name: &str, kind: PayloadKind, range: Range<usize>) -> Self {
+        Payload {
+            name: name.into(),
+            kind,
+            range,
+            load: None,
+            entry: None,
+            compression: None,
+            arch: None,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compression.as_deref().is_some_and(|c| c != "none")
+    }
+
+    /// The bytes of the payload, if `bytes` holds all of them
+    pub fn data<'a>(&self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        bytes.get(self.range.clone())
+    }
+}
+
+/// A payload ends within the wrapper, or the wrapper is malformed
+fn check_range(start: usize, size: usize, len: usize, what: &str) -> Result<Range<usize>> {
+    match start.checked_add(size) {
+        Some(end) if end <= len => Ok(start..end),
+        _ => Err(Error::Malformed(format!(
+            "{} at {:#x} of {:#x} bytes runs past the end ({:#x})",
+            what, start, size, len
+        ))),
+    }
+}
+
+/// The payloads of whichever wrapper `bytes` is, told apart by magic
+pub fn payloads(bytes: &[u8]) -> Result<Vec<Payload>> {
+    if bytes.starts_with(&dtb::MAGIC.to_be_bytes()) {
+        dtb::Fdt::parse(bytes)?.fit_payloads(bytes)
+    } else if bytes.starts_with(&uimage::MAGIC.to_be_bytes()) {
+        uimage::UImage::parse(bytes)?.payloads(bytes)
+    } else if bytes.starts_with(bootimg::MAGIC) {
+        Ok(bootimg::BootImage::parse(bytes)?.payloads())
+    } else {
+        let magic = bytes
+            .get(..4)
+            .map_or(0, |m| u32::from_be_bytes([m[0], m[1], m[2], m[3]]));
+        Err(Error::BadMagic(magic as u64))
+    }
+}
+
+#[cfg(feature = "analysis")]
+/// Map an uncompressed payload at its load address as a file of its own, adding its entry point
+/// if it has one. Returns the name of the file, or None if the payload is compressed or doesn't
+/// say where it loads.
+pub fn load_payload(
+    workspace: &mut crate::workspace::VivWorkspace,
+    filename: &str,
+    bytes: &[u8],
+    payload: &Payload,
+) -> Option<String> {
+    use crate::{constants::MM_RWX, memory::Memory};
+
+    if payload.is_compressed() {
+        return None;
+    }
+    let load = payload.load? as i32;
+    let data = payload.data(bytes)?.to_vec();
+    let size = data.len() as i32;
+    let fname = workspace.add_file(filename, load, data.clone());
+    workspace.add_memory_map(load, MM_RWX, &fname, data, None);
+    workspace.add_segment(load, size, &payload.name, fname.clone());
+    if let Some(entry) = payload.entry {
+        workspace.add_entry_point(entry as i32);
+    }
+    Some(fname)
+}
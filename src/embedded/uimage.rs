@@ -0,0 +1,189 @@
+//! Legacy U-Boot images: a 64 byte big endian header, then the data, or for a multi-file image
+//! the sizes of its parts and then the parts.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use scroll::{Pread, BE};
+
+use super::{check_range, Payload, PayloadKind};
+use crate::error::{Error, Result};
+
+pub const MAGIC: u32 = 0x2705_1956;
+pub const SIZEOF_HEADER: usize = 64;
+
+pub const IH_TYPE_STANDALONE: u8 = 1;
+pub const IH_TYPE_KERNEL: u8 = 2;
+pub const IH_TYPE_RAMDISK: u8 = 3;
+pub const IH_TYPE_MULTI: u8 = 4;
+pub const IH_TYPE_FIRMWARE: u8 = 5;
+pub const IH_TYPE_FLATDT: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UImage {
+    pub header_crc: u32,
+    pub time: u32,
+    pub size: u32,
+    pub load: u32,
+    pub entry: u32,
+    pub data_crc: u32,
+    pub os: u8,
+    pub arch: u8,
+    pub image_type: u8,
+    pub compression: u8,
+    pub name: String,
+}
+
+/// The names `mkimage` uses for the `ih_comp` values
+pub fn compression_name(comp: u8) -> &'static str {
+    match comp {
+        0 => "none",
+        1 => "gzip",
+        2 => "bzip2",
+        3 => "lzma",
+        4 => "lzo",
+        5 => "lz4",
+        6 => "zstd",
+        _ => "unknown",
+    }
+}
+
+/// The names `mkimage` uses for the `ih_arch` values
+pub fn arch_name(arch: u8) -> &'static str {
+    match arch {
+        2 => "arm",
+        3 => "x86",
+        5 => "mips",
+        7 => "powerpc",
+        15 => "sparc",
+        18 => "x86_64",
+        22 => "arm64",
+        26 => "riscv",
+        _ => "unknown",
+    }
+}
+
+impl UImage {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let magic: u32 = bytes.pread_with(0, BE)?;
+        if magic != MAGIC {
+            return Err(Error::BadMagic(magic as u64));
+        }
+        let name = bytes.get(32..SIZEOF_HEADER).ok_or(Error::BufferTooShort(
+            SIZEOF_HEADER,
+            "bytes of uImage header",
+        ))?;
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+        Ok(UImage {
+            header_crc: bytes.pread_with(4, BE)?,
+            time: bytes.pread_with(8, BE)?,
+            size: bytes.pread_with(12, BE)?,
+            load: bytes.pread_with(16, BE)?,
+            entry: bytes.pread_with(20, BE)?,
+            data_crc: bytes.pread_with(24, BE)?,
+            os: bytes.pread(28)?,
+            arch: bytes.pread(29)?,
+            image_type: bytes.pread(30)?,
+            compression: bytes.pread(31)?,
+            name: String::from_utf8_lossy(name).to_string(),
+        })
+    }
+
+    /// The parts of the image. A multi-file image is split into its parts, the first of which
+    /// is the kernel and the second, if any, the ramdisk, as `bootm` takes them.
+    fn parts(&self, bytes: &[u8]) -> Result<Vec<(usize, usize)>> {
+        let len = bytes.len();
+        check_range(SIZEOF_HEADER, self.size as usize, len, "uImage data")?;
+        if self.image_type != IH_TYPE_MULTI {
+            return Ok(vec![(SIZEOF_HEADER, self.size as usize)]);
+        }
+        let mut sizes = Vec::new();
+        let mut offset = SIZEOF_HEADER;
+        loop {
+            let size: u32 = bytes.gread_with(&mut offset, BE)?;
+            if size == 0 {
+                break;
+            }
+            sizes.push(size as usize);
+        }
+        let mut parts = Vec::new();
+        for size in sizes {
+            check_range(offset, size, len, "uImage part")?;
+            parts.push((offset, size));
+            offset += size.next_multiple_of(4);
+        }
+        Ok(parts)
+    }
+
+    /// The payloads of the image in `bytes`, header and all
+    pub fn payloads(&self, bytes: &[u8]) -> Result<Vec<Payload>> {
+        let kind = match self.image_type {
+            IH_TYPE_KERNEL | IH_TYPE_MULTI => PayloadKind::Kernel,
+            IH_TYPE_RAMDISK => PayloadKind::Ramdisk,
+            IH_TYPE_FLATDT => PayloadKind::DeviceTree,
+            _ => PayloadKind::Other,
+        };
+        let parts = self.parts(bytes)?;
+        let multi = parts.len() > 1 || self.image_type == IH_TYPE_MULTI;
+        let mut payloads = Vec::new();
+        for (i, (offset, size)) in parts.into_iter().enumerate() {
+            let (name, kind) = match (multi, i) {
+                (false, _) => (self.name.clone(), kind),
+                (true, 0) => (format!("{}.kernel", self.name), PayloadKind::Kernel),
+                (true, 1) => (format!("{}.ramdisk", self.name), PayloadKind::Ramdisk),
+                (true, i) => (format!("{}.{}", self.name, i), PayloadKind::Other),
+            };
+            let mut payload = Payload::new(&name, kind, offset..offset + size);
+            if i == 0 {
+                payload.load = Some(self.load as u64);
+                if kind == PayloadKind::Kernel {
+                    payload.entry = Some(self.entry as u64);
+                }
+            }
+            payload.compression = Some(compression_name(self.compression).into());
+            payload.arch = Some(arch_name(self.arch).into());
+            payloads.push(payload);
+        }
+        Ok(payloads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_file_uimage() {
+        let mut bytes = vec![0u8; SIZEOF_HEADER];
+        bytes[..4].copy_from_slice(&MAGIC.to_be_bytes());
+        bytes[16..20].copy_from_slice(&0x8000u32.to_be_bytes());
+        bytes[20..24].copy_from_slice(&0x8040u32.to_be_bytes());
+        bytes[29] = 22;
+        bytes[30] = IH_TYPE_MULTI;
+        bytes[32..39].copy_from_slice(b"openwrt");
+        // a kernel of 6 bytes, padded to 8, and a ramdisk of 4
+        let parts = [6u32, 4, 0];
+        for size in parts {
+            bytes.extend(size.to_be_bytes());
+        }
+        bytes.extend([0xaa; 8]);
+        bytes.extend([0xbb; 4]);
+        let size = (bytes.len() - SIZEOF_HEADER) as u32;
+        bytes[12..16].copy_from_slice(&size.to_be_bytes());
+
+        let image = UImage::parse(&bytes).unwrap();
+        assert_eq!(image.name, "openwrt");
+        let payloads = image.payloads(&bytes).unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].name, "openwrt.kernel");
+        assert_eq!(payloads[0].range, 76..82);
+        assert_eq!(
+            (payloads[0].load, payloads[0].entry),
+            (Some(0x8000), Some(0x8040))
+        );
+        assert_eq!(payloads[0].arch.as_deref(), Some("arm64"));
+        assert!(!payloads[0].is_compressed());
+        assert_eq!(payloads[1].kind, PayloadKind::Ramdisk);
+        assert_eq!(payloads[1].data(&bytes), Some(&[0xbb; 4][..]));
+        assert_eq!(super::super::payloads(&bytes).unwrap(), payloads);
+    }
+}
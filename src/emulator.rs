@@ -3,6 +3,9 @@
 use crate::{constants::{IF_CALL, IF_RET}, memory::Memory, monitor::EmulationMonitor, workspace::VivWorkspace};
 use std::{borrow::BorrowMut, rc::Rc, collections::HashMap};
 use crate::envi::CallingConvention;
+use crate::envi::{registers::RegisterContext, Arch};
+use crate::ilemu::{Fault, Interpreter, Ram, Stop};
+use crate::symbolic::Function;
 
 pub const INIT_STACK_SIZE: usize = 0x8000;
 pub const INIT_STACK_MAP: [u8; INIT_STACK_SIZE] = [0xfe; INIT_STACK_SIZE];
@@ -366,10 +369,13 @@ pub struct GenericEmulator {
     safe_mem: bool,
     func_only: bool,
     strict_ops: bool,
+    /// The register file, if the architecture of the workspace is known
+    registers: Option<RegisterContext>,
 }
 
 impl GenericEmulator {
     pub fn new(workspace: VivWorkspace) -> Self {
+        let registers = Arch::from_envi(workspace.arch).map(RegisterContext::new);
         GenericEmulator {
             stack_map_base: None,
             stack_map_mask: None,
@@ -397,8 +403,51 @@ impl GenericEmulator {
             safe_mem: false,
             func_only: false,
             strict_ops: false,
+            registers,
         }
     }
+
+    /// Emulate `arch`, starting over with a zeroed register file
+    pub fn set_arch(&mut self, arch: Arch) {
+        self.registers = Some(RegisterContext::new(arch));
+    }
+
+    pub fn registers(&self) -> Option<&RegisterContext> {
+        self.registers.as_ref()
+    }
+
+    pub fn registers_mut(&mut self) -> Option<&mut RegisterContext> {
+        self.registers.as_mut()
+    }
+
+    /// Run a lifted function on the register file through the IL interpreter, for at most
+    /// `max_blocks` blocks. None if the architecture isn't known.
+    pub fn run_il(
+        &mut self,
+        func: &Function,
+        memory: &mut Ram,
+        max_blocks: usize,
+    ) -> Option<Result<Stop, Fault>> {
+        let registers = self.registers.as_mut()?;
+        Some(Interpreter::new(registers, memory).run(func, max_blocks))
+    }
+
+    /// Give the code written during emulation back to the workspace as dynamically generated
+    /// code (see [`VivWorkspace::add_dynamic_code`]), returning the regions taken in as (va,
+    /// size). `memory` must have logged its code writes, see [`Ram::set_log_code_writes`].
+    pub fn add_dynamic_code(&mut self, memory: &mut Ram) -> Vec<(u64, u64)> {
+        let mut added = Vec::new();
+        for (va, size) in memory.code_written() {
+            let Some(bytes) = memory.read_bytes(va, size as usize) else {
+                continue;
+            };
+            if self.workspace.add_dynamic_code(va as i32, &bytes) {
+                added.push((va, size));
+            }
+        }
+        added
+    }
+
     pub fn read_memory_format(&self, va: i32, taint_bytes: &str) -> Vec<i32> {
         Vec::new()
     }
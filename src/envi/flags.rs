@@ -0,0 +1,606 @@
+//! Condition flag semantics: how arithmetic sets the flags, and which flags a conditional branch
+//! tests.
+//!
+//! Flag values are three valued (`Some(true)`, `Some(false)` or unknown) so a branch folds as
+//! soon as the flags it tests are known, even if others aren't: after `xor eax, eax` a `jz` is
+//! always taken whatever `CF` holds. Instructions which leave a flag undefined make it unknown.
+//!
+//! The carry flag follows the architecture: after a subtraction x86 and H8 set it on a borrow,
+//! ARM, aarch64 and MSP430 set it when there is no borrow.
+
+use crate::envi::registers::RegisterContext;
+use crate::envi::Arch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Carry,
+    Parity,
+    /// The carry out of the low nibble (`AF` on x86, `H` on H8)
+    Adjust,
+    Zero,
+    Sign,
+    Overflow,
+}
+
+const FLAGS: [Flag; 6] = [
+    Flag::Carry,
+    Flag::Parity,
+    Flag::Adjust,
+    Flag::Zero,
+    Flag::Sign,
+    Flag::Overflow,
+];
+
+impl Flag {
+    /// The name of the flag bit in the register model of `arch`, if it has one
+    pub fn register_name(&self, arch: Arch) -> Option<&'static str> {
+        match arch {
+            Arch::I386 | Arch::Amd64 => Some(match self {
+                Flag::Carry => "CF",
+                Flag::Parity => "PF",
+                Flag::Adjust => "AF",
+                Flag::Zero => "ZF",
+                Flag::Sign => "SF",
+                Flag::Overflow => "OF",
+            }),
+            Arch::H8 if *self == Flag::Adjust => Some("H"),
+            _ => match self {
+                Flag::Carry => Some("C"),
+                Flag::Zero => Some("Z"),
+                Flag::Sign => Some("N"),
+                Flag::Overflow => Some("V"),
+                Flag::Parity | Flag::Adjust => None,
+            },
+        }
+    }
+}
+
+/// Whether the carry flag of `arch` is set by a borrow, rather than cleared by one
+fn carry_is_borrow(arch: Arch) -> bool {
+    matches!(arch, Arch::I386 | Arch::Amd64 | Arch::H8)
+}
+
+/// The state of the condition flags, each of which may be unknown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    values: [Option<bool>; 6],
+}
+
+impl Flags {
+    /// Flags about which nothing is known
+    pub fn unknown() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, flag: Flag) -> Option<bool> {
+        self.values[flag as usize]
+    }
+
+    pub fn set(&mut self, flag: Flag, value: Option<bool>) {
+        self.values[flag as usize] = value;
+    }
+
+    /// Apply the flag effects of an instruction
+    pub fn apply(&mut self, update: &FlagUpdate) {
+        for flag in FLAGS {
+            match update.effects[flag as usize] {
+                Effect::Unchanged => {}
+                Effect::Undefined => self.set(flag, None),
+                Effect::Set(value) => self.set(flag, Some(value)),
+            }
+        }
+    }
+}
+
+/// What an instruction does to one flag
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Effect {
+    #[default]
+    Unchanged,
+    Undefined,
+    Set(bool),
+}
+
+/// The result of an operation and its effects on the flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlagUpdate {
+    pub result: u64,
+    effects: [Effect; 6],
+}
+
+impl FlagUpdate {
+    fn new(result: u64) -> Self {
+        FlagUpdate {
+            result,
+            effects: [Effect::Unchanged; 6],
+        }
+    }
+
+    pub fn effect(&self, flag: Flag) -> Effect {
+        self.effects[flag as usize]
+    }
+
+    fn with(mut self, flag: Flag, effect: Effect) -> Self {
+        self.effects[flag as usize] = effect;
+        self
+    }
+
+    fn set(self, flag: Flag, value: bool) -> Self {
+        self.with(flag, Effect::Set(value))
+    }
+
+    /// Set the zero and sign flags, and the parity on x86, from the result
+    fn result_flags(self, arch: Arch, width: u32) -> Self {
+        let update = self
+            .set(Flag::Zero, self.result == 0)
+            .set(Flag::Sign, self.result >> (width - 1) & 1 == 1);
+        if matches!(arch, Arch::I386 | Arch::Amd64) {
+            update.set(Flag::Parity, (self.result as u8).count_ones() & 1 == 0)
+        } else {
+            update
+        }
+    }
+
+    /// Set the adjust flag where the architecture has one
+    fn adjust(self, arch: Arch, a: u64, b: u64) -> Self {
+        match arch {
+            Arch::I386 | Arch::Amd64 | Arch::H8 => {
+                self.set(Flag::Adjust, (a ^ b ^ self.result) & 0x10 != 0)
+            }
+            _ => self,
+        }
+    }
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+fn sign(value: u64, width: u32) -> bool {
+    value >> (width - 1) & 1 == 1
+}
+
+/// `a + b + carry` on `width` bits
+pub fn adc(arch: Arch, a: u64, b: u64, carry: bool, width: u32) -> FlagUpdate {
+    let (a, b) = (a & mask(width), b & mask(width));
+    let wide = a as u128 + b as u128 + u128::from(carry);
+    let result = wide as u64 & mask(width);
+    let overflow = sign(a, width) == sign(b, width) && sign(result, width) != sign(a, width);
+    FlagUpdate::new(result)
+        .result_flags(arch, width)
+        .adjust(arch, a, b)
+        .set(Flag::Carry, wide >> width != 0)
+        .set(Flag::Overflow, overflow)
+}
+
+pub fn add(arch: Arch, a: u64, b: u64, width: u32) -> FlagUpdate {
+    adc(arch, a, b, false, width)
+}
+
+/// `a - b - borrow` on `width` bits. `carry` is the carry flag going in, which is the borrow
+/// on x86 and its inverse on ARM.
+pub fn sbb(arch: Arch, a: u64, b: u64, carry: bool, width: u32) -> FlagUpdate {
+    let borrow_in = carry == carry_is_borrow(arch);
+    let (a, b) = (a & mask(width), b & mask(width));
+    let result = a.wrapping_sub(b).wrapping_sub(u64::from(borrow_in)) & mask(width);
+    let borrow = (a as u128) < b as u128 + u128::from(borrow_in);
+    let overflow = sign(a, width) != sign(b, width) && sign(result, width) != sign(a, width);
+    FlagUpdate::new(result)
+        .result_flags(arch, width)
+        .adjust(arch, a, b)
+        .set(Flag::Carry, borrow == carry_is_borrow(arch))
+        .set(Flag::Overflow, overflow)
+}
+
+/// `a - b`, which is also what a compare sets the flags from
+pub fn sub(arch: Arch, a: u64, b: u64, width: u32) -> FlagUpdate {
+    sbb(arch, a, b, !carry_is_borrow(arch), width)
+}
+
+/// `0 - a`; x86 sets the carry unless `a` is zero
+pub fn neg(arch: Arch, a: u64, width: u32) -> FlagUpdate {
+    sub(arch, 0, a, width)
+}
+
+/// Increment or decrement by one, which leaves the carry alone on x86
+pub fn inc(arch: Arch, a: u64, width: u32) -> FlagUpdate {
+    let update = add(arch, a, 1, width);
+    update.with(Flag::Carry, Effect::Unchanged)
+}
+
+pub fn dec(arch: Arch, a: u64, width: u32) -> FlagUpdate {
+    let update = sub(arch, a, 1, width);
+    update.with(Flag::Carry, Effect::Unchanged)
+}
+
+/// The flags of a bitwise operation (`and`, `or`, `xor`, `test`) which produced `result`. x86
+/// clears the carry and the overflow; ARM leaves them alone, as the carry comes from the
+/// shifter.
+pub fn logic(arch: Arch, result: u64, width: u32) -> FlagUpdate {
+    let update = FlagUpdate::new(result & mask(width)).result_flags(arch, width);
+    match arch {
+        Arch::I386 | Arch::Amd64 => update
+            .set(Flag::Carry, false)
+            .set(Flag::Overflow, false)
+            .with(Flag::Adjust, Effect::Undefined),
+        Arch::Msp430 => update
+            .set(Flag::Carry, result & mask(width) != 0)
+            .set(Flag::Overflow, false),
+        Arch::H8 => update.set(Flag::Overflow, false),
+        _ => update,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shift {
+    Left,
+    /// A logical right shift
+    Right,
+    /// An arithmetic right shift
+    RightArithmetic,
+}
+
+/// Shift `a` by `count` (already masked the way the architecture masks it). The carry is the
+/// last bit shifted out; a zero count changes nothing. x86 only defines the overflow for
+/// single bit shifts.
+pub fn shift(arch: Arch, kind: Shift, a: u64, count: u32, width: u32) -> FlagUpdate {
+    let a = a & mask(width);
+    if count == 0 {
+        return FlagUpdate::new(a);
+    }
+    let bit = |n: u32| n < width && a >> n & 1 == 1;
+    let (result, carry) = match kind {
+        Shift::Left => (
+            if count >= width { 0 } else { a << count },
+            count <= width && bit(width - count),
+        ),
+        Shift::Right => (if count >= width { 0 } else { a >> count }, bit(count - 1)),
+        Shift::RightArithmetic => {
+            let fill = if sign(a, width) { mask(width) } else { 0 };
+            let result = if count >= width {
+                fill
+            } else {
+                (a >> count) | (fill << (width - count))
+            };
+            (
+                result,
+                if count > width {
+                    sign(a, width)
+                } else {
+                    bit(count - 1)
+                },
+            )
+        }
+    };
+    let result = result & mask(width);
+    let update = FlagUpdate::new(result)
+        .result_flags(arch, width)
+        .set(Flag::Carry, carry);
+    if !matches!(arch, Arch::I386 | Arch::Amd64) {
+        return update;
+    }
+    let overflow = match (kind, count) {
+        (Shift::Left, 1) => Effect::Set(sign(result, width) != carry),
+        (Shift::Right, 1) => Effect::Set(sign(a, width)),
+        (Shift::RightArithmetic, 1) => Effect::Set(false),
+        _ => Effect::Undefined,
+    };
+    update
+        .with(Flag::Overflow, overflow)
+        .with(Flag::Adjust, Effect::Undefined)
+}
+
+/// The low `width` bits of `a * b`. x86 sets the carry and the overflow when the product
+/// doesn't fit and leaves the other flags undefined.
+pub fn mul(arch: Arch, a: u64, b: u64, signed: bool, width: u32) -> FlagUpdate {
+    let (a, b) = (a & mask(width), b & mask(width));
+    let (result, fits) = if signed {
+        let extend = |v: u64| ((v << (64 - width)) as i64 >> (64 - width)) as i128;
+        let product = extend(a) * extend(b);
+        let result = product as u64 & mask(width);
+        (result, extend(result) == product)
+    } else {
+        let product = a as u128 * b as u128;
+        (product as u64 & mask(width), product >> width == 0)
+    };
+    let update = FlagUpdate::new(result);
+    match arch {
+        Arch::I386 | Arch::Amd64 => update
+            .set(Flag::Carry, !fits)
+            .set(Flag::Overflow, !fits)
+            .with(Flag::Zero, Effect::Undefined)
+            .with(Flag::Sign, Effect::Undefined)
+            .with(Flag::Parity, Effect::Undefined)
+            .with(Flag::Adjust, Effect::Undefined),
+        _ => update.result_flags(arch, width),
+    }
+}
+
+/// The condition a conditional instruction tests, by the flags it looks at. The unsigned
+/// comparisons differ between x86 and ARM because of the inverted carry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Overflow,
+    NoOverflow,
+    Carry,
+    NoCarry,
+    Zero,
+    NotZero,
+    Sign,
+    NoSign,
+    Parity,
+    NoParity,
+    /// x86 `be`: carry or zero
+    CarryOrZero,
+    /// x86 `a`: neither carry nor zero
+    NoCarryNoZero,
+    /// ARM `hi`: carry and not zero
+    CarryNoZero,
+    /// ARM `ls`: no carry or zero
+    NoCarryOrZero,
+    /// Sign differs from overflow
+    Less,
+    GreaterOrEqual,
+    LessOrEqual,
+    Greater,
+    Always,
+}
+
+impl Condition {
+    /// The condition of an x86 `Jcc`, `SETcc` or `CMOVcc`, from the low nibble of its opcode
+    pub fn from_x86(cc: u8) -> Self {
+        const CONDITIONS: [Condition; 16] = [
+            Condition::Overflow,
+            Condition::NoOverflow,
+            Condition::Carry,
+            Condition::NoCarry,
+            Condition::Zero,
+            Condition::NotZero,
+            Condition::CarryOrZero,
+            Condition::NoCarryNoZero,
+            Condition::Sign,
+            Condition::NoSign,
+            Condition::Parity,
+            Condition::NoParity,
+            Condition::Less,
+            Condition::GreaterOrEqual,
+            Condition::LessOrEqual,
+            Condition::Greater,
+        ];
+        CONDITIONS[usize::from(cc & 0xf)]
+    }
+
+    /// The condition of an ARM or aarch64 instruction, from its 4 bit condition field
+    pub fn from_arm(cc: u8) -> Self {
+        const CONDITIONS: [Condition; 16] = [
+            Condition::Zero,
+            Condition::NotZero,
+            Condition::Carry,
+            Condition::NoCarry,
+            Condition::Sign,
+            Condition::NoSign,
+            Condition::Overflow,
+            Condition::NoOverflow,
+            Condition::CarryNoZero,
+            Condition::NoCarryOrZero,
+            Condition::GreaterOrEqual,
+            Condition::Less,
+            Condition::Greater,
+            Condition::LessOrEqual,
+            Condition::Always,
+            Condition::Always,
+        ];
+        CONDITIONS[usize::from(cc & 0xf)]
+    }
+
+    /// The condition which holds exactly when this one doesn't; `Always` has none
+    pub fn negate(&self) -> Option<Self> {
+        use Condition::*;
+        Some(match self {
+            Overflow => NoOverflow,
+            NoOverflow => Overflow,
+            Carry => NoCarry,
+            NoCarry => Carry,
+            Zero => NotZero,
+            NotZero => Zero,
+            Sign => NoSign,
+            NoSign => Sign,
+            Parity => NoParity,
+            NoParity => Parity,
+            CarryOrZero => NoCarryNoZero,
+            NoCarryNoZero => CarryOrZero,
+            CarryNoZero => NoCarryOrZero,
+            NoCarryOrZero => CarryNoZero,
+            Less => GreaterOrEqual,
+            GreaterOrEqual => Less,
+            LessOrEqual => Greater,
+            Greater => LessOrEqual,
+            Always => return None,
+        })
+    }
+
+    /// Whether the condition holds, or None if it depends on a flag which isn't known
+    pub fn eval(&self, flags: &Flags) -> Option<bool> {
+        use Condition::*;
+        let flag = |flag| flags.get(flag);
+        let not = |value: Option<bool>| value.map(|v| !v);
+        let less = || Some(flag(Flag::Sign)? != flag(Flag::Overflow)?);
+        match self {
+            Overflow => flag(Flag::Overflow),
+            NoOverflow => not(flag(Flag::Overflow)),
+            Carry => flag(Flag::Carry),
+            NoCarry => not(flag(Flag::Carry)),
+            Zero => flag(Flag::Zero),
+            NotZero => not(flag(Flag::Zero)),
+            Sign => flag(Flag::Sign),
+            NoSign => not(flag(Flag::Sign)),
+            Parity => flag(Flag::Parity),
+            NoParity => not(flag(Flag::Parity)),
+            CarryOrZero => or(flag(Flag::Carry), flag(Flag::Zero)),
+            NoCarryNoZero => not(or(flag(Flag::Carry), flag(Flag::Zero))),
+            CarryNoZero => and(flag(Flag::Carry), not(flag(Flag::Zero))),
+            NoCarryOrZero => or(not(flag(Flag::Carry)), flag(Flag::Zero)),
+            Less => less(),
+            GreaterOrEqual => not(less()),
+            LessOrEqual => or(flag(Flag::Zero), less()),
+            Greater => not(or(flag(Flag::Zero), less())),
+            Always => Some(true),
+        }
+    }
+}
+
+/// Three valued or: true as soon as either side is
+fn or(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+/// Three valued and: false as soon as either side is
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+impl RegisterContext {
+    /// The flags as held by the status register; the ones the architecture lacks are unknown
+    pub fn condition_flags(&self) -> Flags {
+        let arch = self.model().arch();
+        let mut flags = Flags::unknown();
+        for flag in FLAGS {
+            let value = flag
+                .register_name(arch)
+                .and_then(|name| self.get_by_name(name));
+            flags.set(flag, value.map(|v| v != 0));
+        }
+        flags
+    }
+
+    /// Write the flag effects of an instruction to the status register. Undefined flags are
+    /// cleared, the register file holds no unknowns.
+    pub fn apply_flags(&mut self, update: &FlagUpdate) {
+        let arch = self.model().arch();
+        for flag in FLAGS {
+            let value = match update.effect(flag) {
+                Effect::Unchanged => continue,
+                Effect::Undefined => false,
+                Effect::Set(value) => value,
+            };
+            if let Some(name) = flag.register_name(arch) {
+                self.set_by_name(name, value.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(update: FlagUpdate) -> Flags {
+        let mut flags = Flags::unknown();
+        flags.apply(&update);
+        flags
+    }
+
+    #[test]
+    fn x86_arithmetic() {
+        let arch = Arch::Amd64;
+        let update = add(arch, 0x7f, 1, 8);
+        assert_eq!(update.result, 0x80);
+        let state = flags(update);
+        assert_eq!(state.get(Flag::Overflow), Some(true));
+        assert_eq!(state.get(Flag::Carry), Some(false));
+        assert_eq!(state.get(Flag::Adjust), Some(true));
+        assert_eq!(state.get(Flag::Sign), Some(true));
+
+        // cmp 1, 2 borrows
+        let state = flags(sub(arch, 1, 2, 32));
+        assert_eq!(state.get(Flag::Carry), Some(true));
+        assert_eq!(Condition::from_x86(0x2).eval(&state), Some(true));
+        assert_eq!(Condition::from_x86(0xc).eval(&state), Some(true));
+        assert_eq!(Condition::from_x86(0x7).eval(&state), Some(false));
+        // sbb with the carry in
+        assert_eq!(sbb(arch, 5, 2, true, 32).result, 2);
+        assert_eq!(adc(arch, u64::MAX, 0, true, 64).result, 0);
+
+        let state = flags(neg(arch, 0, 32));
+        assert_eq!(state.get(Flag::Carry), Some(false));
+        let mut state = flags(inc(arch, 0xff, 8));
+        assert_eq!(state.get(Flag::Zero), Some(true));
+        assert_eq!(state.get(Flag::Carry), None);
+
+        let state = flags(shift(arch, Shift::Left, 0x81, 1, 8));
+        assert_eq!(state.get(Flag::Carry), Some(true));
+        assert_eq!(state.get(Flag::Overflow), Some(true));
+        let state = flags(shift(arch, Shift::RightArithmetic, 0x80, 3, 8));
+        assert_eq!(state.get(Flag::Overflow), None);
+        assert_eq!(shift(arch, Shift::RightArithmetic, 0x80, 3, 8).result, 0xf0);
+        let state = flags(mul(arch, 0x10, 0x10, false, 8));
+        assert_eq!(state.get(Flag::Carry), Some(true));
+        assert_eq!(state.get(Flag::Zero), None);
+        assert_eq!(mul(arch, 0xff, 0xff, true, 8).result, 1);
+        assert_eq!(
+            flags(mul(arch, 0xff, 0xff, true, 8)).get(Flag::Carry),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn fold_with_partial_flags() {
+        // xor eax, eax; jz: taken whatever the other flags are
+        let mut state = Flags::unknown();
+        state.apply(&logic(Arch::I386, 0, 32));
+        assert_eq!(Condition::Zero.eval(&state), Some(true));
+        assert_eq!(Condition::CarryOrZero.eval(&state), Some(true));
+        assert_eq!(Condition::Less.eval(&state), Some(false));
+
+        let mut state = Flags::unknown();
+        state.set(Flag::Zero, Some(false));
+        assert_eq!(Condition::CarryOrZero.eval(&state), None);
+        assert_eq!(Condition::LessOrEqual.eval(&state), None);
+        state.set(Flag::Carry, Some(false));
+        assert_eq!(Condition::NoCarryNoZero.eval(&state), Some(true));
+        for cc in 0..16 {
+            let condition = Condition::from_x86(cc);
+            let negated = condition.negate().unwrap();
+            assert_eq!(negated, Condition::from_x86(cc ^ 1));
+        }
+        assert_eq!(Condition::from_arm(0xe).negate(), None);
+    }
+
+    #[test]
+    fn arm_carry() {
+        // cmp r0, r1 with r0 >= r1 sets the carry on ARM
+        let state = flags(sub(Arch::ArmV7, 2, 1, 32));
+        assert_eq!(state.get(Flag::Carry), Some(true));
+        assert_eq!(Condition::from_arm(0x8).eval(&state), Some(true));
+        assert_eq!(Condition::from_arm(0x3).eval(&state), Some(false));
+        assert_eq!(state.get(Flag::Parity), None);
+        // sbc with the carry set doesn't borrow
+        assert_eq!(sbb(Arch::ArmV7, 5, 2, true, 32).result, 3);
+        // logical operations leave the carry and overflow alone
+        let update = logic(Arch::A64, 0, 64);
+        assert_eq!(update.effect(Flag::Carry), Effect::Unchanged);
+
+        let mut ctx = RegisterContext::new(Arch::ArmV7);
+        ctx.apply_flags(&sub(Arch::ArmV7, 0, 1, 32));
+        assert_eq!(ctx.get_by_name("cpsr"), Some(0x8000_0000));
+        let state = ctx.condition_flags();
+        assert_eq!(Condition::Less.eval(&state), Some(true));
+        assert_eq!(state.get(Flag::Adjust), None);
+
+        let mut ctx = RegisterContext::new(Arch::Amd64);
+        ctx.apply_flags(&sub(Arch::Amd64, 3, 3, 64));
+        assert_eq!(ctx.get_by_name("eflags"), Some(0x44));
+    }
+}
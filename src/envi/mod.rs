@@ -1,7 +1,79 @@
+use crate::constants::{
+    ARCH_A64, ARCH_AMD64, ARCH_ARMV7, ARCH_H8, ARCH_I386, ARCH_MASK, ARCH_MSP430, ARCH_THUMB,
+    ARCH_THUMB16,
+};
 use crate::monitor::EmulationMonitor;
 
+pub mod flags;
+pub mod registers;
+
+/// The architectures envi knows the registers of, one per `ARCH_*` constant
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Arch {
+    I386,
+    Amd64,
+    ArmV7,
+    Thumb16,
+    Thumb,
+    Msp430,
+    H8,
+    A64,
+}
+
+impl Arch {
+    /// The architecture of an `ARCH_*` value, which may be part of a set of instruction flags
+    pub fn from_envi(arch: u32) -> Option<Self> {
+        match (arch & ARCH_MASK) as i32 {
+            ARCH_I386 => Some(Arch::I386),
+            ARCH_AMD64 => Some(Arch::Amd64),
+            ARCH_ARMV7 => Some(Arch::ArmV7),
+            ARCH_THUMB16 => Some(Arch::Thumb16),
+            ARCH_THUMB => Some(Arch::Thumb),
+            ARCH_MSP430 => Some(Arch::Msp430),
+            ARCH_H8 => Some(Arch::H8),
+            ARCH_A64 => Some(Arch::A64),
+            _ => None,
+        }
+    }
+
+    pub fn envi(&self) -> u32 {
+        (match self {
+            Arch::I386 => ARCH_I386,
+            Arch::Amd64 => ARCH_AMD64,
+            Arch::ArmV7 => ARCH_ARMV7,
+            Arch::Thumb16 => ARCH_THUMB16,
+            Arch::Thumb => ARCH_THUMB,
+            Arch::Msp430 => ARCH_MSP430,
+            Arch::H8 => ARCH_H8,
+            Arch::A64 => ARCH_A64,
+        }) as u32
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::I386 => "i386",
+            Arch::Amd64 => "amd64",
+            Arch::ArmV7 => "arm",
+            Arch::Thumb16 => "thumb16",
+            Arch::Thumb => "thumb",
+            Arch::Msp430 => "msp430",
+            Arch::H8 => "h8",
+            Arch::A64 => "a64",
+        }
+    }
+
+    /// The size of a pointer in bytes
+    pub fn pointer_size(&self) -> usize {
+        match self {
+            Arch::Amd64 | Arch::A64 => 8,
+            Arch::Msp430 => 2,
+            _ => 4,
+        }
+    }
+}
+
 pub trait CallingConvention {
     fn get_num_stack_arguments(&self, emu: &EmulationMonitor, argc: i32) -> usize;
     
     fn get_call_args(&self, emu: &EmulationMonitor, argc: i32) -> Vec<u64>;
-}
\ No newline at end of file
+}
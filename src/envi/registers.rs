@@ -0,0 +1,819 @@
+//! The register files of the supported architectures and how their registers alias each other.
+//!
+//! Every architectural register is a full register (`rax`, `r0`, `q0`, `eflags`), and every
+//! other name is a meta register: a bit range of a full register (`eax`, `al`, `ah`, `d1`, `ZF`).
+//! Reads and writes of a meta register go through its full register, so writing `al` changes
+//! `rax` and a dataflow pass sees that a write of `ah` leaves `al` alone. Like envi, a meta
+//! register merges into its full register when written, except where the architecture says the
+//! write zero extends (`eax` on amd64, `w0` and the vector registers on aarch64).
+
+use crate::envi::Arch;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The index of a register in its [`RegisterModel`]
+pub type RegId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterClass {
+    General,
+    ProgramCounter,
+    StackPointer,
+    /// A status register, or one of its flag bits
+    Flags,
+    Segment,
+    Float,
+    Vector,
+    /// The AVX-512 mask registers
+    Mask,
+    /// A register which always reads as zero and ignores writes (`xzr` on aarch64)
+    Zero,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub name: String,
+    /// The full register this register is a part of; itself for a full register
+    pub base: RegId,
+    /// The bit offset in the full register
+    pub offset: u32,
+    /// The width in bits
+    pub width: u32,
+    pub class: RegisterClass,
+    /// Whether writing the register clears the rest of its full register
+    pub zero_extends: bool,
+}
+
+impl Register {
+    /// The bits of the full register this register occupies
+    pub fn bits(&self) -> Range<u32> {
+        self.offset..self.offset + self.width
+    }
+}
+
+fn overlap(a: &Range<u32>, b: &Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The registers of one architecture
+#[derive(Clone, Debug)]
+pub struct RegisterModel {
+    arch: Arch,
+    registers: Vec<Register>,
+    by_name: HashMap<String, RegId>,
+    /// The byte offset of each full register in a register file
+    offsets: HashMap<RegId, usize>,
+    size: usize,
+    pc: RegId,
+    sp: RegId,
+    flags: RegId,
+}
+
+impl RegisterModel {
+    pub fn new(arch: Arch) -> Self {
+        let mut builder = Builder::default();
+        match arch {
+            Arch::I386 => i386(&mut builder),
+            Arch::Amd64 => amd64(&mut builder),
+            Arch::ArmV7 | Arch::Thumb16 | Arch::Thumb => armv7(&mut builder),
+            Arch::A64 => a64(&mut builder),
+            Arch::Msp430 => msp430(&mut builder),
+            Arch::H8 => h8(&mut builder),
+        }
+        let mut offsets = HashMap::new();
+        let mut size = 0;
+        for (idx, register) in builder.registers.iter().enumerate() {
+            if register.base == idx {
+                offsets.insert(idx, size);
+                size += register.width.div_ceil(8) as usize;
+            }
+        }
+        let class = |class| {
+            builder
+                .registers
+                .iter()
+                .enumerate()
+                .find(|(idx, r)| r.class == class && r.base == *idx)
+                .map(|(idx, _)| idx)
+                .unwrap()
+        };
+        let (pc, sp) = (
+            class(RegisterClass::ProgramCounter),
+            class(RegisterClass::StackPointer),
+        );
+        let flags = class(RegisterClass::Flags);
+        RegisterModel {
+            arch,
+            registers: builder.registers,
+            by_name: builder.by_name,
+            offsets,
+            size,
+            pc,
+            sp,
+            flags,
+        }
+    }
+
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
+
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RegId, &Register)> {
+        self.registers.iter().enumerate()
+    }
+
+    /// The full registers, i.e. the ones making up a register file
+    pub fn full_registers(&self) -> impl Iterator<Item = (RegId, &Register)> {
+        self.iter().filter(|(idx, r)| r.base == *idx)
+    }
+
+    pub fn get(&self, reg: RegId) -> &Register {
+        &self.registers[reg]
+    }
+
+    /// Look a register up by its (lower case) name; flag bits go by their upper case name
+    pub fn by_name(&self, name: &str) -> Option<RegId> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, reg: RegId) -> &str {
+        &self.registers[reg].name
+    }
+
+    pub fn pc(&self) -> RegId {
+        self.pc
+    }
+
+    pub fn sp(&self) -> RegId {
+        self.sp
+    }
+
+    /// The status register holding the condition flags
+    pub fn flags(&self) -> RegId {
+        self.flags
+    }
+
+    /// The single bit flags of the status register, e.g. `CF` and `ZF`
+    pub fn flag_bits(&self) -> impl Iterator<Item = (RegId, &Register)> {
+        let flags = self.flags;
+        self.iter()
+            .filter(move |(_, r)| r.base == flags && r.width == 1)
+    }
+
+    /// The full register `reg` is a part of
+    pub fn full(&self, reg: RegId) -> RegId {
+        self.registers[reg].base
+    }
+
+    pub fn is_full(&self, reg: RegId) -> bool {
+        self.registers[reg].base == reg
+    }
+
+    /// Whether `a` and `b` share any bits
+    pub fn overlaps(&self, a: RegId, b: RegId) -> bool {
+        let (a, b) = (&self.registers[a], &self.registers[b]);
+        a.base == b.base && overlap(&a.bits(), &b.bits())
+    }
+
+    /// The other registers sharing bits with `reg`: for `eax` that's `rax`, `ax`, `al` and `ah`
+    pub fn aliases(&self, reg: RegId) -> Vec<RegId> {
+        self.iter()
+            .filter(|(idx, _)| *idx != reg && self.overlaps(*idx, reg))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// The bits of the full register a write of `reg` changes
+    pub fn written_bits(&self, reg: RegId) -> Range<u32> {
+        let register = &self.registers[reg];
+        if register.zero_extends {
+            0..self.registers[register.base].width
+        } else {
+            register.bits()
+        }
+    }
+
+    /// Whether writing `reg` keeps some of the old contents of its full register, which makes
+    /// the write a use of the full register as much as a definition
+    pub fn is_partial_write(&self, reg: RegId) -> bool {
+        self.written_bits(reg) != (0..self.registers[self.full(reg)].width)
+    }
+
+    /// The registers a write of `reg` defines entirely: a write of `eax` on amd64 defines
+    /// `rax`, `eax`, `ax`, `al` and `ah`, a write of `al` only `al`
+    pub fn defines(&self, reg: RegId) -> Vec<RegId> {
+        let base = self.full(reg);
+        let written = self.written_bits(reg);
+        self.iter()
+            .filter(|(_, r)| {
+                r.base == base && written.start <= r.offset && r.offset + r.width <= written.end
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    registers: Vec<Register>,
+    by_name: HashMap<String, RegId>,
+}
+
+impl Builder {
+    fn push(&mut self, register: Register) -> RegId {
+        let idx = self.registers.len();
+        self.by_name.insert(register.name.clone(), idx);
+        self.registers.push(register);
+        idx
+    }
+
+    fn full(&mut self, name: &str, width: u32, class: RegisterClass) -> RegId {
+        let base = self.registers.len();
+        self.push(Register {
+            name: name.to_string(),
+            base,
+            offset: 0,
+            width,
+            class,
+            zero_extends: false,
+        })
+    }
+
+    fn meta(&mut self, name: &str, base: RegId, offset: u32, width: u32) -> RegId {
+        let class = self.registers[base].class;
+        self.push(Register {
+            name: name.to_string(),
+            base,
+            offset,
+            width,
+            class,
+            zero_extends: false,
+        })
+    }
+
+    /// A meta register whose writes clear the rest of the full register
+    fn zx_meta(&mut self, name: &str, base: RegId, width: u32) -> RegId {
+        let idx = self.meta(name, base, 0, width);
+        self.registers[idx].zero_extends = true;
+        idx
+    }
+
+    /// Another name for the whole of `base`
+    fn alias(&mut self, name: &str, base: RegId) -> RegId {
+        let width = self.registers[base].width;
+        self.meta(name, base, 0, width)
+    }
+
+    fn flags(&mut self, base: RegId, bits: &[(&str, u32)]) {
+        for (name, bit) in bits {
+            self.meta(name, base, *bit, 1);
+        }
+    }
+}
+
+const X86_FLAGS: [(&str, u32); 9] = [
+    ("CF", 0),
+    ("PF", 2),
+    ("AF", 4),
+    ("ZF", 6),
+    ("SF", 7),
+    ("TF", 8),
+    ("IF", 9),
+    ("DF", 10),
+    ("OF", 11),
+];
+
+const X86_SEGMENTS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+/// The x87 stack with the MMX registers in its mantissas, and the vector registers
+fn x86_float_and_vector(b: &mut Builder, vectors: usize) {
+    const ST: [&str; 8] = ["st0", "st1", "st2", "st3", "st4", "st5", "st6", "st7"];
+    const MM: [&str; 8] = ["mm0", "mm1", "mm2", "mm3", "mm4", "mm5", "mm6", "mm7"];
+    for (st, mm) in ST.iter().zip(MM) {
+        let base = b.full(st, 80, RegisterClass::Float);
+        b.meta(mm, base, 0, 64);
+    }
+    for i in 0..vectors {
+        let base = b.full(&format!("zmm{}", i), 512, RegisterClass::Vector);
+        b.meta(&format!("ymm{}", i), base, 0, 256);
+        b.meta(&format!("xmm{}", i), base, 0, 128);
+    }
+}
+
+fn i386(b: &mut Builder) {
+    const GPRS: [(&str, &str, &str, &str); 8] = [
+        ("eax", "ax", "al", "ah"),
+        ("ecx", "cx", "cl", "ch"),
+        ("edx", "dx", "dl", "dh"),
+        ("ebx", "bx", "bl", "bh"),
+        ("esp", "sp", "", ""),
+        ("ebp", "bp", "", ""),
+        ("esi", "si", "", ""),
+        ("edi", "di", "", ""),
+    ];
+    for (name, word, low, high) in GPRS {
+        let class = if name == "esp" {
+            RegisterClass::StackPointer
+        } else {
+            RegisterClass::General
+        };
+        let base = b.full(name, 32, class);
+        b.meta(word, base, 0, 16);
+        // only the first four have byte registers outside of 64 bit mode
+        if !low.is_empty() {
+            b.meta(low, base, 0, 8);
+            b.meta(high, base, 8, 8);
+        }
+    }
+    let ip = b.full("eip", 32, RegisterClass::ProgramCounter);
+    b.meta("ip", ip, 0, 16);
+    let eflags = b.full("eflags", 32, RegisterClass::Flags);
+    b.meta("flags", eflags, 0, 16);
+    b.flags(eflags, &X86_FLAGS);
+    for segment in X86_SEGMENTS {
+        b.full(segment, 16, RegisterClass::Segment);
+    }
+    x86_float_and_vector(b, 8);
+    // the bases `fs:` and `gs:` addresses are relative to, the thread pointers
+    b.full("fs_base", 32, RegisterClass::Segment);
+    b.full("gs_base", 32, RegisterClass::Segment);
+}
+
+fn amd64(b: &mut Builder) {
+    const GPRS: [(&str, &str, &str, &str, Option<&str>); 16] = [
+        ("rax", "eax", "ax", "al", Some("ah")),
+        ("rcx", "ecx", "cx", "cl", Some("ch")),
+        ("rdx", "edx", "dx", "dl", Some("dh")),
+        ("rbx", "ebx", "bx", "bl", Some("bh")),
+        ("rsp", "esp", "sp", "spl", None),
+        ("rbp", "ebp", "bp", "bpl", None),
+        ("rsi", "esi", "si", "sil", None),
+        ("rdi", "edi", "di", "dil", None),
+        ("r8", "r8d", "r8w", "r8b", None),
+        ("r9", "r9d", "r9w", "r9b", None),
+        ("r10", "r10d", "r10w", "r10b", None),
+        ("r11", "r11d", "r11w", "r11b", None),
+        ("r12", "r12d", "r12w", "r12b", None),
+        ("r13", "r13d", "r13w", "r13b", None),
+        ("r14", "r14d", "r14w", "r14b", None),
+        ("r15", "r15d", "r15w", "r15b", None),
+    ];
+    for (name, dword, word, low, high) in GPRS {
+        let class = if name == "rsp" {
+            RegisterClass::StackPointer
+        } else {
+            RegisterClass::General
+        };
+        let base = b.full(name, 64, class);
+        // 32 bit writes zero the upper half, narrower ones merge
+        b.zx_meta(dword, base, 32);
+        b.meta(word, base, 0, 16);
+        b.meta(low, base, 0, 8);
+        if let Some(high) = high {
+            b.meta(high, base, 8, 8);
+        }
+    }
+    let rip = b.full("rip", 64, RegisterClass::ProgramCounter);
+    b.zx_meta("eip", rip, 32);
+    let rflags = b.full("rflags", 64, RegisterClass::Flags);
+    b.meta("eflags", rflags, 0, 32);
+    b.flags(rflags, &X86_FLAGS);
+    for segment in X86_SEGMENTS {
+        b.full(segment, 16, RegisterClass::Segment);
+    }
+    x86_float_and_vector(b, 32);
+    for i in 0..8 {
+        b.full(&format!("k{}", i), 64, RegisterClass::Mask);
+    }
+    b.full("fs_base", 64, RegisterClass::Segment);
+    b.full("gs_base", 64, RegisterClass::Segment);
+}
+
+fn armv7(b: &mut Builder) {
+    for i in 0..16 {
+        let class = match i {
+            13 => RegisterClass::StackPointer,
+            15 => RegisterClass::ProgramCounter,
+            _ => RegisterClass::General,
+        };
+        let base = b.full(&format!("r{}", i), 32, class);
+        match i {
+            12 => b.alias("ip", base),
+            13 => b.alias("sp", base),
+            14 => b.alias("lr", base),
+            15 => b.alias("pc", base),
+            _ => continue,
+        };
+    }
+    let cpsr = b.full("cpsr", 32, RegisterClass::Flags);
+    b.flags(
+        cpsr,
+        &[
+            ("T", 5),
+            ("Q", 27),
+            ("V", 28),
+            ("C", 29),
+            ("Z", 30),
+            ("N", 31),
+        ],
+    );
+    b.meta("ge", cpsr, 16, 4);
+    b.full("fpscr", 32, RegisterClass::Float);
+    // s0-s31 make up d0-d15, and d0-d31 make up q0-q15
+    for q in 0..16 {
+        let base = b.full(&format!("q{}", q), 128, RegisterClass::Vector);
+        for half in 0..2 {
+            let d = q * 2 + half;
+            b.meta(&format!("d{}", d), base, half * 64, 64);
+            if d < 16 {
+                for word in 0..2 {
+                    let s = d * 2 + word;
+                    b.meta(&format!("s{}", s), base, half * 64 + word * 32, 32);
+                }
+            }
+        }
+    }
+}
+
+fn a64(b: &mut Builder) {
+    for i in 0..31 {
+        let base = b.full(&format!("x{}", i), 64, RegisterClass::General);
+        b.zx_meta(&format!("w{}", i), base, 32);
+        match i {
+            29 => b.alias("fp", base),
+            30 => b.alias("lr", base),
+            _ => continue,
+        };
+    }
+    let sp = b.full("sp", 64, RegisterClass::StackPointer);
+    b.zx_meta("wsp", sp, 32);
+    let zero = b.full("xzr", 64, RegisterClass::Zero);
+    b.zx_meta("wzr", zero, 32);
+    b.full("pc", 64, RegisterClass::ProgramCounter);
+    let nzcv = b.full("nzcv", 32, RegisterClass::Flags);
+    b.flags(nzcv, &[("V", 28), ("C", 29), ("Z", 30), ("N", 31)]);
+    b.full("fpcr", 32, RegisterClass::Float);
+    b.full("fpsr", 32, RegisterClass::Float);
+    // scalar writes of the SIMD registers clear the rest of the register
+    for i in 0..32 {
+        let base = b.full(&format!("v{}", i), 128, RegisterClass::Vector);
+        b.zx_meta(&format!("q{}", i), base, 128);
+        b.zx_meta(&format!("d{}", i), base, 64);
+        b.zx_meta(&format!("s{}", i), base, 32);
+        b.zx_meta(&format!("h{}", i), base, 16);
+        b.zx_meta(&format!("b{}", i), base, 8);
+    }
+    // the thread pointer
+    b.full("tpidr_el0", 64, RegisterClass::Segment);
+}
+
+fn msp430(b: &mut Builder) {
+    for i in 0..16 {
+        let class = match i {
+            0 => RegisterClass::ProgramCounter,
+            1 => RegisterClass::StackPointer,
+            2 => RegisterClass::Flags,
+            _ => RegisterClass::General,
+        };
+        let base = b.full(&format!("r{}", i), 16, class);
+        match i {
+            0 => b.alias("pc", base),
+            1 => b.alias("sp", base),
+            2 => b.alias("sr", base),
+            3 => b.alias("cg", base),
+            _ => continue,
+        };
+    }
+    let sr = b.by_name["sr"];
+    let sr = b.registers[sr].base;
+    b.flags(
+        sr,
+        &[
+            ("C", 0),
+            ("Z", 1),
+            ("N", 2),
+            ("GIE", 3),
+            ("CPUOFF", 4),
+            ("OSCOFF", 5),
+            ("SCG0", 6),
+            ("SCG1", 7),
+            ("V", 8),
+        ],
+    );
+}
+
+fn h8(b: &mut Builder) {
+    for i in 0..8 {
+        let class = if i == 7 {
+            RegisterClass::StackPointer
+        } else {
+            RegisterClass::General
+        };
+        let base = b.full(&format!("er{}", i), 32, class);
+        b.meta(&format!("e{}", i), base, 16, 16);
+        b.meta(&format!("r{}", i), base, 0, 16);
+        b.meta(&format!("r{}h", i), base, 8, 8);
+        b.meta(&format!("r{}l", i), base, 0, 8);
+        if i == 7 {
+            b.alias("sp", base);
+        }
+    }
+    b.full("pc", 32, RegisterClass::ProgramCounter);
+    let ccr = b.full("ccr", 8, RegisterClass::Flags);
+    b.flags(
+        ccr,
+        &[
+            ("C", 0),
+            ("V", 1),
+            ("Z", 2),
+            ("N", 3),
+            ("U", 4),
+            ("H", 5),
+            ("UI", 6),
+            ("I", 7),
+        ],
+    );
+}
+
+/// The contents of a register file. Values are read and written as `u128`; the bits of a
+/// wider register past the first 128 go through [`RegisterContext::get_bytes`] and
+/// [`RegisterContext::set_bytes`].
+#[derive(Clone, Debug)]
+pub struct RegisterContext {
+    model: Arc<RegisterModel>,
+    values: Vec<u8>,
+}
+
+impl RegisterContext {
+    pub fn new(arch: Arch) -> Self {
+        Self::from_model(Arc::new(RegisterModel::new(arch)))
+    }
+
+    /// A zeroed register file of `model`, which may be shared by many contexts
+    pub fn from_model(model: Arc<RegisterModel>) -> Self {
+        let values = vec![0; model.size];
+        RegisterContext { model, values }
+    }
+
+    pub fn model(&self) -> &RegisterModel {
+        &self.model
+    }
+
+    /// The bytes of the full register `reg` is a part of
+    fn storage(&mut self, reg: RegId) -> &mut [u8] {
+        let base = self.model.registers[reg].base;
+        let start = self.model.offsets[&base];
+        let len = self.model.registers[base].width.div_ceil(8) as usize;
+        &mut self.values[start..start + len]
+    }
+
+    pub fn get(&self, reg: RegId) -> u128 {
+        let register = &self.model.registers[reg];
+        let base = register.base;
+        let start = self.model.offsets[&base];
+        let len = self.model.registers[base].width.div_ceil(8) as usize;
+        read_bits(
+            &self.values[start..start + len],
+            register.offset,
+            register.width.min(128),
+        )
+    }
+
+    /// Write `value` to `reg`, merging it into its full register (or clearing the rest of the
+    /// full register, for a register which zero extends)
+    pub fn set(&mut self, reg: RegId, value: u128) {
+        let register = self.model.registers[reg].clone();
+        if self.model.registers[register.base].class == RegisterClass::Zero {
+            return;
+        }
+        let bytes = self.storage(reg);
+        if register.zero_extends || register.width > 128 {
+            bytes.fill(0);
+        }
+        write_bits(bytes, register.offset, register.width.min(128), value);
+    }
+
+    /// Write `value` to `reg` and clear the rest of its full register, whatever the register
+    /// normally does. This is how VEX encoded instructions write the `xmm` registers.
+    pub fn set_zero_extended(&mut self, reg: RegId, value: u128) {
+        if self.model.registers[self.model.full(reg)].class == RegisterClass::Zero {
+            return;
+        }
+        self.storage(reg).fill(0);
+        self.set(reg, value);
+    }
+
+    /// The little endian bytes of `reg`, for registers of any width
+    pub fn get_bytes(&self, reg: RegId) -> Vec<u8> {
+        let register = &self.model.registers[reg];
+        let len = register.width.div_ceil(8) as usize;
+        if byte_aligned(register.offset, register.width) {
+            let start = self.model.offsets[&register.base] + (register.offset / 8) as usize;
+            return self.values[start..start + len].to_vec();
+        }
+        self.get(reg).to_le_bytes()[..len].to_vec()
+    }
+
+    /// Write the little endian `bytes` to `reg`, zero padded or truncated to its width
+    pub fn set_bytes(&mut self, reg: RegId, bytes: &[u8]) {
+        let register = self.model.registers[reg].clone();
+        if !byte_aligned(register.offset, register.width) || register.width <= 128 {
+            let mut value = [0u8; 16];
+            let len = bytes.len().min(16);
+            value[..len].copy_from_slice(&bytes[..len]);
+            self.set(reg, u128::from_le_bytes(value));
+            return;
+        }
+        if self.model.registers[register.base].class == RegisterClass::Zero {
+            return;
+        }
+        let storage = self.storage(reg);
+        if register.zero_extends {
+            storage.fill(0);
+        }
+        let start = (register.offset / 8) as usize;
+        let target = &mut storage[start..start + (register.width / 8) as usize];
+        let len = bytes.len().min(target.len());
+        target.fill(0);
+        target[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<u128> {
+        self.model.by_name(name).map(|reg| self.get(reg))
+    }
+
+    /// Write the register called `name`, returning false if there is none
+    pub fn set_by_name(&mut self, name: &str, value: u128) -> bool {
+        match self.model.by_name(name) {
+            Some(reg) => {
+                self.set(reg, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.get(self.model.pc) as u64
+    }
+
+    pub fn set_pc(&mut self, value: u64) {
+        self.set(self.model.pc, value.into());
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.get(self.model.sp) as u64
+    }
+
+    pub fn set_sp(&mut self, value: u64) {
+        self.set(self.model.sp, value.into());
+    }
+
+    /// Whether the flag bit `reg` (e.g. `ZF`) is set
+    pub fn flag(&self, reg: RegId) -> bool {
+        self.get(reg) != 0
+    }
+}
+
+fn byte_aligned(offset: u32, width: u32) -> bool {
+    offset.is_multiple_of(8) && width.is_multiple_of(8)
+}
+
+fn read_bits(bytes: &[u8], offset: u32, width: u32) -> u128 {
+    if byte_aligned(offset, width) {
+        let start = (offset / 8) as usize;
+        let mut value = [0u8; 16];
+        value[..(width / 8) as usize].copy_from_slice(&bytes[start..start + (width / 8) as usize]);
+        return u128::from_le_bytes(value);
+    }
+    (0..width)
+        .filter(|i| bytes[((offset + i) / 8) as usize] >> ((offset + i) % 8) & 1 == 1)
+        .fold(0, |value, i| value | 1 << i)
+}
+
+fn write_bits(bytes: &mut [u8], offset: u32, width: u32, value: u128) {
+    if byte_aligned(offset, width) {
+        let start = (offset / 8) as usize;
+        let len = (width / 8) as usize;
+        bytes[start..start + len].copy_from_slice(&value.to_le_bytes()[..len]);
+        return;
+    }
+    for i in 0..width {
+        let bit = offset + i;
+        let byte = &mut bytes[(bit / 8) as usize];
+        if value >> i & 1 == 1 {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_aliases() {
+        let mut ctx = RegisterContext::new(Arch::Amd64);
+        let model = Arc::new(RegisterModel::new(Arch::Amd64));
+        let reg = |name| model.by_name(name).unwrap();
+        ctx.set(reg("rax"), 0x1122_3344_5566_7788);
+        ctx.set(reg("al"), 0xff);
+        assert_eq!(ctx.get(reg("rax")), 0x1122_3344_5566_77ff);
+        ctx.set(reg("ah"), 0xee);
+        assert_eq!(ctx.get(reg("ax")), 0xeeff);
+        assert_eq!(ctx.get(reg("al")), 0xff);
+        // 32 bit writes clear the upper half, 16 bit writes don't
+        ctx.set(reg("eax"), 0xdead_beef);
+        assert_eq!(ctx.get(reg("rax")), 0xdead_beef);
+        ctx.set(reg("r8"), u64::MAX.into());
+        ctx.set(reg("r8w"), 0);
+        assert_eq!(ctx.get(reg("r8")), 0xffff_ffff_ffff_0000);
+
+        ctx.set(reg("ZF"), 1);
+        ctx.set(reg("CF"), 1);
+        assert_eq!(ctx.get(reg("eflags")), 0x41);
+        assert!(ctx.flag(reg("ZF")) && !ctx.flag(reg("SF")));
+        assert_eq!(model.flag_bits().count(), 9);
+
+        ctx.set_bytes(reg("zmm3"), &[0xaa; 64]);
+        ctx.set(reg("xmm3"), 1);
+        assert_eq!(ctx.get_bytes(reg("ymm3"))[16..], [0xaa; 16]);
+        ctx.set_zero_extended(reg("xmm3"), 1);
+        assert_eq!(ctx.get_bytes(reg("zmm3"))[1..], [0; 63]);
+
+        ctx.set_pc(0x401000);
+        assert_eq!(ctx.get(reg("rip")), 0x401000);
+        assert_eq!(model.get(model.sp()).name, "rsp");
+    }
+
+    #[test]
+    fn dataflow_queries() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let names = |regs: Vec<RegId>| {
+            let mut names = regs.into_iter().map(|r| model.name(r)).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(names(model.aliases(reg("ah"))), ["ax", "eax", "rax"]);
+        assert!(!model.overlaps(reg("al"), reg("ah")));
+        assert_eq!(model.full(reg("sil")), reg("rsi"));
+        assert!(model.is_partial_write(reg("al")));
+        assert!(!model.is_partial_write(reg("eax")));
+        assert_eq!(
+            names(model.defines(reg("eax"))),
+            ["ah", "al", "ax", "eax", "rax"]
+        );
+        assert_eq!(names(model.defines(reg("ax"))), ["ah", "al", "ax"]);
+
+        let i386 = RegisterModel::new(Arch::I386);
+        let eax = i386.by_name("eax").unwrap();
+        assert!(!i386.is_partial_write(eax));
+        assert_eq!(i386.full(eax), eax);
+    }
+
+    #[test]
+    fn arm_aliases() {
+        let mut ctx = RegisterContext::new(Arch::ArmV7);
+        let model = ctx.model().clone();
+        let reg = |name| model.by_name(name).unwrap();
+        ctx.set(reg("q1"), 0x0123_4567_89ab_cdef_0011_2233_4455_6677);
+        assert_eq!(ctx.get(reg("d3")), 0x0123_4567_89ab_cdef);
+        assert_eq!(ctx.get(reg("s4")), 0x4455_6677);
+        ctx.set(reg("s5"), 0);
+        assert_eq!(ctx.get(reg("d2")), 0x4455_6677);
+        assert_eq!(reg("sp"), reg("r13") + 1);
+        assert!(model.overlaps(reg("sp"), reg("r13")));
+        ctx.set(reg("N"), 1);
+        assert_eq!(ctx.get(reg("cpsr")), 0x8000_0000);
+
+        let mut ctx = RegisterContext::new(Arch::A64);
+        let model = ctx.model().clone();
+        let reg = |name| model.by_name(name).unwrap();
+        ctx.set(reg("x3"), u64::MAX.into());
+        ctx.set(reg("w3"), 1);
+        assert_eq!(ctx.get(reg("x3")), 1);
+        ctx.set(reg("xzr"), 5);
+        assert_eq!(ctx.get(reg("wzr")), 0);
+        ctx.set(reg("v0"), u128::MAX);
+        ctx.set(reg("d0"), 2);
+        assert_eq!(ctx.get(reg("q0")), 2);
+
+        let mut ctx = RegisterContext::new(Arch::H8);
+        ctx.set_by_name("er0", 0x1234_5678);
+        assert_eq!(ctx.get_by_name("e0"), Some(0x1234));
+        assert_eq!(ctx.get_by_name("r0h"), Some(0x56));
+        assert_eq!(ctx.model().flag_bits().count(), 8);
+    }
+}
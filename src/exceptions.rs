@@ -0,0 +1,465 @@
+//! Control flow through exception and signal handlers.
+//!
+//! Code which means to fault registers a handler first, and the handler is where execution
+//! goes on: a null write or a `ud2` is a jump to it as far as the program is concerned, and an
+//! edge nothing else in the analysis sees. [`find`] looks for the registrations:
+//!
+//! * an SEH frame pushed on i386, `push handler; push fs:[0]; mov fs:[0], esp`, found in the
+//!   bytes of a lifted function (on amd64 SEH lives in tables, not in code);
+//! * calls to `AddVectoredExceptionHandler`, `AddVectoredContinueHandler` and
+//!   `SetUnhandledExceptionFilter`;
+//! * calls to `signal` and `sigaction`, with the signal number when it is a constant.
+//!
+//! The handler is the argument to the call, or the `sa_handler` of the structure passed to
+//! `sigaction`, when the function makes it a constant (see [`crate::vsa::value_before`]).
+//! Each instruction which must fault, reachable in the same function after the registration, is
+//! an [`Edge`] to the handler: `int3`, `ud2` and the like on x86, and loads and stores at
+//! constant addresses which aren't mapped or, for stores, aren't writable. A signal handler
+//! only gets the faults raising its signal. [`annotate`] makes the handlers entry points and
+//! records the edges as conditional code xrefs.
+
+use crate::{
+    constants::{BR_COND, REF_CODE},
+    envi::Arch,
+    pic::AddressSpace,
+    symbolic::{call_argument, Expr, Function, Insn, State, Stmt},
+    syscalls::function_at,
+    vsa::{value_before, ValueSet},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+const SIGILL: u64 = 4;
+const SIGTRAP: u64 = 5;
+const SIGBUS: u64 = 7;
+const SIGSEGV: u64 = 11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HandlerKind {
+    /// A frame pushed on the SEH chain at `fs:[0]`
+    Seh,
+    Vectored,
+    /// `SetUnhandledExceptionFilter`
+    UnhandledFilter,
+    Signal,
+}
+
+impl fmt::Display for HandlerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandlerKind::Seh => "seh",
+            HandlerKind::Vectored => "vectored",
+            HandlerKind::UnhandledFilter => "unhandled filter",
+            HandlerKind::Signal => "signal",
+        })
+    }
+}
+
+/// The functions registering a handler: the kind, whether they follow the Windows ABI, the
+/// argument holding the handler and the one holding the signal number. `sigaction` takes a
+/// structure starting with the handler.
+const REGISTRARS: [(&str, HandlerKind, bool, usize, Option<usize>); 6] = [
+    (
+        "AddVectoredExceptionHandler",
+        HandlerKind::Vectored,
+        true,
+        1,
+        None,
+    ),
+    (
+        "AddVectoredContinueHandler",
+        HandlerKind::Vectored,
+        true,
+        1,
+        None,
+    ),
+    (
+        "SetUnhandledExceptionFilter",
+        HandlerKind::UnhandledFilter,
+        true,
+        0,
+        None,
+    ),
+    ("signal", HandlerKind::Signal, false, 1, Some(0)),
+    ("bsd_signal", HandlerKind::Signal, false, 1, Some(0)),
+    ("sigaction", HandlerKind::Signal, false, 1, Some(0)),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Handler {
+    /// The instruction making the registration
+    pub registration: u64,
+    pub kind: HandlerKind,
+    pub handler: u64,
+    /// The signal a signal handler is for, if known
+    pub signal: Option<u64>,
+}
+
+impl Handler {
+    /// Whether a fault raising `signal` goes to the handler
+    fn catches(&self, signal: u64) -> bool {
+        match self.signal {
+            Some(SIGBUS) => signal == SIGSEGV,
+            Some(caught) => caught == signal,
+            None => true,
+        }
+    }
+}
+
+/// Why an instruction faults
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FaultKind {
+    /// `int3` or `icebp`
+    Breakpoint,
+    /// `ud2`
+    InvalidOpcode,
+    /// `hlt` or `cli` outside the kernel
+    Privileged,
+    /// A load or store at an address it can't use
+    Access,
+}
+
+impl FaultKind {
+    /// The signal the fault raises on POSIX systems
+    pub fn signal(&self) -> u64 {
+        match self {
+            FaultKind::Breakpoint => SIGTRAP,
+            FaultKind::InvalidOpcode => SIGILL,
+            FaultKind::Privileged | FaultKind::Access => SIGSEGV,
+        }
+    }
+}
+
+/// A faulting instruction and the handler execution goes on in
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub fault: u64,
+    pub kind: FaultKind,
+    pub handler: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exceptions {
+    pub handlers: Vec<Handler>,
+    pub edges: Vec<Edge>,
+}
+
+/// The SEH frames the i386 `bytes` at `va` push, as the `mov fs:[0], esp` and the handler
+fn seh_frames(va: u64, bytes: &[u8]) -> Vec<(u64, u64)> {
+    let mut frames = Vec::new();
+    for i in 0..bytes.len() {
+        // push handler; push dword fs:[0]
+        let [0x68, h0, h1, h2, h3, 0x64, 0xff, 0x35, 0, 0, 0, 0, ..] = bytes[i..] else {
+            continue;
+        };
+        let after = &bytes[i + 12..bytes.len().min(i + 28)];
+        let link = after
+            .windows(7)
+            .position(|w| w == [0x64, 0x89, 0x25, 0, 0, 0, 0]);
+        if let Some(at) = link {
+            let handler = u32::from_le_bytes([h0, h1, h2, h3]) as u64;
+            frames.push((va + (i + 12 + at) as u64, handler));
+        }
+    }
+    frames
+}
+
+/// The fault the bytes of an x86 instruction raise whatever the state
+fn x86_fault(bytes: &[u8]) -> Option<FaultKind> {
+    match bytes {
+        [0xcc, ..] | [0xf1, ..] | [0xcd, 0x03, ..] => Some(FaultKind::Breakpoint),
+        [0x0f, 0x0b, ..] => Some(FaultKind::InvalidOpcode),
+        [0xf4, ..] | [0xfa, ..] => Some(FaultKind::Privileged),
+        _ => None,
+    }
+}
+
+/// Whether the instruction must fault, run from `state`, which it updates
+fn fault(
+    arch: Arch,
+    insn: &Insn,
+    state: &mut State,
+    space: &AddressSpace<'_>,
+) -> Option<FaultKind> {
+    let mut kind = None;
+    if matches!(arch, Arch::I386 | Arch::Amd64) {
+        kind = space
+            .bytes(insn.va, 2)
+            .and_then(|(bytes, _)| x86_fault(bytes));
+    }
+    for stmt in insn.stmts.iter() {
+        let mut reads = Vec::new();
+        let mut write = None;
+        match stmt {
+            Stmt::Set(_, value) => value.loads(&mut reads),
+            Stmt::Store(addr, value) => {
+                addr.loads(&mut reads);
+                value.loads(&mut reads);
+                write = Some(addr);
+            }
+            Stmt::Flags(_, a, b) => {
+                a.loads(&mut reads);
+                b.loads(&mut reads);
+            }
+            Stmt::Unknown => {}
+        }
+        let bad_read = reads.iter().any(|addr| {
+            let addr = state.eval(addr).as_const();
+            addr.is_some_and(|addr| !space.is_mapped(addr))
+        });
+        let bad_write = write
+            .and_then(|addr| state.eval(addr).as_const())
+            .is_some_and(|addr| !matches!(space.bytes(addr, 1), Some((_, true))));
+        if kind.is_none() && (bad_read || bad_write) {
+            kind = Some(FaultKind::Access);
+        }
+        state.exec(stmt);
+    }
+    kind
+}
+
+/// The faults of `func` reachable from the start of `handler`'s registration
+fn edges(func: &Function, handler: &Handler, space: &AddressSpace<'_>, edges: &mut BTreeSet<Edge>) {
+    let Some((&start, _)) = func.blocks.range(..=handler.registration).next_back() else {
+        return;
+    };
+    let mut seen = BTreeSet::from([start]);
+    let mut pending = vec![start];
+    while let Some(va) = pending.pop() {
+        let mut state = State::new(func.arch);
+        for insn in func.blocks[&va].insns.iter() {
+            let kind = fault(func.arch, insn, &mut state, space);
+            let after = va != start || insn.va > handler.registration;
+            if let Some(kind) = kind.filter(|kind| after && handler.catches(kind.signal())) {
+                edges.insert(Edge {
+                    fault: insn.va,
+                    kind,
+                    handler: handler.handler,
+                });
+            }
+        }
+        for succ in func.successors(va) {
+            if func.blocks.contains_key(&succ) && seen.insert(succ) {
+                pending.push(succ);
+            }
+        }
+    }
+}
+
+/// The single value of `expr` before the instruction at `va`
+fn constant(func: &Function, space: &AddressSpace<'_>, va: u64, expr: &Expr) -> Option<u64> {
+    match value_before(func, space, va, expr) {
+        ValueSet::Values(values) if values.len() == 1 => values.first().copied(),
+        _ => None,
+    }
+}
+
+/// The handlers registered in the lifted `functions`, and the edges to them from the faults
+/// after each registration. Calls go by the workspace's xrefs to the imports; `space` is the
+/// memory the functions run in.
+pub fn find(
+    workspace: &VivWorkspace,
+    arch: Arch,
+    functions: &BTreeMap<u64, Function>,
+    space: &AddressSpace<'_>,
+) -> Exceptions {
+    let mut handlers = Vec::new();
+    if arch == Arch::I386 {
+        for func in functions.values() {
+            for block in func.blocks.values() {
+                let len = block.end_va.saturating_sub(block.va) as usize;
+                let Some((bytes, _)) = space.bytes(block.va, len) else {
+                    continue;
+                };
+                for (registration, handler) in seh_frames(block.va, bytes) {
+                    handlers.push(Handler {
+                        registration,
+                        kind: HandlerKind::Seh,
+                        handler,
+                        signal: None,
+                    });
+                }
+            }
+        }
+    }
+    for (name, kind, windows, argument, signal) in REGISTRARS {
+        let Some(mut argument) = call_argument(arch, windows, argument) else {
+            continue;
+        };
+        if name == "sigaction" {
+            argument = Expr::Load(Box::new(argument));
+        }
+        for call in workspace.get_callers_of_import(name) {
+            let call = call as u32 as u64;
+            let Some(func) = function_at(functions, call) else {
+                continue;
+            };
+            let Some(handler) = constant(func, space, call, &argument) else {
+                continue;
+            };
+            // SIG_DFL and SIG_IGN aren't code
+            if !space.is_mapped(handler) {
+                continue;
+            }
+            let signal = signal
+                .and_then(|index| call_argument(arch, windows, index))
+                .and_then(|expr| constant(func, space, call, &expr));
+            handlers.push(Handler {
+                registration: call,
+                kind,
+                handler,
+                signal,
+            });
+        }
+    }
+    handlers.sort();
+    handlers.dedup();
+
+    let mut found = BTreeSet::new();
+    for handler in handlers.iter() {
+        if let Some(func) = function_at(functions, handler.registration) {
+            edges(func, handler, space, &mut found);
+        }
+    }
+    Exceptions {
+        handlers,
+        edges: found.into_iter().collect(),
+    }
+}
+
+/// Record the handlers in the workspace as entry points, and the edges as code xrefs
+pub fn annotate(workspace: &mut VivWorkspace, exceptions: &Exceptions) {
+    for handler in exceptions.handlers.iter() {
+        workspace.add_entry_point(handler.handler as i32);
+        let comment = format!(
+            "{} handler, registered at {:#x}",
+            handler.kind, handler.registration
+        );
+        workspace.set_comment(handler.handler as i32, &comment, true);
+    }
+    for edge in exceptions.edges.iter() {
+        workspace.add_xref(edge.fault as i32, edge.handler as i32, REF_CODE, BR_COND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::registers::RegisterModel,
+        symbolic::{BinOp, Block, Terminator},
+    };
+
+    #[test]
+    fn handlers_and_edges() {
+        let model = RegisterModel::new(Arch::I386);
+        let (esp, eax) = (model.sp(), model.by_name("eax").unwrap());
+        let sp = |offset: u64| Expr::binary(BinOp::Add, Expr::Reg(esp), Expr::Const(offset), 32);
+        let insn = |va, stmts| Insn { va, stmts };
+        let mut code = vec![0x90; 0x1400];
+        let mut put = |va: usize, bytes: &[u8]| {
+            code[va - 0xf00..va - 0xf00 + bytes.len()].copy_from_slice(bytes)
+        };
+        // int3 before the handler is registered
+        put(0xf00, &[0xcc]);
+        // push 0x2000; push fs:[0]; mov fs:[0], esp
+        put(0x1000, &[0x68, 0, 0x20, 0, 0, 0x64, 0xff, 0x35, 0, 0, 0, 0]);
+        put(0x100c, &[0x64, 0x89, 0x25, 0, 0, 0, 0]);
+        put(0x1100, &[0x0f, 0x0b]);
+        put(0x1215, &[0xcc]);
+
+        let mut seh = Function::new(Arch::I386, 0xf00);
+        seh.add_block(Block {
+            va: 0xf00,
+            insns: vec![insn(0xf00, vec![Stmt::Unknown])],
+            end_va: 0xf01,
+            end: Terminator::Jump(0x1000),
+        });
+        // ...; xor eax, eax; mov [eax], 1
+        seh.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Set(esp, sp(0xffff_fffc))]),
+                insn(0x1005, vec![Stmt::Set(esp, sp(0xffff_fffc))]),
+                insn(0x100c, vec![Stmt::Unknown]),
+                insn(0x1013, vec![Stmt::Set(eax, Expr::Const(0))]),
+                insn(0x1015, vec![Stmt::Store(Expr::Reg(eax), Expr::Const(1))]),
+            ],
+            end_va: 0x101b,
+            end: Terminator::Jump(0x1100),
+        });
+        seh.add_block(Block {
+            va: 0x1100,
+            insns: vec![insn(0x1100, vec![Stmt::Unknown])],
+            end_va: 0x1102,
+            end: Terminator::Return,
+        });
+        // signal(SIGSEGV, 0x2100); int3; mov eax, [0x10]
+        let mut posix = Function::new(Arch::I386, 0x1200);
+        posix.add_block(Block {
+            va: 0x1200,
+            insns: vec![
+                insn(
+                    0x1200,
+                    vec![
+                        Stmt::Set(esp, sp(0xffff_fff8)),
+                        Stmt::Store(sp(0), Expr::Const(SIGSEGV)),
+                        Stmt::Store(sp(4), Expr::Const(0x2100)),
+                    ],
+                ),
+                insn(0x1210, vec![Stmt::Unknown]),
+                insn(0x1215, vec![Stmt::Unknown]),
+                insn(
+                    0x1216,
+                    vec![Stmt::Set(eax, Expr::Load(Box::new(Expr::Const(0x10))))],
+                ),
+            ],
+            end_va: 0x121b,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0xf00, seh), (0x1200, posix)]);
+        let mut space = AddressSpace::new();
+        space.add_map(0xf00, &code, false);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x4000, "libc", "signal");
+        ws.add_xref(0x1210, 0x4000, REF_CODE, 0);
+
+        let found = find(&ws, Arch::I386, &functions, &space);
+        let handlers: Vec<_> = found
+            .handlers
+            .iter()
+            .map(|h| (h.registration, h.kind, h.handler, h.signal))
+            .collect();
+        assert_eq!(
+            handlers,
+            [
+                (0x100c, HandlerKind::Seh, 0x2000, None),
+                (0x1210, HandlerKind::Signal, 0x2100, Some(SIGSEGV)),
+            ]
+        );
+        let edges: Vec<_> = found
+            .edges
+            .iter()
+            .map(|e| (e.fault, e.kind, e.handler))
+            .collect();
+        assert_eq!(
+            edges,
+            [
+                (0x1015, FaultKind::Access, 0x2000),
+                (0x1100, FaultKind::InvalidOpcode, 0x2000),
+                (0x1216, FaultKind::Access, 0x2100),
+            ]
+        );
+
+        annotate(&mut ws, &found);
+        assert_eq!(ws.get_entry_points(), [0x2000, 0x2100]);
+        assert!(ws
+            .get_xrefs_from(0x1100, Some(REF_CODE))
+            .iter()
+            .any(|xref| xref.1 == 0x2000));
+    }
+}
@@ -0,0 +1,428 @@
+//! Findings of the detection passes, for CI systems and dashboards.
+//!
+//! A pass reports what it found as [`Finding`]s against the rules it checks, and a [`Report`]
+//! collects them for one binary. [`Report::to_sarif`] writes SARIF 2.1.0, which code scanning
+//! services ingest as is; [`Report::to_json`] writes a flat JSON object for everything else.
+//! Addresses go in the SARIF `address` of a result's physical location.
+//!
+//! The obfuscation passes report through [`Report::add_deobfuscation`] and
+//! [`Report::add_flattening`], the anti-analysis pass through [`Report::add_anti_analysis`],
+//! the security audit through [`Report::add_audit`], and code found in the slack of a section
+//! through [`Report::add_cave_code`]. The capability rules report through
+//! [`Report::add_capabilities`], and the tags of imported APIs through [`Report::add_api_usage`].
+//!
+//! Findings which are evidence of a MITRE ATT&CK technique carry its id (see [`crate::attack`]),
+//! in the `attack` list of the JSON and the `properties` of a SARIF result.
+
+use crate::{
+    antianalysis::Detection,
+    attack::{tag_technique, technique_id},
+    audit::Issue,
+    capabilities::{Capabilities, RuleSet},
+    deobfuscate::{self, JunkKind},
+    flattening::Dispatcher,
+    patch::CaveCode,
+    utils::json_string,
+    workspace::VivWorkspace,
+};
+use std::{collections::BTreeMap, fmt};
+
+pub const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// How much a finding matters, as SARIF levels it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Note => "note",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// The id of the rule the finding is against, `opaque-predicate`
+    pub rule: String,
+    pub level: Level,
+    pub message: String,
+    /// Where in the binary, if anywhere in particular
+    pub va: Option<u64>,
+    /// The ATT&CK techniques the finding is evidence of, `T1622`
+    pub attack: Vec<String>,
+}
+
+impl Finding {
+    pub fn new(rule: &str, level: Level, message: String, va: Option<u64>) -> Self {
+        Finding {
+            rule: rule.to_string(),
+            level,
+            message,
+            va,
+            attack: vec![],
+        }
+    }
+
+    /// Add the ATT&CK techniques of `entries`, as ids or as capa writes them
+    pub fn with_attack<'a>(mut self, entries: impl IntoIterator<Item = &'a str>) -> Self {
+        for id in entries.into_iter().filter_map(technique_id) {
+            if !self.attack.iter().any(|have| have == id) {
+                self.attack.push(id.to_string());
+            }
+        }
+        self
+    }
+}
+
+/// The findings for one binary, and the rules they are against
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The path or URI of the binary
+    pub artifact: String,
+    /// Rule id to a description of what the rule checks
+    pub rules: BTreeMap<String, String>,
+    pub findings: Vec<Finding>,
+}
+
+pub const RULE_OPAQUE_PREDICATE: &str = "opaque-predicate";
+pub const RULE_JUNK_CODE: &str = "junk-code";
+pub const RULE_FLATTENING: &str = "control-flow-flattening";
+pub const RULE_CAVE_CODE: &str = "cave-code";
+
+impl Report {
+    pub fn new(artifact: &str) -> Self {
+        Report {
+            artifact: artifact.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_rule(&mut self, id: &str, description: &str) {
+        self.rules.insert(id.to_string(), description.to_string());
+    }
+
+    pub fn add(&mut self, finding: Finding) {
+        self.findings.push(finding);
+    }
+
+    /// The findings of the opaque predicate and junk code pass
+    pub fn add_deobfuscation(&mut self, report: &deobfuscate::Report) {
+        self.add_rule(
+            RULE_OPAQUE_PREDICATE,
+            "A conditional branch which always goes the same way",
+        );
+        self.add_rule(
+            RULE_JUNK_CODE,
+            "Instructions with no effect on the rest of the code",
+        );
+        for predicate in report.opaque.iter() {
+            let message = format!(
+                "Branch is {} taken; {:#x} is never reached through it",
+                if predicate.taken { "always" } else { "never" },
+                predicate.dead
+            );
+            self.add(Finding::new(
+                RULE_OPAQUE_PREDICATE,
+                Level::Warning,
+                message,
+                Some(predicate.va),
+            ));
+        }
+        for junk in report.junk.iter() {
+            let message = match junk.kind {
+                JunkKind::NoEffect => format!("{} instructions with no effect", junk.insns.len()),
+                JunkKind::DeadWrite => "Register write overwritten before it is read".to_string(),
+            };
+            let va = junk.insns.first().copied();
+            self.add(Finding::new(RULE_JUNK_CODE, Level::Note, message, va));
+        }
+    }
+
+    /// A flattened function found by the control flow flattening pass
+    pub fn add_flattening(&mut self, function: u64, dispatcher: &Dispatcher) {
+        self.add_rule(
+            RULE_FLATTENING,
+            "A function whose blocks run as the cases of a dispatcher loop",
+        );
+        let message = format!(
+            "Function {:#x} is flattened: dispatcher at {:#x} selects between {} cases",
+            function,
+            dispatcher.header,
+            dispatcher.cases.len()
+        );
+        self.add(Finding::new(
+            RULE_FLATTENING,
+            Level::Warning,
+            message,
+            Some(dispatcher.header),
+        ));
+    }
+
+    /// The tricks found by the anti-analysis pass, one rule for each technique
+    pub fn add_anti_analysis(&mut self, detections: &[Detection]) {
+        for detection in detections {
+            let technique = detection.technique;
+            self.add_rule(technique.rule(), technique.description());
+            let message = format!(
+                "{} ({}): {}",
+                technique.description(),
+                technique.attack_id(),
+                detection.detail
+            );
+            self.add(
+                Finding::new(
+                    technique.rule(),
+                    Level::Warning,
+                    message,
+                    Some(detection.va),
+                )
+                .with_attack([technique.attack_id()]),
+            );
+        }
+    }
+
+    /// The issues found by the security audit, one rule for each check
+    pub fn add_audit(&mut self, issues: &[Issue]) {
+        for issue in issues {
+            self.add_rule(issue.check.rule(), issue.check.description());
+            let message = format!("In function {:#x}: {}", issue.function, issue.evidence);
+            self.add(Finding::new(
+                issue.check.rule(),
+                issue.level,
+                message,
+                Some(issue.va),
+            ));
+        }
+    }
+
+    /// The functions found in the slack of executable maps, as implants hide in
+    pub fn add_cave_code(&mut self, found: &[CaveCode]) {
+        self.add_rule(
+            RULE_CAVE_CODE,
+            "Code after the padding at the end of an executable section",
+        );
+        for code in found {
+            let mut message = format!(
+                "Function {:#x} follows {:#x} bytes of padding at the end of its section",
+                code.function, code.padding
+            );
+            if code.entry_point {
+                message.push_str(", and is the entry point");
+            }
+            let level = if code.entry_point {
+                Level::Error
+            } else {
+                Level::Warning
+            };
+            self.add(Finding::new(
+                RULE_CAVE_CODE,
+                level,
+                message,
+                Some(code.function),
+            ));
+        }
+    }
+
+    /// The capabilities found by the capability rules, one rule for each capa rule: a finding
+    /// for each function a rule matched in, or one for the file if it matched nowhere in
+    /// particular
+    pub fn add_capabilities(&mut self, caps: &Capabilities, rules: &RuleSet) {
+        for name in caps.file.iter() {
+            let Some(rule) = rules.get(name) else {
+                continue;
+            };
+            self.add_rule(name, rule.namespace.as_deref().unwrap_or_default());
+            let attack = rule.attack.iter().map(String::as_str);
+            let functions: Vec<i32> = caps
+                .functions
+                .iter()
+                .filter(|(_, names)| names.contains(name))
+                .map(|(fva, _)| *fva)
+                .collect();
+            if functions.is_empty() {
+                let message = format!("The file has the capability to {}", name);
+                self.add(Finding::new(name, Level::Note, message, None).with_attack(attack));
+                continue;
+            }
+            for fva in functions {
+                let message = format!("Function {:#x} has the capability to {}", fva, name);
+                let finding = Finding::new(name, Level::Note, message, Some(fva as u32 as u64));
+                self.add(finding.with_attack(attack.clone()));
+            }
+        }
+    }
+
+    /// The imported APIs tagged with a capability which maps onto an ATT&CK technique, one
+    /// rule for each tag
+    pub fn add_api_usage(&mut self, workspace: &VivWorkspace) {
+        for (va, name) in workspace.get_imports() {
+            for tag in workspace.get_tags(va) {
+                let Some(id) = tag_technique(&tag) else {
+                    continue;
+                };
+                let rule = format!("api/{}", tag);
+                self.add_rule(&rule, &format!("Imports an API tagged {}", tag));
+                let message = format!("Imports {} ({})", name, tag);
+                let finding = Finding::new(&rule, Level::Note, message, Some(va as u32 as u64));
+                self.add(finding.with_attack([id]));
+            }
+        }
+    }
+
+    /// The rules with findings, and those registered, by id
+    fn all_rules(&self) -> BTreeMap<&str, &str> {
+        let mut rules: BTreeMap<&str, &str> = self
+            .rules
+            .iter()
+            .map(|(id, description)| (id.as_str(), description.as_str()))
+            .collect();
+        for finding in self.findings.iter() {
+            rules.entry(finding.rule.as_str()).or_insert("");
+        }
+        rules
+    }
+
+    /// The report as a SARIF 2.1.0 log with a single run
+    pub fn to_sarif(&self) -> String {
+        let rules: Vec<String> = self
+            .all_rules()
+            .iter()
+            .map(|(id, description)| {
+                format!(
+                    "{{\"id\": {}, \"shortDescription\": {{\"text\": {}}}}}",
+                    json_string(id),
+                    json_string(description)
+                )
+            })
+            .collect();
+        let results: Vec<String> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let address = finding
+                    .va
+                    .map(|va| format!(", \"address\": {{\"absoluteAddress\": {}}}", va))
+                    .unwrap_or_default();
+                let properties = if finding.attack.is_empty() {
+                    String::new()
+                } else {
+                    format!(", \"properties\": {{\"attack\": {}}}", attack(finding))
+                };
+                format!(
+                    "{{\"ruleId\": {}, \"level\": \"{}\", \"message\": {{\"text\": {}}}, \
+                     \"locations\": [{{\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": \
+                     {}}}{}}}}}]{}}}",
+                    json_string(&finding.rule),
+                    finding.level,
+                    json_string(&finding.message),
+                    json_string(&self.artifact),
+                    address,
+                    properties
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"$schema\": \"{}\",\n  \"version\": \"2.1.0\",\n  \"runs\": [{{\n    \
+             \"tool\": {{\"driver\": {{\"name\": \"vivisect\", \"version\": \"{}\", \"rules\": \
+             [{}]}}}},\n    \"artifacts\": [{{\"location\": {{\"uri\": {}}}}}],\n    \
+             \"results\": [\n      {}\n    ]\n  }}]\n}}\n",
+            SARIF_SCHEMA,
+            env!("CARGO_PKG_VERSION"),
+            rules.join(", "),
+            json_string(&self.artifact),
+            results.join(",\n      ")
+        )
+    }
+
+    /// The report as `{"artifact": ..., "findings": [{"rule", "level", "message", "va",
+    /// "attack"}]}`, with a null `va` for findings not at an address
+    pub fn to_json(&self) -> String {
+        let findings: Vec<String> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                format!(
+                    "    {{\"rule\": {}, \"level\": \"{}\", \"message\": {}, \"va\": {}, \
+                     \"attack\": {}}}",
+                    json_string(&finding.rule),
+                    finding.level,
+                    json_string(&finding.message),
+                    finding.va.map_or("null".to_string(), |va| va.to_string()),
+                    attack(finding)
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"artifact\": {},\n  \"findings\": [\n{}\n  ]\n}}\n",
+            json_string(&self.artifact),
+            findings.join(",\n")
+        )
+    }
+}
+
+/// The techniques of a finding as a JSON array
+fn attack(finding: &Finding) -> String {
+    let ids: Vec<String> = finding.attack.iter().map(|id| json_string(id)).collect();
+    format!("[{}]", ids.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deobfuscate::{Junk, OpaquePredicate};
+
+    #[test]
+    fn sarif_and_json() {
+        let mut report = Report::new("samples/packed \"1\".exe");
+        report.add_deobfuscation(&deobfuscate::Report {
+            opaque: vec![OpaquePredicate {
+                block: 0x1000,
+                va: 0x1008,
+                taken: true,
+                dead: 0x100a,
+            }],
+            junk: vec![Junk {
+                block: 0x1000,
+                insns: vec![0x1000, 0x1001],
+                kind: JunkKind::NoEffect,
+            }],
+            dead_blocks: vec![0x100a],
+        });
+        report.add(
+            Finding::new(
+                "entropy",
+                Level::Error,
+                "Section .text looks packed".to_string(),
+                None,
+            )
+            .with_attack(["Defense Evasion::Software Packing [T1027.002]", "not an id"]),
+        );
+        assert_eq!(report.findings.len(), 3);
+
+        let sarif = report.to_sarif();
+        assert!(sarif.contains("\"version\": \"2.1.0\""));
+        assert!(sarif.contains("{\"id\": \"entropy\", \"shortDescription\": {\"text\": \"\"}}"));
+        assert!(sarif.contains(
+            "\"ruleId\": \"opaque-predicate\", \"level\": \"warning\", \"message\": {\"text\": \
+             \"Branch is always taken; 0x100a is never reached through it\"}"
+        ));
+        assert!(sarif.contains("\"address\": {\"absoluteAddress\": 4104}"));
+        assert!(sarif.contains("\"uri\": \"samples/packed \\\"1\\\".exe\""));
+        assert_eq!(sarif.matches("\"ruleId\"").count(), 3);
+        assert!(sarif.contains("\"properties\": {\"attack\": [\"T1027.002\"]}"));
+        assert_eq!(sarif.matches("\"properties\"").count(), 1);
+
+        let json = report.to_json();
+        assert!(json.contains("\"rule\": \"junk-code\", \"level\": \"note\""));
+        assert!(json.contains("\"va\": null"));
+        assert!(json.contains("\"va\": 4096"));
+        assert!(json.contains("\"va\": null, \"attack\": [\"T1027.002\"]"));
+    }
+}
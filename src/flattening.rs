@@ -0,0 +1,433 @@
+//! Control flow flattening detection and recovery.
+//!
+//! A flattened function runs its original blocks as the cases of a loop around a dispatcher:
+//! the dispatcher compares a state variable against constants to pick the next case, and each
+//! case sets the state variable to the constant of its successor and jumps back, possibly
+//! through an empty pre-dispatcher block.
+//!
+//! [`detect`] finds the dispatcher of a lifted [`Function`], the state variable (a register or
+//! a stack slot), and which case each state value selects by running the dispatcher with the
+//! state variable set to that value, so compare chains and binary search trees work alike.
+//! Each block jumping back to the dispatcher is a [`Transition`] to the state it leaves behind.
+//! [`recover`] then points every transition whose state is constant straight at its case,
+//! which removes the dispatcher once all of them are resolved.
+
+use crate::{
+    symbolic::{Block, Expr, Function, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The fewest cases a dispatcher selects between for the function to count as flattened
+const MIN_CASES: usize = 3;
+
+/// A block jumping back to the dispatcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: u64,
+    /// The case the block belongs to; None for the blocks entering the dispatcher the first
+    /// time
+    pub case: Option<u64>,
+    /// The value the block leaves in the state variable, if it is constant
+    pub state: Option<u64>,
+    /// The case that value selects
+    pub to: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dispatcher {
+    pub header: u64,
+    /// The blocks of the dispatcher: the header, the compares it branches through and the
+    /// pre-dispatchers
+    pub blocks: BTreeSet<u64>,
+    /// The state variable, as of the start of the header
+    pub state: Expr,
+    /// The case each state value selects
+    pub cases: BTreeMap<u64, u64>,
+    pub transitions: Vec<Transition>,
+}
+
+impl Dispatcher {
+    /// The cases in the order they run, following the transitions depth first from the case
+    /// the function enters the dispatcher with
+    pub fn order(&self) -> Vec<u64> {
+        let mut order = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut todo: Vec<u64> = self
+            .transitions
+            .iter()
+            .filter(|t| t.case.is_none())
+            .filter_map(|t| t.to)
+            .collect();
+        todo.reverse();
+        while let Some(case) = todo.pop() {
+            if !seen.insert(case) {
+                continue;
+            }
+            order.push(case);
+            let next = self
+                .transitions
+                .iter()
+                .filter(|t| t.case == Some(case))
+                .filter_map(|t| t.to);
+            let next: Vec<u64> = next.collect();
+            todo.extend(next.into_iter().rev());
+        }
+        order
+    }
+
+    /// Whether every transition has a known target
+    pub fn is_resolved(&self) -> bool {
+        self.transitions.iter().all(|t| t.to.is_some())
+    }
+}
+
+/// The dispatchers of `func`, by header
+pub fn detect(func: &Function) -> Vec<Dispatcher> {
+    let preds = func.predecessors();
+    let states = func.block_states();
+    let mut dispatchers: Vec<Dispatcher> = Vec::new();
+    for header in func.reverse_postorder() {
+        if dispatchers.iter().any(|d| d.blocks.contains(&header)) {
+            continue;
+        }
+        if let Some(dispatcher) = dispatcher_at(func, &preds, &states, header) {
+            dispatchers.push(dispatcher);
+        }
+    }
+    dispatchers
+}
+
+/// The variable a block ending in a conditional branch compares against a constant
+fn compared(state: &State) -> Option<Expr> {
+    let (_, a, b) = state.flag_source()?;
+    match (a, b) {
+        (var @ (Expr::Reg(_) | Expr::Load(_)), Expr::Const(_))
+        | (Expr::Const(_), var @ (Expr::Reg(_) | Expr::Load(_))) => Some(var.clone()),
+        _ => None,
+    }
+}
+
+fn dispatcher_at(
+    func: &Function,
+    preds: &BTreeMap<u64, Vec<u64>>,
+    states: &BTreeMap<u64, State>,
+    header: u64,
+) -> Option<Dispatcher> {
+    let block = &func.blocks[&header];
+    let mut state = State::new(func.arch);
+    for insn in &block.insns {
+        state.exec_insn(insn);
+    }
+    if !matches!(block.end, Terminator::Branch { .. }) {
+        return None;
+    }
+    let var = compared(&state)?;
+
+    // The compares the header branches through, run from the state the header leaves
+    let mut blocks = BTreeSet::from([header]);
+    let mut todo: Vec<(u64, State)> = block
+        .end
+        .successors()
+        .into_iter()
+        .map(|succ| (succ, state.clone()))
+        .collect();
+    while let Some((va, mut state)) = todo.pop() {
+        let Some(block) = func.blocks.get(&va) else {
+            continue;
+        };
+        if blocks.contains(&va) || !matches!(block.end, Terminator::Branch { .. }) {
+            continue;
+        }
+        for insn in &block.insns {
+            state.exec_insn(insn);
+        }
+        if !compares_only(block) || compared(&state).as_ref() != Some(&var) {
+            continue;
+        }
+        blocks.insert(va);
+        for succ in block.end.successors() {
+            todo.push((succ, state.clone()));
+        }
+    }
+    // Empty blocks which only jump to the header
+    for pred in preds.get(&header).into_iter().flatten() {
+        let block = &func.blocks[pred];
+        if block.insns.is_empty() && block.end == Terminator::Jump(header) {
+            blocks.insert(*pred);
+        }
+    }
+
+    let mut cases = BTreeMap::new();
+    let mut transitions = Vec::new();
+    let entries: BTreeSet<u64> = blocks
+        .iter()
+        .copied()
+        .filter(|va| *va == header || func.blocks[va].end == Terminator::Jump(header))
+        .collect();
+    for entry in &entries {
+        for from in preds.get(entry).into_iter().flatten() {
+            if blocks.contains(from) {
+                continue;
+            }
+            let value = states.get(from).map(|state| state.eval(&var));
+            let value = value.and_then(|value| value.as_const());
+            let to = value.and_then(|value| select(func, &blocks, header, &var, value));
+            if let (Some(value), Some(to)) = (value, to) {
+                cases.insert(value, to);
+            }
+            transitions.push(Transition {
+                from: *from,
+                case: None,
+                state: value,
+                to,
+            });
+        }
+    }
+    let case_blocks: BTreeSet<u64> = cases.values().copied().collect();
+    if case_blocks.len() < MIN_CASES {
+        return None;
+    }
+    for transition in &mut transitions {
+        transition.case = owner(preds, &blocks, &case_blocks, transition.from);
+    }
+    Some(Dispatcher {
+        header,
+        blocks,
+        state: var,
+        cases,
+        transitions,
+    })
+}
+
+/// Whether a block does nothing but compute a compare: it sets the flags, and writes no
+/// memory and nothing unknown
+fn compares_only(block: &Block) -> bool {
+    let mut stmts = block.insns.iter().flat_map(|insn| &insn.stmts);
+    stmts.clone().any(|stmt| matches!(stmt, Stmt::Flags(..)))
+        && stmts.all(|stmt| matches!(stmt, Stmt::Set(..) | Stmt::Flags(..)))
+}
+
+/// The case the dispatcher selects when the state variable holds `value`
+fn select(
+    func: &Function,
+    blocks: &BTreeSet<u64>,
+    header: u64,
+    var: &Expr,
+    value: u64,
+) -> Option<u64> {
+    let mut state = State::new(func.arch);
+    match var {
+        Expr::Reg(reg) => state.exec(&Stmt::Set(*reg, Expr::Const(value))),
+        Expr::Load(addr) => state.exec(&Stmt::Store((**addr).clone(), Expr::Const(value))),
+        _ => return None,
+    }
+    let mut va = header;
+    for _ in 0..=blocks.len() {
+        if !blocks.contains(&va) {
+            return Some(va);
+        }
+        let block = func.blocks.get(&va)?;
+        for insn in &block.insns {
+            state.exec_insn(insn);
+        }
+        va = match block.end {
+            Terminator::Jump(to) => to,
+            Terminator::Branch {
+                cond,
+                taken,
+                fallthrough,
+            } => {
+                if state.condition(cond)? {
+                    taken
+                } else {
+                    fallthrough
+                }
+            }
+            _ => return None,
+        };
+    }
+    None
+}
+
+/// The case a block belongs to: the first case found walking back through single predecessors
+fn owner(
+    preds: &BTreeMap<u64, Vec<u64>>,
+    dispatcher: &BTreeSet<u64>,
+    cases: &BTreeSet<u64>,
+    from: u64,
+) -> Option<u64> {
+    let mut va = from;
+    let mut seen = BTreeSet::new();
+    while seen.insert(va) {
+        if cases.contains(&va) {
+            return Some(va);
+        }
+        match preds.get(&va).map(Vec::as_slice) {
+            Some([pred]) if !dispatcher.contains(pred) => va = *pred,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Point the resolved transitions of `dispatcher` straight at their cases, and drop the
+/// blocks which are no longer reachable. Returns whether the dispatcher is gone.
+pub fn recover(func: &mut Function, dispatcher: &Dispatcher) -> bool {
+    for transition in &dispatcher.transitions {
+        let (Some(to), Some(block)) = (transition.to, func.blocks.get_mut(&transition.from)) else {
+            continue;
+        };
+        let redirect = |va: &mut u64| {
+            if dispatcher.blocks.contains(va) {
+                *va = to;
+            }
+        };
+        match &mut block.end {
+            Terminator::Jump(target) => redirect(target),
+            Terminator::Branch {
+                taken, fallthrough, ..
+            } => {
+                redirect(taken);
+                redirect(fallthrough);
+            }
+            _ => {}
+        }
+    }
+    let live: BTreeSet<u64> = func.reverse_postorder().into_iter().collect();
+    func.blocks.retain(|va, _| live.contains(va));
+    !func.blocks.contains_key(&dispatcher.header)
+}
+
+/// Tag the function and comment the dispatcher and its cases in the workspace
+pub fn annotate(workspace: &mut VivWorkspace, func: &Function, dispatcher: &Dispatcher) {
+    let fva = func.entry as i32;
+    if workspace.is_function(fva) {
+        workspace.set_function_meta(fva, "Flattened", dispatcher.cases.len() as i32);
+    }
+    let comment = format!(
+        "control flow flattening dispatcher ({} cases)",
+        dispatcher.cases.len()
+    );
+    workspace.set_comment(dispatcher.header as i32, &comment, true);
+    for (value, case) in &dispatcher.cases {
+        let comment = format!("flattened case, state {:#x}", value);
+        workspace.set_comment(*case as i32, &comment, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envi::{flags::Condition, registers::RegisterModel, Arch};
+    use crate::symbolic::{BinOp, FlagOp, Insn};
+
+    #[test]
+    fn recover_flattened() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let (rbp, rax) = (model.by_name("rbp").unwrap(), model.by_name("rax").unwrap());
+        let slot = Expr::Binary(
+            BinOp::Add,
+            Box::new(Expr::Reg(rbp)),
+            Box::new(Expr::Const(0xffff_ffff_ffff_fff0)),
+        );
+        let set_state = |va, value| Insn {
+            va,
+            stmts: vec![Stmt::Store(slot.clone(), Expr::Const(value))],
+        };
+        let block = |va, insns, end| Block {
+            va,
+            insns,
+            end_va: va + 0xc,
+            end,
+        };
+        let mut func = Function::new(Arch::Amd64, 0);
+        func.add_block(block(0, vec![set_state(0, 0x10)], Terminator::Jump(0x100)));
+        // mov rax, [rbp-0x10]; cmp rax, 0x10; je case_a
+        func.add_block(block(
+            0x100,
+            vec![
+                Insn {
+                    va: 0x100,
+                    stmts: vec![Stmt::Set(rax, Expr::Load(Box::new(slot.clone())))],
+                },
+                Insn {
+                    va: 0x104,
+                    stmts: vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(rax), Expr::Const(0x10))],
+                },
+            ],
+            Terminator::Branch {
+                cond: Condition::Zero,
+                taken: 0x200,
+                fallthrough: 0x110,
+            },
+        ));
+        // cmp rax, 0x20; jne case_c
+        func.add_block(block(
+            0x110,
+            vec![Insn {
+                va: 0x110,
+                stmts: vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(rax), Expr::Const(0x20))],
+            }],
+            Terminator::Branch {
+                cond: Condition::NotZero,
+                taken: 0x400,
+                fallthrough: 0x300,
+            },
+        ));
+        func.add_block(block(0x1f0, vec![], Terminator::Jump(0x100)));
+        // a -> b, b -> c or a, c returns
+        func.add_block(block(
+            0x200,
+            vec![set_state(0x200, 0x20)],
+            Terminator::Jump(0x1f0),
+        ));
+        func.add_block(block(
+            0x300,
+            vec![],
+            Terminator::Branch {
+                cond: Condition::Carry,
+                taken: 0x310,
+                fallthrough: 0x320,
+            },
+        ));
+        func.add_block(block(
+            0x310,
+            vec![set_state(0x310, 0x30)],
+            Terminator::Jump(0x1f0),
+        ));
+        func.add_block(block(
+            0x320,
+            vec![set_state(0x320, 0x10)],
+            Terminator::Jump(0x1f0),
+        ));
+        func.add_block(block(0x400, vec![], Terminator::Return));
+
+        let dispatchers = detect(&func);
+        assert_eq!(dispatchers.len(), 1);
+        let dispatcher = &dispatchers[0];
+        assert_eq!(dispatcher.header, 0x100);
+        assert_eq!(dispatcher.state, Expr::Load(Box::new(slot.clone())));
+        assert_eq!(dispatcher.blocks, BTreeSet::from([0x100, 0x110, 0x1f0]));
+        assert_eq!(
+            dispatcher.cases,
+            BTreeMap::from([(0x10, 0x200), (0x20, 0x300), (0x30, 0x400)])
+        );
+        assert!(dispatcher.is_resolved());
+        let entry = dispatcher.transitions.iter().find(|t| t.from == 0).unwrap();
+        assert_eq!(entry.case, None);
+        let back = dispatcher
+            .transitions
+            .iter()
+            .find(|t| t.from == 0x320)
+            .unwrap();
+        assert_eq!(back.case, Some(0x300));
+        assert_eq!(back.to, Some(0x200));
+        assert_eq!(dispatcher.order(), [0x200, 0x300, 0x400]);
+
+        assert!(recover(&mut func, dispatcher));
+        assert_eq!(func.blocks[&0].end, Terminator::Jump(0x200));
+        assert_eq!(func.blocks[&0x310].end, Terminator::Jump(0x400));
+        assert!(!func.blocks.contains_key(&0x110));
+    }
+}
@@ -0,0 +1,561 @@
+//! Fuzzy hashes for grouping related samples.
+//!
+//! Two schemes, both over arbitrary bytes so they apply to whole files, sections and functions
+//! alike:
+//!
+//! * [`ssdeep`], context triggered piecewise hashing. The input is cut where a rolling hash over
+//!   a 7 byte window hits a trigger value, and each piece contributes one base64 character, so
+//!   an insertion only changes the characters around it. [`ssdeep_compare`] scores two digests
+//!   0 to 100 by the edit distance of their characters.
+//! * [`Tlsh`], the trend micro locality sensitive hash. Byte triplets of a 5 byte window are
+//!   counted into buckets, and the digest is each bucket's quartile plus a little header.
+//!   [`Tlsh::distance`] is 0 for identical inputs and grows as they differ, without an upper
+//!   bound; unlike ssdeep it stays meaningful for inputs with no pieces in common. TLSH needs at
+//!   least [`TLSH_MIN_LEN`] bytes of reasonably varied input.
+//!
+//! [`FuzzyHash`] carries both. [`match_functions`] pairs up the functions of two versions of a
+//! binary by similarity, and [`cluster`] groups samples which are transitively similar.
+//!
+//! Built with the `fuzzy` feature.
+
+use crate::{hashing::function_bytes, memory::Memory, workspace::VivWorkspace};
+use std::{fmt, str::FromStr};
+
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCKSIZE: u32 = 3;
+const SPAMSUM_LENGTH: usize = 64;
+const HASH_PRIME: u32 = 0x01000193;
+const HASH_INIT: u32 = 0x28021967;
+const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Default)]
+struct Roll {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl Roll {
+    fn update(&mut self, c: u8) {
+        let slot = self.n % ROLLING_WINDOW;
+        self.h2 = self
+            .h2
+            .wrapping_sub(self.h1)
+            .wrapping_add(ROLLING_WINDOW as u32 * c as u32);
+        self.h1 = self
+            .h1
+            .wrapping_add(c as u32)
+            .wrapping_sub(self.window[slot] as u32);
+        self.window[slot] = c;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ c as u32;
+    }
+
+    fn sum(&self) -> u32 {
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+fn sum_hash(c: u8, h: u32) -> u32 {
+    h.wrapping_mul(HASH_PRIME) ^ c as u32
+}
+
+/// The ssdeep digest of `data`, as `blocksize:hash:hash`
+pub fn ssdeep(data: &[u8]) -> String {
+    let mut block_size = MIN_BLOCKSIZE;
+    while (block_size as usize) * SPAMSUM_LENGTH < data.len() {
+        block_size *= 2;
+    }
+    loop {
+        let (mut sig1, mut sig2) = (String::new(), String::new());
+        let (mut h1, mut h2) = (HASH_INIT, HASH_INIT);
+        let mut roll = Roll::default();
+        for c in data.iter().copied() {
+            h1 = sum_hash(c, h1);
+            h2 = sum_hash(c, h2);
+            roll.update(c);
+            let trigger = roll.sum();
+            if trigger % block_size == block_size - 1 {
+                if sig1.len() < SPAMSUM_LENGTH - 1 {
+                    sig1.push(B64[(h1 % 64) as usize] as char);
+                    h1 = HASH_INIT;
+                }
+                if trigger % (block_size * 2) == block_size * 2 - 1
+                    && sig2.len() < SPAMSUM_LENGTH / 2 - 1
+                {
+                    sig2.push(B64[(h2 % 64) as usize] as char);
+                    h2 = HASH_INIT;
+                }
+            }
+        }
+        if roll.sum() != 0 {
+            sig1.push(B64[(h1 % 64) as usize] as char);
+            sig2.push(B64[(h2 % 64) as usize] as char);
+        }
+        if block_size > MIN_BLOCKSIZE && sig1.len() < SPAMSUM_LENGTH / 2 {
+            block_size /= 2;
+            continue;
+        }
+        return format!("{}:{}:{}", block_size, sig1, sig2);
+    }
+}
+
+/// A digest with runs of more than three of the same character cut to three, which carry no
+/// information but would dominate the edit distance
+fn eliminate_sequences(s: &str) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(s.len());
+    for c in s.bytes() {
+        if out.len() < 3 || out[out.len() - 3..].iter().any(|x| *x != c) {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn has_common_substring(a: &[u8], b: &[u8]) -> bool {
+    a.windows(ROLLING_WINDOW)
+        .any(|w| b.windows(ROLLING_WINDOW).any(|x| x == w))
+}
+
+/// Edit distance with insertions and deletions costing 1, substitutions 2
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replace = prev[j] + if ca == cb { 0 } else { 2 };
+            cur[j + 1] = replace.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+fn score_strings(a: &[u8], b: &[u8], block_size: u32) -> u32 {
+    if a.len() > SPAMSUM_LENGTH || b.len() > SPAMSUM_LENGTH || !has_common_substring(a, b) {
+        return 0;
+    }
+    let distance = edit_distance(a, b) as u32;
+    let score = distance * SPAMSUM_LENGTH as u32 / (a.len() + b.len()) as u32;
+    let score = score * 100 / SPAMSUM_LENGTH as u32;
+    if score >= 100 {
+        return 0;
+    }
+    let score = 100 - score;
+    // Short digests of small blocks match by chance; don't let them score high
+    let cap_below = (99 + ROLLING_WINDOW as u32) / ROLLING_WINDOW as u32 * MIN_BLOCKSIZE;
+    if block_size >= cap_below {
+        return score;
+    }
+    score.min(block_size / MIN_BLOCKSIZE * a.len().min(b.len()) as u32)
+}
+
+fn parse_ssdeep(digest: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut parts = digest.splitn(3, ':');
+    let block_size = parts.next()?.parse().ok()?;
+    let sig1 = eliminate_sequences(parts.next()?);
+    let sig2 = eliminate_sequences(parts.next()?.split(',').next()?);
+    Some((block_size, sig1, sig2))
+}
+
+/// How alike two ssdeep digests are, 0 (nothing in common, or not comparable) to 100
+pub fn ssdeep_compare(a: &str, b: &str) -> u32 {
+    let (Some((bs1, a1, a2)), Some((bs2, b1, b2))) = (parse_ssdeep(a), parse_ssdeep(b)) else {
+        return 0;
+    };
+    if bs1 == bs2 && a1 == b1 && a2 == b2 {
+        return 100;
+    }
+    if bs1 == bs2 {
+        score_strings(&a1, &b1, bs1).max(score_strings(&a2, &b2, bs1 * 2))
+    } else if bs1 == bs2 * 2 {
+        score_strings(&a1, &b2, bs1)
+    } else if bs2 == bs1 * 2 {
+        score_strings(&a2, &b1, bs2)
+    } else {
+        0
+    }
+}
+
+/// The Pearson permutation TLSH maps byte triplets to buckets with
+const V_TABLE: [u8; 256] = [
+    1, 87, 49, 12, 176, 178, 102, 166, 121, 193, 6, 84, 249, 230, 44, 163, 14, 197, 213, 181, 161,
+    85, 218, 80, 64, 239, 24, 226, 236, 142, 38, 200, 110, 177, 104, 103, 141, 253, 255, 50, 77,
+    101, 81, 18, 45, 96, 31, 222, 25, 107, 190, 70, 86, 237, 240, 34, 72, 242, 20, 214, 244, 227,
+    149, 235, 97, 234, 57, 22, 60, 250, 82, 175, 208, 5, 127, 199, 111, 62, 135, 248, 174, 169,
+    211, 58, 66, 154, 106, 195, 245, 171, 17, 187, 182, 179, 0, 243, 132, 56, 148, 75, 128, 133,
+    158, 100, 130, 126, 91, 13, 153, 246, 216, 219, 119, 68, 223, 78, 83, 88, 201, 99, 122, 11, 92,
+    32, 136, 114, 52, 10, 138, 30, 48, 183, 156, 35, 61, 26, 143, 74, 251, 94, 129, 162, 63, 152,
+    170, 7, 115, 167, 241, 206, 3, 150, 55, 59, 151, 220, 90, 53, 23, 131, 125, 173, 15, 238, 79,
+    95, 89, 16, 105, 137, 225, 224, 217, 160, 37, 123, 118, 73, 2, 157, 46, 116, 9, 145, 134, 228,
+    207, 212, 202, 215, 69, 229, 27, 188, 67, 124, 168, 252, 42, 4, 29, 108, 21, 247, 19, 205, 39,
+    203, 233, 40, 186, 147, 198, 192, 155, 33, 164, 191, 98, 204, 165, 180, 117, 76, 140, 36, 210,
+    172, 41, 54, 159, 8, 185, 232, 113, 196, 231, 47, 146, 120, 51, 65, 28, 144, 254, 221, 93, 189,
+    194, 139, 112, 43, 71, 109, 184, 209,
+];
+
+/// The shortest input TLSH hashes
+pub const TLSH_MIN_LEN: usize = 50;
+const TLSH_BUCKETS: usize = 128;
+const TLSH_CODE_SIZE: usize = 32;
+
+fn pearson(salt: u8, a: u8, b: u8, c: u8) -> u8 {
+    let mut h = V_TABLE[salt as usize];
+    for x in [a, b, c] {
+        h = V_TABLE[(h ^ x) as usize];
+    }
+    h
+}
+
+/// The length of the input, on a log scale, in a byte
+fn tlsh_length(len: usize) -> u8 {
+    let len = len as f64;
+    let l = if len <= 656.0 {
+        (len.ln() / 1.5f64.ln()).floor()
+    } else if len <= 3199.0 {
+        (len.ln() / 1.3f64.ln() - 8.72777).floor()
+    } else {
+        (len.ln() / 1.1f64.ln() - 62.5472).floor()
+    };
+    (l as u32 % 256) as u8
+}
+
+/// A TLSH digest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Tlsh {
+    pub checksum: u8,
+    pub length: u8,
+    pub q1_ratio: u8,
+    pub q2_ratio: u8,
+    /// Two bits per bucket, the first bucket in the low bits of the first byte
+    pub code: [u8; TLSH_CODE_SIZE],
+}
+
+fn swap_nibbles(b: u8) -> u8 {
+    b.rotate_left(4)
+}
+
+fn mod_diff(a: u8, b: u8, range: u32) -> u32 {
+    let d = (a as i32 - b as i32).unsigned_abs();
+    d.min(range - d)
+}
+
+impl Tlsh {
+    /// The TLSH of `data`, or None if it is too short or too uniform to hash
+    pub fn hash(data: &[u8]) -> Option<Tlsh> {
+        if data.len() < TLSH_MIN_LEN {
+            return None;
+        }
+        let mut buckets = [0u32; 256];
+        let mut checksum = 0u8;
+        for i in 4..data.len() {
+            let w = |back: usize| data[i - back];
+            checksum = pearson(0, w(0), w(1), checksum);
+            for (salt, x, y) in [
+                (2, 1, 2),
+                (3, 1, 3),
+                (5, 2, 3),
+                (7, 2, 4),
+                (11, 1, 4),
+                (13, 3, 4),
+            ] {
+                buckets[pearson(salt, w(0), w(x), w(y)) as usize] += 1;
+            }
+        }
+        let mut sorted = buckets[..TLSH_BUCKETS].to_vec();
+        sorted.sort_unstable();
+        let (q1, q2, q3) = (sorted[31], sorted[63], sorted[95]);
+        if q3 == 0 || buckets[..TLSH_BUCKETS].iter().filter(|c| **c > 0).count() <= 64 {
+            return None;
+        }
+        let mut code = [0u8; TLSH_CODE_SIZE];
+        for (i, byte) in code.iter_mut().enumerate() {
+            for j in 0..4 {
+                let count = buckets[4 * i + j];
+                let quartile = if q3 < count {
+                    3
+                } else if q2 < count {
+                    2
+                } else if q1 < count {
+                    1
+                } else {
+                    0
+                };
+                *byte |= quartile << (j * 2);
+            }
+        }
+        Some(Tlsh {
+            checksum,
+            length: tlsh_length(data.len()),
+            q1_ratio: ((q1 as u64 * 100 / q3 as u64) % 16) as u8,
+            q2_ratio: ((q2 as u64 * 100 / q3 as u64) % 16) as u8,
+            code,
+        })
+    }
+
+    /// How far apart two digests are: 0 for the same, up to a few hundred for unrelated inputs
+    pub fn distance(&self, other: &Tlsh) -> u32 {
+        let mut diff = (self.checksum != other.checksum) as u32;
+        diff += match mod_diff(self.length, other.length, 256) {
+            d @ 0..=1 => d,
+            d => d * 12,
+        };
+        for (a, b) in [
+            (self.q1_ratio, other.q1_ratio),
+            (self.q2_ratio, other.q2_ratio),
+        ] {
+            diff += match mod_diff(a, b, 16) {
+                d @ 0..=1 => d,
+                d => (d - 1) * 12,
+            };
+        }
+        for (a, b) in self.code.iter().zip(other.code.iter()) {
+            for j in 0..4 {
+                let d = ((a >> (j * 2)) & 3).abs_diff((b >> (j * 2)) & 3) as u32;
+                diff += if d == 3 { 6 } else { d };
+            }
+        }
+        diff
+    }
+}
+
+impl fmt::Display for Tlsh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "T1{:02X}{:02X}{:02X}",
+            swap_nibbles(self.checksum),
+            swap_nibbles(self.length),
+            self.q2_ratio << 4 | self.q1_ratio
+        )?;
+        for byte in self.code.iter().rev() {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Tlsh {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix("T1").unwrap_or(s);
+        if hex.len() != 6 + 2 * TLSH_CODE_SIZE || !hex.is_ascii() {
+            return Err(format!("Bad TLSH digest: {}", s));
+        }
+        let byte = |i: usize| {
+            u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("Bad TLSH digest: {}", s))
+        };
+        let mut code = [0u8; TLSH_CODE_SIZE];
+        for (i, b) in code.iter_mut().enumerate() {
+            *b = byte(3 + TLSH_CODE_SIZE - 1 - i)?;
+        }
+        let q = byte(2)?;
+        Ok(Tlsh {
+            checksum: swap_nibbles(byte(0)?),
+            length: swap_nibbles(byte(1)?),
+            q1_ratio: q & 0xf,
+            q2_ratio: q >> 4,
+            code,
+        })
+    }
+}
+
+/// Both fuzzy hashes of some bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzyHash {
+    pub ssdeep: String,
+    /// None for input TLSH can't hash
+    pub tlsh: Option<Tlsh>,
+}
+
+impl FuzzyHash {
+    pub fn new(data: &[u8]) -> Self {
+        FuzzyHash {
+            ssdeep: ssdeep(data),
+            tlsh: Tlsh::hash(data),
+        }
+    }
+
+    /// How alike two hashes are, 0 to 100. The ssdeep score, unless the inputs share no pieces
+    /// and both have a TLSH, in which case the TLSH distance scaled so 0 is 100 and 300 or more
+    /// is 0.
+    pub fn similarity(&self, other: &FuzzyHash) -> u32 {
+        let score = ssdeep_compare(&self.ssdeep, &other.ssdeep);
+        match (self.tlsh, other.tlsh) {
+            (Some(a), Some(b)) if score == 0 => 100u32.saturating_sub(a.distance(&b) / 3),
+            _ => score,
+        }
+    }
+}
+
+/// The fuzzy hashes of each segment of the workspace with mapped bytes, as (va, name, hash)
+pub fn section_hashes(workspace: &VivWorkspace) -> Vec<(i32, String, FuzzyHash)> {
+    workspace
+        .get_segments()
+        .into_iter()
+        .filter_map(|(va, size, name, _)| {
+            let bytes = workspace.read_memory(va, size)?;
+            Some((va, name, FuzzyHash::new(&bytes)))
+        })
+        .collect()
+}
+
+/// The fuzzy hashes of each function of the workspace with mapped bytes, by VA
+pub fn function_hashes(workspace: &VivWorkspace) -> Vec<(i32, FuzzyHash)> {
+    let mut fvas = workspace.get_functions();
+    fvas.sort();
+    fvas.into_iter()
+        .filter_map(|fva| Some((fva, FuzzyHash::new(&function_bytes(workspace, fva, true)?))))
+        .collect()
+}
+
+/// Pair the functions of an old and a new version of a binary, best matches first, each
+/// function at most once, as (old va, new va, similarity) for similarities of at least
+/// `threshold`
+pub fn match_functions(
+    old: &[(i32, FuzzyHash)],
+    new: &[(i32, FuzzyHash)],
+    threshold: u32,
+) -> Vec<(i32, i32, u32)> {
+    let mut candidates = Vec::new();
+    for (ova, ohash) in old.iter() {
+        for (nva, nhash) in new.iter() {
+            let score = ohash.similarity(nhash);
+            if score >= threshold {
+                candidates.push((*ova, *nva, score));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+    let (mut used_old, mut used_new) = (Vec::new(), Vec::new());
+    let mut matches = Vec::new();
+    for (ova, nva, score) in candidates {
+        if !used_old.contains(&ova) && !used_new.contains(&nva) {
+            used_old.push(ova);
+            used_new.push(nva);
+            matches.push((ova, nva, score));
+        }
+    }
+    matches
+}
+
+/// Group samples by single linkage: two samples are in a cluster if a chain of samples with
+/// pairwise similarity of at least `threshold` joins them. Clusters are lists of indexes into
+/// `samples`, the largest first.
+pub fn cluster(samples: &[FuzzyHash], threshold: u32) -> Vec<Vec<usize>> {
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut parent: Vec<usize> = (0..samples.len()).collect();
+    for i in 0..samples.len() {
+        for j in i + 1..samples.len() {
+            if samples[i].similarity(&samples[j]) >= threshold {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    let mut index_of = vec![usize::MAX; samples.len()];
+    for i in 0..samples.len() {
+        let r = root(&mut parent, i);
+        if index_of[r] == usize::MAX {
+            index_of[r] = clusters.len();
+            clusters.push(Vec::new());
+        }
+        clusters[index_of[r]].push(i);
+    }
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo random bytes, the same on every run
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn known_answers() {
+        // the examples of the python-ssdeep documentation, and the EICAR test file
+        let ctph = ssdeep(b"Also called fuzzy hashes, Ctph can match inputs that have homologies.");
+        let upper =
+            ssdeep(b"Also called fuzzy hashes, CTPH can match inputs that have homologies.");
+        assert_eq!(ctph, "3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C");
+        assert_eq!(upper, "3:AXGBicFlIHBGcL6wCrFQEv:AXGH6xLsr2C");
+        assert_eq!(ssdeep_compare(&ctph, &upper), 22);
+        let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+        assert_eq!(ssdeep(eicar), "3:a+JraNvsgzsVqSwHq9:tJuOgzsko");
+
+        // checked against a port of the reference implementation
+        let counting: Vec<u8> = (0..=255).chain(0..=255).collect();
+        assert_eq!(
+            Tlsh::hash(&counting).unwrap().to_string(),
+            "T118F05924E6514D7D1F175ADC904E44DF554FCDE302C5002517F186D1C510294440ED1D"
+        );
+        let stepped: Vec<u8> = (0..2000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        assert_eq!(
+            Tlsh::hash(&stepped).unwrap().to_string(),
+            "T15041017E00E72DF3D084A28C53E266B5FB705D06FFAC67D011A780BD8D94D9A042E126"
+        );
+    }
+
+    #[test]
+    fn fuzzy_hashes() {
+        assert_eq!(ssdeep(b""), "3::");
+        let a = noise(1, 4096);
+        let mut b = a.clone();
+        b[2000..2010].fill(0);
+        let c = noise(2, 4096);
+
+        let (ha, hb, hc) = (ssdeep(&a), ssdeep(&b), ssdeep(&c));
+        assert_eq!(ssdeep_compare(&ha, &ha), 100);
+        assert!(ssdeep_compare(&ha, &hb) > 50, "{} {}", ha, hb);
+        assert_eq!(ssdeep_compare(&ha, &hc), 0);
+        assert_eq!(ssdeep_compare(&ha, "garbage"), 0);
+
+        let (ta, tb, tc) = (
+            Tlsh::hash(&a).unwrap(),
+            Tlsh::hash(&b).unwrap(),
+            Tlsh::hash(&c).unwrap(),
+        );
+        assert_eq!(ta.distance(&ta), 0);
+        assert!(ta.distance(&tb) < ta.distance(&tc));
+        let text = ta.to_string();
+        assert_eq!(text.len(), 72);
+        assert_eq!(text.parse::<Tlsh>(), Ok(ta));
+        assert_eq!(Tlsh::hash(&[0u8; 1000]), None);
+        assert_eq!(Tlsh::hash(&a[..10]), None);
+
+        let samples = [a.as_slice(), &c, &b, &noise(3, 512)].map(FuzzyHash::new);
+        assert_eq!(cluster(&samples, 50), [vec![0, 2], vec![1], vec![3]]);
+        let old = vec![(0x1000, samples[0].clone()), (0x2000, samples[1].clone())];
+        let new = vec![(0x5000, samples[1].clone()), (0x6000, samples[2].clone())];
+        assert_eq!(
+            match_functions(&old, &new, 50),
+            [
+                (0x2000, 0x5000, 100),
+                (0x1000, 0x6000, samples[0].similarity(&samples[2]))
+            ]
+        );
+    }
+}
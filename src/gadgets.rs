@@ -0,0 +1,532 @@
+//! ROP and JOP gadgets: the short instruction sequences an exploit chains together, each ending
+//! in a return or an indirect jump or call.
+//!
+//! [`scan`] looks in code for the instructions which end a gadget, `ret`, `ret n`, and `jmp` or
+//! `call` through a register or memory, and decodes backwards from each. Every start up to
+//! `max_insns` instructions before the end which decodes, instruction by instruction and with
+//! no other branch, into a run landing exactly on it is a gadget. x86 decodes from any byte, so
+//! most gadgets start in the middle of the instructions the compiler wrote. Decoding is done by
+//! iced-x86, for i386 and amd64.
+//!
+//! Gadgets with the same instructions are one, at the lowest address, the others kept as its
+//! [`Gadget::duplicates`]. Each carries the [`Effect`]s an exploit picks gadgets by: popping a
+//! register, moving the stack pointer, writing memory. [`find`] (and
+//! `VivWorkspace::find_gadgets`) goes through the executable memory of a workspace.
+//!
+//! [`transfers`] and [`find_transfers`] list single instructions of interest along with them,
+//! by [`Transfer`]: the stack pivots (`xchg esp, eax`, `mov rsp, rbp`, `pop rsp`), and `leave`
+//! or a system call right before a `ret`. Each carries the function holding it, and whether
+//! it is an instruction of the code or is hidden at an offset within others.
+
+use crate::{envi::Arch, workspace::VivWorkspace};
+use iced_x86::{
+    Decoder, DecoderOptions, FlowControl, Formatter, Instruction, InstructionInfoFactory,
+    IntelFormatter, Mnemonic, OpAccess, OpKind, Register,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// The longest x86 instruction
+const MAX_INSN_BYTES: usize = 15;
+
+/// How a gadget hands on to the next
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum End {
+    /// `ret` or `ret n`, to the next address on the stack
+    Ret,
+    /// An indirect jump, for jump oriented programming
+    Jmp,
+    /// An indirect call
+    Call,
+}
+
+/// What a gadget does, for picking gadgets by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Effect {
+    /// Pops a register, loading it with a value off the stack
+    Pop,
+    /// Moves a register to another
+    Move,
+    /// Arithmetic or logic on a register
+    Arithmetic,
+    /// Reads memory other than the stack
+    Read,
+    /// Writes memory other than the stack
+    Write,
+    /// Moves the stack pointer other than by pushing, popping or a constant
+    StackPivot,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Effect::Pop => "pop",
+            Effect::Move => "move",
+            Effect::Arithmetic => "arithmetic",
+            Effect::Read => "read",
+            Effect::Write => "write",
+            Effect::StackPivot => "pivot",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gadget {
+    pub va: u64,
+    /// The instructions in Intel syntax, the one ending the gadget last
+    pub insns: Vec<String>,
+    pub end: End,
+    pub effects: BTreeSet<Effect>,
+    /// The other addresses of the same instructions
+    pub duplicates: Vec<u64>,
+}
+
+impl Gadget {
+    /// The instructions, `pop rdi ; ret`
+    pub fn text(&self) -> String {
+        self.insns.join(" ; ")
+    }
+}
+
+impl fmt::Display for Gadget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}: {}", self.va, self.text())
+    }
+}
+
+/// How the instruction ends a gadget, if it does
+fn end_of(insn: &Instruction) -> Option<End> {
+    match insn.flow_control() {
+        FlowControl::Return if insn.mnemonic() == Mnemonic::Ret => Some(End::Ret),
+        FlowControl::IndirectBranch => Some(End::Jmp),
+        FlowControl::IndirectCall => Some(End::Call),
+        _ => None,
+    }
+}
+
+fn is_sp(reg: Register) -> bool {
+    reg != Register::None && reg.full_register() == Register::RSP
+}
+
+fn bitness(arch: Arch) -> Option<u32> {
+    match arch {
+        Arch::I386 => Some(32),
+        Arch::Amd64 => Some(64),
+        _ => None,
+    }
+}
+
+/// The effects of an instruction of a gadget, other than the one ending it
+fn effects_of(
+    insn: &Instruction,
+    factory: &mut InstructionInfoFactory,
+    effects: &mut BTreeSet<Effect>,
+) {
+    let mnemonic = insn.mnemonic();
+    let info = factory.info(insn);
+    let stack_op = matches!(
+        mnemonic,
+        Mnemonic::Push
+            | Mnemonic::Pop
+            | Mnemonic::Pushfd
+            | Mnemonic::Pushfq
+            | Mnemonic::Popfd
+            | Mnemonic::Popfq
+    );
+    let moves_sp = info.used_registers().iter().any(|used| {
+        is_sp(used.register())
+            && matches!(
+                used.access(),
+                OpAccess::Write
+                    | OpAccess::CondWrite
+                    | OpAccess::ReadWrite
+                    | OpAccess::ReadCondWrite
+            )
+    });
+    let by_constant = insn.op_count() == 2 && insn.op1_kind() != OpKind::Register;
+    if moves_sp && !stack_op && !(by_constant && matches!(mnemonic, Mnemonic::Add | Mnemonic::Sub))
+    {
+        effects.insert(Effect::StackPivot);
+    }
+    for memory in info.used_memory() {
+        if is_sp(memory.base()) {
+            continue;
+        }
+        match memory.access() {
+            OpAccess::Read | OpAccess::CondRead => {
+                effects.insert(Effect::Read);
+            }
+            OpAccess::Write | OpAccess::CondWrite => {
+                effects.insert(Effect::Write);
+            }
+            OpAccess::ReadWrite | OpAccess::ReadCondWrite => {
+                effects.extend([Effect::Read, Effect::Write]);
+            }
+            _ => {}
+        }
+    }
+    let to_register = insn.op_count() > 0 && insn.op0_kind() == OpKind::Register;
+    match mnemonic {
+        Mnemonic::Pop if to_register => {
+            effects.insert(Effect::Pop);
+        }
+        Mnemonic::Mov | Mnemonic::Movzx | Mnemonic::Movsx | Mnemonic::Movsxd | Mnemonic::Xchg
+            if to_register && insn.op1_kind() == OpKind::Register =>
+        {
+            effects.insert(Effect::Move);
+        }
+        Mnemonic::Add
+        | Mnemonic::Sub
+        | Mnemonic::Adc
+        | Mnemonic::Sbb
+        | Mnemonic::And
+        | Mnemonic::Or
+        | Mnemonic::Xor
+        | Mnemonic::Inc
+        | Mnemonic::Dec
+        | Mnemonic::Neg
+        | Mnemonic::Not
+        | Mnemonic::Imul
+        | Mnemonic::Shl
+        | Mnemonic::Shr
+        | Mnemonic::Sar
+        | Mnemonic::Rol
+        | Mnemonic::Ror
+        | Mnemonic::Lea
+            if to_register =>
+        {
+            effects.insert(Effect::Arithmetic);
+        }
+        _ => {}
+    }
+}
+
+/// The gadgets of up to `max_insns` instructions before the end in the code `bytes` at `va`,
+/// duplicates included, in address order
+pub fn scan(arch: Arch, va: u64, bytes: &[u8], max_insns: usize) -> Vec<Gadget> {
+    let Some(bitness) = bitness(arch) else {
+        return Vec::new();
+    };
+    let decode = |from: usize, to: usize| {
+        Decoder::with_ip(
+            bitness,
+            &bytes[from..to],
+            va + from as u64,
+            DecoderOptions::NONE,
+        )
+    };
+    let mut formatter = IntelFormatter::new();
+    let mut factory = InstructionInfoFactory::new();
+    let mut gadgets = Vec::new();
+    for at in 0..bytes.len() {
+        let last = decode(at, bytes.len().min(at + MAX_INSN_BYTES)).decode();
+        let Some(end) = end_of(&last) else {
+            continue;
+        };
+        let stop = at + last.len();
+        for start in at.saturating_sub(max_insns * MAX_INSN_BYTES)..=at {
+            let mut decoder = decode(start, stop);
+            let mut insns = Vec::new();
+            let mut straight = true;
+            while start + decoder.position() < at && straight {
+                let insn = decoder.decode();
+                straight = insns.len() < max_insns
+                    && !insn.is_invalid()
+                    && insn.flow_control() == FlowControl::Next;
+                insns.push(insn);
+            }
+            if !straight || start + decoder.position() != at {
+                continue;
+            }
+            insns.push(last);
+            let mut effects = BTreeSet::new();
+            let mut text = Vec::new();
+            for (i, insn) in insns.iter().enumerate() {
+                if i + 1 < insns.len() {
+                    effects_of(insn, &mut factory, &mut effects);
+                }
+                let mut line = String::new();
+                formatter.format(insn, &mut line);
+                text.push(line);
+            }
+            gadgets.push(Gadget {
+                va: va + start as u64,
+                insns: text,
+                end,
+                effects,
+                duplicates: Vec::new(),
+            });
+        }
+    }
+    gadgets.sort_by_key(|gadget| gadget.va);
+    gadgets
+}
+
+/// Make gadgets with the same instructions one, at the lowest address
+pub fn dedup(gadgets: Vec<Gadget>) -> Vec<Gadget> {
+    let mut by_text: BTreeMap<String, Gadget> = BTreeMap::new();
+    for gadget in gadgets {
+        match by_text.get_mut(&gadget.text()) {
+            Some(first) if first.va < gadget.va => first.duplicates.push(gadget.va),
+            Some(first) => {
+                let later = std::mem::replace(first, gadget);
+                first.duplicates.push(later.va);
+                first.duplicates.extend(later.duplicates);
+                first.duplicates.sort_unstable();
+            }
+            None => {
+                by_text.insert(gadget.text(), gadget);
+            }
+        }
+    }
+    let mut gadgets: Vec<Gadget> = by_text.into_values().collect();
+    gadgets.sort_by_key(|gadget| gadget.va);
+    gadgets
+}
+
+/// The gadgets of the executable memory of `workspace`, duplicates made one
+pub fn find(workspace: &VivWorkspace, max_insns: usize) -> Vec<Gadget> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let mut gadgets = Vec::new();
+    for (va, bytes) in workspace.get_executable_maps() {
+        gadgets.extend(scan(arch, va as u32 as u64, bytes, max_insns));
+    }
+    dedup(gadgets)
+}
+
+/// The instructions of exploitation interest [`transfers`] picks out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Transfer {
+    /// `xchg` of the stack pointer with a register
+    XchgSp,
+    /// `mov` of a register or memory to the stack pointer
+    MovSp,
+    /// `pop` of the stack pointer
+    PopSp,
+    /// `add` or `sub` of a register to the stack pointer
+    AddSp,
+    /// `leave` right before a return
+    LeaveRet,
+    /// A system call right before a return
+    SyscallRet,
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transfer::XchgSp => "xchg-sp",
+            Transfer::MovSp => "mov-sp",
+            Transfer::PopSp => "pop-sp",
+            Transfer::AddSp => "add-sp",
+            Transfer::LeaveRet => "leave-ret",
+            Transfer::SyscallRet => "syscall-ret",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferSite {
+    pub va: u64,
+    pub transfer: Transfer,
+    /// The instructions in Intel syntax
+    pub insns: Vec<String>,
+    /// The function holding it, where [`find_transfers`] knows one
+    pub function: Option<u64>,
+    /// Whether it starts an instruction the code is made of, as far as [`find_transfers`]
+    /// knows; one which doesn't is hidden in the bytes of others
+    pub in_code: bool,
+}
+
+impl fmt::Display for TransferSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} [{}]",
+            self.va,
+            self.insns.join(" ; "),
+            self.transfer
+        )?;
+        if let Some(function) = self.function {
+            write!(f, " in {:#x}", function)?;
+        }
+        if !self.in_code {
+            write!(f, " (unaligned)")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the instruction is of interest as, if anything; `next` is the instruction after it
+fn transfer_of(insn: &Instruction, next: &Instruction) -> Option<Transfer> {
+    let sp_operand = |n: u32| insn.op_kind(n) == OpKind::Register && is_sp(insn.op_register(n));
+    let ret = next.mnemonic() == Mnemonic::Ret;
+    match insn.mnemonic() {
+        Mnemonic::Xchg if sp_operand(0) || sp_operand(1) => Some(Transfer::XchgSp),
+        Mnemonic::Mov if sp_operand(0) && insn.op1_kind() != OpKind::Immediate32 => {
+            Some(Transfer::MovSp)
+        }
+        Mnemonic::Pop if sp_operand(0) => Some(Transfer::PopSp),
+        Mnemonic::Add | Mnemonic::Sub if sp_operand(0) && insn.op1_kind() == OpKind::Register => {
+            Some(Transfer::AddSp)
+        }
+        Mnemonic::Leave if ret => Some(Transfer::LeaveRet),
+        Mnemonic::Syscall | Mnemonic::Sysenter if ret => Some(Transfer::SyscallRet),
+        Mnemonic::Int if ret && insn.immediate8() == 0x80 => Some(Transfer::SyscallRet),
+        _ => None,
+    }
+}
+
+/// The instructions of exploitation interest in the code `bytes` at `va`, at every offset, in
+/// address order. The sites aren't placed in code; see [`find_transfers`].
+pub fn transfers(arch: Arch, va: u64, bytes: &[u8]) -> Vec<TransferSite> {
+    let Some(bitness) = bitness(arch) else {
+        return Vec::new();
+    };
+    let mut formatter = IntelFormatter::new();
+    let mut sites = Vec::new();
+    for at in 0..bytes.len() {
+        let end = bytes.len().min(at + 2 * MAX_INSN_BYTES);
+        let mut decoder = Decoder::with_ip(
+            bitness,
+            &bytes[at..end],
+            va + at as u64,
+            DecoderOptions::NONE,
+        );
+        let insn = decoder.decode();
+        if insn.is_invalid() {
+            continue;
+        }
+        let next = decoder.decode();
+        let Some(transfer) = transfer_of(&insn, &next) else {
+            continue;
+        };
+        let mut insns = vec![insn];
+        if matches!(transfer, Transfer::LeaveRet | Transfer::SyscallRet) {
+            insns.push(next);
+        }
+        let insns = insns
+            .iter()
+            .map(|insn| {
+                let mut line = String::new();
+                formatter.format(insn, &mut line);
+                line
+            })
+            .collect();
+        sites.push(TransferSite {
+            va: va + at as u64,
+            transfer,
+            insns,
+            function: None,
+            in_code: true,
+        });
+    }
+    sites
+}
+
+/// The instructions of exploitation interest in the executable memory of `workspace`, with
+/// the functions holding them. A site is in code where the workspace has a location starting
+/// at it, or has no locations in that memory at all.
+pub fn find_transfers(workspace: &VivWorkspace) -> Vec<TransferSite> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let mut sites = Vec::new();
+    for (va, bytes) in workspace.get_executable_maps() {
+        let mapped =
+            (va..va.saturating_add(bytes.len() as i32)).any(|va| workspace.is_location(va));
+        for mut site in transfers(arch, va as u32 as u64, bytes) {
+            let at = site.va as i32;
+            site.function = workspace.get_function(at).map(|fva| fva as u32 as u64);
+            site.in_code = !mapped || workspace.get_location(at).is_some_and(|loc| loc.0 == at);
+            sites.push(site);
+        }
+    }
+    sites.sort_by_key(|site| site.va);
+    sites
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_AMD64, LOC_OP, MM_EXEC, MM_READ},
+        memory::Memory,
+    };
+
+    #[test]
+    fn gadgets() {
+        // add rsp, 8; pop rdi; ret; mov [rax], rbx; jmp rcx; xchg rsp, rax; ret; pop rdi; ret
+        let code = [
+            0x48, 0x83, 0xc4, 0x08, 0x5f, 0xc3, 0x48, 0x89, 0x18, 0xff, 0xe1, 0x48, 0x94, 0xc3,
+            0x5f, 0xc3,
+        ];
+        let gadgets = scan(Arch::Amd64, 0x1000, &code, 3);
+        let text = |va| {
+            gadgets
+                .iter()
+                .find(|gadget| gadget.va == va)
+                .map(Gadget::text)
+        };
+        assert_eq!(text(0x1000).as_deref(), Some("add rsp,8 ; pop rdi ; ret"));
+        assert_eq!(text(0x1004).as_deref(), Some("pop rdi ; ret"));
+        assert_eq!(text(0x1006).as_deref(), Some("mov [rax],rbx ; jmp rcx"));
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_AMD64 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code.to_vec(), None);
+        let found = ws.find_gadgets(3);
+        let pop = found.iter().find(|g| g.text() == "pop rdi ; ret").unwrap();
+        assert_eq!((pop.va, pop.duplicates.as_slice()), (0x1004, &[0x100e][..]));
+        assert_eq!(pop.effects, BTreeSet::from([Effect::Pop]));
+        let first = found.iter().find(|g| g.va == 0x1000).unwrap();
+        assert_eq!(
+            first.effects,
+            BTreeSet::from([Effect::Pop, Effect::Arithmetic])
+        );
+        let write = found.iter().find(|g| g.va == 0x1006).unwrap();
+        assert_eq!(
+            (write.end, &write.effects),
+            (End::Jmp, &BTreeSet::from([Effect::Write]))
+        );
+        let pivot = found.iter().find(|g| g.va == 0x100b).unwrap();
+        assert_eq!(pivot.text(), "xchg rsp,rax ; ret");
+        assert!(pivot.effects.contains(&Effect::StackPivot));
+    }
+
+    #[test]
+    fn transfers_of_interest() {
+        // mov rsp, rbp; leave; ret; syscall; ret; mov eax, 0xc3c9 (hides leave; ret); pop rsp
+        let code = [
+            0x48, 0x89, 0xec, 0xc9, 0xc3, 0x0f, 0x05, 0xc3, 0xb8, 0xc9, 0xc3, 0x00, 0x00, 0x5c,
+        ];
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_AMD64 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code.to_vec(), None);
+        for (va, size) in [
+            (0x1000, 3),
+            (0x1003, 1),
+            (0x1004, 1),
+            (0x1005, 2),
+            (0x1008, 5),
+        ] {
+            ws.add_location(va, size, LOC_OP, Some(vec![]));
+        }
+        ws.add_function(0x1000, vec![(0x1000, 13)]);
+        let found: Vec<String> = ws.find_transfers().iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            found,
+            [
+                "0x1000: mov rsp,rbp [mov-sp] in 0x1000",
+                "0x1001: mov esp,ebp [mov-sp] in 0x1000 (unaligned)",
+                "0x1003: leave ; ret [leave-ret] in 0x1000",
+                "0x1005: syscall ; ret [syscall-ret] in 0x1000",
+                "0x1009: leave ; ret [leave-ret] in 0x1000 (unaligned)",
+                "0x100d: pop rsp [pop-sp] (unaligned)",
+            ]
+        );
+    }
+}
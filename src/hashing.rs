@@ -0,0 +1,404 @@
+//! Function hashes for allowlisting known code.
+//!
+//! A function is hashed at three levels, each looser than the last:
+//!
+//! * [`HashLevel::Bytes`], the SHA-256 of its bytes, chunks in VA order. Matches only the same
+//!   code at the same address.
+//! * [`HashLevel::Masked`], the same with the bytes a relocation patches zeroed, so the code
+//!   matches wherever the loader puts it.
+//! * [`HashLevel::Semantic`], over the operations of its lifted [`Function`] with every operand
+//!   dropped, blocks in reverse postorder. Registers, constants and addresses don't count, only
+//!   what each instruction does, so the code matches across relinks and register allocation.
+//!   This is the mnemonic-only hash of a disassembler, on the IL.
+//!
+//! An [`Allowlist`] collects the hashes of known good functions, saved one `level hex` per line;
+//! [`unknown`] lists the functions of a workspace matching none of them, the code to look at in
+//! a firmware update.
+
+use crate::{
+    memory::Memory,
+    metrics::ranges,
+    symbolic::{Expr, Function, Stmt, Terminator},
+    utils::{hex, sha256},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+pub type Digest = [u8; 32];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HashLevel {
+    Bytes,
+    Masked,
+    Semantic,
+}
+
+impl fmt::Display for HashLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashLevel::Bytes => "bytes",
+            HashLevel::Masked => "masked",
+            HashLevel::Semantic => "semantic",
+        })
+    }
+}
+
+impl FromStr for HashLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(HashLevel::Bytes),
+            "masked" => Ok(HashLevel::Masked),
+            "semantic" => Ok(HashLevel::Semantic),
+            _ => Err(format!("Unknown hash level: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionHashes {
+    pub va: i32,
+    pub bytes: Digest,
+    pub masked: Digest,
+    /// Only with a lifted function, see [`FunctionHashes::with_cfg`]
+    pub semantic: Option<Digest>,
+}
+
+impl FunctionHashes {
+    pub fn with_cfg(mut self, func: &Function) -> Self {
+        self.semantic = Some(semantic_hash(func));
+        self
+    }
+
+    pub fn get(&self, level: HashLevel) -> Option<Digest> {
+        match level {
+            HashLevel::Bytes => Some(self.bytes),
+            HashLevel::Masked => Some(self.masked),
+            HashLevel::Semantic => self.semantic,
+        }
+    }
+}
+
+/// The most bytes a single relocation patches
+const MAX_RELOCATION: i32 = 8;
+
+/// Which of the `size` bytes at `va` a relocation patches
+pub fn relocated_bytes(workspace: &VivWorkspace, va: i32, size: i32) -> Vec<bool> {
+    let psize = match workspace.get_pointer_size() {
+        0 => 4,
+        size => size,
+    };
+    let mut patched = vec![false; size.max(0) as usize];
+    // a relocation starting before the range may reach into it
+    for rva in va - MAX_RELOCATION + 1..va + size {
+        let rsize = match workspace.get_relocation_size(rva) {
+            Some(0) => psize,
+            Some(rsize) => rsize,
+            None => continue,
+        };
+        let start = (rva - va).max(0) as usize;
+        let end = ((rva + rsize - va).max(0) as usize).min(patched.len());
+        if start < end {
+            patched[start..end].fill(true);
+        }
+    }
+    patched
+}
+
+/// The bytes of a function, chunks in VA order, with the bytes relocations patch zeroed if
+/// `masked`. None if any of them isn't mapped.
+pub fn function_bytes(workspace: &VivWorkspace, fva: i32, masked: bool) -> Option<Vec<u8>> {
+    let mut chunks = ranges(workspace, fva);
+    chunks.sort();
+    let mut out = Vec::new();
+    for (va, size) in chunks {
+        let mut bytes = workspace.read_memory(va, size)?;
+        if bytes.len() != size as usize {
+            return None;
+        }
+        if masked {
+            let patched = relocated_bytes(workspace, va, size);
+            for (byte, patched) in bytes.iter_mut().zip(patched) {
+                if patched {
+                    *byte = 0;
+                }
+            }
+        }
+        out.extend(bytes);
+    }
+    Some(out)
+}
+
+/// The byte and masked hashes of a function, or None if its bytes aren't all mapped
+pub fn hash_function(workspace: &VivWorkspace, fva: i32) -> Option<FunctionHashes> {
+    Some(FunctionHashes {
+        va: fva,
+        bytes: sha256(&function_bytes(workspace, fva, false)?),
+        masked: sha256(&function_bytes(workspace, fva, true)?),
+        semantic: None,
+    })
+}
+
+/// Hash every function of the workspace with readable bytes, by VA
+pub fn hash_all(workspace: &VivWorkspace) -> Vec<FunctionHashes> {
+    let mut fvas = workspace.get_functions();
+    fvas.sort();
+    fvas.into_iter()
+        .filter_map(|fva| hash_function(workspace, fva))
+        .collect()
+}
+
+fn expr_shape(expr: &Expr, out: &mut String) {
+    match expr {
+        Expr::Const(_) => out.push('c'),
+        Expr::Reg(_) => out.push('r'),
+        Expr::Load(addr) => {
+            out.push_str("l(");
+            expr_shape(addr, out);
+            out.push(')');
+        }
+        Expr::Unary(op, e) => {
+            out.push_str(&format!("{:?}(", op));
+            expr_shape(e, out);
+            out.push(')');
+        }
+        Expr::Binary(op, a, b) => {
+            out.push_str(&format!("{:?}(", op));
+            expr_shape(a, out);
+            out.push(',');
+            expr_shape(b, out);
+            out.push(')');
+        }
+    }
+}
+
+/// The hash of what the instructions of a lifted function do, without their operands
+pub fn semantic_hash(func: &Function) -> Digest {
+    let mut shape = String::new();
+    for va in func.reverse_postorder() {
+        let block = &func.blocks[&va];
+        for insn in block.insns.iter() {
+            for stmt in insn.stmts.iter() {
+                match stmt {
+                    Stmt::Set(_, e) => {
+                        shape.push_str("set ");
+                        expr_shape(e, &mut shape);
+                    }
+                    Stmt::Store(addr, value) => {
+                        shape.push_str("store ");
+                        expr_shape(addr, &mut shape);
+                        shape.push(' ');
+                        expr_shape(value, &mut shape);
+                    }
+                    Stmt::Flags(op, a, b) => {
+                        shape.push_str(&format!("flags {:?} ", op));
+                        expr_shape(a, &mut shape);
+                        shape.push(' ');
+                        expr_shape(b, &mut shape);
+                    }
+                    Stmt::Unknown => shape.push('?'),
+                }
+                shape.push(';');
+            }
+            shape.push('\n');
+        }
+        match &block.end {
+            Terminator::Jump(_) => shape.push_str("jump"),
+            Terminator::Branch { cond, .. } => shape.push_str(&format!("branch {:?}", cond)),
+            Terminator::Indirect(e) => {
+                shape.push_str("indirect ");
+                expr_shape(e, &mut shape);
+            }
+            Terminator::Return => shape.push_str("return"),
+            Terminator::TailCall(_) => shape.push_str("tailcall"),
+        }
+        shape.push_str("\n\n");
+    }
+    sha256(shape.as_bytes())
+}
+
+/// Hashes of known good functions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    hashes: BTreeSet<(HashLevel, Digest)>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Allowlist::default()
+    }
+
+    pub fn insert(&mut self, level: HashLevel, digest: Digest) {
+        self.hashes.insert((level, digest));
+    }
+
+    /// Allow a function at each of the levels in `levels` it has a hash for
+    pub fn add(&mut self, hashes: &FunctionHashes, levels: &[HashLevel]) {
+        for level in levels.iter() {
+            if let Some(digest) = hashes.get(*level) {
+                self.insert(*level, digest);
+            }
+        }
+    }
+
+    /// The strictest level a function is allowed at, if any
+    pub fn matches(&self, hashes: &FunctionHashes) -> Option<HashLevel> {
+        [HashLevel::Bytes, HashLevel::Masked, HashLevel::Semantic]
+            .into_iter()
+            .find(|level| {
+                hashes
+                    .get(*level)
+                    .is_some_and(|digest| self.hashes.contains(&(*level, digest)))
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (level, digest) in self.hashes.iter() {
+            writeln!(out, "{} {}", level, hex(digest))?;
+        }
+        Ok(())
+    }
+
+    /// Read an allowlist, skipping blank lines and `#` comments
+    pub fn read<R: BufRead>(input: R) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Bad allowlist line: {}", line),
+            )
+        };
+        let mut allowlist = Allowlist::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (level, digest) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+            let level: HashLevel = level.parse().map_err(|_| invalid(line))?;
+            let digest = digest.trim();
+            if digest.len() != 64 || !digest.is_ascii() {
+                return Err(invalid(line));
+            }
+            let mut bytes = [0u8; 32];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte =
+                    u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16).map_err(|_| invalid(line))?;
+            }
+            allowlist.insert(level, bytes);
+        }
+        Ok(allowlist)
+    }
+}
+
+/// The functions of the workspace not on the allowlist at any level, by VA. Functions whose
+/// bytes aren't mapped can't be checked and are left out.
+pub fn unknown(workspace: &VivWorkspace, allowlist: &Allowlist) -> Vec<i32> {
+    hash_all(workspace)
+        .into_iter()
+        .filter(|hashes| allowlist.matches(hashes).is_none())
+        .map(|hashes| hashes.va)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ},
+        envi::{registers::RegisterModel, Arch},
+        storage::Annotations,
+        symbolic::{BinOp, Block, Insn},
+    };
+
+    #[test]
+    fn function_hashes() {
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // the same code twice, calling through different absolute pointers
+        let mut code = vec![0x55, 0xff, 0x15, 0x00, 0x30, 0x00, 0x00, 0x5d, 0xc3];
+        code.resize(0x10, 0xcc);
+        code.extend([0x55, 0xff, 0x15, 0x08, 0x30, 0x00, 0x00, 0x5d, 0xc3]);
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x1000, 9), (0x1010, 9)]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.set_function_bounds(0x1000, vec![(0x1000, 9)]);
+        ws.set_function_bounds(0x1010, vec![(0x1010, 9)]);
+        // relocations are recorded against the file a map was loaded from
+        let fname = ws.add_file("Cargo.toml", 0, vec![]);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, &fname, code, None);
+        ws.add_relocation(0x1003, 0, Some(vec![]), None);
+        ws.add_relocation(0x1013, 0, Some(vec![]), None);
+
+        let a = hash_function(&ws, 0x1000).unwrap();
+        let b = hash_function(&ws, 0x1010).unwrap();
+        assert_ne!(a.bytes, b.bytes);
+        assert_eq!(a.masked, b.masked);
+        assert_eq!(
+            function_bytes(&ws, 0x1000, true).unwrap(),
+            [0x55, 0xff, 0x15, 0, 0, 0, 0, 0x5d, 0xc3]
+        );
+
+        // the lifted hash ignores registers and constants but not operations
+        let regs = RegisterModel::new(Arch::Amd64);
+        let (rax, rcx) = (regs.by_name("rax").unwrap(), regs.by_name("rcx").unwrap());
+        let lifted = |entry: u64, reg, value, op| {
+            let mut func = Function::new(Arch::Amd64, entry);
+            func.add_block(Block {
+                va: entry,
+                insns: vec![Insn {
+                    va: entry,
+                    stmts: vec![Stmt::Set(
+                        reg,
+                        Expr::Binary(op, Box::new(Expr::Reg(reg)), Box::new(Expr::Const(value))),
+                    )],
+                }],
+                end_va: entry + 4,
+                end: Terminator::Return,
+            });
+            func
+        };
+        let a = a.with_cfg(&lifted(0x1000, rax, 1, BinOp::Add));
+        let b = b.with_cfg(&lifted(0x1010, rcx, 2, BinOp::Add));
+        assert_eq!(a.semantic, b.semantic);
+        assert_ne!(
+            a.semantic,
+            Some(semantic_hash(&lifted(0x1000, rax, 1, BinOp::Sub)))
+        );
+
+        let mut allowlist = Allowlist::new();
+        allowlist.add(&a, &[HashLevel::Bytes]);
+        assert_eq!(allowlist.matches(&a), Some(HashLevel::Bytes));
+        assert_eq!(allowlist.matches(&b), None);
+        assert_eq!(unknown(&ws, &allowlist), [0x1010]);
+        allowlist.add(&a, &[HashLevel::Masked]);
+        assert_eq!(allowlist.matches(&b), Some(HashLevel::Masked));
+        assert!(unknown(&ws, &allowlist).is_empty());
+
+        let mut saved = Vec::new();
+        allowlist.write(&mut saved).unwrap();
+        let text = String::from_utf8(saved).unwrap();
+        assert!(text.starts_with("bytes "));
+        let loaded = Allowlist::read(format!("# known good\n\n{}", text).as_bytes()).unwrap();
+        assert_eq!(loaded, allowlist);
+        assert!(Allowlist::read("masked 00".as_bytes()).is_err());
+    }
+}
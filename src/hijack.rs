@@ -0,0 +1,567 @@
+//! Where the dynamic loader can be made to load someone else's code into a binary.
+//!
+//! [`audit`] reports the imports of a binary another library can take over, and how:
+//!
+//! * a library preloaded with `LD_PRELOAD`, or inserted with `DYLD_INSERT_LIBRARIES`, is looked
+//!   in before the others, so it defines every import of an ELF binary and every flat
+//!   namespace import of a Mach-O one. A two-level namespace import names its dylib, and an
+//!   inserted library only takes it over by interposing it (see [`crate::dyld`]). Neither
+//!   works for a setuid binary, nor on macOS for a restricted or hardened one;
+//! * a library planted in a directory searched ahead of the one holding the real library, or
+//!   when none does, in any directory searched: an `RPATH`, `RUNPATH` or `LC_RPATH` entry
+//!   others can write to or create, or one relative to the working directory; for an
+//!   `@rpath/` dylib, rpaths none of which hold it (the app doesn't embed it); for a DLL, an
+//!   application directory others can write to, since it's searched before the system one;
+//! * a DLL the application directory and the system directory don't have, which Windows goes
+//!   on to look for in the working directory and along `PATH`.
+//!
+//! Directories are looked at on this machine: the binary's own, those of its search paths, and
+//! those of the [`Environment`] given. A directory others can write to is one group or world
+//! writable, or which doesn't exist and whose nearest existing parent is.
+
+use crate::{
+    bundle::{entitlements, signature_blob},
+    dyld::{DyldImage, Lookup},
+    elf::Elf,
+    error,
+    mach::Mach,
+    Object,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// The DLLs Windows only ever loads from the system directory (the `KnownDLLs` key), and
+/// `ntdll`, which is always loaded
+pub const KNOWN_DLLS: &[&str] = &[
+    "advapi32.dll",
+    "clbcatq.dll",
+    "combase.dll",
+    "comdlg32.dll",
+    "coml2.dll",
+    "difxapi.dll",
+    "gdi32.dll",
+    "gdiplus.dll",
+    "imagehlp.dll",
+    "imm32.dll",
+    "kernel32.dll",
+    "msctf.dll",
+    "msvcrt.dll",
+    "normaliz.dll",
+    "nsi.dll",
+    "ntdll.dll",
+    "ole32.dll",
+    "oleaut32.dll",
+    "psapi.dll",
+    "rpcrt4.dll",
+    "sechost.dll",
+    "setupapi.dll",
+    "shcore.dll",
+    "shell32.dll",
+    "shlwapi.dll",
+    "user32.dll",
+    "wldap32.dll",
+    "wow64.dll",
+    "wow64win.dll",
+    "ws2_32.dll",
+];
+
+/// The code signature blob of the code directory, and its flags
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CS_RESTRICT: u32 = 0x800;
+const CS_REQUIRE_LV: u32 = 0x2000;
+const CS_RUNTIME: u32 = 0x10000;
+
+/// The system around the binary
+#[derive(Clone, Debug)]
+pub struct Environment {
+    /// The directories of `PATH`, where Windows looks for a DLL last
+    pub path: Vec<PathBuf>,
+    /// The Windows system directory, to tell the system DLLs from missing ones. Without it, no
+    /// DLL counts as missing.
+    pub system_dir: Option<PathBuf>,
+}
+
+impl Default for Environment {
+    /// The `PATH` of this process
+    fn default() -> Self {
+        Environment {
+            path: std::env::var_os("PATH")
+                .map(|path| std::env::split_paths(&path).collect())
+                .unwrap_or_default(),
+            system_dir: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Vector {
+    /// `LD_PRELOAD` or `DYLD_INSERT_LIBRARIES` defines the imports
+    Preload,
+    /// `DYLD_INSERT_LIBRARIES` interposes the imports
+    Interpose,
+    /// A directory searched for `library` ahead of where it is, which others can write to
+    WritableSearchPath { library: String, dir: PathBuf },
+    /// A search path relative to the working directory, which whoever starts the binary picks
+    RelativeSearchPath { library: String, dir: PathBuf },
+    /// An `@rpath/` dylib none of the rpaths hold
+    UnresolvedRpath { library: String },
+    /// A DLL neither the application nor the system directory has, looked for along `PATH`
+    MissingDll { library: String },
+}
+
+impl fmt::Display for Vector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Vector::Preload => f.write_str("preloading a library"),
+            Vector::Interpose => f.write_str("interposing from an inserted library"),
+            Vector::WritableSearchPath { library, dir } => {
+                write!(f, "planting {} in {}", library, dir.display())
+            }
+            Vector::RelativeSearchPath { library, dir } => write!(
+                f,
+                "planting {} in {} under the working directory",
+                library,
+                dir.display()
+            ),
+            Vector::UnresolvedRpath { library } => {
+                write!(f, "planting {} in an rpath, none of which has it", library)
+            }
+            Vector::MissingDll { library } => {
+                write!(f, "planting {} along PATH, since it's missing", library)
+            }
+        }
+    }
+}
+
+/// The imports a vector takes over
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exposure {
+    pub vector: Vector,
+    pub symbols: Vec<String>,
+}
+
+impl fmt::Display for Exposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.vector, self.symbols.join(", "))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Audit {
+    /// Why the loader ignores `LD_PRELOAD` or `DYLD_INSERT_LIBRARIES` for the binary, if it does
+    pub preload_blocked: Option<&'static str>,
+    /// Why the loader only loads dylibs signed by the binary's team, if it does, which keeps
+    /// planted ones out
+    pub library_validation: Option<&'static str>,
+    pub exposures: Vec<Exposure>,
+}
+
+impl fmt::Display for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(why) = self.preload_blocked {
+            writeln!(f, "preloading is ignored: {}", why)?;
+        }
+        if let Some(why) = self.library_validation {
+            writeln!(f, "planted libraries don't load: {}", why)?;
+        }
+        for exposure in self.exposures.iter() {
+            writeln!(f, "{}", exposure)?;
+        }
+        Ok(())
+    }
+}
+
+/// Audit the binary at `path`, whose contents are `bytes`
+pub fn audit(path: &Path, bytes: &[u8], env: &Environment) -> error::Result<Audit> {
+    let dir = path
+        .parent()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => dir.to_path_buf(),
+        })
+        .unwrap_or_default();
+    let setuid = is_setuid(path);
+    match Object::parse(bytes)? {
+        Object::Elf(elf) => Ok(audit_elf(&elf, &dir, setuid)),
+        Object::PE(pe) => Ok(audit_pe(&pe, &dir, env)),
+        Object::Mach(Mach::Binary(macho)) => audit_macho(path, &macho, bytes, &dir, setuid),
+        Object::Mach(Mach::Fat(fat)) => {
+            let arch = fat.arches()?.into_iter().next().ok_or_else(|| {
+                error::Error::Malformed("A fat binary without architectures".to_string())
+            })?;
+            let bytes = arch.slice(bytes);
+            let macho = crate::mach::MachO::parse(bytes, 0)?;
+            audit_macho(path, &macho, bytes, &dir, setuid)
+        }
+        _ => Ok(Audit::default()),
+    }
+}
+
+fn audit_elf(elf: &Elf, dir: &Path, setuid: bool) -> Audit {
+    let mut audit = Audit::default();
+    if elf.interpreter.is_none() && elf.libraries.is_empty() {
+        return audit;
+    }
+    // the library each import is versioned with, where versioning says
+    let mut versions = BTreeMap::new();
+    for need in elf.verneed.iter().flat_map(|verneed| verneed.iter()) {
+        let Some(file) = elf.dynstrtab.get_at(need.vn_file) else {
+            continue;
+        };
+        for aux in need.iter() {
+            versions.insert(aux.vna_other, file);
+        }
+    }
+    let mut imports: Vec<(String, Option<&str>)> = Vec::new();
+    for (idx, sym) in elf.dynsyms.iter().enumerate() {
+        let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or("");
+        if name.is_empty() || sym.st_shndx != 0 || !sym.is_import() {
+            continue;
+        }
+        let library = elf
+            .versym
+            .as_ref()
+            .and_then(|versym| versym.get_at(idx))
+            .and_then(|versym| versions.get(&versym.version()).copied());
+        imports.push((name.to_string(), library));
+    }
+    let symbols = |library: &str| -> Vec<String> {
+        let versioned: Vec<String> = imports
+            .iter()
+            .filter(|(_, lib)| *lib == Some(library))
+            .map(|(name, _)| name.clone())
+            .collect();
+        match versioned.is_empty() {
+            // unversioned, any of the imports may come from the library
+            true => imports.iter().map(|(name, _)| name.clone()).collect(),
+            false => versioned,
+        }
+    };
+
+    match setuid {
+        true => audit.preload_blocked = Some("setuid, which runs the loader in secure mode"),
+        false if !imports.is_empty() => audit.exposures.push(Exposure {
+            vector: Vector::Preload,
+            symbols: imports.iter().map(|(name, _)| name.clone()).collect(),
+        }),
+        false => {}
+    }
+    // RPATH is ignored when there's a RUNPATH
+    let search = match elf.runpaths.is_empty() {
+        true => &elf.rpaths,
+        false => &elf.runpaths,
+    };
+    let search: Vec<PathBuf> = search
+        .iter()
+        .flat_map(|paths| paths.split(':'))
+        .map(|entry| PathBuf::from(entry.replace("${ORIGIN}", "$ORIGIN")))
+        .map(|entry| match entry.strip_prefix("$ORIGIN") {
+            Ok(rest) => dir.join(rest),
+            Err(_) => entry,
+        })
+        .collect();
+    for library in elf.libraries.iter().filter(|lib| !lib.contains('/')) {
+        planted(&mut audit, library, &search, symbols(library), |dir| {
+            dir.join(library).exists()
+        });
+    }
+    audit
+}
+
+fn audit_macho(
+    path: &Path,
+    macho: &crate::mach::MachO,
+    bytes: &[u8],
+    dir: &Path,
+    setuid: bool,
+) -> error::Result<Audit> {
+    let mut audit = Audit::default();
+    let image = DyldImage::from_macho(&path.to_string_lossy(), macho)?;
+    let flags = signature_blob(macho, bytes, CSMAGIC_CODEDIRECTORY)
+        .and_then(|blob| Some(u32::from_be_bytes(blob.get(12..16)?.try_into().ok()?)))
+        .unwrap_or(0);
+    let entitled = |key: &str| {
+        entitlements(macho, bytes)
+            .and_then(|plist| plist.get(key).and_then(|value| value.as_bool()))
+            .unwrap_or(false)
+    };
+    audit.preload_blocked = if setuid {
+        Some("setuid")
+    } else if macho.section_data("__RESTRICT", "__restrict").is_some() {
+        Some("a __RESTRICT,__restrict section")
+    } else if flags & CS_RESTRICT != 0 {
+        Some("signed restricted")
+    } else if flags & CS_RUNTIME != 0 && !entitled("com.apple.security.cs.allow-dyld-environment-variables")
+    {
+        Some("the hardened runtime, without allow-dyld-environment-variables")
+    } else {
+        None
+    };
+    audit.library_validation = if flags & CS_REQUIRE_LV != 0 {
+        Some("signed requiring library validation")
+    } else if flags & CS_RUNTIME != 0 && !entitled("com.apple.security.cs.disable-library-validation")
+    {
+        Some("the hardened runtime, without disable-library-validation")
+    } else {
+        None
+    };
+
+    if audit.preload_blocked.is_none() {
+        let (mut flat, mut two_level) = (Vec::new(), Vec::new());
+        for bind in image.binds.iter() {
+            match (image.two_level, &bind.lookup) {
+                (true, Lookup::Library(_)) => two_level.push(bind.name.clone()),
+                (_, Lookup::SelfImage) => {}
+                _ => flat.push(bind.name.clone()),
+            }
+        }
+        for (vector, mut symbols) in [(Vector::Preload, flat), (Vector::Interpose, two_level)] {
+            symbols.sort();
+            symbols.dedup();
+            if !symbols.is_empty() {
+                audit.exposures.push(Exposure { vector, symbols });
+            }
+        }
+    }
+    if audit.library_validation.is_some() {
+        return Ok(audit);
+    }
+    let expand = |path: &str| -> PathBuf {
+        ["@executable_path/", "@loader_path/"]
+            .iter()
+            .find_map(|prefix| path.strip_prefix(prefix))
+            .map_or_else(|| PathBuf::from(path), |rest| dir.join(rest))
+    };
+    let rpaths: Vec<PathBuf> = macho.rpaths.iter().map(|rpath| expand(rpath)).collect();
+    for library in image.libraries.iter() {
+        let mut symbols: Vec<String> = image
+            .binds
+            .iter()
+            .filter(|bind| !image.two_level || bind.lookup == Lookup::Library(library.clone()))
+            .map(|bind| bind.name.clone())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        match library.strip_prefix("@rpath/") {
+            Some(rest) => {
+                let found = planted(&mut audit, library, &rpaths, symbols.clone(), |dir| {
+                    dir.join(rest).exists()
+                });
+                if !found {
+                    audit.exposures.push(Exposure {
+                        vector: Vector::UnresolvedRpath {
+                            library: library.clone(),
+                        },
+                        symbols,
+                    });
+                }
+            }
+            // the system libraries are in the shared cache, not where their install names say
+            None => {
+                let path = expand(library);
+                let Some(parent) = path.parent() else {
+                    continue;
+                };
+                if path.exists() && writable(parent) {
+                    audit.exposures.push(Exposure {
+                        vector: Vector::WritableSearchPath {
+                            library: library.clone(),
+                            dir: parent.to_path_buf(),
+                        },
+                        symbols,
+                    });
+                }
+            }
+        }
+    }
+    Ok(audit)
+}
+
+fn audit_pe(pe: &crate::pe::PE, dir: &Path, env: &Environment) -> Audit {
+    let mut audit = Audit::default();
+    let mut dlls: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for import in pe.imports.iter() {
+        dlls.entry(import.dll.to_lowercase())
+            .or_default()
+            .push(import.name.to_string());
+    }
+    for (dll, symbols) in dlls {
+        // API sets are resolved by the loader, not looked for
+        if KNOWN_DLLS.contains(&dll.as_str())
+            || dll.starts_with("api-ms-")
+            || dll.starts_with("ext-ms-")
+        {
+            continue;
+        }
+        if writable(dir) {
+            audit.exposures.push(Exposure {
+                vector: Vector::WritableSearchPath {
+                    library: dll.clone(),
+                    dir: dir.to_path_buf(),
+                },
+                symbols: symbols.clone(),
+            });
+        }
+        let found = |dir: &Path| has_file(dir, &dll);
+        if found(dir) || env.system_dir.as_deref().is_none_or(found) {
+            continue;
+        }
+        audit.exposures.push(Exposure {
+            vector: Vector::MissingDll {
+                library: dll.clone(),
+            },
+            symbols: symbols.clone(),
+        });
+        planted(&mut audit, &dll, &env.path, symbols, found);
+    }
+    audit
+}
+
+/// Report the directories of `search` a copy of `library` planted in would be loaded from:
+/// those ahead of the first one `holds` it, or all of them if none does. Returns whether one
+/// does.
+fn planted(
+    audit: &mut Audit,
+    library: &str,
+    search: &[PathBuf],
+    symbols: Vec<String>,
+    holds: impl Fn(&Path) -> bool,
+) -> bool {
+    for dir in search {
+        if holds(dir) {
+            return true;
+        }
+        let vector = if dir.is_relative() {
+            Vector::RelativeSearchPath {
+                library: library.to_string(),
+                dir: dir.clone(),
+            }
+        } else if writable(dir) {
+            Vector::WritableSearchPath {
+                library: library.to_string(),
+                dir: dir.clone(),
+            }
+        } else {
+            continue;
+        };
+        audit.exposures.push(Exposure {
+            vector,
+            symbols: symbols.clone(),
+        });
+    }
+    false
+}
+
+/// Whether `dir` has the file `name`, whatever its case, as Windows would find it
+fn has_file(dir: &Path, name: &str) -> bool {
+    fs::read_dir(dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.file_name().to_string_lossy().eq_ignore_ascii_case(name))
+    })
+}
+
+/// Whether others than its owner can make files in `dir`
+fn writable(dir: &Path) -> bool {
+    let mut dir = dir;
+    loop {
+        match fs::metadata(dir) {
+            Ok(meta) => return meta.is_dir() && writable_by_others(&meta),
+            Err(_) => match dir.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => dir = parent,
+                _ => return false,
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn writable_by_others(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o022 != 0
+}
+
+#[cfg(not(unix))]
+fn writable_by_others(meta: &fs::Metadata) -> bool {
+    !meta.permissions().readonly()
+}
+
+#[cfg(unix)]
+fn is_setuid(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o6000 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_setuid(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::pe::import::{ImportTable, ImportedFunction};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn finds_planting_spots() {
+        let root = std::env::temp_dir().join(format!("hijack-{}", std::process::id()));
+        let (app, system, shared) = (root.join("app"), root.join("system"), root.join("shared"));
+        for dir in [&app, &system, &shared] {
+            fs::create_dir_all(dir).unwrap();
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(system.join("VERSION.dll"), b"").unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+
+        let mut imports = ImportTable::default();
+        let name = |name: &str| ImportedFunction::Name {
+            hint: 0,
+            name: name.to_string(),
+        };
+        imports.add_function("KERNEL32.dll", name("ExitProcess"));
+        imports.add_function("version.dll", name("GetFileVersionInfoW"));
+        imports.add_function("helper.dll", name("Help"));
+        let (pe, _) = imports.tiny_image();
+        let path = app.join("tool.exe");
+        fs::write(&path, &pe).unwrap();
+        let env = Environment {
+            path: vec![root.join("locked"), shared.clone()],
+            system_dir: Some(system),
+        };
+
+        let audit = audit(&path, &pe, &env).unwrap();
+        assert_eq!(audit.preload_blocked, None);
+        assert_eq!(
+            audit.exposures,
+            [
+                Exposure {
+                    vector: Vector::MissingDll {
+                        library: "helper.dll".to_string()
+                    },
+                    symbols: vec!["Help".to_string()],
+                },
+                Exposure {
+                    vector: Vector::WritableSearchPath {
+                        library: "helper.dll".to_string(),
+                        dir: shared.clone(),
+                    },
+                    symbols: vec!["Help".to_string()],
+                },
+            ]
+        );
+
+        // anyone can drop a version.dll next to the tool now
+        fs::set_permissions(&app, fs::Permissions::from_mode(0o775)).unwrap();
+        let audit = super::audit(&path, &pe, &env).unwrap();
+        assert_eq!(audit.exposures.len(), 4);
+        assert_eq!(
+            audit.exposures[3].to_string(),
+            format!("planting version.dll in {}: GetFileVersionInfoW", app.display())
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! How each import is called, for planning userland hooks.
+//!
+//! An import called through its slot, directly (`call [slot]`) or through a trampoline jumping
+//! through it (see [`crate::trampolines`]), is hooked by pointing the slot elsewhere, which
+//! catches every call at once. An import resolved at run time (see [`crate::dynimports`]) has
+//! its slot written by the code itself after any patch, so the API's own code has to be hooked
+//! instead. [`report`] sorts the imports of a workspace accordingly.
+
+use crate::{
+    constants::{BR_DEREF, REF_CODE, REF_DATA},
+    dynimports::DYNAMIC_LIBRARY,
+    workspace::VivWorkspace,
+};
+use std::fmt;
+
+/// Where an import is hooked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookPoint {
+    /// Patching the slot catches every call
+    Slot,
+    /// The slot is filled at run time; the API's code has to be patched
+    Code,
+    /// Nothing calls the import
+    Unused,
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookPoint::Slot => "slot",
+            HookPoint::Code => "code",
+            HookPoint::Unused => "unused",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportHooks {
+    pub slot: i32,
+    pub name: String,
+    /// Calls through the slot, `call [slot]`
+    pub slot_calls: Vec<i32>,
+    /// Calls to a trampoline jumping through the slot
+    pub stub_calls: Vec<i32>,
+    /// Instructions reading the slot, to call through it later
+    pub loads: Vec<i32>,
+    /// Whether the slot is filled by a `GetProcAddress` or `dlsym` call
+    pub resolved: bool,
+}
+
+impl ImportHooks {
+    pub fn hook_point(&self) -> HookPoint {
+        if self.slot_calls.is_empty() && self.stub_calls.is_empty() && self.loads.is_empty() {
+            HookPoint::Unused
+        } else if self.resolved {
+            HookPoint::Code
+        } else {
+            HookPoint::Slot
+        }
+    }
+}
+
+impl fmt::Display for ImportHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x} {}: {} ({} slot calls, {} stub calls, {} loads)",
+            self.slot,
+            self.name,
+            self.hook_point(),
+            self.slot_calls.len(),
+            self.stub_calls.len(),
+            self.loads.len()
+        )
+    }
+}
+
+/// How each import of the workspace is called, by slot
+pub fn report(workspace: &VivWorkspace) -> Vec<ImportHooks> {
+    let stubs = workspace.get_import_stubs();
+    let is_stub = |va: i32| stubs.iter().any(|(stub, _)| *stub == va);
+    let resolved_prefix = format!("{}.", DYNAMIC_LIBRARY);
+    workspace
+        .get_imports()
+        .into_iter()
+        .map(|(slot, name)| {
+            let mut slot_calls = Vec::new();
+            let mut loads = Vec::new();
+            for (from, _, rtype, rflags) in workspace.get_xrefs_to(slot, None) {
+                if is_stub(from) {
+                    continue;
+                }
+                if rtype == REF_CODE && rflags & BR_DEREF != 0 {
+                    slot_calls.push(from);
+                } else if rtype == REF_DATA {
+                    loads.push(from);
+                }
+            }
+            let mut stub_calls: Vec<i32> = stubs
+                .iter()
+                .filter(|(_, stub_slot)| *stub_slot == slot)
+                .flat_map(|(stub, _)| workspace.get_xrefs_to(*stub, Some(REF_CODE)))
+                .map(|xref| xref.0)
+                .filter(|from| !is_stub(*from))
+                .collect();
+            slot_calls.sort_unstable();
+            stub_calls.sort_unstable();
+            stub_calls.dedup();
+            loads.sort_unstable();
+            ImportHooks {
+                slot,
+                resolved: name.starts_with(&resolved_prefix),
+                name,
+                slot_calls,
+                stub_calls,
+                loads,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::BR_PROC;
+
+    #[test]
+    fn hook_points() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x4000, "kernel32", "Sleep");
+        ws.make_import(0x4004, "kernel32", "ExitProcess");
+        ws.make_import(0x5000, DYNAMIC_LIBRARY, "VirtualAlloc");
+        // call [Sleep]; a thunk jumping through it, called twice
+        ws.add_xref(0x1000, 0x4000, REF_CODE, BR_PROC | BR_DEREF);
+        ws.add_import_stub(0x2000, 0x4000);
+        ws.add_xref(0x2000, 0x4000, REF_CODE, BR_DEREF);
+        ws.add_xref(0x1010, 0x2000, REF_CODE, BR_PROC);
+        ws.add_xref(0x1020, 0x2000, REF_CODE, BR_PROC);
+        // mov eax, [VirtualAlloc]; call eax
+        ws.add_xref(0x1030, 0x5000, REF_DATA, 0);
+
+        let report = report(&ws);
+        let summary: Vec<_> = report
+            .iter()
+            .map(|hooks| (hooks.name.as_str(), hooks.hook_point()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("kernel32.Sleep", HookPoint::Slot),
+                ("kernel32.ExitProcess", HookPoint::Unused),
+                ("dynamic.VirtualAlloc", HookPoint::Code),
+            ]
+        );
+        assert_eq!(report[0].slot_calls, [0x1000]);
+        assert_eq!(report[0].stub_calls, [0x1010, 0x1020]);
+        assert_eq!(
+            report[0].to_string(),
+            "0x00004000 kernel32.Sleep: slot (1 slot calls, 2 stub calls, 0 loads)"
+        );
+        assert_eq!(report[2].loads, [0x1030]);
+    }
+}
@@ -0,0 +1,538 @@
+//! Concrete emulation of the lifted IL.
+//!
+//! An [`Interpreter`] runs the [`Stmt`]s of [`crate::symbolic`] on a [`RegisterContext`] and a
+//! [`Ram`], so an architecture with a lifter can be emulated without an emulator of its own.
+//! The semantics are the symbolic engine's: values are folded by [`Expr::binary`] and
+//! [`Expr::unary`] with constant operands, and flags come from [`FlagOp::update`], the same
+//! functions a [`crate::symbolic::State`] uses once its operands are known. A bug in either is
+//! a bug in both, and concrete and symbolic runs can't disagree.
+//!
+//! Loads and stores move values of the pointer size, in the byte order of the [`Ram`]. The
+//! program counter reads
+//! as [`pc_value`] for each instruction. [`Interpreter::run`] follows the terminators of a
+//! [`Function`] until it returns or leaves the function.
+//!
+//! Code may write code. The [`Ram`] knows which of its bytes are code, those mapped with
+//! [`Ram::map_code`] or marked with [`Ram::mark_code`], and the blocks of a function while it
+//! runs. A write to the function running makes its lifted form stale, so the run stops with
+//! [`Stop::CodeWritten`] for the caller to lift the function again; with
+//! [`Ram::set_log_code_writes`] the regions of code written are kept, for
+//! [`crate::emulator::GenericEmulator::add_dynamic_code`] to give back to the workspace.
+
+use crate::{
+    envi::{registers::RegisterContext, Arch},
+    pic::{load, pc_value},
+    symbolic::{Expr, Function, Insn, Stmt, Terminator},
+};
+use std::{collections::BTreeMap, fmt};
+
+/// Writable memory, as a list of maps
+#[derive(Clone, Debug, Default)]
+pub struct Ram {
+    maps: Vec<(u64, Vec<u8>)>,
+    big_endian: bool,
+    /// The ranges of code, start to end
+    code: BTreeMap<u64, u64>,
+    /// The writes to code since [`Ram::take_code_writes`] last asked, as (va, size)
+    code_writes: Vec<(u64, u64)>,
+    log_code_writes: bool,
+    /// The ranges of code written, start to end, kept apart
+    code_written: BTreeMap<u64, u64>,
+}
+
+/// Add `start..end` to disjoint ranges, merging it with those it overlaps or touches
+fn add_range(ranges: &mut BTreeMap<u64, u64>, mut start: u64, mut end: u64) {
+    let touching: Vec<(u64, u64)> = ranges
+        .range(..=end)
+        .filter(|(_, e)| **e >= start)
+        .map(|(s, e)| (*s, *e))
+        .collect();
+    for (s, e) in touching {
+        ranges.remove(&s);
+        start = start.min(s);
+        end = end.max(e);
+    }
+    ranges.insert(start, end);
+}
+
+impl Ram {
+    pub fn new() -> Self {
+        Ram::default()
+    }
+
+    /// Map `bytes` at `va`, over any earlier map of the same addresses
+    pub fn map(&mut self, va: u64, bytes: Vec<u8>) {
+        self.maps.push((va, bytes));
+    }
+
+    /// Map `bytes` of code at `va`, so writes to them are seen
+    pub fn map_code(&mut self, va: u64, bytes: Vec<u8>) {
+        self.mark_code(va, bytes.len() as u64);
+        self.map(va, bytes);
+    }
+
+    /// Take the `size` bytes at `va` for code, as memory which is run later
+    pub fn mark_code(&mut self, va: u64, size: u64) {
+        if size > 0 {
+            add_range(&mut self.code, va, va.saturating_add(size));
+        }
+    }
+
+    fn is_code(&self, va: u64, size: u64) -> bool {
+        let end = va.saturating_add(size);
+        self.code.range(..end).any(|(_, e)| *e > va)
+    }
+
+    /// Keep the regions of code written, for [`Ram::code_written`]
+    pub fn set_log_code_writes(&mut self, log: bool) {
+        self.log_code_writes = log;
+    }
+
+    /// The regions of code written while logging, as (va, size), in address order
+    pub fn code_written(&self) -> Vec<(u64, u64)> {
+        self.code_written
+            .iter()
+            .map(|(start, end)| (*start, end - start))
+            .collect()
+    }
+
+    /// The writes to code since last asked, as (va, size)
+    pub fn take_code_writes(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.code_writes)
+    }
+
+    /// The `size` bytes at `va`
+    pub fn read_bytes(&mut self, va: u64, size: usize) -> Option<Vec<u8>> {
+        self.bytes(va, size).map(|bytes| bytes.to_vec())
+    }
+
+    /// Keep values most significant byte first, as on PowerPC or MIPS BE
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.big_endian = big_endian;
+    }
+
+    fn bytes(&mut self, va: u64, size: usize) -> Option<&mut [u8]> {
+        self.maps.iter_mut().rev().find_map(|(mva, bytes)| {
+            let offset = usize::try_from(va.checked_sub(*mva)?).ok()?;
+            bytes.get_mut(offset..offset.checked_add(size)?)
+        })
+    }
+
+    /// The `size` byte value at `va`
+    pub fn read(&mut self, va: u64, size: usize) -> Option<u64> {
+        let big_endian = self.big_endian;
+        Some(load(self.bytes(va, size)?, big_endian))
+    }
+
+    /// Write the low `size` bytes of `value` at `va`; false if they aren't all mapped
+    pub fn write(&mut self, va: u64, size: usize, value: u64) -> bool {
+        let big_endian = self.big_endian;
+        let Some(bytes) = self.bytes(va, size) else {
+            return false;
+        };
+        if big_endian {
+            bytes.copy_from_slice(&value.to_be_bytes()[8 - size..]);
+        } else {
+            bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+        }
+        if self.is_code(va, size as u64) {
+            self.code_writes.push((va, size as u64));
+            if self.log_code_writes {
+                add_range(&mut self.code_written, va, va + size as u64);
+            }
+        }
+        true
+    }
+}
+
+/// Why emulation stopped short, with the address of the instruction it stopped at
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// A load or store through an address which isn't mapped
+    Unmapped { va: u64, addr: u64, write: bool },
+    /// An instruction the lifter doesn't model
+    Unknown { va: u64 },
+    /// A branch on flags the architecture doesn't keep
+    Condition { va: u64 },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Unmapped { va, addr, write } => {
+                let access = if *write { "write to" } else { "read from" };
+                write!(f, "{va:#x}: {access} unmapped {addr:#x}")
+            }
+            Fault::Unknown { va } => write!(f, "{va:#x}: instruction not lifted"),
+            Fault::Condition { va } => write!(f, "{va:#x}: branch on unknown flags"),
+        }
+    }
+}
+
+/// Where a run of a function ended
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// At the return ending the block at `va`
+    Return { va: u64 },
+    /// At a tail call to `to`
+    TailCall { va: u64, to: u64 },
+    /// At a jump from `va` to `to`, which isn't a block of the function
+    Exit { va: u64, to: u64 },
+    /// After running as many blocks as allowed, about to run the block at `va`
+    Limit { va: u64 },
+    /// About to run the instruction at `va`, the one asked to stop at
+    Reached { va: u64 },
+    /// After the instruction before `va` wrote to the code of the function at `addr`; the
+    /// function must be lifted again to carry on at `va`
+    CodeWritten { va: u64, addr: u64 },
+}
+
+/// Runs lifted instructions on concrete registers and memory
+#[derive(Debug)]
+pub struct Interpreter<'a> {
+    arch: Arch,
+    width: u32,
+    registers: &'a mut RegisterContext,
+    memory: &'a mut Ram,
+    /// The instruction running
+    va: u64,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(registers: &'a mut RegisterContext, memory: &'a mut Ram) -> Self {
+        let arch = registers.model().arch();
+        Interpreter {
+            arch,
+            width: arch.pointer_size() as u32 * 8,
+            registers,
+            memory,
+            va: 0,
+        }
+    }
+
+    fn mask(&self, value: u64) -> u64 {
+        value & (u64::MAX >> (64 - self.width))
+    }
+
+    /// The value of an expression
+    pub fn eval(&mut self, expr: &Expr) -> Result<u64, Fault> {
+        let width = self.width;
+        let folded = match expr {
+            Expr::Const(c) => return Ok(self.mask(*c)),
+            Expr::Reg(r) => return Ok(self.mask(self.registers.get(*r) as u64)),
+            Expr::Load(addr) => {
+                let addr = self.eval(addr)?;
+                return self
+                    .memory
+                    .read(addr, width as usize / 8)
+                    .ok_or(Fault::Unmapped {
+                        va: self.va,
+                        addr,
+                        write: false,
+                    });
+            }
+            Expr::Unary(op, a) => Expr::unary(*op, Expr::Const(self.eval(a)?), width),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a)?, self.eval(b)?);
+                Expr::binary(*op, Expr::Const(a), Expr::Const(b), width)
+            }
+        };
+        let Expr::Const(value) = folded else {
+            unreachable!("operations on constants fold")
+        };
+        Ok(value)
+    }
+
+    pub fn exec(&mut self, stmt: &Stmt) -> Result<(), Fault> {
+        match stmt {
+            Stmt::Set(reg, value) => {
+                let value = self.eval(value)?;
+                self.registers.set(*reg, value.into());
+            }
+            Stmt::Store(addr, value) => {
+                let (addr, value) = (self.eval(addr)?, self.eval(value)?);
+                if !self.memory.write(addr, self.width as usize / 8, value) {
+                    return Err(Fault::Unmapped {
+                        va: self.va,
+                        addr,
+                        write: true,
+                    });
+                }
+            }
+            Stmt::Flags(op, a, b) => {
+                let (a, b) = (self.eval(a)?, self.eval(b)?);
+                let update = op.update(self.arch, a, b, self.width);
+                self.registers.apply_flags(&update);
+            }
+            Stmt::Unknown => return Err(Fault::Unknown { va: self.va }),
+        }
+        Ok(())
+    }
+
+    /// Run an instruction, `next` being the address of the one after it
+    pub fn exec_insn(&mut self, insn: &Insn, next: Option<u64>) -> Result<(), Fault> {
+        self.va = insn.va;
+        if let Some(pc) = pc_value(self.arch, insn.va, next) {
+            self.registers.set_pc(pc);
+        }
+        insn.stmts.iter().try_for_each(|stmt| self.exec(stmt))
+    }
+
+    /// Run `func` from its entry, for at most `max_blocks` blocks
+    pub fn run(&mut self, func: &Function, max_blocks: usize) -> Result<Stop, Fault> {
+        self.trace(func, max_blocks, &mut Vec::new())
+    }
+
+    /// Run `func` like [`Interpreter::run`], adding the blocks run to `path`
+    pub fn trace(
+        &mut self,
+        func: &Function,
+        max_blocks: usize,
+        path: &mut Vec<u64>,
+    ) -> Result<Stop, Fault> {
+        self.exec_blocks(func, func.entry, None, max_blocks, path)
+    }
+
+    /// Run `func` from the block at `from` up to the instruction at `until`
+    pub fn run_to(
+        &mut self,
+        func: &Function,
+        from: u64,
+        until: u64,
+        max_blocks: usize,
+    ) -> Result<Stop, Fault> {
+        self.exec_blocks(func, from, Some(until), max_blocks, &mut Vec::new())
+    }
+
+    fn exec_blocks(
+        &mut self,
+        func: &Function,
+        from: u64,
+        until: Option<u64>,
+        max_blocks: usize,
+        path: &mut Vec<u64>,
+    ) -> Result<Stop, Fault> {
+        for block in func.blocks.values() {
+            // up to the start of the instruction ending it
+            self.memory
+                .mark_code(block.va, block.end_va.max(block.va) - block.va + 1);
+        }
+        self.memory.take_code_writes();
+        let in_func = |(addr, size): &(u64, u64)| {
+            func.blocks
+                .range(..addr + size)
+                .next_back()
+                .is_some_and(|(_, block)| *addr <= block.end_va)
+        };
+        let mut va = from;
+        for _ in 0..max_blocks {
+            let Some(block) = func.blocks.get(&va) else {
+                return Ok(Stop::Exit { va, to: va });
+            };
+            path.push(va);
+            for (i, insn) in block.insns.iter().enumerate() {
+                let next = match block.insns.get(i + 1) {
+                    Some(next) => Some(next.va),
+                    None => Some(block.end_va).filter(|end| *end > insn.va),
+                };
+                if until == Some(insn.va) {
+                    return Ok(Stop::Reached { va: insn.va });
+                }
+                self.exec_insn(insn, next)?;
+                let writes = self.memory.take_code_writes();
+                if let Some((addr, _)) = writes.iter().find(|write| in_func(write)) {
+                    return Ok(Stop::CodeWritten {
+                        va: next.unwrap_or(block.end_va),
+                        addr: *addr,
+                    });
+                }
+            }
+            self.va = block.end_va;
+            let to = match &block.end {
+                Terminator::Jump(to) => *to,
+                Terminator::Branch {
+                    cond,
+                    taken,
+                    fallthrough,
+                } => match cond.eval(&self.registers.condition_flags()) {
+                    Some(true) => *taken,
+                    Some(false) => *fallthrough,
+                    None => return Err(Fault::Condition { va: block.end_va }),
+                },
+                Terminator::Indirect(target) => self.eval(target)?,
+                Terminator::Return => return Ok(Stop::Return { va: block.end_va }),
+                Terminator::TailCall(to) => {
+                    return Ok(Stop::TailCall {
+                        va: block.end_va,
+                        to: *to,
+                    })
+                }
+            };
+            if !func.blocks.contains_key(&to) {
+                return Ok(Stop::Exit {
+                    va: block.end_va,
+                    to,
+                });
+            }
+            va = to;
+        }
+        Ok(Stop::Limit { va })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_I386, MM_EXEC, MM_READ},
+        emulator::{Emulator, GenericEmulator},
+        envi::{
+            flags::{Condition, Flag},
+            registers::RegisterModel,
+        },
+        memory::Memory,
+        symbolic::{BinOp, Block, FlagOp, State},
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn loop_and_table() {
+        // xor eax, eax; xor ecx, ecx
+        // top: add eax, ecx; inc ecx; cmp ecx, 5; jb top
+        // mov [0x3000], eax; jmp [0x3004]
+        let model = RegisterModel::new(Arch::I386);
+        let (eax, ecx) = (model.by_name("eax").unwrap(), model.by_name("ecx").unwrap());
+        let insn = |va, stmts| Insn { va, stmts };
+        let add = |a, b| Expr::binary(BinOp::Add, a, b, 32);
+        let body = vec![
+            insn(
+                0x1004,
+                vec![Stmt::Set(eax, add(Expr::Reg(eax), Expr::Reg(ecx)))],
+            ),
+            insn(
+                0x1006,
+                vec![Stmt::Set(ecx, add(Expr::Reg(ecx), Expr::Const(1)))],
+            ),
+            insn(
+                0x1007,
+                vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(ecx), Expr::Const(5))],
+            ),
+        ];
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Set(eax, Expr::Const(0))]),
+                insn(0x1002, vec![Stmt::Set(ecx, Expr::Const(0))]),
+            ],
+            end_va: 0x1004,
+            end: Terminator::Jump(0x1004),
+        });
+        func.add_block(Block {
+            va: 0x1004,
+            insns: body.clone(),
+            end_va: 0x100a,
+            end: Terminator::Branch {
+                cond: Condition::Carry,
+                taken: 0x1004,
+                fallthrough: 0x100c,
+            },
+        });
+        func.add_block(Block {
+            va: 0x100c,
+            insns: vec![insn(
+                0x100c,
+                vec![Stmt::Store(Expr::Const(0x3000), Expr::Reg(eax))],
+            )],
+            end_va: 0x1011,
+            end: Terminator::Indirect(Expr::Load(Box::new(Expr::Const(0x3004)))),
+        });
+
+        let mut registers = RegisterContext::new(Arch::I386);
+        let mut memory = Ram::new();
+        memory.map(0x3000, [0, 0, 0, 0, 0x00, 0x20, 0, 0].to_vec());
+        let stop = Interpreter::new(&mut registers, &mut memory).run(&func, 100);
+        assert_eq!(
+            stop,
+            Ok(Stop::Exit {
+                va: 0x1011,
+                to: 0x2000
+            })
+        );
+        assert_eq!(registers.get(eax), 10);
+        assert_eq!(memory.read(0x3000, 4), Some(10));
+
+        // The loop body agrees with the symbolic engine given the same inputs
+        let mut state = State::new(Arch::I386);
+        state.exec(&Stmt::Set(eax, Expr::Const(6)));
+        state.exec(&Stmt::Set(ecx, Expr::Const(4)));
+        body.iter().for_each(|insn| state.exec_insn(insn));
+        registers.set(eax, 6);
+        registers.set(ecx, 4);
+        let mut interpreter = Interpreter::new(&mut registers, &mut memory);
+        for insn in &body {
+            interpreter.exec_insn(insn, None).unwrap();
+        }
+        assert_eq!(state.reg(eax), Expr::Const(10));
+        assert_eq!(registers.get(eax), 10);
+        assert_eq!(
+            state.condition(Condition::Carry),
+            Some(registers.condition_flags().get(Flag::Carry) == Some(true))
+        );
+
+        let mut interpreter = Interpreter::new(&mut registers, &mut memory);
+        let load = Stmt::Set(eax, Expr::Load(Box::new(Expr::Const(0x5000))));
+        assert_eq!(
+            interpreter.exec_insn(&insn(0x1020, vec![load]), None),
+            Err(Fault::Unmapped {
+                va: 0x1020,
+                addr: 0x5000,
+                write: false
+            })
+        );
+    }
+
+    #[test]
+    fn code_written() {
+        // mov dword [0x1006], 0x90909090 rewrites the next instruction, mov eax, 1
+        let model = RegisterModel::new(Arch::I386);
+        let eax = model.by_name("eax").unwrap();
+        let insn = |va, stmts| Insn { va, stmts };
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(
+                    0x1000,
+                    vec![Stmt::Store(Expr::Const(0x1006), Expr::Const(0x9090_9090))],
+                ),
+                insn(0x1006, vec![Stmt::Set(eax, Expr::Const(1))]),
+            ],
+            end_va: 0x100b,
+            end: Terminator::Return,
+        });
+        let mut registers = RegisterContext::new(Arch::I386);
+        let mut memory = Ram::new();
+        memory.map_code(0x1000, vec![0xcc; 0x10]);
+        memory.set_log_code_writes(true);
+        let stop = Interpreter::new(&mut registers, &mut memory).run(&func, 10);
+        assert_eq!(
+            stop,
+            Ok(Stop::CodeWritten {
+                va: 0x1006,
+                addr: 0x1006
+            })
+        );
+        assert_eq!(registers.get(eax), 0);
+        assert_eq!(memory.code_written(), [(0x1006, 4)]);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a.exe", vec![0xcc; 0x10], None);
+        let mut emu = GenericEmulator::new(ws);
+        assert_eq!(emu.add_dynamic_code(&mut memory), [(0x1006, 4)]);
+        let ws = emu.get_vivworkspace();
+        assert_eq!(ws.read_memory(0x1006, 4).unwrap(), [0x90; 4]);
+        assert_eq!(ws.get_va_set_rows("DynamicCode"), Some(vec![0x1006]));
+    }
+}
@@ -0,0 +1,242 @@
+//! Cross references to interesting immediate constants.
+//!
+//! An address an instruction uses gets an xref to it; a constant doesn't, and finding the uses
+//! of an IOCTL code or of the MD5 initial state means searching the bytes, with the false
+//! positives of data and of immediates split across encodings. A [`Watchlist`] says which
+//! constants are interesting, by value or range, with a label for each, and [`find`] looks for
+//! them in the operands of the lifted functions of a workspace.
+//! `VivWorkspace::add_immediate_xrefs` records each use as a `REF_CONST` xref from the
+//! instruction to the value, so the uses of a constant are `get_xrefs_to(value,
+//! Some(REF_CONST))` (see `VivWorkspace::get_immediate_uses`). Values go to the xref table
+//! truncated to 32 bits, as addresses do.
+//!
+//! A compare with a constant may be lifted as an addition of its negation (`sub eax, code` as
+//! `eax + -code`), so the negation of an added constant is looked up too, at 32 and 64 bits.
+
+use crate::{
+    driver::Ioctl,
+    symbolic::{BinOp, Expr, Function, Stmt},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Constants of hash functions, checksums and ciphers, which are rarely anything else
+pub const CRYPTO_CONSTANTS: &[(u64, &str)] = &[
+    (0x6745_2301, "MD5/SHA-1 initial state"),
+    (0xefcd_ab89, "MD5/SHA-1 initial state"),
+    (0x98ba_dcfe, "MD5/SHA-1 initial state"),
+    (0x1032_5476, "MD5/SHA-1 initial state"),
+    (0xc3d2_e1f0, "SHA-1 initial state"),
+    (0xd76a_a478, "MD5 round constant"),
+    (0x5a82_7999, "SHA-1 round constant"),
+    (0x6ed9_eba1, "SHA-1 round constant"),
+    (0x8f1b_bcdc, "SHA-1 round constant"),
+    (0xca62_c1d6, "SHA-1 round constant"),
+    (0x6a09_e667, "SHA-256 initial state"),
+    (0xbb67_ae85, "SHA-256 initial state"),
+    (0x3c6e_f372, "SHA-256 initial state"),
+    (0xa54f_f53a, "SHA-256 initial state"),
+    (0x510e_527f, "SHA-256 initial state"),
+    (0x9b05_688c, "SHA-256 initial state"),
+    (0x1f83_d9ab, "SHA-256 initial state"),
+    (0x5be0_cd19, "SHA-256 initial state"),
+    (0x428a_2f98, "SHA-256 round constant"),
+    (0xedb8_8320, "CRC-32 polynomial"),
+    (0x04c1_1db7, "CRC-32 polynomial"),
+    (0x82f6_3b78, "CRC-32C polynomial"),
+    (0x9e37_79b9, "TEA delta"),
+    (0x61c8_8647, "TEA delta"),
+    (0x243f_6a88, "Blowfish P-array"),
+    (0xb7e1_5163, "RC5/RC6 magic"),
+    (0x6170_7865, "ChaCha/Salsa20 constant"),
+    (0x3320_646e, "ChaCha/Salsa20 constant"),
+    (0x7962_2d32, "ChaCha/Salsa20 constant"),
+    (0x6b20_6574, "ChaCha/Salsa20 constant"),
+    (0x811c_9dc5, "FNV-1 offset basis"),
+    (0x0100_0193, "FNV-1 prime"),
+    (0xcc9e_2d51, "MurmurHash3 constant"),
+    (0x1b87_3593, "MurmurHash3 constant"),
+];
+
+/// The constants worth an xref
+#[derive(Clone, Debug, Default)]
+pub struct Watchlist {
+    values: BTreeMap<u64, String>,
+    /// Inclusive ranges
+    ranges: Vec<(u64, u64, String)>,
+    /// Whether constants which look like IOCTL codes are watched, see [`Ioctl::plausible`]
+    pub ioctls: bool,
+}
+
+impl Watchlist {
+    pub fn new() -> Self {
+        Watchlist::default()
+    }
+
+    /// The [`CRYPTO_CONSTANTS`]
+    pub fn crypto() -> Self {
+        let mut watchlist = Watchlist::new();
+        for (value, label) in CRYPTO_CONSTANTS {
+            watchlist.add_value(*value, label);
+        }
+        watchlist
+    }
+
+    pub fn add_value(&mut self, value: u64, label: &str) {
+        self.values.insert(value, label.to_string());
+    }
+
+    pub fn add_range(&mut self, start: u64, end: u64, label: &str) {
+        self.ranges.push((start, end, label.to_string()));
+    }
+
+    /// What `value` is, if it is watched
+    pub fn label(&self, value: u64) -> Option<String> {
+        if let Some(label) = self.values.get(&value) {
+            return Some(label.clone());
+        }
+        let range = self
+            .ranges
+            .iter()
+            .find(|(start, end, _)| (*start..=*end).contains(&value));
+        if let Some((_, _, label)) = range {
+            return Some(label.clone());
+        }
+        if self.ioctls && Ioctl::plausible(value) {
+            return Some(format!("IOCTL {}", Ioctl(value as u32)));
+        }
+        None
+    }
+}
+
+/// An instruction using a watched constant
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImmediateUse {
+    pub va: u64,
+    pub value: u64,
+    pub label: String,
+}
+
+/// The constants in the operands of each instruction of `func`, as (instruction, value). An
+/// added constant comes with its negations.
+pub fn immediates(func: &Function) -> BTreeSet<(u64, u64)> {
+    let mut found = BTreeSet::new();
+    for block in func.blocks.values() {
+        for insn in block.insns.iter() {
+            let mut add = |expr: &Expr| constants(expr, &mut |value| {
+                found.insert((insn.va, value));
+            });
+            for stmt in insn.stmts.iter() {
+                match stmt {
+                    Stmt::Set(_, value) => add(value),
+                    Stmt::Store(addr, value) | Stmt::Flags(_, addr, value) => {
+                        add(addr);
+                        add(value);
+                    }
+                    Stmt::Unknown => {}
+                }
+            }
+        }
+    }
+    found
+}
+
+fn constants(expr: &Expr, found: &mut dyn FnMut(u64)) {
+    match expr {
+        Expr::Const(value) => found(*value),
+        Expr::Reg(_) => {}
+        Expr::Load(addr) => constants(addr, found),
+        Expr::Unary(_, value) => constants(value, found),
+        Expr::Binary(op, a, b) => {
+            if let (BinOp::Add, Expr::Const(value)) = (op, b.as_ref()) {
+                found(value.wrapping_neg() & 0xffff_ffff);
+                found(value.wrapping_neg());
+            }
+            constants(a, found);
+            constants(b, found);
+        }
+    }
+}
+
+/// The uses of the watched constants in the lifted functions of the workspace
+pub fn find(workspace: &VivWorkspace, watchlist: &Watchlist) -> Vec<ImmediateUse> {
+    let mut uses = BTreeSet::new();
+    for fva in workspace.get_functions_with_il() {
+        let Some(func) = workspace.get_function_il(fva) else {
+            continue;
+        };
+        for (va, value) in immediates(func) {
+            if let Some(label) = watchlist.label(value) {
+                uses.insert(ImmediateUse { va, value, label });
+            }
+        }
+    }
+    uses.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::REF_CONST,
+        envi::{registers::RegisterModel, Arch},
+        symbolic::{Block, FlagOp, Insn, Terminator},
+    };
+
+    #[test]
+    fn finds_watched_constants() {
+        let model = RegisterModel::new(Arch::I386);
+        let (eax, esp) = (model.by_name("eax").unwrap(), model.sp());
+        let mut func = Function::new(Arch::I386, 0x1000);
+        let insn = |va, stmts| Insn { va, stmts };
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                // mov dword [esp+4], 0x67452301
+                insn(
+                    0x1000,
+                    vec![Stmt::Store(
+                        Expr::binary(BinOp::Add, Expr::Reg(esp), Expr::Const(4), 32),
+                        Expr::Const(0x6745_2301),
+                    )],
+                ),
+                // cmp eax, 0x222003
+                insn(
+                    0x1008,
+                    vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(0x22_2003))],
+                ),
+                // sub eax, 0x222007
+                insn(
+                    0x100d,
+                    vec![Stmt::Set(
+                        eax,
+                        Expr::binary(BinOp::Sub, Expr::Reg(eax), Expr::Const(0x22_2007), 32),
+                    )],
+                ),
+            ],
+            end_va: 0x1012,
+            end: Terminator::Return,
+        });
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x1000, vec![]);
+        ws.set_function_il(0x1000, func);
+
+        let mut watchlist = Watchlist::crypto();
+        watchlist.add_range(0x22_2000, 0x22_2fff, "device control");
+        let uses = ws.add_immediate_xrefs(&watchlist);
+        let found = uses.iter().map(|u| (u.va, u.value)).collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [(0x1000, 0x6745_2301), (0x1008, 0x22_2003), (0x100d, 0x22_2007)]
+        );
+        assert_eq!(uses[0].label, "MD5/SHA-1 initial state");
+        assert_eq!(ws.get_immediate_uses(0x22_2003), [0x1008]);
+        assert_eq!(ws.get_xrefs_from(0x100d, Some(REF_CONST)).len(), 1);
+
+        watchlist.ioctls = true;
+        assert_eq!(
+            watchlist.label(0x0022_0004).as_deref(),
+            Some("IOCTL CTL_CODE(0x22, 0x1, METHOD_BUFFERED, FILE_ANY_ACCESS)")
+        );
+    }
+}
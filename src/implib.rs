@@ -0,0 +1,759 @@
+//! Import libraries and text stubs made from the exports of a library, to link against it.
+//!
+//! Linking against a DLL takes its import library (`.lib`) or a module-definition file (`.def`)
+//! to make one from, and linking against a dylib takes the dylib or its `.tbd` text stub. A
+//! binary which was analyzed or patched has neither in its SDK, if it has an SDK at all. An
+//! [`ImportLibrary`] collects the exports of a PE and writes either: the `.lib` is an archive of
+//! MSVC short import objects, with the import descriptor and null thunk objects `link.exe` and
+//! `lld-link` expect. A [`TextStub`] collects the exports of a Mach-O dylib, by target for a fat
+//! one, and writes a version 4 `.tbd`.
+//!
+//! Exports which only have an ordinal can't be named in either and are left out.
+
+use crate::{
+    abidiff::AbiSet,
+    error,
+    mach::{
+        self,
+        constants::cputype::get_arch_name_from_types,
+        exports::{
+            ExportInfo, EXPORT_SYMBOL_FLAGS_KIND_MASK, EXPORT_SYMBOL_FLAGS_KIND_THREAD_LOCAL,
+            EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION,
+        },
+        header::MH_APP_EXTENSION_SAFE,
+        load_command::{platform_to_str, CommandVariant},
+    },
+    pe::{
+        self,
+        characteristic::IMAGE_FILE_32BIT_MACHINE,
+        header::{
+            CoffHeader, COFF_MACHINE_ARM64, COFF_MACHINE_ARMNT, COFF_MACHINE_X86,
+            COFF_MACHINE_X86_64,
+        },
+        relocation::{Relocation, IMAGE_REL_AMD64_ADDR32NB, IMAGE_REL_I386_DIR32NB},
+        section_table::{
+            SectionTable, IMAGE_SCN_ALIGN_2BYTES, IMAGE_SCN_ALIGN_4BYTES, IMAGE_SCN_ALIGN_8BYTES,
+            IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ,
+            IMAGE_SCN_MEM_WRITE,
+        },
+        symbol::{Symbol, IMAGE_SYM_CLASS_EXTERNAL, IMAGE_SYM_CLASS_SECTION, IMAGE_SYM_CLASS_STATIC},
+    },
+};
+use scroll::{Pread, Pwrite, LE};
+use std::{collections::BTreeSet, fmt::Write as _};
+
+/// `IMAGE_REL_ARM_ADDR32NB` and `IMAGE_REL_ARM64_ADDR32NB`
+const IMAGE_REL_ARM_ADDR32NB: u16 = 0x0002;
+
+// The type and name type of a short import object
+const IMPORT_CODE: u16 = 0;
+const IMPORT_DATA: u16 = 1;
+const IMPORT_NAME: u16 = 1;
+const IMPORT_NAME_NOPREFIX: u16 = 2;
+
+/// A named export of a DLL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryExport {
+    pub name: String,
+    pub ordinal: u32,
+    /// Whether it is a variable rather than a function
+    pub data: bool,
+}
+
+/// The exports of a DLL, to write its import library from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportLibrary {
+    /// The file name of the DLL, `kernel32.dll`
+    pub dll: String,
+    /// The `COFF_MACHINE_*` of the DLL
+    pub machine: u16,
+    /// In ordinal order
+    pub exports: Vec<LibraryExport>,
+}
+
+impl ImportLibrary {
+    /// Collect the named exports of the PE in `bytes`. Exports outside the executable sections
+    /// are taken for data.
+    pub fn from_pe(bytes: &[u8]) -> error::Result<Self> {
+        let pe = pe::PE::parse(bytes)?;
+        let dll = pe
+            .name
+            .ok_or_else(|| error::Error::Malformed("The PE doesn't name itself".to_string()))?;
+        let set = AbiSet::parse(bytes)?;
+        let mut exports: Vec<LibraryExport> = set
+            .exports
+            .iter()
+            .filter(|sym| !sym.name.starts_with('#'))
+            .filter_map(|sym| {
+                let rva = pe
+                    .exports
+                    .iter()
+                    .find(|export| export.name == Some(sym.name.as_str()))
+                    .map(|export| export.rva)?;
+                let data = sym.library.is_none()
+                    && !pe.sections.iter().any(|section| {
+                        let start = section.virtual_address as usize;
+                        let size = section.virtual_size.max(section.size_of_raw_data) as usize;
+                        section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+                            && (start..start + size).contains(&rva)
+                    });
+                Some(LibraryExport {
+                    name: sym.name.clone(),
+                    ordinal: sym.ordinal?,
+                    data,
+                })
+            })
+            .collect();
+        exports.sort_by_key(|export| export.ordinal);
+        Ok(ImportLibrary {
+            dll: dll.to_string(),
+            machine: pe.header.coff_header.machine,
+            exports,
+        })
+    }
+
+    /// The module-definition file, for `lib /def:` or `dlltool`
+    pub fn to_def(&self) -> String {
+        let mut def = format!("LIBRARY \"{}\"\nEXPORTS\n", self.dll);
+        for export in self.exports.iter() {
+            let _ = write!(def, "    {} @{}", export.name, export.ordinal);
+            if export.data {
+                def.push_str(" DATA");
+            }
+            def.push('\n');
+        }
+        def
+    }
+
+    /// The import library, as `lib.exe` would make it from [`ImportLibrary::to_def`]
+    pub fn to_lib(&self) -> Vec<u8> {
+        let stem = self
+            .dll
+            .rsplit_once('.')
+            .map_or(self.dll.as_str(), |(stem, _)| stem);
+        let descriptor = format!("__IMPORT_DESCRIPTOR_{}", stem);
+        let null_thunk = format!("\x7f{}_NULL_THUNK_DATA", stem);
+        let mut members = vec![
+            (
+                self.import_descriptor(&descriptor, &null_thunk),
+                vec![descriptor.clone()],
+            ),
+            (
+                self.null_import_descriptor(),
+                vec!["__NULL_IMPORT_DESCRIPTOR".to_string()],
+            ),
+            (self.null_thunk(&null_thunk), vec![null_thunk.clone()]),
+        ];
+        for export in self.exports.iter() {
+            members.push(self.short_import(export));
+        }
+        write_archive(&self.dll, &members)
+    }
+
+    fn is_64(&self) -> bool {
+        matches!(self.machine, COFF_MACHINE_X86_64 | COFF_MACHINE_ARM64)
+    }
+
+    /// The symbol a program refers to an export by. Names are decorated with a `_` on i386,
+    /// C++ ones already are.
+    fn symbol(&self, name: &str) -> (String, u16) {
+        if self.machine == COFF_MACHINE_X86 && !name.starts_with('?') {
+            (format!("_{}", name), IMPORT_NAME_NOPREFIX)
+        } else {
+            (name.to_string(), IMPORT_NAME)
+        }
+    }
+
+    fn short_import(&self, export: &LibraryExport) -> (Vec<u8>, Vec<String>) {
+        let (symbol, name_type) = self.symbol(&export.name);
+        let import_type = if export.data { IMPORT_DATA } else { IMPORT_CODE };
+        let mut object = Vec::new();
+        for half in [0u16, 0xffff, 0, self.machine] {
+            object.extend(half.to_le_bytes());
+        }
+        object.extend(0u32.to_le_bytes());
+        object.extend(((symbol.len() + self.dll.len() + 2) as u32).to_le_bytes());
+        object.extend((export.ordinal as u16).to_le_bytes());
+        object.extend((import_type | name_type << 2).to_le_bytes());
+        for name in [&symbol, &self.dll] {
+            object.extend(name.as_bytes());
+            object.push(0);
+        }
+        let mut symbols = vec![format!("__imp_{}", symbol)];
+        if !export.data {
+            symbols.push(symbol);
+        }
+        (object, symbols)
+    }
+
+    /// The `.idata$2` entry of the DLL, which brings its name and its lookup and address tables
+    fn import_descriptor(&self, descriptor: &str, null_thunk: &str) -> Vec<u8> {
+        let reloc = match self.machine {
+            COFF_MACHINE_X86_64 => IMAGE_REL_AMD64_ADDR32NB,
+            COFF_MACHINE_ARM64 | COFF_MACHINE_ARMNT => IMAGE_REL_ARM_ADDR32NB,
+            _ => IMAGE_REL_I386_DIR32NB,
+        };
+        let mut name = self.dll.as_bytes().to_vec();
+        name.push(0);
+        let data = IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE;
+        let mut coff = Coff::new(self.machine, !self.is_64());
+        // the name, import lookup table and import address table RVAs
+        let relocs = [(12, 2), (0, 3), (16, 4)];
+        coff.section(
+            b".idata$2",
+            vec![0; 20],
+            &relocs.map(|(offset, symbol)| (offset, symbol, reloc)),
+            IMAGE_SCN_ALIGN_4BYTES | data,
+        );
+        coff.section(b".idata$6", name, &[], IMAGE_SCN_ALIGN_2BYTES | data);
+        coff.symbol(descriptor, 1, IMAGE_SYM_CLASS_EXTERNAL);
+        coff.symbol(".idata$2", 1, IMAGE_SYM_CLASS_SECTION);
+        coff.symbol(".idata$6", 2, IMAGE_SYM_CLASS_STATIC);
+        coff.symbol(".idata$4", 0, IMAGE_SYM_CLASS_SECTION);
+        coff.symbol(".idata$5", 0, IMAGE_SYM_CLASS_SECTION);
+        coff.symbol("__NULL_IMPORT_DESCRIPTOR", 0, IMAGE_SYM_CLASS_EXTERNAL);
+        coff.symbol(null_thunk, 0, IMAGE_SYM_CLASS_EXTERNAL);
+        coff.build()
+    }
+
+    /// The zeroed `.idata$3` entry which ends the import directory
+    fn null_import_descriptor(&self) -> Vec<u8> {
+        let mut coff = Coff::new(self.machine, !self.is_64());
+        coff.section(
+            b".idata$3",
+            vec![0; 20],
+            &[],
+            IMAGE_SCN_ALIGN_4BYTES
+                | IMAGE_SCN_CNT_INITIALIZED_DATA
+                | IMAGE_SCN_MEM_READ
+                | IMAGE_SCN_MEM_WRITE,
+        );
+        coff.symbol("__NULL_IMPORT_DESCRIPTOR", 1, IMAGE_SYM_CLASS_EXTERNAL);
+        coff.build()
+    }
+
+    /// The zeroed entries which end the lookup and address tables of the DLL
+    fn null_thunk(&self, null_thunk: &str) -> Vec<u8> {
+        let (size, align) = if self.is_64() {
+            (8, IMAGE_SCN_ALIGN_8BYTES)
+        } else {
+            (4, IMAGE_SCN_ALIGN_4BYTES)
+        };
+        let characteristics =
+            align | IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE;
+        let mut coff = Coff::new(self.machine, !self.is_64());
+        coff.section(b".idata$5", vec![0; size], &[], characteristics);
+        coff.section(b".idata$4", vec![0; size], &[], characteristics);
+        coff.symbol(null_thunk, 1, IMAGE_SYM_CLASS_EXTERNAL);
+        coff.build()
+    }
+}
+
+/// The name, data, relocations and characteristics of a section
+type Section = ([u8; 8], Vec<u8>, Vec<Relocation>, u32);
+
+/// A COFF object being put together
+struct Coff {
+    machine: u16,
+    is_32: bool,
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+    strings: Vec<u8>,
+}
+
+impl Coff {
+    fn new(machine: u16, is_32: bool) -> Self {
+        Coff {
+            machine,
+            is_32,
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    /// Add a section with its `(offset, symbol index, type)` relocations
+    fn section(
+        &mut self,
+        name: &[u8; 8],
+        data: Vec<u8>,
+        relocs: &[(u32, u32, u16)],
+        characteristics: u32,
+    ) {
+        let relocs = relocs
+            .iter()
+            .map(|(offset, symbol, typ)| Relocation {
+                virtual_address: *offset,
+                symbol_table_index: *symbol,
+                typ: *typ,
+            })
+            .collect();
+        self.sections.push((*name, data, relocs, characteristics));
+    }
+
+    fn symbol(&mut self, name: &str, section: i16, storage_class: u8) {
+        let mut raw = [0u8; 8];
+        if name.len() <= 8 {
+            raw[..name.len()].copy_from_slice(name.as_bytes());
+        } else {
+            let offset = 4 + self.strings.len() as u32;
+            raw[4..].copy_from_slice(&offset.to_le_bytes());
+            self.strings.extend(name.as_bytes());
+            self.strings.push(0);
+        }
+        self.symbols.push(Symbol {
+            name: raw,
+            section_number: section,
+            storage_class,
+            ..Default::default()
+        });
+    }
+
+    fn build(self) -> Vec<u8> {
+        const HEADER: usize = 20;
+        const SECTION: usize = 40;
+        const RELOCATION: usize = 10;
+        const SYMBOL: usize = 18;
+        let mut offset = HEADER + SECTION * self.sections.len();
+        let mut headers = Vec::new();
+        for (name, data, relocs, characteristics) in self.sections.iter() {
+            let pointer_to_relocations = if relocs.is_empty() {
+                0
+            } else {
+                offset + data.len()
+            };
+            headers.push(SectionTable {
+                name: *name,
+                size_of_raw_data: data.len() as u32,
+                pointer_to_raw_data: offset as u32,
+                pointer_to_relocations: pointer_to_relocations as u32,
+                number_of_relocations: relocs.len() as u16,
+                characteristics: *characteristics,
+                ..Default::default()
+            });
+            offset += data.len() + relocs.len() * RELOCATION;
+        }
+        let header = CoffHeader {
+            machine: self.machine,
+            number_of_sections: self.sections.len() as u16,
+            pointer_to_symbol_table: offset as u32,
+            number_of_symbol_table: self.symbols.len() as u32,
+            characteristics: if self.is_32 {
+                IMAGE_FILE_32BIT_MACHINE
+            } else {
+                0
+            },
+            ..Default::default()
+        };
+        let size = offset + SYMBOL * self.symbols.len() + 4 + self.strings.len();
+        let mut out = vec![0u8; size];
+        let at = &mut 0;
+        out.gwrite_with(header, at, LE).unwrap();
+        for section in headers {
+            out.gwrite_with(section, at, LE).unwrap();
+        }
+        for (_, data, relocs, _) in self.sections.iter() {
+            out.gwrite(&data[..], at).unwrap();
+            for reloc in relocs.iter() {
+                out.gwrite_with(*reloc, at, LE).unwrap();
+            }
+        }
+        for symbol in self.symbols.iter() {
+            out.gwrite_with(*symbol, at, LE).unwrap();
+        }
+        out.gwrite_with(4 + self.strings.len() as u32, at, LE)
+            .unwrap();
+        out.gwrite(&self.strings[..], at).unwrap();
+        out
+    }
+}
+
+/// An archive of `members` and the symbols each defines, with the two linker members of the
+/// Microsoft format. Every member is named `name`, the way import libraries name them.
+fn write_archive(name: &str, members: &[(Vec<u8>, Vec<String>)]) -> Vec<u8> {
+    let header = |name: &str, size: usize| {
+        format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name, "0", "0", "0", "0", size
+        )
+    };
+    let member_name = if name.len() < 16 {
+        format!("{}/", name)
+    } else {
+        "/0".to_string()
+    };
+    let padded = |size: usize| size + size % 2;
+    let symbols: Vec<(usize, &str)> = members
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, (_, symbols))| symbols.iter().map(move |sym| (idx, sym.as_str())))
+        .collect();
+    let names_size: usize = symbols.iter().map(|(_, sym)| sym.len() + 1).sum();
+    let first_size = 4 + 4 * symbols.len() + names_size;
+    let second_size = 4 + 4 * members.len() + 4 + 2 * symbols.len() + names_size;
+    let long_names = if name.len() < 16 {
+        String::new()
+    } else {
+        format!("{}/\n", name)
+    };
+
+    // where each member starts
+    let mut offset = 8
+        + 60
+        + padded(first_size)
+        + 60
+        + padded(second_size)
+        + if long_names.is_empty() {
+            0
+        } else {
+            60 + padded(long_names.len())
+        };
+    let mut offsets = Vec::new();
+    for (data, _) in members.iter() {
+        offsets.push(offset as u32);
+        offset += 60 + padded(data.len());
+    }
+
+    let mut out = b"!<arch>\n".to_vec();
+    let push = |out: &mut Vec<u8>, name: &str, data: &[u8]| {
+        out.extend(header(name, data.len()).as_bytes());
+        out.extend(data);
+        if data.len() % 2 == 1 {
+            out.push(b'\n');
+        }
+    };
+
+    // the first linker member: the symbols in member order, with big endian offsets
+    let mut first = Vec::with_capacity(first_size);
+    first.extend((symbols.len() as u32).to_be_bytes());
+    for (idx, _) in symbols.iter() {
+        first.extend(offsets[*idx].to_be_bytes());
+    }
+    for (_, sym) in symbols.iter() {
+        first.extend(sym.as_bytes());
+        first.push(0);
+    }
+    push(&mut out, "/", &first);
+
+    // the second: the members, then the symbols sorted by name with one based member indices
+    let mut sorted = symbols.clone();
+    sorted.sort_by(|a, b| a.1.cmp(b.1));
+    let mut second = Vec::with_capacity(second_size);
+    second.extend((members.len() as u32).to_le_bytes());
+    for offset in offsets.iter() {
+        second.extend(offset.to_le_bytes());
+    }
+    second.extend((sorted.len() as u32).to_le_bytes());
+    for (idx, _) in sorted.iter() {
+        second.extend((*idx as u16 + 1).to_le_bytes());
+    }
+    for (_, sym) in sorted.iter() {
+        second.extend(sym.as_bytes());
+        second.push(0);
+    }
+    push(&mut out, "/", &second);
+    if !long_names.is_empty() {
+        push(&mut out, "//", long_names.as_bytes());
+    }
+
+    for (data, _) in members.iter() {
+        push(&mut out, &member_name, data);
+    }
+    out
+}
+
+/// The exports of some of the targets of a dylib, which have the same ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StubExports {
+    /// `<arch>-<platform>`, `arm64-macos`
+    pub targets: Vec<String>,
+    pub symbols: BTreeSet<String>,
+    pub weak_symbols: BTreeSet<String>,
+    pub thread_local_symbols: BTreeSet<String>,
+    /// The classes, metaclasses, exception types and ivars, without their `_OBJC_*_$_` prefix
+    pub objc_classes: BTreeSet<String>,
+    pub objc_eh_types: BTreeSet<String>,
+    pub objc_ivars: BTreeSet<String>,
+    /// Symbols re-exported from other libraries
+    pub reexports: BTreeSet<String>,
+}
+
+/// The exports of a dylib, to write its `.tbd` from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextStub {
+    pub install_name: String,
+    /// Packed as `LC_ID_DYLIB` has them, `xxxx.yy.zz` in 16, 8 and 8 bits
+    pub current_version: u32,
+    pub compatibility_version: u32,
+    pub app_extension_safe: bool,
+    pub targets: Vec<String>,
+    /// The libraries re-exported whole, with the targets re-exporting them
+    pub reexported_libraries: Vec<(Vec<String>, String)>,
+    pub exports: Vec<StubExports>,
+}
+
+impl TextStub {
+    /// Collect the exports of the dylib in `bytes`, of every architecture of a fat one
+    pub fn from_macho(bytes: &[u8]) -> error::Result<Self> {
+        let mut stub = TextStub::default();
+        match mach::Mach::parse(bytes)? {
+            mach::Mach::Binary(macho) => stub.add(&macho, bytes)?,
+            mach::Mach::Fat(fat) => {
+                for arch in fat.iter_arches() {
+                    let bytes = arch?.slice(bytes);
+                    stub.add(&mach::MachO::parse(bytes, 0)?, bytes)?;
+                }
+            }
+        }
+        Ok(stub)
+    }
+
+    /// Add the exports of a thin Mach-O parsed from `bytes`
+    fn add(&mut self, macho: &mach::MachO, bytes: &[u8]) -> error::Result<()> {
+        let dylib = macho
+            .load_commands
+            .iter()
+            .find_map(|lc| match lc.command {
+                CommandVariant::IdDylib(ref id) => Some(id.dylib),
+                _ => None,
+            })
+            .ok_or_else(|| error::Error::Malformed("The Mach-O isn't a dylib".to_string()))?;
+        self.install_name = macho.name.unwrap_or_default().to_string();
+        self.current_version = dylib.current_version;
+        self.compatibility_version = dylib.compatibility_version;
+        self.app_extension_safe = macho.header.flags & MH_APP_EXTENSION_SAFE != 0;
+
+        let arch = get_arch_name_from_types(macho.header.cputype(), macho.header.cpusubtype())
+            .unwrap_or("unknown");
+        let platform = match macho.platform().map(platform_to_str) {
+            Some(simulator) if simulator.ends_with("simulator") => {
+                format!("{}-simulator", simulator.trim_end_matches("simulator"))
+            }
+            Some(platform) => platform.to_string(),
+            None => "macos".to_string(),
+        };
+        let target = format!("{}-{}", arch, platform);
+        self.targets.push(target.clone());
+
+        for lc in macho.load_commands.iter() {
+            if let CommandVariant::ReexportDylib(ref command) = lc.command {
+                let lib: &str = bytes.pread(lc.offset + command.dylib.name as usize)?;
+                match self
+                    .reexported_libraries
+                    .iter_mut()
+                    .find(|(_, name)| name == lib)
+                {
+                    Some((targets, _)) => targets.push(target.clone()),
+                    None => self
+                        .reexported_libraries
+                        .push((vec![target.clone()], lib.to_string())),
+                }
+            }
+        }
+
+        let mut exports = StubExports::default();
+        for export in macho.exports()? {
+            let name = export.name;
+            let flags = match export.info {
+                ExportInfo::Reexport { .. } => {
+                    exports.reexports.insert(name);
+                    continue;
+                }
+                ExportInfo::Regular { flags, .. } | ExportInfo::Stub { flags, .. } => flags,
+            };
+            let objc = |prefix: &str| name.strip_prefix(prefix).map(str::to_string);
+            if let Some(class) = objc("_OBJC_CLASS_$_").or_else(|| objc("_OBJC_METACLASS_$_")) {
+                exports.objc_classes.insert(class);
+            } else if let Some(eh_type) = objc("_OBJC_EHTYPE_$_") {
+                exports.objc_eh_types.insert(eh_type);
+            } else if let Some(ivar) = objc("_OBJC_IVAR_$_") {
+                exports.objc_ivars.insert(ivar);
+            } else if flags & EXPORT_SYMBOL_FLAGS_KIND_MASK == EXPORT_SYMBOL_FLAGS_KIND_THREAD_LOCAL
+            {
+                exports.thread_local_symbols.insert(name);
+            } else if flags & EXPORT_SYMBOL_FLAGS_WEAK_DEFINITION != 0 {
+                exports.weak_symbols.insert(name);
+            } else {
+                exports.symbols.insert(name);
+            }
+        }
+        // targets which export the same go together
+        let same = self.exports.iter_mut().find(|other| {
+            StubExports {
+                targets: vec![],
+                ..(*other).clone()
+            } == exports
+        });
+        match same {
+            Some(other) => other.targets.push(target),
+            None => {
+                exports.targets.push(target);
+                self.exports.push(exports);
+            }
+        }
+        Ok(())
+    }
+
+    /// The stub as a version 4 `.tbd`
+    pub fn to_tbd(&self) -> String {
+        let list = |items: &mut dyn Iterator<Item = &String>| {
+            let items: Vec<String> = items.map(|item| quote(item)).collect();
+            format!("[ {} ]", items.join(", "))
+        };
+        let mut tbd = String::from("--- !tapi-tbd\ntbd-version: 4\n");
+        let _ = writeln!(tbd, "targets: {}", list(&mut self.targets.iter()));
+        if !self.app_extension_safe {
+            tbd.push_str("flags: [ not_app_extension_safe ]\n");
+        }
+        let _ = writeln!(tbd, "install-name: {}", quote(&self.install_name));
+        let _ = writeln!(tbd, "current-version: {}", version(self.current_version));
+        let _ = writeln!(
+            tbd,
+            "compatibility-version: {}",
+            version(self.compatibility_version)
+        );
+        if !self.reexported_libraries.is_empty() {
+            tbd.push_str("reexported-libraries:\n");
+            for (targets, lib) in self.reexported_libraries.iter() {
+                let _ = writeln!(tbd, "  - targets: {}", list(&mut targets.iter()));
+                let _ = writeln!(tbd, "    libraries: {}", list(&mut std::iter::once(lib)));
+            }
+        }
+        let sections = |tbd: &mut String, key: &str, pick: &dyn Fn(&StubExports) -> Lists<'_>| {
+            let exports: Vec<_> = self
+                .exports
+                .iter()
+                .filter(|exports| pick(exports).iter().any(|(_, set)| !set.is_empty()))
+                .collect();
+            if exports.is_empty() {
+                return;
+            }
+            let _ = writeln!(tbd, "{}:", key);
+            for exports in exports {
+                let _ = writeln!(tbd, "  - targets: {}", list(&mut exports.targets.iter()));
+                for (name, set) in pick(exports) {
+                    if !set.is_empty() {
+                        let _ = writeln!(tbd, "    {}: {}", name, list(&mut set.iter()));
+                    }
+                }
+            }
+        };
+        sections(&mut tbd, "exports", &|exports| {
+            vec![
+                ("symbols", &exports.symbols),
+                ("objc-classes", &exports.objc_classes),
+                ("objc-eh-types", &exports.objc_eh_types),
+                ("objc-ivars", &exports.objc_ivars),
+                ("weak-symbols", &exports.weak_symbols),
+                ("thread-local-symbols", &exports.thread_local_symbols),
+            ]
+        });
+        sections(&mut tbd, "reexports", &|exports| {
+            vec![("symbols", &exports.reexports)]
+        });
+        tbd.push_str("...\n");
+        tbd
+    }
+}
+
+/// The lists of symbols of a section of a stub, by key
+type Lists<'a> = Vec<(&'static str, &'a BTreeSet<String>)>;
+
+/// A version packed in 16, 8 and 8 bits, without the trailing zeroes past the major version
+fn version(packed: u32) -> String {
+    let (major, minor, patch) = (packed >> 16, (packed >> 8) & 0xff, packed & 0xff);
+    match (minor, patch) {
+        (0, 0) => format!("{}", major),
+        (_, 0) => format!("{}.{}", major, minor),
+        _ => format!("{}.{}.{}", major, minor, patch),
+    }
+}
+
+/// A YAML scalar, quoted when it would be read as something else
+fn quote(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_$.-/+@".contains(c))
+        && !s.starts_with(['-', '@']);
+    if plain {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+
+    #[test]
+    fn writes_import_libraries_and_stubs() {
+        let lib = ImportLibrary {
+            dll: "widgets.dll".to_string(),
+            machine: COFF_MACHINE_X86,
+            exports: vec![
+                LibraryExport {
+                    name: "CreateWidget".to_string(),
+                    ordinal: 1,
+                    data: false,
+                },
+                LibraryExport {
+                    name: "WidgetCount".to_string(),
+                    ordinal: 2,
+                    data: true,
+                },
+            ],
+        };
+        assert_eq!(
+            lib.to_def(),
+            "LIBRARY \"widgets.dll\"\nEXPORTS\n    CreateWidget @1\n    WidgetCount @2 DATA\n"
+        );
+        let bytes = lib.to_lib();
+        let archive = Archive::parse(&bytes).unwrap();
+        assert_eq!(archive.len(), 5);
+        let symbols: BTreeSet<&str> = archive
+            .summarize()
+            .into_iter()
+            .flat_map(|(_, _, symbols)| symbols)
+            .collect();
+        assert_eq!(
+            symbols,
+            BTreeSet::from([
+                "_CreateWidget",
+                "__IMPORT_DESCRIPTOR_widgets",
+                "__NULL_IMPORT_DESCRIPTOR",
+                "__imp__CreateWidget",
+                "__imp__WidgetCount",
+                "\x7fwidgets_NULL_THUNK_DATA",
+            ])
+        );
+        let import = archive.get_at(3).unwrap();
+        let import: &[u8] = bytes.pread_with(import.offset as usize, import.size()).unwrap();
+        assert_eq!(&import[..4], &[0, 0, 0xff, 0xff]);
+        assert_eq!(&import[20..], b"_CreateWidget\0widgets.dll\0");
+
+        let stub = TextStub {
+            install_name: "@rpath/libwidgets.dylib".to_string(),
+            current_version: 0x1_02_03,
+            compatibility_version: 0x1_00_00,
+            app_extension_safe: true,
+            targets: vec!["x86_64-macos".to_string(), "arm64-macos".to_string()],
+            reexported_libraries: vec![],
+            exports: vec![StubExports {
+                targets: vec!["x86_64-macos".to_string(), "arm64-macos".to_string()],
+                symbols: BTreeSet::from(["_create_widget".to_string()]),
+                objc_classes: BTreeSet::from(["Widget".to_string()]),
+                ..Default::default()
+            }],
+        };
+        assert_eq!(
+            stub.to_tbd(),
+            "--- !tapi-tbd\ntbd-version: 4\ntargets: [ x86_64-macos, arm64-macos ]\n\
+             install-name: '@rpath/libwidgets.dylib'\ncurrent-version: 1.2.3\n\
+             compatibility-version: 1\nexports:\n  - targets: [ x86_64-macos, arm64-macos ]\n\
+             \x20   symbols: [ _create_widget ]\n    objc-classes: [ Widget ]\n...\n"
+        );
+    }
+}
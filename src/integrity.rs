@@ -0,0 +1,279 @@
+//! Import table integrity of a process memory image.
+//!
+//! A userland hook or rootkit shows in the memory of the process it sits in: an import slot (an
+//! IAT or GOT entry) pointing somewhere else than the function it imports, a trampoline (a PLT
+//! entry, a Mach-O stub or an import thunk, see [`crate::trampolines`]) rewritten to jump
+//! elsewhere, or the first bytes of an exported function overwritten with a jump.
+//!
+//! [`check`] compares a workspace of the memory snapshot with a workspace of the same modules
+//! loaded from their files, at the bases they have in the snapshot. The second one says what the
+//! imports, trampolines and exports are and what their bytes should be; the first one what they
+//! are. A slot still holding what the file has isn't bound yet (a lazy GOT entry), and one bound
+//! to an export of the name it imports is fine, whichever module exports it. A forwarder to an
+//! export of another name (`HeapAlloc` to `RtlAllocateHeap`) is reported as a redirection, the
+//! workspace not knowing forwarders.
+//!
+//! Pointers are read little endian, as on every architecture trampolines are recognised on.
+
+use crate::{
+    detours::detour, envi::Arch, memory::Memory, trampolines::find_stubs,
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+/// How many bytes of each exported function are compared
+pub const PROLOGUE_SIZE: u64 = 16;
+
+/// What was done to an import slot, a trampoline or an export
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tamper {
+    /// The slot points at an export of another name than the one imported
+    Redirected { to: String },
+    /// The slot points into a module, but at none of its exports
+    IntoModule { file: String },
+    /// The slot points outside every module
+    OutsideModules,
+    /// The trampoline jumping through `slot` was rewritten
+    PatchedStub { slot: u64 },
+    /// The first bytes of the export were rewritten
+    PatchedExport,
+}
+
+/// A hooked or redirected entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// The slot, trampoline or export
+    pub va: u64,
+    /// The import or export name
+    pub name: String,
+    /// Where the slot points, or where the rewritten code jumps if that can be told
+    pub target: Option<u64>,
+    pub tamper: Tamper,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} {}: ", self.va, self.name)?;
+        match &self.tamper {
+            Tamper::Redirected { to } => write!(f, "slot redirected to {}", to)?,
+            Tamper::IntoModule { file } => write!(f, "slot points into {}", file)?,
+            Tamper::OutsideModules => write!(f, "slot points outside the modules")?,
+            Tamper::PatchedStub { slot } => write!(f, "trampoline of slot {:#x} patched", slot)?,
+            Tamper::PatchedExport => write!(f, "function entry patched")?,
+        }
+        if let Some(target) = self.target {
+            write!(f, " ({:#x})", target)?;
+        }
+        Ok(())
+    }
+}
+
+/// The hooked import slots, trampolines and exports of `snapshot`, going by what `modules`, the
+/// same modules loaded from their files at the same bases, says they should be. Sorted by VA.
+pub fn check(snapshot: &VivWorkspace, modules: &VivWorkspace) -> Vec<Finding> {
+    let arch = Arch::from_envi(modules.arch);
+    let psize = match arch {
+        Some(arch) => arch.pointer_size(),
+        None if modules.get_pointer_size() > 0 => modules.get_pointer_size() as usize,
+        None => 4,
+    };
+    let imports = modules.get_imports();
+    let exports: HashMap<u64, String> = modules
+        .get_exports()
+        .into_iter()
+        .map(|(va, name)| (va as u32 as u64, name))
+        .collect();
+    let mut findings = Vec::new();
+
+    for (slot, name) in imports.iter() {
+        let slot = *slot as u32 as u64;
+        let Some(live) = read_pointer(snapshot, slot, psize) else {
+            continue;
+        };
+        if live == 0 || read_pointer(modules, slot, psize) == Some(live) {
+            continue;
+        }
+        let tamper = match exports.get(&live) {
+            Some(export) if same_function(name, export) => continue,
+            Some(export) => Tamper::Redirected { to: export.clone() },
+            None => match modules.get_file_by_va(live as i32) {
+                Some(file) => Tamper::IntoModule { file },
+                None => Tamper::OutsideModules,
+            },
+        };
+        findings.push(Finding {
+            va: slot,
+            name: name.clone(),
+            target: Some(live),
+            tamper,
+        });
+    }
+
+    let maps: Vec<(u64, u64)> = modules
+        .get_executable_maps()
+        .into_iter()
+        .map(|(va, bytes)| (va as u32 as u64, bytes.len() as u64))
+        .collect();
+    if let Some(arch) = arch {
+        let names: HashMap<u64, &str> = imports
+            .iter()
+            .map(|(va, name)| (*va as u32 as u64, name.as_str()))
+            .collect();
+        let slots: BTreeSet<u64> = names.keys().copied().collect();
+        for (va, bytes) in modules.get_executable_maps() {
+            for stub in find_stubs(arch, va as u32 as u64, bytes, &slots) {
+                if let Some(target) = patched(snapshot, modules, Some(arch), stub.va, stub.size) {
+                    findings.push(Finding {
+                        va: stub.va,
+                        name: names[&stub.slot].to_string(),
+                        target,
+                        tamper: Tamper::PatchedStub { slot: stub.slot },
+                    });
+                }
+            }
+        }
+    }
+
+    let mut entries: Vec<u64> = exports.keys().copied().collect();
+    entries.sort_unstable();
+    for (i, va) in entries.iter().enumerate() {
+        let Some((mva, msize)) = maps.iter().find(|(mva, msize)| mva <= va && *va < mva + msize)
+        else {
+            continue;
+        };
+        // up to the next export, for the functions shorter than a prologue
+        let next = entries.get(i + 1).copied().unwrap_or(u64::MAX);
+        let size = PROLOGUE_SIZE.min(mva + msize - va).min(next - va);
+        if let Some(target) = patched(snapshot, modules, arch, *va, size) {
+            findings.push(Finding {
+                va: *va,
+                name: exports[va].clone(),
+                target,
+                tamper: Tamper::PatchedExport,
+            });
+        }
+    }
+
+    findings.sort_by_key(|finding| finding.va);
+    findings
+}
+
+/// Whether the `size` bytes at `va` differ between the snapshot and the modules, and if so
+/// where the rewritten code jumps, if it can be told (see [`crate::detours`])
+fn patched(
+    snapshot: &VivWorkspace,
+    modules: &VivWorkspace,
+    arch: Option<Arch>,
+    va: u64,
+    size: u64,
+) -> Option<Option<u64>> {
+    let live = snapshot.read_memory(va as i32, size as i32)?;
+    let expected = modules.read_memory(va as i32, size as i32)?;
+    if live == expected {
+        return None;
+    }
+    Some(arch.and_then(|arch| detour(snapshot, arch, va)?.target))
+}
+
+fn read_pointer(memory: &VivWorkspace, va: u64, psize: usize) -> Option<u64> {
+    let bytes = memory.read_memory(va as i32, psize as i32)?;
+    let mut word = [0u8; 8];
+    word[..psize].copy_from_slice(bytes.get(..psize)?);
+    Some(u64::from_le_bytes(word))
+}
+
+/// The function of an import or export name, `send` of `ws2_32.send`
+fn function_name(name: &str) -> &str {
+    name.split_once('.').map_or(name, |(_, function)| function)
+}
+
+/// Whether an export is the function imported. The name of an export which an import of the
+/// same name took first was made unique, `ws2_32.send_0`.
+fn same_function(import: &str, export: &str) -> bool {
+    let (import, export) = (function_name(import), function_name(export));
+    export == import
+        || export
+            .strip_prefix(import)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ},
+        pe::import::{ImportTable, ImportedFunction},
+    };
+
+    #[test]
+    fn finds_hooks() {
+        let mut imports = ImportTable::default();
+        for function in ["send", "recv", "connect", "bind"] {
+            imports.add_function("WS2_32.dll", ImportedFunction::by_name(function));
+        }
+        let (pe, idata) = imports.tiny_image();
+        let slots: Vec<u64> = idata.slots.iter().map(|slot| 0x400000 + slot.2 as u64).collect();
+        // the exports send and recv of a fake ws2_32, and a trampoline through the connect slot
+        let mut code = vec![0xc3; 0x20];
+        code[0x10..0x12].copy_from_slice(&[0xff, 0x25]);
+        code[0x12..0x16].copy_from_slice(&(slots[2] as u32).to_le_bytes());
+        let load = || {
+            let mut ws = VivWorkspace::new("", false);
+            ws.load_from_bytes("tiny.exe", &pe, None);
+            ws.add_memory_map(0x10000000, MM_READ | MM_EXEC, "ws2_32", code.clone(), None);
+            ws.add_segment(0x10000000, 0x20, ".text", "ws2_32".to_string());
+            ws.add_export(0x10000000, "send", "ws2_32");
+            ws.add_export(0x10000008, "recv", "ws2_32");
+            ws
+        };
+        let modules = load();
+        let mut snapshot = load();
+        // bound: send to send, recv to send, connect into ws2_32, bind left alone
+        snapshot.patch_bytes(slots[0] as i32, &0x10000000u32.to_le_bytes());
+        snapshot.patch_bytes(slots[1] as i32, &0x10000000u32.to_le_bytes());
+        snapshot.patch_bytes(slots[2] as i32, &0x10000004u32.to_le_bytes());
+        // recv jumps to shellcode, and so does the trampoline
+        snapshot.patch_bytes(0x10000008, &[0xe9, 0xf3, 0xff, 0xff, 0x5f]);
+        snapshot.patch_bytes(0x10000010, &[0xe9, 0xeb, 0xff, 0xff, 0x5f]);
+
+        let findings = check(&snapshot, &modules);
+        let found: Vec<(u64, &Tamper, Option<u64>)> = findings
+            .iter()
+            .map(|finding| (finding.va, &finding.tamper, finding.target))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    slots[1],
+                    &Tamper::Redirected {
+                        to: "ws2_32.send_0".to_string()
+                    },
+                    Some(0x10000000)
+                ),
+                (
+                    slots[2],
+                    &Tamper::IntoModule {
+                        file: "ws2_32".to_string()
+                    },
+                    Some(0x10000004)
+                ),
+                (0x10000008, &Tamper::PatchedExport, Some(0x70000000)),
+                (
+                    0x10000010,
+                    &Tamper::PatchedStub { slot: slots[2] },
+                    Some(0x70000000)
+                ),
+            ]
+        );
+        assert_eq!(findings[3].name, "ws2_32.connect");
+        assert_eq!(
+            findings[2].to_string(),
+            "0x10000008 ws2_32.recv_0: function entry patched (0x70000000)"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+//! Importers for the projects of other analysis tools, so a team can bring the functions, names
+//! and comments it already has into a workspace:
+//!
+//! * a Ghidra XML export (File > Export Program > XML): the `SYMBOL`s of the `SYMBOL_TABLE`,
+//!   the `FUNCTION`s with their `ADDRESS_RANGE`s, and the `COMMENT`s,
+//! * a rizin or radare2 project script: `af+`/`afb+` functions and blocks, `afn` renames,
+//!   `f` flags and `CC`/`CCu` comments (base64 ones included). Any other command is ignored.
+//!
+//! Both parse into a [`Project`], which [`apply`] adds to a workspace. Names the tools made up
+//! (Ghidra's `FUN_00401000`, radare2's `fcn.00401000`) are left out.
+
+use crate::workspace::VivWorkspace;
+use std::{collections::BTreeMap, io};
+
+/// What an imported project knows
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Project {
+    pub names: BTreeMap<i32, String>,
+    pub comments: BTreeMap<i32, String>,
+    /// Function entry to the (va, size) ranges of its code; empty when only the entry is known
+    pub functions: BTreeMap<i32, Vec<(i32, i32)>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A Ghidra address: hex digits, maybe after an address space (`ram:00401000`)
+fn ghidra_address(s: &str) -> Option<i32> {
+    let hex = s.rsplit(':').next()?;
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    u32::from_str_radix(hex, 16).ok().map(|va| va as i32)
+}
+
+pub(crate) fn xml_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A tag of an XML document: its name (with a leading `/` for a closing tag), attributes, and
+/// the text up to the next tag
+pub(crate) struct Tag<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) attrs: Vec<(&'a str, String)>,
+    /// Whether the tag closes itself, `<TAG />`
+    pub(crate) empty: bool,
+    pub(crate) text: &'a str,
+}
+
+impl Tag<'_> {
+    pub(crate) fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) fn xml_tags(xml: &str) -> io::Result<Vec<Tag<'_>>> {
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        // Declarations, comments and doctypes
+        if rest.starts_with('?') || rest.starts_with('!') {
+            let end = if rest.starts_with("!--") {
+                rest.find("-->").map(|end| end + 2)
+            } else {
+                rest.find('>')
+            };
+            rest = &rest[end.ok_or_else(|| invalid("Unterminated XML tag".to_string()))? + 1..];
+            continue;
+        }
+        // Attribute values may hold '>', so find the end outside quotes
+        let mut quote = None;
+        let close = rest.char_indices().find(|(_, c)| match (quote, *c) {
+            (None, '"' | '\'') => {
+                quote = Some(*c);
+                false
+            }
+            (Some(q), c) if c == q => {
+                quote = None;
+                false
+            }
+            (None, '>') => true,
+            _ => false,
+        });
+        let (close, _) = close.ok_or_else(|| invalid("Unterminated XML tag".to_string()))?;
+        let body = rest[..close].trim();
+        let empty = body.ends_with('/');
+        let body = body.trim_end_matches('/').trim_end();
+        rest = &rest[close + 1..];
+        let (name, mut attrs_text) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+        let mut attrs = Vec::new();
+        while let Some((key, value)) = attrs_text.split_once('=') {
+            let value = value.trim_start();
+            let Some(q) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                return Err(invalid(format!("Unquoted XML attribute {}", key.trim())));
+            };
+            let value = &value[1..];
+            let end = value
+                .find(q)
+                .ok_or_else(|| invalid("Unterminated XML attribute".to_string()))?;
+            attrs.push((key.trim(), xml_unescape(&value[..end])));
+            attrs_text = &value[end + 1..];
+        }
+        let text = &rest[..rest.find('<').unwrap_or(rest.len())];
+        tags.push(Tag {
+            name,
+            attrs,
+            empty,
+            text,
+        });
+    }
+    Ok(tags)
+}
+
+/// Read a Ghidra XML export
+pub fn parse_ghidra_xml(xml: &str) -> io::Result<Project> {
+    let tags = xml_tags(xml)?;
+    if !tags.iter().any(|tag| tag.name == "PROGRAM") {
+        return Err(invalid("Not a Ghidra program XML export".to_string()));
+    }
+    let mut project = Project::default();
+    let mut function: Option<i32> = None;
+    for tag in tags.iter() {
+        match tag.name {
+            "SYMBOL" => {
+                let (Some(va), Some(name)) = (
+                    tag.attr("ADDRESS").and_then(ghidra_address),
+                    tag.attr("NAME"),
+                ) else {
+                    continue;
+                };
+                if tag.attr("SOURCE_TYPE") != Some("DEFAULT") && tag.attr("PRIMARY") != Some("n") {
+                    project.names.insert(va, name.to_string());
+                }
+            }
+            "FUNCTION" => {
+                let Some(va) = tag.attr("ENTRY_POINT").and_then(ghidra_address) else {
+                    continue;
+                };
+                project.functions.entry(va).or_default();
+                match tag.attr("NAME") {
+                    Some(name) if !name.starts_with("FUN_") => {
+                        project.names.insert(va, name.to_string());
+                    }
+                    _ => {}
+                }
+                function = (!tag.empty).then_some(va);
+            }
+            "/FUNCTION" => function = None,
+            "ADDRESS_RANGE" => {
+                let (Some(fva), Some(start), Some(end)) = (
+                    function,
+                    tag.attr("START").and_then(ghidra_address),
+                    tag.attr("END").and_then(ghidra_address),
+                ) else {
+                    continue;
+                };
+                let size = end.wrapping_sub(start).wrapping_add(1);
+                project
+                    .functions
+                    .entry(fva)
+                    .or_default()
+                    .push((start, size));
+            }
+            "COMMENT" => {
+                let Some(va) = tag.attr("ADDRESS").and_then(ghidra_address) else {
+                    continue;
+                };
+                let text = xml_unescape(tag.text);
+                let comment = project.comments.entry(va).or_default();
+                if !comment.is_empty() {
+                    comment.push('\n');
+                }
+                comment.push_str(&text);
+            }
+            _ => {}
+        }
+    }
+    Ok(project)
+}
+
+pub(crate) fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes().filter(|c| *c != b'=') {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn r2_number(s: &str) -> Option<i32> {
+    let s = s.trim();
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u32>().ok(),
+    };
+    parsed.map(|va| va as i32)
+}
+
+/// Flags radare2 and rizin make by themselves
+const R2_AUTO_FLAGS: [&str; 10] = [
+    "fcn.", "loc.", "section.", "segment.", "reloc.", "str.", "case.", "switch.", "sym.imp.",
+    "entry",
+];
+
+/// The name for a flag, or None for one of the made up ones
+fn r2_name(flag: &str) -> Option<String> {
+    if R2_AUTO_FLAGS.iter().any(|prefix| flag.starts_with(prefix)) {
+        return None;
+    }
+    Some(flag.strip_prefix("sym.").unwrap_or(flag).to_string())
+}
+
+/// Read a rizin or radare2 project script
+pub fn parse_r2_script(script: &str) -> io::Result<Project> {
+    let mut project = Project::default();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        let line = line
+            .strip_prefix('"')
+            .and_then(|line| line.strip_suffix('"'))
+            .unwrap_or(line);
+        // The address a command applies to, after an @
+        let (command, at) = match line.rsplit_once(" @ ") {
+            Some((command, at)) => (command, r2_number(at)),
+            None => (line, None),
+        };
+        let mut words = command.split_whitespace();
+        let Some(verb) = words.next() else {
+            continue;
+        };
+        let args: Vec<&str> = words.collect();
+        let bad = || invalid(format!("line {}: bad {} command", number + 1, verb));
+        match verb {
+            // af+ addr name, and the older af+ addr size name
+            "af+" => {
+                let va = args.first().and_then(|va| r2_number(va)).ok_or_else(bad)?;
+                project.functions.entry(va).or_default();
+                if let Some(name) = args.last().filter(|_| args.len() > 1) {
+                    if let Some(name) = r2_name(name) {
+                        project.names.insert(va, name);
+                    }
+                }
+            }
+            // afb+ fcn addr size [jump] [fail]
+            "afb+" => {
+                let numbers: Vec<i32> = args.iter().take(3).filter_map(|a| r2_number(a)).collect();
+                let [fva, va, size] = numbers[..] else {
+                    return Err(bad());
+                };
+                project.functions.entry(fva).or_default().push((va, size));
+            }
+            "afn" => {
+                let name = args.first().ok_or_else(bad)?;
+                let va = at.or_else(|| args.get(1).and_then(|va| r2_number(va)));
+                if let (Some(va), Some(name)) = (va, r2_name(name)) {
+                    project.names.insert(va, name);
+                }
+            }
+            // f name [size] [addr], or f name [size] @ addr
+            "f" => {
+                let Some(flag) = args.first() else {
+                    continue;
+                };
+                let va = at.or_else(|| args.get(2).and_then(|va| r2_number(va)));
+                if let (Some(va), Some(name)) = (va, r2_name(flag)) {
+                    project.names.insert(va, name);
+                }
+            }
+            "CC" | "CCu" => {
+                let Some(va) = at else {
+                    continue;
+                };
+                let text = command[verb.len()..].trim();
+                let text = match text.strip_prefix("base64:") {
+                    Some(encoded) => {
+                        let decoded = base64_decode(encoded).ok_or_else(bad)?;
+                        String::from_utf8_lossy(&decoded).into_owned()
+                    }
+                    None => text.to_string(),
+                };
+                project.comments.insert(va, text);
+            }
+            _ => {}
+        }
+    }
+    Ok(project)
+}
+
+/// Add a project to the workspace: its functions (with their ranges as the function bounds),
+/// names made unique where they clash, and comments. Returns the number of functions, names
+/// and comments added.
+pub fn apply(workspace: &mut VivWorkspace, project: &Project) -> usize {
+    let mut applied = 0;
+    for (fva, ranges) in project.functions.iter() {
+        if !workspace.is_function(*fva) && workspace.add_function(*fva, ranges.clone()) {
+            applied += 1;
+        }
+    }
+    for (va, name) in project.names.iter() {
+        if workspace
+            .make_name(*va, name.clone(), false, true)
+            .is_some()
+        {
+            applied += 1;
+        }
+    }
+    for (va, comment) in project.comments.iter() {
+        workspace.set_comment(*va, comment, false);
+        applied += 1;
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_projects() {
+        let xml = r#"<?xml version="1.0" standalone="yes"?>
+<!DOCTYPE PROGRAM SYSTEM "program_09.dtd">
+<PROGRAM NAME="sample.exe" EXE_FORMAT="Portable Executable (PE)" IMAGE_BASE="00400000">
+    <SYMBOL_TABLE>
+        <SYMBOL ADDRESS="ram:00403000" NAME="g_config" NAMESPACE="" TYPE="global" SOURCE_TYPE="USER_DEFINED" PRIMARY="y" />
+        <SYMBOL ADDRESS="00404000" NAME="DAT_00404000" NAMESPACE="" TYPE="global" SOURCE_TYPE="DEFAULT" PRIMARY="y" />
+    </SYMBOL_TABLE>
+    <FUNCTIONS>
+        <FUNCTION ENTRY_POINT="00401000" NAME="parse_header" LIBRARY_FUNCTION="n">
+            <ADDRESS_RANGE START="00401000" END="0040104f" />
+            <ADDRESS_RANGE START="00409000" END="0040900f" />
+        </FUNCTION>
+        <FUNCTION ENTRY_POINT="00402000" NAME="FUN_00402000" LIBRARY_FUNCTION="n">
+            <ADDRESS_RANGE START="00402000" END="0040201f" />
+        </FUNCTION>
+    </FUNCTIONS>
+    <COMMENTS>
+        <COMMENT ADDRESS="00401004" TYPE="end-of-line">checks the &lt;magic&gt; &amp; size</COMMENT>
+    </COMMENTS>
+</PROGRAM>
+"#;
+        let project = parse_ghidra_xml(xml).unwrap();
+        assert_eq!(
+            project.functions[&0x401000],
+            vec![(0x401000, 0x50), (0x409000, 0x10)]
+        );
+        assert_eq!(project.functions[&0x402000], vec![(0x402000, 0x20)]);
+        assert_eq!(
+            project.names.values().collect::<Vec<_>>(),
+            ["parse_header", "g_config"]
+        );
+        assert_eq!(project.comments[&0x401004], "checks the <magic> & size");
+        assert!(parse_ghidra_xml("<NOT_A_PROGRAM />").is_err());
+
+        let script = "fs functions\n\
+                      af+ 0x401000 sym.parse_header\n\
+                      afb+ 0x401000 0x401000 0x50 0x401050\n\
+                      af+ 0x402000 fcn.00402000\n\
+                      afn decode @ 0x402000\n\
+                      f sym.imp.printf 8 0x405000\n\
+                      f g_config 4 @ 0x403000\n\
+                      \"CCu base64:Y2hlY2tzIHRoZSBtYWdpYw== @ 0x401004\"\n\
+                      CC plain note @ 0x401008\n\
+                      e asm.arch=x86\n";
+        let project = parse_r2_script(script).unwrap();
+        assert_eq!(project.functions[&0x401000], vec![(0x401000, 0x50)]);
+        assert!(project.functions[&0x402000].is_empty());
+        assert_eq!(
+            project.names.values().collect::<Vec<_>>(),
+            ["parse_header", "decode", "g_config"]
+        );
+        assert_eq!(project.comments[&0x401004], "checks the magic");
+        assert_eq!(project.comments[&0x401008], "plain note");
+        assert!(parse_r2_script("afb+ 0x401000").is_err());
+
+        let mut ws = VivWorkspace::new("", false);
+        assert_eq!(apply(&mut ws, &project), 7);
+        assert!(ws.is_function(0x401000) && ws.is_function(0x402000));
+        assert_eq!(
+            ws.get_function_bounds(0x401000),
+            Some(vec![(0x401000, 0x50)])
+        );
+        assert_eq!(ws.get_name(0x402000, false).as_deref(), Some("decode"));
+        assert_eq!(ws.get_comment(0x401004), "checks the magic");
+    }
+}
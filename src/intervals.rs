@@ -0,0 +1,181 @@
+//! An index of address ranges, for finding what holds an address.
+//!
+//! [`IntervalIndex`] maps half-open ranges of addresses to values, and answers which ranges hold
+//! an address (a stab query) and which ranges overlap a range. The ranges may overlap and nest,
+//! as sections do segments, or functions their chunks, and several may start at one address.
+//!
+//! The ranges are kept sorted by start, with the greatest end among each prefix of them: a
+//! query finds the last range starting at or before the address by binary search, and walks
+//! back only as far as a range could still reach it. Inserting is linear in the ranges after
+//! the new one, so an index is best built in one go with `collect` where it is large.
+
+use std::ops::Range;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry<T> {
+    start: u64,
+    end: u64,
+    value: T,
+}
+
+/// Values by half-open address range
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntervalIndex<T> {
+    /// Sorted by start, ranges starting at the same address in the order they were inserted
+    entries: Vec<Entry<T>>,
+    /// The greatest end of the entries up to each one
+    max_end: Vec<u64>,
+}
+
+impl<T> Default for IntervalIndex<T> {
+    fn default() -> Self {
+        IntervalIndex {
+            entries: Vec::new(),
+            max_end: Vec::new(),
+        }
+    }
+}
+
+impl<T> IntervalIndex<T> {
+    pub fn new() -> Self {
+        IntervalIndex::default()
+    }
+
+    /// Add `value` for `range`. An empty range holds no address, so no query finds it.
+    pub fn insert(&mut self, range: Range<u64>, value: T) {
+        let idx = self.entries.partition_point(|entry| entry.start <= range.start);
+        self.entries.insert(
+            idx,
+            Entry {
+                start: range.start,
+                end: range.end,
+                value,
+            },
+        );
+        self.max_end.truncate(idx);
+        self.update_max_end();
+    }
+
+    /// Keep only the ranges `keep` says to
+    pub fn retain(&mut self, mut keep: impl FnMut(Range<u64>, &T) -> bool) {
+        self.entries
+            .retain(|entry| keep(entry.start..entry.end, &entry.value));
+        self.max_end.clear();
+        self.update_max_end();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The ranges holding `va`, by start
+    pub fn stab(&self, va: u64) -> Vec<(Range<u64>, &T)> {
+        self.overlapping(va..va.saturating_add(1))
+    }
+
+    /// The range holding `va` which starts last, the innermost of nested ones, and of those
+    /// starting there the last inserted
+    pub fn get(&self, va: u64) -> Option<(Range<u64>, &T)> {
+        self.stab(va).pop()
+    }
+
+    /// The ranges sharing an address with `range`, by start
+    pub fn overlapping(&self, range: Range<u64>) -> Vec<(Range<u64>, &T)> {
+        let mut found = Vec::new();
+        if range.start >= range.end {
+            return found;
+        }
+        let idx = self.entries.partition_point(|entry| entry.start < range.end);
+        for i in (0..idx).rev() {
+            if self.max_end[i] <= range.start {
+                break;
+            }
+            let entry = &self.entries[i];
+            if entry.end > range.start && entry.start < entry.end {
+                found.push((entry.start..entry.end, &entry.value));
+            }
+        }
+        found.reverse();
+        found
+    }
+
+    /// Every range, by start
+    pub fn iter(&self) -> impl Iterator<Item = (Range<u64>, &T)> + '_ {
+        self.entries
+            .iter()
+            .map(|entry| (entry.start..entry.end, &entry.value))
+    }
+
+    /// Bring `max_end` up to date from the last entry it covers
+    fn update_max_end(&mut self) {
+        let mut max = self.max_end.last().copied().unwrap_or(0);
+        for entry in self.entries[self.max_end.len()..].iter() {
+            max = max.max(entry.end);
+            self.max_end.push(max);
+        }
+    }
+}
+
+impl<T> FromIterator<(Range<u64>, T)> for IntervalIndex<T> {
+    fn from_iter<I: IntoIterator<Item = (Range<u64>, T)>>(iter: I) -> Self {
+        let mut entries: Vec<Entry<T>> = iter
+            .into_iter()
+            .map(|(range, value)| Entry {
+                start: range.start,
+                end: range.end,
+                value,
+            })
+            .collect();
+        // stable, so ranges starting together stay in order
+        entries.sort_by_key(|entry| entry.start);
+        let mut index = IntervalIndex {
+            entries,
+            max_end: Vec::new(),
+        };
+        index.update_max_end();
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names<'a>(found: Vec<(Range<u64>, &&'a str)>) -> Vec<&'a str> {
+        found.into_iter().map(|(_, name)| *name).collect()
+    }
+
+    #[test]
+    fn stabs_and_overlaps() {
+        let mut index: IntervalIndex<&str> = [
+            (0x1000..0x3000, "segment"),
+            (0x1000..0x1800, ".text"),
+            (0x1800..0x3000, ".data"),
+        ]
+        .into_iter()
+        .collect();
+        index.insert(0x1100..0x1140, "main");
+        index.insert(0x4000..0x4000, "empty");
+        index.insert(0x8000..u64::MAX, "kernel");
+
+        assert_eq!(names(index.stab(0x1120)), ["segment", ".text", "main"]);
+        assert_eq!(names(index.stab(0x2fff)), ["segment", ".data"]);
+        assert_eq!(names(index.stab(0x3000)), Vec::<&str>::new());
+        assert_eq!(names(index.stab(0x4000)), Vec::<&str>::new());
+        assert_eq!(names(index.stab(u64::MAX - 1)), ["kernel"]);
+        assert_eq!(index.get(0x1800), Some((0x1800..0x3000, &".data")));
+        assert_eq!(
+            names(index.overlapping(0x17f0..0x1810)),
+            ["segment", ".text", ".data"]
+        );
+        assert_eq!(names(index.overlapping(0x3000..0x8000)), Vec::<&str>::new());
+
+        index.retain(|range, _| range.end - range.start < 0x1000);
+        assert_eq!(index.len(), 3);
+        assert_eq!(names(index.stab(0x1120)), [".text", "main"]);
+    }
+}
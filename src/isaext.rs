@@ -0,0 +1,464 @@
+//! The instruction set extensions a binary uses, by function.
+//!
+//! Whether a binary runs on a CPU without AVX2 or on an ARMv8.0 core without the LSE atomics is a
+//! question of the instructions it has in its functions, not of what its headers say. [`scan`]
+//! goes through the instructions of each function of a workspace (its `LOC_OP` locations) and
+//! counts them by mnemonic and by [`Extension`]. A function full of SIMD or using the AES, SHA
+//! or carry-less multiply instructions is often hand written crypto or a codec, see
+//! [`IsaReport::notable`].
+//!
+//! x86 goes by the CPUID feature bits iced-x86 gives each instruction, so it needs one of the
+//! features bringing iced-x86 in; without it x86 functions have no counts. AArch64 and ARM (not
+//! Thumb) go by the encoding groups of the instruction words, which the histogram counts in
+//! place of mnemonics.
+
+use crate::{constants::LOC_OP, envi::Arch, memory::Memory, workspace::VivWorkspace};
+use std::{collections::BTreeMap, fmt};
+
+/// An instruction set extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Extension {
+    Mmx,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    /// SSE4.1, SSE4.2 and SSE4a
+    Sse4,
+    Avx,
+    Avx2,
+    Fma,
+    /// Any of the AVX-512 subsets
+    Avx512,
+    AesNi,
+    /// `PCLMULQDQ` and `VPCLMULQDQ`
+    Pclmul,
+    ShaNi,
+    /// `RDRAND` and `RDSEED`
+    Rdrand,
+    /// BMI1 and BMI2
+    Bmi,
+    /// Locked read-modify-write instructions
+    Atomics,
+    /// ARM Advanced SIMD
+    Neon,
+    Sve,
+    /// The ARM AES, SHA, SHA-3, SM3, SM4 and polynomial multiply instructions
+    ArmCrypto,
+    /// The ARM `CRC32` instructions
+    Crc32,
+    /// The ARMv8.1 Large System Extensions atomics
+    Lse,
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::Mmx => "MMX",
+            Extension::Sse => "SSE",
+            Extension::Sse2 => "SSE2",
+            Extension::Sse3 => "SSE3",
+            Extension::Ssse3 => "SSSE3",
+            Extension::Sse4 => "SSE4",
+            Extension::Avx => "AVX",
+            Extension::Avx2 => "AVX2",
+            Extension::Fma => "FMA",
+            Extension::Avx512 => "AVX-512",
+            Extension::AesNi => "AES-NI",
+            Extension::Pclmul => "PCLMUL",
+            Extension::ShaNi => "SHA",
+            Extension::Rdrand => "RDRAND",
+            Extension::Bmi => "BMI",
+            Extension::Atomics => "atomics",
+            Extension::Neon => "NEON",
+            Extension::Sve => "SVE",
+            Extension::ArmCrypto => "ARM crypto",
+            Extension::Crc32 => "CRC32",
+            Extension::Lse => "LSE",
+        }
+    }
+
+    /// Whether the extension is vector instructions
+    pub fn is_simd(&self) -> bool {
+        matches!(
+            self,
+            Extension::Mmx
+                | Extension::Sse
+                | Extension::Sse2
+                | Extension::Sse3
+                | Extension::Ssse3
+                | Extension::Sse4
+                | Extension::Avx
+                | Extension::Avx2
+                | Extension::Fma
+                | Extension::Avx512
+                | Extension::Neon
+                | Extension::Sve
+        )
+    }
+
+    /// Whether the extension is instructions for ciphers and hashes
+    pub fn is_crypto(&self) -> bool {
+        matches!(
+            self,
+            Extension::AesNi | Extension::Pclmul | Extension::ShaNi | Extension::ArmCrypto
+        )
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The smallest share of SIMD instructions making a function notable
+const SIMD_SHARE: usize = 4;
+/// The fewest instructions a function notable for its SIMD has
+const MIN_SIMD_INSNS: usize = 8;
+
+/// The instructions of a function
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionIsa {
+    pub fva: i32,
+    pub insns: usize,
+    /// By mnemonic, or encoding group where there is no disassembler
+    pub mnemonics: BTreeMap<String, usize>,
+    pub extensions: BTreeMap<Extension, usize>,
+}
+
+impl FunctionIsa {
+    /// How many instructions are of a SIMD extension
+    pub fn simd(&self) -> usize {
+        self.extensions
+            .iter()
+            .filter(|(ext, _)| ext.is_simd())
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Whether the function uses crypto instructions, or is mostly SIMD
+    pub fn is_notable(&self) -> bool {
+        self.extensions.keys().any(Extension::is_crypto)
+            || self.insns >= MIN_SIMD_INSNS && self.simd() * SIMD_SHARE >= self.insns
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IsaReport {
+    pub functions: Vec<FunctionIsa>,
+}
+
+impl IsaReport {
+    /// The instructions of each extension, with the functions using it
+    pub fn extensions(&self) -> BTreeMap<Extension, (usize, Vec<i32>)> {
+        let mut totals: BTreeMap<Extension, (usize, Vec<i32>)> = BTreeMap::new();
+        for func in self.functions.iter() {
+            for (ext, count) in func.extensions.iter() {
+                let total = totals.entry(*ext).or_default();
+                total.0 += count;
+                total.1.push(func.fva);
+            }
+        }
+        totals
+    }
+
+    /// The instruction histogram of the whole binary
+    pub fn mnemonics(&self) -> BTreeMap<String, usize> {
+        let mut totals: BTreeMap<String, usize> = BTreeMap::new();
+        for func in self.functions.iter() {
+            for (mnemonic, count) in func.mnemonics.iter() {
+                *totals.entry(mnemonic.clone()).or_default() += count;
+            }
+        }
+        totals
+    }
+
+    /// The functions worth a closer look, see [`FunctionIsa::is_notable`]
+    pub fn notable(&self) -> Vec<i32> {
+        self.functions
+            .iter()
+            .filter(|func| func.is_notable())
+            .map(|func| func.fva)
+            .collect()
+    }
+}
+
+impl fmt::Display for IsaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (ext, (count, functions)) in self.extensions() {
+            writeln!(
+                f,
+                "{:<12} {:>8} instructions in {} functions",
+                ext.name(),
+                count,
+                functions.len()
+            )?;
+        }
+        for fva in self.notable() {
+            writeln!(f, "notable      {:#x}", fva as u32)?;
+        }
+        Ok(())
+    }
+}
+
+/// The mnemonic or encoding group of an instruction, with its extension if any
+type Classified = (String, Option<Extension>);
+
+/// Classifies the instruction at an address from its bytes
+type Classifier = fn(u64, &[u8]) -> Option<Classified>;
+
+/// The extension of an AArch64 instruction word, with its encoding group
+pub fn classify_a64(word: u32) -> Classified {
+    let ext = if word & 0xffff_cc00 == 0x4e28_4800
+        || word & 0xffe0_8c00 == 0x5e00_0000
+        || word & 0xffff_0c00 == 0x5e28_0800
+        || word & 0xff00_0000 == 0xce00_0000
+        || word & 0xbfe0_fc00 == 0x0ee0_e000
+    {
+        // AES, SHA-1/SHA-256, the ARMv8.2 SHA-512/SHA-3/SM3/SM4 group, PMULL
+        Some(Extension::ArmCrypto)
+    } else if word & 0x7fe0_f000 == 0x1ac0_4000 {
+        Some(Extension::Crc32)
+    } else if word & 0x3f20_0c00 == 0x3820_0000 || word & 0x3fa0_7c00 == 0x08a0_7c00 {
+        // LDADD, LDCLR, LDEOR, LDSET, the min and max, SWP; CAS
+        Some(Extension::Lse)
+    } else if word & 0x1e00_0000 == 0x0400_0000 {
+        Some(Extension::Sve)
+    } else if word & 0x0e00_0000 == 0x0e00_0000 && (word >> 28 & 1 == 0 || word >> 30 & 1 == 1)
+    {
+        // the vector and the scalar Advanced SIMD groups, leaving floating point
+        Some(Extension::Neon)
+    } else {
+        None
+    };
+    let group = match (ext, word >> 25 & 0xf) {
+        (Some(ext), _) => ext.name().to_lowercase(),
+        (None, 0b1000 | 0b1001) => "data processing (immediate)".to_string(),
+        (None, 0b1010 | 0b1011) => "branch".to_string(),
+        (None, 0b0101 | 0b1101) => "data processing (register)".to_string(),
+        (None, 0b0111 | 0b1111) => "floating point".to_string(),
+        (None, 0b0100 | 0b0110 | 0b1100 | 0b1110) => "load/store".to_string(),
+        _ => "other".to_string(),
+    };
+    (group, ext)
+}
+
+/// The extension of an ARM (A32) instruction word, with its encoding group
+pub fn classify_a32(word: u32) -> Classified {
+    if word & 0xfe00_0000 == 0xf200_0000 || word & 0xff10_0000 == 0xf400_0000 {
+        return ("neon".to_string(), Some(Extension::Neon));
+    }
+    let group = match word >> 25 & 0x7 {
+        0b000 | 0b001 => "data processing",
+        0b010 | 0b011 => "load/store",
+        0b100 => "load/store multiple",
+        0b101 => "branch",
+        _ => "coprocessor",
+    };
+    (group.to_string(), None)
+}
+
+/// The mnemonic of an x86 instruction, with its extension
+#[cfg(feature = "iced-x86")]
+pub fn classify_x86(insn: &iced_x86::Instruction) -> Classified {
+    let mnemonic = format!("{:?}", insn.mnemonic()).to_lowercase();
+    if insn.has_lock_prefix() {
+        return (mnemonic, Some(Extension::Atomics));
+    }
+    let ext = insn.cpuid_features().iter().find_map(|feature| {
+        let name = format!("{:?}", feature);
+        let ext = match name.as_str() {
+            _ if name.starts_with("AVX512") => Extension::Avx512,
+            _ if name.starts_with("AVX2") => Extension::Avx2,
+            _ if name.starts_with("AVX") || name == "F16C" => Extension::Avx,
+            _ if name.starts_with("FMA") => Extension::Fma,
+            _ if name.starts_with("VAES") || name == "AES" => Extension::AesNi,
+            _ if name.ends_with("PCLMULQDQ") => Extension::Pclmul,
+            _ if name.starts_with("SHA") => Extension::ShaNi,
+            _ if name.starts_with("SSE4") => Extension::Sse4,
+            "SSSE3" => Extension::Ssse3,
+            "SSE3" => Extension::Sse3,
+            "SSE2" => Extension::Sse2,
+            "SSE" => Extension::Sse,
+            "MMX" => Extension::Mmx,
+            "RDRAND" | "RDSEED" => Extension::Rdrand,
+            "BMI1" | "BMI2" => Extension::Bmi,
+            _ => return None,
+        };
+        Some(ext)
+    });
+    (mnemonic, ext)
+}
+
+/// Classifies the instruction in `bytes`, for the architecture of the workspace
+fn classifier(arch: Arch) -> Option<Classifier> {
+    match arch {
+        Arch::A64 => Some(a64),
+        Arch::ArmV7 => Some(a32),
+        #[cfg(feature = "iced-x86")]
+        Arch::I386 => Some(x86_32),
+        #[cfg(feature = "iced-x86")]
+        Arch::Amd64 => Some(x86_64),
+        _ => None,
+    }
+}
+
+fn word(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn a64(_va: u64, bytes: &[u8]) -> Option<Classified> {
+    word(bytes).map(classify_a64)
+}
+
+fn a32(_va: u64, bytes: &[u8]) -> Option<Classified> {
+    word(bytes).map(classify_a32)
+}
+
+#[cfg(feature = "iced-x86")]
+fn x86_32(va: u64, bytes: &[u8]) -> Option<Classified> {
+    x86(va, bytes, 32)
+}
+
+#[cfg(feature = "iced-x86")]
+fn x86_64(va: u64, bytes: &[u8]) -> Option<Classified> {
+    x86(va, bytes, 64)
+}
+
+#[cfg(feature = "iced-x86")]
+fn x86(va: u64, bytes: &[u8], bitness: u32) -> Option<Classified> {
+    let mut decoder =
+        iced_x86::Decoder::with_ip(bitness, bytes, va, iced_x86::DecoderOptions::NONE);
+    let insn = decoder.decode();
+    (!insn.is_invalid()).then(|| classify_x86(&insn))
+}
+
+/// The (va, size) of the instructions of the function at `fva`, by its code blocks or else
+/// its bounds
+fn function_ops(workspace: &VivWorkspace, fva: i32) -> Vec<(i32, i32)> {
+    let mut ranges: Vec<(i32, i32)> = workspace
+        .get_function_blocks(fva)
+        .into_iter()
+        .map(|(va, size, ..)| (va, size))
+        .collect();
+    if ranges.is_empty() {
+        ranges = workspace.get_function_bounds(fva).unwrap_or_default();
+    }
+    let mut ops = Vec::new();
+    for (start, size) in ranges {
+        let end = start as i64 + size as i64;
+        let mut va = start;
+        while (va as i64) < end {
+            match workspace.get_location(va) {
+                Some((lva, lsize, ltype, _)) if lva == va && lsize > 0 => {
+                    if ltype == LOC_OP {
+                        ops.push((va, lsize));
+                    }
+                    va = va.wrapping_add(lsize);
+                }
+                _ => va = va.wrapping_add(1),
+            }
+        }
+    }
+    ops
+}
+
+/// Count the instructions of each function of the workspace by mnemonic and extension
+pub fn scan(workspace: &VivWorkspace) -> IsaReport {
+    let mut report = IsaReport::default();
+    let Some(classify) = Arch::from_envi(workspace.arch).and_then(classifier) else {
+        return report;
+    };
+    let mut functions = workspace.get_functions();
+    functions.sort_unstable();
+    for fva in functions {
+        let mut func = FunctionIsa {
+            fva,
+            ..Default::default()
+        };
+        for (va, size) in function_ops(workspace, fva) {
+            let Some(bytes) = workspace.read_memory(va, size) else {
+                continue;
+            };
+            let Some((mnemonic, ext)) = classify(va as u32 as u64, &bytes) else {
+                continue;
+            };
+            func.insns += 1;
+            *func.mnemonics.entry(mnemonic).or_default() += 1;
+            if let Some(ext) = ext {
+                *func.extensions.entry(ext).or_default() += 1;
+            }
+        }
+        report.functions.push(func);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_A64, MM_EXEC, MM_READ};
+
+    #[test]
+    fn counts_extensions() {
+        let words: [u32; 6] = [
+            0x4e28_4800, // aese v0.16b, v0.16b
+            0x4e28_6800, // aesmc v0.16b, v0.16b
+            0x4e20_8400, // add v0.16b, v0.16b, v0.16b
+            0x1ac1_4000, // crc32b w0, w0, w1
+            0xb820_0020, // ldadd w0, w0, [x1]
+            0xd65f_03c0, // ret
+        ];
+        let code: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_A64 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "text", code, None);
+        for va in (0x1000..0x1018).step_by(4) {
+            ws.add_location(va, 4, LOC_OP, Some(vec![]));
+        }
+        ws.add_function(0x1000, vec![(0x1000, 0x18)]);
+
+        let report = scan(&ws);
+        let func = &report.functions[0];
+        assert_eq!(func.insns, 6);
+        assert_eq!(
+            func.extensions,
+            BTreeMap::from([
+                (Extension::Neon, 1),
+                (Extension::ArmCrypto, 2),
+                (Extension::Crc32, 1),
+                (Extension::Lse, 1),
+            ])
+        );
+        assert_eq!(report.mnemonics().get("branch"), Some(&1));
+        assert_eq!(report.notable(), [0x1000]);
+        assert_eq!(
+            classify_a64(0x0420_3000).1,
+            Some(Extension::Sve),
+            "sve index"
+        );
+        assert_eq!(classify_a64(0x1e22_2800).1, None, "scalar fadd");
+        assert!(report.to_string().contains("ARM crypto"));
+
+        #[cfg(feature = "iced-x86")]
+        {
+            // aesenc xmm0, xmm1; lock add [rax], eax; vpaddd ymm0, ymm0, ymm1
+            let code: [&[u8]; 3] = [
+                &[0x66, 0x0f, 0x38, 0xdc, 0xc1],
+                &[0xf0, 0x01, 0x00],
+                &[0xc5, 0xfd, 0xfe, 0xc1],
+            ];
+            let classified: Vec<Classified> =
+                code.iter().filter_map(|bytes| x86_64(0, bytes)).collect();
+            assert_eq!(
+                classified,
+                [
+                    ("aesenc".to_string(), Some(Extension::AesNi)),
+                    ("add".to_string(), Some(Extension::Atomics)),
+                    ("vpaddd".to_string(), Some(Extension::Avx2)),
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,887 @@
+//! The journal of a workspace: the changes made to it, in the order they were made.
+//!
+//! Where an annotation snapshot (see [`crate::storage`]) keeps what the workspace ended up with,
+//! the journal keeps how it got there. Replaying it onto a fresh workspace rebuilds the
+//! workspace, replaying a prefix rebuilds it as it was part of the way through, and the events
+//! past a sequence number are what a peer that has seen up to there is missing.
+//!
+//! A workspace keeps a journal once [`VivWorkspace::start_journal`] is called. The analysis
+//! passes run on the workspace itself, and each pass is marked in the journal by an
+//! [`Event::Pass`], so [`Journal::bisect`] can tell which pass made a bad artifact. A copy of the
+//! workspace goes on with a journal of its own.
+
+use crate::{overrides::RegionKind, workspace::VivWorkspace};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// The header line every journal file starts with.
+pub const JOURNAL_MAGIC: &str = "VIVRS-JOURNAL 1";
+
+/// One change to a workspace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A file was added, under its normalized name
+    AddFile {
+        name: String,
+        imagebase: i32,
+    },
+    AddMemoryMap {
+        va: i32,
+        perms: i32,
+        fname: String,
+        bytes: Vec<u8>,
+    },
+    AddSegment {
+        va: i32,
+        size: i32,
+        name: String,
+        fname: String,
+    },
+    SetFileMeta {
+        fname: String,
+        key: String,
+        value: i32,
+    },
+    /// The import slot `va`, named after the library and function it imports
+    AddImport {
+        va: i32,
+        libname: String,
+        impname: String,
+    },
+    AddExport {
+        va: i32,
+        name: String,
+        fname: String,
+    },
+    /// A relocation of `size` bytes, `data` being what its type needs, such as the addend
+    AddRelocation {
+        va: i32,
+        rtype: i32,
+        data: Vec<u8>,
+        size: i32,
+    },
+    AddLocation {
+        va: i32,
+        size: i32,
+        ltype: i32,
+        tinfo: Vec<(i32, i32)>,
+    },
+    AddXref {
+        from: i32,
+        to: i32,
+        rtype: i32,
+        rflags: i32,
+    },
+    /// The name `va` ended up with, after it was made unique
+    SetName {
+        va: i32,
+        name: String,
+    },
+    /// `va` renamed along with the references to its old name (see `VivWorkspace::rename`),
+    /// one event for the whole change. `comments` are the VAs whose comments it rewrote.
+    Rename {
+        va: i32,
+        name: String,
+        comments: Vec<i32>,
+    },
+    /// An empty comment removes the comment
+    SetComment {
+        va: i32,
+        comment: String,
+    },
+    SetType {
+        va: i32,
+        tname: String,
+    },
+    SetMeta {
+        key: String,
+        value: Option<String>,
+    },
+    SetVaSetRows {
+        name: String,
+        rows: Vec<i32>,
+    },
+    AddFunction {
+        fva: i32,
+        ranges: Vec<(i32, i32)>,
+    },
+    DelFunction {
+        fva: i32,
+    },
+    SetFunctionMeta {
+        fva: i32,
+        key: String,
+        value: i32,
+    },
+    /// Every range of code making up the function, replacing those it had
+    SetFunctionBounds {
+        fva: i32,
+        ranges: Vec<(i32, i32)>,
+    },
+    AddFunctionChunk {
+        fva: i32,
+        va: i32,
+        size: i32,
+    },
+    DelFunctionChunk {
+        fva: i32,
+        va: i32,
+    },
+    AddFunctionEntry {
+        fva: i32,
+        va: i32,
+    },
+    AddCodeBlock {
+        va: i32,
+        size: i32,
+        fva: i32,
+    },
+    DelCodeBlock {
+        va: i32,
+    },
+    AddRegionOverride {
+        va: i32,
+        size: i32,
+        kind: RegionKind,
+    },
+    AddTag {
+        va: i32,
+        tag: String,
+    },
+    DelTag {
+        va: i32,
+        tag: String,
+    },
+    SetArchitecture(u32),
+    SetPointerSize(i32),
+    /// Bytes of a memory map were overwritten
+    PatchMemory {
+        va: i32,
+        bytes: Vec<u8>,
+    },
+    /// The analysis pass with this name started
+    Pass(String),
+}
+
+impl Event {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Event::AddFile { name, imagebase } => {
+                writeln!(w, "file {:#x} {}", imagebase, field(name))
+            }
+            Event::AddMemoryMap {
+                va,
+                perms,
+                fname,
+                bytes,
+            } => writeln!(
+                w,
+                "mmap {:#x} {:#x} {} {}",
+                va,
+                perms,
+                field(fname),
+                hex(bytes)
+            ),
+            Event::AddSegment {
+                va,
+                size,
+                name,
+                fname,
+            } => writeln!(
+                w,
+                "segment {:#x} {:#x} {} {}",
+                va,
+                size,
+                field(fname),
+                field(name)
+            ),
+            Event::SetFileMeta { fname, key, value } => {
+                writeln!(w, "filemeta {} {} {:#x}", field(fname), field(key), value)
+            }
+            Event::AddImport {
+                va,
+                libname,
+                impname,
+            } => writeln!(w, "import {:#x} {} {}", va, field(libname), field(impname)),
+            Event::AddExport { va, name, fname } => {
+                writeln!(w, "export {:#x} {} {}", va, field(fname), field(name))
+            }
+            Event::AddRelocation {
+                va,
+                rtype,
+                data,
+                size,
+            } => writeln!(w, "reloc {:#x} {} {:#x} {}", va, rtype, size, hex(data)),
+            Event::AddLocation {
+                va,
+                size,
+                ltype,
+                tinfo,
+            } => writeln!(
+                w,
+                "location {:#x} {:#x} {} {}",
+                va,
+                size,
+                ltype,
+                pairs(tinfo)
+            ),
+            Event::AddXref {
+                from,
+                to,
+                rtype,
+                rflags,
+            } => writeln!(w, "xref {:#x} {:#x} {} {:#x}", from, to, rtype, rflags),
+            Event::SetName { va, name } => writeln!(w, "name {:#x} {}", va, field(name)),
+            Event::Rename { va, name, comments } => {
+                writeln!(w, "rename {:#x} {} {}", va, field(name), vas(comments))
+            }
+            Event::SetComment { va, comment } => {
+                writeln!(w, "comment {:#x} {}", va, field(comment))
+            }
+            Event::SetType { va, tname } => writeln!(w, "type {:#x} {}", va, field(tname)),
+            Event::SetMeta { key, value } => match value {
+                Some(value) => writeln!(w, "meta {} {}", field(key), field(value)),
+                None => writeln!(w, "meta {}", field(key)),
+            },
+            Event::SetVaSetRows { name, rows } => {
+                writeln!(w, "vaset {} {}", field(name), vas(rows))
+            }
+            Event::AddFunction { fva, ranges } => {
+                writeln!(w, "function {:#x} {}", fva, pairs(ranges))
+            }
+            Event::DelFunction { fva } => writeln!(w, "delfunction {:#x}", fva),
+            Event::SetFunctionMeta { fva, key, value } => {
+                writeln!(w, "funcmeta {:#x} {} {:#x}", fva, field(key), value)
+            }
+            Event::SetFunctionBounds { fva, ranges } => {
+                writeln!(w, "bounds {:#x} {}", fva, pairs(ranges))
+            }
+            Event::AddFunctionChunk { fva, va, size } => {
+                writeln!(w, "chunk {:#x} {:#x} {:#x}", fva, va, size)
+            }
+            Event::DelFunctionChunk { fva, va } => writeln!(w, "delchunk {:#x} {:#x}", fva, va),
+            Event::AddFunctionEntry { fva, va } => writeln!(w, "entry {:#x} {:#x}", fva, va),
+            Event::AddCodeBlock { va, size, fva } => {
+                writeln!(w, "block {:#x} {:#x} {:#x}", va, size, fva)
+            }
+            Event::DelCodeBlock { va } => writeln!(w, "delblock {:#x}", va),
+            Event::AddRegionOverride { va, size, kind } => {
+                writeln!(w, "override {:#x} {:#x} {}", va, size, kind)
+            }
+            Event::AddTag { va, tag } => writeln!(w, "tag {:#x} {}", va, field(tag)),
+            Event::DelTag { va, tag } => writeln!(w, "untag {:#x} {}", va, field(tag)),
+            Event::SetArchitecture(arch) => writeln!(w, "arch {:#x}", arch),
+            Event::SetPointerSize(size) => writeln!(w, "psize {}", size),
+            Event::PatchMemory { va, bytes } => writeln!(w, "patch {:#x} {}", va, hex(bytes)),
+            Event::Pass(name) => writeln!(w, "pass {}", field(name)),
+        }
+    }
+
+    fn parse(line: &str) -> io::Result<Self> {
+        let mut parts = line.split(' ');
+        let kind = parts.next().unwrap_or_default();
+        let mut next = || {
+            parts
+                .next()
+                .ok_or_else(|| invalid(&format!("Truncated journal record: {}", line)))
+        };
+        let event = match kind {
+            "file" => Event::AddFile {
+                imagebase: number(next()?)?,
+                name: unfield(next()?),
+            },
+            "mmap" => Event::AddMemoryMap {
+                va: number(next()?)?,
+                perms: number(next()?)?,
+                fname: unfield(next()?),
+                bytes: unhex(next()?)?,
+            },
+            "segment" => Event::AddSegment {
+                va: number(next()?)?,
+                size: number(next()?)?,
+                fname: unfield(next()?),
+                name: unfield(next()?),
+            },
+            "filemeta" => Event::SetFileMeta {
+                fname: unfield(next()?),
+                key: unfield(next()?),
+                value: number(next()?)?,
+            },
+            "import" => Event::AddImport {
+                va: number(next()?)?,
+                libname: unfield(next()?),
+                impname: unfield(next()?),
+            },
+            "export" => Event::AddExport {
+                va: number(next()?)?,
+                fname: unfield(next()?),
+                name: unfield(next()?),
+            },
+            "reloc" => Event::AddRelocation {
+                va: number(next()?)?,
+                rtype: number(next()?)?,
+                size: number(next()?)?,
+                data: unhex(next()?)?,
+            },
+            "location" => Event::AddLocation {
+                va: number(next()?)?,
+                size: number(next()?)?,
+                ltype: number(next()?)?,
+                tinfo: unpairs(next()?)?,
+            },
+            "xref" => Event::AddXref {
+                from: number(next()?)?,
+                to: number(next()?)?,
+                rtype: number(next()?)?,
+                rflags: number(next()?)?,
+            },
+            "name" => Event::SetName {
+                va: number(next()?)?,
+                name: unfield(next()?),
+            },
+            "rename" => Event::Rename {
+                va: number(next()?)?,
+                name: unfield(next()?),
+                comments: unvas(next()?)?,
+            },
+            "comment" => Event::SetComment {
+                va: number(next()?)?,
+                comment: unfield(next()?),
+            },
+            "type" => Event::SetType {
+                va: number(next()?)?,
+                tname: unfield(next()?),
+            },
+            "meta" => Event::SetMeta {
+                key: unfield(next()?),
+                value: next().ok().map(unfield),
+            },
+            "vaset" => Event::SetVaSetRows {
+                name: unfield(next()?),
+                rows: unvas(next()?)?,
+            },
+            "function" => Event::AddFunction {
+                fva: number(next()?)?,
+                ranges: unpairs(next()?)?,
+            },
+            "delfunction" => Event::DelFunction {
+                fva: number(next()?)?,
+            },
+            "funcmeta" => Event::SetFunctionMeta {
+                fva: number(next()?)?,
+                key: unfield(next()?),
+                value: number(next()?)?,
+            },
+            "bounds" => Event::SetFunctionBounds {
+                fva: number(next()?)?,
+                ranges: unpairs(next()?)?,
+            },
+            "chunk" => Event::AddFunctionChunk {
+                fva: number(next()?)?,
+                va: number(next()?)?,
+                size: number(next()?)?,
+            },
+            "delchunk" => Event::DelFunctionChunk {
+                fva: number(next()?)?,
+                va: number(next()?)?,
+            },
+            "entry" => Event::AddFunctionEntry {
+                fva: number(next()?)?,
+                va: number(next()?)?,
+            },
+            "block" => Event::AddCodeBlock {
+                va: number(next()?)?,
+                size: number(next()?)?,
+                fva: number(next()?)?,
+            },
+            "delblock" => Event::DelCodeBlock {
+                va: number(next()?)?,
+            },
+            "override" => Event::AddRegionOverride {
+                va: number(next()?)?,
+                size: number(next()?)?,
+                kind: next()?.parse().map_err(|err: String| invalid(&err))?,
+            },
+            "tag" => Event::AddTag {
+                va: number(next()?)?,
+                tag: unfield(next()?),
+            },
+            "untag" => Event::DelTag {
+                va: number(next()?)?,
+                tag: unfield(next()?),
+            },
+            "arch" => Event::SetArchitecture(number(next()?)? as u32),
+            "psize" => Event::SetPointerSize(number(next()?)?),
+            "patch" => Event::PatchMemory {
+                va: number(next()?)?,
+                bytes: unhex(next()?)?,
+            },
+            "pass" => Event::Pass(unfield(next()?)),
+            _ => return Err(invalid(&format!("Unknown journal record: {}", kind))),
+        };
+        Ok(event)
+    }
+}
+
+/// The events of a workspace, numbered from 0 in the order they happened
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Journal {
+    events: Vec<Event>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Journal::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// The events from sequence number `seq` on, for a peer which has the ones before it
+    pub fn since(&self, seq: usize) -> Journal {
+        Journal {
+            events: self.events.get(seq..).unwrap_or_default().to_vec(),
+        }
+    }
+
+    /// Add events following on from this journal's, such as those from [`Journal::since`]
+    pub fn extend(&mut self, other: &Journal) {
+        self.events.extend(other.events.iter().cloned());
+    }
+
+    /// The analysis pass running when event `seq` happened
+    pub fn pass_at(&self, seq: usize) -> Option<&str> {
+        self.events
+            .get(..=seq)?
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                Event::Pass(name) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Apply every event to `workspace`
+    pub fn replay(&self, workspace: &mut VivWorkspace) {
+        self.replay_to(workspace, self.events.len());
+    }
+
+    /// Apply the events before sequence number `end` to `workspace`
+    pub fn replay_to(&self, workspace: &mut VivWorkspace, end: usize) {
+        for event in self.events.iter().take(end) {
+            workspace.apply_event(event);
+        }
+    }
+
+    /// Find the first event after which the workspace is bad, replaying prefixes of the journal
+    /// onto the workspaces `fresh` makes. Once a workspace is bad it is taken to stay bad, so
+    /// this takes a logarithmic number of replays. Returns None if the whole journal is fine.
+    pub fn bisect<F, B>(&self, mut fresh: F, mut is_bad: B) -> Option<usize>
+    where
+        F: FnMut() -> VivWorkspace,
+        B: FnMut(&VivWorkspace) -> bool,
+    {
+        let mut bad_after = |end: usize| {
+            let mut workspace = fresh();
+            self.replay_to(&mut workspace, end);
+            is_bad(&workspace)
+        };
+        if !bad_after(self.events.len()) {
+            return None;
+        }
+        // Bad after all the events and, as far as we know, fine after none of them
+        let (mut good, mut bad) = (0, self.events.len());
+        while bad - good > 1 {
+            let mid = good + (bad - good) / 2;
+            if bad_after(mid) {
+                bad = mid;
+            } else {
+                good = mid;
+            }
+        }
+        Some(bad - 1)
+    }
+
+    /// Serialize the journal, one event per line: `<kind> <fields>`.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{}", JOURNAL_MAGIC)?;
+        for event in self.events.iter() {
+            event.write(w)?;
+        }
+        Ok(())
+    }
+
+    /// Parse a journal previously produced by `write`.
+    pub fn read<R: BufRead>(r: R) -> io::Result<Self> {
+        let mut lines = r.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header.trim_end() != JOURNAL_MAGIC {
+            return Err(invalid("Missing journal header"));
+        }
+        let mut ret = Journal::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            ret.push(Event::parse(&line)?);
+        }
+        Ok(ret)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        self.write(&mut file)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Journal::read(BufReader::new(fs::File::open(path)?))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// A list of VAs, `-` when empty
+fn vas(vas: &[i32]) -> String {
+    if vas.is_empty() {
+        return "-".to_string();
+    }
+    let vas: Vec<String> = vas.iter().map(|va| format!("{:#x}", va)).collect();
+    vas.join(",")
+}
+
+fn unvas(s: &str) -> io::Result<Vec<i32>> {
+    match s {
+        "-" => Ok(Vec::new()),
+        vas => vas.split(',').map(number).collect(),
+    }
+}
+
+fn number(s: &str) -> io::Result<i32> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).map(|x| x as i32),
+        None => s.parse::<i32>(),
+    };
+    let parsed = parsed.map_err(|_| invalid(&format!("Invalid number in journal: {}", s)))?;
+    Ok(if negative {
+        parsed.wrapping_neg()
+    } else {
+        parsed
+    })
+}
+
+/// A string as one space separated field. The empty string is `\0`.
+fn field(s: &str) -> String {
+    if s.is_empty() {
+        return "\\0".to_string();
+    }
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            ' ' => ret.push_str("\\s"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn unfield(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => {}
+            Some('s') => ret.push(' '),
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some('t') => ret.push('\t'),
+            Some(other) => ret.push(other),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+fn hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+    let mut ret = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(ret, "{:02x}", b);
+    }
+    ret
+}
+
+fn unhex(s: &str) -> io::Result<Vec<u8>> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid("Odd length of hex bytes in journal"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| invalid("Invalid hex bytes in journal"))
+        })
+        .collect()
+}
+
+fn pairs(pairs: &[(i32, i32)]) -> String {
+    if pairs.is_empty() {
+        return "-".to_string();
+    }
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(a, b)| format!("{:#x}:{:#x}", a, b))
+        .collect();
+    pairs.join(",")
+}
+
+fn unpairs(s: &str) -> io::Result<Vec<(i32, i32)>> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    s.split(',')
+        .map(|pair| {
+            let (a, b) = pair
+                .split_once(':')
+                .ok_or_else(|| invalid(&format!("Invalid pair in journal: {}", pair)))?;
+            Ok((number(a)?, number(b)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::Analyzer,
+        constants::{BR_PROC, MM_EXEC, MM_READ, REF_CODE},
+        memory::Memory,
+    };
+    use std::sync::Arc;
+
+    struct Namer;
+
+    impl Analyzer for Namer {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            workspace.make_name(0x1004, "bad name".to_string(), false, true);
+        }
+
+        fn name(&self) -> &str {
+            "namer"
+        }
+    }
+
+    struct Caller;
+
+    impl Analyzer for Caller {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            workspace.add_function(0x1000, vec![(0x1000, 4)]);
+            workspace.add_function(0x1004, vec![(0x1004, 1)]);
+            workspace.add_xref(0x1002, 0x1004, REF_CODE, BR_PROC);
+        }
+
+        fn name(&self) -> &str {
+            "caller"
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.start_journal();
+        ws.add_memory_map(
+            0x1000,
+            MM_READ | MM_EXEC,
+            "test",
+            b"\x90\x90\xcc\xc3\xcc".to_vec(),
+            None,
+        );
+        ws.add_xref(0x1000, 0x1001, REF_CODE, 0);
+        ws.add_analyzer(Arc::new(Caller));
+        ws.run_analyzers();
+        // nothing done to a copy goes into the journal
+        let mut copy = ws.clone();
+        copy.add_function(0x1003, vec![(0x1003, 1)]);
+
+        let mut text = Vec::new();
+        ws.journal().unwrap().write(&mut text).unwrap();
+        let mut replayed = VivWorkspace::new("", false);
+        Journal::read(text.as_slice())
+            .unwrap()
+            .replay(&mut replayed);
+        assert_eq!(ws.get_functions(), [0x1000, 0x1004]);
+        assert_eq!(replayed.get_functions(), ws.get_functions());
+        assert_eq!(ws.get_xrefs(None).len(), 2);
+        assert_eq!(replayed.get_xrefs(None), ws.get_xrefs(None));
+    }
+
+    #[test]
+    fn replay_and_bisect() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.start_journal();
+        ws.add_memory_map(
+            0x1000,
+            MM_READ,
+            "test",
+            b"\x90\x90\xcc\xc3\xcc".to_vec(),
+            None,
+        );
+        ws.add_segment(0x1000, 5, ".text", "test".to_string());
+        ws.add_xref(0x1000, 0x1004, REF_CODE, 0);
+        ws.set_comment(0x1000, "two\tnops", false);
+        ws.add_entry_point(0x1000);
+        ws.add_analyzer(Arc::new(Namer));
+        ws.run_analyzers();
+
+        let journal = ws.journal().unwrap();
+        let mut text = Vec::new();
+        journal.write(&mut text).unwrap();
+        let journal = Journal::read(text.as_slice()).unwrap();
+        assert_eq!(Some(&journal), ws.journal().as_ref());
+
+        let mut replayed = VivWorkspace::new("", false);
+        journal.replay(&mut replayed);
+        assert_eq!(replayed.read_memory(0x1002, 2), Some(vec![0xcc, 0xc3]));
+        assert_eq!(replayed.get_segments(), ws.get_segments());
+        assert_eq!(replayed.get_xrefs_to(0x1004, None).len(), 1);
+        assert_eq!(replayed.get_comments(), ws.get_comments());
+        assert_eq!(replayed.get_entry_points(), [0x1000]);
+        assert_eq!(
+            replayed.get_name(0x1004, false).as_deref(),
+            Some("bad name")
+        );
+
+        let culprit = journal
+            .bisect(
+                || VivWorkspace::new("", false),
+                |ws| ws.get_name(0x1004, false).is_some(),
+            )
+            .unwrap();
+        assert_eq!(journal.pass_at(culprit), Some("namer"));
+        assert_eq!(journal.since(culprit).len(), 1);
+    }
+
+    #[test]
+    fn replays_a_loaded_file() {
+        use crate::{
+            overrides::RegionKind,
+            pe::import::{ImportTable, ImportedFunction},
+        };
+
+        let mut imports = ImportTable::default();
+        for function in ["CreateFileA", "CloseHandle"] {
+            imports.add_function("KERNEL32.dll", ImportedFunction::by_name(function));
+        }
+        let (mut pe, idata) = imports.tiny_image();
+        assert!(idata.data.len() <= 0x100);
+        let mut put = |offset: usize, bytes: &[u8]| {
+            pe[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        let raw = |rva: usize| rva - 0x1000 + 0x200;
+        // .idata is 0x200 bytes, its virtual size and the export and base relocation directories
+        // pointing into it, with an export of "helper" at 0x1100 and a base relocation at
+        // 0x1180 patching the pointer at 0x11f0
+        put(0x140, &0x200u32.to_le_bytes());
+        let words = |words: &[u32]| {
+            words
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        put(0xb8, &words(&[0x1100, 0x60]));
+        put(0xe0, &words(&[0x1180, 12]));
+        put(
+            raw(0x1100),
+            &words(&[0, 0, 0, 0x1150, 1, 1, 1, 0x1130, 0x1134, 0x1138]),
+        );
+        put(raw(0x1130), &words(&[0x1000, 0x1140]));
+        put(raw(0x1140), b"helper\0");
+        put(raw(0x1150), b"tiny.exe\0");
+        put(raw(0x1180), &words(&[0x1000, 12, 0x31f0]));
+        put(raw(0x11f0), &0x401000u32.to_le_bytes());
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.start_journal();
+        let fname = ws.load_from_bytes("tiny.exe", &pe, None);
+        // what the analysis passes go on to make of a function
+        let fva = 0x401000;
+        ws.add_function(fva, vec![(fva, 0x10)]);
+        ws.add_code_block(fva, 8, fva);
+        ws.add_code_block(fva + 8, 8, fva);
+        ws.del_code_block(fva + 8);
+        ws.add_code_block(fva + 8, 4, fva);
+        ws.set_function_meta(fva, "BlockCount", 2);
+        ws.add_function_chunk(fva, 0x401100, 0x20);
+        ws.add_function_chunk(fva, 0x401140, 0x10);
+        ws.del_function_chunk(fva, 0x401140);
+        ws.add_function_entry(fva, 0x401004);
+        ws.set_file_meta(fname.clone(), "Analyzed".to_string(), 1);
+        ws.add_region_override(0x4011c0, 0x40, RegionKind::ForceData);
+        assert_eq!(ws.get_imports().len(), 2);
+        assert_eq!(ws.get_exports().len(), 1);
+        assert_eq!(ws.get_relocations().len(), 1);
+
+        let mut text = Vec::new();
+        ws.journal().unwrap().write(&mut text).unwrap();
+        let mut replayed = VivWorkspace::new("", false);
+        Journal::read(text.as_slice())
+            .unwrap()
+            .replay(&mut replayed);
+        assert_eq!(replayed.get_imports(), ws.get_imports());
+        assert_eq!(replayed.get_exports(), ws.get_exports());
+        assert_eq!(replayed.get_relocations(), ws.get_relocations());
+        assert_eq!(replayed.get_code_blocks(), ws.get_code_blocks());
+        assert_eq!(replayed.get_functions(), [fva]);
+        assert_eq!(
+            replayed.get_function_meta_dict(fva),
+            ws.get_function_meta_dict(fva)
+        );
+        assert_eq!(
+            replayed.get_function_chunks(fva),
+            ws.get_function_chunks(fva)
+        );
+        assert_eq!(
+            replayed.get_file_meta_dict(&fname),
+            ws.get_file_meta_dict(&fname)
+        );
+        assert_eq!(replayed.get_region_overrides(), ws.get_region_overrides());
+        assert_eq!(replayed.journal(), None);
+    }
+}
@@ -0,0 +1,423 @@
+//! Name and function export to, and import from, the formats other tools speak, so work done
+//! in one tool carries over to another:
+//!
+//! * IDA `.idc` scripts (`set_name`/`add_func`, and the older `MakeName`/`MakeFunction`),
+//! * IDAPython scripts (`idc.set_name`, `ida_funcs.add_func`),
+//! * Ghidra's symbol table CSV export (`"Name","Location","Type",...`),
+//! * a plain map of `addr name` lines.
+//!
+//! Only the names users or files gave are exported; automatic names (see [`crate::naming`]) are
+//! the other tool's business. Imported functions become entry points, which analysis turns into
+//! functions.
+
+use crate::workspace::VivWorkspace;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameFormat {
+    Idc,
+    IdaPython,
+    GhidraCsv,
+    /// `0x401000 main`, one per line
+    Plain,
+}
+
+/// A name, a function, or both, at an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label {
+    pub va: i32,
+    pub name: Option<String>,
+    pub is_function: bool,
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, msg),
+    )
+}
+
+fn parse_va(s: &str) -> Option<i32> {
+    let s = s.trim();
+    let s = s.strip_suffix('L').unwrap_or(s);
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+    parsed.ok().map(|va| va as i32)
+}
+
+/// The labels of the workspace worth exporting, by VA
+pub fn labels(workspace: &VivWorkspace) -> Vec<Label> {
+    let ann = workspace.get_annotations();
+    let mut labels: Vec<Label> = ann
+        .names
+        .iter()
+        .filter(|(va, _)| !workspace.is_auto_name(**va))
+        .map(|(va, name)| Label {
+            va: *va,
+            name: Some(name.clone()),
+            is_function: ann.functions.contains_key(va),
+        })
+        .collect();
+    for fva in ann.functions.keys() {
+        if !labels.iter().any(|label| label.va == *fva) {
+            labels.push(Label {
+                va: *fva,
+                name: None,
+                is_function: true,
+            });
+        }
+    }
+    labels.sort_by_key(|label| label.va as u32);
+    labels
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Export the names and functions of the workspace
+pub fn export(workspace: &VivWorkspace, format: NameFormat) -> String {
+    let labels = labels(workspace);
+    let mut out = String::new();
+    match format {
+        NameFormat::Idc => {
+            out.push_str("#include <idc.idc>\n\nstatic main() {\n");
+            for label in labels.iter() {
+                let va = label.va as u32;
+                if label.is_function {
+                    out.push_str(&format!("    add_func({:#x});\n", va));
+                }
+                if let Some(name) = &label.name {
+                    out.push_str(&format!(
+                        "    set_name({:#x}, {}, SN_NOWARN);\n",
+                        va,
+                        quote(name)
+                    ));
+                }
+            }
+            out.push_str("}\n");
+        }
+        NameFormat::IdaPython => {
+            out.push_str("import idc\nimport ida_funcs\n\n");
+            for label in labels.iter() {
+                let va = label.va as u32;
+                if label.is_function {
+                    out.push_str(&format!("ida_funcs.add_func({:#x})\n", va));
+                }
+                if let Some(name) = &label.name {
+                    out.push_str(&format!(
+                        "idc.set_name({:#x}, {}, idc.SN_NOWARN)\n",
+                        va,
+                        quote(name)
+                    ));
+                }
+            }
+        }
+        NameFormat::GhidraCsv => {
+            out.push_str("\"Name\",\"Location\",\"Type\",\"Namespace\",\"Source\"\n");
+            for label in labels.iter() {
+                let Some(name) = &label.name else {
+                    continue;
+                };
+                let kind = if label.is_function {
+                    "Function"
+                } else {
+                    "Label"
+                };
+                out.push_str(&format!(
+                    "\"{}\",\"{:08x}\",\"{}\",\"Global\",\"User Defined\"\n",
+                    name.replace('"', "\"\""),
+                    label.va as u32,
+                    kind
+                ));
+            }
+        }
+        NameFormat::Plain => {
+            for label in labels.iter() {
+                if let Some(name) = &label.name {
+                    out.push_str(&format!("{:#x} {}\n", label.va as u32, name));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Split call arguments on the commas outside string literals
+fn split_args(args: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quote: Option<char> = None;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                parts.last_mut().unwrap().push(c);
+                if let Some(next) = chars.next() {
+                    parts.last_mut().unwrap().push(next);
+                }
+            }
+            (Some(q), c) if c == q => {
+                quote = None;
+                parts.last_mut().unwrap().push(c);
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                parts.last_mut().unwrap().push(c);
+            }
+            (None, ',') => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts.iter().map(|part| part.trim().to_string()).collect()
+}
+
+fn unquote(s: &str) -> Option<String> {
+    let q = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let inner = s.strip_prefix(q)?.strip_suffix(q)?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
+
+const NAME_CALLS: [&str; 3] = ["set_name", "MakeName", "MakeNameEx"];
+const FUNCTION_CALLS: [&str; 2] = ["add_func", "MakeFunction"];
+
+/// The `set_name` and `add_func` calls of an IDC or IDAPython script. Anything else the
+/// script does is ignored.
+fn parse_script(text: &str) -> io::Result<Vec<Label>> {
+    let mut labels = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with("//") || line.starts_with('#') {
+            continue;
+        }
+        let Some((callee, rest)) = line.split_once('(') else {
+            continue;
+        };
+        // idc.set_name, ida_name.set_name, ida_funcs.add_func
+        let callee = callee.trim().rsplit('.').next().unwrap_or_default();
+        let is_name = NAME_CALLS.contains(&callee);
+        if !is_name && !FUNCTION_CALLS.contains(&callee) {
+            continue;
+        }
+        let args = rest.trim_end().trim_end_matches(';').trim_end();
+        let Some(args) = args.strip_suffix(')') else {
+            return Err(invalid(number + 1, "unterminated call"));
+        };
+        let args = split_args(args);
+        let va = args
+            .first()
+            .and_then(|va| parse_va(va))
+            .ok_or_else(|| invalid(number + 1, "bad address"))?;
+        let name = if is_name {
+            let name = args.get(1).and_then(|name| unquote(name));
+            Some(name.ok_or_else(|| invalid(number + 1, "bad name"))?)
+        } else {
+            None
+        };
+        labels.push(Label {
+            va,
+            name,
+            is_function: !is_name,
+        });
+    }
+    Ok(labels)
+}
+
+/// Split a CSV record, with `""` for a quote inside a quoted field
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            (_, '"') => quoted = !quoted,
+            (false, ',') => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The rows of a Ghidra symbol table export. Rows without a memory address (externals) are
+/// skipped, and Ghidra's default names (`FUN_00401000`) are only kept as functions.
+fn parse_ghidra(text: &str) -> io::Result<Vec<Label>> {
+    let mut lines = text.lines().enumerate();
+    let header = lines.next().map(|(_, line)| csv_fields(line));
+    let header = header.ok_or_else(|| invalid(1, "missing header"))?;
+    let column = |name: &str| header.iter().position(|field| field == name);
+    let (Some(name_col), Some(va_col)) = (column("Name"), column("Location")) else {
+        return Err(invalid(1, "no Name and Location columns"));
+    };
+    let (type_col, source_col) = (column("Type"), column("Source"));
+    let mut labels = Vec::new();
+    for (number, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(line);
+        let field = |col: Option<usize>| col.and_then(|col| fields.get(col)).map(String::as_str);
+        let (Some(name), Some(location)) = (field(Some(name_col)), field(Some(va_col))) else {
+            return Err(invalid(number + 1, "missing fields"));
+        };
+        if location.to_ascii_lowercase().starts_with("external") {
+            continue;
+        }
+        // ram:00401000, or 00401000
+        let location = location.rsplit(':').next().unwrap_or_default();
+        let Ok(va) = u32::from_str_radix(location, 16) else {
+            continue;
+        };
+        let is_function = field(type_col) == Some("Function");
+        let is_default = field(source_col) == Some("Default");
+        if is_default && !is_function {
+            continue;
+        }
+        labels.push(Label {
+            va: va as i32,
+            name: (!is_default).then(|| name.to_string()),
+            is_function,
+        });
+    }
+    Ok(labels)
+}
+
+fn parse_plain(text: &str) -> io::Result<Vec<Label>> {
+    let mut labels = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (va, name) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| invalid(number + 1, "expected an address and a name"))?;
+        let va = va.strip_prefix("0x").unwrap_or(va);
+        let va = u32::from_str_radix(va, 16).map_err(|_| invalid(number + 1, "bad address"))?;
+        labels.push(Label {
+            va: va as i32,
+            name: Some(name.trim().to_string()),
+            is_function: false,
+        });
+    }
+    Ok(labels)
+}
+
+/// Read the labels of a file in `format`
+pub fn parse(text: &str, format: NameFormat) -> io::Result<Vec<Label>> {
+    match format {
+        NameFormat::Idc | NameFormat::IdaPython => parse_script(text),
+        NameFormat::GhidraCsv => parse_ghidra(text),
+        NameFormat::Plain => parse_plain(text),
+    }
+}
+
+/// Import the labels of a file in `format` into the workspace: names replace what is there
+/// (made unique if another address has the name) and functions become entry points. Returns
+/// the number of labels applied.
+pub fn import(workspace: &mut VivWorkspace, text: &str, format: NameFormat) -> io::Result<usize> {
+    let labels = parse(text, format)?;
+    for label in labels.iter() {
+        if let Some(name) = &label.name {
+            workspace.make_name(label.va, name.clone(), false, true);
+        }
+        if label.is_function && !workspace.is_function(label.va) {
+            workspace.add_entry_point(label.va);
+        }
+    }
+    Ok(labels.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Annotations;
+
+    #[test]
+    fn round_trips() {
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x401000, 0x10), (0x402000, 0x10)]);
+        ann.names.insert(0x401000, "main".to_string());
+        ann.names.insert(0x403000, "g_\"config\"".to_string());
+        ann.names.insert(0x402000, "sub_402000".to_string());
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+
+        let expected = vec![
+            Label {
+                va: 0x401000,
+                name: Some("main".to_string()),
+                is_function: true,
+            },
+            Label {
+                va: 0x402000,
+                name: None,
+                is_function: true,
+            },
+            Label {
+                va: 0x403000,
+                name: Some("g_\"config\"".to_string()),
+                is_function: false,
+            },
+        ];
+        for format in [NameFormat::Idc, NameFormat::IdaPython] {
+            let mut labels = parse(&export(&ws, format), format).unwrap();
+            // add_func and set_name come out as two labels for the same address
+            labels.dedup_by(|b, a| {
+                a.va == b.va && {
+                    a.is_function |= b.is_function;
+                    a.name = a.name.take().or(b.name.take());
+                    true
+                }
+            });
+            assert_eq!(labels, expected, "{:?}", format);
+        }
+        let csv = export(&ws, NameFormat::GhidraCsv);
+        assert!(csv.contains("\"main\",\"00401000\",\"Function\""));
+        let named: Vec<Label> = expected
+            .iter()
+            .filter(|l| l.name.is_some())
+            .cloned()
+            .collect();
+        assert_eq!(parse(&csv, NameFormat::GhidraCsv).unwrap(), named);
+        let plain = export(&ws, NameFormat::Plain);
+        assert_eq!(plain.lines().next(), Some("0x401000 main"));
+
+        let ghidra = "\"Name\",\"Location\",\"Type\",\"Namespace\",\"Source\"\n\
+                      \"FUN_00405000\",\"00405000\",\"Function\",\"Global\",\"Default\"\n\
+                      \"parse\",\"ram:00406000\",\"Function\",\"Global\",\"User Defined\"\n\
+                      \"printf\",\"EXTERNAL:00000001\",\"Function\",\"msvcrt.dll\",\"Imported\"\n";
+        let mut fresh = VivWorkspace::new("", false);
+        assert_eq!(
+            import(&mut fresh, ghidra, NameFormat::GhidraCsv).unwrap(),
+            2
+        );
+        assert_eq!(fresh.get_name(0x405000, false), None);
+        assert_eq!(fresh.get_name(0x406000, false).as_deref(), Some("parse"));
+        assert_eq!(fresh.get_entry_points(), vec![0x405000, 0x406000]);
+        assert_eq!(
+            import(
+                &mut fresh,
+                "# names\n401000 main\n0x401010\tloop\n",
+                NameFormat::Plain
+            )
+            .unwrap(),
+            2
+        );
+        assert_eq!(fresh.get_name(0x401010, false).as_deref(), Some("loop"));
+        assert!(parse("MakeName(0x1000, \"a\"", NameFormat::Idc).is_err());
+    }
+}
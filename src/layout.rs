@@ -0,0 +1,554 @@
+//! The layout of an address space: the regions a binary or a workspace maps, with the gaps
+//! between them and what is wrong with them.
+//!
+//! [`from_workspace`] lays out what was loaded, memory maps and segments alike, and
+//! [`from_bytes`] what the headers of a PE, ELF or Mach-O file ask for, with the file ranges
+//! backing each region. The anomalies reported are regions of one kind overlapping, regions off
+//! their alignment, executable regions over the file headers and regions backed by bytes past
+//! the end of the file.
+
+use crate::{
+    constants::{MM_EXEC, MM_READ, MM_WRITE},
+    elf::{program_header, section_header, Elf},
+    error::{Error, Result},
+    mach::{Mach, MachO},
+    memory::Memory,
+    pe::{section_table, PE},
+    workspace::VivWorkspace,
+    Object,
+};
+use std::{fmt, ops::Range};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// The file headers, as mapped
+    Header,
+    /// A memory map of the workspace
+    Map,
+    /// A segment of the workspace, or of a program header table or load commands
+    Segment,
+    /// A section of a section table
+    Section,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Header => "header",
+            Source::Map => "map",
+            Source::Segment => "segment",
+            Source::Section => "section",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub source: Source,
+    pub name: String,
+    pub va: u64,
+    pub size: u64,
+    /// `MM_*` permissions, where they are known
+    pub perms: Option<i32>,
+    /// The bytes of the file backing the start of the region
+    pub file_range: Option<Range<u64>>,
+}
+
+impl Region {
+    pub fn new(source: Source, name: &str, va: u64, size: u64) -> Self {
+        Region {
+            source,
+            name: name.to_string(),
+            va,
+            size,
+            perms: None,
+            file_range: None,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.va.saturating_add(self.size)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.perms.is_some_and(|perms| perms & MM_EXEC != 0)
+    }
+
+    fn overlap(&self, other: &Region) -> Option<Range<u64>> {
+        let start = self.va.max(other.va);
+        let end = self.end().min(other.end());
+        (start < end).then_some(start..end)
+    }
+}
+
+/// Unmapped space between two regions of the same kind
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gap {
+    pub source: Source,
+    /// The region the gap follows
+    pub after: String,
+    pub va: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    Overlap {
+        source: Source,
+        first: String,
+        second: String,
+        range: Range<u64>,
+    },
+    Misaligned {
+        name: String,
+        va: u64,
+        alignment: u64,
+    },
+    /// An executable region over the mapped file headers
+    ExecutableHeader { name: String, va: u64 },
+    /// A region backed by bytes from past the end of the file
+    OutsideFile {
+        name: String,
+        file_end: u64,
+        file_size: u64,
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Overlap {
+                source,
+                first,
+                second,
+                range,
+            } => write!(
+                f,
+                "{} {} overlaps {} at {:#x}..{:#x}",
+                source, second, first, range.start, range.end
+            ),
+            Anomaly::Misaligned {
+                name,
+                va,
+                alignment,
+            } => write!(
+                f,
+                "{} at {:#x} is off its {:#x} alignment",
+                name, va, alignment
+            ),
+            Anomaly::ExecutableHeader { name, va } => {
+                write!(f, "{} at {:#x} maps the headers executable", name, va)
+            }
+            Anomaly::OutsideFile {
+                name,
+                file_end,
+                file_size,
+            } => write!(
+                f,
+                "{} takes file bytes up to {:#x}, the file is {:#x} bytes",
+                name, file_end, file_size
+            ),
+        }
+    }
+}
+
+/// The regions of an address space by VA, with their gaps and anomalies
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub regions: Vec<Region>,
+    pub gaps: Vec<Gap>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Layout {
+    /// Lay the regions out, finding the gaps, overlaps and executable headers among them, and
+    /// the regions backed by bytes past `file_size` if it is known.
+    pub fn new(mut regions: Vec<Region>, file_size: Option<u64>) -> Self {
+        regions.sort_by_key(|region| (region.source, region.va, region.size));
+        let mut layout = Layout::default();
+        for source in [Source::Map, Source::Segment, Source::Section] {
+            let mut prev: Option<&Region> = None;
+            for region in regions.iter().filter(|region| region.source == source) {
+                if let Some(prev) = prev {
+                    if region.va > prev.end() {
+                        layout.gaps.push(Gap {
+                            source,
+                            after: prev.name.clone(),
+                            va: prev.end(),
+                            size: region.va - prev.end(),
+                        });
+                    } else if let Some(range) = prev.overlap(region) {
+                        layout.anomalies.push(Anomaly::Overlap {
+                            source,
+                            first: prev.name.clone(),
+                            second: region.name.clone(),
+                            range,
+                        });
+                    }
+                }
+                if prev.is_none_or(|prev| region.end() > prev.end()) {
+                    prev = Some(region);
+                }
+            }
+        }
+        for header in regions
+            .iter()
+            .filter(|region| region.source == Source::Header)
+        {
+            for region in regions.iter().filter(|region| region.is_executable()) {
+                if region.overlap(header).is_some() {
+                    layout.anomalies.push(Anomaly::ExecutableHeader {
+                        name: region.name.clone(),
+                        va: region.va,
+                    });
+                }
+            }
+        }
+        if let Some(file_size) = file_size {
+            for region in regions.iter() {
+                match region.file_range.as_ref() {
+                    Some(range) if range.end > file_size => {
+                        layout.anomalies.push(Anomaly::OutsideFile {
+                            name: region.name.clone(),
+                            file_end: range.end,
+                            file_size,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        regions.sort_by_key(|region| (region.va, region.source));
+        layout.regions = regions;
+        layout
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+
+    /// The span from the lowest mapped address to the highest
+    pub fn span(&self) -> Option<Range<u64>> {
+        let start = self.regions.iter().map(|region| region.va).min()?;
+        let end = self.regions.iter().map(Region::end).max()?;
+        Some(start..end)
+    }
+}
+
+fn perms_str(perms: Option<i32>) -> String {
+    let Some(perms) = perms else {
+        return "???".to_string();
+    };
+    [(MM_READ, 'r'), (MM_WRITE, 'w'), (MM_EXEC, 'x')]
+        .iter()
+        .map(|(flag, c)| if perms & flag != 0 { *c } else { '-' })
+        .collect()
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in self.regions.iter() {
+            write!(
+                f,
+                "{:#010x}-{:#010x} {} {:<7} {}",
+                region.va,
+                region.end(),
+                perms_str(region.perms),
+                region.source.to_string(),
+                region.name
+            )?;
+            if let Some(range) = region.file_range.as_ref() {
+                write!(f, " (file {:#x}..{:#x})", range.start, range.end)?;
+            }
+            writeln!(f)?;
+        }
+        for gap in self.gaps.iter() {
+            writeln!(
+                f,
+                "gap of {:#x} bytes at {:#x}, after {} {}",
+                gap.size, gap.va, gap.source, gap.after
+            )?;
+        }
+        for anomaly in self.anomalies.iter() {
+            writeln!(f, "anomaly: {}", anomaly)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lay out the memory maps and segments loaded into the workspace. The permissions of a
+/// segment are those of the map it starts in, and the headers are the segments named as such.
+pub fn from_workspace(workspace: &mut VivWorkspace) -> Layout {
+    let maps: Vec<Region> = workspace
+        .get_memory_maps()
+        .into_iter()
+        .map(|(va, size, perms, fname)| {
+            let mut region = Region::new(Source::Map, &fname, va as u32 as u64, size as u32 as u64);
+            region.perms = Some(perms);
+            region
+        })
+        .collect();
+    let mut regions = Vec::new();
+    for (va, size, name, _) in workspace.get_segments() {
+        let source = match name.as_str() {
+            "PE_Header" | "ELF_Header" | "MachO_Header" => Source::Header,
+            _ => Source::Segment,
+        };
+        let mut region = Region::new(source, &name, va as u32 as u64, size as u32 as u64);
+        region.perms = maps
+            .iter()
+            .find(|map| map.va <= region.va && region.va < map.end())
+            .and_then(|map| map.perms);
+        regions.push(region);
+    }
+    regions.extend(maps);
+    Layout::new(regions, None)
+}
+
+/// Lay out what the headers of a PE, ELF or Mach-O file map, and where from in the file.
+pub fn from_bytes(bytes: &[u8]) -> Result<Layout> {
+    let (regions, misaligned) = match Object::parse(bytes)? {
+        Object::PE(pe) => pe_regions(&pe),
+        Object::Elf(elf) => elf_regions(&elf),
+        Object::Mach(Mach::Binary(macho)) => macho_regions(&macho),
+        Object::Mach(Mach::Fat(fat)) => macho_regions(&fat.get(0)?),
+        _ => return Err(Error::Malformed("Not a PE, ELF or Mach-O file".into())),
+    };
+    let mut layout = Layout::new(regions, Some(bytes.len() as u64));
+    layout.anomalies.extend(misaligned);
+    Ok(layout)
+}
+
+type Regions = (Vec<Region>, Vec<Anomaly>);
+
+fn pe_regions(pe: &PE) -> Regions {
+    let base = pe.image_base as u64;
+    let (mut regions, mut misaligned) = (Vec::new(), Vec::new());
+    let (section_alignment, file_alignment) = pe.header.optional_header.map_or((0, 0), |opt| {
+        (
+            opt.windows_fields.section_alignment as u64,
+            opt.windows_fields.file_alignment as u64,
+        )
+    });
+    if let Some(opt) = pe.header.optional_header {
+        let size = opt.windows_fields.size_of_headers as u64;
+        let mut header = Region::new(Source::Header, "PE_Header", base, size);
+        header.perms = Some(MM_READ);
+        header.file_range = Some(0..size);
+        regions.push(header);
+    }
+    for section in pe.sections.iter() {
+        let name = section.name().unwrap_or("").to_string();
+        let size = match section.virtual_size {
+            0 => section.size_of_raw_data,
+            vsize => vsize,
+        } as u64;
+        let mut region = Region::new(
+            Source::Section,
+            &name,
+            base + section.virtual_address as u64,
+            size,
+        );
+        let mut perms = 0;
+        for (flag, perm) in [
+            (section_table::IMAGE_SCN_MEM_READ, MM_READ),
+            (section_table::IMAGE_SCN_MEM_WRITE, MM_WRITE),
+            (section_table::IMAGE_SCN_MEM_EXECUTE, MM_EXEC),
+        ] {
+            if section.characteristics & flag != 0 {
+                perms |= perm;
+            }
+        }
+        region.perms = Some(perms);
+        if section.size_of_raw_data != 0 {
+            let start = section.pointer_to_raw_data as u64;
+            region.file_range = Some(start..start + section.size_of_raw_data as u64);
+            if file_alignment > 1 && !start.is_multiple_of(file_alignment) {
+                misaligned.push(Anomaly::Misaligned {
+                    name: format!("{} in the file", name),
+                    va: start,
+                    alignment: file_alignment,
+                });
+            }
+        }
+        if section_alignment > 1
+            && !(section.virtual_address as u64).is_multiple_of(section_alignment)
+        {
+            misaligned.push(Anomaly::Misaligned {
+                name: name.clone(),
+                va: region.va,
+                alignment: section_alignment,
+            });
+        }
+        regions.push(region);
+    }
+    (regions, misaligned)
+}
+
+fn elf_regions(elf: &Elf) -> Regions {
+    let (mut regions, mut misaligned) = (Vec::new(), Vec::new());
+    let headers_size =
+        elf.header.e_phoff + elf.header.e_phentsize as u64 * elf.header.e_phnum as u64;
+    let headers_size = headers_size.max(elf.header.e_ehsize as u64);
+    for (i, ph) in elf.program_headers.iter().enumerate() {
+        if ph.p_type != program_header::PT_LOAD {
+            continue;
+        }
+        let name = format!("PHDR{}", i);
+        let mut region = Region::new(Source::Segment, &name, ph.p_vaddr, ph.p_memsz);
+        let mut perms = 0;
+        for (flag, perm) in [
+            (program_header::PF_R, MM_READ),
+            (program_header::PF_W, MM_WRITE),
+            (program_header::PF_X, MM_EXEC),
+        ] {
+            if ph.p_flags & flag != 0 {
+                perms |= perm;
+            }
+        }
+        region.perms = Some(perms);
+        if ph.p_filesz != 0 {
+            region.file_range = Some(ph.p_offset..ph.p_offset + ph.p_filesz);
+        }
+        if ph.p_align > 1 && ph.p_vaddr % ph.p_align != ph.p_offset % ph.p_align {
+            misaligned.push(Anomaly::Misaligned {
+                name: name.clone(),
+                va: ph.p_vaddr,
+                alignment: ph.p_align,
+            });
+        }
+        // The headers are mapped by the segment loading the start of the file
+        if ph.p_offset == 0 && ph.p_filesz >= headers_size {
+            let mut header = Region::new(Source::Header, "ELF_Header", ph.p_vaddr, headers_size);
+            header.perms = Some(perms & !MM_EXEC);
+            header.file_range = Some(0..headers_size);
+            regions.push(header);
+        }
+        regions.push(region);
+    }
+    for section in elf.section_headers.iter() {
+        if section.sh_addr == 0 || section.sh_size == 0 {
+            continue;
+        }
+        let name = elf.shdr_strtab.get_at(section.sh_name).unwrap_or("");
+        let mut region = Region::new(Source::Section, name, section.sh_addr, section.sh_size);
+        let mut perms = MM_READ;
+        if section.sh_flags & section_header::SHF_WRITE as u64 != 0 {
+            perms |= MM_WRITE;
+        }
+        if section.sh_flags & section_header::SHF_EXECINSTR as u64 != 0 {
+            perms |= MM_EXEC;
+        }
+        region.perms = Some(perms);
+        if section.sh_type != section_header::SHT_NOBITS {
+            region.file_range = Some(section.sh_offset..section.sh_offset + section.sh_size);
+        }
+        if section.sh_addralign > 1 && !section.sh_addr.is_multiple_of(section.sh_addralign) {
+            misaligned.push(Anomaly::Misaligned {
+                name: name.to_string(),
+                va: section.sh_addr,
+                alignment: section.sh_addralign,
+            });
+        }
+        regions.push(region);
+    }
+    (regions, misaligned)
+}
+
+fn macho_regions(macho: &MachO) -> Regions {
+    let mut regions = Vec::new();
+    for seg in macho.segments.iter() {
+        if seg.vmsize == 0 {
+            continue;
+        }
+        let name = seg.name().unwrap_or("");
+        let mut region = Region::new(Source::Segment, name, seg.vmaddr, seg.vmsize);
+        let prot = seg.init_protection();
+        let mut perms = 0;
+        for (set, perm) in [
+            (prot.is_readable(), MM_READ),
+            (prot.is_writable(), MM_WRITE),
+            (prot.is_executable(), MM_EXEC),
+        ] {
+            if set {
+                perms |= perm;
+            }
+        }
+        region.perms = Some(perms);
+        if seg.filesize != 0 {
+            region.file_range = Some(seg.fileoff..seg.fileoff + seg.filesize);
+        }
+        regions.push(region);
+    }
+    (regions, Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workspace_layout() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", vec![0; 0x200], None);
+        ws.add_memory_map(0x2000, MM_READ, "test", vec![0; 0x100], None);
+        ws.add_memory_map(0x2080, MM_READ | MM_WRITE, "test", vec![0; 0x100], None);
+        ws.add_segment(0x1000, 0x100, "PE_Header", "test".to_string());
+        ws.add_segment(0x1100, 0x100, ".text", "test".to_string());
+        ws.add_segment(0x2000, 0x100, ".rdata", "test".to_string());
+
+        let layout = from_workspace(&mut ws);
+        assert_eq!(layout.span(), Some(0x1000..0x2180));
+        assert_eq!(
+            layout.gaps,
+            [
+                Gap {
+                    source: Source::Map,
+                    after: "test".to_string(),
+                    va: 0x1200,
+                    size: 0xe00,
+                },
+                Gap {
+                    source: Source::Segment,
+                    after: ".text".to_string(),
+                    va: 0x1200,
+                    size: 0xe00,
+                },
+            ]
+        );
+        assert_eq!(
+            layout.anomalies,
+            [
+                Anomaly::Overlap {
+                    source: Source::Map,
+                    first: "test".to_string(),
+                    second: "test".to_string(),
+                    range: 0x2080..0x2100,
+                },
+                Anomaly::ExecutableHeader {
+                    name: "PE_Header".to_string(),
+                    va: 0x1000,
+                },
+                Anomaly::ExecutableHeader {
+                    name: "test".to_string(),
+                    va: 0x1000,
+                },
+            ]
+        );
+        assert!(layout
+            .to_string()
+            .contains("0x00001100-0x00001200 r-x segment .text"));
+
+        let mut region = Region::new(Source::Section, ".data", 0x3000, 0x100);
+        region.file_range = Some(0x400..0x500);
+        let layout = Layout::new(vec![region], Some(0x480));
+        assert_eq!(
+            layout.anomalies,
+            [Anomaly::OutsideFile {
+                name: ".data".to_string(),
+                file_end: 0x500,
+                file_size: 0x480,
+            }]
+        );
+    }
+}
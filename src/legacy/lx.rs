@@ -0,0 +1,415 @@
+//! LE and LX, the linear executables of Windows VxDs and OS/2 2.x and later.
+//!
+//! Code and data are in objects, each with the address it prefers and a run of pages in the
+//! object page map. The two formats differ in that map: LE gives each page's number in the data
+//! pages, the last page possibly short, where LX gives each page's own offset and size and may
+//! leave pages zero filled. Offsets in the header are from the header itself, except for the
+//! data pages' and the non-resident name table's, which are from the start of the file.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use scroll::{Pread, LE};
+
+use super::{name_table, pascal_string};
+use crate::error::{Error, Result};
+
+pub const OBJ_READ: u32 = 0x0001;
+pub const OBJ_WRITE: u32 = 0x0002;
+pub const OBJ_EXEC: u32 = 0x0004;
+pub const OBJ_RESOURCE: u32 = 0x0008;
+pub const OBJ_DISCARDABLE: u32 = 0x0010;
+pub const OBJ_SHARED: u32 = 0x0020;
+pub const OBJ_PRELOAD: u32 = 0x0040;
+/// 32 bit code, rather than 16 bit
+pub const OBJ_BIG: u32 = 0x2000;
+
+/// Windows 386 enhanced mode, a VxD
+pub const OS_WINDOWS_386: u16 = 4;
+pub const OS_OS2: u16 = 1;
+
+const SIZEOF_OBJECT: usize = 24;
+
+/// How an LX page is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    Physical,
+    /// Compressed with one of the iterated data schemes, not expanded here
+    Iterated,
+    Invalid,
+    ZeroFilled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LxPage {
+    pub file_offset: usize,
+    pub size: usize,
+    pub kind: PageKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LxObject {
+    /// Objects are numbered from 1
+    pub number: u32,
+    pub virtual_size: u32,
+    /// The address the object prefers to load at
+    pub base: u32,
+    pub flags: u32,
+    pub pages: Vec<LxPage>,
+}
+
+impl LxObject {
+    pub fn is_code(&self) -> bool {
+        self.flags & OBJ_EXEC != 0
+    }
+
+    /// The object's contents: its pages, zero filled up to its virtual size. Pages which
+    /// aren't physical, or not all in `bytes`, read as zeros.
+    pub fn data(&self, bytes: &[u8], page_size: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.virtual_size as usize);
+        for page in self.pages.iter() {
+            let start = data.len();
+            if page.kind == PageKind::Physical {
+                let contents = page
+                    .file_offset
+                    .checked_add(page.size)
+                    .and_then(|end| bytes.get(page.file_offset..end))
+                    .unwrap_or_default();
+                data.extend(contents);
+            }
+            data.resize(start + page_size, 0);
+        }
+        data.resize(self.virtual_size as usize, 0);
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LxEntryKind {
+    Entry16,
+    /// A 286 call gate, with its selector
+    CallGate(u16),
+    Entry32,
+    /// An entry of another module, by its index in the import module table and its ordinal
+    /// or the offset of its name in the import procedure table
+    Forwarder {
+        module: u16,
+        target: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LxEntry {
+    pub ordinal: u16,
+    /// The object number, 0 for a forwarder
+    pub object: u16,
+    pub offset: u32,
+    pub flags: u8,
+    pub kind: LxEntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lx {
+    /// The file offset of the header
+    pub offset: usize,
+    /// LX rather than LE
+    pub is_lx: bool,
+    /// 1 for the 286, 2 for the 386, 3 for the 486
+    pub cpu: u16,
+    pub os: u16,
+    pub flags: u32,
+    pub page_size: u32,
+    /// The entry point as object number and offset, object 0 for none
+    pub eip: (u32, u32),
+    pub esp: (u32, u32),
+    pub objects: Vec<LxObject>,
+    pub entries: Vec<LxEntry>,
+    pub module_name: String,
+    pub description: Option<String>,
+    /// The exported names, resident and non-resident, with their ordinals
+    pub names: Vec<(String, u16)>,
+    /// The modules imported from
+    pub imports: Vec<String>,
+}
+
+impl Lx {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        match super::new_header(bytes)? {
+            (offset, [b'L', b'E' | b'X']) => Self::parse_at(bytes, offset),
+            (offset, _) => Err(Error::Malformed(format!(
+                "No LE or LX header at {:#x}",
+                offset
+            ))),
+        }
+    }
+
+    /// Parse the LE or LX header at `offset`
+    pub fn parse_at(bytes: &[u8], offset: usize) -> Result<Self> {
+        let u32_at = |at: usize| -> Result<u32> { Ok(bytes.pread_with(offset + at, LE)?) };
+        let u16_at = |at: usize| -> Result<u16> { Ok(bytes.pread_with(offset + at, LE)?) };
+        let is_lx = bytes.get(offset + 1) == Some(&b'X');
+        if bytes.get(offset + 2..offset + 4) != Some(&[0, 0]) {
+            return Err(Error::Malformed("Big endian LE/LX is not supported".into()));
+        }
+        let page_size = u32_at(0x28)?;
+        // the size of the last page in LE, the shift of the page offsets in LX
+        let last_or_shift = u32_at(0x2c)?;
+        if page_size == 0 || (is_lx && last_or_shift >= 32) {
+            return Err(Error::Malformed(format!(
+                "Bad LE/LX page size {:#x} or shift {}",
+                page_size, last_or_shift
+            )));
+        }
+        let page_count = u32_at(0x14)?;
+        let page_map = offset + u32_at(0x48)? as usize;
+        let data_pages = u32_at(0x80)? as usize;
+        let page = |number: u32| -> Result<LxPage> {
+            let index = number
+                .checked_sub(1)
+                .ok_or_else(|| Error::Malformed("LE/LX object page numbers start at 1".into()))?
+                as usize;
+            if is_lx {
+                let at = page_map + index * 8;
+                let page_offset: u32 = bytes.pread_with(at, LE)?;
+                let size: u16 = bytes.pread_with(at + 4, LE)?;
+                let flags: u16 = bytes.pread_with(at + 6, LE)?;
+                Ok(LxPage {
+                    file_offset: data_pages + ((page_offset as usize) << last_or_shift),
+                    size: size as usize,
+                    kind: match flags {
+                        0 => PageKind::Physical,
+                        1 | 5 => PageKind::Iterated,
+                        3 => PageKind::ZeroFilled,
+                        _ => PageKind::Invalid,
+                    },
+                })
+            } else {
+                // a 24 bit big endian page number, then flags
+                let entry = bytes
+                    .get(page_map + index * 4..page_map + index * 4 + 4)
+                    .ok_or(Error::BufferTooShort(4, "bytes of an LE page map entry"))?;
+                let number = u32::from_be_bytes([0, entry[0], entry[1], entry[2]]);
+                let size = if number == page_count {
+                    last_or_shift
+                } else {
+                    page_size
+                };
+                Ok(LxPage {
+                    file_offset: data_pages
+                        + (number.saturating_sub(1) as usize) * page_size as usize,
+                    size: size as usize,
+                    kind: match entry[3] {
+                        0 => PageKind::Physical,
+                        _ => PageKind::Invalid,
+                    },
+                })
+            }
+        };
+
+        let mut objects = Vec::new();
+        let object_table = offset + u32_at(0x40)? as usize;
+        for number in 1..=u32_at(0x44)? {
+            let at = object_table + (number as usize - 1) * SIZEOF_OBJECT;
+            let first_page: u32 = bytes.pread_with(at + 12, LE)?;
+            let pages: u32 = bytes.pread_with(at + 16, LE)?;
+            if pages > page_count {
+                return Err(Error::Malformed(format!(
+                    "LE/LX object {} has {} pages of {}",
+                    number, pages, page_count
+                )));
+            }
+            objects.push(LxObject {
+                number,
+                virtual_size: bytes.pread_with(at, LE)?,
+                base: bytes.pread_with(at + 4, LE)?,
+                flags: bytes.pread_with(at + 8, LE)?,
+                pages: (first_page..first_page.saturating_add(pages))
+                    .map(page)
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        let entries = entry_table_entries(bytes, offset + u32_at(0x5c)? as usize)?;
+
+        let mut names = name_table(bytes, offset + u32_at(0x58)? as usize)?;
+        if names.is_empty() {
+            return Err(Error::Malformed("LE/LX without a module name".into()));
+        }
+        let (module_name, _) = names.remove(0);
+        let mut description = None;
+        let nonresident = u32_at(0x88)? as usize;
+        if nonresident != 0 && u32_at(0x8c)? != 0 {
+            let mut nonresident = name_table(bytes, nonresident)?;
+            if !nonresident.is_empty() {
+                description = Some(nonresident.remove(0).0);
+            }
+            names.extend(nonresident);
+        }
+
+        let mut imports = Vec::new();
+        let mut at = offset + u32_at(0x70)? as usize;
+        for _ in 0..u32_at(0x74)? {
+            let (name, end) = pascal_string(bytes, at)?;
+            imports.push(name);
+            at = end;
+        }
+
+        Ok(Lx {
+            offset,
+            is_lx,
+            cpu: u16_at(0x08)?,
+            os: u16_at(0x0a)?,
+            flags: u32_at(0x10)?,
+            page_size,
+            eip: (u32_at(0x18)?, u32_at(0x1c)?),
+            esp: (u32_at(0x20)?, u32_at(0x24)?),
+            objects,
+            entries,
+            module_name,
+            description,
+            names,
+            imports,
+        })
+    }
+
+    /// A Windows virtual device driver
+    pub fn is_vxd(&self) -> bool {
+        self.os == OS_WINDOWS_386
+    }
+
+    pub fn entry_by_ordinal(&self, ordinal: u16) -> Option<&LxEntry> {
+        self.entries.iter().find(|entry| entry.ordinal == ordinal)
+    }
+
+    /// The exports, as name and entry. A VxD exports its device descriptor block as
+    /// `<NAME>_DDB`, ordinal 1.
+    pub fn exports(&self) -> Vec<(&str, &LxEntry)> {
+        self.names
+            .iter()
+            .filter_map(|(name, ordinal)| Some((name.as_str(), self.entry_by_ordinal(*ordinal)?)))
+            .collect()
+    }
+
+    /// The address an entry has with the objects at their preferred bases
+    pub fn entry_address(&self, entry: &LxEntry) -> Option<u32> {
+        let object = self.objects.get((entry.object as usize).checked_sub(1)?)?;
+        Some(object.base.wrapping_add(entry.offset))
+    }
+}
+
+/// The entries of the entry table at `at`: bundles of entries of one kind and object, numbered
+/// by ordinal from 1 on, empty bundles skipping ordinals, up to a zero count
+fn entry_table_entries(bytes: &[u8], mut at: usize) -> Result<Vec<LxEntry>> {
+    let mut entries = Vec::new();
+    let mut ordinal = 1u16;
+    loop {
+        let count: u8 = bytes.gread(&mut at)?;
+        if count == 0 {
+            return Ok(entries);
+        }
+        // the high bit marks parameter typing information, which changes nothing here
+        let bundle = bytes.gread::<u8>(&mut at)? & 0x7f;
+        if bundle == 0 {
+            ordinal = ordinal.wrapping_add(count as u16);
+            continue;
+        }
+        let object: u16 = bytes.gread_with(&mut at, LE)?;
+        for _ in 0..count {
+            let flags: u8 = bytes.gread(&mut at)?;
+            let (offset, kind) = match bundle {
+                1 => (
+                    bytes.gread_with::<u16>(&mut at, LE)? as u32,
+                    LxEntryKind::Entry16,
+                ),
+                2 => {
+                    let offset = bytes.gread_with::<u16>(&mut at, LE)? as u32;
+                    (
+                        offset,
+                        LxEntryKind::CallGate(bytes.gread_with(&mut at, LE)?),
+                    )
+                }
+                3 => (bytes.gread_with(&mut at, LE)?, LxEntryKind::Entry32),
+                4 => {
+                    let module = bytes.gread_with(&mut at, LE)?;
+                    let target = bytes.gread_with(&mut at, LE)?;
+                    (0, LxEntryKind::Forwarder { module, target })
+                }
+                kind => {
+                    return Err(Error::Malformed(format!(
+                        "Unknown LE/LX entry bundle type {}",
+                        kind
+                    )))
+                }
+            };
+            entries.push(LxEntry {
+                ordinal,
+                object: if bundle == 4 { 0 } else { object },
+                offset,
+                flags,
+                kind,
+            });
+            ordinal = ordinal.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vxd_objects_and_entries() {
+        let mut bytes = b"MZ".to_vec();
+        bytes.resize(0x3c, 0);
+        bytes.extend(0x40u32.to_le_bytes());
+        let mut le = vec![0u8; 0xb0];
+        le[..2].copy_from_slice(b"LE");
+        let mut put = |at: usize, value: u32| le[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        put(0x08, 2 | (OS_WINDOWS_386 as u32) << 16);
+        put(0x14, 2);
+        put(0x28, 0x10);
+        put(0x2c, 4);
+        put(0x40, 0xb0);
+        put(0x44, 2);
+        put(0x48, 0xe0);
+        put(0x58, 0xe8);
+        put(0x5c, 0x100);
+        put(0x80, 0x200);
+        // objects: code of 0x18 bytes at 0xc0000000 in page 1, data of 8 in page 2
+        for word in [0x18u32, 0xc000_0000, OBJ_READ | OBJ_EXEC | OBJ_BIG, 1, 1, 0] {
+            le.extend(word.to_le_bytes());
+        }
+        for word in [8u32, 0xc000_1000, OBJ_READ | OBJ_WRITE, 2, 1, 0] {
+            le.extend(word.to_le_bytes());
+        }
+        // page map, then the resident names, then the entry table
+        le.extend([0, 0, 1, 0, 0, 0, 2, 0]);
+        le.extend(b"\x04DEMO\x00\x00\x08DEMO_DDB\x01\x00\x00");
+        le.resize(0x100, 0);
+        le.extend([1, 3, 2, 0, 3, 4, 0, 0, 0, 0]);
+        bytes.extend(le);
+        bytes.resize(0x200, 0);
+        bytes.extend([0xcc; 0x10]);
+        bytes.extend([1, 2, 3, 4]);
+
+        let lx = Lx::parse(&bytes).unwrap();
+        assert!(lx.is_vxd() && !lx.is_lx);
+        assert_eq!(lx.module_name, "DEMO");
+        assert_eq!(lx.objects.len(), 2);
+        assert!(lx.objects[0].is_code());
+        let code = lx.objects[0].data(&bytes, lx.page_size as usize);
+        assert_eq!(code.len(), 0x18);
+        assert_eq!(&code[..0x10], &[0xcc; 0x10]);
+        assert_eq!(&code[0x10..], &[0; 8]);
+        // the last page is short
+        assert_eq!(
+            lx.objects[1].data(&bytes, lx.page_size as usize),
+            [1, 2, 3, 4, 0, 0, 0, 0]
+        );
+        let exports = lx.exports();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].0, "DEMO_DDB");
+        assert_eq!(exports[0].1.kind, LxEntryKind::Entry32);
+        assert_eq!(lx.entry_address(exports[0].1), Some(0xc000_1004));
+        assert!(Lx::parse(&bytes[..0x80]).is_err());
+    }
+}
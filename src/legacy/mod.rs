@@ -0,0 +1,71 @@
+//! Executables that came after DOS and before PE, behind the same `MZ` stub: NE, the 16 bit
+//! format of Windows 3.x and OS/2 1.x, and LE and LX, the 32 bit formats of Windows VxDs and
+//! OS/2 2.x and later.
+//!
+//! The stub's header points at the newer header at offset 0x3c, as for PE. Both formats are
+//! parsed down to their segments or objects, their entry tables, and the names the entries are
+//! exported by.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use scroll::{Pread, LE};
+
+use crate::error::{Error, Result};
+
+pub mod lx;
+pub mod ne;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Legacy {
+    Ne(ne::Ne),
+    Lx(lx::Lx),
+}
+
+/// The offset of the header an `MZ` stub points to, and the signature there
+pub fn new_header(bytes: &[u8]) -> Result<(usize, [u8; 2])> {
+    let magic: u16 = bytes.pread_with(0, LE)?;
+    if &magic.to_le_bytes() != b"MZ" {
+        return Err(Error::BadMagic(magic as u64));
+    }
+    let offset = bytes.pread_with::<u32>(0x3c, LE)? as usize;
+    let signature = bytes
+        .get(offset..offset.saturating_add(2))
+        .ok_or_else(|| Error::Malformed(format!("New header at {:#x} past the end", offset)))?;
+    Ok((offset, [signature[0], signature[1]]))
+}
+
+/// Parse whichever of NE, LE or LX `bytes` is
+pub fn parse(bytes: &[u8]) -> Result<Legacy> {
+    match new_header(bytes)? {
+        (offset, [b'N', b'E']) => Ok(Legacy::Ne(ne::Ne::parse_at(bytes, offset)?)),
+        (offset, [b'L', b'E' | b'X']) => Ok(Legacy::Lx(lx::Lx::parse_at(bytes, offset)?)),
+        (offset, signature) => Err(Error::Malformed(format!(
+            "Not an NE, LE or LX header at {:#x}: {:02x?}",
+            offset, signature
+        ))),
+    }
+}
+
+/// The name table at `offset`: names with a length byte in front and an ordinal after, up to a
+/// zero length
+fn name_table(bytes: &[u8], mut offset: usize) -> Result<Vec<(String, u16)>> {
+    let mut names = Vec::new();
+    loop {
+        let (name, end) = pascal_string(bytes, offset)?;
+        if name.is_empty() {
+            return Ok(names);
+        }
+        offset = end;
+        names.push((name, bytes.gread_with(&mut offset, LE)?));
+    }
+}
+
+/// The string at `offset` with its length byte in front, and where it ends
+fn pascal_string(bytes: &[u8], offset: usize) -> Result<(String, usize)> {
+    let len: u8 = bytes.pread(offset)?;
+    let end = offset + 1 + len as usize;
+    let name = bytes
+        .get(offset + 1..end)
+        .ok_or(Error::BufferTooShort(len as usize, "bytes of a name"))?;
+    Ok((String::from_utf8_lossy(name).to_string(), end))
+}
@@ -0,0 +1,301 @@
+//! NE, the segmented executables of Windows 3.x and OS/2 1.x.
+//!
+//! Offsets in the NE header are from the header itself, except for the non-resident name
+//! table's, which is from the start of the file. Segment data is found in units of the
+//! alignment shift, 512 bytes unless the header says otherwise.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use scroll::{Pread, LE};
+
+use super::{name_table, pascal_string};
+use crate::error::{Error, Result};
+
+/// The segment holds data, not code
+pub const NE_SEG_DATA: u16 = 0x0001;
+pub const NE_SEG_MOVABLE: u16 = 0x0010;
+pub const NE_SEG_PRELOAD: u16 = 0x0040;
+/// Relocations follow the segment's data
+pub const NE_SEG_RELOCINFO: u16 = 0x0100;
+pub const NE_SEG_DISCARDABLE: u16 = 0x1000;
+
+/// The module is a library, a DLL or a driver
+pub const NE_FLAG_LIBRARY: u16 = 0x8000;
+
+pub const NE_OS_OS2: u8 = 1;
+pub const NE_OS_WINDOWS: u8 = 2;
+
+/// The segment "number" of an entry which is a constant, not an address
+pub const NE_ENTRY_CONSTANT: u16 = 0xfe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeSegment {
+    /// Segments are numbered from 1
+    pub number: u16,
+    /// 0 for a segment without data in the file
+    pub file_offset: usize,
+    pub file_size: usize,
+    pub flags: u16,
+    /// The size to allocate for the segment
+    pub min_alloc: u32,
+}
+
+impl NeSegment {
+    pub fn is_code(&self) -> bool {
+        self.flags & NE_SEG_DATA == 0
+    }
+
+    pub fn has_relocations(&self) -> bool {
+        self.flags & NE_SEG_RELOCINFO != 0
+    }
+
+    /// The segment's data in `bytes`, empty for one without any in the file
+    pub fn data<'a>(&self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        bytes.get(self.file_offset..self.file_offset.checked_add(self.file_size)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeEntry {
+    pub ordinal: u16,
+    /// The segment number, or [`NE_ENTRY_CONSTANT`]
+    pub segment: u16,
+    pub offset: u16,
+    /// Bit 0 exported, bit 1 uses the shared data segment
+    pub flags: u8,
+    /// Reached through a thunk, as the segment may move
+    pub movable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ne {
+    /// The file offset of the NE header
+    pub offset: usize,
+    pub flags: u16,
+    pub target_os: u8,
+    /// The entry point as segment number and offset, segment 0 for none
+    pub entry: (u16, u16),
+    pub stack: (u16, u16),
+    pub alignment_shift: u16,
+    pub segments: Vec<NeSegment>,
+    pub entries: Vec<NeEntry>,
+    pub module_name: String,
+    pub description: Option<String>,
+    /// The exported names, resident and non-resident, with their ordinals
+    pub names: Vec<(String, u16)>,
+    /// The modules imported from
+    pub imports: Vec<String>,
+}
+
+impl Ne {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        match super::new_header(bytes)? {
+            (offset, [b'N', b'E']) => Self::parse_at(bytes, offset),
+            (offset, _) => Err(Error::Malformed(format!("No NE header at {:#x}", offset))),
+        }
+    }
+
+    /// Parse the NE header at `offset`
+    pub fn parse_at(bytes: &[u8], offset: usize) -> Result<Self> {
+        let header = |at: usize| -> Result<u16> { Ok(bytes.pread_with(offset + at, LE)?) };
+        let alignment_shift = match header(0x32)? {
+            0 => 9,
+            shift if shift < 16 => shift,
+            shift => {
+                return Err(Error::Malformed(format!(
+                    "NE alignment shift {} too large",
+                    shift
+                )))
+            }
+        };
+
+        let mut segments = Vec::new();
+        let mut at = offset + header(0x22)? as usize;
+        for number in 1..=header(0x1c)? {
+            let sector: u16 = bytes.gread_with(&mut at, LE)?;
+            let length: u16 = bytes.gread_with(&mut at, LE)?;
+            let flags: u16 = bytes.gread_with(&mut at, LE)?;
+            let min_alloc: u16 = bytes.gread_with(&mut at, LE)?;
+            let file_offset = (sector as usize) << alignment_shift;
+            segments.push(NeSegment {
+                number,
+                file_offset,
+                file_size: match (sector, length) {
+                    (0, _) => 0,
+                    (_, 0) => 0x10000,
+                    (_, length) => length as usize,
+                },
+                flags,
+                min_alloc: if min_alloc == 0 {
+                    0x10000
+                } else {
+                    min_alloc as u32
+                },
+            });
+        }
+
+        let entry_table = offset + header(0x04)? as usize;
+        let entries = entry_table_entries(bytes, entry_table, header(0x06)? as usize)?;
+
+        let mut names = name_table(bytes, offset + header(0x26)? as usize)?;
+        if names.is_empty() {
+            return Err(Error::Malformed("NE without a module name".into()));
+        }
+        let (module_name, _) = names.remove(0);
+        let mut description = None;
+        let nonresident = bytes.pread_with::<u32>(offset + 0x2c, LE)? as usize;
+        if header(0x20)? != 0 && nonresident != 0 {
+            let mut nonresident = name_table(bytes, nonresident)?;
+            if !nonresident.is_empty() {
+                description = Some(nonresident.remove(0).0);
+            }
+            names.extend(nonresident);
+        }
+
+        let imported_names = offset + header(0x2a)? as usize;
+        let mut at = offset + header(0x28)? as usize;
+        let mut imports = Vec::new();
+        for _ in 0..header(0x1e)? {
+            let name: u16 = bytes.gread_with(&mut at, LE)?;
+            imports.push(pascal_string(bytes, imported_names + name as usize)?.0);
+        }
+
+        Ok(Ne {
+            offset,
+            flags: header(0x0c)?,
+            target_os: bytes.pread(offset + 0x36)?,
+            entry: (header(0x16)?, header(0x14)?),
+            stack: (header(0x1a)?, header(0x18)?),
+            alignment_shift,
+            segments,
+            entries,
+            module_name,
+            description,
+            names,
+            imports,
+        })
+    }
+
+    pub fn is_library(&self) -> bool {
+        self.flags & NE_FLAG_LIBRARY != 0
+    }
+
+    pub fn entry_by_ordinal(&self, ordinal: u16) -> Option<&NeEntry> {
+        self.entries.iter().find(|entry| entry.ordinal == ordinal)
+    }
+
+    /// The exports, as name and entry
+    pub fn exports(&self) -> Vec<(&str, &NeEntry)> {
+        self.names
+            .iter()
+            .filter_map(|(name, ordinal)| Some((name.as_str(), self.entry_by_ordinal(*ordinal)?)))
+            .collect()
+    }
+}
+
+/// The entries of the entry table of `len` bytes at `at`: bundles of entries of one kind,
+/// numbered by ordinal from 1 on, empty bundles skipping ordinals
+fn entry_table_entries(bytes: &[u8], mut at: usize, len: usize) -> Result<Vec<NeEntry>> {
+    let end = at + len;
+    let mut entries = Vec::new();
+    let mut ordinal = 1u16;
+    while at < end {
+        let count: u8 = bytes.gread(&mut at)?;
+        if count == 0 {
+            break;
+        }
+        let indicator: u8 = bytes.gread(&mut at)?;
+        for _ in 0..count {
+            let entry = match indicator {
+                0 => None,
+                // flags, int 3fh, segment, offset
+                0xff => {
+                    let flags: u8 = bytes.gread(&mut at)?;
+                    at += 2;
+                    let segment: u8 = bytes.gread(&mut at)?;
+                    Some(NeEntry {
+                        ordinal,
+                        segment: segment as u16,
+                        offset: bytes.gread_with(&mut at, LE)?,
+                        flags,
+                        movable: true,
+                    })
+                }
+                segment => Some(NeEntry {
+                    ordinal,
+                    segment: segment as u16,
+                    flags: bytes.gread(&mut at)?,
+                    offset: bytes.gread_with(&mut at, LE)?,
+                    movable: false,
+                }),
+            };
+            entries.extend(entry);
+            ordinal = ordinal.wrapping_add(1);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy::{parse, Legacy};
+
+    #[test]
+    fn win16_library() {
+        let mut bytes = b"MZ".to_vec();
+        bytes.resize(0x3c, 0);
+        bytes.extend(0x40u32.to_le_bytes());
+        // the header, its tables after it at 0x40 on
+        let mut ne = vec![0u8; 0x40];
+        ne[..2].copy_from_slice(b"NE");
+        let mut put = |at: usize, value: u16| ne[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        put(0x0c, NE_FLAG_LIBRARY);
+        put(0x14, 0x10);
+        put(0x16, 1);
+        put(0x1c, 2);
+        put(0x1e, 1);
+        put(0x22, 0x40);
+        put(0x26, 0x50);
+        put(0x28, 0x60);
+        put(0x2a, 0x62);
+        put(0x04, 0x70);
+        put(0x06, 8);
+        put(0x32, 4);
+        ne[0x36] = NE_OS_WINDOWS;
+        // segments: code at 0x100 of 0x20 bytes, then data with none in the file
+        for word in [0x10u16, 0x20, 0, 0, 0, 0, NE_SEG_DATA, 0x100] {
+            ne.extend(word.to_le_bytes());
+        }
+        ne.resize(0x50, 0);
+        // resident names: the module, then FOO as ordinal 2
+        ne.extend(b"\x04DEMO\x00\x00\x03FOO\x02\x00\x00");
+        ne.resize(0x60, 0);
+        // module references and imported names
+        ne.extend(1u16.to_le_bytes());
+        ne.extend(b"\x00\x06KERNEL");
+        ne.resize(0x70, 0);
+        // entry table: an empty bundle of one, then one fixed entry in segment 1
+        ne.extend([1, 0, 1, 1, 1, 0x34, 0x12, 0]);
+        bytes.extend(ne);
+
+        let ne = Ne::parse(&bytes).unwrap();
+        assert!(ne.is_library());
+        assert_eq!(ne.module_name, "DEMO");
+        assert_eq!(ne.entry, (1, 0x10));
+        assert_eq!(ne.imports, ["KERNEL"]);
+        assert_eq!(ne.segments.len(), 2);
+        assert!(ne.segments[0].is_code());
+        assert_eq!(
+            (ne.segments[0].file_offset, ne.segments[0].file_size),
+            (0x100, 0x20)
+        );
+        assert_eq!(ne.segments[1].file_size, 0);
+        assert_eq!(ne.segments[1].min_alloc, 0x100);
+        let exports = ne.exports();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].0, "FOO");
+        assert_eq!((exports[0].1.segment, exports[0].1.offset), (1, 0x1234));
+        assert!(matches!(parse(&bytes), Ok(Legacy::Ne(_))));
+    }
+}
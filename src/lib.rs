@@ -1,24 +1,16 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-pub mod analysis;
-pub mod constants;
-pub mod context;
-pub mod emulator;
-pub mod ihex;
-pub mod memory;
-pub mod monitor;
-pub mod page_lookup;
-pub mod parser;
-pub mod utils;
-pub mod vstruct;
-pub mod workspace;
-
-#[cfg(feature = "std")]
-extern crate core;
-
-#[cfg(feature = "alloc")]
-#[macro_use]
-extern crate alloc;
+/// Report something wrong with the input that parsing or loading worked around. With the
+/// `tracing` feature it is a warning event of the current span, otherwise a `log` warning.
+#[allow(unused)]
+macro_rules! anomaly {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!(target: "vivisect::anomaly", $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::warn!(target: "vivisect::anomaly", $($arg)+);
+    }};
+}
 
 /////////////////////////
 // Misc/Helper Modules
@@ -32,6 +24,14 @@ macro_rules! if_std {
     )*)
 }
 
+#[allow(unused)]
+macro_rules! if_analysis {
+    ($($i:item)*) => ($(
+        #[cfg(feature = "analysis")]
+        $i
+    )*)
+}
+
 #[allow(unused)]
 macro_rules! if_alloc {
     ($($i:item)*) => ($(
@@ -40,10 +40,162 @@ macro_rules! if_alloc {
     )*)
 }
 
+/////////////////////////
+// Loading, Analysis and Emulation
+/////////////////////////
+
+// The workspace and everything around it need std and the container parsers, which build
+// with alloc alone.
+if_analysis! {
+    pub mod abidiff;
+    pub mod analysis;
+    pub mod antianalysis;
+    pub mod arena;
+    pub mod assemble;
+    pub mod attack;
+    pub mod audit;
+    pub mod auditlog;
+    pub mod basefind;
+    pub mod batch;
+    pub mod bitcode;
+    #[cfg(feature = "objc")]
+    pub mod blocks;
+    pub mod bundle;
+    pub mod callargs;
+    pub mod capabilities;
+    pub mod carve;
+    #[cfg(feature = "solver")]
+    pub mod concolic;
+    pub mod constants;
+    pub mod constdecode;
+    pub mod context;
+    pub mod cortexm;
+    pub mod coverage;
+    pub mod debuginfo;
+    pub mod decoder;
+    pub mod deobfuscate;
+    pub mod detours;
+    pub mod devirt;
+    pub mod dex;
+    pub mod driver;
+    pub mod dyld;
+    pub mod dynimports;
+    pub mod emulator;
+    pub mod envi;
+    pub mod exceptions;
+    pub mod findings;
+    pub mod flattening;
+    #[cfg(feature = "fuzzy")]
+    pub mod fuzzy;
+    #[cfg(feature = "gadgets")]
+    pub mod gadgets;
+    pub mod hashing;
+    pub mod hijack;
+    pub mod hooks;
+    pub mod ihex;
+    pub mod ilemu;
+    pub mod immediates;
+    mod impapi;
+    pub mod implib;
+    pub mod integrity;
+    pub mod interop;
+    pub mod intervals;
+    pub mod isaext;
+    pub mod journal;
+    pub mod labels;
+    pub mod layout;
+    pub mod libsigs;
+    pub mod listing;
+    pub mod loader;
+    pub mod locations;
+    pub mod mapfile;
+    pub mod memory;
+    pub mod merge;
+    pub mod metrics;
+    pub mod minidump;
+    pub mod mitigations;
+    pub mod monitor;
+    pub mod naming;
+    pub mod noreturn;
+    #[cfg(feature = "objc")]
+    pub mod objc;
+    pub mod ordinals;
+    pub mod origins;
+    pub mod osprofile;
+    pub mod overrides;
+    pub mod page_lookup;
+    pub mod parser;
+    pub mod patch;
+    pub mod pattern;
+    pub mod persistent;
+    pub mod pic;
+    pub mod plist;
+    #[cfg(feature = "plugins")]
+    pub mod plugins;
+    pub mod profile;
+    pub mod prototypes;
+    pub mod provenance;
+    pub mod pseudoimports;
+    pub mod query;
+    pub mod realmode;
+    pub mod regstate;
+    #[cfg(feature = "report")]
+    pub mod report;
+    pub mod resolve;
+    pub mod sanitizers;
+    #[cfg(feature = "scripting")]
+    pub mod scripting;
+    pub mod shared;
+    pub mod slicing;
+    pub mod snapshot;
+    #[cfg(feature = "solver")]
+    pub mod solver;
+    #[cfg(feature = "sqlite")]
+    pub mod sqlite;
+    pub mod stackdepth;
+    pub mod stacktrace;
+    pub mod staticpie;
+    pub mod storage;
+    pub mod structs;
+    pub mod stubdis;
+    pub mod switches;
+    pub mod symbolic;
+    pub mod symcache;
+    pub mod syscalls;
+    pub mod tablehash;
+    pub mod tags;
+    pub mod tailcall;
+    pub mod taint;
+    pub mod tls;
+    pub mod trampolines;
+    pub mod transplant;
+    pub mod unpack;
+    pub mod unwind;
+    pub mod upx;
+    pub mod utils;
+    pub mod vsa;
+    pub mod vstruct;
+    #[cfg(feature = "wasm")]
+    pub mod wasm;
+    pub mod watch;
+    pub mod workspace;
+}
+
+#[cfg(feature = "std")]
+extern crate core;
+
+#[cfg(feature = "alloc")]
+#[macro_use]
+extern crate alloc;
+
 #[cfg(feature = "alloc")]
 pub mod error;
 
 pub mod strtab;
+#[cfg(feature = "alloc")]
+pub mod overlay;
+#[cfg(feature = "alloc")]
+pub mod transform;
 
 /// Binary container size information and byte-order context
 pub mod container {
@@ -146,6 +298,8 @@ macro_rules! if_everything {
 
 if_everything! {
 
+    use alloc::{string::ToString, vec::Vec};
+
     #[derive(Debug, Default)]
     /// Information obtained from a peek `Hint`
     pub struct HintData {
@@ -256,6 +410,28 @@ if_everything! {
                 Err(error::Error::Malformed("Object is too small.".to_string()))
             }
         }
+
+        /// The identity of this module: the Mach-O `LC_UUID`, the ELF GNU build-id or the PE PDB GUID.
+        /// `bytes` must be the bytes this object was parsed from.
+        pub fn uuid(&self, bytes: &'a [u8]) -> Option<Vec<u8>> {
+            match self {
+                Object::Elf(elf) => elf.build_id(bytes).map(|id| id.to_vec()),
+                Object::PE(pe) => pe.uuid().map(|id| id.to_vec()),
+                Object::Mach(mach::Mach::Binary(macho)) => macho.uuid().map(|id| id.to_vec()),
+                _ => None,
+            }
+        }
+
+        /// Whether the target stores values least significant byte first. PE is always little
+        /// endian; a fat Mach-O and archives don't have one byte order.
+        pub fn is_little_endian(&self) -> Option<bool> {
+            match self {
+                Object::Elf(elf) => Some(elf.little_endian),
+                Object::PE(_) => Some(true),
+                Object::Mach(mach::Mach::Binary(macho)) => Some(macho.little_endian),
+                _ => None,
+            }
+        }
     }
 } // end if_endian_fd
 
@@ -309,8 +485,10 @@ pub mod pe;
 
 #[cfg(feature = "archive")]
 pub mod archive;
-mod impapi;
-mod envi;
+#[cfg(feature = "alloc")]
+pub mod embedded;
+#[cfg(feature = "alloc")]
+pub mod legacy;
 
 #[cfg(test)]
 mod tests {
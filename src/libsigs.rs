@@ -0,0 +1,452 @@
+//! Library function signatures.
+//!
+//! A signature is the bytes of a function with the position dependent ones wildcarded, its
+//! length and the names of the functions and imports it calls. [`generate`] makes one for each
+//! named function of an analyzed static library or known good binary, so a pack of them can be
+//! built up over several workspaces and written out, and [`SignatureSet::identify`] finds the
+//! functions of another workspace they match.
+//!
+//! The bytes relocations patch are wildcarded, as are the operands of the instructions
+//! referencing anything outside the function (calls, tail jumps and RIP relative data), since
+//! linking moves those. Branches within the function keep their bytes. When several functions
+//! generate the same signature under different names none of them can be told apart, and the
+//! signature is dropped.
+//!
+//! Signatures are written one per line as the pattern in hex with `..` for a wildcard, the
+//! length, the name and the callees, separated by spaces. Functions with whitespace in their
+//! names are skipped.
+
+use crate::{
+    constants::{BR_PROC, LOC_IMPORT, LOC_OP, REF_CODE},
+    envi::Arch,
+    hashing::{function_bytes, relocated_bytes},
+    memory::Memory,
+    metrics::ranges,
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+/// The fewest fixed bytes a signature may have
+pub const MIN_FIXED_BYTES: usize = 12;
+
+/// A masked byte pattern, `None` for a wildcard
+pub type Pattern = Vec<Option<u8>>;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signature {
+    pub pattern: Pattern,
+    /// The length of the function, which is the length of the pattern
+    pub size: usize,
+    pub name: String,
+    /// The names of what the function calls, imports as `library.name`
+    pub callees: BTreeSet<String>,
+}
+
+impl Signature {
+    /// Whether `bytes` fit the pattern
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() == self.size
+            && self
+                .pattern
+                .iter()
+                .zip(bytes)
+                .all(|(want, byte)| want.is_none_or(|want| want == *byte))
+    }
+
+    /// How many of the pattern bytes aren't wildcards
+    pub fn fixed(&self) -> usize {
+        self.pattern.iter().filter(|byte| byte.is_some()).count()
+    }
+
+    fn key(&self) -> (Pattern, BTreeSet<String>) {
+        (self.pattern.clone(), self.callees.clone())
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.pattern.iter() {
+            match byte {
+                Some(byte) => write!(f, "{:02x}", byte)?,
+                None => write!(f, "..")?,
+            }
+        }
+        write!(f, " {} {}", self.size, self.name)?;
+        for callee in self.callees.iter() {
+            write!(f, " {}", callee)?;
+        }
+        Ok(())
+    }
+}
+
+/// A pack of signatures
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SignatureSet {
+    signatures: BTreeMap<(Pattern, BTreeSet<String>), Signature>,
+    /// Signatures generated under more than one name
+    ambiguous: BTreeSet<(Pattern, BTreeSet<String>)>,
+}
+
+impl SignatureSet {
+    pub fn new() -> Self {
+        SignatureSet::default()
+    }
+
+    /// Add a signature, dropping it and any signature already added with the same pattern and
+    /// callees if their names differ
+    pub fn add(&mut self, signature: Signature) {
+        let key = signature.key();
+        if self.ambiguous.contains(&key) {
+            return;
+        }
+        match self.signatures.get(&key) {
+            Some(known) if known.name != signature.name => {
+                self.signatures.remove(&key);
+                self.ambiguous.insert(key);
+            }
+            Some(_) => {}
+            None => {
+                self.signatures.insert(key, signature);
+            }
+        }
+    }
+
+    /// Add the signatures of another pack
+    pub fn merge(&mut self, other: SignatureSet) {
+        self.ambiguous.extend(other.ambiguous);
+        for signature in other.signatures.into_values() {
+            self.add(signature);
+        }
+        let ambiguous = &self.ambiguous;
+        self.signatures.retain(|key, _| !ambiguous.contains(key));
+    }
+
+    pub fn signatures(&self) -> impl Iterator<Item = &Signature> {
+        self.signatures.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    /// The functions of `workspace` matching a signature, with the name of the signature. A
+    /// function matching more than one is given the one sharing the most callees with it, if
+    /// that is a single one.
+    pub fn identify(&self, workspace: &VivWorkspace) -> Vec<(i32, String)> {
+        let mut by_size: BTreeMap<usize, Vec<&Signature>> = BTreeMap::new();
+        for signature in self.signatures.values() {
+            by_size.entry(signature.size).or_default().push(signature);
+        }
+        let mut functions = workspace.get_functions();
+        functions.sort_unstable();
+        let mut found = Vec::new();
+        for fva in functions {
+            let Some(bytes) = function_bytes(workspace, fva, false) else {
+                continue;
+            };
+            let Some(sized) = by_size.get(&bytes.len()) else {
+                continue;
+            };
+            let candidates: Vec<&Signature> = sized
+                .iter()
+                .filter(|signature| signature.matches(&bytes))
+                .copied()
+                .collect();
+            let callees = callees(workspace, fva);
+            let shared = |signature: &Signature| signature.callees.intersection(&callees).count();
+            let best = candidates.iter().map(|signature| shared(signature)).max();
+            let mut best: Vec<&Signature> = candidates
+                .into_iter()
+                .filter(|signature| Some(shared(signature)) == best)
+                .collect();
+            if best.len() == 1 {
+                found.push((fva, best.remove(0).name.clone()));
+            }
+        }
+        found
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for signature in self.signatures.values() {
+            writeln!(out, "{}", signature)?;
+        }
+        Ok(())
+    }
+
+    /// Read a pack, skipping blank lines and `#` comments
+    pub fn read<R: BufRead>(input: R) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Bad signature line: {}", line),
+            )
+        };
+        let mut set = SignatureSet::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(hex), Some(size), Some(name)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid(line));
+            };
+            let size: usize = size.parse().map_err(|_| invalid(line))?;
+            if hex.len() != size * 2 || !hex.is_ascii() {
+                return Err(invalid(line));
+            }
+            let pattern = (0..size)
+                .map(|i| match &hex[i * 2..i * 2 + 2] {
+                    ".." => Ok(None),
+                    byte => u8::from_str_radix(byte, 16).map(Some),
+                })
+                .collect::<Result<Pattern, _>>()
+                .map_err(|_| invalid(line))?;
+            set.add(Signature {
+                pattern,
+                size,
+                name: name.to_string(),
+                callees: fields.map(str::to_string).collect(),
+            });
+        }
+        Ok(set)
+    }
+}
+
+/// The bytes of the operand of an instruction of `size` bytes which hold a target address or
+/// displacement
+fn operand(arch: Option<Arch>, size: usize) -> Range<usize> {
+    match arch {
+        // the 24 bit branch offset below the condition and opcode
+        Some(Arch::ArmV7) => 0..size.min(3),
+        // the offsets of these are scattered through the instruction
+        Some(Arch::A64 | Arch::Thumb | Arch::Thumb16) => 0..size,
+        // a trailing displacement of up to 32 bits after at least an opcode byte
+        _ => size - size.saturating_sub(1).min(4)..size,
+    }
+}
+
+/// Whether `va` is in one of `ranges`
+fn within(ranges: &[(i32, i32)], va: i32) -> bool {
+    ranges
+        .iter()
+        .any(|(start, size)| *start <= va && (va as i64) < *start as i64 + *size as i64)
+}
+
+/// The instructions of the function at `fva` in `ranges`
+fn ops(workspace: &VivWorkspace, ranges: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut ops = Vec::new();
+    for (start, size) in ranges.iter() {
+        let end = *start as i64 + *size as i64;
+        let mut va = *start;
+        while (va as i64) < end {
+            match workspace.get_location(va) {
+                Some((lva, lsize, ltype, _)) if lva == va && lsize > 0 => {
+                    if ltype == LOC_OP {
+                        ops.push((va, lsize));
+                    }
+                    va = va.wrapping_add(lsize);
+                }
+                _ => va = va.wrapping_add(1),
+            }
+        }
+    }
+    ops
+}
+
+/// The masked byte pattern of the function at `fva`
+pub fn pattern(workspace: &VivWorkspace, fva: i32) -> Option<Pattern> {
+    let arch = Arch::from_envi(workspace.arch);
+    let mut chunks = ranges(workspace, fva);
+    chunks.sort();
+    let mut pattern = Vec::new();
+    for (va, size) in chunks.iter().copied() {
+        let bytes = workspace.read_memory(va, size)?;
+        if bytes.len() != size as usize {
+            return None;
+        }
+        let patched = relocated_bytes(workspace, va, size);
+        pattern.extend(
+            bytes
+                .into_iter()
+                .zip(patched)
+                .map(|(byte, patched)| (!patched).then_some(byte)),
+        );
+    }
+    // where each chunk starts in the pattern
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for (va, size) in chunks.iter() {
+        offsets.push((*va, *size, offset));
+        offset += *size as usize;
+    }
+    for (va, size) in ops(workspace, &chunks) {
+        let leaves = workspace
+            .get_xrefs_from(va, None)
+            .iter()
+            .any(|(_, to, ..)| !within(&chunks, *to));
+        if !leaves {
+            continue;
+        }
+        let Some((start, _, offset)) = offsets
+            .iter()
+            .find(|(start, csize, _)| within(&[(*start, *csize)], va))
+        else {
+            continue;
+        };
+        let at = offset + (va - start) as usize;
+        for i in operand(arch, size as usize) {
+            if let Some(byte) = pattern.get_mut(at + i) {
+                *byte = None;
+            }
+        }
+    }
+    Some(pattern)
+}
+
+/// The name of `va` without the name of its file, if it isn't one made up by analysis. Imports
+/// keep their `library.name` names.
+fn portable_name(workspace: &VivWorkspace, va: i32) -> Option<String> {
+    if workspace.is_auto_name(va) {
+        return None;
+    }
+    let name = workspace.get_name(va, false)?;
+    if workspace
+        .get_location(va)
+        .is_some_and(|(_, _, ltype, _)| ltype == LOC_IMPORT)
+    {
+        return Some(name);
+    }
+    match workspace.get_file_by_va(va) {
+        Some(fname) => Some(
+            name.strip_prefix(&format!("{}.", fname))
+                .unwrap_or(&name)
+                .to_string(),
+        ),
+        None => Some(name),
+    }
+}
+
+/// The names of the functions and imports the function at `fva` calls
+pub fn callees(workspace: &VivWorkspace, fva: i32) -> BTreeSet<String> {
+    let chunks = ranges(workspace, fva);
+    let mut callees = BTreeSet::new();
+    for (va, _) in ops(workspace, &chunks) {
+        for (_, to, _, rflags) in workspace.get_xrefs_from(va, Some(REF_CODE)) {
+            let import = workspace
+                .get_location(to)
+                .is_some_and(|(_, _, ltype, _)| ltype == LOC_IMPORT);
+            if rflags & BR_PROC == 0 && !import {
+                continue;
+            }
+            if let Some(name) = portable_name(workspace, to) {
+                callees.insert(name);
+            }
+        }
+    }
+    callees
+}
+
+/// Signatures for the named functions of `workspace`. Functions with fewer than
+/// [`MIN_FIXED_BYTES`] fixed bytes are too generic to sign and are left out.
+pub fn generate(workspace: &VivWorkspace) -> SignatureSet {
+    let mut functions = workspace.get_functions();
+    functions.sort_unstable();
+    let mut set = SignatureSet::new();
+    for fva in functions {
+        let Some(name) = portable_name(workspace, fva) else {
+            continue;
+        };
+        if name.contains(char::is_whitespace) {
+            continue;
+        }
+        let Some(pattern) = pattern(workspace, fva) else {
+            continue;
+        };
+        let signature = Signature {
+            size: pattern.len(),
+            pattern,
+            name,
+            callees: callees(workspace, fva),
+        };
+        if signature.fixed() >= MIN_FIXED_BYTES {
+            set.add(signature);
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_I386, MM_EXEC, MM_READ};
+
+    #[test]
+    fn generates_and_identifies() {
+        // push ebp; mov ebp, esp; push 0x10; call memset; xor eax, eax; mov esp, ebp; pop ebp;
+        // add eax, 0x12345678; ret
+        let code = |call: i32| {
+            let mut code = vec![0x55, 0x8b, 0xec, 0x6a, 0x10, 0xe8];
+            code.extend(call.to_le_bytes());
+            code.extend([0x31, 0xc0, 0x8b, 0xe5, 0x5d, 0x05, 0x78, 0x56, 0x34, 0x12, 0xc3]);
+            code
+        };
+        let layout = |ws: &mut VivWorkspace, base: i32, name: &str, auto: bool| {
+            let mut text = code(0x100 - 10);
+            text.resize(0x100, 0xcc);
+            text.extend([0xc3; 0x10]);
+            ws.set_mem_architecture(ARCH_I386 as u32);
+            ws.add_memory_map(base, MM_READ | MM_EXEC, "text", text, None);
+            ws.add_segment(base, 0x110, ".text", "lib".to_string());
+            let mut va = base;
+            for size in [1, 2, 2, 5, 2, 2, 1, 5, 1] {
+                ws.add_location(va, size, LOC_OP, Some(vec![]));
+                va += size;
+            }
+            ws.add_function(base, vec![(base, 0x15)]);
+            ws.add_function(base + 0x100, vec![(base + 0x100, 1)]);
+            ws.add_xref(base + 5, base + 0x100, REF_CODE, BR_PROC);
+            if auto {
+                return;
+            }
+            ws.make_name(base, name.to_string(), true, false);
+            ws.make_name(base + 0x100, "memset".to_string(), true, false);
+        };
+
+        let mut lib = VivWorkspace::new("", false);
+        layout(&mut lib, 0x1000, "clear", false);
+        let set = generate(&lib);
+        // memset is a single byte, too short to sign
+        assert_eq!(set.len(), 1);
+        let mut text = Vec::new();
+        set.write(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text.clone()).unwrap(),
+            "558bec6a10e8........31c08be55d0578563412c3 21 clear memset\n"
+        );
+        let set = SignatureSet::read(&text[..]).unwrap();
+
+        // the same function linked elsewhere, unnamed
+        let mut target = VivWorkspace::new("", false);
+        layout(&mut target, 0x40_0000, "", true);
+        assert_eq!(set.identify(&target), [(0x40_0000, "clear".to_string())]);
+
+        // a second function with the same bytes under another name can't be told apart
+        let mut other = VivWorkspace::new("", false);
+        layout(&mut other, 0x2000, "wipe", false);
+        let mut merged = set.clone();
+        merged.merge(generate(&other));
+        assert!(merged.is_empty());
+    }
+}
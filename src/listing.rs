@@ -0,0 +1,675 @@
+//! Full program listings, as text in the layout of `objdump -D` or of an IDA `.lst` file.
+//!
+//! [`write_listing`] walks every memory map of a workspace in VA order and writes it out one
+//! location at a time: instructions, strings, pointers and numbers as analysis made them, and
+//! the bytes no location covers as raw data. Names label what they name, functions open and
+//! close with `proc`/`endp` in the IDA layout, and the xrefs to an address, the comments on it
+//! and a preview of the string an instruction or pointer refers to are written alongside.
+//!
+//! The listing is written to the writer as it goes, line by line, so one for a large program
+//! never has to be held in memory. Instructions are decoded with iced-x86 for i386, amd64 and
+//! real mode code when the crate is built with it; without a decoder, and on other
+//! architectures, they are written as their bytes.
+
+use crate::{
+    constants::{
+        LOC_NUMBER, LOC_OP, LOC_POINTER, LOC_STRING, LOC_UNI, MM_EXEC, REF_CODE, REF_CONST,
+        REF_DATA,
+    },
+    memory::Memory,
+    naming::{auto_name, AutoKind},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+/// The longest string preview written in a comment, in characters
+const PREVIEW_LEN: usize = 48;
+/// The most xrefs written for an address, the rest are counted
+const MAX_XREFS: usize = 4;
+/// Bytes per line of raw data, and of instruction bytes in the objdump layout
+const OBJDUMP_ROW: usize = 7;
+const IDA_ROW: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// `  401000:\t55                   \tpush ebp`, with sections and `<symbol>:` labels as
+    /// `objdump -D -M intel` lays them out
+    Objdump,
+    /// `.text:00401000                 push    ebp`, with `db`/`dd` data and `proc`/`endp`
+    /// around functions as IDA's `.lst` output lays them out
+    Ida,
+}
+
+impl Style {
+    fn comment(&self) -> &'static str {
+        match self {
+            Style::Objdump => "#",
+            Style::Ida => ";",
+        }
+    }
+}
+
+/// Write the listing of every memory map of `workspace` in `style`.
+pub fn write_listing<W: Write>(
+    workspace: &VivWorkspace,
+    style: Style,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut lister = Lister::new(workspace, style, out);
+    lister.header()?;
+    for (va, perms, bytes) in workspace.get_maps() {
+        lister.map(va, perms, bytes)?;
+    }
+    Ok(())
+}
+
+struct Lister<'a, W> {
+    workspace: &'a VivWorkspace,
+    style: Style,
+    out: &'a mut W,
+    /// The segment name of the map being listed, which prefixes every IDA line
+    segment: String,
+    /// Where the functions seen so far end, with their name, for `endp`
+    ends: BTreeMap<i32, Vec<String>>,
+    comments: HashMap<i32, String>,
+    decoder: Option<Decoder>,
+}
+
+impl<'a, W: Write> Lister<'a, W> {
+    fn new(workspace: &'a VivWorkspace, style: Style, out: &'a mut W) -> Self {
+        Lister {
+            workspace,
+            style,
+            out,
+            segment: String::new(),
+            ends: BTreeMap::new(),
+            comments: workspace.get_comments(),
+            decoder: Decoder::new(workspace, style),
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let files = self.workspace.get_files().join(", ");
+        let format = self.workspace.get_meta("Format").unwrap_or_default();
+        match self.style {
+            Style::Objdump => writeln!(self.out, "\n{}:     file format {}", files, format),
+            Style::Ida => {
+                writeln!(self.out, "; File Name   : {}", files)?;
+                writeln!(self.out, "; Format      : {}", format)
+            }
+        }
+    }
+
+    fn map(&mut self, mva: i32, perms: i32, bytes: &[u8]) -> io::Result<()> {
+        self.segment = match self.workspace.get_segment(mva) {
+            Some((_, _, name, _)) if !name.is_empty() => name,
+            _ => format!("seg{:x}", mva as u32),
+        };
+        let end = mva.wrapping_add(bytes.len() as i32);
+        match self.style {
+            Style::Objdump => writeln!(self.out, "\nDisassembly of section {}:", self.segment)?,
+            Style::Ida => {
+                let kind = if perms & MM_EXEC != 0 {
+                    "Pure code"
+                } else {
+                    "Pure data"
+                };
+                self.ida_line(mva, &format!("; Segment type: {}", kind))?;
+                self.ida_line(mva, &format!("{} segment", self.segment))?;
+            }
+        }
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let va = mva.wrapping_add(offset as i32);
+            self.close_functions(va)?;
+            self.labels(va)?;
+            let rest = &bytes[offset..];
+            let size = match self.workspace.get_location(va) {
+                Some((lva, lsize, ltype, _)) if lva == va && lsize > 0 => {
+                    let size = (lsize as usize).min(rest.len());
+                    self.location(va, ltype, &rest[..size])?;
+                    size
+                }
+                _ => self.undefined(va, rest)?,
+            };
+            offset += size;
+        }
+        self.close_functions(end)?;
+        if self.style == Style::Ida {
+            self.ida_line(end, &format!("{} ends", self.segment))?;
+        }
+        Ok(())
+    }
+
+    /// The label, function start and xrefs lines of `va`
+    fn labels(&mut self, va: i32) -> io::Result<()> {
+        let function = self.workspace.is_function(va);
+        let name = match self.workspace.get_name(va, false) {
+            Some(name) => Some(name),
+            None if function => Some(auto_name(AutoKind::Function, va)),
+            None => None,
+        };
+        if let Some(name) = name.as_ref() {
+            match self.style {
+                Style::Objdump => {
+                    let width = if self.workspace.get_pointer_size() == 8 {
+                        16
+                    } else {
+                        8
+                    };
+                    writeln!(self.out, "\n{:0width$x} <{}>:", va as u32, name)?
+                }
+                Style::Ida if function => {
+                    self.ida_line(va, "")?;
+                    self.ida_line(va, &format!("{} proc near", name))?;
+                }
+                Style::Ida => self.ida_line(va, &format!("{}:", name))?,
+            }
+        }
+        if function {
+            // the body of a function with chunks elsewhere ends with the range it starts
+            let size = match self.workspace.get_function_bounds(va) {
+                Some(ranges) => ranges
+                    .iter()
+                    .find(|(rva, _)| *rva == va)
+                    .map_or(0, |(_, size)| *size),
+                None => self
+                    .workspace
+                    .get_function_meta_dict(va)
+                    .get("Size")
+                    .copied()
+                    .unwrap_or(0),
+            };
+            if size > 0 {
+                let name = name.unwrap_or_default();
+                self.ends
+                    .entry(va.wrapping_add(size))
+                    .or_default()
+                    .push(name);
+            }
+        }
+        let xrefs = self.workspace.get_xrefs_to(va, None);
+        for (from, _, rtype, _) in xrefs.iter().take(MAX_XREFS) {
+            let kind = match *rtype {
+                REF_CODE => "CODE",
+                REF_DATA => "DATA",
+                REF_CONST => "CONST",
+                _ => "PTR",
+            };
+            let line = format!(
+                "{} {} XREF: {}",
+                self.style.comment(),
+                kind,
+                self.describe(*from)
+            );
+            self.note(va, &line)?;
+        }
+        if xrefs.len() > MAX_XREFS {
+            let line = format!(
+                "{} ... and {} more",
+                self.style.comment(),
+                xrefs.len() - MAX_XREFS
+            );
+            self.note(va, &line)?;
+        }
+        Ok(())
+    }
+
+    /// `endp` for the functions ending at or before `va`
+    fn close_functions(&mut self, va: i32) -> io::Result<()> {
+        while let Some(entry) = self.ends.first_entry() {
+            if *entry.key() > va {
+                break;
+            }
+            let (end, names) = entry.remove_entry();
+            if self.style == Style::Ida {
+                for name in names {
+                    self.ida_line(end, &format!("{} endp", name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn location(&mut self, va: i32, ltype: i32, bytes: &[u8]) -> io::Result<()> {
+        let text = match ltype {
+            LOC_OP => self
+                .decoder
+                .as_mut()
+                .and_then(|decoder| decoder.decode(va, bytes)),
+            LOC_STRING => Some(self.string(&String::from_utf8_lossy(trim_nul(bytes)), false)),
+            LOC_UNI => Some(self.string(&utf16(bytes), true)),
+            LOC_POINTER | LOC_NUMBER if matches!(bytes.len(), 1 | 2 | 4 | 8) => {
+                let value = self.value(bytes);
+                let target = (ltype == LOC_POINTER)
+                    .then(|| self.workspace.get_name(value as i32, false))
+                    .flatten();
+                Some(self.number(bytes.len(), value, target))
+            }
+            _ => None,
+        };
+        let mut comment = self.comments.get(&va).cloned();
+        if let Some(preview) = self.preview(va) {
+            comment = Some(match comment {
+                Some(comment) => format!("{} {}", comment, preview),
+                None => preview,
+            });
+        }
+        match text {
+            Some(text) => self.row(va, bytes, &text, comment.as_deref()),
+            None => self.raw(va, bytes, comment.as_deref()),
+        }
+    }
+
+    /// The bytes at `va` up to the next location or label, returning how many were written
+    fn undefined(&mut self, va: i32, rest: &[u8]) -> io::Result<usize> {
+        let mut size = 1;
+        while size < rest.len() {
+            let next = va.wrapping_add(size as i32);
+            if self.workspace.get_location(next).is_some()
+                || self.workspace.get_name(next, false).is_some()
+                || self.workspace.is_function(next)
+                || !self.workspace.get_xrefs_to(next, None).is_empty()
+                || self.ends.contains_key(&next)
+            {
+                break;
+            }
+            size += 1;
+        }
+        let comment = self.comments.get(&va).cloned();
+        self.raw(va, &rest[..size], comment.as_deref())?;
+        Ok(size)
+    }
+
+    /// Bytes without a location: rows of bytes, and a run of zeros as `...` or a `dup`
+    fn raw(&mut self, va: i32, bytes: &[u8], comment: Option<&str>) -> io::Result<()> {
+        let zeros = bytes.iter().take_while(|b| **b == 0).count();
+        if zeros >= IDA_ROW {
+            match self.style {
+                Style::Objdump => writeln!(self.out, "\t...")?,
+                Style::Ida => {
+                    let text = format!("db {} dup(0)", ida_hex(zeros as u64));
+                    self.code_line(va, &text, comment)?
+                }
+            }
+            if zeros < bytes.len() {
+                self.raw(va.wrapping_add(zeros as i32), &bytes[zeros..], None)?;
+            }
+            return Ok(());
+        }
+        match self.style {
+            Style::Objdump => self.row(va, bytes, "", comment),
+            Style::Ida => {
+                for (i, chunk) in bytes.chunks(IDA_ROW).enumerate() {
+                    let text = chunk
+                        .iter()
+                        .map(|b| ida_hex(u64::from(*b)))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let rva = va.wrapping_add((i * IDA_ROW) as i32);
+                    let comment = if i == 0 { comment } else { None };
+                    self.code_line(rva, &format!("db {}", text), comment)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// One item: its bytes (in the objdump layout), its text and a comment
+    fn row(&mut self, va: i32, bytes: &[u8], text: &str, comment: Option<&str>) -> io::Result<()> {
+        match self.style {
+            Style::Objdump => {
+                for (i, chunk) in bytes.chunks(OBJDUMP_ROW).enumerate() {
+                    let hex = chunk
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let rva = va.wrapping_add((i * OBJDUMP_ROW) as i32);
+                    write!(self.out, "{:8x}:\t{:<20}", rva as u32, hex)?;
+                    if i == 0 {
+                        write!(self.out, "\t{}", text)?;
+                        if let Some(comment) = comment {
+                            write!(self.out, " {} {}", self.style.comment(), comment)?;
+                        }
+                    }
+                    writeln!(self.out)?;
+                }
+                Ok(())
+            }
+            Style::Ida => self.code_line(va, text, comment),
+        }
+    }
+
+    /// An indented IDA line, or an objdump line without bytes
+    fn note(&mut self, va: i32, text: &str) -> io::Result<()> {
+        match self.style {
+            Style::Objdump => writeln!(self.out, "{:8x}:\t{:<20}\t{}", va as u32, "", text),
+            Style::Ida => self.code_line(va, text, None),
+        }
+    }
+
+    fn code_line(&mut self, va: i32, text: &str, comment: Option<&str>) -> io::Result<()> {
+        let mut line = format!("{:16}{}", "", text);
+        if let Some(comment) = comment {
+            let _ = write!(line, " ; {}", comment);
+        }
+        self.ida_line(va, &line)
+    }
+
+    fn ida_line(&mut self, va: i32, text: &str) -> io::Result<()> {
+        writeln!(self.out, "{}:{:08X} {}", self.segment, va as u32, text)
+    }
+
+    /// A string location, as data
+    fn string(&self, text: &str, wide: bool) -> String {
+        match self.style {
+            Style::Objdump if wide => format!(".string16 \"{}\"", text.escape_default()),
+            Style::Objdump => format!(".asciz \"{}\"", text.escape_default()),
+            Style::Ida => {
+                let mut parts = Vec::new();
+                let mut quoted = String::new();
+                for c in text.chars() {
+                    if (' '..='~').contains(&c) && c != '\'' {
+                        quoted.push(c);
+                        continue;
+                    }
+                    if !quoted.is_empty() {
+                        parts.push(format!("'{}'", quoted));
+                        quoted.clear();
+                    }
+                    parts.push(ida_hex(u64::from(u32::from(c))));
+                }
+                if !quoted.is_empty() {
+                    parts.push(format!("'{}'", quoted));
+                }
+                parts.push("0".to_string());
+                if wide {
+                    format!("text \"UTF-16LE\", {}", parts.join(","))
+                } else {
+                    format!("db {}", parts.join(","))
+                }
+            }
+        }
+    }
+
+    fn number(&self, size: usize, value: u64, target: Option<String>) -> String {
+        match self.style {
+            Style::Objdump => {
+                let directive = match size {
+                    1 => ".byte",
+                    2 => ".short",
+                    4 => ".long",
+                    _ => ".quad",
+                };
+                match target {
+                    Some(name) => format!("{} {:#x} <{}>", directive, value, name),
+                    None => format!("{} {:#x}", directive, value),
+                }
+            }
+            Style::Ida => {
+                let directive = match size {
+                    1 => "db",
+                    2 => "dw",
+                    4 => "dd",
+                    _ => "dq",
+                };
+                match target {
+                    Some(name) => format!("{} offset {}", directive, name),
+                    None => format!("{} {}", directive, ida_hex(value)),
+                }
+            }
+        }
+    }
+
+    /// The value of a number or pointer, in the byte order of the target
+    fn value(&self, bytes: &[u8]) -> u64 {
+        let big = self.workspace.get_meta("bigend").as_deref() == Some("true");
+        let fold = |value: u64, b: &u8| value << 8 | u64::from(*b);
+        if big {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    }
+
+    /// A preview of the string the item at `va` refers to
+    fn preview(&self, va: i32) -> Option<String> {
+        self.workspace
+            .get_xrefs_from(va, None)
+            .into_iter()
+            .find_map(|(_, to, _, _)| {
+                let (lva, lsize, ltype, _) = self.workspace.get_location(to)?;
+                if lva != to || !matches!(ltype, LOC_STRING | LOC_UNI) {
+                    return None;
+                }
+                let bytes = self.workspace.read_memory(lva, lsize)?;
+                let text = match ltype {
+                    LOC_STRING => String::from_utf8_lossy(trim_nul(&bytes)).into_owned(),
+                    _ => utf16(&bytes),
+                };
+                let mut preview = text.chars().take(PREVIEW_LEN).collect::<String>();
+                if text.chars().count() > PREVIEW_LEN {
+                    preview.push_str("...");
+                }
+                Some(format!("\"{}\"", preview.escape_default()))
+            })
+    }
+
+    /// "name+off" for a VA within a function, else the VA
+    fn describe(&self, va: i32) -> String {
+        let name = |va: i32| {
+            self.workspace
+                .get_name(va, false)
+                .unwrap_or_else(|| auto_name(AutoKind::Function, va))
+        };
+        match (self.workspace.get_function(va), self.style) {
+            (Some(fva), _) if fva == va => name(fva),
+            (Some(fva), Style::Objdump) => format!("<{}+{:#x}>", name(fva), va.wrapping_sub(fva)),
+            (Some(fva), Style::Ida) => {
+                format!(
+                    "{}+{}",
+                    name(fva),
+                    ida_hex(va.wrapping_sub(fva) as u32 as u64)
+                )
+            }
+            (None, Style::Objdump) => format!("{:#x}", va as u32),
+            (None, Style::Ida) => format!("{}:{:08X}", self.segment, va as u32),
+        }
+    }
+}
+
+/// A number as MASM writes it: `5`, `0Ah`, `0CCh`
+fn ida_hex(value: u64) -> String {
+    if value < 10 {
+        return value.to_string();
+    }
+    let hex = format!("{:X}h", value);
+    if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", hex)
+    } else {
+        hex
+    }
+}
+
+pub(crate) fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+pub(crate) fn utf16(bytes: &[u8]) -> String {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(feature = "iced-x86")]
+struct Decoder {
+    bitness: u32,
+    formatter: iced_x86::IntelFormatter,
+}
+
+#[cfg(feature = "iced-x86")]
+impl Decoder {
+    fn new(workspace: &VivWorkspace, style: Style) -> Option<Self> {
+        use crate::constants::{ARCH_AMD64, ARCH_I386, ARCH_I8086, ARCH_MASK};
+        use iced_x86::Formatter;
+
+        let bitness = match (workspace.arch & ARCH_MASK) as i32 {
+            ARCH_I386 => 32,
+            ARCH_AMD64 => 64,
+            ARCH_I8086 => 16,
+            _ => return None,
+        };
+        let mut names = BTreeMap::new();
+        for (va, name) in workspace.get_annotations().names {
+            names.insert(va as u32 as u64, name);
+        }
+        for fva in workspace.get_functions() {
+            names
+                .entry(fva as u32 as u64)
+                .or_insert_with(|| auto_name(AutoKind::Function, fva));
+        }
+        let mut formatter =
+            iced_x86::IntelFormatter::with_options(Some(Box::new(Names(names))), None);
+        let options = formatter.options_mut();
+        match style {
+            Style::Objdump => options.set_first_operand_char_index(7),
+            Style::Ida => {
+                options.set_first_operand_char_index(8);
+                options.set_hex_prefix("");
+                options.set_hex_suffix("h");
+                options.set_uppercase_hex(true);
+            }
+        }
+        options.set_space_after_operand_separator(style == Style::Ida);
+        Some(Decoder { bitness, formatter })
+    }
+
+    /// The text of the instruction which is exactly `bytes`
+    fn decode(&mut self, va: i32, bytes: &[u8]) -> Option<String> {
+        use iced_x86::Formatter;
+
+        let mut decoder = iced_x86::Decoder::with_ip(
+            self.bitness,
+            bytes,
+            va as u32 as u64,
+            iced_x86::DecoderOptions::NONE,
+        );
+        let insn = decoder.decode();
+        if insn.is_invalid() || insn.len() != bytes.len() {
+            return None;
+        }
+        let mut text = String::new();
+        self.formatter.format(&insn, &mut text);
+        Some(text)
+    }
+}
+
+#[cfg(feature = "iced-x86")]
+struct Names(BTreeMap<u64, String>);
+
+#[cfg(feature = "iced-x86")]
+impl iced_x86::SymbolResolver for Names {
+    fn symbol(
+        &mut self,
+        _instruction: &iced_x86::Instruction,
+        _operand: u32,
+        _instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<iced_x86::SymbolResult<'_>> {
+        let name = self.0.get(&address)?;
+        Some(iced_x86::SymbolResult::with_str(address, name))
+    }
+}
+
+/// Without a decoder, instructions are written as their bytes
+#[cfg(not(feature = "iced-x86"))]
+struct Decoder;
+
+#[cfg(not(feature = "iced-x86"))]
+impl Decoder {
+    fn new(_workspace: &VivWorkspace, _style: Style) -> Option<Self> {
+        None
+    }
+
+    fn decode(&mut self, _va: i32, _bytes: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_I386, MM_READ, REF_PTR};
+
+    #[test]
+    fn writes_listings() {
+        let mut ws = VivWorkspace::new("", false);
+        let fname = ws.add_file("Cargo.toml", 0x1000, vec![]);
+        // push 0x2000; call 0x100c; ret; int3; then a function of its own
+        let mut code = vec![
+            0x68, 0x00, 0x20, 0x00, 0x00, 0xe8, 0x02, 0x00, 0x00, 0x00, 0xc3,
+        ];
+        code.extend([0xcc, 0xc3]);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, &fname, code, None);
+        ws.add_segment(0x1000, 13, ".text", fname.clone());
+        let mut data = b"Hello, world\0".to_vec();
+        data.resize(0x20, 0);
+        ws.add_memory_map(0x2000, MM_READ, &fname, data, None);
+        ws.add_segment(0x2000, 0x20, ".rdata", fname.clone());
+        ws.set_pointer_size(4);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        for (va, size) in [(0x1000, 5), (0x1005, 5), (0x100a, 1), (0x100c, 1)] {
+            ws.add_location(va, size, LOC_OP, Some(vec![]));
+        }
+        ws.add_location(0x2000, 13, LOC_STRING, Some(vec![]));
+        ws.add_function(0x1000, vec![(0x1000, 11)]);
+        ws.add_function(0x100c, vec![(0x100c, 1)]);
+        ws.make_name(0x1000, "main".to_string(), false, false);
+        ws.add_xref(0x1000, 0x2000, REF_PTR, 0);
+        ws.add_xref(0x1005, 0x100c, REF_CODE, 0);
+        ws.set_comment(0x1005, "say it", false);
+
+        let mut ida = Vec::new();
+        write_listing(&ws, Style::Ida, &mut ida).unwrap();
+        let ida = String::from_utf8(ida).unwrap();
+        for line in [
+            ".text:00001000 main proc near",
+            ".text:0000100B main endp",
+            ".text:0000100B                 db 0CCh",
+            ".text:0000100C sub_100c proc near",
+            ".text:0000100C                 ; CODE XREF: main+5",
+            ".rdata:00002000                 ; PTR XREF: main",
+            ".rdata:00002000                 db 'Hello, world',0",
+            ".rdata:0000200D                 db 13h dup(0)",
+        ] {
+            assert!(ida.lines().any(|l| l == line), "{}\n{}", line, ida);
+        }
+
+        let mut objdump = Vec::new();
+        write_listing(&ws, Style::Objdump, &mut objdump).unwrap();
+        let objdump = String::from_utf8(objdump).unwrap();
+        assert!(objdump.contains("\nDisassembly of section .rdata:\n"));
+        assert!(objdump.contains("\n00001000 <main>:\n"));
+        #[cfg(feature = "iced-x86")]
+        {
+            assert!(ida.contains("push    2000h ; \"Hello, world\""), "{}", ida);
+            assert!(
+                objdump.contains("\tcall   sub_100c # say it\n"),
+                "{}",
+                objdump
+            );
+        }
+        #[cfg(not(feature = "iced-x86"))]
+        assert!(objdump.contains("    1005:\te8 02 00 00 00      \t # say it\n"));
+    }
+}
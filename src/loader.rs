@@ -0,0 +1,155 @@
+//! Loaders of one's own, for containers the crate doesn't know.
+//!
+//! A [`Loader`] recognizes a format from the first bytes of a file and loads it the way the
+//! built in parsers do: it adds the file, its memory maps and segments, its entry points,
+//! imports and exports, and the `Architecture`, `Platform` and `Format` metadata (see
+//! [`set_load_meta`]). Registered with `VivWorkspace::add_loader`, it is offered every file
+//! `VivWorkspace::load_from_file` loads before the built in formats are tried, so it can take
+//! over a format they would misread, and the files it loads get their content id, symbol
+//! cache and analysis like any other.
+//!
+//! A plugin (see `crate::plugins`) with a load callback is a loader too.
+
+use crate::workspace::VivWorkspace;
+use std::fmt::{Debug, Formatter};
+
+pub use crate::parser::set_load_meta;
+
+pub trait Loader: Send + Sync {
+    /// Whether the loader takes a file starting with `bytes`
+    fn detect(&self, bytes: &[u8]) -> bool;
+
+    /// Load the whole of a file the loader took. Returns the name the file was added to the
+    /// workspace under.
+    fn load(
+        &self,
+        workspace: &mut VivWorkspace,
+        filename: &str,
+        bytes: &[u8],
+        base_addr: Option<i32>,
+    ) -> Result<String, String>;
+
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+impl Debug for dyn Loader + 'static {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loader")
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/// Offer a file to the loaders of the workspace in the order they were added. Returns the name
+/// the file was loaded under by the first loader to take it and load it.
+pub(crate) fn load(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    bytes: &[u8],
+    base_addr: Option<i32>,
+) -> Option<String> {
+    for loader in workspace.get_loaders() {
+        if !loader.detect(bytes) {
+            continue;
+        }
+        match loader.load(workspace, filename, bytes, base_addr) {
+            Ok(fname) => return Some(fname),
+            Err(e) => anomaly!("{} failed to load {}: {}", loader.name(), filename, e),
+        }
+    }
+    None
+}
+
+#[cfg(feature = "plugins")]
+impl Loader for crate::plugins::Plugin {
+    /// A plugin tells whether it takes a file by loading it
+    fn detect(&self, _bytes: &[u8]) -> bool {
+        true
+    }
+
+    fn load(
+        &self,
+        workspace: &mut VivWorkspace,
+        _filename: &str,
+        bytes: &[u8],
+        _base_addr: Option<i32>,
+    ) -> Result<String, String> {
+        let before = workspace.get_files();
+        if !self.load_file(workspace, bytes) {
+            return Err("not taken".to_string());
+        }
+        workspace
+            .get_files()
+            .into_iter()
+            .find(|fname| !before.contains(fname))
+            .ok_or_else(|| "no file was added".to_string())
+    }
+
+    fn name(&self) -> &str {
+        crate::analysis::Analyzer::name(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{constants::ARCH_I386, memory::Memory};
+    use std::sync::Arc;
+
+    /// A game archive: "PAK!", the load address and the code
+    struct Pak;
+
+    impl Loader for Pak {
+        fn detect(&self, bytes: &[u8]) -> bool {
+            bytes.starts_with(b"PAK!")
+        }
+
+        fn load(
+            &self,
+            workspace: &mut VivWorkspace,
+            filename: &str,
+            bytes: &[u8],
+            _base_addr: Option<i32>,
+        ) -> Result<String, String> {
+            let header = bytes.get(4..8).ok_or("truncated")?;
+            let base = i32::from_le_bytes(header.try_into().unwrap());
+            let fname = workspace.add_file(filename, base, vec![]);
+            let code = bytes[8..].to_vec();
+            workspace.add_segment(base, code.len() as i32, "code", fname.clone());
+            workspace.add_memory_map(base, 7, &fname, code, None);
+            workspace.add_entry_point(base);
+            set_load_meta(workspace, ARCH_I386, "pak", "pak", false, false);
+            Ok(fname)
+        }
+    }
+
+    #[test]
+    fn loads_with_custom_loaders() {
+        let path = std::env::temp_dir().join(format!("loader-{}.pak", std::process::id()));
+        let mut bytes = b"PAK!".to_vec();
+        bytes.extend(0x4000i32.to_le_bytes());
+        bytes.extend([0x55, 0x89, 0xe5, 0xc3]);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_loader(Arc::new(Pak));
+        assert_eq!(
+            format!("{:?}", ws.get_loaders()),
+            "[Loader { name: \"Pak\" }]"
+        );
+        let fname = ws.load_from_file(path.to_str().unwrap(), None, None);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ws.get_file_meta(&fname, "imagebase"), 0x4000);
+        assert_eq!(ws.get_meta("Format").as_deref(), Some("pak"));
+        assert_eq!(
+            ws.read_memory(0x4000, 4),
+            Some(vec![0x55, 0x89, 0xe5, 0xc3])
+        );
+        assert_eq!(ws.get_entry_points(), [0x4000]);
+        assert!(ws.get_content_id(&fname).is_some());
+    }
+}
@@ -0,0 +1,386 @@
+//! Where a workspace keeps its locations.
+//!
+//! Locations are the bulk of a workspace: a firmware image or a dyld shared cache runs to tens of
+//! millions of them. The workspace goes through the [`LocationStore`] trait, so the default
+//! [`MemoryLocations`] can be swapped for [`DiskLocations`] (with the `mmap` feature), which
+//! keeps the locations in a sorted index file mapped into memory and only holds the ones added
+//! since the last flush on the heap.
+//!
+//! Stores hand locations out in VA order and hold at most one location per VA; adding a location
+//! at a VA which already has one replaces it.
+
+use crate::persistent::PersistentMap;
+use std::fmt::Debug;
+
+/// A location as the workspace deals with it: `(va, size, type, type info)`
+pub type Location = (i32, i32, i32, Vec<(i32, i32)>);
+
+pub trait LocationStore: Debug + Send + Sync {
+    /// Add a location, replacing any already at its VA
+    fn add(&mut self, loc: Location);
+
+    /// The location starting at or covering `va`
+    fn get(&self, va: i32) -> Option<Location>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every location, in VA order
+    fn iter(&self) -> Box<dyn Iterator<Item = Location> + '_>;
+
+    fn clone_store(&self) -> Box<dyn LocationStore>;
+
+    /// The locations as a map a snapshot can hold on to. A store which doesn't keep them in one
+    /// makes it, which takes a pass over every location.
+    fn persistent(&self) -> PersistentMap<Location> {
+        self.iter().map(|loc| (loc.0, loc)).collect()
+    }
+}
+
+impl Clone for Box<dyn LocationStore> {
+    fn clone(&self) -> Self {
+        self.clone_store()
+    }
+}
+
+fn covers(loc: &Location, va: i32) -> bool {
+    va >= loc.0 && (va as i64) < loc.0 as i64 + loc.1.max(1) as i64
+}
+
+/// Locations kept on the heap, the default. They are kept in a [`PersistentMap`], so copies of
+/// the store and snapshots of it share what they have in common.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryLocations {
+    locations: PersistentMap<Location>,
+}
+
+impl MemoryLocations {
+    pub fn new() -> Self {
+        MemoryLocations::default()
+    }
+}
+
+impl LocationStore for MemoryLocations {
+    fn add(&mut self, loc: Location) {
+        self.locations.insert(loc.0, loc);
+    }
+
+    fn get(&self, va: i32) -> Option<Location> {
+        let (_, loc) = self.locations.last_at_or_before(va)?;
+        covers(loc, va).then(|| loc.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Location> + '_> {
+        Box::new(self.locations.values().cloned())
+    }
+
+    fn clone_store(&self) -> Box<dyn LocationStore> {
+        Box::new(self.clone())
+    }
+
+    fn persistent(&self) -> PersistentMap<Location> {
+        self.locations.clone()
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub use disk::DiskLocations;
+
+#[cfg(feature = "mmap")]
+mod disk {
+    use super::{covers, Location, LocationStore};
+    use memmap2::Mmap;
+    use std::{
+        collections::BTreeMap,
+        fs::{self, OpenOptions},
+        io::{self, BufWriter, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    const MAGIC: &[u8; 8] = b"VIVLOC01";
+    const HEADER_SIZE: usize = 16;
+    // va, size, type, type info start and length (in pairs)
+    const RECORD_SIZE: usize = 20;
+
+    // Pending locations held on the heap before they are merged into the index
+    const DEFAULT_FLUSH_AT: usize = 1 << 20;
+
+    static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+    // A location without its VA, which keys it
+    type Entry = (i32, i32, Vec<(i32, i32)>);
+
+    /// A sorted, memory mapped index file. The file is laid out as a header (magic and record
+    /// count), fixed size records sorted by VA, then the type info pairs the records point into.
+    /// It is removed once nothing maps it any more.
+    #[derive(Debug)]
+    struct Index {
+        path: Option<PathBuf>,
+        map: Option<Mmap>,
+        count: usize,
+    }
+
+    impl Index {
+        fn empty() -> Self {
+            Index {
+                path: None,
+                map: None,
+                count: 0,
+            }
+        }
+
+        fn word(&self, at: usize) -> i32 {
+            let map = self.map.as_ref().expect("Reading an empty location index");
+            i32::from_le_bytes(map[at..at + 4].try_into().unwrap())
+        }
+
+        fn va(&self, idx: usize) -> i32 {
+            self.word(HEADER_SIZE + idx * RECORD_SIZE)
+        }
+
+        fn record(&self, idx: usize) -> Location {
+            let at = HEADER_SIZE + idx * RECORD_SIZE;
+            let start = self.word(at + 12) as u32 as usize;
+            let len = self.word(at + 16) as u32 as usize;
+            let info = HEADER_SIZE + self.count * RECORD_SIZE;
+            let tinfo = (start..start + len)
+                .map(|i| {
+                    let pair = info + i * 8;
+                    (self.word(pair), self.word(pair + 4))
+                })
+                .collect();
+            (self.word(at), self.word(at + 4), self.word(at + 8), tinfo)
+        }
+
+        /// The index of the last record at or before `va`
+        fn floor(&self, va: i32) -> Option<usize> {
+            let (mut lo, mut hi) = (0, self.count);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.va(mid) <= va {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            lo.checked_sub(1)
+        }
+
+        fn contains(&self, va: i32) -> bool {
+            self.floor(va).is_some_and(|idx| self.va(idx) == va)
+        }
+
+        fn iter(&self) -> impl Iterator<Item = Location> + '_ {
+            (0..self.count).map(|idx| self.record(idx))
+        }
+    }
+
+    impl Drop for Index {
+        fn drop(&mut self) {
+            self.map = None;
+            if let Some(path) = self.path.take() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Locations kept in a memory mapped index file under a scratch directory, so the kernel
+    /// pages them in and out instead of the workspace holding them all. New locations are kept
+    /// on the heap until [`DiskLocations::flush`], which happens on its own every
+    /// `flush_at` additions.
+    ///
+    /// The files are scratch space, removed when the store is dropped; saving a workspace goes
+    /// through [`crate::storage`] as usual. Clones share the mapped index.
+    #[derive(Clone, Debug)]
+    pub struct DiskLocations {
+        dir: PathBuf,
+        index: Arc<Index>,
+        pending: BTreeMap<i32, Entry>,
+        // Pending locations with no record in the index yet
+        added: usize,
+        flush_at: usize,
+    }
+
+    impl DiskLocations {
+        pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+            Self::with_flush_at(dir, DEFAULT_FLUSH_AT)
+        }
+
+        pub fn with_flush_at(dir: impl AsRef<Path>, flush_at: usize) -> io::Result<Self> {
+            fs::create_dir_all(dir.as_ref())?;
+            Ok(DiskLocations {
+                dir: dir.as_ref().to_path_buf(),
+                index: Arc::new(Index::empty()),
+                pending: BTreeMap::new(),
+                added: 0,
+                flush_at: flush_at.max(1),
+            })
+        }
+
+        /// Merge the pending locations into a new index file and map it
+        pub fn flush(&mut self) -> io::Result<()> {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+            let count = self.index.count + self.added;
+            let path = self.dir.join(format!(
+                "locations-{}-{}.idx",
+                std::process::id(),
+                NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            let mut out = BufWriter::new(file);
+            out.write_all(MAGIC)?;
+            out.write_all(&(count as u64).to_le_bytes())?;
+            // Records first, keeping count of where each one's type info will go
+            let mut start = 0u32;
+            for (va, size, ltype, tinfo) in self.iter() {
+                for word in [va, size, ltype, start as i32, tinfo.len() as i32] {
+                    out.write_all(&word.to_le_bytes())?;
+                }
+                start += tinfo.len() as u32;
+            }
+            for (_, _, _, tinfo) in self.iter() {
+                for (a, b) in tinfo {
+                    out.write_all(&a.to_le_bytes())?;
+                    out.write_all(&b.to_le_bytes())?;
+                }
+            }
+            let file = out.into_inner().map_err(|e| e.into_error())?;
+            // SAFETY: the file is private to this store and never written once mapped
+            let map = unsafe { Mmap::map(&file)? };
+            self.index = Arc::new(Index {
+                path: Some(path),
+                map: Some(map),
+                count,
+            });
+            self.pending.clear();
+            self.added = 0;
+            Ok(())
+        }
+
+        fn pending_floor(&self, va: i32) -> Option<Location> {
+            self.pending
+                .range(..=va)
+                .next_back()
+                .map(|(lva, (size, ltype, tinfo))| (*lva, *size, *ltype, tinfo.clone()))
+        }
+    }
+
+    impl LocationStore for DiskLocations {
+        fn add(&mut self, (va, size, ltype, tinfo): Location) {
+            let new = self.pending.insert(va, (size, ltype, tinfo)).is_none();
+            if new && !self.index.contains(va) {
+                self.added += 1;
+            }
+            if self.pending.len() >= self.flush_at {
+                if let Err(e) = self.flush() {
+                    // Keep going on the heap rather than losing locations
+                    log::warn!("Failed to flush locations to {:?}: {}", self.dir, e);
+                }
+            }
+        }
+
+        fn get(&self, va: i32) -> Option<Location> {
+            let base = self.index.floor(va).map(|idx| self.index.record(idx));
+            let loc = match (base, self.pending_floor(va)) {
+                (Some(base), Some(pending)) if base.0 > pending.0 => base,
+                (_, Some(pending)) => pending,
+                (base, None) => base?,
+            };
+            covers(&loc, va).then_some(loc)
+        }
+
+        fn len(&self) -> usize {
+            self.index.count + self.added
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Location> + '_> {
+            let mut base = self.index.iter().peekable();
+            let mut pending = self
+                .pending
+                .iter()
+                .map(|(va, (size, ltype, tinfo))| (*va, *size, *ltype, tinfo.clone()))
+                .peekable();
+            Box::new(std::iter::from_fn(move || {
+                match (base.peek(), pending.peek()) {
+                    (Some(b), Some(p)) if b.0 < p.0 => base.next(),
+                    (Some(b), Some(p)) => {
+                        // A pending location replaces the indexed one at its VA
+                        if b.0 == p.0 {
+                            base.next();
+                        }
+                        pending.next()
+                    }
+                    (Some(_), None) => base.next(),
+                    (None, _) => pending.next(),
+                }
+            }))
+        }
+
+        fn clone_store(&self) -> Box<dyn LocationStore> {
+            Box::new(self.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(store: &mut dyn LocationStore) {
+        for va in (0..1000).rev() {
+            store.add((va * 4, 4, 1, vec![]));
+        }
+        store.add((0x2000, 0x10, 2, vec![(0x2000, 8), (0x2008, 8)]));
+        // replaces the location already there
+        store.add((8, 2, 3, vec![(8, 2)]));
+        assert_eq!(store.len(), 1001);
+        assert_eq!(store.get(9), Some((8, 2, 3, vec![(8, 2)])));
+        assert_eq!(store.get(10), None);
+        assert_eq!(store.get(0x200f).map(|loc| loc.0), Some(0x2000));
+        assert_eq!(store.get(0x2010), None);
+        assert_eq!(store.get(-1), None);
+        let vas = store.iter().map(|loc| loc.0).collect::<Vec<_>>();
+        assert_eq!(vas.len(), 1001);
+        assert!(vas.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn memory_locations() {
+        exercise(&mut MemoryLocations::new());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn disk_locations() {
+        let dir = std::env::temp_dir().join(format!("viv-locations-{}", std::process::id()));
+        let mut store = DiskLocations::with_flush_at(&dir, 64).unwrap();
+        exercise(&mut store);
+        let copy = store.clone_store();
+        store.flush().unwrap();
+        assert_eq!(
+            copy.iter().collect::<Vec<_>>(),
+            store.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(store.get(9), Some((8, 2, 3, vec![(8, 2)])));
+        drop((store, copy));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
@@ -102,6 +102,8 @@ pub const S_THREAD_LOCAL_VARIABLES: u32 = 0x13;
 pub const S_THREAD_LOCAL_VARIABLE_POINTERS: u32 = 0x14;
 /// functions to call to initialize TLV values
 pub const S_THREAD_LOCAL_INIT_FUNCTION_POINTERS: u32 = 0x15;
+/// 32-bit offsets from the image base to initializers
+pub const S_INIT_FUNC_OFFSETS: u32 = 0x16;
 
 // Constants for the section attributes part of the flags field of a section
 // structure.
@@ -0,0 +1,604 @@
+//! Human readable listings of the dyld info opcode streams (rebase, bind, weak bind and lazy bind) and of the export trie, similar to `dyldinfo -opcodes`
+//!
+//! Every opcode is decoded together with its immediates and the state of the location being
+//! bound or rebased after it has executed, so a listing can be used to debug malformed streams as
+//! well as to see how a symbol ended up at a given address.
+
+use crate::{
+    container, error,
+    mach::{
+        bind_opcodes,
+        limits::{Budget, Limits},
+        load_command, rebase_opcodes,
+    },
+};
+use alloc::{collections::BTreeSet, format, string::String, vec::Vec};
+use core::{fmt, ops::Range};
+use scroll::{Pread, Sleb128};
+
+/// An operand of a dyld info opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand<'a> {
+    /// An immediate encoded in the low nibble of the opcode
+    Imm(u8),
+    /// An address or offset
+    Addr(u64),
+    /// A count
+    Count(u64),
+    /// A signed value, e.g. an addend
+    Signed(i64),
+    /// A symbol name
+    Str(&'a str),
+}
+
+impl<'a> fmt::Display for Operand<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Imm(imm) => write!(fmt, "{}", imm),
+            Operand::Addr(addr) => write!(fmt, "{:#010x}", addr),
+            Operand::Count(count) => write!(fmt, "{}", count),
+            Operand::Signed(value) => write!(fmt, "{}", value),
+            Operand::Str(s) => write!(fmt, "{}", s),
+        }
+    }
+}
+
+/// The state of the dyld opcode interpreter after an opcode has executed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpcodeState<'a> {
+    pub seg_index: u8,
+    pub seg_offset: u64,
+    /// The bind or rebase type
+    pub kind: u8,
+    /// The library ordinal; the special dylib values are negative
+    pub library_ordinal: i64,
+    pub symbol_name: &'a str,
+    pub symbol_flags: u8,
+    pub addend: i64,
+}
+
+/// A single decoded opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeLine<'a> {
+    /// The offset of the opcode relative to the start of its stream
+    pub offset: usize,
+    /// The raw opcode byte, including the immediate
+    pub opcode: u8,
+    pub name: &'static str,
+    pub operands: Vec<Operand<'a>>,
+    /// The number of locations this opcode bound or rebased
+    pub count: u64,
+    /// The interpreter state after this opcode executed
+    pub state: OpcodeState<'a>,
+}
+
+impl<'a> fmt::Display for OpcodeLine<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:#06x} {}(", self.offset, self.name)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            if i > 0 {
+                write!(fmt, ", ")?;
+            }
+            write!(fmt, "{}", operand)?;
+        }
+        write!(fmt, ")")?;
+        if self.count > 0 {
+            write!(
+                fmt,
+                "\t; seg {} offset {:#x}",
+                self.state.seg_index, self.state.seg_offset
+            )?;
+            if !self.state.symbol_name.is_empty() {
+                write!(
+                    fmt,
+                    " {} (ordinal {})",
+                    self.state.symbol_name, self.state.library_ordinal
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode the rebase opcode stream found at `location` in `bytes`, under `limits`
+pub fn rebase_opcodes(
+    bytes: &[u8],
+    location: Range<usize>,
+    ctx: container::Ctx,
+    limits: &Limits,
+) -> error::Result<Vec<OpcodeLine<'static>>> {
+    use self::rebase_opcodes::*;
+    let size = ctx.size() as u64;
+    let mut state = OpcodeState::default();
+    let mut lines = Vec::new();
+    let mut opcodes = Budget::new(limits.max_iterations, "rebase opcodes");
+    let mut offset = location.start;
+    while offset < location.end {
+        opcodes.take(1)?;
+        let start = offset - location.start;
+        let opcode = bytes.gread::<u8>(&mut offset)?;
+        let imm = opcode & REBASE_IMMEDIATE_MASK;
+        let mut operands = Vec::new();
+        let mut count = 0;
+        // the location which was rebased is reported, not the advanced one
+        let mut rebased = state;
+        match opcode & REBASE_OPCODE_MASK {
+            REBASE_OPCODE_DONE => {}
+            REBASE_OPCODE_SET_TYPE_IMM => {
+                state.kind = imm;
+                operands.push(Operand::Imm(imm));
+            }
+            REBASE_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB => {
+                let seg_offset = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_index = imm;
+                state.seg_offset = seg_offset;
+                operands.push(Operand::Imm(imm));
+                operands.push(Operand::Addr(seg_offset));
+            }
+            REBASE_OPCODE_ADD_ADDR_ULEB => {
+                let addr = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_offset = state.seg_offset.wrapping_add(addr);
+                operands.push(Operand::Addr(addr));
+            }
+            REBASE_OPCODE_ADD_ADDR_IMM_SCALED => {
+                state.seg_offset = state.seg_offset.wrapping_add(u64::from(imm) * size);
+                operands.push(Operand::Imm(imm));
+            }
+            REBASE_OPCODE_DO_REBASE_IMM_TIMES => {
+                count = u64::from(imm);
+                state.seg_offset = state.seg_offset.wrapping_add(count.wrapping_mul(size));
+                operands.push(Operand::Imm(imm));
+            }
+            REBASE_OPCODE_DO_REBASE_ULEB_TIMES => {
+                count = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_offset = state.seg_offset.wrapping_add(count.wrapping_mul(size));
+                operands.push(Operand::Count(count));
+            }
+            REBASE_OPCODE_DO_REBASE_ADD_ADDR_ULEB => {
+                let addr = limits.read_uleb(bytes, &mut offset)?;
+                count = 1;
+                state.seg_offset = state.seg_offset.wrapping_add(addr).wrapping_add(size);
+                operands.push(Operand::Addr(addr));
+            }
+            REBASE_OPCODE_DO_REBASE_ULEB_TIMES_SKIPPING_ULEB => {
+                count = limits.read_uleb(bytes, &mut offset)?;
+                let skip = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_offset = state
+                    .seg_offset
+                    .wrapping_add(count.wrapping_mul(skip.wrapping_add(size)));
+                operands.push(Operand::Count(count));
+                operands.push(Operand::Addr(skip));
+            }
+            _ => {}
+        }
+        if count == 0 {
+            rebased = state;
+        }
+        lines.push(OpcodeLine {
+            offset: start,
+            opcode,
+            name: rebase_opcodes::opcode_to_str(opcode & REBASE_OPCODE_MASK),
+            operands,
+            count,
+            state: rebased,
+        });
+    }
+    Ok(lines)
+}
+
+/// Decode the (weak or lazy) bind opcode stream found at `location` in `bytes`, under `limits`;
+/// lazy streams reset the interpreter state on every `BIND_OPCODE_DONE`
+pub fn bind_opcodes<'a>(
+    bytes: &'a [u8],
+    location: Range<usize>,
+    is_lazy: bool,
+    ctx: container::Ctx,
+    limits: &Limits,
+) -> error::Result<Vec<OpcodeLine<'a>>> {
+    use self::bind_opcodes::*;
+    let size = ctx.size() as u64;
+    let fresh = || OpcodeState {
+        kind: if is_lazy { BIND_TYPE_POINTER } else { 0 },
+        ..Default::default()
+    };
+    let mut state = fresh();
+    let mut lines = Vec::new();
+    let mut opcodes = Budget::new(limits.max_iterations, "bind opcodes");
+    let mut offset = location.start;
+    while offset < location.end {
+        opcodes.take(1)?;
+        let start = offset - location.start;
+        let opcode = bytes.gread::<u8>(&mut offset)?;
+        let imm = opcode & BIND_IMMEDIATE_MASK;
+        let mut operands = Vec::new();
+        let mut count = 0;
+        let bound = state;
+        match opcode & BIND_OPCODE_MASK {
+            BIND_OPCODE_DONE if is_lazy => {
+                state = fresh();
+            }
+            BIND_OPCODE_SET_DYLIB_ORDINAL_IMM => {
+                state.library_ordinal = i64::from(imm);
+                operands.push(Operand::Imm(imm));
+            }
+            BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB => {
+                let ordinal = limits.read_uleb(bytes, &mut offset)?;
+                state.library_ordinal = ordinal as i64;
+                operands.push(Operand::Count(ordinal));
+            }
+            BIND_OPCODE_SET_DYLIB_SPECIAL_IMM => {
+                // sign extend the nibble, so 0xf is -1 (main executable), 0xe is -2 (flat lookup)
+                state.library_ordinal = if imm == 0 {
+                    0
+                } else {
+                    i64::from((BIND_OPCODE_MASK | imm) as i8)
+                };
+                operands.push(Operand::Signed(state.library_ordinal));
+            }
+            BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM => {
+                let symbol_name = bytes.pread::<&str>(offset)?;
+                offset += symbol_name.len() + 1;
+                state.symbol_name = symbol_name;
+                state.symbol_flags = imm;
+                operands.push(Operand::Imm(imm));
+                operands.push(Operand::Str(symbol_name));
+            }
+            BIND_OPCODE_SET_TYPE_IMM => {
+                state.kind = imm;
+                operands.push(Operand::Imm(imm));
+            }
+            BIND_OPCODE_SET_ADDEND_SLEB => {
+                let addend = Sleb128::read(bytes, &mut offset)?;
+                state.addend = addend;
+                operands.push(Operand::Signed(addend));
+            }
+            BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB => {
+                let seg_offset = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_index = imm;
+                state.seg_offset = seg_offset;
+                operands.push(Operand::Imm(imm));
+                operands.push(Operand::Addr(seg_offset));
+            }
+            BIND_OPCODE_ADD_ADDR_ULEB => {
+                let addr = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_offset = state.seg_offset.wrapping_add(addr);
+                operands.push(Operand::Addr(addr));
+            }
+            BIND_OPCODE_DO_BIND => {
+                count = 1;
+                state.seg_offset = state.seg_offset.wrapping_add(size);
+            }
+            BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB => {
+                let addr = limits.read_uleb(bytes, &mut offset)?;
+                count = 1;
+                state.seg_offset = state.seg_offset.wrapping_add(addr).wrapping_add(size);
+                operands.push(Operand::Addr(addr));
+            }
+            BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED => {
+                count = 1;
+                state.seg_offset = state
+                    .seg_offset
+                    .wrapping_add(u64::from(imm) * size)
+                    .wrapping_add(size);
+                operands.push(Operand::Imm(imm));
+            }
+            BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB => {
+                count = limits.read_uleb(bytes, &mut offset)?;
+                let skip = limits.read_uleb(bytes, &mut offset)?;
+                state.seg_offset = state
+                    .seg_offset
+                    .wrapping_add(count.wrapping_mul(skip.wrapping_add(size)));
+                operands.push(Operand::Count(count));
+                operands.push(Operand::Addr(skip));
+            }
+            _ => {}
+        }
+        lines.push(OpcodeLine {
+            offset: start,
+            opcode,
+            name: bind_opcodes::opcode_to_str(opcode & BIND_OPCODE_MASK),
+            operands,
+            count,
+            state: if count > 0 { bound } else { state },
+        });
+    }
+    Ok(lines)
+}
+
+/// A node of the export trie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportNode<'a> {
+    /// The offset of the node relative to the start of the trie
+    pub offset: usize,
+    /// How many edges lead from the root to this node
+    pub depth: usize,
+    /// The edge leading to this node, empty for the root
+    pub edge: &'a str,
+    /// The symbol spelled by the edges leading to this node, if it is a terminal one
+    pub name: Option<String>,
+    /// The export flags if this is a terminal node
+    pub flags: Option<u64>,
+    /// The rest of the terminal payload: the address, the reexport ordinal and name, or the stub and resolver offsets
+    pub info: Vec<Operand<'a>>,
+    /// The outgoing edges, with the offset of the child node relative to the start of the trie
+    pub edges: Vec<(&'a str, usize)>,
+}
+
+impl<'a> fmt::Display for ExportNode<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:#06x} {:indent$}\"{}\"",
+            self.offset,
+            "",
+            self.edge,
+            indent = self.depth
+        )?;
+        if let (Some(name), Some(flags)) = (&self.name, self.flags) {
+            write!(fmt, " {} flags={:#x}", name, flags)?;
+            for operand in self.info.iter() {
+                write!(fmt, " {}", operand)?;
+            }
+        }
+        for (edge, child) in self.edges.iter() {
+            write!(fmt, "\n\t\"{}\" -> {:#06x}", edge, child)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode every node of the export trie found at `location` in `bytes` under `limits`, in trie
+/// order
+pub fn export_trie_nodes<'a>(
+    bytes: &'a [u8],
+    location: Range<usize>,
+    limits: &Limits,
+) -> error::Result<Vec<ExportNode<'a>>> {
+    use crate::mach::exports::{
+        EXPORT_SYMBOL_FLAGS_REEXPORT, EXPORT_SYMBOL_FLAGS_STUB_AND_RESOLVER,
+    };
+    let mut nodes = Vec::new();
+    if location.start >= location.end {
+        return Ok(nodes);
+    }
+    let mut budget = Budget::new(limits.max_iterations, "export trie nodes");
+    let mut seen = BTreeSet::new();
+    // the symbol of the node being decoded; the pending nodes hold how much of it is their
+    // parent's, which the walk in trie order leaves in place for them
+    let mut symbol = String::new();
+    let mut pending = vec![(location.start, 0, "", 0)];
+    while let Some((start, depth, edge, parent_len)) = pending.pop() {
+        // a malformed trie may point back into itself
+        if start >= location.end || !seen.insert(start) {
+            continue;
+        }
+        budget.take(1)?;
+        if depth > limits.max_depth {
+            return Err(error::Error::Malformed(format!(
+                "Export trie nests deeper than {} at {:#x}",
+                limits.max_depth, start
+            )));
+        }
+        symbol.truncate(parent_len);
+        symbol.push_str(edge);
+        let mut offset = start;
+        let terminal_size = limits.read_uleb(bytes, &mut offset)? as usize;
+        let mut flags = None;
+        let mut info = Vec::new();
+        if terminal_size != 0 {
+            let mut payload = offset;
+            let node_flags = limits.read_uleb(bytes, &mut payload)?;
+            if node_flags & EXPORT_SYMBOL_FLAGS_REEXPORT != 0 {
+                info.push(Operand::Count(limits.read_uleb(bytes, &mut payload)?));
+                info.push(Operand::Str(bytes.pread::<&str>(payload)?));
+            } else if node_flags & EXPORT_SYMBOL_FLAGS_STUB_AND_RESOLVER != 0 {
+                info.push(Operand::Addr(limits.read_uleb(bytes, &mut payload)?));
+                info.push(Operand::Addr(limits.read_uleb(bytes, &mut payload)?));
+            } else {
+                info.push(Operand::Addr(limits.read_uleb(bytes, &mut payload)?));
+            }
+            flags = Some(node_flags);
+            offset = offset.saturating_add(terminal_size);
+        }
+        let nchildren = limits.read_uleb(bytes, &mut offset)? as usize;
+        if nchildren > bytes.len() {
+            return Err(error::Error::BufferTooShort(nchildren, "branches"));
+        }
+        budget.take(nchildren as u64)?;
+        let mut edges = Vec::with_capacity(nchildren);
+        for _ in 0..nchildren {
+            let edge = bytes.pread::<&str>(offset)?;
+            offset += edge.len() + 1;
+            let child = limits.read_uleb(bytes, &mut offset)? as usize;
+            edges.push((edge, child));
+        }
+        // walk the children in order
+        for (edge, child) in edges.iter().rev() {
+            pending.push((
+                location.start.saturating_add(*child),
+                depth + 1,
+                *edge,
+                symbol.len(),
+            ));
+        }
+        nodes.push(ExportNode {
+            offset: start - location.start,
+            depth,
+            edge,
+            name: flags.map(|_| symbol.clone()),
+            flags,
+            info,
+            edges,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Render every opcode stream and the export trie described by the `LC_DYLD_INFO` `command` as
+/// one listing, decoding them under `limits`
+pub fn listing(
+    bytes: &[u8],
+    command: &load_command::DyldInfoCommand,
+    ctx: container::Ctx,
+    limits: &Limits,
+) -> error::Result<String> {
+    use core::fmt::Write;
+    let range = |off: u32, size: u32| {
+        let start = off as usize;
+        start..start.saturating_add(size as usize)
+    };
+    let mut out = String::new();
+    let streams = [
+        (
+            "binding opcodes:",
+            command.bind_off,
+            command.bind_size,
+            false,
+        ),
+        (
+            "weak binding opcodes:",
+            command.weak_bind_off,
+            command.weak_bind_size,
+            false,
+        ),
+        (
+            "lazy binding opcodes:",
+            command.lazy_bind_off,
+            command.lazy_bind_size,
+            true,
+        ),
+    ];
+    if command.rebase_size != 0 {
+        writeln!(out, "rebase opcodes:").unwrap();
+        for line in rebase_opcodes(
+            bytes,
+            range(command.rebase_off, command.rebase_size),
+            ctx,
+            limits,
+        )? {
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    for (title, off, size, is_lazy) in streams.iter() {
+        if *size == 0 {
+            continue;
+        }
+        writeln!(out, "{}", title).unwrap();
+        for line in bind_opcodes(bytes, range(*off, *size), *is_lazy, ctx, limits)? {
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    if command.export_size != 0 {
+        writeln!(out, "export trie:").unwrap();
+        for node in export_trie_nodes(
+            bytes,
+            range(command.export_off, command.export_size),
+            limits,
+        )? {
+            writeln!(out, "{}", node).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::Endian;
+
+    const CTX: container::Ctx = container::Ctx {
+        container: container::Container::Big,
+        le: Endian::Little,
+    };
+
+    #[test]
+    fn bind_listing() {
+        // ordinal 1, _printf, pointer, segment 2 offset 0x10, bind, done
+        let stream = [
+            0x11, 0x40, b'_', b'p', b'r', b'i', b'n', b't', b'f', 0x00, 0x51, 0x72, 0x10, 0x90,
+            0x00,
+        ];
+        let lines = bind_opcodes(&stream, 0..stream.len(), false, CTX, &Limits::default()).unwrap();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[1].to_string(),
+            "0x0001 BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM(0, _printf)"
+        );
+        assert_eq!(
+            lines[3].operands,
+            vec![Operand::Imm(2), Operand::Addr(0x10)]
+        );
+        let bind = &lines[4];
+        assert_eq!(bind.count, 1);
+        assert_eq!(bind.state.seg_offset, 0x10);
+        assert_eq!(bind.state.symbol_name, "_printf");
+        assert_eq!(bind.state.library_ordinal, 1);
+    }
+
+    #[test]
+    fn rebase_listing() {
+        // pointer, segment 1 offset 0x20, rebase 3 times, done
+        let stream = [0x11, 0x21, 0x20, 0x53, 0x00];
+        let lines = rebase_opcodes(&stream, 0..stream.len(), CTX, &Limits::default()).unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2].name, "REBASE_OPCODE_DO_REBASE_IMM_TIMES");
+        assert_eq!(lines[2].count, 3);
+        assert_eq!(lines[2].state.seg_offset, 0x20);
+        assert_eq!(lines[3].state.seg_offset, 0x38);
+    }
+
+    #[test]
+    fn export_trie_listing() {
+        const EXPORTS: [u8; 64] = [
+            0x00, 0x01, 0x5f, 0x00, 0x05, 0x00, 0x02, 0x5f, 0x6d, 0x68, 0x5f, 0x65, 0x78, 0x65,
+            0x63, 0x75, 0x74, 0x65, 0x5f, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x00, 0x1f, 0x6d,
+            0x61, 0x00, 0x23, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x78, 0x69, 0x6d, 0x75, 0x6d,
+            0x00, 0x30, 0x69, 0x6e, 0x00, 0x35, 0x03, 0x00, 0xc0, 0x1e, 0x00, 0x03, 0x00, 0xd0,
+            0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let nodes = export_trie_nodes(&EXPORTS, 0..EXPORTS.len(), &Limits::default()).unwrap();
+        let terminals = nodes
+            .iter()
+            .filter_map(|node| node.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(terminals, vec!["__mh_execute_header", "_maximum", "_main"]);
+        let maximum = nodes.iter().find(|node| node.edge == "ximum").unwrap();
+        assert_eq!(
+            (maximum.depth, maximum.name.as_deref()),
+            (3, Some("_maximum"))
+        );
+    }
+
+    #[test]
+    fn hostile_listings() {
+        // a chain of nodes, each the only child of the one before
+        let mut trie = Vec::new();
+        for i in 0..20u8 {
+            trie.extend([0x00, 0x01, b'a', 0x00, (i + 1) * 5]);
+        }
+        trie.extend([0x00, 0x00]);
+        let limits = Limits {
+            max_depth: 16,
+            ..Limits::default()
+        };
+        assert!(export_trie_nodes(&trie, 0..trie.len(), &Limits::default()).is_ok());
+        assert!(export_trie_nodes(&trie, 0..trie.len(), &limits).is_err());
+        let limits = Limits {
+            max_iterations: 16,
+            ..Limits::default()
+        };
+        assert!(export_trie_nodes(&trie, 0..trie.len(), &limits).is_err());
+        // set type, over and over
+        let stream = [0x11; 32];
+        assert!(rebase_opcodes(&stream, 0..stream.len(), CTX, &limits).is_err());
+        assert!(bind_opcodes(&stream, 0..stream.len(), false, CTX, &limits).is_err());
+        // add an address with an overlong ULEB128
+        let mut stream = vec![0x30];
+        stream.extend([0x80; 11]);
+        stream.push(0x01);
+        let limits = Limits::default();
+        assert!(rebase_opcodes(&stream, 0..stream.len(), CTX, &limits).is_err());
+        stream[0] = 0x80;
+        assert!(bind_opcodes(&stream, 0..stream.len(), false, CTX, &limits).is_err());
+    }
+}
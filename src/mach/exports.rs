@@ -6,8 +6,14 @@
 // (1) Weak of regular_symbol_info type probably needs to be added ?
 // (3) /usr/lib/libstdc++.6.0.9.dylib has flag 0xc at many offsets... they're weak
 
-use crate::{error, mach::load_command};
-use alloc::{string::String, vec::Vec};
+use crate::{
+    error,
+    mach::{
+        limits::{Budget, Limits},
+        load_command,
+    },
+};
+use alloc::{format, string::String, vec::Vec};
 use core::{
     fmt::{self, Debug},
     ops::Range,
@@ -179,6 +185,12 @@ pub struct ExportTrie<'a> {
     location: Range<usize>,
 }
 
+/// The limits a walk of the trie runs under, and what is left of them
+struct Walk<'l> {
+    limits: &'l Limits,
+    nodes: Budget,
+}
+
 impl<'a> ExportTrie<'a> {
     #[inline]
     fn walk_nodes(
@@ -186,9 +198,11 @@ impl<'a> ExportTrie<'a> {
         libs: &[&'a str],
         branches: Vec<(String, usize)>,
         acc: &mut Vec<Export<'a>>,
+        walk: &mut Walk,
+        depth: usize,
     ) -> error::Result<()> {
         for (symbol, next_node) in branches {
-            self.walk_trie(libs, symbol, next_node, acc)?;
+            self.walk_trie(libs, symbol, next_node, acc, walk, depth + 1)?;
         }
         Ok(())
     }
@@ -199,10 +213,12 @@ impl<'a> ExportTrie<'a> {
         nbranches: usize,
         current_symbol: String,
         mut offset: usize,
+        walk: &mut Walk,
     ) -> error::Result<Vec<(String, usize)>> {
         if nbranches > self.data.len() {
             return Err(error::Error::BufferTooShort(nbranches, "branches"));
         }
+        walk.nodes.take(nbranches as u64)?;
         let mut branches = Vec::with_capacity(nbranches);
         //println!("\t@{:#x}", *offset);
         for _i in 0..nbranches {
@@ -215,7 +231,8 @@ impl<'a> ExportTrie<'a> {
             *offset = *offset + string.len() + 1;
             //println!("\t({}) string_len: {} offset: {:#x}", i, string.len(), *offset);
             // value is relative to export trie base
-            let next_node = Uleb128::read(self.data, offset)? as usize + self.location.start;
+            let next_node = (walk.limits.read_uleb(self.data, offset)? as usize)
+                .wrapping_add(self.location.start);
             //println!("\t({}) string: {} next_node: {:#x}", _i, key, next_node);
             branches.push((key, next_node));
         }
@@ -228,28 +245,43 @@ impl<'a> ExportTrie<'a> {
         current_symbol: String,
         start: usize,
         exports: &mut Vec<Export<'a>>,
+        walk: &mut Walk,
+        depth: usize,
     ) -> error::Result<()> {
+        if depth > walk.limits.max_depth {
+            return Err(error::Error::Malformed(format!(
+                "Export trie nests deeper than {} at {:#x}",
+                walk.limits.max_depth, start
+            )));
+        }
         if start < self.location.end {
+            walk.nodes.take(1)?;
             let mut offset = start;
-            let terminal_size = Uleb128::read(self.data, &mut offset)?;
+            let terminal_size = walk.limits.read_uleb(self.data, &mut offset)?;
             // let mut input = String::new();
             // ::std::io::stdin().read_line(&mut input).unwrap();
             // println!("@ {:#x} node: {:#x} current_symbol: {}", start, terminal_size, current_symbol);
             if terminal_size == 0 {
-                let nbranches = Uleb128::read(self.data, &mut offset)? as usize;
+                let nbranches = walk.limits.read_uleb(self.data, &mut offset)? as usize;
                 //println!("\t@ {:#x} BRAN {}", *offset, nbranches);
-                let branches = self.walk_branches(nbranches, current_symbol, offset)?;
-                self.walk_nodes(libs, branches, exports)
+                let branches = self.walk_branches(nbranches, current_symbol, offset, walk)?;
+                self.walk_nodes(libs, branches, exports, walk, depth)
             } else {
                 // terminal node, but the tricky part is that they can have children...
                 let pos = offset;
-                let children_start = &mut (pos + terminal_size as usize);
-                let nchildren = Uleb128::read(self.data, children_start)? as usize;
-                let flags = Uleb128::read(self.data, &mut offset)?;
+                let children_start = &mut pos.wrapping_add(terminal_size as usize);
+                let nchildren = walk.limits.read_uleb(self.data, children_start)? as usize;
+                let flags = walk.limits.read_uleb(self.data, &mut offset)?;
                 //println!("\t@ {:#x} TERM {} flags: {:#x}", offset, nchildren, flags);
                 let info = ExportInfo::parse(self.data, libs, flags, offset)?;
                 let export = Export::new(current_symbol.clone(), info);
                 //println!("\t{:?}", &export);
+                if exports.len() >= walk.limits.max_exports {
+                    return Err(error::Error::Malformed(format!(
+                        "More than {} exports in the export trie",
+                        walk.limits.max_exports
+                    )));
+                }
                 exports.push(export);
                 if nchildren == 0 {
                     // this branch is done
@@ -257,8 +289,8 @@ impl<'a> ExportTrie<'a> {
                 } else {
                     // more branches to walk
                     let branches =
-                        self.walk_branches(nchildren, current_symbol, *children_start)?;
-                    self.walk_nodes(libs, branches, exports)
+                        self.walk_branches(nchildren, current_symbol, *children_start, walk)?;
+                    self.walk_nodes(libs, branches, exports, walk, depth)
                 }
             }
         } else {
@@ -287,10 +319,23 @@ impl<'a> ExportTrie<'a> {
 
     /// Walk the export trie for symbols exported by this binary, using the provided `libs` to resolve re-exports
     pub fn exports(&self, libs: &[&'a str]) -> error::Result<Vec<Export<'a>>> {
+        self.exports_with_limits(libs, &Limits::default())
+    }
+
+    /// Walk the export trie like [`ExportTrie::exports`], failing if it goes over `limits`
+    pub fn exports_with_limits(
+        &self,
+        libs: &[&'a str],
+        limits: &Limits,
+    ) -> error::Result<Vec<Export<'a>>> {
         let offset = self.location.start;
         let current_symbol = String::new();
         let mut exports = Vec::new();
-        self.walk_trie(libs, current_symbol, offset, &mut exports)?;
+        let mut walk = Walk {
+            limits,
+            nodes: Budget::new(limits.max_iterations, "export trie nodes"),
+        };
+        self.walk_trie(libs, current_symbol, offset, &mut exports, &mut walk, 0)?;
         Ok(exports)
     }
 
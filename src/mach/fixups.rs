@@ -0,0 +1,483 @@
+//! Chained fixups (`LC_DYLD_CHAINED_FIXUPS`) and arm64e pointer authentication
+//!
+//! Binaries built for macOS 12 and iOS 15 onwards have no rebase and bind opcodes. Instead,
+//! every pointer the loader fixes up holds an encoded fixup in place of its value: the target
+//! (or the import it binds to) and the distance to the next fixup of its page. On arm64e a
+//! fixup may also ask for the pointer to be signed, with one of four keys and a 16 bit
+//! diversity, optionally blended with the address of the pointer. Read raw, such a pointer is a
+//! wild address; [`ChainedPointer::decode`] takes it apart.
+
+use crate::{
+    error,
+    mach::{
+        imports::Dylib,
+        limits::{Budget, Limits},
+        load_command::LinkeditDataCommand,
+        segment::Segment,
+    },
+};
+use alloc::{format, vec::Vec};
+use core::fmt;
+use scroll::Pread;
+
+/// Stride 8, rebase targets are addresses
+pub const DYLD_CHAINED_PTR_ARM64E: u16 = 1;
+/// Stride 4, rebase targets are addresses
+pub const DYLD_CHAINED_PTR_64: u16 = 2;
+pub const DYLD_CHAINED_PTR_32: u16 = 3;
+pub const DYLD_CHAINED_PTR_32_CACHE: u16 = 4;
+pub const DYLD_CHAINED_PTR_32_FIRMWARE: u16 = 5;
+/// Stride 4, rebase targets are offsets from the image base
+pub const DYLD_CHAINED_PTR_64_OFFSET: u16 = 6;
+/// Stride 4, rebase targets are offsets from the image base
+pub const DYLD_CHAINED_PTR_ARM64E_KERNEL: u16 = 7;
+pub const DYLD_CHAINED_PTR_64_KERNEL_CACHE: u16 = 8;
+/// Stride 8, rebase targets are offsets from the image base
+pub const DYLD_CHAINED_PTR_ARM64E_USERLAND: u16 = 9;
+pub const DYLD_CHAINED_PTR_ARM64E_FIRMWARE: u16 = 10;
+pub const DYLD_CHAINED_PTR_X86_64_KERNEL_CACHE: u16 = 11;
+/// Like `DYLD_CHAINED_PTR_ARM64E_USERLAND`, with 24 bit import ordinals
+pub const DYLD_CHAINED_PTR_ARM64E_USERLAND24: u16 = 12;
+
+/// A page without fixups
+pub const DYLD_CHAINED_PTR_START_NONE: u16 = 0xffff;
+/// A page with several chains, which only 32-bit formats use
+pub const DYLD_CHAINED_PTR_START_MULTI: u16 = 0x8000;
+
+pub const DYLD_CHAINED_IMPORT: u32 = 1;
+pub const DYLD_CHAINED_IMPORT_ADDEND: u32 = 2;
+pub const DYLD_CHAINED_IMPORT_ADDEND64: u32 = 3;
+
+/// The key a pointer is signed with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PacKey {
+    /// Instruction key A
+    IA,
+    /// Instruction key B
+    IB,
+    /// Data key A
+    DA,
+    /// Data key B
+    DB,
+}
+
+impl PacKey {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 3 {
+            0 => PacKey::IA,
+            1 => PacKey::IB,
+            2 => PacKey::DA,
+            _ => PacKey::DB,
+        }
+    }
+}
+
+impl fmt::Display for PacKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, fmt)
+    }
+}
+
+/// How the loader signs a pointer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PointerAuth {
+    pub key: PacKey,
+    /// The constant discriminator
+    pub diversity: u16,
+    /// Whether the address of the pointer is blended into the discriminator
+    pub addr_div: bool,
+}
+
+impl fmt::Display for PointerAuth {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "key {}, diversity {:#x}", self.key, self.diversity)?;
+        if self.addr_div {
+            fmt.write_str(", address diversified")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fixup {
+    /// A pointer into the image. `high8` is the top byte of the pointer, which the target
+    /// doesn't hold.
+    Rebase { target: u64, high8: u8 },
+    /// A pointer to an import, an index into [`ChainedFixups::imports`]
+    Bind { ordinal: u32, addend: i64 },
+    /// A plain value in a 32 bit chain, which isn't slid
+    Value { value: u32 },
+}
+
+/// A decoded chained fixup
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChainedPointer {
+    pub fixup: Fixup,
+    pub auth: Option<PointerAuth>,
+    /// The distance to the next fixup of the page in strides, 0 at the end of the chain
+    pub next: u64,
+    /// Whether a rebase target is an offset from the image base rather than an address
+    runtime_offset: bool,
+}
+
+impl ChainedPointer {
+    /// Decode a pointer of the given `DYLD_CHAINED_PTR_*` format
+    pub fn decode(raw: u64, format: u16) -> error::Result<Self> {
+        let bits = |shift: u32, width: u32| (raw >> shift) & ((1 << width) - 1);
+        match format {
+            DYLD_CHAINED_PTR_ARM64E
+            | DYLD_CHAINED_PTR_ARM64E_KERNEL
+            | DYLD_CHAINED_PTR_ARM64E_USERLAND
+            | DYLD_CHAINED_PTR_ARM64E_USERLAND24 => {
+                let (is_bind, is_auth) = (bits(62, 1) != 0, bits(63, 1) != 0);
+                let auth = is_auth.then(|| PointerAuth {
+                    key: PacKey::from_bits(bits(49, 2)),
+                    diversity: bits(32, 16) as u16,
+                    addr_div: bits(48, 1) != 0,
+                });
+                let ordinal_width = if format == DYLD_CHAINED_PTR_ARM64E_USERLAND24 {
+                    24
+                } else {
+                    16
+                };
+                let fixup = match (is_bind, is_auth) {
+                    (true, _) => Fixup::Bind {
+                        ordinal: bits(0, ordinal_width) as u32,
+                        // signed 19 bits, and not there at all in an authenticated bind
+                        addend: if is_auth {
+                            0
+                        } else {
+                            ((bits(32, 19) << 45) as i64) >> 45
+                        },
+                    },
+                    (false, true) => Fixup::Rebase {
+                        target: bits(0, 32),
+                        high8: 0,
+                    },
+                    (false, false) => Fixup::Rebase {
+                        target: bits(0, 43),
+                        high8: bits(43, 8) as u8,
+                    },
+                };
+                Ok(ChainedPointer {
+                    fixup,
+                    auth,
+                    next: bits(51, 11),
+                    // the authenticated rebases of every arm64e format are offsets
+                    runtime_offset: format != DYLD_CHAINED_PTR_ARM64E || is_auth,
+                })
+            }
+            DYLD_CHAINED_PTR_64 | DYLD_CHAINED_PTR_64_OFFSET => {
+                let fixup = if bits(63, 1) != 0 {
+                    Fixup::Bind {
+                        ordinal: bits(0, 24) as u32,
+                        addend: bits(24, 8) as i64,
+                    }
+                } else {
+                    Fixup::Rebase {
+                        target: bits(0, 36),
+                        high8: bits(36, 8) as u8,
+                    }
+                };
+                Ok(ChainedPointer {
+                    fixup,
+                    auth: None,
+                    next: bits(51, 12),
+                    runtime_offset: format == DYLD_CHAINED_PTR_64_OFFSET,
+                })
+            }
+            DYLD_CHAINED_PTR_32 => {
+                let fixup = if bits(31, 1) != 0 {
+                    Fixup::Bind {
+                        ordinal: bits(0, 20) as u32,
+                        addend: bits(20, 6) as i64,
+                    }
+                } else {
+                    Fixup::Rebase {
+                        target: bits(0, 26),
+                        high8: 0,
+                    }
+                };
+                Ok(ChainedPointer {
+                    fixup,
+                    auth: None,
+                    next: bits(26, 5),
+                    runtime_offset: false,
+                })
+            }
+            _ => Err(error::Error::Malformed(format!(
+                "Unsupported chained pointer format {}",
+                format
+            ))),
+        }
+    }
+
+    /// The address a rebase points to, in an image loaded at `image_base`, with the signature
+    /// bits it is stored with stripped
+    pub fn target(&self, image_base: u64) -> Option<u64> {
+        match self.fixup {
+            Fixup::Rebase { target, high8 } => {
+                let target = if self.runtime_offset {
+                    image_base.wrapping_add(target)
+                } else {
+                    target
+                };
+                Some(target | (high8 as u64) << 56)
+            }
+            Fixup::Bind { .. } | Fixup::Value { .. } => None,
+        }
+    }
+
+    /// What a 32 bit chain with pointers up to `max_valid_pointer` holds: rebases past it are
+    /// plain values, biased to fit the 26 bits of the target
+    fn unbias(&mut self, max_valid_pointer: u32) {
+        if let Fixup::Rebase { target, .. } = self.fixup {
+            if target > max_valid_pointer as u64 {
+                let bias = (0x0400_0000 + max_valid_pointer as u64) / 2;
+                self.fixup = Fixup::Value {
+                    value: target.wrapping_sub(bias) as u32,
+                };
+            }
+        }
+    }
+}
+
+/// Whether the pointers of a format are 32 bits wide
+fn is_32(format: u16) -> bool {
+    matches!(
+        format,
+        DYLD_CHAINED_PTR_32 | DYLD_CHAINED_PTR_32_CACHE | DYLD_CHAINED_PTR_32_FIRMWARE
+    )
+}
+
+/// The bytes between fixups of a chain, per step of `next`
+fn stride(format: u16) -> u64 {
+    match format {
+        DYLD_CHAINED_PTR_ARM64E
+        | DYLD_CHAINED_PTR_ARM64E_USERLAND
+        | DYLD_CHAINED_PTR_ARM64E_USERLAND24 => 8,
+        _ => 4,
+    }
+}
+
+/// Strip the signature of a pointer signed at run time, as found in a memory dump: the bits
+/// above the 47 bit virtual address are the signature, and bit 55 tells a kernel pointer
+/// (upper bits all set) from a user one.
+pub fn strip_pac(pointer: u64) -> u64 {
+    const VA_MASK: u64 = (1 << 47) - 1;
+    if pointer & (1 << 55) != 0 {
+        pointer | !VA_MASK
+    } else {
+        pointer & VA_MASK
+    }
+}
+
+/// An entry of the imports table, which bind fixups refer to by index
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChainedImport<'a> {
+    pub name: &'a str,
+    pub dylib: Option<Dylib<'a>>,
+    pub weak: bool,
+    pub addend: i64,
+}
+
+/// The chained fixups of an image
+#[derive(Debug, Clone, Default)]
+pub struct ChainedFixups<'a> {
+    /// Each fixup, by the address of the pointer
+    pub fixups: Vec<(u64, ChainedPointer)>,
+    pub imports: Vec<ChainedImport<'a>>,
+}
+
+impl<'a> ChainedFixups<'a> {
+    /// Walk the chains described by the `LC_DYLD_CHAINED_FIXUPS` payload `command` points to
+    pub fn parse(
+        data: &'a [u8],
+        command: &LinkeditDataCommand,
+        segments: &[Segment<'a>],
+        libs: &[&'a str],
+        limits: &Limits,
+    ) -> error::Result<Self> {
+        let header = command.dataoff as usize;
+        let starts_offset: u32 = data.pread_with(header + 4, scroll::LE)?;
+        let imports_offset: u32 = data.pread_with(header + 8, scroll::LE)?;
+        let symbols_offset: u32 = data.pread_with(header + 12, scroll::LE)?;
+        let imports_count: u32 = data.pread_with(header + 16, scroll::LE)?;
+        let imports_format: u32 = data.pread_with(header + 20, scroll::LE)?;
+        let symbols_format: u32 = data.pread_with(header + 24, scroll::LE)?;
+        if symbols_format != 0 {
+            return Err(error::Error::Malformed(format!(
+                "Unsupported chained fixups symbol format {}",
+                symbols_format
+            )));
+        }
+        let mut budget = Budget::new(limits.max_iterations, "chained fixups");
+        let mut fixups = ChainedFixups::default();
+
+        let symbols = header + symbols_offset as usize;
+        let mut offset = header + imports_offset as usize;
+        budget.take(imports_count as u64)?;
+        for _ in 0..imports_count {
+            let (ordinal, weak, name_offset, addend) = match imports_format {
+                DYLD_CHAINED_IMPORT | DYLD_CHAINED_IMPORT_ADDEND => {
+                    let import: u32 = data.gread_with(&mut offset, scroll::LE)?;
+                    let addend = if imports_format == DYLD_CHAINED_IMPORT_ADDEND {
+                        data.gread_with::<i32>(&mut offset, scroll::LE)? as i64
+                    } else {
+                        0
+                    };
+                    // the special ordinals are negative
+                    let ordinal = match import & 0xff {
+                        special @ 0xf0.. => special as u8 as i8 as i64,
+                        ordinal => ordinal as i64,
+                    };
+                    (ordinal, import & 0x100 != 0, (import >> 9) as usize, addend)
+                }
+                DYLD_CHAINED_IMPORT_ADDEND64 => {
+                    let import: u64 = data.gread_with(&mut offset, scroll::LE)?;
+                    let addend: i64 = data.gread_with(&mut offset, scroll::LE)?;
+                    let ordinal = match import & 0xffff {
+                        special @ 0xfff0.. => special as u16 as i16 as i64,
+                        ordinal => ordinal as i64,
+                    };
+                    let name_offset = (import >> 32) as usize;
+                    (ordinal, import & 0x10000 != 0, name_offset, addend)
+                }
+                _ => {
+                    return Err(error::Error::Malformed(format!(
+                        "Unsupported chained fixups import format {}",
+                        imports_format
+                    )))
+                }
+            };
+            fixups.imports.push(ChainedImport {
+                name: data.pread(symbols + name_offset)?,
+                dylib: Dylib::from_ordinal(ordinal, libs),
+                weak,
+                addend,
+            });
+        }
+
+        let starts = header + starts_offset as usize;
+        let seg_count: u32 = data.pread_with(starts, scroll::LE)?;
+        for index in 0..seg_count as usize {
+            let seg_info: u32 = data.pread_with(starts + 4 + index * 4, scroll::LE)?;
+            if seg_info == 0 {
+                continue;
+            }
+            let segment = segments.get(index).ok_or_else(|| {
+                error::Error::Malformed(format!("Chained fixups of missing segment {}", index))
+            })?;
+            let mut offset = starts + seg_info as usize + 4;
+            let page_size: u16 = data.gread_with(&mut offset, scroll::LE)?;
+            let format: u16 = data.gread_with(&mut offset, scroll::LE)?;
+            // the segment offset
+            offset += 8;
+            let max_valid_pointer: u32 = data.gread_with(&mut offset, scroll::LE)?;
+            let page_count: u16 = data.gread_with(&mut offset, scroll::LE)?;
+            for page in 0..page_count as u64 {
+                let start: u16 = data.gread_with(&mut offset, scroll::LE)?;
+                if start == DYLD_CHAINED_PTR_START_NONE || start & DYLD_CHAINED_PTR_START_MULTI != 0
+                {
+                    continue;
+                }
+                let mut at = page * page_size as u64 + start as u64;
+                loop {
+                    budget.take(1)?;
+                    let raw: u64 = if is_32(format) {
+                        segment.data.pread_with::<u32>(at as usize, scroll::LE)? as u64
+                    } else {
+                        segment.data.pread_with(at as usize, scroll::LE)?
+                    };
+                    let mut pointer = ChainedPointer::decode(raw, format)?;
+                    if is_32(format) {
+                        pointer.unbias(max_valid_pointer);
+                    }
+                    fixups.fixups.push((segment.vmaddr + at, pointer));
+                    if pointer.next == 0 {
+                        break;
+                    }
+                    at += pointer.next * stride(format);
+                }
+            }
+        }
+        Ok(fixups)
+    }
+
+    /// The import a bind fixup refers to
+    pub fn import(&self, pointer: &ChainedPointer) -> Option<&ChainedImport<'a>> {
+        match pointer.fixup {
+            Fixup::Bind { ordinal, .. } => self.imports.get(ordinal as usize),
+            Fixup::Rebase { .. } | Fixup::Value { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arm64e_pointers() {
+        // an authenticated rebase to base + 0x4000, key DA, diversity 0x1234, address diversified
+        let raw: u64 = 1 << 63 | 2 << 49 | 1 << 48 | 0x1234 << 32 | 2 << 51 | 0x4000;
+        let pointer = ChainedPointer::decode(raw, DYLD_CHAINED_PTR_ARM64E).unwrap();
+        let auth = pointer.auth.unwrap();
+        assert_eq!(
+            (auth.key, auth.diversity, auth.addr_div),
+            (PacKey::DA, 0x1234, true)
+        );
+        assert_eq!(pointer.next, 2);
+        assert_eq!(pointer.target(0x100000000), Some(0x100004000));
+        assert_eq!(
+            auth.to_string(),
+            "key DA, diversity 0x1234, address diversified"
+        );
+
+        // a plain rebase to 0x100008000 with a tagged top byte
+        let raw: u64 = 0x7f << 43 | 0x100008000;
+        let pointer = ChainedPointer::decode(raw, DYLD_CHAINED_PTR_ARM64E).unwrap();
+        assert_eq!(pointer.auth, None);
+        assert_eq!(pointer.target(0x100000000), Some(0x7f00000100008000));
+        // the same in the userland format is an offset
+        let pointer = ChainedPointer::decode(0x8000, DYLD_CHAINED_PTR_ARM64E_USERLAND).unwrap();
+        assert_eq!(pointer.target(0x100000000), Some(0x100008000));
+
+        // a bind to import 3 with an addend of -8
+        let raw: u64 = 1 << 62 | ((-8i64 as u64) & 0x7ffff) << 32 | 3;
+        let pointer = ChainedPointer::decode(raw, DYLD_CHAINED_PTR_ARM64E).unwrap();
+        assert_eq!(
+            pointer.fixup,
+            Fixup::Bind {
+                ordinal: 3,
+                addend: -8
+            }
+        );
+        assert_eq!(pointer.target(0), None);
+        assert!(ChainedPointer::decode(raw, DYLD_CHAINED_PTR_32_CACHE).is_err());
+
+        // arm64_32: a rebase to 0x4000, then a bind to import 2 with an addend of 4
+        let pointer = ChainedPointer::decode(1 << 26 | 0x4000, DYLD_CHAINED_PTR_32).unwrap();
+        assert_eq!((pointer.target(0x1000), pointer.next), (Some(0x4000), 1));
+        let pointer = ChainedPointer::decode(1 << 31 | 4 << 20 | 2, DYLD_CHAINED_PTR_32).unwrap();
+        assert_eq!(
+            pointer.fixup,
+            Fixup::Bind {
+                ordinal: 2,
+                addend: 4
+            }
+        );
+
+        // past the max valid pointer of 0x10_0000 a rebase is a value, biased by 0x208_0000
+        let mut pointer = ChainedPointer::decode(0x208_002a, DYLD_CHAINED_PTR_32).unwrap();
+        pointer.unbias(0x10_0000);
+        assert_eq!(pointer.fixup, Fixup::Value { value: 0x2a });
+        assert_eq!(pointer.target(0x1000), None);
+        let mut pointer = ChainedPointer::decode(0x4000, DYLD_CHAINED_PTR_32).unwrap();
+        pointer.unbias(0x10_0000);
+        assert_eq!(pointer.target(0x1000), Some(0x4000));
+
+        assert_eq!(strip_pac(0x002b_0001_0000_4000), 0x1_0000_4000);
+        assert_eq!(strip_pac(0xffa8_fff0_0712_3456), 0xffff_fff0_0712_3456);
+    }
+}
@@ -6,26 +6,30 @@
 
 use crate::{
     container, error,
-    mach::{bind_opcodes, load_command, segment},
+    mach::{
+        bind_opcodes,
+        limits::{Budget, Limits},
+        load_command, segment,
+    },
 };
-use alloc::vec::Vec;
+use alloc::{format, vec::Vec};
 use core::{
     fmt::{self, Debug},
     ops::Range,
 };
-use scroll::{Pread, Sleb128, Uleb128};
+use scroll::{Pread, Sleb128};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 /// Import binding information generated by running the Finite State Automaton programmed via `bind_opcodes`
 struct BindInformation<'a> {
     seg_index: u8,
     seg_offset: u64,
     bind_type: u8,
-    symbol_library_ordinal: u8,
+    /// An index into the libs, or one of the special (zero or negative) ordinals
+    symbol_library_ordinal: i64,
     symbol_name: &'a str,
     symbol_flags: u8,
     addend: i64,
-    special_dylib: u8, // seeing self = 0 assuming this means the symbol is imported from itself, because its... libSystem.B.dylib?
     is_lazy: bool,
 }
 
@@ -43,18 +47,54 @@ impl<'a> BindInformation<'a> {
     }
 }
 
-impl<'a> Default for BindInformation<'a> {
-    fn default() -> Self {
-        BindInformation {
-            seg_index: 0,
-            seg_offset: 0x0,
-            bind_type: 0x0,
-            special_dylib: 1,
-            symbol_library_ordinal: 0,
-            symbol_name: "",
-            symbol_flags: 0,
-            addend: 0,
-            is_lazy: false,
+/// Where dyld looks for the definition of an import
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dylib<'a> {
+    /// One of the dylibs the binary links against (two-level namespace)
+    Ordinary(&'a str),
+    /// The binary itself
+    SelfModule,
+    /// The main executable of the process
+    MainExecutable,
+    /// Every loaded image in load order, as with a flat namespace
+    FlatLookup,
+    /// The weak definitions of every loaded image, with the first one winning
+    WeakLookup,
+}
+
+impl<'a> Dylib<'a> {
+    /// Resolve a library ordinal as dyld does: positive ordinals index `libs`, and zero and the
+    /// negative ordinals set by `BIND_OPCODE_SET_DYLIB_SPECIAL_IMM` are special
+    pub fn from_ordinal(ordinal: i64, libs: &[&'a str]) -> Option<Dylib<'a>> {
+        match ordinal {
+            0 => Some(Dylib::SelfModule),
+            -1 => Some(Dylib::MainExecutable),
+            -2 => Some(Dylib::FlatLookup),
+            -3 => Some(Dylib::WeakLookup),
+            1.. => libs
+                .get(usize::try_from(ordinal).ok()?)
+                .map(|lib| Dylib::Ordinary(lib)),
+            _ => None,
+        }
+    }
+
+    /// The install name of the dylib, if the import names one
+    pub fn name(&self) -> Option<&'a str> {
+        match self {
+            Dylib::Ordinary(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Dylib<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dylib::Ordinary(name) => fmt.write_str(name),
+            Dylib::SelfModule => fmt.write_str("this-image"),
+            Dylib::MainExecutable => fmt.write_str("main-executable"),
+            Dylib::FlatLookup => fmt.write_str("flat-namespace"),
+            Dylib::WeakLookup => fmt.write_str("weak"),
         }
     }
 }
@@ -64,8 +104,9 @@ impl<'a> Default for BindInformation<'a> {
 pub struct Import<'a> {
     /// The symbol name dyld uses to resolve this import
     pub name: &'a str,
-    /// The library this symbol belongs to (thanks to two-level namespaces)
-    pub dylib: &'a str,
+    /// The library this symbol belongs to (thanks to two-level namespaces), or how dyld looks
+    /// it up otherwise
+    pub dylib: Dylib<'a>,
     ///  Whether the symbol is lazily resolved or not
     pub is_lazy: bool,
     /// The offset in the binary this import is found
@@ -89,18 +130,26 @@ impl<'a> Import<'a> {
         libs: &[&'a str],
         segments: &[segment::Segment],
         start_of_sequence_offset: usize,
-    ) -> Import<'a> {
-        let (offset, address) = {
-            let segment = &segments[bi.seg_index as usize];
-            (
-                segment.fileoff + bi.seg_offset,
-                segment.vmaddr + bi.seg_offset,
-            )
-        };
-        let size = if bi.is_lazy { 8 } else { 0 };
-        Import {
+        ctx: container::Ctx,
+    ) -> error::Result<Import<'a>> {
+        let segment = segments.get(bi.seg_index as usize).ok_or_else(|| {
+            error::Error::Malformed(format!("Bind to unknown segment {}", bi.seg_index))
+        })?;
+        let (offset, address) = (
+            segment.fileoff.wrapping_add(bi.seg_offset),
+            segment.vmaddr.wrapping_add(bi.seg_offset),
+        );
+        let dylib = Dylib::from_ordinal(bi.symbol_library_ordinal, libs).ok_or_else(|| {
+            error::Error::Malformed(format!(
+                "Bind to unknown dylib ordinal {}",
+                bi.symbol_library_ordinal
+            ))
+        })?;
+        // a lazy pointer is as wide as the container's, 4 bytes on arm64_32
+        let size = if bi.is_lazy { ctx.size() } else { 0 };
+        Ok(Import {
             name: bi.symbol_name,
-            dylib: libs[bi.symbol_library_ordinal as usize],
+            dylib,
             is_lazy: bi.is_lazy,
             offset,
             size,
@@ -108,13 +157,14 @@ impl<'a> Import<'a> {
             addend: bi.addend,
             is_weak: bi.is_weak(),
             start_of_sequence_offset: start_of_sequence_offset as u64,
-        }
+        })
     }
 }
 
 /// An interpreter for mach BIND opcodes.
 /// Runs on prebound (non lazy) symbols (usually dylib extern consts and extern variables),
-/// and lazy symbols (usually dylib functions)
+/// and lazy symbols (usually dylib functions).
+/// See [`dyld_info`](../dyld_info/index.html) for a human readable listing of the opcode streams.
 #[derive(Clone)]
 pub struct BindInterpreter<'a> {
     data: &'a [u8],
@@ -162,18 +212,42 @@ impl<'a> BindInterpreter<'a> {
         libs: &[&'a str],
         segments: &[segment::Segment],
         ctx: container::Ctx,
+    ) -> error::Result<Vec<Import<'a>>> {
+        self.imports_with_limits(libs, segments, ctx, &Limits::default())
+    }
+    /// Return the imports in this binary, failing if the opcodes go over `limits`
+    pub fn imports_with_limits(
+        &self,
+        libs: &[&'a str],
+        segments: &[segment::Segment],
+        ctx: container::Ctx,
+        limits: &Limits,
     ) -> error::Result<Vec<Import<'a>>> {
         let mut imports = Vec::new();
-        self.run(false, libs, segments, ctx, &mut imports)?;
-        self.run(true, libs, segments, ctx, &mut imports)?;
+        let mut budget = Budget::new(limits.max_iterations, "bind opcodes");
+        let mut import_budget = Budget::new(limits.max_imports, "imports");
+        for is_lazy in [false, true] {
+            self.run(
+                is_lazy,
+                libs,
+                segments,
+                ctx,
+                limits,
+                (&mut budget, &mut import_budget),
+                &mut imports,
+            )?;
+        }
         Ok(imports)
     }
+    #[allow(clippy::too_many_arguments)]
     fn run(
         &self,
         is_lazy: bool,
         libs: &[&'a str],
         segments: &[segment::Segment],
         ctx: container::Ctx,
+        limits: &Limits,
+        (budget, import_budget): (&mut Budget, &mut Budget),
         imports: &mut Vec<Import<'a>>,
     ) -> error::Result<()> {
         use crate::mach::bind_opcodes::*;
@@ -186,10 +260,8 @@ impl<'a> BindInterpreter<'a> {
         let mut offset = location.start;
         let mut start_of_sequence: usize = 0;
         while offset < location.end {
+            budget.take(1)?;
             let opcode = self.data.gread::<i8>(&mut offset)? as bind_opcodes::Opcode;
-            // let mut input = String::new();
-            // ::std::io::stdin().read_line(&mut input).unwrap();
-            // println!("opcode: {} ({:#x}) offset: {:#x}\n {:?}", opcode_to_str(opcode & BIND_OPCODE_MASK), opcode, offset - location.start - 1, &bind_info);
             match opcode & BIND_OPCODE_MASK {
                 // we do nothing, don't update our records, and add a new, fresh record
                 BIND_OPCODE_DONE => {
@@ -198,17 +270,21 @@ impl<'a> BindInterpreter<'a> {
                 }
                 BIND_OPCODE_SET_DYLIB_ORDINAL_IMM => {
                     let symbol_library_ordinal = opcode & BIND_IMMEDIATE_MASK;
-                    bind_info.symbol_library_ordinal = symbol_library_ordinal;
+                    bind_info.symbol_library_ordinal = i64::from(symbol_library_ordinal);
                 }
                 BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB => {
-                    let symbol_library_ordinal = Uleb128::read(self.data, &mut offset)?;
-                    bind_info.symbol_library_ordinal = symbol_library_ordinal as u8;
+                    let symbol_library_ordinal = limits.read_uleb(self.data, &mut offset)?;
+                    bind_info.symbol_library_ordinal =
+                        i64::try_from(symbol_library_ordinal).unwrap_or(i64::MAX);
                 }
                 BIND_OPCODE_SET_DYLIB_SPECIAL_IMM => {
-                    // dyld puts the immediate into the symbol_library_ordinal field...
+                    // dyld puts the immediate, sign extended, into the library ordinal
                     let special_dylib = opcode & BIND_IMMEDIATE_MASK;
-                    // Printf.printf "special_dylib: 0x%x\n" special_dylib
-                    bind_info.special_dylib = special_dylib;
+                    bind_info.symbol_library_ordinal = if special_dylib == 0 {
+                        0
+                    } else {
+                        i64::from((BIND_OPCODE_MASK | special_dylib) as i8)
+                    };
                 }
                 BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM => {
                     let symbol_flags = opcode & BIND_IMMEDIATE_MASK;
@@ -229,12 +305,12 @@ impl<'a> BindInterpreter<'a> {
                     let seg_index = opcode & BIND_IMMEDIATE_MASK;
                     // dyld sets the address to the segActualLoadAddress(segIndex) + uleb128
                     // address = segActualLoadAddress(segmentIndex) + read_uleb128(p, end);
-                    let seg_offset = Uleb128::read(self.data, &mut offset)?;
+                    let seg_offset = limits.read_uleb(self.data, &mut offset)?;
                     bind_info.seg_index = seg_index;
                     bind_info.seg_offset = seg_offset;
                 }
                 BIND_OPCODE_ADD_ADDR_ULEB => {
-                    let addr = Uleb128::read(self.data, &mut offset)?;
+                    let addr = limits.read_uleb(self.data, &mut offset)?;
                     let seg_offset = bind_info.seg_offset.wrapping_add(addr);
                     bind_info.seg_offset = seg_offset;
                 }
@@ -245,7 +321,14 @@ impl<'a> BindInterpreter<'a> {
                     // throwBadBindingAddress(address, segmentEndAddress, segmentIndex, start, end, p);
                     // (this->*handler)(context, address, type, symbolName, symboFlags, addend, libraryOrdinal, "", &last);
                     // address += sizeof(intptr_t);
-                    imports.push(Import::new(&bind_info, libs, segments, start_of_sequence));
+                    import_budget.take(1)?;
+                    imports.push(Import::new(
+                        &bind_info,
+                        libs,
+                        segments,
+                        start_of_sequence,
+                        ctx,
+                    )?);
                     let seg_offset = bind_info.seg_offset.wrapping_add(ctx.size() as u64);
                     bind_info.seg_offset = seg_offset;
                 }
@@ -256,8 +339,15 @@ impl<'a> BindInterpreter<'a> {
                     // (this->*handler)(context, address, type, symbolName, symboFlags, addend, libraryOrdinal, "", &last);
                     // address += read_uleb128(p, end) + sizeof(intptr_t);
                     // we bind the old record, then increment bind info address for the next guy, plus the ptr offset *)
-                    imports.push(Import::new(&bind_info, libs, segments, start_of_sequence));
-                    let addr = Uleb128::read(self.data, &mut offset)?;
+                    import_budget.take(1)?;
+                    imports.push(Import::new(
+                        &bind_info,
+                        libs,
+                        segments,
+                        start_of_sequence,
+                        ctx,
+                    )?);
+                    let addr = limits.read_uleb(self.data, &mut offset)?;
                     let seg_offset = bind_info
                         .seg_offset
                         .wrapping_add(addr)
@@ -272,7 +362,14 @@ impl<'a> BindInterpreter<'a> {
                     // address += immediate*sizeof(intptr_t) + sizeof(intptr_t);
                     // break;
                     // similarly, we bind the old record, then perform address manipulation for the next record
-                    imports.push(Import::new(&bind_info, libs, segments, start_of_sequence));
+                    import_budget.take(1)?;
+                    imports.push(Import::new(
+                        &bind_info,
+                        libs,
+                        segments,
+                        start_of_sequence,
+                        ctx,
+                    )?);
                     let scale = opcode & BIND_IMMEDIATE_MASK;
                     let size = ctx.size() as u64;
                     let seg_offset = bind_info
@@ -292,11 +389,20 @@ impl<'a> BindInterpreter<'a> {
                     // address += skip + sizeof(intptr_t);
                     // }
                     // break;
-                    let count = Uleb128::read(self.data, &mut offset)?;
-                    let skip = Uleb128::read(self.data, &mut offset)?;
-                    let skip_plus_size = skip + ctx.size() as u64;
+                    let count = limits.read_uleb(self.data, &mut offset)?;
+                    let skip = limits.read_uleb(self.data, &mut offset)?;
+                    let skip_plus_size = skip.wrapping_add(ctx.size() as u64);
+                    // checked up front, so a huge count fails before binding anything
+                    budget.take(count)?;
+                    import_budget.take(count)?;
                     for _i in 0..count {
-                        imports.push(Import::new(&bind_info, libs, segments, start_of_sequence));
+                        imports.push(Import::new(
+                            &bind_info,
+                            libs,
+                            segments,
+                            start_of_sequence,
+                            ctx,
+                        )?);
                         let seg_offset = bind_info.seg_offset.wrapping_add(skip_plus_size);
                         bind_info.seg_offset = seg_offset;
                     }
@@ -307,3 +413,83 @@ impl<'a> BindInterpreter<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container::{Container, Ctx},
+        mach::bind_opcodes::*,
+    };
+
+    #[test]
+    fn special_dylibs() {
+        let ctx = Ctx::new(Container::Big, scroll::LE);
+        let mut segment = segment::Segment::new(ctx, &[]);
+        segment.vmaddr = 0x1000;
+        let mut stream = vec![BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB, 0x10];
+        for special in [
+            BIND_SPECIAL_DYLIB_FLAT_LOOKUP,
+            BIND_SPECIAL_DYLIB_WEAK_LOOKUP,
+            BIND_SPECIAL_DYLIB_SELF,
+            BIND_SPECIAL_DYLIB_MAIN_EXECUTABLE,
+        ] {
+            stream.extend([
+                BIND_OPCODE_SET_DYLIB_SPECIAL_IMM | special,
+                BIND_OPCODE_DO_BIND,
+            ]);
+        }
+        stream.extend([BIND_OPCODE_SET_DYLIB_ORDINAL_IMM | 1, BIND_OPCODE_DO_BIND]);
+        let command = load_command::DyldInfoCommand {
+            bind_size: stream.len() as u32,
+            ..Default::default()
+        };
+        let imports = BindInterpreter::new(&stream, &command)
+            .imports(&["self", "/usr/lib/libSystem.B.dylib"], &[segment], ctx)
+            .unwrap();
+        let dylibs = imports
+            .iter()
+            .map(|import| import.dylib)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dylibs,
+            [
+                Dylib::FlatLookup,
+                Dylib::WeakLookup,
+                Dylib::SelfModule,
+                Dylib::MainExecutable,
+                Dylib::Ordinary("/usr/lib/libSystem.B.dylib"),
+            ]
+        );
+        assert_eq!(imports[4].address, 0x1030);
+        assert_eq!(Dylib::from_ordinal(2, &["self", "libz"]), None);
+        assert_eq!(Dylib::from_ordinal(-4, &[]), None);
+    }
+
+    #[test]
+    fn lazy_pointer_size() {
+        // arm64_32 binaries have a 32 bit container
+        let ctx = Ctx::new(Container::Little, scroll::LE);
+        let mut segment = segment::Segment::new(ctx, &[]);
+        segment.vmaddr = 0x4000;
+        let mut stream = vec![
+            BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB,
+            0x8,
+            BIND_OPCODE_SET_DYLIB_ORDINAL_IMM | 1,
+            BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM,
+        ];
+        stream.extend(b"_puts\0");
+        stream.extend([BIND_OPCODE_DO_BIND, BIND_OPCODE_DONE]);
+        let command = load_command::DyldInfoCommand {
+            lazy_bind_size: stream.len() as u32,
+            ..Default::default()
+        };
+        let imports = BindInterpreter::new(&stream, &command)
+            .imports(&["self", "/usr/lib/libSystem.B.dylib"], &[segment], ctx)
+            .unwrap();
+        assert_eq!(imports.len(), 1);
+        assert!(imports[0].is_lazy);
+        assert_eq!((imports[0].name, imports[0].address), ("_puts", 0x4008));
+        assert_eq!(imports[0].size, 4);
+    }
+}
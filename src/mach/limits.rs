@@ -0,0 +1,151 @@
+//! Resource limits for the bind opcode interpreter and the export trie walker
+//!
+//! Both are little programs read out of the binary: a single `BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB`
+//! can ask for 2^64 imports, and an export trie can point back at itself or fan out into an
+//! exponential number of walks. Running them under [`Limits`] turns a hostile binary into an error
+//! instead of an allocation failure or a hang.
+
+use crate::error;
+use alloc::format;
+use scroll::Uleb128;
+
+/// The limits the opcode interpreters run under. The defaults are well above what the largest
+/// real binaries (dyld shared cache dylibs included) need.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The most imports the bind opcodes may produce
+    pub max_imports: usize,
+    /// The most exports the export trie may hold
+    pub max_exports: usize,
+    /// The most opcodes, repeated binds and trie nodes an interpreter may go through
+    pub max_iterations: usize,
+    /// The longest ULEB128 in bytes; 10 is enough for any 64-bit value
+    pub max_uleb_length: usize,
+    /// How deep the export trie may nest
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_imports: 1 << 22,
+            max_exports: 1 << 22,
+            max_iterations: 1 << 26,
+            max_uleb_length: 10,
+            max_depth: 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// No limits at all, for trusted input
+    pub fn unlimited() -> Self {
+        Limits {
+            max_imports: usize::MAX,
+            max_exports: usize::MAX,
+            max_iterations: usize::MAX,
+            max_uleb_length: usize::MAX,
+            max_depth: usize::MAX,
+        }
+    }
+
+    /// Read a ULEB128 at `offset`, refusing one longer than `max_uleb_length`
+    pub fn read_uleb(&self, data: &[u8], offset: &mut usize) -> error::Result<u64> {
+        let length = data
+            .get(*offset..)
+            .and_then(|rest| rest.iter().position(|byte| byte & 0x80 == 0))
+            .map_or(usize::MAX, |last| last + 1);
+        if length > self.max_uleb_length && length != usize::MAX {
+            return Err(error::Error::Malformed(format!(
+                "ULEB128 at {:#x} is {} bytes long, more than the limit of {}",
+                *offset, length, self.max_uleb_length
+            )));
+        }
+        Ok(Uleb128::read(data, offset)?)
+    }
+}
+
+/// Counts work done against one of the [`Limits`]
+#[derive(Debug)]
+pub(crate) struct Budget {
+    left: usize,
+    what: &'static str,
+}
+
+impl Budget {
+    pub(crate) fn new(limit: usize, what: &'static str) -> Self {
+        Budget { left: limit, what }
+    }
+
+    /// Take `n` from the budget, or fail if there isn't that much left
+    pub(crate) fn take(&mut self, n: u64) -> error::Result<()> {
+        match usize::try_from(n)
+            .ok()
+            .and_then(|n| self.left.checked_sub(n))
+        {
+            Some(left) => {
+                self.left = left;
+                Ok(())
+            }
+            None => Err(error::Error::Malformed(format!(
+                "Too many {}, the limit was reached",
+                self.what
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uleb_length() {
+        let limits = Limits::default();
+        let long = [0x80u8; 11]
+            .iter()
+            .chain(&[0x01])
+            .copied()
+            .collect::<Vec<u8>>();
+        assert!(limits.read_uleb(&long, &mut 0).is_err());
+        let mut offset = 0;
+        assert_eq!(
+            limits.read_uleb(&[0xe5, 0x8e, 0x26], &mut offset).unwrap(),
+            624485
+        );
+        assert_eq!(offset, 3);
+
+        let mut budget = Budget::new(3, "imports");
+        assert!(budget.take(2).is_ok());
+        assert!(budget.take(2).is_err());
+        assert!(budget.take(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn hostile_streams() {
+        use crate::{
+            container::{Container, Ctx},
+            mach::{bind_opcodes::*, exports::ExportTrie, imports::BindInterpreter, load_command},
+        };
+        // bind 2^63 times
+        let mut stream = vec![BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB];
+        stream.extend([0xff; 8]);
+        stream.extend([0x7f, 0x00]);
+        let command = load_command::DyldInfoCommand {
+            bind_size: stream.len() as u32,
+            ..Default::default()
+        };
+        let ctx = Ctx::new(Container::Big, scroll::LE);
+        let interpreter = BindInterpreter::new(&stream, &command);
+        assert!(interpreter.imports(&["self"], &[], ctx).is_err());
+
+        // a node which is its own child
+        let trie = [0x00, 0x01, b'a', 0x00, 0x00];
+        let command = load_command::DyldInfoCommand {
+            export_size: trie.len() as u32,
+            ..Default::default()
+        };
+        let trie = ExportTrie::new(&trie, &command);
+        assert!(trie.exports(&[]).is_err());
+    }
+}
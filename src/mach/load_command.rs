@@ -1067,6 +1067,60 @@ impl VersionMinCommand {
 
 pub const SIZEOF_VERSION_MIN_COMMAND: usize = 16;
 
+pub const PLATFORM_MACOS: u32 = 1;
+pub const PLATFORM_IOS: u32 = 2;
+pub const PLATFORM_TVOS: u32 = 3;
+pub const PLATFORM_WATCHOS: u32 = 4;
+pub const PLATFORM_BRIDGEOS: u32 = 5;
+pub const PLATFORM_MACCATALYST: u32 = 6;
+pub const PLATFORM_IOSSIMULATOR: u32 = 7;
+pub const PLATFORM_TVOSSIMULATOR: u32 = 8;
+pub const PLATFORM_WATCHOSSIMULATOR: u32 = 9;
+pub const PLATFORM_DRIVERKIT: u32 = 10;
+pub const PLATFORM_VISIONOS: u32 = 11;
+pub const PLATFORM_VISIONOSSIMULATOR: u32 = 12;
+
+/// The name of a `PLATFORM_*` constant, lowercase and without the prefix
+pub fn platform_to_str(platform: u32) -> &'static str {
+    match platform {
+        PLATFORM_MACOS => "macos",
+        PLATFORM_IOS => "ios",
+        PLATFORM_TVOS => "tvos",
+        PLATFORM_WATCHOS => "watchos",
+        PLATFORM_BRIDGEOS => "bridgeos",
+        PLATFORM_MACCATALYST => "maccatalyst",
+        PLATFORM_IOSSIMULATOR => "iossimulator",
+        PLATFORM_TVOSSIMULATOR => "tvossimulator",
+        PLATFORM_WATCHOSSIMULATOR => "watchossimulator",
+        PLATFORM_DRIVERKIT => "driverkit",
+        PLATFORM_VISIONOS => "visionos",
+        PLATFORM_VISIONOSSIMULATOR => "visionossimulator",
+        _ => "unknown",
+    }
+}
+
+/// The build_version_command names the platform the binary was built for, and the minimum OS
+/// and SDK versions. It replaces the version_min_commands, and is the only one naming
+/// platforms like DriverKit.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pread, Pwrite, IOread, IOwrite, SizeWith)]
+pub struct BuildVersionCommand {
+    /// LC_BUILD_VERSION
+    pub cmd: u32,
+    /// sizeof(struct build_version_command) plus the tool entries following it
+    pub cmdsize: u32,
+    /// One of the PLATFORM_* constants
+    pub platform: u32,
+    /// X.Y.Z is encoded in nibbles xxxx.yy.zz
+    pub minos: u32,
+    /// X.Y.Z is encoded in nibbles xxxx.yy.zz
+    pub sdk: u32,
+    /// The number of build_tool_version entries following this command
+    pub ntools: u32,
+}
+
+pub const SIZEOF_BUILD_VERSION_COMMAND: usize = 24;
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pread, Pwrite, SizeWith)]
 pub struct DyldInfoCommand {
@@ -1387,6 +1441,7 @@ pub enum CommandVariant {
     VersionMinWatchos(VersionMinCommand),
     DyldExportsTrie(LinkeditDataCommand),
     DyldChainedFixups(LinkeditDataCommand),
+    BuildVersion(BuildVersionCommand),
     Unimplemented(LoadCommandHeader),
 }
 
@@ -1609,9 +1664,12 @@ impl<'a> ctx::TryFromCtx<'a, Endian> for CommandVariant {
                 let comm = bytes.pread_with::<LinkeditDataCommand>(0, le)?;
                 Ok((DyldChainedFixups(comm), size))
             }
-            // TODO: LC_NOTE (NoteCommand) and LC_BUILD_VERSION (BuildVersionCommand)
-            // are unimplemented.
-            LC_NOTE | LC_BUILD_VERSION | _ => Ok((Unimplemented(lc), size)),
+            LC_BUILD_VERSION => {
+                let comm = bytes.pread_with::<BuildVersionCommand>(0, le)?;
+                Ok((BuildVersion(comm), size))
+            }
+            // TODO: LC_NOTE (NoteCommand) is unimplemented.
+            _ => Ok((Unimplemented(lc), size)),
         }
     }
 }
@@ -1671,6 +1729,7 @@ impl CommandVariant {
             VersionMinWatchos(comm) => comm.cmdsize,
             DyldExportsTrie(comm) => comm.cmdsize,
             DyldChainedFixups(comm) => comm.cmdsize,
+            BuildVersion(comm) => comm.cmdsize,
             Unimplemented(comm) => comm.cmdsize,
         };
         cmdsize as usize
@@ -1729,6 +1788,7 @@ impl CommandVariant {
             VersionMinWatchos(comm) => comm.cmd,
             DyldExportsTrie(comm) => comm.cmd,
             DyldChainedFixups(comm) => comm.cmd,
+            BuildVersion(comm) => comm.cmd,
             Unimplemented(comm) => comm.cmd,
         }
     }
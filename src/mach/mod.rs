@@ -1,6 +1,6 @@
 //! The Mach-o, mostly zero-copy, binary format parser and raw struct definitions
 use crate::{container, error};
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use core::fmt;
 use log::debug;
 use scroll::{
@@ -10,14 +10,19 @@ use scroll::{
 
 pub mod bind_opcodes;
 pub mod constants;
+pub mod dyld_info;
 pub mod exports;
 pub mod fat;
+pub mod fixups;
 pub mod header;
 pub mod imports;
+pub mod limits;
 pub mod load_command;
+pub mod rebase_opcodes;
 pub mod relocation;
 pub mod segment;
 pub mod symbols;
+pub mod writer;
 
 pub use self::constants::cputype;
 
@@ -105,6 +110,41 @@ impl<'a> MachO<'a> {
     pub fn is_object_file(&self) -> bool {
         self.header.filetype == header::MH_OBJECT
     }
+    /// Return the 128-bit `LC_UUID` of this binary, if it has one
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.load_commands.iter().find_map(|lc| match lc.command {
+            load_command::CommandVariant::Uuid(ref uuid) => Some(uuid.uuid),
+            _ => None,
+        })
+    }
+    /// Return the `PLATFORM_*` this binary was built for, from its `LC_BUILD_VERSION` or an
+    /// older `LC_VERSION_MIN_*`, if it has one
+    pub fn platform(&self) -> Option<u32> {
+        use load_command::*;
+        self.load_commands.iter().find_map(|lc| match lc.command {
+            CommandVariant::BuildVersion(ref build) => Some(build.platform),
+            CommandVariant::VersionMinMacosx(_) => Some(PLATFORM_MACOS),
+            CommandVariant::VersionMinIphoneos(_) => Some(PLATFORM_IOS),
+            CommandVariant::VersionMinTvos(_) => Some(PLATFORM_TVOS),
+            CommandVariant::VersionMinWatchos(_) => Some(PLATFORM_WATCHOS),
+            _ => None,
+        })
+    }
+    /// Return the data of section `sectname` of segment `segname`, as in `__TEXT,__info_plist`,
+    /// if the binary has it
+    pub fn section_data(&self, segname: &str, sectname: &str) -> Option<&'a [u8]> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.name().ok() == Some(segname))
+            .find_map(|segment| {
+                segment
+                    .sections()
+                    .ok()?
+                    .into_iter()
+                    .find(|(section, _)| section.name().ok() == Some(sectname))
+                    .map(|(_, data)| data)
+            })
+    }
     /// Return an iterator over all the symbols in this binary
     pub fn symbols(&self) -> symbols::SymbolIterator<'a> {
         if let Some(ref symbols) = self.symbols {
@@ -129,22 +169,102 @@ impl<'a> MachO<'a> {
         }
         Ok(relocs)
     }
+    /// The segments mapped writable and executable at once. Only the dyld stubs of old 32-bit
+    /// binaries (`__IMPORT`) have a good reason to be. Whether a segment may be made so later is
+    /// [`segment::Segment::may_become_wx`].
+    pub fn writable_executable_segments(&self) -> Vec<&segment::Segment<'a>> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.is_wx())
+            .collect()
+    }
     /// Return the exported symbols in this binary (if any)
     pub fn exports(&self) -> error::Result<Vec<exports::Export>> {
+        self.exports_with_limits(&limits::Limits::default())
+    }
+    /// Return the exported symbols in this binary (if any), walking the export trie under `limits`
+    pub fn exports_with_limits(
+        &self,
+        limits: &limits::Limits,
+    ) -> error::Result<Vec<exports::Export<'_>>> {
         if let Some(ref trie) = self.export_trie {
-            trie.exports(self.libs.as_slice())
+            trie.exports_with_limits(self.libs.as_slice(), limits)
         } else {
             Ok(vec![])
         }
     }
     /// Return the imported symbols in this binary that dyld knows about (if any)
     pub fn imports(&self) -> error::Result<Vec<imports::Import>> {
+        self.imports_with_limits(&limits::Limits::default())
+    }
+    /// Return the imported symbols in this binary that dyld knows about (if any), running the bind
+    /// opcodes under `limits`
+    pub fn imports_with_limits(
+        &self,
+        limits: &limits::Limits,
+    ) -> error::Result<Vec<imports::Import<'_>>> {
         if let Some(ref interpreter) = self.bind_interpreter {
-            interpreter.imports(self.libs.as_slice(), self.segments.as_slice(), self.ctx)
+            interpreter.imports_with_limits(
+                self.libs.as_slice(),
+                self.segments.as_slice(),
+                self.ctx,
+                limits,
+            )
         } else {
             Ok(vec![])
         }
     }
+    /// Return the chained fixups of this binary, if it has `LC_DYLD_CHAINED_FIXUPS`
+    pub fn chained_fixups(&self) -> error::Result<Option<fixups::ChainedFixups<'a>>> {
+        self.chained_fixups_with_limits(&limits::Limits::default())
+    }
+    /// Return the chained fixups of this binary, walking the chains under `limits`
+    pub fn chained_fixups_with_limits(
+        &self,
+        limits: &limits::Limits,
+    ) -> error::Result<Option<fixups::ChainedFixups<'a>>> {
+        for lc in self.load_commands.iter() {
+            if let load_command::CommandVariant::DyldChainedFixups(ref command) = lc.command {
+                return fixups::ChainedFixups::parse(
+                    self.data,
+                    command,
+                    self.segments.as_slice(),
+                    self.libs.as_slice(),
+                    limits,
+                )
+                .map(Some);
+            }
+        }
+        Ok(None)
+    }
+    /// Render the rebase, bind, weak bind and lazy bind opcode streams and the export trie of this binary, like `dyldinfo -opcodes`
+    pub fn dyld_info_listing(&self) -> error::Result<String> {
+        self.dyld_info_listing_with_limits(&limits::Limits::default())
+    }
+    /// Render the listing of [`MachO::dyld_info_listing`], decoding the streams under `limits`
+    pub fn dyld_info_listing_with_limits(&self, limits: &limits::Limits) -> error::Result<String> {
+        for lc in self.load_commands.iter() {
+            match lc.command {
+                load_command::CommandVariant::DyldInfo(ref command)
+                | load_command::CommandVariant::DyldInfoOnly(ref command) => {
+                    return dyld_info::listing(self.data, command, self.ctx, limits);
+                }
+                _ => {}
+            }
+        }
+        // binaries using chained fixups only have an export trie
+        for lc in self.load_commands.iter() {
+            if let load_command::CommandVariant::DyldExportsTrie(ref command) = lc.command {
+                let command = load_command::DyldInfoCommand {
+                    export_off: command.dataoff,
+                    export_size: command.datasize,
+                    ..Default::default()
+                };
+                return dyld_info::listing(self.data, &command, self.ctx, limits);
+            }
+        }
+        Ok(String::new())
+    }
     /// Parses the Mach-o binary from `bytes` at `offset`
     pub fn parse(bytes: &'a [u8], mut offset: usize) -> error::Result<MachO<'a>> {
         let (magic, maybe_ctx) = parse_magic_and_ctx(bytes, offset)?;
@@ -465,3 +585,65 @@ impl<'a> Mach<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mach::{
+        constants::cputype::CPU_TYPE_ARM64_32,
+        header::{MH_EXECUTE, MH_MAGIC},
+        load_command::{platform_to_str, LC_BUILD_VERSION, LC_SEGMENT, PLATFORM_WATCHOS},
+    };
+
+    #[test]
+    fn build_version_platform() {
+        // an arm64_32 watchOS executable, its header then a build version with no tools
+        let words = [
+            MH_MAGIC,
+            CPU_TYPE_ARM64_32,
+            0,
+            MH_EXECUTE,
+            1,
+            24,
+            0,
+            LC_BUILD_VERSION,
+            24,
+            PLATFORM_WATCHOS,
+            0x000a_0000,
+            0x000a_0000,
+            0,
+        ];
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let macho = MachO::parse(&bytes, 0).unwrap();
+        assert!(!macho.is_64);
+        let platform = macho.platform().unwrap();
+        assert_eq!(platform, PLATFORM_WATCHOS);
+        assert_eq!(platform_to_str(platform), "watchos");
+    }
+
+    #[test]
+    fn writable_executable() {
+        // a __TEXT mapped r-x which may be made rwx, and an __IMPORT mapped rwx
+        let mut bytes: Vec<u8> = [MH_MAGIC, 7, 3, MH_EXECUTE, 2, 2 * 56, 0]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        for (name, vmaddr, initprot) in
+            [(&b"__TEXT"[..], 0x1000u32, 5u32), (b"__IMPORT", 0x2000, 7)]
+        {
+            bytes.extend(LC_SEGMENT.to_le_bytes());
+            bytes.extend(56u32.to_le_bytes());
+            let mut segname = [0u8; 16];
+            segname[..name.len()].copy_from_slice(name);
+            bytes.extend(segname);
+            for word in [vmaddr, 0x1000, 0, 0, 7, initprot, 0, 0] {
+                bytes.extend(word.to_le_bytes());
+            }
+        }
+        let macho = MachO::parse(&bytes, 0).unwrap();
+        assert!(macho.segments.iter().all(|segment| segment.may_become_wx()));
+        let wx = macho.writable_executable_segments();
+        assert_eq!(wx.len(), 1);
+        assert_eq!(wx[0].name().unwrap(), "__IMPORT");
+    }
+}
@@ -11,7 +11,12 @@ use core::ops::{Deref, DerefMut};
 use crate::container;
 use crate::error;
 
-use crate::mach::constants::{SECTION_TYPE, S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL, S_ZEROFILL};
+use crate::mach::constants::{
+    SECTION_ATTRIBUTES, SECTION_TYPE, S_ATTR_DEBUG, S_ATTR_PURE_INSTRUCTIONS,
+    S_ATTR_SOME_INSTRUCTIONS, S_GB_ZEROFILL, S_THREAD_LOCAL_INIT_FUNCTION_POINTERS,
+    S_THREAD_LOCAL_REGULAR, S_THREAD_LOCAL_ZEROFILL, S_ZEROFILL, VM_PROT_EXECUTE, VM_PROT_READ,
+    VM_PROT_WRITE,
+};
 use crate::mach::load_command::{
     Section32, Section64, SegmentCommand32, SegmentCommand64, LC_SEGMENT, LC_SEGMENT_64,
     SIZEOF_SECTION_32, SIZEOF_SECTION_64, SIZEOF_SEGMENT_COMMAND_32, SIZEOF_SEGMENT_COMMAND_64,
@@ -64,11 +69,73 @@ pub struct Section {
     pub flags: u32,
 }
 
+/// Memory protection of a segment, as found in its `initprot` or `maxprot`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Protection(pub u32);
+
+impl Protection {
+    pub fn is_readable(self) -> bool {
+        self.0 & VM_PROT_READ != 0
+    }
+    pub fn is_writable(self) -> bool {
+        self.0 & VM_PROT_WRITE != 0
+    }
+    pub fn is_executable(self) -> bool {
+        self.0 & VM_PROT_EXECUTE != 0
+    }
+    /// Both writable and executable
+    pub fn is_wx(self) -> bool {
+        self.is_writable() && self.is_executable()
+    }
+}
+
+impl fmt::Display for Protection {
+    /// `r-x` style, as `vmmap` prints it
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            fmt,
+            "{}{}{}",
+            flag(self.is_readable(), 'r'),
+            flag(self.is_writable(), 'w'),
+            flag(self.is_executable(), 'x')
+        )
+    }
+}
+
 impl Section {
     /// The name of this section
     pub fn name(&self) -> error::Result<&str> {
         Ok(self.sectname.pread::<&str>(0)?)
     }
+    /// The section type, one of the `S_*` constants
+    pub fn section_type(&self) -> u32 {
+        self.flags & SECTION_TYPE
+    }
+    /// The `S_ATTR_*` attributes of this section
+    pub fn attributes(&self) -> u32 {
+        self.flags & SECTION_ATTRIBUTES
+    }
+    /// Whether the section takes no space in the file and is zeroed when loaded
+    pub fn is_zerofill(&self) -> bool {
+        matches!(
+            self.section_type(),
+            S_ZEROFILL | S_GB_ZEROFILL | S_THREAD_LOCAL_ZEROFILL
+        )
+    }
+    /// Whether the section holds instructions
+    pub fn is_executable(&self) -> bool {
+        self.flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) != 0
+    }
+    /// Whether the section holds thread local data or its descriptors
+    pub fn is_thread_local(&self) -> bool {
+        (S_THREAD_LOCAL_REGULAR..=S_THREAD_LOCAL_INIT_FUNCTION_POINTERS)
+            .contains(&self.section_type())
+    }
+    /// Whether the section holds debug information (DWARF) which isn't loaded
+    pub fn is_debug(&self) -> bool {
+        self.flags & S_ATTR_DEBUG != 0
+    }
     /// The containing segment's name
     pub fn segname(&self) -> error::Result<&str> {
         Ok(self.segname.pread::<&str>(0)?)
@@ -248,11 +315,7 @@ impl<'a> Iterator for SectionIterator<'a> {
             self.idx += 1;
             match self.data.gread_with::<Section>(&mut self.offset, self.ctx) {
                 Ok(section) => {
-                    let section_type = section.flags & SECTION_TYPE;
-                    let data = if section_type == S_ZEROFILL
-                        || section_type == S_GB_ZEROFILL
-                        || section_type == S_THREAD_LOCAL_ZEROFILL
-                    {
+                    let data = if section.is_zerofill() {
                         &[]
                     } else {
                         // it's not uncommon to encounter macho files where files are
@@ -453,6 +516,31 @@ impl<'a> Segment<'a> {
     pub fn name(&self) -> error::Result<&str> {
         Ok(self.segname.pread::<&str>(0)?)
     }
+    /// The protection the segment is mapped with
+    pub fn init_protection(&self) -> Protection {
+        Protection(self.initprot)
+    }
+    /// The most the protection of the segment may be raised to
+    pub fn max_protection(&self) -> Protection {
+        Protection(self.maxprot)
+    }
+    /// Whether the segment is mapped executable
+    pub fn is_executable(&self) -> bool {
+        self.init_protection().is_executable()
+    }
+    /// Whether the segment is mapped writable
+    pub fn is_writable(&self) -> bool {
+        self.init_protection().is_writable()
+    }
+    /// Whether the segment is mapped writable and executable at once
+    pub fn is_wx(&self) -> bool {
+        self.init_protection().is_wx()
+    }
+    /// Whether the protection of the segment may later be raised to writable and executable at
+    /// once. Most segments allow it, so this isn't a violation as [`Segment::is_wx`] is.
+    pub fn may_become_wx(&self) -> bool {
+        self.max_protection().is_wx()
+    }
     /// Get the sections from this segment, erroring if any section couldn't be retrieved
     pub fn sections(&self) -> error::Result<Vec<(Section, SectionData<'a>)>> {
         let mut sections = Vec::new();
@@ -557,3 +645,41 @@ impl<'a> Segments<'a> {
         Box::new(self.segments.iter().map(|segment| segment.into_iter()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mach::constants::S_THREAD_LOCAL_VARIABLES;
+
+    #[test]
+    fn protection_and_flags() {
+        let ctx = container::Ctx::default();
+        let mut segment = Segment::new(ctx, &[]);
+        segment.initprot = VM_PROT_READ | VM_PROT_EXECUTE;
+        segment.maxprot = VM_PROT_READ | VM_PROT_WRITE | VM_PROT_EXECUTE;
+        assert!(segment.is_executable() && !segment.is_writable());
+        assert!(!segment.is_wx() && segment.may_become_wx());
+        assert_eq!(segment.init_protection().to_string(), "r-x");
+        assert_eq!(segment.max_protection().to_string(), "rwx");
+        segment.initprot |= VM_PROT_WRITE;
+        assert!(segment.is_wx());
+        segment.maxprot = segment.initprot;
+        assert!(segment.may_become_wx());
+
+        let text = Section {
+            flags: S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS,
+            ..Default::default()
+        };
+        assert!(text.is_executable() && !text.is_zerofill());
+        let tlv = Section {
+            flags: S_THREAD_LOCAL_VARIABLES,
+            ..Default::default()
+        };
+        assert!(tlv.is_thread_local() && !tlv.is_executable());
+        let bss = Section {
+            flags: S_GB_ZEROFILL,
+            ..Default::default()
+        };
+        assert!(bss.is_zerofill() && bss.attributes() == 0);
+    }
+}
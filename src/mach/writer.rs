@@ -0,0 +1,1092 @@
+//! Rewriting of Mach-O binaries, e.g. to give a stripped binary back the names recovered by
+//! analysis.
+//!
+//! The rebuilt symbol and string tables are appended to the end of the file, inside a grown
+//! `__LINKEDIT` segment, and `LC_SYMTAB`/`LC_DYSYMTAB` are pointed at them. The indirect symbol
+//! table and the external relocations are renumbered to match the new symbol order. Rewriting a
+//! signed binary invalidates its code signature, it has to be signed again;
+//! [`MachOWriter::set_appended_data`] can drop the `LC_CODE_SIGNATURE` so it doesn't get in the
+//! way. Data appended after the last segment is kept after the new tables unless stripped.
+//!
+//! Transforms registered for a section run on its final contents. Every section of a Mach-O is
+//! mapped by its segment, so a transform may shrink a section but not grow it.
+//!
+//! Load commands can be added (e.g. an `LC_LOAD_DYLIB` or an `LC_RPATH`) and removed. Added
+//! commands go after the original ones and have to fit in the padding between the load commands
+//! and the first section contents; nothing is moved to make room.
+
+use crate::container::{self, Container};
+use crate::error;
+use crate::mach::{
+    constants::cputype::CPU_TYPE_ARM64,
+    header::Header,
+    imports::Dylib,
+    load_command::{
+        cmd_to_str, CommandVariant, DysymtabCommand, LoadCommand, Section32, Section64,
+        SymtabCommand, LC_CODE_SIGNATURE, LC_LOAD_DYLIB, LC_LOAD_WEAK_DYLIB, LC_RPATH,
+        SIZEOF_SEGMENT_COMMAND_32, SIZEOF_SEGMENT_COMMAND_64,
+    },
+    relocation::{RelocationInfo, SIZEOF_RELOCATION_INFO},
+    segment::Section,
+    symbols::{Nlist, N_EXT, N_SECT, N_STAB, N_TYPE, N_UNDF},
+    MachO,
+};
+use crate::overlay::{self, AppendedData, Artifact, Preserve};
+use crate::transform::{SectionRef, SectionTransform, SectionTransforms};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use scroll::{ctx::SizeWith, Pread, Pwrite};
+
+/// Indirect symbol table entries with these bits set don't refer to a symbol
+const INDIRECT_SYMBOL_LOCAL: u32 = 0x8000_0000;
+const INDIRECT_SYMBOL_ABS: u32 = 0x4000_0000;
+/// Library ordinals above this are the special `DYNAMIC_LOOKUP_ORDINAL` and `EXECUTABLE_ORDINAL`
+const MAX_LIBRARY_ORDINAL: usize = 0xfd;
+
+/// An nlist entry as the writer sees it, with its name instead of a string table offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolEntry {
+    pub name: String,
+    pub n_type: u8,
+    /// The 1 based section number, or `NO_SECT`
+    pub n_sect: u8,
+    pub n_desc: u16,
+    pub n_value: u64,
+}
+
+impl SymbolEntry {
+    /// Debugging entries and non external symbols
+    pub fn is_local(&self) -> bool {
+        self.n_type & N_STAB != 0 || self.n_type & N_EXT == 0
+    }
+
+    pub fn is_undefined(&self) -> bool {
+        !self.is_local() && self.n_type & N_TYPE == N_UNDF
+    }
+
+    fn to_nlist(&self, n_strx: usize) -> Nlist {
+        Nlist {
+            n_strx,
+            n_type: self.n_type,
+            n_sect: usize::from(self.n_sect),
+            n_desc: self.n_desc,
+            n_value: self.n_value,
+        }
+    }
+}
+
+/// Edits the symbol table of a (thin) Mach-O binary and emits the rewritten file
+pub struct MachOWriter<'a> {
+    bytes: &'a [u8],
+    macho: MachO<'a>,
+    /// The symbols, with their index in the original symbol table
+    symbols: Vec<(Option<usize>, SymbolEntry)>,
+    /// The load commands written after the original ones that are kept
+    added_commands: Vec<Vec<u8>>,
+    /// The indices of the original load commands which are removed
+    removed_commands: BTreeSet<usize>,
+    transforms: SectionTransforms,
+    appended: AppendedData,
+}
+
+impl<'a> MachOWriter<'a> {
+    pub fn new(bytes: &'a [u8]) -> error::Result<Self> {
+        let macho = MachO::parse(bytes, 0)?;
+        if macho.is_object_file() {
+            return Err(error::Error::Malformed(
+                "Section relocations of object files are not renumbered".to_string(),
+            ));
+        }
+        let mut symbols = Vec::new();
+        for (idx, symbol) in macho.symbols().enumerate() {
+            let (name, nlist) = symbol?;
+            symbols.push((
+                Some(idx),
+                SymbolEntry {
+                    name: name.to_string(),
+                    n_type: nlist.n_type,
+                    n_sect: nlist.n_sect as u8,
+                    n_desc: nlist.n_desc,
+                    n_value: nlist.n_value,
+                },
+            ));
+        }
+        Ok(MachOWriter {
+            bytes,
+            macho,
+            symbols,
+            added_commands: Vec::new(),
+            removed_commands: BTreeSet::new(),
+            transforms: SectionTransforms::new(),
+            appended: AppendedData::default(),
+        })
+    }
+
+    /// The binary as it was parsed, before any edits
+    pub fn macho(&self) -> &MachO<'a> {
+        &self.macho
+    }
+
+    /// The symbols which will be written, in their current (not yet sorted) order
+    pub fn symbols(&self) -> impl Iterator<Item = &SymbolEntry> {
+        self.symbols.iter().map(|(_, symbol)| symbol)
+    }
+
+    pub fn add_symbol(&mut self, symbol: SymbolEntry) {
+        self.symbols.push((None, symbol));
+    }
+
+    /// Add an external symbol defined in whichever section contains `address`
+    pub fn add_function(&mut self, name: &str, address: u64) -> error::Result<()> {
+        let n_sect = self.section_for_address(address)?.ok_or_else(|| {
+            error::Error::Malformed(format!("No section contains address {:#x}", address))
+        })?;
+        self.add_symbol(SymbolEntry {
+            name: name.to_string(),
+            n_type: N_SECT | N_EXT,
+            n_sect,
+            n_desc: 0,
+            n_value: address,
+        });
+        Ok(())
+    }
+
+    /// Rename every symbol called `old`, returning how many were renamed
+    pub fn rename_symbol(&mut self, old: &str, new: &str) -> usize {
+        let mut count = 0;
+        for (_, symbol) in self.symbols.iter_mut().filter(|(_, s)| s.name == old) {
+            symbol.name = new.to_string();
+            count += 1;
+        }
+        count
+    }
+
+    /// Remove every symbol called `name`, returning how many were removed
+    pub fn strip_symbol(&mut self, name: &str) -> usize {
+        let before = self.symbols.len();
+        self.symbols.retain(|(_, s)| s.name != name);
+        before - self.symbols.len()
+    }
+
+    /// Keep only the symbols for which `f` returns true
+    pub fn retain_symbols<F: FnMut(&SymbolEntry) -> bool>(&mut self, mut f: F) {
+        self.symbols.retain(|(_, s)| f(s));
+    }
+
+    /// The room left for load commands: the space between the end of the load commands, as
+    /// edited so far, and the first section (or segment) contents in the file
+    pub fn headroom(&self) -> error::Result<usize> {
+        let mut first_data = self.bytes.len();
+        for segment in self.macho.segments.iter() {
+            if segment.fileoff != 0 && segment.filesize != 0 {
+                first_data = first_data.min(segment.fileoff as usize);
+            }
+            for (section, _) in segment.sections()? {
+                if section.offset != 0 && section.size != 0 && !section.is_zerofill() {
+                    first_data = first_data.min(section.offset as usize);
+                }
+            }
+        }
+        let commands = self
+            .macho
+            .load_commands
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !self.is_removed(*idx))
+            .map(|(_, lc)| lc.command.cmdsize())
+            .chain(self.added_commands.iter().map(Vec::len))
+            .sum::<usize>();
+        let end = Header::size_with(&self.macho.ctx.container) + commands;
+        first_data.checked_sub(end).ok_or_else(|| {
+            error::Error::Malformed(format!(
+                "Load commands end at {:#x}, past the first section contents at {:#x}",
+                end, first_data
+            ))
+        })
+    }
+
+    /// Add the raw load command `command`, which needs a correct `cmdsize` and has to fit in the
+    /// headroom
+    pub fn add_load_command(&mut self, command: Vec<u8>) -> error::Result<()> {
+        let align = match self.macho.ctx.container {
+            Container::Little => 4,
+            Container::Big => 8,
+        };
+        let cmdsize = command.pread_with::<u32>(4, self.macho.ctx.le)? as usize;
+        if cmdsize != command.len() || !cmdsize.is_multiple_of(align) {
+            return Err(error::Error::Malformed(format!(
+                "Load command of {:#x} bytes has a bad cmdsize {:#x}",
+                command.len(),
+                cmdsize
+            )));
+        }
+        let headroom = self.headroom()?;
+        if command.len() > headroom {
+            return Err(error::Error::Malformed(format!(
+                "No room for a {:#x} byte load command, only {:#x} bytes are free",
+                command.len(),
+                headroom
+            )));
+        }
+        self.added_commands.push(command);
+        Ok(())
+    }
+
+    /// Add an `LC_LOAD_DYLIB` (or an `LC_LOAD_WEAK_DYLIB` if `weak` is set) for `path`. The
+    /// library gets the next library ordinal.
+    pub fn add_dylib(&mut self, path: &str, weak: bool) -> error::Result<()> {
+        let cmd = if weak {
+            LC_LOAD_WEAK_DYLIB
+        } else {
+            LC_LOAD_DYLIB
+        };
+        // the timestamp and the current and compatibility versions (1.0.0)
+        let command = self.string_command(cmd, &[2, 0x1_0000, 0x1_0000], path)?;
+        self.add_load_command(command)
+    }
+
+    /// Add an `LC_RPATH` for `path`
+    pub fn add_rpath(&mut self, path: &str) -> error::Result<()> {
+        let command = self.string_command(LC_RPATH, &[], path)?;
+        self.add_load_command(command)
+    }
+
+    /// A load command made of the offset of the string `string`, `fields` and then the string
+    fn string_command(&self, cmd: u32, fields: &[u32], string: &str) -> error::Result<Vec<u8>> {
+        let le = self.macho.ctx.le;
+        let align = match self.macho.ctx.container {
+            Container::Little => 4,
+            Container::Big => 8,
+        };
+        let header = 12 + 4 * fields.len();
+        let cmdsize = (header + string.len() + 1).next_multiple_of(align);
+        let mut command = vec![0u8; cmdsize];
+        let offset = &mut 0;
+        command.gwrite_with(cmd, offset, le)?;
+        command.gwrite_with(cmdsize as u32, offset, le)?;
+        command.gwrite_with(header as u32, offset, le)?;
+        for field in fields {
+            command.gwrite_with(*field, offset, le)?;
+        }
+        command[header..header + string.len()].copy_from_slice(string.as_bytes());
+        Ok(command)
+    }
+
+    /// Remove the original load command `index`. The segments and the symbol tables can't be
+    /// removed, and neither can a dylib if the binding info refers to it or to a later dylib by
+    /// ordinal, since removing it renumbers the ones after it.
+    pub fn remove_load_command(&mut self, index: usize) -> error::Result<()> {
+        let lc = self.macho.load_commands.get(index).ok_or_else(|| {
+            error::Error::Malformed(format!("There is no load command {}", index))
+        })?;
+        match lc.command {
+            CommandVariant::Segment32(_)
+            | CommandVariant::Segment64(_)
+            | CommandVariant::Symtab(_)
+            | CommandVariant::Dysymtab(_) => {
+                return Err(error::Error::Malformed(format!(
+                    "{} can't be removed",
+                    cmd_to_str(lc.command.cmd())
+                )))
+            }
+            _ => {}
+        }
+        if let Some(ordinal) = self.dylib_ordinal(index) {
+            if let Some(referenced) = self.referenced_ordinals().range(ordinal..).next() {
+                return Err(error::Error::Malformed(format!(
+                    "Can't remove {}, the binding info refers to library ordinal {}",
+                    self.macho.libs.get(ordinal).unwrap_or(&"?"),
+                    referenced
+                )));
+            }
+        }
+        self.removed_commands.insert(index);
+        Ok(())
+    }
+
+    /// Remove the `LC_RPATH` for `path`, returning whether there was one
+    pub fn remove_rpath(&mut self, path: &str) -> error::Result<bool> {
+        let index = self.find_command(
+            |lc| match lc.command {
+                CommandVariant::Rpath(cmd) => Some(lc.offset + cmd.path as usize),
+                _ => None,
+            },
+            path,
+        );
+        match index {
+            Some(index) => self.remove_load_command(index).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove the dylib load command for `path`, returning whether there was one
+    pub fn remove_dylib(&mut self, path: &str) -> error::Result<bool> {
+        let index = self.find_command(
+            |lc| match lc.command {
+                CommandVariant::LoadDylib(cmd)
+                | CommandVariant::LoadWeakDylib(cmd)
+                | CommandVariant::ReexportDylib(cmd)
+                | CommandVariant::LazyLoadDylib(cmd)
+                | CommandVariant::LoadUpwardDylib(cmd) => Some(lc.offset + cmd.dylib.name as usize),
+                _ => None,
+            },
+            path,
+        );
+        match index {
+            Some(index) => self.remove_load_command(index).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The index of the first load command which is still there and whose string, found at the
+    /// offset `string` returns, is `value`
+    fn find_command<F: Fn(&LoadCommand) -> Option<usize>>(
+        &self,
+        string: F,
+        value: &str,
+    ) -> Option<usize> {
+        self.macho
+            .load_commands
+            .iter()
+            .enumerate()
+            .position(|(idx, lc)| {
+                !self.removed_commands.contains(&idx)
+                    && string(lc).and_then(|offset| self.bytes.pread::<&str>(offset).ok())
+                        == Some(value)
+            })
+    }
+
+    /// The library ordinal of the load command `index`, if it loads a dylib
+    fn dylib_ordinal(&self, index: usize) -> Option<usize> {
+        let is_dylib = |lc: &LoadCommand| {
+            matches!(
+                lc.command,
+                CommandVariant::LoadDylib(_)
+                    | CommandVariant::LoadWeakDylib(_)
+                    | CommandVariant::ReexportDylib(_)
+                    | CommandVariant::LazyLoadDylib(_)
+                    | CommandVariant::LoadUpwardDylib(_)
+            )
+        };
+        if !is_dylib(self.macho.load_commands.get(index)?) {
+            return None;
+        }
+        Some(
+            self.macho.load_commands[..=index]
+                .iter()
+                .filter(|lc| is_dylib(lc))
+                .count(),
+        )
+    }
+
+    /// The library ordinals the undefined symbols and the bind opcodes refer to
+    fn referenced_ordinals(&self) -> BTreeSet<usize> {
+        let mut ordinals = self
+            .symbols()
+            .filter(|symbol| symbol.is_undefined())
+            .map(|symbol| usize::from(symbol.n_desc >> 8))
+            .filter(|ordinal| (1..=MAX_LIBRARY_ORDINAL).contains(ordinal))
+            .collect::<BTreeSet<_>>();
+        for import in self.macho.imports().unwrap_or_default() {
+            ordinals.extend(
+                self.macho
+                    .libs
+                    .iter()
+                    .enumerate()
+                    .skip(1)
+                    .filter(|(_, lib)| import.dylib == Dylib::Ordinary(lib))
+                    .map(|(ordinal, _)| ordinal),
+            );
+        }
+        ordinals
+    }
+
+    /// The data appended after the last segment of the binary, in file order
+    pub fn appended(&self) -> Vec<Artifact> {
+        overlay::artifacts(self.bytes, self.content_end(), None)
+    }
+
+    /// Choose which kinds of appended data are kept in the built binary. Stripping the
+    /// signature removes the `LC_CODE_SIGNATURE` command; its data stays in `__LINKEDIT`.
+    pub fn set_appended_data(&mut self, appended: AppendedData) {
+        self.appended = appended;
+    }
+
+    /// The end of the load commands or of the last segment, whichever comes later
+    fn content_end(&self) -> usize {
+        let commands =
+            Header::size_with(&self.macho.ctx.container) + self.macho.header.sizeofcmds as usize;
+        self.macho
+            .segments
+            .iter()
+            .map(|segment| segment.fileoff.saturating_add(segment.filesize) as usize)
+            .fold(commands, usize::max)
+            .min(self.bytes.len())
+    }
+
+    /// Whether the original load command `idx` is left out of the built binary
+    fn is_removed(&self, idx: usize) -> bool {
+        self.removed_commands.contains(&idx)
+            || (self.appended.signature == Preserve::Strip
+                && self.macho.load_commands[idx].command.cmd() == LC_CODE_SIGNATURE)
+    }
+
+    /// Run `transform` on the contents of the section called `section` (e.g. `__TEXT,__text`)
+    /// when the binary is built
+    pub fn add_section_transform<T: SectionTransform + 'static>(
+        &mut self,
+        section: &str,
+        transform: T,
+    ) {
+        self.transforms.register(section, transform);
+    }
+
+    /// The 1 based number of the section containing `address`, as used by `n_sect`
+    pub fn section_for_address(&self, address: u64) -> error::Result<Option<u8>> {
+        let mut number = 0;
+        for segment in self.macho.segments.iter() {
+            for (section, _) in segment.sections()? {
+                number += 1;
+                if address >= section.addr && address < section.addr + section.size {
+                    return Ok(u8::try_from(number).ok());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Emit the binary with the edited symbol table
+    pub fn build(&mut self) -> error::Result<Vec<u8>> {
+        let ctx = self.macho.ctx;
+        let le = ctx.le;
+        let (symtab_offset, mut symtab) = self
+            .macho
+            .load_commands
+            .iter()
+            .find_map(|lc| match lc.command {
+                CommandVariant::Symtab(cmd) => Some((lc.offset, cmd)),
+                _ => None,
+            })
+            .ok_or_else(|| error::Error::Malformed("Binary has no LC_SYMTAB".to_string()))?;
+        let dysymtab = self
+            .macho
+            .load_commands
+            .iter()
+            .find_map(|lc| match lc.command {
+                CommandVariant::Dysymtab(cmd) => Some((lc.offset, cmd)),
+                _ => None,
+            });
+
+        // dyld wants the locals first, then the defined externals and the undefined ones, each
+        // sorted by name so it can binary search them
+        let mut locals = Vec::new();
+        let mut extdefs = Vec::new();
+        let mut undefs = Vec::new();
+        for entry in self.symbols.iter() {
+            if entry.1.is_local() {
+                locals.push(entry);
+            } else if entry.1.is_undefined() {
+                undefs.push(entry);
+            } else {
+                extdefs.push(entry);
+            }
+        }
+        extdefs.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        undefs.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        let (nlocals, nextdefs, nundefs) = (locals.len(), extdefs.len(), undefs.len());
+        let ordered = locals
+            .into_iter()
+            .chain(extdefs)
+            .chain(undefs)
+            .collect::<Vec<_>>();
+        let renumbered = ordered
+            .iter()
+            .enumerate()
+            .filter_map(|(new, (old, _))| old.map(|old| (old, new as u32)))
+            .collect::<BTreeMap<_, _>>();
+
+        let artifacts = self.appended();
+        let mut out = self.bytes[..self.content_end()].to_vec();
+        if let Some((_, ref dysymtab)) = dysymtab {
+            self.renumber_indirect_symbols(&mut out, dysymtab, &renumbered)?;
+            self.renumber_external_relocations(&mut out, dysymtab, &renumbered)?;
+        }
+
+        let nlist_size = Nlist::size_with(&ctx);
+        let mut strtab = vec![0u8];
+        let mut offsets = BTreeMap::new();
+        let mut nlists = vec![0u8; nlist_size * ordered.len()];
+        let mut offset = 0;
+        for (_, symbol) in ordered.iter() {
+            let n_strx = if symbol.name.is_empty() {
+                0
+            } else {
+                *offsets.entry(symbol.name.as_str()).or_insert_with(|| {
+                    let at = strtab.len();
+                    strtab.extend_from_slice(symbol.name.as_bytes());
+                    strtab.push(0);
+                    at
+                })
+            };
+            nlists.gwrite_with(symbol.to_nlist(n_strx), &mut offset, ctx)?;
+        }
+        let align = match ctx.container {
+            Container::Little => 4,
+            Container::Big => 8,
+        };
+        strtab.resize(strtab.len().div_ceil(align) * align, 0);
+
+        symtab.symoff = append(&mut out, &nlists, align) as u32;
+        symtab.nsyms = ordered.len() as u32;
+        symtab.stroff = append(&mut out, &strtab, align) as u32;
+        symtab.strsize = strtab.len() as u32;
+        out.pwrite_with::<SymtabCommand>(symtab, symtab_offset, le)?;
+        if let Some((dysymtab_offset, mut dysymtab)) = dysymtab {
+            dysymtab.ilocalsym = 0;
+            dysymtab.nlocalsym = nlocals as u32;
+            dysymtab.iextdefsym = nlocals as u32;
+            dysymtab.nextdefsym = nextdefs as u32;
+            dysymtab.iundefsym = (nlocals + nextdefs) as u32;
+            dysymtab.nundefsym = nundefs as u32;
+            out.pwrite_with::<DysymtabCommand>(dysymtab, dysymtab_offset, le)?;
+        }
+        self.transform_sections(&mut out)?;
+        self.grow_linkedit(&mut out, ctx)?;
+        self.rewrite_load_commands(&mut out)?;
+        overlay::append_artifacts(&mut out, self.bytes, &artifacts, &self.appended)?;
+        Ok(out)
+    }
+
+    /// Write the kept and the added load commands over the original ones. This goes last, as
+    /// everything before it patches the commands at their original offsets.
+    fn rewrite_load_commands(&self, out: &mut [u8]) -> error::Result<()> {
+        let removed = (0..self.macho.load_commands.len()).any(|idx| self.is_removed(idx));
+        if self.added_commands.is_empty() && !removed {
+            return Ok(());
+        }
+        // makes sure the added commands still fit
+        self.headroom()?;
+        let le = self.macho.ctx.le;
+        let mut commands = Vec::with_capacity(self.macho.header.sizeofcmds as usize);
+        let mut ncmds = 0u32;
+        for (idx, lc) in self.macho.load_commands.iter().enumerate() {
+            if !self.is_removed(idx) {
+                commands.extend_from_slice(&out[lc.offset..lc.offset + lc.command.cmdsize()]);
+                ncmds += 1;
+            }
+        }
+        for command in self.added_commands.iter() {
+            commands.extend_from_slice(command);
+            ncmds += 1;
+        }
+        let start = Header::size_with(&self.macho.ctx.container);
+        let old_end = start + self.macho.header.sizeofcmds as usize;
+        out[start..start + commands.len()].copy_from_slice(&commands);
+        if start + commands.len() < old_end {
+            out[start + commands.len()..old_end].fill(0);
+        }
+        // ncmds and sizeofcmds follow the magic, the cpu type and subtype and the file type
+        out.pwrite_with(ncmds, 16, le)?;
+        out.pwrite_with(commands.len() as u32, 20, le)?;
+        Ok(())
+    }
+
+    fn transform_sections(&mut self, out: &mut [u8]) -> error::Result<()> {
+        if self.transforms.is_empty() {
+            return Ok(());
+        }
+        let le = self.macho.ctx.le;
+        for lc in self.macho.load_commands.iter() {
+            let (mut offset, nsects, is_64) = match lc.command {
+                CommandVariant::Segment32(segment) => {
+                    (lc.offset + SIZEOF_SEGMENT_COMMAND_32, segment.nsects, false)
+                }
+                CommandVariant::Segment64(segment) => {
+                    (lc.offset + SIZEOF_SEGMENT_COMMAND_64, segment.nsects, true)
+                }
+                _ => continue,
+            };
+            for _ in 0..nsects {
+                let header_offset = offset;
+                let section: Section = if is_64 {
+                    out.gread_with::<Section64>(&mut offset, le)?.into()
+                } else {
+                    out.gread_with::<Section32>(&mut offset, le)?.into()
+                };
+                if section.is_zerofill() {
+                    continue;
+                }
+                let name = format!("{},{}", section.segname()?, section.name()?);
+                let start = section.offset as usize;
+                let end = usize::try_from(section.size)
+                    .ok()
+                    .and_then(|size| start.checked_add(size))
+                    .ok_or_else(|| {
+                        error::Error::Malformed(format!(
+                            "Section {} of {:#x} bytes at {:#x} runs past the end of the file",
+                            name, section.size, start
+                        ))
+                    })?;
+                let range = start..end;
+                let section_ref = SectionRef {
+                    name: &name,
+                    address: section.addr,
+                    offset: range.start,
+                    loaded: true,
+                    file: out,
+                };
+                let data = match self.transforms.apply(&section_ref, range.clone())? {
+                    Some(data) => data,
+                    None => continue,
+                };
+                out[range.start..range.start + data.len()].copy_from_slice(&data);
+                out[range.start + data.len()..range.end].fill(0);
+                if data.len() == range.len() {
+                    continue;
+                }
+                if is_64 {
+                    let mut header = out.pread_with::<Section64>(header_offset, le)?;
+                    header.size = data.len() as u64;
+                    out.pwrite_with(header, header_offset, le)?;
+                } else {
+                    let mut header = out.pread_with::<Section32>(header_offset, le)?;
+                    header.size = data.len() as u32;
+                    out.pwrite_with(header, header_offset, le)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn renumber_indirect_symbols(
+        &self,
+        out: &mut [u8],
+        dysymtab: &DysymtabCommand,
+        renumbered: &BTreeMap<usize, u32>,
+    ) -> error::Result<()> {
+        let le = self.macho.ctx.le;
+        for i in 0..dysymtab.nindirectsyms as usize {
+            let offset = dysymtab.indirectsymoff as usize + i * 4;
+            let index = self.bytes.pread_with::<u32>(offset, le)?;
+            if index & (INDIRECT_SYMBOL_LOCAL | INDIRECT_SYMBOL_ABS) != 0 {
+                continue;
+            }
+            out.pwrite_with(self.renumber(index as usize, renumbered)?, offset, le)?;
+        }
+        Ok(())
+    }
+
+    fn renumber_external_relocations(
+        &self,
+        out: &mut [u8],
+        dysymtab: &DysymtabCommand,
+        renumbered: &BTreeMap<usize, u32>,
+    ) -> error::Result<()> {
+        let le = self.macho.ctx.le;
+        for i in 0..dysymtab.nextrel as usize {
+            let offset = dysymtab.extreloff as usize + i * SIZEOF_RELOCATION_INFO;
+            let mut reloc = self.bytes.pread_with::<RelocationInfo>(offset, le)?;
+            if !reloc.is_extern() {
+                continue;
+            }
+            let index = self.renumber(reloc.r_symbolnum(), renumbered)?;
+            reloc.r_info = (reloc.r_info & 0xff00_0000) | index;
+            out.pwrite_with(reloc, offset, le)?;
+        }
+        Ok(())
+    }
+
+    fn renumber(&self, old: usize, renumbered: &BTreeMap<usize, u32>) -> error::Result<u32> {
+        renumbered.get(&old).copied().ok_or_else(|| {
+            let name = self
+                .macho
+                .symbols()
+                .nth(old)
+                .and_then(|symbol| symbol.ok())
+                .map(|(name, _)| name)
+                .unwrap_or("?");
+            error::Error::Malformed(format!(
+                "Symbol {} ({}) is still referenced and cannot be stripped",
+                old, name
+            ))
+        })
+    }
+
+    /// Make `__LINKEDIT` cover everything up to the (grown) end of the file
+    fn grow_linkedit(&self, out: &mut [u8], ctx: container::Ctx) -> error::Result<()> {
+        let page_size = if self.macho.header.cputype == CPU_TYPE_ARM64 {
+            0x4000
+        } else {
+            0x1000
+        };
+        let end = out.len();
+        for lc in self.macho.load_commands.iter() {
+            match lc.command {
+                CommandVariant::Segment32(mut segment) if segment.name()? == "__LINKEDIT" => {
+                    let (filesize, vmsize) =
+                        linkedit_size(end, u64::from(segment.fileoff), page_size)?;
+                    let too_big = |_| {
+                        error::Error::Malformed(format!(
+                            "__LINKEDIT of {:#x} bytes doesn't fit a 32 bit segment",
+                            vmsize
+                        ))
+                    };
+                    segment.filesize = u32::try_from(filesize).map_err(too_big)?;
+                    segment.vmsize = segment.vmsize.max(u32::try_from(vmsize).map_err(too_big)?);
+                    out.pwrite_with(segment, lc.offset, ctx.le)?;
+                }
+                CommandVariant::Segment64(mut segment) if segment.name()? == "__LINKEDIT" => {
+                    let (filesize, vmsize) = linkedit_size(end, segment.fileoff, page_size)?;
+                    segment.filesize = filesize;
+                    segment.vmsize = segment.vmsize.max(vmsize);
+                    out.pwrite_with(segment, lc.offset, ctx.le)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file size of a `__LINKEDIT` at `fileoff` running to `end`, and the memory size it takes in
+/// pages of `page_size`
+fn linkedit_size(end: usize, fileoff: u64, page_size: u64) -> error::Result<(u64, u64)> {
+    let filesize = (end as u64).checked_sub(fileoff).ok_or_else(|| {
+        error::Error::Malformed(format!(
+            "__LINKEDIT at {:#x} starts past the end of the file at {:#x}",
+            fileoff, end
+        ))
+    })?;
+    Ok((filesize, filesize.div_ceil(page_size) * page_size))
+}
+
+/// Append `data` to `out` at the next `align` boundary and return the offset it was written at
+fn append(out: &mut Vec<u8>, data: &[u8], align: usize) -> usize {
+    let offset = out.len().div_ceil(align) * align;
+    out.resize(offset, 0);
+    out.extend_from_slice(data);
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mach::{
+        constants::cputype::CPU_TYPE_X86_64,
+        header::{Header64, MH_EXECUTE, MH_MAGIC_64, SIZEOF_HEADER_64},
+        load_command::{
+            LC_SEGMENT_64, SIZEOF_DYSYMTAB_COMMAND, SIZEOF_SECTION_64, SIZEOF_SEGMENT_COMMAND_64,
+            SIZEOF_SYMTAB_COMMAND,
+        },
+        symbols::NO_SECT,
+    };
+    use scroll::LE;
+
+    const LINKEDIT: usize = 0x200;
+
+    fn name16(name: &str) -> [u8; 16] {
+        let mut ret = [0u8; 16];
+        ret[..name.len()].copy_from_slice(name.as_bytes());
+        ret
+    }
+
+    fn segment(bytes: &mut [u8], offset: &mut usize, name: &str, vmaddr: u64, fileoff: u64) {
+        let nsects = u32::from(name == "__TEXT");
+        bytes.gwrite_with(LC_SEGMENT_64, offset, LE).unwrap();
+        let cmdsize = SIZEOF_SEGMENT_COMMAND_64 + nsects as usize * SIZEOF_SECTION_64;
+        bytes.gwrite_with(cmdsize as u32, offset, LE).unwrap();
+        bytes.gwrite(&name16(name)[..], offset).unwrap();
+        for value in [vmaddr, 0x1000, fileoff, 0x200] {
+            bytes.gwrite_with(value, offset, LE).unwrap();
+        }
+        for value in [7u32, 5, nsects, 0] {
+            bytes.gwrite_with(value, offset, LE).unwrap();
+        }
+        if nsects == 1 {
+            bytes.gwrite(&name16("__text")[..], offset).unwrap();
+            bytes.gwrite(&name16("__TEXT")[..], offset).unwrap();
+            bytes.gwrite_with(0x11e0u64, offset, LE).unwrap();
+            bytes.gwrite_with(0x20u64, offset, LE).unwrap();
+            for value in [0x1e0u32, 0, 0, 0, 0, 0, 0, 0] {
+                bytes.gwrite_with(value, offset, LE).unwrap();
+            }
+        }
+    }
+
+    /// A minimal executable with a local, a defined and an undefined symbol, where the undefined
+    /// one is referenced from the indirect symbol table
+    fn executable() -> Vec<u8> {
+        let mut bytes = vec![0u8; 0x400];
+        let sizeofcmds = 2 * SIZEOF_SEGMENT_COMMAND_64
+            + SIZEOF_SECTION_64
+            + SIZEOF_SYMTAB_COMMAND
+            + SIZEOF_DYSYMTAB_COMMAND;
+        let header = Header64 {
+            magic: MH_MAGIC_64,
+            cputype: CPU_TYPE_X86_64,
+            filetype: MH_EXECUTE,
+            ncmds: 4,
+            sizeofcmds: sizeofcmds as u32,
+            ..Default::default()
+        };
+        bytes.pwrite_with(header, 0, LE).unwrap();
+        let mut offset = SIZEOF_HEADER_64;
+        segment(&mut bytes, &mut offset, "__TEXT", 0x1000, 0);
+        segment(
+            &mut bytes,
+            &mut offset,
+            "__LINKEDIT",
+            0x2000,
+            LINKEDIT as u64,
+        );
+
+        let strtab = b"\0_helper\0_main\0_printf\0\0";
+        let symtab = SymtabCommand {
+            symoff: LINKEDIT as u32 + 8,
+            nsyms: 3,
+            stroff: LINKEDIT as u32 + 8 + 3 * 16,
+            strsize: strtab.len() as u32,
+            ..Default::default()
+        };
+        bytes.gwrite_with(symtab, &mut offset, LE).unwrap();
+        let dysymtab = DysymtabCommand {
+            ilocalsym: 0,
+            nlocalsym: 1,
+            iextdefsym: 1,
+            nextdefsym: 1,
+            iundefsym: 2,
+            nundefsym: 1,
+            indirectsymoff: LINKEDIT as u32,
+            nindirectsyms: 2,
+            ..Default::default()
+        };
+        bytes.gwrite_with(dysymtab, &mut offset, LE).unwrap();
+
+        let mut offset = LINKEDIT;
+        bytes.gwrite_with(2u32, &mut offset, LE).unwrap();
+        bytes
+            .gwrite_with(INDIRECT_SYMBOL_LOCAL, &mut offset, LE)
+            .unwrap();
+        let ctx = container::Ctx::new(Container::Big, LE);
+        let nlists = [
+            (1, N_SECT, 1, 0x11e0),
+            (9, N_SECT | N_EXT, 1, 0x11f0),
+            (15, N_UNDF | N_EXT, NO_SECT, 0),
+        ];
+        for (n_strx, n_type, n_sect, n_value) in nlists {
+            let nlist = Nlist {
+                n_strx,
+                n_type,
+                n_sect: usize::from(n_sect),
+                n_desc: 0,
+                n_value,
+            };
+            bytes.gwrite_with(nlist, &mut offset, ctx).unwrap();
+        }
+        bytes.gwrite(&strtab[..], &mut offset).unwrap();
+        bytes
+    }
+
+    fn names(macho: &MachO) -> Vec<String> {
+        macho
+            .symbols()
+            .map(|symbol| symbol.unwrap().0.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn linkedit_past_the_end() {
+        let mut bytes = executable();
+        // an empty __LINKEDIT which starts past the end of the file
+        let fileoff = SIZEOF_HEADER_64 + SIZEOF_SEGMENT_COMMAND_64 + SIZEOF_SECTION_64 + 40;
+        bytes.pwrite_with(0x10_0000u64, fileoff, LE).unwrap();
+        bytes.pwrite_with(0u64, fileoff + 8, LE).unwrap();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer.rename_symbol("_helper", "_parse_args");
+        assert!(matches!(writer.build(), Err(error::Error::Malformed(_))));
+    }
+
+    #[test]
+    fn resymbolicate() {
+        let bytes = executable();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        assert_eq!(names(writer.macho()), ["_helper", "_main", "_printf"]);
+        writer.add_function("_checksum", 0x11f8).unwrap();
+        assert!(writer.add_function("_nowhere", 0x9000).is_err());
+        assert_eq!(writer.rename_symbol("_helper", "_parse_args"), 1);
+        let out = writer.build().unwrap();
+
+        let macho = MachO::parse(&out, 0).unwrap();
+        assert_eq!(
+            names(&macho),
+            ["_parse_args", "_checksum", "_main", "_printf"]
+        );
+        let dysymtab = macho
+            .load_commands
+            .iter()
+            .find_map(|lc| match lc.command {
+                CommandVariant::Dysymtab(cmd) => Some(cmd),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(dysymtab.nlocalsym, 1);
+        assert_eq!(dysymtab.nextdefsym, 2);
+        assert_eq!(dysymtab.iundefsym, 3);
+        // the stub for _printf follows the symbol to its new index
+        assert_eq!(out.pread_with::<u32>(LINKEDIT, LE).unwrap(), 3);
+        assert_eq!(
+            out.pread_with::<u32>(LINKEDIT + 4, LE).unwrap(),
+            INDIRECT_SYMBOL_LOCAL
+        );
+        let linkedit = macho
+            .segments
+            .iter()
+            .find(|s| s.name().unwrap() == "__LINKEDIT");
+        assert_eq!(linkedit.unwrap().filesize as usize, out.len() - LINKEDIT);
+    }
+
+    #[test]
+    fn referenced_symbols_stay() {
+        let bytes = executable();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        assert_eq!(writer.strip_symbol("_printf"), 1);
+        assert!(writer.build().is_err());
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer.retain_symbols(|symbol| !symbol.is_local());
+        let out = writer.build().unwrap();
+        assert_eq!(names(&MachO::parse(&out, 0).unwrap()), ["_main", "_printf"]);
+        assert_eq!(out.pread_with::<u32>(LINKEDIT, LE).unwrap(), 1);
+    }
+
+    #[test]
+    fn section_transforms() {
+        let bytes = executable();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer.add_section_transform(
+            "__TEXT,__text",
+            |section: &SectionRef, data: &mut Vec<u8>| {
+                assert_eq!(section.address, 0x11e0);
+                data.truncate(0x10);
+                data.iter_mut().for_each(|b| *b = 0xcc);
+                Ok(())
+            },
+        );
+        let out = writer.build().unwrap();
+        let macho = MachO::parse(&out, 0).unwrap();
+        let (text, data) = macho.segments[0].sections().unwrap().remove(0);
+        assert_eq!(text.size, 0x10);
+        assert_eq!(data, &[0xcc; 0x10][..]);
+        assert_eq!(out[0x1f0..0x200], [0; 0x10]);
+
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer.add_section_transform("__TEXT,__text", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.push(0x90);
+            Ok(())
+        });
+        assert!(writer.build().is_err());
+
+        // a size the offset of __text can't be added to
+        let mut bytes = bytes.clone();
+        let size = SIZEOF_HEADER_64 + SIZEOF_SEGMENT_COMMAND_64 + 40;
+        bytes.pwrite_with(u64::MAX, size, LE).unwrap();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer.add_section_transform("__TEXT,__text", |_: &SectionRef, _: &mut Vec<u8>| Ok(()));
+        assert!(matches!(writer.build(), Err(error::Error::Malformed(_))));
+    }
+
+    fn libs(out: &[u8]) -> (Vec<String>, Vec<String>) {
+        let macho = MachO::parse(out, 0).unwrap();
+        let libs = macho.libs[1..].iter().map(|lib| lib.to_string()).collect();
+        let rpaths = macho.rpaths.iter().map(|path| path.to_string()).collect();
+        (libs, rpaths)
+    }
+
+    #[test]
+    fn appended_data() {
+        let mut bytes = executable();
+        let end = bytes.len();
+        bytes.extend_from_slice(b"appended payload");
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        assert_eq!(writer.appended()[0].range, end..bytes.len());
+        let mut signature = Vec::new();
+        for value in [LC_CODE_SIGNATURE, 16, LINKEDIT as u32, 0x10] {
+            signature.extend_from_slice(&value.to_le_bytes());
+        }
+        writer.add_load_command(signature).unwrap();
+        let out = writer.build().unwrap();
+        assert!(out.ends_with(b"appended payload"));
+        let signed = |bytes: &[u8]| {
+            MachO::parse(bytes, 0)
+                .unwrap()
+                .load_commands
+                .iter()
+                .any(|lc| lc.command.cmd() == LC_CODE_SIGNATURE)
+        };
+        assert!(signed(&out));
+
+        let mut writer = MachOWriter::new(&out).unwrap();
+        writer.set_appended_data(AppendedData {
+            signature: Preserve::Strip,
+            ..Default::default()
+        });
+        let stripped = writer.build().unwrap();
+        assert!(!signed(&stripped));
+        assert!(stripped.ends_with(b"appended payload"));
+        writer.set_appended_data(AppendedData::strip_all());
+        assert!(!writer.build().unwrap().ends_with(b"appended payload"));
+    }
+
+    #[test]
+    fn add_and_remove_load_commands() {
+        let bytes = executable();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        assert_eq!(writer.headroom().unwrap(), 0x78);
+        writer.add_dylib("/usr/lib/libz.1.dylib", false).unwrap();
+        writer.add_rpath("@loader_path").unwrap();
+        assert_eq!(writer.headroom().unwrap(), 0x78 - 0x30 - 0x20);
+        assert!(writer.add_dylib("/usr/lib/libc++.1.dylib", true).is_err());
+        assert!(writer.remove_load_command(0).is_err());
+        let out = writer.build().unwrap();
+        assert_eq!(
+            libs(&out),
+            (
+                vec!["/usr/lib/libz.1.dylib".to_string()],
+                vec!["@loader_path".to_string()]
+            )
+        );
+        // the section contents didn't move
+        assert_eq!(out[0x1e0..0x200], bytes[0x1e0..0x200]);
+
+        let mut writer = MachOWriter::new(&out).unwrap();
+        assert!(writer.remove_rpath("@loader_path").unwrap());
+        assert!(!writer.remove_rpath("@loader_path").unwrap());
+        assert!(writer.remove_dylib("/usr/lib/libz.1.dylib").unwrap());
+        let out = writer.build().unwrap();
+        assert_eq!(libs(&out), (vec![], vec![]));
+        let macho = MachO::parse(&out, 0).unwrap();
+        assert_eq!(macho.header.ncmds, 4);
+        assert_eq!(
+            macho.header.sizeofcmds,
+            bytes.pread_with::<u32>(20, LE).unwrap()
+        );
+    }
+
+    #[test]
+    fn referenced_dylibs_stay() {
+        let mut bytes = executable();
+        // bind _printf to the first library through the ordinal in its n_desc
+        bytes
+            .pwrite_with(1u16 << 8, LINKEDIT + 8 + 2 * 16 + 6, LE)
+            .unwrap();
+        let mut writer = MachOWriter::new(&bytes).unwrap();
+        writer
+            .add_dylib("/usr/lib/libSystem.B.dylib", false)
+            .unwrap();
+        let out = writer.build().unwrap();
+        let mut writer = MachOWriter::new(&out).unwrap();
+        assert!(writer.remove_dylib("/usr/lib/libSystem.B.dylib").is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Map files and linker scripts describing a workspace, for firmware and re-linking work which
+//! wants the layout analysis found in the form a toolchain reads:
+//!
+//! * [`map_file`], a classic `.map`: the sections with their address and length, then each
+//!   symbol with its address, size and section,
+//! * [`linker_script`], a GNU ld fragment: a `MEMORY` region per memory map, a `SECTIONS`
+//!   statement placing each section at its address in the region holding it, and the symbols
+//!   as absolute assignments.
+//!
+//! The sections are the segments of the workspace, the regions its memory maps (see
+//! [`crate::layout::from_workspace`]). The symbols are its functions, named or not, and the
+//! other names; a function's size is the size analysis gave it, anything else the size of the
+//! location at it.
+
+use crate::{
+    constants::{MM_EXEC, MM_READ, MM_WRITE},
+    layout::{self, Region, Source},
+    naming::{auto_name, AutoKind},
+    workspace::VivWorkspace,
+};
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub va: u64,
+    pub size: u64,
+    /// The section holding the symbol, if any does
+    pub section: Option<String>,
+    pub is_function: bool,
+}
+
+fn containing(regions: &[Region], va: u64) -> Option<&Region> {
+    regions
+        .iter()
+        .find(|region| region.va <= va && va < region.end())
+}
+
+/// The sections and memory regions of the workspace, by address
+fn regions(workspace: &mut VivWorkspace) -> (Vec<Region>, Vec<Region>) {
+    let (mut sections, mut maps): (Vec<_>, Vec<_>) = layout::from_workspace(workspace)
+        .regions
+        .into_iter()
+        .filter(|region| region.source != Source::Header)
+        .partition(|region| region.source == Source::Segment);
+    sections.sort_by_key(|region| region.va);
+    maps.sort_by_key(|region| region.va);
+    (sections, maps)
+}
+
+/// The symbols of the workspace, by address
+pub fn symbols(workspace: &mut VivWorkspace) -> Vec<Symbol> {
+    let (sections, _) = regions(workspace);
+    let ann = workspace.get_annotations();
+    let mut vas: Vec<i32> = ann
+        .functions
+        .keys()
+        .chain(ann.names.keys())
+        .copied()
+        .collect();
+    vas.sort_by_key(|va| *va as u32);
+    vas.dedup();
+    vas.into_iter()
+        .map(|va| {
+            let function = ann.functions.get(&va);
+            let name = match ann.names.get(&va) {
+                Some(name) => name.clone(),
+                None => auto_name(AutoKind::Function, va),
+            };
+            let size = match function {
+                Some(size) => *size,
+                None => workspace.get_location(va).map_or(0, |loc| loc.1),
+            };
+            let va = va as u32 as u64;
+            Symbol {
+                name,
+                va,
+                size: size as u32 as u64,
+                section: containing(&sections, va).map(|section| section.name.clone()),
+                is_function: function.is_some(),
+            }
+        })
+        .collect()
+}
+
+/// A classic `.map` file of the sections and symbols of the workspace
+pub fn map_file(workspace: &mut VivWorkspace) -> String {
+    let (sections, _) = regions(workspace);
+    let symbols = symbols(workspace);
+    let mut out = String::new();
+    let _ = writeln!(out, " Start       Length      Name");
+    for section in sections.iter() {
+        let _ = writeln!(
+            out,
+            " 0x{:08x}  0x{:08x}  {}",
+            section.va, section.size, section.name
+        );
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(out, " Address     Size        Section         Symbol");
+    for symbol in symbols.iter() {
+        let _ = writeln!(
+            out,
+            " 0x{:08x}  0x{:08x}  {:<14}  {}",
+            symbol.va,
+            symbol.size,
+            symbol.section.as_deref().unwrap_or("*ABS*"),
+            symbol.name
+        );
+    }
+    out
+}
+
+/// A name ld takes bare, quoting it otherwise
+fn script_name(name: &str) -> String {
+    let bare = name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || matches!(c, '_' | '.' | '$') || (i > 0 && c.is_ascii_digit())
+    });
+    if bare && !name.is_empty() {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', ""))
+    }
+}
+
+/// The `MEMORY` region name of a memory map: its file name, made an identifier
+fn memory_name(region: &Region, i: usize) -> String {
+    let name: String = region
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_{}", name.trim_matches('_'), i)
+        .trim_start_matches('_')
+        .to_string()
+}
+
+/// A GNU linker script fragment placing the sections and symbols of the workspace where they
+/// were found
+pub fn linker_script(workspace: &mut VivWorkspace) -> String {
+    let (sections, maps) = regions(workspace);
+    let symbols = symbols(workspace);
+    let mut out = String::new();
+    let names: Vec<String> = maps
+        .iter()
+        .enumerate()
+        .map(|(i, map)| memory_name(map, i))
+        .collect();
+    let _ = writeln!(out, "MEMORY\n{{");
+    for (map, name) in maps.iter().zip(names.iter()) {
+        let perms = map.perms.unwrap_or(0);
+        let attrs: String = [(MM_READ, 'r'), (MM_WRITE, 'w'), (MM_EXEC, 'x')]
+            .iter()
+            .filter(|(perm, _)| perms & perm != 0)
+            .map(|(_, c)| *c)
+            .collect();
+        let _ = writeln!(
+            out,
+            "  {} ({}) : ORIGIN = {:#x}, LENGTH = {:#x}",
+            name, attrs, map.va, map.size
+        );
+    }
+    let _ = writeln!(out, "}}\n\nSECTIONS\n{{");
+    for section in sections.iter() {
+        let name = script_name(&section.name);
+        let _ = write!(out, "  {} {:#x} : {{ *({}) }}", name, section.va, name);
+        match maps
+            .iter()
+            .position(|map| map.va <= section.va && section.va < map.end())
+        {
+            Some(i) => {
+                let _ = writeln!(out, " > {}", names[i]);
+            }
+            None => {
+                let _ = writeln!(out);
+            }
+        }
+    }
+    let _ = writeln!(out, "}}\n");
+    for symbol in symbols.iter() {
+        let _ = writeln!(out, "{} = {:#x};", script_name(&symbol.name), symbol.va);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::Memory, storage::Annotations};
+
+    #[test]
+    fn map_and_script() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x8000, MM_READ | MM_EXEC, "fw.bin", vec![0; 0x2000], None);
+        ws.add_memory_map(
+            0x2000_0000,
+            MM_READ | MM_WRITE,
+            "ram",
+            vec![0; 0x1000],
+            None,
+        );
+        ws.add_segment(0x8000, 0x1800, ".text", "fw.bin".to_string());
+        ws.add_segment(0x2000_0000, 0x400, ".data", "fw.bin".to_string());
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x8000, 0x40), (0x8040, 0x20)]);
+        ann.names.insert(0x8000, "reset_handler".to_string());
+        ann.names.insert(0x2000_0010, "g state".to_string());
+        ws.apply_annotations(&ann);
+
+        let symbols: Vec<_> = symbols(&mut ws)
+            .into_iter()
+            .map(|s| (s.name, s.va, s.size, s.section))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                (
+                    "reset_handler".to_string(),
+                    0x8000,
+                    0x40,
+                    Some(".text".to_string())
+                ),
+                (
+                    "sub_8040".to_string(),
+                    0x8040,
+                    0x20,
+                    Some(".text".to_string())
+                ),
+                (
+                    "g state".to_string(),
+                    0x2000_0010,
+                    0,
+                    Some(".data".to_string())
+                ),
+            ]
+        );
+
+        let map = map_file(&mut ws);
+        assert!(map.contains(" 0x00008000  0x00001800  .text\n"));
+        assert!(map.contains(" 0x00008040  0x00000020  .text           sub_8040\n"));
+
+        let script = linker_script(&mut ws);
+        assert!(script.contains("  fw_bin_0 (rx) : ORIGIN = 0x8000, LENGTH = 0x2000\n"));
+        assert!(script.contains("  ram_1 (rw) : ORIGIN = 0x20000000, LENGTH = 0x1000\n"));
+        assert!(script.contains("  .data 0x20000000 : { *(.data) } > ram_1\n"));
+        assert!(script.contains("reset_handler = 0x8000;\n"));
+        assert!(script.contains("\"g state\" = 0x20000010;\n"));
+    }
+}
@@ -3,7 +3,7 @@
 use crate::{
     constants::{MM_EXEC, MM_READ, MM_SHARED, MM_WRITE},
     emulator::GenericEmulator,
-    utils::parse_bytes,
+    utils::{build_bytes, parse_bytes},
 };
 use log::{debug, info, warn};
 use std::{cmp::min, collections::HashMap};
@@ -84,7 +84,7 @@ pub trait Memory {
 
     /// Write a number from memory of the given size.
     fn write_mem_value(&mut self, addr: i32, val: i32, size: i32) {
-        let bytes = val.to_ne_bytes().to_vec()[..size as usize].to_vec();
+        let bytes = build_bytes(val as u32 as u64, size as usize, self.get_endian());
         self.write_memory(addr, bytes);
     }
 
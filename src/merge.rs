@@ -0,0 +1,248 @@
+//! Three-way merging of workspace annotations, so two analysts working from the same starting
+//! snapshot can combine their names, comments, types, tags, function boundaries and region
+//! overrides.
+
+use crate::storage::Annotations;
+use std::{collections::BTreeMap, fmt, io, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnotationKind {
+    Name,
+    Comment,
+    Type,
+    Tag,
+    Function,
+    Region,
+}
+
+/// Both sides changed the same annotation in different ways. The merged snapshot keeps "ours".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub kind: AnnotationKind,
+    pub va: i32,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} conflict at {:#x}: base {:?}, ours {:?}, theirs {:?}",
+            self.kind, self.va, self.base, self.ours, self.theirs
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MergeResult {
+    pub merged: Annotations,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merge the annotations of `ours` and `theirs`, which were both derived from `base`.
+/// A change made on only one side is taken as is (including deletions); identical changes on
+/// both sides are taken once; anything else is reported as a conflict.
+pub fn merge_annotations(
+    base: &Annotations,
+    ours: &Annotations,
+    theirs: &Annotations,
+) -> MergeResult {
+    let mut result = MergeResult::default();
+    result.merged.names = merge_map(
+        AnnotationKind::Name,
+        &base.names,
+        &ours.names,
+        &theirs.names,
+        &mut result.conflicts,
+    );
+    result.merged.comments = merge_map(
+        AnnotationKind::Comment,
+        &base.comments,
+        &ours.comments,
+        &theirs.comments,
+        &mut result.conflicts,
+    );
+    result.merged.types = merge_map(
+        AnnotationKind::Type,
+        &base.types,
+        &ours.types,
+        &theirs.types,
+        &mut result.conflicts,
+    );
+    result.merged.tags = merge_map(
+        AnnotationKind::Tag,
+        &base.tags,
+        &ours.tags,
+        &theirs.tags,
+        &mut result.conflicts,
+    );
+    result.merged.functions = merge_map(
+        AnnotationKind::Function,
+        &base.functions,
+        &ours.functions,
+        &theirs.functions,
+        &mut result.conflicts,
+    );
+    result.merged.bounds = merge_map(
+        AnnotationKind::Function,
+        &base.bounds,
+        &ours.bounds,
+        &theirs.bounds,
+        &mut result.conflicts,
+    );
+    result.merged.regions = merge_map(
+        AnnotationKind::Region,
+        &base.regions,
+        &ours.regions,
+        &theirs.regions,
+        &mut result.conflicts,
+    );
+    // the runs of both sides, ours first
+    result.merged.audit = ours.audit.clone();
+    for record in theirs.audit.iter() {
+        if !ours.audit.contains(record) {
+            result.merged.audit.push(record.clone());
+        }
+    }
+    result
+}
+
+/// Convenience wrapper around `merge_annotations` for snapshot files on disk.
+pub fn merge_files<P: AsRef<Path>>(base: P, ours: P, theirs: P) -> io::Result<MergeResult> {
+    let base = Annotations::load(base)?;
+    let ours = Annotations::load(ours)?;
+    let theirs = Annotations::load(theirs)?;
+    Ok(merge_annotations(&base, &ours, &theirs))
+}
+
+fn merge_map<V: Clone + PartialEq + fmt::Debug>(
+    kind: AnnotationKind,
+    base: &BTreeMap<i32, V>,
+    ours: &BTreeMap<i32, V>,
+    theirs: &BTreeMap<i32, V>,
+    conflicts: &mut Vec<MergeConflict>,
+) -> BTreeMap<i32, V> {
+    let mut vas = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect::<Vec<_>>();
+    vas.sort();
+    vas.dedup();
+    let mut merged = BTreeMap::new();
+    for va in vas {
+        let b = base.get(va);
+        let o = ours.get(va);
+        let t = theirs.get(va);
+        let value = if o == t || t == b {
+            o
+        } else if o == b {
+            t
+        } else {
+            conflicts.push(MergeConflict {
+                kind,
+                va: *va,
+                base: b.map(|x| format!("{:?}", x)),
+                ours: o.map(|x| format!("{:?}", x)),
+                theirs: t.map(|x| format!("{:?}", x)),
+            });
+            o
+        };
+        if let Some(value) = value {
+            merged.insert(*va, value.clone());
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[(i32, &str)]) -> Annotations {
+        let mut ret = Annotations::new();
+        for (va, name) in entries {
+            ret.names.insert(*va, name.to_string());
+        }
+        ret
+    }
+
+    #[test]
+    fn one_sided_changes_merge_cleanly() {
+        let base = names(&[(0x1000, "sub_1000"), (0x2000, "sub_2000")]);
+        let ours = names(&[(0x1000, "parse_header"), (0x2000, "sub_2000")]);
+        let mut theirs = names(&[(0x1000, "sub_1000")]);
+        theirs
+            .comments
+            .insert(0x1004, "checks the magic".to_string());
+        let result = merge_annotations(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        assert_eq!(result.merged.names.get(&0x1000).unwrap(), "parse_header");
+        // deleted on their side, untouched on ours
+        assert!(!result.merged.names.contains_key(&0x2000));
+        assert_eq!(result.merged.comments.len(), 1);
+    }
+
+    #[test]
+    fn divergent_changes_conflict() {
+        let base = names(&[(0x1000, "sub_1000")]);
+        let ours = names(&[(0x1000, "parse_header")]);
+        let theirs = names(&[(0x1000, "read_header")]);
+        let result = merge_annotations(&base, &ours, &theirs);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].kind, AnnotationKind::Name);
+        assert_eq!(result.merged.names.get(&0x1000).unwrap(), "parse_header");
+    }
+
+    #[test]
+    fn keeps_function_bounds() {
+        use crate::workspace::VivWorkspace;
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x1000, vec![(0x1000, 0x10), (0x5000, 0x10)]);
+        ws.add_function(0x2000, vec![(0x2000, 0x10)]);
+        let base = ws.get_annotations();
+        ws.make_name(0x1000, "parse_header".to_string(), false, false);
+        let mut theirs = base.clone();
+        theirs
+            .comments
+            .insert(0x1004, "checks the magic".to_string());
+        theirs
+            .bounds
+            .insert(0x2000, vec![(0x2000, 0x10), (0x6000, 0x8)]);
+
+        let result = merge_annotations(&base, &ws.get_annotations(), &theirs);
+        assert!(result.is_clean());
+        assert_eq!(result.merged.bounds.len(), 2);
+        assert!(ws.merge_annotations(&base, &theirs).is_empty());
+        assert_eq!(
+            ws.get_function_bounds(0x1000),
+            Some(vec![(0x1000, 0x10), (0x5000, 0x10)])
+        );
+        assert_eq!(
+            ws.get_function_bounds(0x2000),
+            Some(vec![(0x2000, 0x10), (0x6000, 0x8)])
+        );
+        assert_eq!(ws.get_functions_containing(0x5004), [0x1000]);
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut ann = names(&[(0x1000, "with space\tand tab")]);
+        ann.comments
+            .insert(-16, "multi\nline \\ comment".to_string());
+        ann.functions.insert(0x1000, 0x40);
+        let mut buf = Vec::new();
+        ann.write(&mut buf).unwrap();
+        let parsed = Annotations::read(buf.as_slice()).unwrap();
+        assert_eq!(ann, parsed);
+    }
+}
@@ -0,0 +1,256 @@
+//! Per-function metrics for triage.
+//!
+//! [`measure`] sizes up a function of a workspace: its bytes and blocks, its cyclomatic
+//! complexity, how many functions call it and how many it calls, and how many strings and other
+//! data it refers to. Without a lifted CFG the complexity is one more than the decisions the
+//! code xrefs show (a conditional branch is one, a switch of `n` arms `n - 1`);
+//! [`FunctionMetrics::with_cfg`] replaces the block count and complexity with the exact figures
+//! from a [`Function`].
+//!
+//! [`report`] measures every function, [`most_complex`] picks the ones to look at first, and
+//! [`to_csv`] and [`to_json`] export the lot.
+
+use crate::{
+    constants::{BR_COND, BR_PROC, LOC_STRING, LOC_UNI, REF_CODE, REF_DATA, REF_PTR},
+    symbolic::Function,
+    utils::json_string,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionMetrics {
+    pub va: i32,
+    pub name: Option<String>,
+    /// The bytes of code in the function, chunks included
+    pub size: u64,
+    pub blocks: usize,
+    pub complexity: usize,
+    /// The distinct functions calling this one
+    pub fan_in: usize,
+    /// The distinct functions this one calls
+    pub fan_out: usize,
+    /// The distinct strings referred to
+    pub strings: usize,
+    /// The distinct other data and constant addresses referred to
+    pub constants: usize,
+}
+
+impl FunctionMetrics {
+    /// Take the block count and complexity (`edges - blocks + 2`) from the lifted CFG of the
+    /// function
+    pub fn with_cfg(mut self, func: &Function) -> Self {
+        let blocks = func.reverse_postorder();
+        let edges: usize = blocks.iter().map(|va| func.successors(*va).len()).sum();
+        self.blocks = blocks.len();
+        self.complexity = (edges + 2).saturating_sub(blocks.len()).max(1);
+        self
+    }
+}
+
+/// The (va, size) ranges of a function: its chunks if it has them, or else its code blocks
+pub(crate) fn ranges(workspace: &VivWorkspace, fva: i32) -> Vec<(i32, i32)> {
+    workspace.get_function_bounds(fva).unwrap_or_else(|| {
+        let blocks = workspace.get_function_blocks(fva);
+        blocks.iter().map(|(va, size, ..)| (*va, *size)).collect()
+    })
+}
+
+fn measure_with(
+    workspace: &VivWorkspace,
+    fva: i32,
+    xrefs: &[(i32, i32, i32, i32)],
+) -> FunctionMetrics {
+    let ranges = ranges(workspace, fva);
+    let contains = |va: i32| {
+        va == fva
+            || ranges
+                .iter()
+                .any(|(start, size)| *start <= va && (va as i64) < *start as i64 + *size as i64)
+    };
+    let callers: BTreeSet<i32> = workspace
+        .get_xrefs_to(fva, Some(REF_CODE))
+        .iter()
+        .filter(|(.., rflags)| rflags & BR_PROC != 0)
+        .map(|(from, ..)| workspace.get_function(*from).unwrap_or(*from))
+        .collect();
+    let mut callees = BTreeSet::new();
+    let mut strings = BTreeSet::new();
+    let mut constants = BTreeSet::new();
+    let mut decisions: BTreeMap<i32, usize> = BTreeMap::new();
+    for (from, to, rtype, rflags) in xrefs.iter().filter(|xref| contains(xref.0)) {
+        match *rtype {
+            REF_CODE if rflags & BR_PROC != 0 => {
+                callees.insert(*to);
+            }
+            REF_CODE if rflags & BR_COND != 0 => *decisions.entry(*from).or_default() += 1,
+            REF_DATA | REF_PTR => {
+                let ltype = workspace.get_location(*to).map(|loc| loc.2);
+                if matches!(ltype, Some(LOC_STRING | LOC_UNI)) {
+                    strings.insert(*to);
+                } else {
+                    constants.insert(*to);
+                }
+            }
+            _ => {}
+        }
+    }
+    let decisions: usize = decisions
+        .values()
+        .map(|targets| targets.saturating_sub(1).max(1))
+        .sum();
+    FunctionMetrics {
+        va: fva,
+        name: workspace.get_name(fva, false),
+        size: ranges.iter().map(|(_, size)| *size as u64).sum(),
+        blocks: workspace.get_function_blocks(fva).len(),
+        complexity: decisions + 1,
+        fan_in: callers.len(),
+        fan_out: callees.len(),
+        strings: strings.len(),
+        constants: constants.len(),
+    }
+}
+
+/// Measure a function of the workspace
+pub fn measure(workspace: &VivWorkspace, fva: i32) -> FunctionMetrics {
+    measure_with(workspace, fva, &workspace.get_xrefs(None))
+}
+
+/// Measure every function of the workspace, by VA
+pub fn report(workspace: &VivWorkspace) -> Vec<FunctionMetrics> {
+    let xrefs = workspace.get_xrefs(None);
+    let functions = workspace.get_functions();
+    functions
+        .iter()
+        .map(|fva| measure_with(workspace, *fva, &xrefs))
+        .collect()
+}
+
+/// The `count` most complex functions, larger ones first where the complexity ties; only the
+/// ones without a name if `unnamed`
+pub fn most_complex(
+    metrics: &[FunctionMetrics],
+    count: usize,
+    unnamed: bool,
+) -> Vec<&FunctionMetrics> {
+    let mut ranked: Vec<&FunctionMetrics> = metrics
+        .iter()
+        .filter(|m| !unnamed || m.name.is_none())
+        .collect();
+    ranked.sort_by(|a, b| {
+        (b.complexity, b.size)
+            .cmp(&(a.complexity, a.size))
+            .then(a.va.cmp(&b.va))
+    });
+    ranked.truncate(count);
+    ranked
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The metrics as CSV with a header row; VAs are in hex
+pub fn to_csv(metrics: &[FunctionMetrics]) -> String {
+    let mut out = String::from("va,name,size,blocks,complexity,fan_in,fan_out,strings,constants\n");
+    for m in metrics {
+        out.push_str(&format!(
+            "{:#x},{},{},{},{},{},{},{},{}\n",
+            m.va as u32,
+            csv_field(m.name.as_deref().unwrap_or("")),
+            m.size,
+            m.blocks,
+            m.complexity,
+            m.fan_in,
+            m.fan_out,
+            m.strings,
+            m.constants
+        ));
+    }
+    out
+}
+
+/// The metrics as a JSON array of objects, one per line; VAs are numbers and a missing name
+/// is null
+pub fn to_json(metrics: &[FunctionMetrics]) -> String {
+    let objects: Vec<String> = metrics
+        .iter()
+        .map(|m| {
+            format!(
+                "  {{\"va\": {}, \"name\": {}, \"size\": {}, \"blocks\": {}, \"complexity\": {}, \
+                 \"fan_in\": {}, \"fan_out\": {}, \"strings\": {}, \"constants\": {}}}",
+                m.va as u32,
+                m.name.as_deref().map_or("null".to_string(), json_string),
+                m.size,
+                m.blocks,
+                m.complexity,
+                m.fan_in,
+                m.fan_out,
+                m.strings,
+                m.constants
+            )
+        })
+        .collect();
+    if objects.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Annotations;
+
+    #[test]
+    fn function_metrics() {
+        let mut ann = Annotations::new();
+        ann.functions
+            .extend([(0x1000, 0x40), (0x2000, 0x10), (0x3000, 0x10)]);
+        ann.names.insert(0x2000, "helper".to_string());
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.set_function_bounds(0x1000, vec![(0x1000, 0x40), (0x1800, 0x10)]);
+        ws.set_function_bounds(0x2000, vec![(0x2000, 0x10)]);
+        ws.set_function_bounds(0x3000, vec![(0x3000, 0x10)]);
+        ws.add_location(0x5000, 6, LOC_STRING, Some(vec![]));
+        // two conditional branches, a four way switch, calls and data refs
+        ws.add_xref(0x1004, 0x1010, REF_CODE, BR_COND);
+        ws.add_xref(0x1804, 0x1000, REF_CODE, BR_COND);
+        for case in [0x1020, 0x1024, 0x1028, 0x102c] {
+            ws.add_xref(0x1014, case, REF_CODE, BR_COND);
+        }
+        ws.add_xref(0x1030, 0x2000, REF_CODE, BR_PROC);
+        ws.add_xref(0x1034, 0x2000, REF_CODE, BR_PROC);
+        ws.add_xref(0x3004, 0x2000, REF_CODE, BR_PROC);
+        ws.add_xref(0x1038, 0x5000, REF_PTR, 0);
+        ws.add_xref(0x103c, 0x6000, REF_DATA, 0);
+
+        let metrics = report(&ws);
+        assert_eq!(metrics.len(), 3);
+        let main = &metrics[0];
+        assert_eq!((main.size, main.complexity), (0x50, 6));
+        assert_eq!((main.fan_in, main.fan_out), (0, 1));
+        assert_eq!((main.strings, main.constants), (1, 1));
+        assert_eq!(metrics[1].fan_in, 2);
+        assert_eq!(metrics[1].name.as_deref(), Some("helper"));
+
+        let top = most_complex(&metrics, 2, true);
+        assert_eq!(
+            top.iter().map(|m| m.va).collect::<Vec<_>>(),
+            [0x1000, 0x3000]
+        );
+
+        let csv = to_csv(&metrics);
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.contains("0x2000,helper,16,0,1,2,0,0,0"));
+        let json = to_json(&metrics[1..2]);
+        assert!(json.contains("\"name\": \"helper\""));
+        assert!(to_json(&metrics).contains("\"name\": null"));
+    }
+}
@@ -0,0 +1,421 @@
+//! Minidumps, the crash artifacts of Windows and of Breakpad and Crashpad elsewhere.
+//!
+//! [`Minidump::parse`] reads the threads and their register contexts, the loaded modules and the
+//! saved memory (thread stacks, the memory list and the full memory list) of a dump. It is a
+//! [`CrashArtifact`], so its threads are unwound and symbolized like those of a core file. Only
+//! dumps of x86-64 processes are supported.
+
+use crate::{
+    error,
+    stacktrace::{CrashArtifact, ThreadContext},
+    unwind::{StackMemory, REGISTERS, WINDOWS_TO_DWARF},
+};
+use alloc::{string::String, vec::Vec};
+use scroll::{Pread, LE};
+
+/// "MDMP"
+pub const MINIDUMP_SIGNATURE: u32 = 0x504d_444d;
+pub const THREAD_LIST_STREAM: u32 = 3;
+pub const MODULE_LIST_STREAM: u32 = 4;
+pub const MEMORY_LIST_STREAM: u32 = 5;
+pub const SYSTEM_INFO_STREAM: u32 = 7;
+pub const MEMORY64_LIST_STREAM: u32 = 9;
+pub const PROCESSOR_ARCHITECTURE_AMD64: u16 = 9;
+
+const SIZEOF_HEADER: usize = 32;
+const SIZEOF_DIRECTORY: usize = 12;
+const SIZEOF_THREAD: usize = 48;
+const SIZEOF_MODULE: usize = 108;
+const SIZEOF_MEMORY_DESCRIPTOR: usize = 16;
+const SIZEOF_MEMORY64_DESCRIPTOR: usize = 16;
+/// The offsets of rax (the first of the general purpose registers, in Windows order) and rip in
+/// the AMD64 `CONTEXT`
+const CONTEXT_RAX: usize = 0x78;
+const CONTEXT_RIP: usize = 0xf8;
+
+/// A module from the module list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinidumpModule {
+    pub base: u64,
+    pub size: u64,
+    pub path: String,
+}
+
+/// A minidump
+pub struct Minidump<'a> {
+    pub threads: Vec<ThreadContext>,
+    pub modules: Vec<MinidumpModule>,
+    /// The saved memory ranges: their start address and contents
+    memory: Vec<(u64, &'a [u8])>,
+}
+
+impl<'a> Minidump<'a> {
+    pub fn parse(bytes: &'a [u8]) -> error::Result<Self> {
+        let header = bytes
+            .get(..SIZEOF_HEADER)
+            .ok_or_else(|| error::Error::Malformed("Minidump header is truncated".into()))?;
+        let signature: u32 = header.pread_with(0, LE)?;
+        if signature != MINIDUMP_SIGNATURE {
+            return Err(error::Error::Malformed("Not a minidump".into()));
+        }
+        let count: u32 = header.pread_with(8, LE)?;
+        let directory: u32 = header.pread_with(12, LE)?;
+        let directory = location(
+            bytes,
+            directory,
+            (count as usize).saturating_mul(SIZEOF_DIRECTORY),
+        )?;
+        let mut minidump = Minidump {
+            threads: Vec::new(),
+            modules: Vec::new(),
+            memory: Vec::new(),
+        };
+        let mut amd64 = false;
+        for entry in directory.chunks_exact(SIZEOF_DIRECTORY) {
+            let stream_type: u32 = entry.pread_with(0, LE)?;
+            let size: u32 = entry.pread_with(4, LE)?;
+            let rva: u32 = entry.pread_with(8, LE)?;
+            match stream_type {
+                THREAD_LIST_STREAM => {
+                    minidump.parse_threads(bytes, location(bytes, rva, size as usize)?)?
+                }
+                MODULE_LIST_STREAM => {
+                    minidump.parse_modules(bytes, location(bytes, rva, size as usize)?)?
+                }
+                MEMORY_LIST_STREAM => {
+                    minidump.parse_memory(bytes, location(bytes, rva, size as usize)?)?
+                }
+                MEMORY64_LIST_STREAM => {
+                    minidump.parse_memory64(bytes, location(bytes, rva, size as usize)?)?
+                }
+                SYSTEM_INFO_STREAM => {
+                    let arch: u16 = location(bytes, rva, size as usize)?.pread_with(0, LE)?;
+                    amd64 = arch == PROCESSOR_ARCHITECTURE_AMD64;
+                    if !amd64 {
+                        return Err(error::Error::Malformed(format!(
+                            "Minidumps of processor architecture {} are not supported",
+                            arch
+                        )));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !amd64 {
+            return Err(error::Error::Malformed(
+                "Minidump has no system info".into(),
+            ));
+        }
+        Ok(minidump)
+    }
+
+    /// `count` and `size` byte entries from `stream`, checking the count against the stream
+    fn entries<'s>(
+        stream: &'s [u8],
+        header: usize,
+        size: usize,
+    ) -> error::Result<core::slice::ChunksExact<'s, u8>> {
+        let count: u32 = stream.pread_with(0, LE)?;
+        let entries = (count as usize)
+            .checked_mul(size)
+            .and_then(|length| stream.get(header..header.checked_add(length)?))
+            .ok_or_else(|| {
+                error::Error::Malformed(format!("Minidump stream claims {} entries", count))
+            })?;
+        Ok(entries.chunks_exact(size))
+    }
+
+    fn parse_threads(&mut self, bytes: &'a [u8], stream: &[u8]) -> error::Result<()> {
+        for thread in Self::entries(stream, 4, SIZEOF_THREAD)? {
+            // the stack, then the context
+            let stack: u64 = thread.pread_with(24, LE)?;
+            let stack_size: u32 = thread.pread_with(32, LE)?;
+            let stack_rva: u32 = thread.pread_with(36, LE)?;
+            self.memory
+                .push((stack, location(bytes, stack_rva, stack_size as usize)?));
+            let context_size: u32 = thread.pread_with(40, LE)?;
+            let context_rva: u32 = thread.pread_with(44, LE)?;
+            let context = location(bytes, context_rva, context_size as usize)?;
+            let mut regs = [0; REGISTERS];
+            for (windows, &dwarf) in WINDOWS_TO_DWARF.iter().enumerate() {
+                regs[dwarf] = context.pread_with(CONTEXT_RAX + windows * 8, LE)?;
+            }
+            self.threads.push(ThreadContext {
+                tid: thread.pread_with(0, LE)?,
+                pc: context.pread_with(CONTEXT_RIP, LE)?,
+                regs,
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_modules(&mut self, bytes: &'a [u8], stream: &[u8]) -> error::Result<()> {
+        for module in Self::entries(stream, 4, SIZEOF_MODULE)? {
+            // a MINIDUMP_STRING: its length in bytes, then UTF-16
+            let name: u32 = module.pread_with(20, LE)?;
+            let length: u32 = bytes.pread_with(name as usize, LE)?;
+            let name = location(bytes, name.saturating_add(4), length as usize)?;
+            let name = name
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect::<Vec<_>>();
+            self.modules.push(MinidumpModule {
+                base: module.pread_with(0, LE)?,
+                size: u64::from(module.pread_with::<u32>(8, LE)?),
+                path: String::from_utf16_lossy(&name),
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_memory(&mut self, bytes: &'a [u8], stream: &[u8]) -> error::Result<()> {
+        for range in Self::entries(stream, 4, SIZEOF_MEMORY_DESCRIPTOR)? {
+            let size: u32 = range.pread_with(8, LE)?;
+            let rva: u32 = range.pread_with(12, LE)?;
+            self.memory.push((
+                range.pread_with(0, LE)?,
+                location(bytes, rva, size as usize)?,
+            ));
+        }
+        Ok(())
+    }
+
+    /// The full memory list: the ranges' contents follow each other from a base RVA
+    fn parse_memory64(&mut self, bytes: &'a [u8], stream: &[u8]) -> error::Result<()> {
+        let count: u64 = stream.pread_with(0, LE)?;
+        let mut offset: u64 = stream.pread_with(8, LE)?;
+        let ranges = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(SIZEOF_MEMORY64_DESCRIPTOR))
+            .and_then(|length| stream.get(16..length.checked_add(16)?))
+            .ok_or_else(|| {
+                error::Error::Malformed(format!("Minidump memory list claims {} ranges", count))
+            })?;
+        for range in ranges.chunks_exact(SIZEOF_MEMORY64_DESCRIPTOR) {
+            let size: u64 = range.pread_with(8, LE)?;
+            let data = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(size).ok())
+                .and_then(|(offset, size)| bytes.get(offset..offset.checked_add(size)?))
+                .ok_or_else(|| {
+                    error::Error::Malformed(format!(
+                        "Minidump memory at {:#x} is truncated",
+                        offset
+                    ))
+                })?;
+            self.memory.push((range.pread_with(0, LE)?, data));
+            offset += size;
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes of process memory at `va`, if the dump holds them
+    pub fn read_memory(&self, va: u64, len: usize) -> Option<&'a [u8]> {
+        self.memory.iter().find_map(|&(start, data)| {
+            let offset = usize::try_from(va.checked_sub(start)?).ok()?;
+            data.get(offset..offset.checked_add(len)?)
+        })
+    }
+}
+
+/// `size` bytes at `rva`
+fn location(bytes: &[u8], rva: u32, size: usize) -> error::Result<&[u8]> {
+    let rva = rva as usize;
+    rva.checked_add(size)
+        .and_then(|end| bytes.get(rva..end))
+        .ok_or_else(|| {
+            error::Error::Malformed(format!(
+                "Minidump location {:#x}+{:#x} is outside the file",
+                rva, size
+            ))
+        })
+}
+
+impl StackMemory for Minidump<'_> {
+    fn read_memory(&self, va: u64, len: usize) -> Option<&[u8]> {
+        Minidump::read_memory(self, va, len)
+    }
+}
+
+impl CrashArtifact for Minidump<'_> {
+    fn threads(&self) -> &[ThreadContext] {
+        &self.threads
+    }
+
+    fn modules(&self) -> Vec<&str> {
+        let mut modules: Vec<&str> = Vec::new();
+        for module in self.modules.iter() {
+            if !modules.contains(&module.path.as_str()) {
+                modules.push(&module.path);
+            }
+        }
+        modules
+    }
+
+    fn load_address(&self, path: &str) -> Option<u64> {
+        self.modules
+            .iter()
+            .find(|module| module.path == path)
+            .map(|module| module.base)
+    }
+
+    fn module_ranges(&self, path: &str) -> Vec<(u64, u64)> {
+        self.modules
+            .iter()
+            .filter(|module| module.path == path)
+            .map(|module| (module.base, module.base.saturating_add(module.size)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stacktrace::symbolize;
+    use crate::unwind::{RBP, RSP};
+    use scroll::Pwrite;
+
+    const STACK: u64 = 0x7ffd_0000;
+    const BASE: u64 = 0x1_4000_0000;
+    const APP: &str = "C:\\app.exe";
+
+    /// A dump with system info, one thread three frames deep in a module at `BASE`, its stack,
+    /// and a heap range in the full memory list
+    fn minidump() -> Vec<u8> {
+        let mut out = vec![0u8; 0x1000];
+        out.pwrite_with(MINIDUMP_SIGNATURE, 0, LE).unwrap();
+        out.pwrite_with(0xa793u32, 4, LE).unwrap();
+        out.pwrite_with(4u32, 8, LE).unwrap();
+        out.pwrite_with(SIZEOF_HEADER as u32, 12, LE).unwrap();
+        let streams = [
+            (SYSTEM_INFO_STREAM, 56u32, 0x100u32),
+            (THREAD_LIST_STREAM, 4 + 48, 0x200),
+            (MODULE_LIST_STREAM, 4 + 108, 0x300),
+            (MEMORY64_LIST_STREAM, 32, 0x400),
+        ];
+        for (i, (stream_type, size, rva)) in streams.into_iter().enumerate() {
+            let entry = SIZEOF_HEADER + i * SIZEOF_DIRECTORY;
+            out.pwrite_with(stream_type, entry, LE).unwrap();
+            out.pwrite_with(size, entry + 4, LE).unwrap();
+            out.pwrite_with(rva, entry + 8, LE).unwrap();
+        }
+        out.pwrite_with(PROCESSOR_ARCHITECTURE_AMD64, 0x100, LE)
+            .unwrap();
+
+        // a thread whose context is at 0x500 and 0x100 bytes of stack at 0x900
+        out.pwrite_with(1u32, 0x200, LE).unwrap();
+        out.pwrite_with(4242u32, 0x204, LE).unwrap();
+        out.pwrite_with(STACK, 0x204 + 24, LE).unwrap();
+        out.pwrite_with(0x100u32, 0x204 + 32, LE).unwrap();
+        out.pwrite_with(0x900u32, 0x204 + 36, LE).unwrap();
+        out.pwrite_with(0x100u32, 0x204 + 40, LE).unwrap();
+        out.pwrite_with(0x500u32, 0x204 + 44, LE).unwrap();
+        out.pwrite_with(BASE + 0x1010, 0x500 + CONTEXT_RIP, LE)
+            .unwrap();
+        out.pwrite_with(STACK, 0x500 + CONTEXT_RAX + 4 * 8, LE)
+            .unwrap();
+        out.pwrite_with(STACK + 0x10, 0x500 + CONTEXT_RAX + 5 * 8, LE)
+            .unwrap();
+        out.pwrite_with(0x1234u64, 0x500 + CONTEXT_RAX + 8, LE)
+            .unwrap();
+        for (offset, value) in [
+            (0x10, STACK + 0x40),
+            (0x18, BASE + 0x1025),
+            (0x40, 0),
+            (0x48, BASE + 0x1105),
+        ] {
+            out.pwrite_with(value, 0x900 + offset, LE).unwrap();
+        }
+
+        // the module, named at 0x600
+        out.pwrite_with(1u32, 0x300, LE).unwrap();
+        out.pwrite_with(BASE, 0x304, LE).unwrap();
+        out.pwrite_with(0x3000u32, 0x304 + 8, LE).unwrap();
+        out.pwrite_with(0x600u32, 0x304 + 20, LE).unwrap();
+        let name = APP
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        out.pwrite_with(name.len() as u32, 0x600, LE).unwrap();
+        out[0x604..0x604 + name.len()].copy_from_slice(&name);
+
+        // 0x10 bytes of heap at 0xa00
+        out.pwrite_with(1u64, 0x400, LE).unwrap();
+        out.pwrite_with(0xa00u64, 0x408, LE).unwrap();
+        out.pwrite_with(0x10_0000u64, 0x410, LE).unwrap();
+        out.pwrite_with(0x10u64, 0x418, LE).unwrap();
+        out[0xa00..0xa10].copy_from_slice(b"heap contents!!\0");
+        out
+    }
+
+    #[test]
+    fn parse() {
+        let bytes = minidump();
+        let dump = Minidump::parse(&bytes).unwrap();
+        assert_eq!(dump.threads.len(), 1);
+        let thread = dump.threads[0];
+        assert_eq!((thread.tid, thread.pc), (4242, BASE + 0x1010));
+        assert_eq!((thread.sp(), thread.fp()), (STACK, STACK + 0x10));
+        // rcx is the second register of the context and the third by DWARF number
+        assert_eq!(thread.regs[2], 0x1234);
+        assert_eq!((thread.regs[RSP], thread.regs[RBP]), (STACK, STACK + 0x10));
+        assert_eq!(
+            dump.modules,
+            [MinidumpModule {
+                base: BASE,
+                size: 0x3000,
+                path: APP.into()
+            }]
+        );
+        assert_eq!(dump.read_memory(0x10_0000, 4), Some(&b"heap"[..]));
+        assert_eq!(dump.read_memory(0x10_000c, 8), None);
+        assert_eq!(dump.read_memory(u64::MAX - 3, 8), None);
+        assert_eq!(dump.read_u64(STACK + 0x18), Some(BASE + 0x1025));
+    }
+
+    #[test]
+    fn symbolize_minidump() {
+        let bytes = minidump();
+        let dump = Minidump::parse(&bytes).unwrap();
+        let traces = symbolize(&dump, &[], 16).unwrap();
+        let frames = traces[0]
+            .frames
+            .iter()
+            .map(|frame| frame.symbol.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                "C:\\app.exe+0x1010",
+                "C:\\app.exe+0x1025",
+                "C:\\app.exe+0x1105"
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_input() {
+        let bytes = minidump();
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(Minidump::parse(&bad).is_err());
+        // more threads than the stream holds
+        let mut bad = bytes.clone();
+        bad.pwrite_with(u32::MAX, 0x200, LE).unwrap();
+        assert!(Minidump::parse(&bad).is_err());
+        // a module name past the end of the file
+        let mut bad = bytes.clone();
+        bad.pwrite_with(u32::MAX - 2, 0x304 + 20, LE).unwrap();
+        assert!(Minidump::parse(&bad).is_err());
+        // a full memory list whose ranges overflow
+        let mut bad = bytes.clone();
+        bad.pwrite_with(u64::MAX, 0x418, LE).unwrap();
+        assert!(Minidump::parse(&bad).is_err());
+        bad.pwrite_with(u64::MAX >> 4, 0x400, LE).unwrap();
+        assert!(Minidump::parse(&bad).is_err());
+        // dumps of other processors
+        let mut bad = bytes;
+        bad.pwrite_with(12u16, 0x100, LE).unwrap();
+        assert!(Minidump::parse(&bad).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Per function mitigation coverage, from the code rather than the header flags.
+//!
+//! A binary built with `-fstack-protector-strong`, `_FORTIFY_SOURCE` or `-fcf-protection` only
+//! protects the functions the compiler chose to, and objects built without them link in just
+//! the same. So each function is checked for itself:
+//!
+//! * a stack canary, where it calls `__stack_chk_fail` (or `__stack_chk_fail_local`),
+//! * fortified calls, to the `__*_chk` versions of the libc functions (`__memcpy_chk`),
+//! * a landing pad at its entry for indirect branches: `endbr64`/`endbr32` for CET, `bti c`,
+//!   `bti jc` or `paciasp` for BTI.
+//!
+//! [`Coverage`] adds them up for the workspace; a function with none of them is what's left to
+//! look at.
+
+use crate::{memory::Memory, workspace::VivWorkspace};
+use std::{collections::BTreeMap, fmt};
+
+const CANARY_FAIL: [&str; 2] = ["__stack_chk_fail", "__stack_chk_fail_local"];
+
+const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+const ENDBR32: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfb];
+/// `bti c`, `bti jc` and `paciasp`, which is an implicit `bti c`
+const BTI_PADS: [u32; 3] = [0xd503245f, 0xd50324df, 0xd503233f];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LandingPad {
+    Endbr64,
+    Endbr32,
+    Bti,
+}
+
+impl fmt::Display for LandingPad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LandingPad::Endbr64 => "endbr64",
+            LandingPad::Endbr32 => "endbr32",
+            LandingPad::Bti => "bti",
+        })
+    }
+}
+
+/// The landing pad starting `bytes`, the bytes at a function entry
+pub fn landing_pad(bytes: &[u8]) -> Option<LandingPad> {
+    let head: [u8; 4] = bytes.get(..4)?.try_into().ok()?;
+    if head == ENDBR64 {
+        Some(LandingPad::Endbr64)
+    } else if head == ENDBR32 {
+        Some(LandingPad::Endbr32)
+    } else if BTI_PADS.contains(&u32::from_le_bytes(head)) {
+        Some(LandingPad::Bti)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionMitigations {
+    /// The calls to `__stack_chk_fail`
+    pub canary: Vec<i32>,
+    /// The `_chk` functions called, with the calls
+    pub fortified: BTreeMap<String, Vec<i32>>,
+    pub landing_pad: Option<LandingPad>,
+}
+
+impl FunctionMitigations {
+    pub fn has_canary(&self) -> bool {
+        !self.canary.is_empty()
+    }
+
+    pub fn is_fortified(&self) -> bool {
+        !self.fortified.is_empty()
+    }
+
+    /// None of the mitigations show up in the function
+    pub fn is_bare(&self) -> bool {
+        !self.has_canary() && !self.is_fortified() && self.landing_pad.is_none()
+    }
+}
+
+fn is_fortified_name(name: &str) -> bool {
+    name.starts_with("__") && name.ends_with("_chk") && !CANARY_FAIL.contains(&name)
+}
+
+/// Check every function of the workspace, by VA. Calls are attributed to the functions holding
+/// them.
+pub fn check(workspace: &VivWorkspace) -> BTreeMap<i32, FunctionMitigations> {
+    let mut functions: BTreeMap<i32, FunctionMitigations> = workspace
+        .get_functions()
+        .into_iter()
+        .map(|fva| {
+            let landing_pad = workspace
+                .read_memory(fva, 4)
+                .and_then(|bytes| landing_pad(&bytes));
+            (
+                fva,
+                FunctionMitigations {
+                    landing_pad,
+                    ..Default::default()
+                },
+            )
+        })
+        .collect();
+    let mut names: Vec<String> = workspace
+        .get_imports()
+        .into_iter()
+        .map(|(_, name)| match name.split_once('.') {
+            Some((_, name)) => name.to_string(),
+            None => name,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let canary = CANARY_FAIL.contains(&name.as_str());
+        if !canary && !is_fortified_name(&name) {
+            continue;
+        }
+        for va in workspace.get_callers_of_import(&name) {
+            let Some(func) = workspace
+                .get_function(va)
+                .and_then(|fva| functions.get_mut(&fva))
+            else {
+                continue;
+            };
+            if canary {
+                func.canary.push(va);
+            } else {
+                func.fortified.entry(name.clone()).or_default().push(va);
+            }
+        }
+    }
+    for func in functions.values_mut() {
+        func.canary.sort_unstable();
+        func.canary.dedup();
+    }
+    functions
+}
+
+/// How many of the functions have each mitigation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub functions: usize,
+    pub canary: usize,
+    pub fortified: usize,
+    pub landing_pad: usize,
+    pub bare: usize,
+}
+
+impl Coverage {
+    pub fn new<'a, I: IntoIterator<Item = &'a FunctionMitigations>>(functions: I) -> Self {
+        let mut coverage = Coverage::default();
+        for func in functions {
+            coverage.functions += 1;
+            coverage.canary += func.has_canary() as usize;
+            coverage.fortified += func.is_fortified() as usize;
+            coverage.landing_pad += func.landing_pad.is_some() as usize;
+            coverage.bare += func.is_bare() as usize;
+        }
+        coverage
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} functions: {} with a stack canary, {} fortified, {} with a landing pad, {} with none",
+            self.functions, self.canary, self.fortified, self.landing_pad, self.bare
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ, REF_CODE},
+        storage::Annotations,
+    };
+
+    #[test]
+    fn mitigation_coverage() {
+        assert_eq!(
+            landing_pad(&0xd503245fu32.to_le_bytes()),
+            Some(LandingPad::Bti)
+        );
+        assert!(!is_fortified_name("__stack_chk_fail"));
+
+        // endbr64 at 0x1000 with a canary and a fortified memcpy, nothing at 0x1010
+        let mut code = vec![0xf3, 0x0f, 0x1e, 0xfa];
+        code.resize(0x20, 0x90);
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x1000, 0x10), (0x1010, 0x10)]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.set_function_bounds(0x1000, vec![(0x1000, 0x10)]);
+        ws.set_function_bounds(0x1010, vec![(0x1010, 0x10)]);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code, None);
+        ws.make_import(0x3000, "libc", "__stack_chk_fail");
+        ws.make_import(0x3008, "libc", "__memcpy_chk");
+        ws.add_xref(0x1008, 0x3000, REF_CODE, 0);
+        ws.add_xref(0x1004, 0x3008, REF_CODE, 0);
+
+        let functions = check(&ws);
+        let protected = &functions[&0x1000];
+        assert_eq!(protected.canary, [0x1008]);
+        assert_eq!(protected.fortified["__memcpy_chk"], [0x1004]);
+        assert_eq!(protected.landing_pad, Some(LandingPad::Endbr64));
+        assert!(functions[&0x1010].is_bare());
+
+        let coverage = Coverage::new(functions.values());
+        assert_eq!(
+            coverage.to_string(),
+            "2 functions: 1 with a stack canary, 1 fortified, 1 with a landing pad, 1 with none"
+        );
+    }
+}
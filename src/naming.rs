@@ -0,0 +1,244 @@
+//! Automatic names for what analysis discovers.
+//!
+//! Every automatic name is a prefix for the kind of thing named and its VA in hex: `sub_401000`
+//! for a function, `loc_401020` for a branch target inside one, `str_`, `vtbl_` and `off_` for
+//! strings, vtables and pointers. As the VA is in the name, automatic names can't collide with
+//! each other and come out the same on every run, and a name read back from a saved workspace
+//! can be told apart from one a user gave by [`parse_auto_name`]. The workspace keeps track of
+//! which names are automatic so analysis never overwrites a user's rename (see
+//! `VivWorkspace::apply_auto_names`).
+
+use crate::constants::{LOC_POINTER, LOC_STRING, LOC_UNI, LOC_VFTABLE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AutoKind {
+    Function,
+    /// A branch target within a function
+    Label,
+    String,
+    Vtable,
+    Pointer,
+}
+
+impl AutoKind {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            AutoKind::Function => "sub",
+            AutoKind::Label => "loc",
+            AutoKind::String => "str",
+            AutoKind::Vtable => "vtbl",
+            AutoKind::Pointer => "off",
+        }
+    }
+
+    /// The kind of automatic name a location of type `ltype` gets, if any
+    pub fn for_location(ltype: i32) -> Option<AutoKind> {
+        match ltype {
+            LOC_STRING | LOC_UNI => Some(AutoKind::String),
+            LOC_VFTABLE => Some(AutoKind::Vtable),
+            LOC_POINTER => Some(AutoKind::Pointer),
+            _ => None,
+        }
+    }
+
+    /// Which of two kinds names an address both apply to: a function over a label, anything
+    /// over a pointer
+    pub fn precedence(&self) -> u8 {
+        match self {
+            AutoKind::Function => 4,
+            AutoKind::Vtable => 3,
+            AutoKind::String => 2,
+            AutoKind::Label => 1,
+            AutoKind::Pointer => 0,
+        }
+    }
+}
+
+const KINDS: [AutoKind; 5] = [
+    AutoKind::Function,
+    AutoKind::Label,
+    AutoKind::String,
+    AutoKind::Vtable,
+    AutoKind::Pointer,
+];
+
+/// The automatic name of `va`
+pub fn auto_name(kind: AutoKind, va: i32) -> String {
+    format!("{}_{:x}", kind.prefix(), va as u32)
+}
+
+/// The kind and VA of an automatic name, or None for any other name
+pub fn parse_auto_name(name: &str) -> Option<(AutoKind, i32)> {
+    let (prefix, hex) = name.split_once('_')?;
+    let kind = KINDS.into_iter().find(|kind| kind.prefix() == prefix)?;
+    if hex.is_empty() || hex.starts_with('+') || hex.chars().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let va = u32::from_str_radix(hex, 16).ok()? as i32;
+    (auto_name(kind, va) == name).then_some((kind, va))
+}
+
+/// The workspace metadata keys whose values may hold names, rewritten by
+/// `VivWorkspace::rename`. The rest, such as `Format` and `Platform`, configure the workspace.
+pub const NAME_META_KEYS: &[&str] = &["Notes"];
+
+/// What `VivWorkspace::rename` changed besides the name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Renamed {
+    /// The name before, if there was one
+    pub old: Option<String>,
+    /// The VAs whose comments named it
+    pub comments: Vec<i32>,
+    /// The metadata keys whose values named it
+    pub meta: Vec<String>,
+    /// Whether it is an export, renamed in the export table too
+    pub export: bool,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '$' | '@' | '?')
+}
+
+/// `text` with every whole occurrence of the name `old` replaced by `new`, or None if it has
+/// none. An occurrence is whole where the characters around it can't be part of a name: a dot
+/// before it or a dot and a name character after it make it part of a qualified name.
+pub fn replace_token(text: &str, old: &str, new: &str) -> Option<String> {
+    if old.is_empty() {
+        return None;
+    }
+    let mut out = String::new();
+    let mut last = 0;
+    for (start, _) in text.match_indices(old) {
+        let end = start + old.len();
+        if start < last {
+            continue;
+        }
+        let before = text[..start].chars().next_back();
+        let mut after = text[end..].chars();
+        let whole_before = before.is_none_or(|c| !is_name_char(c) && c != '.');
+        let whole_after = match after.next() {
+            None => true,
+            Some('.') => after.next().is_none_or(|c| !is_name_char(c)),
+            Some(c) => !is_name_char(c),
+        };
+        if whole_before && whole_after {
+            out.push_str(&text[last..start]);
+            out.push_str(new);
+            last = end;
+        }
+    }
+    if last == 0 {
+        return None;
+    }
+    out.push_str(&text[last..]);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{BR_COND, LOC_OP, REF_CODE},
+        storage::Annotations,
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn auto_names() {
+        assert_eq!(auto_name(AutoKind::Function, 0x401000), "sub_401000");
+        assert_eq!(auto_name(AutoKind::Vtable, -1), "vtbl_ffffffff");
+        assert_eq!(
+            parse_auto_name("off_8040"),
+            Some((AutoKind::Pointer, 0x8040))
+        );
+        for name in ["sub_0401000", "sub_401000_0", "sub_", "foo_1000", "main"] {
+            assert_eq!(parse_auto_name(name), None, "{}", name);
+        }
+
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x1000, 0x40), (0x2000, 0x10)]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.set_function_bounds(0x1000, vec![(0x1000, 0x40)]);
+        ws.add_location(0x1000, 2, LOC_OP, Some(vec![]));
+        ws.add_location(0x5000, 6, LOC_STRING, Some(vec![]));
+        ws.add_location(0x6000, 4, LOC_POINTER, Some(vec![]));
+        ws.add_xref(0x1004, 0x1020, REF_CODE, BR_COND);
+        ws.make_name(0x2000, "parse_header".to_string(), false, false);
+        assert_eq!(ws.apply_auto_names(), 4);
+        assert_eq!(ws.get_name(0x1000, false).as_deref(), Some("sub_1000"));
+        assert_eq!(ws.get_name(0x1020, false).as_deref(), Some("loc_1020"));
+        assert_eq!(ws.get_name(0x5000, false).as_deref(), Some("str_5000"));
+        assert_eq!(ws.get_name(0x6000, false).as_deref(), Some("off_6000"));
+        assert_eq!(ws.get_name(0x2000, false).as_deref(), Some("parse_header"));
+        assert!(ws.is_auto_name(0x1000) && !ws.is_auto_name(0x2000));
+
+        // Nothing changes on a second run, nor after a save and load
+        assert_eq!(ws.apply_auto_names(), 0);
+        let mut reloaded = VivWorkspace::new("", false);
+        reloaded.apply_annotations(&ws.get_annotations());
+        assert!(reloaded.is_auto_name(0x1020) && !reloaded.is_auto_name(0x2000));
+
+        // A user rename sticks, and an auto name goes away with what it named
+        ws.make_name(0x1000, "main".to_string(), false, false);
+        ws.add_region_override(0x1000, 0x40, crate::overrides::RegionKind::NeverCode);
+        assert_eq!(ws.apply_auto_names(), 1);
+        assert_eq!(ws.get_name(0x1000, false).as_deref(), Some("main"));
+        assert_eq!(ws.get_name(0x1020, false), None);
+    }
+
+    #[test]
+    fn renames_everywhere() {
+        assert_eq!(
+            replace_token("sub_1000, sub_10000 and x.sub_1000", "sub_1000", "main").as_deref(),
+            Some("main, sub_10000 and x.sub_1000")
+        );
+        assert_eq!(replace_token("sub_1000_0", "sub_1000", "main"), None);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_segment(0x1000, 0x2000, ".text", "prog".to_string());
+        ws.make_name(0x1000, "prog.sub_1000".to_string(), false, false);
+        ws.add_export(0x2000, "helper", "prog");
+        ws.make_name(0x2800, "prog.other".to_string(), false, false);
+        ws.set_comment(0x1010, "calls prog.helper, like sub_1000.", false);
+        ws.set_comment(0x1020, "prog.sub_1000_0 is another", false);
+        ws.set_meta("Notes", Some("entry=prog.sub_1000".to_string()));
+        ws.make_name(0x2c00, "prog.linux".to_string(), false, false);
+        ws.set_meta("Format", Some("elf".to_string()));
+        ws.set_meta("Platform", Some("linux".to_string()));
+        ws.start_journal();
+
+        let renamed = ws.rename(0x1000, "prog.decrypt").unwrap();
+        assert_eq!(renamed.old.as_deref(), Some("prog.sub_1000"));
+        assert_eq!(renamed.comments, [0x1010]);
+        assert_eq!(renamed.meta, ["Notes"]);
+        assert_eq!(ws.get_comment(0x1010), "calls prog.helper, like decrypt.");
+        assert_eq!(ws.get_meta("Notes").as_deref(), Some("entry=prog.decrypt"));
+        let renamed = ws.rename(0x2000, "prog.unpack").unwrap();
+        assert!(renamed.export);
+        assert_eq!(ws.get_comment(0x1010), "calls prog.unpack, like decrypt.");
+        assert!(ws.get_exports().contains(&(0x2000, "prog.unpack".to_string())));
+        assert!(ws.rename(0x2000, "prog.other").is_err());
+        assert_eq!(ws.get_name(0x2000, false).as_deref(), Some("prog.unpack"));
+        // the metadata configuring the workspace isn't names
+        let renamed = ws.rename(0x2c00, "prog.elf").unwrap();
+        assert!(renamed.meta.is_empty());
+        assert_eq!(ws.get_meta("Platform").as_deref(), Some("linux"));
+        assert_eq!(ws.get_meta("Format").as_deref(), Some("elf"));
+
+        // one event per rename, which replays to the same names and comments
+        let journal = ws.journal().unwrap();
+        assert_eq!(journal.len(), 3);
+        let mut text = Vec::new();
+        journal.write(&mut text).unwrap();
+        let journal = crate::journal::Journal::read(text.as_slice()).unwrap();
+        assert_eq!(
+            journal.events()[1],
+            crate::journal::Event::Rename {
+                va: 0x2000,
+                name: "prog.unpack".to_string(),
+                comments: vec![0x1010],
+            }
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Functions which never return, and where the functions calling them end.
+//!
+//! A function never returns when it is one of the runtime's (`exit`, `abort`,
+//! `__stack_chk_fail`, `ExitProcess`, ...), or when no path of its IL gets to a return
+//! without calling one that doesn't. Compilers put no epilogue after such a call: what follows
+//! is a trap or the padding aligning the next function, then the next function, which a lifter
+//! going on past the call glues onto the caller.
+//!
+//! [`split`] ends a function at its calls to functions which never return, each call turned
+//! into a [`Terminator::TailCall`] to the callee, and drops the code only reached past them.
+//! The [`Boundary`] of each call says where the function ends, how much padding follows and
+//! where the code after it, if it looks like a function, starts.
+//!
+//! Two function metas override the heuristics per function: [`META_NO_RETURN`] set to 1 or 0
+//! says whether the function returns, and [`META_SPLIT`] set to 0 keeps a function whole.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::Arch,
+    memory::Memory,
+    symbolic::{Function, Stmt, Terminator},
+    tailcall::has_prologue,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The functions of the runtimes which never return, without their leading underscores
+pub const NO_RETURN_APIS: &[&str] = &[
+    "exit",
+    "Exit",
+    "quick_exit",
+    "abort",
+    "assert_fail",
+    "assert_perror_fail",
+    "assert_rtn",
+    "stack_chk_fail",
+    "chk_fail",
+    "fortify_fail",
+    "cxa_throw",
+    "cxa_rethrow",
+    "cxa_bad_cast",
+    "cxa_bad_typeid",
+    "cxa_pure_virtual",
+    "Unwind_Resume",
+    "ZSt9terminatev",
+    "longjmp",
+    "siglongjmp",
+    "longjmp_chk",
+    "pthread_exit",
+    "err",
+    "errx",
+    "verr",
+    "verrx",
+    "libc_start_main",
+    "ExitProcess",
+    "ExitThread",
+    "FatalExit",
+    "FatalAppExitA",
+    "FatalAppExitW",
+    "RaiseFailFastException",
+    "RtlExitUserProcess",
+    "RtlExitUserThread",
+    "CxxThrowException",
+    "report_gsfailure",
+    "invalid_parameter_noinfo_noreturn",
+    "assert_func",
+    "objc_exception_throw",
+];
+
+/// The function meta saying whether a function returns, 0 if it does and 1 if it never does,
+/// whatever its name or its code
+pub const META_NO_RETURN: &str = "NoReturn";
+/// The function meta which, set to 0, keeps a function whole past its calls to functions
+/// which never return
+pub const META_SPLIT: &str = "SplitAtNoReturn";
+
+/// Where a function ends at a call to a function which never returns
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Boundary {
+    pub fva: u64,
+    pub call: u64,
+    pub callee: u64,
+    /// The address after the call
+    pub end: u64,
+    /// The bytes of traps and nops from `end` on
+    pub padding: u64,
+    /// The function starting after the padding, if the code there is one
+    pub next: Option<u64>,
+}
+
+/// The symbol an import or a name is for, without the library and the leading underscores
+fn symbol(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name).trim_start_matches('_')
+}
+
+fn meta(workspace: &VivWorkspace, fva: u64, key: &str) -> Option<i32> {
+    let fva = fva as i32;
+    workspace
+        .is_function(fva)
+        .then(|| workspace.get_function_meta_dict(fva).get(key).copied())?
+}
+
+/// The functions the call at `va` calls, as direct calls or through import trampolines
+fn callees(workspace: &VivWorkspace, va: u64) -> Vec<u64> {
+    workspace
+        .get_xrefs_from(va as i32, Some(REF_CODE))
+        .into_iter()
+        .filter(|(_, _, _, rflags)| rflags & BR_PROC != 0)
+        .map(|(_, to, _, _)| to as u32 as u64)
+        .collect()
+}
+
+/// The call of `insn` to one of `no_return`, if it makes one
+fn no_return_call(
+    workspace: &VivWorkspace,
+    va: u64,
+    stmts: &[Stmt],
+    no_return: &BTreeSet<u64>,
+) -> Option<u64> {
+    if !stmts.contains(&Stmt::Unknown) {
+        return None;
+    }
+    callees(workspace, va)
+        .into_iter()
+        .find(|callee| no_return.contains(callee))
+}
+
+/// Whether a path of `func` gets to a return, its calls to `no_return` ending their paths
+fn returns(workspace: &VivWorkspace, func: &Function, no_return: &BTreeSet<u64>) -> bool {
+    if !func.blocks.contains_key(&func.entry) {
+        return true;
+    }
+    let mut seen = BTreeSet::new();
+    let mut work = vec![func.entry];
+    while let Some(va) = work.pop() {
+        let Some(block) = func.blocks.get(&va).filter(|_| seen.insert(va)) else {
+            continue;
+        };
+        let stops = block
+            .insns
+            .iter()
+            .any(|insn| no_return_call(workspace, insn.va, &insn.stmts, no_return).is_some());
+        match &block.end {
+            _ if stops => {}
+            Terminator::Return => return true,
+            Terminator::TailCall(to) if !no_return.contains(to) => return true,
+            // a computed jump to nowhere known may go anywhere
+            Terminator::Indirect(_) if func.successors(va).is_empty() => return true,
+            _ => work.extend(func.successors(va)),
+        }
+    }
+    false
+}
+
+/// The functions of `workspace` which never return: the runtime's, by the workspace's OS
+/// profile or else all of [`NO_RETURN_APIS`], and their import trampolines, and the functions
+/// with IL which can't return without calling one
+pub fn no_return_functions(workspace: &VivWorkspace) -> BTreeSet<u64> {
+    let forced = |fva: u64, value| meta(workspace, fva, META_NO_RETURN) == Some(value);
+    let apis = workspace
+        .get_os_profile()
+        .map_or_else(|| NO_RETURN_APIS.to_vec(), |profile| profile.no_return_apis());
+    let mut found: BTreeSet<u64> = workspace
+        .get_names()
+        .into_iter()
+        .filter(|(_, name)| apis.contains(&symbol(name)))
+        .map(|(va, _)| va as u32 as u64)
+        .chain(
+            workspace
+                .get_functions()
+                .into_iter()
+                .map(|fva| fva as u32 as u64)
+                .filter(|fva| forced(*fva, 1)),
+        )
+        .collect();
+    let stubs: Vec<(u64, u64)> = workspace
+        .get_import_stubs()
+        .into_iter()
+        .map(|(va, slot)| (va as u32 as u64, slot as u32 as u64))
+        .collect();
+    found.extend(
+        stubs
+            .iter()
+            .filter(|(_, slot)| found.contains(slot))
+            .map(|(va, _)| *va)
+            .collect::<Vec<_>>(),
+    );
+    found.retain(|va| !forced(*va, 0));
+
+    // a function which only calls them doesn't return either, nor do its callers
+    let fvas: Vec<u64> = workspace
+        .get_functions_with_il()
+        .into_iter()
+        .map(|fva| fva as u32 as u64)
+        .filter(|fva| !forced(*fva, 0))
+        .collect();
+    loop {
+        let more: Vec<u64> = fvas
+            .iter()
+            .filter(|fva| !found.contains(fva))
+            .filter(|fva| {
+                let func = workspace.get_function_il(**fva as i32).unwrap();
+                !returns(workspace, func, &found)
+            })
+            .copied()
+            .collect();
+        if more.is_empty() {
+            return found;
+        }
+        found.extend(more);
+    }
+}
+
+/// The length of the padding at the start of `bytes`: the traps, undefined instructions and
+/// nops compilers fill the space between functions with
+pub fn padding(arch: Arch, bytes: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some(size) = bytes.get(len..).and_then(|rest| pad_insn(arch, rest)) {
+        len += size;
+    }
+    len
+}
+
+fn pad_insn(arch: Arch, bytes: &[u8]) -> Option<usize> {
+    let word = || Some(u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()));
+    let half = || Some(u16::from_le_bytes(bytes.get(..2)?.try_into().unwrap()));
+    match arch {
+        Arch::I386 | Arch::Amd64 => {
+            const PADS: &[&[u8]] = &[
+                // int3, nop, ud2
+                &[0xcc],
+                &[0x90],
+                &[0x0f, 0x0b],
+                // the long nops, which may have 0x66 prefixes
+                &[0x0f, 0x1f, 0x00],
+                &[0x0f, 0x1f, 0x40, 0x00],
+                &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+                &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+                &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[0x2e, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ];
+            let prefixes = bytes.iter().take_while(|b| **b == 0x66).count();
+            let rest = &bytes[prefixes..];
+            PADS.iter()
+                .filter(|pad| rest.starts_with(pad) && (prefixes == 0 || pad[0] != 0xcc))
+                .map(|pad| prefixes + pad.len())
+                .max()
+        }
+        // udf #0, nop, brk
+        Arch::A64 => word()
+            .filter(|w| *w == 0 || *w == 0xd503_201f || w & 0xffe0_001f == 0xd420_0000)
+            .map(|_| 4),
+        // nop, mov r0, r0, udf
+        Arch::ArmV7 => word()
+            .filter(|w| *w == 0xe320_f000 || *w == 0xe1a0_0000 || w & 0xfff0_00f0 == 0xe7f0_00f0)
+            .map(|_| 4),
+        // nop, mov r8, r8, udf, bkpt
+        Arch::Thumb | Arch::Thumb16 => half()
+            .filter(|h| *h == 0xbf00 || *h == 0x46c0 || matches!(h >> 8, 0xde | 0xbe))
+            .map(|_| 2),
+        // mov #0, r3
+        Arch::Msp430 => half().filter(|h| *h == 0x4303).map(|_| 2),
+        Arch::H8 => bytes.starts_with(&[0, 0]).then_some(2),
+    }
+}
+
+/// End `func`, the IL of the function at `fva`, at its calls to `no_return`, and drop the
+/// blocks no longer reached. Returns where it ends at each of them.
+pub fn split(
+    workspace: &VivWorkspace,
+    fva: u64,
+    func: &mut Function,
+    no_return: &BTreeSet<u64>,
+) -> Vec<Boundary> {
+    if meta(workspace, fva, META_SPLIT) == Some(0) {
+        return Vec::new();
+    }
+    // the address after each instruction is where the next one the lifter went on to starts
+    let starts: BTreeSet<u64> = func
+        .blocks
+        .values()
+        .flat_map(|block| block.insns.iter().map(|insn| insn.va).chain([block.end_va]))
+        .collect();
+    let mut cuts: BTreeMap<u64, u64> = BTreeMap::new();
+    for block in func.blocks.values_mut() {
+        let Some((at, callee)) = block.insns.iter().enumerate().find_map(|(at, insn)| {
+            no_return_call(workspace, insn.va, &insn.stmts, no_return).map(|callee| (at, callee))
+        }) else {
+            continue;
+        };
+        let call = block.insns[at].va;
+        block.insns.truncate(at);
+        block.end_va = call;
+        block.end = Terminator::TailCall(callee);
+        cuts.insert(call, callee);
+    }
+    let live: BTreeSet<u64> = func.reverse_postorder().into_iter().collect();
+    func.blocks.retain(|va, _| live.contains(va));
+
+    let mut boundaries = Vec::new();
+    for (call, callee) in cuts {
+        if !live.iter().any(|va| func.blocks[va].end_va == call) {
+            continue;
+        }
+        let Some(end) = starts.range(call + 1..).next().copied() else {
+            continue;
+        };
+        let bytes = workspace.read_memory(end as i32, 0x40).unwrap_or_default();
+        let padding = padding(func.arch, &bytes) as u64;
+        let after = end + padding;
+        let rest = workspace.read_memory(after as i32, 0x10).unwrap_or_default();
+        let function = padding > 0
+            || workspace.is_function(after as i32)
+            || has_prologue(func.arch, &rest);
+        boundaries.push(Boundary {
+            fva,
+            call,
+            callee,
+            end,
+            padding,
+            next: (function && !live.contains(&after) && !rest.is_empty()).then_some(after),
+        });
+    }
+    boundaries
+}
+
+/// Where the functions with IL end at their calls to functions which never return, leaving
+/// their IL as it is
+pub fn boundaries(workspace: &VivWorkspace) -> Vec<Boundary> {
+    let no_return = no_return_functions(workspace);
+    let mut boundaries = Vec::new();
+    for fva in workspace.get_functions_with_il() {
+        let mut func = workspace.get_function_il(fva).unwrap().clone();
+        boundaries.extend(split(workspace, fva as u32 as u64, &mut func, &no_return));
+    }
+    boundaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ},
+        envi::registers::RegisterModel,
+        symbolic::{Block, Expr, Insn},
+    };
+
+    #[test]
+    fn ends_at_calls_that_never_return() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let rdi = model.by_name("rdi").unwrap();
+        let insn = |va, stmts| Insn { va, stmts };
+        let block = |va, insns, end_va| Block {
+            va,
+            insns,
+            end_va,
+            end: Terminator::Return,
+        };
+
+        // mov edi, 1; call exit; int3; nop dword [rax]; nop; then the next function,
+        // push rbp; mov rbp, rsp; ret
+        let mut first = Function::new(Arch::Amd64, 0x1000);
+        first.add_block(block(
+            0x1000,
+            vec![
+                insn(0x1000, vec![Stmt::Set(rdi, Expr::Const(1))]),
+                insn(0x1005, vec![Stmt::Unknown]),
+                insn(0x100a, vec![Stmt::Unknown]),
+                insn(0x100b, vec![]),
+                insn(0x100f, vec![]),
+                insn(0x1010, vec![]),
+                insn(0x1011, vec![]),
+            ],
+            0x1014,
+        ));
+        // call first; ret
+        let mut second = Function::new(Arch::Amd64, 0x1200);
+        second.add_block(block(
+            0x1200,
+            vec![insn(0x1200, vec![Stmt::Unknown])],
+            0x1205,
+        ));
+        let mut code = vec![0xc3; 0x400];
+        code[0xa..0x14].copy_from_slice(&[0xcc, 0x0f, 0x1f, 0x40, 0, 0x90, 0x55, 0x48, 0x89, 0xe5]);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a", code, None);
+        ws.make_import(0x3000, "libc", "exit");
+        ws.add_import_stub(0x1100, 0x3000);
+        ws.add_xref(0x1005, 0x1100, REF_CODE, BR_PROC);
+        ws.add_xref(0x1200, 0x1000, REF_CODE, BR_PROC);
+        ws.add_function(0x1000, Vec::new());
+        ws.add_function(0x1200, Vec::new());
+        ws.set_function_il(0x1000, first);
+        ws.set_function_il(0x1200, second);
+
+        assert_eq!(
+            no_return_functions(&ws),
+            [0x1000, 0x1100, 0x1200, 0x3000].into()
+        );
+        assert_eq!(padding(Arch::Amd64, &[0x66, 0x66, 0x2e, 0x0f, 0x1f, 0x84, 0, 0, 0, 0, 0]), 11);
+        let found = boundaries(&ws);
+        assert_eq!(
+            found[0],
+            Boundary {
+                fva: 0x1000,
+                call: 0x1005,
+                callee: 0x1100,
+                end: 0x100a,
+                padding: 6,
+                next: Some(0x1010),
+            }
+        );
+        assert_eq!((found[1].call, found[1].padding, found[1].next), (0x1200, 0, None));
+
+        // kept whole, the caller isn't split
+        ws.set_function_meta(0x1200, META_SPLIT, 0);
+        assert_eq!(ws.split_at_no_return_calls(), found[..1]);
+        let func = ws.get_function_il(0x1000).unwrap();
+        assert_eq!(func.blocks[&0x1000].insns.len(), 1);
+        assert_eq!(func.blocks[&0x1000].end, Terminator::TailCall(0x1100));
+        assert_eq!(ws.get_entry_points(), [0x1010]);
+        assert_eq!(ws.get_va_set_rows("NoReturnCalls").unwrap(), [0x1005]);
+
+        // nor is a function said to return
+        ws.set_function_meta(0x1000, META_NO_RETURN, 0);
+        assert!(!no_return_functions(&ws).contains(&0x1000));
+    }
+}
@@ -0,0 +1,607 @@
+//! Objective-C metadata and message send resolution.
+//!
+//! Objective-C code calls methods through `objc_msgSend(receiver, selector, ...)`, so a call
+//! graph built from direct calls alone ends at the runtime. [`ObjcMetadata`] reads the classes,
+//! categories and method lists of a 64-bit Mach-O image out of its `__objc_*` sections, which
+//! gives the implementations of every selector. [`link_message_sends`] then finds the calls to
+//! `objc_msgSend` (through its import trampolines, or through the `__objc_stubs` the linker
+//! makes per selector), recovers the selector loaded into the second argument register and,
+//! when the receiver is loaded from a class reference, the class, and records call xrefs to the
+//! candidate implementations.
+//!
+//! Like [`crate::trampolines`], the call sites are found in the raw bytes: the selector is the
+//! last selector reference loaded into `x1`/`rsi` in the few instructions before the call.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::Arch,
+    mach::MachO,
+    memory::Memory,
+    trampolines::{a64_adrp, a64_insn, a64_ldr, find_calls},
+    workspace::VivWorkspace,
+};
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The instructions looked through before an A64 call for the selector
+const A64_WINDOW: usize = 12;
+/// The bytes looked through before an x86-64 call for the selector
+const X86_WINDOW: usize = 48;
+
+/// The mapped bytes of an image, for reading its metadata by address
+#[derive(Debug, Clone, Default)]
+pub struct Image<'a> {
+    maps: Vec<(u64, &'a [u8])>,
+    sections: Vec<(String, u64, u64)>,
+    binds: BTreeMap<u64, String>,
+    fixups: BTreeMap<u64, Option<u64>>,
+}
+
+impl<'a> Image<'a> {
+    pub fn new() -> Self {
+        Image::default()
+    }
+
+    pub fn from_macho(macho: &MachO<'a>) -> Self {
+        let base = macho
+            .segments
+            .iter()
+            .find(|seg| seg.name().ok() == Some("__TEXT"))
+            .map_or(0, |seg| seg.vmaddr);
+        let mut image = Image::new();
+        for seg in macho.segments.iter() {
+            image.add_map(seg.vmaddr, seg.data);
+            for (section, _) in seg.sections().unwrap_or_default() {
+                if let Ok(name) = section.name() {
+                    image.add_section(name, section.addr, section.size);
+                }
+            }
+        }
+        for import in macho.imports().unwrap_or_default() {
+            image.add_bind(import.address, import.name);
+        }
+        if let Ok(Some(fixups)) = macho.chained_fixups() {
+            for (va, pointer) in fixups.fixups.iter() {
+                image.add_fixup(*va, pointer.target(base));
+                if let Some(import) = fixups.import(pointer) {
+                    image.add_bind(*va, import.name);
+                }
+            }
+        }
+        image
+    }
+
+    pub fn add_map(&mut self, va: u64, bytes: &'a [u8]) {
+        self.maps.push((va, bytes));
+    }
+
+    pub fn add_section(&mut self, name: &str, va: u64, size: u64) {
+        self.sections.push((name.to_string(), va, size));
+    }
+
+    /// A pointer at `va` which the loader binds to the symbol `name`
+    pub fn add_bind(&mut self, va: u64, name: &str) {
+        self.binds.insert(va, name.to_string());
+    }
+
+    /// A pointer at `va` stored as a chained fixup, which points to `target` once the loader has
+    /// fixed it up, or to an import
+    pub fn add_fixup(&mut self, va: u64, target: Option<u64>) {
+        self.fixups.insert(va, target);
+    }
+
+    fn section(&self, name: &str) -> Option<(u64, u64)> {
+        self.sections
+            .iter()
+            .find(|(sname, _, _)| sname == name)
+            .map(|(_, va, size)| (*va, *size))
+    }
+
+    fn bytes(&self, va: u64, size: usize) -> Option<&'a [u8]> {
+        self.maps.iter().find_map(|(mva, bytes)| {
+            let offset = usize::try_from(va.checked_sub(*mva)?).ok()?;
+            bytes.get(offset..offset.checked_add(size)?)
+        })
+    }
+
+    fn read_u32(&self, va: u64) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(va, 4)?.try_into().unwrap()))
+    }
+
+    /// The address a pointer at `va` points to; a pointer bound to an import (or a null
+    /// pointer) has no target in the image
+    fn pointer(&self, va: u64) -> Option<u64> {
+        if let Some(target) = self.fixups.get(&va) {
+            return *target;
+        }
+        let raw = u64::from_le_bytes(self.bytes(va, 8)?.try_into().unwrap());
+        (raw != 0).then_some(raw)
+    }
+
+    fn cstr(&self, va: u64) -> Option<String> {
+        let (mva, bytes) = self
+            .maps
+            .iter()
+            .find(|(mva, bytes)| *mva <= va && va - mva < bytes.len() as u64)?;
+        let rest = &bytes[(va - mva) as usize..];
+        let len = rest.iter().position(|b| *b == 0)?;
+        std::str::from_utf8(&rest[..len]).ok().map(str::to_string)
+    }
+
+    /// The pointers of a section of pointers, with their address
+    fn pointers(&self, section: &str) -> Vec<(u64, Option<u64>)> {
+        let (va, size) = match self.section(section) {
+            Some(section) => section,
+            None => return Vec::new(),
+        };
+        (0..size / 8)
+            .map(|i| va + i * 8)
+            .map(|va| (va, self.pointer(va)))
+            .collect()
+    }
+}
+
+/// A method implementation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjcMethod {
+    pub class: String,
+    pub selector: String,
+    pub imp: u64,
+    /// A `+` method, of the metaclass
+    pub is_class_method: bool,
+}
+
+impl ObjcMethod {
+    /// `-[Class selector]`, or `+[Class selector]` for a class method
+    pub fn name(&self) -> String {
+        let kind = if self.is_class_method { '+' } else { '-' };
+        format!("{}[{} {}]", kind, self.class, self.selector)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjcMetadata {
+    pub methods: Vec<ObjcMethod>,
+    /// The selector name of each selector reference
+    pub selrefs: BTreeMap<u64, String>,
+    /// The class name of each class reference, local or bound
+    pub classrefs: BTreeMap<u64, String>,
+    /// The superclass of each class defined in the image
+    pub superclasses: BTreeMap<String, String>,
+    /// The selector of each `__objc_stubs` entry
+    pub stubs: BTreeMap<u64, String>,
+}
+
+impl ObjcMetadata {
+    /// Read the Objective-C metadata of a 64-bit image; empty when there is none
+    pub fn parse(image: &Image<'_>) -> Self {
+        let mut meta = ObjcMetadata::default();
+        for (va, ptr) in image.pointers("__objc_selrefs") {
+            if let Some(name) = ptr.and_then(|ptr| image.cstr(ptr)) {
+                meta.selrefs.insert(va, name);
+            }
+        }
+        for (va, ptr) in image.pointers("__objc_classrefs") {
+            if let Some(name) = meta.class_name(image, va, ptr) {
+                meta.classrefs.insert(va, name);
+            }
+        }
+        for (_, ptr) in image.pointers("__objc_classlist") {
+            if let Some(class) = ptr {
+                meta.read_class(image, class);
+            }
+        }
+        for (_, ptr) in image.pointers("__objc_catlist") {
+            if let Some(category) = ptr {
+                meta.read_category(image, category);
+            }
+        }
+        if let Some((va, size)) = image.section("__objc_stubs") {
+            meta.read_stubs(image, va, size);
+        }
+        meta
+    }
+
+    /// The implementations `selector` may dispatch to. Sent to a class, only the class methods
+    /// of the class and its superclasses are candidates; sent to anything else, every instance
+    /// method of that name is.
+    pub fn implementations(&self, selector: &str, class: Option<&str>) -> Vec<&ObjcMethod> {
+        let mut classes = Vec::new();
+        let mut next = class;
+        while let Some(class) = next {
+            if classes.contains(&class) {
+                break;
+            }
+            classes.push(class);
+            next = self.superclasses.get(class).map(String::as_str);
+        }
+        let methods = self
+            .methods
+            .iter()
+            .filter(|method| method.selector == selector);
+        if classes.is_empty() {
+            return methods.filter(|method| !method.is_class_method).collect();
+        }
+        // the first class up the chain implementing it wins
+        classes
+            .iter()
+            .map(|class| {
+                methods
+                    .clone()
+                    .filter(|method| method.is_class_method && method.class == *class)
+                    .collect::<Vec<_>>()
+            })
+            .find(|found| !found.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// The name of the class a pointer at `va` refers to, read from the class or from the
+    /// `_OBJC_CLASS_$_` symbol it is bound to
+    fn class_name(&self, image: &Image<'_>, va: u64, ptr: Option<u64>) -> Option<String> {
+        match ptr {
+            Some(class) => {
+                let ro = image.pointer(class + 32)? & !7;
+                image.cstr(image.pointer(ro + 24)?)
+            }
+            None => image
+                .binds
+                .get(&va)?
+                .strip_prefix("_OBJC_CLASS_$_")
+                .map(str::to_string),
+        }
+    }
+
+    fn read_class(&mut self, image: &Image<'_>, class: u64) {
+        let name = match self.class_name(image, 0, Some(class)) {
+            Some(name) => name,
+            None => return,
+        };
+        if let Some(superclass) = self.class_name(image, class + 8, image.pointer(class + 8)) {
+            self.superclasses.insert(name.clone(), superclass);
+        }
+        let ro = image.pointer(class + 32).map(|ro| ro & !7);
+        if let Some(list) = ro.and_then(|ro| image.pointer(ro + 32)) {
+            self.read_methods(image, list, &name, false);
+        }
+        // the class methods are the instance methods of the metaclass
+        let meta_ro = image
+            .pointer(class)
+            .and_then(|meta| image.pointer(meta + 32))
+            .map(|ro| ro & !7);
+        if let Some(list) = meta_ro.and_then(|ro| image.pointer(ro + 32)) {
+            self.read_methods(image, list, &name, true);
+        }
+    }
+
+    fn read_category(&mut self, image: &Image<'_>, category: u64) {
+        let class = match self.class_name(image, category + 8, image.pointer(category + 8)) {
+            Some(class) => class,
+            None => return,
+        };
+        if let Some(list) = image.pointer(category + 16) {
+            self.read_methods(image, list, &class, false);
+        }
+        if let Some(list) = image.pointer(category + 24) {
+            self.read_methods(image, list, &class, true);
+        }
+    }
+
+    fn read_methods(&mut self, image: &Image<'_>, list: u64, class: &str, is_class_method: bool) {
+        let (flags, count) = match (image.read_u32(list), image.read_u32(list + 4)) {
+            (Some(flags), Some(count)) => (flags, count as u64),
+            _ => return,
+        };
+        // Relative method lists hold 32-bit offsets from each field: to the selector reference,
+        // the type string and the implementation
+        let relative = flags & 0x8000_0000 != 0;
+        let entsize = (flags & 0xfffc) as u64;
+        if entsize == 0 {
+            return;
+        }
+        for i in 0..count {
+            let entry = list + 8 + i * entsize;
+            let (selector, imp) = if relative {
+                let offset = |field: u64| {
+                    image
+                        .read_u32(entry + field)
+                        .map(|off| (entry + field).wrapping_add(off as i32 as i64 as u64))
+                };
+                let selref = offset(0);
+                let selector = selref.and_then(|selref| {
+                    self.selrefs
+                        .get(&selref)
+                        .cloned()
+                        .or_else(|| image.cstr(image.pointer(selref)?))
+                });
+                (selector, offset(8))
+            } else {
+                (
+                    image.pointer(entry).and_then(|name| image.cstr(name)),
+                    image.pointer(entry + 16),
+                )
+            };
+            if let (Some(selector), Some(imp)) = (selector, imp) {
+                self.methods.push(ObjcMethod {
+                    class: class.to_string(),
+                    selector,
+                    imp,
+                    is_class_method,
+                });
+            }
+        }
+    }
+
+    /// Each `__objc_stubs` entry starts by loading its selector into `x1`
+    fn read_stubs(&mut self, image: &Image<'_>, va: u64, size: u64) {
+        let bytes = match image.bytes(va, size as usize) {
+            Some(bytes) => bytes,
+            None => return,
+        };
+        for offset in (0..bytes.len()).step_by(4) {
+            let pc = va + offset as u64;
+            let adrp = a64_insn(bytes, offset).and_then(|insn| a64_adrp(pc, insn));
+            let ldr = a64_insn(bytes, offset + 4).and_then(a64_ldr);
+            if let (Some((reg, page)), Some((1, base, pageoff))) = (adrp, ldr) {
+                if let Some(selector) = self.selrefs.get(&(page + pageoff)).filter(|_| reg == base)
+                {
+                    self.stubs.insert(pc, selector.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The selector and the class loaded into the first two argument registers before `call_va`
+fn a64_message(
+    meta: &ObjcMetadata,
+    va: u64,
+    bytes: &[u8],
+    call_va: u64,
+) -> (Option<String>, Option<String>) {
+    let end = (call_va - va) as usize;
+    let start = end.saturating_sub(A64_WINDOW * 4);
+    let mut pages = [None; 32];
+    let (mut selector, mut class) = (None, None);
+    for offset in (start..end).step_by(4) {
+        let insn = match a64_insn(bytes, offset) {
+            Some(insn) => insn,
+            None => break,
+        };
+        // the argument registers don't survive a call
+        if insn & 0xfc00_0000 == 0x9400_0000 || insn & 0xffff_fc1f == 0xd63f_0000 {
+            (selector, class) = (None, None);
+        } else if let Some((reg, page)) = a64_adrp(va + offset as u64, insn) {
+            pages[reg as usize] = Some(page);
+        } else if let Some((target, base, pageoff)) = a64_ldr(insn) {
+            let addr = match pages[base as usize] {
+                Some(page) => page + pageoff,
+                None => continue,
+            };
+            match target {
+                0 => class = meta.classrefs.get(&addr).cloned().or(class),
+                1 => selector = meta.selrefs.get(&addr).cloned().or(selector),
+                _ => {}
+            }
+        }
+    }
+    (selector, class)
+}
+
+/// The same for x86-64, from `mov rsi, [rip + selref]` and `mov rdi, [rip + classref]`
+fn x86_message(
+    meta: &ObjcMetadata,
+    va: u64,
+    bytes: &[u8],
+    call_va: u64,
+) -> (Option<String>, Option<String>) {
+    let end = (call_va - va) as usize;
+    let start = end.saturating_sub(X86_WINDOW);
+    let (mut selector, mut class) = (None, None);
+    for offset in start..end {
+        let insn = match bytes.get(offset..offset + 7) {
+            Some(insn) if offset + 7 <= end && insn[..2] == [0x48, 0x8b] => insn,
+            _ => continue,
+        };
+        let disp = i32::from_le_bytes(insn[3..].try_into().unwrap());
+        let addr = (va + offset as u64 + 7).wrapping_add(disp as i64 as u64);
+        match insn[2] {
+            0x3d => class = meta.classrefs.get(&addr).cloned().or(class),
+            0x35 => selector = meta.selrefs.get(&addr).cloned().or(selector),
+            _ => {}
+        }
+    }
+    (selector, class)
+}
+
+/// Name the method implementations of `meta` and add them as entry points
+pub fn add_methods(workspace: &mut VivWorkspace, meta: &ObjcMetadata) {
+    for method in meta.methods.iter() {
+        let imp = method.imp as i32;
+        if !workspace.is_valid_pointer(imp) {
+            continue;
+        }
+        if workspace.get_name(imp, false).is_none() {
+            workspace.make_name(imp, method.name(), true, true);
+        }
+        workspace.add_entry_point(imp);
+    }
+}
+
+/// Find the message sends in the code of `workspace`, and record a call xref from each to the
+/// implementations its selector may dispatch to, with the message as a comment. Returns the
+/// number of message sends whose selector was recovered.
+pub fn link_message_sends(workspace: &mut VivWorkspace, meta: &ObjcMetadata) -> usize {
+    let arch = match Arch::from_envi(workspace.get_mem_architecture()) {
+        Some(arch @ (Arch::A64 | Arch::Amd64)) => arch,
+        _ => return 0,
+    };
+    let slots: BTreeSet<u64> = workspace
+        .get_imports()
+        .into_iter()
+        .filter(|(_, name)| name.rsplit('.').next() == Some("objc_msgSend"))
+        .map(|(va, _)| va as u32 as u64)
+        .collect();
+    let mut targets: BTreeSet<u64> = workspace
+        .get_import_stubs()
+        .into_iter()
+        .filter(|(_, slot)| slots.contains(&(*slot as u32 as u64)))
+        .map(|(va, _)| va as u32 as u64)
+        .collect();
+    // The workspace keeps the low 32 bits of addresses
+    let wrap = |refs: &BTreeMap<u64, String>| {
+        refs.iter()
+            .map(|(va, name)| (*va as u32 as u64, name.clone()))
+            .collect()
+    };
+    let meta = ObjcMetadata {
+        selrefs: wrap(&meta.selrefs),
+        classrefs: wrap(&meta.classrefs),
+        stubs: wrap(&meta.stubs),
+        ..meta.clone()
+    };
+    targets.extend(meta.stubs.keys());
+    let maps = workspace
+        .get_executable_maps()
+        .into_iter()
+        .map(|(va, bytes)| (va as u32 as u64, bytes.to_vec()))
+        .collect::<Vec<_>>();
+    let mut resolved = 0;
+    for (va, bytes) in maps.iter() {
+        for call in find_calls(arch, *va, bytes, &targets, &slots) {
+            let (selector, class) = match arch {
+                Arch::A64 => a64_message(&meta, *va, bytes, call.from),
+                _ => x86_message(&meta, *va, bytes, call.from),
+            };
+            let selector = match meta.stubs.get(&call.to).cloned().or(selector) {
+                Some(selector) => selector,
+                None => continue,
+            };
+            resolved += 1;
+            let from = call.from as i32;
+            for method in meta.implementations(&selector, class.as_deref()) {
+                workspace.add_xref(from, method.imp as i32, REF_CODE, BR_PROC);
+            }
+            let receiver = class.as_deref().unwrap_or("?");
+            workspace.set_comment(from, &format!("[{} {}]", receiver, selector), true);
+        }
+    }
+    debug!("Resolved {} Objective-C message sends", resolved);
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_sends() {
+        use crate::constants::{ARCH_A64, MM_EXEC, MM_READ};
+        // __objc_methname, __objc_selrefs and __objc_classrefs
+        let mut data = b"count\0Widget\0\0\0\0".to_vec();
+        data.extend(0x100008000u64.to_le_bytes());
+        data.extend(0x100008020u64.to_le_bytes());
+        // a class_t for Widget with a metaclass, class_ro_ts and method lists
+        let class = 0x100008020u64;
+        let words: [u64; 14] = [
+            // class: isa, superclass, cache, vtable, ro
+            class + 40,
+            0,
+            0,
+            0,
+            0x100008100,
+            // metaclass
+            0,
+            0,
+            0,
+            0,
+            0x100008180,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for word in words {
+            data.extend(word.to_le_bytes());
+        }
+        data.resize(0x100, 0);
+        // class_ro_t: flags, start, size, reserved, ivar layout, name, methods
+        let mut ro = vec![0u8; 24];
+        ro.extend(0x100008006u64.to_le_bytes());
+        ro.extend(0x100008200u64.to_le_bytes());
+        data.extend(&ro);
+        data.resize(0x180, 0);
+        data.extend(&ro[..24]);
+        data.extend(0x100008006u64.to_le_bytes());
+        data.extend(0x100008240u64.to_le_bytes());
+        data.resize(0x200, 0);
+        // a method list of one -[Widget count] at 0x100004000
+        data.extend(24u32.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(0x100008000u64.to_le_bytes());
+        data.extend(0u64.to_le_bytes());
+        data.extend(0x100004000u64.to_le_bytes());
+        data.resize(0x240, 0);
+        // and one +[Widget count] at 0x100004010
+        data.extend(24u32.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(0x100008000u64.to_le_bytes());
+        data.extend(0u64.to_le_bytes());
+        data.extend(0x100004010u64.to_le_bytes());
+        let classlist = class.to_le_bytes();
+
+        let code = [
+            // adrp x8, 0x100008000; ldr x1, [x8, #0x10]; bl objc_msgSend
+            0x90000028u32,
+            0xf9400901,
+            0x94000000 | 6,
+            // adrp x8, 0x100008000; ldr x0, [x8, #0x18]; ldr x1, [x8, #0x10]; bl objc_msgSend
+            0x90000028,
+            0xf9400d00,
+            0xf9400901,
+            0x94000000 | 2,
+            0xd503201f,
+            // the objc_msgSend stub, adrp x16, 0x10000c000; ldr x16, [x16]; br x16
+            0x90000050,
+            0xf9400210,
+            0xd61f0200,
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect::<Vec<_>>();
+
+        let mut image = Image::new();
+        image.add_map(0x100008000, &data);
+        image.add_map(0x100009000, &classlist);
+        image.add_section("__objc_selrefs", 0x100008010, 8);
+        image.add_section("__objc_classrefs", 0x100008018, 8);
+        image.add_section("__objc_classlist", 0x100009000, 8);
+        let meta = ObjcMetadata::parse(&image);
+        assert_eq!(meta.selrefs[&0x100008010], "count");
+        assert_eq!(meta.classrefs[&0x100008018], "Widget");
+        assert_eq!(meta.methods.len(), 2);
+        assert_eq!(meta.methods[1].name(), "+[Widget count]");
+        assert_eq!(meta.implementations("count", None)[0].imp, 0x100004000);
+        assert_eq!(
+            meta.implementations("count", Some("Widget"))[0].imp,
+            0x100004010
+        );
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_A64 as u32);
+        ws.add_memory_map(0x100004000u64 as i32, MM_READ | MM_EXEC, "test", code, None);
+        ws.make_import(0x10000c000u64 as i32, "libobjc", "objc_msgSend");
+        assert_eq!(crate::trampolines::link_import_stubs(&mut ws), 1);
+        assert_eq!(link_message_sends(&mut ws, &meta), 2);
+        let callees = |ws: &VivWorkspace, va: i32| {
+            ws.get_xrefs_from(va, Some(REF_CODE))
+                .iter()
+                .map(|xref| xref.1)
+                .collect::<Vec<_>>()
+        };
+        // the workspace keeps the low 32 bits of addresses
+        assert_eq!(callees(&ws, 0x4008), [0x4000, 0x4020]);
+        assert_eq!(callees(&ws, 0x4018), [0x4010, 0x4020]);
+        assert_eq!(ws.get_comment(0x4008), "[? count]");
+        assert_eq!(ws.get_comment(0x4018), "[Widget count]");
+    }
+}
@@ -0,0 +1,246 @@
+//! Names for the functions DLLs export by ordinal only.
+//!
+//! An import by ordinal names nothing but a number, `ws2_32.ORDINAL 19`, and a DLL exporting
+//! with `NONAME` leaves its exports nameless. [`OrdinalNames`] maps the ordinals of a DLL back
+//! to the names its import library knows them by, so the PE loader can name the import slot
+//! `ws2_32.send` and the export of `ws2_32.dll` itself `send`.
+//!
+//! The tables of the system DLLs with well known ordinals (winsock and OLE automation) are
+//! built in. Mappings for other DLLs, proprietary ones included, are added one by one or from
+//! their module-definition file (see [`crate::implib`] for making one from a DLL).
+
+use std::collections::HashMap;
+
+/// The Winsock 1.1 ordinals, which `wsock32` and `ws2_32` both keep
+const WINSOCK: &[(u16, &str)] = &[
+    (1, "accept"),
+    (2, "bind"),
+    (3, "closesocket"),
+    (4, "connect"),
+    (5, "getpeername"),
+    (6, "getsockname"),
+    (7, "getsockopt"),
+    (8, "htonl"),
+    (9, "htons"),
+    (10, "ioctlsocket"),
+    (11, "inet_addr"),
+    (12, "inet_ntoa"),
+    (13, "listen"),
+    (14, "ntohl"),
+    (15, "ntohs"),
+    (16, "recv"),
+    (17, "recvfrom"),
+    (18, "select"),
+    (19, "send"),
+    (20, "sendto"),
+    (21, "setsockopt"),
+    (22, "shutdown"),
+    (23, "socket"),
+    (51, "gethostbyaddr"),
+    (52, "gethostbyname"),
+    (53, "getprotobyname"),
+    (54, "getprotobynumber"),
+    (55, "getservbyname"),
+    (56, "getservbyport"),
+    (57, "gethostname"),
+    (101, "WSAAsyncSelect"),
+    (102, "WSAAsyncGetHostByAddr"),
+    (103, "WSAAsyncGetHostByName"),
+    (104, "WSAAsyncGetProtoByNumber"),
+    (105, "WSAAsyncGetProtoByName"),
+    (106, "WSAAsyncGetServByPort"),
+    (107, "WSAAsyncGetServByName"),
+    (108, "WSACancelAsyncRequest"),
+    (109, "WSASetBlockingHook"),
+    (110, "WSAUnhookBlockingHook"),
+    (111, "WSAGetLastError"),
+    (112, "WSASetLastError"),
+    (113, "WSACancelBlockingCall"),
+    (114, "WSAIsBlocking"),
+    (115, "WSAStartup"),
+    (116, "WSACleanup"),
+    (151, "__WSAFDIsSet"),
+    (500, "WEP"),
+];
+
+const OLEAUT32: &[(u16, &str)] = &[
+    (2, "SysAllocString"),
+    (3, "SysReAllocString"),
+    (4, "SysAllocStringLen"),
+    (5, "SysReAllocStringLen"),
+    (6, "SysFreeString"),
+    (7, "SysStringLen"),
+    (8, "VariantInit"),
+    (9, "VariantClear"),
+    (10, "VariantCopy"),
+    (11, "VariantCopyInd"),
+    (12, "VariantChangeType"),
+    (15, "SafeArrayCreate"),
+    (16, "SafeArrayDestroy"),
+    (17, "SafeArrayGetDim"),
+    (18, "SafeArrayGetElemsize"),
+    (19, "SafeArrayGetUBound"),
+    (20, "SafeArrayGetLBound"),
+    (21, "SafeArrayLock"),
+    (22, "SafeArrayUnlock"),
+    (23, "SafeArrayAccessData"),
+    (24, "SafeArrayUnaccessData"),
+    (25, "SafeArrayGetElement"),
+    (26, "SafeArrayPutElement"),
+    (27, "SafeArrayCopy"),
+    (147, "VariantChangeTypeEx"),
+    (148, "SafeArrayPtrOfIndex"),
+    (149, "SysStringByteLen"),
+    (150, "SysAllocStringByteLen"),
+    (161, "LoadTypeLib"),
+    (162, "LoadRegTypeLib"),
+    (163, "RegisterTypeLib"),
+    (200, "GetErrorInfo"),
+    (201, "SetErrorInfo"),
+    (202, "CreateErrorInfo"),
+];
+
+const KNOWN: &[(&str, &[(u16, &str)])] = &[
+    ("ws2_32", WINSOCK),
+    ("wsock32", WINSOCK),
+    ("oleaut32", OLEAUT32),
+];
+
+/// The names of exports by ordinal, by DLL
+#[derive(Clone, Debug, Default)]
+pub struct OrdinalNames {
+    dlls: HashMap<String, HashMap<u16, String>>,
+}
+
+impl OrdinalNames {
+    /// No names at all
+    pub fn new() -> Self {
+        OrdinalNames::default()
+    }
+
+    /// The names of the system DLLs with well known ordinals
+    pub fn known() -> Self {
+        let mut names = OrdinalNames::new();
+        for (dll, table) in KNOWN.iter() {
+            for (ordinal, name) in table.iter() {
+                names.add(dll, *ordinal, name);
+            }
+        }
+        names
+    }
+
+    /// Name the export `ordinal` of `dll`, `ws2_32` or `WS2_32.dll`, over any name it had
+    pub fn add(&mut self, dll: &str, ordinal: u16, name: &str) {
+        self.dlls
+            .entry(dll_key(dll))
+            .or_default()
+            .insert(ordinal, name.to_string());
+    }
+
+    /// Add the exports with an ordinal of a module-definition file. The DLL is the one of its
+    /// `LIBRARY` statement. Returns how many were added.
+    pub fn add_def(&mut self, def: &str) -> Result<usize, String> {
+        let mut dll = None;
+        let mut in_exports = false;
+        let mut added = 0;
+        for line in def.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let Some(first) = words.next() else {
+                continue;
+            };
+            match first {
+                "LIBRARY" | "NAME" => {
+                    dll = words.next().map(|name| name.trim_matches('"').to_string());
+                    in_exports = false;
+                }
+                "EXPORTS" => in_exports = true,
+                _ if !in_exports => {}
+                _ => {
+                    // name[=internal] [@ordinal] [NONAME] [DATA] [PRIVATE]
+                    let name = first.split('=').next().unwrap_or(first);
+                    let ordinal = line[first.len()..]
+                        .split_once('@')
+                        .and_then(|(_, rest)| rest.split_whitespace().next());
+                    let Some(ordinal) = ordinal else {
+                        continue;
+                    };
+                    let ordinal = ordinal
+                        .parse()
+                        .map_err(|_| format!("Bad ordinal of {}: {}", name, ordinal))?;
+                    let dll = dll
+                        .as_deref()
+                        .ok_or("The exports come before the LIBRARY statement")?;
+                    self.add(dll, ordinal, name.trim_matches('"'));
+                    added += 1;
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn get(&self, dll: &str, ordinal: u16) -> Option<&str> {
+        self.dlls
+            .get(&dll_key(dll))?
+            .get(&ordinal)
+            .map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dlls.is_empty()
+    }
+}
+
+/// The name of a DLL without its extension, in lower case
+fn dll_key(dll: &str) -> String {
+    let dll = dll.to_lowercase();
+    match dll.rsplit_once('.') {
+        Some((stem, "dll" | "drv" | "ocx" | "sys" | "exe")) => stem.to_string(),
+        _ => dll,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pe::import::{ImportTable, ImportedFunction},
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn names_ordinals() {
+        let mut names = OrdinalNames::known();
+        assert_eq!(names.get("WS2_32.dll", 19), Some("send"));
+        assert_eq!(names.get("wsock32", 115), Some("WSAStartup"));
+        assert_eq!(names.get("oleaut32.dll", 6), Some("SysFreeString"));
+        assert_eq!(names.get("ws2_32", 999), None);
+
+        let def = "LIBRARY \"acme.dll\"\nEXPORTS\n    Frob @1\n    Twiddle=_Twiddle@8 @ 2 NONAME\n    Unnumbered\n    Table @3 DATA ; the table\n";
+        assert_eq!(names.add_def(def), Ok(3));
+        assert_eq!(names.get("ACME", 2), Some("Twiddle"));
+        assert_eq!(names.get("acme.dll", 3), Some("Table"));
+        names.add("acme", 1, "Frobnicate");
+        assert_eq!(names.get("acme", 1), Some("Frobnicate"));
+        assert!(names.add_def("EXPORTS\n    Frob @x\n").is_err());
+    }
+
+    #[test]
+    fn names_ordinal_imports() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_ordinal_name("ACME.DLL", 7, "Frob");
+        let mut imports = ImportTable::default();
+        imports.add_function("WS2_32.dll", ImportedFunction::Ordinal(19));
+        imports.add_function("acme.dll", ImportedFunction::Ordinal(7));
+        let (pe, idata) = imports.tiny_image();
+        ws.load_from_bytes("tiny.exe", &pe, None);
+        let slots: Vec<i32> = idata.slots.iter().map(|slot| 0x400000 + slot.2 as i32).collect();
+        assert_eq!(
+            ws.get_imports(),
+            [
+                (slots[0], "ws2_32.send".to_string()),
+                (slots[1], "acme.Frob".to_string())
+            ]
+        );
+    }
+}
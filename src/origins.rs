@@ -0,0 +1,193 @@
+//! Where the functions, locations and xrefs of a workspace came from.
+//!
+//! Each one the workspace makes is put down with the analysis pass running at the time (or
+//! [`AnalysisStats::OTHER`] outside of one, for what loaders and users make) and how sure that
+//! pass was of it, as a percentage. Passes which guess, such as a linear sweep for function
+//! prologues, lower the confidence with `VivWorkspace::set_analysis_confidence` before making
+//! what they found, so their results can be filtered out with [`Origins::below`], and a bogus
+//! function can be traced back to the pass which made it.
+
+use crate::analysis::AnalysisStats;
+use std::{collections::BTreeMap, fmt};
+
+/// Something analysis makes in a workspace
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Artifact {
+    Function(i32),
+    Location(i32),
+    /// From, to and the reference type
+    Xref(i32, i32, i32),
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Artifact::Function(va) => write!(f, "function {:#x}", va),
+            Artifact::Location(va) => write!(f, "location {:#x}", va),
+            Artifact::Xref(from, to, rtype) => {
+                write!(f, "xref {:#x} -> {:#x} ({})", from, to, rtype)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Origin {
+    /// The name of the pass, as it is reported in [`AnalysisStats`]
+    pub pass: String,
+    /// 0 to [`Origin::CERTAIN`]
+    pub confidence: u8,
+}
+
+impl Origin {
+    pub const CERTAIN: u8 = 100;
+
+    pub fn new(pass: &str, confidence: u8) -> Self {
+        Origin {
+            pass: pass.to_string(),
+            confidence: confidence.min(Origin::CERTAIN),
+        }
+    }
+
+    /// Made outside of any pass, by a loader or a user
+    pub fn other() -> Self {
+        Origin::new(AnalysisStats::OTHER, Origin::CERTAIN)
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}%)", self.pass, self.confidence)
+    }
+}
+
+/// The origin of each artifact, by artifact
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origins {
+    origins: BTreeMap<Artifact, Origin>,
+}
+
+impl Origins {
+    pub fn new() -> Self {
+        Origins::default()
+    }
+
+    /// Put down the origin of an artifact. One made again keeps its first origin, unless it is
+    /// more certain now.
+    pub fn add(&mut self, artifact: Artifact, origin: Origin) {
+        match self.origins.get(&artifact) {
+            Some(old) if old.confidence >= origin.confidence => {}
+            _ => {
+                self.origins.insert(artifact, origin);
+            }
+        }
+    }
+
+    /// Replace the origin of an artifact, whatever it was
+    pub fn set(&mut self, artifact: Artifact, origin: Origin) {
+        self.origins.insert(artifact, origin);
+    }
+
+    pub fn get(&self, artifact: &Artifact) -> Option<&Origin> {
+        self.origins.get(artifact)
+    }
+
+    pub fn remove(&mut self, artifact: &Artifact) -> Option<Origin> {
+        self.origins.remove(artifact)
+    }
+
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Artifact, &Origin)> {
+        self.origins.iter()
+    }
+
+    /// The artifacts the named pass made
+    pub fn by_pass(&self, pass: &str) -> Vec<Artifact> {
+        self.origins
+            .iter()
+            .filter(|(_, origin)| origin.pass == pass)
+            .map(|(artifact, _)| *artifact)
+            .collect()
+    }
+
+    /// The artifacts made with less than `confidence`
+    pub fn below(&self, confidence: u8) -> Vec<Artifact> {
+        self.origins
+            .iter()
+            .filter(|(_, origin)| origin.confidence < confidence)
+            .map(|(artifact, _)| *artifact)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::Analyzer, constants::REF_CODE, workspace::VivWorkspace};
+    use std::sync::Arc;
+
+    struct Sweep;
+
+    impl Analyzer for Sweep {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            workspace.set_analysis_confidence(30);
+            workspace.add_function(0x2000, vec![(0x2000, 0x10)]);
+            workspace.add_xref(0x1004, 0x2000, REF_CODE, 0);
+        }
+
+        fn name(&self) -> &str {
+            "sweep"
+        }
+    }
+
+    #[test]
+    fn tracks_origins() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x1000, vec![(0x1000, 0x10)]);
+        ws.add_analyzer(Arc::new(Sweep));
+        ws.run_analyzers();
+
+        // what the pass made is in the workspace, as well as where it came from
+        assert!(ws.is_function(0x2000));
+        assert_eq!(
+            ws.get_xrefs_to(0x2000, None),
+            [(0x1004, 0x2000, REF_CODE, 0)]
+        );
+        assert_eq!(
+            ws.get_origin(&Artifact::Function(0x1000)),
+            Some(Origin::other())
+        );
+        assert_eq!(
+            ws.get_origin(&Artifact::Function(0x2000)),
+            Some(Origin::new("sweep", 30))
+        );
+        let origins = ws.get_origins();
+        assert_eq!(
+            origins.by_pass("sweep"),
+            [
+                Artifact::Function(0x2000),
+                Artifact::Xref(0x1004, 0x2000, REF_CODE)
+            ]
+        );
+        assert_eq!(origins.below(50).len(), 2);
+
+        // a copy keeps its own
+        let mut copy = ws.clone();
+        copy.del_function(0x2000);
+        assert_eq!(copy.get_origin(&Artifact::Function(0x2000)), None);
+        assert_eq!(ws.get_origins(), origins);
+
+        // a pass sure of what another guessed takes it over
+        ws.add_function(0x2000, vec![(0x2000, 0x10)]);
+        assert_eq!(ws.get_origins().below(50).len(), 1);
+        ws.del_function(0x2000);
+        assert_eq!(ws.get_origin(&Artifact::Function(0x2000)), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! What a workspace assumes about the runtime its files were built for.
+//!
+//! How a program starts, where its thread locals are, which runtime functions never return and
+//! how its imports bind all follow from the operating system and C library it targets. An
+//! [`OsProfile`] bundles those defaults so the analysis passes ask it instead of each guessing
+//! from the file format. A workspace takes its profile from its `OsProfile` meta when one was
+//! set, and otherwise infers it from the `Format` and `Platform` metas of the loaded file and,
+//! for ELF, the C library its interpreter belongs to (the `Libc` meta).
+
+use crate::{envi::Arch, tls::Layout, workspace::VivWorkspace};
+use std::{fmt, str::FromStr};
+
+/// The workspace meta holding a profile chosen by hand
+pub const META_OS_PROFILE: &str = "OsProfile";
+/// The workspace meta naming the C library of an ELF, from its interpreter
+pub const META_LIBC: &str = "Libc";
+
+/// The C runtime functions which never return everywhere
+const C_NO_RETURN: &[&str] = &["exit", "Exit", "quick_exit", "abort", "longjmp"];
+/// Those of the C++ runtime and the unwinder of the Itanium ABI
+const CXX_NO_RETURN: &[&str] = &[
+    "cxa_throw",
+    "cxa_rethrow",
+    "cxa_bad_cast",
+    "cxa_bad_typeid",
+    "cxa_pure_virtual",
+    "Unwind_Resume",
+    "ZSt9terminatev",
+];
+/// Those of the POSIX and BSD libcs
+const POSIX_NO_RETURN: &[&str] = &[
+    "siglongjmp",
+    "pthread_exit",
+    "err",
+    "errx",
+    "verr",
+    "verrx",
+    "stack_chk_fail",
+];
+const GLIBC_NO_RETURN: &[&str] = &[
+    "assert_fail",
+    "assert_perror_fail",
+    "chk_fail",
+    "fortify_fail",
+    "longjmp_chk",
+    "libc_start_main",
+];
+const MUSL_NO_RETURN: &[&str] = &["assert_fail", "libc_start_main"];
+const DARWIN_NO_RETURN: &[&str] = &["assert_rtn", "objc_exception_throw"];
+const WINDOWS_NO_RETURN: &[&str] = &[
+    "ExitProcess",
+    "ExitThread",
+    "FatalExit",
+    "FatalAppExitA",
+    "FatalAppExitW",
+    "RaiseFailFastException",
+    "RtlExitUserProcess",
+    "RtlExitUserThread",
+    "CxxThrowException",
+    "report_gsfailure",
+    "invalid_parameter_noinfo_noreturn",
+];
+/// newlib's, for firmware
+const BARE_METAL_NO_RETURN: &[&str] = &["assert_func", "stack_chk_fail"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OsProfile {
+    Windows,
+    /// Linux with the GNU C library
+    Glibc,
+    /// Linux with musl
+    Musl,
+    /// macOS and iOS
+    Darwin,
+    /// Firmware with no operating system
+    BareMetal,
+}
+
+/// How the code at the entry point gets to the program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryConvention {
+    /// The entry point is the CRT startup, which calls `main`, `WinMain` or `DllMain`
+    CrtStartup,
+    /// `_start` passes `main` to `__libc_start_main`, with the stack holding argc and argv
+    LibcStartMain,
+    /// dyld calls `main` with argc, argv, envp and the apple strings
+    Dyld,
+    /// The entry point is the reset handler, run with no arguments and no stack set up
+    Reset,
+}
+
+/// How imports bind to the libraries exporting them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportStyle {
+    /// By library and name, into the import address table
+    Iat,
+    /// By name alone, to the first library loaded defining it, through the GOT and PLT
+    Flat,
+    /// By library and name, through stubs and lazy pointers
+    TwoLevel,
+    /// Everything is linked in
+    Static,
+}
+
+impl OsProfile {
+    pub const ALL: [OsProfile; 5] = [
+        OsProfile::Windows,
+        OsProfile::Glibc,
+        OsProfile::Musl,
+        OsProfile::Darwin,
+        OsProfile::BareMetal,
+    ];
+
+    /// The profile of a file of `format` for `platform`, as the loaders name them, with the
+    /// C library `libc` if known
+    pub fn infer(format: &str, platform: &str, libc: Option<&str>) -> Option<OsProfile> {
+        match (format.to_lowercase().as_str(), platform.to_lowercase().as_str()) {
+            ("pe", _) | (_, "windows") => Some(OsProfile::Windows),
+            ("macho", _) | (_, "darwin") => Some(OsProfile::Darwin),
+            ("elf", _) | (_, "linux") => match libc {
+                Some("musl") => Some(OsProfile::Musl),
+                _ => Some(OsProfile::Glibc),
+            },
+            ("blob" | "ihex", _) | (_, "cortex-m" | "bios") => Some(OsProfile::BareMetal),
+            _ => None,
+        }
+    }
+
+    pub fn entry(&self) -> EntryConvention {
+        match self {
+            OsProfile::Windows => EntryConvention::CrtStartup,
+            OsProfile::Glibc | OsProfile::Musl => EntryConvention::LibcStartMain,
+            OsProfile::Darwin => EntryConvention::Dyld,
+            OsProfile::BareMetal => EntryConvention::Reset,
+        }
+    }
+
+    /// Where the TLS block of a thread on `arch` is, if threads have one
+    pub fn tls_layout(&self, arch: Arch) -> Option<Layout> {
+        match self {
+            OsProfile::Windows => Some(Layout::Indexed),
+            OsProfile::Darwin => Some(Layout::Descriptors),
+            OsProfile::Glibc | OsProfile::Musl => match arch {
+                Arch::I386 | Arch::Amd64 => Some(Layout::Below),
+                _ => Some(Layout::Above),
+            },
+            OsProfile::BareMetal => None,
+        }
+    }
+
+    pub fn imports(&self) -> ImportStyle {
+        match self {
+            OsProfile::Windows => ImportStyle::Iat,
+            OsProfile::Glibc | OsProfile::Musl => ImportStyle::Flat,
+            OsProfile::Darwin => ImportStyle::TwoLevel,
+            OsProfile::BareMetal => ImportStyle::Static,
+        }
+    }
+
+    /// Whether calls follow the Windows ABI, with its shadow space and argument registers on
+    /// amd64
+    pub fn windows_abi(&self) -> bool {
+        *self == OsProfile::Windows
+    }
+
+    /// The runtime functions which never return, without their leading underscores (see
+    /// [`crate::noreturn::NO_RETURN_APIS`])
+    pub fn no_return_apis(&self) -> Vec<&'static str> {
+        let lists: &[&[&str]] = match self {
+            OsProfile::Windows => &[C_NO_RETURN, CXX_NO_RETURN, WINDOWS_NO_RETURN],
+            OsProfile::Glibc => &[C_NO_RETURN, CXX_NO_RETURN, POSIX_NO_RETURN, GLIBC_NO_RETURN],
+            OsProfile::Musl => &[C_NO_RETURN, CXX_NO_RETURN, POSIX_NO_RETURN, MUSL_NO_RETURN],
+            OsProfile::Darwin => &[C_NO_RETURN, CXX_NO_RETURN, POSIX_NO_RETURN, DARWIN_NO_RETURN],
+            OsProfile::BareMetal => &[C_NO_RETURN, BARE_METAL_NO_RETURN],
+        };
+        lists.concat()
+    }
+}
+
+impl fmt::Display for OsProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OsProfile::Windows => "windows",
+            OsProfile::Glibc => "glibc",
+            OsProfile::Musl => "musl",
+            OsProfile::Darwin => "darwin",
+            OsProfile::BareMetal => "bare-metal",
+        })
+    }
+}
+
+impl FromStr for OsProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OsProfile::ALL
+            .into_iter()
+            .find(|profile| profile.to_string() == s)
+            .ok_or_else(|| format!("Unknown OS profile: {}", s))
+    }
+}
+
+/// The C library an ELF interpreter belongs to
+pub fn libc_of_interpreter(interpreter: &str) -> Option<&'static str> {
+    let name = interpreter.rsplit('/').next().unwrap_or(interpreter);
+    if name.starts_with("ld-musl") {
+        Some("musl")
+    } else if name.starts_with("ld-linux") || name.starts_with("ld64.so") {
+        Some("glibc")
+    } else {
+        None
+    }
+}
+
+/// Whether the workspace follows the Windows ABI by its profile
+pub fn is_windows(workspace: &VivWorkspace) -> bool {
+    workspace
+        .get_os_profile()
+        .is_some_and(|profile| profile.windows_abi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_and_overrides() {
+        let mut ws = VivWorkspace::new("", false);
+        assert_eq!(ws.get_os_profile(), None);
+        ws.set_meta("Format", Some("elf".to_string()));
+        ws.set_meta("Platform", Some("linux".to_string()));
+        assert_eq!(ws.get_os_profile(), Some(OsProfile::Glibc));
+        ws.set_meta(
+            META_LIBC,
+            libc_of_interpreter("/lib/ld-musl-x86_64.so.1").map(str::to_string),
+        );
+        let musl = ws.get_os_profile().unwrap();
+        assert_eq!(musl, OsProfile::Musl);
+        assert_eq!(musl.entry(), EntryConvention::LibcStartMain);
+        assert_eq!(musl.tls_layout(Arch::A64), Some(Layout::Above));
+        assert!(musl.no_return_apis().contains(&"assert_fail"));
+        assert!(!musl.no_return_apis().contains(&"fortify_fail"));
+
+        // one chosen by hand wins
+        ws.set_os_profile(Some(OsProfile::BareMetal));
+        assert_eq!(ws.get_os_profile(), Some(OsProfile::BareMetal));
+        assert_eq!(ws.get_os_profile().unwrap().imports(), ImportStyle::Static);
+        assert!(!is_windows(&ws));
+        assert_eq!("bare-metal".parse(), Ok(OsProfile::BareMetal));
+    }
+}
@@ -0,0 +1,292 @@
+//! The data appended after the end of a binary proper, and what the writers do with it.
+//!
+//! Installers, self-extractors and signing tools all append data the loader never looks at: an
+//! Authenticode signature at the end of a PE, the zip archive of a self-extracting executable,
+//! or an arbitrary payload (the overlay). The writers rebuild a binary in front of that data and
+//! then append it again, each kind of artifact kept or stripped as asked by [`AppendedData`].
+//!
+//! Zip archives are read from their end, so an archive always goes last (before a signature,
+//! which covers it), and archives whose offsets are relative to the start of the file are
+//! relocated to where they end up.
+
+use crate::error;
+use alloc::vec::Vec;
+use core::ops::Range;
+use scroll::{Pread, Pwrite};
+
+/// What a writer does with one kind of appended data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preserve {
+    #[default]
+    Keep,
+    Strip,
+}
+
+/// The choices for each kind of appended data; everything is kept by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AppendedData {
+    /// Appended data which is neither a signature nor an archive
+    pub overlay: Preserve,
+    /// The Authenticode signature of a PE, or the `LC_CODE_SIGNATURE` of a Mach-O. A rewritten
+    /// binary has to be signed again either way.
+    pub signature: Preserve,
+    /// The zip archive of a self-extracting executable
+    pub archive: Preserve,
+}
+
+impl AppendedData {
+    /// Strip every kind of appended data
+    pub fn strip_all() -> Self {
+        AppendedData {
+            overlay: Preserve::Strip,
+            signature: Preserve::Strip,
+            archive: Preserve::Strip,
+        }
+    }
+
+    fn preserve(&self, kind: ArtifactKind) -> Preserve {
+        match kind {
+            ArtifactKind::Overlay => self.overlay,
+            ArtifactKind::Signature => self.signature,
+            ArtifactKind::Archive => self.archive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Overlay,
+    Signature,
+    Archive,
+}
+
+/// A run of appended data and where it is in the input file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub range: Range<usize>,
+}
+
+const EOCD_MAGIC: u32 = 0x0605_4b50;
+const CENTRAL_MAGIC: u32 = 0x0201_4b50;
+const LOCAL_MAGIC: u32 = 0x0403_4b50;
+const SIZEOF_EOCD: usize = 22;
+const SIZEOF_CENTRAL_HEADER: usize = 46;
+
+/// The end of central directory record of a zip archive ending at `end`, no earlier than `start`
+fn find_eocd(bytes: &[u8], start: usize, end: usize) -> Option<usize> {
+    let last = end.checked_sub(SIZEOF_EOCD)?;
+    // the record ends with a comment of at most 64k
+    let first = last.saturating_sub(0xffff).max(start);
+    (first..=last).rev().find(|&at| {
+        bytes.pread_with::<u32>(at, scroll::LE).ok() == Some(EOCD_MAGIC)
+            && bytes
+                .pread_with::<u16>(at + 20, scroll::LE)
+                .is_ok_and(|comment| at + SIZEOF_EOCD + usize::from(comment) == end)
+    })
+}
+
+/// The offsets of the local headers listed in the central directory at `cd`
+fn local_headers(bytes: &[u8], mut cd: usize, entries: u16) -> Option<Vec<u32>> {
+    let mut offsets = Vec::with_capacity(usize::from(entries));
+    for _ in 0..entries {
+        if bytes.pread_with::<u32>(cd, scroll::LE).ok()? != CENTRAL_MAGIC {
+            return None;
+        }
+        let name = bytes.pread_with::<u16>(cd + 28, scroll::LE).ok()?;
+        let extra = bytes.pread_with::<u16>(cd + 30, scroll::LE).ok()?;
+        let comment = bytes.pread_with::<u16>(cd + 32, scroll::LE).ok()?;
+        offsets.push(bytes.pread_with::<u32>(cd + 42, scroll::LE).ok()?);
+        cd += SIZEOF_CENTRAL_HEADER + usize::from(name) + usize::from(extra) + usize::from(comment);
+    }
+    Some(offsets)
+}
+
+/// The zip archive making up the end of `bytes[start..end]`, if there is one. Archives whose
+/// offsets are relative to the start of the file and ones whose offsets are relative to the
+/// archive itself are both recognized.
+pub fn find_zip(bytes: &[u8], start: usize, end: usize) -> Option<Range<usize>> {
+    let eocd = find_eocd(bytes, start, end)?;
+    let entries = bytes.pread_with::<u16>(eocd + 10, scroll::LE).ok()?;
+    let cd_size = bytes.pread_with::<u32>(eocd + 12, scroll::LE).ok()? as usize;
+    let cd_offset = bytes.pread_with::<u32>(eocd + 16, scroll::LE).ok()? as usize;
+    let cd = eocd.checked_sub(cd_size).filter(|&cd| cd >= start)?;
+    // the central directory says where it is relative to whatever the offsets are relative to
+    let base = cd.checked_sub(cd_offset)?;
+    let archive = match local_headers(bytes, cd, entries)?.into_iter().min() {
+        Some(first) => base + first as usize,
+        None => cd,
+    };
+    if archive < start
+        || (entries != 0 && bytes.pread_with::<u32>(archive, scroll::LE).ok() != Some(LOCAL_MAGIC))
+    {
+        return None;
+    }
+    Some(archive..end)
+}
+
+/// Split the data from `start` to the end of `bytes` into the signature at `signature` (if
+/// any), a zip archive right before it or at the end, and the overlay around them.
+pub fn artifacts(bytes: &[u8], start: usize, signature: Option<Range<usize>>) -> Vec<Artifact> {
+    let mut ret = Vec::new();
+    let mut push = |kind, range: Range<usize>| {
+        if !range.is_empty() {
+            ret.push(Artifact { kind, range });
+        }
+    };
+    let signature = signature.filter(|s| s.start >= start && s.end <= bytes.len());
+    let end = signature.as_ref().map_or(bytes.len(), |s| s.start);
+    match find_zip(bytes, start, end) {
+        Some(archive) => {
+            push(ArtifactKind::Overlay, start..archive.start);
+            push(ArtifactKind::Archive, archive);
+        }
+        None => push(ArtifactKind::Overlay, start..end),
+    }
+    if let Some(signature) = signature {
+        push(ArtifactKind::Signature, signature.clone());
+        push(ArtifactKind::Overlay, signature.end..bytes.len());
+    }
+    ret
+}
+
+/// Append the `artifacts` of `bytes` which `options` keeps to `out`, relocating a zip archive
+/// to its new place. A signature is aligned to 8 bytes, as Authenticode requires; its new range
+/// is returned if it was kept.
+pub fn append_artifacts(
+    out: &mut Vec<u8>,
+    bytes: &[u8],
+    artifacts: &[Artifact],
+    options: &AppendedData,
+) -> error::Result<Option<Range<usize>>> {
+    let mut signature = None;
+    for artifact in artifacts {
+        if options.preserve(artifact.kind) == Preserve::Strip {
+            continue;
+        }
+        let data = bytes.get(artifact.range.clone()).ok_or_else(|| {
+            error::Error::Malformed(format!(
+                "Appended data at {:#x} is truncated",
+                artifact.range.start
+            ))
+        })?;
+        match artifact.kind {
+            ArtifactKind::Signature => {
+                out.resize(out.len().div_ceil(8) * 8, 0);
+                signature = Some(out.len()..out.len() + data.len());
+                out.extend_from_slice(data);
+            }
+            ArtifactKind::Archive => {
+                let at = out.len();
+                out.extend_from_slice(data);
+                relocate_zip(&mut out[at..], artifact.range.start, at)?;
+            }
+            ArtifactKind::Overlay => out.extend_from_slice(data),
+        }
+    }
+    Ok(signature)
+}
+
+/// Move the offsets of `archive` from `from` to `to`, if they are relative to the file
+fn relocate_zip(archive: &mut [u8], from: usize, to: usize) -> error::Result<()> {
+    let eocd = match find_eocd(archive, 0, archive.len()) {
+        Some(eocd) => eocd,
+        None => return Ok(()),
+    };
+    let entries: u16 = archive.pread_with(eocd + 10, scroll::LE)?;
+    let cd_size: u32 = archive.pread_with(eocd + 12, scroll::LE)?;
+    let cd_offset: u32 = archive.pread_with(eocd + 16, scroll::LE)?;
+    let cd = eocd - cd_size as usize;
+    // zip64 archives keep their offsets elsewhere
+    if from == to || cd_offset as usize != from + cd || cd_offset == u32::MAX {
+        return Ok(());
+    }
+    let moved = |offset: u32| (offset as usize + to - from) as u32;
+    archive.pwrite_with(moved(cd_offset), eocd + 16, scroll::LE)?;
+    let mut at = cd;
+    for _ in 0..entries {
+        let name: u16 = archive.pread_with(at + 28, scroll::LE)?;
+        let extra: u16 = archive.pread_with(at + 30, scroll::LE)?;
+        let comment: u16 = archive.pread_with(at + 32, scroll::LE)?;
+        let local: u32 = archive.pread_with(at + 42, scroll::LE)?;
+        archive.pwrite_with(moved(local), at + 42, scroll::LE)?;
+        at += SIZEOF_CENTRAL_HEADER + usize::from(name) + usize::from(extra) + usize::from(comment);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stored zip archive of one file, with offsets relative to `base`
+    fn zip(base: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&LOCAL_MAGIC.to_le_bytes());
+        out.extend_from_slice(&[0u8; 22]);
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(b"a");
+        let cd = out.len();
+        out.extend_from_slice(&CENTRAL_MAGIC.to_le_bytes());
+        out.extend_from_slice(&[0u8; 24]);
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&[0u8; 12]);
+        out.extend_from_slice(&base.to_le_bytes());
+        out.extend_from_slice(b"a");
+        let cd_size = out.len() - cd;
+        out.extend_from_slice(&EOCD_MAGIC.to_le_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        out.extend_from_slice(&(cd_size as u32).to_le_bytes());
+        out.extend_from_slice(&(base + cd as u32).to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend_from_slice(b"!!");
+        out
+    }
+
+    #[test]
+    fn split_and_relocate() {
+        let mut bytes = vec![0xcc; 0x40];
+        bytes.extend_from_slice(b"payload");
+        bytes.extend_from_slice(&zip(0x47));
+        let end = bytes.len();
+        bytes.extend_from_slice(b"signature");
+        let found = artifacts(&bytes, 0x40, Some(end..bytes.len()));
+        let kinds = found.iter().map(|a| a.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ArtifactKind::Overlay,
+                ArtifactKind::Archive,
+                ArtifactKind::Signature
+            ]
+        );
+        assert_eq!(found[1].range, 0x47..end);
+
+        // the archive moves back by the stripped overlay, and the signature gets aligned
+        let mut out = vec![0xcc; 0x40];
+        let options = AppendedData {
+            overlay: Preserve::Strip,
+            ..Default::default()
+        };
+        let signature = append_artifacts(&mut out, &bytes, &found, &options).unwrap();
+        let archive = 0x40..0x40 + end - 0x47;
+        assert_eq!(find_zip(&out, 0x40, archive.end), Some(archive.clone()));
+        assert_eq!(out[archive], zip(0x40)[..]);
+        let signature = signature.unwrap();
+        assert_eq!(signature.start % 8, 0);
+        assert_eq!(&out[signature], b"signature");
+
+        // offsets relative to the archive don't change
+        let mut bytes = vec![0xcc; 0x40];
+        bytes.extend_from_slice(&zip(0));
+        let found = artifacts(&bytes, 0x40, None);
+        assert_eq!(found[0].kind, ArtifactKind::Archive);
+        let mut out = vec![0xcc; 0x20];
+        append_artifacts(&mut out, &bytes, &found, &AppendedData::default()).unwrap();
+        assert_eq!(out[0x20..], zip(0)[..]);
+        let mut out = Vec::new();
+        append_artifacts(&mut out, &bytes, &found, &AppendedData::strip_all()).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! User overrides of what analysis may make of a range of addresses.
+//!
+//! Firmware interrupt vector tables look like code to a linear sweep, and packed regions
+//! disassemble into plausible garbage. A [`RegionKind`] marks a VA range as never code, always
+//! code or always data; the workspace keeps them in [`Overrides`], refuses to create functions,
+//! entry points or pointers against them, and saves them with its annotations so re-analysis
+//! honours them too.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionKind {
+    /// Nothing in the range is code, whatever points into it
+    NeverCode,
+    /// The range is code: no data locations are made in it, and its start is an entry point
+    ForceCode,
+    /// The range is data: neither code nor functions are made in it
+    ForceData,
+}
+
+impl RegionKind {
+    pub fn allows_code(&self) -> bool {
+        *self == RegionKind::ForceCode
+    }
+
+    pub fn allows_data(&self) -> bool {
+        *self != RegionKind::ForceCode
+    }
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RegionKind::NeverCode => "never-code",
+            RegionKind::ForceCode => "force-code",
+            RegionKind::ForceData => "force-data",
+        })
+    }
+}
+
+impl FromStr for RegionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never-code" => Ok(RegionKind::NeverCode),
+            "force-code" => Ok(RegionKind::ForceCode),
+            "force-data" => Ok(RegionKind::ForceData),
+            _ => Err(format!("Unknown region override: {}", s)),
+        }
+    }
+}
+
+/// The overridden regions, as start VA to (size, kind). Where regions overlap, the one starting
+/// last wins, so a small region carves an exception out of a larger one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Overrides {
+    regions: BTreeMap<i32, (i32, RegionKind)>,
+}
+
+impl Overrides {
+    pub fn new() -> Self {
+        Overrides::default()
+    }
+
+    /// Override `size` bytes from `va`, replacing any region starting at `va`
+    pub fn add(&mut self, va: i32, size: i32, kind: RegionKind) {
+        self.regions.insert(va, (size, kind));
+    }
+
+    pub fn remove(&mut self, va: i32) -> Option<(i32, RegionKind)> {
+        self.regions.remove(&va)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// The override in effect at `va`, if any
+    pub fn kind_at(&self, va: i32) -> Option<RegionKind> {
+        self.regions
+            .range(..=va)
+            .rev()
+            .find(|(start, (size, _))| (va as i64) < **start as i64 + *size as i64)
+            .map(|(_, (_, kind))| *kind)
+    }
+
+    /// Whether analysis may make code at `va`. Addresses without an override are left to
+    /// analysis.
+    pub fn allows_code(&self, va: i32) -> bool {
+        self.kind_at(va).is_none_or(|kind| kind.allows_code())
+    }
+
+    /// Whether analysis may make data locations at `va`
+    pub fn allows_data(&self, va: i32) -> bool {
+        self.kind_at(va).is_none_or(|kind| kind.allows_data())
+    }
+
+    /// The regions as (va, size, kind), by VA
+    pub fn iter(&self) -> impl Iterator<Item = (i32, i32, RegionKind)> + '_ {
+        self.regions
+            .iter()
+            .map(|(va, (size, kind))| (*va, *size, *kind))
+    }
+}
+
+impl FromIterator<(i32, i32, RegionKind)> for Overrides {
+    fn from_iter<I: IntoIterator<Item = (i32, i32, RegionKind)>>(iter: I) -> Self {
+        let mut overrides = Overrides::new();
+        for (va, size, kind) in iter {
+            overrides.add(va, size, kind);
+        }
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{journal::Event, storage::Annotations, workspace::VivWorkspace};
+
+    #[test]
+    fn region_overrides() {
+        let mut overrides = Overrides::new();
+        overrides.add(0x1000, 0x100, RegionKind::ForceData);
+        overrides.add(0x1040, 0x10, RegionKind::ForceCode);
+        assert_eq!(overrides.kind_at(0x1000), Some(RegionKind::ForceData));
+        assert_eq!(overrides.kind_at(0x1048), Some(RegionKind::ForceCode));
+        assert_eq!(overrides.kind_at(0x1050), Some(RegionKind::ForceData));
+        assert_eq!(overrides.kind_at(0x1100), None);
+        assert!(!overrides.allows_code(0x1000) && overrides.allows_code(0x1040));
+        assert!(!overrides.allows_data(0x1040) && overrides.allows_code(0x2000));
+        assert_eq!("never-code".parse(), Ok(RegionKind::NeverCode));
+
+        // Overrides drop what analysis already made and keep it from coming back
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x1000, 0x10), (0x2000, 0x10)]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.add_entry_point(0x1000);
+        ws.set_function_args(0x1000, vec![(String::new(), "argc".to_string())]);
+        ws.start_journal();
+        let (_, watch) = ws.watch(0x1000, 1);
+        ws.add_region_override(0x1000, 0x100, RegionKind::NeverCode);
+        assert!(!ws.is_function(0x1000) && ws.is_function(0x2000));
+        assert!(ws.get_function_args(0x1000).is_empty());
+        let events: Vec<Event> = watch.try_iter().collect();
+        assert!(events.contains(&Event::DelFunction { fva: 0x1000 }));
+        let mut replayed = VivWorkspace::new("", false);
+        replayed.apply_annotations(&ann);
+        ws.journal().unwrap().replay(&mut replayed);
+        assert_eq!(replayed.get_functions(), [0x2000]);
+        assert_eq!(replayed.get_region_overrides(), ws.get_region_overrides());
+        assert!(ws.get_entry_points().is_empty());
+        ws.add_entry_point(0x1010);
+        assert!(ws.get_entry_points().is_empty());
+        ws.add_region_override(0x3000, 0x10, RegionKind::ForceCode);
+        assert_eq!(ws.get_entry_points(), vec![0x3000]);
+
+        let mut saved = Vec::new();
+        ws.get_annotations().write(&mut saved).unwrap();
+        let loaded = Annotations::read(saved.as_slice()).unwrap();
+        let mut fresh = VivWorkspace::new("", false);
+        fresh.apply_annotations(&loaded);
+        assert_eq!(
+            fresh.get_region_overrides(),
+            vec![
+                (0x1000, 0x100, RegionKind::NeverCode),
+                (0x3000, 0x10, RegionKind::ForceCode)
+            ]
+        );
+        fresh.del_region_override(0x1000);
+        assert_eq!(fresh.get_region_override(0x1000), None);
+    }
+}
@@ -1,20 +1,114 @@
 #![allow(dead_code, unused)]
 
-use crate::constants::ARCH_DEFAULT;
+#[cfg(feature = "objc")]
+use crate::blocks;
+use crate::constants::{
+    ARCH_A64, ARCH_AMD64, ARCH_ARMV7, ARCH_DEFAULT, ARCH_I386, ARCH_S390X, ARCH_SPARC,
+    ARCH_SPARC64, MM_EXEC, MM_READ, MM_WRITE, RTYPE_BASERELOC, RTYPE_FIXUP,
+};
+use crate::elf::{header as elf_header, program_header, sym as elf_sym, Elf};
 use crate::ihex::IHexFile;
+use crate::loader;
+use crate::mach::{
+    constants::{S_INIT_FUNC_OFFSETS, S_MOD_INIT_FUNC_POINTERS, S_MOD_TERM_FUNC_POINTERS},
+    cputype,
+    fixups::{ChainedFixups, Fixup},
+    imports::Dylib,
+    load_command::platform_to_str,
+    Mach, MachO,
+};
 use crate::memory::Memory;
+#[cfg(feature = "objc")]
+use crate::objc::{self, Image, ObjcMetadata};
+use crate::osprofile;
+use crate::pe::{export::ExportAddressTableEntry, header as pe_header, section_table, PE};
+use crate::realmode;
+use crate::staticpie;
+use crate::symcache::content_id;
+use crate::trampolines::link_import_stubs;
 use crate::workspace::VivWorkspace;
-use log::{debug, error, info};
+use crate::Object;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Cursor, Read};
+use std::path::Path;
 
-pub fn parse_file(mut workspace: VivWorkspace, filename: &str, _base_addr: Option<i32>) -> String {
+/// Parse the given file into the workspace, picking the parser from the file contents: the
+/// first loader of the workspace to take it (see [`crate::loader`]), or else a built in one.
+/// Returns the normalized name the file was added to the workspace under.
+pub fn parse_file(workspace: &mut VivWorkspace, filename: &str, base_addr: Option<i32>) -> String {
+    let contents = fs::read(filename).expect("Error reading the file.");
+    parse_contents(workspace, filename, &contents, base_addr)
+}
+
+/// Parse the contents of a file into the workspace as [`parse_file`] does, for contents which
+/// don't come from the file system.
+pub fn parse_contents(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    contents: &[u8],
+    base_addr: Option<i32>,
+) -> String {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("load", file = filename).entered();
+    let object = Object::parse(contents).ok();
+    let fname = match loader::load(workspace, filename, contents, base_addr) {
+        Some(fname) => fname,
+        None => parse_builtin(workspace, filename, contents, object.as_ref(), base_addr),
+    };
+    if let Some(module_id) = object.and_then(|obj| obj.uuid(contents)) {
+        workspace.set_module_id(&fname, module_id);
+    }
+    workspace.set_content_id(&fname, content_id(contents));
+    fname
+}
+
+/// Parse the contents with the built in parser for the `object` they were parsed as. The first
+/// arch of a fat Mach-O which parses is taken; the contents go to the raw parsers otherwise.
+fn parse_builtin(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    contents: &[u8],
+    object: Option<&Object>,
+    base_addr: Option<i32>,
+) -> String {
+    match object {
+        Some(Object::PE(pe)) => return parse_pe(workspace, filename, contents, pe),
+        Some(Object::Elf(elf)) => return parse_elf(workspace, filename, contents, elf, base_addr),
+        Some(Object::Mach(Mach::Binary(macho))) => {
+            return parse_macho(workspace, filename, contents, macho, base_addr)
+        }
+        Some(Object::Mach(Mach::Fat(fat))) => {
+            for (i, macho) in fat.into_iter().enumerate() {
+                match macho {
+                    Ok(macho) => {
+                        return parse_macho(workspace, filename, contents, &macho, base_addr)
+                    }
+                    Err(e) => anomaly!("Skipping fat arch {} of {}: {}", i, filename, e),
+                }
+            }
+            anomaly!("No fat arch of {} parses, loading it raw", filename);
+        }
+        _ => {}
+    }
+    match realmode::load(workspace, filename, contents.to_vec()) {
+        Some((fname, _)) => fname,
+        None => parse_ihex(workspace, filename, contents.to_vec(), base_addr),
+    }
+}
+
+pub fn parse_ihex(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    contents: Vec<u8>,
+    _base_addr: Option<i32>,
+) -> String {
     workspace.set_meta("Architecture", Some(ARCH_DEFAULT.to_string()));
     workspace.set_meta("Platform", Some("Unknown".to_string()));
     workspace.set_meta("Format", Some("ihex".to_string()));
     let offset = 0;
     let ihex = IHexFile::new();
-    let contents = fs::read(filename).expect("Error reading the file.");
     let mut cursor = Cursor::new(contents);
     let mut shdr = Vec::with_capacity(offset);
     cursor.read(&mut shdr).unwrap();
@@ -43,3 +137,954 @@ pub fn parse_file(mut workspace: VivWorkspace, filename: &str, _base_addr: Optio
     }
     fname
 }
+
+pub fn parse_pe(workspace: &mut VivWorkspace, filename: &str, bytes: &[u8], pe: &PE) -> String {
+    let arch = match pe.header.coff_header.machine {
+        pe_header::COFF_MACHINE_X86 => ARCH_I386,
+        pe_header::COFF_MACHINE_X86_64 => ARCH_AMD64,
+        pe_header::COFF_MACHINE_ARM | pe_header::COFF_MACHINE_ARMNT => ARCH_ARMV7,
+        _ => ARCH_DEFAULT as i32,
+    };
+    set_load_meta(workspace, arch, "windows", "pe", pe.is_64, false);
+    let baseaddr = pe.image_base as i32;
+    let fname = workspace.add_file(filename, baseaddr, bytes.to_vec());
+    // The headers are mapped too, the loader does the same.
+    let hdr_size = pe
+        .header
+        .optional_header
+        .map(|opt| opt.windows_fields.size_of_headers as usize)
+        .unwrap_or(0)
+        .min(bytes.len());
+    if hdr_size > 0 {
+        workspace.add_memory_map(baseaddr, MM_READ, &fname, bytes[..hdr_size].to_vec(), None);
+        workspace.add_segment(baseaddr, hdr_size as i32, "PE_Header", fname.clone());
+    }
+    for section in pe.sections.iter() {
+        let sva = baseaddr.wrapping_add(section.virtual_address as i32);
+        let mut perms = 0;
+        if section.characteristics & section_table::IMAGE_SCN_MEM_READ != 0 {
+            perms |= MM_READ;
+        }
+        if section.characteristics & section_table::IMAGE_SCN_MEM_WRITE != 0 {
+            perms |= MM_WRITE;
+        }
+        if section.characteristics & section_table::IMAGE_SCN_MEM_EXECUTE != 0 {
+            perms |= MM_EXEC;
+        }
+        let vsize = match section.virtual_size {
+            0 => section.size_of_raw_data,
+            vsize => vsize,
+        } as usize;
+        let sname = section.name().unwrap_or("").to_string();
+        if !fits_map(sva, vsize, &sname) {
+            continue;
+        }
+        let sbytes = file_bytes(
+            bytes,
+            section.pointer_to_raw_data as usize,
+            (section.size_of_raw_data as usize).min(vsize),
+            vsize,
+            &sname,
+        );
+        if sbytes.is_empty() {
+            anomaly!("Skipping the empty section {} at {:#x}", sname, sva);
+            continue;
+        }
+        workspace.add_memory_map(sva, perms, &fname, sbytes, None);
+        workspace.add_segment(sva, vsize as i32, &sname, fname.clone());
+    }
+    if pe.entry != 0 {
+        workspace.add_entry_point(baseaddr.wrapping_add(pe.entry as i32));
+    }
+    for export in pe.exports.iter() {
+        if export.reexport.is_some() {
+            continue;
+        }
+        let eva = baseaddr.wrapping_add(export.rva as i32);
+        if let Some(name) = export.name {
+            workspace.add_export(eva, name, &fname);
+        }
+        if workspace.is_executable(eva) {
+            workspace.add_entry_point(eva);
+        }
+    }
+    // The exports without a name (NONAME) go by the names known for their ordinals
+    if let (Some(dll), Some(data)) = (pe.name, &pe.export_data) {
+        let base = data.export_directory_table.ordinal_base;
+        for (idx, entry) in data.export_address_table.iter().enumerate() {
+            let ExportAddressTableEntry::ExportRVA(rva) = *entry else {
+                continue;
+            };
+            if rva == 0 || data.export_ordinal_table.contains(&(idx as u16)) {
+                continue;
+            }
+            let Some(name) = workspace.get_ordinal_name(dll, (base as usize + idx) as u16) else {
+                continue;
+            };
+            let eva = baseaddr.wrapping_add(rva as i32);
+            workspace.add_export(eva, &name, &fname);
+            if workspace.is_executable(eva) {
+                workspace.add_entry_point(eva);
+            }
+        }
+    }
+    // The exception directory lists every non-leaf function of an x64 image, with the code
+    // chained to it.
+    if let (ARCH_AMD64, Some(exceptions)) = (arch, &pe.exception_data) {
+        for extent in exceptions
+            .function_extents(&pe.sections)
+            .unwrap_or_default()
+        {
+            let fva = baseaddr.wrapping_add(extent.begin_address as i32);
+            if !workspace.is_executable(fva) {
+                continue;
+            }
+            let ranges = extent
+                .ranges
+                .iter()
+                .map(|(begin, end)| {
+                    let va = baseaddr.wrapping_add(*begin as i32);
+                    (va, end.saturating_sub(*begin) as i32)
+                })
+                .collect();
+            workspace.set_function_bounds(fva, ranges);
+            workspace.add_entry_point(fva);
+        }
+    }
+    // The native code of a precompiled .NET image goes by the methods it compiles
+    if let Some(dotnet) = pe.dotnet(bytes) {
+        workspace.set_meta("DotNet", Some(dotnet.kind.to_string()));
+        if let Some(rva) = dotnet.native_entry() {
+            workspace.add_entry_point(baseaddr.wrapping_add(rva as i32));
+        }
+        for method in dotnet.ready_to_run.iter().flat_map(|r2r| r2r.methods.iter()) {
+            let va = baseaddr.wrapping_add(method.rva as i32);
+            if !workspace.is_executable(va) {
+                continue;
+            }
+            if workspace.get_name(va, false).is_none() {
+                let name = format!("methoddef_{:08x}", method.token);
+                workspace.make_name(va, name, true, true);
+            }
+            workspace.add_entry_point(va);
+        }
+    }
+    // The base relocations patch every absolute address in the image
+    let relocs = pe
+        .header
+        .optional_header
+        .and_then(|opt| *opt.data_directories.get_base_relocation_table());
+    if let Some(table) = relocs {
+        let tva = baseaddr.wrapping_add(table.virtual_address as i32);
+        // The size is the file's say, so the table has to be in one readable map
+        let size = table.size as i64;
+        let table = match workspace.get_memory_map(tva) {
+            Some((mva, msize, perms, _))
+                if perms & MM_READ != 0 && size <= mva as i64 + msize as i64 - tva as i64 =>
+            {
+                workspace.read_memory(tva, size as i32)
+            }
+            _ => None,
+        };
+        match table {
+            Some(table) => {
+                for (rva, size) in base_relocations(&table) {
+                    let ptr_size = if pe.is_64 { 8 } else { 4 };
+                    add_fixup(
+                        workspace,
+                        baseaddr.wrapping_add(rva as i32),
+                        size,
+                        size == ptr_size,
+                    );
+                }
+            }
+            None => anomaly!(
+                "The base relocations of {} at {:#x}, {:#x} bytes, aren't mapped",
+                fname,
+                tva,
+                size
+            ),
+        }
+    }
+    for import in pe.imports.iter() {
+        let libname = import.dll.split('.').next().unwrap_or(import.dll);
+        // An import by ordinal has no hint/name entry, and goes by the name known for it
+        let name = match import.rva {
+            0 => workspace.get_ordinal_name(import.dll, import.ordinal),
+            _ => None,
+        };
+        workspace.make_import(
+            baseaddr.wrapping_add(import.offset as i32),
+            &libname.to_lowercase(),
+            name.as_deref().unwrap_or(&import.name),
+        );
+    }
+    link_stubs(workspace, &fname);
+    fname
+}
+
+pub fn parse_elf(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    bytes: &[u8],
+    elf: &Elf,
+    base_addr: Option<i32>,
+) -> String {
+    let arch = match elf.header.e_machine {
+        elf_header::EM_386 => ARCH_I386,
+        elf_header::EM_X86_64 => ARCH_AMD64,
+        elf_header::EM_ARM => ARCH_ARMV7,
+        elf_header::EM_SPARC | elf_header::EM_SPARC32PLUS => ARCH_SPARC,
+        elf_header::EM_SPARCV9 => ARCH_SPARC64,
+        // 31 bit s390 code decodes the same
+        elf_header::EM_S390 => ARCH_S390X,
+        _ => ARCH_DEFAULT as i32,
+    };
+    set_load_meta(
+        workspace,
+        arch,
+        "linux",
+        "elf",
+        elf.is_64,
+        !elf.little_endian,
+    );
+    if let Some(libc) = elf.interpreter.and_then(osprofile::libc_of_interpreter) {
+        workspace.set_meta(osprofile::META_LIBC, Some(libc.to_string()));
+    }
+    let loads = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == program_header::PT_LOAD)
+        .collect::<Vec<_>>();
+    let mut baseaddr = loads.iter().map(|ph| ph.p_vaddr as i32).min().unwrap_or(0);
+    // Position independent objects are linked at 0 and get moved to the requested base.
+    let delta = match base_addr {
+        Some(base) if elf.header.e_type == elf_header::ET_DYN && baseaddr == 0 => base,
+        _ => 0,
+    };
+    baseaddr = baseaddr.wrapping_add(delta);
+    let fname = workspace.add_file(filename, baseaddr, bytes.to_vec());
+    for (i, ph) in loads.iter().enumerate() {
+        let mut perms = 0;
+        if ph.p_flags & program_header::PF_R != 0 {
+            perms |= MM_READ;
+        }
+        if ph.p_flags & program_header::PF_W != 0 {
+            perms |= MM_WRITE;
+        }
+        if ph.p_flags & program_header::PF_X != 0 {
+            perms |= MM_EXEC;
+        }
+        let pva = (ph.p_vaddr as i32).wrapping_add(delta);
+        let memsz = usize::try_from(ph.p_memsz).unwrap_or(usize::MAX);
+        if !fits_map(pva, memsz, &format!("PHDR{}", i)) {
+            continue;
+        }
+        let pbytes = file_bytes(
+            bytes,
+            ph.p_offset as usize,
+            ph.p_filesz as usize,
+            ph.p_memsz as usize,
+            &format!("PHDR{}", i),
+        );
+        if pbytes.is_empty() {
+            anomaly!("Skipping the empty load segment {} at {:#x}", i, pva);
+            continue;
+        }
+        workspace.add_memory_map(pva, perms, &fname, pbytes, None);
+        workspace.add_segment(pva, ph.p_memsz as i32, &format!("PHDR{}", i), fname.clone());
+    }
+    // Nothing else relocates a static-PIE, it does so itself before main
+    if staticpie::is_static_pie(elf) {
+        let relocations = staticpie::self_relocations(elf, delta as i64 as u64);
+        let ptr_size = if elf.is_64 { 8 } else { 4 };
+        for relocation in relocations.iter() {
+            let va = relocation.slot() as i32;
+            if !workspace.probe_memory(va, ptr_size as i32, MM_WRITE) {
+                continue;
+            }
+            let value = match elf.little_endian {
+                true => relocation.value().to_le_bytes()[..ptr_size].to_vec(),
+                false => relocation.value().to_be_bytes()[8 - ptr_size..].to_vec(),
+            };
+            workspace.write_memory(va, value);
+        }
+        staticpie::add_irelative(workspace, &relocations);
+    }
+    if elf.entry != 0 {
+        workspace.add_entry_point((elf.entry as i32).wrapping_add(delta));
+    }
+    let symtabs = [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+    let mut cold = Vec::new();
+    for (syms, strtab) in symtabs.iter() {
+        for sym in syms.iter() {
+            // TLS symbol values are offsets into the thread's TLS block, not addresses
+            if sym.st_value == 0 || sym.st_shndx == 0 || sym.st_type() == elf_sym::STT_TLS {
+                continue;
+            }
+            if sym.st_type() == elf_sym::STT_SPARC_REGISTER
+                && matches!(
+                    elf.header.e_machine,
+                    elf_header::EM_SPARC | elf_header::EM_SPARC32PLUS | elf_header::EM_SPARCV9
+                )
+            {
+                continue;
+            }
+            let name = match strtab.get_at(sym.st_name) {
+                Some(name) if !name.is_empty() => name,
+                _ => continue,
+            };
+            let sva = (sym.st_value as i32).wrapping_add(delta);
+            if !workspace.is_valid_pointer(sva) {
+                continue;
+            }
+            if workspace.get_name(sva, false).is_none() {
+                workspace.make_name(sva, name.to_string(), true, true);
+            }
+            if !sym.is_function() || !workspace.is_executable(sva) {
+                continue;
+            }
+            match cold_parent(name) {
+                Some(parent) => cold.push((parent, sva, sym.st_size as i32)),
+                None => workspace.add_entry_point(sva),
+            }
+        }
+    }
+    add_cold_chunks(workspace, &fname, &cold);
+    // Relocatable objects have no load segments, so the section relocations left to record are
+    // those kept in a linked image with `--emit-relocs`
+    let ptr_size = if elf.is_64 { 8 } else { 4 };
+    let sections = elf.shdr_relocs.iter().map(|(_, relocs)| relocs);
+    for relocs in [&elf.dynrelas, &elf.dynrels, &elf.pltrelocs]
+        .into_iter()
+        .chain(sections)
+    {
+        for reloc in relocs.iter() {
+            if let Some((size, pointer)) =
+                elf_reloc_size(elf.header.e_machine, reloc.r_type, ptr_size)
+            {
+                let va = (reloc.r_offset as i32).wrapping_add(delta);
+                add_fixup(workspace, va, size, pointer);
+            }
+        }
+    }
+    for reloc in elf.pltrelocs.iter() {
+        let sym = match elf.dynsyms.get(reloc.r_sym) {
+            Some(sym) => sym,
+            None => continue,
+        };
+        if let Some(name) = elf.dynstrtab.get_at(sym.st_name) {
+            workspace.make_import((reloc.r_offset as i32).wrapping_add(delta), "*", name);
+        }
+    }
+    link_stubs(workspace, &fname);
+    fname
+}
+
+/// The function a GCC `foo.cold` or `foo.cold.N` symbol holds the unlikely paths of
+fn cold_parent(name: &str) -> Option<&str> {
+    if let Some(parent) = name.strip_suffix(".cold") {
+        return Some(parent);
+    }
+    let (rest, n) = name.rsplit_once('.')?;
+    let parent = rest.strip_suffix(".cold")?;
+    (!n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())).then_some(parent)
+}
+
+/// Add each (parent, va, size) cold part to its parent function, named in the file `fname`,
+/// or make it an entry point of its own if there is no such function
+fn add_cold_chunks(workspace: &mut VivWorkspace, fname: &str, cold: &[(&str, i32, i32)]) {
+    for (parent, sva, size) in cold.iter() {
+        let fva = workspace
+            .va_by_name(format!("{}.{}", fname, parent))
+            .or_else(|| workspace.va_by_name(parent.to_string()));
+        match fva {
+            Some(fva) => workspace.add_function_chunk(fva, *sva, *size),
+            None => workspace.add_entry_point(*sva),
+        }
+    }
+}
+
+pub fn parse_macho(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    bytes: &[u8],
+    macho: &MachO,
+    base_addr: Option<i32>,
+) -> String {
+    let arch = match macho.header.cputype {
+        cputype::CPU_TYPE_X86 => ARCH_I386,
+        cputype::CPU_TYPE_X86_64 => ARCH_AMD64,
+        cputype::CPU_TYPE_ARM => ARCH_ARMV7,
+        // arm64_32 is A64 code with 32 bit pointers, in a 32 bit container
+        cputype::CPU_TYPE_ARM64 | cputype::CPU_TYPE_ARM64_32 => ARCH_A64,
+        _ => ARCH_DEFAULT as i32,
+    };
+    set_load_meta(
+        workspace,
+        arch,
+        "darwin",
+        "macho",
+        macho.is_64,
+        !macho.little_endian,
+    );
+    // macOS, iOS, watchOS, DriverKit and so on all share the "darwin" platform
+    if let Some(platform) = macho.platform() {
+        workspace.set_meta(
+            "DarwinPlatform",
+            Some(platform_to_str(platform).to_string()),
+        );
+    }
+    let ptr_size = if macho.is_64 { 8 } else { 4 };
+    let segments = macho
+        .segments
+        .iter()
+        .filter(|seg| seg.vmsize != 0 && seg.initprot != 0)
+        .collect::<Vec<_>>();
+    let baseaddr = segments
+        .iter()
+        .find(|seg| seg.name().ok() == Some("__TEXT"))
+        .or_else(|| segments.first())
+        .map(|seg| seg.vmaddr as i32)
+        .unwrap_or(0);
+    // Images are position independent; one slid to `base_addr` keeps its layout
+    let delta = base_addr.map_or(0, |base| base.wrapping_sub(baseaddr));
+    let fname = workspace.add_file(filename, baseaddr.wrapping_add(delta), bytes.to_vec());
+    // With chained fixups, the pointers in the file are encoded fixups until the loader is done
+    let fixups = macho.chained_fixups().unwrap_or_else(|e| {
+        anomaly!("Failed to walk the chained fixups of {}: {}", filename, e);
+        None
+    });
+    let image_base = segments
+        .iter()
+        .find(|seg| seg.name().ok() == Some("__TEXT"))
+        .map_or(0, |seg| seg.vmaddr);
+    for seg in segments.iter() {
+        let prot = seg.init_protection();
+        let mut perms = 0;
+        if prot.is_readable() {
+            perms |= MM_READ;
+        }
+        if prot.is_writable() {
+            perms |= MM_WRITE;
+        }
+        if prot.is_executable() {
+            perms |= MM_EXEC;
+        }
+        let sva = (seg.vmaddr as i32).wrapping_add(delta);
+        let vmsize = usize::try_from(seg.vmsize).unwrap_or(usize::MAX);
+        if !fits_map(sva, vmsize, seg.name().unwrap_or("")) {
+            continue;
+        }
+        let mut sbytes = seg.data.to_vec();
+        sbytes.resize(vmsize, 0);
+        for (va, pointer) in fixups.iter().flat_map(|fixups| fixups.fixups.iter()) {
+            let offset = va.wrapping_sub(seg.vmaddr) as usize;
+            if let Some(slot) = sbytes.get_mut(offset..offset.saturating_add(ptr_size)) {
+                // imports stay null, as they are until bound, and values aren't slid
+                let target = match pointer.fixup {
+                    Fixup::Value { value } => value as u64,
+                    _ => pointer
+                        .target(image_base)
+                        .map_or(0, |target| target.wrapping_add(delta as i64 as u64)),
+                };
+                slot.copy_from_slice(&target.to_le_bytes()[..ptr_size]);
+            }
+        }
+        workspace.add_memory_map(sva, perms, &fname, sbytes, None);
+        workspace.add_segment(
+            sva,
+            seg.vmsize as i32,
+            seg.name().unwrap_or(""),
+            fname.clone(),
+        );
+    }
+    if macho.entry != 0 {
+        workspace.add_entry_point((macho.entry as i32).wrapping_add(delta));
+    }
+    add_initializers(workspace, macho, fixups.as_ref(), image_base, delta);
+    for (name, nlist) in macho.symbols().flatten() {
+        if nlist.is_undefined() || nlist.n_value == 0 || name.is_empty() {
+            continue;
+        }
+        let sva = (nlist.n_value as i32).wrapping_add(delta);
+        if workspace.is_valid_pointer(sva) && workspace.get_name(sva, false).is_none() {
+            workspace.make_name(sva, name.trim_start_matches('_').to_string(), true, true);
+        }
+    }
+    let libname = |dylib: Option<Dylib<'_>>| match dylib {
+        Some(Dylib::Ordinary(dylib)) => Path::new(dylib)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(dylib)
+            .to_string(),
+        Some(Dylib::SelfModule) => fname.clone(),
+        // Looked up in every image, or in the main executable whatever it is
+        _ => "*".to_string(),
+    };
+    match macho.imports() {
+        Ok(imports) => {
+            for import in imports.iter() {
+                let va = (import.address as i32).wrapping_add(delta);
+                add_fixup(workspace, va, ptr_size as i32, true);
+                workspace.make_import(
+                    va,
+                    &libname(Some(import.dylib)),
+                    import.name.trim_start_matches('_'),
+                );
+            }
+        }
+        Err(e) => anomaly!("Skipping the bound imports of {}: {}", filename, e),
+    }
+    // The pointers dyld binds or slides, and in objects what the static linker patches
+    for (va, pointer) in fixups.iter().flat_map(|fixups| fixups.fixups.iter()) {
+        if let Fixup::Value { .. } = pointer.fixup {
+            continue;
+        }
+        add_fixup(
+            workspace,
+            (*va as i32).wrapping_add(delta),
+            ptr_size as i32,
+            true,
+        );
+    }
+    for (_, relocs, section) in macho.relocations().unwrap_or_default() {
+        for reloc in relocs.flatten() {
+            // scattered relocations keep their address in the low 24 bits
+            let address = if reloc.r_address < 0 {
+                reloc.r_address & 0x00ff_ffff
+            } else {
+                reloc.r_address
+            };
+            let va = (section.addr as i32)
+                .wrapping_add(address)
+                .wrapping_add(delta);
+            let size = 1 << reloc.r_length();
+            add_fixup(
+                workspace,
+                va,
+                size,
+                size == ptr_size as i32 && !reloc.is_pic(),
+            );
+        }
+    }
+    for (va, pointer) in fixups.iter().flat_map(|fixups| fixups.fixups.iter()) {
+        let va = (*va as i32).wrapping_add(delta);
+        if let Some(import) = fixups.as_ref().and_then(|fixups| fixups.import(pointer)) {
+            workspace.make_import(
+                va,
+                &libname(import.dylib),
+                import.name.trim_start_matches('_'),
+            );
+        }
+        // The signature is made at load time, keep what it is made with
+        if let Some(auth) = pointer.auth {
+            workspace.set_comment(va, &format!("signed pointer: {}", auth), true);
+        }
+    }
+    link_stubs(workspace, &fname);
+    #[cfg(feature = "objc")]
+    if workspace.get_profile().runs_load_passes() {
+        add_objc(workspace, &fname, macho, delta);
+    }
+    fname
+}
+
+/// Add the constructors and destructors a Mach-O image lists, as pointers in `__mod_init_func`
+/// and `__mod_term_func` or as offsets from the image base in `__init_offsets`, as entry points
+fn add_initializers(
+    workspace: &mut VivWorkspace,
+    macho: &MachO,
+    fixups: Option<&ChainedFixups>,
+    image_base: u64,
+    delta: i32,
+) {
+    let ptr_size = if macho.is_64 { 8 } else { 4 };
+    // With chained fixups the pointers are encoded fixups, otherwise link time addresses
+    let rebased = fixups
+        .iter()
+        .flat_map(|fixups| fixups.fixups.iter())
+        .filter_map(|(va, pointer)| Some((*va, pointer.target(image_base)?)))
+        .collect::<HashMap<_, _>>();
+    let mut initializers = Vec::new();
+    for segment in macho.segments.iter() {
+        for (section, data) in segment.sections().unwrap_or_default() {
+            match section.section_type() {
+                S_MOD_INIT_FUNC_POINTERS | S_MOD_TERM_FUNC_POINTERS => {
+                    for (idx, slot) in data.chunks_exact(ptr_size).enumerate() {
+                        let va = section.addr + (idx * ptr_size) as u64;
+                        let mut pointer = [0; 8];
+                        pointer[..ptr_size].copy_from_slice(slot);
+                        let target = rebased
+                            .get(&va)
+                            .copied()
+                            .unwrap_or(u64::from_le_bytes(pointer));
+                        initializers.push(target);
+                    }
+                }
+                S_INIT_FUNC_OFFSETS => {
+                    for offset in data.chunks_exact(4) {
+                        let offset = u32::from_le_bytes(offset.try_into().unwrap());
+                        initializers.push(image_base + u64::from(offset));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    for target in initializers {
+        let va = (target as i32).wrapping_add(delta);
+        if target != 0 && workspace.is_executable(va) {
+            workspace.add_entry_point(va);
+        }
+    }
+}
+
+/// Add the Objective-C methods and block literals of the Mach-O file just loaded, and link its
+/// message sends
+#[cfg(feature = "objc")]
+fn add_objc(workspace: &mut VivWorkspace, fname: &str, macho: &MachO, delta: i32) {
+    let closures = blocks::add_closures(workspace);
+    debug!("Found {} block literals and closures in {}", closures.len(), fname);
+    // The metadata is read at its link time addresses, and with 64 bit pointers
+    if delta != 0 || !macho.is_64 {
+        debug!("Skipping the Objective-C metadata of {}", fname);
+        return;
+    }
+    let objc = ObjcMetadata::parse(&Image::from_macho(macho));
+    if !objc.selrefs.is_empty() {
+        objc::add_methods(workspace, &objc);
+        objc::link_message_sends(workspace, &objc);
+    }
+}
+
+/// Link the import trampolines of the file just loaded to their callers
+fn link_stubs(workspace: &mut VivWorkspace, fname: &str) {
+    if !workspace.get_profile().runs_load_passes() {
+        return;
+    }
+    let stubs = link_import_stubs(workspace);
+    debug!("Linked {} import trampolines of {}", stubs, fname);
+}
+
+/// Record the `size` bytes a relocation patches at `va`, as a pointer if it is an absolute one of
+/// pointer size, so the hashes of code differing only by link time addresses match
+fn add_fixup(workspace: &mut VivWorkspace, va: i32, size: i32, pointer: bool) {
+    if size == 0 || !workspace.is_valid_pointer(va) {
+        return;
+    }
+    let rtype = if pointer {
+        RTYPE_BASERELOC
+    } else {
+        RTYPE_FIXUP
+    };
+    workspace.add_relocation(va, rtype, Some(vec![]), Some(size));
+}
+
+/// The RVA and size of each location a PE base relocation table patches
+fn base_relocations(table: &[u8]) -> Vec<(u32, i32)> {
+    let mut ret = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = table.get(offset..offset + 8) {
+        let page = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let block = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if block < 8 {
+            break;
+        }
+        let end = offset.saturating_add(block).min(table.len());
+        for entry in table[offset + 8..end].chunks_exact(2) {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            let size = match entry >> 12 {
+                // IMAGE_REL_BASED_HIGH and IMAGE_REL_BASED_LOW
+                1 | 2 => 2,
+                // IMAGE_REL_BASED_HIGHLOW
+                3 => 4,
+                // IMAGE_REL_BASED_DIR64
+                10 => 8,
+                // padding, or a type the loaders of the supported architectures don't apply
+                _ => continue,
+            };
+            ret.push((page.wrapping_add(u32::from(entry & 0xfff)), size));
+        }
+        offset = end;
+    }
+    ret
+}
+
+/// The number of bytes an ELF relocation patches and whether it is an absolute pointer, or None
+/// if it patches nothing
+fn elf_reloc_size(machine: u16, r_type: u32, ptr_size: i32) -> Option<(i32, bool)> {
+    use crate::elf::reloc::*;
+    match machine {
+        elf_header::EM_X86_64 => match r_type {
+            R_X86_64_NONE | R_X86_64_COPY | R_X86_64_TLSDESC_CALL => None,
+            R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT | R_X86_64_RELATIVE
+            | R_X86_64_IRELATIVE => Some((8, true)),
+            R_X86_64_DTPMOD64 | R_X86_64_DTPOFF64 | R_X86_64_TPOFF64 | R_X86_64_PC64
+            | R_X86_64_GOTOFF64 | R_X86_64_GOT64 | R_X86_64_GOTPCREL64 | R_X86_64_GOTPC64
+            | R_X86_64_GOTPLT64 | R_X86_64_PLTOFF64 | R_X86_64_SIZE64 | R_X86_64_TLSDESC
+            | R_X86_64_RELATIVE64 => Some((8, false)),
+            R_X86_64_16 | R_X86_64_PC16 => Some((2, false)),
+            R_X86_64_8 | R_X86_64_PC8 => Some((1, false)),
+            _ => Some((4, false)),
+        },
+        elf_header::EM_386 => match r_type {
+            R_386_NONE | R_386_COPY => None,
+            // markers on the instructions of a TLS sequence
+            R_386_TLS_GD_PUSH | R_386_TLS_GD_CALL | R_386_TLS_GD_POP | R_386_TLS_LDM_PUSH
+            | R_386_TLS_LDM_CALL | R_386_TLS_LDM_POP => None,
+            R_386_32 | R_386_GLOB_DAT | R_386_JMP_SLOT | R_386_RELATIVE | R_386_IRELATIVE => {
+                Some((4, true))
+            }
+            R_386_16 | R_386_PC16 => Some((2, false)),
+            R_386_8 | R_386_PC8 => Some((1, false)),
+            _ => Some((4, false)),
+        },
+        elf_header::EM_AARCH64 => match r_type {
+            0 | R_AARCH64_TLSDESC_CALL => None,
+            R_AARCH64_ABS64 | R_AARCH64_GLOB_DAT | R_AARCH64_JUMP_SLOT | R_AARCH64_RELATIVE
+            | R_AARCH64_IRELATIVE => Some((ptr_size, true)),
+            R_AARCH64_PREL64 | R_AARCH64_TLS_DTPMOD | R_AARCH64_TLS_DTPREL
+            | R_AARCH64_TLS_TPREL | R_AARCH64_TLSDESC => Some((8, false)),
+            R_AARCH64_ABS16 | R_AARCH64_PREL16 => Some((2, false)),
+            // the data relocations and every instruction one
+            _ => Some((4, false)),
+        },
+        elf_header::EM_ARM => match r_type {
+            0 => None,
+            R_ARM_ABS32 | R_ARM_GLOB_DAT | R_ARM_JUMP_SLOT | R_ARM_RELATIVE | R_ARM_IRELATIVE => {
+                Some((4, true))
+            }
+            _ => Some((4, false)),
+        },
+        _ if r_type == 0 => None,
+        _ => Some((ptr_size, false)),
+    }
+}
+
+/// Set the metadata of a loaded file: its `ARCH_*` architecture, platform and format, and
+/// its pointer size and byte order
+pub fn set_load_meta(
+    workspace: &mut VivWorkspace,
+    arch: i32,
+    platform: &str,
+    format: &str,
+    is_64: bool,
+    big_endian: bool,
+) {
+    workspace.set_meta("Architecture", Some(arch.to_string()));
+    workspace.set_meta("Platform", Some(platform.to_string()));
+    workspace.set_meta("Format", Some(format.to_string()));
+    workspace.set_mem_architecture(arch as u32);
+    workspace.set_pointer_size(if is_64 { 8 } else { 4 });
+    workspace.set_meta("bigend", Some(big_endian.to_string()));
+}
+
+/// The most a section or segment maps; a larger size in a file is taken to be made up
+const MAX_MAP_SIZE: usize = 0x1000_0000;
+
+/// Whether the `size` bytes at `va` of the section or segment `what` can be mapped, as an
+/// anomaly if not: no larger than [`MAX_MAP_SIZE`] and not wrapping around the address space
+fn fits_map(va: i32, size: usize, what: &str) -> bool {
+    if size <= MAX_MAP_SIZE && va.checked_add(size as i32).is_some() {
+        return true;
+    }
+    anomaly!(
+        "Skipping {} at {:#x}, {:#x} bytes don't fit in memory",
+        what,
+        va,
+        size
+    );
+    false
+}
+
+/// Slice `filesz` bytes at `offset` out of the file, zero padded up to `memsz`. What is past the
+/// end of the file is padded too, as an anomaly of the section or segment `what`.
+fn file_bytes(bytes: &[u8], offset: usize, filesz: usize, memsz: usize, what: &str) -> Vec<u8> {
+    let start = offset.min(bytes.len());
+    let end = offset.saturating_add(filesz).min(bytes.len());
+    if end - start < filesz {
+        anomaly!(
+            "{} at file offset {:#x} is cut short, {:#x} of {:#x} bytes in the file",
+            what,
+            offset,
+            end - start,
+            filesz
+        );
+    }
+    let mut ret = bytes[start..end].to_vec();
+    ret.resize(memsz.max(ret.len()), 0);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_relocations() {
+        // a page of a HIGHLOW, a DIR64 and the padding, then a page of a HIGH
+        let mut table = vec![];
+        table.extend(0x1000u32.to_le_bytes());
+        table.extend(16u32.to_le_bytes());
+        table.extend(
+            [0x10u16 | 3 << 12, 0x20 | 10 << 12, 0]
+                .iter()
+                .flat_map(|e| e.to_le_bytes()),
+        );
+        table.extend([0, 0]);
+        table.extend(0x3000u32.to_le_bytes());
+        table.extend(10u32.to_le_bytes());
+        table.extend((0xffeu16 | 1 << 12).to_le_bytes());
+        assert_eq!(
+            base_relocations(&table),
+            [(0x1010, 4), (0x1020, 8), (0x3ffe, 2)]
+        );
+
+        use crate::elf::reloc::*;
+        assert_eq!(
+            elf_reloc_size(elf_header::EM_X86_64, R_X86_64_RELATIVE, 8),
+            Some((8, true))
+        );
+        assert_eq!(
+            elf_reloc_size(elf_header::EM_X86_64, R_X86_64_PLT32, 8),
+            Some((4, false))
+        );
+        assert_eq!(elf_reloc_size(elf_header::EM_386, R_386_COPY, 4), None);
+    }
+
+    #[test]
+    fn links_cold_chunks() {
+        assert_eq!(cold_parent("foo.cold"), Some("foo"));
+        assert_eq!(cold_parent("foo.cold.12"), Some("foo"));
+        assert_eq!(cold_parent("foo.coldstart"), None);
+        assert_eq!(cold_parent("init.cold_path"), None);
+        assert_eq!(cold_parent("foo.cold."), None);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "prog", vec![0xc3; 0x100], None);
+        ws.add_segment(0x1000, 0x100, ".text", "prog".to_string());
+        ws.make_name(0x1000, "foo".to_string(), true, false);
+        ws.add_function(0x1000, vec![(0x1000, 0x20)]);
+        add_cold_chunks(&mut ws, "prog", &[("foo", 0x1080, 0x10), ("bar", 0x10c0, 8)]);
+        assert_eq!(ws.get_function(0x1084), Some(0x1000));
+        assert_eq!(ws.get_functions_containing(0x1084), [0x1000]);
+        // a cold part of a function not there is one of its own
+        assert!(ws.get_entry_points().contains(&0x10c0));
+    }
+
+    #[test]
+    fn seeds_mach_o_initializers() {
+        let name = |name: &str| {
+            let mut bytes = [0u8; 16];
+            bytes[..name.len()].copy_from_slice(name.as_bytes());
+            bytes
+        };
+        // __TEXT maps the first page at 0x1000 and __DATA the second at 0x2000
+        let segments = [
+            (
+                "__TEXT",
+                0x1000u64,
+                5u32,
+                [("__text", 0x400u64, 0x30u64, 0x8000_0400u32), ("__init_offsets", 0x500, 4, 0x16)],
+            ),
+            (
+                "__DATA",
+                0x2000,
+                3,
+                [("__mod_init_func", 0, 8, 0x9), ("__mod_term_func", 8, 8, 0xa)],
+            ),
+        ];
+        let mut bytes = Vec::new();
+        for word in [0xfeed_facfu32, 0x0100_0007, 3, 2, 2, 2 * 232, 0, 0] {
+            bytes.extend(word.to_le_bytes());
+        }
+        for (idx, (segname, vmaddr, prot, sections)) in segments.iter().enumerate() {
+            bytes.extend(0x19u32.to_le_bytes());
+            bytes.extend(232u32.to_le_bytes());
+            bytes.extend(name(segname));
+            for value in [*vmaddr, 0x1000, idx as u64 * 0x1000, 0x1000] {
+                bytes.extend(value.to_le_bytes());
+            }
+            for value in [*prot, *prot, 2, 0] {
+                bytes.extend(value.to_le_bytes());
+            }
+            for (sectname, offset, size, flags) in sections {
+                bytes.extend(name(sectname));
+                bytes.extend(name(segname));
+                bytes.extend((vmaddr + offset).to_le_bytes());
+                bytes.extend(size.to_le_bytes());
+                let fileoff = (idx as u64 * 0x1000 + offset) as u32;
+                for value in [fileoff, 0, 0, 0, *flags, 0, 0, 0] {
+                    bytes.extend(value.to_le_bytes());
+                }
+            }
+        }
+        bytes.resize(0x2000, 0xcc);
+        bytes[0x400..0x430].fill(0xc3);
+        bytes[0x500..0x504].copy_from_slice(&0x420u32.to_le_bytes());
+        bytes[0x1000..0x1008].copy_from_slice(&0x1400u64.to_le_bytes());
+        bytes[0x1008..0x1010].copy_from_slice(&0x1410u64.to_le_bytes());
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.load_from_bytes("init", &bytes, None);
+        assert_eq!(ws.get_entry_points(), [0x1400, 0x1410, 0x1420]);
+        // slid, the pointers are rebased and the offsets stay relative to the image
+        let mut ws = VivWorkspace::new("", false);
+        ws.load_from_bytes("init", &bytes, Some(0x10000));
+        assert_eq!(ws.get_entry_points(), [0x10400, 0x10410, 0x10420]);
+    }
+
+    #[test]
+    fn checks_relocation_size() {
+        use crate::pe::import::{ImportTable, ImportedFunction};
+
+        let mut imports = ImportTable::default();
+        imports.add_function("kernel32.dll", ImportedFunction::by_name("ExitProcess"));
+        let (pe, _) = imports.tiny_image();
+        // the base relocation directory is in .idata, and runs past its end or backwards
+        for size in [0x7fff_fff0u32, 0xffff_fff0] {
+            let mut pe = pe.clone();
+            pe[0xe0..0xe4].copy_from_slice(&0x1000u32.to_le_bytes());
+            pe[0xe4..0xe8].copy_from_slice(&size.to_le_bytes());
+            let mut ws = VivWorkspace::new("", false);
+            ws.load_from_bytes("tiny.exe", &pe, None);
+            assert_eq!(ws.get_imports().len(), 1);
+            assert!(ws.get_relocations().is_empty());
+        }
+    }
+
+    #[test]
+    fn skips_what_does_not_fit() {
+        assert!(fits_map(0x1000, 0x1000, "fits"));
+        assert!(!fits_map(0x7fff_f000, 0x2000, "wraps"));
+        assert!(!fits_map(0x1000, MAX_MAP_SIZE + 1, "huge"));
+
+        // a fat file whose first arch is past its end, and whose second has a segment of 4 GiB
+        let mut bytes = Vec::new();
+        for word in [0xcafe_babeu32, 2, 0x0100_0007, 3, 0x8000, 0x1000, 12] {
+            bytes.extend(word.to_be_bytes());
+        }
+        for word in [0x0100_0007u32, 3, 0x1000, 0x100, 12] {
+            bytes.extend(word.to_be_bytes());
+        }
+        bytes.resize(0x1000, 0);
+        for word in [0xfeed_facfu32, 0x0100_0007, 3, 2, 1, 72, 0, 0, 0x19, 72] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend(b"__DATA\0\0\0\0\0\0\0\0\0\0");
+        for value in [0x1000u64, 0x1_0000_0000, 0, 0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for value in [3u32, 3, 0, 0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.resize(0x1100, 0);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.load_from_bytes("fat", &bytes, None);
+        assert_eq!(ws.get_meta("Format").as_deref(), Some("macho"));
+        assert!(ws.get_segments().is_empty());
+        assert!(ws.get_memory_maps().is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+//! Patch operations on top of [`VivWorkspace::patch_bytes`], the staples of binary
+//! instrumentation:
+//!
+//! * [`nop`] fills a range with the architecture's no-ops, the multi-byte ones on x86,
+//! * [`find_caves`] finds runs of padding in the executable maps which no location covers,
+//!   room for code of our own, as padding between code or as the slack at the end of a map,
+//! * [`find_cave_code`] finds the other side of that: functions which sit in the slack of a map,
+//!   as code an implant appended there does,
+//! * [`detour`], with the `assembler` feature, hooks an instruction on x86: it jumps from there
+//!   to a code cave holding the hook, the instructions the jump overwrote, relocated, and a
+//!   jump back after them.
+
+use crate::{constants::ENDIAN_MSB, envi::Arch, memory::Memory, workspace::VivWorkspace};
+use std::io;
+
+/// The recommended x86 no-ops of 1 to 9 bytes
+const X86_NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// Where in a map a [`Cave`] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CaveKind {
+    /// Between pieces of code, as compilers align functions
+    Padding,
+    /// After the last code of the map, up to its end, as sections are rounded up
+    Slack,
+}
+
+/// A run of padding bytes free for code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cave {
+    pub va: u64,
+    pub size: u64,
+    pub kind: CaveKind,
+}
+
+impl Cave {
+    /// The largest power of two the start is aligned to, up to a page
+    pub fn align(&self) -> u64 {
+        1 << self.va.trailing_zeros().min(12)
+    }
+}
+
+/// The no-op of a fixed width architecture, and its width
+fn fixed_nop(arch: Arch) -> Option<(u32, usize)> {
+    match arch {
+        // mov r0, r0
+        Arch::ArmV7 => Some((0xe1a0_0000, 4)),
+        Arch::Thumb | Arch::Thumb16 => Some((0xbf00, 2)),
+        Arch::A64 => Some((0xd503_201f, 4)),
+        // mov #0, r3
+        Arch::Msp430 => Some((0x4303, 2)),
+        Arch::H8 => Some((0x0000, 2)),
+        Arch::I386 | Arch::Amd64 => None,
+    }
+}
+
+/// How code is aligned on an architecture
+fn alignment(arch: Arch) -> u64 {
+    fixed_nop(arch).map_or(1, |(_, width)| width as u64)
+}
+
+/// `len` bytes of no-ops, as few as can be, or None if `len` is not a whole number of
+/// instructions
+pub fn nops(arch: Arch, big_endian: bool, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(len);
+    match fixed_nop(arch) {
+        None => {
+            while bytes.len() < len {
+                let n = (len - bytes.len()).min(X86_NOPS.len());
+                bytes.extend_from_slice(X86_NOPS[n - 1]);
+            }
+        }
+        Some((nop, width)) => {
+            if !len.is_multiple_of(width) {
+                return None;
+            }
+            let word = if big_endian {
+                nop.to_be_bytes()
+            } else {
+                nop.to_le_bytes()
+            };
+            let word = if big_endian {
+                &word[4 - width..]
+            } else {
+                &word[..width]
+            };
+            for _ in 0..len / width {
+                bytes.extend_from_slice(word);
+            }
+        }
+    }
+    Some(bytes)
+}
+
+fn workspace_arch(workspace: &VivWorkspace) -> io::Result<Arch> {
+    Arch::from_envi(workspace.arch)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "unknown architecture"))
+}
+
+/// Overwrite `len` bytes at `va` with no-ops
+pub fn nop(workspace: &mut VivWorkspace, va: i32, len: usize) -> io::Result<()> {
+    let arch = workspace_arch(workspace)?;
+    let big_endian = workspace.get_endian() == ENDIAN_MSB;
+    let bytes = nops(arch, big_endian, len).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:#x} bytes is not a whole number of {} no-ops",
+                len,
+                arch.name()
+            ),
+        )
+    })?;
+    if !workspace.patch_bytes(va, &bytes) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:#x}: {:#x} bytes not in a memory map", va, len),
+        ));
+    }
+    Ok(())
+}
+
+/// The bytes compilers and linkers pad code with
+fn is_padding(arch: Arch, byte: u8) -> bool {
+    match arch {
+        Arch::I386 | Arch::Amd64 => matches!(byte, 0x00 | 0x90 | 0xcc),
+        _ => matches!(byte, 0x00 | 0xff),
+    }
+}
+
+/// The runs of at least `min_size` padding bytes in the executable maps of the workspace
+/// which no location covers, aligned for code
+pub fn find_caves(workspace: &VivWorkspace, min_size: u64) -> Vec<Cave> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let align = alignment(arch);
+    let mut caves = Vec::new();
+    let mut push = |start: u64, end: u64, kind: CaveKind| {
+        let va = start.next_multiple_of(align);
+        if end >= va + min_size.max(1) {
+            caves.push(Cave {
+                va,
+                size: end - va,
+                kind,
+            });
+        }
+    };
+    for (mva, bytes) in workspace.get_executable_maps() {
+        let mva = mva as u32 as u64;
+        let mut start = None;
+        for (offset, byte) in bytes.iter().enumerate() {
+            let va = mva + offset as u64;
+            let free = is_padding(arch, *byte) && workspace.get_location(va as i32).is_none();
+            match (free, start) {
+                (true, None) => start = Some(va),
+                (false, Some(from)) => {
+                    push(from, va, CaveKind::Padding);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = start {
+            push(from, mva + bytes.len() as u64, CaveKind::Slack);
+        }
+    }
+    caves
+}
+
+/// A function in the slack of an executable map, found by [`find_cave_code`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaveCode {
+    pub function: u64,
+    /// How many bytes of padding come right before it
+    pub padding: u64,
+    /// Whether it is an entry point of the binary
+    pub entry_point: bool,
+}
+
+/// The functions which sit in the slack of an executable map: the last function of the map,
+/// after at least `min_padding` bytes of padding. Code a linker laid out comes before the
+/// padding rounding up its section, so code after it was most likely put there later, in a
+/// cave, and more so if the entry point was moved to it.
+pub fn find_cave_code(workspace: &VivWorkspace, min_padding: u64) -> Vec<CaveCode> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let functions = workspace.get_functions();
+    let entry_points = workspace.get_entry_points();
+    let mut found = Vec::new();
+    for (mva, bytes) in workspace.get_executable_maps() {
+        let end = mva.saturating_add(bytes.len() as i32);
+        let Some(&fva) = functions.iter().rfind(|fva| (mva..end).contains(fva)) else {
+            continue;
+        };
+        let offset = (fva - mva) as usize;
+        let padding = bytes[..offset]
+            .iter()
+            .rev()
+            .take_while(|byte| is_padding(arch, **byte))
+            .count() as u64;
+        if padding >= min_padding.max(1) && padding < offset as u64 {
+            found.push(CaveCode {
+                function: fva as u32 as u64,
+                padding,
+                entry_point: entry_points.contains(&fva),
+            });
+        }
+    }
+    found
+}
+
+/// A hook placed by [`detour`]
+#[cfg(feature = "assembler")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Detour {
+    /// The hooked instruction
+    pub va: u64,
+    /// How many bytes of instructions the jump to the cave took the place of
+    pub stolen: u64,
+    /// Where execution carries on after the cave
+    pub resume: u64,
+    pub cave: u64,
+    /// How many bytes of the cave were used
+    pub size: u64,
+}
+
+/// Hook the instruction at `va`, on i386 or amd64: the cave gets `hook`, which must be code
+/// for the start of the cave, then the instructions at `va` which the jump to the cave
+/// overwrites, relocated, and then a jump back to the instruction after them. The overwritten
+/// instructions left over after the jump become no-ops, and branches among them go to their
+/// relocated copies.
+///
+/// Nothing may branch into the overwritten instructions, past `va` itself.
+#[cfg(feature = "assembler")]
+pub fn detour(
+    workspace: &mut VivWorkspace,
+    va: i32,
+    cave: &Cave,
+    hook: &[u8],
+) -> io::Result<Detour> {
+    use iced_x86::{
+        BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, Encoder, Instruction,
+        InstructionBlock,
+    };
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let arch = workspace_arch(workspace)?;
+    let (bitness, jmp) = match arch {
+        Arch::I386 => (32, Code::Jmp_rel32_32),
+        Arch::Amd64 => (64, Code::Jmp_rel32_64),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("no detours on {}", arch.name()),
+            ))
+        }
+    };
+    let iced = |err: iced_x86::IcedError| invalid(err.to_string());
+    let jump = |from: u64, to: u64| -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(bitness);
+        encoder
+            .encode(&Instruction::with_branch(jmp, to).map_err(iced)?, from)
+            .map_err(iced)?;
+        Ok(encoder.take_buffer())
+    };
+
+    let hva = va as u32 as u64;
+    let to_cave = jump(hva, cave.va)?;
+    let code = workspace
+        .get_executable_maps()
+        .into_iter()
+        .find_map(|(mva, bytes)| {
+            let offset = hva.checked_sub(mva as u32 as u64)? as usize;
+            bytes.get(offset..)
+        })
+        .ok_or_else(|| invalid(format!("{:#x}: not in an executable map", va)))?;
+    let mut decoder = Decoder::with_ip(bitness, code, hva, DecoderOptions::NONE);
+    let mut stolen = Vec::new();
+    let mut stolen_len = 0;
+    while stolen_len < to_cave.len() {
+        let insn = decoder.decode();
+        if insn.is_invalid() {
+            return Err(invalid(format!("{:#x}: cannot decode", insn.ip())));
+        }
+        stolen_len += insn.len();
+        stolen.push(insn);
+    }
+    let resume = hva + stolen_len as u64;
+
+    let mut body = hook.to_vec();
+    let relocated = cave.va + body.len() as u64;
+    stolen.push(Instruction::with_branch(jmp, resume).map_err(iced)?);
+    let block = InstructionBlock::new(&stolen, relocated);
+    let encoded = BlockEncoder::encode(bitness, block, BlockEncoderOptions::NONE).map_err(iced)?;
+    body.extend(encoded.code_buffer);
+    if body.len() as u64 > cave.size {
+        return Err(invalid(format!(
+            "{:#x} bytes do not fit the cave at {:#x}",
+            body.len(),
+            cave.va
+        )));
+    }
+
+    let mut patch = to_cave;
+    let pad = stolen_len - patch.len();
+    patch.extend(nops(arch, false, pad).unwrap_or_default());
+    if !workspace.patch_bytes(cave.va as i32, &body) || !workspace.patch_bytes(va, &patch) {
+        return Err(invalid(format!("{:#x}: cave not in a memory map", cave.va)));
+    }
+    Ok(Detour {
+        va: hva,
+        stolen: stolen_len as u64,
+        resume,
+        cave: cave.va,
+        size: body.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ARCH_A64, LOC_OP, MM_EXEC, MM_READ};
+
+    #[test]
+    fn nops_and_caves() {
+        assert_eq!(nops(Arch::I386, false, 11).unwrap().len(), 11);
+        assert_eq!(nops(Arch::I386, false, 11).unwrap()[9..], [0x66, 0x90]);
+        assert_eq!(nops(Arch::A64, false, 4).unwrap(), [0x1f, 0x20, 0x03, 0xd5]);
+        assert_eq!(
+            nops(Arch::ArmV7, true, 4).unwrap(),
+            [0xe1, 0xa0, 0x00, 0x00]
+        );
+        assert_eq!(
+            nops(Arch::Thumb, false, 4).unwrap(),
+            [0x00, 0xbf, 0x00, 0xbf]
+        );
+        assert_eq!(nops(Arch::A64, false, 6), None);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_A64 as u32);
+        let mut code = vec![0x11; 0x40];
+        code[0x13..0x30].fill(0);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a.bin", code, None);
+        ws.add_location(0x1020, 8, LOC_OP, Some(vec![]));
+        // aligned to 4, and up to the location
+        assert_eq!(
+            find_caves(&ws, 9),
+            [Cave {
+                va: 0x1014,
+                size: 0xc,
+                kind: CaveKind::Padding,
+            }]
+        );
+        assert_eq!(find_caves(&ws, 9)[0].align(), 4);
+        ws.add_function(0x1000, vec![]);
+        ws.add_function(0x1030, vec![]);
+        assert_eq!(
+            find_cave_code(&ws, 16),
+            [CaveCode {
+                function: 0x1030,
+                padding: 0x1d,
+                entry_point: false,
+            }]
+        );
+        assert_eq!(find_cave_code(&ws, 0x20), []);
+        nop(&mut ws, 0x1000, 8).unwrap();
+        assert_eq!(
+            ws.read_memory(0x1000, 8).unwrap(),
+            [0x1f, 0x20, 0x03, 0xd5, 0x1f, 0x20, 0x03, 0xd5]
+        );
+        assert!(nop(&mut ws, 0x1000, 3).is_err());
+
+        #[cfg(feature = "assembler")]
+        {
+            use crate::constants::ARCH_I386;
+
+            let mut ws = VivWorkspace::new("", false);
+            ws.set_mem_architecture(ARCH_I386 as u32);
+            // push ebp; mov ebp, esp; jz 0x1000; ret, then padding
+            let mut code = vec![0x55, 0x89, 0xe5, 0x74, 0xfb, 0xc3];
+            code.resize(0x40, 0xcc);
+            ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "a.exe", code, None);
+            ws.add_location(0x1000, 6, LOC_OP, Some(vec![]));
+            let cave = find_caves(&ws, 16)[0];
+            assert_eq!(
+                cave,
+                Cave {
+                    va: 0x1006,
+                    size: 0x3a,
+                    kind: CaveKind::Slack,
+                }
+            );
+            let detour = detour(&mut ws, 0x1000, &cave, &[0x90]).unwrap();
+            assert_eq!((detour.stolen, detour.resume), (5, 0x1005));
+            // jmp 0x1006
+            assert_eq!(
+                ws.read_memory(0x1000, 6).unwrap(),
+                [0xe9, 0x01, 0, 0, 0, 0xc3]
+            );
+            // nop; push ebp; mov ebp, esp; je 0x1007, the relocated push; jmp 0x1005
+            assert_eq!(
+                ws.read_memory(0x1006, detour.size as i32).unwrap(),
+                [0x90, 0x55, 0x89, 0xe5, 0x74, 0xfb, 0xeb, 0xf7]
+            );
+        }
+    }
+}
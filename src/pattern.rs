@@ -0,0 +1,449 @@
+//! Matching idioms in lifted code.
+//!
+//! A [`Pat`] describes the shape of an [`Expr`]: operations and operands, any of which may be
+//! a wildcard, with [`capture`]s naming the parts wanted out of a match. A name captured twice
+//! must match the same expression both times, so `xor(capture("r", any_reg()), capture("r",
+//! any_reg()))` is a register xored with itself. The operands of commutative operations match
+//! either way round.
+//!
+//! A [`Sequence`] strings statement patterns ([`StmtPat`]) together, with gaps of unrelated
+//! statements between them, and optionally the way the block ends. [`Sequence::find`] looks
+//! for it in the statements of a block.
+//!
+//! Expressions are simplified as they are built (see [`Expr::binary`]), which patterns have to
+//! follow: constants are on the right, and `x - c` is `x + -c`. A pattern matched against what a
+//! [`crate::symbolic::State`] evaluates to asks about the value computed rather than the
+//! instructions computing it, which is usually the better question.
+
+use crate::{
+    envi::{flags::Condition, registers::RegId},
+    symbolic::{BinOp, Block, Expr, FlagOp, Function, Stmt, Terminator, UnOp},
+};
+use std::collections::BTreeMap;
+
+/// The shape of an expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pat {
+    Any,
+    Const(u64),
+    AnyConst,
+    Reg(RegId),
+    AnyReg,
+    Load(Box<Pat>),
+    Unary(UnOp, Box<Pat>),
+    Binary(BinOp, Box<Pat>, Box<Pat>),
+    /// What the pattern matches, by name
+    Capture(String, Box<Pat>),
+    /// The first of the patterns to match
+    OneOf(Vec<Pat>),
+}
+
+pub fn any() -> Pat {
+    Pat::Any
+}
+
+pub fn constant(value: u64) -> Pat {
+    Pat::Const(value)
+}
+
+pub fn any_constant() -> Pat {
+    Pat::AnyConst
+}
+
+pub fn reg(reg: RegId) -> Pat {
+    Pat::Reg(reg)
+}
+
+pub fn any_reg() -> Pat {
+    Pat::AnyReg
+}
+
+pub fn load(addr: Pat) -> Pat {
+    Pat::Load(Box::new(addr))
+}
+
+pub fn unary(op: UnOp, a: Pat) -> Pat {
+    Pat::Unary(op, Box::new(a))
+}
+
+pub fn binary(op: BinOp, a: Pat, b: Pat) -> Pat {
+    Pat::Binary(op, Box::new(a), Box::new(b))
+}
+
+pub fn add(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Add, a, b)
+}
+
+pub fn mul(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Mul, a, b)
+}
+
+pub fn and(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::And, a, b)
+}
+
+pub fn or(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Or, a, b)
+}
+
+pub fn xor(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Xor, a, b)
+}
+
+pub fn shl(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Shl, a, b)
+}
+
+pub fn shr(a: Pat, b: Pat) -> Pat {
+    binary(BinOp::Shr, a, b)
+}
+
+pub fn capture(name: &str, pat: Pat) -> Pat {
+    Pat::Capture(name.to_string(), Box::new(pat))
+}
+
+pub fn one_of(pats: impl IntoIterator<Item = Pat>) -> Pat {
+    Pat::OneOf(pats.into_iter().collect())
+}
+
+/// The expressions a match captured, by name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Captures {
+    values: BTreeMap<String, Expr>,
+}
+
+impl Captures {
+    pub fn get(&self, name: &str) -> Option<&Expr> {
+        self.values.get(name)
+    }
+
+    pub fn constant(&self, name: &str) -> Option<u64> {
+        self.get(name)?.as_const()
+    }
+
+    pub fn reg(&self, name: &str) -> Option<RegId> {
+        match self.get(name)? {
+            Expr::Reg(reg) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    fn bind(&mut self, name: &str, expr: &Expr) -> bool {
+        match self.values.get(name) {
+            Some(bound) => bound == expr,
+            None => {
+                self.values.insert(name.to_string(), expr.clone());
+                true
+            }
+        }
+    }
+}
+
+impl Pat {
+    /// The captures of a match of `expr`
+    pub fn matches(&self, expr: &Expr) -> Option<Captures> {
+        let mut captures = Captures::default();
+        self.bind(expr, &mut captures).then_some(captures)
+    }
+
+    /// Match `expr`, adding to `captures`; they're left as they were when it doesn't match
+    fn bind(&self, expr: &Expr, captures: &mut Captures) -> bool {
+        let saved = captures.clone();
+        let matched = match (self, expr) {
+            (Pat::Any, _) => true,
+            (Pat::Const(c), Expr::Const(value)) => c == value,
+            (Pat::AnyConst, Expr::Const(_)) => true,
+            (Pat::Reg(r), Expr::Reg(reg)) => r == reg,
+            (Pat::AnyReg, Expr::Reg(_)) => true,
+            (Pat::Load(addr), Expr::Load(a)) => addr.bind(a, captures),
+            (Pat::Unary(op, pat), Expr::Unary(o, a)) => op == o && pat.bind(a, captures),
+            (Pat::Binary(op, pa, pb), Expr::Binary(o, a, b)) if op == o => {
+                (pa.bind(a, captures) && pb.bind(b, captures))
+                    || (op.commutative() && {
+                        *captures = saved.clone();
+                        pa.bind(b, captures) && pb.bind(a, captures)
+                    })
+            }
+            (Pat::Capture(name, pat), _) => pat.bind(expr, captures) && captures.bind(name, expr),
+            (Pat::OneOf(pats), _) => pats.iter().any(|pat| {
+                *captures = saved.clone();
+                pat.bind(expr, captures)
+            }),
+            _ => false,
+        };
+        if !matched {
+            *captures = saved;
+        }
+        matched
+    }
+}
+
+/// The shape of a statement
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StmtPat {
+    Any,
+    /// A register, matched as an [`Expr::Reg`], set to a value
+    Set(Pat, Pat),
+    /// A store of a value to an address
+    Store(Pat, Pat),
+    /// Flags set by an operation, any if None
+    Flags(Option<FlagOp>, Pat, Pat),
+}
+
+impl StmtPat {
+    fn bind(&self, stmt: &Stmt, captures: &mut Captures) -> bool {
+        let saved = captures.clone();
+        let matched = match (self, stmt) {
+            (StmtPat::Any, _) => true,
+            (StmtPat::Set(reg, value), Stmt::Set(r, v)) => {
+                reg.bind(&Expr::Reg(*r), captures) && value.bind(v, captures)
+            }
+            (StmtPat::Store(addr, value), Stmt::Store(a, v)) => {
+                addr.bind(a, captures) && value.bind(v, captures)
+            }
+            (StmtPat::Flags(op, a, b), Stmt::Flags(o, x, y)) => {
+                op.is_none_or(|op| op == *o) && a.bind(x, captures) && b.bind(y, captures)
+            }
+            _ => false,
+        };
+        if !matched {
+            *captures = saved;
+        }
+        matched
+    }
+}
+
+/// The shape of the end of a block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EndPat {
+    /// A computed jump to a target
+    Indirect(Pat),
+    /// A branch on a condition, any if None
+    Branch(Option<Condition>),
+    Return,
+}
+
+impl EndPat {
+    fn bind(&self, end: &Terminator, captures: &mut Captures) -> bool {
+        match (self, end) {
+            (EndPat::Indirect(pat), Terminator::Indirect(target)) => pat.bind(target, captures),
+            (EndPat::Branch(cond), Terminator::Branch { cond: c, .. }) => {
+                cond.is_none_or(|cond| cond == *c)
+            }
+            (EndPat::Return, Terminator::Return) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Stmt(StmtPat),
+    /// Up to this many statements of any kind
+    Gap(usize),
+}
+
+/// Statements one after the other in a block
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sequence {
+    steps: Vec<Step>,
+    end: Option<EndPat>,
+}
+
+/// Where a sequence matched, and what it captured
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The instruction of the first statement matched
+    pub start: u64,
+    /// The instruction of the last statement matched, or the one ending the block
+    pub end: u64,
+    pub captures: Captures,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Sequence::default()
+    }
+
+    pub fn then(mut self, stmt: StmtPat) -> Self {
+        self.steps.push(Step::Stmt(stmt));
+        self
+    }
+
+    /// Allow up to `max` statements of any kind before the next step
+    pub fn gap(mut self, max: usize) -> Self {
+        self.steps.push(Step::Gap(max));
+        self
+    }
+
+    /// The statements matched have to be the last of the block, which ends like `end`
+    pub fn ending(mut self, end: EndPat) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// The end of a match of the steps from `step` at `pos` on
+    fn bind(
+        &self,
+        block: &Block,
+        stmts: &[(u64, &Stmt)],
+        step: usize,
+        pos: usize,
+        captures: &mut Captures,
+    ) -> Option<usize> {
+        let Some(current) = self.steps.get(step) else {
+            return match &self.end {
+                None => Some(pos),
+                Some(end) => (pos == stmts.len() && end.bind(&block.end, captures)).then_some(pos),
+            };
+        };
+        match current {
+            Step::Stmt(pat) => {
+                let (_, stmt) = stmts.get(pos)?;
+                let saved = captures.clone();
+                if !pat.bind(stmt, captures) {
+                    return None;
+                }
+                let end = self.bind(block, stmts, step + 1, pos + 1, captures);
+                if end.is_none() {
+                    *captures = saved;
+                }
+                end
+            }
+            Step::Gap(max) => (pos..=(pos + max).min(stmts.len()))
+                .find_map(|pos| self.bind(block, stmts, step + 1, pos, captures)),
+        }
+    }
+
+    /// The matches in a block, one for each statement a match starts at
+    pub fn find(&self, block: &Block) -> Vec<Match> {
+        let stmts: Vec<(u64, &Stmt)> = block
+            .insns
+            .iter()
+            .flat_map(|insn| insn.stmts.iter().map(move |stmt| (insn.va, stmt)))
+            .collect();
+        let mut matches = Vec::new();
+        for start in 0..=stmts.len() {
+            let mut captures = Captures::default();
+            let Some(end) = self.bind(block, &stmts, 0, start, &mut captures) else {
+                continue;
+            };
+            let end = match (&self.end, end.checked_sub(1)) {
+                (None, Some(last)) if last >= start => stmts[last].0,
+                (None, _) => continue,
+                (Some(_), _) => block.end_va,
+            };
+            matches.push(Match {
+                start: stmts.get(start).map_or(block.end_va, |(va, _)| *va),
+                end,
+                captures,
+            });
+        }
+        matches
+    }
+
+    /// The matches in every block of a function
+    pub fn find_in(&self, func: &Function) -> Vec<Match> {
+        func.blocks
+            .values()
+            .flat_map(|block| self.find(block))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::{registers::RegisterModel, Arch},
+        symbolic::Insn,
+    };
+
+    #[test]
+    fn table_dispatch() {
+        // and eax, 7; xor ecx, ecx; nop; jmp [0x4000 + eax*4]
+        let model = RegisterModel::new(Arch::I386);
+        let (eax, ecx) = (model.by_name("eax").unwrap(), model.by_name("ecx").unwrap());
+        let w = 32;
+        let slot = Expr::binary(
+            BinOp::Add,
+            Expr::binary(BinOp::Shl, Expr::Reg(eax), Expr::Const(2), w),
+            Expr::Const(0x4000),
+            w,
+        );
+        let block = Block {
+            va: 0x1000,
+            insns: vec![
+                Insn {
+                    va: 0x1000,
+                    stmts: vec![Stmt::Set(
+                        eax,
+                        Expr::binary(BinOp::And, Expr::Reg(eax), Expr::Const(7), w),
+                    )],
+                },
+                Insn {
+                    va: 0x1003,
+                    stmts: vec![Stmt::Set(
+                        ecx,
+                        Expr::binary(BinOp::Xor, Expr::Reg(ecx), Expr::Reg(ecx), w),
+                    )],
+                },
+                Insn {
+                    va: 0x1005,
+                    stmts: vec![Stmt::Set(ecx, Expr::Reg(ecx))],
+                },
+            ],
+            end_va: 0x1006,
+            end: Terminator::Indirect(Expr::Load(Box::new(slot))),
+        };
+
+        let index = || capture("index", any_reg());
+        let scaled = one_of([
+            mul(index(), capture("size", any_constant())),
+            shl(index(), capture("shift", any_constant())),
+        ]);
+        let dispatch = Sequence::new()
+            .then(StmtPat::Set(
+                index(),
+                and(index(), capture("mask", any_constant())),
+            ))
+            .gap(2)
+            .ending(EndPat::Indirect(load(add(
+                scaled,
+                capture("table", any_constant()),
+            ))));
+        let found = dispatch.find(&block);
+        assert_eq!(found.len(), 1);
+        let captures = &found[0].captures;
+        assert_eq!((found[0].start, found[0].end), (0x1000, 0x1006));
+        assert_eq!(captures.reg("index"), Some(eax));
+        assert_eq!(captures.constant("mask"), Some(7));
+        assert_eq!(captures.constant("shift"), Some(2));
+        assert_eq!(captures.constant("table"), Some(0x4000));
+        // Without room for both statements in between, there's no match
+        let tight = Sequence::new()
+            .then(StmtPat::Set(index(), and(any(), any())))
+            .gap(1)
+            .ending(EndPat::Indirect(any()));
+        assert!(tight.find(&block).is_empty());
+
+        // The same name captures the same expression; `xor ecx, ecx` folded to zero
+        let zeroed = Sequence::new().then(StmtPat::Set(capture("r", any_reg()), constant(0)));
+        let found = zeroed.find(&block);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].captures.reg("r"), Some(ecx));
+        let same = xor(capture("r", any_reg()), capture("r", any_reg()));
+        let twice = Expr::Binary(
+            BinOp::Xor,
+            Box::new(Expr::Reg(eax)),
+            Box::new(Expr::Reg(eax)),
+        );
+        let mixed = Expr::Binary(
+            BinOp::Xor,
+            Box::new(Expr::Reg(eax)),
+            Box::new(Expr::Reg(ecx)),
+        );
+        assert!(same.matches(&twice).is_some());
+        assert!(same.matches(&mixed).is_none());
+    }
+}
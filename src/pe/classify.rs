@@ -0,0 +1,472 @@
+//! High level classification of a PE image for triage.
+//!
+//! [`PE::classify`] tells a GUI application from a console one, a DLL, a service or a kernel
+//! driver, from the subsystem, the characteristics and what the image imports, and sums up what
+//! the imports say it can do: talk to the network, touch the filesystem or the registry, start
+//! or inject into other processes, and so on. Each [`Capability`] comes with the imports it was
+//! inferred from, so the summary can be checked. Imports only show intent; an image resolving
+//! its APIs at runtime shows none.
+
+use crate::pe::{
+    characteristic::{is_dll, IMAGE_FILE_SYSTEM},
+    optional_header::*,
+    PE,
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImageKind {
+    GuiApplication,
+    ConsoleApplication,
+    Dll,
+    /// An application which runs as a Windows service
+    Service,
+    Driver,
+    /// An EFI application, driver or ROM image
+    Efi,
+    /// A native NT process, or an image for a subsystem this doesn't know
+    Other,
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageKind::GuiApplication => "GUI application",
+            ImageKind::ConsoleApplication => "console application",
+            ImageKind::Dll => "DLL",
+            ImageKind::Service => "service",
+            ImageKind::Driver => "driver",
+            ImageKind::Efi => "EFI image",
+            ImageKind::Other => "other",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    Network,
+    Filesystem,
+    Registry,
+    /// Starting, enumerating or terminating processes
+    Process,
+    /// Writing to or running code in another process
+    Injection,
+    /// Installing or controlling services
+    ServiceControl,
+    Crypto,
+    AntiDebug,
+    /// Hooking input or windows of other programs
+    Hooking,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Network => "network",
+            Capability::Filesystem => "filesystem",
+            Capability::Registry => "registry",
+            Capability::Process => "process",
+            Capability::Injection => "injection",
+            Capability::ServiceControl => "service control",
+            Capability::Crypto => "crypto",
+            Capability::AntiDebug => "anti-debug",
+            Capability::Hooking => "hooking",
+        })
+    }
+}
+
+/// The APIs each capability is inferred from. A name matches with any `Ex`, `A` or `W` suffix
+/// too, and a name ending in `*` is a prefix for a whole family of APIs.
+const API_FAMILIES: &[(Capability, &[&str])] = &[
+    (
+        Capability::Network,
+        &[
+            "WSAStartup",
+            "WSASocket",
+            "socket",
+            "connect",
+            "bind",
+            "listen",
+            "accept",
+            "send",
+            "recv",
+            "gethostbyname",
+            "getaddrinfo",
+            "Internet*",
+            "HttpOpenRequest",
+            "HttpSendRequest",
+            "URLDownloadToFile",
+            "URLDownloadToCacheFile",
+            "WinHttp*",
+            "DnsQuery",
+            "WskRegister",
+        ],
+    ),
+    (
+        Capability::Filesystem,
+        &[
+            "CreateFile",
+            "ReadFile",
+            "WriteFile",
+            "DeleteFile",
+            "MoveFile",
+            "CopyFile",
+            "FindFirstFile",
+            "FindNextFile",
+            "CreateDirectory",
+            "RemoveDirectory",
+            "SetFileAttributes",
+            "NtCreateFile",
+            "ZwCreateFile",
+            "NtWriteFile",
+            "ZwWriteFile",
+            "FltRegisterFilter",
+        ],
+    ),
+    (
+        Capability::Registry,
+        &[
+            "RegOpenKey",
+            "RegCreateKey",
+            "RegSetValue",
+            "RegSetKeyValue",
+            "RegQueryValue",
+            "RegDeleteKey",
+            "RegDeleteValue",
+            "RegEnumKey",
+            "RegEnumValue",
+            "NtOpenKey",
+            "ZwOpenKey",
+            "ZwCreateKey",
+            "ZwSetValueKey",
+            "ZwQueryValueKey",
+            "CmRegisterCallback",
+        ],
+    ),
+    (
+        Capability::Process,
+        &[
+            "CreateProcess",
+            "ShellExecute",
+            "WinExec",
+            "OpenProcess",
+            "TerminateProcess",
+            "CreateToolhelp32Snapshot",
+            "Process32First",
+            "Process32Next",
+            "EnumProcesses",
+            "PsSetCreateProcessNotifyRoutine",
+            "PsLookupProcessByProcessId",
+        ],
+    ),
+    (
+        Capability::Injection,
+        &[
+            "VirtualAllocEx",
+            "VirtualProtectEx",
+            "WriteProcessMemory",
+            "CreateRemoteThread",
+            "NtCreateThreadEx",
+            "RtlCreateUserThread",
+            "QueueUserAPC",
+            "NtQueueApcThread",
+            "SetThreadContext",
+            "NtUnmapViewOfSection",
+            "ZwUnmapViewOfSection",
+            "NtMapViewOfSection",
+            "KeStackAttachProcess",
+        ],
+    ),
+    (
+        Capability::ServiceControl,
+        &[
+            "OpenSCManager",
+            "CreateService",
+            "StartService",
+            "ControlService",
+            "DeleteService",
+            "ChangeServiceConfig",
+        ],
+    ),
+    (
+        Capability::Crypto,
+        &[
+            "CryptAcquireContext",
+            "CryptEncrypt",
+            "CryptDecrypt",
+            "CryptGenKey",
+            "CryptImportKey",
+            "CryptDeriveKey",
+            "CryptHashData",
+            "BCryptOpenAlgorithmProvider",
+            "BCryptEncrypt",
+            "BCryptDecrypt",
+        ],
+    ),
+    (
+        Capability::AntiDebug,
+        &[
+            "IsDebuggerPresent",
+            "CheckRemoteDebuggerPresent",
+            "NtQueryInformationProcess",
+            "NtSetInformationThread",
+            "OutputDebugString",
+        ],
+    ),
+    (
+        Capability::Hooking,
+        &[
+            "SetWindowsHookEx",
+            "GetAsyncKeyState",
+            "GetKeyState",
+            "RegisterRawInputDevices",
+        ],
+    ),
+];
+
+/// The libraries only kernel mode code imports from
+const KERNEL_LIBRARIES: &[&str] = &[
+    "ntoskrnl.exe",
+    "hal.dll",
+    "ndis.sys",
+    "fltmgr.sys",
+    "wdfldr.sys",
+    "cng.sys",
+    "ksecdd.sys",
+    "netio.sys",
+];
+
+/// What the imports of a driver say about it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriverTraits {
+    /// Creates a device object (`IoCreateDevice`), so user mode can open it
+    pub creates_device: bool,
+    /// Names the device for user mode (`IoCreateSymbolicLink`)
+    pub symbolic_link: bool,
+    /// Completes IRPs, so it has IRP dispatch routines
+    pub dispatches_irps: bool,
+    /// Registers process, thread, image load, registry or object callbacks
+    pub callbacks: bool,
+    /// Is a filesystem minifilter
+    pub minifilter: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classification {
+    pub kind: ImageKind,
+    pub subsystem: u16,
+    /// Only set for drivers
+    pub driver: Option<DriverTraits>,
+    /// The capabilities, with the imports they were inferred from
+    pub capabilities: BTreeMap<Capability, Vec<String>>,
+}
+
+fn matches_family(name: &str, patterns: &[&str]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name.strip_prefix(pattern).is_some_and(|rest| {
+                let rest = rest.strip_prefix("Ex").unwrap_or(rest);
+                matches!(rest, "" | "A" | "W")
+            }),
+        })
+}
+
+impl Classification {
+    /// Classify an image from its header fields and its imports as (dll, function) pairs
+    pub fn new<'a, I>(
+        subsystem: u16,
+        characteristics: u16,
+        dll_characteristics: u16,
+        imports: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut capabilities: BTreeMap<Capability, Vec<String>> = BTreeMap::new();
+        let mut kernel = false;
+        let mut service = false;
+        let mut driver = DriverTraits::default();
+        for (dll, name) in imports {
+            kernel |= KERNEL_LIBRARIES
+                .iter()
+                .any(|library| dll.eq_ignore_ascii_case(library));
+            service |= name.starts_with("StartServiceCtrlDispatcher")
+                || name.starts_with("RegisterServiceCtrlHandler");
+            driver.creates_device |= name == "IoCreateDevice" || name == "WdfDeviceCreate";
+            driver.symbolic_link |= name == "IoCreateSymbolicLink";
+            driver.dispatches_irps |= name == "IofCompleteRequest" || name == "IoCompleteRequest";
+            driver.callbacks |= name.starts_with("PsSetCreate")
+                || name.starts_with("PsSetLoadImageNotifyRoutine")
+                || name == "CmRegisterCallbackEx"
+                || name == "CmRegisterCallback"
+                || name == "ObRegisterCallbacks";
+            driver.minifilter |= name == "FltRegisterFilter";
+            for (capability, prefixes) in API_FAMILIES.iter() {
+                if matches_family(name, prefixes) {
+                    let evidence = capabilities.entry(*capability).or_default();
+                    if !evidence.iter().any(|seen| seen == name) {
+                        evidence.push(name.into());
+                    }
+                }
+            }
+        }
+        let kind = match subsystem {
+            IMAGE_SUBSYSTEM_EFI_APPLICATION
+            | IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER
+            | IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER
+            | IMAGE_SUBSYSTEM_EFI_ROM => ImageKind::Efi,
+            _ if dll_characteristics & IMAGE_DLLCHARACTERISTICS_WDM_DRIVER != 0
+                || characteristics & IMAGE_FILE_SYSTEM != 0
+                || (subsystem == IMAGE_SUBSYSTEM_NATIVE && kernel) =>
+            {
+                ImageKind::Driver
+            }
+            _ if is_dll(characteristics) => ImageKind::Dll,
+            _ if service => ImageKind::Service,
+            IMAGE_SUBSYSTEM_WINDOWS_GUI | IMAGE_SUBSYSTEM_WINDOWS_CE_GUI => {
+                ImageKind::GuiApplication
+            }
+            IMAGE_SUBSYSTEM_WINDOWS_CUI | IMAGE_SUBSYSTEM_OS2_CUI | IMAGE_SUBSYSTEM_POSIX_CUI => {
+                ImageKind::ConsoleApplication
+            }
+            _ => ImageKind::Other,
+        };
+        Classification {
+            kind,
+            subsystem,
+            driver: (kind == ImageKind::Driver).then_some(driver),
+            capabilities,
+        }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains_key(&capability)
+    }
+}
+
+impl fmt::Display for Classification {
+    /// A one line summary, `driver: device, IRP dispatch; filesystem, registry`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        let mut traits = Vec::new();
+        if let Some(driver) = self.driver {
+            for (set, name) in [
+                (driver.creates_device, "device"),
+                (driver.symbolic_link, "symbolic link"),
+                (driver.dispatches_irps, "IRP dispatch"),
+                (driver.callbacks, "callbacks"),
+                (driver.minifilter, "minifilter"),
+            ] {
+                if set {
+                    traits.push(name);
+                }
+            }
+        }
+        let capabilities: Vec<String> = self
+            .capabilities
+            .keys()
+            .map(|capability| format!("{}", capability))
+            .collect();
+        if !traits.is_empty() {
+            write!(f, ": {}", traits.join(", "))?;
+        }
+        if !capabilities.is_empty() {
+            let separator = if traits.is_empty() { ": " } else { "; " };
+            write!(f, "{}{}", separator, capabilities.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PE<'a> {
+    /// Classify the image and sum up its capabilities, see [`Classification`]
+    pub fn classify(&self) -> Classification {
+        let (subsystem, dll_characteristics) = self
+            .header
+            .optional_header
+            .map(|header| {
+                (
+                    header.windows_fields.subsystem,
+                    header.windows_fields.dll_characteristics,
+                )
+            })
+            .unwrap_or_default();
+        Classification::new(
+            subsystem,
+            self.header.coff_header.characteristics,
+            dll_characteristics,
+            self.imports
+                .iter()
+                .map(|import| (import.dll, import.name.as_ref())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::characteristic::{IMAGE_FILE_DLL, IMAGE_FILE_EXECUTABLE_IMAGE};
+
+    #[test]
+    fn classify_images() {
+        let driver = Classification::new(
+            IMAGE_SUBSYSTEM_NATIVE,
+            IMAGE_FILE_EXECUTABLE_IMAGE,
+            0,
+            [
+                ("ntoskrnl.exe", "IoCreateDevice"),
+                ("ntoskrnl.exe", "IoCreateSymbolicLink"),
+                ("ntoskrnl.exe", "IofCompleteRequest"),
+                ("ntoskrnl.exe", "ZwCreateFile"),
+                ("ntoskrnl.exe", "ZwSetValueKey"),
+            ],
+        );
+        assert_eq!(driver.kind, ImageKind::Driver);
+        let traits = driver.driver.unwrap();
+        assert!(traits.creates_device && traits.dispatches_irps && !traits.minifilter);
+        assert_eq!(
+            driver.to_string(),
+            "driver: device, symbolic link, IRP dispatch; filesystem, registry"
+        );
+
+        let dropper = Classification::new(
+            IMAGE_SUBSYSTEM_WINDOWS_GUI,
+            IMAGE_FILE_EXECUTABLE_IMAGE,
+            0,
+            [
+                ("KERNEL32.dll", "VirtualAllocEx"),
+                ("KERNEL32.dll", "WriteProcessMemory"),
+                ("KERNEL32.dll", "CreateRemoteThread"),
+                ("WININET.dll", "InternetOpenUrlA"),
+                ("USER32.dll", "SendMessageW"),
+                ("KERNEL32.dll", "GetTickCount"),
+            ],
+        );
+        assert_eq!(dropper.kind, ImageKind::GuiApplication);
+        assert_eq!(dropper.driver, None);
+        assert_eq!(
+            dropper.capabilities[&Capability::Injection],
+            ["VirtualAllocEx", "WriteProcessMemory", "CreateRemoteThread"]
+        );
+        assert_eq!(
+            dropper.capabilities.keys().copied().collect::<Vec<_>>(),
+            [Capability::Network, Capability::Injection]
+        );
+
+        let service = Classification::new(
+            IMAGE_SUBSYSTEM_WINDOWS_CUI,
+            IMAGE_FILE_EXECUTABLE_IMAGE,
+            0,
+            [("ADVAPI32.dll", "StartServiceCtrlDispatcherW")],
+        );
+        assert_eq!(service.to_string(), "service");
+        let dll = Classification::new(IMAGE_SUBSYSTEM_WINDOWS_GUI, IMAGE_FILE_DLL, 0, []);
+        assert_eq!(dll.kind, ImageKind::Dll);
+        let console = Classification::new(IMAGE_SUBSYSTEM_WINDOWS_CUI, 0, 0, []);
+        assert_eq!(console.kind, ImageKind::ConsoleApplication);
+        assert!(!console.has(Capability::Network));
+    }
+}
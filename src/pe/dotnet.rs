@@ -0,0 +1,562 @@
+//! .NET images: IL only, mixed mode, ReadyToRun and NativeAOT.
+//!
+//! A .NET assembly has a CLI header (the CLR runtime header data directory) pointing at its
+//! metadata. Its code is IL unless:
+//!
+//! * it is mixed mode (C++/CLI), not flagged IL only, and has native code as any other PE;
+//! * it is ReadyToRun, precompiled by crossgen: the CLI header's managed native header points at
+//!   a `READYTORUN_HEADER` whose sections list the native code (`RuntimeFunctions`) and which
+//!   method each entry compiles (`MethodDefEntryPoints`). A composite image of several
+//!   assemblies has no CLI header and exports the header as `RTR_HEADER` instead;
+//! * it is NativeAOT, compiled ahead of time into a native executable with no CLI header at all.
+//!   The managed code is in the `.managed` section, and a ReadyToRun header of the NativeAOT
+//!   flavour, with pointers instead of RVAs, lists the runtime's data regions.
+//!
+//! [`PE::dotnet`] tells which, and collects the native code and the method it compiles.
+
+use crate::{
+    error,
+    pe::{options::ParseOptions, utils::find_offset, PE},
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use scroll::Pread;
+
+/// The image has no native code to run but the entry stub
+pub const COMIMAGE_FLAGS_ILONLY: u32 = 0x1;
+pub const COMIMAGE_FLAGS_32BITREQUIRED: u32 = 0x2;
+pub const COMIMAGE_FLAGS_STRONGNAMESIGNED: u32 = 0x8;
+/// The CLI header's entry point is an RVA of native code, not a method token
+pub const COMIMAGE_FLAGS_NATIVE_ENTRYPOINT: u32 = 0x10;
+
+/// "RTR", the signature of both ReadyToRun header flavours
+pub const READYTORUN_SIGNATURE: u32 = 0x0052_5452;
+
+pub const READYTORUN_SECTION_COMPILER_IDENTIFIER: u32 = 100;
+pub const READYTORUN_SECTION_IMPORT_SECTIONS: u32 = 101;
+pub const READYTORUN_SECTION_RUNTIME_FUNCTIONS: u32 = 102;
+pub const READYTORUN_SECTION_METHODDEF_ENTRYPOINTS: u32 = 103;
+pub const READYTORUN_SECTION_EXCEPTION_INFO: u32 = 104;
+pub const READYTORUN_SECTION_DEBUG_INFO: u32 = 105;
+/// The stubs calls to methods not compiled in the image go through
+pub const READYTORUN_SECTION_DELAYLOAD_METHODCALL_THUNKS: u32 = 106;
+pub const READYTORUN_SECTION_AVAILABLE_TYPES: u32 = 108;
+pub const READYTORUN_SECTION_INSTANCE_METHOD_ENTRYPOINTS: u32 = 109;
+pub const READYTORUN_SECTION_MANIFEST_METADATA: u32 = 112;
+pub const READYTORUN_SECTION_COMPONENT_ASSEMBLIES: u32 = 115;
+
+/// The metadata table of method definitions, the high byte of their tokens
+pub const TABLE_METHODDEF: u32 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DotNetKind {
+    /// IL only, compiled at run time
+    Il,
+    /// IL and native code, as C++/CLI makes
+    Mixed,
+    /// IL with precompiled native code
+    ReadyToRun,
+    /// Native code only
+    NativeAot,
+}
+
+impl fmt::Display for DotNetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DotNetKind::Il => "IL",
+            DotNetKind::Mixed => "mixed mode",
+            DotNetKind::ReadyToRun => "ReadyToRun",
+            DotNetKind::NativeAot => "NativeAOT",
+        })
+    }
+}
+
+/// The parts of the CLI header (`IMAGE_COR20_HEADER`) which locate code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CliHeader {
+    pub major_runtime_version: u16,
+    pub minor_runtime_version: u16,
+    /// The RVA and size of the metadata
+    pub metadata: (u32, u32),
+    /// The `COMIMAGE_FLAGS_*`
+    pub flags: u32,
+    /// The method token of the entry point, or its RVA with `COMIMAGE_FLAGS_NATIVE_ENTRYPOINT`
+    pub entry_point: u32,
+    /// The RVA and size of the table of native thunks into managed methods (mixed mode)
+    pub vtable_fixups: (u32, u32),
+    /// The RVA and size of the ReadyToRun header
+    pub managed_native_header: (u32, u32),
+}
+
+/// A section of a ReadyToRun header, `READYTORUN_SECTION_*` for a precompiled image and the
+/// runtime's own ids for a NativeAOT one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadyToRunSection {
+    pub section_type: u32,
+    pub rva: u32,
+    pub size: u32,
+}
+
+/// A function of native code; `end` isn't known but on x64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeFunction {
+    pub begin: u32,
+    pub end: Option<u32>,
+    pub unwind: u32,
+}
+
+/// The native code compiled for a method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManagedMethod {
+    /// The `MethodDef` token
+    pub token: u32,
+    pub rva: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadyToRun {
+    /// The RVA of the header
+    pub rva: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub flags: u32,
+    pub sections: Vec<ReadyToRunSection>,
+    /// The native code, methods with their funclets (exception handlers) after them
+    pub runtime_functions: Vec<RuntimeFunction>,
+    pub methods: Vec<ManagedMethod>,
+}
+
+impl ReadyToRun {
+    pub fn section(&self, section_type: u32) -> Option<&ReadyToRunSection> {
+        self.sections
+            .iter()
+            .find(|section| section.section_type == section_type)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NativeAot {
+    /// The RVA and size of the `.managed` section
+    pub managed_code: Option<(u32, u32)>,
+    /// The ReadyToRun header, if found, with the regions it lists
+    pub header: Option<ReadyToRun>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotNet {
+    pub kind: DotNetKind,
+    pub cli: Option<CliHeader>,
+    pub ready_to_run: Option<ReadyToRun>,
+    pub native_aot: Option<NativeAot>,
+}
+
+impl DotNet {
+    /// The RVA of the native entry point the CLI header gives, if it gives one
+    pub fn native_entry(&self) -> Option<u32> {
+        self.cli
+            .filter(|cli| cli.flags & COMIMAGE_FLAGS_NATIVE_ENTRYPOINT != 0)
+            .map(|cli| cli.entry_point)
+    }
+
+    /// The RVA and size of the stubs of a ReadyToRun image which call into the runtime, to
+    /// compile a method or bind an import on first call
+    pub fn entry_stubs(&self) -> Option<(u32, u32)> {
+        self.ready_to_run
+            .as_ref()
+            .and_then(|r2r| r2r.section(READYTORUN_SECTION_DELAYLOAD_METHODCALL_THUNKS))
+            .map(|section| (section.rva, section.size))
+    }
+}
+
+impl<'a> PE<'a> {
+    /// What kind of .NET image this is, and its native code, or `None` if it isn't one
+    pub fn dotnet(&self, bytes: &[u8]) -> Option<DotNet> {
+        let image = Image { pe: self, bytes };
+        let cli = self
+            .header
+            .optional_header
+            .and_then(|header| *header.data_directories.get_clr_runtime_header())
+            .and_then(|dd| image.cli_header(dd.virtual_address).ok());
+        let header = match cli {
+            Some(cli) if cli.managed_native_header.0 != 0 => Some(cli.managed_native_header.0),
+            Some(_) => None,
+            // A composite image has no CLI header, and the component assemblies point at it
+            None => self
+                .exports
+                .iter()
+                .find(|export| export.name == Some("RTR_HEADER"))
+                .map(|export| export.rva as u32),
+        };
+        if let Some(rva) = header {
+            match image.ready_to_run(rva) {
+                Ok(r2r) => {
+                    return Some(DotNet {
+                        kind: DotNetKind::ReadyToRun,
+                        cli,
+                        ready_to_run: Some(r2r),
+                        native_aot: None,
+                    })
+                }
+                Err(e) => anomaly!("Skipping the ReadyToRun header at {:#x}: {}", rva, e),
+            }
+        }
+        if let Some(cli) = cli {
+            let kind = if cli.flags & COMIMAGE_FLAGS_ILONLY != 0 {
+                DotNetKind::Il
+            } else {
+                DotNetKind::Mixed
+            };
+            return Some(DotNet {
+                kind,
+                cli: Some(cli),
+                ready_to_run: None,
+                native_aot: None,
+            });
+        }
+        let managed = self
+            .sections
+            .iter()
+            .find(|section| section.name().ok() == Some(".managed"))
+            .map(|section| (section.virtual_address, section.virtual_size));
+        let debug_header = self
+            .exports
+            .iter()
+            .any(|export| export.name == Some("DotNetRuntimeDebugHeader"));
+        if managed.is_none() && !debug_header {
+            return None;
+        }
+        Some(DotNet {
+            kind: DotNetKind::NativeAot,
+            cli: None,
+            ready_to_run: None,
+            native_aot: Some(NativeAot {
+                managed_code: managed,
+                header: image.native_aot_header(),
+            }),
+        })
+    }
+}
+
+struct Image<'a, 'b> {
+    pe: &'b PE<'a>,
+    bytes: &'b [u8],
+}
+
+impl Image<'_, '_> {
+    fn offset(&self, rva: u32) -> error::Result<usize> {
+        let file_alignment = self
+            .pe
+            .header
+            .optional_header
+            .map_or(0x200, |header| header.windows_fields.file_alignment);
+        let opts = ParseOptions::default();
+        find_offset(rva as usize, &self.pe.sections, file_alignment, &opts)
+            .ok_or_else(|| error::Error::Malformed(format!("RVA {:#x} is in no section", rva)))
+    }
+
+    fn u32(&self, rva: u32) -> error::Result<u32> {
+        Ok(self.bytes.pread_with(self.offset(rva)?, scroll::LE)?)
+    }
+
+    fn u16(&self, rva: u32) -> error::Result<u16> {
+        Ok(self.bytes.pread_with(self.offset(rva)?, scroll::LE)?)
+    }
+
+    fn data(&self, rva: u32, size: u32) -> error::Result<&[u8]> {
+        let offset = self.offset(rva)?;
+        self.bytes
+            .get(offset..offset.saturating_add(size as usize))
+            .ok_or_else(|| error::Error::Malformed(format!("{:#x} bytes at {:#x}", size, rva)))
+    }
+
+    fn cli_header(&self, rva: u32) -> error::Result<CliHeader> {
+        let dd = |at: u32| -> error::Result<(u32, u32)> {
+            Ok((self.u32(rva + at)?, self.u32(rva + at + 4)?))
+        };
+        Ok(CliHeader {
+            major_runtime_version: self.u16(rva + 4)?,
+            minor_runtime_version: self.u16(rva + 6)?,
+            metadata: dd(8)?,
+            flags: self.u32(rva + 16)?,
+            entry_point: self.u32(rva + 20)?,
+            vtable_fixups: dd(48)?,
+            managed_native_header: dd(64)?,
+        })
+    }
+
+    fn ready_to_run(&self, rva: u32) -> error::Result<ReadyToRun> {
+        if self.u32(rva)? != READYTORUN_SIGNATURE {
+            return Err(error::Error::Malformed(String::from(
+                "No ReadyToRun signature",
+            )));
+        }
+        let mut r2r = ReadyToRun {
+            rva,
+            major_version: self.u16(rva + 4)?,
+            minor_version: self.u16(rva + 6)?,
+            flags: self.u32(rva + 8)?,
+            ..Default::default()
+        };
+        let count = self.u32(rva + 12)?;
+        for idx in 0..count.min(0x100) {
+            let at = rva + 16 + idx * 12;
+            r2r.sections.push(ReadyToRunSection {
+                section_type: self.u32(at)?,
+                rva: self.u32(at + 4)?,
+                size: self.u32(at + 8)?,
+            });
+        }
+        if let Some(section) = r2r.section(READYTORUN_SECTION_RUNTIME_FUNCTIONS) {
+            let x64 = self.pe.header.coff_header.machine == crate::pe::header::COFF_MACHINE_X86_64;
+            let entry_size = if x64 { 12 } else { 8 };
+            let data = self.data(section.rva, section.size)?;
+            for entry in data.chunks_exact(entry_size) {
+                let word = |idx: usize| entry.pread_with::<u32>(idx * 4, scroll::LE);
+                r2r.runtime_functions.push(RuntimeFunction {
+                    begin: word(0)?,
+                    end: if x64 { Some(word(1)?) } else { None },
+                    unwind: word(entry_size / 4 - 1)?,
+                });
+            }
+        }
+        if let Some(section) = r2r.section(READYTORUN_SECTION_METHODDEF_ENTRYPOINTS) {
+            let entry_points = NativeArray::new(self.data(section.rva, section.size)?)?;
+            for idx in 0..entry_points.len {
+                let Some(index) = entry_points.get(idx) else {
+                    continue;
+                };
+                if let Some(function) = r2r.runtime_functions.get(index as usize) {
+                    r2r.methods.push(ManagedMethod {
+                        token: TABLE_METHODDEF << 24 | (idx + 1),
+                        rva: function.begin,
+                    });
+                }
+            }
+        }
+        Ok(r2r)
+    }
+
+    /// The NativeAOT header isn't pointed at by anything but code; it is the one holding the
+    /// signature in a data section, followed by rows of the pointer size
+    fn native_aot_header(&self) -> Option<ReadyToRun> {
+        let ptr_size = if self.pe.is_64 { 8 } else { 4 };
+        let image_base = self.pe.image_base as u64;
+        for section in self.pe.sections.iter() {
+            if section.characteristics & 0x2000_0000 != 0 {
+                continue;
+            }
+            let start = section.pointer_to_raw_data as usize;
+            let size = section.size_of_raw_data.min(section.virtual_size) as usize;
+            let Some(data) = self.bytes.get(start..start.saturating_add(size)) else {
+                continue;
+            };
+            for at in (0..data.len().saturating_sub(16)).step_by(4) {
+                if data.pread_with::<u32>(at, scroll::LE).ok() != Some(READYTORUN_SIGNATURE) {
+                    continue;
+                }
+                let count = data.pread_with::<u16>(at + 12, scroll::LE).unwrap_or(0);
+                // the rows are a section id, flags and the start and end pointers
+                if data.get(at + 14).copied() != Some(8 + 2 * ptr_size as u8) {
+                    continue;
+                }
+                let mut header = ReadyToRun {
+                    rva: section.virtual_address + at as u32,
+                    major_version: data.pread_with(at + 4, scroll::LE).ok()?,
+                    minor_version: data.pread_with(at + 6, scroll::LE).ok()?,
+                    flags: data.pread_with(at + 8, scroll::LE).ok()?,
+                    ..Default::default()
+                };
+                for idx in 0..count as usize {
+                    let row = at + 16 + idx * (8 + 2 * ptr_size);
+                    let pointer = |at: usize| -> Option<u64> {
+                        if ptr_size == 8 {
+                            data.pread_with::<u64>(at, scroll::LE).ok()
+                        } else {
+                            data.pread_with::<u32>(at, scroll::LE).ok().map(u64::from)
+                        }
+                    };
+                    let (Some(start), Some(end)) = (pointer(row + 8), pointer(row + 8 + ptr_size))
+                    else {
+                        break;
+                    };
+                    header.sections.push(ReadyToRunSection {
+                        section_type: data.pread_with(row, scroll::LE).ok()?,
+                        rva: start.wrapping_sub(image_base) as u32,
+                        size: end.saturating_sub(start) as u32,
+                    });
+                }
+                return Some(header);
+            }
+        }
+        None
+    }
+}
+
+/// An array of the runtime's NativeFormat: a header, an offset per block of 16 elements, and
+/// each block a binary tree of the elements in it
+struct NativeArray<'a> {
+    data: &'a [u8],
+    base: usize,
+    len: u32,
+    index_size: u32,
+}
+
+const BLOCK_SIZE: u32 = 16;
+
+impl<'a> NativeArray<'a> {
+    fn new(data: &'a [u8]) -> error::Result<Self> {
+        let (header, base) = decode_unsigned(data, 0)
+            .ok_or_else(|| error::Error::Malformed(String::from("Truncated native array")))?;
+        Ok(NativeArray {
+            data,
+            base,
+            len: header >> 2,
+            index_size: header & 3,
+        })
+    }
+
+    /// The runtime function index the element `idx` holds, if it holds one
+    fn get(&self, idx: u32) -> Option<u32> {
+        let block = (idx / BLOCK_SIZE) as usize;
+        let mut offset = self.base
+            + match self.index_size {
+                0 => *self.data.get(self.base + block)? as usize,
+                1 => self
+                    .data
+                    .pread_with::<u16>(self.base + 2 * block, scroll::LE)
+                    .ok()? as usize,
+                _ => self
+                    .data
+                    .pread_with::<u32>(self.base + 4 * block, scroll::LE)
+                    .ok()? as usize,
+            };
+        let mut bit = BLOCK_SIZE >> 1;
+        while bit > 0 {
+            let (val, next) = decode_unsigned(self.data, offset)?;
+            if idx & bit != 0 && val & 2 != 0 {
+                offset += (val >> 2) as usize;
+            } else if idx & bit == 0 && val & 1 != 0 {
+                offset = next;
+            } else if val & 3 == 0 && val >> 2 == idx & (BLOCK_SIZE - 1) {
+                offset = next;
+                break;
+            } else {
+                return None;
+            }
+            bit >>= 1;
+        }
+        // the fixups to resolve before the method runs may come first
+        let (id, _) = decode_unsigned(self.data, offset)?;
+        Some(if id & 1 != 0 { id >> 2 } else { id >> 1 })
+    }
+}
+
+/// A NativeFormat unsigned number at `offset`, and the offset after it
+fn decode_unsigned(data: &[u8], offset: usize) -> Option<(u32, usize)> {
+    let byte = |idx: usize| data.get(offset + idx).map(|b| u32::from(*b));
+    let first = byte(0)?;
+    Some(if first & 1 == 0 {
+        (first >> 1, offset + 1)
+    } else if first & 2 == 0 {
+        (first >> 2 | byte(1)? << 6, offset + 2)
+    } else if first & 4 == 0 {
+        (first >> 3 | byte(1)? << 5 | byte(2)? << 13, offset + 3)
+    } else if first & 8 == 0 {
+        let value = first >> 4 | byte(1)? << 4 | byte(2)? << 12 | byte(3)? << 20;
+        (value, offset + 4)
+    } else if first & 16 == 0 {
+        (data.pread_with(offset + 1, scroll::LE).ok()?, offset + 5)
+    } else {
+        return None;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An i386 image with a CLI header, a ReadyToRun header listing one function compiling the
+    /// first method, and the function
+    fn ready_to_run_image() -> Vec<u8> {
+        let mut pe = b"MZ".to_vec();
+        pe.resize(0x3c, 0);
+        pe.extend(0x40u32.to_le_bytes());
+        pe.extend(b"PE\0\0");
+        for half in [0x14cu16, 1, 0, 0, 0, 0, 0, 0, 0xe0, 0x102] {
+            pe.extend(half.to_le_bytes());
+        }
+        pe.extend(0x10bu16.to_le_bytes());
+        pe.resize(pe.len() + 26, 0);
+        for word in [0x400000u32, 0x1000, 0x200, 0, 0, 0x4, 0, 0x2000, 0x200, 0] {
+            pe.extend(word.to_le_bytes());
+        }
+        pe.extend(3u16.to_le_bytes());
+        pe.resize(pe.len() + 22, 0);
+        pe.extend(16u32.to_le_bytes());
+        pe.resize(pe.len() + 14 * 8, 0);
+        pe.extend(0x1000u32.to_le_bytes());
+        pe.extend(72u32.to_le_bytes());
+        pe.resize(pe.len() + 8, 0);
+        pe.extend(b".text\0\0\0");
+        for word in [0x200u32, 0x1000, 0x200, 0x200, 0, 0, 0] {
+            pe.extend(word.to_le_bytes());
+        }
+        pe.extend(0x6000_0020u32.to_le_bytes());
+        pe.resize(0x200, 0);
+
+        let mut cli = [0u32; 18];
+        cli[0] = 72;
+        cli[1] = 5 << 16 | 2;
+        cli[4] = COMIMAGE_FLAGS_ILONLY;
+        cli[5] = 0x0600_0001;
+        cli[16] = 0x1050;
+        cli[17] = 40;
+        pe.extend(cli.iter().flat_map(|word| word.to_le_bytes()));
+        pe.resize(0x250, 0);
+        for word in [READYTORUN_SIGNATURE, 9, 0, 2] {
+            pe.extend(word.to_le_bytes());
+        }
+        for word in [102u32, 0x1080, 8, 103, 0x1090, 4] {
+            pe.extend(word.to_le_bytes());
+        }
+        pe.resize(0x280, 0);
+        pe.extend(0x10c0u32.to_le_bytes());
+        pe.extend(0x10f0u32.to_le_bytes());
+        pe.resize(0x290, 0);
+        // one element, indexes of a byte; the block a leaf for element 0, which is function 0
+        pe.extend([0x08, 0x01, 0x00, 0x00]);
+        pe.resize(0x2c0, 0);
+        pe.push(0xc3);
+        pe.resize(0x400, 0);
+        pe
+    }
+
+    #[test]
+    fn ready_to_run() {
+        let bytes = ready_to_run_image();
+        let pe = PE::parse(&bytes).unwrap();
+        let dotnet = pe.dotnet(&bytes).unwrap();
+        assert_eq!(dotnet.kind, DotNetKind::ReadyToRun);
+        assert_eq!(dotnet.native_entry(), None);
+        let r2r = dotnet.ready_to_run.unwrap();
+        assert_eq!((r2r.major_version, r2r.sections.len()), (9, 2));
+        assert_eq!(r2r.runtime_functions.len(), 1);
+        assert_eq!(
+            r2r.methods,
+            [ManagedMethod {
+                token: 0x0600_0001,
+                rva: 0x10c0
+            }]
+        );
+
+        assert_eq!(decode_unsigned(&[0x05, 0x02], 0), Some((0x81, 2)));
+        let mut native = bytes.clone();
+        // neither IL only nor precompiled
+        native[0x210] = 0;
+        native[0x240..0x248].fill(0);
+        let pe = PE::parse(&native).unwrap();
+        assert_eq!(pe.dotnet(&native).unwrap().kind, DotNetKind::Mixed);
+        let (plain, _) = crate::pe::import::ImportTable::default().tiny_image();
+        assert!(PE::parse(&plain).unwrap().dotnet(&plain).is_none());
+    }
+}
@@ -39,6 +39,8 @@
 //! [`UnwindInfo`]: struct.UnwindInfo.html
 //! [x64 exception handling]: https://docs.microsoft.com/en-us/cpp/build/exception-handling-x64?view=vs-2017
 
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::cmp::Ordering;
 use core::fmt;
 use core::iter::FusedIterator;
@@ -93,6 +95,8 @@ const UWOP_PUSH_MACHFRAME: u8 = 10;
 const RUNTIME_FUNCTION_SIZE: usize = 12;
 /// Size of unwind code slots. Codes take 1 - 3 slots.
 const UNWIND_CODE_SIZE: usize = 2;
+/// The most chained unwind infos followed to the primary entry of a function.
+const MAX_CHAIN_DEPTH: usize = 32;
 
 /// An unwind entry for a range of a function.
 ///
@@ -123,6 +127,20 @@ impl fmt::Debug for RuntimeFunction {
     }
 }
 
+/// A function as described by the exception directory: its entry, and every range of code that
+/// belongs to it, including the ranges of entries chained to it.
+///
+/// Compilers chain the unwind info of code they moved away from its function (cold paths,
+/// shrink-wrapped regions) to the entry of the function, so the merged ranges give the real
+/// extent of hot/cold split functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionExtent {
+    /// The RVA of the function entry.
+    pub begin_address: u32,
+    /// The `(begin, end)` RVAs of the code of the function, sorted.
+    pub ranges: Vec<(u32, u32)>,
+}
+
 /// Iterator over runtime function entries in [`ExceptionData`](struct.ExceptionData.html).
 #[derive(Debug)]
 pub struct RuntimeFunctionIterator<'a> {
@@ -809,6 +827,85 @@ impl<'a> ExceptionData<'a> {
         UnwindInfo::parse(self.bytes, offset)
     }
 
+    /// Returns the primary entry `function` is chained to, or `function` itself if it isn't
+    /// chained.
+    pub fn primary_function(
+        &self,
+        function: RuntimeFunction,
+        sections: &[section_table::SectionTable],
+    ) -> error::Result<RuntimeFunction> {
+        self.primary_function_with_opts(function, sections, &options::ParseOptions::default())
+    }
+
+    /// Returns the primary entry `function` is chained to, or `function` itself if it isn't
+    /// chained.
+    pub fn primary_function_with_opts(
+        &self,
+        mut function: RuntimeFunction,
+        sections: &[section_table::SectionTable],
+        opts: &options::ParseOptions,
+    ) -> error::Result<RuntimeFunction> {
+        for _ in 0..MAX_CHAIN_DEPTH {
+            // An odd unwind info address points at the entry of another function instead.
+            if !function.unwind_info_address.is_multiple_of(2) {
+                let rva = (function.unwind_info_address & !1) as usize;
+                function = self.get_function_by_rva_with_opts(rva, sections, opts)?;
+                continue;
+            }
+            match self
+                .get_unwind_info_with_opts(function, sections, opts)?
+                .chained_info
+            {
+                Some(parent) => function = parent,
+                None => return Ok(function),
+            }
+        }
+        Err(error::Error::Malformed(format!(
+            "unwind info chain of function {:#x} is too long",
+            function.begin_address
+        )))
+    }
+
+    /// Returns every function of the image, with the entries chained to a function merged into
+    /// its ranges. Entries whose unwind info can't be read are functions of their own.
+    pub fn function_extents(
+        &self,
+        sections: &[section_table::SectionTable],
+    ) -> error::Result<Vec<FunctionExtent>> {
+        self.function_extents_with_opts(sections, &options::ParseOptions::default())
+    }
+
+    /// Returns every function of the image, with the entries chained to a function merged into
+    /// its ranges. Entries whose unwind info can't be read are functions of their own.
+    pub fn function_extents_with_opts(
+        &self,
+        sections: &[section_table::SectionTable],
+        opts: &options::ParseOptions,
+    ) -> error::Result<Vec<FunctionExtent>> {
+        let mut extents: BTreeMap<u32, Vec<(u32, u32)>> = BTreeMap::new();
+        for function in self.functions() {
+            let function = function?;
+            let primary = self
+                .primary_function_with_opts(function, sections, opts)
+                .unwrap_or(function);
+            extents
+                .entry(primary.begin_address)
+                .or_default()
+                .push((function.begin_address, function.end_address));
+        }
+        Ok(extents
+            .into_iter()
+            .map(|(begin_address, mut ranges)| {
+                ranges.sort_unstable();
+                ranges.dedup();
+                FunctionExtent {
+                    begin_address,
+                    ranges,
+                }
+            })
+            .collect())
+    }
+
     #[allow(dead_code)]
     fn get_function_by_rva(
         &self,
@@ -877,6 +974,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn chained_function_extents() {
+        let sections = [section_table::SectionTable {
+            virtual_address: 0x1000,
+            virtual_size: 0x1000,
+            size_of_raw_data: 0x1000,
+            pointer_to_raw_data: 0x400,
+            ..Default::default()
+        }];
+        let mut bytes = vec![0u8; 0x1400];
+        let functions = [
+            (0x1100, 0x1180, 0x1800),
+            // chained through its unwind info
+            (0x1180, 0x11a0, 0x1810),
+            // chained through an odd unwind info address, pointing at the first entry
+            (0x11a0, 0x11c0, 0x1001),
+            (0x1200, 0x1240, 0x1820),
+        ];
+        for (idx, (begin_address, end_address, unwind_info_address)) in
+            functions.into_iter().enumerate()
+        {
+            let function = RuntimeFunction {
+                begin_address,
+                end_address,
+                unwind_info_address,
+            };
+            bytes
+                .pwrite_with(function, 0x400 + idx * RUNTIME_FUNCTION_SIZE, scroll::LE)
+                .unwrap();
+        }
+        bytes[0xc00] = 1;
+        bytes[0xc10] = 1 | (UNW_FLAG_CHAININFO << 3);
+        bytes
+            .pwrite_with(
+                RuntimeFunction {
+                    begin_address: 0x1100,
+                    end_address: 0x1180,
+                    unwind_info_address: 0x1800,
+                },
+                0xc14,
+                scroll::LE,
+            )
+            .unwrap();
+        bytes[0xc20] = 1;
+        let directory = data_directories::DataDirectory {
+            virtual_address: 0x1000,
+            size: 4 * RUNTIME_FUNCTION_SIZE as u32,
+        };
+        let exceptions = ExceptionData::parse(&bytes, directory, &sections, 0x200).unwrap();
+        let extents = exceptions.function_extents(&sections).unwrap();
+        assert_eq!(
+            extents,
+            [
+                FunctionExtent {
+                    begin_address: 0x1100,
+                    ranges: vec![(0x1100, 0x1180), (0x1180, 0x11a0), (0x11a0, 0x11c0)],
+                },
+                FunctionExtent {
+                    begin_address: 0x1200,
+                    ranges: vec![(0x1200, 0x1240)],
+                },
+            ]
+        );
+    }
+
     // Tests disabled until there is a solution for handling binary test data
     // See https://github.com/m4b/goblin/issues/185
 
@@ -1,4 +1,6 @@
 use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::fmt::{Debug, LowerHex};
 
@@ -415,3 +417,322 @@ impl<'a> Import<'a> {
         Ok(imports)
     }
 }
+
+/// A function imported from a DLL, as [`ImportTable`] sees it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImportedFunction {
+    Name { hint: u16, name: String },
+    Ordinal(u16),
+}
+
+impl ImportedFunction {
+    /// Import `name`, with no hint
+    pub fn by_name(name: &str) -> Self {
+        ImportedFunction::Name {
+            hint: 0,
+            name: name.to_string(),
+        }
+    }
+
+    /// Whether this is the function `name`, or the ordinal `name` parses as
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            ImportedFunction::Name { name: n, .. } => n == name,
+            ImportedFunction::Ordinal(ordinal) => name.parse() == Ok(*ordinal),
+        }
+    }
+}
+
+/// The functions imported from one DLL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedDll {
+    pub name: String,
+    pub functions: Vec<ImportedFunction>,
+    /// The RVA of the IAT this DLL was parsed with and the functions it holds. The IAT stays
+    /// where it is as long as those functions remain the first ones imported, since the code
+    /// refers to its slots.
+    original: Option<(u32, Vec<ImportedFunction>)>,
+}
+
+impl ImportedDll {
+    pub fn new(name: &str) -> Self {
+        ImportedDll {
+            name: name.to_string(),
+            functions: Vec::new(),
+            original: None,
+        }
+    }
+
+    /// The RVA of the IAT the original functions of the DLL stay in, if it is kept
+    fn kept_iat(&self) -> Option<u32> {
+        let (iat, original) = self.original.as_ref()?;
+        if !original.is_empty() && self.functions.starts_with(original) {
+            Some(*iat)
+        } else {
+            None
+        }
+    }
+}
+
+/// An import table being edited, for the PE writer to rebuild
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportTable {
+    pub dlls: Vec<ImportedDll>,
+}
+
+/// An import table built into a section by [`ImportTable::build`]
+#[derive(Debug, Clone, Default)]
+pub struct BuiltImportTable {
+    /// The section contents, starting with the import directory table
+    pub data: Vec<u8>,
+    /// The size of the import directory table, including the terminating null descriptor
+    pub directory_size: u32,
+    /// The RVA of the IAT slot of every import, as `(dll, function, rva)`
+    pub slots: Vec<(String, ImportedFunction, u32)>,
+}
+
+impl ImportTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The imports of a parsed binary
+    pub fn from_import_data(import_data: &ImportData) -> error::Result<Self> {
+        let mut dlls = Vec::with_capacity(import_data.import_data.len());
+        for entry in import_data.import_data.iter() {
+            let lookup_table = entry.import_lookup_table.as_ref().ok_or_else(|| {
+                error::Error::Malformed(format!("Can't read the imports of {}", entry.name))
+            })?;
+            let functions = lookup_table
+                .iter()
+                .map(|entry| match entry {
+                    SyntheticImportLookupTableEntry::OrdinalNumber(ordinal) => {
+                        ImportedFunction::Ordinal(*ordinal)
+                    }
+                    SyntheticImportLookupTableEntry::HintNameTableRVA((_, entry)) => {
+                        ImportedFunction::Name {
+                            hint: entry.hint,
+                            name: entry.name.to_string(),
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            // an entry with a bad hint/name RVA is skipped, which misaligns it with the IAT
+            let original = (functions.len() == entry.import_address_table.len()).then(|| {
+                (
+                    entry.import_directory_entry.import_address_table_rva,
+                    functions.clone(),
+                )
+            });
+            dlls.push(ImportedDll {
+                name: entry.name.to_string(),
+                functions,
+                original,
+            });
+        }
+        Ok(ImportTable { dlls })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dlls.iter().all(|dll| dll.functions.is_empty())
+    }
+
+    /// The DLL `name`; DLL names compare case insensitively
+    pub fn dll(&self, name: &str) -> Option<&ImportedDll> {
+        self.dlls
+            .iter()
+            .find(|dll| dll.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Import `function` from `dll`, returning false if it already is
+    pub fn add_function(&mut self, dll: &str, function: ImportedFunction) -> bool {
+        let idx = match self
+            .dlls
+            .iter()
+            .position(|d| d.name.eq_ignore_ascii_case(dll))
+        {
+            Some(idx) => idx,
+            None => {
+                self.dlls.push(ImportedDll::new(dll));
+                self.dlls.len() - 1
+            }
+        };
+        let functions = &mut self.dlls[idx].functions;
+        if functions.contains(&function) {
+            return false;
+        }
+        functions.push(function);
+        true
+    }
+
+    /// Stop importing the function (or ordinal) `function` from `dll`, returning whether it was
+    /// imported. The remaining imports of the DLL get a new IAT.
+    pub fn remove_function(&mut self, dll: &str, function: &str) -> bool {
+        match self
+            .dlls
+            .iter_mut()
+            .find(|d| d.name.eq_ignore_ascii_case(dll))
+        {
+            Some(dll) => {
+                let before = dll.functions.len();
+                dll.functions.retain(|f| !f.matches(function));
+                before != dll.functions.len()
+            }
+            None => false,
+        }
+    }
+
+    /// Stop importing anything from `dll`, returning whether it was imported
+    pub fn remove_dll(&mut self, dll: &str) -> bool {
+        let before = self.dlls.len();
+        self.dlls.retain(|d| !d.name.eq_ignore_ascii_case(dll));
+        before != self.dlls.len()
+    }
+
+    /// Build the import directory table, the import lookup tables, the new IATs, the hint/name
+    /// table and the DLL names as a section which will be loaded at `rva`. A DLL whose original
+    /// functions are all still imported keeps its IAT, and the functions added to it are
+    /// imported through a second descriptor for the same DLL.
+    pub fn build(&self, rva: u32, is_64: bool) -> BuiltImportTable {
+        let thunk_size = if is_64 { 8 } else { 4 };
+        // (dll, functions, the IAT to keep)
+        let mut descriptors = Vec::new();
+        for dll in self.dlls.iter().filter(|dll| !dll.functions.is_empty()) {
+            match dll.kept_iat() {
+                Some(iat) => {
+                    let kept = dll.original.as_ref().map_or(0, |(_, f)| f.len());
+                    descriptors.push((dll, &dll.functions[..kept], Some(iat)));
+                    if kept < dll.functions.len() {
+                        descriptors.push((dll, &dll.functions[kept..], None));
+                    }
+                }
+                None => descriptors.push((dll, &dll.functions[..], None)),
+            }
+        }
+
+        let directory_size = SIZEOF_IMPORT_DIRECTORY_ENTRY * (descriptors.len() + 1);
+        let mut lookup_tables = directory_size.next_multiple_of(8);
+        let mut address_tables = lookup_tables
+            + descriptors
+                .iter()
+                .map(|(_, functions, _)| (functions.len() + 1) * thunk_size)
+                .sum::<usize>();
+        let strings = address_tables
+            + descriptors
+                .iter()
+                .filter(|(_, _, iat)| iat.is_none())
+                .map(|(_, functions, _)| (functions.len() + 1) * thunk_size)
+                .sum::<usize>();
+        let mut data = vec![0u8; strings];
+        let mut slots = Vec::new();
+        let mut dll_names: BTreeMap<&str, u32> = BTreeMap::new();
+        for (i, (dll, functions, iat)) in descriptors.iter().enumerate() {
+            let name_rva = match dll_names.get(dll.name.as_str()) {
+                Some(name_rva) => *name_rva,
+                None => {
+                    let name_rva = rva + data.len() as u32;
+                    data.extend_from_slice(dll.name.as_bytes());
+                    data.push(0);
+                    dll_names.insert(&dll.name, name_rva);
+                    name_rva
+                }
+            };
+            let iat = match iat {
+                Some(iat) => *iat,
+                None => {
+                    let iat = rva + address_tables as u32;
+                    address_tables += (functions.len() + 1) * thunk_size;
+                    iat
+                }
+            };
+            let descriptor = ImportDirectoryEntry {
+                import_lookup_table_rva: rva + lookup_tables as u32,
+                time_date_stamp: 0,
+                forwarder_chain: 0,
+                name_rva,
+                import_address_table_rva: iat,
+            };
+            data.pwrite_with(descriptor, SIZEOF_IMPORT_DIRECTORY_ENTRY * i, scroll::LE)
+                .unwrap();
+
+            for (j, function) in functions.iter().enumerate() {
+                let thunk = match function {
+                    ImportedFunction::Ordinal(ordinal) if is_64 => {
+                        IMPORT_BY_ORDINAL_64 | u64::from(*ordinal)
+                    }
+                    ImportedFunction::Ordinal(ordinal) => {
+                        u64::from(IMPORT_BY_ORDINAL_32 | u32::from(*ordinal))
+                    }
+                    ImportedFunction::Name { hint, name } => {
+                        data.resize(data.len().next_multiple_of(2), 0);
+                        let hint_rva = rva + data.len() as u32;
+                        data.extend_from_slice(&hint.to_le_bytes());
+                        data.extend_from_slice(name.as_bytes());
+                        data.push(0);
+                        u64::from(hint_rva)
+                    }
+                };
+                write_thunk(&mut data, lookup_tables + j * thunk_size, thunk, is_64);
+                let slot = iat + (j * thunk_size) as u32;
+                // the IATs built here start out as copies of the lookup tables
+                if slot >= rva {
+                    write_thunk(&mut data, (slot - rva) as usize, thunk, is_64);
+                }
+                slots.push((dll.name.clone(), function.clone(), slot));
+            }
+            lookup_tables += (functions.len() + 1) * thunk_size;
+        }
+        data.resize(data.len().next_multiple_of(thunk_size), 0);
+        BuiltImportTable {
+            data,
+            directory_size: directory_size as u32,
+            slots,
+        }
+    }
+}
+
+fn write_thunk(data: &mut [u8], at: usize, thunk: u64, is_64: bool) {
+    if is_64 {
+        data.pwrite_with(thunk, at, scroll::LE).unwrap();
+    } else {
+        data.pwrite_with(thunk as u32, at, scroll::LE).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl ImportTable {
+    /// An i386 executable at 0x400000 with nothing in it but the import table, at 0x1000
+    pub(crate) fn tiny_image(&self) -> (Vec<u8>, BuiltImportTable) {
+        let idata = self.build(0x1000, false);
+        let mut pe = b"MZ".to_vec();
+        pe.resize(0x3c, 0);
+        pe.extend(0x40u32.to_le_bytes());
+        pe.extend(b"PE\0\0");
+        // i386, one section, the optional header size, executable image
+        for half in [0x14cu16, 1, 0, 0, 0, 0, 0, 0, 0xe0, 0x102] {
+            pe.extend(half.to_le_bytes());
+        }
+        pe.extend(0x10bu16.to_le_bytes());
+        pe.resize(pe.len() + 26, 0);
+        // image base, alignments, versions, image and header sizes
+        for word in [0x400000u32, 0x1000, 0x200, 0, 0, 0x4, 0, 0x2000, 0x200, 0] {
+            pe.extend(word.to_le_bytes());
+        }
+        pe.extend(3u16.to_le_bytes()); // console
+        pe.resize(pe.len() + 22, 0);
+        pe.extend(16u32.to_le_bytes());
+        pe.resize(pe.len() + 8, 0);
+        pe.extend(0x1000u32.to_le_bytes());
+        pe.extend(idata.directory_size.to_le_bytes());
+        pe.resize(pe.len() + 14 * 8, 0);
+        pe.extend(b".idata\0\0");
+        for word in [idata.data.len() as u32, 0x1000, 0x200, 0x200, 0, 0, 0] {
+            pe.extend(word.to_le_bytes());
+        }
+        pe.extend(0xc000_0040u32.to_le_bytes());
+        pe.resize(0x200, 0);
+        pe.extend(&idata.data);
+        pe.resize(0x400, 0);
+        (pe, idata)
+    }
+}
@@ -6,8 +6,10 @@
 use alloc::vec::Vec;
 
 pub mod characteristic;
+pub mod classify;
 pub mod data_directories;
 pub mod debug;
+pub mod dotnet;
 pub mod exception;
 pub mod export;
 pub mod header;
@@ -15,9 +17,11 @@ pub mod import;
 pub mod optional_header;
 pub mod options;
 pub mod relocation;
+pub mod resource;
 pub mod section_table;
 pub mod symbol;
 pub mod utils;
+pub mod writer;
 
 use crate::container;
 use crate::error;
@@ -63,6 +67,12 @@ pub struct PE<'a> {
 }
 
 impl<'a> PE<'a> {
+    /// The CodeView PDB GUID of this binary, if it has one; this is what identifies the module to a symbol server
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.debug_data
+            .as_ref()
+            .and_then(|debug_data| debug_data.guid())
+    }
     /// Reads a PE binary from the underlying `bytes`
     pub fn parse(bytes: &'a [u8]) -> error::Result<Self> {
         Self::parse_with_opts(bytes, &options::ParseOptions::default())
@@ -100,24 +110,27 @@ impl<'a> PE<'a> {
             );
             let file_alignment = optional_header.windows_fields.file_alignment;
             if let Some(export_table) = *optional_header.data_directories.get_export_table() {
-                if let Ok(ed) = export::ExportData::parse_with_opts(
+                match export::ExportData::parse_with_opts(
                     bytes,
                     export_table,
                     &sections,
                     file_alignment,
                     opts,
                 ) {
-                    debug!("export data {:#?}", ed);
-                    exports = export::Export::parse_with_opts(
-                        bytes,
-                        &ed,
-                        &sections,
-                        file_alignment,
-                        opts,
-                    )?;
-                    name = ed.name;
-                    debug!("name: {:#?}", name);
-                    export_data = Some(ed);
+                    Ok(ed) => {
+                        debug!("export data {:#?}", ed);
+                        exports = export::Export::parse_with_opts(
+                            bytes,
+                            &ed,
+                            &sections,
+                            file_alignment,
+                            opts,
+                        )?;
+                        name = ed.name;
+                        debug!("name: {:#?}", name);
+                        export_data = Some(ed);
+                    }
+                    Err(e) => anomaly!("Skipping the export directory: {}", e),
                 }
             }
             debug!("exports: {:#?}", exports);
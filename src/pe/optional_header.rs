@@ -92,6 +92,25 @@ pub const MAGIC_32: u16 = 0x10b;
 /// Standard fields magic number for 64-bit binary
 pub const MAGIC_64: u16 = 0x20b;
 
+pub const IMAGE_SUBSYSTEM_UNKNOWN: u16 = 0;
+/// Drivers and native NT processes
+pub const IMAGE_SUBSYSTEM_NATIVE: u16 = 1;
+pub const IMAGE_SUBSYSTEM_WINDOWS_GUI: u16 = 2;
+pub const IMAGE_SUBSYSTEM_WINDOWS_CUI: u16 = 3;
+pub const IMAGE_SUBSYSTEM_OS2_CUI: u16 = 5;
+pub const IMAGE_SUBSYSTEM_POSIX_CUI: u16 = 7;
+pub const IMAGE_SUBSYSTEM_NATIVE_WINDOWS: u16 = 8;
+pub const IMAGE_SUBSYSTEM_WINDOWS_CE_GUI: u16 = 9;
+pub const IMAGE_SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+pub const IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER: u16 = 11;
+pub const IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER: u16 = 12;
+pub const IMAGE_SUBSYSTEM_EFI_ROM: u16 = 13;
+pub const IMAGE_SUBSYSTEM_XBOX: u16 = 14;
+pub const IMAGE_SUBSYSTEM_WINDOWS_BOOT_APPLICATION: u16 = 16;
+
+/// The image is a WDM driver
+pub const IMAGE_DLLCHARACTERISTICS_WDM_DRIVER: u16 = 0x2000;
+
 /// Windows specific fields
 #[repr(C)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Pread, Pwrite, SizeWith)]
@@ -0,0 +1,746 @@
+//! The resource tree of a PE binary (`.rsrc`): parsing it, editing the resources, and building a
+//! new resource section, which [`PEWriter`](super::writer::PEWriter) writes back into the binary.
+//!
+//! The tree has the usual three levels: the resource type, the resource name and the language.
+//! Editing helpers cover the common cases of replacing the version info, swapping the icons and
+//! adding a manifest.
+
+use crate::error;
+use crate::pe::{options, utils, PE};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use scroll::{Pread, Pwrite};
+
+pub const RT_CURSOR: u16 = 1;
+pub const RT_BITMAP: u16 = 2;
+pub const RT_ICON: u16 = 3;
+pub const RT_MENU: u16 = 4;
+pub const RT_DIALOG: u16 = 5;
+pub const RT_STRING: u16 = 6;
+pub const RT_FONTDIR: u16 = 7;
+pub const RT_FONT: u16 = 8;
+pub const RT_ACCELERATOR: u16 = 9;
+pub const RT_RCDATA: u16 = 10;
+pub const RT_MESSAGETABLE: u16 = 11;
+pub const RT_GROUP_CURSOR: u16 = 12;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+pub const RT_MANIFEST: u16 = 24;
+
+pub const LANG_NEUTRAL: u16 = 0;
+pub const LANG_EN_US: u16 = 0x409;
+/// The code page the Windows tools record for resources
+pub const CODEPAGE_UNICODE: u16 = 1200;
+
+/// The manifest resource id the loader uses for executables
+pub const CREATEPROCESS_MANIFEST_RESOURCE_ID: u16 = 1;
+/// The manifest resource id the loader uses for DLLs
+pub const ISOLATIONAWARE_MANIFEST_RESOURCE_ID: u16 = 2;
+
+const SIZEOF_DIRECTORY: usize = 16;
+const SIZEOF_DIRECTORY_ENTRY: usize = 8;
+const SIZEOF_DATA_ENTRY: usize = 16;
+const HIGH_BIT: u32 = 0x8000_0000;
+const VS_FFI_SIGNATURE: u32 = 0xfeef_04bd;
+
+/// The type, name or language of a resource. Named entries sort before numbered ones, which is
+/// the order the directory tables keep them in.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResourceId {
+    Name(String),
+    Id(u16),
+}
+
+impl From<u16> for ResourceId {
+    fn from(id: u16) -> Self {
+        ResourceId::Id(id)
+    }
+}
+
+impl From<&str> for ResourceId {
+    fn from(name: &str) -> Self {
+        ResourceId::Name(name.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub data: Vec<u8>,
+    pub codepage: u32,
+}
+
+/// The resources of a binary, keyed by type, name and language
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceTree {
+    resources: BTreeMap<(ResourceId, ResourceId, u16), Resource>,
+}
+
+impl ResourceTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the resource tree of `pe`, which was parsed from `bytes`. A binary without resources
+    /// has an empty tree.
+    pub fn parse(bytes: &[u8], pe: &PE) -> error::Result<Self> {
+        let optional_header = match pe.header.optional_header {
+            Some(optional_header) => optional_header,
+            None => return Ok(Self::new()),
+        };
+        let directory = match *optional_header.data_directories.get_resource_table() {
+            Some(directory) => directory,
+            None => return Ok(Self::new()),
+        };
+        let file_alignment = optional_header.windows_fields.file_alignment;
+        let opts = options::ParseOptions::default();
+        let offset =
+            |rva: u32| utils::find_offset(rva as usize, &pe.sections, file_alignment, &opts);
+        let base = offset(directory.virtual_address).ok_or_else(|| {
+            error::Error::Malformed(format!(
+                "Resource directory at {:#x} is not in any section",
+                directory.virtual_address
+            ))
+        })?;
+        let mut tree = Self::new();
+        tree.parse_directory(bytes, base, 0, &mut Vec::new(), &|rva, size| {
+            offset(rva).and_then(|offset| bytes.get(offset..offset + size))
+        })?;
+        Ok(tree)
+    }
+
+    /// Read a resource section on its own, as built by [`ResourceTree::build`] for `rva`
+    pub fn parse_section(data: &[u8], rva: u32) -> error::Result<Self> {
+        let mut tree = Self::new();
+        tree.parse_directory(data, 0, 0, &mut Vec::new(), &|at, size| {
+            let offset = at.checked_sub(rva)? as usize;
+            data.get(offset..offset + size)
+        })?;
+        Ok(tree)
+    }
+
+    fn parse_directory<'b>(
+        &mut self,
+        bytes: &'b [u8],
+        base: usize,
+        offset: usize,
+        path: &mut Vec<ResourceId>,
+        data_at: &dyn Fn(u32, usize) -> Option<&'b [u8]>,
+    ) -> error::Result<()> {
+        let mut at = base + offset + 12;
+        let named: u16 = bytes.gread_with(&mut at, scroll::LE)?;
+        let ids: u16 = bytes.gread_with(&mut at, scroll::LE)?;
+        for _ in 0..usize::from(named) + usize::from(ids) {
+            let name: u32 = bytes.gread_with(&mut at, scroll::LE)?;
+            let target: u32 = bytes.gread_with(&mut at, scroll::LE)?;
+            let id = if name & HIGH_BIT != 0 {
+                let mut offset = base + (name & !HIGH_BIT) as usize;
+                let len: u16 = bytes.gread_with(&mut offset, scroll::LE)?;
+                let mut units = Vec::with_capacity(usize::from(len));
+                for _ in 0..len {
+                    units.push(bytes.gread_with::<u16>(&mut offset, scroll::LE)?);
+                }
+                ResourceId::Name(String::from_utf16_lossy(&units))
+            } else {
+                ResourceId::Id(name as u16)
+            };
+            match (target & HIGH_BIT != 0, path.len()) {
+                (true, depth) if depth < 2 => {
+                    path.push(id);
+                    self.parse_directory(
+                        bytes,
+                        base,
+                        (target & !HIGH_BIT) as usize,
+                        path,
+                        data_at,
+                    )?;
+                    path.pop();
+                }
+                (false, 2) => {
+                    let mut offset = base + target as usize;
+                    let rva: u32 = bytes.gread_with(&mut offset, scroll::LE)?;
+                    let size: u32 = bytes.gread_with(&mut offset, scroll::LE)?;
+                    let codepage: u32 = bytes.gread_with(&mut offset, scroll::LE)?;
+                    let data = data_at(rva, size as usize).ok_or_else(|| {
+                        error::Error::Malformed(format!(
+                            "Resource data at {:#x} ({:#x} bytes) is not in the file",
+                            rva, size
+                        ))
+                    })?;
+                    let lang = match id {
+                        ResourceId::Id(lang) => lang,
+                        ResourceId::Name(_) => LANG_NEUTRAL,
+                    };
+                    self.resources.insert(
+                        (path[0].clone(), path[1].clone(), lang),
+                        Resource {
+                            data: data.to_vec(),
+                            codepage,
+                        },
+                    );
+                }
+                _ => {
+                    return Err(error::Error::Malformed(
+                        "Resource tree is not three levels deep".into(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.resources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    /// Every resource as `(type, name, language, resource)`, in directory order
+    pub fn iter(&self) -> impl Iterator<Item = (&ResourceId, &ResourceId, u16, &Resource)> {
+        self.resources
+            .iter()
+            .map(|((typ, name, lang), resource)| (typ, name, *lang, resource))
+    }
+
+    pub fn get(&self, typ: &ResourceId, name: &ResourceId, lang: u16) -> Option<&Resource> {
+        self.resources.get(&(typ.clone(), name.clone(), lang))
+    }
+
+    /// The first resource of type `typ`, in any language
+    pub fn first(&self, typ: &ResourceId) -> Option<(&ResourceId, u16, &Resource)> {
+        self.iter()
+            .find(|(t, _, _, _)| *t == typ)
+            .map(|(_, name, lang, resource)| (name, lang, resource))
+    }
+
+    /// Add or replace a resource, returning the one it replaced
+    pub fn insert(
+        &mut self,
+        typ: ResourceId,
+        name: ResourceId,
+        lang: u16,
+        resource: Resource,
+    ) -> Option<Resource> {
+        self.resources.insert((typ, name, lang), resource)
+    }
+
+    pub fn remove(&mut self, typ: &ResourceId, name: &ResourceId, lang: u16) -> Option<Resource> {
+        self.resources.remove(&(typ.clone(), name.clone(), lang))
+    }
+
+    /// Remove the resource `name` of type `typ` in every language, returning how many were removed
+    pub fn remove_name(&mut self, typ: &ResourceId, name: &ResourceId) -> usize {
+        let before = self.resources.len();
+        self.resources.retain(|(t, n, _), _| t != typ || n != name);
+        before - self.resources.len()
+    }
+
+    /// Remove every resource of type `typ`, returning how many were removed
+    pub fn remove_type(&mut self, typ: &ResourceId) -> usize {
+        let before = self.resources.len();
+        self.resources.retain(|(t, _, _), _| t != typ);
+        before - self.resources.len()
+    }
+
+    /// Replace the version info with `version`, which keeps the language of the version info it
+    /// replaces if it doesn't name one
+    pub fn set_version_info(&mut self, version: &VersionInfo) {
+        let typ = ResourceId::Id(RT_VERSION);
+        let lang = self.first(&typ).map(|(_, lang, _)| lang);
+        self.remove_type(&typ);
+        let lang = match (version.language, lang) {
+            (0, Some(lang)) => lang,
+            (0, None) => LANG_EN_US,
+            (language, _) => language,
+        };
+        self.insert(
+            typ,
+            ResourceId::Id(1),
+            lang,
+            Resource {
+                data: version.to_bytes(),
+                codepage: 0,
+            },
+        );
+    }
+
+    /// Replace the manifest with the XML `manifest`, keeping the id and language of the manifest
+    /// it replaces. A binary without one gets it under `CREATEPROCESS_MANIFEST_RESOURCE_ID`.
+    pub fn set_manifest(&mut self, manifest: &str) {
+        let typ = ResourceId::Id(RT_MANIFEST);
+        let (name, lang) = match self.first(&typ) {
+            Some((name, lang, _)) => (name.clone(), lang),
+            None => (
+                ResourceId::Id(CREATEPROCESS_MANIFEST_RESOURCE_ID),
+                LANG_EN_US,
+            ),
+        };
+        self.remove_type(&typ);
+        self.insert(
+            typ,
+            name,
+            lang,
+            Resource {
+                data: manifest.as_bytes().to_vec(),
+                codepage: 0,
+            },
+        );
+    }
+
+    /// Replace the icon group `group` with the images of the `.ico` file `ico`. The icons of the
+    /// old group are removed and the new images get fresh `RT_ICON` ids.
+    pub fn set_icon(&mut self, group: ResourceId, ico: &[u8]) -> error::Result<()> {
+        let images = parse_ico(ico)?;
+        let group_type = ResourceId::Id(RT_GROUP_ICON);
+        let icon_type = ResourceId::Id(RT_ICON);
+        let mut lang = None;
+        let mut stale = Vec::new();
+        for (typ, name, l, resource) in self.iter() {
+            if *typ == group_type && *name == group {
+                lang.get_or_insert(l);
+                stale.extend(group_icon_ids(&resource.data));
+            }
+        }
+        let lang = lang.unwrap_or(LANG_EN_US);
+        for id in stale {
+            self.remove_name(&icon_type, &ResourceId::Id(id));
+        }
+        self.remove_name(&group_type, &group);
+
+        let mut next = self
+            .iter()
+            .filter_map(|(typ, name, _, _)| match (typ, name) {
+                (t, ResourceId::Id(id)) if *t == icon_type => Some(*id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mut directory = vec![0u8; 6 + 14 * images.len()];
+        directory.pwrite_with(1u16, 2, scroll::LE)?;
+        directory.pwrite_with(images.len() as u16, 4, scroll::LE)?;
+        for (i, (entry, image)) in images.into_iter().enumerate() {
+            next = next
+                .checked_add(1)
+                .ok_or_else(|| error::Error::Malformed("No free icon resource ids left".into()))?;
+            let at = 6 + 14 * i;
+            directory[at..at + 12].copy_from_slice(&entry[..12]);
+            directory.pwrite_with(next, at + 12, scroll::LE)?;
+            self.insert(
+                icon_type.clone(),
+                ResourceId::Id(next),
+                lang,
+                Resource {
+                    data: image.to_vec(),
+                    codepage: 0,
+                },
+            );
+        }
+        self.insert(
+            group_type,
+            group,
+            lang,
+            Resource {
+                data: directory,
+                codepage: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Serialize the tree as a resource section which will be loaded at `rva`: the directory
+    /// tables, the data entries, the names and then the resource data.
+    pub fn build(&self, rva: u32) -> Vec<u8> {
+        let mut types: BTreeMap<&ResourceId, Names> = BTreeMap::new();
+        for ((typ, name, lang), resource) in self.resources.iter() {
+            types
+                .entry(typ)
+                .or_default()
+                .entry(name)
+                .or_default()
+                .push((*lang, resource));
+        }
+        let directory_size = |entries: usize| SIZEOF_DIRECTORY + SIZEOF_DIRECTORY_ENTRY * entries;
+        let mut tables = directory_size(types.len());
+        for names in types.values() {
+            tables += directory_size(names.len());
+            tables += names
+                .values()
+                .map(|langs| directory_size(langs.len()))
+                .sum::<usize>();
+        }
+        let data_entries = tables;
+        let mut strings_end = data_entries + SIZEOF_DATA_ENTRY * self.resources.len();
+        let mut strings = BTreeMap::new();
+        for id in types
+            .iter()
+            .flat_map(|(typ, names)| core::iter::once(*typ).chain(names.keys().copied()))
+        {
+            if let ResourceId::Name(name) = id {
+                strings.entry(name.as_str()).or_insert_with(|| {
+                    let at = strings_end;
+                    strings_end += 2 + 2 * name.encode_utf16().count();
+                    at
+                });
+            }
+        }
+        let data_start = strings_end.next_multiple_of(8);
+        let data_size = self
+            .resources
+            .values()
+            .map(|resource| resource.data.len().next_multiple_of(8))
+            .sum::<usize>();
+        let mut out = vec![0u8; data_start + data_size];
+
+        for (name, at) in strings.iter() {
+            let mut at = *at;
+            let units = name.encode_utf16().collect::<Vec<_>>();
+            out.gwrite_with(units.len() as u16, &mut at, scroll::LE)
+                .unwrap();
+            for unit in units {
+                out.gwrite_with(unit, &mut at, scroll::LE).unwrap();
+            }
+        }
+        let entry_name = |id: &ResourceId| match id {
+            ResourceId::Name(name) => HIGH_BIT | strings[name.as_str()] as u32,
+            ResourceId::Id(id) => u32::from(*id),
+        };
+
+        let mut next_directory = 0;
+        let mut next_data_entry = data_entries;
+        let mut next_data = data_start;
+        let root = write_directory(&mut out, &mut next_directory, types.keys().copied());
+        for (i, (typ, names)) in types.iter().enumerate() {
+            let directory = write_directory(&mut out, &mut next_directory, names.keys().copied());
+            write_entry(
+                &mut out,
+                root,
+                i,
+                entry_name(typ),
+                HIGH_BIT | directory as u32,
+            );
+            for (j, (name, langs)) in names.iter().enumerate() {
+                let ids = langs
+                    .iter()
+                    .map(|(lang, _)| ResourceId::Id(*lang))
+                    .collect::<Vec<_>>();
+                let languages = write_directory(&mut out, &mut next_directory, ids.iter());
+                write_entry(
+                    &mut out,
+                    directory,
+                    j,
+                    entry_name(name),
+                    HIGH_BIT | languages as u32,
+                );
+                for (k, (lang, resource)) in langs.iter().enumerate() {
+                    write_entry(
+                        &mut out,
+                        languages,
+                        k,
+                        u32::from(*lang),
+                        next_data_entry as u32,
+                    );
+                    let at = &mut next_data_entry;
+                    out.gwrite_with(rva + next_data as u32, at, scroll::LE)
+                        .unwrap();
+                    out.gwrite_with(resource.data.len() as u32, at, scroll::LE)
+                        .unwrap();
+                    out.gwrite_with(resource.codepage, at, scroll::LE).unwrap();
+                    *at += 4;
+                    out[next_data..next_data + resource.data.len()].copy_from_slice(&resource.data);
+                    next_data += resource.data.len().next_multiple_of(8);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The names of one resource type and the languages of each name
+type Names<'b> = BTreeMap<&'b ResourceId, Vec<(u16, &'b Resource)>>;
+
+/// Write the header of a directory with the entries `ids` at `next`, returning its offset
+fn write_directory<'b>(
+    out: &mut [u8],
+    next: &mut usize,
+    ids: impl Iterator<Item = &'b ResourceId>,
+) -> usize {
+    let (mut named, mut numbered) = (0u16, 0u16);
+    for id in ids {
+        match id {
+            ResourceId::Name(_) => named += 1,
+            ResourceId::Id(_) => numbered += 1,
+        }
+    }
+    let at = *next;
+    out.pwrite_with(named, at + 12, scroll::LE).unwrap();
+    out.pwrite_with(numbered, at + 14, scroll::LE).unwrap();
+    *next += SIZEOF_DIRECTORY + SIZEOF_DIRECTORY_ENTRY * usize::from(named + numbered);
+    at
+}
+
+fn write_entry(out: &mut [u8], directory: usize, index: usize, name: u32, target: u32) {
+    let at = directory + SIZEOF_DIRECTORY + SIZEOF_DIRECTORY_ENTRY * index;
+    out.pwrite_with(name, at, scroll::LE).unwrap();
+    out.pwrite_with(target, at + 4, scroll::LE).unwrap();
+}
+
+/// The entries and images of an `.ico` file. An entry is the 16 byte `ICONDIRENTRY`, whose first
+/// 12 bytes are the same in the `GRPICONDIRENTRY` of an icon group.
+fn parse_ico(ico: &[u8]) -> error::Result<Vec<([u8; 16], &[u8])>> {
+    let typ: u16 = ico.pread_with(2, scroll::LE)?;
+    let count: u16 = ico.pread_with(4, scroll::LE)?;
+    if typ != 1 || count == 0 {
+        return Err(error::Error::Malformed("Not an icon file".into()));
+    }
+    let mut images = Vec::with_capacity(usize::from(count));
+    for i in 0..usize::from(count) {
+        let at = 6 + 16 * i;
+        let entry: [u8; 16] = ico
+            .get(at..at + 16)
+            .and_then(|entry| entry.try_into().ok())
+            .ok_or_else(|| error::Error::Malformed("Icon directory is truncated".into()))?;
+        let size: u32 = entry.pread_with(8, scroll::LE)?;
+        let offset: u32 = entry.pread_with(12, scroll::LE)?;
+        let image = ico
+            .get(offset as usize..offset as usize + size as usize)
+            .ok_or_else(|| error::Error::Malformed(format!("Icon image {} is truncated", i)))?;
+        images.push((entry, image));
+    }
+    Ok(images)
+}
+
+/// The `RT_ICON` ids an icon group refers to
+fn group_icon_ids(group: &[u8]) -> Vec<u16> {
+    let count = group.pread_with::<u16>(4, scroll::LE).unwrap_or(0);
+    (0..usize::from(count))
+        .map_while(|i| group.pread_with::<u16>(6 + 14 * i + 12, scroll::LE).ok())
+        .collect()
+}
+
+/// The contents of a `VS_VERSIONINFO` resource
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// `major.minor.build.revision`
+    pub file_version: [u16; 4],
+    pub product_version: [u16; 4],
+    /// `VS_FF_*` flags
+    pub file_flags: u32,
+    /// `VOS_*`, `VOS_NT_WINDOWS32` (0x40004) for current binaries
+    pub file_os: u32,
+    /// `VFT_APP` (1) or `VFT_DLL` (2)
+    pub file_type: u32,
+    /// The language of the strings and of the resource; 0 keeps the language of the version
+    /// info being replaced
+    pub language: u16,
+    pub codepage: u16,
+    /// `CompanyName`, `FileDescription`, `ProductName` and so on
+    pub strings: Vec<(String, String)>,
+}
+
+impl VersionInfo {
+    pub fn new(file_version: [u16; 4]) -> Self {
+        VersionInfo {
+            file_version,
+            product_version: file_version,
+            file_os: 0x40004,
+            file_type: 1,
+            codepage: CODEPAGE_UNICODE,
+            ..Default::default()
+        }
+    }
+
+    /// Add or replace the string `key`
+    pub fn set_string(&mut self, key: &str, value: &str) {
+        match self.strings.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.strings.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Serialize as a `VS_VERSIONINFO` structure
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let version = |v: [u16; 4]| {
+            (
+                u32::from(v[0]) << 16 | u32::from(v[1]),
+                u32::from(v[2]) << 16 | u32::from(v[3]),
+            )
+        };
+        let (file_ms, file_ls) = version(self.file_version);
+        let (product_ms, product_ls) = version(self.product_version);
+        let mut fixed = Vec::with_capacity(52);
+        for value in [
+            VS_FFI_SIGNATURE,
+            0x0001_0000,
+            file_ms,
+            file_ls,
+            product_ms,
+            product_ls,
+            0x3f,
+            self.file_flags,
+            self.file_os,
+            self.file_type,
+            0,
+            0,
+            0,
+        ] {
+            fixed.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let language = if self.language == 0 {
+            LANG_EN_US
+        } else {
+            self.language
+        };
+        let strings = self
+            .strings
+            .iter()
+            .map(|(key, value)| {
+                let value = utf16z(value);
+                version_block(key, 1, &value, (value.len() / 2) as u16, &[])
+            })
+            .collect::<Vec<_>>();
+        let table = version_block(
+            &format!("{:04x}{:04x}", language, self.codepage),
+            1,
+            &[],
+            0,
+            &strings,
+        );
+        let string_file_info = version_block("StringFileInfo", 1, &[], 0, &[table]);
+        let mut translation = language.to_le_bytes().to_vec();
+        translation.extend_from_slice(&self.codepage.to_le_bytes());
+        let var = version_block("Translation", 0, &translation, 4, &[]);
+        let var_file_info = version_block("VarFileInfo", 1, &[], 0, &[var]);
+        version_block(
+            "VS_VERSION_INFO",
+            0,
+            &fixed,
+            52,
+            &[string_file_info, var_file_info],
+        )
+    }
+}
+
+/// `text` as NUL terminated UTF-16LE
+fn utf16z(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(core::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// A version info block: `wLength`, `wValueLength`, `wType`, the key, the value and the children,
+/// each starting on a 4 byte boundary
+fn version_block(
+    key: &str,
+    typ: u16,
+    value: &[u8],
+    value_len: u16,
+    children: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut block = vec![0u8; 6];
+    block.extend_from_slice(&utf16z(key));
+    if !value.is_empty() {
+        block.resize(block.len().next_multiple_of(4), 0);
+        block.extend_from_slice(value);
+    }
+    for child in children {
+        block.resize(block.len().next_multiple_of(4), 0);
+        block.extend_from_slice(child);
+    }
+    let len = block.len() as u16;
+    block[0..2].copy_from_slice(&len.to_le_bytes());
+    block[2..4].copy_from_slice(&value_len.to_le_bytes());
+    block[4..6].copy_from_slice(&typ.to_le_bytes());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `.ico` with two (bogus) images
+    fn ico() -> Vec<u8> {
+        let mut ico = vec![0, 0, 1, 0, 2, 0];
+        for (i, size) in [(0u32, 4u32), (1, 6)] {
+            ico.extend_from_slice(&[16 << i, 16 << i, 0, 0, 1, 0, 32, 0]);
+            ico.extend_from_slice(&size.to_le_bytes());
+            ico.extend_from_slice(&(38 + 4 * i).to_le_bytes());
+        }
+        ico.extend_from_slice(b"img0img1..");
+        ico
+    }
+
+    #[test]
+    fn build_and_parse() {
+        let mut tree = ResourceTree::new();
+        let data = |data: &[u8]| Resource {
+            data: data.to_vec(),
+            codepage: 0,
+        };
+        tree.insert(RT_RCDATA.into(), "CONFIG".into(), LANG_EN_US, data(b"abc"));
+        tree.insert(RT_RCDATA.into(), 7.into(), LANG_EN_US, data(b"seven"));
+        tree.insert("PAYLOAD".into(), "CONFIG".into(), 0x407, data(b"x"));
+        tree.insert("PAYLOAD".into(), "CONFIG".into(), LANG_NEUTRAL, data(b""));
+        tree.set_manifest("<assembly/>");
+        tree.set_icon(1.into(), &ico()).unwrap();
+
+        let section = tree.build(0x5000);
+        assert_eq!(section.len() % 8, 0);
+        // named entries precede numbered ones
+        assert_eq!(section.pread_with::<u16>(12, scroll::LE).unwrap(), 1);
+        assert_eq!(section.pread_with::<u16>(14, scroll::LE).unwrap(), 4);
+        let parsed = ResourceTree::parse_section(&section, 0x5000).unwrap();
+        assert_eq!(parsed, tree);
+
+        let group = tree
+            .get(&RT_GROUP_ICON.into(), &1.into(), LANG_EN_US)
+            .unwrap();
+        assert_eq!(group_icon_ids(&group.data), vec![1, 2]);
+        assert_eq!(
+            tree.get(&RT_ICON.into(), &2.into(), LANG_EN_US)
+                .unwrap()
+                .data,
+            b"img1.."
+        );
+        // swapping the icons drops the images of the old group
+        tree.set_icon(1.into(), &ico()).unwrap();
+        let icons = tree
+            .iter()
+            .filter(|(typ, _, _, _)| **typ == ResourceId::Id(RT_ICON))
+            .map(|(_, name, _, _)| name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(icons, vec![ResourceId::Id(1), ResourceId::Id(2)]);
+    }
+
+    #[test]
+    fn version_info() {
+        let mut version = VersionInfo::new([1, 2, 3, 4]);
+        version.set_string("ProductName", "vivisect");
+        let bytes = version.to_bytes();
+        assert_eq!(
+            bytes.pread_with::<u16>(0, scroll::LE).unwrap() as usize,
+            bytes.len()
+        );
+        assert_eq!(bytes.pread_with::<u16>(2, scroll::LE).unwrap(), 52);
+        // the fixed file info follows the padded "VS_VERSION_INFO" key
+        assert_eq!(
+            bytes.pread_with::<u32>(40, scroll::LE).unwrap(),
+            VS_FFI_SIGNATURE
+        );
+        assert_eq!(
+            bytes.pread_with::<u32>(48, scroll::LE).unwrap(),
+            0x0001_0002
+        );
+        assert_eq!(
+            bytes.pread_with::<u32>(52, scroll::LE).unwrap(),
+            0x0003_0004
+        );
+        let key = utf16z("040904b0");
+        assert!(bytes.windows(key.len()).any(|w| w == key.as_slice()));
+    }
+}
@@ -0,0 +1,766 @@
+//! Rewriting of PE binaries, e.g. to replace the resources of an executable or to make it import
+//! more functions.
+//!
+//! A rebuilt table goes into a section of its own. If the section the table used to live in is
+//! the last one of the image it is rewritten in place, otherwise a new section is appended to
+//! the image and the old contents stay behind unreferenced. The data directories, the section
+//! table, `SizeOfImage` and the checksum (if the binary had one) are updated to match. Data
+//! appended after the last section (the overlay, a zip archive, the Authenticode signature) is
+//! kept at the end of the file unless [`PEWriter::set_appended_data`] strips it. Rewriting a
+//! signed binary invalidates its signature.
+//!
+//! The code of an image calls its imports through the slots of the import address tables, so a
+//! rebuilt import table keeps the IAT of every DLL whose original imports all remain, and
+//! [`PEWriter::import_address`] tells where the slot of a new import ended up.
+//!
+//! Transforms registered for a section run on its final contents. Every section of an image is
+//! mapped, so a transform may shrink a section but not grow it.
+
+use crate::error;
+use crate::overlay::{self, AppendedData, Artifact, ArtifactKind};
+use crate::pe::{
+    header::{SIZEOF_COFF_HEADER, SIZEOF_PE_MAGIC},
+    import::{ImportTable, ImportedFunction},
+    optional_header::MAGIC_64,
+    resource::ResourceTree,
+    section_table::{
+        SectionTable, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE,
+        SIZEOF_SECTION_TABLE,
+    },
+    PE,
+};
+use crate::transform::{SectionRef, SectionTransform, SectionTransforms};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use scroll::{Pread, Pwrite};
+
+/// The data directory indices the writer updates
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+pub const IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT: usize = 11;
+
+/// Edits the tables of a PE image and emits the rewritten file
+pub struct PEWriter<'a> {
+    bytes: &'a [u8],
+    pe: PE<'a>,
+    resources: Option<ResourceTree>,
+    imports: Option<ImportTable>,
+    /// The IAT slots of the imports of the last built binary
+    import_slots: Vec<(String, ImportedFunction, u32)>,
+    transforms: SectionTransforms,
+    appended: AppendedData,
+}
+
+impl<'a> PEWriter<'a> {
+    pub fn new(bytes: &'a [u8]) -> error::Result<Self> {
+        let pe = PE::parse(bytes)?;
+        let windows_fields = match pe.header.optional_header {
+            Some(optional_header) => optional_header.windows_fields,
+            None => {
+                return Err(error::Error::Malformed(
+                    "Can't rewrite a PE without an optional header".into(),
+                ))
+            }
+        };
+        if windows_fields.section_alignment == 0 || windows_fields.file_alignment == 0 {
+            return Err(error::Error::Malformed(format!(
+                "Can't rewrite a PE aligning sections to {:#x} and file data to {:#x}",
+                windows_fields.section_alignment, windows_fields.file_alignment
+            )));
+        }
+        Ok(PEWriter {
+            bytes,
+            pe,
+            resources: None,
+            imports: None,
+            import_slots: Vec::new(),
+            transforms: SectionTransforms::new(),
+            appended: AppendedData::default(),
+        })
+    }
+
+    /// The binary as it was parsed, before any edits
+    pub fn pe(&self) -> &PE<'a> {
+        &self.pe
+    }
+
+    /// The resources which will be written, read from the binary on first use. The resource
+    /// section is only rebuilt if this (or [`PEWriter::set_resources`]) was called.
+    pub fn resources(&mut self) -> error::Result<&mut ResourceTree> {
+        if self.resources.is_none() {
+            self.resources = Some(ResourceTree::parse(self.bytes, &self.pe)?);
+        }
+        Ok(self.resources.as_mut().unwrap())
+    }
+
+    pub fn set_resources(&mut self, resources: ResourceTree) {
+        self.resources = Some(resources);
+    }
+
+    /// The imports which will be written, read from the binary on first use. The import table
+    /// is only rebuilt if this (or [`PEWriter::set_imports`]) was called.
+    pub fn imports(&mut self) -> error::Result<&mut ImportTable> {
+        if self.imports.is_none() {
+            self.imports = Some(match self.pe.import_data {
+                Some(ref import_data) => ImportTable::from_import_data(import_data)?,
+                None => ImportTable::new(),
+            });
+        }
+        Ok(self.imports.as_mut().unwrap())
+    }
+
+    pub fn set_imports(&mut self, imports: ImportTable) {
+        self.imports = Some(imports);
+    }
+
+    /// The RVA of the IAT slot the loader stores the address of `function` (a name or an
+    /// ordinal) imported from `dll` in, in the binary built last. This is where code calling a
+    /// newly added import has to call through.
+    pub fn import_address(&self, dll: &str, function: &str) -> Option<u32> {
+        self.import_slots
+            .iter()
+            .find(|(d, f, _)| d.eq_ignore_ascii_case(dll) && f.matches(function))
+            .map(|(_, _, slot)| *slot)
+    }
+
+    /// The data appended after the last section of the binary, in file order
+    pub fn appended(&self) -> Vec<Artifact> {
+        overlay::artifacts(
+            self.bytes,
+            overlay_start(self.bytes, &self.pe),
+            certificate_table(&self.pe),
+        )
+    }
+
+    /// Choose which kinds of appended data are kept in the built binary
+    pub fn set_appended_data(&mut self, appended: AppendedData) {
+        self.appended = appended;
+    }
+
+    /// Run `transform` on the contents of the section called `section` when the binary is built
+    pub fn add_section_transform<T: SectionTransform + 'static>(
+        &mut self,
+        section: &str,
+        transform: T,
+    ) {
+        self.transforms.register(section, transform);
+    }
+
+    /// Emit the binary with the edited tables
+    pub fn build(&mut self) -> error::Result<Vec<u8>> {
+        let mut image = Image::new(self.bytes, &self.pe)?;
+        let artifacts = self.appended();
+        if let Some(ref resources) = self.resources {
+            if resources.is_empty() {
+                image.set_data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE, 0, 0)?;
+            } else {
+                let (rva, size) = image.place_section(
+                    ".rsrc",
+                    IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+                    Some(IMAGE_DIRECTORY_ENTRY_RESOURCE),
+                    |rva| resources.build(rva),
+                )?;
+                image.set_data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE, rva, size)?;
+            }
+        }
+        if let Some(ref imports) = self.imports {
+            self.import_slots.clear();
+            if imports.is_empty() {
+                image.set_data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT, 0, 0)?;
+            } else {
+                // the kept IATs may hold bound addresses, which are resolved again without the
+                // bound import directory
+                let is_64 = self.pe.is_64;
+                let mut built = None;
+                let (rva, _) = image.place_section(
+                    ".idata",
+                    IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+                    None,
+                    |rva| {
+                        let table = imports.build(rva, is_64);
+                        let data = table.data.clone();
+                        built = Some(table);
+                        data
+                    },
+                )?;
+                let built = built.unwrap();
+                image.set_data_directory(
+                    IMAGE_DIRECTORY_ENTRY_IMPORT,
+                    rva,
+                    built.directory_size,
+                )?;
+                self.import_slots = built.slots;
+            }
+            image.set_data_directory(IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT, 0, 0)?;
+        }
+        image.transform_sections(&mut self.transforms)?;
+        image.finish(&artifacts, &self.appended)
+    }
+}
+
+/// Where the data appended after the last section starts
+fn overlay_start(bytes: &[u8], pe: &PE) -> usize {
+    let size_of_headers = pe
+        .header
+        .optional_header
+        .map_or(0, |header| header.windows_fields.size_of_headers);
+    pe.sections
+        .iter()
+        .filter(|section| section.size_of_raw_data != 0)
+        .map(|section| section.pointer_to_raw_data as usize + section.size_of_raw_data as usize)
+        .max()
+        .unwrap_or(size_of_headers as usize)
+        .min(bytes.len())
+}
+
+/// The file range of the certificate table, the one directory which holds a file offset
+/// rather than an RVA
+fn certificate_table(pe: &PE) -> Option<core::ops::Range<usize>> {
+    let directory = (*pe
+        .header
+        .optional_header?
+        .data_directories
+        .get_certificate_table())?;
+    let start = directory.virtual_address as usize;
+    Some(start..start + directory.size as usize)
+}
+
+/// The file being rebuilt: the headers and the section contents, followed by the appended data
+struct Image<'b> {
+    out: Vec<u8>,
+    bytes: &'b [u8],
+    sections: Vec<SectionTable>,
+    /// The offset of the optional header
+    optional_header: usize,
+    /// The offset of the section table
+    section_table: usize,
+    is_64: bool,
+    section_alignment: u32,
+    file_alignment: u32,
+    size_of_headers: u32,
+    check_sum: u32,
+}
+
+impl<'b> Image<'b> {
+    fn new(bytes: &'b [u8], pe: &PE) -> error::Result<Self> {
+        let optional_header = pe.header.optional_header.unwrap();
+        let windows_fields = optional_header.windows_fields;
+        let start = pe.header.dos_header.pe_pointer as usize + SIZEOF_PE_MAGIC;
+        Ok(Image {
+            out: bytes[..overlay_start(bytes, pe)].to_vec(),
+            bytes,
+            sections: pe.sections.clone(),
+            optional_header: start + SIZEOF_COFF_HEADER,
+            section_table: start
+                + SIZEOF_COFF_HEADER
+                + usize::from(pe.header.coff_header.size_of_optional_header),
+            is_64: optional_header.standard_fields.magic == MAGIC_64,
+            section_alignment: windows_fields.section_alignment,
+            file_alignment: windows_fields.file_alignment,
+            size_of_headers: windows_fields.size_of_headers,
+            check_sum: windows_fields.check_sum,
+        })
+    }
+
+    fn data_directory_offset(&self, index: usize) -> usize {
+        self.optional_header + if self.is_64 { 112 } else { 96 } + 8 * index
+    }
+
+    fn data_directory(&self, index: usize) -> error::Result<(u32, u32)> {
+        let offset = self.data_directory_offset(index);
+        Ok((
+            self.out.pread_with(offset, scroll::LE)?,
+            self.out.pread_with(offset + 4, scroll::LE)?,
+        ))
+    }
+
+    fn set_data_directory(&mut self, index: usize, rva: u32, size: u32) -> error::Result<()> {
+        let offset = self.data_directory_offset(index);
+        self.out.pwrite_with(rva, offset, scroll::LE)?;
+        self.out.pwrite_with(size, offset + 4, scroll::LE)?;
+        Ok(())
+    }
+
+    /// The RVA just past the last section, where a new section would be loaded
+    fn next_rva(&self) -> error::Result<u32> {
+        let mut end = None;
+        for section in self.sections.iter() {
+            let size = section.virtual_size.max(section.size_of_raw_data);
+            let section_end = section.virtual_address.checked_add(size).ok_or_else(|| {
+                error::Error::Malformed(format!(
+                    "Section {} at {:#x} runs past the end of the image",
+                    section.name().unwrap_or(""),
+                    section.virtual_address
+                ))
+            })?;
+            end = end.max(Some(section_end));
+        }
+        let end = end.unwrap_or(self.size_of_headers);
+        end.checked_next_multiple_of(self.section_alignment)
+            .ok_or_else(|| {
+                error::Error::Malformed(format!("No room for a section past {:#x}", end))
+            })
+    }
+
+    /// Build a table with `build`, which gets the RVA the table will be loaded at. The table
+    /// replaces the section the data directory `directory` currently points into if that is the
+    /// last section and is called `name`, otherwise it goes into a new section called `name`.
+    /// Returns the RVA and the size of the table.
+    fn place_section(
+        &mut self,
+        name: &str,
+        characteristics: u32,
+        directory: Option<usize>,
+        build: impl FnOnce(u32) -> Vec<u8>,
+    ) -> error::Result<(u32, u32)> {
+        let current = match directory {
+            Some(directory) => self.data_directory(directory)?.0,
+            None => 0,
+        };
+        let next_rva = self.next_rva()?;
+        let last = self.sections.len().checked_sub(1).filter(|&idx| {
+            let section = &self.sections[idx];
+            current != 0
+                && section.name().ok() == Some(name)
+                && (section.virtual_address..next_rva).contains(&current)
+                && section.pointer_to_raw_data as usize + section.size_of_raw_data as usize
+                    == self.out.len()
+        });
+        let rva = match last {
+            Some(idx) => self.sections[idx].virtual_address,
+            None => next_rva,
+        };
+        let data = build(rva);
+        let section = match last {
+            Some(idx) => {
+                let offset = self.sections[idx].pointer_to_raw_data as usize;
+                self.out.truncate(offset);
+                &mut self.sections[idx]
+            }
+            None => {
+                let table_end =
+                    self.section_table + SIZEOF_SECTION_TABLE * (self.sections.len() + 1);
+                let first_data = self
+                    .sections
+                    .iter()
+                    .filter(|section| section.size_of_raw_data != 0)
+                    .map(|section| section.pointer_to_raw_data)
+                    .min()
+                    .unwrap_or(u32::MAX)
+                    .min(self.size_of_headers);
+                if table_end > first_data as usize {
+                    return Err(error::Error::Malformed(format!(
+                        "No room for another section header, the headers end at {:#x}",
+                        first_data
+                    )));
+                }
+                let mut section = SectionTable {
+                    virtual_address: rva,
+                    characteristics,
+                    ..Default::default()
+                };
+                let len = name.len().min(8);
+                section.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+                section.real_name = Some(name[..len].to_string());
+                let offset = self
+                    .out
+                    .len()
+                    .next_multiple_of(self.file_alignment as usize);
+                self.out.resize(offset, 0);
+                self.sections.push(section);
+                self.sections.last_mut().unwrap()
+            }
+        };
+        section.pointer_to_raw_data = self.out.len() as u32;
+        section.virtual_size = data.len() as u32;
+        section.size_of_raw_data = (data.len() as u32).next_multiple_of(self.file_alignment);
+        self.out.extend_from_slice(&data);
+        self.out.resize(
+            section.pointer_to_raw_data as usize + section.size_of_raw_data as usize,
+            0,
+        );
+        Ok((rva, data.len() as u32))
+    }
+
+    fn transform_sections(&mut self, transforms: &mut SectionTransforms) -> error::Result<()> {
+        if transforms.is_empty() {
+            return Ok(());
+        }
+        for section in self.sections.iter() {
+            let name = section.name().unwrap_or("");
+            let mut size = section.size_of_raw_data;
+            if section.virtual_size != 0 {
+                size = size.min(section.virtual_size);
+            }
+            let start = section.pointer_to_raw_data as usize;
+            let range = start..start + size as usize;
+            let section_ref = SectionRef {
+                name,
+                address: u64::from(section.virtual_address),
+                offset: start,
+                loaded: true,
+                file: &self.out,
+            };
+            if let Some(data) = transforms.apply(&section_ref, range.clone())? {
+                self.out[start..start + data.len()].copy_from_slice(&data);
+                self.out[start + data.len()..range.end].fill(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the section table and the image size, append the kept `artifacts` and update the
+    /// checksum
+    fn finish(mut self, artifacts: &[Artifact], appended: &AppendedData) -> error::Result<Vec<u8>> {
+        let size_of_image = self.next_rva()?;
+        let mut offset = self.section_table;
+        for section in self.sections.iter() {
+            self.out
+                .gwrite_with(section.clone(), &mut offset, scroll::LE)?;
+        }
+        let coff_header = self.optional_header - SIZEOF_COFF_HEADER;
+        self.out
+            .pwrite_with(self.sections.len() as u16, coff_header + 2, scroll::LE)?;
+        self.out
+            .pwrite_with(size_of_image, self.optional_header + 56, scroll::LE)?;
+
+        let signature = overlay::append_artifacts(&mut self.out, self.bytes, artifacts, appended)?;
+        if artifacts.iter().any(|a| a.kind == ArtifactKind::Signature) {
+            let (start, end) = signature.map_or((0, 0), |range| (range.start, range.end));
+            self.set_data_directory(
+                IMAGE_DIRECTORY_ENTRY_SECURITY,
+                start as u32,
+                (end - start) as u32,
+            )?;
+        }
+
+        if self.check_sum != 0 {
+            let at = self.optional_header + 64;
+            let sum = checksum(&self.out, at);
+            self.out.pwrite_with(sum, at, scroll::LE)?;
+        }
+        Ok(self.out)
+    }
+}
+
+/// The PE checksum of `bytes`, skipping the checksum field at `at`
+pub fn checksum(bytes: &[u8], at: usize) -> u32 {
+    let mut sum = 0u64;
+    for (i, word) in bytes.chunks(2).enumerate() {
+        if i * 2 == at || i * 2 == at + 2 {
+            continue;
+        }
+        sum += u64::from(word[0]) | u64::from(*word.get(1).unwrap_or(&0)) << 8;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u32 + bytes.len() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::resource::{
+        Resource, ResourceId, VersionInfo, LANG_EN_US, RT_MANIFEST, RT_RCDATA, RT_VERSION,
+    };
+
+    const OVERLAY: &[u8] = b"appended payload";
+
+    /// A PE32+ image with a `.text`, an `.rdata` holding the imports and a `.rsrc` section,
+    /// followed by a `.data` section if `data` is set, and an overlay
+    fn executable(data: bool) -> Vec<u8> {
+        let mut imports = ImportTable::new();
+        imports.add_function("KERNEL32.dll", ImportedFunction::by_name("ExitProcess"));
+        imports.add_function("KERNEL32.dll", ImportedFunction::by_name("GetTickCount"));
+        let imports = imports.build(0x2000, true);
+        let mut resources = ResourceTree::new();
+        resources.insert(
+            RT_RCDATA.into(),
+            "CONFIG".into(),
+            LANG_EN_US,
+            Resource {
+                data: b"key=value".to_vec(),
+                codepage: 0,
+            },
+        );
+        let rsrc = resources.build(0x3000);
+        let sections = if data { 4 } else { 3 };
+
+        let mut bytes = vec![0u8; 0x400 + 0x200 * sections];
+        bytes.pwrite_with(0x5a4du16, 0, scroll::LE).unwrap();
+        bytes.pwrite_with(0x40u32, 0x3c, scroll::LE).unwrap();
+        bytes.pwrite_with(0x4550u32, 0x40, scroll::LE).unwrap();
+        let coff = 0x44;
+        bytes.pwrite_with(0x8664u16, coff, scroll::LE).unwrap();
+        bytes
+            .pwrite_with(sections as u16, coff + 2, scroll::LE)
+            .unwrap();
+        bytes.pwrite_with(0xf0u16, coff + 16, scroll::LE).unwrap();
+        bytes.pwrite_with(0x22u16, coff + 18, scroll::LE).unwrap();
+        let opt = coff + SIZEOF_COFF_HEADER;
+        bytes.pwrite_with(MAGIC_64, opt, scroll::LE).unwrap();
+        bytes.pwrite_with(0x1000u32, opt + 16, scroll::LE).unwrap();
+        bytes
+            .pwrite_with(0x1_4000_0000u64, opt + 24, scroll::LE)
+            .unwrap();
+        bytes.pwrite_with(0x1000u32, opt + 32, scroll::LE).unwrap();
+        bytes.pwrite_with(0x200u32, opt + 36, scroll::LE).unwrap();
+        bytes.pwrite_with(6u16, opt + 48, scroll::LE).unwrap();
+        bytes
+            .pwrite_with(0x1000 * (sections as u32 + 1), opt + 56, scroll::LE)
+            .unwrap();
+        bytes.pwrite_with(0x400u32, opt + 60, scroll::LE).unwrap();
+        bytes.pwrite_with(1u32, opt + 64, scroll::LE).unwrap();
+        bytes.pwrite_with(3u16, opt + 68, scroll::LE).unwrap();
+        bytes.pwrite_with(16u32, opt + 108, scroll::LE).unwrap();
+        let directories = [
+            (IMAGE_DIRECTORY_ENTRY_IMPORT, 0x2000, imports.directory_size),
+            (IMAGE_DIRECTORY_ENTRY_RESOURCE, 0x3000, rsrc.len() as u32),
+        ];
+        for (index, rva, size) in directories {
+            let at = opt + 112 + 8 * index;
+            bytes.pwrite_with(rva as u32, at, scroll::LE).unwrap();
+            bytes.pwrite_with(size, at + 4, scroll::LE).unwrap();
+        }
+
+        let mut offset = opt + 0xf0;
+        for (i, name) in [".text", ".rdata", ".rsrc", ".data"]
+            .iter()
+            .take(sections)
+            .enumerate()
+        {
+            let mut section = SectionTable {
+                virtual_address: 0x1000 * (i as u32 + 1),
+                virtual_size: 0x100,
+                size_of_raw_data: 0x200,
+                pointer_to_raw_data: 0x400 + 0x200 * i as u32,
+                characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+                ..Default::default()
+            };
+            section.name[..name.len()].copy_from_slice(name.as_bytes());
+            bytes.gwrite_with(section, &mut offset, scroll::LE).unwrap();
+        }
+        bytes[0x400..0x410].copy_from_slice(&[0xcc; 16]);
+        bytes[0x600..0x600 + imports.data.len()].copy_from_slice(&imports.data);
+        bytes[0x800..0x800 + rsrc.len()].copy_from_slice(&rsrc);
+        bytes.extend_from_slice(OVERLAY);
+        bytes
+    }
+
+    fn edit(writer: &mut PEWriter) {
+        let resources = writer.resources().unwrap();
+        let mut version = VersionInfo::new([2, 0, 1, 7]);
+        version.set_string("CompanyName", "Example");
+        resources.set_version_info(&version);
+        resources.set_manifest(&"<assembly>padding</assembly>".repeat(40));
+    }
+
+    #[test]
+    fn rewrite_last_section() {
+        let bytes = executable(false);
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        edit(&mut writer);
+        let expected = writer.resources().unwrap().clone();
+        let out = writer.build().unwrap();
+
+        let pe = PE::parse(&out).unwrap();
+        assert_eq!(pe.sections.len(), 3);
+        let rsrc = &pe.sections[2];
+        assert_eq!(rsrc.virtual_address, 0x3000);
+        assert!(rsrc.size_of_raw_data > 0x200);
+        let windows_fields = pe.header.optional_header.unwrap().windows_fields;
+        assert_eq!(
+            windows_fields.size_of_image,
+            (0x3000 + rsrc.virtual_size).next_multiple_of(0x1000)
+        );
+        assert_eq!(windows_fields.check_sum, checksum(&out, 0x58 + 64));
+        let resources = ResourceTree::parse(&out, &pe).unwrap();
+        assert_eq!(resources, expected);
+        assert!(resources
+            .first(&ResourceId::Id(RT_VERSION))
+            .is_some_and(|(_, lang, _)| lang == LANG_EN_US));
+        assert!(resources.first(&ResourceId::Id(RT_MANIFEST)).is_some());
+        assert_eq!(resources.len(), 3);
+        assert!(out.ends_with(OVERLAY));
+        assert_eq!(out[0x400..0x800], bytes[0x400..0x800]);
+    }
+
+    #[test]
+    fn rejects_bad_layout() {
+        let opt = 0x44 + SIZEOF_COFF_HEADER;
+        let mut bytes = executable(true);
+        bytes.pwrite_with(0u32, opt + 32, scroll::LE).unwrap();
+        assert!(matches!(
+            PEWriter::new(&bytes),
+            Err(error::Error::Malformed(_))
+        ));
+
+        // .data ends past 4 GiB
+        let mut bytes = executable(true);
+        let data = opt + 0xf0 + 3 * SIZEOF_SECTION_TABLE;
+        bytes
+            .pwrite_with(0xffff_ff80u32, data + 12, scroll::LE)
+            .unwrap();
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        edit(&mut writer);
+        assert!(matches!(writer.build(), Err(error::Error::Malformed(_))));
+    }
+
+    #[test]
+    fn append_section() {
+        let bytes = executable(true);
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        edit(&mut writer);
+        let out = writer.build().unwrap();
+
+        let pe = PE::parse(&out).unwrap();
+        assert_eq!(pe.sections.len(), 5);
+        let rsrc = &pe.sections[4];
+        assert_eq!(rsrc.name().unwrap(), ".rsrc");
+        assert_eq!(rsrc.virtual_address, 0x5000);
+        assert_eq!(rsrc.pointer_to_raw_data, 0xc00);
+        let directories = pe.header.optional_header.unwrap().data_directories;
+        assert_eq!(
+            directories.get_resource_table().unwrap().virtual_address,
+            0x5000
+        );
+        // the old section is left alone
+        assert_eq!(out[0x400..0xc00], bytes[0x400..0xc00]);
+        assert_eq!(ResourceTree::parse(&out, &pe).unwrap().len(), 3);
+        assert!(out.ends_with(OVERLAY));
+    }
+
+    #[test]
+    fn appended_data() {
+        let mut bytes = executable(true);
+        let certificate = bytes.len();
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&0x0200u16.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(b"pkcs#7!!");
+        let at = 0x58 + 112 + 8 * IMAGE_DIRECTORY_ENTRY_SECURITY;
+        bytes
+            .pwrite_with(certificate as u32, at, scroll::LE)
+            .unwrap();
+        bytes.pwrite_with(16u32, at + 4, scroll::LE).unwrap();
+
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        let kinds = writer
+            .appended()
+            .iter()
+            .map(|artifact| artifact.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [ArtifactKind::Overlay, ArtifactKind::Signature]);
+        edit(&mut writer);
+        let out = writer.build().unwrap();
+        let pe = PE::parse(&out).unwrap();
+        let table = certificate_table(&pe).unwrap();
+        assert_eq!(table.start % 8, 0);
+        assert_eq!(out[table.clone()], bytes[certificate..]);
+        assert_eq!(out[table.start - OVERLAY.len()..table.start], *OVERLAY);
+
+        writer.set_appended_data(AppendedData {
+            signature: overlay::Preserve::Strip,
+            ..Default::default()
+        });
+        let out = writer.build().unwrap();
+        let pe = PE::parse(&out).unwrap();
+        assert!(certificate_table(&pe).is_none_or(|table| table.is_empty()));
+        assert!(out.ends_with(OVERLAY));
+
+        writer.set_appended_data(AppendedData::strip_all());
+        let out = writer.build().unwrap();
+        let pe = PE::parse(&out).unwrap();
+        assert_eq!(out.len(), overlay_start(&out, &pe));
+    }
+
+    #[test]
+    fn add_imports() {
+        let bytes = executable(false);
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        let imports = writer.imports().unwrap();
+        assert!(imports.add_function("kernel32.dll", ImportedFunction::by_name("Sleep")));
+        assert!(!imports.add_function("KERNEL32.dll", ImportedFunction::by_name("Sleep")));
+        imports.add_function("USER32.dll", ImportedFunction::by_name("MessageBoxA"));
+        imports.add_function("WS2_32.dll", ImportedFunction::Ordinal(115));
+        let out = writer.build().unwrap();
+
+        let pe = PE::parse(&out).unwrap();
+        assert_eq!(pe.sections.len(), 4);
+        assert_eq!(pe.sections[3].virtual_address, 0x4000);
+        let import_data = pe.import_data.as_ref().unwrap();
+        let descriptors = import_data
+            .import_data
+            .iter()
+            .map(|entry| {
+                (
+                    entry.name,
+                    entry.import_directory_entry.import_address_table_rva,
+                )
+            })
+            .collect::<Vec<_>>();
+        // the original functions keep their IAT, the new ones come through a second descriptor
+        let original_iat = bytes.pread_with::<u32>(0x600 + 16, scroll::LE).unwrap();
+        assert_eq!(descriptors[0], ("KERNEL32.dll", original_iat));
+        assert_eq!(descriptors[1].0, "KERNEL32.dll");
+        assert_eq!(descriptors[2].0, "USER32.dll");
+        assert_eq!(descriptors[3].0, "WS2_32.dll");
+        for import in pe.imports.iter() {
+            let name = import.name.trim_start_matches("ORDINAL ");
+            assert_eq!(
+                writer.import_address(import.dll, name),
+                Some(import.offset as u32)
+            );
+        }
+        assert_eq!(
+            writer.import_address("KERNEL32.dll", "GetTickCount"),
+            Some(original_iat + 8)
+        );
+        assert!(writer.import_address("KERNEL32.dll", "Sleep").unwrap() >= 0x4000);
+        assert_eq!(pe.imports.len(), 5);
+        assert!(out.ends_with(OVERLAY));
+    }
+
+    #[test]
+    fn remove_imports() {
+        let bytes = executable(false);
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        assert!(writer
+            .imports()
+            .unwrap()
+            .remove_function("KERNEL32.dll", "ExitProcess"));
+        let out = writer.build().unwrap();
+        let pe = PE::parse(&out).unwrap();
+        assert_eq!(pe.imports.len(), 1);
+        assert_eq!(pe.imports[0].name, "GetTickCount");
+        // the DLL lost an import, so its IAT is rebuilt
+        assert!(pe.imports[0].offset >= 0x4000);
+
+        assert!(writer.imports().unwrap().remove_dll("kernel32.dll"));
+        let out = writer.build().unwrap();
+        let pe = PE::parse(&out).unwrap();
+        assert!(pe.import_data.is_none());
+        assert_eq!(pe.sections.len(), 3);
+    }
+
+    #[test]
+    fn section_transforms() {
+        let bytes = executable(false);
+        let mut writer = PEWriter::new(&bytes).unwrap();
+        writer.add_section_transform(".text", |section: &SectionRef, data: &mut Vec<u8>| {
+            assert_eq!(section.address, 0x1000);
+            data.iter_mut().for_each(|b| *b ^= 0xff);
+            data.truncate(0x10);
+            Ok(())
+        });
+        let out = writer.build().unwrap();
+        assert_eq!(out[0x400..0x410], [0x33; 16]);
+        assert!(out[0x410..0x600].iter().all(|&b| b == 0));
+        // nothing else changes but the checksum
+        assert_eq!(out[0x600..], bytes[0x600..]);
+        assert_eq!(PE::parse(&out).unwrap().sections[0].virtual_size, 0x100);
+    }
+}
@@ -0,0 +1,450 @@
+//! A map from `i32` to values which is cheap to copy.
+//!
+//! [`PersistentMap`] is a radix trie of 16 way nodes behind `Arc`s, ordered by key. Cloning one
+//! only bumps a reference count, and the copies share their nodes: a change copies the nodes on
+//! the path to the key it touches (at most eight nodes of sixteen pointers) and leaves the rest
+//! shared. The workspace keeps the stores it hands to [`crate::snapshot`] views in these, so a
+//! view costs nothing to take and analysis going on doesn't copy the whole store for each one.
+
+use std::{fmt, sync::Arc};
+
+const BITS: u32 = 4;
+const WIDTH: usize = 1 << BITS;
+const LEVELS: u32 = u32::BITS / BITS;
+
+/// The key with its sign bit flipped, so that unsigned order is the order of the keys
+fn ordered(key: i32) -> u32 {
+    key as u32 ^ 0x8000_0000
+}
+
+/// The child of a node at `level` the key goes down to
+fn slot(bits: u32, level: u32) -> usize {
+    ((bits >> (u32::BITS - BITS * (level + 1))) as usize) & (WIDTH - 1)
+}
+
+enum Node<V> {
+    Branch([Option<Arc<Node<V>>>; WIDTH]),
+    /// The last level, holding the entries
+    Leaf([Option<Arc<(i32, V)>>; WIDTH]),
+}
+
+// cloning a node copies its pointers, not what they point at, so the values needn't be Clone
+impl<V> Clone for Node<V> {
+    fn clone(&self) -> Self {
+        match self {
+            Node::Branch(children) => Node::Branch(children.clone()),
+            Node::Leaf(entries) => Node::Leaf(entries.clone()),
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn new(level: u32) -> Self {
+        if level + 1 == LEVELS {
+            Node::Leaf(Default::default())
+        } else {
+            Node::Branch(Default::default())
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Node::Branch(children) => children.iter().all(Option::is_none),
+            Node::Leaf(entries) => entries.iter().all(Option::is_none),
+        }
+    }
+
+    /// The last entry under this node, whose key is at most `bits` if `bounded`
+    fn last(&self, bits: u32, level: u32, bounded: bool) -> Option<&(i32, V)> {
+        let top = if bounded {
+            slot(bits, level)
+        } else {
+            WIDTH - 1
+        };
+        match self {
+            Node::Leaf(entries) => entries[..=top]
+                .iter()
+                .rev()
+                .find_map(|entry| entry.as_deref()),
+            Node::Branch(children) => children[top]
+                .as_deref()
+                .and_then(|child| child.last(bits, level + 1, bounded))
+                .or_else(|| {
+                    children[..top]
+                        .iter()
+                        .rev()
+                        .find_map(|child| child.as_deref()?.last(bits, level + 1, false))
+                }),
+        }
+    }
+}
+
+/// An ordered map from `i32`, whose copies share what they have in common
+pub struct PersistentMap<V> {
+    root: Option<Arc<Node<V>>>,
+    len: usize,
+}
+
+impl<V> Default for PersistentMap<V> {
+    fn default() -> Self {
+        PersistentMap { root: None, len: 0 }
+    }
+}
+
+impl<V> Clone for PersistentMap<V> {
+    fn clone(&self) -> Self {
+        PersistentMap {
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<V> PersistentMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether the two maps are the same copy, without a change made to either since
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn get(&self, key: &i32) -> Option<&V> {
+        let bits = ordered(*key);
+        let mut node = self.root.as_deref()?;
+        for level in 0..LEVELS {
+            match node {
+                Node::Branch(children) => node = children[slot(bits, level)].as_deref()?,
+                Node::Leaf(entries) => {
+                    return entries[slot(bits, level)]
+                        .as_deref()
+                        .map(|(_, value)| value)
+                }
+            }
+        }
+        None
+    }
+
+    pub fn contains_key(&self, key: &i32) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The entry with the greatest key at most `key`
+    pub fn last_at_or_before(&self, key: i32) -> Option<(&i32, &V)> {
+        let (key, value) = self.root.as_deref()?.last(ordered(key), 0, true)?;
+        Some((key, value))
+    }
+
+    /// The entries in key order
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            stack: self
+                .root
+                .as_deref()
+                .map(|root| (root, 0))
+                .into_iter()
+                .collect(),
+            remaining: self.len,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &i32> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<V: Clone> PersistentMap<V> {
+    /// The slot of `key`, copying the nodes on the way to it which other maps share
+    fn slot_mut(&mut self, key: i32) -> &mut Option<Arc<(i32, V)>> {
+        let bits = ordered(key);
+        let mut node = Arc::make_mut(self.root.get_or_insert_with(|| Arc::new(Node::new(0))));
+        let mut level = 0;
+        loop {
+            match node {
+                Node::Branch(children) => {
+                    let child = children[slot(bits, level)]
+                        .get_or_insert_with(|| Arc::new(Node::new(level + 1)));
+                    node = Arc::make_mut(child);
+                    level += 1;
+                }
+                Node::Leaf(entries) => return &mut entries[slot(bits, level)],
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &i32) -> Option<&mut V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.slot_mut(*key)
+            .as_mut()
+            .map(|entry| &mut Arc::make_mut(entry).1)
+    }
+
+    pub fn insert(&mut self, key: i32, value: V) -> Option<V> {
+        let old = self.slot_mut(key).replace(Arc::new((key, value)));
+        if old.is_none() {
+            self.len += 1;
+        }
+        old.map(unwrap)
+    }
+
+    pub fn remove(&mut self, key: &i32) -> Option<V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let root = self.root.as_mut()?;
+        let old = remove(root, ordered(*key), 0);
+        if root.is_empty() {
+            self.root = None;
+        }
+        self.len -= 1;
+        old.map(unwrap)
+    }
+
+    /// The value of `key`, inserting the one `value` makes if there is none
+    pub fn get_or_insert_with(&mut self, key: i32, value: impl FnOnce() -> V) -> &mut V {
+        if !self.contains_key(&key) {
+            self.len += 1;
+        }
+        let entry = self
+            .slot_mut(key)
+            .get_or_insert_with(|| Arc::new((key, value())));
+        &mut Arc::make_mut(entry).1
+    }
+
+    pub fn entry(&mut self, key: i32) -> Entry<'_, V> {
+        Entry { map: self, key }
+    }
+
+    /// Keep only the entries `keep` is true of
+    pub fn retain(&mut self, mut keep: impl FnMut(&i32, &V) -> bool) {
+        let gone: Vec<i32> = self
+            .iter()
+            .filter(|(key, value)| !keep(key, value))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in gone {
+            self.remove(&key);
+        }
+    }
+}
+
+fn unwrap<V: Clone>(entry: Arc<(i32, V)>) -> V {
+    Arc::try_unwrap(entry).map_or_else(|entry| entry.1.clone(), |(_, value)| value)
+}
+
+fn remove<V: Clone>(node: &mut Arc<Node<V>>, bits: u32, level: u32) -> Option<Arc<(i32, V)>> {
+    match Arc::make_mut(node) {
+        Node::Branch(children) => {
+            let index = slot(bits, level);
+            let child = children[index].as_mut()?;
+            let old = remove(child, bits, level + 1);
+            if child.is_empty() {
+                children[index] = None;
+            }
+            old
+        }
+        Node::Leaf(entries) => entries[slot(bits, level)].take(),
+    }
+}
+
+/// A key of a [`PersistentMap`], which may have a value
+pub struct Entry<'a, V> {
+    map: &'a mut PersistentMap<V>,
+    key: i32,
+}
+
+impl<'a, V: Clone> Entry<'a, V> {
+    pub fn or_insert_with(self, value: impl FnOnce() -> V) -> &'a mut V {
+        self.map.get_or_insert_with(self.key, value)
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+/// The entries of a [`PersistentMap`] in key order
+pub struct Iter<'a, V> {
+    stack: Vec<(&'a Node<V>, usize)>,
+    remaining: usize,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a i32, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, index) = self.stack.last_mut()?;
+            let node: &'a Node<V> = node;
+            if *index == WIDTH {
+                self.stack.pop();
+                continue;
+            }
+            let at = *index;
+            *index += 1;
+            match node {
+                Node::Branch(children) => {
+                    if let Some(child) = children[at].as_deref() {
+                        self.stack.push((child, 0));
+                    }
+                }
+                Node::Leaf(entries) => {
+                    if let Some((key, value)) = entries[at].as_deref() {
+                        self.remaining -= 1;
+                        return Some((key, value));
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, V> IntoIterator for &'a PersistentMap<V> {
+    type Item = (&'a i32, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<V: Clone> FromIterator<(i32, V)> for PersistentMap<V> {
+    fn from_iter<I: IntoIterator<Item = (i32, V)>>(iter: I) -> Self {
+        let mut map = PersistentMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<V: PartialEq> PartialEq for PersistentMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for PersistentMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn like_a_btree_map() {
+        let keys = [
+            0,
+            1,
+            -1,
+            0x1000,
+            0x1010,
+            i32::MIN,
+            i32::MAX,
+            0x7fff_0000,
+            -0x1000,
+        ];
+        let mut map = PersistentMap::new();
+        let mut expected = BTreeMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            assert_eq!(map.insert(key, i), None);
+            expected.insert(key, i);
+        }
+        assert_eq!(map.insert(0x1000, 42), Some(3));
+        expected.insert(0x1000, 42);
+        assert_eq!(map.len(), keys.len());
+        assert!(map.iter().eq(expected.iter()));
+        assert_eq!(map.get(&-1), Some(&2));
+        assert_eq!(map.get(&2), None);
+
+        // the greatest key at or before, across nodes
+        assert_eq!(map.last_at_or_before(0x100f), Some((&0x1000, &42)));
+        assert_eq!(map.last_at_or_before(0x1010), Some((&0x1010, &4)));
+        assert_eq!(map.last_at_or_before(0x7ffe_ffff), Some((&0x1010, &4)));
+        assert_eq!(map.last_at_or_before(-2), Some((&-0x1000, &8)));
+        assert_eq!(map.last_at_or_before(i32::MIN), Some((&i32::MIN, &5)));
+        map.remove(&i32::MIN);
+        assert_eq!(map.last_at_or_before(i32::MIN), None);
+
+        *map.entry(5).or_default() += 7;
+        *map.get_mut(&0).unwrap() += 100;
+        map.retain(|key, _| *key != 1);
+        assert_eq!(map.get(&5), Some(&7));
+        assert_eq!(map.get(&0), Some(&100));
+        assert_eq!(map.get(&1), None);
+        for key in map.keys().copied().collect::<Vec<_>>() {
+            map.remove(&key);
+        }
+        assert!(map.is_empty());
+        assert!(map.root.is_none());
+    }
+
+    #[test]
+    fn copies_share() {
+        // four keys under each of the root's children
+        let mut map: PersistentMap<String> = (0..WIDTH as u32)
+            .flat_map(|top| {
+                (0..4).map(move |low| (((top << 28) | low) as i32, format!("{}.{}", top, low)))
+            })
+            .collect();
+        let copy = map.clone();
+        assert!(map.ptr_eq(&copy));
+        map.insert(0x1000_0002, "changed".into());
+        map.remove(&0x1000_0003);
+        assert!(!map.ptr_eq(&copy));
+        assert_eq!(copy.get(&0x1000_0002).map(String::as_str), Some("1.2"));
+        assert_eq!(copy.get(&0x1000_0003).map(String::as_str), Some("1.3"));
+        assert_eq!(map.get(&0x1000_0002).map(String::as_str), Some("changed"));
+        assert_eq!((map.len(), copy.len()), (63, 64));
+
+        // the changes copied the path to the keys they touched, the other children are shared
+        let children = |map: &PersistentMap<String>| match map.root.as_deref() {
+            Some(Node::Branch(children)) => children.clone(),
+            _ => unreachable!(),
+        };
+        let (ours, theirs) = (children(&map), children(&copy));
+        let shared = ours
+            .iter()
+            .zip(theirs.iter())
+            .filter(|(a, b)| match (a, b) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                _ => false,
+            })
+            .count();
+        assert_eq!(shared, WIDTH - 1);
+    }
+}
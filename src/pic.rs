@@ -0,0 +1,510 @@
+//! Resolving the addresses position independent code computes.
+//!
+//! PIC doesn't hold the addresses of its data, it computes them from the program counter:
+//! `lea rax, [rip + x]` on x86-64, `adrp x8, x@page; add x8, x8, x@pageoff` (or `ldr` through
+//! it) on A64, `ldr r0, [pc, #n]` from a literal pool on ARM. Or from a register set up once
+//! to point at the GOT: `ebx` in i386 PIC, `$gp` on MIPS. A lifted function only sees `rip + x`
+//! in terms of an unknown `rip`, so none of these become data references.
+//!
+//! [`data_refs`] runs a lifted [`Function`] with the program counter bound to each instruction
+//! as the architecture defines it, and the GOT registers (see [`AddressSpace::set_register`])
+//! bound to their value, so those computations fold to constants. Loads from read-only memory,
+//! such as literal pools, fold to the value loaded. Each memory access through a known address
+//! is a read or a write reference, and each address computed from the program counter or a GOT
+//! register and left in a register is an address reference; the intermediate values (the page
+//! of an `adrp`) aren't reported. [`add_xrefs`] records them in a workspace.
+
+use crate::{
+    constants::{REF_DATA, REF_PTR},
+    envi::{
+        registers::{RegId, RegisterModel},
+        Arch,
+    },
+    symbolic::{Expr, Function, Insn, State, Stmt},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The memory the code runs in, and the registers known to hold an address throughout it
+#[derive(Debug, Clone, Default)]
+pub struct AddressSpace<'a> {
+    maps: Vec<(u64, &'a [u8], bool)>,
+    registers: Vec<(RegId, u64)>,
+    big_endian: bool,
+}
+
+impl<'a> AddressSpace<'a> {
+    pub fn new() -> Self {
+        AddressSpace::default()
+    }
+
+    /// Map `bytes` at `va`. Loads from memory which isn't writable fold to the value loaded.
+    pub fn add_map(&mut self, va: u64, bytes: &'a [u8], writable: bool) {
+        self.maps.push((va, bytes, writable));
+    }
+
+    /// `reg` holds `value` wherever the function doesn't set it, like the GOT base in `ebx` or
+    /// `$gp`
+    pub fn set_register(&mut self, reg: RegId, value: u64) {
+        self.registers.retain(|(r, _)| *r != reg);
+        self.registers.push((reg, value));
+    }
+
+    /// Read values from memory most significant byte first, as on PowerPC or MIPS BE
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.big_endian = big_endian;
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// The maps, as address, bytes and whether they are writable
+    pub fn maps(&self) -> impl Iterator<Item = (u64, &'a [u8], bool)> + '_ {
+        self.maps.iter().copied()
+    }
+
+    pub fn is_mapped(&self, va: u64) -> bool {
+        self.maps
+            .iter()
+            .any(|(mva, bytes, _)| *mva <= va && va - mva < bytes.len() as u64)
+    }
+
+    /// The `len` bytes at `va`, and whether they are writable, if they are mapped
+    pub fn bytes(&self, va: u64, len: usize) -> Option<(&'a [u8], bool)> {
+        self.maps.iter().find_map(|(mva, bytes, writable)| {
+            let offset = usize::try_from(va.checked_sub(*mva)?).ok()?;
+            let bytes = bytes.get(offset..offset.checked_add(len)?)?;
+            Some((bytes, *writable))
+        })
+    }
+
+    /// The `size` byte value at `va`, if it can't change
+    pub fn read_constant(&self, va: u64, size: usize) -> Option<u64> {
+        self.maps.iter().find_map(|(mva, bytes, writable)| {
+            let offset = usize::try_from(va.checked_sub(*mva)?).ok()?;
+            let bytes = bytes.get(offset..offset.checked_add(size)?)?;
+            if *writable {
+                return None;
+            }
+            Some(load(bytes, self.big_endian))
+        })
+    }
+}
+
+/// The value of up to 8 `bytes`, in the given byte order
+pub(crate) fn load(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut value = [0; 8];
+    if big_endian {
+        value[8 - bytes.len()..].copy_from_slice(bytes);
+        u64::from_be_bytes(value)
+    } else {
+        value[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(value)
+    }
+}
+
+/// The value the program counter reads as in the instruction at `va`, `next` being the address
+/// of the instruction after it
+pub fn pc_value(arch: Arch, va: u64, next: Option<u64>) -> Option<u64> {
+    match arch {
+        Arch::I386 | Arch::Amd64 => next,
+        Arch::ArmV7 => Some(va + 8),
+        Arch::Thumb | Arch::Thumb16 => Some(va + 4),
+        _ => Some(va),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RefKind {
+    Read,
+    Write,
+    /// An address computed into a register
+    Address,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DataRef {
+    /// The instruction making the reference
+    pub from: u64,
+    pub to: u64,
+    pub kind: RefKind,
+}
+
+/// Fold the loads from read-only memory of an evaluated expression
+fn fold_loads(expr: &Expr, width: u32, space: &AddressSpace<'_>) -> Expr {
+    match expr {
+        Expr::Const(_) | Expr::Reg(_) => expr.clone(),
+        Expr::Load(addr) => {
+            let addr = fold_loads(addr, width, space);
+            match addr
+                .as_const()
+                .and_then(|a| space.read_constant(a, width as usize / 8))
+            {
+                Some(value) => Expr::Const(value),
+                None => Expr::Load(Box::new(addr)),
+            }
+        }
+        Expr::Unary(op, a) => Expr::unary(*op, fold_loads(a, width, space), width),
+        Expr::Binary(op, a, b) => Expr::binary(
+            *op,
+            fold_loads(a, width, space),
+            fold_loads(b, width, space),
+            width,
+        ),
+    }
+}
+
+/// The address expressions of the loads in `expr`
+fn load_addresses<'e>(expr: &'e Expr, out: &mut Vec<&'e Expr>) {
+    match expr {
+        Expr::Const(_) | Expr::Reg(_) => {}
+        Expr::Load(addr) => {
+            out.push(addr);
+            load_addresses(addr, out);
+        }
+        Expr::Unary(_, a) => load_addresses(a, out),
+        Expr::Binary(_, a, b) => {
+            load_addresses(a, out);
+            load_addresses(b, out);
+        }
+    }
+}
+
+fn registers(expr: &Expr) -> BTreeSet<RegId> {
+    let mut regs = BTreeSet::new();
+    expr.registers(&mut regs);
+    regs
+}
+
+/// Runs the instructions of a block, collecting references
+struct Walk<'s, 'a> {
+    arch: Arch,
+    pc: RegId,
+    space: &'s AddressSpace<'a>,
+    refs: BTreeSet<DataRef>,
+    /// Registers holding an address computed from the program counter or a GOT register, and
+    /// the instruction which computed it
+    pending: BTreeMap<RegId, (u64, u64)>,
+}
+
+impl<'s, 'a> Walk<'s, 'a> {
+    /// Whether an expression of the instruction is one of the idioms
+    fn is_derived(&self, regs: &BTreeSet<RegId>) -> bool {
+        regs.iter().any(|reg| {
+            *reg == self.pc
+                || self.pending.contains_key(reg)
+                || self.space.registers.iter().any(|(r, _)| r == reg)
+        })
+    }
+
+    fn eval(&self, state: &State, expr: &Expr) -> Option<u64> {
+        fold_loads(&state.eval(expr), state.width(), self.space).as_const()
+    }
+
+    /// A memory access through `addr`: the registers it is computed from were intermediates
+    fn access(&mut self, state: &State, va: u64, addr: &Expr, kind: RefKind) {
+        let regs = registers(addr);
+        if !self.is_derived(&regs) {
+            return;
+        }
+        if let Some(to) = self.eval(state, addr) {
+            self.refs.insert(DataRef { from: va, to, kind });
+            for reg in regs {
+                self.pending.remove(&reg);
+            }
+        }
+    }
+
+    fn flush(&mut self, reg: RegId) {
+        if let Some((from, to)) = self.pending.remove(&reg) {
+            if self.space.is_mapped(to) {
+                self.refs.insert(DataRef {
+                    from,
+                    to,
+                    kind: RefKind::Address,
+                });
+            }
+        }
+    }
+
+    fn insn(&mut self, state: &mut State, insn: &Insn, next: Option<u64>) {
+        let pc = match pc_value(self.arch, insn.va, next) {
+            Some(pc) => Expr::Const(pc),
+            None => Expr::Reg(self.pc),
+        };
+        state.exec(&Stmt::Set(self.pc, pc));
+        for stmt in insn.stmts.iter() {
+            let mut loads = Vec::new();
+            match stmt {
+                Stmt::Set(_, value) => load_addresses(value, &mut loads),
+                Stmt::Store(addr, value) | Stmt::Flags(_, addr, value) => {
+                    if let Stmt::Store(..) = stmt {
+                        self.access(state, insn.va, addr, RefKind::Write);
+                    }
+                    load_addresses(addr, &mut loads);
+                    load_addresses(value, &mut loads);
+                }
+                Stmt::Unknown => {}
+            }
+            for addr in loads {
+                self.access(state, insn.va, addr, RefKind::Read);
+            }
+            if let Stmt::Set(reg, value) = stmt {
+                let regs = registers(value);
+                let computed = if self.is_derived(&regs) {
+                    self.eval(state, value)
+                } else {
+                    None
+                };
+                match computed {
+                    // `add x8, x8, #pageoff` finishes what the `adrp` started, and an address
+                    // loaded from a literal pool is one to follow
+                    Some(to) => {
+                        for reg in regs {
+                            self.pending.remove(&reg);
+                        }
+                        self.pending.insert(*reg, (insn.va, to));
+                    }
+                    None => self.flush(*reg),
+                }
+            }
+            state.exec(stmt);
+        }
+    }
+}
+
+/// The data references of a lifted function whose addresses are computed from the program
+/// counter or a GOT register
+pub fn data_refs(func: &Function, space: &AddressSpace<'_>) -> Vec<DataRef> {
+    let model = RegisterModel::new(func.arch);
+    let mut walk = Walk {
+        arch: func.arch,
+        pc: model.pc(),
+        space,
+        refs: BTreeSet::new(),
+        pending: BTreeMap::new(),
+    };
+    let fresh = || {
+        let mut state = State::new(func.arch);
+        for (reg, value) in space.registers.iter() {
+            state.exec(&Stmt::Set(*reg, Expr::Const(*value)));
+        }
+        state
+    };
+    let preds = func.predecessors();
+    let mut states: BTreeMap<u64, State> = BTreeMap::new();
+    for va in func.reverse_postorder() {
+        let block = &func.blocks[&va];
+        let mut state = match preds.get(&va).map(Vec::as_slice) {
+            Some([pred]) if *pred != va => states.get(pred).cloned().unwrap_or_else(fresh),
+            _ => fresh(),
+        };
+        for (i, insn) in block.insns.iter().enumerate() {
+            let next = match block.insns.get(i + 1) {
+                Some(next) => Some(next.va),
+                None => Some(block.end_va).filter(|end| *end > insn.va),
+            };
+            walk.insn(&mut state, insn, next);
+        }
+        let pending = walk.pending.keys().copied().collect::<Vec<_>>();
+        for reg in pending {
+            walk.flush(reg);
+        }
+        states.insert(va, state);
+    }
+    walk.refs.into_iter().collect()
+}
+
+/// Record the references as data (reads and writes) and pointer (addresses) xrefs
+pub fn add_xrefs(workspace: &mut VivWorkspace, refs: &[DataRef]) {
+    for r in refs {
+        let rtype = match r.kind {
+            RefKind::Read | RefKind::Write => REF_DATA,
+            RefKind::Address => REF_PTR,
+        };
+        workspace.add_xref(r.from as i32, r.to as i32, rtype, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolic::{BinOp, Block, Terminator};
+
+    fn block(va: u64, insns: Vec<(u64, Vec<Stmt>)>, end_va: u64) -> Block {
+        Block {
+            va,
+            insns: insns
+                .into_iter()
+                .map(|(va, stmts)| Insn { va, stmts })
+                .collect(),
+            end_va,
+            end: Terminator::Return,
+        }
+    }
+
+    #[test]
+    fn pic_idioms() {
+        let add = |a, b| Expr::Binary(BinOp::Add, Box::new(a), Box::new(b));
+        let load = |a| Expr::Load(Box::new(a));
+
+        // lea rax, [rip + 0x2ff9]; mov rcx, [rip + 0x1ff2]; ret
+        let regs = RegisterModel::new(Arch::Amd64);
+        let (rip, rax, rcx) = (
+            regs.pc(),
+            regs.by_name("rax").unwrap(),
+            regs.by_name("rcx").unwrap(),
+        );
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![
+                (
+                    0x1000,
+                    vec![Stmt::Set(rax, add(Expr::Reg(rip), Expr::Const(0x2ff9)))],
+                ),
+                (
+                    0x1007,
+                    vec![Stmt::Set(
+                        rcx,
+                        load(add(Expr::Reg(rip), Expr::Const(0x1ff2))),
+                    )],
+                ),
+            ],
+            0x100e,
+        ));
+        let mut space = AddressSpace::new();
+        let data = [0u8; 0x4000];
+        space.add_map(0x1000, &data, true);
+        assert_eq!(
+            data_refs(&func, &space),
+            [
+                DataRef {
+                    from: 0x1000,
+                    to: 0x4000,
+                    kind: RefKind::Address
+                },
+                DataRef {
+                    from: 0x1007,
+                    to: 0x3000,
+                    kind: RefKind::Read
+                },
+            ]
+        );
+
+        // adrp x8, 0x5000; add x8, x8, #0x10; adrp x9, 0x5000; ldr x0, [x9, #0x20]; ret
+        let regs = RegisterModel::new(Arch::A64);
+        let reg = |name| regs.by_name(name).unwrap();
+        let page = add(
+            Expr::Binary(
+                BinOp::And,
+                Box::new(Expr::Reg(regs.pc())),
+                Box::new(Expr::Const(!0xfff)),
+            ),
+            Expr::Const(0x1000),
+        );
+        let mut func = Function::new(Arch::A64, 0x4000);
+        func.add_block(block(
+            0x4000,
+            vec![
+                (0x4000, vec![Stmt::Set(reg("x8"), page.clone())]),
+                (
+                    0x4004,
+                    vec![Stmt::Set(
+                        reg("x8"),
+                        add(Expr::Reg(reg("x8")), Expr::Const(0x10)),
+                    )],
+                ),
+                (0x4008, vec![Stmt::Set(reg("x9"), page)]),
+                (
+                    0x400c,
+                    vec![Stmt::Set(
+                        reg("x0"),
+                        load(add(Expr::Reg(reg("x9")), Expr::Const(0x20))),
+                    )],
+                ),
+            ],
+            0x4010,
+        ));
+        let mut space = AddressSpace::new();
+        let data = [0u8; 0x100];
+        space.add_map(0x5000, &data, true);
+        assert_eq!(
+            data_refs(&func, &space),
+            [
+                DataRef {
+                    from: 0x4004,
+                    to: 0x5010,
+                    kind: RefKind::Address
+                },
+                DataRef {
+                    from: 0x400c,
+                    to: 0x5020,
+                    kind: RefKind::Read
+                },
+            ]
+        );
+
+        // ldr r0, [pc, #0] from a literal pool holding 0x8000, then ldr r1, [r0]. The pool is
+        // read only, so r0 is known.
+        let regs = RegisterModel::new(Arch::ArmV7);
+        let reg = |name| regs.by_name(name).unwrap();
+        let mut func = Function::new(Arch::ArmV7, 0x100);
+        func.add_block(block(
+            0x100,
+            vec![
+                (
+                    0x100,
+                    vec![Stmt::Set(reg("r0"), load(Expr::Reg(regs.pc())))],
+                ),
+                (
+                    0x104,
+                    vec![Stmt::Set(reg("r1"), load(Expr::Reg(reg("r0"))))],
+                ),
+            ],
+            0x108,
+        ));
+        let code = [0u8, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x80, 0, 0];
+        let mut space = AddressSpace::new();
+        space.add_map(0x100, &code, false);
+        assert_eq!(
+            data_refs(&func, &space),
+            [
+                DataRef {
+                    from: 0x100,
+                    to: 0x108,
+                    kind: RefKind::Read
+                },
+                DataRef {
+                    from: 0x104,
+                    to: 0x8000,
+                    kind: RefKind::Read
+                },
+            ]
+        );
+
+        // i386 PIC: mov eax, [ebx + 0x10] with ebx the GOT at 0x3000
+        let regs = RegisterModel::new(Arch::I386);
+        let (eax, ebx) = (regs.by_name("eax").unwrap(), regs.by_name("ebx").unwrap());
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![(
+                0x1000,
+                vec![Stmt::Set(eax, load(add(Expr::Reg(ebx), Expr::Const(0x10))))],
+            )],
+            0x1003,
+        ));
+        let mut space = AddressSpace::new();
+        space.set_register(ebx, 0x3000);
+        assert_eq!(
+            data_refs(&func, &space),
+            [DataRef {
+                from: 0x1000,
+                to: 0x3010,
+                kind: RefKind::Read
+            }]
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! Property lists, the configuration format of Apple platforms: an app bundle's `Info.plist`
+//! and the entitlements in a code signature. Both the XML form and the binary `bplist00` form
+//! are read.
+
+use crate::{
+    interop::{base64_decode, xml_tags, xml_unescape, Tag},
+    mach::MachO,
+};
+use std::{collections::BTreeMap, io};
+
+const BPLIST_MAGIC: &[u8] = b"bplist00";
+/// How deep containers may nest, which also stops reference cycles in binary plists
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Plist {
+    Bool(bool),
+    Integer(i64),
+    Real(f64),
+    String(String),
+    Data(Vec<u8>),
+    /// Seconds since 2001-01-01, as the binary form keeps it; the XML form's ISO 8601 text is
+    /// kept as a string
+    Date(f64),
+    Array(Vec<Plist>),
+    Dict(BTreeMap<String, Plist>),
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Plist {
+    /// Parse a property list, XML or binary
+    pub fn parse(bytes: &[u8]) -> io::Result<Plist> {
+        if bytes.starts_with(BPLIST_MAGIC) {
+            return parse_binary(bytes);
+        }
+        let xml = std::str::from_utf8(bytes).map_err(|err| invalid(err.to_string()))?;
+        let tags = xml_tags(xml)?;
+        let mut at = tags
+            .iter()
+            .position(|tag| tag.name == "plist")
+            .map_or(0, |plist| plist + 1);
+        parse_xml(&tags, &mut at, 0)
+    }
+
+    /// The `Info.plist` linked into the `__TEXT,__info_plist` section of a command line tool,
+    /// None if `macho` has none
+    pub fn from_macho(macho: &MachO) -> Option<io::Result<Plist>> {
+        let data = macho.section_data("__TEXT", "__info_plist")?;
+        if data.starts_with(BPLIST_MAGIC) {
+            return Some(Plist::parse(data));
+        }
+        // the XML may be padded with NULs to the section's alignment
+        let end = data
+            .iter()
+            .rposition(|b| *b != 0)
+            .map_or(0, |last| last + 1);
+        Some(Plist::parse(&data[..end]))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Plist> {
+        match self {
+            Plist::Dict(dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Plist::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Plist::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Plist]> {
+        match self {
+            Plist::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// The value starting at `tags[*at]`, leaving `at` after it
+fn parse_xml(tags: &[Tag], at: &mut usize, depth: usize) -> io::Result<Plist> {
+    let tag = tags
+        .get(*at)
+        .ok_or_else(|| invalid("Truncated property list".to_string()))?;
+    *at += 1;
+    if depth > MAX_DEPTH {
+        return Err(invalid("Property list nested too deep".to_string()));
+    }
+    // the text of a leaf, up to its closing tag
+    let mut text = || -> String {
+        if tag.empty {
+            return String::new();
+        }
+        if tags
+            .get(*at)
+            .is_some_and(|close| close.name == format!("/{}", tag.name))
+        {
+            *at += 1;
+        }
+        xml_unescape(tag.text)
+    };
+    let number = |text: String| invalid(format!("Bad {}: {}", tag.name, text));
+    Ok(match tag.name {
+        "true" => Plist::Bool(true),
+        "false" => Plist::Bool(false),
+        "string" => Plist::String(text()),
+        "date" => Plist::String(text()),
+        "integer" => {
+            let text = text();
+            let value = text.trim();
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            };
+            Plist::Integer(parsed.ok_or_else(|| number(text.clone()))?)
+        }
+        "real" => {
+            let text = text();
+            Plist::Real(text.trim().parse().map_err(|_| number(text.clone()))?)
+        }
+        "data" => {
+            let text: String = text().split_whitespace().collect();
+            Plist::Data(base64_decode(&text).ok_or_else(|| number(text.clone()))?)
+        }
+        "array" | "dict" if tag.empty => match tag.name {
+            "array" => Plist::Array(Vec::new()),
+            _ => Plist::Dict(BTreeMap::new()),
+        },
+        "array" => {
+            let mut items = Vec::new();
+            while tags.get(*at).is_some_and(|tag| tag.name != "/array") {
+                items.push(parse_xml(tags, at, depth + 1)?);
+            }
+            *at += 1;
+            Plist::Array(items)
+        }
+        "dict" => {
+            let mut dict = BTreeMap::new();
+            while let Some(key) = tags.get(*at).filter(|tag| tag.name != "/dict") {
+                if key.name != "key" {
+                    return Err(invalid(format!("Expected a key, not <{}>", key.name)));
+                }
+                let name = xml_unescape(key.text);
+                *at += if key.empty { 1 } else { 2 };
+                dict.insert(name, parse_xml(tags, at, depth + 1)?);
+            }
+            *at += 1;
+            Plist::Dict(dict)
+        }
+        name => return Err(invalid(format!("Unexpected <{}>", name))),
+    })
+}
+
+/// A binary property list: objects, a table of their offsets, and a trailer saying where the
+/// table is and how wide its entries and the object references are
+fn parse_binary(bytes: &[u8]) -> io::Result<Plist> {
+    let truncated = || invalid("Truncated binary property list".to_string());
+    let trailer = bytes
+        .len()
+        .checked_sub(32)
+        .filter(|at| *at >= BPLIST_MAGIC.len())
+        .map(|at| &bytes[at..])
+        .ok_or_else(truncated)?;
+    let u64_at = |at: usize| u64::from_be_bytes(trailer[at..at + 8].try_into().unwrap()) as usize;
+    let reader = Binary {
+        bytes,
+        offset_size: trailer[6] as usize,
+        ref_size: trailer[7] as usize,
+        count: u64_at(8),
+        table: u64_at(24),
+    };
+    reader.object(u64_at(16), 0)
+}
+
+struct Binary<'a> {
+    bytes: &'a [u8],
+    offset_size: usize,
+    ref_size: usize,
+    count: usize,
+    table: usize,
+}
+
+impl Binary<'_> {
+    /// The big endian number of `size` bytes at `at`
+    fn uint(&self, at: usize, size: usize) -> io::Result<u64> {
+        let bytes = at
+            .checked_add(size)
+            .and_then(|end| self.bytes.get(at..end))
+            .filter(|_| size <= 8)
+            .ok_or_else(|| invalid(format!("Bad binary property list field at {:#x}", at)))?;
+        Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Where entry `index` of `size` bytes of a table at `start` is, an error if the file
+    /// can't hold it
+    fn entry(&self, start: usize, index: usize, size: usize) -> io::Result<usize> {
+        index
+            .checked_mul(size)
+            .and_then(|offset| start.checked_add(offset))
+            .ok_or_else(|| invalid(format!("Bad binary property list field at {:#x}", start)))
+    }
+
+    /// The length of the object with `marker` at `at`, and where its contents start
+    fn length(&self, marker: u8, at: usize) -> io::Result<(usize, usize)> {
+        if marker & 0xf != 0xf {
+            return Ok(((marker & 0xf) as usize, self.entry(at, 1, 1)?));
+        }
+        let int = self.uint(self.entry(at, 1, 1)?, 1)? as u8;
+        let size = 1 << (int & 0xf);
+        let start = self.entry(at, 2, 1)?;
+        let end = self.entry(start, 1, size)?;
+        Ok((self.uint(start, size)? as usize, end))
+    }
+
+    fn object(&self, index: usize, depth: usize) -> io::Result<Plist> {
+        if index >= self.count || depth > MAX_DEPTH {
+            return Err(invalid(format!(
+                "Bad binary property list object {}",
+                index
+            )));
+        }
+        let offset = self.entry(self.table, index, self.offset_size)?;
+        let at = self.uint(offset, self.offset_size)? as usize;
+        let marker = self.uint(at, 1)? as u8;
+        let data = |len: usize, start: usize| {
+            self.bytes
+                .get(start..start.saturating_add(len))
+                .ok_or_else(|| invalid(format!("Truncated object at {:#x}", at)))
+        };
+        let refs = |len: usize, start: usize| -> io::Result<Vec<usize>> {
+            (0..len)
+                .map(|i| {
+                    Ok(self.uint(self.entry(start, i, self.ref_size)?, self.ref_size)? as usize)
+                })
+                .collect()
+        };
+        Ok(match marker >> 4 {
+            0x0 => Plist::Bool(marker == 0x09),
+            0x1 => {
+                let size = 1 << (marker & 0xf);
+                let value = self.uint(self.entry(at, 1, 1)?, size)?;
+                // 8 byte integers are signed, the shorter ones not
+                Plist::Integer(value as i64)
+            }
+            0x2 | 0x3 => {
+                let size = 1 << (marker & 0xf);
+                let bits = self.uint(self.entry(at, 1, 1)?, size)?;
+                let value = match size {
+                    4 => f32::from_bits(bits as u32) as f64,
+                    _ => f64::from_bits(bits),
+                };
+                match marker >> 4 {
+                    0x2 => Plist::Real(value),
+                    _ => Plist::Date(value),
+                }
+            }
+            0x4 => {
+                let (len, start) = self.length(marker, at)?;
+                Plist::Data(data(len, start)?.to_vec())
+            }
+            0x5 => {
+                let (len, start) = self.length(marker, at)?;
+                Plist::String(String::from_utf8_lossy(data(len, start)?).to_string())
+            }
+            0x6 => {
+                let (len, start) = self.length(marker, at)?;
+                let units: Vec<u16> = data(self.entry(0, len, 2)?, start)?
+                    .chunks(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .collect();
+                Plist::String(String::from_utf16_lossy(&units))
+            }
+            0xa => {
+                let (len, start) = self.length(marker, at)?;
+                let items = refs(len, start)?
+                    .into_iter()
+                    .map(|item| self.object(item, depth + 1))
+                    .collect::<io::Result<_>>()?;
+                Plist::Array(items)
+            }
+            0xd => {
+                let (len, start) = self.length(marker, at)?;
+                let keys = refs(len, start)?;
+                let values = refs(len, self.entry(start, len, self.ref_size)?)?;
+                let mut dict = BTreeMap::new();
+                for (key, value) in keys.into_iter().zip(values) {
+                    let Plist::String(key) = self.object(key, depth + 1)? else {
+                        return Err(invalid(format!("Non-string key in the dict at {:#x}", at)));
+                    };
+                    dict.insert(key, self.object(value, depth + 1)?);
+                }
+                Plist::Dict(dict)
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "Unsupported binary property list object {:#04x} at {:#x}",
+                    marker, at
+                )))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_and_binary() {
+        let xml = br#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>CFBundleIdentifier</key>
+	<string>com.example.App</string>
+	<key>get-task-allow</key>
+	<true/>
+	<key>groups</key>
+	<array>
+		<string>a &amp; b</string>
+		<integer>-3</integer>
+	</array>
+	<key>blob</key>
+	<data>
+	AAEC
+	</data>
+	<key>empty</key>
+	<dict/>
+</dict>
+</plist>"#;
+        let plist = Plist::parse(xml).unwrap();
+        assert_eq!(
+            plist.get("CFBundleIdentifier").and_then(Plist::as_str),
+            Some("com.example.App")
+        );
+        assert_eq!(
+            plist.get("get-task-allow").and_then(Plist::as_bool),
+            Some(true)
+        );
+        assert_eq!(
+            plist.get("groups").and_then(Plist::as_array).unwrap(),
+            [Plist::String("a & b".to_string()), Plist::Integer(-3)]
+        );
+        assert_eq!(plist.get("blob"), Some(&Plist::Data(vec![0, 1, 2])));
+        assert_eq!(plist.get("empty"), Some(&Plist::Dict(BTreeMap::new())));
+
+        // {"id": "x", "n": [1]}
+        let mut binary = BPLIST_MAGIC.to_vec();
+        let objects: [&[u8]; 6] = [
+            &[0xd2, 1, 2, 3, 4],
+            b"\x52id",
+            b"\x51n",
+            b"\x51x",
+            &[0xa1, 5],
+            &[0x10, 1],
+        ];
+        let mut offsets = Vec::new();
+        for object in objects {
+            offsets.push(binary.len() as u8);
+            binary.extend(object);
+        }
+        let table = binary.len() as u64;
+        binary.extend(&offsets);
+        binary.extend([0, 0, 0, 0, 0, 0, 1, 1]);
+        binary.extend((objects.len() as u64).to_be_bytes());
+        binary.extend(0u64.to_be_bytes());
+        binary.extend(table.to_be_bytes());
+        let plist = Plist::parse(&binary).unwrap();
+        assert_eq!(plist.get("id").and_then(Plist::as_str), Some("x"));
+        assert_eq!(
+            plist.get("n").and_then(Plist::as_array).unwrap(),
+            [Plist::Integer(1)]
+        );
+        assert!(Plist::parse(&binary[..20]).is_err());
+
+        // a trailer putting the offset table at the end of the address space
+        let mut hostile = BPLIST_MAGIC.to_vec();
+        hostile.resize(46, 0);
+        hostile.extend([0, 0, 0, 0, 0, 0, 8, 1]);
+        for value in [2, 1, u64::MAX - 3] {
+            hostile.extend(value.to_be_bytes());
+        }
+        assert_eq!(hostile.len(), 78);
+        assert!(Plist::parse(&hostile).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Analyzers and loaders shipped as shared libraries.
+//!
+//! A plugin is a `cdylib` exporting a function `vivisect_plugin` that returns its
+//! [`PluginVTable`]. Everything crossing the boundary is `repr(C)`, so a plugin needn't be
+//! built with the same compiler as the host, or in Rust at all:
+//!
+//! ```text
+//! static VTABLE: PluginVTable = PluginVTable {
+//!     abi_version: PLUGIN_ABI_VERSION,
+//!     name: c"my-analyzer".as_ptr(),
+//!     analyze: Some(analyze),
+//!     load: None,
+//! };
+//!
+//! #[no_mangle]
+//! pub extern "C" fn vivisect_plugin() -> *const PluginVTable {
+//!     &VTABLE
+//! }
+//! ```
+//!
+//! The plugin reaches the workspace through the [`HostApi`] handed to its callbacks, a table of
+//! functions over the workspace being analyzed. Both tables start with [`PLUGIN_ABI_VERSION`];
+//! the host refuses plugins built for another version, and only ever adds callbacks at the end
+//! of [`HostApi`] within a version.
+//!
+//! [`discover`] loads every library of a plugin directory; a [`Plugin`] runs as an
+//! [`Analyzer`] pass, or offers to load files with [`Plugin::load_file`].
+
+use crate::{analysis::Analyzer, memory::Memory, workspace::VivWorkspace};
+use log::{debug, error, info, warn};
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fs,
+    path::Path,
+};
+
+/// The version of [`HostApi`] and [`PluginVTable`]
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// The symbol a plugin library exports, a `extern "C" fn() -> *const PluginVTable`
+pub const PLUGIN_ENTRY: &[u8] = b"vivisect_plugin";
+
+/// The workspace callbacks a plugin is given. `ctx` is passed back as the first argument of
+/// each; it is only valid for the duration of the plugin call it was handed to.
+///
+/// The functions of the workspace are listed once, as the callbacks are built, since a plugin
+/// walking them by index would otherwise have them listed and sorted on every call.
+#[repr(C)]
+pub struct HostApi {
+    pub abi_version: u32,
+    pub ctx: *mut c_void,
+    pub function_count: extern "C" fn(ctx: *mut c_void) -> usize,
+    /// The VA of function `index`, functions sorted by VA
+    pub function_at: extern "C" fn(ctx: *mut c_void, index: usize) -> i64,
+    /// Read up to `size` bytes at `va` into `buf`, returning how many were read
+    pub read_memory: extern "C" fn(ctx: *mut c_void, va: i64, buf: *mut u8, size: usize) -> usize,
+    /// Copy the name at `va` into `buf`, up to `size` bytes and without a NUL, returning its
+    /// full length, 0 if there is none
+    pub get_name: extern "C" fn(ctx: *mut c_void, va: i64, buf: *mut u8, size: usize) -> usize,
+    /// Name `va`, the name UTF-8 of `len` bytes. Returns false if it isn't UTF-8.
+    pub set_name: extern "C" fn(ctx: *mut c_void, va: i64, name: *const u8, len: usize) -> bool,
+    pub set_comment: extern "C" fn(ctx: *mut c_void, va: i64, text: *const u8, len: usize) -> bool,
+    pub add_entry_point: extern "C" fn(ctx: *mut c_void, va: i64),
+    pub add_xref: extern "C" fn(ctx: *mut c_void, from: i64, to: i64, rtype: i32),
+    /// Log through the host's logger, `level` 1 for errors to 4 for debug
+    pub log: extern "C" fn(ctx: *mut c_void, level: u32, msg: *const u8, len: usize),
+}
+
+/// What a plugin exports
+#[repr(C)]
+pub struct PluginVTable {
+    pub abi_version: u32,
+    /// NUL terminated, and alive as long as the library is loaded
+    pub name: *const c_char,
+    /// Run as an analysis pass, returning 0 on success
+    pub analyze: Option<extern "C" fn(host: *const HostApi) -> i32>,
+    /// Load a file into the workspace if the plugin knows its format, returning nonzero if it
+    /// did
+    pub load: Option<extern "C" fn(host: *const HostApi, bytes: *const u8, len: usize) -> i32>,
+}
+
+// A vtable is immutable static data, so a plugin can keep its own in a `static`
+unsafe impl Sync for PluginVTable {}
+
+/// What `ctx` points to: the workspace and its functions sorted by VA
+pub struct HostContext<'a> {
+    workspace: &'a mut VivWorkspace,
+    functions: Vec<i32>,
+}
+
+impl<'a> HostContext<'a> {
+    pub fn new(workspace: &'a mut VivWorkspace) -> Self {
+        let mut functions = workspace.get_functions();
+        functions.sort_unstable();
+        HostContext {
+            workspace,
+            functions,
+        }
+    }
+}
+
+fn context<'a>(ctx: *mut c_void) -> &'a mut HostContext<'a> {
+    // ctx is the context a HostApi was built over, alive for the duration of the call
+    unsafe { &mut *(ctx as *mut HostContext) }
+}
+
+fn workspace<'a>(ctx: *mut c_void) -> &'a mut VivWorkspace {
+    context(ctx).workspace
+}
+
+fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() || len == 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(ptr, len) }
+}
+
+fn text<'a>(ptr: *const u8, len: usize) -> Option<&'a str> {
+    std::str::from_utf8(bytes(ptr, len)).ok()
+}
+
+extern "C" fn host_function_count(ctx: *mut c_void) -> usize {
+    context(ctx).functions.len()
+}
+
+extern "C" fn host_function_at(ctx: *mut c_void, index: usize) -> i64 {
+    context(ctx)
+        .functions
+        .get(index)
+        .map_or(-1, |fva| *fva as i64)
+}
+
+extern "C" fn host_read_memory(ctx: *mut c_void, va: i64, buf: *mut u8, size: usize) -> usize {
+    if buf.is_null() {
+        return 0;
+    }
+    let Some(data) = workspace(ctx).read_memory(va as i32, size as i32) else {
+        return 0;
+    };
+    let len = data.len().min(size);
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf, len) };
+    len
+}
+
+extern "C" fn host_get_name(ctx: *mut c_void, va: i64, buf: *mut u8, size: usize) -> usize {
+    let Some(name) = workspace(ctx).get_name(va as i32, false) else {
+        return 0;
+    };
+    if !buf.is_null() {
+        let len = name.len().min(size);
+        unsafe { std::ptr::copy_nonoverlapping(name.as_ptr(), buf, len) };
+    }
+    name.len()
+}
+
+extern "C" fn host_set_name(ctx: *mut c_void, va: i64, name: *const u8, len: usize) -> bool {
+    let Some(name) = text(name, len) else {
+        return false;
+    };
+    workspace(ctx).make_name(va as i32, name.to_string(), false, true);
+    true
+}
+
+extern "C" fn host_set_comment(ctx: *mut c_void, va: i64, text_ptr: *const u8, len: usize) -> bool {
+    let Some(comment) = text(text_ptr, len) else {
+        return false;
+    };
+    workspace(ctx).set_comment(va as i32, comment, false);
+    true
+}
+
+extern "C" fn host_add_entry_point(ctx: *mut c_void, va: i64) {
+    workspace(ctx).add_entry_point(va as i32);
+}
+
+extern "C" fn host_add_xref(ctx: *mut c_void, from: i64, to: i64, rtype: i32) {
+    workspace(ctx).add_xref(from as i32, to as i32, rtype, 0);
+}
+
+extern "C" fn host_log(_ctx: *mut c_void, level: u32, msg: *const u8, len: usize) {
+    let msg = String::from_utf8_lossy(bytes(msg, len));
+    match level {
+        0 | 1 => error!("{}", msg),
+        2 => warn!("{}", msg),
+        3 => info!("{}", msg),
+        _ => debug!("{}", msg),
+    }
+}
+
+impl HostApi {
+    /// The callbacks over the workspace of `context`, valid as long as it is borrowed
+    pub fn new(context: &mut HostContext) -> Self {
+        HostApi {
+            abi_version: PLUGIN_ABI_VERSION,
+            ctx: context as *mut HostContext as *mut c_void,
+            function_count: host_function_count,
+            function_at: host_function_at,
+            read_memory: host_read_memory,
+            get_name: host_get_name,
+            set_name: host_set_name,
+            set_comment: host_set_comment,
+            add_entry_point: host_add_entry_point,
+            add_xref: host_add_xref,
+            log: host_log,
+        }
+    }
+}
+
+/// A loaded plugin
+pub struct Plugin {
+    name: String,
+    vtable: *const PluginVTable,
+    // unloaded last, after the vtable pointing into it is gone
+    #[allow(dead_code)]
+    library: Option<libloading::Library>,
+}
+
+// The vtable is immutable static data of the library, and the plugin is called with a workspace
+// of its own each time.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// A plugin from its vtable, for plugins linked into the host
+    ///
+    /// # Safety
+    ///
+    /// `vtable` must point to a [`PluginVTable`] that outlives the plugin, with a valid name
+    /// and callbacks following this module's ABI.
+    pub unsafe fn from_vtable(vtable: *const PluginVTable) -> Result<Self, String> {
+        Plugin::with_library(vtable, None)
+    }
+
+    unsafe fn with_library(
+        vtable: *const PluginVTable,
+        library: Option<libloading::Library>,
+    ) -> Result<Self, String> {
+        let table = vtable.as_ref().ok_or("The plugin has no vtable")?;
+        if table.abi_version != PLUGIN_ABI_VERSION {
+            return Err(format!(
+                "The plugin is built for ABI version {}, not {}",
+                table.abi_version, PLUGIN_ABI_VERSION
+            ));
+        }
+        if table.name.is_null() {
+            return Err("The plugin has no name".to_string());
+        }
+        let name = CStr::from_ptr(table.name).to_string_lossy().to_string();
+        Ok(Plugin {
+            name,
+            vtable,
+            library,
+        })
+    }
+
+    /// Load the plugin library at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let fail = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        // loading runs the library's initializers, which is what installing a plugin trusts
+        unsafe {
+            let library = libloading::Library::new(path).map_err(|err| fail(&err))?;
+            let entry = library
+                .get::<extern "C" fn() -> *const PluginVTable>(PLUGIN_ENTRY)
+                .map_err(|err| fail(&err))?;
+            let vtable = entry();
+            Plugin::with_library(vtable, Some(library)).map_err(|err| fail(&err))
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vtable(&self) -> &PluginVTable {
+        unsafe { &*self.vtable }
+    }
+
+    pub fn can_analyze(&self) -> bool {
+        self.vtable().analyze.is_some()
+    }
+
+    pub fn can_load(&self) -> bool {
+        self.vtable().load.is_some()
+    }
+
+    /// Run the plugin's analysis over the workspace
+    pub fn run(&self, workspace: &mut VivWorkspace) -> Result<(), String> {
+        let Some(analyze) = self.vtable().analyze else {
+            return Ok(());
+        };
+        let mut context = HostContext::new(workspace);
+        let host = HostApi::new(&mut context);
+        match analyze(&host) {
+            0 => Ok(()),
+            code => Err(format!("Plugin {} failed with {}", self.name, code)),
+        }
+    }
+
+    /// Offer the plugin a file to load into the workspace. Returns whether it did.
+    pub fn load_file(&self, workspace: &mut VivWorkspace, bytes: &[u8]) -> bool {
+        let Some(load) = self.vtable().load else {
+            return false;
+        };
+        let mut context = HostContext::new(workspace);
+        let host = HostApi::new(&mut context);
+        load(&host, bytes.as_ptr(), bytes.len()) != 0
+    }
+}
+
+impl Analyzer for Plugin {
+    fn analyze(&self, workspace: &mut VivWorkspace) {
+        if let Err(err) = self.run(workspace) {
+            warn!("{}", err);
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Load every plugin library of `dir`, by file name. Libraries that fail to load are logged
+/// and skipped, so one broken plugin doesn't take the others with it.
+pub fn discover<P: AsRef<Path>>(dir: P) -> Vec<Plugin> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == std::env::consts::DLL_EXTENSION)
+            })
+            .collect(),
+        Err(err) => {
+            warn!("Can't read plugin directory {}: {}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| match Plugin::load(&path) {
+            Ok(plugin) => {
+                info!("Loaded plugin {} from {}", plugin.name, path.display());
+                Some(plugin)
+            }
+            Err(err) => {
+                warn!("Skipping plugin {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{MM_READ, REF_CODE};
+
+    /// Names every function after its first byte, the way a plugin would through the host
+    extern "C" fn analyze(host: *const HostApi) -> i32 {
+        let host = unsafe { &*host };
+        for index in 0..(host.function_count)(host.ctx) {
+            let fva = (host.function_at)(host.ctx, index);
+            let mut byte = [0u8; 1];
+            if (host.read_memory)(host.ctx, fva, byte.as_mut_ptr(), 1) != 1 {
+                return 1;
+            }
+            let name = format!("starts_{:02x}", byte[0]);
+            (host.set_name)(host.ctx, fva, name.as_ptr(), name.len());
+            (host.add_xref)(host.ctx, fva, fva + 1, REF_CODE);
+        }
+        0
+    }
+
+    static VTABLE: PluginVTable = PluginVTable {
+        abi_version: PLUGIN_ABI_VERSION,
+        name: c"first-byte".as_ptr(),
+        analyze: Some(analyze),
+        load: None,
+    };
+
+    static OLD: PluginVTable = PluginVTable {
+        abi_version: 0,
+        name: c"old".as_ptr(),
+        analyze: None,
+        load: None,
+    };
+
+    #[test]
+    fn plugin_abi() {
+        let mut ann = crate::storage::Annotations::new();
+        ann.functions.extend([(0x1000, 2), (0x1002, 2)]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.apply_annotations(&ann);
+        ws.add_memory_map(0x1000, MM_READ, "test", vec![0x55, 0xc3, 0x90, 0xc3], None);
+
+        let plugin = unsafe { Plugin::from_vtable(&VTABLE) }.unwrap();
+        assert_eq!(plugin.name(), "first-byte");
+        assert!(plugin.can_analyze() && !plugin.can_load());
+        plugin.run(&mut ws).unwrap();
+        assert_eq!(ws.get_name(0x1000, false).as_deref(), Some("starts_55"));
+        assert_eq!(ws.get_name(0x1002, false).as_deref(), Some("starts_90"));
+        assert_eq!(ws.get_xrefs_from(0x1002, None).len(), 1);
+        assert!(!plugin.load_file(&mut ws, b"MZ"));
+
+        assert!(unsafe { Plugin::from_vtable(&OLD) }.is_err());
+        assert!(Plugin::load("/nonexistent/plugin.so").is_err());
+        assert!(discover("/nonexistent").is_empty());
+    }
+}
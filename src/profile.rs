@@ -0,0 +1,112 @@
+//! How much a workspace does with the files it loads.
+//!
+//! A tool listing the imports of a thousand binaries doesn't want stub linking, Objective-C
+//! metadata or the analysis passes; a reverse engineer wants all of them. The [`Profile`] of a
+//! workspace, kept in its `AnalysisProfile` meta so it is saved with it, says which run:
+//!
+//! * [`Profile::Parse`] loads the memory maps, imports, exports, symbols and relocations only;
+//! * [`Profile::Light`] also runs the passes a file gets as it loads, linking import
+//!   trampolines and reading the Objective-C metadata;
+//! * [`Profile::Full`], the default, also runs the analysis passes of `VivWorkspace::analyze`.
+//!
+//! The profile chooses what runs, not what is built: the analysis passes are compiled into any
+//! build with the workspace. Of the cargo features, `parse` leaves out the workspace and with it
+//! every pass, and `analysis` leaves out disk backed storage, fuzzy hashing and the Objective-C
+//! metadata that `full` adds.
+
+use std::{fmt, str::FromStr};
+
+/// The workspace meta holding the profile
+pub const META_PROFILE: &str = "AnalysisProfile";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Profile {
+    Parse,
+    Light,
+    #[default]
+    Full,
+}
+
+impl Profile {
+    /// Whether the passes run as a file loads do
+    pub fn runs_load_passes(&self) -> bool {
+        *self >= Profile::Light
+    }
+
+    /// Whether the analysis passes do
+    pub fn runs_analyzers(&self) -> bool {
+        *self == Profile::Full
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Profile::Parse => "parse",
+            Profile::Light => "light",
+            Profile::Full => "full",
+        })
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parse" => Ok(Profile::Parse),
+            "light" => Ok(Profile::Light),
+            "full" => Ok(Profile::Full),
+            _ => Err(format!("Unknown analysis profile: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::Analyzer,
+        pe::import::{ImportTable, ImportedFunction},
+        workspace::VivWorkspace,
+    };
+    use std::sync::Arc;
+
+    struct Pass;
+
+    impl Analyzer for Pass {
+        fn analyze(&self, _workspace: &mut VivWorkspace) {}
+
+        fn name(&self) -> &str {
+            "pass"
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        assert_eq!("light".parse(), Ok(Profile::Light));
+        assert!("fast".parse::<Profile>().is_err());
+        assert!(Profile::Light.runs_load_passes() && !Profile::Light.runs_analyzers());
+
+        let mut ws = VivWorkspace::new("", false);
+        assert_eq!(ws.get_profile(), Profile::Full);
+        ws.set_profile(Profile::Parse);
+        assert_eq!(ws.get_meta(META_PROFILE).as_deref(), Some("parse"));
+        let mut imports = ImportTable::default();
+        imports.add_function("kernel32.dll", ImportedFunction::by_name("ExitProcess"));
+        let (pe, _) = imports.tiny_image();
+        ws.load_from_bytes("tiny.exe", &pe, None);
+        assert_eq!(ws.get_imports().len(), 1);
+
+        let runs = |ws: &VivWorkspace| {
+            let stats = ws.get_analysis_stats();
+            stats.passes.get("pass").map_or(0, |pass| pass.runs)
+        };
+        ws.add_analyzer(Arc::new(Pass));
+        ws.run_analyzers();
+        assert_eq!(runs(&ws), 0);
+        ws.set_profile(Profile::Full);
+        ws.run_analyzers();
+        assert_eq!(runs(&ws), 1);
+    }
+}
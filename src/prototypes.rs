@@ -0,0 +1,365 @@
+//! What the calling convention leaves open about a prototype: variadic arguments and struct
+//! returns.
+//!
+//! [`crate::symbolic::call_argument`] says where argument `n` of a call is, which holds for a
+//! function taking a fixed number of integer arguments and returning in a register. Two cases
+//! move the arguments around:
+//!
+//! * a variadic function (`printf`) takes as many arguments as each call passes. On SysV amd64
+//!   a call to one sets `al` to how many vector registers it passes, which [`vector_count`]
+//!   reads; on i386 the caller pops the arguments it pushed, and calls popping different
+//!   amounts ([`caller_cleanup`]) pass different numbers of arguments. A callee popping its
+//!   own arguments (`ret 8`, [`callee_pops`]) takes a fixed number.
+//! * a function returning a struct too large for registers is passed a hidden pointer to
+//!   write it to (sret), as the first argument, or in `x8` on aarch64, and hands the pointer
+//!   back in the return register ([`struct_return`]). The visible arguments come after it.
+//!
+//! [`recover`] puts the two together for the functions and imports of a workspace, and
+//! [`Prototype::argument`] gives where an argument is with the hidden pointer skipped.
+
+use crate::{
+    constants::REF_CODE,
+    envi::{registers::RegisterModel, Arch},
+    symbolic::{call_argument, return_value, BinOp, Expr, Function, State, Stmt, Terminator},
+    syscalls::function_at,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The most a caller is taken to pop after a call; more is a frame teardown
+const MAX_CLEANUP: u64 = 0x400;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Prototype {
+    /// How many integer arguments come before any variadic ones, not counting the hidden
+    /// pointer of a struct return, where the stack cleanup tells
+    pub fixed_args: Option<usize>,
+    pub variadic: bool,
+    /// Whether the function returns a struct through a hidden pointer
+    pub struct_return: bool,
+    /// How many bytes of arguments the function pops on return (stdcall, thiscall)
+    pub callee_pops: Option<u64>,
+}
+
+impl Prototype {
+    /// Where the hidden pointer of a struct return is just before the call
+    pub fn hidden_pointer(&self, arch: Arch, windows: bool) -> Option<Expr> {
+        if !self.struct_return {
+            return None;
+        }
+        match arch {
+            Arch::A64 => RegisterModel::new(arch).by_name("x8").map(Expr::Reg),
+            _ => call_argument(arch, windows, 0),
+        }
+    }
+
+    /// Where integer argument `index` is just before the call, past the hidden pointer
+    pub fn argument(&self, arch: Arch, windows: bool, index: usize) -> Option<Expr> {
+        let hidden = self.struct_return && arch != Arch::A64;
+        call_argument(arch, windows, index + hidden as usize)
+    }
+}
+
+/// The block of `func` holding the instruction at `va`, with the instruction's index in it
+fn insn_at(func: &Function, va: u64) -> Option<(u64, usize)> {
+    let (start, block) = func
+        .blocks
+        .range(..=va)
+        .next_back()
+        .filter(|(_, block)| va <= block.end_va)?;
+    let index = block.insns.iter().position(|insn| insn.va == va)?;
+    Some((*start, index))
+}
+
+/// The value a SysV amd64 call at `call` leaves in `al`, the number of vector registers it
+/// passes, if the block of the call sets it. Only calls to variadic functions set it.
+pub fn vector_count(func: &Function, call: u64) -> Option<u8> {
+    if func.arch != Arch::Amd64 {
+        return None;
+    }
+    let regs = RegisterModel::new(func.arch);
+    let al = regs.by_name("al")?;
+    let (start, index) = insn_at(func, call)?;
+    let mut state = State::new(func.arch);
+    let mut count = None;
+    for insn in &func.blocks[&start].insns[..index] {
+        for stmt in &insn.stmts {
+            match stmt {
+                Stmt::Set(reg, value) if regs.overlaps(*reg, al) => {
+                    count = state.eval(value).as_const().map(|c| c as u8);
+                }
+                Stmt::Unknown => count = None,
+                _ => {}
+            }
+            state.exec(stmt);
+        }
+    }
+    count
+}
+
+/// How many bytes the i386 caller pops off the stack right after the call at `call`
+/// (`add esp, 8`), if it does
+pub fn caller_cleanup(func: &Function, call: u64) -> Option<u64> {
+    if func.arch != Arch::I386 {
+        return None;
+    }
+    let (start, index) = insn_at(func, call)?;
+    let block = &func.blocks[&start];
+    let next = match block.insns.get(index + 1) {
+        Some(insn) => insn,
+        None => match block.end {
+            Terminator::Jump(to) => func.blocks.get(&to)?.insns.first()?,
+            _ => return None,
+        },
+    };
+    let mut state = State::new(func.arch);
+    state.exec_insn(next);
+    state
+        .stack_offset()
+        .and_then(|offset| u64::try_from(offset).ok())
+        .filter(|popped| (1..=MAX_CLEANUP).contains(popped))
+}
+
+/// How many bytes of arguments an i386 function pops as it returns, from the stack offset
+/// its return blocks end at: the return address and the `n` of `ret n`. None if the returns
+/// don't agree or aren't known.
+pub fn callee_pops(func: &Function) -> Option<u64> {
+    if func.arch != Arch::I386 {
+        return None;
+    }
+    let offsets = func.stack_offsets();
+    let mut pops = func
+        .blocks
+        .values()
+        .filter(|block| block.end == Terminator::Return)
+        .map(|block| {
+            let offset = (*offsets.get(&block.va)?)?;
+            u64::try_from(offset - 4).ok()
+        });
+    let first = pops.next()??;
+    pops.all(|pops| pops == Some(first)).then_some(first)
+}
+
+/// Whether `addr` is `base` or `base` plus a constant
+fn based_on(addr: &Expr, base: &Expr) -> bool {
+    match addr {
+        Expr::Binary(BinOp::Add, a, c) => **a == *base && c.as_const().is_some(),
+        _ => addr == base,
+    }
+}
+
+/// Whether `func` returns a struct through a hidden pointer: it stores through the pointer,
+/// and, but on aarch64 where the pointer comes in `x8`, every return hands it back
+pub fn struct_return(func: &Function, windows: bool) -> bool {
+    let hidden = Prototype {
+        struct_return: true,
+        ..Default::default()
+    };
+    let (Some(pointer), Some(ret)) = (
+        hidden.hidden_pointer(func.arch, windows),
+        return_value(func.arch),
+    ) else {
+        return false;
+    };
+    if matches!(func.arch, Arch::ArmV7 | Arch::Thumb | Arch::Thumb16) {
+        // r0 is both the hidden pointer and the first argument, and nothing is handed back
+        return false;
+    }
+    let states = func.block_states();
+    let stores = states.values().any(|state| {
+        state
+            .stores()
+            .iter()
+            .any(|(addr, _)| based_on(addr, &pointer))
+    });
+    if func.arch == Arch::A64 {
+        return stores;
+    }
+    let mut returns = func
+        .blocks
+        .values()
+        .filter(|block| block.end == Terminator::Return)
+        .peekable();
+    stores
+        && returns.peek().is_some()
+        && returns.all(|block| {
+            states
+                .get(&block.va)
+                .is_some_and(|state| state.eval(&ret) == pointer)
+        })
+}
+
+/// Recover the prototypes of the lifted `functions` and of the imports of the workspace, by
+/// VA (the import slot for an import). Calls go by the workspace's xrefs; the amd64 ABI is
+/// the Windows one if `windows`.
+pub fn recover(
+    workspace: &VivWorkspace,
+    functions: &BTreeMap<u64, Function>,
+    windows: bool,
+) -> BTreeMap<u64, Prototype> {
+    let mut callees: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for fva in functions.keys() {
+        let calls = workspace
+            .get_xrefs_to(*fva as i32, Some(REF_CODE))
+            .into_iter()
+            .map(|xref| xref.0 as u32 as u64);
+        callees.entry(*fva).or_default().extend(calls);
+    }
+    for (slot, name) in workspace.get_imports() {
+        let name = name.split_once('.').map_or(name.as_str(), |(_, name)| name);
+        let calls = workspace.get_callers_of_import(name);
+        let known = callees.entry(slot as u32 as u64).or_default();
+        known.extend(calls.into_iter().map(|va| va as u32 as u64));
+    }
+
+    let mut prototypes = BTreeMap::new();
+    for (callee, calls) in callees {
+        let mut prototype = Prototype::default();
+        let mut cleanups = BTreeSet::new();
+        for call in calls {
+            let Some(caller) = function_at(functions, call) else {
+                continue;
+            };
+            if !windows && vector_count(caller, call).is_some() {
+                prototype.variadic = true;
+            }
+            cleanups.extend(caller_cleanup(caller, call));
+        }
+        if let Some(func) = functions.get(&callee) {
+            prototype.struct_return = struct_return(func, windows);
+            prototype.callee_pops = callee_pops(func);
+        }
+        let hidden = prototype.struct_return as usize;
+        match prototype.callee_pops {
+            Some(pops) if pops > 0 => {
+                // a callee can only pop what every call passes
+                prototype.variadic = false;
+                prototype.fixed_args = Some((pops as usize / 4).saturating_sub(hidden));
+            }
+            _ => {
+                if cleanups.len() > 1 {
+                    prototype.variadic = true;
+                }
+                if let Some(least) = cleanups.first() {
+                    prototype.fixed_args = Some((*least as usize / 4).saturating_sub(hidden));
+                }
+            }
+        }
+        prototypes.insert(callee, prototype);
+    }
+    prototypes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolic::{Block, Insn};
+
+    fn function(arch: Arch, insns: Vec<(u64, Vec<Stmt>)>) -> Function {
+        let entry = insns[0].0;
+        let end_va = insns.last().unwrap().0;
+        let mut func = Function::new(arch, entry);
+        func.add_block(Block {
+            va: entry,
+            insns: insns
+                .into_iter()
+                .map(|(va, stmts)| Insn { va, stmts })
+                .collect(),
+            end_va,
+            end: Terminator::Return,
+        });
+        func
+    }
+
+    #[test]
+    fn variadic_and_struct_returns() {
+        let regs = RegisterModel::new(Arch::Amd64);
+        let reg = |name| regs.by_name(name).unwrap();
+        let plus = |reg, c| Expr::binary(BinOp::Add, Expr::Reg(reg), Expr::Const(c), 64);
+        // printf("%d", 1) with `xor eax, eax`, then a struct returning call
+        let caller = function(
+            Arch::Amd64,
+            vec![
+                (0x1000, vec![Stmt::Set(reg("rdi"), Expr::Const(0x5000))]),
+                (0x1007, vec![Stmt::Set(reg("eax"), Expr::Const(0))]),
+                (0x1009, vec![Stmt::Unknown]),
+                (0x100e, vec![Stmt::Unknown]),
+            ],
+        );
+        assert_eq!(vector_count(&caller, 0x1009), Some(0));
+        assert_eq!(vector_count(&caller, 0x100e), None);
+        let make = function(
+            Arch::Amd64,
+            vec![
+                (
+                    0x2000,
+                    vec![Stmt::Store(Expr::Reg(reg("rdi")), Expr::Const(1))],
+                ),
+                (
+                    0x2003,
+                    vec![Stmt::Store(plus(reg("rdi"), 8), Expr::Const(2))],
+                ),
+                (0x2007, vec![Stmt::Set(reg("rax"), Expr::Reg(reg("rdi")))]),
+            ],
+        );
+        assert!(struct_return(&make, false));
+        assert!(!struct_return(&caller, false));
+
+        // an i386 stdcall function, `ret 8`, and two cdecl calls popping 8 and 12 bytes
+        let esp = RegisterModel::new(Arch::I386).sp();
+        let pop = |c| {
+            vec![Stmt::Set(
+                esp,
+                Expr::binary(BinOp::Add, Expr::Reg(esp), Expr::Const(c), 32),
+            )]
+        };
+        let stdcall = function(Arch::I386, vec![(0x4000, pop(12))]);
+        assert_eq!(callee_pops(&stdcall), Some(8));
+        let cdecl = function(
+            Arch::I386,
+            vec![
+                (0x5000, vec![Stmt::Unknown]),
+                (0x5005, pop(8)),
+                (0x5008, vec![Stmt::Unknown]),
+                (0x500d, pop(12)),
+                (0x5010, pop(4)),
+            ],
+        );
+        assert_eq!(caller_cleanup(&cdecl, 0x5000), Some(8));
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x3000, "libc", "printf");
+        ws.make_import(0x3008, "msvcrt", "sprintf");
+        ws.add_xref(0x1009, 0x3000, REF_CODE, 0);
+        ws.add_xref(0x100e, 0x2000, REF_CODE, 0);
+        ws.add_xref(0x5000, 0x3008, REF_CODE, 0);
+        ws.add_xref(0x5008, 0x3008, REF_CODE, 0);
+        let functions = BTreeMap::from([
+            (0x1000, caller),
+            (0x2000, make),
+            (0x4000, stdcall),
+            (0x5000, cdecl),
+        ]);
+        let prototypes = recover(&ws, &functions, false);
+        assert!(prototypes[&0x3000].variadic);
+        let sret = prototypes[&0x2000];
+        assert!(sret.struct_return && !sret.variadic);
+        assert_eq!(
+            sret.hidden_pointer(Arch::Amd64, false),
+            Some(Expr::Reg(reg("rdi")))
+        );
+        assert_eq!(
+            sret.argument(Arch::Amd64, false, 0),
+            Some(Expr::Reg(reg("rsi")))
+        );
+        assert_eq!(
+            prototypes[&0x3008],
+            Prototype {
+                fixed_args: Some(2),
+                variadic: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(prototypes[&0x4000].fixed_args, Some(2));
+    }
+}
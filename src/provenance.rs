@@ -0,0 +1,581 @@
+//! Toolchain provenance: what built a binary, from everything the toolchain leaves in it.
+//!
+//! * ELF `.comment` strings: `GCC: (GNU) 12.2.0`, `clang version 16.0.6`, `rustc version
+//!   1.70.0`, `Linker: LLD 16.0.6`,
+//! * ELF notes: gold's version, and the Go build ID in `.note.go.buildid`,
+//! * the Go build ID at the start of the text on other formats, and the Go runtime's build
+//!   information: the Go version, the main module, its dependencies and the build settings,
+//! * paths into the Rust standard library, which name the commit of the compiler,
+//! * the PE Rich header and the linker version of the optional header,
+//! * the tools of a Mach-O `LC_BUILD_VERSION`.
+//!
+//! [`provenance`] gathers them into one [`Provenance`] for a PE, ELF or Mach-O file.
+
+use crate::{
+    elf::{note, Elf},
+    error::{Error, Result},
+    mach::{load_command::CommandVariant, Mach, MachO},
+    pe::PE,
+    Object,
+};
+use std::fmt;
+
+const GO_BUILDINFO_MAGIC: &[u8] = b"\xff Go buildinf:";
+const GO_BUILDID_PREFIX: &[u8] = b"\xff Go build ID: \"";
+/// The note type of the Go build ID, under the name `Go`
+const NT_GO_BUILD_ID: u32 = 4;
+const RUSTC_PATH: &[u8] = b"/rustc/";
+const RICH_MAGIC: &[u8] = b"Rich";
+const DANS: u32 = 0x536e_6144;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// A string of the ELF `.comment` section
+    Comment,
+    /// An ELF note
+    Note,
+    /// The linker version of the PE optional header
+    OptionalHeader,
+    /// A tool of the Mach-O `LC_BUILD_VERSION`
+    BuildVersion,
+    /// The Go runtime's build information
+    GoBuildInfo,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Comment => ".comment",
+            Source::Note => "note",
+            Source::OptionalHeader => "optional header",
+            Source::BuildVersion => "LC_BUILD_VERSION",
+            Source::GoBuildInfo => "Go build info",
+        })
+    }
+}
+
+/// A compiler, linker or other tool which had a hand in the binary
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tool {
+    /// Lower case: `gcc`, `clang`, `rustc`, `go`, `lld`, `gold`, `ld`, `link`, `swift`
+    pub name: String,
+    pub version: Option<String>,
+    pub source: Source,
+    /// The string the tool was found in, as it is in the binary
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GoModule {
+    pub path: String,
+    pub version: String,
+    pub sum: Option<String>,
+    /// The module it was replaced by
+    pub replace: Option<Box<GoModule>>,
+}
+
+/// The build information of a Go binary, as `go version -m` shows it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GoBuildInfo {
+    /// `go1.21.0`; None for the pointer based layout before Go 1.18
+    pub version: Option<String>,
+    /// The package of the main function
+    pub path: Option<String>,
+    pub main: Option<GoModule>,
+    pub deps: Vec<GoModule>,
+    /// `-buildmode`, `CGO_ENABLED`, `vcs.revision` and the like
+    pub settings: Vec<(String, String)>,
+}
+
+/// An entry of the PE Rich header: a tool of the MSVC toolchain and how many objects it made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RichEntry {
+    pub product: u16,
+    pub build: u16,
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// `elf`, `pe` or `macho`
+    pub format: &'static str,
+    /// The GNU build ID, the CodeView GUID or the Mach-O UUID
+    pub build_id: Option<Vec<u8>>,
+    pub go_build_id: Option<String>,
+    pub go: Option<GoBuildInfo>,
+    /// The commit of the Rust compiler, from the paths of the standard library
+    pub rustc_commit: Option<String>,
+    pub rich: Vec<RichEntry>,
+    pub tools: Vec<Tool>,
+}
+
+impl Provenance {
+    /// The first tool of that name
+    pub fn tool(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.name == name)
+    }
+
+    fn add(&mut self, name: &str, version: Option<String>, source: Source, text: &str) {
+        let tool = Tool {
+            name: name.to_string(),
+            version,
+            source,
+            text: text.to_string(),
+        };
+        if !self.tools.contains(&tool) {
+            self.tools.push(tool);
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "format: {}", self.format)?;
+        if let Some(id) = &self.build_id {
+            let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(f, "build id: {}", hex)?;
+        }
+        if let Some(id) = &self.go_build_id {
+            writeln!(f, "go build id: {}", id)?;
+        }
+        if let Some(commit) = &self.rustc_commit {
+            writeln!(f, "rustc commit: {}", commit)?;
+        }
+        for tool in &self.tools {
+            let version = tool.version.as_deref().unwrap_or("?");
+            writeln!(f, "{} {} ({})", tool.name, version, tool.source)?;
+        }
+        if let Some(go) = &self.go {
+            for module in go.main.iter().chain(&go.deps) {
+                writeln!(f, "module {} {}", module.path, module.version)?;
+            }
+        }
+        for entry in &self.rich {
+            writeln!(
+                f,
+                "rich: product {:#x} build {} x{}",
+                entry.product, entry.build, entry.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The tool and version a `.comment` string names
+pub fn parse_comment(comment: &str) -> Option<(&'static str, Option<String>)> {
+    let word_after = |marker: &str| {
+        comment.find(marker).and_then(|at| {
+            comment[at + marker.len()..]
+                .split_whitespace()
+                .next()
+                .map(str::to_string)
+        })
+    };
+    if let Some(rest) = comment.strip_prefix("GCC: ") {
+        // the vendor's version comes in parentheses, GCC's own last
+        return Some(("gcc", rest.split_whitespace().last().map(str::to_string)));
+    }
+    if comment.contains("clang version ") {
+        return Some(("clang", word_after("clang version ")));
+    }
+    if comment.starts_with("rustc version ") {
+        return Some(("rustc", word_after("rustc version ")));
+    }
+    if let Some(rest) = comment.strip_prefix("Linker: ") {
+        let mut words = rest.split_whitespace();
+        let name = match words.next()? {
+            "LLD" => "lld",
+            "mold" => "mold",
+            _ => "ld",
+        };
+        return Some((name, words.next().map(str::to_string)));
+    }
+    None
+}
+
+/// The Go build ID between its quotes at the start of the text
+fn go_build_id(bytes: &[u8]) -> Option<String> {
+    let start = find(bytes, GO_BUILDID_PREFIX)? + GO_BUILDID_PREFIX.len();
+    let len = bytes[start..].iter().take(256).position(|b| *b == b'"')?;
+    Some(String::from_utf8_lossy(&bytes[start..start + len]).into_owned())
+}
+
+fn uvarint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn varint_string(bytes: &mut &[u8]) -> Option<String> {
+    let len = uvarint(bytes)? as usize;
+    let string = bytes.get(..len)?;
+    *bytes = &bytes[len..];
+    Some(String::from_utf8_lossy(string).into_owned())
+}
+
+/// The Go build information in `bytes`, from its magic on
+pub fn go_buildinfo(bytes: &[u8]) -> Option<GoBuildInfo> {
+    let at = find(bytes, GO_BUILDINFO_MAGIC)?;
+    let header = bytes.get(at..at + 32)?;
+    let mut info = GoBuildInfo::default();
+    // before Go 1.18 the strings are behind pointers into the data
+    if header[15] & 2 == 0 {
+        return Some(info);
+    }
+    let mut rest = &bytes[at + 32..];
+    info.version = Some(varint_string(&mut rest)?);
+    let mut modinfo = varint_string(&mut rest).unwrap_or_default();
+    // the module information is wrapped in 16 byte sentinels
+    if modinfo.len() >= 33 && modinfo.as_bytes()[modinfo.len() - 17] == b'\n' {
+        modinfo = String::from_utf8_lossy(&modinfo.as_bytes()[16..modinfo.len() - 16]).into_owned();
+    }
+    for line in modinfo.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let module = || GoModule {
+            path: fields.get(1).unwrap_or(&"").to_string(),
+            version: fields.get(2).unwrap_or(&"").to_string(),
+            sum: fields.get(3).map(|sum| sum.to_string()),
+            replace: None,
+        };
+        match fields[0] {
+            "path" => info.path = fields.get(1).map(|path| path.to_string()),
+            "mod" => info.main = Some(module()),
+            "dep" => info.deps.push(module()),
+            "=>" => {
+                if let Some(last) = info.deps.last_mut().or(info.main.as_mut()) {
+                    last.replace = Some(Box::new(module()));
+                }
+            }
+            "build" => {
+                if let Some((key, value)) = fields.get(1).and_then(|kv| kv.split_once('=')) {
+                    info.settings.push((key.to_string(), value.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+/// The commit of the Rust compiler in a `/rustc/<commit>/library/...` path
+fn rustc_commit(bytes: &[u8]) -> Option<String> {
+    let mut rest = bytes;
+    while let Some(at) = find(rest, RUSTC_PATH) {
+        rest = &rest[at + RUSTC_PATH.len()..];
+        let commit = rest.get(..41)?;
+        if commit[..40].iter().all(u8::is_ascii_hexdigit) && commit[40] == b'/' {
+            return Some(String::from_utf8_lossy(&commit[..40]).into_owned());
+        }
+    }
+    None
+}
+
+/// The entries of the Rich header in the DOS stub, before the PE header at `pe_offset`
+pub fn rich_header(bytes: &[u8], pe_offset: usize) -> Vec<RichEntry> {
+    let dword = |at: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+    };
+    let stub = &bytes[..pe_offset.min(bytes.len())];
+    let Some(rich) = stub
+        .windows(4)
+        .rposition(|window| window == RICH_MAGIC)
+        .filter(|at| at % 4 == 0)
+    else {
+        return Vec::new();
+    };
+    let Some(key) = dword(rich + 4) else {
+        return Vec::new();
+    };
+    let Some(dans) = (0x40..rich)
+        .step_by(4)
+        .find(|at| dword(*at).map(|word| word ^ key) == Some(DANS))
+    else {
+        return Vec::new();
+    };
+    // three zero dwords of padding follow the marker
+    (dans + 16..rich)
+        .step_by(8)
+        .filter_map(|at| {
+            let id = dword(at)? ^ key;
+            Some(RichEntry {
+                product: (id >> 16) as u16,
+                build: id as u16,
+                count: dword(at + 4)? ^ key,
+            })
+        })
+        .collect()
+}
+
+/// Gather the provenance of the PE, ELF or Mach-O file `bytes`
+pub fn provenance(bytes: &[u8]) -> Result<Provenance> {
+    let mut provenance = match Object::parse(bytes)? {
+        Object::Elf(elf) => elf_provenance(&elf, bytes),
+        Object::PE(pe) => pe_provenance(&pe, bytes),
+        Object::Mach(Mach::Binary(macho)) => macho_provenance(&macho, bytes),
+        Object::Mach(Mach::Fat(fat)) => {
+            let arches = fat.arches()?;
+            let arch = arches
+                .first()
+                .ok_or_else(|| Error::Malformed("Fat binary without architectures".into()))?;
+            return provenance(arch.slice(bytes));
+        }
+        _ => return Err(Error::Malformed("Not a PE, ELF or Mach-O file".into())),
+    };
+    if provenance.go_build_id.is_none() {
+        provenance.go_build_id = go_build_id(bytes);
+    }
+    if provenance.go.is_none() {
+        provenance.go = go_buildinfo(bytes);
+    }
+    if let Some(version) = provenance.go.as_ref().and_then(|go| go.version.clone()) {
+        provenance.add("go", Some(version.clone()), Source::GoBuildInfo, &version);
+    }
+    provenance.rustc_commit = rustc_commit(bytes);
+    Ok(provenance)
+}
+
+fn elf_provenance(elf: &Elf, bytes: &[u8]) -> Provenance {
+    let mut provenance = Provenance {
+        format: "elf",
+        build_id: elf.build_id(bytes).map(<[u8]>::to_vec),
+        ..Provenance::default()
+    };
+    let section = |name: &str| {
+        elf.section_headers
+            .iter()
+            .find(|shdr| elf.shdr_strtab.get_at(shdr.sh_name) == Some(name))
+            .and_then(|shdr| {
+                let start = shdr.sh_offset as usize;
+                bytes.get(start..start.checked_add(shdr.sh_size as usize)?)
+            })
+    };
+    if let Some(comment) = section(".comment") {
+        for text in comment.split(|b| *b == 0).filter(|text| !text.is_empty()) {
+            let text = String::from_utf8_lossy(text);
+            if let Some((name, version)) = parse_comment(&text) {
+                provenance.add(name, version, Source::Comment, &text);
+            }
+        }
+    }
+    let notes = elf
+        .iter_note_headers(bytes)
+        .into_iter()
+        .chain(elf.iter_note_sections(bytes, None))
+        .flatten()
+        // a note which can't be read is read again on every call, so stop at it
+        .map_while(Result::ok);
+    for note in notes {
+        let text = String::from_utf8_lossy(note.desc);
+        let text = text.trim_end_matches('\0');
+        match (note.name, note.n_type) {
+            ("GNU", note::NT_GNU_GOLD_VERSION) => {
+                let version = text.split_whitespace().last().map(str::to_string);
+                provenance.add("gold", version, Source::Note, text);
+            }
+            ("Go", NT_GO_BUILD_ID) => provenance.go_build_id = Some(text.to_string()),
+            _ => {}
+        }
+    }
+    if let Some(buildinfo) = section(".go.buildinfo") {
+        provenance.go = go_buildinfo(buildinfo);
+    }
+    provenance
+}
+
+fn pe_provenance(pe: &PE, bytes: &[u8]) -> Provenance {
+    let mut provenance = Provenance {
+        format: "pe",
+        build_id: pe.uuid().map(|id| id.to_vec()),
+        rich: rich_header(bytes, pe.header.dos_header.pe_pointer as usize),
+        ..Provenance::default()
+    };
+    if let Some(opt) = pe.header.optional_header {
+        let fields = opt.standard_fields;
+        let version = format!(
+            "{}.{}",
+            fields.major_linker_version, fields.minor_linker_version
+        );
+        let text = format!("linker version {}", version);
+        provenance.add("link", Some(version), Source::OptionalHeader, &text);
+    }
+    provenance
+}
+
+fn macho_provenance(macho: &MachO, bytes: &[u8]) -> Provenance {
+    let mut provenance = Provenance {
+        format: "macho",
+        build_id: macho.uuid().map(|id| id.to_vec()),
+        ..Provenance::default()
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let word: [u8; 4] = bytes.get(at..at + 4)?.try_into().ok()?;
+        Some(match macho.little_endian {
+            true => u32::from_le_bytes(word),
+            false => u32::from_be_bytes(word),
+        })
+    };
+    for lc in &macho.load_commands {
+        let CommandVariant::BuildVersion(build) = &lc.command else {
+            continue;
+        };
+        // build_tool_version entries follow the command
+        for i in 0..build.ntools as usize {
+            let at = lc.offset + 24 + i * 8;
+            let (Some(tool), Some(version)) = (u32_at(at), u32_at(at + 4)) else {
+                break;
+            };
+            let name = match tool {
+                1 => "clang",
+                2 => "swift",
+                3 => "ld",
+                4 => "lld",
+                _ => continue,
+            };
+            let version = format!(
+                "{}.{}.{}",
+                version >> 16,
+                (version >> 8) & 0xff,
+                version & 0xff
+            );
+            let text = format!("{} {}", name, version);
+            provenance.add(name, Some(version), Source::BuildVersion, &text);
+        }
+    }
+    if let Some(buildinfo) = macho.section_data("__DATA", "__go_buildinfo") {
+        provenance.go = go_buildinfo(buildinfo);
+    }
+    provenance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{Container, Ctx};
+    use crate::elf::{
+        header,
+        section_header::{self, SectionHeader},
+        Header,
+    };
+    use scroll::{ctx::IntoCtx, Pwrite, LE};
+
+    fn go_buildinfo_section() -> Vec<u8> {
+        let modinfo = "path\texample.com/cmd/tool\n\
+             mod\texample.com\t(devel)\t\n\
+             dep\tgolang.org/x/sys\tv0.10.0\th1:abc=\n\
+             =>\t../sys\t(devel)\t\n\
+             build\tCGO_ENABLED=0\n";
+        let mut wrapped = vec![b'0'; 16];
+        wrapped.extend(modinfo.as_bytes());
+        wrapped.extend([b'1'; 16]);
+        let mut section = GO_BUILDINFO_MAGIC.to_vec();
+        section.extend([8, 2]);
+        section.resize(32, 0);
+        section.push(8);
+        section.extend(b"go1.21.0");
+        // the module information is longer than 127 bytes, two bytes of varint
+        section.push(wrapped.len() as u8 | 0x80);
+        section.push((wrapped.len() >> 7) as u8);
+        section.extend(wrapped);
+        section
+    }
+
+    #[test]
+    fn elf_toolchains() {
+        assert_eq!(
+            parse_comment("GCC: (Ubuntu 11.4.0-1ubuntu1~22.04) 11.4.0"),
+            Some(("gcc", Some("11.4.0".to_string())))
+        );
+        assert_eq!(
+            parse_comment("Ubuntu clang version 14.0.0-1ubuntu1.1"),
+            Some(("clang", Some("14.0.0-1ubuntu1.1".to_string())))
+        );
+
+        let ctx = Ctx::new(Container::Big, LE);
+        let shstrtab = b"\0.comment\0.note.go.buildid\0.go.buildinfo\0.shstrtab\0";
+        let comment =
+            b"GCC: (GNU) 12.2.0\0rustc version 1.70.0 (90c541806 2023-05-31)\0Linker: LLD 16.0.6\0";
+        let mut note = Vec::new();
+        for word in [3u32, 8, NT_GO_BUILD_ID] {
+            note.extend(word.to_le_bytes());
+        }
+        note.extend(b"Go\0\0abc/def\0");
+        let mut out = vec![0u8; 64];
+        let mut shdrs = vec![SectionHeader::default()];
+        let rodata =
+            b"/rustc/90c541806f23a127002de5b4038be731ba1458ca/library/core/src/panicking.rs";
+        let buildinfo = go_buildinfo_section();
+        for (name, sh_type, data) in [
+            (1, section_header::SHT_PROGBITS, &comment[..]),
+            (10, section_header::SHT_NOTE, &note),
+            (27, section_header::SHT_PROGBITS, &buildinfo),
+            (0, section_header::SHT_PROGBITS, &rodata[..]),
+            (42, section_header::SHT_STRTAB, &shstrtab[..]),
+        ] {
+            shdrs.push(SectionHeader {
+                sh_name: name,
+                sh_type,
+                sh_offset: out.len() as u64,
+                sh_size: data.len() as u64,
+                sh_addralign: 1,
+                ..Default::default()
+            });
+            out.extend(data);
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        let mut header = Header::new(ctx);
+        header.e_type = header::ET_EXEC;
+        header.e_machine = header::EM_X86_64;
+        header.e_shoff = out.len() as u64;
+        header.e_shnum = shdrs.len() as u16;
+        header.e_shstrndx = shdrs.len() as u16 - 1;
+        header.into_ctx(&mut out, ctx);
+        let shoff = out.len();
+        out.resize(shoff + shdrs.len() * 64, 0);
+        for (i, shdr) in shdrs.into_iter().enumerate() {
+            out.pwrite_with(shdr, shoff + i * 64, ctx).unwrap();
+        }
+
+        let provenance = provenance(&out).unwrap();
+        assert_eq!(provenance.format, "elf");
+        let version = |name| {
+            provenance
+                .tool(name)
+                .and_then(|tool| tool.version.as_deref())
+        };
+        assert_eq!(version("gcc"), Some("12.2.0"));
+        assert_eq!(version("rustc"), Some("1.70.0"));
+        assert_eq!(version("lld"), Some("16.0.6"));
+        assert_eq!(version("go"), Some("go1.21.0"));
+        assert_eq!(provenance.go_build_id.as_deref(), Some("abc/def"));
+        assert_eq!(
+            provenance.rustc_commit.as_deref(),
+            Some("90c541806f23a127002de5b4038be731ba1458ca")
+        );
+        let go = provenance.go.as_ref().unwrap();
+        assert_eq!(go.path.as_deref(), Some("example.com/cmd/tool"));
+        assert_eq!(go.deps.len(), 1);
+        assert_eq!(go.deps[0].version, "v0.10.0");
+        assert_eq!(go.deps[0].replace.as_ref().unwrap().path, "../sys");
+        assert_eq!(go.settings, [("CGO_ENABLED".to_string(), "0".to_string())]);
+        assert!(provenance.to_string().contains("gcc 12.2.0 (.comment)"));
+
+        // a note whose name runs past the end of the file ends the notes
+        let at = out.windows(note.len()).position(|data| data == note).unwrap();
+        out[at..at + 4].copy_from_slice(&[0xff; 4]);
+        let provenance = super::provenance(&out).unwrap();
+        assert_eq!(provenance.go_build_id, None);
+        assert!(provenance.tool("gcc").is_some());
+    }
+}
@@ -0,0 +1,186 @@
+//! Imports rebuilt from the function pointers of a memory dump.
+//!
+//! Malware which resolves its APIs itself, walking the export tables of the loaded modules and
+//! matching names by hash, keeps the pointers in a table of its own data instead of an IAT. In a
+//! dump of the process loaded with the modules it ran with, each such pointer is the address of
+//! an export of another module of the workspace. [`find`] scans the data maps (the maps without
+//! execute permission) of the workspace for aligned pointers to exports of a module other than
+//! the one holding the pointer, and [`apply`] makes each slot a pseudo-import of
+//! [`PSEUDO_LIBRARY`] named for the export, so calls through the slot read as calls to the API.
+//! The export keeps its own `library.name` name; the slot gets a pointer xref to it and a
+//! `library!name` comment, and is recorded in the `PseudoImports` VA set.
+//!
+//! Slots which are imports already, such as the real IAT of a module, are left alone.
+
+use crate::{
+    constants::{MM_EXEC, REF_PTR},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The library pseudo-imports are made from
+pub const PSEUDO_LIBRARY: &str = "resolved";
+
+/// The VA set of the slots made pseudo-imports
+pub const VA_SET_PSEUDO_IMPORTS: &str = "PseudoImports";
+
+/// A slot holding the address of an export of another module
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PseudoImport {
+    pub slot: i32,
+    /// The export the slot points at
+    pub target: i32,
+    /// The module exporting it
+    pub library: String,
+    pub name: String,
+}
+
+/// The module and bare name of each export of the workspace, by address
+fn exports(workspace: &VivWorkspace) -> BTreeMap<i32, (String, String)> {
+    let mut exports = BTreeMap::new();
+    for (va, name) in workspace.get_exports() {
+        let export = match workspace.get_file_by_va(va) {
+            Some(fname) => {
+                let bare = name
+                    .strip_prefix(&format!("{}.", fname))
+                    .unwrap_or(&name)
+                    .to_string();
+                (fname, bare)
+            }
+            None => match name.split_once('.') {
+                Some((fname, bare)) => (fname.to_string(), bare.to_string()),
+                None => continue,
+            },
+        };
+        exports.insert(va, export);
+    }
+    exports
+}
+
+/// The slots in the data maps of the workspace pointing at exports of other modules
+pub fn find(workspace: &VivWorkspace) -> Vec<PseudoImport> {
+    let exports = exports(workspace);
+    if exports.is_empty() {
+        return Vec::new();
+    }
+    let size = workspace.get_pointer_size().max(1) as usize;
+    let big_endian = workspace.get_meta("bigend").as_deref() == Some("true");
+    let imports: BTreeSet<i32> = workspace
+        .get_imports()
+        .into_iter()
+        .map(|(va, _)| va)
+        .collect();
+    let mut slots = Vec::new();
+    for (map_va, perms, bytes) in workspace.get_maps() {
+        if perms & MM_EXEC != 0 {
+            continue;
+        }
+        for (i, chunk) in bytes.chunks_exact(size).enumerate() {
+            let mut word = [0u8; 8];
+            let value = if big_endian {
+                word[8 - size..].copy_from_slice(chunk);
+                u64::from_be_bytes(word)
+            } else {
+                word[..size].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            };
+            let slot = map_va.wrapping_add((i * size) as i32);
+            if value > u32::MAX as u64 || imports.contains(&slot) {
+                continue;
+            }
+            if let Some((library, name)) = exports.get(&(value as i32)) {
+                slots.push((slot, value as i32, library.clone(), name.clone()));
+            }
+        }
+    }
+    let mut found: Vec<PseudoImport> = slots
+        .into_iter()
+        .filter(|(slot, _, library, _)| {
+            workspace.get_file_by_va(*slot).as_ref() != Some(library)
+        })
+        .map(|(slot, target, library, name)| PseudoImport {
+            slot,
+            target,
+            library,
+            name,
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+/// Make each slot a pseudo-import of the export it points at
+pub fn apply(workspace: &mut VivWorkspace, imports: &[PseudoImport]) {
+    let mut slots = workspace
+        .get_va_set_rows(VA_SET_PSEUDO_IMPORTS)
+        .unwrap_or_default();
+    for import in imports {
+        workspace.make_import(import.slot, PSEUDO_LIBRARY, &import.name);
+        workspace.add_xref(import.slot, import.target, REF_PTR, 0);
+        let comment = format!("{}!{}", import.library, import.name);
+        workspace.set_comment(import.slot, &comment, true);
+        if !slots.contains(&import.slot) {
+            slots.push(import.slot);
+        }
+    }
+    workspace.set_va_set_row(VA_SET_PSEUDO_IMPORTS, slots);
+}
+
+/// Find the pseudo-imports of the workspace and make them, giving back how many there were
+pub fn rebuild(workspace: &mut VivWorkspace) -> usize {
+    let imports = find(workspace);
+    apply(workspace, &imports);
+    imports.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_READ, MM_WRITE, REF_CODE},
+        memory::Memory,
+    };
+
+    #[test]
+    fn rebuilds_hashed_imports() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_pointer_size(4);
+        // kernel32 at 0x7000_0000 exporting CreateFileW and Sleep
+        ws.add_memory_map(0x7000_0000, MM_READ | MM_EXEC, "k32", vec![0xc3; 0x100], None);
+        ws.add_segment(0x7000_0000, 0x100, ".text", "kernel32".to_string());
+        ws.add_export(0x7000_0010, "CreateFileW", "kernel32");
+        ws.add_export(0x7000_0020, "Sleep", "kernel32");
+        // the dumped image, with the pointers it resolved in its data
+        let mut data = vec![0u8; 0x20];
+        data[4..8].copy_from_slice(&0x7000_0020u32.to_le_bytes());
+        data[8..12].copy_from_slice(&0x7000_0010u32.to_le_bytes());
+        // an unaligned copy doesn't count
+        data[0x11..0x15].copy_from_slice(&0x7000_0020u32.to_le_bytes());
+        ws.add_memory_map(0x40_3000, MM_READ | MM_WRITE, "data", data, None);
+        ws.add_segment(0x40_3000, 0x20, ".data", "dump".to_string());
+        // call [0x403004]
+        ws.add_xref(0x40_1000, 0x40_3004, REF_CODE, 0);
+
+        let found = find(&ws);
+        let names: Vec<(i32, &str, &str)> = found
+            .iter()
+            .map(|i| (i.slot, i.library.as_str(), i.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0x40_3004, "kernel32", "Sleep"),
+                (0x40_3008, "kernel32", "CreateFileW")
+            ]
+        );
+        apply(&mut ws, &found);
+        assert_eq!(ws.get_comment(0x40_3004), "kernel32!Sleep");
+        assert_eq!(ws.get_callers_of_import("Sleep"), [0x40_1000]);
+        assert_eq!(
+            ws.get_va_set_rows(VA_SET_PSEUDO_IMPORTS),
+            Some(vec![0x40_3004, 0x40_3008])
+        );
+        // the slots are imports now, and aren't found again
+        assert!(find(&ws).is_empty());
+    }
+}
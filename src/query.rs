@@ -0,0 +1,692 @@
+//! A small query language over the entities of a workspace, for bulk filtering without a loop
+//! per question:
+//!
+//! ```text
+//! functions where size > 0x400 and calls 'memcpy' and not named
+//! strings where text ~ 'http' and xrefs > 0
+//! imports where name like '*Crypt*' or segment = '.idata'
+//! ```
+//!
+//! A query names a kind of entity, `functions`, `strings`, `imports`, `exports` or `names`,
+//! then optionally `where` and a condition of `and`, `or`, `not` and parentheses over:
+//!
+//! * numeric fields compared with `=`, `!=`, `<`, `<=`, `>` or `>=` against a decimal or `0x`
+//!   number: `va`, `size`, `xrefs` (references to it), `callers` (functions calling it), and
+//!   of functions `blocks` and `insns`,
+//! * text fields compared with `=`, `!=`, `~` (contains) or `like` (a glob of `*` and `?`)
+//!   against a quoted string: `name`, `segment`, and of strings `text`,
+//! * `named`, true of an entity named other than automatically,
+//! * `calls 'glob'`, true of a function calling a function or import of a matching name. An
+//!   import matches by its bare name as well as by `library.name`,
+//! * `tagged 'tag'`, true of an entity with the tag or one below it (see [`crate::tags`]),
+//! * `reaches 'tag'`, true of an entity which can end up calling something tagged so.
+//!
+//! [`Query::parse`] turns text into a [`Query`], which can as well be built from an [`Expr`],
+//! and [`Query::run`] gives the addresses of the matching entities, sorted. Scripts get the
+//! same through `ws.query(text)`.
+
+use crate::{
+    constants::{LOC_STRING, LOC_UNI, REF_CODE},
+    memory::Memory,
+    tags::{self, propagate, CallGraph, Rule},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entity {
+    Functions,
+    Strings,
+    Imports,
+    Exports,
+    Names,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Va,
+    Size,
+    Xrefs,
+    Callers,
+    Blocks,
+    Insns,
+    Name,
+    Segment,
+    Text,
+}
+
+impl Field {
+    fn from_word(word: &str) -> Option<Field> {
+        Some(match word {
+            "va" => Field::Va,
+            "size" => Field::Size,
+            "xrefs" => Field::Xrefs,
+            "callers" => Field::Callers,
+            "blocks" => Field::Blocks,
+            "insns" => Field::Insns,
+            "name" => Field::Name,
+            "segment" => Field::Segment,
+            "text" => Field::Text,
+            _ => return None,
+        })
+    }
+
+    fn is_text(self) -> bool {
+        matches!(self, Field::Name | Field::Segment | Field::Text)
+    }
+
+    fn applies_to(self, entity: Entity) -> bool {
+        match self {
+            Field::Blocks | Field::Insns => entity == Entity::Functions,
+            Field::Text => entity == Entity::Strings,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Match {
+    Eq,
+    Ne,
+    Contains,
+    Like,
+}
+
+/// A condition on an entity
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Number(Field, Cmp, i64),
+    Text(Field, Match, String),
+    Named,
+    /// Calls a function or import whose name matches the glob
+    Calls(String),
+    /// Has the tag or one below it
+    Tagged(String),
+    /// Can end up calling something with the tag, as [`crate::tags::Rule::capability`]
+    Reaches(String),
+}
+
+impl Expr {
+    fn uses_calls(&self) -> bool {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => a.uses_calls() || b.uses_calls(),
+            Expr::Not(a) => a.uses_calls(),
+            Expr::Calls(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The tags `reaches` asks about
+    fn reached_tags<'a>(&'a self, tags: &mut Vec<&'a str>) {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                a.reached_tags(tags);
+                b.reached_tags(tags);
+            }
+            Expr::Not(a) => a.reached_tags(tags),
+            Expr::Reaches(tag) => tags.push(tag),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    pub entity: Entity,
+    pub condition: Option<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Str(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Number(number) => write!(f, "{:#x}", number),
+            Token::Str(text) => write!(f, "{:?}", text),
+            Token::Op(op) => write!(f, "'{}'", op),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+/// The tokens of `text` with where each starts
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '\'' | '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => string.push(escaped),
+                            None => return Err(format!("Unterminated string at {}", at)),
+                        },
+                        Some((_, c)) => string.push(c),
+                        None => return Err(format!("Unterminated string at {}", at)),
+                    }
+                }
+                Token::Str(string)
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                let equals = chars.next_if(|(_, next)| *next == '=').is_some();
+                Token::Op(match (c, equals) {
+                    ('=', _) => "=",
+                    ('!', true) => "!=",
+                    ('<', false) => "<",
+                    ('<', true) => "<=",
+                    ('>', false) => ">",
+                    ('>', true) => ">=",
+                    ('~', false) => "~",
+                    _ => return Err(format!("Unknown operator at {}", at)),
+                })
+            }
+            c if c.is_ascii_digit() => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                }
+                let number = match word.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                Token::Number(number.map_err(|_| format!("Bad number '{}' at {}", word, at))?)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                Token::Word(word.to_lowercase())
+            }
+            c => return Err(format!("Unexpected '{}' at {}", c, at)),
+        };
+        tokens.push((at, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+    entity: Entity,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn at(&self) -> usize {
+        self.tokens.get(self.next).map_or(self.end, |(at, _)| *at)
+    }
+
+    fn take(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).map(|(_, token)| token.clone());
+        self.next += 1;
+        token
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Word(next)) if next == word) {
+            self.next += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expected(&self, what: &str) -> String {
+        match self.peek() {
+            Some(token) => format!("Expected {} at {}, found {}", what, self.at(), token),
+            None => format!("Expected {} at the end", what),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_word("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let expr = self.or()?;
+            if self.take() != Some(Token::Close) {
+                self.next -= 1;
+                return Err(self.expected("')'"));
+            }
+            return Ok(expr);
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr, String> {
+        let at = self.at();
+        let word = match self.take() {
+            Some(Token::Word(word)) => word,
+            _ => {
+                self.next -= 1;
+                return Err(self.expected("a condition"));
+            }
+        };
+        match word.as_str() {
+            "named" => return Ok(Expr::Named),
+            "tagged" | "reaches" | "calls" => {
+                if word == "calls" && self.entity != Entity::Functions {
+                    return Err(format!("Only functions call, at {}", at));
+                }
+                let Some(Token::Str(text)) = self.take() else {
+                    self.next -= 1;
+                    return Err(self.expected(&format!("a quoted string after '{}'", word)));
+                };
+                return Ok(match word.as_str() {
+                    "tagged" => Expr::Tagged(text),
+                    "reaches" => Expr::Reaches(text),
+                    _ => Expr::Calls(text),
+                });
+            }
+            _ => {}
+        }
+        let field = Field::from_word(&word)
+            .filter(|field| field.applies_to(self.entity))
+            .ok_or_else(|| format!("Unknown condition '{}' at {}", word, at))?;
+        let op = match self.take() {
+            Some(Token::Op(op)) => op,
+            Some(Token::Word(word)) if word == "like" => "like",
+            _ => {
+                self.next -= 1;
+                return Err(self.expected("a comparison"));
+            }
+        };
+        if field.is_text() {
+            let matching = match op {
+                "=" => Match::Eq,
+                "!=" => Match::Ne,
+                "~" => Match::Contains,
+                "like" => Match::Like,
+                _ => return Err(format!("'{}' doesn't compare text, at {}", op, at)),
+            };
+            match self.take() {
+                Some(Token::Str(text)) => Ok(Expr::Text(field, matching, text)),
+                _ => {
+                    self.next -= 1;
+                    Err(self.expected("a quoted string"))
+                }
+            }
+        } else {
+            let cmp = match op {
+                "=" => Cmp::Eq,
+                "!=" => Cmp::Ne,
+                "<" => Cmp::Lt,
+                "<=" => Cmp::Le,
+                ">" => Cmp::Gt,
+                ">=" => Cmp::Ge,
+                _ => return Err(format!("'{}' doesn't compare numbers, at {}", op, at)),
+            };
+            match self.take() {
+                Some(Token::Number(number)) => Ok(Expr::Number(field, cmp, number)),
+                _ => {
+                    self.next -= 1;
+                    Err(self.expected("a number"))
+                }
+            }
+        }
+    }
+}
+
+/// Whether `text` matches `glob`, where `*` is any run of characters and `?` any one
+pub fn glob_match(glob: &str, text: &str) -> bool {
+    let (glob, text): (Vec<char>, Vec<char>) = (glob.chars().collect(), text.chars().collect());
+    let (mut g, mut t) = (0, 0);
+    // where the last `*` was, and the text it has taken up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_g, star_t)) => {
+                    star = Some((star_g, star_t + 1));
+                    g = star_g + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// What evaluating a condition over one workspace needs, worked out once
+struct Context<'a> {
+    workspace: &'a VivWorkspace,
+    segments: Vec<(i32, i32, String, String)>,
+    /// The names each function calls
+    calls: HashMap<i32, HashSet<String>>,
+    /// What each tag `reaches` asks about reaches
+    reach: HashMap<String, HashSet<i32>>,
+}
+
+impl<'a> Context<'a> {
+    fn new(workspace: &'a VivWorkspace, condition: &Expr) -> Self {
+        let mut calls: HashMap<i32, HashSet<String>> = HashMap::new();
+        if condition.uses_calls() {
+            let stubs: HashMap<i32, i32> = workspace.get_import_stubs().into_iter().collect();
+            for (from, to, ..) in workspace.get_xrefs(Some(REF_CODE)) {
+                let Some(fva) = workspace.get_function(from) else {
+                    continue;
+                };
+                let to = stubs.get(&to).copied().unwrap_or(to);
+                if let Some(name) = workspace.get_name(to, false) {
+                    calls.entry(fva).or_default().insert(name);
+                }
+            }
+        }
+        let mut tags = Vec::new();
+        condition.reached_tags(&mut tags);
+        let mut reach = HashMap::new();
+        if !tags.is_empty() {
+            let graph = CallGraph::new(workspace);
+            let rules: Vec<Rule> = tags.iter().map(|tag| Rule::capability(tag)).collect();
+            let reached = propagate(workspace, &graph, &rules);
+            for tag in tags {
+                reach.insert(tag.to_string(), reached.reaching(tag).into_iter().collect());
+            }
+        }
+        Context {
+            workspace,
+            segments: workspace.get_segments(),
+            calls,
+            reach,
+        }
+    }
+
+    fn number(&self, entity: Entity, va: i32, field: Field) -> i64 {
+        let workspace = self.workspace;
+        let meta = |key: &str| {
+            workspace
+                .get_function_meta_dict(va)
+                .get(key)
+                .copied()
+                .unwrap_or(0) as i64
+        };
+        match field {
+            Field::Va => va as i64,
+            Field::Size if entity == Entity::Functions => match meta("Size") {
+                0 => workspace.get_function_bounds(va).map_or(0, |ranges| {
+                    ranges.iter().map(|(_, size)| *size as i64).sum()
+                }),
+                size => size,
+            },
+            Field::Size => workspace.get_location(va).map_or(0, |loc| loc.1 as i64),
+            Field::Xrefs => workspace.get_xrefs_to(va, None).len() as i64,
+            Field::Callers => workspace
+                .get_xrefs_to(va, Some(REF_CODE))
+                .into_iter()
+                .filter_map(|(from, ..)| workspace.get_function(from))
+                .collect::<HashSet<_>>()
+                .len() as i64,
+            Field::Blocks => meta("BlockCount"),
+            Field::Insns => meta("InstructionCount"),
+            Field::Name | Field::Segment | Field::Text => 0,
+        }
+    }
+
+    fn text(&self, va: i32, field: Field) -> Option<String> {
+        let workspace = self.workspace;
+        match field {
+            Field::Name => workspace.get_name(va, false),
+            Field::Segment => self
+                .segments
+                .iter()
+                .find(|(start, size, ..)| (*start..start + size).contains(&va))
+                .map(|(.., name, _)| name.clone()),
+            Field::Text => {
+                let (_, size, ltype, _) = workspace.get_location(va)?;
+                let bytes = workspace.read_memory(va, size)?;
+                match ltype {
+                    LOC_STRING => Some(
+                        String::from_utf8_lossy(&bytes)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    ),
+                    LOC_UNI => {
+                        let units: Vec<u16> = bytes
+                            .chunks_exact(2)
+                            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                            .take_while(|unit| *unit != 0)
+                            .collect();
+                        Some(String::from_utf16_lossy(&units))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn eval(&self, entity: Entity, va: i32, expr: &Expr) -> bool {
+        match expr {
+            Expr::And(a, b) => self.eval(entity, va, a) && self.eval(entity, va, b),
+            Expr::Or(a, b) => self.eval(entity, va, a) || self.eval(entity, va, b),
+            Expr::Not(a) => !self.eval(entity, va, a),
+            Expr::Number(field, cmp, value) => {
+                let number = self.number(entity, va, *field);
+                match cmp {
+                    Cmp::Eq => number == *value,
+                    Cmp::Ne => number != *value,
+                    Cmp::Lt => number < *value,
+                    Cmp::Le => number <= *value,
+                    Cmp::Gt => number > *value,
+                    Cmp::Ge => number >= *value,
+                }
+            }
+            Expr::Text(field, matching, value) => {
+                let text = self.text(va, *field);
+                let text = text.as_deref().unwrap_or_default();
+                match matching {
+                    Match::Eq => text == value,
+                    Match::Ne => text != value,
+                    Match::Contains => text.contains(value.as_str()),
+                    Match::Like => glob_match(value, text),
+                }
+            }
+            Expr::Named => {
+                self.workspace.get_name(va, false).is_some() && !self.workspace.is_auto_name(va)
+            }
+            Expr::Calls(glob) => self.calls.get(&va).is_some_and(|names| {
+                names.iter().any(|name| {
+                    glob_match(glob, name)
+                        || name
+                            .split_once('.')
+                            .is_some_and(|(_, bare)| glob_match(glob, bare))
+                })
+            }),
+            Expr::Tagged(tag) => self
+                .workspace
+                .get_tags(va)
+                .iter()
+                .any(|t| tags::is_under(t, tag)),
+            Expr::Reaches(tag) => self.reach.get(tag).is_some_and(|vas| vas.contains(&va)),
+        }
+    }
+}
+
+impl Query {
+    pub fn new(entity: Entity, condition: Option<Expr>) -> Self {
+        Query { entity, condition }
+    }
+
+    /// Parse a query. Errors say what was expected and where, as a byte offset.
+    pub fn parse(text: &str) -> Result<Query, String> {
+        let tokens = tokenize(text)?;
+        let entity = match tokens.first() {
+            Some((_, Token::Word(word))) => match word.as_str() {
+                "functions" => Entity::Functions,
+                "strings" => Entity::Strings,
+                "imports" => Entity::Imports,
+                "exports" => Entity::Exports,
+                "names" => Entity::Names,
+                _ => return Err(format!("Unknown entity '{}' at 0", word)),
+            },
+            _ => return Err("Expected functions, strings, imports, exports or names".into()),
+        };
+        let mut parser = Parser {
+            tokens,
+            next: 1,
+            end: text.len(),
+            entity,
+        };
+        let condition = if parser.eat_word("where") {
+            Some(parser.or()?)
+        } else {
+            None
+        };
+        if parser.peek().is_some() {
+            return Err(parser.expected("the end"));
+        }
+        Ok(Query { entity, condition })
+    }
+
+    /// The addresses of the entities of `workspace` the query matches, sorted
+    pub fn run(&self, workspace: &VivWorkspace) -> Vec<i32> {
+        let mut vas: Vec<i32> = match self.entity {
+            Entity::Functions => workspace.get_functions(),
+            Entity::Strings => [LOC_STRING, LOC_UNI]
+                .into_iter()
+                .flat_map(|ltype| workspace.get_locations(Some(ltype), None))
+                .map(|loc| loc.0)
+                .collect(),
+            Entity::Imports => workspace
+                .get_imports()
+                .into_iter()
+                .map(|(va, _)| va)
+                .collect(),
+            Entity::Exports => workspace
+                .get_exports()
+                .into_iter()
+                .map(|(va, _)| va)
+                .collect(),
+            Entity::Names => workspace
+                .get_names()
+                .into_iter()
+                .map(|(va, _)| va)
+                .collect(),
+        };
+        if let Some(condition) = &self.condition {
+            let context = Context::new(workspace, condition);
+            vas.retain(|va| context.eval(self.entity, *va, condition));
+        }
+        vas.sort_unstable();
+        vas.dedup();
+        vas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{MM_READ, REF_DATA};
+
+    #[test]
+    fn filter_functions_and_strings() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x3000, MM_READ, "test", b"http://x.io\0".to_vec(), None);
+        ws.add_segment(0x3000, 0x100, ".rdata", "test".to_string());
+        ws.make_import(0x2000, "msvcrt", "memcpy");
+        ws.add_function(0x1000, vec![(0x1000, 0x500)]);
+        ws.add_function(0x1600, vec![(0x1600, 0x10)]);
+        ws.add_function(0x1700, vec![(0x1700, 0x600)]);
+        ws.make_name(0x1700, "copy_all".to_string(), false, false);
+        ws.add_xref(0x1004, 0x2000, REF_CODE, 0);
+        ws.add_xref(0x1704, 0x2000, REF_CODE, 0);
+        ws.add_xref(0x1604, 0x1000, REF_CODE, 0);
+        ws.add_xref(0x1608, 0x3000, REF_DATA, 0);
+        ws.add_location(0x3000, 12, LOC_STRING, Some(vec![]));
+
+        let run = |text: &str| Query::parse(text).unwrap().run(&ws);
+        assert_eq!(
+            run("functions where size > 0x400 and calls 'memcpy' and not named"),
+            [0x1000]
+        );
+        assert_eq!(run("functions where calls 'msvcrt.mem*'"), [0x1000, 0x1700]);
+        assert_eq!(
+            run("functions where callers = 1 or name like 'copy_*'"),
+            [0x1000, 0x1700]
+        );
+        assert_eq!(
+            run("strings where text ~ 'http' and (xrefs >= 1 and segment = '.rdata')"),
+            [0x3000]
+        );
+        assert_eq!(run("names where named"), [0x1700, 0x2000]);
+        ws.add_tag(0x2000, "memory/copy");
+        let run = |text: &str| Query::parse(text).unwrap().run(&ws);
+        assert_eq!(run("imports where tagged 'memory'"), [0x2000]);
+        assert_eq!(
+            run("functions where reaches 'memory' and va < 0x1700"),
+            [0x1000, 0x1600]
+        );
+
+        assert_eq!(
+            Query::parse("functions where size >"),
+            Err("Expected a number at the end".to_string())
+        );
+        assert_eq!(
+            Query::parse("strings where blocks > 1"),
+            Err("Unknown condition 'blocks' at 14".to_string())
+        );
+        assert!(Query::parse("functions where (named").is_err());
+    }
+}
@@ -0,0 +1,421 @@
+//! Real mode x86: MBR and VBR boot sectors, DOS `MZ` executables and option ROMs.
+//!
+//! Real mode addresses memory as `segment:offset`, the linear address being `segment * 16 +
+//! offset`, wrapping at 20 bits. A [`SegAddr`] is such an address. The workspace keeps linear
+//! addresses, so [`load`] maps each image where the BIOS or DOS would put it in the first MiB:
+//! a boot sector at 0000:7C00, an option ROM at C000:0000, and a DOS program after its PSP at
+//! [`DOS_PSP_SEGMENT`], with the segment relocations of its header applied. Executables with a
+//! `PE` header are left to the PE loader; their DOS stub is not what runs.
+//!
+//! With the `realmode` feature, [`decode`] and [`disassemble`] decode 16 bit code with
+//! iced-x86. Branches and calls keep their segment: a near one stays in the segment of the
+//! instruction, a far one names its own, so the code reached from an entry is followed across
+//! segments as the CPU would.
+
+use crate::{
+    constants::{ARCH_I8086, MM_RWX},
+    memory::Memory,
+    workspace::VivWorkspace,
+};
+use std::{fmt, str::FromStr};
+
+/// Where the BIOS loads a boot sector
+pub const BOOT_SECTOR: SegAddr = SegAddr::new(0, 0x7c00);
+/// Where the first option ROM is mapped, the VGA BIOS on a PC
+pub const OPTION_ROM: SegAddr = SegAddr::new(0xc000, 0);
+/// The segment of the PSP DOS builds in front of a program; the program follows it
+pub const DOS_PSP_SEGMENT: u16 = 0x1000;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// A real mode `segment:offset` address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SegAddr {
+    pub seg: u16,
+    pub off: u16,
+}
+
+impl SegAddr {
+    pub const fn new(seg: u16, off: u16) -> Self {
+        SegAddr { seg, off }
+    }
+
+    /// The 20 bit linear address
+    pub fn linear(&self) -> u32 {
+        (((self.seg as u32) << 4) + self.off as u32) & 0xfffff
+    }
+
+    /// The address `delta` bytes on, wrapping within the segment as IP does
+    pub fn wrapping_add(&self, delta: u16) -> Self {
+        SegAddr::new(self.seg, self.off.wrapping_add(delta))
+    }
+}
+
+impl fmt::Display for SegAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.seg, self.off)
+    }
+}
+
+impl FromStr for SegAddr {
+    type Err = String;
+
+    /// Hex `segment:offset`, as in `0000:7C00`
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("Bad segment:offset address: {}", s);
+        let (seg, off) = s.split_once(':').ok_or_else(bad)?;
+        let seg = u16::from_str_radix(seg.trim(), 16).map_err(|_| bad())?;
+        let off = u16::from_str_radix(off.trim(), 16).map_err(|_| bad())?;
+        Ok(SegAddr::new(seg, off))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A master or volume boot record, a sector ending in `55 AA`
+    BootSector,
+    /// A DOS executable without a newer header
+    Mz,
+    /// An expansion ROM, starting `55 AA` and its size in 512 byte blocks
+    OptionRom,
+}
+
+impl Kind {
+    fn format(&self) -> &'static str {
+        match self {
+            Kind::BootSector => "mbr",
+            Kind::Mz => "mz",
+            Kind::OptionRom => "optionrom",
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// Which real mode image `bytes` is, if any
+pub fn classify(bytes: &[u8]) -> Option<Kind> {
+    if bytes.starts_with(&BOOT_SIGNATURE) && bytes.len() >= 3 {
+        let size = bytes[2] as usize * 512;
+        if size != 0 && size <= bytes.len() {
+            return Some(Kind::OptionRom);
+        }
+    }
+    if bytes.starts_with(b"MZ") || bytes.starts_with(b"ZM") {
+        return MzHeader::parse(bytes).map(|_| Kind::Mz);
+    }
+    if bytes.len() >= 512 && bytes.len().is_multiple_of(512) && bytes[510..512] == BOOT_SIGNATURE {
+        return Some(Kind::BootSector);
+    }
+    None
+}
+
+/// The header of a DOS executable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MzHeader {
+    /// Bytes used in the last 512 byte page, 0 for all of it
+    pub last_page: u16,
+    pub pages: u16,
+    pub relocations: u16,
+    /// The header size, in 16 byte paragraphs
+    pub header_paragraphs: u16,
+    pub ss: u16,
+    pub sp: u16,
+    pub ip: u16,
+    pub cs: u16,
+    /// The file offset of the relocation table
+    pub relocation_table: u16,
+}
+
+impl MzHeader {
+    /// The header of `bytes`, None if it isn't a DOS executable or has a PE header after it
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if !bytes.starts_with(b"MZ") && !bytes.starts_with(b"ZM") {
+            return None;
+        }
+        let header = MzHeader {
+            last_page: u16_at(bytes, 2)?,
+            pages: u16_at(bytes, 4)?,
+            relocations: u16_at(bytes, 6)?,
+            header_paragraphs: u16_at(bytes, 8)?,
+            ss: u16_at(bytes, 0xe)?,
+            sp: u16_at(bytes, 0x10)?,
+            ip: u16_at(bytes, 0x14)?,
+            cs: u16_at(bytes, 0x16)?,
+            relocation_table: u16_at(bytes, 0x18)?,
+        };
+        let pe = bytes
+            .get(0x3c..0x40)
+            .map(|at| u32::from_le_bytes(at.try_into().unwrap()) as usize)
+            .and_then(|at| bytes.get(at..at.checked_add(4)?));
+        if header.relocation_table >= 0x40 && pe == Some(b"PE\0\0") {
+            return None;
+        }
+        if header.header_paragraphs as usize * 16 > bytes.len() {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// The bytes of the load image: after the header, up to the size the header gives
+    pub fn image<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        let start = self.header_paragraphs as usize * 16;
+        let end = match self.last_page {
+            0 => self.pages as usize * 512,
+            last => (self.pages as usize).saturating_sub(1) * 512 + last as usize,
+        };
+        bytes
+            .get(start..end.clamp(start, bytes.len()))
+            .unwrap_or_default()
+    }
+
+    /// The words of the image holding a segment, to add the load segment to
+    pub fn relocation_entries(&self, bytes: &[u8]) -> Vec<SegAddr> {
+        (0..self.relocations as usize)
+            .map_while(|i| {
+                let at = self.relocation_table as usize + i * 4;
+                Some(SegAddr::new(u16_at(bytes, at + 2)?, u16_at(bytes, at)?))
+            })
+            .collect()
+    }
+}
+
+/// Load a real mode image into the workspace where it would run, and add its entry point.
+/// Returns the name of the file and the entry, or None if `bytes` is no real mode image.
+pub fn load(
+    workspace: &mut VivWorkspace,
+    filename: &str,
+    bytes: Vec<u8>,
+) -> Option<(String, SegAddr)> {
+    let kind = classify(&bytes)?;
+    let (base, image, entry) = match kind {
+        Kind::BootSector => (BOOT_SECTOR, bytes[..512].to_vec(), BOOT_SECTOR),
+        Kind::OptionRom => {
+            let size = bytes[2] as usize * 512;
+            // the initialization entry is a jump at offset 3
+            (
+                OPTION_ROM,
+                bytes[..size].to_vec(),
+                OPTION_ROM.wrapping_add(3),
+            )
+        }
+        Kind::Mz => {
+            let header = MzHeader::parse(&bytes)?;
+            let load = DOS_PSP_SEGMENT + 0x10;
+            let mut image = header.image(&bytes).to_vec();
+            for reloc in header.relocation_entries(&bytes) {
+                let at = reloc.linear() as usize;
+                if let Some(word) = image.get_mut(at..at + 2) {
+                    let seg = u16::from_le_bytes([word[0], word[1]]).wrapping_add(load);
+                    word.copy_from_slice(&seg.to_le_bytes());
+                }
+            }
+            let entry = SegAddr::new(header.cs.wrapping_add(load), header.ip);
+            (SegAddr::new(load, 0), image, entry)
+        }
+    };
+    let platform = match kind {
+        Kind::Mz => "dos",
+        _ => "bios",
+    };
+    workspace.set_meta("Architecture", Some(ARCH_I8086.to_string()));
+    workspace.set_meta("Platform", Some(platform.to_string()));
+    workspace.set_meta("Format", Some(kind.format().to_string()));
+    workspace.set_mem_architecture(ARCH_I8086 as u32);
+    workspace.set_pointer_size(2);
+    let va = base.linear() as i32;
+    let size = image.len() as i32;
+    let fname = workspace.add_file(filename, va, bytes.clone());
+    workspace.add_memory_map(va, MM_RWX, &fname, image, None);
+    workspace.add_segment(va, size, &base.to_string(), fname.clone());
+    workspace.add_entry_point(entry.linear() as i32);
+    Some((fname, entry))
+}
+
+#[cfg(feature = "realmode")]
+pub use decoding::*;
+
+#[cfg(feature = "realmode")]
+mod decoding {
+    use super::SegAddr;
+    use crate::{memory::Memory, workspace::VivWorkspace};
+    use iced_x86::{Decoder, DecoderOptions, FlowControl};
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Flow {
+        /// Goes on to the next instruction
+        Fall,
+        /// A call, to the target if it is direct
+        Call(Option<SegAddr>),
+        /// A jump, to the target if it is direct, which may not be taken if `conditional`
+        Branch {
+            target: Option<SegAddr>,
+            conditional: bool,
+        },
+        Return,
+        /// Stops here, as `ud2` does
+        Halt,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Insn16 {
+        pub at: SegAddr,
+        pub size: usize,
+        pub mnemonic: String,
+        pub flow: Flow,
+    }
+
+    /// Decode the 16 bit instruction at the start of `bytes`, which are at `at`
+    pub fn decode(bytes: &[u8], at: SegAddr) -> Option<Insn16> {
+        let mut decoder = Decoder::with_ip(16, bytes, at.off as u64, DecoderOptions::NONE);
+        let insn = decoder.decode();
+        if insn.is_invalid() {
+            return None;
+        }
+        let near = || Some(SegAddr::new(at.seg, insn.near_branch16()));
+        let far = || {
+            Some(SegAddr::new(
+                insn.far_branch_selector(),
+                insn.far_branch16(),
+            ))
+        };
+        let flow = match insn.flow_control() {
+            FlowControl::UnconditionalBranch if insn.is_jmp_far() => Flow::Branch {
+                target: far(),
+                conditional: false,
+            },
+            FlowControl::UnconditionalBranch => Flow::Branch {
+                target: near(),
+                conditional: false,
+            },
+            FlowControl::ConditionalBranch => Flow::Branch {
+                target: near(),
+                conditional: true,
+            },
+            FlowControl::Call if insn.is_call_far() => Flow::Call(far()),
+            FlowControl::Call => Flow::Call(near()),
+            FlowControl::IndirectBranch => Flow::Branch {
+                target: None,
+                conditional: false,
+            },
+            FlowControl::IndirectCall => Flow::Call(None),
+            FlowControl::Return => Flow::Return,
+            FlowControl::Exception => Flow::Halt,
+            _ => Flow::Fall,
+        };
+        Some(Insn16 {
+            at,
+            size: insn.len(),
+            mnemonic: format!("{:?}", insn.mnemonic()).to_lowercase(),
+            flow,
+        })
+    }
+
+    /// The instructions reached from `entry` in the workspace's memory, following branches
+    /// and calls, by linear address
+    pub fn disassemble(workspace: &VivWorkspace, entry: SegAddr) -> BTreeMap<u32, Insn16> {
+        let mut insns = BTreeMap::new();
+        let mut todo = vec![entry];
+        while let Some(at) = todo.pop() {
+            if insns.contains_key(&at.linear()) {
+                continue;
+            }
+            // up to the longest instruction, less at the end of a map
+            let va = at.linear() as i32;
+            let Some(insn) = (1..=15)
+                .rev()
+                .find_map(|len| workspace.read_memory(va, len))
+                .and_then(|bytes| decode(&bytes, at))
+            else {
+                continue;
+            };
+            let next = at.wrapping_add(insn.size as u16);
+            match insn.flow {
+                Flow::Fall | Flow::Call(None) => todo.push(next),
+                Flow::Call(Some(target))
+                | Flow::Branch {
+                    target: Some(target),
+                    conditional: true,
+                } => todo.extend([next, target]),
+                Flow::Branch {
+                    target: None,
+                    conditional: true,
+                } => todo.push(next),
+                Flow::Branch {
+                    target: Some(target),
+                    ..
+                } => todo.push(target),
+                Flow::Branch { target: None, .. } | Flow::Return | Flow::Halt => {}
+            }
+            insns.insert(at.linear(), insn);
+        }
+        insns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dos_and_boot_images() {
+        assert_eq!(SegAddr::new(0x1234, 0x10).linear(), 0x12350);
+        assert_eq!(SegAddr::new(0xffff, 0x10).linear(), 0);
+        assert_eq!("0000:7c00".parse(), Ok(BOOT_SECTOR));
+        assert_eq!(BOOT_SECTOR.to_string(), "0000:7C00");
+
+        // mov ax, seg; mov ds, ax; call 0xc; mov ah, 0x4c; int 0x21; ret
+        let code = [
+            0xb8, 0, 0, 0x8e, 0xd8, 0xe8, 4, 0, 0xb4, 0x4c, 0xcd, 0x21, 0xc3,
+        ];
+        let mut mz = b"MZ".to_vec();
+        let len = 0x20 + code.len() as u16;
+        for word in [len, 1, 1, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0, 1, 0] {
+            mz.extend(word.to_le_bytes());
+        }
+        mz.extend(&code);
+        assert_eq!(classify(&mz), Some(Kind::Mz));
+        let mut boot = vec![0xf4; 512];
+        boot[510..].copy_from_slice(&BOOT_SIGNATURE);
+        assert_eq!(classify(&boot), Some(Kind::BootSector));
+        let mut rom = vec![0; 1024];
+        rom[..3].copy_from_slice(&[0x55, 0xaa, 2]);
+        assert_eq!(classify(&rom), Some(Kind::OptionRom));
+        assert_eq!(classify(&boot[..400]), None);
+
+        let dir = std::env::temp_dir().join(format!("vivisect-realmode-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prog.exe");
+        std::fs::write(&path, &mz).unwrap();
+        let mut ws = VivWorkspace::new("", false);
+        let (_, entry) = load(&mut ws, &path.to_string_lossy(), mz).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entry, SegAddr::new(0x1010, 0));
+        assert_eq!(ws.get_entry_points(), [0x10100]);
+        assert_eq!(ws.get_meta("Format"), Some("mz".to_string()));
+        // the relocation put the load segment into the mov
+        assert_eq!(ws.read_memory(0x10101, 2), Some(vec![0x10, 0x10]));
+
+        #[cfg(feature = "realmode")]
+        {
+            let insns = disassemble(&ws, entry);
+            let offsets: Vec<u16> = insns.values().map(|insn| insn.at.off).collect();
+            assert_eq!(offsets, [0, 3, 5, 8, 0xa, 0xc]);
+            assert_eq!(
+                insns[&0x10105].flow,
+                Flow::Call(Some(SegAddr::new(0x1010, 0xc)))
+            );
+            assert_eq!(insns[&0x1010c].mnemonic, "ret");
+            let far = decode(&[0xea, 0x00, 0x7c, 0x00, 0x00], OPTION_ROM).unwrap();
+            assert_eq!(
+                far.flow,
+                Flow::Branch {
+                    target: Some(BOOT_SECTOR),
+                    conditional: false
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! What each register holds at an instruction, by a light abstract interpretation of the IL.
+//!
+//! [`analyze`] runs over the blocks of a lifted function to a fixed point, tracking each
+//! register as a constant, as an offset from the stack pointer at the entry, or as unknown, and
+//! keeps the registers before every instruction. Two paths disagreeing make a register
+//! unknown, so a register changes at most once and the analysis ends without widening.
+//!
+//! Memory isn't tracked: a load is unknown, and so is everything after a statement the lifter
+//! didn't model, calls included. `VivWorkspace::get_reg_state` caches the result per function
+//! and shows the constants which are the address of a name as a [`Value::Symbol`].
+
+use crate::{
+    envi::registers::{RegId, RegisterModel},
+    symbolic::{BinOp, Expr, Function, Stmt},
+};
+use std::{collections::BTreeMap, fmt};
+
+/// The abstract value of a register
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Const(u64),
+    /// The stack pointer at the function entry plus the offset
+    StackOffset(i64),
+    /// A constant which is the address of a name
+    Symbol { va: u64, name: String },
+    Unknown,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Const(c) => write!(f, "{:#x}", c),
+            Value::StackOffset(off) if *off < 0 => write!(f, "sp-{:#x}", off.unsigned_abs()),
+            Value::StackOffset(off) => write!(f, "sp+{:#x}", off),
+            Value::Symbol { va, name } => write!(f, "{} ({:#x})", name, va),
+            Value::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+/// The values of the registers; a register missing is unknown
+pub type RegState = BTreeMap<RegId, Value>;
+
+/// The registers before each instruction of `func` reached from its entry, by instruction VA
+pub fn analyze(func: &Function) -> BTreeMap<u64, RegState> {
+    let width = func.arch.pointer_size() as u32 * 8;
+    let mut envs: BTreeMap<u64, RegState> = BTreeMap::new();
+    let mut before: BTreeMap<u64, RegState> = BTreeMap::new();
+    if !func.blocks.contains_key(&func.entry) {
+        return before;
+    }
+    let sp = RegisterModel::new(func.arch).sp();
+    envs.insert(func.entry, RegState::from([(sp, Value::StackOffset(0))]));
+    let mut work = vec![func.entry];
+    while let Some(va) = work.pop() {
+        let mut env = envs[&va].clone();
+        for insn in &func.blocks[&va].insns {
+            before.insert(insn.va, env.clone());
+            for stmt in &insn.stmts {
+                exec(&mut env, stmt, width);
+            }
+        }
+        for succ in func.successors(va) {
+            if !func.blocks.contains_key(&succ) {
+                continue;
+            }
+            let merged = match envs.get(&succ) {
+                Some(old) => join(old, &env),
+                None => env.clone(),
+            };
+            if envs.get(&succ) == Some(&merged) {
+                continue;
+            }
+            envs.insert(succ, merged);
+            if !work.contains(&succ) {
+                work.push(succ);
+            }
+        }
+    }
+    before
+}
+
+pub(crate) fn exec(env: &mut RegState, stmt: &Stmt, width: u32) {
+    match stmt {
+        Stmt::Set(reg, value) => match eval(value, env, width) {
+            Value::Unknown => {
+                env.remove(reg);
+            }
+            value => {
+                env.insert(*reg, value);
+            }
+        },
+        Stmt::Store(..) | Stmt::Flags(..) => {}
+        Stmt::Unknown => env.clear(),
+    }
+}
+
+fn join(a: &RegState, b: &RegState) -> RegState {
+    a.iter()
+        .filter(|(reg, value)| b.get(reg) == Some(value))
+        .map(|(reg, value)| (*reg, value.clone()))
+        .collect()
+}
+
+/// The value of `expr`, its registers having the values of `env`
+pub(crate) fn eval(expr: &Expr, env: &RegState, width: u32) -> Value {
+    let signed = |c: u64| {
+        let shift = 64 - width;
+        ((c << shift) as i64) >> shift
+    };
+    let constant = |expr: Expr| expr.as_const().map_or(Value::Unknown, Value::Const);
+    // folded as the symbolic engine folds them, to the register width
+    let fold = |op, a, b| constant(Expr::binary(op, Expr::Const(a), Expr::Const(b), width));
+    match expr {
+        Expr::Const(c) => fold(BinOp::Add, *c, 0),
+        Expr::Reg(reg) => env.get(reg).cloned().unwrap_or(Value::Unknown),
+        Expr::Load(_) => Value::Unknown,
+        Expr::Unary(op, a) => match eval(a, env, width) {
+            Value::Const(c) => constant(Expr::unary(*op, Expr::Const(c), width)),
+            _ => Value::Unknown,
+        },
+        Expr::Binary(op, a, b) => match (op, eval(a, env, width), eval(b, env, width)) {
+            (_, Value::Const(x), Value::Const(y)) => fold(*op, x, y),
+            (BinOp::Add, Value::StackOffset(off), Value::Const(c))
+            | (BinOp::Add, Value::Const(c), Value::StackOffset(off)) => {
+                Value::StackOffset(off.wrapping_add(signed(c)))
+            }
+            (BinOp::Sub, Value::StackOffset(off), Value::Const(c)) => {
+                Value::StackOffset(off.wrapping_sub(signed(c)))
+            }
+            (BinOp::Sub, Value::StackOffset(x), Value::StackOffset(y)) => {
+                fold(BinOp::Add, x.wrapping_sub(y) as u64, 0)
+            }
+            _ => Value::Unknown,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::Arch,
+        symbolic::{Block, Insn, Terminator},
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn registers_at_instruction() {
+        let model = RegisterModel::new(Arch::I386);
+        let reg = |name| model.by_name(name).unwrap();
+        let (esp, ebp, eax, ecx) = (reg("esp"), reg("ebp"), reg("eax"), reg("ecx"));
+        let insn = |va, stmts| Insn { va, stmts };
+        let set = |reg, value| Stmt::Set(reg, value);
+        let sub = |a, c| Expr::binary(BinOp::Sub, a, Expr::Const(c), 32);
+
+        // push ebp; mov ebp, esp; mov eax, 0x2000; then ecx is 1 or 2 before the ret
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(
+                    0x1000,
+                    vec![
+                        set(esp, sub(Expr::Reg(esp), 4)),
+                        Stmt::Store(Expr::Reg(esp), Expr::Reg(ebp)),
+                    ],
+                ),
+                insn(0x1001, vec![set(ebp, Expr::Reg(esp))]),
+                insn(0x1003, vec![set(eax, Expr::Const(0x2000))]),
+            ],
+            end_va: 0x1008,
+            end: Terminator::Indirect(Expr::Reg(eax)),
+        });
+        func.add_targets(0x1000, vec![0x1010, 0x1020]);
+        for (va, value) in [(0x1010, 1), (0x1020, 2)] {
+            func.add_block(Block {
+                va,
+                insns: vec![insn(va, vec![set(ecx, Expr::Const(value))])],
+                end_va: va + 5,
+                end: Terminator::Jump(0x1030),
+            });
+        }
+        func.add_block(Block {
+            va: 0x1030,
+            insns: vec![insn(0x1030, vec![])],
+            end_va: 0x1030,
+            end: Terminator::Return,
+        });
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x1000, vec![(0x1000, 0x31)]);
+        ws.make_name(0x2000, "g_table".to_string(), false, false);
+        assert_eq!(ws.get_reg_state(0x1000), None);
+        ws.set_function_il(0x1000, func);
+
+        let state = ws.get_reg_state(0x1000).unwrap();
+        assert_eq!(state, RegState::from([(esp, Value::StackOffset(0))]));
+        assert_eq!(ws.get_reg_state(0x1010).unwrap()[&ebp], Value::StackOffset(-4));
+        let state = ws.get_reg_state(0x1030).unwrap();
+        assert_eq!(state[&esp].to_string(), "sp-0x4");
+        assert_eq!(
+            state[&eax],
+            Value::Symbol {
+                va: 0x2000,
+                name: "g_table".to_string()
+            }
+        );
+        assert_eq!(state.get(&ecx), None);
+        assert_eq!(ws.get_reg_state(0x1031), None);
+    }
+}
@@ -0,0 +1,432 @@
+//! Self-contained HTML reports of triage results.
+//!
+//! [`write_report`] writes a single HTML page, styles and script inline, for sharing what
+//! analysis found with people who won't run the tool: a summary of the files and of the
+//! workspace, the imports and exports, the strings, a table of every function with its
+//! [`metrics`](crate::metrics) (sorted by clicking a column, filtered by name) and an SVG of the
+//! control flow graph of each function asked for. [`most_complex`](metrics::most_complex) is a good place to pick
+//! those from.
+//!
+//! The CFGs are drawn from the code blocks of the workspace: a block goes to the blocks the code
+//! xrefs out of it point at, and to the block after it unless it ends in an instruction which
+//! doesn't fall through. Blocks are laid out in rows by their distance from the entry.
+
+use crate::{
+    constants::{BR_PROC, IF_NOFALL, LOC_OP, LOC_STRING, LOC_UNI, REF_CODE},
+    listing::{trim_nul, utf16},
+    memory::Memory,
+    metrics::{self, FunctionMetrics},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::{self, Write},
+};
+
+/// The longest string written in the strings table, in characters
+const STRING_LEN: usize = 120;
+const BLOCK_WIDTH: i32 = 110;
+const BLOCK_HEIGHT: i32 = 26;
+const BLOCK_GAP: i32 = 30;
+const ROW_GAP: i32 = 50;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:1em}\
+th,td{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+th{background:#eee;cursor:pointer}\
+td.n{text-align:right;font-family:monospace}\
+code,.va{font-family:monospace}\
+svg{border:1px solid #ccc;margin-bottom:1em}\
+svg text{font:11px monospace}";
+
+const SCRIPT: &str = "function sortBy(th){\
+var table=th.closest('table'),body=table.tBodies[0],i=th.cellIndex,\
+asc=th.dataset.asc!=='1';th.dataset.asc=asc?'1':'0';\
+var key=function(r){var t=r.cells[i].textContent;var n=Number(t);return isNaN(n)?t:n;};\
+Array.from(body.rows).sort(function(a,b){var x=key(a),y=key(b);\
+return (x<y?-1:x>y?1:0)*(asc?1:-1);}).forEach(function(r){body.appendChild(r);});}\
+function filterRows(input,id){var q=input.value.toLowerCase();\
+Array.from(document.getElementById(id).tBodies[0].rows).forEach(function(r){\
+r.style.display=r.textContent.toLowerCase().indexOf(q)<0?'none':'';});}";
+
+/// Escape text for HTML element content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Write the report of `workspace`, titled `title`, with the CFGs of the functions in `graphs`.
+pub fn write_report<W: Write>(
+    workspace: &VivWorkspace,
+    title: &str,
+    graphs: &[i32],
+    out: &mut W,
+) -> io::Result<()> {
+    let metrics = metrics::report(workspace);
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>{}</title>", escape(title))?;
+    writeln!(out, "<style>{}</style>", STYLE)?;
+    writeln!(out, "<script>{}</script>", SCRIPT)?;
+    writeln!(out, "</head><body>")?;
+    writeln!(out, "<h1>{}</h1>", escape(title))?;
+    summary(workspace, &metrics, out)?;
+    symbols(out, "Imports", "imports", &workspace.get_imports())?;
+    symbols(out, "Exports", "exports", &workspace.get_exports())?;
+    strings(workspace, out)?;
+    functions(&metrics, out)?;
+    if !graphs.is_empty() {
+        writeln!(out, "<h2>Control flow graphs</h2>")?;
+        for fva in graphs {
+            graph(workspace, *fva, out)?;
+        }
+    }
+    writeln!(out, "</body></html>")
+}
+
+fn va(va: i32) -> String {
+    format!("<span class=\"va\">{:#x}</span>", va as u32)
+}
+
+fn name(workspace: &VivWorkspace, fva: i32) -> String {
+    workspace
+        .get_name(fva, false)
+        .unwrap_or_else(|| format!("sub_{:x}", fva as u32))
+}
+
+fn filter<W: Write>(out: &mut W, id: &str) -> io::Result<()> {
+    writeln!(
+        out,
+        "<p><input placeholder=\"filter\" oninput=\"filterRows(this,'{}')\"></p>",
+        id
+    )
+}
+
+fn summary<W: Write>(
+    workspace: &VivWorkspace,
+    metrics: &[FunctionMetrics],
+    out: &mut W,
+) -> io::Result<()> {
+    writeln!(out, "<h2>Summary</h2>")?;
+    writeln!(out, "<table>")?;
+    for key in ["Architecture", "Format", "Platform"] {
+        if let Some(value) = workspace.get_meta(key) {
+            writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", key, escape(&value))?;
+        }
+    }
+    let strings = workspace.get_locations(Some(LOC_STRING), None).len()
+        + workspace.get_locations(Some(LOC_UNI), None).len();
+    let counts = [
+        ("Functions", metrics.len()),
+        ("Imports", workspace.get_imports().len()),
+        ("Exports", workspace.get_exports().len()),
+        ("Strings", strings),
+        ("Xrefs", workspace.get_xrefs(None).len()),
+    ];
+    for (key, count) in counts {
+        writeln!(
+            out,
+            "<tr><th>{}</th><td class=\"n\">{}</td></tr>",
+            key, count
+        )?;
+    }
+    writeln!(out, "</table>")?;
+    let files = workspace.get_files();
+    if files.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "<table>")?;
+    writeln!(
+        out,
+        "<tr><th>File</th><th>Image base</th><th>Module id</th></tr>"
+    )?;
+    for fname in files {
+        let id = workspace
+            .get_module_id(&fname)
+            .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td><code>{}</code></td></tr>",
+            escape(&fname),
+            va(workspace.get_file_meta(&fname, "imagebase")),
+            id
+        )?;
+    }
+    writeln!(out, "</table>")
+}
+
+fn symbols<W: Write>(
+    out: &mut W,
+    heading: &str,
+    id: &str,
+    symbols: &[(i32, String)],
+) -> io::Result<()> {
+    writeln!(out, "<h2>{}</h2>", heading)?;
+    if symbols.is_empty() {
+        return writeln!(out, "<p>None</p>");
+    }
+    filter(out, id)?;
+    writeln!(out, "<table id=\"{}\">", id)?;
+    writeln!(
+        out,
+        "<thead><tr><th onclick=\"sortBy(this)\">VA</th><th onclick=\"sortBy(this)\">Name</th></tr></thead><tbody>"
+    )?;
+    for (sva, sname) in symbols {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            va(*sva),
+            escape(sname)
+        )?;
+    }
+    writeln!(out, "</tbody></table>")
+}
+
+fn strings<W: Write>(workspace: &VivWorkspace, out: &mut W) -> io::Result<()> {
+    let mut locs = workspace.get_locations(Some(LOC_STRING), None);
+    locs.extend(workspace.get_locations(Some(LOC_UNI), None));
+    locs.sort_unstable();
+    writeln!(out, "<h2>Strings</h2>")?;
+    if locs.is_empty() {
+        return writeln!(out, "<p>None</p>");
+    }
+    filter(out, "strings")?;
+    writeln!(out, "<table id=\"strings\">")?;
+    writeln!(
+        out,
+        "<thead><tr><th onclick=\"sortBy(this)\">VA</th><th onclick=\"sortBy(this)\">Type</th><th onclick=\"sortBy(this)\">Xrefs</th><th onclick=\"sortBy(this)\">String</th></tr></thead><tbody>"
+    )?;
+    for (lva, size, ltype, _) in locs {
+        let Some(bytes) = workspace.read_memory(lva, size) else {
+            continue;
+        };
+        let (kind, text) = if ltype == LOC_UNI {
+            ("utf-16", utf16(&bytes))
+        } else {
+            (
+                "ascii",
+                String::from_utf8_lossy(trim_nul(&bytes)).into_owned(),
+            )
+        };
+        let mut text: String = text.chars().take(STRING_LEN).collect();
+        if text.chars().count() == STRING_LEN {
+            text.push('…');
+        }
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td class=\"n\">{}</td><td><code>{}</code></td></tr>",
+            va(lva),
+            kind,
+            workspace.get_xrefs_to(lva, None).len(),
+            escape(&text)
+        )?;
+    }
+    writeln!(out, "</tbody></table>")
+}
+
+fn functions<W: Write>(metrics: &[FunctionMetrics], out: &mut W) -> io::Result<()> {
+    writeln!(out, "<h2>Functions</h2>")?;
+    if metrics.is_empty() {
+        return writeln!(out, "<p>None</p>");
+    }
+    filter(out, "functions")?;
+    writeln!(out, "<table id=\"functions\"><thead><tr>")?;
+    for column in [
+        "VA",
+        "Name",
+        "Size",
+        "Blocks",
+        "Complexity",
+        "Fan in",
+        "Fan out",
+        "Strings",
+        "Constants",
+    ] {
+        write!(out, "<th onclick=\"sortBy(this)\">{}</th>", column)?;
+    }
+    writeln!(out, "</tr></thead><tbody>")?;
+    for m in metrics {
+        let fname = m
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("sub_{:x}", m.va as u32));
+        write!(out, "<tr><td>{}</td><td>{}</td>", va(m.va), escape(&fname))?;
+        for value in [
+            m.size as usize,
+            m.blocks,
+            m.complexity,
+            m.fan_in,
+            m.fan_out,
+            m.strings,
+            m.constants,
+        ] {
+            write!(out, "<td class=\"n\">{}</td>", value)?;
+        }
+        writeln!(out, "</tr>")?;
+    }
+    writeln!(out, "</tbody></table>")
+}
+
+/// The edges between the code blocks of a function
+pub fn block_edges(workspace: &VivWorkspace, fva: i32) -> Vec<(i32, i32)> {
+    let blocks = workspace.get_function_blocks(fva);
+    let starts: BTreeSet<i32> = blocks.iter().map(|(bva, ..)| *bva).collect();
+    let mut edges = BTreeSet::new();
+    for (bva, size, ..) in blocks.iter() {
+        let end = bva.wrapping_add(*size);
+        for from in *bva..end {
+            for (_, to, _, rflags) in workspace.get_xrefs_from(from, Some(REF_CODE)) {
+                if rflags & BR_PROC == 0 && starts.contains(&to) {
+                    edges.insert((*bva, to));
+                }
+            }
+        }
+        let falls = match workspace.get_location(end.wrapping_sub(1)) {
+            Some((_, _, LOC_OP, tinfo)) => tinfo
+                .first()
+                .is_none_or(|(iflags, _)| *iflags as u32 & IF_NOFALL == 0),
+            _ => true,
+        };
+        if falls && starts.contains(&end) {
+            edges.insert((*bva, end));
+        }
+    }
+    edges.into_iter().collect()
+}
+
+fn graph<W: Write>(workspace: &VivWorkspace, fva: i32, out: &mut W) -> io::Result<()> {
+    let blocks = workspace.get_function_blocks(fva);
+    let edges = block_edges(workspace, fva);
+    writeln!(
+        out,
+        "<h3>{} {}</h3>",
+        escape(&name(workspace, fva)),
+        va(fva)
+    )?;
+    if blocks.is_empty() {
+        return writeln!(out, "<p>No code blocks</p>");
+    }
+    // rows by distance from the entry, unreachable blocks in a last row of their own
+    let mut rank: BTreeMap<i32, i32> = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    if blocks.iter().any(|(bva, ..)| *bva == fva) {
+        rank.insert(fva, 0);
+        queue.push_back(fva);
+    }
+    while let Some(bva) = queue.pop_front() {
+        let next = rank[&bva] + 1;
+        for (_, to) in edges.iter().filter(|(from, _)| *from == bva) {
+            if !rank.contains_key(to) {
+                rank.insert(*to, next);
+                queue.push_back(*to);
+            }
+        }
+    }
+    let last = rank.values().max().map_or(0, |r| r + 1);
+    let mut rows: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+    for (bva, ..) in blocks.iter() {
+        rows.entry(*rank.get(bva).unwrap_or(&last))
+            .or_default()
+            .push(*bva);
+    }
+    let mut pos: BTreeMap<i32, (i32, i32)> = BTreeMap::new();
+    for (row, bvas) in rows.iter() {
+        for (col, bva) in bvas.iter().enumerate() {
+            let x = BLOCK_GAP / 2 + col as i32 * (BLOCK_WIDTH + BLOCK_GAP);
+            let y = BLOCK_GAP / 2 + row * (BLOCK_HEIGHT + ROW_GAP);
+            pos.insert(*bva, (x, y));
+        }
+    }
+    let columns = rows.values().map(Vec::len).max().unwrap_or(1) as i32;
+    let width = columns * (BLOCK_WIDTH + BLOCK_GAP);
+    let height = rows.len() as i32 * (BLOCK_HEIGHT + ROW_GAP);
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        width, height
+    )?;
+    writeln!(
+        out,
+        "<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"6\" markerHeight=\"6\" orient=\"auto\"><path d=\"M0,0L10,5L0,10z\"/></marker></defs>"
+    )?;
+    for (from, to) in edges.iter() {
+        let ((fx, fy), (tx, ty)) = (pos[from], pos[to]);
+        // edges back up the graph are loops
+        let color = if ty <= fy { "#c33" } else { "#555" };
+        writeln!(
+            out,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" marker-end=\"url(#arrow)\"/>",
+            fx + BLOCK_WIDTH / 2,
+            fy + BLOCK_HEIGHT,
+            tx + BLOCK_WIDTH / 2,
+            ty,
+            color
+        )?;
+    }
+    for (bva, size, ..) in blocks.iter() {
+        let (x, y) = pos[bva];
+        writeln!(
+            out,
+            "<g><title>{:#x}: {} bytes</title><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"#333\"/><text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{:#x}</text></g>",
+            *bva as u32,
+            size,
+            x,
+            y,
+            BLOCK_WIDTH,
+            BLOCK_HEIGHT,
+            if *bva == fva { "#def" } else { "#fff" },
+            x + BLOCK_WIDTH / 2,
+            y + BLOCK_HEIGHT / 2 + 4,
+            *bva as u32
+        )?;
+    }
+    writeln!(out, "</svg>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{BR_COND, MM_READ, MM_RWX};
+
+    #[test]
+    fn writes_reports() {
+        let mut ws = VivWorkspace::new("", false);
+        let fname = ws.add_file("Cargo.toml", 0x1000, vec![]);
+        let mut bytes = vec![0x90; 0x40];
+        bytes.extend(b"<b>&\0");
+        ws.add_memory_map(0x1000, MM_RWX, &fname, bytes, None);
+        ws.add_memory_map(0x2000, MM_READ, &fname, vec![0; 4], None);
+        ws.add_function(0x1000, vec![(0x1000, 0x20)]);
+        ws.add_code_block(0x1000, 0x10, 0x1000);
+        ws.add_code_block(0x1010, 0x8, 0x1000);
+        ws.add_code_block(0x1018, 0x8, 0x1000);
+        ws.add_xref(0x100e, 0x1018, REF_CODE, BR_COND);
+        ws.add_location(0x1040, 5, LOC_STRING, Some(vec![]));
+        ws.make_name(0x1000, "check<T>".to_string(), true, false);
+
+        assert_eq!(
+            block_edges(&ws, 0x1000),
+            [(0x1000, 0x1010), (0x1000, 0x1018), (0x1010, 0x1018)]
+        );
+        let mut html = Vec::new();
+        write_report(&ws, "triage & notes", &[0x1000], &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<title>triage &amp; notes</title>"));
+        assert!(html.contains("check&lt;T&gt;"));
+        assert!(html.contains("<code>&lt;b&gt;&amp;</code>"));
+        assert_eq!(html.matches("<rect").count(), 3);
+        assert_eq!(html.matches("<line").count(), 3);
+        assert!(html.ends_with("</body></html>\n"));
+    }
+}
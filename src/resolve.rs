@@ -0,0 +1,192 @@
+//! Address to symbol resolution across every module loaded in a workspace, for symbolicating
+//! logs and annotating stack traces with `function+0x42` style names. Addresses are 64 bit so
+//! the index also serves crash artifacts of 64 bit processes; workspace VAs are zero extended.
+
+use crate::intervals::IntervalIndex;
+use std::{fmt, ops::Range};
+
+/// A resolved address: the symbol it falls in and the offset into it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    pub name: String,
+    /// The VA of the symbol (or of the module image base, if no symbol precedes the address).
+    pub va: u64,
+    pub offset: u64,
+    /// The file the address belongs to.
+    pub module: String,
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.offset == 0 {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}+{:#x}", self.name, self.offset)
+        }
+    }
+}
+
+/// The module of a mapped range; symbols only resolve addresses within their own range.
+#[derive(Clone, Debug)]
+struct Module {
+    name: String,
+    imagebase: u64,
+}
+
+/// A sorted interval index over the functions, names and mapped ranges of the loaded modules.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolIndex {
+    /// The code of each function, with the function's va and name
+    functions: IntervalIndex<(u64, String)>,
+    /// Function code added since the last `finish`
+    pending: Vec<(Range<u64>, (u64, String))>,
+    /// (va, name) sorted by va
+    names: Vec<(u64, String)>,
+    ranges: IntervalIndex<Module>,
+}
+
+impl SymbolIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function of `size` bytes (0 when unknown, holding only `va`) at `va`.
+    pub fn add_function(&mut self, va: u64, size: u64, name: &str) {
+        self.add_function_chunk(va, va, size.max(1), name);
+    }
+
+    /// Add `size` bytes of code at `va` belonging to the function at `fva`, for functions
+    /// which aren't one contiguous range.
+    pub fn add_function_chunk(&mut self, fva: u64, va: u64, size: u64, name: &str) {
+        self.pending
+            .push((va..va.saturating_add(size), (fva, name.to_string())));
+    }
+
+    pub fn add_name(&mut self, va: u64, name: &str) {
+        self.names.push((va, name.to_string()));
+    }
+
+    /// Add a mapped range (e.g. a segment) of `module`, which is loaded at `imagebase`.
+    pub fn add_range(&mut self, va: u64, size: u64, module: &str, imagebase: u64) {
+        self.ranges.insert(
+            va..va.saturating_add(size),
+            Module {
+                name: module.to_string(),
+                imagebase,
+            },
+        );
+    }
+
+    /// Sort the index; must be called after adding entries and before looking anything up.
+    pub fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.functions = self
+                .functions
+                .iter()
+                .map(|(range, function)| (range, function.clone()))
+                .chain(pending)
+                .collect();
+        }
+        self.names.sort();
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len() + self.pending.len() + self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Resolve `va` to the function containing it, the innermost where functions nest. Failing
+    /// that, to the nearest preceding name in the same mapped range, and failing that to an
+    /// offset from the module image base. Addresses outside of every mapped range don't resolve.
+    pub fn lookup(&self, va: u64) -> Option<Resolved> {
+        let (range, module) = self.ranges.get(va)?;
+        let resolved = |name: &str, sva: u64| Resolved {
+            name: name.to_string(),
+            va: sva,
+            offset: va.wrapping_sub(sva),
+            module: module.name.clone(),
+        };
+
+        if let Some((_, (fva, name))) = self.functions.get(va) {
+            return Some(resolved(name, *fva));
+        }
+        let idx = self.names.partition_point(|(nva, _)| *nva <= va);
+        if let Some((nva, name)) = self.names[..idx].last() {
+            if *nva >= range.start {
+                return Some(resolved(name, *nva));
+            }
+        }
+        Some(resolved(&module.name, module.imagebase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> SymbolIndex {
+        let mut index = SymbolIndex::new();
+        index.add_range(0x1000, 0x1000, "app", 0);
+        index.add_range(0x2000, 0x1000, "app", 0);
+        index.add_range(0x7000, 0x800, "libc", 0x7000);
+        index.add_function(0x1100, 0x40, "parse_header");
+        index.add_name(0x1100, "parse_header");
+        index.add_name(0x1180, "jump_table");
+        index.add_name(0x2010, "g_config");
+        index.add_function(0x7100, 0x10, "memcpy");
+        index.finish();
+        index
+    }
+
+    #[test]
+    fn nearest_preceding() {
+        let index = index();
+        assert_eq!(index.lookup(0x1100).unwrap().to_string(), "parse_header");
+        assert_eq!(
+            index.lookup(0x1142).unwrap().to_string(),
+            "parse_header+0x42"
+        );
+        assert_eq!(
+            index.lookup(0x113f).unwrap().to_string(),
+            "parse_header+0x3f"
+        );
+        assert_eq!(index.lookup(0x1190).unwrap().to_string(), "jump_table+0x10");
+        let resolved = index.lookup(0x7108).unwrap();
+        assert_eq!(resolved.to_string(), "memcpy+0x8");
+        assert_eq!(resolved.module, "libc");
+    }
+
+    #[test]
+    fn module_boundaries() {
+        let index = index();
+        // names don't leak across mapped ranges or modules
+        assert_eq!(index.lookup(0x2004).unwrap().to_string(), "app+0x2004");
+        assert_eq!(index.lookup(0x7200).unwrap().to_string(), "libc+0x200");
+        assert_eq!(index.lookup(0x1080).unwrap().to_string(), "app+0x1080");
+        assert!(index.lookup(0x5000).is_none());
+        assert!(index.lookup(0x7800).is_none());
+    }
+
+    #[test]
+    fn function_chunks() {
+        let mut index = index();
+        index.add_range(0x5000, 0x1000, "app", 0);
+        index.add_function_chunk(0x1000, 0x1000, 0x10, "main");
+        index.add_function_chunk(0x1000, 0x5000, 0x10, "main");
+        index.add_function(0x1010, 0, "sub_1010");
+        index.finish();
+        assert_eq!(index.lookup(0x1004).unwrap().to_string(), "main+0x4");
+        assert_eq!(index.lookup(0x5004).unwrap().to_string(), "main+0x4004");
+        assert_eq!(index.lookup(0x1010).unwrap().to_string(), "sub_1010");
+        assert_eq!(index.lookup(0x5010).unwrap().to_string(), "app+0x5010");
+        // the functions from before this finish are kept
+        assert_eq!(
+            index.lookup(0x1108).unwrap().to_string(),
+            "parse_header+0x8"
+        );
+    }
+}
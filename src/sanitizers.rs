@@ -0,0 +1,352 @@
+//! Sanitizer builds: detecting the instrumentation and seeing through it.
+//!
+//! A binary built with `-fsanitize=address` checks every memory access against its shadow
+//! memory, and one built with `-fsanitize=undefined` checks every signed add and shift, so
+//! that the code of interest is scattered among the checks. [`detect`] finds the sanitizers a
+//! binary was built with, from the calls to their runtimes (`__asan_report_load4`,
+//! `__ubsan_handle_add_overflow`) and from the shadow addresses the lifted code computes
+//! (`(addr >> 3) + 0x7fff8000`).
+//!
+//! [`logical_cfg`] then rewrites a lifted function as if it were built without the checks: a
+//! branch to a block which only reports an error, and goes nowhere else but on, becomes a
+//! jump past it, and the blocks left unreachable (the reports and the slow paths of the checks)
+//! are removed.
+
+use crate::{
+    constants::REF_CODE,
+    symbolic::{BinOp, Expr, Function, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// The shadow offsets of ASan: x86-64 Linux, i386 Linux, AArch64 Linux, x86-64 macOS and
+/// i386 macOS
+pub const SHADOW_OFFSETS: [u64; 5] = [
+    0x7fff_8000,
+    0x2000_0000,
+    0x10_0000_0000,
+    0x1000_0000_0000,
+    0x4000_0000,
+];
+
+/// The shift from an address to its shadow
+const SHADOW_SCALE: u64 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Sanitizer {
+    Address,
+    Undefined,
+    Memory,
+    Thread,
+}
+
+impl fmt::Display for Sanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Sanitizer::Address => "asan",
+            Sanitizer::Undefined => "ubsan",
+            Sanitizer::Memory => "msan",
+            Sanitizer::Thread => "tsan",
+        })
+    }
+}
+
+/// The sanitizer whose runtime a function of `name` belongs to, with whether the function
+/// reports an error found by a check. Mach-O names keep their extra leading underscore.
+pub fn runtime(name: &str) -> Option<(Sanitizer, bool)> {
+    let name = name.trim_start_matches('_');
+    if let Some(rest) = name.strip_prefix("asan_") {
+        Some((Sanitizer::Address, rest.starts_with("report_")))
+    } else if let Some(rest) = name.strip_prefix("ubsan_") {
+        Some((Sanitizer::Undefined, rest.starts_with("handle_")))
+    } else if let Some(rest) = name.strip_prefix("msan_") {
+        Some((Sanitizer::Memory, rest.starts_with("warning")))
+    } else if name.starts_with("tsan_") {
+        Some((Sanitizer::Thread, false))
+    } else {
+        None
+    }
+}
+
+/// The shadow offset `addr` adds to an address scaled down by 8, if it is one of
+/// [`SHADOW_OFFSETS`]
+pub fn shadow_offset(addr: &Expr) -> Option<u64> {
+    let Expr::Binary(BinOp::Add | BinOp::Or, scaled, offset) = addr else {
+        return None;
+    };
+    let Expr::Binary(BinOp::Shr | BinOp::Sar, _, shift) = &**scaled else {
+        return None;
+    };
+    let offset = offset.as_const()?;
+    (shift.as_const() == Some(SHADOW_SCALE) && SHADOW_OFFSETS.contains(&offset)).then_some(offset)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Detection {
+    pub sanitizers: BTreeSet<Sanitizer>,
+    /// The functions of the runtimes called, with the calls
+    pub runtime: BTreeMap<String, Vec<u64>>,
+    /// The calls reporting an error found by a check
+    pub reports: BTreeSet<u64>,
+    /// The instructions loading from shadow memory, with the shadow offset
+    pub shadow: Vec<(u64, u64)>,
+}
+
+impl Detection {
+    pub fn is_sanitized(&self) -> bool {
+        !self.sanitizers.is_empty()
+    }
+}
+
+/// Find the sanitizers the workspace was built with, from the calls to their runtimes, be
+/// they imported or linked in, and from the shadow loads in the lifted `functions`
+pub fn detect(workspace: &VivWorkspace, functions: &BTreeMap<u64, Function>) -> Detection {
+    let mut detection = Detection::default();
+    let imports = workspace.get_imports();
+    let slots: BTreeSet<i32> = imports.iter().map(|(va, _)| *va).collect();
+    let strip = |name: &str| match name.split_once('.') {
+        Some((_, name)) => name.to_string(),
+        None => name.to_string(),
+    };
+    let mut found = |name: String, calls: Vec<u64>| {
+        let Some((sanitizer, report)) = runtime(&name) else {
+            return;
+        };
+        detection.sanitizers.insert(sanitizer);
+        if report {
+            detection.reports.extend(calls.iter().copied());
+        }
+        let known = detection.runtime.entry(name).or_default();
+        known.extend(calls);
+        known.sort_unstable();
+        known.dedup();
+    };
+    for (_, name) in imports.iter() {
+        let name = strip(name);
+        let calls = workspace.get_callers_of_import(&name);
+        found(name, calls.into_iter().map(|va| va as u32 as u64).collect());
+    }
+    for (va, name) in workspace.get_names() {
+        if slots.contains(&va) {
+            continue;
+        }
+        let calls = workspace
+            .get_xrefs_to(va, Some(REF_CODE))
+            .into_iter()
+            .map(|xref| xref.0 as u32 as u64)
+            .collect();
+        found(strip(&name), calls);
+    }
+
+    for func in functions.values() {
+        for block in func.blocks.values() {
+            let mut state = State::new(func.arch);
+            for insn in block.insns.iter() {
+                let mut addrs = Vec::new();
+                for stmt in insn.stmts.iter() {
+                    match stmt {
+                        Stmt::Set(_, value) => value.loads(&mut addrs),
+                        Stmt::Store(addr, value) | Stmt::Flags(_, addr, value) => {
+                            addr.loads(&mut addrs);
+                            value.loads(&mut addrs);
+                        }
+                        Stmt::Unknown => {}
+                    }
+                }
+                if let Some(offset) = addrs
+                    .iter()
+                    .find_map(|addr| shadow_offset(&state.eval(addr)))
+                {
+                    detection.shadow.push((insn.va, offset));
+                }
+                state.exec_insn(insn);
+            }
+        }
+    }
+    if !detection.shadow.is_empty() {
+        detection.sanitizers.insert(Sanitizer::Address);
+    }
+    detection
+}
+
+/// The blocks reachable from the entry of `func`
+fn reachable(func: &Function) -> BTreeSet<u64> {
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![func.entry];
+    while let Some(va) = todo.pop() {
+        if func.blocks.contains_key(&va) && seen.insert(va) {
+            todo.extend(func.successors(va));
+        }
+    }
+    seen
+}
+
+/// `func` without the checks of the sanitizers, whose reports are the calls of `reports`
+/// (see [`Detection::reports`]). A block is a check if it makes a report, or if it is only
+/// reached from one block and its own branch was a check, as the slow path of an ASan check
+/// is; a branch is a check if one way leads to a check which goes nowhere but the other way.
+pub fn logical_cfg(func: &Function, reports: &BTreeSet<u64>) -> Function {
+    let mut logical = func.clone();
+    let preds = func.predecessors();
+    let mut checks: BTreeSet<u64> = func
+        .blocks
+        .values()
+        .filter(|block| reports.range(block.va..=block.end_va).next().is_some())
+        .map(|block| block.va)
+        .collect();
+    let is_check = |checks: &BTreeSet<u64>, logical: &Function, side: u64, other: u64| {
+        checks.contains(&side)
+            && logical
+                .blocks
+                .get(&side)
+                .is_some_and(|block| block.end.successors().iter().all(|next| *next == other))
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let branches: Vec<(u64, u64, u64)> = logical
+            .blocks
+            .values()
+            .filter_map(|block| match block.end {
+                Terminator::Branch {
+                    taken, fallthrough, ..
+                } => Some((block.va, taken, fallthrough)),
+                _ => None,
+            })
+            .collect();
+        for (va, taken, fallthrough) in branches {
+            let on = if is_check(&checks, &logical, taken, fallthrough) {
+                fallthrough
+            } else if is_check(&checks, &logical, fallthrough, taken) {
+                taken
+            } else {
+                continue;
+            };
+            logical.blocks.get_mut(&va).unwrap().end = Terminator::Jump(on);
+            if preds.get(&va).is_some_and(|from| from.len() == 1) {
+                checks.insert(va);
+            }
+            changed = true;
+        }
+    }
+    let before = reachable(func);
+    let after = reachable(&logical);
+    for va in before.difference(&after) {
+        logical.blocks.remove(va);
+        logical.switches.remove(va);
+    }
+    logical
+}
+
+/// The lifted `functions` without the checks of the sanitizers `detection` found
+pub fn logical_functions(
+    functions: &BTreeMap<u64, Function>,
+    detection: &Detection,
+) -> BTreeMap<u64, Function> {
+    functions
+        .iter()
+        .map(|(va, func)| (*va, logical_cfg(func, &detection.reports)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::{flags::Condition, registers::RegisterModel, Arch},
+        symbolic::{Block, FlagOp, Insn},
+    };
+
+    #[test]
+    fn sees_through_asan() {
+        assert_eq!(
+            runtime("___asan_report_load4"),
+            Some((Sanitizer::Address, true))
+        );
+        assert_eq!(runtime("__asan_init"), Some((Sanitizer::Address, false)));
+        assert_eq!(runtime("memcpy"), None);
+
+        let regs = RegisterModel::new(Arch::Amd64);
+        let (rax, rdi) = (regs.by_name("rax").unwrap(), regs.by_name("rdi").unwrap());
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        let block = |va, insns, end_va, end| Block {
+            va,
+            insns,
+            end_va,
+            end,
+        };
+        let branch = |cond, taken, fallthrough| Terminator::Branch {
+            cond,
+            taken,
+            fallthrough,
+        };
+        // the fast path: is the shadow byte of rdi zero?
+        let shadow = Expr::binary(BinOp::Add, Expr::Reg(rax), Expr::Const(0x7fff_8000), 64);
+        func.add_block(block(
+            0x1000,
+            vec![
+                Insn {
+                    va: 0x1000,
+                    stmts: vec![Stmt::Set(
+                        rax,
+                        Expr::binary(BinOp::Shr, Expr::Reg(rdi), Expr::Const(3), 64),
+                    )],
+                },
+                Insn {
+                    va: 0x1004,
+                    stmts: vec![Stmt::Flags(
+                        FlagOp::Sub,
+                        Expr::Load(Box::new(shadow)),
+                        Expr::Const(0),
+                    )],
+                },
+            ],
+            0x100b,
+            branch(Condition::NotZero, 0x1020, 0x1010),
+        ));
+        // the access itself
+        func.add_block(block(
+            0x1010,
+            vec![Insn {
+                va: 0x1010,
+                stmts: vec![Stmt::Store(Expr::Reg(rdi), Expr::Const(0))],
+            }],
+            0x1016,
+            Terminator::Return,
+        ));
+        // the slow path, for a partly addressable granule, and the report
+        func.add_block(block(
+            0x1020,
+            Vec::new(),
+            0x1028,
+            branch(Condition::Less, 0x1010, 0x1030),
+        ));
+        func.add_block(block(0x1030, Vec::new(), 0x1030, Terminator::Return));
+        let functions = BTreeMap::from([(0x1000, func)]);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x3000, "libclang_rt", "__asan_report_store8");
+        ws.add_xref(0x1030, 0x3000, REF_CODE, 0);
+        let detection = detect(&ws, &functions);
+        assert!(detection.is_sanitized());
+        assert_eq!(detection.sanitizers, BTreeSet::from([Sanitizer::Address]));
+        assert_eq!(detection.reports, BTreeSet::from([0x1030]));
+        assert_eq!(detection.shadow, [(0x1004, 0x7fff_8000)]);
+
+        let logical = logical_functions(&functions, &detection);
+        let logical = &logical[&0x1000];
+        assert_eq!(
+            logical.blocks.keys().copied().collect::<Vec<_>>(),
+            [0x1000, 0x1010]
+        );
+        assert_eq!(logical.blocks[&0x1000].end, Terminator::Jump(0x1010));
+        // without the reports, nothing is taken for a check
+        assert_eq!(
+            &logical_cfg(&functions[&0x1000], &BTreeSet::new()),
+            &functions[&0x1000]
+        );
+    }
+}
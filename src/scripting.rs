@@ -0,0 +1,359 @@
+//! Rhai scripts over a workspace, and as analysis passes.
+//!
+//! The scripting feature embeds [rhai](https://rhai.rs) so analysis can be automated without a
+//! Rust toolchain, the way the Python original was driven from its shell. A script sees the
+//! workspace as `ws`:
+//!
+//! ```text
+//! for fva in ws.functions() {
+//!     if ws.name(fva) == () && ws.xrefs_to(fva).len() > 20 {
+//!         ws.set_name(fva, `hot_${fva}`);
+//!     }
+//! }
+//! ```
+//!
+//! * `functions()`, `name(va)`, `set_name(va, name)`, `comment(va)`, `set_comment(va, text)`
+//! * `read(va, size)` as a blob, `search(blob)` for the addresses of a byte pattern
+//! * `xrefs_to(va)` and `xrefs_from(va)` as arrays of addresses, `imports()` as a map of name
+//!   to address, `callers_of_import(name)`, `segments()` as maps of `va`, `size` and `name`
+//! * `query(text)`, the addresses a [`crate::query`] matches
+//! * `emulator()`, with `reg(name)` and `set_reg(name, value)` on its register file
+//!
+//! Addresses are integers. [`ScriptAnalyzer`] runs a script as a pass of an
+//! [`AnalysisModTracker`](crate::analysis::AnalysisModTracker).
+
+use crate::{
+    analysis::Analyzer, emulator::GenericEmulator, memory::Memory, workspace::VivWorkspace,
+};
+use log::warn;
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// The workspace as a script sees it. Clones share the workspace.
+#[derive(Clone)]
+pub struct ScriptWorkspace(Arc<Mutex<VivWorkspace>>);
+
+impl ScriptWorkspace {
+    fn lock(&self) -> MutexGuard<'_, VivWorkspace> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn functions(&mut self) -> Array {
+        let mut fvas = self.lock().get_functions();
+        fvas.sort_unstable();
+        fvas.into_iter()
+            .map(|fva| Dynamic::from(fva as INT))
+            .collect()
+    }
+
+    fn name(&mut self, va: INT) -> Dynamic {
+        self.lock()
+            .get_name(va as i32, false)
+            .map_or(Dynamic::UNIT, Dynamic::from)
+    }
+
+    fn set_name(&mut self, va: INT, name: &str) {
+        self.lock()
+            .make_name(va as i32, name.to_string(), false, true);
+    }
+
+    fn comment(&mut self, va: INT) -> Dynamic {
+        self.lock()
+            .get_comments()
+            .remove(&(va as i32))
+            .map_or(Dynamic::UNIT, Dynamic::from)
+    }
+
+    fn set_comment(&mut self, va: INT, text: &str) {
+        self.lock().set_comment(va as i32, text, false);
+    }
+
+    fn read(&mut self, va: INT, size: INT) -> Dynamic {
+        self.lock()
+            .read_memory(va as i32, size as i32)
+            .map_or(Dynamic::UNIT, Dynamic::from_blob)
+    }
+
+    fn search(&mut self, pattern: Blob) -> Array {
+        if pattern.is_empty() {
+            return Array::new();
+        }
+        let mut workspace = self.lock();
+        let mut hits = Vec::new();
+        for (va, size, ..) in workspace.get_memory_maps() {
+            let Some(bytes) = workspace.read_memory(va, size) else {
+                continue;
+            };
+            hits.extend(
+                bytes
+                    .windows(pattern.len())
+                    .enumerate()
+                    .filter(|(_, window)| *window == pattern.as_slice())
+                    .map(|(offset, _)| Dynamic::from(va as INT + offset as INT)),
+            );
+        }
+        hits
+    }
+
+    fn xrefs_to(&mut self, va: INT) -> Array {
+        self.lock()
+            .get_xrefs_to(va as i32, None)
+            .into_iter()
+            .map(|(from, ..)| Dynamic::from(from as INT))
+            .collect()
+    }
+
+    fn xrefs_from(&mut self, va: INT) -> Array {
+        self.lock()
+            .get_xrefs_from(va as i32, None)
+            .into_iter()
+            .map(|(_, to, ..)| Dynamic::from(to as INT))
+            .collect()
+    }
+
+    fn imports(&mut self) -> Map {
+        self.lock()
+            .get_imports()
+            .into_iter()
+            .map(|(va, name)| (name.into(), Dynamic::from(va as INT)))
+            .collect()
+    }
+
+    fn callers_of_import(&mut self, name: &str) -> Array {
+        self.lock()
+            .get_callers_of_import(name)
+            .into_iter()
+            .map(|va| Dynamic::from(va as INT))
+            .collect()
+    }
+
+    fn segments(&mut self) -> Array {
+        self.lock()
+            .get_segments()
+            .into_iter()
+            .map(|(va, size, name, _)| {
+                let mut segment = Map::new();
+                segment.insert("va".into(), Dynamic::from(va as INT));
+                segment.insert("size".into(), Dynamic::from(size as INT));
+                segment.insert("name".into(), Dynamic::from(name));
+                Dynamic::from_map(segment)
+            })
+            .collect()
+    }
+
+    fn query(&mut self, text: &str) -> Result<Array, Box<EvalAltResult>> {
+        let query = crate::query::Query::parse(text)?;
+        Ok(query
+            .run(&self.lock())
+            .into_iter()
+            .map(|va| Dynamic::from(va as INT))
+            .collect())
+    }
+
+    fn emulator(&mut self) -> ScriptEmulator {
+        ScriptEmulator(Arc::new(Mutex::new(GenericEmulator::new(
+            self.lock().clone(),
+        ))))
+    }
+}
+
+/// An emulator of the workspace as a script sees it
+#[derive(Clone)]
+pub struct ScriptEmulator(Arc<Mutex<GenericEmulator>>);
+
+impl ScriptEmulator {
+    fn lock(&self) -> MutexGuard<'_, GenericEmulator> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn reg(&mut self, name: &str) -> Dynamic {
+        self.lock()
+            .registers()
+            .and_then(|regs| regs.get_by_name(name))
+            .map_or(Dynamic::UNIT, |value| Dynamic::from(value as INT))
+    }
+
+    fn set_reg(&mut self, name: &str, value: INT) -> bool {
+        let mut emulator = self.lock();
+        let Some(regs) = emulator.registers_mut() else {
+            return false;
+        };
+        match regs.model().by_name(name) {
+            Some(reg) => {
+                regs.set(reg, value as u64 as u128);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A rhai engine with the workspace API registered
+pub struct ScriptEngine {
+    engine: Engine,
+}
+
+impl Default for ScriptEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptEngine {
+    pub fn new() -> Self {
+        let mut engine = Engine::new();
+        engine
+            .register_type_with_name::<ScriptWorkspace>("Workspace")
+            .register_fn("functions", ScriptWorkspace::functions)
+            .register_fn("name", ScriptWorkspace::name)
+            .register_fn("set_name", ScriptWorkspace::set_name)
+            .register_fn("comment", ScriptWorkspace::comment)
+            .register_fn("set_comment", ScriptWorkspace::set_comment)
+            .register_fn("read", ScriptWorkspace::read)
+            .register_fn("search", ScriptWorkspace::search)
+            .register_fn("xrefs_to", ScriptWorkspace::xrefs_to)
+            .register_fn("xrefs_from", ScriptWorkspace::xrefs_from)
+            .register_fn("imports", ScriptWorkspace::imports)
+            .register_fn("callers_of_import", ScriptWorkspace::callers_of_import)
+            .register_fn("segments", ScriptWorkspace::segments)
+            .register_fn("query", ScriptWorkspace::query)
+            .register_fn("emulator", ScriptWorkspace::emulator)
+            .register_type_with_name::<ScriptEmulator>("Emulator")
+            .register_fn("reg", ScriptEmulator::reg)
+            .register_fn("set_reg", ScriptEmulator::set_reg);
+        ScriptEngine { engine }
+    }
+
+    /// The engine, to register more functions on
+    pub fn engine_mut(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    pub fn compile(&self, source: &str) -> Result<AST, String> {
+        self.engine.compile(source).map_err(|err| err.to_string())
+    }
+
+    /// Run a compiled script against the workspace, returning the value it ends with. The
+    /// changes it makes are made to `workspace`, also when it fails part of the way through.
+    pub fn run_ast(&self, workspace: &mut VivWorkspace, ast: &AST) -> Result<Dynamic, String> {
+        // the script has the workspace itself, its watches and journal with it
+        let taken = std::mem::replace(workspace, VivWorkspace::new("", false));
+        let shared = ScriptWorkspace(Arc::new(Mutex::new(taken)));
+        let mut scope = Scope::new();
+        scope.push("ws", shared.clone());
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+            .map_err(|err| err.to_string());
+        drop(scope);
+        *workspace = std::mem::replace(&mut *shared.lock(), VivWorkspace::new("", false));
+        result
+    }
+
+    pub fn run(&self, workspace: &mut VivWorkspace, source: &str) -> Result<Dynamic, String> {
+        let ast = self.compile(source)?;
+        self.run_ast(workspace, &ast)
+    }
+}
+
+/// A script run as an analysis pass. Failures are logged, as a pass can't fail the analysis.
+pub struct ScriptAnalyzer {
+    name: String,
+    engine: ScriptEngine,
+    ast: AST,
+}
+
+impl ScriptAnalyzer {
+    pub fn new(name: &str, source: &str) -> Result<Self, String> {
+        let engine = ScriptEngine::new();
+        let ast = engine.compile(source)?;
+        Ok(ScriptAnalyzer {
+            name: name.to_string(),
+            engine,
+            ast,
+        })
+    }
+
+    /// A pass from a script file, named after the file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let name = path.file_stem().map_or_else(
+            || path.display().to_string(),
+            |stem| stem.to_string_lossy().to_string(),
+        );
+        ScriptAnalyzer::new(&name, &source)
+    }
+}
+
+impl Analyzer for ScriptAnalyzer {
+    fn analyze(&self, workspace: &mut VivWorkspace) {
+        if let Err(err) = self.engine.run_ast(workspace, &self.ast) {
+            warn!("Analysis script {} failed: {}", self.name, err);
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{MM_READ, REF_CODE};
+
+    #[test]
+    fn workspace_scripts() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(
+            0x1000,
+            MM_READ,
+            "test",
+            b"\x90\x90\xcc\x90\xcc".to_vec(),
+            None,
+        );
+        ws.add_xref(0x1000, 0x1004, REF_CODE, 0);
+        ws.add_xref(0x1003, 0x1004, REF_CODE, 0);
+
+        let engine = ScriptEngine::new();
+        let hits = engine
+            .run(&mut ws, "ws.search(blob(1, 0xcc))")
+            .unwrap()
+            .into_array()
+            .unwrap();
+        assert_eq!(
+            hits.into_iter()
+                .map(|hit| hit.as_int().unwrap())
+                .collect::<Vec<_>>(),
+            [0x1002, 0x1004]
+        );
+        let _ = engine
+            .run(
+                &mut ws,
+                r#"
+                let callers = ws.xrefs_to(0x1004);
+                ws.set_name(0x1004, `int3_${callers.len()}`);
+                ws.set_comment(0x1000, "nop sled");
+                "#,
+            )
+            .unwrap();
+        assert_eq!(ws.get_name(0x1004, false).as_deref(), Some("int3_2"));
+        assert_eq!(ws.get_comment(0x1000), "nop sled");
+        let named = engine
+            .run(&mut ws, "ws.query(\"names where name like 'int3_*'\")")
+            .unwrap()
+            .into_array()
+            .unwrap();
+        assert_eq!(named[0].as_int(), Ok(0x1004));
+        assert!(engine.run(&mut ws, "ws.query(\"names where\")").is_err());
+        assert!(engine.run(&mut ws, "ws.nonsense()").is_err());
+
+        let pass = ScriptAnalyzer::new("tag", "ws.read(0x1000, 2)").unwrap();
+        assert_eq!(Analyzer::name(&pass), "tag");
+    }
+}
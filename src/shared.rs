@@ -0,0 +1,293 @@
+//! Sharing a workspace between threads.
+//!
+//! A [`SharedWorkspace`] is a cheap handle to one workspace behind a reader-writer lock: any
+//! number of threads query it at once, and a writer gets it to itself. Analysis should hold the
+//! write lock for one unit of work at a time rather than the whole analysis, so queries from a UI
+//! or other workers get in between; [`SharedWorkspace::analyze`] runs the analysis passes that
+//! way, each on the shared workspace through the write guard, letting the readers waiting for the
+//! lock in after each pass to see what it made.
+//!
+//! Every write bumps a generation counter which is read without locking, so a reader can tell
+//! whether anything changed since it last looked.
+
+use crate::workspace::VivWorkspace;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    thread,
+};
+
+#[derive(Clone, Debug)]
+pub struct SharedWorkspace {
+    workspace: Arc<RwLock<VivWorkspace>>,
+    generation: Arc<AtomicU64>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl SharedWorkspace {
+    pub fn new(workspace: VivWorkspace) -> Self {
+        SharedWorkspace {
+            workspace: Arc::new(RwLock::new(workspace)),
+            generation: Arc::new(AtomicU64::new(0)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Lock the workspace for reading, waiting for a writer to finish. A writer which panicked
+    /// doesn't make the workspace unreadable.
+    pub fn read(&self) -> RwLockReadGuard<'_, VivWorkspace> {
+        match self.workspace.try_read() {
+            Ok(workspace) => return workspace,
+            Err(TryLockError::Poisoned(poisoned)) => return poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        self.waiting.fetch_add(1, Ordering::AcqRel);
+        let workspace = self
+            .workspace
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        workspace
+    }
+
+    /// Lock the workspace for reading if no writer holds it
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, VivWorkspace>> {
+        self.workspace.try_read().ok()
+    }
+
+    /// Lock the workspace for writing; the generation moves on when the guard is dropped
+    pub fn write(&self) -> WorkspaceWriteGuard<'_> {
+        WorkspaceWriteGuard {
+            workspace: self
+                .workspace
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            generation: &self.generation,
+        }
+    }
+
+    pub fn query<R>(&self, f: impl FnOnce(&VivWorkspace) -> R) -> R {
+        f(&self.read())
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut VivWorkspace) -> R) -> R {
+        f(&mut self.write())
+    }
+
+    /// Run the workspace's analysis passes in order, holding the write lock for one pass at a
+    /// time. Each pass makes its changes in the shared workspace, and the readers which blocked on
+    /// it see them before the next one starts. Returns how many passes ran.
+    pub fn analyze(&self) -> usize {
+        let passes = {
+            let workspace = self.read();
+            if !workspace.get_profile().runs_analyzers() {
+                return 0;
+            }
+            workspace.get_analyzer_names().len()
+        };
+        let mut ran = 0;
+        while ran < passes {
+            let mut workspace = self.write();
+            if !workspace.run_analyzer(ran) {
+                break;
+            }
+            drop(workspace);
+            ran += 1;
+            while self.waiting_readers() > 0 {
+                thread::yield_now();
+            }
+        }
+        ran
+    }
+
+    /// The number of threads blocked waiting to read the workspace
+    pub fn waiting_readers(&self) -> usize {
+        self.waiting.load(Ordering::Acquire)
+    }
+
+    /// The number of writes made so far
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The workspace, if this is the last handle to it
+    pub fn into_inner(self) -> Result<VivWorkspace, Self> {
+        let SharedWorkspace {
+            workspace,
+            generation,
+            waiting,
+        } = self;
+        match Arc::try_unwrap(workspace) {
+            Ok(lock) => Ok(lock.into_inner().unwrap_or_else(PoisonError::into_inner)),
+            Err(workspace) => Err(SharedWorkspace {
+                workspace,
+                generation,
+                waiting,
+            }),
+        }
+    }
+}
+
+impl From<VivWorkspace> for SharedWorkspace {
+    fn from(workspace: VivWorkspace) -> Self {
+        SharedWorkspace::new(workspace)
+    }
+}
+
+/// Write access to a [`SharedWorkspace`]
+pub struct WorkspaceWriteGuard<'a> {
+    workspace: RwLockWriteGuard<'a, VivWorkspace>,
+    generation: &'a AtomicU64,
+}
+
+impl Deref for WorkspaceWriteGuard<'_> {
+    type Target = VivWorkspace;
+
+    fn deref(&self) -> &VivWorkspace {
+        &self.workspace
+    }
+}
+
+impl DerefMut for WorkspaceWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut VivWorkspace {
+        &mut self.workspace
+    }
+}
+
+impl Drop for WorkspaceWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Analyzer;
+    use std::{
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn readers_and_writer() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<SharedWorkspace>();
+
+        let shared = SharedWorkspace::new(VivWorkspace::new("", false));
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for va in 0..100 {
+                    shared.update(|ws| ws.set_comment(va, "seen", false));
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 100 {
+                        let count = shared.query(|ws| ws.get_comments().len());
+                        assert!(count >= last);
+                        last = count;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(shared.generation(), 100);
+        assert!(shared.try_read().is_some());
+        let workspace = shared.into_inner().unwrap();
+        assert_eq!(workspace.get_comment(99), "seen");
+    }
+
+    #[test]
+    fn resolves_for_readers() {
+        let mut workspace = VivWorkspace::new("", false);
+        workspace.add_segment(0x1000, 0x1000, ".text", "app".to_string());
+        workspace.add_function(0x1000, vec![(0x1000, 0x10)]);
+        let shared = SharedWorkspace::new(workspace);
+        let resolve = |va| shared.query(|ws| ws.resolve(va).map(|r| r.to_string()));
+        assert_eq!(resolve(0x1004).as_deref(), Some("sub_1000+0x4"));
+        shared.update(|ws| ws.make_name(0x1000, "main".to_string(), false, false));
+        assert_eq!(resolve(0x1004).as_deref(), Some("main+0x4"));
+        let reader = shared.clone();
+        let resolved = thread::spawn(move || reader.read().resolve(0x1008))
+            .join()
+            .unwrap();
+        assert_eq!(resolved.unwrap().to_string(), "main+0x8");
+    }
+
+    /// Holds the write lock until a reader blocks on it, then makes a function
+    struct Pass {
+        shared: Arc<OnceLock<SharedWorkspace>>,
+        blocked: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl Analyzer for Pass {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let shared = self.shared.get().unwrap();
+            while shared.waiting_readers() == 0 && Instant::now() < deadline {
+                thread::yield_now();
+            }
+            let mut blocked = self.blocked.lock().unwrap();
+            let fva = 0x1000 + blocked.len() as i32 * 0x10;
+            workspace.add_function(fva, vec![(fva, 0x10)]);
+            blocked.push(shared.waiting_readers() > 0);
+        }
+    }
+
+    #[test]
+    fn readers_during_analysis() {
+        let shared = Arc::new(OnceLock::new());
+        let blocked = Arc::new(Mutex::new(Vec::new()));
+        let mut workspace = VivWorkspace::new("", false);
+        for _ in 0..3 {
+            workspace.add_analyzer(Arc::new(Pass {
+                shared: shared.clone(),
+                blocked: blocked.clone(),
+            }));
+        }
+        shared.set(SharedWorkspace::new(workspace)).unwrap();
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let shared = shared.get().unwrap();
+                let mut seen = vec![];
+                while seen.last().map(|&(generation, _)| generation) != Some(3) {
+                    let now = shared.query(|ws| (shared.generation(), ws.get_functions()));
+                    if seen.last() != Some(&now) {
+                        seen.push(now);
+                    }
+                }
+                seen
+            })
+        };
+        let shared = shared.get().unwrap();
+        assert_eq!(shared.analyze(), 3);
+        // every pass had a reader waiting, which read the workspace before the next pass
+        assert_eq!(*blocked.lock().unwrap(), [true, true, true]);
+        // and saw the function each pass made
+        let seen = reader.join().unwrap();
+        assert!(
+            seen.ends_with(&[
+                (1, vec![0x1000]),
+                (2, vec![0x1000, 0x1010]),
+                (3, vec![0x1000, 0x1010, 0x1020]),
+            ]),
+            "{:?}",
+            seen
+        );
+        let stats = shared.read().get_analysis_stats();
+        assert_eq!(stats.pass("Pass").unwrap().runs, 3);
+    }
+}
@@ -0,0 +1,635 @@
+//! Data flow slices of lifted functions: the instructions an operand depends on, or which
+//! depend on it.
+//!
+//! [`DefUse`] works out the definitions reaching each instruction of a function, for the full
+//! registers, the flags, the stack slots (by offset from the stack pointer at the entry, see
+//! [`crate::regstate`]) and the rest of memory as one location. Each location an instruction
+//! sets is linked to the locations it was computed from, so `push ebp` sets the slot from
+//! `ebp` and `esp` from `esp`, and a slice follows only the one it is after. A store elsewhere
+//! in memory and a write to part of a register leave the rest of the old value, so they don't
+//! hide the definitions before them.
+//!
+//! The lifter lifts a call as [`Stmt::Unknown`]: it sets the registers the calling convention
+//! doesn't preserve, and memory, from its arguments. [`backward`] and [`forward`] slice in the
+//! functions of a workspace with IL (see `VivWorkspace::set_function_il`), and may follow the
+//! arguments across calls: back from the parameters of a function to what its callers pass,
+//! and on from what a call passes into the callee.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::{
+        registers::{RegId, RegisterModel},
+        Arch,
+    },
+    osprofile,
+    regstate::{self, RegState, Value},
+    symbolic::{call_argument, Expr, Function, Stmt, Terminator},
+    taint::callee_saved,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How many arguments of a call are followed
+pub const MAX_ARGS: usize = 4;
+
+/// A place holding a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Operand {
+    /// A full register
+    Reg(RegId),
+    /// The stack slot at an offset from the stack pointer at the function entry
+    Stack(i64),
+    /// Memory other than the stack
+    Memory,
+    Flags,
+}
+
+/// Where the value of a location was set
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Def {
+    /// Before the function was entered
+    Entry,
+    Insn(u64),
+}
+
+/// The definitions reaching a point; a location missing has the value it had at the entry
+type Reaching = BTreeMap<Operand, BTreeSet<Def>>;
+
+/// What an instruction reads and sets
+#[derive(Clone, Debug, Default)]
+struct Effects {
+    /// The locations set, each with the locations it was computed from as the instruction
+    /// started
+    sets: BTreeMap<Operand, BTreeSet<Operand>>,
+    /// The sets leaving some of the old value
+    weak: BTreeSet<Operand>,
+    reads: BTreeSet<Operand>,
+    /// Where a call finds each argument
+    passed: Vec<Option<Operand>>,
+    /// The locations each argument of a call comes from
+    args: Vec<BTreeSet<Operand>>,
+}
+
+/// The instructions of a slice
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Slice {
+    pub insns: BTreeSet<u64>,
+    /// The arguments of the function a backward slice reaches as they were passed in
+    pub params: BTreeSet<usize>,
+    /// The calls a forward slice passes a value to, with the argument
+    pub args: BTreeSet<(u64, usize)>,
+}
+
+/// The def-use chains of a function
+#[derive(Clone, Debug)]
+pub struct DefUse {
+    arch: Arch,
+    windows: bool,
+    effects: BTreeMap<u64, Effects>,
+    /// The definitions reaching each instruction
+    before: BTreeMap<u64, Reaching>,
+    /// The instructions reading each definition of a location
+    users: BTreeMap<(Def, Operand), BTreeSet<u64>>,
+}
+
+impl DefUse {
+    /// The chains of `func`, with calls passing arguments by the Windows convention on amd64
+    /// if `windows`
+    pub fn new(func: &Function, windows: bool) -> Self {
+        let model = RegisterModel::new(func.arch);
+        let width = func.arch.pointer_size() as u32 * 8;
+        let saved = callee_saved(&model);
+        let clobbered: Vec<RegId> = model
+            .full_registers()
+            .map(|(reg, _)| reg)
+            .filter(|reg| !saved.contains(reg))
+            .collect();
+        let states = regstate::analyze(func);
+
+        let mut effects: BTreeMap<u64, Effects> = BTreeMap::new();
+        for block in func.blocks.values() {
+            let mut env = RegState::new();
+            for insn in block.insns.iter() {
+                env = states.get(&insn.va).cloned().unwrap_or_default();
+                let fx = effects.entry(insn.va).or_default();
+                for stmt in insn.stmts.iter() {
+                    fx.exec(stmt, &env, func.arch, windows, &model, &clobbered);
+                    regstate::exec(&mut env, stmt, width);
+                }
+            }
+            // the terminator reads as the last instruction of the block does
+            let reads = match &block.end {
+                Terminator::Branch { .. } => vec![Operand::Flags],
+                Terminator::Indirect(target) => Effects::default()
+                    .sources(target, &env, width, &model)
+                    .into_iter()
+                    .collect(),
+                _ => vec![],
+            };
+            effects
+                .entry(block.end_va)
+                .or_default()
+                .reads
+                .extend(reads);
+        }
+
+        let mut du = DefUse {
+            arch: func.arch,
+            windows,
+            effects,
+            before: BTreeMap::new(),
+            users: BTreeMap::new(),
+        };
+        du.reach(func);
+        for (va, fx) in du.effects.iter() {
+            for loc in fx.reads.iter() {
+                for def in du.defs(*va, *loc) {
+                    du.users.entry((def, *loc)).or_default().insert(*va);
+                }
+            }
+        }
+        du
+    }
+
+    /// Work out the definitions reaching each instruction, to a fixed point
+    fn reach(&mut self, func: &Function) {
+        let mut entries: BTreeMap<u64, Reaching> = BTreeMap::new();
+        if !func.blocks.contains_key(&func.entry) {
+            return;
+        }
+        entries.insert(func.entry, Reaching::new());
+        let mut work = vec![func.entry];
+        while let Some(va) = work.pop() {
+            let block = &func.blocks[&va];
+            let mut reaching = entries[&va].clone();
+            let vas = block.insns.iter().map(|insn| insn.va);
+            let end = block.insns.iter().all(|insn| insn.va != block.end_va);
+            for at in vas.chain(end.then_some(block.end_va)) {
+                self.before.insert(at, reaching.clone());
+                let Some(fx) = self.effects.get(&at) else {
+                    continue;
+                };
+                for loc in fx.sets.keys() {
+                    let defs = reaching.entry(*loc).or_insert_with(|| [Def::Entry].into());
+                    if !fx.weak.contains(loc) {
+                        defs.clear();
+                    }
+                    defs.insert(Def::Insn(at));
+                }
+            }
+            for succ in func.successors(va) {
+                if !func.blocks.contains_key(&succ) {
+                    continue;
+                }
+                let merged = match entries.get(&succ) {
+                    Some(old) => join(old, &reaching),
+                    None => reaching.clone(),
+                };
+                if entries.get(&succ) == Some(&merged) {
+                    continue;
+                }
+                entries.insert(succ, merged);
+                if !work.contains(&succ) {
+                    work.push(succ);
+                }
+            }
+        }
+    }
+
+    /// The definitions of `loc` reaching the instruction at `va`
+    fn defs(&self, va: u64, loc: Operand) -> BTreeSet<Def> {
+        match self.before.get(&va) {
+            Some(reaching) => reaching
+                .get(&loc)
+                .cloned()
+                .unwrap_or_else(|| [Def::Entry].into()),
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// The instructions the value of `operand` as the instruction at `va` reads it depends on,
+    /// `va` among them
+    pub fn backward(&self, va: u64, operand: Operand) -> Slice {
+        let mut slice = Slice::default();
+        if !self.before.contains_key(&va) {
+            return slice;
+        }
+        slice.insns.insert(va);
+        let mut work: Vec<(Def, Operand)> = self
+            .defs(va, operand)
+            .into_iter()
+            .map(|def| (def, operand))
+            .collect();
+        let mut seen = BTreeSet::new();
+        while let Some((def, loc)) = work.pop() {
+            if !seen.insert((def, loc)) {
+                continue;
+            }
+            let Def::Insn(at) = def else {
+                if let Some(index) = (0..MAX_ARGS).find(|i| self.param(*i) == Some(loc)) {
+                    slice.params.insert(index);
+                }
+                continue;
+            };
+            slice.insns.insert(at);
+            for src in self.effects[&at].sets[&loc].iter() {
+                work.extend(self.defs(at, *src).into_iter().map(|def| (def, *src)));
+            }
+        }
+        slice
+    }
+
+    /// The instructions depending on `operand` at `va`: on the value the instruction leaves
+    /// there if it sets it, else on the value it reads. `va` is among them.
+    pub fn forward(&self, va: u64, operand: Operand) -> Slice {
+        let mut slice = Slice::default();
+        let Some(fx) = self.effects.get(&va) else {
+            return slice;
+        };
+        slice.insns.insert(va);
+        let work = if fx.sets.contains_key(&operand) {
+            vec![(Def::Insn(va), operand)]
+        } else {
+            self.note_args(&mut slice, va, operand);
+            fx.sets
+                .iter()
+                .filter(|(_, srcs)| srcs.contains(&operand))
+                .map(|(loc, _)| (Def::Insn(va), *loc))
+                .collect()
+        };
+        self.follow(slice, work)
+    }
+
+    /// The instructions depending on argument `index` of the function
+    pub fn forward_from_param(&self, index: usize) -> Slice {
+        let work = self
+            .param(index)
+            .map(|loc| (Def::Entry, loc))
+            .into_iter()
+            .collect();
+        self.follow(Slice::default(), work)
+    }
+
+    /// Add the users of the definitions of `work` to `slice`, and theirs, and so on
+    fn follow(&self, mut slice: Slice, mut work: Vec<(Def, Operand)>) -> Slice {
+        let mut seen = BTreeSet::new();
+        while let Some((def, loc)) = work.pop() {
+            if !seen.insert((def, loc)) {
+                continue;
+            }
+            for user in self.users.get(&(def, loc)).into_iter().flatten() {
+                slice.insns.insert(*user);
+                self.note_args(&mut slice, *user, loc);
+                for (set, srcs) in self.effects[user].sets.iter() {
+                    if srcs.contains(&loc) {
+                        work.push((Def::Insn(*user), *set));
+                    }
+                }
+            }
+        }
+        slice
+    }
+
+    /// Note the arguments the call at `va` passes `loc` as
+    fn note_args(&self, slice: &mut Slice, va: u64, loc: Operand) {
+        for (index, srcs) in self.effects[&va].args.iter().enumerate() {
+            if srcs.contains(&loc) {
+                slice.args.insert((va, index));
+            }
+        }
+    }
+
+    /// Where argument `index` is at the function entry, the return address pushed on x86
+    pub fn param(&self, index: usize) -> Option<Operand> {
+        let ret = match self.arch {
+            Arch::I386 | Arch::Amd64 => self.arch.pointer_size() as i64,
+            _ => 0,
+        };
+        let model = RegisterModel::new(self.arch);
+        let env = RegState::from([(model.sp(), Value::StackOffset(ret))]);
+        let width = self.arch.pointer_size() as u32 * 8;
+        location(&call_argument(self.arch, self.windows, index)?, &env, width, &model)
+    }
+
+    /// Where the call at `va` passes argument `index`
+    pub fn argument(&self, va: u64, index: usize) -> Option<Operand> {
+        *self.effects.get(&va)?.passed.get(index)?
+    }
+}
+
+impl Effects {
+    fn exec(
+        &mut self,
+        stmt: &Stmt,
+        env: &RegState,
+        arch: Arch,
+        windows: bool,
+        model: &RegisterModel,
+        clobbered: &[RegId],
+    ) {
+        let width = arch.pointer_size() as u32 * 8;
+        match stmt {
+            Stmt::Set(reg, value) => {
+                let from = self.sources(value, env, width, model);
+                let loc = Operand::Reg(model.full(*reg));
+                self.set(loc, from, model.is_partial_write(*reg));
+            }
+            Stmt::Store(addr, value) => {
+                let mut from = self.sources(addr, env, width, model);
+                from.extend(self.sources(value, env, width, model));
+                let loc = address(addr, env, width);
+                self.set(loc, from, loc == Operand::Memory);
+            }
+            Stmt::Flags(_, a, b) => {
+                let mut from = self.sources(a, env, width, model);
+                from.extend(self.sources(b, env, width, model));
+                self.set(Operand::Flags, from, false);
+            }
+            Stmt::Unknown => {
+                self.passed = (0..MAX_ARGS)
+                    .map_while(|index| call_argument(arch, windows, index))
+                    .map(|arg| location(&arg, env, width, model))
+                    .collect();
+                self.args = self
+                    .passed
+                    .clone()
+                    .into_iter()
+                    .map(|loc| loc.map_or_else(BTreeSet::new, |loc| self.source(loc)))
+                    .collect();
+                let from: BTreeSet<Operand> = self.args.iter().flatten().copied().collect();
+                for reg in clobbered {
+                    self.set(Operand::Reg(*reg), from.clone(), false);
+                }
+                self.set(Operand::Memory, from, true);
+            }
+        }
+    }
+
+    fn set(&mut self, loc: Operand, mut from: BTreeSet<Operand>, weak: bool) {
+        if weak {
+            from.extend(self.source(loc));
+            self.weak.insert(loc);
+        } else {
+            self.weak.remove(&loc);
+        }
+        self.sets.insert(loc, from);
+    }
+
+    /// The locations the value of `loc` so far in the instruction comes from, reading it if
+    /// the instruction hasn't set it yet
+    fn source(&mut self, loc: Operand) -> BTreeSet<Operand> {
+        match self.sets.get(&loc) {
+            Some(from) => from.clone(),
+            None => {
+                self.reads.insert(loc);
+                [loc].into()
+            }
+        }
+    }
+
+    /// The locations the value of `expr` comes from
+    fn sources(
+        &mut self,
+        expr: &Expr,
+        env: &RegState,
+        width: u32,
+        model: &RegisterModel,
+    ) -> BTreeSet<Operand> {
+        let mut regs = BTreeSet::new();
+        expr.registers(&mut regs);
+        let mut loads = Vec::new();
+        expr.loads(&mut loads);
+        let locs: Vec<Operand> = regs
+            .into_iter()
+            .map(|reg| Operand::Reg(model.full(reg)))
+            .chain(loads.iter().map(|addr| address(addr, env, width)))
+            .collect();
+        locs.into_iter().flat_map(|loc| self.source(loc)).collect()
+    }
+}
+
+/// The location `expr` reads, if it is just one
+fn location(expr: &Expr, env: &RegState, width: u32, model: &RegisterModel) -> Option<Operand> {
+    match expr {
+        Expr::Reg(reg) => Some(Operand::Reg(model.full(*reg))),
+        Expr::Load(addr) => Some(address(addr, env, width)),
+        _ => None,
+    }
+}
+
+/// The location at `addr`, its registers having the values of `env`
+fn address(addr: &Expr, env: &RegState, width: u32) -> Operand {
+    match regstate::eval(addr, env, width) {
+        Value::StackOffset(offset) => Operand::Stack(offset),
+        _ => Operand::Memory,
+    }
+}
+
+fn join(a: &Reaching, b: &Reaching) -> Reaching {
+    let entry = || BTreeSet::from([Def::Entry]);
+    let mut joined = a.clone();
+    for (loc, defs) in b.iter() {
+        joined.entry(*loc).or_insert_with(entry).extend(defs);
+    }
+    for (loc, defs) in joined.iter_mut() {
+        if !b.contains_key(loc) {
+            defs.insert(Def::Entry);
+        }
+    }
+    joined
+}
+
+/// Whether `workspace` follows the Windows calling convention
+/// The chains of the functions of a workspace with IL, made as they are needed
+struct Chains<'a> {
+    workspace: &'a VivWorkspace,
+    windows: bool,
+    chains: BTreeMap<i32, Option<DefUse>>,
+}
+
+impl<'a> Chains<'a> {
+    fn new(workspace: &'a VivWorkspace) -> Self {
+        Chains {
+            workspace,
+            windows: osprofile::is_windows(workspace),
+            chains: BTreeMap::new(),
+        }
+    }
+
+    /// The function holding `va` and its chains
+    fn of(&mut self, va: u64) -> Option<(i32, &DefUse)> {
+        let fva = self.workspace.get_function(va as i32)?;
+        let (workspace, windows) = (self.workspace, self.windows);
+        let chains = self.chains.entry(fva).or_insert_with(|| {
+            let func = workspace.get_function_il(fva)?;
+            Some(DefUse::new(func, windows))
+        });
+        chains.as_ref().map(|chains| (fva, chains))
+    }
+}
+
+/// The instructions the value of `operand` at `va` depends on, in the function holding `va`
+/// and, if `interprocedural`, in the callers passing the parameters it depends on
+pub fn backward(
+    workspace: &VivWorkspace,
+    va: u64,
+    operand: Operand,
+    interprocedural: bool,
+) -> BTreeSet<u64> {
+    let mut chains = Chains::new(workspace);
+    let mut insns = BTreeSet::new();
+    let mut work = vec![(va, operand)];
+    let mut seen = BTreeSet::new();
+    while let Some((va, operand)) = work.pop() {
+        if !seen.insert((va, operand)) {
+            continue;
+        }
+        let Some((fva, du)) = chains.of(va) else {
+            continue;
+        };
+        let slice = du.backward(va, operand);
+        insns.extend(slice.insns);
+        if !interprocedural {
+            continue;
+        }
+        for (call, _, _, rflags) in workspace.get_xrefs_to(fva, Some(REF_CODE)) {
+            if rflags & BR_PROC == 0 {
+                continue;
+            }
+            let call = call as u32 as u64;
+            let Some((_, caller)) = chains.of(call) else {
+                continue;
+            };
+            work.extend(
+                slice
+                    .params
+                    .iter()
+                    .filter_map(|index| caller.argument(call, *index))
+                    .map(|arg| (call, arg)),
+            );
+        }
+    }
+    insns
+}
+
+/// The instructions depending on `operand` at `va` (see [`DefUse::forward`]), in the function
+/// holding `va` and, if `interprocedural`, in the callees it is passed to
+pub fn forward(
+    workspace: &VivWorkspace,
+    va: u64,
+    operand: Operand,
+    interprocedural: bool,
+) -> BTreeSet<u64> {
+    let mut chains = Chains::new(workspace);
+    let Some((_, du)) = chains.of(va) else {
+        return BTreeSet::new();
+    };
+    let slice = du.forward(va, operand);
+    let mut insns = slice.insns;
+    let mut work: Vec<(u64, usize)> = slice.args.into_iter().collect();
+    let mut seen = BTreeSet::new();
+    while let Some((call, index)) = work.pop() {
+        if !interprocedural || !seen.insert((call, index)) {
+            continue;
+        }
+        for (_, callee, _, rflags) in workspace.get_xrefs_from(call as i32, Some(REF_CODE)) {
+            if rflags & BR_PROC == 0 {
+                continue;
+            }
+            let Some((fva, du)) = chains.of(callee as u32 as u64) else {
+                continue;
+            };
+            if fva != callee {
+                continue;
+            }
+            let slice = du.forward_from_param(index);
+            insns.extend(slice.insns);
+            work.extend(slice.args);
+        }
+    }
+    insns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolic::{BinOp, Block, Insn};
+
+    fn function(entry: u64, insns: Vec<Insn>, end_va: u64) -> Function {
+        let mut func = Function::new(Arch::I386, entry);
+        func.add_block(Block {
+            va: entry,
+            insns,
+            end_va,
+            end: Terminator::Return,
+        });
+        func
+    }
+
+    #[test]
+    fn slices_across_calls() {
+        let model = RegisterModel::new(Arch::I386);
+        let reg = |name| model.by_name(name).unwrap();
+        let (esp, eax, ebx) = (reg("esp"), reg("eax"), reg("ebx"));
+        let (ecx, edx) = (reg("ecx"), reg("edx"));
+        let insn = |va, stmts| Insn { va, stmts };
+        let add = |a, c| Expr::binary(BinOp::Add, a, Expr::Const(c), 32);
+        let load = |addr| Expr::Load(Box::new(addr));
+
+        // eax = [0x5000]; ebx = 7; push eax; call 0x1000; ecx = eax
+        let caller = function(
+            0x2000,
+            vec![
+                insn(0x2000, vec![Stmt::Set(eax, load(Expr::Const(0x5000)))]),
+                insn(0x2005, vec![Stmt::Set(ebx, Expr::Const(7))]),
+                insn(
+                    0x200a,
+                    vec![
+                        Stmt::Set(esp, add(Expr::Reg(esp), 0xffff_fffc)),
+                        Stmt::Store(Expr::Reg(esp), Expr::Reg(eax)),
+                    ],
+                ),
+                insn(0x200b, vec![Stmt::Unknown]),
+                insn(0x2010, vec![Stmt::Set(ecx, Expr::Reg(eax))]),
+            ],
+            0x2012,
+        );
+        // edx = [esp + 4]; edx += 1; ecx = 3; eax = edx
+        let callee = function(
+            0x1000,
+            vec![
+                insn(0x1000, vec![Stmt::Set(edx, load(add(Expr::Reg(esp), 4)))]),
+                insn(0x1004, vec![Stmt::Set(edx, add(Expr::Reg(edx), 1))]),
+                insn(0x1007, vec![Stmt::Set(ecx, Expr::Const(3))]),
+                insn(0x100c, vec![Stmt::Set(eax, Expr::Reg(edx))]),
+            ],
+            0x100e,
+        );
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x2000, vec![(0x2000, 0x13)]);
+        ws.add_function(0x1000, vec![(0x1000, 0xf)]);
+        ws.add_xref(0x200b, 0x1000, REF_CODE, BR_PROC);
+        ws.set_function_il(0x2000, caller);
+        ws.set_function_il(0x1000, callee);
+
+        let edx = Operand::Reg(edx);
+        let set = |vas: &[u64]| vas.iter().copied().collect::<BTreeSet<u64>>();
+        assert_eq!(
+            ws.slice_backward(0x100c, edx, false),
+            set(&[0x1000, 0x1004, 0x100c])
+        );
+        assert_eq!(
+            ws.slice_backward(0x100c, edx, true),
+            set(&[0x1000, 0x1004, 0x100c, 0x2000, 0x200a, 0x200b])
+        );
+        assert_eq!(
+            ws.slice_forward(0x2000, Operand::Reg(eax), true),
+            set(&[0x1000, 0x1004, 0x100c, 0x2000, 0x200a, 0x200b, 0x2010])
+        );
+        assert_eq!(
+            ws.slice_forward(0x2000, Operand::Reg(eax), false),
+            set(&[0x2000, 0x200a, 0x200b, 0x2010])
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Consistent views of a workspace for long running queries.
+//!
+//! A report generator or a server answering queries wants the locations, xrefs, functions and
+//! names of a workspace as they were at one moment, while analysis goes on making more.
+//! `VivWorkspace::snapshot_view` hands out a [`Snapshot`]: a view of those stores which never
+//! changes, is cheap to clone and can be sent to and shared between threads.
+//!
+//! The workspace keeps those stores in [`PersistentMap`]s, so taking a snapshot clones a few
+//! roots and copies nothing. A change the workspace makes afterwards copies the path to what it
+//! touches and shares the rest with every snapshot still around.
+//!
+//! The workspace counts its changes. A snapshot taken when nothing changed since the last one
+//! is the last one, so asking for views often hands out the same one while the workspace is idle.
+
+use crate::{locations::Location, persistent::PersistentMap, workspace::FunctionChunks};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+/// An xref as the workspace deals with it: `(from, to, type, flags)`
+pub type Xref = (i32, i32, i32, i32);
+
+/// The stores of a workspace, as a snapshot takes them
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Stores {
+    pub(crate) locations: PersistentMap<Location>,
+    pub(crate) xrefs_by_from: PersistentMap<Vec<Xref>>,
+    pub(crate) xrefs_by_to: PersistentMap<Vec<Xref>>,
+    pub(crate) functions: PersistentMap<HashMap<String, i32>>,
+    pub(crate) chunks: PersistentMap<FunctionChunks>,
+    pub(crate) names: PersistentMap<String>,
+}
+
+#[derive(Debug, Default)]
+struct Taken {
+    generation: u64,
+    stores: Stores,
+}
+
+/// The locations, xrefs, functions and names of a workspace at one moment
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot(Arc<Taken>);
+
+impl Snapshot {
+    pub(crate) fn new(generation: u64, stores: Stores) -> Self {
+        Snapshot(Arc::new(Taken { generation, stores }))
+    }
+
+    /// The count of changes the workspace had seen when the snapshot was taken
+    pub fn generation(&self) -> u64 {
+        self.0.generation
+    }
+
+    /// Whether two snapshots share their stores
+    pub fn ptr_eq(&self, other: &Snapshot) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// The location starting at or covering `va`
+    pub fn get_location(&self, va: i32) -> Option<&Location> {
+        let (_, loc) = self.0.stores.locations.last_at_or_before(va)?;
+        ((va as i64) < loc.0 as i64 + loc.1.max(1) as i64).then_some(loc)
+    }
+
+    /// The locations, of one type or of all, in VA order
+    pub fn get_locations(&self, ltype: Option<i32>) -> Vec<&Location> {
+        self.0
+            .stores
+            .locations
+            .values()
+            .filter(|loc| ltype.is_none_or(|ltype| loc.2 == ltype))
+            .collect()
+    }
+
+    pub fn get_xrefs(&self, r_type: Option<i32>) -> Vec<Xref> {
+        let xrefs: Vec<Xref> = self
+            .0
+            .stores
+            .xrefs_by_from
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        filter(&xrefs, r_type)
+    }
+
+    pub fn get_xrefs_from(&self, va: i32, r_type: Option<i32>) -> Vec<Xref> {
+        self.0
+            .stores
+            .xrefs_by_from
+            .get(&va)
+            .map(|xrefs| filter(xrefs, r_type))
+            .unwrap_or_default()
+    }
+
+    pub fn get_xrefs_to(&self, va: i32, r_type: Option<i32>) -> Vec<Xref> {
+        self.0
+            .stores
+            .xrefs_by_to
+            .get(&va)
+            .map(|xrefs| filter(xrefs, r_type))
+            .unwrap_or_default()
+    }
+
+    pub fn get_functions(&self) -> Vec<i32> {
+        self.0.stores.functions.keys().copied().collect()
+    }
+
+    pub fn is_function(&self, fva: i32) -> bool {
+        self.0.stores.functions.contains_key(&fva)
+    }
+
+    pub fn get_function_bounds(&self, fva: i32) -> Option<&[(i32, i32)]> {
+        if !self.is_function(fva) {
+            return None;
+        }
+        self.0
+            .stores
+            .chunks
+            .get(&fva)
+            .filter(|chunks| !chunks.ranges.is_empty())
+            .map(|chunks| chunks.ranges.as_slice())
+    }
+
+    pub fn get_name(&self, va: i32) -> Option<&str> {
+        self.0.stores.names.get(&va).map(String::as_str)
+    }
+
+    /// Every named address with its name, sorted by address
+    pub fn get_names(&self) -> impl Iterator<Item = (i32, &str)> {
+        self.0
+            .stores
+            .names
+            .iter()
+            .map(|(va, name)| (*va, name.as_str()))
+    }
+}
+
+fn filter(xrefs: &[Xref], r_type: Option<i32>) -> Vec<Xref> {
+    let mut ret: Vec<Xref> = xrefs
+        .iter()
+        .filter(|xref| r_type.is_none_or(|r_type| xref.2 == r_type))
+        .copied()
+        .collect();
+    ret.sort_unstable();
+    ret
+}
+
+/// The change count of a workspace and the last snapshot taken of it. Every copy of a workspace
+/// changes apart from the others, so a copy starts out with no snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshots {
+    generation: AtomicU64,
+    last: Mutex<Option<Snapshot>>,
+}
+
+impl Clone for Snapshots {
+    fn clone(&self) -> Self {
+        Snapshots {
+            generation: AtomicU64::new(self.generation()),
+            last: Default::default(),
+        }
+    }
+}
+
+impl Snapshots {
+    pub(crate) fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// The last snapshot if nothing changed since it was taken, or else the one `take` makes
+    pub(crate) fn get_or_take(&self, take: impl FnOnce(u64) -> Snapshot) -> Snapshot {
+        let generation = self.generation();
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        match last.as_ref() {
+            Some(snapshot) if snapshot.generation() == generation => snapshot.clone(),
+            _ => last.insert(take(generation)).clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::{LOC_NUMBER, LOC_OP, REF_CODE},
+        workspace::VivWorkspace,
+    };
+
+    #[test]
+    fn snapshots_stay_put() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_location(0x1000, 4, LOC_NUMBER, Some(vec![]));
+        ws.add_function(0x2000, vec![(0x2000, 0x10)]);
+        ws.add_xref(0x2004, 0x3000, REF_CODE, 0);
+        let before = ws.snapshot_view();
+        assert!(ws.snapshot_view().ptr_eq(&before));
+
+        let reader = {
+            let view = before.clone();
+            std::thread::spawn(move || {
+                (
+                    view.get_location(0x1002).map(|loc| loc.0),
+                    view.get_xrefs_to(0x3000, None).len(),
+                    view.get_functions(),
+                )
+            })
+        };
+        ws.add_location(0x2004, 2, LOC_OP, Some(vec![]));
+        ws.add_xref(0x2006, 0x3000, REF_CODE, 0);
+        ws.make_name(0x2000, "main".to_string(), true, false);
+        ws.add_function(0x4000, vec![(0x4000, 0x20)]);
+        assert_eq!(reader.join().unwrap(), (Some(0x1000), 1, vec![0x2000]));
+
+        assert_eq!(before.get_location(0x2004), None);
+        assert_eq!(before.get_name(0x2000), None);
+        assert_eq!(
+            before.get_function_bounds(0x2000),
+            Some(&[(0x2000, 0x10)][..])
+        );
+        let after = ws.snapshot_view();
+        assert!(after.generation() > before.generation());
+        assert!(!after.0.stores.names.ptr_eq(&before.0.stores.names));
+
+        let named = ws.snapshot_view();
+        ws.make_name(0x4000, "helper".to_string(), true, false);
+        let renamed = ws.snapshot_view();
+        assert!(!renamed.ptr_eq(&named));
+        assert!(renamed.0.stores.locations.ptr_eq(&named.0.stores.locations));
+        assert!(renamed
+            .0
+            .stores
+            .xrefs_by_to
+            .ptr_eq(&named.0.stores.xrefs_by_to));
+        assert_eq!(named.get_name(0x4000), None);
+        assert_eq!(renamed.get_name(0x4000), Some("helper"));
+        assert_eq!(after.get_locations(Some(LOC_OP)).len(), 1);
+        assert_eq!(after.get_xrefs_to(0x3000, Some(REF_CODE)).len(), 2);
+        assert_eq!(after.get_name(0x2000), Some("main"));
+        assert_eq!(after.get_functions(), [0x2000, 0x4000]);
+    }
+}
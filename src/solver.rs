@@ -0,0 +1,927 @@
+//! Path feasibility queries over the symbolic engine.
+//!
+//! [`path_constraints`] runs a [`State`] along a path of blocks and collects what the branches
+//! it takes require of the values registers and memory held on entry. [`solve`] decides whether
+//! constraints can hold together, and if they can gives values which make them hold: the
+//! constraints are blasted into a circuit over the bits of the inputs and handed to a small CDCL
+//! SAT solver. [`reach`] asks "what input reaches this block" by solving the paths to it.
+//!
+//! The semantics are the engine's own. Flags are encoded from what [`FlagOp::update`] sets on
+//! the architecture, and a condition from its truth table under [`Condition::eval`], so a
+//! model found here runs down the same path in [`crate::ilemu`]. Flags the last operation
+//! doesn't set, and loads, are free inputs; loads through the same address expression agree.
+//!
+//! Queries give up after a budget of conflicts and answer [`Answer::Unknown`].
+
+use crate::{
+    envi::{
+        flags::{Condition, Effect, Flag, Flags},
+        registers::RegId,
+        Arch,
+    },
+    symbolic::{BinOp, Expr, FlagOp, Function, State, Stmt, Terminator, UnOp},
+};
+use std::collections::BTreeMap;
+
+/// The conflicts a query may run into before it is given up
+pub const DEFAULT_BUDGET: u64 = 100_000;
+
+const FLAGS: [Flag; 6] = [
+    Flag::Carry,
+    Flag::Parity,
+    Flag::Adjust,
+    Flag::Zero,
+    Flag::Sign,
+    Flag::Overflow,
+];
+
+/// What a path requires, in terms of the values on entry to its first block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Constraint {
+    /// The flags set by `op` on `a` and `b` make `cond` hold, or not
+    Flags {
+        op: FlagOp,
+        a: Expr,
+        b: Expr,
+        cond: Condition,
+        holds: bool,
+    },
+    Equal(Expr, Expr),
+}
+
+/// Values of the inputs of a query which satisfy it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Model {
+    pub registers: BTreeMap<RegId, u64>,
+    /// The value loaded through each address expression
+    pub loads: BTreeMap<Expr, u64>,
+}
+
+impl Model {
+    /// The value of `expr` given the model; inputs it doesn't mention are zero
+    pub fn eval(&self, expr: &Expr, width: u32) -> u64 {
+        let folded = match expr {
+            Expr::Const(c) => Expr::Const(*c),
+            Expr::Reg(r) => Expr::Const(self.registers.get(r).copied().unwrap_or(0)),
+            Expr::Load(_) => Expr::Const(self.loads.get(expr).copied().unwrap_or(0)),
+            Expr::Unary(op, a) => Expr::unary(*op, Expr::Const(self.eval(a, width)), width),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a, width), self.eval(b, width));
+                Expr::binary(*op, Expr::Const(a), Expr::Const(b), width)
+            }
+        };
+        folded.as_const().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Answer {
+    Sat(Model),
+    Unsat,
+    /// The budget ran out
+    Unknown,
+}
+
+/// The constraints on the values entering `path[0]` for execution to go down `path`. None if
+/// the blocks don't follow one another, or run an instruction that isn't lifted.
+pub fn path_constraints(func: &Function, path: &[u64]) -> Option<Vec<Constraint>> {
+    let mut state = State::new(func.arch);
+    let mut constraints = Vec::new();
+    for (i, va) in path.iter().enumerate() {
+        let block = func.blocks.get(va)?;
+        for insn in &block.insns {
+            if insn.stmts.contains(&Stmt::Unknown) {
+                return None;
+            }
+            state.exec_insn(insn);
+        }
+        let Some(next) = path.get(i + 1) else {
+            break;
+        };
+        match &block.end {
+            Terminator::Jump(to) if to == next => {}
+            Terminator::Branch {
+                cond,
+                taken,
+                fallthrough,
+            } if next == taken || next == fallthrough => {
+                // Flags set before the path are inputs, and the branch doesn't constrain them
+                if let (Some((op, a, b)), false) = (state.flag_source(), taken == fallthrough) {
+                    constraints.push(Constraint::Flags {
+                        op: *op,
+                        a: a.clone(),
+                        b: b.clone(),
+                        cond: *cond,
+                        holds: next == taken,
+                    });
+                }
+            }
+            Terminator::Indirect(target) => {
+                constraints.push(Constraint::Equal(state.eval(target), Expr::Const(*next)));
+            }
+            _ => return None,
+        }
+    }
+    Some(constraints)
+}
+
+/// Whether `constraints` can all hold, giving up after `budget` conflicts
+pub fn solve(arch: Arch, constraints: &[Constraint], budget: u64) -> Answer {
+    let width = arch.pointer_size() as u32 * 8;
+    let mut blaster = Blaster::new(arch, width);
+    for constraint in constraints {
+        blaster.assert(constraint);
+    }
+    match blaster.sat.solve(budget) {
+        Some(true) => Answer::Sat(blaster.model()),
+        Some(false) => Answer::Unsat,
+        None => Answer::Unknown,
+    }
+}
+
+/// Whether a block can be reached from the entry of its function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reach {
+    /// Down `path`, with `model` as the values on entry
+    Reached { path: Vec<u64>, model: Model },
+    /// Every path to the block is infeasible
+    Unreachable,
+    /// Some path couldn't be decided, or goes round a loop
+    Unknown,
+}
+
+/// Look for values on entry to `func` which reach `target`, trying at most `max_paths` paths
+/// which go through each block once
+pub fn reach(func: &Function, target: u64, max_paths: usize, budget: u64) -> Reach {
+    let mut paths = Vec::new();
+    let mut complete = true;
+    let mut path = vec![func.entry];
+    let mut stack = vec![func.successors(func.entry).into_iter()];
+    if func.entry == target {
+        paths.push(path.clone());
+    }
+    while let Some(successors) = stack.last_mut() {
+        let Some(next) = successors.next() else {
+            stack.pop();
+            path.pop();
+            continue;
+        };
+        if paths.len() >= max_paths {
+            complete = false;
+            break;
+        }
+        if path.contains(&next) {
+            complete = false;
+            continue;
+        }
+        path.push(next);
+        if next == target {
+            paths.push(path.clone());
+            path.pop();
+            continue;
+        }
+        stack.push(func.successors(next).into_iter());
+    }
+    for path in paths {
+        let Some(constraints) = path_constraints(func, &path) else {
+            complete = false;
+            continue;
+        };
+        match solve(func.arch, &constraints, budget) {
+            Answer::Sat(model) => return Reach::Reached { path, model },
+            Answer::Unsat => {}
+            Answer::Unknown => complete = false,
+        }
+    }
+    if complete {
+        Reach::Unreachable
+    } else {
+        Reach::Unknown
+    }
+}
+
+/// A literal: a variable, or its negation in the low bit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Lit(u32);
+
+impl Lit {
+    fn var(self) -> usize {
+        (self.0 >> 1) as usize
+    }
+
+    fn negative(self) -> bool {
+        self.0 & 1 != 0
+    }
+}
+
+impl std::ops::Not for Lit {
+    type Output = Lit;
+
+    fn not(self) -> Lit {
+        Lit(self.0 ^ 1)
+    }
+}
+
+/// A CDCL solver with two watched literals, first-UIP learning and restarts
+#[derive(Default)]
+struct Sat {
+    clauses: Vec<Vec<Lit>>,
+    /// The clauses watching each literal, to look at when it becomes false
+    watches: Vec<Vec<usize>>,
+    values: Vec<Option<bool>>,
+    levels: Vec<usize>,
+    reasons: Vec<Option<usize>>,
+    activity: Vec<f64>,
+    bump: f64,
+    /// The value each variable last had, to decide it the same way again
+    phases: Vec<bool>,
+    trail: Vec<Lit>,
+    /// Where each decision level starts on the trail
+    decisions: Vec<usize>,
+    propagated: usize,
+    units: Vec<Lit>,
+    empty: bool,
+}
+
+impl Sat {
+    fn new_var(&mut self) -> Lit {
+        let var = self.values.len() as u32;
+        self.values.push(None);
+        self.levels.push(0);
+        self.reasons.push(None);
+        self.activity.push(0.0);
+        self.phases.push(false);
+        self.watches.push(Vec::new());
+        self.watches.push(Vec::new());
+        Lit(var << 1)
+    }
+
+    fn add_clause(&mut self, lits: &[Lit]) {
+        let mut clause: Vec<Lit> = Vec::with_capacity(lits.len());
+        for lit in lits {
+            if clause.contains(&!*lit) {
+                return;
+            }
+            if !clause.contains(lit) {
+                clause.push(*lit);
+            }
+        }
+        match clause.len() {
+            0 => self.empty = true,
+            1 => self.units.push(clause[0]),
+            _ => {
+                self.attach(clause);
+            }
+        }
+    }
+
+    fn attach(&mut self, clause: Vec<Lit>) -> usize {
+        let index = self.clauses.len();
+        self.watches[clause[0].0 as usize].push(index);
+        self.watches[clause[1].0 as usize].push(index);
+        self.clauses.push(clause);
+        index
+    }
+
+    fn value(&self, lit: Lit) -> Option<bool> {
+        self.values[lit.var()].map(|value| value != lit.negative())
+    }
+
+    fn assign(&mut self, lit: Lit, reason: Option<usize>) {
+        let var = lit.var();
+        self.values[var] = Some(!lit.negative());
+        self.levels[var] = self.decisions.len();
+        self.reasons[var] = reason;
+        self.trail.push(lit);
+    }
+
+    /// Propagate the assignments on the trail; the clause which became false, if any
+    fn propagate(&mut self) -> Option<usize> {
+        while self.propagated < self.trail.len() {
+            let falsified = !self.trail[self.propagated];
+            self.propagated += 1;
+            let watching = std::mem::take(&mut self.watches[falsified.0 as usize]);
+            let mut kept = Vec::with_capacity(watching.len());
+            let mut conflict = None;
+            for (i, &index) in watching.iter().enumerate() {
+                if conflict.is_some() {
+                    kept.extend_from_slice(&watching[i..]);
+                    break;
+                }
+                let clause = &mut self.clauses[index];
+                if clause[0] == falsified {
+                    clause.swap(0, 1);
+                }
+                let first = clause[0];
+                if self.values[first.var()].map(|v| v != first.negative()) == Some(true) {
+                    kept.push(index);
+                    continue;
+                }
+                let replacement = (2..clause.len()).find(|&k| {
+                    let lit = clause[k];
+                    self.values[lit.var()].map(|v| v != lit.negative()) != Some(false)
+                });
+                if let Some(k) = replacement {
+                    clause.swap(1, k);
+                    let watch = clause[1];
+                    self.watches[watch.0 as usize].push(index);
+                    continue;
+                }
+                kept.push(index);
+                match self.value(first) {
+                    Some(false) => conflict = Some(index),
+                    _ => self.assign(first, Some(index)),
+                }
+            }
+            self.watches[falsified.0 as usize] = kept;
+            if conflict.is_some() {
+                return conflict;
+            }
+        }
+        None
+    }
+
+    /// The clause learnt from a conflict, asserting at index 0, and the level to go back to
+    fn analyze(&mut self, conflict: usize) -> (Vec<Lit>, usize) {
+        let level = self.decisions.len();
+        let mut seen = vec![false; self.values.len()];
+        let mut learnt = vec![Lit(0)];
+        let mut pending = 0;
+        let mut clause = conflict;
+        let mut index = self.trail.len();
+        let mut first = true;
+        let asserting = loop {
+            let skip = if first { 0 } else { 1 };
+            first = false;
+            for k in skip..self.clauses[clause].len() {
+                let lit = self.clauses[clause][k];
+                let var = lit.var();
+                if seen[var] || self.levels[var] == 0 {
+                    continue;
+                }
+                seen[var] = true;
+                self.activity[var] += self.bump;
+                if self.levels[var] == level {
+                    pending += 1;
+                } else {
+                    learnt.push(lit);
+                }
+            }
+            let lit = loop {
+                index -= 1;
+                if seen[self.trail[index].var()] {
+                    break self.trail[index];
+                }
+            };
+            seen[lit.var()] = false;
+            pending -= 1;
+            if pending == 0 {
+                break lit;
+            }
+            clause = self.reasons[lit.var()].expect("implied literals have reasons");
+        };
+        learnt[0] = !asserting;
+        let mut back = 0;
+        for k in 1..learnt.len() {
+            let level = self.levels[learnt[k].var()];
+            if level > back {
+                back = level;
+                learnt.swap(1, k);
+            }
+        }
+        self.bump *= 1.05;
+        if self.bump > 1e100 {
+            self.activity.iter_mut().for_each(|a| *a *= 1e-100);
+            self.bump *= 1e-100;
+        }
+        (learnt, back)
+    }
+
+    fn backtrack(&mut self, level: usize) {
+        if self.decisions.len() <= level {
+            return;
+        }
+        let start = self.decisions[level];
+        for lit in self.trail.drain(start..) {
+            self.phases[lit.var()] = !lit.negative();
+            self.values[lit.var()] = None;
+            self.reasons[lit.var()] = None;
+        }
+        self.decisions.truncate(level);
+        self.propagated = start;
+    }
+
+    fn decide(&mut self) -> Option<Lit> {
+        let var = (0..self.values.len())
+            .filter(|&v| self.values[v].is_none())
+            .max_by(|&a, &b| self.activity[a].total_cmp(&self.activity[b]))?;
+        Some(Lit(((var as u32) << 1) | u32::from(!self.phases[var])))
+    }
+
+    /// Whether the clauses are satisfiable; None if `budget` conflicts didn't decide it
+    fn solve(&mut self, budget: u64) -> Option<bool> {
+        if self.empty {
+            return Some(false);
+        }
+        self.bump = 1.0;
+        for lit in std::mem::take(&mut self.units) {
+            match self.value(lit) {
+                Some(true) => {}
+                Some(false) => return Some(false),
+                None => self.assign(lit, None),
+            }
+        }
+        let mut conflicts = 0;
+        let mut restart = 100;
+        loop {
+            if let Some(conflict) = self.propagate() {
+                if self.decisions.is_empty() {
+                    return Some(false);
+                }
+                conflicts += 1;
+                if conflicts > budget {
+                    return None;
+                }
+                let (learnt, back) = self.analyze(conflict);
+                self.backtrack(back);
+                let asserting = learnt[0];
+                if learnt.len() == 1 {
+                    self.assign(asserting, None);
+                } else {
+                    let index = self.attach(learnt);
+                    self.assign(asserting, Some(index));
+                }
+                continue;
+            }
+            if conflicts >= restart {
+                restart += restart / 2;
+                self.backtrack(0);
+            }
+            let Some(lit) = self.decide() else {
+                return Some(true);
+            };
+            self.decisions.push(self.trail.len());
+            self.assign(lit, None);
+        }
+    }
+}
+
+type Bits = Vec<Lit>;
+
+/// Encodes constraints as clauses over the bits of their inputs
+struct Blaster {
+    arch: Arch,
+    width: u32,
+    sat: Sat,
+    truth: Lit,
+    registers: BTreeMap<RegId, Bits>,
+    loads: BTreeMap<Expr, Bits>,
+}
+
+impl Blaster {
+    fn new(arch: Arch, width: u32) -> Self {
+        let mut sat = Sat::default();
+        let truth = sat.new_var();
+        sat.add_clause(&[truth]);
+        Blaster {
+            arch,
+            width,
+            sat,
+            truth,
+            registers: BTreeMap::new(),
+            loads: BTreeMap::new(),
+        }
+    }
+
+    fn constant(&self, value: bool) -> Lit {
+        if value {
+            self.truth
+        } else {
+            !self.truth
+        }
+    }
+
+    fn inputs(&mut self) -> Bits {
+        (0..self.width).map(|_| self.sat.new_var()).collect()
+    }
+
+    fn and(&mut self, a: Lit, b: Lit) -> Lit {
+        if a == !self.truth || b == !self.truth || a == !b {
+            return !self.truth;
+        }
+        if a == self.truth || a == b {
+            return b;
+        }
+        if b == self.truth {
+            return a;
+        }
+        let out = self.sat.new_var();
+        self.sat.add_clause(&[!out, a]);
+        self.sat.add_clause(&[!out, b]);
+        self.sat.add_clause(&[out, !a, !b]);
+        out
+    }
+
+    fn or(&mut self, a: Lit, b: Lit) -> Lit {
+        !self.and(!a, !b)
+    }
+
+    fn xor(&mut self, a: Lit, b: Lit) -> Lit {
+        if a == b {
+            return !self.truth;
+        }
+        if a == !b {
+            return self.truth;
+        }
+        for (x, y) in [(a, b), (b, a)] {
+            if x == self.truth {
+                return !y;
+            }
+            if x == !self.truth {
+                return y;
+            }
+        }
+        let out = self.sat.new_var();
+        self.sat.add_clause(&[!out, a, b]);
+        self.sat.add_clause(&[!out, !a, !b]);
+        self.sat.add_clause(&[out, !a, b]);
+        self.sat.add_clause(&[out, a, !b]);
+        out
+    }
+
+    /// `then` if `cond`, else `other`
+    fn mux(&mut self, cond: Lit, then: Lit, other: Lit) -> Lit {
+        let (a, b) = (self.and(cond, then), self.and(!cond, other));
+        self.or(a, b)
+    }
+
+    /// The sum and the carry out
+    fn add(&mut self, a: &[Lit], b: &[Lit], carry: Lit) -> (Bits, Lit) {
+        let mut carry = carry;
+        let mut sum = Vec::with_capacity(a.len());
+        for (&x, &y) in a.iter().zip(b) {
+            let half = self.xor(x, y);
+            sum.push(self.xor(half, carry));
+            let (both, either) = (self.and(x, y), self.and(half, carry));
+            carry = self.or(both, either);
+        }
+        (sum, carry)
+    }
+
+    fn mul(&mut self, a: &[Lit], b: &[Lit]) -> Bits {
+        let mut product = vec![!self.truth; a.len()];
+        for (shift, &bit) in b.iter().enumerate() {
+            let mut partial = vec![!self.truth; shift];
+            for &x in &a[..a.len() - shift] {
+                partial.push(self.and(x, bit));
+            }
+            product = self.add(&product, &partial, !self.truth).0;
+        }
+        product
+    }
+
+    /// Shift by a variable amount; `fill` is what comes in, and all there is once the amount
+    /// is the width or more
+    fn shift(&mut self, a: &[Lit], amount: &[Lit], left: bool, fill: Lit) -> Bits {
+        let width = a.len();
+        let mut value = a.to_vec();
+        let mut stage = 0;
+        while 1 << stage < width {
+            let by = 1 << stage;
+            let shifted: Bits = (0..width)
+                .map(|i| match left {
+                    true if i >= by => value[i - by],
+                    false if i + by < width => value[i + by],
+                    _ => fill,
+                })
+                .collect();
+            value = (0..width)
+                .map(|i| self.mux(amount[stage], shifted[i], value[i]))
+                .collect();
+            stage += 1;
+        }
+        let mut past = !self.truth;
+        for &bit in &amount[stage..] {
+            past = self.or(past, bit);
+        }
+        value
+            .into_iter()
+            .map(|bit| self.mux(past, fill, bit))
+            .collect()
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Bits {
+        let width = self.width as usize;
+        match expr {
+            Expr::Const(c) => (0..width).map(|i| self.constant(c >> i & 1 != 0)).collect(),
+            Expr::Reg(r) => match self.registers.get(r) {
+                Some(bits) => bits.clone(),
+                None => {
+                    let bits = self.inputs();
+                    self.registers.insert(*r, bits.clone());
+                    bits
+                }
+            },
+            Expr::Load(_) => match self.loads.get(expr) {
+                Some(bits) => bits.clone(),
+                None => {
+                    let bits = self.inputs();
+                    self.loads.insert(expr.clone(), bits.clone());
+                    bits
+                }
+            },
+            Expr::Unary(op, a) => {
+                let a: Bits = self.expr(a).into_iter().map(|bit| !bit).collect();
+                match op {
+                    UnOp::Not => a,
+                    UnOp::Neg => self.add(&a, &vec![!self.truth; width], self.truth).0,
+                }
+            }
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.expr(a), self.expr(b));
+                match op {
+                    BinOp::Add => self.add(&a, &b, !self.truth).0,
+                    BinOp::Sub => self.sub(&a, &b).0,
+                    BinOp::Mul => self.mul(&a, &b),
+                    BinOp::And => (0..width).map(|i| self.and(a[i], b[i])).collect(),
+                    BinOp::Or => (0..width).map(|i| self.or(a[i], b[i])).collect(),
+                    BinOp::Xor => (0..width).map(|i| self.xor(a[i], b[i])).collect(),
+                    BinOp::Shl => self.shift(&a, &b, true, !self.truth),
+                    BinOp::Shr => self.shift(&a, &b, false, !self.truth),
+                    BinOp::Sar => self.shift(&a, &b, false, a[width - 1]),
+                }
+            }
+        }
+    }
+
+    fn sub(&mut self, a: &[Lit], b: &[Lit]) -> (Bits, Lit) {
+        let b: Bits = b.iter().map(|bit| !*bit).collect();
+        self.add(a, &b, self.truth)
+    }
+
+    /// The flags `op` sets on `a` and `b`
+    fn flags(&mut self, op: FlagOp, a: &Expr, b: &Expr) -> [Lit; 6] {
+        let width = self.width as usize;
+        let (a, b) = (self.expr(a), self.expr(b));
+        let (result, carry) = match op {
+            FlagOp::Add => self.add(&a, &b, !self.truth),
+            FlagOp::Sub => self.sub(&a, &b),
+            FlagOp::And => (
+                (0..width).map(|i| self.and(a[i], b[i])).collect(),
+                self.truth,
+            ),
+            FlagOp::Or => (
+                (0..width).map(|i| self.or(a[i], b[i])).collect(),
+                self.truth,
+            ),
+            FlagOp::Xor => (
+                (0..width).map(|i| self.xor(a[i], b[i])).collect(),
+                self.truth,
+            ),
+        };
+        let sign = result[width - 1];
+        let overflow = match op {
+            FlagOp::Add | FlagOp::Sub => {
+                // The operands agree in sign, the second one negated for a subtraction, and
+                // the result doesn't
+                let same = self.xor(a[width - 1], b[width - 1]);
+                let same = if op == FlagOp::Add { !same } else { same };
+                let flipped = self.xor(sign, a[width - 1]);
+                self.and(same, flipped)
+            }
+            _ => !self.truth,
+        };
+        let mut any = !self.truth;
+        for &bit in &result {
+            any = self.or(any, bit);
+        }
+        let mut odd = !self.truth;
+        for &bit in &result[..8.min(width)] {
+            odd = self.xor(odd, bit);
+        }
+        // Whether the carry is a borrow, as on x86, shows in subtracting 1 from 0
+        let borrow =
+            op.update(self.arch, 0, 1, self.width).effect(Flag::Carry) == Effect::Set(true);
+        let carry = if op == FlagOp::Sub && borrow {
+            !carry
+        } else {
+            carry
+        };
+        let template = op.update(self.arch, 0, 1, self.width);
+        FLAGS.map(|flag| {
+            let logic = !matches!(op, FlagOp::Add | FlagOp::Sub);
+            match (template.effect(flag), flag) {
+                (Effect::Set(_), Flag::Zero) => !any,
+                (Effect::Set(_), Flag::Sign) => sign,
+                (Effect::Set(_), Flag::Parity) => !odd,
+                (Effect::Set(value), Flag::Carry | Flag::Overflow) if logic => self.constant(value),
+                (Effect::Set(_), Flag::Carry) => carry,
+                (Effect::Set(_), Flag::Overflow) => overflow,
+                _ => self.sat.new_var(),
+            }
+        })
+    }
+
+    /// A literal true when `cond` holds on `flags`, from its truth table
+    fn condition(&mut self, cond: Condition, flags: [Lit; 6]) -> Lit {
+        let assignment = |bits: usize| {
+            let mut values = Flags::unknown();
+            for (i, flag) in FLAGS.iter().enumerate() {
+                values.set(*flag, Some(bits >> i & 1 != 0));
+            }
+            cond.eval(&values) == Some(true)
+        };
+        let relevant: Vec<usize> = (0..FLAGS.len())
+            .filter(|i| {
+                (0..1 << FLAGS.len()).any(|bits| assignment(bits) != assignment(bits ^ 1 << i))
+            })
+            .collect();
+        let mut holds = !self.truth;
+        for combination in 0..1usize << relevant.len() {
+            let bits = relevant
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (k, i)| bits | (combination >> k & 1) << i);
+            if !assignment(bits) {
+                continue;
+            }
+            let mut term = self.truth;
+            for i in &relevant {
+                let lit = if bits >> i & 1 != 0 {
+                    flags[*i]
+                } else {
+                    !flags[*i]
+                };
+                term = self.and(term, lit);
+            }
+            holds = self.or(holds, term);
+        }
+        holds
+    }
+
+    fn assert(&mut self, constraint: &Constraint) {
+        match constraint {
+            Constraint::Flags {
+                op,
+                a,
+                b,
+                cond,
+                holds,
+            } => {
+                let flags = self.flags(*op, a, b);
+                let lit = self.condition(*cond, flags);
+                self.sat.add_clause(&[if *holds { lit } else { !lit }]);
+            }
+            Constraint::Equal(a, b) => {
+                let (a, b) = (self.expr(a), self.expr(b));
+                for (x, y) in a.into_iter().zip(b) {
+                    let differ = self.xor(x, y);
+                    self.sat.add_clause(&[!differ]);
+                }
+            }
+        }
+    }
+
+    fn value(&self, bits: &[Lit]) -> u64 {
+        bits.iter().enumerate().fold(0, |value, (i, lit)| {
+            let bit = self.sat.value(*lit).unwrap_or(false);
+            value | u64::from(bit) << i
+        })
+    }
+
+    fn model(&self) -> Model {
+        Model {
+            registers: self
+                .registers
+                .iter()
+                .map(|(reg, bits)| (*reg, self.value(bits)))
+                .collect(),
+            loads: self
+                .loads
+                .iter()
+                .map(|(addr, bits)| (addr.clone(), self.value(bits)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::registers::RegisterModel,
+        symbolic::{Block, Insn},
+    };
+
+    #[test]
+    fn branch_feasibility() {
+        // imul eax, eax, 7; cmp eax, 0x2a; jne out
+        //   test ecx, ecx; jne deep       ; reachable with eax = 6 and ecx != 0
+        // lea ebx, [eax*2]; cmp ebx, 7; je never  ; even, so never 7
+        let model = RegisterModel::new(Arch::I386);
+        let (eax, ebx, ecx) = (
+            model.by_name("eax").unwrap(),
+            model.by_name("ebx").unwrap(),
+            model.by_name("ecx").unwrap(),
+        );
+        let insn = |va, stmts| Insn { va, stmts };
+        let block = |va: u64, insns, end| Block {
+            va,
+            insns,
+            end_va: va + 8,
+            end,
+        };
+        let branch = |cond, taken, fallthrough| Terminator::Branch {
+            cond,
+            taken,
+            fallthrough,
+        };
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![
+                insn(
+                    0x1000,
+                    vec![Stmt::Set(
+                        eax,
+                        Expr::binary(BinOp::Mul, Expr::Reg(eax), Expr::Const(7), 32),
+                    )],
+                ),
+                insn(
+                    0x1003,
+                    vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(0x2a))],
+                ),
+            ],
+            branch(Condition::NotZero, 0x1300, 0x1100),
+        ));
+        func.add_block(block(
+            0x1100,
+            vec![insn(
+                0x1100,
+                vec![Stmt::Flags(FlagOp::And, Expr::Reg(ecx), Expr::Reg(ecx))],
+            )],
+            branch(Condition::NotZero, 0x1200, 0x1300),
+        ));
+        func.add_block(block(0x1200, vec![], Terminator::Return));
+        func.add_block(block(
+            0x1300,
+            vec![
+                insn(
+                    0x1300,
+                    vec![Stmt::Set(
+                        ebx,
+                        Expr::binary(BinOp::Shl, Expr::Reg(eax), Expr::Const(1), 32),
+                    )],
+                ),
+                insn(
+                    0x1303,
+                    vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(ebx), Expr::Const(7))],
+                ),
+            ],
+            branch(Condition::Zero, 0x1400, 0x1500),
+        ));
+        func.add_block(block(0x1400, vec![], Terminator::Return));
+        func.add_block(block(0x1500, vec![], Terminator::Return));
+
+        let Reach::Reached { path, model } = reach(&func, 0x1200, 16, DEFAULT_BUDGET) else {
+            panic!("0x1200 is reachable");
+        };
+        assert_eq!(path, [0x1000, 0x1100, 0x1200]);
+        let (x, c) = (model.registers[&eax], model.registers[&ecx]);
+        assert_eq!(x.wrapping_mul(7) & 0xffff_ffff, 0x2a);
+        assert_ne!(c, 0);
+        assert_eq!(reach(&func, 0x1400, 16, DEFAULT_BUDGET), Reach::Unreachable);
+        assert!(matches!(
+            reach(&func, 0x1500, 16, DEFAULT_BUDGET),
+            Reach::Reached { .. }
+        ));
+
+        // Unsigned and signed comparisons follow the flags of the architecture
+        let below = |cond, holds| Constraint::Flags {
+            op: FlagOp::Sub,
+            a: Expr::Reg(eax),
+            b: Expr::Const(0x10),
+            cond,
+            holds,
+        };
+        let Answer::Sat(model) = solve(
+            Arch::I386,
+            &[below(Condition::Carry, false), below(Condition::Less, true)],
+            DEFAULT_BUDGET,
+        ) else {
+            panic!("a negative value is above 0x10 unsigned");
+        };
+        assert!(model.registers[&eax] >= 0x8000_0000);
+        assert_eq!(
+            solve(
+                Arch::I386,
+                &[
+                    below(Condition::Carry, true),
+                    below(Condition::Greater, true)
+                ],
+                DEFAULT_BUDGET
+            ),
+            Answer::Unsat
+        );
+    }
+}
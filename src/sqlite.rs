@@ -0,0 +1,223 @@
+//! Exporting a workspace to SQLite, to query analysis results with SQL.
+//!
+//! [`export`] writes the workspace into a database of the schema below, [`SCHEMA`], creating
+//! the tables if they aren't there and replacing their rows if they are. The rows go in sorted,
+//! so the same workspace always makes the same database. Addresses are unsigned; the
+//! `type` and `flags` of a location or xref are the `LOC_*`, `REF_*` and `BR_*` constants of
+//! [`crate::constants`].
+//!
+//! | table       | columns                                                              |
+//! |-------------|----------------------------------------------------------------------|
+//! | `meta`      | `key`, `value`: the workspace metadata, `Format`, `Architecture`, ... |
+//! | `files`     | `name`, `imagebase`                                                  |
+//! | `segments`  | `va`, `size`, `name`, `file`                                         |
+//! | `locations` | `va`, `size`, `type`, `tinfo` (`va:size` pairs, comma separated)     |
+//! | `functions` | `va`, `name`, `size`                                                 |
+//! | `xrefs`     | `from_va`, `to_va`, `type`, `flags`                                  |
+//! | `names`     | `va`, `name`                                                         |
+//! | `comments`  | `va`, `comment`                                                      |
+//! | `strings`   | `va`, `size`, `unicode`, `value`                                     |
+//! | `imports`   | `va`, `name`: the import slots                                       |
+//! | `exports`   | `va`, `name`                                                         |
+//!
+//! ```sql
+//! -- the functions calling an import, through its slot or a trampoline
+//! SELECT DISTINCT f.name FROM xrefs x JOIN functions f
+//!   ON x.from_va >= f.va AND x.from_va < f.va + f.size
+//!   JOIN imports i ON x.to_va = i.va WHERE i.name = 'kernel32.CreateFileW';
+//! ```
+
+use crate::{
+    constants::{LOC_STRING, LOC_UNI},
+    driver::string_at,
+    workspace::VivWorkspace,
+};
+use rusqlite::{params, Connection, Result};
+use std::path::Path;
+
+/// The tables [`export`] writes
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS files (name TEXT PRIMARY KEY, imagebase INTEGER NOT NULL);
+CREATE TABLE IF NOT EXISTS segments (
+    va INTEGER NOT NULL, size INTEGER NOT NULL, name TEXT NOT NULL, file TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS locations (
+    va INTEGER PRIMARY KEY, size INTEGER NOT NULL, type INTEGER NOT NULL, tinfo TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS functions (va INTEGER PRIMARY KEY, name TEXT, size INTEGER NOT NULL);
+CREATE TABLE IF NOT EXISTS xrefs (
+    from_va INTEGER NOT NULL, to_va INTEGER NOT NULL, type INTEGER NOT NULL,
+    flags INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS xrefs_from ON xrefs (from_va);
+CREATE INDEX IF NOT EXISTS xrefs_to ON xrefs (to_va);
+CREATE TABLE IF NOT EXISTS names (va INTEGER PRIMARY KEY, name TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS comments (va INTEGER PRIMARY KEY, comment TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS strings (
+    va INTEGER PRIMARY KEY, size INTEGER NOT NULL, unicode INTEGER NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS imports (va INTEGER PRIMARY KEY, name TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS exports (va INTEGER PRIMARY KEY, name TEXT NOT NULL);
+";
+
+const TABLES: &[&str] = &[
+    "meta",
+    "files",
+    "segments",
+    "locations",
+    "functions",
+    "xrefs",
+    "names",
+    "comments",
+    "strings",
+    "imports",
+    "exports",
+];
+
+/// Write `workspace` into the database of `conn`, in one transaction
+pub fn export(workspace: &VivWorkspace, conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    for table in TABLES {
+        tx.execute(&format!("DELETE FROM {}", table), [])?;
+    }
+
+    let mut stmt = tx.prepare("INSERT INTO meta VALUES (?1, ?2)")?;
+    for (key, value) in workspace.get_meta_dict() {
+        stmt.execute(params![key, value])?;
+    }
+    drop(stmt);
+
+    let mut stmt = tx.prepare("INSERT INTO files VALUES (?1, ?2)")?;
+    let mut files = workspace.get_files();
+    files.sort();
+    for file in files {
+        let imagebase = workspace.get_file_meta(&file, "imagebase");
+        stmt.execute(params![file, addr(imagebase)])?;
+    }
+    drop(stmt);
+
+    let mut stmt = tx.prepare("INSERT INTO segments VALUES (?1, ?2, ?3, ?4)")?;
+    let mut segments = workspace.get_segments();
+    segments.sort();
+    for (va, size, name, file) in segments {
+        stmt.execute(params![addr(va), size, name, file])?;
+    }
+    drop(stmt);
+
+    let mut stmt = tx.prepare("INSERT INTO locations VALUES (?1, ?2, ?3, ?4)")?;
+    let mut strings = tx.prepare("INSERT INTO strings VALUES (?1, ?2, ?3, ?4)")?;
+    let mut locations = workspace.get_locations(None, None);
+    locations.sort_by_key(|loc| addr(loc.0));
+    for (va, size, ltype, tinfo) in locations {
+        let tinfo: Vec<String> = tinfo
+            .iter()
+            .map(|(va, size)| format!("{}:{}", addr(*va), size))
+            .collect();
+        stmt.execute(params![addr(va), size, ltype, tinfo.join(",")])?;
+        if ltype == LOC_STRING || ltype == LOC_UNI {
+            if let Some(text) = string_at(workspace, va, size, ltype == LOC_UNI) {
+                strings.execute(params![addr(va), size, ltype == LOC_UNI, text])?;
+            }
+        }
+    }
+    drop((stmt, strings));
+
+    let mut stmt = tx.prepare("INSERT INTO functions VALUES (?1, ?2, ?3)")?;
+    let mut functions = workspace.get_functions();
+    functions.sort_by_key(|va| addr(*va));
+    for fva in functions {
+        let size = workspace
+            .get_function_meta_dict(fva)
+            .get("Size")
+            .copied()
+            .unwrap_or(0);
+        stmt.execute(params![addr(fva), workspace.get_name(fva, false), size])?;
+    }
+    drop(stmt);
+
+    let mut stmt = tx.prepare("INSERT INTO xrefs VALUES (?1, ?2, ?3, ?4)")?;
+    let mut xrefs = workspace.get_xrefs(None);
+    xrefs.sort_by_key(|(from, to, rtype, rflags)| (addr(*from), addr(*to), *rtype, *rflags));
+    for (from, to, rtype, rflags) in xrefs {
+        stmt.execute(params![addr(from), addr(to), rtype, rflags])?;
+    }
+    drop(stmt);
+
+    for (table, mut rows) in [
+        ("names", workspace.get_names()),
+        ("comments", workspace.get_comments().into_iter().collect()),
+        ("imports", workspace.get_imports()),
+        ("exports", workspace.get_exports()),
+    ] {
+        let mut stmt = tx.prepare(&format!("INSERT OR REPLACE INTO {} VALUES (?1, ?2)", table))?;
+        rows.sort_by_key(|(va, _)| addr(*va));
+        for (va, text) in rows {
+            stmt.execute(params![addr(va), text])?;
+        }
+    }
+
+    tx.commit()
+}
+
+/// Write `workspace` into the SQLite database at `path`, creating it if it doesn't exist
+pub fn export_to_path<P: AsRef<Path>>(workspace: &VivWorkspace, path: P) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    export(workspace, &mut conn)
+}
+
+/// A VA as the unsigned number it is
+fn addr(va: i32) -> i64 {
+    va as u32 as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{BR_PROC, MM_READ, REF_CODE},
+        memory::Memory,
+    };
+
+    #[test]
+    fn exports_workspace() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_meta("Format", Some("pe".to_string()));
+        let mut bytes = vec![0xc3; 0x10];
+        bytes.extend(b"hello\0");
+        ws.add_memory_map(0x80001000u32 as i32, MM_READ, "test", bytes, None);
+        ws.add_function(0x80001000u32 as i32, vec![(0x80001000u32 as i32, 1)]);
+        ws.make_name(0x80001000u32 as i32, "main".to_string(), false, false);
+        ws.add_location(0x80001010u32 as i32, 6, LOC_STRING, Some(vec![]));
+        ws.add_xref(
+            0x80001000u32 as i32,
+            0x80001010u32 as i32,
+            REF_CODE,
+            BR_PROC,
+        );
+        ws.set_comment(0x80001000u32 as i32, "entry", false);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        export(&ws, &mut conn).unwrap();
+        // a second export replaces the rows of the first
+        export(&ws, &mut conn).unwrap();
+        let query = |sql: &str| -> String { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(query("SELECT value FROM meta WHERE key = 'Format'"), "pe");
+        assert_eq!(query("SELECT name FROM functions WHERE va = 2147487744"), "main");
+        assert_eq!(query("SELECT value FROM strings"), "hello");
+        assert_eq!(query("SELECT comment FROM comments"), "entry");
+        assert_eq!(
+            query(
+                "SELECT s.value FROM xrefs x JOIN functions f ON x.from_va = f.va \
+                 JOIN strings s ON x.to_va = s.va WHERE f.name = 'main'"
+            ),
+            "hello"
+        );
+        let xrefs: i64 = conn
+            .query_row("SELECT COUNT(*) FROM xrefs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(xrefs, 1);
+    }
+}
@@ -0,0 +1,438 @@
+//! Worst case stack depth, from the frame sizes of lifted functions and the call graph.
+//!
+//! The frame of a function is the furthest its stack pointer gets below where it was at the
+//! entry, as the symbolic IL tracks it. The depth of an entry point (a reset or interrupt
+//! handler, a thread) is the largest sum of frames along the calls it can make, plus what each
+//! call instruction pushes. Recursion makes the depth unbounded unless one of the functions of
+//! the cycle is annotated with how deep it recurses at most.
+//!
+//! Functions without a known frame count for nothing and are reported, as are calls through
+//! pointers, which the call graph doesn't have; the depths are only as good as the two.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::Arch,
+    symbolic::{Function, State},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// How many bytes below the stack pointer at its entry the function uses at most. None if the
+/// stack pointer isn't known all through the function, as after an `alloca`.
+pub fn frame_size(func: &Function) -> Option<u64> {
+    let preds = func.predecessors();
+    let ends = func.stack_offsets();
+    let mut deepest = 0i64;
+    for va in func.reverse_postorder() {
+        let start = if va == func.entry {
+            0
+        } else {
+            let mut incoming = preds
+                .get(&va)
+                .into_iter()
+                .flatten()
+                .filter_map(|pred| ends.get(pred).copied());
+            let first = incoming.next()??;
+            if !incoming.all(|offset| offset == Some(first)) {
+                return None;
+            }
+            first
+        };
+        let mut state = State::new(func.arch);
+        for insn in &func.blocks[&va].insns {
+            state.exec_insn(insn);
+            deepest = deepest.min(start + state.stack_offset()?);
+        }
+    }
+    Some(deepest.unsigned_abs())
+}
+
+/// What a call instruction pushes on the stack
+pub fn call_overhead(arch: Arch) -> u64 {
+    match arch {
+        Arch::I386 => 4,
+        Arch::Amd64 => 8,
+        Arch::Msp430 | Arch::H8 => 2,
+        // the return address goes to the link register
+        Arch::ArmV7 | Arch::Thumb16 | Arch::Thumb | Arch::A64 => 0,
+    }
+}
+
+/// The functions each function of the workspace calls, by the procedural branches between them
+pub fn call_graph(workspace: &VivWorkspace) -> BTreeMap<u64, BTreeSet<u64>> {
+    let mut graph: BTreeMap<u64, BTreeSet<u64>> = workspace
+        .get_functions()
+        .into_iter()
+        .map(|fva| (fva as u32 as u64, BTreeSet::new()))
+        .collect();
+    for (from, to, _, rflags) in workspace.get_xrefs(Some(REF_CODE)) {
+        if rflags & BR_PROC == 0 || workspace.get_function(to) != Some(to) {
+            continue;
+        }
+        if let Some(caller) = workspace.get_function(from) {
+            graph
+                .entry(caller as u32 as u64)
+                .or_default()
+                .insert(to as u32 as u64);
+        }
+    }
+    graph
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The entry points to work out the depth of. When empty, every function nothing calls.
+    pub entries: Vec<u64>,
+    /// How many times over a function recurses at most, for the functions known to
+    pub recursion: BTreeMap<u64, u64>,
+    /// The stack there is, to tell which entry points risk overflowing it
+    pub stack_limit: Option<u64>,
+}
+
+/// The worst case stack depth of an entry point
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryDepth {
+    pub entry: u64,
+    /// None where unannotated recursion makes it unbounded
+    pub depth: Option<u64>,
+    /// The calls down to the deepest point, from the entry
+    pub path: Vec<u64>,
+    /// Recursive functions reached with no bound on their recursion
+    pub recursive: BTreeSet<u64>,
+    /// Functions reached whose frame isn't known, which count for nothing
+    pub unknown: BTreeSet<u64>,
+}
+
+impl EntryDepth {
+    /// Whether the stack may not be enough: the depth goes over the limit or is unbounded
+    pub fn at_risk(&self, limit: u64) -> bool {
+        self.depth.is_none_or(|depth| depth > limit)
+    }
+}
+
+impl fmt::Display for EntryDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.depth {
+            Some(depth) => write!(f, "{:#x}: {} bytes", self.entry, depth)?,
+            None => write!(f, "{:#x}: unbounded", self.entry)?,
+        }
+        let path: Vec<String> = self.path.iter().map(|va| format!("{:#x}", va)).collect();
+        write!(f, " via {}", path.join(" -> "))?;
+        if !self.unknown.is_empty() {
+            write!(f, " ({} frames unknown)", self.unknown.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackReport {
+    pub frames: BTreeMap<u64, Option<u64>>,
+    pub entries: Vec<EntryDepth>,
+    pub stack_limit: Option<u64>,
+}
+
+impl StackReport {
+    /// The entry points which may overflow the stack limit
+    pub fn at_risk(&self) -> Vec<&EntryDepth> {
+        let Some(limit) = self.stack_limit else {
+            return Vec::new();
+        };
+        self.entries
+            .iter()
+            .filter(|entry| entry.at_risk(limit))
+            .collect()
+    }
+}
+
+/// The worst case below a strongly connected component of the call graph
+#[derive(Clone, Debug, Default)]
+struct Worst {
+    depth: Option<u64>,
+    path: Vec<u64>,
+    recursive: BTreeSet<u64>,
+    unknown: BTreeSet<u64>,
+}
+
+struct Tarjan<'a> {
+    graph: &'a BTreeMap<u64, BTreeSet<u64>>,
+    index: BTreeMap<u64, usize>,
+    low: BTreeMap<u64, usize>,
+    stack: Vec<u64>,
+    on_stack: BTreeSet<u64>,
+    /// The functions being visited, with the callees left to visit
+    work: Vec<(u64, Vec<u64>)>,
+    components: Vec<Vec<u64>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, va: u64) {
+        let n = self.index.len();
+        self.index.insert(va, n);
+        self.low.insert(va, n);
+        self.stack.push(va);
+        self.on_stack.insert(va);
+        let callees = self.graph.get(&va).into_iter().flatten().rev().copied();
+        self.work.push((va, callees.collect()));
+    }
+
+    fn lower(&mut self, va: u64, low: usize) {
+        let low = self.low[&va].min(low);
+        self.low.insert(va, low);
+    }
+
+    /// Visit from `root` without recursing, as call chains can be deep
+    fn run(&mut self, root: u64) {
+        self.visit(root);
+        while let Some((va, callees)) = self.work.last_mut() {
+            let va = *va;
+            if let Some(callee) = callees.pop() {
+                if !self.index.contains_key(&callee) {
+                    self.visit(callee);
+                } else if self.on_stack.contains(&callee) {
+                    self.lower(va, self.index[&callee]);
+                }
+                continue;
+            }
+            self.work.pop();
+            if let Some((caller, _)) = self.work.last() {
+                self.lower(*caller, self.low[&va]);
+            }
+            if self.low[&va] == self.index[&va] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == va {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                self.components.push(component);
+            }
+        }
+    }
+}
+
+/// The strongly connected components of the call graph, callees before their callers
+fn components(graph: &BTreeMap<u64, BTreeSet<u64>>) -> Vec<Vec<u64>> {
+    let mut tarjan = Tarjan {
+        graph,
+        index: BTreeMap::new(),
+        low: BTreeMap::new(),
+        stack: Vec::new(),
+        on_stack: BTreeSet::new(),
+        work: Vec::new(),
+        components: Vec::new(),
+    };
+    for &root in graph.keys() {
+        if !tarjan.index.contains_key(&root) {
+            tarjan.run(root);
+        }
+    }
+    tarjan.components
+}
+
+/// Work out the frame of every lifted function and the worst case stack depth of the entry
+/// points, over the call graph `graph` (see [`call_graph`]).
+pub fn analyze(
+    functions: &BTreeMap<u64, Function>,
+    graph: &BTreeMap<u64, BTreeSet<u64>>,
+    options: &Options,
+) -> StackReport {
+    // Every function called or lifted is a node of the graph
+    let mut graph = graph.clone();
+    let nodes: Vec<u64> = graph
+        .values()
+        .flatten()
+        .chain(functions.keys())
+        .copied()
+        .collect();
+    for va in nodes {
+        graph.entry(va).or_default();
+    }
+    let frames: BTreeMap<u64, Option<u64>> = graph
+        .keys()
+        .map(|va| (*va, functions.get(va).and_then(frame_size)))
+        .collect();
+    let overhead = |va: u64| {
+        functions
+            .get(&va)
+            .map_or(0, |func| call_overhead(func.arch))
+    };
+    let cost = |va: u64| frames[&va].unwrap_or(0) + overhead(va);
+
+    let components = components(&graph);
+    let component_of: BTreeMap<u64, usize> = components
+        .iter()
+        .enumerate()
+        .flat_map(|(i, members)| members.iter().map(move |va| (*va, i)))
+        .collect();
+    let mut worst: Vec<Worst> = Vec::with_capacity(components.len());
+    for (i, members) in components.iter().enumerate() {
+        let recursive = members.len() > 1 || graph[&members[0]].contains(&members[0]);
+        let mut here = Worst::default();
+        let own = if recursive {
+            let bound = members
+                .iter()
+                .filter_map(|va| options.recursion.get(va))
+                .max();
+            match bound {
+                Some(bound) => Some(members.iter().map(|va| cost(*va)).sum::<u64>() * bound),
+                None => {
+                    here.recursive.extend(members);
+                    None
+                }
+            }
+        } else {
+            Some(cost(members[0]))
+        };
+        here.unknown
+            .extend(members.iter().filter(|va| frames[*va].is_none()));
+        // The deepest of the components called from this one, all of which come before it
+        let mut deepest: Option<&Worst> = None;
+        let mut unbounded = false;
+        for callee in members.iter().flat_map(|va| graph[va].iter()) {
+            let j = component_of[callee];
+            if j == i {
+                continue;
+            }
+            let below = &worst[j];
+            here.recursive.extend(&below.recursive);
+            here.unknown.extend(&below.unknown);
+            match below.depth {
+                None => unbounded = true,
+                Some(depth) if deepest.is_none_or(|d| d.depth < Some(depth)) => {
+                    deepest = Some(below)
+                }
+                _ => {}
+            }
+        }
+        here.depth = match (own, unbounded) {
+            (Some(own), false) => Some(own + deepest.and_then(|d| d.depth).unwrap_or(0)),
+            _ => None,
+        };
+        here.path = vec![members[0]];
+        if let Some(deepest) = deepest {
+            here.path.extend(&deepest.path);
+        }
+        worst.push(here);
+    }
+
+    let entries: Vec<u64> = if options.entries.is_empty() {
+        let called: BTreeSet<u64> = graph
+            .iter()
+            .flat_map(|(caller, callees)| callees.iter().filter(move |callee| *callee != caller))
+            .copied()
+            .collect();
+        graph
+            .keys()
+            .filter(|va| !called.contains(va))
+            .copied()
+            .collect()
+    } else {
+        options.entries.clone()
+    };
+    let entries = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let worst = &worst[*component_of.get(&entry)?];
+            let mut path = worst.path.clone();
+            path[0] = entry;
+            Some(EntryDepth {
+                entry,
+                depth: worst.depth,
+                path,
+                recursive: worst.recursive.clone(),
+                unknown: worst.unknown.clone(),
+            })
+        })
+        .collect();
+    StackReport {
+        frames,
+        entries,
+        stack_limit: options.stack_limit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::registers::RegisterModel,
+        symbolic::{BinOp, Block, Expr, Insn, Stmt, Terminator},
+    };
+
+    /// A function moving the stack pointer by each of `deltas` in turn
+    fn function(entry: u64, deltas: &[i64]) -> Function {
+        let sp = RegisterModel::new(Arch::Amd64).sp();
+        let insns = deltas
+            .iter()
+            .enumerate()
+            .map(|(i, delta)| Insn {
+                va: entry + i as u64,
+                stmts: vec![Stmt::Set(
+                    sp,
+                    Expr::binary(BinOp::Add, Expr::Reg(sp), Expr::Const(*delta as u64), 64),
+                )],
+            })
+            .collect();
+        let mut func = Function::new(Arch::Amd64, entry);
+        func.add_block(Block {
+            va: entry,
+            insns,
+            end_va: entry + deltas.len() as u64,
+            end: Terminator::Return,
+        });
+        func
+    }
+
+    #[test]
+    fn worst_case_depth() {
+        // push rbp; sub rsp, 0x20; add rsp, 0x20; pop rbp
+        assert_eq!(
+            frame_size(&function(0x1000, &[-8, -0x20, 0x20, 8])),
+            Some(0x28)
+        );
+
+        let functions = BTreeMap::from([
+            (0x1000, function(0x1000, &[-0x10, 0x10])),
+            (0x2000, function(0x2000, &[-0x100, 0x100])),
+            (0x3000, function(0x3000, &[-0x20, 0x20])),
+            (0x4000, function(0x4000, &[-0x8, 0x8])),
+        ]);
+        // 0x1000 calls 0x2000 and 0x3000, 0x3000 recurses, 0x4000 calls what nobody lifted
+        let graph = BTreeMap::from([
+            (0x1000, BTreeSet::from([0x2000, 0x3000])),
+            (0x2000, BTreeSet::new()),
+            (0x3000, BTreeSet::from([0x3000])),
+            (0x4000, BTreeSet::from([0x5000])),
+        ]);
+        let mut options = Options {
+            stack_limit: Some(0x100),
+            ..Default::default()
+        };
+        let report = analyze(&functions, &graph, &options);
+        assert_eq!(report.frames[&0x3000], Some(0x20));
+        let depths: Vec<(u64, Option<u64>)> = report
+            .entries
+            .iter()
+            .map(|entry| (entry.entry, entry.depth))
+            .collect();
+        assert_eq!(depths, [(0x1000, None), (0x4000, Some(0x10))]);
+        assert_eq!(report.entries[0].recursive, BTreeSet::from([0x3000]));
+        assert_eq!(report.entries[1].unknown, BTreeSet::from([0x5000]));
+
+        // recursing 10 deep takes 10 frames and return addresses of 0x3000
+        options.recursion.insert(0x3000, 10);
+        let report = analyze(&functions, &graph, &options);
+        let entry = &report.entries[0];
+        assert_eq!(entry.depth, Some(0x18 + 0x28 * 10));
+        assert_eq!(entry.path, [0x1000, 0x3000]);
+        assert_eq!(report.at_risk(), [entry]);
+        assert_eq!(entry.to_string(), "0x1000: 424 bytes via 0x1000 -> 0x3000");
+    }
+}
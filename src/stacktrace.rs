@@ -0,0 +1,541 @@
+//! Symbolized stack traces from crash artifacts.
+//!
+//! A [`CoreDump`] gives access to the threads, the mapped files and the memory of an ELF core
+//! file, and a [`Minidump`](crate::minidump::Minidump) to those of a minidump. Both are
+//! [`CrashArtifact`]s: each thread is unwound with the [`UnwindTables`] of the modules named in
+//! the artifact (`.eh_frame` CFI for ELF modules, `.pdata` for PE ones, and the frame pointer
+//! chain for code without either), and the frames are resolved with a [`SymbolIndex`] built from
+//! the same modules.
+//!
+//! Only x86-64 processes are supported so far.
+
+use crate::{
+    elf::{header, note, program_header, sym, Elf},
+    error,
+    pe::PE,
+    resolve::{Resolved, SymbolIndex},
+    unwind::{FrameState, StackMemory, UnwindTables, RBP, REGISTERS, RSP},
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use scroll::Pread;
+
+/// The offset of `pr_pid` in the x86-64 `elf_prstatus`
+const PRSTATUS_PID: usize = 32;
+/// The offset of `pr_reg` (a `user_regs_struct`) in the x86-64 `elf_prstatus`
+const PRSTATUS_REGS: usize = 112;
+/// The index of rip in the x86-64 `user_regs_struct`
+const REG_RIP: usize = 16;
+/// The index in the x86-64 `user_regs_struct` of each register, by DWARF number
+const USER_REGS: [usize; REGISTERS] = [10, 12, 11, 5, 13, 14, 4, 19, 9, 8, 7, 6, 3, 2, 1, 0];
+
+/// The registers of a thread when the process crashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadContext {
+    pub tid: u32,
+    pub pc: u64,
+    /// The general purpose registers by DWARF number, see [`FrameState`]
+    pub regs: [u64; REGISTERS],
+}
+
+impl ThreadContext {
+    pub fn sp(&self) -> u64 {
+        self.regs[RSP]
+    }
+
+    pub fn fp(&self) -> u64 {
+        self.regs[RBP]
+    }
+
+    /// The innermost frame of the thread
+    pub fn state(&self) -> FrameState {
+        FrameState {
+            pc: self.pc,
+            regs: self.regs.map(Some),
+        }
+    }
+}
+
+/// The threads, memory and modules of a crashed process
+pub trait CrashArtifact: StackMemory {
+    fn threads(&self) -> &[ThreadContext];
+
+    /// The distinct modules loaded into the process, in load order
+    fn modules(&self) -> Vec<&str>;
+
+    /// The address the module `path` is loaded at
+    fn load_address(&self, path: &str) -> Option<u64>;
+
+    /// The (start, end) address ranges the module `path` is mapped at
+    fn module_ranges(&self, path: &str) -> Vec<(u64, u64)>;
+
+    /// Unwind `thread` with `tables`, returning at most `max_frames` program counters, innermost
+    /// first. The unwind ends at a null return address, or a frame which doesn't move up the stack
+    fn unwind(&self, tables: &UnwindTables, thread: &ThreadContext, max_frames: usize) -> Vec<u64> {
+        let mut state = thread.state();
+        let mut pcs = vec![state.pc];
+        while pcs.len() < max_frames {
+            match tables.step(self, &state, pcs.len() == 1) {
+                Some(next) => {
+                    pcs.push(next.pc);
+                    state = next;
+                }
+                None => break,
+            }
+        }
+        pcs
+    }
+
+    /// Add the mappings and symbols of the module `path`, whose contents are `bytes`, to `index`,
+    /// and its unwind information to `tables`. ELF and PE modules are read, others only get
+    /// their mappings
+    fn add_module<'b>(
+        &self,
+        index: &mut SymbolIndex,
+        tables: &mut UnwindTables<'b>,
+        path: &str,
+        bytes: &'b [u8],
+    ) -> error::Result<()> {
+        let base = match self.load_address(path) {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+        let ranges = self.module_ranges(path);
+        for &(start, end) in ranges.iter() {
+            index.add_range(start, end.saturating_sub(start), path, base);
+        }
+        if bytes.starts_with(b"MZ") {
+            let module = PE::parse(bytes)?;
+            for export in module.exports.iter() {
+                if let Some(name) = export.name {
+                    let address = base.wrapping_add(export.rva as u64);
+                    index.add_function(address, export.size as u64, name);
+                    index.add_name(address, name);
+                }
+            }
+            return tables.add_pe(&ranges, base, bytes);
+        }
+        if !bytes.starts_with(header::ELFMAG) {
+            return Ok(());
+        }
+        let module = Elf::parse(bytes)?;
+        // symbols are relative to the first loadable segment, which is mapped at the base
+        let first_vaddr = module
+            .program_headers
+            .iter()
+            .filter(|phdr| phdr.p_type == program_header::PT_LOAD)
+            .map(|phdr| phdr.p_vaddr & !(phdr.p_align.max(1) - 1))
+            .min()
+            .unwrap_or(0);
+        let bias = base.wrapping_sub(first_vaddr);
+        for symbol in module.symbols_unified() {
+            if symbol.mapping.is_some() || symbol.typ == sym::STT_TLS {
+                continue;
+            }
+            let address = symbol.address.wrapping_add(bias);
+            if symbol.typ == sym::STT_FUNC || symbol.typ == sym::STT_GNU_IFUNC {
+                index.add_function(address, symbol.size, symbol.name);
+            }
+            index.add_name(address, symbol.name);
+        }
+        tables.add_elf(&ranges, bias, bytes)
+    }
+
+    /// Unwind every thread with `tables` and symbolize its stack with `index`
+    fn stack_traces(
+        &self,
+        index: &SymbolIndex,
+        tables: &UnwindTables,
+        max_frames: usize,
+    ) -> Vec<StackTrace> {
+        self.threads()
+            .iter()
+            .map(|thread| {
+                let frames = self
+                    .unwind(tables, thread, max_frames)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(depth, pc)| {
+                        // return addresses point after the call, which may be the next symbol
+                        let lookup = if depth == 0 { pc } else { pc - 1 };
+                        Frame {
+                            pc,
+                            symbol: index.lookup(lookup).map(|mut resolved| {
+                                resolved.offset = pc - resolved.va;
+                                resolved
+                            }),
+                        }
+                    })
+                    .collect();
+                StackTrace {
+                    tid: thread.tid,
+                    frames,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A file mapping from the `NT_FILE` note
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedFile {
+    pub start: u64,
+    pub end: u64,
+    /// The offset into the file the mapping starts at
+    pub file_offset: u64,
+    pub path: String,
+}
+
+/// An ELF core file
+pub struct CoreDump<'a> {
+    bytes: &'a [u8],
+    elf: Elf<'a>,
+    pub threads: Vec<ThreadContext>,
+    pub files: Vec<MappedFile>,
+}
+
+impl<'a> CoreDump<'a> {
+    pub fn parse(bytes: &'a [u8]) -> error::Result<Self> {
+        let elf = Elf::parse(bytes)?;
+        if elf.header.e_type != header::ET_CORE {
+            return Err(error::Error::Malformed("Not a core file".into()));
+        }
+        if elf.header.e_machine != header::EM_X86_64 {
+            return Err(error::Error::Malformed(format!(
+                "Cores of {} processes are not supported",
+                header::machine_to_str(elf.header.e_machine)
+            )));
+        }
+        let mut threads = Vec::new();
+        let mut files = Vec::new();
+        for note in elf.iter_note_headers(bytes).into_iter().flatten() {
+            let note = note?;
+            match note.n_type {
+                note::NT_PRSTATUS => threads.push(parse_prstatus(note.desc)?),
+                note::NT_FILE => files = parse_file_note(note.desc)?,
+                _ => {}
+            }
+        }
+        Ok(CoreDump {
+            bytes,
+            elf,
+            threads,
+            files,
+        })
+    }
+
+    pub fn elf(&self) -> &Elf<'a> {
+        &self.elf
+    }
+
+    /// Read `len` bytes of process memory at `va`, if the core holds them
+    pub fn read_memory(&self, va: u64, len: usize) -> Option<&'a [u8]> {
+        // addresses past the top of the address space are unmapped rather than wrapped
+        va.checked_add(len as u64)?;
+        self.elf.program_headers.iter().find_map(|phdr| {
+            let offset = va.checked_sub(phdr.p_vaddr)?;
+            if phdr.p_type != program_header::PT_LOAD
+                || offset.checked_add(len as u64)? > phdr.p_filesz
+            {
+                return None;
+            }
+            let offset = usize::try_from(phdr.p_offset.checked_add(offset)?).ok()?;
+            self.bytes.get(offset..offset.checked_add(len)?)
+        })
+    }
+}
+
+impl StackMemory for CoreDump<'_> {
+    fn read_memory(&self, va: u64, len: usize) -> Option<&[u8]> {
+        CoreDump::read_memory(self, va, len)
+    }
+}
+
+impl CrashArtifact for CoreDump<'_> {
+    fn threads(&self) -> &[ThreadContext] {
+        &self.threads
+    }
+
+    fn modules(&self) -> Vec<&str> {
+        let mut modules: Vec<&str> = Vec::new();
+        for file in self.files.iter() {
+            if !modules.contains(&file.path.as_str()) {
+                modules.push(&file.path);
+            }
+        }
+        modules
+    }
+
+    /// The start of the mapping of `path` at file offset 0
+    fn load_address(&self, path: &str) -> Option<u64> {
+        self.files
+            .iter()
+            .filter(|file| file.path == path && file.file_offset == 0)
+            .map(|file| file.start)
+            .min()
+    }
+
+    fn module_ranges(&self, path: &str) -> Vec<(u64, u64)> {
+        self.files
+            .iter()
+            .filter(|file| file.path == path)
+            .map(|file| (file.start, file.end))
+            .collect()
+    }
+}
+
+/// Build a symbol index and unwind tables over the modules of `crash` and symbolize the stack of
+/// every thread. `modules` holds the path (as named in the artifact) and the contents of each
+/// available module; frames in modules which aren't available resolve to an offset from the
+/// module base, and are unwound by frame pointer
+pub fn symbolize<C: CrashArtifact>(
+    crash: &C,
+    modules: &[(&str, &[u8])],
+    max_frames: usize,
+) -> error::Result<Vec<StackTrace>> {
+    let mut index = SymbolIndex::new();
+    let mut tables = UnwindTables::new();
+    for path in crash.modules() {
+        match modules.iter().find(|(name, _)| *name == path) {
+            Some((_, bytes)) => crash.add_module(&mut index, &mut tables, path, bytes)?,
+            None => {
+                let base = crash.load_address(path).unwrap_or(0);
+                for (start, end) in crash.module_ranges(path) {
+                    index.add_range(start, end.saturating_sub(start), path, base);
+                }
+            }
+        }
+    }
+    index.finish();
+    Ok(crash.stack_traces(&index, &tables, max_frames))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u64,
+    pub symbol: Option<Resolved>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace {
+    pub tid: u32,
+    /// Innermost frame first
+    pub frames: Vec<Frame>,
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Thread {}:", self.tid)?;
+        for (depth, frame) in self.frames.iter().enumerate() {
+            write!(f, "#{:<2} {:#018x}", depth, frame.pc)?;
+            if let Some(ref symbol) = frame.symbol {
+                write!(f, " {} ({})", symbol, symbol.module)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_prstatus(desc: &[u8]) -> error::Result<ThreadContext> {
+    let reg = |idx: usize| desc.pread_with::<u64>(PRSTATUS_REGS + idx * 8, scroll::LE);
+    let mut regs = [0; REGISTERS];
+    for (value, &idx) in regs.iter_mut().zip(USER_REGS.iter()) {
+        *value = reg(idx)?;
+    }
+    Ok(ThreadContext {
+        tid: desc.pread_with(PRSTATUS_PID, scroll::LE)?,
+        pc: reg(REG_RIP)?,
+        regs,
+    })
+}
+
+/// `count`, `page_size`, `count` (start, end, page offset) triples, then `count` paths
+fn parse_file_note(desc: &[u8]) -> error::Result<Vec<MappedFile>> {
+    let offset = &mut 0;
+    let count: u64 = desc.gread_with(offset, scroll::LE)?;
+    let page_size: u64 = desc.gread_with(offset, scroll::LE)?;
+    if count > (desc.len() / 24) as u64 {
+        return Err(error::Error::Malformed(format!(
+            "NT_FILE note claims {} mappings",
+            count
+        )));
+    }
+    let mut ranges = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let start: u64 = desc.gread_with(offset, scroll::LE)?;
+        let end: u64 = desc.gread_with(offset, scroll::LE)?;
+        let page: u64 = desc.gread_with(offset, scroll::LE)?;
+        ranges.push((start, end, page * page_size));
+    }
+    let mut files = Vec::with_capacity(ranges.len());
+    for (start, end, file_offset) in ranges {
+        let path: &str = desc.gread(offset)?;
+        files.push(MappedFile {
+            start,
+            end,
+            file_offset,
+            path: path.into(),
+        });
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{Container, Ctx};
+    use crate::elf::{Header, ProgramHeader};
+    use scroll::{ctx::IntoCtx, Pwrite, LE};
+
+    const STACK: u64 = 0x7ffd_0000;
+    const APP: &str = "/usr/bin/app";
+
+    fn note(out: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+        for word in [5, desc.len() as u32, n_type] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out.extend_from_slice(b"CORE\0\0\0\0");
+        out.extend_from_slice(desc);
+        out.resize(out.len().div_ceil(4) * 4, 0);
+    }
+
+    /// A core with one thread, three frames deep, in a module mapped at 0x400000
+    fn core() -> Vec<u8> {
+        let ctx = Ctx::new(Container::Big, LE);
+        let mut prstatus = vec![0u8; 336];
+        prstatus.pwrite_with(4242u32, PRSTATUS_PID, LE).unwrap();
+        for (reg, value) in [
+            (REG_RIP, 0x401010u64),
+            (USER_REGS[RSP], STACK),
+            (USER_REGS[RBP], STACK + 0x10),
+        ] {
+            prstatus
+                .pwrite_with(value, PRSTATUS_REGS + reg * 8, LE)
+                .unwrap();
+        }
+        let mut files = Vec::new();
+        for value in [2u64, 0x1000, 0x400000, 0x401000, 0, 0x401000, 0x402000, 1] {
+            files.extend_from_slice(&value.to_le_bytes());
+        }
+        files.extend_from_slice(b"/usr/bin/app\0/usr/bin/app\0");
+        let mut notes = Vec::new();
+        note(&mut notes, note::NT_PRSTATUS, &prstatus);
+        note(&mut notes, note::NT_FILE, &files);
+
+        // saved frame pointer and return address pairs
+        let mut stack = vec![0u8; 0x100];
+        for (offset, value) in [
+            (0x10, STACK + 0x40),
+            (0x18, 0x401025u64),
+            (0x40, 0),
+            (0x48, 0x401105),
+        ] {
+            stack.pwrite_with(value, offset, LE).unwrap();
+        }
+
+        let notes_offset = 64 + 2 * 56;
+        let stack_offset = notes_offset + notes.len();
+        let mut out = vec![0u8; stack_offset];
+        out.extend_from_slice(&stack);
+        let mut header = Header::new(ctx);
+        header.e_type = header::ET_CORE;
+        header.e_machine = header::EM_X86_64;
+        header.e_phoff = 64;
+        header.e_phnum = 2;
+        header.into_ctx(&mut out, ctx);
+        let phdrs = [
+            ProgramHeader {
+                p_type: program_header::PT_NOTE,
+                p_offset: notes_offset as u64,
+                p_filesz: notes.len() as u64,
+                p_align: 4,
+                ..Default::default()
+            },
+            ProgramHeader {
+                p_type: program_header::PT_LOAD,
+                p_offset: stack_offset as u64,
+                p_vaddr: STACK,
+                p_filesz: stack.len() as u64,
+                p_memsz: stack.len() as u64,
+                p_align: 0x1000,
+                ..Default::default()
+            },
+        ];
+        for (i, phdr) in phdrs.into_iter().enumerate() {
+            out.pwrite_with(phdr, 64 + i * 56, ctx).unwrap();
+        }
+        out[notes_offset..stack_offset].copy_from_slice(&notes);
+        out
+    }
+
+    #[test]
+    fn parse_core() {
+        let bytes = core();
+        let core = CoreDump::parse(&bytes).unwrap();
+        assert_eq!(core.threads.len(), 1);
+        assert_eq!(core.threads[0].tid, 4242);
+        assert_eq!(core.modules(), [APP]);
+        assert_eq!(core.load_address(APP), Some(0x400000));
+        assert_eq!(core.files[1].file_offset, 0x1000);
+        assert_eq!(core.threads[0].sp(), STACK);
+        // without unwind information the frame pointer chain is followed
+        let tables = UnwindTables::new();
+        assert_eq!(
+            core.unwind(&tables, &core.threads[0], 16),
+            [0x401010, 0x401025, 0x401105]
+        );
+        assert_eq!(core.unwind(&tables, &core.threads[0], 2).len(), 2);
+    }
+
+    #[test]
+    fn overflow() {
+        let bytes = core();
+        let mut core = CoreDump::parse(&bytes).unwrap();
+        assert_eq!(core.read_memory(STACK + 0xf8, 8).map(<[u8]>::len), Some(8));
+        assert_eq!(core.read_memory(STACK + 0xfc, 8), None);
+        assert_eq!(core.read_memory(u64::MAX - 3, 8), None);
+        // a frame pointer whose return address slot wraps around ends the unwind
+        let tables = UnwindTables::new();
+        let mut thread = core.threads[0];
+        thread.regs[RBP] = u64::MAX - 4;
+        assert_eq!(core.unwind(&tables, &thread, 16), [0x401010]);
+        // so does a segment at the top of the address space
+        core.elf.program_headers[1].p_vaddr = u64::MAX - 0x10;
+        assert_eq!(
+            core.read_memory(u64::MAX - 8, 8),
+            Some(&bytes[bytes.len() - 0xf8..][..8])
+        );
+        assert_eq!(core.read_memory(u64::MAX - 8, 16), None);
+        thread.regs[RBP] = u64::MAX - 8;
+        assert_eq!(core.unwind(&tables, &thread, 16), [0x401010]);
+    }
+
+    #[test]
+    fn symbolize() {
+        let bytes = core();
+        let core = CoreDump::parse(&bytes).unwrap();
+        let mut index = SymbolIndex::new();
+        index.add_range(0x400000, 0x2000, APP, 0x400000);
+        index.add_function(0x401000, 0x20, "crash_here");
+        index.add_function(0x401020, 0x5, "caller");
+        index.add_function(0x401100, 0x10, "main");
+        index.finish();
+        let traces = core.stack_traces(&index, &UnwindTables::new(), 16);
+        let frames = traces[0]
+            .frames
+            .iter()
+            .map(|frame| frame.symbol.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        // the call in caller is its last instruction, the return address is past its end
+        assert_eq!(frames, ["crash_here+0x10", "caller+0x5", "main+0x5"]);
+        assert!(traces[0]
+            .to_string()
+            .starts_with("Thread 4242:\n#0  0x0000000000401010 crash_here+0x10"));
+
+        // without the module binary the frames are offsets from its base
+        let traces = super::symbolize(&core, &[], 16).unwrap();
+        let frame = traces[0].frames[0].symbol.as_ref().unwrap();
+        assert_eq!(frame.to_string(), "/usr/bin/app+0x1010");
+    }
+}
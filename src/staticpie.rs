@@ -0,0 +1,242 @@
+//! Static-PIE ELF executables, which relocate themselves.
+//!
+//! A static-PIE has no program interpreter to apply its relocations: the kernel maps it at a
+//! random base and jumps to `e_entry`, where `_dl_relocate_static_pie` finds `_DYNAMIC`, applies
+//! the `R_*_RELATIVE` relocations and `__libc_start_main` runs the `R_*_IRELATIVE` resolvers
+//! before `main`. The image on disk holds the link time values, and with `RELA` relocations,
+//! as aarch64 links them, often nothing at all in the slots.
+//!
+//! The loader puts the image in memory as the self-relocation leaves it, see
+//! [`self_relocations`]: `RELATIVE` slots get the base plus the addend, and `IRELATIVE` slots
+//! the address of their resolver, recorded in the `IRelativeSlots` VA set with a pointer xref
+//! to the resolver. Running the stub again over this memory writes the same values. `REL`
+//! relocations keep their addend in the slot, and the stub would add the base twice, so they
+//! are left to it; they are right as long as the image isn't moved.
+//!
+//! [`resolve_ifuncs`] runs the resolvers through the IL interpreter and binds the slots to the
+//! implementations they pick, which is what `apply_irel` does before `main`.
+
+use crate::{
+    constants::REF_PTR,
+    elf::{
+        dynamic::DF_1_PIE,
+        header::{ET_DYN, EM_386, EM_AARCH64, EM_ARM, EM_X86_64},
+        program_header::{PT_DYNAMIC, PT_INTERP},
+        reloc::*,
+        Elf,
+    },
+    envi::{registers::RegisterContext, Arch},
+    ilemu::{Interpreter, Ram, Stop},
+    memory::Memory,
+    symbolic::return_value,
+    unpack::{Lifter, STACK_TOP},
+    workspace::VivWorkspace,
+};
+
+/// The VA set of the `IRELATIVE` slots
+pub const VA_SET_IRELATIVE: &str = "IRelativeSlots";
+
+/// How many blocks a resolver may run
+pub const MAX_RESOLVER_BLOCKS: usize = 0x400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfRelocation {
+    /// The slot holds `target`
+    Relative { slot: u64, target: u64 },
+    /// The slot holds what `resolver` returns
+    IRelative { slot: u64, resolver: u64 },
+}
+
+impl SelfRelocation {
+    pub fn slot(&self) -> u64 {
+        match self {
+            SelfRelocation::Relative { slot, .. } | SelfRelocation::IRelative { slot, .. } => {
+                *slot
+            }
+        }
+    }
+
+    /// The value the slot holds once the stub ran, before the resolvers are
+    pub fn value(&self) -> u64 {
+        match self {
+            SelfRelocation::Relative { target, .. } => *target,
+            SelfRelocation::IRelative { resolver, .. } => *resolver,
+        }
+    }
+}
+
+/// Whether `elf` is a static-PIE: position independent, with a dynamic section but no
+/// interpreter or library to process it. The dynamic linker is one too unless flagged a PIE,
+/// and goes by its soname.
+pub fn is_static_pie(elf: &Elf) -> bool {
+    let has = |p_type| elf.program_headers.iter().any(|ph| ph.p_type == p_type);
+    let pie = elf
+        .dynamic
+        .as_ref()
+        .is_some_and(|dynamic| dynamic.info.flags_1 & DF_1_PIE != 0);
+    elf.header.e_type == ET_DYN
+        && has(PT_DYNAMIC)
+        && !has(PT_INTERP)
+        && elf.interpreter.is_none()
+        && elf.libraries.is_empty()
+        && (pie || elf.soname.is_none() && elf.entry != 0)
+}
+
+/// The relocations the self-relocation stub of a static-PIE moved by `delta` applies, with
+/// explicit addends, by slot
+pub fn self_relocations(elf: &Elf, delta: u64) -> Vec<SelfRelocation> {
+    let (relative, irelative) = match elf.header.e_machine {
+        EM_X86_64 => (R_X86_64_RELATIVE, R_X86_64_IRELATIVE),
+        EM_AARCH64 => (R_AARCH64_RELATIVE, R_AARCH64_IRELATIVE),
+        EM_386 => (R_386_RELATIVE, R_386_IRELATIVE),
+        EM_ARM => (R_ARM_RELATIVE, R_ARM_IRELATIVE),
+        _ => return Vec::new(),
+    };
+    let mut relocations = Vec::new();
+    for reloc in elf.dynrelas.iter().chain(elf.pltrelocs.iter()) {
+        let Some(addend) = reloc.r_addend else {
+            continue;
+        };
+        let slot = reloc.r_offset.wrapping_add(delta);
+        let value = delta.wrapping_add(addend as u64);
+        if reloc.r_type == relative {
+            relocations.push(SelfRelocation::Relative {
+                slot,
+                target: value,
+            });
+        } else if reloc.r_type == irelative {
+            relocations.push(SelfRelocation::IRelative {
+                slot,
+                resolver: value,
+            });
+        }
+    }
+    relocations.sort_by_key(SelfRelocation::slot);
+    relocations
+}
+
+/// Record the `IRELATIVE` slots of the static-PIE just loaded, and their resolvers as entry
+/// points
+pub fn add_irelative(workspace: &mut VivWorkspace, relocations: &[SelfRelocation]) {
+    let mut slots = workspace
+        .get_va_set_rows(VA_SET_IRELATIVE)
+        .unwrap_or_default();
+    for relocation in relocations {
+        if let SelfRelocation::IRelative { slot, resolver } = *relocation {
+            workspace.add_xref(slot as i32, resolver as i32, REF_PTR, 0);
+            if workspace.is_executable(resolver as i32) {
+                workspace.add_entry_point(resolver as i32);
+            }
+            if !slots.contains(&(slot as i32)) {
+                slots.push(slot as i32);
+            }
+        }
+    }
+    workspace.set_va_set_row(VA_SET_IRELATIVE, slots);
+}
+
+/// Run the resolver of each `IRELATIVE` slot of the workspace and store what it returns in the
+/// slot in `memory`, giving back the slots bound as (slot, implementation). A resolver which
+/// can't be lifted, faults or doesn't return leaves its slot pointing at it.
+pub fn resolve_ifuncs(
+    workspace: &VivWorkspace,
+    lifter: &mut dyn Lifter,
+    memory: &mut Ram,
+) -> Vec<(u64, u64)> {
+    let Some(arch) = Arch::from_envi(workspace.arch) else {
+        return Vec::new();
+    };
+    let Some(result) = return_value(arch) else {
+        return Vec::new();
+    };
+    let size = arch.pointer_size();
+    let mask = u64::MAX >> (64 - 8 * size);
+    let stack = STACK_TOP - 0x1000;
+    if memory.read(stack, size).is_none() {
+        memory.map(stack, vec![0; 0x1000]);
+    }
+    let mut bound = Vec::new();
+    for slot in workspace
+        .get_va_set_rows(VA_SET_IRELATIVE)
+        .unwrap_or_default()
+    {
+        let xrefs = workspace.get_xrefs_from(slot, Some(REF_PTR));
+        let Some((_, resolver, _, _)) = xrefs.first() else {
+            continue;
+        };
+        let resolver = *resolver as u64 & mask;
+        let Some(func) = lifter.lift(arch, memory, resolver) else {
+            continue;
+        };
+        let mut registers = RegisterContext::new(arch);
+        let sp = registers.model().sp();
+        registers.set(sp, (STACK_TOP - 2 * size as u64).into());
+        let mut interpreter = Interpreter::new(&mut registers, memory);
+        let Ok(Stop::Return { .. }) = interpreter.run(&func, MAX_RESOLVER_BLOCKS) else {
+            continue;
+        };
+        let Ok(target) = interpreter.eval(&result) else {
+            continue;
+        };
+        let slot = slot as u64 & mask;
+        if memory.write(slot, size, target) {
+            bound.push((slot, target));
+        }
+    }
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_AMD64, MM_EXEC, MM_READ, MM_WRITE},
+        envi::registers::RegisterModel,
+        symbolic::{Block, Expr, Function, Insn, Stmt, Terminator},
+    };
+
+    #[test]
+    fn binds_irelative_slots() {
+        let relocations = [
+            SelfRelocation::Relative {
+                slot: 0x3000,
+                target: 0x1100,
+            },
+            SelfRelocation::IRelative {
+                slot: 0x3008,
+                resolver: 0x1000,
+            },
+        ];
+        assert_eq!(relocations[1].value(), 0x1000);
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_AMD64 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "text", vec![0xc3; 0x200], None);
+        ws.add_memory_map(0x3000, MM_READ | MM_WRITE, "got", vec![0; 0x10], None);
+        add_irelative(&mut ws, &relocations);
+        assert_eq!(ws.get_va_set_rows(VA_SET_IRELATIVE), Some(vec![0x3008]));
+        assert!(ws.get_entry_points().contains(&0x1000));
+
+        // the resolver picks the implementation at 0x1180
+        let rax = RegisterModel::new(Arch::Amd64).by_name("rax").unwrap();
+        let mut lifter = |arch: Arch, _: &mut Ram, va: u64| {
+            let mut func = Function::new(arch, va);
+            func.add_block(Block {
+                va,
+                insns: vec![Insn {
+                    va,
+                    stmts: vec![Stmt::Set(rax, Expr::Const(0x1180))],
+                }],
+                end_va: va,
+                end: Terminator::Return,
+            });
+            Some(func)
+        };
+        let mut memory = Ram::new();
+        memory.map(0x3000, vec![0; 0x10]);
+        assert_eq!(
+            resolve_ifuncs(&ws, &mut lifter, &mut memory),
+            [(0x3008, 0x1180)]
+        );
+        assert_eq!(memory.read(0x3008, 8), Some(0x1180));
+    }
+}
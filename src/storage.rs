@@ -0,0 +1,280 @@
+//! A plain text snapshot of the user facing workspace annotations (names, comments, types,
+//! tags, function boundaries and region overrides), and of the audit log. This is what gets
+//! written by `VivWorkspace::save_workspace` and what the merge tooling operates on.
+
+use crate::{auditlog::AuditRecord, overrides::RegionKind};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// The header line every snapshot file starts with.
+pub const SNAPSHOT_MAGIC: &str = "VIVRS-ANNOTATIONS 1";
+
+/// The annotation data which analysts add on top of the automatic analysis, keyed by VA.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Annotations {
+    pub names: BTreeMap<i32, String>,
+    pub comments: BTreeMap<i32, String>,
+    pub types: BTreeMap<i32, String>,
+    pub tags: BTreeMap<i32, BTreeSet<String>>,
+    /// Function entry VA to function size in bytes.
+    pub functions: BTreeMap<i32, i32>,
+    /// Region start VA to the size and kind of the override.
+    pub regions: BTreeMap<i32, (i32, RegionKind)>,
+    /// Function entry VA to the (va, size) ranges of code making the function up, where known.
+    pub bounds: BTreeMap<i32, Vec<(i32, i32)>>,
+    /// Function entry VA to the (va, size) of each of its code blocks. Only symbol cache
+    /// records keep these (see [`crate::symcache`]).
+    pub blocks: BTreeMap<i32, Vec<(i32, i32)>>,
+    /// The (from, to, type, flags) cross references within the file. Only symbol cache records
+    /// keep these.
+    pub xrefs: Vec<(i32, i32, i32, i32)>,
+    /// The analysis runs, oldest first (see [`crate::auditlog`]).
+    pub audit: Vec<AuditRecord>,
+}
+
+impl Annotations {
+    pub fn new() -> Self {
+        Annotations::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+            && self.comments.is_empty()
+            && self.types.is_empty()
+            && self.tags.is_empty()
+            && self.functions.is_empty()
+            && self.regions.is_empty()
+            && self.bounds.is_empty()
+            && self.blocks.is_empty()
+            && self.xrefs.is_empty()
+            && self.audit.is_empty()
+    }
+
+    /// Serialize the snapshot, one record per line: `<kind> <va> <value>`.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{}", SNAPSHOT_MAGIC)?;
+        for (va, name) in self.names.iter() {
+            writeln!(w, "name {:#x} {}", va, escape(name))?;
+        }
+        for (va, comment) in self.comments.iter() {
+            writeln!(w, "comment {:#x} {}", va, escape(comment))?;
+        }
+        for (va, tname) in self.types.iter() {
+            writeln!(w, "type {:#x} {}", va, escape(tname))?;
+        }
+        for (va, tags) in self.tags.iter() {
+            for tag in tags {
+                writeln!(w, "tag {:#x} {}", va, escape(tag))?;
+            }
+        }
+        for (va, size) in self.functions.iter() {
+            writeln!(w, "function {:#x} {:#x}", va, size)?;
+        }
+        for (va, (size, kind)) in self.regions.iter() {
+            writeln!(w, "region {:#x} {:#x} {}", va, size, kind)?;
+        }
+        for (va, ranges) in self.bounds.iter() {
+            let ranges: Vec<String> = ranges
+                .iter()
+                .map(|(rva, size)| format!("{:#x}:{:#x}", rva, size))
+                .collect();
+            writeln!(w, "bounds {:#x} {}", va, ranges.join(","))?;
+        }
+        for (fva, blocks) in self.blocks.iter() {
+            let blocks: Vec<String> = blocks
+                .iter()
+                .map(|(va, size)| format!("{:#x}:{:#x}", va, size))
+                .collect();
+            writeln!(w, "blocks {:#x} {}", fva, blocks.join(","))?;
+        }
+        for (from, to, rtype, rflags) in self.xrefs.iter() {
+            writeln!(w, "xref {:#x} {:#x} {:#x} {:#x}", from, to, rtype, rflags)?;
+        }
+        for (seq, record) in self.audit.iter().enumerate() {
+            for field in record.fields() {
+                writeln!(w, "audit {:#x} {}", seq, escape(&field))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse a snapshot previously produced by `write`.
+    pub fn read<R: BufRead>(r: R) -> io::Result<Self> {
+        let mut ret = Annotations::new();
+        let mut lines = r.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header.trim_end() != SNAPSHOT_MAGIC {
+            return Err(invalid("Missing annotation snapshot header"));
+        }
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(3, ' ');
+            let kind = parts.next().unwrap_or_default();
+            let va = parse_number(parts.next())?;
+            let value = unescape(parts.next().unwrap_or_default());
+            match kind {
+                "name" => {
+                    ret.names.insert(va, value);
+                }
+                "comment" => {
+                    ret.comments.insert(va, value);
+                }
+                "type" => {
+                    ret.types.insert(va, value);
+                }
+                "tag" => {
+                    ret.tags.entry(va).or_default().insert(value);
+                }
+                "function" => {
+                    ret.functions
+                        .insert(va, parse_number(Some(value.as_str()))?);
+                }
+                "region" => {
+                    let mut parts = value.splitn(2, ' ');
+                    let size = parse_number(parts.next())?;
+                    let region = parts.next().unwrap_or_default();
+                    let region = region.parse().map_err(|e: String| invalid(&e))?;
+                    ret.regions.insert(va, (size, region));
+                }
+                "bounds" => {
+                    // a function whose bounds are known to be none has an empty list
+                    ret.bounds.insert(va, parse_ranges(&value)?);
+                }
+                "blocks" => {
+                    ret.blocks.insert(va, parse_ranges(&value)?);
+                }
+                "xref" => {
+                    let mut parts = value.splitn(3, ' ');
+                    let to = parse_number(parts.next())?;
+                    let rtype = parse_number(parts.next())?;
+                    let rflags = parse_number(parts.next())?;
+                    ret.xrefs.push((va, to, rtype, rflags));
+                }
+                "audit" => {
+                    // written in order, each record's fields together
+                    let seq = usize::try_from(va)
+                        .ok()
+                        .filter(|seq| *seq <= ret.audit.len() && *seq + 1 >= ret.audit.len())
+                        .ok_or_else(|| invalid(&format!("Out of order audit record: {}", va)))?;
+                    if seq == ret.audit.len() {
+                        ret.audit.push(AuditRecord::default());
+                    }
+                    ret.audit[seq].read_field(&value).map_err(|e| invalid(&e))?;
+                }
+                _ => return Err(invalid(&format!("Unknown annotation record: {}", kind))),
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        self.write(&mut file)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Annotations::read(BufReader::new(fs::File::open(path)?))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_number(s: Option<&str>) -> io::Result<i32> {
+    let s = s.ok_or_else(|| invalid("Truncated annotation record"))?;
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).map(|x| x as i32),
+        None => s.parse::<i32>(),
+    };
+    parsed.map_err(|_| invalid(&format!("Invalid number in annotation record: {}", s)))
+}
+
+/// Parse a comma separated list of `<va>:<size>` ranges, which may be empty
+fn parse_ranges(s: &str) -> io::Result<Vec<(i32, i32)>> {
+    s.split(',')
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (va, size) = range
+                .split_once(':')
+                .ok_or_else(|| invalid("Truncated annotation record"))?;
+            Ok((parse_number(Some(va))?, parse_number(Some(size))?))
+        })
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn unescape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some('t') => ret.push('\t'),
+            Some(other) => ret.push(other),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_sparse_audit_records() {
+        let read = |seqs: &[&str]| {
+            let mut text = format!("{}\n", SNAPSHOT_MAGIC);
+            for seq in seqs {
+                text.push_str(&format!("audit {} file a.out\n", seq));
+            }
+            Annotations::read(text.as_bytes())
+        };
+        assert_eq!(read(&["0", "0", "1"]).unwrap().audit.len(), 2);
+        for seq in ["0xffffffff", "0x7fffffff", "1"] {
+            assert!(read(&[seq]).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_bounds_round_trip() {
+        let mut ann = Annotations::new();
+        ann.functions.extend([(0x1000, 0x10), (0x2000, 0x20)]);
+        ann.bounds.insert(0x1000, vec![]);
+        ann.bounds
+            .insert(0x2000, vec![(0x2000, 0x10), (0x3000, 0x10)]);
+        let mut text = Vec::new();
+        ann.write(&mut text).unwrap();
+        assert_eq!(Annotations::read(text.as_slice()).unwrap(), ann);
+        assert!(Annotations::read(
+            format!("{}\nbounds 0x1000 0x1000\n", SNAPSHOT_MAGIC).as_bytes()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! Structure layouts inferred from how lifted code accesses memory.
+//!
+//! Code reaching the fields of a structure loads and stores at constant offsets from a pointer
+//! to it: `mov eax, [ecx + 8]`. [`infer`] gathers those accesses in the functions of a workspace
+//! with IL, grouped by the pointer they are off, as the symbolic engine sees it from the start
+//! of a run of blocks (or from the last call, which forgets the registers). A pointer passed to
+//! a function is the same structure as the parameter it becomes, so the groups of a caller and
+//! a callee join at each call with an xref to the callee. A group with accesses at two offsets
+//! or more is a [`Structure`], with a field at each offset.
+//!
+//! The IL doesn't say how wide a load is, so a field runs to the next one, up to the pointer
+//! size. A field whose value is the pointer of another group is a pointer to that structure,
+//! and any other field an integer. The structures are named after the first function using
+//! them, and `VivWorkspace::infer_structures` adds them to the workspace to be renamed.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::{registers::RegisterModel, Arch},
+    osprofile,
+    symbolic::{call_argument, BinOp, Expr, Function, State, Stmt},
+    workspace::VivWorkspace,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// The greatest offset taken as a field, rather than as an index or a negative offset
+pub const MAX_OFFSET: u64 = 0x1000;
+/// How many fields make a structure
+pub const MIN_FIELDS: usize = 2;
+/// How many arguments of a call are followed into the callee
+const MAX_ARGS: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    /// An integer of the size of the field
+    Int,
+    /// A pointer, to the structure named if it is one
+    Pointer(Option<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub offset: u64,
+    pub size: u64,
+    pub ty: FieldType,
+    /// The instructions accessing it
+    pub accesses: BTreeSet<u64>,
+}
+
+impl Field {
+    pub fn name(&self) -> String {
+        format!("field_{:x}", self.offset)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Structure {
+    pub name: String,
+    pub size: u64,
+    /// By offset
+    pub fields: Vec<Field>,
+    /// The functions taking a pointer to it, with the argument
+    pub params: BTreeSet<(u64, usize)>,
+}
+
+impl fmt::Display for Structure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "struct {} {{", self.name)?;
+        let mut end = 0;
+        for field in self.fields.iter() {
+            if field.offset > end {
+                writeln!(f, "    uint8_t pad_{:x}[{}];", end, field.offset - end)?;
+            }
+            let name = field.name();
+            match (&field.ty, field.size) {
+                (FieldType::Pointer(Some(to)), _) => writeln!(f, "    struct {} *{};", to, name)?,
+                (FieldType::Pointer(None), _) => writeln!(f, "    void *{};", name)?,
+                (FieldType::Int, size @ (1 | 2 | 4 | 8)) => {
+                    writeln!(f, "    uint{}_t {};", size * 8, name)?
+                }
+                (FieldType::Int, size) => writeln!(f, "    uint8_t {}[{}];", name, size)?,
+            }
+            end = field.offset + field.size;
+        }
+        write!(f, "}};")
+    }
+}
+
+/// A pointer as of a point of a function: the expression of it, in the registers of the
+/// function at the start of a run of blocks or after a call, numbered in the function
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Base {
+    fva: u64,
+    run: usize,
+    expr: Expr,
+}
+
+/// What the functions do with pointers
+#[derive(Default)]
+struct Uses {
+    /// The offsets accessed off each pointer, with the instructions
+    accesses: BTreeMap<Base, BTreeMap<u64, BTreeSet<u64>>>,
+    /// A pointer loaded from a field of another: the field's pointer and offset, and it
+    loaded: Vec<(Base, u64, Base)>,
+    /// A pointer passed to a function, and the pointer the function has it as
+    passed: Vec<(Base, Base)>,
+    /// The parameters of the functions, as pointers
+    params: Vec<(Base, u64, usize)>,
+}
+
+impl Uses {
+    /// Note the memory `expr` reads, as of `state`
+    fn note_loads(&mut self, state: &State, run: &Run, expr: &Expr, va: u64) {
+        let mut addrs = Vec::new();
+        expr.loads(&mut addrs);
+        for addr in addrs {
+            self.note(run, &state.eval(&addr), va);
+        }
+    }
+
+    fn note(&mut self, run: &Run, addr: &Expr, va: u64) {
+        let Some((expr, offset)) = run.split(addr) else {
+            return;
+        };
+        let base = run.base(expr.clone());
+        self.accesses
+            .entry(base.clone())
+            .or_default()
+            .entry(offset)
+            .or_default()
+            .insert(va);
+        if let Expr::Load(inner) = &expr {
+            if let Some((parent, offset)) = run.split(inner) {
+                self.loaded.push((run.base(parent), offset, base));
+            }
+        }
+    }
+
+    /// Note the pointers the call at `va` passes to a function with IL
+    fn note_call(
+        &mut self,
+        workspace: &VivWorkspace,
+        run: &Run,
+        state: &State,
+        va: u64,
+        arch: Arch,
+        windows: bool,
+    ) {
+        for (_, callee, _, rflags) in workspace.get_xrefs_from(va as i32, Some(REF_CODE)) {
+            if rflags & BR_PROC == 0 || workspace.get_function_il(callee).is_none() {
+                continue;
+            }
+            for index in 0..MAX_ARGS {
+                let (Some(arg), Some(expr)) = (
+                    call_argument(arch, windows, index),
+                    param(arch, windows, index),
+                ) else {
+                    break;
+                };
+                if let Some((arg, 0)) = run.split(&state.eval(&arg)) {
+                    let param = Base {
+                        fva: callee as u32 as u64,
+                        run: 0,
+                        expr,
+                    };
+                    self.passed.push((run.base(arg), param));
+                }
+            }
+        }
+    }
+}
+
+/// A run of blocks of a function, which the symbolic state goes through from one to the next
+struct Run {
+    fva: u64,
+    number: usize,
+    sp: Expr,
+}
+
+impl Run {
+    fn base(&self, expr: Expr) -> Base {
+        Base {
+            fva: self.fva,
+            run: self.number,
+            expr,
+        }
+    }
+
+    /// The pointer and offset an address is made of, where the pointer may be a structure's:
+    /// a register or a loaded value, and not the stack pointer
+    fn split(&self, addr: &Expr) -> Option<(Expr, u64)> {
+        let (base, offset) = match addr {
+            Expr::Binary(BinOp::Add, base, offset) => ((**base).clone(), offset.as_const()?),
+            addr => (addr.clone(), 0),
+        };
+        let pointer = match &base {
+            Expr::Reg(_) => base != self.sp,
+            Expr::Load(_) => true,
+            _ => false,
+        };
+        (pointer && offset <= MAX_OFFSET).then_some((base, offset))
+    }
+}
+
+/// Where argument `index` of a function is as it starts, the return address pushed on x86
+pub(crate) fn param(arch: Arch, windows: bool, index: usize) -> Option<Expr> {
+    let width = arch.pointer_size() as u32 * 8;
+    let sp = RegisterModel::new(arch).sp();
+    let ret = match arch {
+        Arch::I386 | Arch::Amd64 => arch.pointer_size() as u64,
+        _ => 0,
+    };
+    let mut state = State::new(arch);
+    let entry = Expr::binary(BinOp::Add, Expr::Reg(sp), Expr::Const(ret), width);
+    state.exec(&Stmt::Set(sp, entry));
+    Some(state.eval(&call_argument(arch, windows, index)?))
+}
+
+/// Gather the uses of pointers in `func`
+fn gather(workspace: &VivWorkspace, fva: u64, func: &Function, windows: bool, uses: &mut Uses) {
+    let sp = Expr::Reg(RegisterModel::new(func.arch).sp());
+    let preds = func.predecessors();
+    let mut runs = 0;
+    let mut ends: BTreeMap<u64, (usize, State)> = BTreeMap::new();
+    let new_run = |runs: &mut usize| {
+        *runs += 1;
+        *runs - 1
+    };
+    for va in func.reverse_postorder() {
+        let (mut number, mut state) = match preds.get(&va).map(Vec::as_slice) {
+            Some([pred]) if *pred != va && ends.contains_key(pred) => ends[pred].clone(),
+            _ => (new_run(&mut runs), State::new(func.arch)),
+        };
+        for insn in func.blocks[&va].insns.iter() {
+            for stmt in insn.stmts.iter() {
+                let run = Run {
+                    fva,
+                    number,
+                    sp: sp.clone(),
+                };
+                match stmt {
+                    Stmt::Set(_, value) => uses.note_loads(&state, &run, value, insn.va),
+                    Stmt::Store(addr, value) => {
+                        uses.note_loads(&state, &run, addr, insn.va);
+                        uses.note_loads(&state, &run, value, insn.va);
+                        uses.note(&run, &state.eval(addr), insn.va);
+                    }
+                    Stmt::Flags(_, a, b) => {
+                        uses.note_loads(&state, &run, a, insn.va);
+                        uses.note_loads(&state, &run, b, insn.va);
+                    }
+                    Stmt::Unknown => {
+                        uses.note_call(workspace, &run, &state, insn.va, func.arch, windows);
+                        number = new_run(&mut runs);
+                    }
+                }
+                state.exec(stmt);
+            }
+        }
+        ends.insert(va, (number, state));
+    }
+    for index in 0..MAX_ARGS {
+        let Some(expr) = param(func.arch, windows, index) else {
+            break;
+        };
+        let base = Base { fva, run: 0, expr };
+        uses.params.push((base, fva, index));
+    }
+}
+
+/// Sets of pointers which are the same
+#[derive(Default)]
+struct Groups {
+    ids: BTreeMap<Base, usize>,
+    parent: Vec<usize>,
+}
+
+impl Groups {
+    fn id(&mut self, base: &Base) -> usize {
+        if let Some(id) = self.ids.get(base) {
+            return *id;
+        }
+        let id = self.parent.len();
+        self.parent.push(id);
+        self.ids.insert(base.clone(), id);
+        id
+    }
+
+    fn find(&mut self, id: usize) -> usize {
+        let mut root = id;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        self.parent[id] = root;
+        root
+    }
+
+    fn group(&mut self, base: &Base) -> usize {
+        let id = self.id(base);
+        self.find(id)
+    }
+
+    fn join(&mut self, a: &Base, b: &Base) {
+        let (a, b) = (self.group(a), self.group(b));
+        self.parent[a.max(b)] = a.min(b);
+    }
+}
+
+/// The structures the functions of `workspace` with IL access, named apart from those the
+/// workspace has
+pub fn infer(workspace: &VivWorkspace) -> Vec<Structure> {
+    let windows = osprofile::is_windows(workspace);
+    let mut uses = Uses::default();
+    let mut pointer_size = 4;
+    for fva in workspace.get_functions_with_il() {
+        let func = workspace.get_function_il(fva).unwrap();
+        pointer_size = func.arch.pointer_size() as u64;
+        gather(workspace, fva as u32 as u64, func, windows, &mut uses);
+    }
+
+    let mut groups = Groups::default();
+    for (arg, param) in uses.passed.iter() {
+        groups.join(arg, param);
+    }
+    let mut offsets: BTreeMap<usize, BTreeMap<u64, BTreeSet<u64>>> = BTreeMap::new();
+    let mut first: BTreeMap<usize, u64> = BTreeMap::new();
+    for (base, accesses) in uses.accesses.iter() {
+        let group = groups.group(base);
+        let into = offsets.entry(group).or_default();
+        for (offset, insns) in accesses {
+            into.entry(*offset).or_default().extend(insns);
+        }
+        let fva = first.entry(group).or_insert(base.fva);
+        *fva = (*fva).min(base.fva);
+    }
+    offsets.retain(|_, fields| fields.len() >= MIN_FIELDS);
+
+    // name them in the order of the first function using them
+    let mut order: Vec<usize> = offsets.keys().copied().collect();
+    order.sort_by_key(|group| (first[group], *group));
+    let mut names: BTreeMap<usize, String> = BTreeMap::new();
+    let mut taken: BTreeSet<String> = BTreeSet::new();
+    for group in order.iter() {
+        let stem = format!("struct_{:x}", first[group]);
+        let name = (0..)
+            .map(|n| match n {
+                0 => stem.clone(),
+                n => format!("{}_{}", stem, n),
+            })
+            .find(|name| !taken.contains(name) && workspace.get_structure(name).is_none())
+            .unwrap();
+        taken.insert(name.clone());
+        names.insert(*group, name);
+    }
+
+    let mut pointers: BTreeMap<(usize, u64), Option<String>> = BTreeMap::new();
+    for (parent, offset, child) in uses.loaded.iter() {
+        let (parent, child) = (groups.group(parent), groups.group(child));
+        let to = names.get(&child).cloned();
+        let slot = pointers.entry((parent, *offset)).or_default();
+        if slot.is_none() {
+            *slot = to;
+        }
+    }
+    let mut params: BTreeMap<usize, BTreeSet<(u64, usize)>> = BTreeMap::new();
+    for (base, fva, index) in uses.params.iter() {
+        let group = groups.group(base);
+        params.entry(group).or_default().insert((*fva, *index));
+    }
+
+    order
+        .into_iter()
+        .map(|group| {
+            let accesses = &offsets[&group];
+            let mut fields: Vec<Field> = Vec::new();
+            let mut iter = accesses.iter().peekable();
+            while let Some((offset, insns)) = iter.next() {
+                let gap = iter.peek().map_or(pointer_size, |(next, _)| *next - offset);
+                let size = gap.min(pointer_size);
+                let ty = match pointers.get(&(group, *offset)) {
+                    Some(to) if size == pointer_size => FieldType::Pointer(to.clone()),
+                    _ => FieldType::Int,
+                };
+                fields.push(Field {
+                    offset: *offset,
+                    size,
+                    ty,
+                    accesses: insns.clone(),
+                });
+            }
+            let size = fields.last().map_or(0, |field| field.offset + field.size);
+            Structure {
+                name: names[&group].clone(),
+                size,
+                fields,
+                params: params.remove(&group).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolic::{Block, Insn, Terminator};
+
+    #[test]
+    fn infers_layouts() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let (rax, rcx, rsi, rdi) = (reg("rax"), reg("rcx"), reg("rsi"), reg("rdi"));
+        let field = |base, offset| {
+            let addr = Expr::binary(BinOp::Add, Expr::Reg(base), Expr::Const(offset), 64);
+            Expr::Load(Box::new(addr))
+        };
+        let function = |entry, insns: Vec<(u64, Stmt)>| {
+            let mut func = Function::new(Arch::Amd64, entry);
+            func.add_block(Block {
+                va: entry,
+                insns: insns
+                    .into_iter()
+                    .map(|(va, stmt)| Insn {
+                        va,
+                        stmts: vec![stmt],
+                    })
+                    .collect(),
+                end_va: entry + 0x20,
+                end: Terminator::Return,
+            });
+            func
+        };
+
+        // reads fields 0 and 0x10 of its argument, writes field 8, follows the pointer at 0x10
+        // to read fields 0 and 4 there, then passes the argument on
+        let first = function(
+            0x1000,
+            vec![
+                (0x1000, Stmt::Set(rax, field(rdi, 0))),
+                (
+                    0x1004,
+                    Stmt::Store(
+                        Expr::binary(BinOp::Add, Expr::Reg(rdi), Expr::Const(8), 64),
+                        Expr::Const(1),
+                    ),
+                ),
+                (0x1008, Stmt::Set(rsi, field(rdi, 0x10))),
+                (0x100c, Stmt::Set(rax, field(rsi, 4))),
+                (0x1010, Stmt::Set(rcx, field(rsi, 0))),
+                (0x1014, Stmt::Unknown),
+            ],
+        );
+        // reads field 0x18 of its argument
+        let second = function(0x2000, vec![(0x2000, Stmt::Set(rax, field(rdi, 0x18)))]);
+
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_xref(0x1014, 0x2000, REF_CODE, BR_PROC);
+        ws.set_function_il(0x1000, first);
+        ws.set_function_il(0x2000, second);
+        assert_eq!(ws.infer_structures(), ["struct_1000", "struct_1000_1"]);
+
+        let found = ws.get_structure("struct_1000").unwrap();
+        assert_eq!(found.size, 0x20);
+        assert_eq!(found.params, [(0x1000, 0), (0x2000, 0)].into());
+        assert_eq!(found.fields[1].accesses, [0x1004].into());
+        let node = ws.get_structure("struct_1000_1").unwrap();
+        assert_eq!(
+            node.fields
+                .iter()
+                .map(|field| (field.offset, field.size))
+                .collect::<Vec<_>>(),
+            [(0, 4), (4, 8)]
+        );
+
+        ws.rename_structure("struct_1000_1", "node").unwrap();
+        assert!(ws.rename_structure("struct_1000", "node").is_err());
+        assert_eq!(
+            ws.get_structure("struct_1000").unwrap().to_string(),
+            "struct struct_1000 {\n    uint64_t field_0;\n    uint64_t field_8;\n    \
+             struct node *field_10;\n    uint64_t field_18;\n};"
+        );
+    }
+}
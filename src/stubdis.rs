@@ -0,0 +1,255 @@
+//! Stub decoders for architectures without a full disassembler: SPARC and s390x.
+//!
+//! They decode no more than code flow needs: how long each instruction is, and whether it
+//! falls through, branches, calls or returns, and where to when the target is direct. That is
+//! enough to sweep the code of mainframe and Solaris binaries into instructions and functions;
+//! operands and mnemonics beyond the branches are left for real decoders.
+//!
+//! Both are big endian. SPARC branches and calls have a delay slot: the instruction after them
+//! runs before the branch is taken, which a sweep has to step over as part of the branch.
+
+use crate::constants::{ARCH_S390X, ARCH_SPARC, ARCH_SPARC64};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Goes on to the next instruction
+    Fall,
+    /// A call, to the target if it is direct
+    Call(Option<u64>),
+    /// A jump, to the target if it is direct, which may not be taken if `conditional`
+    Branch {
+        target: Option<u64>,
+        conditional: bool,
+    },
+    Return,
+    /// Stops, as an undefined instruction or a trap does
+    Halt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StubInsn {
+    pub va: u64,
+    pub size: usize,
+    pub flow: Flow,
+    /// The instruction after this one runs before the branch is taken
+    pub delay_slot: bool,
+}
+
+/// Decode the instruction at the start of `bytes`, mapped at `va`, for one of the `ARCH_*`
+/// constants with a stub decoder. None for other architectures or a truncated instruction.
+pub fn decode(arch: i32, va: u64, bytes: &[u8]) -> Option<StubInsn> {
+    match arch {
+        ARCH_SPARC | ARCH_SPARC64 => sparc(va, bytes),
+        ARCH_S390X => s390x(va, bytes),
+        _ => None,
+    }
+}
+
+/// Decode instructions from the start of `bytes` on, until one doesn't decode or the flow
+/// doesn't go on to the next. A delay slot is decoded with its branch.
+pub fn sweep(arch: i32, va: u64, bytes: &[u8]) -> Vec<StubInsn> {
+    let mut insns: Vec<StubInsn> = Vec::new();
+    let mut offset = 0;
+    while let Some(insn) = bytes
+        .get(offset..)
+        .and_then(|rest| decode(arch, va + offset as u64, rest))
+    {
+        offset += insn.size;
+        insns.push(insn);
+        let slot = insns.len() > 1 && insns[insns.len() - 2].delay_slot;
+        let last = if slot { insns[insns.len() - 2] } else { insn };
+        if insn.delay_slot {
+            continue;
+        }
+        match last.flow {
+            Flow::Fall
+            | Flow::Call(_)
+            | Flow::Branch {
+                conditional: true, ..
+            } => {}
+            Flow::Branch { .. } | Flow::Return | Flow::Halt => break,
+        }
+    }
+    insns
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    ((value << (64 - bits)) as i64 >> (64 - bits)) as u64
+}
+
+fn sparc(va: u64, bytes: &[u8]) -> Option<StubInsn> {
+    let word = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?);
+    let field = |shift: u32, bits: u32| ((word >> shift) & ((1 << bits) - 1)) as u64;
+    let relative = |disp: u64, bits: u32| Some(va.wrapping_add(sign_extend(disp, bits) << 2));
+    let mut delay_slot = true;
+    let flow = match field(30, 2) {
+        // call disp30
+        1 => Flow::Call(relative(field(0, 30), 30)),
+        0 => {
+            let cond = field(25, 4) & 7;
+            // "never" and "always" differ by the top bit of cond, the rest being 0
+            let conditional = cond != 0;
+            let target = match field(22, 3) {
+                // Bicc, FBfcc
+                2 | 6 => relative(field(0, 22), 22),
+                // BPcc, FBPfcc
+                1 | 5 => relative(field(0, 19), 19),
+                // BPr, its displacement split in two
+                3 => relative(field(20, 2) << 14 | field(0, 14), 16),
+                // sethi, nop and the rest
+                _ => {
+                    delay_slot = false;
+                    None
+                }
+            };
+            match target {
+                Some(_) if field(22, 3) != 3 && !conditional && field(28, 1) == 0 => {
+                    // bn, branch never
+                    delay_slot = true;
+                    Flow::Fall
+                }
+                Some(target) => Flow::Branch {
+                    target: Some(target),
+                    conditional: conditional || field(22, 3) == 3,
+                },
+                None => Flow::Fall,
+            }
+        }
+        2 => match field(19, 6) {
+            // jmpl: ret and retl are jmpl %i7+8 and jmpl %o7+8, a call links into %o7
+            0x38 => {
+                let (rd, rs1) = (field(25, 5), field(14, 5));
+                match (rd, rs1) {
+                    (0, 31) | (0, 15) if field(13, 1) == 1 && field(0, 13) == 8 => Flow::Return,
+                    (15, _) => Flow::Call(None),
+                    _ => Flow::Branch {
+                        target: None,
+                        conditional: false,
+                    },
+                }
+            }
+            // rett, return
+            0x39 => Flow::Return,
+            _ => {
+                delay_slot = false;
+                Flow::Fall
+            }
+        },
+        _ => {
+            delay_slot = false;
+            Flow::Fall
+        }
+    };
+    Some(StubInsn {
+        va,
+        size: 4,
+        flow,
+        delay_slot,
+    })
+}
+
+fn s390x(va: u64, bytes: &[u8]) -> Option<StubInsn> {
+    // the top two bits of the first byte give the length
+    let size = match bytes.first()? >> 6 {
+        0 => 2,
+        1 | 2 => 4,
+        _ => 6,
+    };
+    let insn = bytes.get(..size)?;
+    let mask = insn[1] >> 4;
+    // relative branches count halfwords
+    let relative = |disp: u64, bits: u32| Some(va.wrapping_add(sign_extend(disp, bits) << 1));
+    let branch = |target: Option<u64>| match mask {
+        0 => Flow::Fall,
+        15 => Flow::Branch {
+            target,
+            conditional: false,
+        },
+        _ => Flow::Branch {
+            target,
+            conditional: true,
+        },
+    };
+    let imm16 = || u16::from_be_bytes([insn[2], insn[3]]) as u64;
+    let imm32 = || u32::from_be_bytes([insn[2], insn[3], insn[4], insn[5]]) as u64;
+    let flow = match (insn[0], insn[1] & 0xf) {
+        // bcr mask, reg: br %r14 returns
+        (0x07, reg) => match (mask, reg) {
+            (_, 0) => Flow::Fall,
+            (15, 14) => Flow::Return,
+            _ => branch(None),
+        },
+        // basr
+        (0x0d, reg) if reg != 0 => Flow::Call(None),
+        // brc
+        (0xa7, 0x4) => branch(relative(imm16(), 16)),
+        // bras
+        (0xa7, 0x5) => Flow::Call(relative(imm16(), 16)),
+        // brcl
+        (0xc0, 0x4) => branch(relative(imm32(), 32)),
+        // brasl
+        (0xc0, 0x5) => Flow::Call(relative(imm32(), 32)),
+        _ => Flow::Fall,
+    };
+    Some(StubInsn {
+        va,
+        size,
+        flow,
+        delay_slot: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparc_and_s390x() {
+        // call +0x10; nop; be -4; nop; retl; nop
+        let code: Vec<u8> = [
+            0x4000_0004u32,
+            0x0100_0000,
+            0x02bf_ffff,
+            0x0100_0000,
+            0x81c3_e008,
+            0x0100_0000,
+        ]
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .collect();
+        let insns = sweep(ARCH_SPARC, 0x1000, &code);
+        assert_eq!(insns.len(), 6);
+        assert_eq!(insns[0].flow, Flow::Call(Some(0x1010)));
+        assert!(insns[0].delay_slot);
+        assert_eq!(insns[1].flow, Flow::Fall);
+        assert_eq!(
+            insns[2].flow,
+            Flow::Branch {
+                target: Some(0x1004),
+                conditional: true
+            }
+        );
+        assert_eq!(insns[4].flow, Flow::Return);
+
+        // brasl %r14, +0x20; lgr %r2, %r3; j -6; br %r14
+        let code = [
+            0xc0, 0xe5, 0, 0, 0, 0x10, 0xb9, 0x04, 0, 0x23, 0xa7, 0xf4, 0xff, 0xfd, 0x07, 0xfe,
+        ];
+        let insns = sweep(ARCH_S390X, 0x2000, &code);
+        let sizes: Vec<usize> = insns.iter().map(|insn| insn.size).collect();
+        assert_eq!(sizes, [6, 4, 4]);
+        assert_eq!(insns[0].flow, Flow::Call(Some(0x2020)));
+        assert_eq!(
+            insns[2].flow,
+            Flow::Branch {
+                target: Some(0x2004),
+                conditional: false
+            }
+        );
+        assert_eq!(
+            decode(ARCH_S390X, 0x200e, &code[14..]).unwrap().flow,
+            Flow::Return
+        );
+        assert_eq!(decode(ARCH_SPARC, 0, &code[..3]), None);
+    }
+}
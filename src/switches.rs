@@ -0,0 +1,353 @@
+//! Jump table recovery.
+//!
+//! A compiled `switch` is a bounds check on the index followed by an indirect jump through a
+//! table of targets: `cmp eax, 5; ja default; jmp [table + eax*4]`, or with table relative
+//! entries `movsxd rax, [rdx + rax*4]; add rax, rdx; jmp rax`. [`recover`] matches the jump
+//! target of each [`Terminator::Indirect`] block against those shapes, takes the bound and the
+//! default target from the guarding branch, and reads the entries out of an [`AddressSpace`].
+//!
+//! The result is a [`Switch`] describing the whole statement, which is attached to the
+//! [`Function`] so its cases become successors of the jump, and which [`annotate`] records in a
+//! workspace.
+
+use crate::{
+    constants::{BR_COND, REF_CODE, REF_DATA},
+    envi::{
+        flags::{self, Flags},
+        registers::RegId,
+        Arch,
+    },
+    pattern::{add, any, any_constant, capture, load, one_of},
+    pic::AddressSpace,
+    symbolic::{BinOp, Expr, FlagOp, Function, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::collections::BTreeMap;
+
+/// The most cases a table is read for; larger bounds are more likely a misread guard
+const MAX_CASES: u64 = 4096;
+
+/// A switch statement recovered from a jump table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Switch {
+    /// The block ending in the indirect jump
+    pub block: u64,
+    /// The address of the indirect jump
+    pub jump: u64,
+    /// The block comparing the index against the bound
+    pub guard: u64,
+    /// The register the guard compares, if the index is in one
+    pub index: Option<RegId>,
+    /// The number of cases; the index runs from 0 to `bound - 1`
+    pub bound: u64,
+    pub table: u64,
+    /// The size of an entry of the table in bytes
+    pub entry_size: u32,
+    /// The address entries are relative to, for tables of offsets rather than addresses
+    pub base: Option<u64>,
+    /// Where an index out of bounds goes
+    pub default: u64,
+    /// The target of each index
+    pub cases: Vec<u64>,
+}
+
+impl Switch {
+    /// The distinct case targets, each with the indices which go to it
+    pub fn arms(&self) -> BTreeMap<u64, Vec<u64>> {
+        let mut arms: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for (value, target) in self.cases.iter().enumerate() {
+            arms.entry(*target).or_default().push(value as u64);
+        }
+        arms
+    }
+}
+
+/// Split an address into the table and the index scaled by the entry size
+fn split_index(addr: &Expr) -> Option<(u64, Expr, u32)> {
+    let Expr::Binary(BinOp::Add, scaled, table) = addr else {
+        return None;
+    };
+    let table = table.as_const()?;
+    let (index, size) = match &**scaled {
+        Expr::Binary(BinOp::Mul, index, size) => ((**index).clone(), size.as_const()?),
+        Expr::Binary(BinOp::Shl, index, shift) => (
+            (**index).clone(),
+            1u64.checked_shl(shift.as_const()? as u32)?,
+        ),
+        index => (index.clone(), 1),
+    };
+    matches!(size, 1 | 2 | 4 | 8).then_some((table, index, size as u32))
+}
+
+/// The table, index expression, entry size and base of a jump target
+fn table_jump(target: &Expr) -> Option<(u64, Expr, u32, Option<u64>)> {
+    let entry = || load(capture("addr", any()));
+    let found = one_of([add(entry(), capture("base", any_constant())), entry()]).matches(target)?;
+    let (table, index, size) = split_index(found.get("addr")?)?;
+    Some((table, index, size, found.constant("base")))
+}
+
+/// Whether an index of `value` gets past a guard comparing it against `bound` with `cond`
+fn in_range(
+    arch: Arch,
+    width: u32,
+    cond: flags::Condition,
+    taken: bool,
+    value: u64,
+    bound: u64,
+) -> Option<bool> {
+    let mut state = Flags::unknown();
+    state.apply(&flags::sub(arch, value, bound, width));
+    cond.eval(&state).map(|holds| holds == taken)
+}
+
+fn recover_at(
+    func: &Function,
+    states: &BTreeMap<u64, State>,
+    preds: &BTreeMap<u64, Vec<u64>>,
+    va: u64,
+    space: &AddressSpace<'_>,
+) -> Option<Switch> {
+    let block = &func.blocks[&va];
+    let Terminator::Indirect(target) = &block.end else {
+        return None;
+    };
+    let state = states.get(&va)?;
+    let (table, index, entry_size, base) = table_jump(&state.eval(target))?;
+
+    // The guard is the only predecessor, and branches either here or to the default
+    let [guard] = preds.get(&va)?.as_slice() else {
+        return None;
+    };
+    let guard_block = &func.blocks[guard];
+    let Terminator::Branch {
+        cond,
+        taken,
+        fallthrough,
+    } = guard_block.end
+    else {
+        return None;
+    };
+    let (to_switch, default) = if taken == va {
+        (true, fallthrough)
+    } else {
+        (false, taken)
+    };
+    let (op, compared, bound) = states.get(guard)?.flag_source()?;
+    if *op != FlagOp::Sub || *compared != index {
+        return None;
+    }
+    let bound = bound.as_const()?;
+    let width = state.width();
+    let bound = match (
+        in_range(func.arch, width, cond, to_switch, 0, bound)?,
+        in_range(func.arch, width, cond, to_switch, bound, bound)?,
+    ) {
+        (true, true) => bound.checked_add(1)?,
+        (true, false) => bound,
+        _ => return None,
+    };
+    if bound == 0 || bound > MAX_CASES {
+        return None;
+    }
+
+    let mut cases = Vec::new();
+    for value in 0..bound {
+        let entry =
+            space.read_constant(table + value * u64::from(entry_size), entry_size as usize)?;
+        let target = match base {
+            Some(base) => {
+                let shift = 64 - entry_size * 8;
+                base.wrapping_add((((entry << shift) as i64) >> shift) as u64)
+            }
+            None => entry,
+        };
+        cases.push(target & (u64::MAX >> (64 - width)));
+    }
+    let index = guard_block
+        .insns
+        .iter()
+        .flat_map(|insn| &insn.stmts)
+        .rev()
+        .find_map(|stmt| match stmt {
+            Stmt::Flags(_, Expr::Reg(reg), _) => Some(Some(*reg)),
+            Stmt::Flags(..) => Some(None),
+            _ => None,
+        })
+        .flatten();
+    Some(Switch {
+        block: va,
+        jump: block.end_va,
+        guard: *guard,
+        index,
+        bound,
+        table,
+        entry_size,
+        base,
+        default,
+        cases,
+    })
+}
+
+/// Recover the jump tables of a function and attach them to it. Returns the switches found;
+/// their cases may be blocks the function doesn't have yet.
+pub fn recover(func: &mut Function, space: &AddressSpace<'_>) -> Vec<Switch> {
+    let states = func.block_states();
+    let preds = func.predecessors();
+    let switches: Vec<Switch> = func
+        .blocks
+        .keys()
+        .filter_map(|va| recover_at(func, &states, &preds, *va, space))
+        .collect();
+    for switch in &switches {
+        func.add_switch(switch.clone());
+    }
+    switches
+}
+
+/// Record a switch in the workspace: code xrefs from the jump to each case, a data xref to the
+/// table, comments on the jump and the cases, and the jump in the `SwitchCases` set
+pub fn annotate(workspace: &mut VivWorkspace, switch: &Switch) {
+    let jump = switch.jump as i32;
+    workspace.add_xref(jump, switch.table as i32, REF_DATA, 0);
+    for (target, values) in switch.arms() {
+        workspace.add_xref(jump, target as i32, REF_CODE, BR_COND);
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let comment = format!("case {}", values.join(", "));
+        workspace.set_comment(target as i32, &comment, true);
+    }
+    let comment = format!(
+        "switch: {} cases, table {:#x}, {} byte entries, default {:#x}",
+        switch.bound, switch.table, switch.entry_size, switch.default
+    );
+    workspace.set_comment(switch.default as i32, "default case", true);
+    workspace.set_comment(jump, &comment, true);
+    let mut rows = workspace.get_va_set_rows("SwitchCases").unwrap_or_default();
+    if !rows.contains(&jump) {
+        rows.push(jump);
+    }
+    workspace.set_va_set_row("SwitchCases", rows);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envi::{flags::Condition, registers::RegisterModel};
+    use crate::symbolic::{Block, FlagOp, Insn};
+
+    #[test]
+    fn jump_tables() {
+        let block = |va, insns, end_va, end| Block {
+            va,
+            insns,
+            end_va,
+            end,
+        };
+
+        // i386: cmp eax, 3; ja default; jmp [0x2000 + eax*4]
+        let model = RegisterModel::new(Arch::I386);
+        let eax = model.by_name("eax").unwrap();
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![Insn {
+                va: 0x1000,
+                stmts: vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(3))],
+            }],
+            0x1003,
+            Terminator::Branch {
+                cond: Condition::NoCarryNoZero,
+                taken: 0x1100,
+                fallthrough: 0x1005,
+            },
+        ));
+        let scaled = Expr::binary(BinOp::Mul, Expr::Reg(eax), Expr::Const(4), 32);
+        let addr = Expr::binary(BinOp::Add, scaled, Expr::Const(0x2000), 32);
+        func.add_block(block(
+            0x1005,
+            vec![],
+            0x1005,
+            Terminator::Indirect(Expr::Load(Box::new(addr))),
+        ));
+        let table: Vec<u8> = [0x1010u32, 0x1020, 0x1010, 0x1030]
+            .iter()
+            .flat_map(|t| t.to_le_bytes())
+            .collect();
+        let mut space = AddressSpace::new();
+        space.add_map(0x2000, &table, false);
+        let switches = recover(&mut func, &space);
+        assert_eq!(switches.len(), 1);
+        let switch = &switches[0];
+        assert_eq!(
+            (
+                switch.guard,
+                switch.index,
+                switch.bound,
+                switch.table,
+                switch.entry_size
+            ),
+            (0x1000, Some(eax), 4, 0x2000, 4)
+        );
+        assert_eq!(switch.default, 0x1100);
+        assert_eq!(switch.cases, vec![0x1010, 0x1020, 0x1010, 0x1030]);
+        assert_eq!(switch.arms()[&0x1010], vec![0, 2]);
+        assert_eq!(func.successors(0x1005), vec![0x1010, 0x1020, 0x1030]);
+        assert_eq!(func.switches[&0x1005], *switch);
+
+        // amd64 with relative entries: cmp rdi, 2; jae default; lea rdx, [rip + table];
+        // movsxd rax, [rdx + rdi*4]; add rax, rdx; jmp rax
+        let model = RegisterModel::new(Arch::Amd64);
+        let (rdi, rdx, rax) = (
+            model.by_name("rdi").unwrap(),
+            model.by_name("rdx").unwrap(),
+            model.by_name("rax").unwrap(),
+        );
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        func.add_block(block(
+            0x1000,
+            vec![Insn {
+                va: 0x1000,
+                stmts: vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(rdi), Expr::Const(2))],
+            }],
+            0x1004,
+            Terminator::Branch {
+                cond: Condition::NoCarry,
+                taken: 0x1100,
+                fallthrough: 0x1006,
+            },
+        ));
+        let scaled = Expr::binary(BinOp::Shl, Expr::Reg(rdi), Expr::Const(2), 64);
+        let addr = Expr::binary(BinOp::Add, scaled, Expr::Reg(rdx), 64);
+        let loaded = Expr::binary(BinOp::Add, Expr::Load(Box::new(addr)), Expr::Reg(rdx), 64);
+        func.add_block(block(
+            0x1006,
+            vec![
+                Insn {
+                    va: 0x1006,
+                    stmts: vec![Stmt::Set(rdx, Expr::Const(0x2000))],
+                },
+                Insn {
+                    va: 0x100d,
+                    stmts: vec![Stmt::Set(rax, loaded)],
+                },
+            ],
+            0x1014,
+            Terminator::Indirect(Expr::Reg(rax)),
+        ));
+        let table: Vec<u8> = [-0x1000i32 + 0x10, -0x1000 + 0x40]
+            .iter()
+            .flat_map(|t| t.to_le_bytes())
+            .collect();
+        let mut space = AddressSpace::new();
+        space.add_map(0x2000, &table, false);
+        let switches = recover(&mut func, &space);
+        assert_eq!(switches.len(), 1);
+        assert_eq!(switches[0].bound, 2);
+        assert_eq!(switches[0].base, Some(0x2000));
+        assert_eq!(switches[0].cases, vec![0x1010, 0x1040]);
+
+        let mut workspace = VivWorkspace::new("", false);
+        annotate(&mut workspace, &switches[0]);
+        assert_eq!(workspace.get_va_set_rows("SwitchCases"), Some(vec![0x1014]));
+    }
+}
@@ -0,0 +1,891 @@
+//! Symbolic execution over a small lifted form of machine code.
+//!
+//! Instructions are lifted into [`Stmt`]s over full registers, and basic blocks and functions
+//! into [`Block`]s and [`Function`]s. A [`State`] runs statements symbolically: registers and
+//! memory hold [`Expr`]s in terms of the values registers held when the state was created, and
+//! the condition flags are tracked three valued (see [`crate::envi::flags`]) so a conditional
+//! branch can be decided without knowing every input.
+//!
+//! Expressions are simplified as they are built, and [`Expr::known_bits`] proves bits of values
+//! which aren't constant: `x * (x + 1)` is always even, `x | 1` is never zero.
+//!
+//! The crate has no instruction decoder yet, so lifting is up to the caller. Memory is modelled
+//! by address expressions only: a load sees the last store through an identical address, and
+//! stores through different addresses are assumed not to alias.
+
+use crate::{
+    envi::{
+        flags::{self, Condition, Effect, Flag, FlagUpdate, Flags},
+        registers::{RegId, RegisterModel},
+        Arch,
+    },
+    switches::Switch,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UnOp {
+    Not,
+    Neg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Sar,
+}
+
+impl BinOp {
+    pub(crate) fn commutative(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor
+        )
+    }
+}
+
+/// A value of the register width of the architecture
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Expr {
+    Const(u64),
+    /// The value of a register, as of the start of execution
+    Reg(RegId),
+    /// A load from memory
+    Load(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+fn fold(op: BinOp, a: u64, b: u64, width: u32) -> u64 {
+    let m = mask(width);
+    let value = match op {
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        BinOp::And => a & b,
+        BinOp::Or => a | b,
+        BinOp::Xor => a ^ b,
+        BinOp::Shl if b >= u64::from(width) => 0,
+        BinOp::Shl => a << b,
+        BinOp::Shr if b >= u64::from(width) => 0,
+        BinOp::Shr => a >> b,
+        BinOp::Sar => {
+            let extended = ((a << (64 - width)) as i64) >> (64 - width);
+            (extended >> b.min(63)) as u64
+        }
+    };
+    value & m
+}
+
+impl Expr {
+    pub fn binary(op: BinOp, a: Expr, b: Expr, width: u32) -> Expr {
+        use BinOp::*;
+        use Expr::Const;
+        let m = mask(width);
+        match (op, &a, &b) {
+            (_, Const(x), Const(y)) => Const(fold(op, *x, *y, width)),
+            // Keep constants on the right
+            (_, Const(_), _) if op.commutative() => Expr::binary(op, b, a, width),
+            (Sub, _, Const(c)) => Expr::binary(Add, a, Const(c.wrapping_neg() & m), width),
+            (Sub | Xor, _, _) if a == b => Const(0),
+            (And | Or, _, _) if a == b => a,
+            (Add | Or | Xor | Shl | Shr | Sar, _, Const(0)) => a,
+            (Mul | And, _, Const(0)) => Const(0),
+            (Mul, _, Const(1)) => a,
+            (And, _, Const(c)) if *c == m => a,
+            (Or, _, Const(c)) if *c == m => Const(m),
+            (Shl | Shr, _, Const(c)) if *c >= u64::from(width) => Const(0),
+            // (x op c1) op c2 is x op (c1 op c2)
+            (Add | Mul | And | Or | Xor, Expr::Binary(inner, x, c1), Const(c2))
+                if *inner == op && matches!(**c1, Const(_)) =>
+            {
+                let Const(c1) = **c1 else { unreachable!() };
+                let c = Const(fold(op, c1, *c2, width));
+                Expr::binary(op, (**x).clone(), c, width)
+            }
+            // (x ^ y) ^ y is x, (x + y) - y is x, (x - y) + y is x
+            (Xor, Expr::Binary(Xor, x, y), _) | (Sub, Expr::Binary(Add, x, y), _) if **y == b => {
+                (**x).clone()
+            }
+            (Xor, Expr::Binary(Xor, y, x), _) | (Sub, Expr::Binary(Add, y, x), _) if **y == b => {
+                (**x).clone()
+            }
+            (Add, Expr::Binary(Sub, x, y), _) if **y == b => (**x).clone(),
+            _ => Expr::Binary(op, Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn unary(op: UnOp, a: Expr, width: u32) -> Expr {
+        match (op, a) {
+            (UnOp::Not, Expr::Const(c)) => Expr::Const(!c & mask(width)),
+            (UnOp::Neg, Expr::Const(c)) => Expr::Const(c.wrapping_neg() & mask(width)),
+            (op, Expr::Unary(inner, x)) if inner == op => *x,
+            (op, a) => Expr::Unary(op, Box::new(a)),
+        }
+    }
+
+    pub fn as_const(&self) -> Option<u64> {
+        match self {
+            Expr::Const(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    /// The addresses the expression loads from, innermost first
+    pub fn loads(&self, addrs: &mut Vec<Expr>) {
+        match self {
+            Expr::Const(_) | Expr::Reg(_) => {}
+            Expr::Load(addr) => {
+                addr.loads(addrs);
+                addrs.push((**addr).clone());
+            }
+            Expr::Unary(_, a) => a.loads(addrs),
+            Expr::Binary(_, a, b) => {
+                a.loads(addrs);
+                b.loads(addrs);
+            }
+        }
+    }
+
+    /// The registers the expression reads
+    pub fn registers(&self, regs: &mut BTreeSet<RegId>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Reg(r) => {
+                regs.insert(*r);
+            }
+            Expr::Load(addr) => addr.registers(regs),
+            Expr::Unary(_, a) => a.registers(regs),
+            Expr::Binary(_, a, b) => {
+                a.registers(regs);
+                b.registers(regs);
+            }
+        }
+    }
+
+    /// The bits of the value which are the same whatever the registers and memory hold
+    pub fn known_bits(&self, width: u32) -> KnownBits {
+        let m = mask(width);
+        let mut bits = match self {
+            Expr::Const(c) => KnownBits {
+                zeros: !c & m,
+                ones: *c & m,
+            },
+            Expr::Reg(_) | Expr::Load(_) => KnownBits::default(),
+            Expr::Unary(UnOp::Not, a) => a.known_bits(width).not(width),
+            Expr::Unary(UnOp::Neg, a) => {
+                let one = Expr::Const(1).known_bits(width);
+                KnownBits::add(a.known_bits(width).not(width), one, width)
+            }
+            Expr::Binary(op, a, b) => {
+                let (x, y) = (a.known_bits(width), b.known_bits(width));
+                let shift = b.as_const().map(|c| c.min(u64::from(width)) as u32);
+                match (op, shift) {
+                    (BinOp::And, _) => KnownBits {
+                        zeros: x.zeros | y.zeros,
+                        ones: x.ones & y.ones,
+                    },
+                    (BinOp::Or, _) => KnownBits {
+                        zeros: x.zeros & y.zeros,
+                        ones: x.ones | y.ones,
+                    },
+                    (BinOp::Xor, _) => KnownBits {
+                        zeros: (x.zeros & y.zeros) | (x.ones & y.ones),
+                        ones: (x.zeros & y.ones) | (x.ones & y.zeros),
+                    },
+                    (BinOp::Add, _) => KnownBits::add(x, y, width),
+                    (BinOp::Sub, _) => {
+                        let negated =
+                            KnownBits::add(y.not(width), Expr::Const(1).known_bits(width), width);
+                        KnownBits::add(x, negated, width)
+                    }
+                    (BinOp::Mul, _) => {
+                        let low = (x.trailing_zeros() + y.trailing_zeros()).min(width);
+                        KnownBits {
+                            zeros: mask(low),
+                            ones: x.ones & y.ones & 1,
+                        }
+                    }
+                    (BinOp::Shl, Some(c)) if c < width => KnownBits {
+                        zeros: ((x.zeros << c) | mask(c)) & m,
+                        ones: (x.ones << c) & m,
+                    },
+                    (BinOp::Shr, Some(c)) if c < width => KnownBits {
+                        zeros: (x.zeros >> c) | (m & !(m >> c)),
+                        ones: x.ones >> c,
+                    },
+                    _ => KnownBits::default(),
+                }
+            }
+        };
+        // The low bit may follow from the shape of the expression alone
+        if let Some((atoms, bit)) = self.low_bit() {
+            if atoms.is_empty() {
+                if bit {
+                    bits.ones |= 1;
+                } else {
+                    bits.zeros |= 1;
+                }
+            }
+        }
+        bits
+    }
+
+    /// The low bit of the value as the xor of the low bits of a set of leaves and a constant,
+    /// where that is possible
+    fn low_bit(&self) -> Option<(BTreeSet<Expr>, bool)> {
+        let xor = |(mut a, x): (BTreeSet<Expr>, bool), (b, y): (BTreeSet<Expr>, bool)| {
+            for atom in b {
+                if !a.remove(&atom) {
+                    a.insert(atom);
+                }
+            }
+            (a, x ^ y)
+        };
+        match self {
+            Expr::Const(c) => Some((BTreeSet::new(), c & 1 == 1)),
+            Expr::Reg(_) | Expr::Load(_) => Some(([self.clone()].into(), false)),
+            Expr::Unary(UnOp::Neg, a) => a.low_bit(),
+            Expr::Unary(UnOp::Not, a) => a.low_bit().map(|(atoms, bit)| (atoms, !bit)),
+            Expr::Binary(BinOp::Add | BinOp::Sub | BinOp::Xor, a, b) => {
+                Some(xor(a.low_bit()?, b.low_bit()?))
+            }
+            Expr::Binary(BinOp::Mul | BinOp::And, a, b) => {
+                let (a, x) = a.low_bit()?;
+                let (b, y) = b.low_bit()?;
+                match (a.is_empty(), b.is_empty()) {
+                    (true, _) if !x => Some((BTreeSet::new(), false)),
+                    (_, true) if !y => Some((BTreeSet::new(), false)),
+                    (true, _) => Some((b, y)),
+                    (_, true) => Some((a, x)),
+                    // v & v is v, v & !v is 0
+                    _ if a == b && x == y => Some((a, x)),
+                    _ if a == b => Some((BTreeSet::new(), false)),
+                    _ => None,
+                }
+            }
+            Expr::Binary(BinOp::Shl, _, b) if b.as_const().is_some_and(|c| c > 0) => {
+                Some((BTreeSet::new(), false))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Bits of a value known to be zero or one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KnownBits {
+    pub zeros: u64,
+    pub ones: u64,
+}
+
+impl KnownBits {
+    fn not(self, width: u32) -> Self {
+        KnownBits {
+            zeros: self.ones,
+            ones: self.zeros & mask(width),
+        }
+    }
+
+    fn trailing_zeros(&self) -> u32 {
+        self.zeros.trailing_ones()
+    }
+
+    /// Ripple the known bits through an adder
+    fn add(a: Self, b: Self, width: u32) -> Self {
+        let mut result = KnownBits::default();
+        let mut carry = Some(false);
+        let bit = |bits: Self, n: u32| {
+            if bits.ones >> n & 1 == 1 {
+                Some(true)
+            } else if bits.zeros >> n & 1 == 1 {
+                Some(false)
+            } else {
+                None
+            }
+        };
+        for n in 0..width {
+            let (x, y) = (bit(a, n), bit(b, n));
+            match (x, y, carry) {
+                (Some(x), Some(y), Some(c)) => {
+                    if x ^ y ^ c {
+                        result.ones |= 1 << n;
+                    } else {
+                        result.zeros |= 1 << n;
+                    }
+                    carry = Some((x & y) | (c & (x ^ y)));
+                }
+                _ => {
+                    // The carry is still known if two of the three inputs agree
+                    let known: Vec<bool> = [x, y, carry].into_iter().flatten().collect();
+                    carry = match known[..] {
+                        [p, q] if p == q => Some(p),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        result
+    }
+
+    pub fn is_nonzero(&self) -> bool {
+        self.ones != 0
+    }
+}
+
+/// The operation a flag setting statement computes the flags from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FlagOp {
+    Add,
+    /// A subtraction or compare
+    Sub,
+    /// A bitwise and, which is also a `test`
+    And,
+    Or,
+    Xor,
+}
+
+impl FlagOp {
+    /// The flags set by the operation on two known values
+    pub fn update(self, arch: Arch, a: u64, b: u64, width: u32) -> FlagUpdate {
+        match self {
+            FlagOp::Add => flags::add(arch, a, b, width),
+            FlagOp::Sub => flags::sub(arch, a, b, width),
+            FlagOp::And => flags::logic(arch, a & b, width),
+            FlagOp::Or => flags::logic(arch, a | b, width),
+            FlagOp::Xor => flags::logic(arch, a ^ b, width),
+        }
+    }
+}
+
+/// The effects of a lifted instruction, in terms of the values the registers hold before it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stmt {
+    Set(RegId, Expr),
+    Store(Expr, Expr),
+    /// Set the flags from an operation on two values
+    Flags(FlagOp, Expr, Expr),
+    /// An instruction whose effects aren't modelled; everything is unknown after it
+    Unknown,
+}
+
+impl Stmt {
+    /// The registers the statement reads; None for an unknown statement, which may read any
+    pub fn reads(&self) -> Option<BTreeSet<RegId>> {
+        let mut regs = BTreeSet::new();
+        match self {
+            Stmt::Set(_, e) => e.registers(&mut regs),
+            Stmt::Store(addr, value) | Stmt::Flags(_, addr, value) => {
+                addr.registers(&mut regs);
+                value.registers(&mut regs);
+            }
+            Stmt::Unknown => return None,
+        }
+        Some(regs)
+    }
+}
+
+/// An instruction and its effects
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Insn {
+    pub va: u64,
+    pub stmts: Vec<Stmt>,
+}
+
+/// How a basic block ends
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(u64),
+    Branch {
+        cond: Condition,
+        taken: u64,
+        fallthrough: u64,
+    },
+    /// A computed jump, through a switch table or a dispatcher
+    Indirect(Expr),
+    Return,
+    /// A jump to the entry of another function
+    TailCall(u64),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<u64> {
+        match self {
+            Terminator::Jump(to) => vec![*to],
+            Terminator::Branch {
+                taken, fallthrough, ..
+            } => vec![*taken, *fallthrough],
+            Terminator::Indirect(_) | Terminator::Return | Terminator::TailCall(_) => vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub va: u64,
+    pub insns: Vec<Insn>,
+    /// The address of the instruction the block ends with
+    pub end_va: u64,
+    pub end: Terminator,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub arch: Arch,
+    pub entry: u64,
+    pub blocks: BTreeMap<u64, Block>,
+    /// The switches the indirect jumps of blocks dispatch through, by block
+    pub switches: BTreeMap<u64, Switch>,
+    /// The targets of indirect jumps resolved otherwise than as a switch, by block
+    pub targets: BTreeMap<u64, Vec<u64>>,
+}
+
+impl Function {
+    pub fn new(arch: Arch, entry: u64) -> Self {
+        Function {
+            arch,
+            entry,
+            blocks: BTreeMap::new(),
+            switches: BTreeMap::new(),
+            targets: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_block(&mut self, block: Block) {
+        self.blocks.insert(block.va, block);
+    }
+
+    pub fn add_switch(&mut self, switch: Switch) {
+        self.switches.insert(switch.block, switch);
+    }
+
+    /// Where the indirect jump ending `block` goes, when it isn't through a switch
+    pub fn add_targets(&mut self, block: u64, targets: Vec<u64>) {
+        self.targets.insert(block, targets);
+    }
+
+    /// The successors of a block, the cases of its switch or its resolved targets included
+    pub fn successors(&self, va: u64) -> Vec<u64> {
+        let Some(block) = self.blocks.get(&va) else {
+            return vec![];
+        };
+        if let Terminator::Indirect(_) = block.end {
+            if let Some(switch) = self.switches.get(&va) {
+                return switch.arms().into_keys().collect();
+            }
+            if let Some(targets) = self.targets.get(&va) {
+                return targets.clone();
+            }
+        }
+        block.end.successors()
+    }
+
+    /// The blocks branching to each block
+    pub fn predecessors(&self) -> BTreeMap<u64, Vec<u64>> {
+        let mut preds: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for block in self.blocks.values() {
+            for succ in self.successors(block.va) {
+                let entry = preds.entry(succ).or_default();
+                if !entry.contains(&block.va) {
+                    entry.push(block.va);
+                }
+            }
+        }
+        preds
+    }
+
+    /// The state at the end of each reachable block. A block with a single predecessor starts
+    /// from the state its predecessor ended with, any other block from a fresh state.
+    pub fn block_states(&self) -> BTreeMap<u64, State> {
+        let preds = self.predecessors();
+        let mut states: BTreeMap<u64, State> = BTreeMap::new();
+        for va in self.reverse_postorder() {
+            let mut state = match preds.get(&va).map(Vec::as_slice) {
+                Some([pred]) if *pred != va => states
+                    .get(pred)
+                    .cloned()
+                    .unwrap_or_else(|| State::new(self.arch)),
+                _ => State::new(self.arch),
+            };
+            for insn in &self.blocks[&va].insns {
+                state.exec_insn(insn);
+            }
+            states.insert(va, state);
+        }
+        states
+    }
+
+    /// The stack pointer at the end of each reachable block, relative to its value at the
+    /// entry, where every path into the block agrees on it
+    pub fn stack_offsets(&self) -> BTreeMap<u64, Option<i64>> {
+        let preds = self.predecessors();
+        let mut offsets: BTreeMap<u64, Option<i64>> = BTreeMap::new();
+        for va in self.reverse_postorder() {
+            let incoming: Vec<Option<i64>> = if va == self.entry {
+                vec![Some(0)]
+            } else {
+                let preds = preds.get(&va).into_iter().flatten();
+                preds
+                    .filter_map(|pred| offsets.get(pred).copied())
+                    .collect()
+            };
+            let start = match incoming.split_first() {
+                Some((first, rest)) if rest.iter().all(|o| o == first) => *first,
+                _ => None,
+            };
+            let mut state = State::new(self.arch);
+            for insn in &self.blocks[&va].insns {
+                state.exec_insn(insn);
+            }
+            let end = start.zip(state.stack_offset()).map(|(s, d)| s + d);
+            offsets.insert(va, end);
+        }
+        offsets
+    }
+
+    /// The blocks reachable from the entry, in reverse postorder
+    pub fn reverse_postorder(&self) -> Vec<u64> {
+        let mut order = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![(self.entry, false)];
+        while let Some((va, done)) = stack.pop() {
+            if done {
+                order.push(va);
+                continue;
+            }
+            if !self.blocks.contains_key(&va) || !seen.insert(va) {
+                continue;
+            }
+            stack.push((va, true));
+            for succ in self.successors(va).into_iter().rev() {
+                stack.push((succ, false));
+            }
+        }
+        order.reverse();
+        order
+    }
+}
+
+/// Where integer argument `index` of a call is just before the call instruction: on the stack
+/// on i386, and in registers elsewhere, by the Windows ABI on amd64 if `windows`
+pub fn call_argument(arch: Arch, windows: bool, index: usize) -> Option<Expr> {
+    let regs = RegisterModel::new(arch);
+    let names: &[&str] = match arch {
+        Arch::I386 => {
+            let offset = Expr::Const(index as u64 * 4);
+            let slot = Expr::binary(BinOp::Add, Expr::Reg(regs.sp()), offset, 32);
+            return Some(Expr::Load(Box::new(slot)));
+        }
+        Arch::Amd64 if windows => &["rcx", "rdx", "r8", "r9"],
+        Arch::Amd64 => &["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+        Arch::A64 => &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"],
+        Arch::ArmV7 | Arch::Thumb | Arch::Thumb16 => &["r0", "r1", "r2", "r3"],
+        Arch::Msp430 | Arch::H8 => return None,
+    };
+    regs.by_name(names.get(index)?).map(Expr::Reg)
+}
+
+/// Where a call leaves its integer result
+pub fn return_value(arch: Arch) -> Option<Expr> {
+    let name = match arch {
+        Arch::I386 => "eax",
+        Arch::Amd64 => "rax",
+        Arch::A64 => "x0",
+        Arch::ArmV7 | Arch::Thumb | Arch::Thumb16 => "r0",
+        Arch::Msp430 | Arch::H8 => return None,
+    };
+    RegisterModel::new(arch).by_name(name).map(Expr::Reg)
+}
+
+/// Symbolic registers, memory and flags
+#[derive(Clone, Debug)]
+pub struct State {
+    arch: Arch,
+    width: u32,
+    sp: RegId,
+    regs: HashMap<RegId, Expr>,
+    memory: Vec<(Expr, Expr)>,
+    flags: Flags,
+    /// The operation the flags were last set from
+    compare: Option<(FlagOp, Expr, Expr)>,
+}
+
+impl State {
+    pub fn new(arch: Arch) -> Self {
+        State {
+            arch,
+            width: arch.pointer_size() as u32 * 8,
+            sp: RegisterModel::new(arch).sp(),
+            regs: HashMap::new(),
+            memory: Vec::new(),
+            flags: Flags::unknown(),
+            compare: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn reg(&self, reg: RegId) -> Expr {
+        self.regs.get(&reg).cloned().unwrap_or(Expr::Reg(reg))
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    /// The operation and operands the flags were last set from
+    pub fn flag_source(&self) -> Option<&(FlagOp, Expr, Expr)> {
+        self.compare.as_ref()
+    }
+
+    /// The stores made, as address and value
+    pub fn stores(&self) -> &[(Expr, Expr)] {
+        &self.memory
+    }
+
+    /// Substitute the current register and memory values into an expression
+    pub fn eval(&self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Const(c) => Expr::Const(*c & mask(self.width)),
+            Expr::Reg(r) => self.reg(*r),
+            Expr::Load(addr) => {
+                let addr = self.eval(addr);
+                match self.memory.iter().rev().find(|(a, _)| *a == addr) {
+                    Some((_, value)) => value.clone(),
+                    None => Expr::Load(Box::new(addr)),
+                }
+            }
+            Expr::Unary(op, a) => Expr::unary(*op, self.eval(a), self.width),
+            Expr::Binary(op, a, b) => Expr::binary(*op, self.eval(a), self.eval(b), self.width),
+        }
+    }
+
+    /// Run a statement. Statements of an instruction are run one after the other, so lift
+    /// instructions whose effects depend on each other (`xchg`) through a temporary.
+    pub fn exec(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Set(reg, value) => {
+                let value = self.eval(value);
+                if value == Expr::Reg(*reg) {
+                    self.regs.remove(reg);
+                } else {
+                    self.regs.insert(*reg, value);
+                }
+            }
+            Stmt::Store(addr, value) => {
+                let (addr, value) = (self.eval(addr), self.eval(value));
+                self.memory.retain(|(a, _)| *a != addr);
+                self.memory.push((addr, value));
+            }
+            Stmt::Flags(op, a, b) => {
+                let (a, b) = (self.eval(a), self.eval(b));
+                self.set_flags(*op, &a, &b);
+                self.compare = Some((*op, a, b));
+            }
+            Stmt::Unknown => *self = State::new(self.arch),
+        }
+    }
+
+    pub fn exec_insn(&mut self, insn: &Insn) {
+        for stmt in &insn.stmts {
+            self.exec(stmt);
+        }
+    }
+
+    fn set_flags(&mut self, op: FlagOp, a: &Expr, b: &Expr) {
+        let (arch, width) = (self.arch, self.width);
+        let concrete = |x: u64, y: u64| op.update(arch, x, y, width);
+        if let (Some(x), Some(y)) = (a.as_const(), b.as_const()) {
+            self.flags.apply(&concrete(x, y));
+            return;
+        }
+        // Work out what we can from the shape of the result
+        let result = match op {
+            FlagOp::Add => Expr::binary(BinOp::Add, a.clone(), b.clone(), width),
+            FlagOp::Sub => Expr::binary(BinOp::Sub, a.clone(), b.clone(), width),
+            FlagOp::And => Expr::binary(BinOp::And, a.clone(), b.clone(), width),
+            FlagOp::Or => Expr::binary(BinOp::Or, a.clone(), b.clone(), width),
+            FlagOp::Xor => Expr::binary(BinOp::Xor, a.clone(), b.clone(), width),
+        };
+        let bits = result.known_bits(width);
+        let sign = 1 << (width - 1);
+        let mut known = Flags::unknown();
+        if bits.is_nonzero() {
+            known.set(Flag::Zero, Some(false));
+        } else if bits.zeros == mask(width) {
+            known.set(Flag::Zero, Some(true));
+        }
+        if bits.ones & sign != 0 {
+            known.set(Flag::Sign, Some(true));
+        } else if bits.zeros & sign != 0 {
+            known.set(Flag::Sign, Some(false));
+        }
+        // The carry and overflow are known when they don't depend on the operands: after a
+        // logical operation, or comparing a value with itself
+        let templates = match op {
+            FlagOp::Sub if a == b => Some((concrete(0, 0), concrete(1, 1))),
+            FlagOp::And | FlagOp::Or | FlagOp::Xor => {
+                Some((flags::logic(arch, 0, width), flags::logic(arch, 1, width)))
+            }
+            _ => None,
+        };
+        if let Some((first, second)) = templates {
+            for flag in [Flag::Carry, Flag::Overflow] {
+                if let (Effect::Set(x), Effect::Set(y)) = (first.effect(flag), second.effect(flag))
+                {
+                    if x == y {
+                        known.set(flag, Some(x));
+                    }
+                }
+            }
+        }
+        let template = concrete(0, 1);
+        for flag in [
+            Flag::Carry,
+            Flag::Parity,
+            Flag::Adjust,
+            Flag::Zero,
+            Flag::Sign,
+            Flag::Overflow,
+        ] {
+            match template.effect(flag) {
+                Effect::Unchanged => {}
+                Effect::Undefined => self.flags.set(flag, None),
+                Effect::Set(_) => self.flags.set(flag, known.get(flag)),
+            }
+        }
+    }
+
+    /// Whether a condition holds, if the flags it tests are known
+    pub fn condition(&self, cond: Condition) -> Option<bool> {
+        cond.eval(&self.flags)
+    }
+
+    /// Whether the registers hold what they held at the start
+    pub fn registers_unchanged(&self) -> bool {
+        self.regs.is_empty()
+    }
+
+    /// Whether the stack pointer is `sp + offset` for the starting `sp`
+    pub fn stack_offset(&self) -> Option<i64> {
+        match self.reg(self.sp) {
+            Expr::Reg(r) if r == self.sp => Some(0),
+            Expr::Binary(BinOp::Add, a, c) if *a == Expr::Reg(self.sp) => {
+                let shift = 64 - self.width;
+                c.as_const().map(|c| ((c << shift) as i64) >> shift)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `addr` is below the stack pointer the state started with, where a store is dead
+    /// once the stack pointer is back
+    pub fn below_stack(&self, addr: &Expr) -> bool {
+        let shift = 64 - self.width;
+        match addr {
+            Expr::Binary(BinOp::Add, a, c) if **a == Expr::Reg(self.sp) => c
+                .as_const()
+                .is_some_and(|c| (((c << shift) as i64) >> shift) < 0),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simplify() {
+        let x = || Expr::Reg(0);
+        let w = 32;
+        let add = Expr::binary(BinOp::Add, x(), Expr::Const(5), w);
+        assert_eq!(Expr::binary(BinOp::Sub, add, Expr::Const(5), w), x());
+        let xored = Expr::binary(BinOp::Xor, x(), Expr::Reg(1), w);
+        assert_eq!(Expr::binary(BinOp::Xor, xored, Expr::Reg(1), w), x());
+        assert_eq!(Expr::binary(BinOp::Sub, x(), x(), w), Expr::Const(0));
+        assert_eq!(
+            Expr::binary(BinOp::Add, Expr::Const(0xffff_ffff), Expr::Const(2), w),
+            Expr::Const(1)
+        );
+        assert_eq!(
+            Expr::unary(UnOp::Not, Expr::unary(UnOp::Not, x(), w), w),
+            x()
+        );
+    }
+
+    #[test]
+    fn known_bits() {
+        let x = || Expr::Reg(0);
+        let w = 32;
+        // x * (x + 1) is even
+        let next = Expr::binary(BinOp::Add, x(), Expr::Const(1), w);
+        let product = Expr::binary(BinOp::Mul, x(), next, w);
+        assert_eq!(product.known_bits(w).zeros & 1, 1);
+        // and so is x * x + x
+        let square = Expr::binary(BinOp::Mul, x(), x(), w);
+        let sum = Expr::binary(BinOp::Add, square, x(), w);
+        assert_eq!(sum.known_bits(w).zeros & 1, 1);
+        let odd = Expr::binary(BinOp::Or, x(), Expr::Const(1), w);
+        assert!(odd.known_bits(w).is_nonzero());
+        let shifted = Expr::binary(BinOp::Shl, x(), Expr::Const(4), w);
+        assert_eq!(shifted.known_bits(w).zeros, 0xf);
+        let added = Expr::binary(BinOp::Add, shifted, Expr::Const(3), w);
+        assert_eq!(
+            added.known_bits(w),
+            KnownBits {
+                zeros: 0xc,
+                ones: 3
+            }
+        );
+    }
+
+    #[test]
+    fn state() {
+        let model = RegisterModel::new(Arch::I386);
+        let reg = |name| model.by_name(name).unwrap();
+        let (eax, esp) = (reg("eax"), reg("esp"));
+        let mut state = State::new(Arch::I386);
+        // push eax; pop eax
+        let top = Expr::binary(BinOp::Sub, Expr::Reg(esp), Expr::Const(4), 32);
+        state.exec(&Stmt::Set(esp, top));
+        state.exec(&Stmt::Store(Expr::Reg(esp), Expr::Reg(eax)));
+        state.exec(&Stmt::Set(eax, Expr::Load(Box::new(Expr::Reg(esp)))));
+        let bottom = Expr::binary(BinOp::Add, Expr::Reg(esp), Expr::Const(4), 32);
+        state.exec(&Stmt::Set(esp, bottom));
+        assert!(state.registers_unchanged());
+        assert!(state.below_stack(&state.stores()[0].0));
+
+        // cmp eax, eax; je
+        state.exec(&Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Reg(eax)));
+        assert_eq!(state.condition(Condition::Zero), Some(true));
+        assert_eq!(state.condition(Condition::Carry), Some(false));
+        // test eax, 1 after or eax, 1
+        let odd = Expr::binary(BinOp::Or, Expr::Reg(eax), Expr::Const(1), 32);
+        state.exec(&Stmt::Set(eax, odd));
+        state.exec(&Stmt::Flags(FlagOp::And, Expr::Reg(eax), Expr::Const(1)));
+        assert_eq!(state.condition(Condition::NotZero), Some(true));
+        assert_eq!(state.condition(Condition::Less), Some(false));
+        state.exec(&Stmt::Flags(FlagOp::Sub, Expr::Reg(eax), Expr::Const(7)));
+        assert_eq!(state.condition(Condition::Zero), None);
+        state.exec(&Stmt::Unknown);
+        assert_eq!(state.reg(eax), Expr::Reg(eax));
+    }
+}
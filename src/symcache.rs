@@ -0,0 +1,262 @@
+//! A local symbol cache keyed by module identity (the Mach-O `LC_UUID`, the ELF GNU build-id or
+//! the PE PDB GUID, see `Object::uuid`) and by the SHA-256 of the file's contents, which every
+//! file has (see [`content_id`]). The names, function sizes, function bounds and code blocks
+//! recovered for a module are stored relative to its image base, so they can be applied again
+//! whenever the same module is loaded, in whatever workspace and at whatever address it ends up.
+
+use crate::{storage::Annotations, utils::sha256};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// The extension of the per module cache files.
+pub const SYMCACHE_EXT: &str = "syms";
+
+/// The function meta key marking a function whose code blocks came from the cache.
+pub const FUNC_META_CACHED: &str = "SymbolCache";
+
+#[derive(Clone, Debug)]
+pub struct SymbolCache {
+    dir: PathBuf,
+}
+
+impl SymbolCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        SymbolCache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file the cache entry for the given module id lives in.
+    pub fn path_for(&self, module_id: &[u8]) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", module_id_hex(module_id), SYMCACHE_EXT))
+    }
+
+    /// Return the cached names and function boundaries for the module, relative to its image
+    /// base, or None if the module has not been seen before.
+    pub fn lookup(&self, module_id: &[u8]) -> io::Result<Option<Annotations>> {
+        match Annotations::load(self.path_for(module_id)) {
+            Ok(ann) => Ok(Some(ann)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store the names, function sizes, function bounds, code blocks and cross references of the
+    /// given (image base relative) annotations. Comments and types are workspace specific and
+    /// are not cached.
+    pub fn store(&self, module_id: &[u8], ann: &Annotations) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut entry = Annotations::new();
+        entry.names = ann.names.clone();
+        entry.functions = ann.functions.clone();
+        entry.bounds = ann.bounds.clone();
+        entry.blocks = ann.blocks.clone();
+        entry.xrefs = ann.xrefs.clone();
+        entry.save(self.path_for(module_id))
+    }
+
+    /// Remove the cache entry for the module, if any.
+    pub fn remove(&self, module_id: &[u8]) -> io::Result<()> {
+        match fs::remove_file(self.path_for(module_id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The cache key of a file by its contents: their SHA-256, which is longer than any module id
+pub fn content_id(contents: &[u8]) -> Vec<u8> {
+    sha256(contents).to_vec()
+}
+
+/// Render a module id the way it is used as a cache key.
+pub fn module_id_hex(module_id: &[u8]) -> String {
+    module_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Shift every VA of the annotations by `delta`.
+pub fn rebase(ann: &Annotations, delta: i32) -> Annotations {
+    let mut ret = Annotations::new();
+    ret.names = ann
+        .names
+        .iter()
+        .map(|(va, n)| (va.wrapping_add(delta), n.clone()))
+        .collect();
+    ret.comments = ann
+        .comments
+        .iter()
+        .map(|(va, c)| (va.wrapping_add(delta), c.clone()))
+        .collect();
+    ret.types = ann
+        .types
+        .iter()
+        .map(|(va, t)| (va.wrapping_add(delta), t.clone()))
+        .collect();
+    ret.tags = ann
+        .tags
+        .iter()
+        .map(|(va, tags)| (va.wrapping_add(delta), tags.clone()))
+        .collect();
+    ret.functions = ann
+        .functions
+        .iter()
+        .map(|(va, size)| (va.wrapping_add(delta), *size))
+        .collect();
+    ret.regions = ann
+        .regions
+        .iter()
+        .map(|(va, region)| (va.wrapping_add(delta), *region))
+        .collect();
+    ret.bounds = ann
+        .bounds
+        .iter()
+        .map(|(va, ranges)| {
+            let ranges = ranges
+                .iter()
+                .map(|(rva, size)| (rva.wrapping_add(delta), *size))
+                .collect();
+            (va.wrapping_add(delta), ranges)
+        })
+        .collect();
+    ret.blocks = ann
+        .blocks
+        .iter()
+        .map(|(fva, blocks)| {
+            let blocks = blocks
+                .iter()
+                .map(|(va, size)| (va.wrapping_add(delta), *size))
+                .collect();
+            (fva.wrapping_add(delta), blocks)
+        })
+        .collect();
+    ret.xrefs = ann
+        .xrefs
+        .iter()
+        .map(|(from, to, rtype, rflags)| {
+            (
+                from.wrapping_add(delta),
+                to.wrapping_add(delta),
+                *rtype,
+                *rflags,
+            )
+        })
+        .collect();
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_lookup() {
+        let dir = std::env::temp_dir().join(format!("vivisect-symcache-{}", std::process::id()));
+        let cache = SymbolCache::new(&dir);
+        let id = [0xde, 0xad, 0xbe, 0xef];
+        assert!(cache.lookup(&id).unwrap().is_none());
+        let mut ann = Annotations::new();
+        ann.names.insert(0x1000, "parse_header".to_string());
+        ann.comments.insert(0x1000, "not cached".to_string());
+        ann.functions.insert(0x1000, 0x40);
+        cache.store(&id, &ann).unwrap();
+        assert!(cache.path_for(&id).ends_with("deadbeef.syms"));
+        let cached = cache.lookup(&id).unwrap().unwrap();
+        assert_eq!(cached.names, ann.names);
+        assert_eq!(cached.functions, ann.functions);
+        assert!(cached.comments.is_empty());
+        cache.remove(&id).unwrap();
+        assert!(cache.lookup(&id).unwrap().is_none());
+        let _ = fs::remove_dir(&dir);
+    }
+
+    #[test]
+    fn rebase_roundtrip() {
+        let mut ann = Annotations::new();
+        ann.names.insert(0x401000, "main".to_string());
+        ann.functions.insert(0x401000, 0x20);
+        let relative = rebase(&ann, -0x400000);
+        assert_eq!(relative.names.get(&0x1000).unwrap(), "main");
+        assert_eq!(rebase(&relative, 0x400000), ann);
+    }
+
+    #[test]
+    fn reuse_by_content() {
+        use crate::{constants::REF_CODE, overrides::RegionKind, workspace::VivWorkspace};
+
+        let dir = std::env::temp_dir().join(format!("vivisect-content-{}", std::process::id()));
+        let confdir = dir.to_str().unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("libfoo.so");
+        fs::write(&path, b"libfoo contents").unwrap();
+        let id = content_id(b"libfoo contents");
+        let load = |imagebase: i32| {
+            let mut ws = VivWorkspace::new(confdir, false);
+            let fname = ws.add_file(path.to_str().unwrap(), imagebase, vec![]);
+            ws.add_segment(imagebase, 0x10000, ".text", fname.clone());
+            ws.set_content_id(&fname, id.clone());
+            (ws, fname)
+        };
+
+        let (mut ws, _) = load(0x10000);
+        ws.add_function(0x11000, vec![(0x11000, 0x20), (0x11800, 0x10)]);
+        ws.make_name(0x11000, "foo_init".to_string(), false, false);
+        ws.add_code_block(0x11000, 0x20, 0x11000);
+        ws.add_code_block(0x11800, 0x10, 0x11000);
+        ws.add_xref(0x1101c, 0x11800, REF_CODE, 0);
+        ws.save_symbol_cache().unwrap();
+        assert!(ws.get_symbol_cache().unwrap().path_for(&id).exists());
+        let found: Vec<_> = ws
+            .get_function_blocks(0x11000)
+            .into_iter()
+            .map(|(va, size, fva, _)| (va + 0x30000, size, fva + 0x30000))
+            .collect();
+
+        let (mut ws, fname) = load(0x40000);
+        assert_eq!(ws.apply_symbol_cache(&fname), 2);
+        assert_eq!(ws.get_name(0x41000, false).as_deref(), Some("foo_init"));
+        assert_eq!(
+            ws.get_function_bounds(0x41000),
+            Some(vec![(0x41000, 0x20), (0x41800, 0x10)])
+        );
+        assert_eq!(ws.resolve(0x41804).unwrap().to_string(), "foo_init+0x804");
+        // the blocks come back as they were found, and the function isn't analyzed again
+        let blocks = ws.get_function_blocks(0x41000);
+        let blocks: Vec<_> = blocks
+            .into_iter()
+            .map(|(va, size, fva, _)| (va, size, fva))
+            .collect();
+        assert_eq!(blocks, found);
+        assert_eq!(
+            ws.get_xrefs_to(0x41800, None),
+            [(0x4101c, 0x41800, REF_CODE, 0)]
+        );
+        ws.analyze_function(0x41000);
+        assert_eq!(ws.get_function_blocks(0x41000).len(), 2);
+
+        // a stale cache brings no function back into code overridden away, and what it does
+        // bring back is journaled
+        let (mut ws, fname) = load(0x40000);
+        ws.add_region_override(0x41000, 0x100, RegionKind::NeverCode);
+        ws.start_journal();
+        assert_eq!(ws.apply_symbol_cache(&fname), 1);
+        assert!(!ws.is_function(0x41000));
+        assert!(ws.get_functions().is_empty());
+        let (mut ws, fname) = load(0x40000);
+        ws.start_journal();
+        assert_eq!(ws.apply_symbol_cache(&fname), 2);
+        let mut replayed = VivWorkspace::new("", false);
+        ws.journal().unwrap().replay(&mut replayed);
+        assert_eq!(replayed.get_functions(), [0x41000]);
+        assert_eq!(
+            replayed.get_function_meta_dict(0x41000),
+            ws.get_function_meta_dict(0x41000)
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,590 @@
+//! The Linux system calls a binary makes, for sandbox profiles and capability triage.
+//!
+//! System calls come from two places: the syscall instructions in the code (`syscall`,
+//! `int 0x80` and `sysenter`, `svc #0`), with the number in a register (`rax`, `eax`, `x8`,
+//! `r7`), and the libc `syscall()` wrapper, with the number as the first argument. [`inventory`]
+//! finds both, and works out the number at each site from the lifted function around it where
+//! the code sets it to a constant. Calls to the libc wrappers of individual system calls
+//! (`read`, `mmap`, `execve`) count as uses of them too, as far as [`syscall_number`] knows the
+//! name.
+//!
+//! Numbers are per architecture, so [`syscall_name`] needs the architecture too. The tables
+//! cover the calls a sandbox profile usually deals with; others are reported by number.
+
+use crate::{
+    envi::{registers::RegisterModel, Arch},
+    symbolic::{BinOp, Expr, Function, State},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+const AMD64: &[(u64, &str)] = &[
+    (0, "read"),
+    (1, "write"),
+    (2, "open"),
+    (3, "close"),
+    (4, "stat"),
+    (5, "fstat"),
+    (6, "lstat"),
+    (7, "poll"),
+    (8, "lseek"),
+    (9, "mmap"),
+    (10, "mprotect"),
+    (11, "munmap"),
+    (12, "brk"),
+    (13, "rt_sigaction"),
+    (14, "rt_sigprocmask"),
+    (15, "rt_sigreturn"),
+    (16, "ioctl"),
+    (17, "pread64"),
+    (18, "pwrite64"),
+    (19, "readv"),
+    (20, "writev"),
+    (21, "access"),
+    (22, "pipe"),
+    (23, "select"),
+    (24, "sched_yield"),
+    (25, "mremap"),
+    (28, "madvise"),
+    (32, "dup"),
+    (33, "dup2"),
+    (35, "nanosleep"),
+    (39, "getpid"),
+    (40, "sendfile"),
+    (41, "socket"),
+    (42, "connect"),
+    (43, "accept"),
+    (44, "sendto"),
+    (45, "recvfrom"),
+    (46, "sendmsg"),
+    (47, "recvmsg"),
+    (48, "shutdown"),
+    (49, "bind"),
+    (50, "listen"),
+    (54, "setsockopt"),
+    (55, "getsockopt"),
+    (56, "clone"),
+    (57, "fork"),
+    (58, "vfork"),
+    (59, "execve"),
+    (60, "exit"),
+    (61, "wait4"),
+    (62, "kill"),
+    (63, "uname"),
+    (72, "fcntl"),
+    (79, "getcwd"),
+    (80, "chdir"),
+    (82, "rename"),
+    (83, "mkdir"),
+    (84, "rmdir"),
+    (87, "unlink"),
+    (89, "readlink"),
+    (90, "chmod"),
+    (92, "chown"),
+    (101, "ptrace"),
+    (102, "getuid"),
+    (104, "getgid"),
+    (105, "setuid"),
+    (106, "setgid"),
+    (107, "geteuid"),
+    (108, "getegid"),
+    (110, "getppid"),
+    (157, "prctl"),
+    (158, "arch_prctl"),
+    (165, "mount"),
+    (186, "gettid"),
+    (202, "futex"),
+    (217, "getdents64"),
+    (218, "set_tid_address"),
+    (228, "clock_gettime"),
+    (231, "exit_group"),
+    (232, "epoll_wait"),
+    (233, "epoll_ctl"),
+    (257, "openat"),
+    (262, "newfstatat"),
+    (273, "set_robust_list"),
+    (288, "accept4"),
+    (291, "epoll_create1"),
+    (292, "dup3"),
+    (293, "pipe2"),
+    (302, "prlimit64"),
+    (317, "seccomp"),
+    (318, "getrandom"),
+    (319, "memfd_create"),
+    (322, "execveat"),
+    (334, "rseq"),
+    (435, "clone3"),
+];
+
+const I386: &[(u64, &str)] = &[
+    (1, "exit"),
+    (2, "fork"),
+    (3, "read"),
+    (4, "write"),
+    (5, "open"),
+    (6, "close"),
+    (7, "waitpid"),
+    (11, "execve"),
+    (12, "chdir"),
+    (20, "getpid"),
+    (26, "ptrace"),
+    (37, "kill"),
+    (39, "mkdir"),
+    (40, "rmdir"),
+    (41, "dup"),
+    (42, "pipe"),
+    (45, "brk"),
+    (54, "ioctl"),
+    (63, "dup2"),
+    (64, "getppid"),
+    (90, "mmap"),
+    (91, "munmap"),
+    (102, "socketcall"),
+    (114, "wait4"),
+    (120, "clone"),
+    (122, "uname"),
+    (125, "mprotect"),
+    (162, "nanosleep"),
+    (172, "prctl"),
+    (174, "rt_sigaction"),
+    (175, "rt_sigprocmask"),
+    (190, "vfork"),
+    (192, "mmap2"),
+    (195, "stat64"),
+    (197, "fstat64"),
+    (224, "gettid"),
+    (240, "futex"),
+    (243, "set_thread_area"),
+    (252, "exit_group"),
+    (258, "set_tid_address"),
+    (265, "clock_gettime"),
+    (295, "openat"),
+    (355, "getrandom"),
+    (359, "socket"),
+    (361, "bind"),
+    (362, "connect"),
+    (363, "listen"),
+    (364, "accept4"),
+];
+
+/// The numbering A64 shares with the other architectures added since
+const GENERIC: &[(u64, &str)] = &[
+    (17, "getcwd"),
+    (23, "dup"),
+    (24, "dup3"),
+    (25, "fcntl"),
+    (29, "ioctl"),
+    (34, "mkdirat"),
+    (35, "unlinkat"),
+    (38, "renameat"),
+    (48, "faccessat"),
+    (49, "chdir"),
+    (56, "openat"),
+    (57, "close"),
+    (59, "pipe2"),
+    (61, "getdents64"),
+    (62, "lseek"),
+    (63, "read"),
+    (64, "write"),
+    (65, "readv"),
+    (66, "writev"),
+    (67, "pread64"),
+    (68, "pwrite64"),
+    (78, "readlinkat"),
+    (79, "newfstatat"),
+    (80, "fstat"),
+    (93, "exit"),
+    (94, "exit_group"),
+    (96, "set_tid_address"),
+    (98, "futex"),
+    (101, "nanosleep"),
+    (113, "clock_gettime"),
+    (117, "ptrace"),
+    (124, "sched_yield"),
+    (129, "kill"),
+    (134, "rt_sigaction"),
+    (135, "rt_sigprocmask"),
+    (139, "rt_sigreturn"),
+    (160, "uname"),
+    (167, "prctl"),
+    (172, "getpid"),
+    (173, "getppid"),
+    (174, "getuid"),
+    (175, "geteuid"),
+    (176, "getgid"),
+    (177, "getegid"),
+    (178, "gettid"),
+    (198, "socket"),
+    (200, "bind"),
+    (201, "listen"),
+    (202, "accept"),
+    (203, "connect"),
+    (206, "sendto"),
+    (207, "recvfrom"),
+    (214, "brk"),
+    (215, "munmap"),
+    (220, "clone"),
+    (221, "execve"),
+    (222, "mmap"),
+    (226, "mprotect"),
+    (233, "madvise"),
+    (242, "accept4"),
+    (260, "wait4"),
+    (261, "prlimit64"),
+    (277, "seccomp"),
+    (278, "getrandom"),
+    (279, "memfd_create"),
+    (281, "execveat"),
+    (435, "clone3"),
+];
+
+const ARM: &[(u64, &str)] = &[
+    (1, "exit"),
+    (2, "fork"),
+    (3, "read"),
+    (4, "write"),
+    (5, "open"),
+    (6, "close"),
+    (11, "execve"),
+    (20, "getpid"),
+    (26, "ptrace"),
+    (37, "kill"),
+    (45, "brk"),
+    (54, "ioctl"),
+    (91, "munmap"),
+    (120, "clone"),
+    (125, "mprotect"),
+    (172, "prctl"),
+    (192, "mmap2"),
+    (240, "futex"),
+    (248, "exit_group"),
+    (281, "socket"),
+    (282, "bind"),
+    (283, "connect"),
+    (284, "listen"),
+    (322, "openat"),
+    (384, "getrandom"),
+];
+
+fn table(arch: Arch) -> &'static [(u64, &'static str)] {
+    match arch {
+        Arch::Amd64 => AMD64,
+        Arch::I386 => I386,
+        Arch::A64 => GENERIC,
+        Arch::ArmV7 | Arch::Thumb | Arch::Thumb16 => ARM,
+        Arch::Msp430 | Arch::H8 => &[],
+    }
+}
+
+/// The name of system call `number` on `arch`, if the table has it
+pub fn syscall_name(arch: Arch, number: u64) -> Option<&'static str> {
+    table(arch)
+        .iter()
+        .find(|(n, _)| *n == number)
+        .map(|(_, name)| *name)
+}
+
+/// The number of the system call `name` on `arch`, if the table has it
+pub fn syscall_number(arch: Arch, name: &str) -> Option<u64> {
+    table(arch)
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(n, _)| *n)
+}
+
+/// The offsets of the syscall instructions in `bytes`, at the instruction alignment of `arch`.
+/// On x86 a match may be the middle of another instruction; see [`inventory`].
+pub fn find_syscall_insns(arch: Arch, bytes: &[u8]) -> Vec<usize> {
+    let patterns: &[(&[u8], usize)] = match arch {
+        Arch::Amd64 => &[(&[0x0f, 0x05], 1)],
+        Arch::I386 => &[(&[0xcd, 0x80], 1), (&[0x0f, 0x34], 1)],
+        Arch::A64 => &[(&[0x01, 0x00, 0x00, 0xd4], 4)],
+        Arch::ArmV7 => &[(&[0x00, 0x00, 0x00, 0xef], 4)],
+        Arch::Thumb | Arch::Thumb16 => &[(&[0x00, 0xdf], 2)],
+        Arch::Msp430 | Arch::H8 => &[],
+    };
+    let mut offsets = Vec::new();
+    for (pattern, align) in patterns.iter() {
+        offsets.extend(
+            bytes
+                .windows(pattern.len())
+                .enumerate()
+                .filter(|(offset, window)| offset % align == 0 && window == pattern)
+                .map(|(offset, _)| offset),
+        );
+    }
+    offsets.sort_unstable();
+    offsets
+}
+
+/// Where the system call number is, just before a syscall instruction or a call to `syscall()`
+fn number_location(arch: Arch, wrapper: bool) -> Option<Expr> {
+    let regs = RegisterModel::new(arch);
+    let name = match (arch, wrapper) {
+        (Arch::Amd64, false) => "rax",
+        (Arch::Amd64, true) => "rdi",
+        (Arch::I386, false) => "eax",
+        (Arch::I386, true) => return Some(Expr::Load(Box::new(Expr::Reg(regs.sp())))),
+        (Arch::A64, false) => "x8",
+        (Arch::A64, true) => "x0",
+        (Arch::ArmV7 | Arch::Thumb | Arch::Thumb16, false) => "r7",
+        (Arch::ArmV7 | Arch::Thumb | Arch::Thumb16, true) => "r0",
+        (Arch::Msp430 | Arch::H8, _) => return None,
+    };
+    regs.by_name(name).map(Expr::Reg)
+}
+
+/// What `location` holds just before the instruction at `va` runs. The block `va` is in starts
+/// from its predecessor's state, where it has just the one.
+pub(crate) fn expr_before(func: &Function, va: u64, location: &Expr) -> Option<Expr> {
+    let (start, block) = func
+        .blocks
+        .range(..=va)
+        .next_back()
+        .filter(|(_, block)| va <= block.end_va)?;
+    let preds = func.predecessors();
+    let mut state = match preds.get(start).map(Vec::as_slice) {
+        Some([pred]) if pred != start => func
+            .block_states()
+            .remove(pred)
+            .unwrap_or_else(|| State::new(func.arch)),
+        _ => State::new(func.arch),
+    };
+    for insn in block.insns.iter().take_while(|insn| insn.va < va) {
+        state.exec_insn(insn);
+    }
+    Some(state.eval(location))
+}
+
+/// The constant value `location` holds just before the instruction at `va` runs, if the code of
+/// the function makes it one
+fn value_before(func: &Function, va: u64, location: &Expr) -> Option<u64> {
+    let value = expr_before(func, va, location)?;
+    // a 32 bit move to the number register leaves it zero extended, `w8 = n` as `x8 & mask = n`
+    let value = match value {
+        Expr::Binary(BinOp::And, ref x, ref mask) if mask.as_const() == Some(0xffff_ffff) => {
+            (**x).clone()
+        }
+        value => value,
+    };
+    value.as_const()
+}
+
+/// How a system call is made
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Via {
+    /// A syscall instruction
+    Instruction,
+    /// The libc `syscall()` function
+    Syscall,
+    /// The libc wrapper of that system call, by name
+    Wrapper(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyscallSite {
+    pub va: u64,
+    /// None where the number couldn't be worked out
+    pub number: Option<u64>,
+    pub via: Via,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Inventory {
+    pub arch: Option<Arch>,
+    pub sites: Vec<SyscallSite>,
+}
+
+impl Inventory {
+    /// The system calls made by number, with where they are made from
+    pub fn by_number(&self) -> BTreeMap<u64, Vec<u64>> {
+        let mut calls: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for site in self.sites.iter() {
+            if let Some(number) = site.number {
+                calls.entry(number).or_default().push(site.va);
+            }
+        }
+        calls
+    }
+
+    /// The names of the system calls made, sorted, those not in the table as `syscall_<n>`.
+    /// Where [`Inventory::unresolved`] isn't empty this is a lower bound.
+    pub fn names(&self) -> Vec<String> {
+        let arch = self.arch;
+        let names: BTreeSet<String> = self
+            .by_number()
+            .into_keys()
+            .map(|number| {
+                arch.and_then(|arch| syscall_name(arch, number))
+                    .map_or_else(|| format!("syscall_{}", number), str::to_string)
+            })
+            .collect();
+        names.into_iter().collect()
+    }
+
+    /// The sites making a system call whose number is unknown
+    pub fn unresolved(&self) -> Vec<u64> {
+        self.sites
+            .iter()
+            .filter(|site| site.number.is_none())
+            .map(|site| site.va)
+            .collect()
+    }
+}
+
+/// The function of `functions` containing the instruction at `va`
+pub(crate) fn function_at(functions: &BTreeMap<u64, Function>, va: u64) -> Option<&Function> {
+    functions.values().find(|func| {
+        func.blocks
+            .range(..=va)
+            .next_back()
+            .is_some_and(|(_, block)| block.insns.iter().any(|insn| insn.va == va))
+    })
+}
+
+/// Inventory the system calls of a workspace for `arch`. Syscall instructions count where a
+/// lifted function or a code location of the workspace starts an instruction at them; calls go
+/// by the workspace's xrefs to the imports.
+pub fn inventory(
+    workspace: &VivWorkspace,
+    arch: Arch,
+    functions: &BTreeMap<u64, Function>,
+) -> Inventory {
+    let mut sites = Vec::new();
+    let number_reg = number_location(arch, false);
+    for (map_va, bytes) in workspace.get_executable_maps() {
+        for offset in find_syscall_insns(arch, bytes) {
+            let va = (map_va as u32 as u64) + offset as u64;
+            let func = function_at(functions, va);
+            if func.is_none() && !workspace.is_location(va as i32) {
+                continue;
+            }
+            let number = func
+                .zip(number_reg.as_ref())
+                .and_then(|(func, location)| value_before(func, va, location));
+            sites.push(SyscallSite {
+                va,
+                number,
+                via: Via::Instruction,
+            });
+        }
+    }
+    let argument = number_location(arch, true);
+    for va in workspace.get_callers_of_import("syscall") {
+        let va = va as u32 as u64;
+        let number = function_at(functions, va)
+            .zip(argument.as_ref())
+            .and_then(|(func, location)| value_before(func, va, location));
+        sites.push(SyscallSite {
+            va,
+            number,
+            via: Via::Syscall,
+        });
+    }
+    let imported: BTreeSet<String> = workspace
+        .get_imports()
+        .into_iter()
+        .map(|(_, name)| match name.split_once('.') {
+            Some((_, name)) => name.to_string(),
+            None => name,
+        })
+        .collect();
+    for name in imported {
+        let Some(number) = syscall_number(arch, &name) else {
+            continue;
+        };
+        for va in workspace.get_callers_of_import(&name) {
+            sites.push(SyscallSite {
+                va: va as u32 as u64,
+                number: Some(number),
+                via: Via::Wrapper(name.clone()),
+            });
+        }
+    }
+    sites.sort_by(|a, b| (a.va, &a.via).cmp(&(b.va, &b.via)));
+    Inventory {
+        arch: Some(arch),
+        sites,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_EXEC, MM_READ, REF_CODE},
+        memory::Memory,
+        symbolic::{Block, Insn, Stmt, Terminator},
+    };
+
+    #[test]
+    fn syscall_inventory() {
+        assert_eq!(syscall_name(Arch::Amd64, 59), Some("execve"));
+        assert_eq!(syscall_number(Arch::A64, "execve"), Some(221));
+        assert_eq!(
+            find_syscall_insns(Arch::A64, &[0, 0, 0, 0, 1, 0, 0, 0xd4, 0, 1, 0, 0, 0xd4]),
+            [4]
+        );
+
+        // mov eax, 60; syscall at 0x1005, and a syscall nobody lifted at 0x1010
+        let mut code = vec![0xb8, 0x3c, 0, 0, 0, 0x0f, 0x05];
+        code.resize(0x10, 0x90);
+        code.extend([0x0f, 0x05]);
+        code.resize(0x20, 0x90);
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code, None);
+        // call syscall with edi = 39 at 0x1020, call write at 0x1030
+        ws.make_import(0x3000, "libc", "syscall");
+        ws.make_import(0x3008, "libc", "write");
+        ws.add_xref(0x1020, 0x3000, REF_CODE, 0);
+        ws.add_xref(0x1030, 0x3008, REF_CODE, 0);
+
+        let regs = RegisterModel::new(Arch::Amd64);
+        let (rax, rdi) = (regs.by_name("rax").unwrap(), regs.by_name("rdi").unwrap());
+        let insn = |va, stmts| Insn { va, stmts };
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Set(rax, Expr::Const(60))]),
+                insn(0x1005, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x1005,
+            end: Terminator::Jump(0x1019),
+        });
+        func.add_block(Block {
+            va: 0x1019,
+            insns: vec![
+                insn(0x1019, vec![Stmt::Set(rdi, Expr::Const(39))]),
+                insn(0x1020, vec![Stmt::Unknown]),
+            ],
+            end_va: 0x1020,
+            end: Terminator::Return,
+        });
+        let functions = BTreeMap::from([(0x1000, func)]);
+
+        let inventory = inventory(&ws, Arch::Amd64, &functions);
+        assert_eq!(
+            inventory.sites,
+            [
+                SyscallSite {
+                    va: 0x1005,
+                    number: Some(60),
+                    via: Via::Instruction
+                },
+                SyscallSite {
+                    va: 0x1020,
+                    number: Some(39),
+                    via: Via::Syscall
+                },
+                SyscallSite {
+                    va: 0x1030,
+                    number: Some(1),
+                    via: Via::Wrapper("write".to_string())
+                },
+            ]
+        );
+        assert_eq!(inventory.names(), ["exit", "getpid", "write"]);
+        assert!(inventory.unresolved().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+//! Stable hashes of the import set, export set and section table of a binary.
+//!
+//! A system library whose imports, exports or sections differ from the ones of a known good
+//! copy was modified. [`Tables`] normalizes the three tables of a PE, ELF or Mach-O binary into
+//! lines of text, and [`TableHashes`] holds the SHA-256 of each:
+//!
+//! * imports are `library!name`, the library lowercased (PE binds import names case
+//!   insensitively) and empty when the format doesn't say (ELF without symbol versions);
+//! * exports are `name`, followed by ` #ordinal` for PE, `@version` for ELF, ` weak` and
+//!   ` -> library` for a forwarder or re-export;
+//! * sections are `name address size flags`, PE addresses being relative to the image base.
+//!
+//! Imports and exports are sorted and deduplicated, so the hashes don't change with the order a
+//! linker laid the tables out in or with lazy and non lazy bindings of the same symbol. Sections
+//! keep the order of the section table, which is part of what a modified file changes.
+
+use crate::{
+    abidiff::{AbiSet, AbiSymbol},
+    error, mach,
+    utils::{hex, sha256},
+    Object,
+};
+use std::fmt;
+
+/// The normalized import set, export set and section table of a binary
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tables {
+    pub imports: Vec<String>,
+    pub exports: Vec<String>,
+    pub sections: Vec<String>,
+}
+
+/// The SHA-256 of each of the tables, as hex
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TableHashes {
+    pub imports: String,
+    pub exports: String,
+    pub sections: String,
+}
+
+impl fmt::Display for TableHashes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "imports  {}", self.imports)?;
+        writeln!(f, "exports  {}", self.exports)?;
+        write!(f, "sections {}", self.sections)
+    }
+}
+
+impl Tables {
+    /// Collect the tables of `object`, which was parsed from `bytes`
+    pub fn from_object(object: &Object, bytes: &[u8]) -> error::Result<Self> {
+        let set = AbiSet::from_object(object, bytes)?;
+        let sections = match object {
+            Object::PE(pe) => pe_sections(pe)?,
+            Object::Elf(elf) => elf_sections(elf),
+            Object::Mach(mach::Mach::Binary(macho)) => macho_sections(macho)?,
+            _ => {
+                return Err(error::Error::Malformed(
+                    "Only PE, ELF and Mach-O binaries have section tables".to_string(),
+                ))
+            }
+        };
+        Ok(Tables {
+            imports: normalize(set.imports.iter().map(import_line)),
+            exports: normalize(set.exports.iter().map(export_line)),
+            sections,
+        })
+    }
+
+    /// Parse `bytes` and collect its tables
+    pub fn parse(bytes: &[u8]) -> error::Result<Self> {
+        Tables::from_object(&Object::parse(bytes)?, bytes)
+    }
+
+    pub fn hashes(&self) -> TableHashes {
+        TableHashes {
+            imports: hash_lines(&self.imports),
+            exports: hash_lines(&self.exports),
+            sections: hash_lines(&self.sections),
+        }
+    }
+}
+
+/// Parse `bytes` and hash its tables
+pub fn hash_bytes(bytes: &[u8]) -> error::Result<TableHashes> {
+    Ok(Tables::parse(bytes)?.hashes())
+}
+
+/// The SHA-256 of `lines` joined by newlines
+pub fn hash_lines(lines: &[String]) -> String {
+    hex(&sha256(lines.join("\n").as_bytes()))
+}
+
+fn normalize(lines: impl Iterator<Item = String>) -> Vec<String> {
+    let mut lines = lines.collect::<Vec<_>>();
+    lines.sort();
+    lines.dedup();
+    lines
+}
+
+fn import_line(sym: &AbiSymbol) -> String {
+    let library = sym.library.as_deref().unwrap_or_default().to_lowercase();
+    let mut line = format!("{}!{}", library, sym.name);
+    if let Some(ref version) = sym.version {
+        line.push('@');
+        line.push_str(version);
+    }
+    line
+}
+
+fn export_line(sym: &AbiSymbol) -> String {
+    let mut line = sym.name.clone();
+    if let Some(ordinal) = sym.ordinal {
+        line.push_str(&format!(" #{}", ordinal));
+    }
+    if let Some(ref version) = sym.version {
+        line.push('@');
+        line.push_str(version);
+    }
+    if sym.weak {
+        line.push_str(" weak");
+    }
+    if let (true, Some(library)) = (sym.reexport, &sym.library) {
+        line.push_str(&format!(" -> {}", library.to_lowercase()));
+    }
+    line
+}
+
+fn pe_sections(pe: &crate::pe::PE) -> error::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for section in pe.sections.iter() {
+        lines.push(format!(
+            "{} {:#x} {:#x} {:#x}",
+            section.name()?,
+            section.virtual_address,
+            section.virtual_size,
+            section.characteristics
+        ));
+    }
+    Ok(lines)
+}
+
+fn elf_sections(elf: &crate::elf::Elf) -> Vec<String> {
+    elf.section_headers
+        .iter()
+        .map(|sh| {
+            let name = elf.shdr_strtab.get_at(sh.sh_name).unwrap_or_default();
+            format!(
+                "{} {:#x} {:#x} {:#x}",
+                name, sh.sh_addr, sh.sh_size, sh.sh_flags
+            )
+        })
+        .collect()
+}
+
+fn macho_sections(macho: &mach::MachO) -> error::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for segment in macho.segments.iter() {
+        for (section, _) in segment.sections()? {
+            lines.push(format!(
+                "{},{} {:#x} {:#x} {:#x}",
+                section.segname()?,
+                section.name()?,
+                section.addr,
+                section.size,
+                section.flags
+            ));
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::import::{ImportTable, ImportedFunction};
+
+    #[test]
+    fn hashes_tables() {
+        let mut imports = ImportTable::default();
+        imports.add_function("KERNEL32.dll", ImportedFunction::by_name("ExitProcess"));
+        imports.add_function("user32.dll", ImportedFunction::by_name("MessageBoxA"));
+        let (pe, _) = imports.tiny_image();
+        let tables = Tables::parse(&pe).unwrap();
+        assert_eq!(
+            tables.imports,
+            ["kernel32.dll!ExitProcess", "user32.dll!MessageBoxA"]
+        );
+        assert!(tables.exports.is_empty());
+        assert_eq!(tables.sections.len(), 1);
+
+        // the order the imports were laid out in doesn't matter, the names do
+        let mut reordered = ImportTable::default();
+        reordered.add_function("user32.dll", ImportedFunction::by_name("MessageBoxA"));
+        reordered.add_function("kernel32.dll", ImportedFunction::by_name("ExitProcess"));
+        let hashes = hash_bytes(&reordered.tiny_image().0).unwrap();
+        assert_eq!(hashes.imports, tables.hashes().imports);
+        let mut other = ImportTable::default();
+        other.add_function("kernel32.dll", ImportedFunction::by_name("ExitThread"));
+        other.add_function("user32.dll", ImportedFunction::by_name("MessageBoxA"));
+        assert_ne!(hash_bytes(&other.tiny_image().0).unwrap(), hashes);
+        assert_eq!(hash_lines(&[]), hex(&sha256(b"")));
+        assert!(Tables::from_object(&Object::Unknown(0), &[]).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Tags on functions and other addresses, such as "network", "crypto/aes" or "anti-debug", and
+//! their propagation along call edges.
+//!
+//! Tags are paths: "crypto" holds "crypto/aes", so asking for "crypto" finds both. Analysts
+//! tag with `VivWorkspace::add_tag`; [`tag_imports`] tags import slots by the API they name,
+//! with [`IMPORT_TAGS`] or rules of one's own.
+//!
+//! A capability spreads along calls the way taint does: a function calling one which talks to
+//! the network can talk to the network itself. [`propagate`] spreads the tags a [`Rule`] picks
+//! out over the call graph, to callers or to callees and as far as the rule says, and keeps
+//! for each function reached the call it was reached through, so [`Reach::path`] can explain
+//! it. [`entry_points_reaching`] answers the usual question in one call.
+
+use crate::{constants::REF_CODE, query::glob_match, workspace::VivWorkspace};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// Tags for the import slots of well known APIs: a glob of the bare import name and the tag
+pub const IMPORT_TAGS: &[(&str, &str)] = &[
+    ("socket", "network"),
+    ("connect", "network"),
+    ("bind", "network"),
+    ("listen", "network"),
+    ("accept", "network"),
+    ("send", "network"),
+    ("sendto", "network"),
+    ("recv", "network"),
+    ("recvfrom", "network"),
+    ("WSAStartup", "network"),
+    ("WSASocket*", "network"),
+    ("getaddrinfo", "network/dns"),
+    ("gethostbyname", "network/dns"),
+    ("DnsQuery*", "network/dns"),
+    ("Internet*", "network/http"),
+    ("HttpOpenRequest*", "network/http"),
+    ("HttpSendRequest*", "network/http"),
+    ("WinHttp*", "network/http"),
+    ("URLDownloadTo*", "network/http"),
+    ("curl_easy_*", "network/http"),
+    ("Crypt*", "crypto"),
+    ("BCrypt*", "crypto"),
+    ("EVP_*", "crypto"),
+    ("AES_*", "crypto/aes"),
+    ("RC4*", "crypto/rc4"),
+    ("IsDebuggerPresent", "anti-debug"),
+    ("CheckRemoteDebuggerPresent", "anti-debug"),
+    ("NtQueryInformationProcess", "anti-debug"),
+    ("OutputDebugString*", "anti-debug"),
+    ("ptrace", "anti-debug"),
+    ("CreateProcess*", "process"),
+    ("ShellExecute*", "process"),
+    ("WinExec", "process"),
+    ("system", "process"),
+    ("execve", "process"),
+    ("execl*", "process"),
+    ("execv*", "process"),
+    ("posix_spawn*", "process"),
+    ("VirtualAllocEx", "injection"),
+    ("WriteProcessMemory", "injection"),
+    ("CreateRemoteThread*", "injection"),
+    ("QueueUserAPC", "injection"),
+    ("RegCreateKey*", "registry"),
+    ("RegSetValue*", "registry"),
+    ("RegDeleteKey*", "registry"),
+    ("SetWindowsHookEx*", "hooking"),
+    ("GetAsyncKeyState", "keylogging"),
+];
+
+/// Whether `tag` is `parent` or a tag below it
+pub fn is_under(tag: &str, parent: &str) -> bool {
+    tag.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Tag each import slot whose bare name, without the library and any symbol version, matches
+/// a glob of `rules`. Returns how many tags were added.
+pub fn tag_imports(workspace: &mut VivWorkspace, rules: &[(&str, &str)]) -> usize {
+    let mut added = 0;
+    for (va, name) in workspace.get_imports() {
+        let bare = name.split_once('.').map_or(name.as_str(), |(_, bare)| bare);
+        let bare = bare.split_once('@').map_or(bare, |(bare, _)| bare);
+        for (glob, tag) in rules {
+            if glob_match(glob, bare) && !workspace.get_tags(va).iter().any(|t| t == tag) {
+                workspace.add_tag(va, tag);
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From a function to the functions calling it, for capabilities
+    Callers,
+    /// From a function to the functions it calls, for context such as "reached from a
+    /// signal handler"
+    Callees,
+}
+
+/// Which tags spread, which way and how far
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// The tag, and the tags below it, which spread
+    pub tag: String,
+    pub direction: Direction,
+    /// How many calls away at most; unlimited if none
+    pub depth: Option<usize>,
+}
+
+impl Rule {
+    /// A capability: `tag` spreads to every function which can end up calling a tagged one
+    pub fn capability(tag: &str) -> Self {
+        Rule {
+            tag: tag.to_string(),
+            direction: Direction::Callers,
+            depth: None,
+        }
+    }
+}
+
+/// The calls between functions and into import slots, from the code references of a workspace
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    pub callees: HashMap<i32, HashSet<i32>>,
+    pub callers: HashMap<i32, HashSet<i32>>,
+}
+
+impl CallGraph {
+    /// The call graph of `workspace`; calls through import stubs go to the import slot
+    pub fn new(workspace: &VivWorkspace) -> Self {
+        let stubs: HashMap<i32, i32> = workspace.get_import_stubs().into_iter().collect();
+        let mut graph = CallGraph::default();
+        for (from, to, ..) in workspace.get_xrefs(Some(REF_CODE)) {
+            let Some(caller) = workspace.get_function(from) else {
+                continue;
+            };
+            let callee = stubs.get(&to).copied().unwrap_or(to);
+            if callee == caller || workspace.get_function(callee) == Some(caller) {
+                // a branch within the function
+                continue;
+            }
+            graph.callees.entry(caller).or_default().insert(callee);
+            graph.callers.entry(callee).or_default().insert(caller);
+        }
+        graph
+    }
+}
+
+/// How a tag reached an address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Via {
+    /// How many calls away the tagged address is, 0 for the tagged address itself
+    pub distance: usize,
+    /// The next address toward the tagged one
+    pub next: i32,
+}
+
+/// The tags spread by [`propagate`], by address
+#[derive(Clone, Debug, Default)]
+pub struct Reach {
+    pub tags: HashMap<i32, BTreeMap<String, Via>>,
+}
+
+impl Reach {
+    /// The addresses `tag` or a tag below it reached, sorted
+    pub fn reaching(&self, tag: &str) -> Vec<i32> {
+        let mut vas: Vec<i32> = self
+            .tags
+            .iter()
+            .filter(|(_, tags)| tags.keys().any(|t| is_under(t, tag)))
+            .map(|(va, _)| *va)
+            .collect();
+        vas.sort_unstable();
+        vas
+    }
+
+    /// The addresses from `va` to one tagged with exactly `tag`, following the nearest
+    pub fn path(&self, va: i32, tag: &str) -> Option<Vec<i32>> {
+        let mut path = vec![va];
+        let mut via = *self.tags.get(&va)?.get(tag)?;
+        while via.distance > 0 {
+            path.push(via.next);
+            via = *self.tags.get(&via.next)?.get(tag)?;
+        }
+        Some(path)
+    }
+}
+
+/// Spread the tags of `workspace` over `graph` by `rules`
+pub fn propagate(workspace: &VivWorkspace, graph: &CallGraph, rules: &[Rule]) -> Reach {
+    let mut reach = Reach::default();
+    for rule in rules {
+        let edges = match rule.direction {
+            Direction::Callers => &graph.callers,
+            Direction::Callees => &graph.callees,
+        };
+        let mut sources: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+        for va in workspace.get_tagged(&rule.tag) {
+            for tag in workspace.get_tags(va) {
+                if is_under(&tag, &rule.tag) {
+                    sources.entry(tag).or_default().push(va);
+                }
+            }
+        }
+        // breadth first from every address with the tag, so each gets its nearest
+        for (tag, vas) in sources {
+            let mut queue: VecDeque<i32> = VecDeque::new();
+            for va in vas {
+                let via = Via {
+                    distance: 0,
+                    next: va,
+                };
+                reach.tags.entry(va).or_default().insert(tag.clone(), via);
+                queue.push_back(va);
+            }
+            while let Some(va) = queue.pop_front() {
+                let distance = reach.tags[&va][&tag].distance + 1;
+                if rule.depth.is_some_and(|depth| distance > depth) {
+                    continue;
+                }
+                let mut next: Vec<i32> = edges
+                    .get(&va)
+                    .map(|next| next.iter().copied().collect())
+                    .unwrap_or_default();
+                next.sort_unstable();
+                for other in next {
+                    let tags = reach.tags.entry(other).or_default();
+                    if tags.get(&tag).is_some_and(|via| via.distance <= distance) {
+                        continue;
+                    }
+                    tags.insert(tag.clone(), Via { distance, next: va });
+                    queue.push_back(other);
+                }
+            }
+        }
+    }
+    reach
+}
+
+/// The entry points of `workspace` which can end up calling something tagged `tag`
+pub fn entry_points_reaching(workspace: &VivWorkspace, tag: &str) -> Vec<i32> {
+    let graph = CallGraph::new(workspace);
+    let reach = propagate(workspace, &graph, &[Rule::capability(tag)]);
+    let reached: HashSet<i32> = reach.reaching(tag).into_iter().collect();
+    workspace
+        .get_entry_points()
+        .into_iter()
+        .filter(|va| reached.contains(va))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_reach_entry_points() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.make_import(0x9000, "ws2_32", "connect");
+        ws.make_import(0x9004, "kernel32", "IsDebuggerPresent");
+        for fva in [0x1000, 0x1100, 0x1200, 0x1300] {
+            ws.add_function(fva, vec![(fva, 0x100)]);
+        }
+        ws.add_entry_point(0x1000);
+        ws.add_entry_point(0x1300);
+        // main -> helper -> connect; the other entry only checks for a debugger
+        ws.add_xref(0x1010, 0x1100, REF_CODE, 0);
+        ws.add_xref(0x1110, 0x1200, REF_CODE, 0);
+        ws.add_xref(0x1210, 0x9000, REF_CODE, 0);
+        ws.add_xref(0x1310, 0x9004, REF_CODE, 0);
+        ws.add_tag(0x1200, "crypto/aes");
+
+        assert_eq!(tag_imports(&mut ws, IMPORT_TAGS), 2);
+        assert_eq!(ws.get_tags(0x9000), ["network"]);
+        assert_eq!(ws.get_tagged("crypto"), [0x1200]);
+        assert_eq!(entry_points_reaching(&ws, "network"), [0x1000]);
+        assert_eq!(entry_points_reaching(&ws, "anti-debug"), [0x1300]);
+
+        let graph = CallGraph::new(&ws);
+        let rules = [
+            Rule::capability("network"),
+            Rule {
+                depth: Some(1),
+                ..Rule::capability("crypto")
+            },
+        ];
+        let reach = propagate(&ws, &graph, &rules);
+        assert_eq!(
+            reach.path(0x1000, "network"),
+            Some(vec![0x1000, 0x1100, 0x1200, 0x9000])
+        );
+        assert_eq!(reach.reaching("crypto"), [0x1100, 0x1200]);
+        assert!(!is_under("networking", "network"));
+
+        ws.del_tag(0x1200, "crypto/aes");
+        assert!(ws.get_tagged("crypto").is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+//! Telling tail calls apart from jumps within a function.
+//!
+//! An unconditional jump is a tail call when it leaves for another function: its target is a
+//! known function entry, it lands in a different symbol than the function it comes from, or
+//! it lands on a function prologue with the stack back where the function found it. A jump
+//! made with anything left on the stack is never a tail call.
+//!
+//! [`split_tail_calls`] turns the tail calls of a lifted [`Function`] into
+//! [`Terminator::TailCall`] and drops the blocks only reachable through them, so neighbouring
+//! functions are no longer glued together; [`add_xrefs`] records the call graph edges in a
+//! workspace as procedural branches, which the CFG builder in [`crate::analysis`] doesn't
+//! follow.
+//!
+//! Only unconditional jumps are considered; a conditional tail call stays a branch.
+
+use crate::{
+    constants::{BR_PROC, REF_CODE},
+    envi::Arch,
+    resolve::SymbolIndex,
+    symbolic::{Function, Terminator},
+    workspace::VivWorkspace,
+};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpKind {
+    Local,
+    TailCall,
+}
+
+/// What is known about where functions start and end
+#[derive(Debug, Clone)]
+pub struct Boundaries<'a> {
+    arch: Arch,
+    entries: BTreeSet<u64>,
+    symbols: Option<&'a SymbolIndex>,
+    code: Vec<(u64, &'a [u8])>,
+}
+
+impl<'a> Boundaries<'a> {
+    pub fn new(arch: Arch) -> Self {
+        Boundaries {
+            arch,
+            entries: BTreeSet::new(),
+            symbols: None,
+            code: Vec::new(),
+        }
+    }
+
+    /// Known function entries, from exports, symbols or earlier analysis
+    pub fn add_functions(&mut self, entries: impl IntoIterator<Item = u64>) {
+        self.entries.extend(entries);
+    }
+
+    pub fn set_symbols(&mut self, symbols: &'a SymbolIndex) {
+        self.symbols = Some(symbols);
+    }
+
+    /// Code mapped at `va`, for looking for prologues
+    pub fn add_code(&mut self, va: u64, bytes: &'a [u8]) {
+        self.code.push((va, bytes));
+    }
+
+    fn bytes_at(&self, va: u64) -> Option<&'a [u8]> {
+        self.code.iter().find_map(|(start, bytes)| {
+            let offset = usize::try_from(va.checked_sub(*start)?).ok()?;
+            bytes.get(offset..)
+        })
+    }
+
+    /// Classify a jump from the function starting at `entry` to `target`, made with the stack
+    /// pointer `stack` bytes off where the function found it
+    pub fn classify(&self, entry: u64, target: u64, stack: Option<i64>) -> JumpKind {
+        if target == entry || stack.is_some_and(|offset| offset != 0) {
+            return JumpKind::Local;
+        }
+        if self.entries.contains(&target) {
+            return JumpKind::TailCall;
+        }
+        if let Some(symbols) = self.symbols {
+            // Landing on a symbol, or past the start of a symbol after this function
+            if let (Some(from), Some(to)) = (symbols.lookup(entry), symbols.lookup(target)) {
+                if from.va != to.va && (to.offset == 0 || to.va > entry) {
+                    return JumpKind::TailCall;
+                }
+            }
+        }
+        let prologue = self
+            .bytes_at(target)
+            .is_some_and(|bytes| has_prologue(self.arch, bytes));
+        if prologue && stack == Some(0) {
+            return JumpKind::TailCall;
+        }
+        JumpKind::Local
+    }
+}
+
+/// Whether `bytes` start with a common function prologue
+pub fn has_prologue(arch: Arch, bytes: &[u8]) -> bool {
+    let word = |at: usize| {
+        let b = bytes.get(at..at + 4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let half = |at: usize| {
+        let b = bytes.get(at..at + 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    };
+    match arch {
+        Arch::I386 | Arch::Amd64 => {
+            const PROLOGUES: &[&[u8]] = &[
+                // endbr64, endbr32
+                &[0xf3, 0x0f, 0x1e, 0xfa],
+                &[0xf3, 0x0f, 0x1e, 0xfb],
+                // push ebp; mov ebp, esp
+                &[0x55, 0x8b, 0xec],
+                &[0x55, 0x89, 0xe5],
+                // mov edi, edi (hotpatch)
+                &[0x8b, 0xff],
+                // push rbp; mov rbp, rsp
+                &[0x55, 0x48, 0x89, 0xe5],
+                &[0x55, 0x48, 0x8b, 0xec],
+                // sub rsp, imm
+                &[0x48, 0x83, 0xec],
+                &[0x48, 0x81, 0xec],
+                // mov [rsp+x], rbx (MSVC home space spills)
+                &[0x48, 0x89, 0x5c, 0x24],
+                &[0x48, 0x89, 0x4c, 0x24],
+            ];
+            PROLOGUES.iter().any(|prologue| bytes.starts_with(prologue))
+        }
+        // push {..., lr}
+        Arch::ArmV7 => word(0).is_some_and(|w| w & 0xffff_4000 == 0xe92d_4000),
+        Arch::Thumb | Arch::Thumb16 => match half(0) {
+            Some(h) if h & 0xff00 == 0xb500 => true,
+            Some(0xe92d) => half(2).is_some_and(|h| h & 0x4000 != 0),
+            _ => false,
+        },
+        Arch::A64 => word(0).is_some_and(|w| {
+            // stp x29, x30, [sp, #-n]!; paciasp; bti c; sub sp, sp, #n
+            w & 0xffc0_7fff == 0xa980_7bfd
+                || w == 0xd503_233f
+                || w == 0xd503_245f
+                || w & 0xff00_03ff == 0xd100_03ff
+        }),
+        Arch::Msp430 | Arch::H8 => false,
+    }
+}
+
+/// Turn the unconditional jumps of `func` which are tail calls into [`Terminator::TailCall`],
+/// and drop the blocks no longer reachable. Returns the call graph edges, as the address of
+/// the jump and its target.
+pub fn split_tail_calls(func: &mut Function, boundaries: &Boundaries) -> Vec<(u64, u64)> {
+    let offsets = func.stack_offsets();
+    let mut edges = Vec::new();
+    for (va, block) in func.blocks.iter_mut() {
+        let Terminator::Jump(target) = block.end else {
+            continue;
+        };
+        let Some(stack) = offsets.get(va) else {
+            continue;
+        };
+        if boundaries.classify(func.entry, target, *stack) == JumpKind::TailCall {
+            block.end = Terminator::TailCall(target);
+            edges.push((block.end_va, target));
+        }
+    }
+    let live: BTreeSet<u64> = func.reverse_postorder().into_iter().collect();
+    func.blocks.retain(|va, _| live.contains(va));
+    edges
+}
+
+/// Record tail calls as procedural code references
+pub fn add_xrefs(workspace: &mut VivWorkspace, edges: &[(u64, u64)]) {
+    for (from, to) in edges {
+        workspace.add_xref(*from as i32, *to as i32, REF_CODE, BR_PROC);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envi::registers::RegisterModel;
+    use crate::symbolic::{BinOp, Block, Expr, Insn, Stmt};
+
+    #[test]
+    fn tail_calls() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let rsp = model.by_name("rsp").unwrap();
+        let adjust = |va, delta| Insn {
+            va,
+            stmts: vec![Stmt::Set(
+                rsp,
+                Expr::Binary(
+                    BinOp::Add,
+                    Box::new(Expr::Reg(rsp)),
+                    Box::new(Expr::Const(delta)),
+                ),
+            )],
+        };
+        // sub rsp, 8; jmp 0x10; add rsp, 8; jmp 0x40 (another function's prologue)
+        let mut func = Function::new(Arch::Amd64, 0);
+        func.add_block(Block {
+            va: 0,
+            insns: vec![adjust(0, 0xffff_ffff_ffff_fff8)],
+            end_va: 4,
+            end: Terminator::Jump(0x10),
+        });
+        func.add_block(Block {
+            va: 0x10,
+            insns: vec![adjust(0x10, 8)],
+            end_va: 0x14,
+            end: Terminator::Jump(0x40),
+        });
+        func.add_block(Block {
+            va: 0x40,
+            insns: vec![],
+            end_va: 0x40,
+            end: Terminator::Return,
+        });
+        let code = [0u8; 0x40]
+            .into_iter()
+            .chain([0x55, 0x48, 0x89, 0xe5, 0xc3])
+            .collect::<Vec<u8>>();
+        let mut boundaries = Boundaries::new(Arch::Amd64);
+        boundaries.add_code(0, &code);
+        assert_eq!(boundaries.classify(0, 0x40, Some(8)), JumpKind::Local);
+        assert_eq!(boundaries.classify(0, 0x10, Some(0)), JumpKind::Local);
+
+        let edges = split_tail_calls(&mut func, &boundaries);
+        assert_eq!(edges, [(0x14, 0x40)]);
+        assert_eq!(func.blocks[&0x10].end, Terminator::TailCall(0x40));
+        assert!(!func.blocks.contains_key(&0x40));
+
+        // a known entry or a different symbol is enough
+        let mut boundaries = Boundaries::new(Arch::Amd64);
+        boundaries.add_functions([0x80]);
+        assert_eq!(boundaries.classify(0, 0x80, None), JumpKind::TailCall);
+        let mut symbols = SymbolIndex::new();
+        symbols.add_range(0, 0x1000, "app", 0);
+        symbols.add_function(0, 0x20, "f");
+        symbols.add_function(0x20, 0x20, "g");
+        symbols.finish();
+        boundaries.set_symbols(&symbols);
+        assert_eq!(boundaries.classify(0, 0x24, None), JumpKind::TailCall);
+        assert_eq!(boundaries.classify(0, 0x8, None), JumpKind::Local);
+    }
+
+    #[test]
+    fn prologues() {
+        assert!(has_prologue(Arch::A64, &0xa9bf_7bfdu32.to_le_bytes()));
+        assert!(has_prologue(Arch::ArmV7, &0xe92d_4ff0u32.to_le_bytes()));
+        assert!(has_prologue(Arch::Thumb, &[0x80, 0xb5]));
+        assert!(!has_prologue(Arch::Amd64, &[0x31, 0xc0]));
+    }
+}
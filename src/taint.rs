@@ -0,0 +1,301 @@
+//! Which values in a lifted function the program's input controls.
+//!
+//! A value is tainted when it comes from input: the result of a call reading or converting it,
+//! `recv`, `read`, `getenv` or `atoi` ([`INPUT_APIS`]), at the call sites given as sources.
+//! [`Taint::new`] follows tainted values forward through a function: a register set from a
+//! tainted value is tainted, as is a load from a tainted address or from where a tainted value
+//! was stored. At a join a register or a stack slot is tainted if it is on any path in.
+//!
+//! The lifter knows nothing of what a call does (it lifts as [`Stmt::Unknown`]), so across a
+//! call other than a source only the registers the calling convention preserves, and what they
+//! point at, stay tainted. The taint is an under-approximation: what it says is tainted is,
+//! within the function.
+
+use crate::{
+    envi::{
+        registers::{RegId, RegisterModel},
+        Arch,
+    },
+    symbolic::{return_value, Expr, Function, Insn, State, Stmt},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// APIs whose result comes from input, by the bare name they are imported by
+pub const INPUT_APIS: &[&str] = &[
+    "recv",
+    "recvfrom",
+    "read",
+    "fread",
+    "fgetc",
+    "getc",
+    "getchar",
+    "getenv",
+    "atoi",
+    "atol",
+    "atoll",
+    "strtol",
+    "strtoul",
+    "strtoll",
+    "strtoull",
+    "ntohl",
+    "ntohs",
+    "_wtoi",
+    "InternetReadFile",
+];
+
+/// The calls to [`INPUT_APIS`] in a workspace, as sources for [`Taint::new`]
+pub fn input_calls(workspace: &VivWorkspace) -> BTreeSet<u64> {
+    INPUT_APIS
+        .iter()
+        .flat_map(|name| workspace.get_callers_of_import(name))
+        .map(|va| va as u32 as u64)
+        .collect()
+}
+
+/// The registers a call leaves as they were, the stack pointer among them
+pub(crate) fn callee_saved(model: &RegisterModel) -> BTreeSet<RegId> {
+    let names: &[&str] = match model.arch() {
+        Arch::I386 => &["ebx", "esi", "edi", "ebp"],
+        Arch::Amd64 => &["rbx", "rbp", "r12", "r13", "r14", "r15"],
+        Arch::A64 => &[
+            "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29",
+        ],
+        Arch::ArmV7 | Arch::Thumb | Arch::Thumb16 => {
+            &["r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11"]
+        }
+        Arch::Msp430 | Arch::H8 => &[],
+    };
+    let mut regs: BTreeSet<RegId> = names
+        .iter()
+        .filter_map(|name| model.by_name(name))
+        .map(|reg| model.full(reg))
+        .collect();
+    regs.insert(model.full(model.sp()));
+    regs
+}
+
+/// What is tainted at a point of a function
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Tainted {
+    /// Full registers
+    regs: BTreeSet<RegId>,
+    /// The addresses tainted values were stored to, in terms of the registers at that point
+    memory: BTreeSet<Expr>,
+}
+
+/// The tainted registers and memory at the start of each block of a function
+#[derive(Clone, Debug)]
+pub struct Taint {
+    model: RegisterModel,
+    /// The full register a call leaves its result in
+    result: Option<RegId>,
+    callee_saved: BTreeSet<RegId>,
+    sources: BTreeSet<u64>,
+    entries: BTreeMap<u64, Tainted>,
+}
+
+/// Taint through the instructions of a block
+struct Tracker<'a> {
+    taint: &'a Taint,
+    state: State,
+    tainted: Tainted,
+}
+
+impl Tracker<'_> {
+    fn is_tainted(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Const(_) => false,
+            Expr::Reg(reg) => self.tainted.regs.contains(&self.taint.model.full(*reg)),
+            Expr::Load(addr) => {
+                self.is_tainted(addr) || self.tainted.memory.contains(&self.state.eval(addr))
+            }
+            Expr::Unary(_, a) => self.is_tainted(a),
+            Expr::Binary(_, a, b) => self.is_tainted(a) || self.is_tainted(b),
+        }
+    }
+
+    /// The tainted addresses which mean the same once the state starts over: those in terms of
+    /// registers the block hasn't changed, and across a call only of registers it keeps
+    fn carried(&self, call: bool) -> BTreeSet<Expr> {
+        let model = &self.taint.model;
+        self.tainted
+            .memory
+            .iter()
+            .filter(|addr| {
+                let mut regs = BTreeSet::new();
+                addr.registers(&mut regs);
+                regs.iter().all(|reg| {
+                    self.state.reg(*reg) == Expr::Reg(*reg)
+                        && (!call || self.taint.callee_saved.contains(&model.full(*reg)))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn exec_insn(&mut self, insn: &Insn) {
+        for stmt in insn.stmts.iter() {
+            match stmt {
+                Stmt::Set(reg, value) => {
+                    let full = self.taint.model.full(*reg);
+                    if self.is_tainted(value) {
+                        self.tainted.regs.insert(full);
+                    } else if !self.taint.model.is_partial_write(*reg) {
+                        self.tainted.regs.remove(&full);
+                    }
+                }
+                Stmt::Store(addr, value) => {
+                    let addr = self.state.eval(addr);
+                    if self.is_tainted(value) {
+                        self.tainted.memory.insert(addr);
+                    } else {
+                        self.tainted.memory.remove(&addr);
+                    }
+                }
+                Stmt::Flags(..) => {}
+                Stmt::Unknown => {
+                    self.tainted.memory = self.carried(true);
+                    let saved = &self.taint.callee_saved;
+                    self.tainted.regs.retain(|reg| saved.contains(reg));
+                    if self.taint.sources.contains(&insn.va) {
+                        self.tainted.regs.extend(self.taint.result);
+                    }
+                }
+            }
+            self.state.exec(stmt);
+        }
+    }
+
+    /// What is tainted as the next block starts
+    fn finish(self) -> Tainted {
+        Tainted {
+            memory: self.carried(false),
+            regs: self.tainted.regs,
+        }
+    }
+}
+
+impl Taint {
+    /// Follow the results of the calls at `sources` through `func`
+    pub fn new(func: &Function, sources: &BTreeSet<u64>) -> Self {
+        let model = RegisterModel::new(func.arch);
+        let result = match return_value(func.arch) {
+            Some(Expr::Reg(reg)) => Some(model.full(reg)),
+            _ => None,
+        };
+        let mut taint = Taint {
+            callee_saved: callee_saved(&model),
+            model,
+            result,
+            sources: sources.clone(),
+            entries: BTreeMap::new(),
+        };
+        let order = func.reverse_postorder();
+        for va in order.iter() {
+            taint.entries.insert(*va, Tainted::default());
+        }
+        // taint only grows, so this settles
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for va in order.iter() {
+                let mut tracker = taint.tracker(*va);
+                for insn in func.blocks[va].insns.iter() {
+                    tracker.exec_insn(insn);
+                }
+                let out = tracker.finish();
+                for succ in func.successors(*va) {
+                    if let Some(entry) = taint.entries.get_mut(&succ) {
+                        let before = (entry.regs.len(), entry.memory.len());
+                        entry.regs.extend(out.regs.iter().copied());
+                        entry.memory.extend(out.memory.iter().cloned());
+                        changed |= (entry.regs.len(), entry.memory.len()) != before;
+                    }
+                }
+            }
+        }
+        taint
+    }
+
+    fn tracker(&self, block: u64) -> Tracker<'_> {
+        Tracker {
+            taint: self,
+            state: State::new(self.model.arch()),
+            tainted: self.entries.get(&block).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Whether `expr` is tainted right before the instruction at `va` runs
+    pub fn is_tainted_before(&self, func: &Function, va: u64, expr: &Expr) -> bool {
+        let Some((start, block)) = func.blocks.range(..=va).next_back() else {
+            return false;
+        };
+        let mut tracker = self.tracker(*start);
+        for insn in block.insns.iter().take_while(|insn| insn.va < va) {
+            tracker.exec_insn(insn);
+        }
+        tracker.is_tainted(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envi::flags::Condition,
+        symbolic::{BinOp, Block, Terminator},
+    };
+
+    #[test]
+    fn taint_flows() {
+        let model = RegisterModel::new(Arch::Amd64);
+        let reg = |name| model.by_name(name).unwrap();
+        let (rax, rbx, rcx, rsp) = (reg("rax"), reg("rbx"), reg("rcx"), model.sp());
+        let insn = |va, stmts| Insn { va, stmts };
+        let slot = Expr::binary(BinOp::Add, Expr::Reg(rsp), Expr::Const(8), 64);
+        let mut func = Function::new(Arch::Amd64, 0x1000);
+        // n = atoi(s); [rsp+8] = n; then on one side rbx = [rsp+8] * 4
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![
+                insn(0x1000, vec![Stmt::Unknown]),
+                insn(0x1005, vec![Stmt::Store(slot.clone(), Expr::Reg(rax))]),
+                insn(0x100a, vec![Stmt::Set(rcx, Expr::Const(1))]),
+            ],
+            end_va: 0x100f,
+            end: Terminator::Branch {
+                cond: Condition::Zero,
+                taken: 0x1020,
+                fallthrough: 0x1010,
+            },
+        });
+        func.add_block(Block {
+            va: 0x1010,
+            insns: vec![insn(
+                0x1010,
+                vec![Stmt::Set(
+                    rbx,
+                    Expr::binary(BinOp::Mul, Expr::Load(Box::new(slot)), Expr::Const(4), 64),
+                )],
+            )],
+            end_va: 0x1015,
+            end: Terminator::Jump(0x1020),
+        });
+        func.add_block(Block {
+            va: 0x1020,
+            insns: vec![insn(0x1020, vec![Stmt::Unknown])],
+            end_va: 0x1020,
+            end: Terminator::Return,
+        });
+
+        let taint = Taint::new(&func, &BTreeSet::from([0x1000]));
+        assert!(taint.is_tainted_before(&func, 0x100a, &Expr::Reg(reg("eax"))));
+        assert!(!taint.is_tainted_before(&func, 0x100f, &Expr::Reg(rcx)));
+        assert!(taint.is_tainted_before(&func, 0x1020, &Expr::Reg(rbx)));
+        assert!(!taint.is_tainted_before(&func, 0x1020, &Expr::Reg(rcx)));
+
+        let untainted = Taint::new(&func, &BTreeSet::new());
+        assert!(!untainted.is_tainted_before(&func, 0x1020, &Expr::Reg(rbx)));
+    }
+}
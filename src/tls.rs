@@ -0,0 +1,725 @@
+//! Thread local storage: the template of a binary's TLS block and the accesses to it.
+//!
+//! Each thread gets a copy of the TLS template, the initialization image of a PE's TLS
+//! directory, an ELF `PT_TLS` segment or the Mach-O `__thread_data` and `__thread_bss`
+//! sections, and finds it through the thread pointer. [`template`] reads the template of a
+//! file, with the variables in it where the file names them (ELF `STT_TLS` symbols, Mach-O
+//! thread variable descriptors).
+//!
+//! The lifted code reaches the block in a few ways, which [`accesses`] resolves to an offset
+//! into the template:
+//!
+//! * off the thread pointer, `fs_base` or `gs_base` on x86 and `tpidr_el0` on aarch64
+//!   (segment relative addresses lift as the base plus the offset, `fs:[-8]` as
+//!   `fs_base - 8`). ELF puts the block below the thread pointer on x86 and above the thread
+//!   control block on aarch64, and an initial exec access reads its offset from the GOT.
+//! * through the TLS array of the Windows TEB (`gs:[0x58]`, `fs:[0x2c]`), at the module's
+//!   `_tls_index`,
+//! * by calling `__tls_get_addr` with a `tls_index`, for the general dynamic model,
+//! * by loading the thunk of a Mach-O thread variable descriptor.
+//!
+//! For emulation, [`map_thread`] lays a thread's block out in an [`crate::ilemu::Ram`] the
+//! way the loader would and points the thread pointer at it.
+
+use crate::{
+    elf::{program_header::PT_TLS, sym::STT_TLS, Elf},
+    envi::{
+        registers::{RegisterContext, RegisterModel},
+        Arch,
+    },
+    error::{Error, Result},
+    ilemu::Ram,
+    mach::{Mach, MachO},
+    memory::Memory,
+    pe::{options::ParseOptions, utils::find_offset, PE},
+    symbolic::{BinOp, Expr, Function, State, Stmt},
+    syscalls::{expr_before, function_at},
+    workspace::VivWorkspace,
+    Object,
+};
+use std::collections::BTreeMap;
+
+/// Where the TLS array pointer is in the TEB: amd64, i386
+const TEB_TLS_ARRAY: [u64; 2] = [0x58, 0x2c];
+/// What a thread control block takes above the aarch64 thread pointer, before the block
+const TCB_SIZE: u64 = 16;
+const TLS_GET_ADDR: [&str; 2] = ["__tls_get_addr", "___tls_get_addr"];
+
+/// Where the block sits relative to the thread pointer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// The block ends at the thread pointer (ELF on x86)
+    Below,
+    /// The block follows the thread control block the thread pointer points at (ELF on
+    /// aarch64)
+    Above,
+    /// The thread pointer is the TEB, whose TLS array points at the block (PE)
+    Indexed,
+    /// The block is found through descriptors (Mach-O)
+    Descriptors,
+}
+
+/// A variable of the template
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    /// The offset in the block
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    pub layout: Layout,
+    /// Where the initialization image is
+    pub va: u64,
+    /// The size of the image; the rest of the block is zero filled
+    pub init_size: u64,
+    /// The size of the block
+    pub size: u64,
+    pub align: u64,
+    pub variables: Vec<Variable>,
+    /// Where the module's TLS index is, for a PE
+    pub index: Option<u64>,
+    /// The thread variable descriptors of a Mach-O file, by address, with the offset each
+    /// describes
+    pub descriptors: BTreeMap<u64, u64>,
+}
+
+impl Template {
+    /// The variable at `offset` in the block
+    pub fn variable_at(&self, offset: u64) -> Option<&Variable> {
+        self.variables
+            .iter()
+            .find(|var| var.offset <= offset && offset - var.offset < var.size.max(1))
+    }
+
+    /// Where the initial value of the block at `offset` is, if the image has it
+    pub fn init_va(&self, offset: u64) -> Option<u64> {
+        (offset < self.init_size).then(|| self.va + offset)
+    }
+
+    /// The offset in the block of `tp_offset` from the thread pointer, for a block laid out
+    /// around the thread pointer
+    pub fn block_offset(&self, tp_offset: i64) -> Option<u64> {
+        let offset = match self.layout {
+            Layout::Below => tp_offset.checked_add(align_up(self.size, self.align) as i64)?,
+            Layout::Above => tp_offset.checked_sub(align_up(TCB_SIZE, self.align) as i64)?,
+            Layout::Indexed | Layout::Descriptors => return None,
+        };
+        u64::try_from(offset)
+            .ok()
+            .filter(|offset| *offset < self.size)
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    match align {
+        0 | 1 => value,
+        _ => value.div_ceil(align) * align,
+    }
+}
+
+/// The TLS template of the PE, ELF or Mach-O file `bytes`, if it has one
+pub fn template(bytes: &[u8]) -> Result<Option<Template>> {
+    match Object::parse(bytes)? {
+        Object::Elf(elf) => Ok(elf_template(&elf)),
+        Object::PE(pe) => Ok(pe_template(&pe, bytes)),
+        Object::Mach(Mach::Binary(macho)) => Ok(macho_template(&macho)),
+        Object::Mach(Mach::Fat(fat)) => {
+            let arches = fat.arches()?;
+            let arch = arches
+                .first()
+                .ok_or_else(|| Error::Malformed("Fat binary without architectures".into()))?;
+            template(arch.slice(bytes))
+        }
+        _ => Err(Error::Malformed("Not a PE, ELF or Mach-O file".into())),
+    }
+}
+
+fn elf_template(elf: &Elf) -> Option<Template> {
+    let phdr = elf
+        .program_headers
+        .iter()
+        .find(|phdr| phdr.p_type == PT_TLS)?;
+    let layout = match elf.header.e_machine {
+        crate::elf::header::EM_AARCH64 | crate::elf::header::EM_ARM => Layout::Above,
+        _ => Layout::Below,
+    };
+    let mut variables: Vec<Variable> = Vec::new();
+    let tables = [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+    for (syms, strtab) in tables {
+        for sym in syms.iter().filter(|sym| sym.st_type() == STT_TLS) {
+            let Some(name) = strtab.get_at(sym.st_name).filter(|name| !name.is_empty()) else {
+                continue;
+            };
+            if variables.iter().any(|var| var.name == name) {
+                continue;
+            }
+            variables.push(Variable {
+                name: name.to_string(),
+                offset: sym.st_value,
+                size: sym.st_size,
+            });
+        }
+    }
+    variables.sort_by_key(|var| var.offset);
+    Some(Template {
+        layout,
+        va: phdr.p_vaddr,
+        init_size: phdr.p_filesz,
+        size: phdr.p_memsz,
+        align: phdr.p_align,
+        variables,
+        index: None,
+        descriptors: BTreeMap::new(),
+    })
+}
+
+fn pe_template(pe: &PE, bytes: &[u8]) -> Option<Template> {
+    let opt = pe.header.optional_header?;
+    let dir = (*opt.data_directories.get_tls_table())?;
+    let offset = find_offset(
+        dir.virtual_address as usize,
+        &pe.sections,
+        opt.windows_fields.file_alignment,
+        &ParseOptions::default(),
+    )?;
+    let word = if pe.is_64 { 8 } else { 4 };
+    let field = |i: usize| -> Option<u64> {
+        let at = offset + i * word;
+        let raw = bytes.get(at..at + word)?;
+        Some(match word {
+            8 => u64::from_le_bytes(raw.try_into().ok()?),
+            _ => u32::from_le_bytes(raw.try_into().ok()?) as u64,
+        })
+    };
+    let (start, end, index) = (field(0)?, field(1)?, field(2)?);
+    // SizeOfZeroFill and Characteristics are 32 bits either way
+    let tail = offset + 4 * word;
+    let u32_at = |at: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+    };
+    let zero_fill = u32_at(tail)? as u64;
+    let align = match (u32_at(tail + 4)? >> 20) & 0xf {
+        0 => 1,
+        n => 1 << (n - 1),
+    };
+    let init_size = end.checked_sub(start)?;
+    Some(Template {
+        layout: Layout::Indexed,
+        va: start,
+        init_size,
+        size: init_size + zero_fill,
+        align,
+        variables: Vec::new(),
+        index: Some(index),
+        descriptors: BTreeMap::new(),
+    })
+}
+
+fn macho_template(macho: &MachO) -> Option<Template> {
+    let mut data = None;
+    let mut bss = None;
+    let mut vars = None;
+    for segment in macho.segments.iter() {
+        for (section, bytes) in segment.sections().ok()? {
+            match section.name().ok()? {
+                "__thread_data" => data = Some((section.addr, section.size, section.align)),
+                "__thread_bss" => bss = Some((section.addr, section.size, section.align)),
+                "__thread_vars" => vars = Some((section.addr, bytes)),
+                _ => {}
+            }
+        }
+    }
+    let (va, init_size, align) = data.or(bss)?;
+    let size = match (data, bss) {
+        (Some(_), Some((bss_va, bss_size, _))) => (bss_va + bss_size).saturating_sub(va),
+        _ => init_size,
+    };
+    let init_size = if data.is_some() { init_size } else { 0 };
+    let mut descriptors = BTreeMap::new();
+    let mut variables = Vec::new();
+    if let Some((vars_va, bytes)) = vars {
+        let word = if macho.is_64 { 8 } else { 4 };
+        let names: BTreeMap<u64, String> = macho
+            .symbols()
+            .flatten()
+            .map(|(name, nlist)| (nlist.n_value, name.trim_start_matches('_').to_string()))
+            .collect();
+        // thunk, key and offset
+        for (i, desc) in bytes.chunks_exact(word * 3).enumerate() {
+            let raw = &desc[word * 2..];
+            let offset = match (word, macho.little_endian) {
+                (8, true) => u64::from_le_bytes(raw.try_into().ok()?),
+                (8, false) => u64::from_be_bytes(raw.try_into().ok()?),
+                (_, true) => u32::from_le_bytes(raw.try_into().ok()?) as u64,
+                (_, false) => u32::from_be_bytes(raw.try_into().ok()?) as u64,
+            };
+            let desc_va = vars_va + (i * word * 3) as u64;
+            descriptors.insert(desc_va, offset);
+            if let Some(name) = names.get(&desc_va) {
+                variables.push(Variable {
+                    name: name.clone(),
+                    offset,
+                    size: 0,
+                });
+            }
+        }
+    }
+    variables.sort_by_key(|var| var.offset);
+    Some(Template {
+        layout: Layout::Descriptors,
+        va,
+        init_size,
+        size,
+        align: 1 << align,
+        variables,
+        index: None,
+        descriptors,
+    })
+}
+
+/// How the code reached the block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Via {
+    ThreadPointer,
+    TlsArray,
+    TlsGetAddr,
+    Descriptor,
+}
+
+/// An instruction reaching into the block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    /// The instruction, or the call to `__tls_get_addr`
+    pub va: u64,
+    /// The offset in the block
+    pub offset: u64,
+    pub via: Via,
+}
+
+impl Access {
+    pub fn variable<'a>(&self, template: &'a Template) -> Option<&'a Variable> {
+        template.variable_at(self.offset)
+    }
+
+    /// How to name the access: the variable and how far into it, else the offset
+    pub fn describe(&self, template: &Template) -> String {
+        match self.variable(template) {
+            Some(var) if var.offset == self.offset => format!("tls:{}", var.name),
+            Some(var) => format!("tls:{}+{:#x}", var.name, self.offset - var.offset),
+            None => format!("tls+{:#x}", self.offset),
+        }
+    }
+}
+
+/// The thread pointers of `arch`
+fn thread_pointers(arch: Arch) -> Vec<Expr> {
+    let regs = RegisterModel::new(arch);
+    let names: &[&str] = match arch {
+        Arch::I386 | Arch::Amd64 => &["fs_base", "gs_base"],
+        Arch::A64 => &["tpidr_el0"],
+        _ => &[],
+    };
+    names
+        .iter()
+        .filter_map(|name| regs.by_name(name))
+        .map(Expr::Reg)
+        .collect()
+}
+
+/// `expr` as a base and a constant added to it
+fn split(expr: &Expr) -> (&Expr, u64) {
+    match expr {
+        Expr::Binary(BinOp::Add, base, c) => match c.as_const() {
+            Some(c) => (base, c),
+            None => (expr, 0),
+        },
+        _ => (expr, 0),
+    }
+}
+
+fn contains(expr: &Expr, needle: &Expr) -> bool {
+    expr == needle
+        || match expr {
+            Expr::Const(_) | Expr::Reg(_) => false,
+            Expr::Load(a) | Expr::Unary(_, a) => contains(a, needle),
+            Expr::Binary(_, a, b) => contains(a, needle) || contains(b, needle),
+        }
+}
+
+fn sign_extend(value: u64, width: u32) -> i64 {
+    let shift = 64 - width.min(64);
+    ((value << shift) as i64) >> shift
+}
+
+/// The offset in the block of the address `addr`, as evaluated at the instruction
+fn resolve(
+    workspace: &VivWorkspace,
+    template: &Template,
+    tps: &[Expr],
+    width: u32,
+    addr: &Expr,
+) -> Option<(u64, Via)> {
+    let (base, c) = split(addr);
+    match template.layout {
+        Layout::Below | Layout::Above => {
+            let mut tp_offset = sign_extend(c, width);
+            // fs:[0] holds the thread pointer itself
+            let at_tp = |e: &Expr| {
+                tps.iter()
+                    .any(|tp| e == tp || *e == Expr::Load(Box::new(tp.clone())))
+            };
+            if !at_tp(base) {
+                // initial exec: the offset comes from the GOT, `fs:[rax]` for `rax = [got]`
+                let Expr::Binary(BinOp::Add, a, b) = base else {
+                    return None;
+                };
+                let slot = match (&**a, &**b) {
+                    (tp, Expr::Load(slot)) | (Expr::Load(slot), tp) if at_tp(tp) => slot,
+                    _ => return None,
+                };
+                let slot = slot.as_const()?;
+                let raw = workspace.read_memory(slot as i32, (width / 8) as i32)?;
+                let mut word = [0; 8];
+                word[..raw.len()].copy_from_slice(&raw);
+                tp_offset = tp_offset.wrapping_add(sign_extend(u64::from_le_bytes(word), width));
+            }
+            template
+                .block_offset(tp_offset)
+                .map(|offset| (offset, Via::ThreadPointer))
+        }
+        Layout::Indexed => {
+            // [[teb + 0x58] + index * 8] + c
+            let Expr::Load(slot) = base else {
+                return None;
+            };
+            let array = tps.iter().any(|tp| {
+                TEB_TLS_ARRAY.iter().any(|at| {
+                    let field = Expr::binary(BinOp::Add, tp.clone(), Expr::Const(*at), width);
+                    contains(slot, &Expr::Load(Box::new(field)))
+                })
+            });
+            (array && c < template.size).then_some((c, Via::TlsArray))
+        }
+        Layout::Descriptors => {
+            let desc = match base {
+                Expr::Load(desc) => desc.as_const()?,
+                _ => return None,
+            };
+            (c == 0)
+                .then(|| template.descriptors.get(&desc))
+                .flatten()
+                .map(|offset| (*offset, Via::Descriptor))
+        }
+    }
+}
+
+/// The accesses of the lifted `functions` to the TLS block of `template`. Calls to
+/// `__tls_get_addr` go by the workspace's xrefs to the import, and count where the
+/// `tls_index` they pass has its offset in the workspace.
+pub fn accesses(
+    workspace: &VivWorkspace,
+    functions: &BTreeMap<u64, Function>,
+    template: &Template,
+) -> Vec<Access> {
+    let mut found = Vec::new();
+    for func in functions.values() {
+        let tps = thread_pointers(func.arch);
+        if tps.is_empty() && template.layout != Layout::Descriptors {
+            continue;
+        }
+        let width = func.arch.pointer_size() as u32 * 8;
+        for block in func.blocks.values() {
+            let mut state = State::new(func.arch);
+            for insn in block.insns.iter() {
+                let mut addrs = Vec::new();
+                for stmt in insn.stmts.iter() {
+                    match stmt {
+                        Stmt::Set(_, value) => value.loads(&mut addrs),
+                        Stmt::Store(addr, value) => {
+                            addrs.push(addr.clone());
+                            addr.loads(&mut addrs);
+                            value.loads(&mut addrs);
+                        }
+                        Stmt::Flags(_, a, b) => {
+                            a.loads(&mut addrs);
+                            b.loads(&mut addrs);
+                        }
+                        Stmt::Unknown => {}
+                    }
+                }
+                let resolved = addrs
+                    .iter()
+                    .find_map(|addr| resolve(workspace, template, &tps, width, &state.eval(addr)));
+                if let Some((offset, via)) = resolved {
+                    found.push(Access {
+                        va: insn.va,
+                        offset,
+                        via,
+                    });
+                }
+                state.exec_insn(insn);
+            }
+        }
+    }
+
+    for name in TLS_GET_ADDR {
+        for call in workspace.get_callers_of_import(name) {
+            let call = call as u32 as u64;
+            let Some(func) = function_at(functions, call) else {
+                continue;
+            };
+            let regs = RegisterModel::new(func.arch);
+            let arg = match (func.arch, name) {
+                // ___tls_get_addr takes its argument in eax
+                (Arch::I386, "___tls_get_addr") => regs.by_name("eax").map(Expr::Reg),
+                (arch, _) => crate::symbolic::call_argument(arch, false, 0),
+            };
+            let index = arg
+                .and_then(|arg| expr_before(func, call, &arg))
+                .and_then(|index| index.as_const());
+            let size = func.arch.pointer_size();
+            let Some(raw) = index
+                .and_then(|index| workspace.read_memory((index + size as u64) as i32, size as i32))
+            else {
+                continue;
+            };
+            let mut word = [0; 8];
+            word[..raw.len()].copy_from_slice(&raw);
+            found.push(Access {
+                va: call,
+                offset: u64::from_le_bytes(word),
+                via: Via::TlsGetAddr,
+            });
+        }
+    }
+    found.sort_by_key(|access| access.va);
+    found
+}
+
+/// Comment the accesses with the variables they reach, leaving existing comments alone
+pub fn annotate(workspace: &mut VivWorkspace, template: &Template, accesses: &[Access]) {
+    for access in accesses {
+        workspace.set_comment(access.va as i32, &access.describe(template), true);
+    }
+}
+
+/// The initial contents of a thread's block: the image, as the workspace has it, then
+/// zeroes
+pub fn thread_block(workspace: &VivWorkspace, template: &Template) -> Vec<u8> {
+    let mut block = workspace
+        .read_memory(template.va as i32, template.init_size as i32)
+        .unwrap_or_default();
+    block.resize(template.size as usize, 0);
+    block
+}
+
+/// Lay out a thread with the TLS block `block` (see [`thread_block`]) at `at` in `memory`, as
+/// the loader would for the template, and point the thread pointer of `registers` at it.
+/// Returns the thread pointer, or None for a layout the interpreter can't follow (Mach-O,
+/// whose descriptors call into the loader).
+pub fn map_thread(
+    memory: &mut Ram,
+    registers: &mut RegisterContext,
+    template: &Template,
+    block: Vec<u8>,
+    at: u64,
+) -> Option<u64> {
+    let arch = registers.model().arch();
+    let size = arch.pointer_size();
+    let (tp, name) = match (template.layout, arch) {
+        (Layout::Below, Arch::I386 | Arch::Amd64) => {
+            let tp = at + align_up(template.size, template.align);
+            let mut area = block;
+            area.resize((tp - at) as usize, 0);
+            // the thread control block starts with a pointer to itself
+            area.extend_from_slice(&tp.to_le_bytes()[..size]);
+            area.resize(area.len() + 0x100, 0);
+            memory.map(at, area);
+            (
+                tp,
+                if arch == Arch::Amd64 {
+                    "fs_base"
+                } else {
+                    "gs_base"
+                },
+            )
+        }
+        (Layout::Above, Arch::A64) => {
+            let mut area = vec![0; align_up(TCB_SIZE, template.align) as usize];
+            area.extend(block);
+            memory.map(at, area);
+            (at, "tpidr_el0")
+        }
+        (Layout::Indexed, Arch::I386 | Arch::Amd64) => {
+            // the TEB, with the TLS array in its second half, then the block for index 0
+            let (field, name) = match arch {
+                Arch::Amd64 => (TEB_TLS_ARRAY[0], "gs_base"),
+                _ => (TEB_TLS_ARRAY[1], "fs_base"),
+            };
+            let mut teb = vec![0; 0x1000];
+            let array = at + 0x800;
+            teb[field as usize..field as usize + size]
+                .copy_from_slice(&array.to_le_bytes()[..size]);
+            teb[0x800..0x800 + size].copy_from_slice(&(at + 0x1000).to_le_bytes()[..size]);
+            teb.extend(block);
+            memory.map(at, teb);
+            if let Some(index) = template.index {
+                memory.write(index, 4, 0);
+            }
+            (at, name)
+        }
+        _ => return None,
+    };
+    let reg = registers.model().by_name(name)?;
+    registers.set(reg, tp.into());
+    Some(tp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MM_READ, MM_WRITE, REF_CODE},
+        symbolic::{Block, Insn, Terminator},
+    };
+
+    fn function(insns: Vec<(u64, Stmt)>) -> BTreeMap<u64, Function> {
+        let entry = insns[0].0;
+        let mut func = Function::new(Arch::Amd64, entry);
+        func.add_block(Block {
+            va: entry,
+            end_va: insns.last().unwrap().0,
+            insns: insns
+                .into_iter()
+                .map(|(va, stmt)| Insn {
+                    va,
+                    stmts: vec![stmt],
+                })
+                .collect(),
+            end: Terminator::Return,
+        });
+        BTreeMap::from([(entry, func)])
+    }
+
+    #[test]
+    fn resolves_accesses() {
+        let regs = RegisterModel::new(Arch::Amd64);
+        let reg = |name| regs.by_name(name).unwrap();
+        let add = |a, b| Expr::binary(BinOp::Add, a, b, 64);
+        let load = |a| Expr::Load(Box::new(a));
+        let fs = Expr::Reg(reg("fs_base"));
+
+        let mut ws = VivWorkspace::new("", false);
+        let image = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        ws.add_memory_map(0x6000, MM_READ | MM_WRITE, "test", image, None);
+        // a GOT slot holding a TP offset, and a tls_index { module, offset }
+        let mut got = (-8i64).to_le_bytes().to_vec();
+        got.resize(0x100, 0);
+        got.extend_from_slice(&1u64.to_le_bytes());
+        got.extend_from_slice(&8u64.to_le_bytes());
+        ws.add_memory_map(0x7000, MM_READ | MM_WRITE, "test", got, None);
+        ws.make_import(0x3000, "libc", "__tls_get_addr");
+        ws.add_xref(0x1030, 0x3000, REF_CODE, 0);
+
+        let template = Template {
+            layout: Layout::Below,
+            va: 0x6000,
+            init_size: 8,
+            size: 0x10,
+            align: 8,
+            variables: vec![
+                Variable {
+                    name: "counter".to_string(),
+                    offset: 0,
+                    size: 4,
+                },
+                Variable {
+                    name: "buf".to_string(),
+                    offset: 8,
+                    size: 8,
+                },
+            ],
+            index: None,
+            descriptors: BTreeMap::new(),
+        };
+        let functions = function(vec![
+            // mov eax, fs:[-0x10]
+            (
+                0x1000,
+                Stmt::Set(reg("eax"), load(add(fs.clone(), Expr::Const(!0xf)))),
+            ),
+            // mov rax, fs:[0]; mov qword [rax-4], 1
+            (0x1008, Stmt::Set(reg("rax"), load(fs.clone()))),
+            (
+                0x1010,
+                Stmt::Store(add(Expr::Reg(reg("rax")), Expr::Const(!3)), Expr::Const(1)),
+            ),
+            // mov rcx, [got]; mov edx, fs:[rcx]
+            (0x1018, Stmt::Set(reg("rcx"), load(Expr::Const(0x7000)))),
+            (
+                0x1020,
+                Stmt::Set(reg("edx"), load(add(fs.clone(), Expr::Reg(reg("rcx"))))),
+            ),
+            // lea rdi, [tls_index]; call __tls_get_addr
+            (0x1028, Stmt::Set(reg("rdi"), Expr::Const(0x7100))),
+            (0x1030, Stmt::Unknown),
+        ]);
+        let found = accesses(&ws, &functions, &template);
+        let described: Vec<(u64, String)> = found
+            .iter()
+            .map(|access| (access.va, access.describe(&template)))
+            .collect();
+        assert_eq!(
+            described,
+            [
+                (0x1000, "tls:counter".to_string()),
+                (0x1010, "tls:buf+0x4".to_string()),
+                (0x1020, "tls:buf".to_string()),
+                (0x1030, "tls:buf".to_string()),
+            ]
+        );
+        assert_eq!(found[3].via, Via::TlsGetAddr);
+        annotate(&mut ws, &template, &found);
+        assert_eq!(ws.get_comment(0x1000), "tls:counter");
+
+        // a thread for the interpreter: the block below the thread pointer, which points at
+        // itself
+        let mut memory = Ram::new();
+        let mut registers = RegisterContext::new(Arch::Amd64);
+        let block = thread_block(&ws, &template);
+        let tp = map_thread(&mut memory, &mut registers, &template, block, 0x10_0000).unwrap();
+        assert_eq!(tp, 0x10_0010);
+        assert_eq!(registers.get(reg("fs_base")), 0x10_0010);
+        assert_eq!(memory.read(tp - 0x10, 4), Some(1));
+        assert_eq!(memory.read(tp, 8), Some(tp));
+
+        // the Windows way: [[gs:[0x58] + _tls_index * 8] + 4]
+        let windows = Template {
+            layout: Layout::Indexed,
+            index: Some(0x7000),
+            variables: Vec::new(),
+            ..template
+        };
+        let array = load(add(Expr::Reg(reg("gs_base")), Expr::Const(0x58)));
+        let index = Expr::binary(BinOp::Shl, load(Expr::Const(0x7000)), Expr::Const(3), 64);
+        let functions = function(vec![
+            (0x2000, Stmt::Set(reg("rax"), load(add(array, index)))),
+            (
+                0x2008,
+                Stmt::Set(reg("edx"), load(add(Expr::Reg(reg("rax")), Expr::Const(4)))),
+            ),
+        ]);
+        let found = accesses(&ws, &functions, &windows);
+        assert_eq!(
+            found,
+            [Access {
+                va: 0x2008,
+                offset: 4,
+                via: Via::TlsArray,
+            }]
+        );
+    }
+}
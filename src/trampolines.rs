@@ -0,0 +1,391 @@
+//! Linking import trampolines back to their call sites.
+//!
+//! Code rarely calls an import through its slot directly. A Mach-O `__stubs` entry, an ELF PLT
+//! entry or a PE import thunk is a small trampoline jumping through the slot the loader fills
+//! in, and call sites call the trampoline. [`find_stubs`] recognises the trampolines of the
+//! known slots by their encoding, [`find_calls`] the direct calls to them (and the indirect
+//! calls through the slots themselves), and [`link_import_stubs`] records both in a workspace,
+//! so [`VivWorkspace::get_callers_of_import`] answers in one query whatever the format.
+//!
+//! The scan works on the raw bytes of the executable maps, without decoding whole functions,
+//! so a call is only taken when it lands exactly on a trampoline or a slot. Calls found by
+//! later analysis are picked up as well, they are ordinary code xrefs.
+
+use crate::{
+    constants::{BR_DEREF, BR_PROC, REF_CODE},
+    envi::Arch,
+    memory::Memory,
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A trampoline jumping through an import slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stub {
+    pub va: u64,
+    pub size: u64,
+    pub slot: u64,
+}
+
+/// A call (or jump) to a trampoline, or an indirect call through a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub from: u64,
+    pub to: u64,
+    /// Whether `to` is the slot, called through, rather than a trampoline
+    pub deref: bool,
+}
+
+/// The trampolines in the code mapped at `va` which jump through one of `slots`
+///
+/// Recognised are `jmp [slot]` on i386 and amd64 (after an `endbr64` or a `bnd` prefix too),
+/// and the `adrp x16, slot@page; ldr xN, [x16, slot@pageoff]; [add x16, ...;] br xN` of A64.
+pub fn find_stubs(arch: Arch, va: u64, bytes: &[u8], slots: &BTreeSet<u64>) -> Vec<Stub> {
+    let mut stubs = Vec::new();
+    match arch {
+        Arch::I386 | Arch::Amd64 => {
+            let mut offset = 0;
+            while offset < bytes.len() {
+                match x86_stub(arch, va, bytes, offset) {
+                    Some(stub) if slots.contains(&stub.slot) => {
+                        offset += stub.size as usize;
+                        stubs.push(stub);
+                    }
+                    _ => offset += 1,
+                }
+            }
+        }
+        Arch::A64 => {
+            let mut offset = 0;
+            while offset + 12 <= bytes.len() {
+                match a64_stub(va, bytes, offset) {
+                    Some(stub) if slots.contains(&stub.slot) => {
+                        offset += stub.size as usize;
+                        stubs.push(stub);
+                    }
+                    _ => offset += 4,
+                }
+            }
+        }
+        _ => {}
+    }
+    stubs
+}
+
+fn x86_stub(arch: Arch, va: u64, bytes: &[u8], offset: usize) -> Option<Stub> {
+    let rest = &bytes[offset..];
+    let mut prefix = 0;
+    if rest.starts_with(&[0xf3, 0x0f, 0x1e, 0xfa]) {
+        prefix += 4;
+    }
+    if rest.get(prefix) == Some(&0xf2) {
+        prefix += 1;
+    }
+    let insn = rest.get(prefix..prefix + 6)?;
+    if insn[..2] != [0xff, 0x25] {
+        return None;
+    }
+    let size = (prefix + 6) as u64;
+    let disp = i32::from_le_bytes(insn[2..].try_into().unwrap());
+    let slot = match arch {
+        // rip relative
+        Arch::Amd64 => (va + offset as u64 + size).wrapping_add(disp as i64 as u64),
+        _ => disp as u32 as u64,
+    };
+    Some(Stub {
+        va: va + offset as u64,
+        size,
+        slot,
+    })
+}
+
+pub(crate) fn a64_insn(bytes: &[u8], offset: usize) -> Option<u32> {
+    let insn = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(insn.try_into().unwrap()))
+}
+
+/// The register and the page an `adrp` at `pc` loads
+pub(crate) fn a64_adrp(pc: u64, insn: u32) -> Option<(u32, u64)> {
+    if insn & 0x9f00_0000 != 0x9000_0000 {
+        return None;
+    }
+    let imm = (((insn >> 5) & 0x7ffff) << 2 | (insn >> 29) & 3) as u64;
+    // sign extend the 21 bit page count
+    let pages = ((imm << 43) as i64 >> 43) as u64;
+    Some((insn & 0x1f, (pc & !0xfff).wrapping_add(pages << 12)))
+}
+
+/// The target register, base register and offset of a 64-bit `ldr xT, [xN, #offset]`
+pub(crate) fn a64_ldr(insn: u32) -> Option<(u32, u32, u64)> {
+    if insn & 0xffc0_0000 != 0xf940_0000 {
+        return None;
+    }
+    Some((
+        insn & 0x1f,
+        (insn >> 5) & 0x1f,
+        ((insn >> 10) & 0xfff) as u64 * 8,
+    ))
+}
+
+fn a64_stub(va: u64, bytes: &[u8], offset: usize) -> Option<Stub> {
+    let pc = va + offset as u64;
+    let (base, page) = a64_adrp(pc, a64_insn(bytes, offset)?)?;
+    let (target, ldr_base, pageoff) = a64_ldr(a64_insn(bytes, offset + 4)?)?;
+    if ldr_base != base {
+        return None;
+    }
+    let slot = page + pageoff;
+
+    let mut size = 8;
+    let mut insn = a64_insn(bytes, offset + size)?;
+    // `add x16, x16, #pageoff` of the ELF PLT entries
+    if insn & 0xff80_0000 == 0x9100_0000 {
+        size += 4;
+        insn = a64_insn(bytes, offset + size)?;
+    }
+    if insn & 0xffff_fc1f != 0xd61f_0000 || (insn >> 5) & 0x1f != target {
+        return None;
+    }
+    Some(Stub {
+        va: pc,
+        size: size as u64 + 4,
+        slot,
+    })
+}
+
+/// The calls in the code mapped at `va` to one of `stubs`, and the indirect calls through one
+/// of `slots`
+///
+/// Recognised are `call rel32` and `jmp rel32` and `call [slot]` on i386 and amd64, and `bl`
+/// and `b` on A64.
+pub fn find_calls(
+    arch: Arch,
+    va: u64,
+    bytes: &[u8],
+    stubs: &BTreeSet<u64>,
+    slots: &BTreeSet<u64>,
+) -> Vec<Call> {
+    let mut calls = Vec::new();
+    match arch {
+        Arch::I386 | Arch::Amd64 => {
+            for offset in 0..bytes.len() {
+                let from = va + offset as u64;
+                let rest = &bytes[offset..];
+                if let (Some(0xe8 | 0xe9), Some(rel)) = (rest.first(), rest.get(1..5)) {
+                    let rel = i32::from_le_bytes(rel.try_into().unwrap());
+                    let to = (from + 5).wrapping_add(rel as i64 as u64);
+                    if stubs.contains(&to) {
+                        calls.push(Call {
+                            from,
+                            to,
+                            deref: false,
+                        });
+                    }
+                }
+                if let (Some([0xff, 0x15]), Some(disp)) = (rest.get(..2), rest.get(2..6)) {
+                    let disp = i32::from_le_bytes(disp.try_into().unwrap());
+                    let to = match arch {
+                        Arch::Amd64 => (from + 6).wrapping_add(disp as i64 as u64),
+                        _ => disp as u32 as u64,
+                    };
+                    if slots.contains(&to) {
+                        calls.push(Call {
+                            from,
+                            to,
+                            deref: true,
+                        });
+                    }
+                }
+            }
+        }
+        Arch::A64 => {
+            for offset in (0..bytes.len()).step_by(4) {
+                let insn = match a64_insn(bytes, offset) {
+                    Some(insn) => insn,
+                    None => break,
+                };
+                // bl and b
+                if insn & 0x7c00_0000 != 0x1400_0000 {
+                    continue;
+                }
+                let from = va + offset as u64;
+                let imm = ((insn & 0x03ff_ffff) as u64) << 2;
+                let to = from.wrapping_add(((imm << 36) as i64 >> 36) as u64);
+                if stubs.contains(&to) {
+                    calls.push(Call {
+                        from,
+                        to,
+                        deref: false,
+                    });
+                }
+            }
+        }
+        _ => {}
+    }
+    calls
+}
+
+/// Find the trampolines of the imports of `workspace` and the calls to them, and record them.
+/// Returns the number of trampolines found.
+pub fn link_import_stubs(workspace: &mut VivWorkspace) -> usize {
+    let arch = match Arch::from_envi(workspace.get_mem_architecture()) {
+        Some(arch) => arch,
+        None => return 0,
+    };
+    let slots: BTreeSet<u64> = workspace
+        .get_imports()
+        .iter()
+        .map(|(va, _)| *va as u32 as u64)
+        .collect();
+    if slots.is_empty() {
+        return 0;
+    }
+    let maps = workspace
+        .get_executable_maps()
+        .into_iter()
+        .map(|(va, bytes)| (va as u32 as u64, bytes.to_vec()))
+        .collect::<Vec<_>>();
+    let mut stubs = BTreeMap::new();
+    for (va, bytes) in maps.iter() {
+        for stub in find_stubs(arch, *va, bytes, &slots) {
+            stubs.insert(stub.va, stub);
+        }
+    }
+    let targets = stubs.keys().copied().collect::<BTreeSet<_>>();
+    for stub in stubs.values() {
+        workspace.add_import_stub(stub.va as i32, stub.slot as i32);
+        workspace.add_xref(stub.va as i32, stub.slot as i32, REF_CODE, BR_DEREF);
+    }
+    for (va, bytes) in maps.iter() {
+        for call in find_calls(arch, *va, bytes, &targets, &slots) {
+            // the trampolines themselves aren't callers
+            if stubs.contains_key(&call.from) {
+                continue;
+            }
+            let flags = if call.deref {
+                BR_PROC | BR_DEREF
+            } else {
+                BR_PROC
+            };
+            workspace.add_xref(call.from as i32, call.to as i32, REF_CODE, flags);
+        }
+    }
+    stubs.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_stubs() {
+        // a PLT entry at 0x1010 jumping through the slot at 0x4018, and a call to it
+        let mut code = vec![0x90; 0x10];
+        code.extend([0xf3, 0x0f, 0x1e, 0xfa, 0xf2, 0xff, 0x25]);
+        code.extend((0x4018i32 - 0x101b).to_le_bytes());
+        code.extend([0xe8]);
+        code.extend((0x1010i32 - 0x1020).to_le_bytes());
+        // call [rip + slot]
+        code.extend([0xff, 0x15]);
+        code.extend((0x4018i32 - 0x1026).to_le_bytes());
+        let slots = BTreeSet::from([0x4018]);
+        let stubs = find_stubs(Arch::Amd64, 0x1000, &code, &slots);
+        assert_eq!(
+            stubs,
+            [Stub {
+                va: 0x1010,
+                size: 11,
+                slot: 0x4018
+            }]
+        );
+        assert!(find_stubs(Arch::Amd64, 0x1000, &code, &BTreeSet::new()).is_empty());
+        let calls = find_calls(
+            Arch::Amd64,
+            0x1000,
+            &code,
+            &BTreeSet::from([0x1010]),
+            &slots,
+        );
+        assert_eq!(
+            calls,
+            [
+                Call {
+                    from: 0x101b,
+                    to: 0x1010,
+                    deref: false
+                },
+                Call {
+                    from: 0x1020,
+                    to: 0x4018,
+                    deref: true
+                }
+            ]
+        );
+
+        // an i386 import thunk, jmp [0x403000]
+        let thunk = [0xff, 0x25, 0x00, 0x30, 0x40, 0x00];
+        let stubs = find_stubs(Arch::I386, 0x401000, &thunk, &BTreeSet::from([0x403000]));
+        assert_eq!(stubs[0].slot, 0x403000);
+    }
+
+    #[test]
+    fn a64_stubs() {
+        let words = [
+            // bl 0x100004000
+            0x94000000 | ((0x4000 - 0x3ff8) / 4),
+            0xd503201f,
+            // adrp x16, 0x100008000; ldr x16, [x16, #0x10]; br x16
+            0x90000030,
+            0xf9400a10,
+            0xd61f0200,
+        ];
+        let code = words
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let slots = BTreeSet::from([0x100008010]);
+        let stubs = find_stubs(Arch::A64, 0x100003ff8, &code, &slots);
+        assert_eq!(
+            stubs,
+            [Stub {
+                va: 0x100004000,
+                size: 12,
+                slot: 0x100008010
+            }]
+        );
+        let calls = find_calls(
+            Arch::A64,
+            0x100003ff8,
+            &code,
+            &BTreeSet::from([0x100004000]),
+            &slots,
+        );
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].from, 0x100003ff8);
+    }
+
+    #[test]
+    fn callers_of_import() {
+        use crate::constants::{ARCH_I386, MM_EXEC, MM_READ};
+        // two calls to the thunk of memcpy at 0x1010, one call through its slot
+        let mut code = vec![0xe8];
+        code.extend((0x1010i32 - 0x1005).to_le_bytes());
+        code.extend([0xe8]);
+        code.extend((0x1010i32 - 0x100a).to_le_bytes());
+        code.extend([0xff, 0x15, 0x00, 0x30, 0x00, 0x00]);
+        code.resize(0x10, 0xcc);
+        code.extend([0xff, 0x25, 0x00, 0x30, 0x00, 0x00]);
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        ws.add_memory_map(0x1000, MM_READ | MM_EXEC, "test", code, None);
+        ws.make_import(0x3000, "msvcrt", "memcpy");
+        assert_eq!(link_import_stubs(&mut ws), 1);
+        assert_eq!(ws.get_import_stubs(), [(0x1010, 0x3000)]);
+        assert_eq!(ws.get_callers_of_import("memcpy"), [0x1000, 0x1005, 0x100a]);
+        assert_eq!(
+            ws.get_callers_of_import("msvcrt.memcpy"),
+            ws.get_callers_of_import("memcpy")
+        );
+        assert!(ws.get_callers_of_import("memmove").is_empty());
+    }
+}
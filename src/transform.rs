@@ -0,0 +1,147 @@
+//! Section content transformations run while a modified binary is written out.
+//!
+//! The format writers only keep their own tables consistent. Packers and unpackers usually need
+//! more than that: a section has to be encrypted or compressed again, or a checksum embedded in
+//! the binary has to be recomputed. Such invariants are kept by registering a
+//! [`SectionTransform`] for the section with the writer, which runs it on the final contents of
+//! the section just before the file is emitted.
+
+use crate::error;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::ops::Range;
+
+/// The section a transform runs on
+#[derive(Debug, Clone, Copy)]
+pub struct SectionRef<'a> {
+    /// The name the transform was registered under; the section name for ELF and PE,
+    /// `segment,section` (e.g. `__TEXT,__text`) for Mach-O
+    pub name: &'a str,
+    pub address: u64,
+    /// Where the section currently lives in the output file
+    pub offset: usize,
+    /// Whether the section is mapped at runtime. Loaded sections can't grow, the writers never
+    /// move anything the loader relies on
+    pub loaded: bool,
+    /// The whole output file as it stands, including the results of the earlier transforms
+    pub file: &'a [u8],
+}
+
+pub trait SectionTransform {
+    /// Rewrite `data`, the current contents of `section`. The contents may shrink, and may only
+    /// grow if the section is not loaded.
+    fn transform(&mut self, section: &SectionRef, data: &mut Vec<u8>) -> error::Result<()>;
+}
+
+impl<F> SectionTransform for F
+where
+    F: FnMut(&SectionRef, &mut Vec<u8>) -> error::Result<()>,
+{
+    fn transform(&mut self, section: &SectionRef, data: &mut Vec<u8>) -> error::Result<()> {
+        self(section, data)
+    }
+}
+
+/// The transforms registered with a writer, run in registration order
+#[derive(Default)]
+pub struct SectionTransforms {
+    transforms: Vec<(String, Box<dyn SectionTransform>)>,
+}
+
+impl SectionTransforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<T: SectionTransform + 'static>(&mut self, section: &str, transform: T) {
+        self.transforms
+            .push((section.to_string(), Box::new(transform)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Whether any transform is registered for the section `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.transforms.iter().any(|(section, _)| section == name)
+    }
+
+    /// Run the transforms registered for `section`, which occupies `range` of `section.file`.
+    /// Returns None if there are none, otherwise the new contents.
+    pub fn apply(
+        &mut self,
+        section: &SectionRef,
+        range: Range<usize>,
+    ) -> error::Result<Option<Vec<u8>>> {
+        if !self.contains(section.name) {
+            return Ok(None);
+        }
+        let mut data = section
+            .file
+            .get(range.clone())
+            .ok_or_else(|| {
+                error::Error::Malformed(format!("Section {} is truncated", section.name))
+            })?
+            .to_vec();
+        for (_, transform) in self
+            .transforms
+            .iter_mut()
+            .filter(|(name, _)| name == section.name)
+        {
+            transform.transform(section, &mut data)?;
+        }
+        if section.loaded && data.len() > range.len() {
+            return Err(error::Error::Malformed(format!(
+                "Section {} is loaded and can't grow from {:#x} to {:#x} bytes",
+                section.name,
+                range.len(),
+                data.len()
+            )));
+        }
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_run_in_order() {
+        let file = [1u8, 2, 3, 4, 5, 6];
+        let mut transforms = SectionTransforms::new();
+        transforms.register(".data", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.iter_mut().for_each(|b| *b ^= 0xff);
+            Ok(())
+        });
+        transforms.register(".data", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.truncate(1);
+            Ok(())
+        });
+        transforms.register(".note", |_: &SectionRef, data: &mut Vec<u8>| {
+            data.push(0);
+            Ok(())
+        });
+        let mut section = SectionRef {
+            name: ".data",
+            address: 0x1000,
+            offset: 2,
+            loaded: true,
+            file: &file,
+        };
+        assert_eq!(transforms.apply(&section, 2..4).unwrap(), Some(vec![0xfc]));
+        section.name = ".bss";
+        assert_eq!(transforms.apply(&section, 2..4).unwrap(), None);
+        section.name = ".note";
+        assert!(transforms.apply(&section, 2..4).is_err());
+        section.loaded = false;
+        assert_eq!(
+            transforms.apply(&section, 2..4).unwrap(),
+            Some(vec![3, 4, 0])
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Symbol transplant between two builds of the same module.
+//!
+//! A stripped binary and an unstripped (or symbolicated) variant of the same build share their
+//! module id: the ELF GNU build-id, the Mach-O `LC_UUID` or the PE PDB GUID. Once the ids match,
+//! the names and function boundaries of the variant's workspace are carried over to the stripped
+//! one, relative to the image base of each, so the two may be loaded at different addresses.
+//! Functions analysis found in the stripped binary inside what the variant knows to be a single
+//! function are dropped, and names a user gave are left alone.
+
+use crate::{symcache::rebase, workspace::VivWorkspace};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransplantError {
+    /// The named file of either workspace has no module id to match by
+    NoModuleId(String),
+    /// The files are different builds
+    Mismatch,
+}
+
+impl fmt::Display for TransplantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransplantError::NoModuleId(fname) => write!(f, "{} has no module id", fname),
+            TransplantError::Mismatch => write!(f, "the module ids don't match"),
+        }
+    }
+}
+
+/// What a transplant changed in the stripped workspace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transplant {
+    pub names: usize,
+    pub functions: usize,
+    /// Functions of the stripped workspace which were part of another function
+    pub dropped: Vec<i32>,
+    /// Names a user gave, kept over those of the variant
+    pub kept: Vec<i32>,
+}
+
+/// Carry the names and function boundaries of `fname` in `from` over to `to_fname` in `to`, if
+/// both are the same build.
+pub fn transplant(
+    to: &mut VivWorkspace,
+    to_fname: &str,
+    from: &VivWorkspace,
+    fname: &str,
+) -> Result<Transplant, TransplantError> {
+    let to_id = to
+        .get_module_id(to_fname)
+        .ok_or_else(|| TransplantError::NoModuleId(to_fname.to_string()))?;
+    let from_id = from
+        .get_module_id(fname)
+        .ok_or_else(|| TransplantError::NoModuleId(fname.to_string()))?;
+    if to_id != from_id {
+        return Err(TransplantError::Mismatch);
+    }
+    let symbols = rebase(
+        &from.file_symbols(fname),
+        to.get_file_meta(to_fname, "imagebase"),
+    );
+    let mut ret = Transplant::default();
+    for (fva, size) in symbols.functions.iter() {
+        let ranges = symbols
+            .bounds
+            .get(fva)
+            .cloned()
+            .unwrap_or_else(|| vec![(*fva, *size)]);
+        for func in to.get_functions() {
+            let inside = ranges
+                .iter()
+                .any(|(va, size)| *va <= func && func < va.wrapping_add(*size));
+            if func != *fva && inside && !symbols.functions.contains_key(&func) {
+                to.del_function(func);
+                ret.dropped.push(func);
+            }
+        }
+        if to.add_function(*fva, ranges) {
+            ret.functions += 1;
+        }
+    }
+    let prefix = format!("{}.", fname);
+    for (va, name) in symbols.names.iter() {
+        // file local names take the name of the stripped file
+        let name = match name.strip_prefix(&prefix) {
+            Some(name) => format!("{}.{}", to_fname, name),
+            None => name.clone(),
+        };
+        match to.get_name(*va, false) {
+            Some(current) if current == name => continue,
+            Some(_) if !to.is_auto_name(*va) => {
+                ret.kept.push(*va);
+                continue;
+            }
+            _ => {}
+        }
+        if to.make_name(*va, name, false, true).is_some() {
+            ret.names += 1;
+        }
+    }
+    ret.dropped.sort_unstable();
+    ret.dropped.dedup();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::AutoKind;
+
+    #[test]
+    fn transplants_symbols() {
+        let load = |imagebase: i32| {
+            let mut ws = VivWorkspace::new("", false);
+            let fname = ws.add_file("Cargo.toml", imagebase, vec![]);
+            ws.add_segment(imagebase, 0x10000, ".text", fname.clone());
+            ws.set_module_id(&fname, vec![0xaa; 20]);
+            (ws, fname)
+        };
+        let (mut from, fname) = load(0x400000);
+        from.add_function(0x401000, vec![(0x401000, 0x40), (0x403000, 0x10)]);
+        from.make_name(0x401000, "parse_header".to_string(), true, false);
+        from.make_name(0x402000, "config".to_string(), false, false);
+
+        // the stripped build is loaded elsewhere, and split the function at its cold part
+        let (mut to, to_fname) = load(0x10000);
+        to.add_function(0x11000, vec![(0x11000, 0x40)]);
+        to.add_function(0x13000, vec![(0x13000, 0x10)]);
+        to.make_auto_name(0x11000, AutoKind::Function);
+        to.make_name(0x12000, "my_config".to_string(), false, false);
+
+        let done = transplant(&mut to, &to_fname, &from, &fname).unwrap();
+        assert_eq!(done.dropped, [0x13000]);
+        assert_eq!(done.kept, [0x12000]);
+        assert_eq!(to.get_functions(), [0x11000]);
+        assert_eq!(
+            to.get_function_bounds(0x11000),
+            Some(vec![(0x11000, 0x40), (0x13000, 0x10)])
+        );
+        assert_eq!(
+            to.get_name(0x11000, false),
+            Some(format!("{}.parse_header", to_fname))
+        );
+        assert_eq!(to.get_name(0x12000, false).as_deref(), Some("my_config"));
+
+        to.set_module_id(&to_fname, vec![0xbb; 20]);
+        assert_eq!(
+            transplant(&mut to, &to_fname, &from, &fname),
+            Err(TransplantError::Mismatch)
+        );
+    }
+}
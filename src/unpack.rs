@@ -0,0 +1,344 @@
+//! Unpacking by emulation: run a packed binary's stub until it jumps to the original entry
+//! point, then take the unpacked image out of memory.
+//!
+//! [`unpack`] loads the maps of a workspace into a [`Ram`], the executable ones as code, and
+//! runs the IL interpreter (see [`crate::ilemu`]) from the entry point, lifting code as it goes
+//! with the caller's [`Lifter`]: the crate has no decoder of its own, and the stub rewrites its
+//! code, so each function is lifted from memory as it stands when it is reached, and again
+//! when a write to it stops the run with [`Stop::CodeWritten`].
+//!
+//! The jump to the original entry point (the OEP) is told by where it goes: into code the stub
+//! wrote, or into a segment other than the one the stub started in, as UPX jumps from `UPX1` to
+//! `UPX0`. The image dumped at that point becomes a fresh workspace, with the code written at
+//! runtime in its `DynamicCode` va set and the OEP as its entry point.
+//!
+//! Imports are rebuilt by marking them. Before the run each import slot of the packed binary
+//! gets an address of its own in an unmapped range, and whatever the stub copies those to is
+//! an import slot of the unpacked image, of the same function. Imports the stub resolves by
+//! name with `GetProcAddress` aren't seen, the call not being emulated; see
+//! [`crate::dynimports`] for those.
+
+use crate::{
+    constants::{ENDIAN_MSB, MM_EXEC},
+    emulator::{INIT_STACK_MAP, INIT_STACK_SIZE},
+    envi::{registers::RegisterContext, Arch},
+    ilemu::{Fault, Interpreter, Ram, Stop},
+    memory::Memory,
+    pic::load,
+    symbolic::Function,
+    workspace::VivWorkspace,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// Where the stack is mapped, below its top
+pub const STACK_TOP: u64 = 0x7ff0_0000;
+
+/// The address the first import slot is marked with; the others follow it
+pub const IMPORT_MARKS: u64 = 0x7fe0_0000;
+
+/// Lifts the function at an address from memory as it is
+pub trait Lifter {
+    fn lift(&mut self, arch: Arch, memory: &mut Ram, va: u64) -> Option<Function>;
+}
+
+impl<F: FnMut(Arch, &mut Ram, u64) -> Option<Function>> Lifter for F {
+    fn lift(&mut self, arch: Arch, memory: &mut Ram, va: u64) -> Option<Function> {
+        self(arch, memory, va)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How many blocks to run at most, over the whole run
+    pub max_blocks: usize,
+    /// How many times to lift code at most
+    pub max_lifts: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_blocks: 1_000_000,
+            max_lifts: 10_000,
+        }
+    }
+}
+
+/// Why [`unpack`] found no OEP
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The architecture of the workspace isn't known
+    Arch,
+    /// The workspace has no entry point
+    NoEntry,
+    /// The lifter couldn't lift the code at `va`
+    Lift {
+        va: u64,
+    },
+    /// The stub returned from the block at `va`
+    Returned {
+        va: u64,
+    },
+    Fault(Fault),
+    /// The stub ran out of blocks or lifts, about to run `va`
+    Budget {
+        va: u64,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Arch => write!(f, "unknown architecture"),
+            Failure::NoEntry => write!(f, "no entry point"),
+            Failure::Lift { va } => write!(f, "{va:#x}: cannot lift"),
+            Failure::Returned { va } => write!(f, "{va:#x}: stub returned"),
+            Failure::Fault(fault) => write!(f, "{fault}"),
+            Failure::Budget { va } => write!(f, "{va:#x}: out of budget"),
+        }
+    }
+}
+
+/// What [`unpack`] took out of memory
+#[derive(Clone, Debug)]
+pub struct Unpacked {
+    /// The original entry point
+    pub oep: u64,
+    /// The jump to it
+    pub jump: u64,
+    /// The unpacked image
+    pub workspace: VivWorkspace,
+    /// The regions of code written at runtime, as (va, size)
+    pub code_written: Vec<(u64, u64)>,
+    /// The import slots of the unpacked image
+    pub imports: Vec<(u64, String)>,
+}
+
+/// A memory map of the workspace: va, perms, name and bytes
+type Map = (u64, i32, String, Vec<u8>);
+
+fn maps(workspace: &mut VivWorkspace) -> Vec<Map> {
+    workspace
+        .get_memory_maps()
+        .into_iter()
+        .filter_map(|(va, size, perms, name)| {
+            let bytes = workspace.read_memory(va, size)?;
+            Some((va as u32 as u64, perms, name, bytes))
+        })
+        .collect()
+}
+
+/// The segment, or failing that the map, holding `va`
+fn region_of(segments: &[(u64, u64)], va: u64) -> Option<usize> {
+    segments
+        .iter()
+        .position(|(start, size)| *start <= va && va - start < *size)
+}
+
+/// Emulate the stub of a packed binary from its entry point up to the jump to the original
+/// entry point, and dump the unpacked image into a new workspace
+pub fn unpack(
+    workspace: &mut VivWorkspace,
+    lifter: &mut dyn Lifter,
+    options: &Options,
+) -> Result<Unpacked, Failure> {
+    let arch = Arch::from_envi(workspace.arch).ok_or(Failure::Arch)?;
+    let size = arch.pointer_size();
+    let maps = maps(workspace);
+    let big_endian = workspace.get_endian() == ENDIAN_MSB;
+    let mut memory = Ram::new();
+    memory.set_big_endian(big_endian);
+    for (va, perms, _, bytes) in maps.iter() {
+        if perms & MM_EXEC != 0 {
+            memory.map_code(*va, bytes.clone());
+        } else {
+            memory.map(*va, bytes.clone());
+        }
+    }
+    memory.map(STACK_TOP - INIT_STACK_SIZE as u64, INIT_STACK_MAP.to_vec());
+    memory.set_log_code_writes(true);
+
+    let mut marks = BTreeMap::new();
+    if size >= 4 {
+        for (i, (va, name)) in workspace.get_imports().into_iter().enumerate() {
+            let mark = IMPORT_MARKS + (i * size) as u64;
+            if memory.write(va as u32 as u64, size, mark) {
+                marks.insert(mark, name);
+            }
+        }
+    }
+
+    let mut segments: Vec<(u64, u64)> = workspace
+        .get_segments()
+        .iter()
+        .map(|(va, size, _, _)| (*va as u32 as u64, *size as u32 as u64))
+        .collect();
+    if segments.is_empty() {
+        segments = maps
+            .iter()
+            .map(|(va, _, _, bytes)| (*va, bytes.len() as u64))
+            .collect();
+    }
+    let entry = workspace
+        .get_entry_points()
+        .first()
+        .map(|va| *va as u32 as u64)
+        .ok_or(Failure::NoEntry)?;
+    let stub = region_of(&segments, entry);
+
+    let mut registers = RegisterContext::new(arch);
+    let sp = registers.model().sp();
+    registers.set(sp, (STACK_TOP - 2 * size as u64).into());
+    let mut blocks = options.max_blocks;
+    let mut va = entry;
+    let (jump, oep) = 'run: {
+        for _ in 0..options.max_lifts {
+            if blocks == 0 {
+                break;
+            }
+            let func = lifter
+                .lift(arch, &mut memory, va)
+                .ok_or(Failure::Lift { va })?;
+            let mut path = Vec::new();
+            let stop = Interpreter::new(&mut registers, &mut memory)
+                .trace(&func, blocks, &mut path)
+                .map_err(Failure::Fault)?;
+            blocks = blocks.saturating_sub(path.len());
+            va = match stop {
+                Stop::Exit { va: from, to } | Stop::TailCall { va: from, to } => {
+                    let written = memory
+                        .code_written()
+                        .iter()
+                        .any(|(start, size)| *start <= to && to - start < *size);
+                    if written || region_of(&segments, to) != stub {
+                        break 'run (from, to);
+                    }
+                    to
+                }
+                Stop::Return { va } => return Err(Failure::Returned { va }),
+                Stop::Limit { va } | Stop::Reached { va } | Stop::CodeWritten { va, .. } => va,
+            };
+        }
+        return Err(Failure::Budget { va });
+    };
+
+    let mut unpacked = VivWorkspace::new("", false);
+    unpacked.set_mem_architecture(workspace.arch);
+    unpacked.set_endian(workspace.get_endian());
+    let mut imports = Vec::new();
+    for (mva, perms, name, bytes) in maps.iter() {
+        let bytes = memory
+            .read_bytes(*mva, bytes.len())
+            .unwrap_or_else(|| bytes.clone());
+        for (offset, slot) in bytes.chunks_exact(size).enumerate() {
+            let value = load(slot, big_endian);
+            if let Some(name) = marks.get(&value) {
+                imports.push((mva + (offset * size) as u64, name.clone()));
+            }
+        }
+        unpacked.add_memory_map(*mva as i32, *perms, name, bytes, None);
+    }
+    for (va, size, name, fname) in workspace.get_segments() {
+        unpacked.add_segment(va, size, &name, fname);
+    }
+    let code_written = memory.code_written();
+    for (va, size) in code_written.iter() {
+        if let Some(bytes) = memory.read_bytes(*va, *size as usize) {
+            unpacked.add_dynamic_code(*va as i32, &bytes);
+        }
+    }
+    for (va, name) in imports.iter() {
+        let (library, function) = name.rsplit_once('.').unwrap_or(("", name));
+        unpacked.make_import(*va as i32, library, function);
+    }
+    unpacked.add_entry_point(oep as i32);
+    Ok(Unpacked {
+        oep,
+        jump,
+        workspace: unpacked,
+        code_written,
+        imports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{ARCH_I386, MM_READ, MM_WRITE},
+        envi::registers::RegisterModel,
+        symbolic::{BinOp, Block, Expr, Insn, Stmt, Terminator},
+    };
+
+    #[test]
+    fn unpacks_stub() {
+        // The stub at 0x2000 xors the dword at 0x1000 with 0x55555555, copies the import slot
+        // at 0x3000 to 0x1004, and jumps to 0x1000
+        let mut ws = VivWorkspace::new("", false);
+        ws.set_mem_architecture(ARCH_I386 as u32);
+        ws.add_memory_map(
+            0x1000,
+            MM_READ | MM_WRITE | MM_EXEC,
+            "UPX0",
+            vec![0x55; 8],
+            None,
+        );
+        ws.add_memory_map(0x2000, MM_READ | MM_EXEC, "UPX1", vec![0xcc; 0x10], None);
+        ws.add_memory_map(0x3000, MM_READ | MM_WRITE, "imports", vec![0; 4], None);
+        ws.make_import(0x3000, "kernel32", "ExitProcess");
+        ws.add_entry_point(0x2000);
+
+        let eax = RegisterModel::new(Arch::I386).by_name("eax").unwrap();
+        let mut lifts = Vec::new();
+        let mut lifter = |arch: Arch, _: &mut Ram, va: u64| {
+            lifts.push(va);
+            if va != 0x2000 {
+                return None;
+            }
+            let load = |addr| Expr::Load(Box::new(Expr::Const(addr)));
+            let mut func = Function::new(arch, va);
+            func.add_block(Block {
+                va: 0x2000,
+                insns: vec![
+                    Insn {
+                        va: 0x2000,
+                        stmts: vec![Stmt::Store(
+                            Expr::Const(0x1000),
+                            Expr::binary(BinOp::Xor, load(0x1000), Expr::Const(0x5555_5555), 32),
+                        )],
+                    },
+                    Insn {
+                        va: 0x2006,
+                        stmts: vec![Stmt::Set(eax, load(0x3000))],
+                    },
+                    Insn {
+                        va: 0x200b,
+                        stmts: vec![Stmt::Store(Expr::Const(0x1004), Expr::Reg(eax))],
+                    },
+                ],
+                end_va: 0x2010,
+                end: Terminator::Jump(0x1000),
+            });
+            Some(func)
+        };
+        let unpacked = unpack(&mut ws, &mut lifter, &Options::default()).unwrap();
+        assert_eq!((unpacked.jump, unpacked.oep), (0x2010, 0x1000));
+        assert_eq!(unpacked.code_written, [(0x1000, 8)]);
+        assert_eq!(
+            unpacked.imports,
+            [
+                (0x1004, "kernel32.ExitProcess".to_string()),
+                (0x3000, "kernel32.ExitProcess".to_string())
+            ]
+        );
+        let dump = unpacked.workspace;
+        assert_eq!(dump.read_memory(0x1000, 4).unwrap(), [0; 4]);
+        assert_eq!(dump.get_entry_points(), [0x1000]);
+        assert_eq!(dump.get_va_set_rows("DynamicCode"), Some(vec![0x1000]));
+        assert_eq!(
+            dump.get_imports()[0],
+            (0x1004, "kernel32.ExitProcess".into())
+        );
+        assert_eq!(lifts, [0x2000]);
+    }
+}
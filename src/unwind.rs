@@ -0,0 +1,1021 @@
+//! Unwinding a thread of a crashed x86-64 process, one frame at a time.
+//!
+//! [`UnwindTables`] holds the unwind information of the modules mapped into the process: the
+//! `.eh_frame` call frame information (CFI) of ELF modules and the `.pdata` unwind codes of PE
+//! ones. [`UnwindTables::step`] recovers the registers of the caller of a frame from them,
+//! reading the stack through [`StackMemory`]. Code without unwind information (including Mach-O
+//! modules, whose compact unwind info isn't read yet) is unwound by following the frame pointer.
+
+use crate::{
+    elf::{program_header, Elf},
+    error,
+    pe::{exception::StackFrameOffset, exception::UnwindOperation, PE},
+};
+use alloc::{boxed::Box, vec::Vec};
+use scroll::{Pread, Sleb128, Uleb128, LE};
+
+/// How many general purpose registers a frame has
+pub const REGISTERS: usize = 16;
+/// DWARF numbers of the registers the unwinder needs by name
+pub const RBP: usize = 6;
+pub const RSP: usize = 7;
+/// The DWARF column of the return address
+const RA: usize = 16;
+/// The DWARF number of each Windows (PE unwind code, `CONTEXT`) register number
+pub(crate) const WINDOWS_TO_DWARF: [usize; REGISTERS] =
+    [0, 2, 1, 3, 7, 6, 4, 5, 8, 9, 10, 11, 12, 13, 14, 15];
+/// How deep `DW_CFA_remember_state` may nest
+const MAX_STATES: usize = 64;
+/// How long a chain of PE unwind infos may be
+const MAX_CHAIN: usize = 32;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+
+/// Memory of a crashed process
+pub trait StackMemory {
+    /// Read `len` bytes of process memory at `va`, if the artifact holds them
+    fn read_memory(&self, va: u64, len: usize) -> Option<&[u8]>;
+
+    fn read_u64(&self, va: u64) -> Option<u64> {
+        self.read_memory(va, 8)?.pread_with(0, LE).ok()
+    }
+}
+
+/// The registers of a frame: the program counter and the general purpose registers by DWARF
+/// number (rax, rdx, rcx, rbx, rsi, rdi, rbp, rsp, then r8 to r15); registers whose value was
+/// lost are `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameState {
+    pub pc: u64,
+    pub regs: [Option<u64>; REGISTERS],
+}
+
+/// The unwind information of one module
+enum Info<'a> {
+    /// `.eh_frame`, the VA it is linked at, and its FDEs as sorted (begin, end, offset) triples
+    /// of link time addresses
+    EhFrame {
+        bias: u64,
+        section: &'a [u8],
+        address: u64,
+        fdes: Vec<(u64, u64, usize)>,
+    },
+    Pdata {
+        base: u64,
+        pe: Box<PE<'a>>,
+    },
+}
+
+struct Module<'a> {
+    ranges: Vec<(u64, u64)>,
+    info: Info<'a>,
+}
+
+/// The unwind information of the modules of a process
+#[derive(Default)]
+pub struct UnwindTables<'a> {
+    modules: Vec<Module<'a>>,
+}
+
+impl<'a> UnwindTables<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the `.eh_frame` of the ELF module `bytes`, mapped at `ranges` with `bias` added to
+    /// its addresses. It is found by its section header, or else through `PT_GNU_EH_FRAME`
+    pub fn add_elf(
+        &mut self,
+        ranges: &[(u64, u64)],
+        bias: u64,
+        bytes: &'a [u8],
+    ) -> error::Result<()> {
+        let elf = Elf::parse(bytes)?;
+        let section = elf
+            .section_headers
+            .iter()
+            .find(|shdr| elf.shdr_strtab.get_at(shdr.sh_name) == Some(".eh_frame"));
+        let (section, address) = match section {
+            Some(shdr) => (
+                file_range(bytes, shdr.sh_offset, shdr.sh_size)?,
+                shdr.sh_addr,
+            ),
+            None => match eh_frame_from_hdr(&elf, bytes)? {
+                Some(found) => found,
+                None => return Ok(()),
+            },
+        };
+        self.add_eh_frame(ranges, bias, section, address)
+    }
+
+    /// Add an `.eh_frame` section linked at `address`, for a module mapped at `ranges` with
+    /// `bias` added to its addresses
+    pub fn add_eh_frame(
+        &mut self,
+        ranges: &[(u64, u64)],
+        bias: u64,
+        section: &'a [u8],
+        address: u64,
+    ) -> error::Result<()> {
+        let fdes = index_fdes(section, address)?;
+        self.modules.push(Module {
+            ranges: ranges.to_vec(),
+            info: Info::EhFrame {
+                bias,
+                section,
+                address,
+                fdes,
+            },
+        });
+        Ok(())
+    }
+
+    /// Add the `.pdata` of the PE module `bytes`, loaded at `base` and mapped at `ranges`
+    pub fn add_pe(
+        &mut self,
+        ranges: &[(u64, u64)],
+        base: u64,
+        bytes: &'a [u8],
+    ) -> error::Result<()> {
+        let pe = PE::parse(bytes)?;
+        if pe.exception_data.is_some() {
+            self.modules.push(Module {
+                ranges: ranges.to_vec(),
+                info: Info::Pdata {
+                    base,
+                    pe: Box::new(pe),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// The frame of the caller of `state`, or `None` at the end of the stack. `innermost` is set
+    /// for the crashing frame, whose pc is the faulting instruction rather than a return address
+    pub fn step<M: StackMemory + ?Sized>(
+        &self,
+        memory: &M,
+        state: &FrameState,
+        innermost: bool,
+    ) -> Option<FrameState> {
+        // return addresses point after the call, which may be the start of the next function
+        let lookup = if innermost {
+            state.pc
+        } else {
+            state.pc.wrapping_sub(1)
+        };
+        let module = self.modules.iter().find(|module| {
+            module
+                .ranges
+                .iter()
+                .any(|&(start, end)| start <= lookup && lookup < end)
+        });
+        let next = match module.map(|module| &module.info) {
+            Some(Info::EhFrame {
+                bias,
+                section,
+                address,
+                fdes,
+            }) => {
+                let pc = lookup.wrapping_sub(*bias);
+                let found = fdes.partition_point(|&(begin, _, _)| begin <= pc);
+                match found.checked_sub(1).map(|at| fdes[at]) {
+                    Some((_, end, offset)) if pc < end => cfi_row(section, *address, offset, pc)
+                        .ok()
+                        .and_then(|row| row.apply(state, memory))
+                        .or_else(|| frame_pointer(memory, state)),
+                    _ => frame_pointer(memory, state),
+                }
+            }
+            Some(Info::Pdata { base, pe }) => pdata_step(pe, *base, lookup, state, memory),
+            None => frame_pointer(memory, state),
+        }?;
+        // the stack grows down, so every caller's frame is above its callee's
+        if next.pc == 0 || next.regs[RSP]? <= state.regs[RSP]? {
+            return None;
+        }
+        Some(next)
+    }
+}
+
+/// `size` bytes of `bytes` at `offset`
+fn file_range(bytes: &[u8], offset: u64, size: u64) -> error::Result<&[u8]> {
+    usize::try_from(offset)
+        .ok()
+        .zip(usize::try_from(size).ok())
+        .and_then(|(offset, size)| bytes.get(offset..offset.checked_add(size)?))
+        .ok_or_else(|| error::Error::Malformed("eh_frame is outside the file".into()))
+}
+
+/// Find `.eh_frame` from the pointer in `.eh_frame_hdr`, for modules without section headers.
+/// The section runs to the end of the segment it is loaded in, its terminator ends it earlier
+fn eh_frame_from_hdr<'a>(elf: &Elf, bytes: &'a [u8]) -> error::Result<Option<(&'a [u8], u64)>> {
+    let hdr = match elf
+        .program_headers
+        .iter()
+        .find(|phdr| phdr.p_type == program_header::PT_GNU_EH_FRAME)
+    {
+        Some(hdr) => hdr,
+        None => return Ok(None),
+    };
+    let data = file_range(bytes, hdr.p_offset, hdr.p_filesz)?;
+    let version: u8 = data.pread(0)?;
+    let encoding: u8 = data.pread(1)?;
+    if version != 1 || encoding == DW_EH_PE_OMIT {
+        return Ok(None);
+    }
+    let address = read_pointer(data, &mut 4, encoding, hdr.p_vaddr)?;
+    for phdr in elf.program_headers.iter() {
+        let end = phdr.p_vaddr.checked_add(phdr.p_filesz);
+        if phdr.p_type == program_header::PT_LOAD
+            && phdr.p_vaddr <= address
+            && end.is_some_and(|end| address < end)
+        {
+            let skip = address - phdr.p_vaddr;
+            let segment = file_range(bytes, phdr.p_offset, phdr.p_filesz)?;
+            return Ok(Some((&segment[skip as usize..], address)));
+        }
+    }
+    Ok(None)
+}
+
+/// A CIE or FDE: where its id is, the id, and the range of the rest of it
+struct Entry {
+    id_at: usize,
+    id: u32,
+    start: usize,
+    end: usize,
+}
+
+/// The entry at `offset`, or `None` at the terminator
+fn read_entry(section: &[u8], offset: usize) -> error::Result<Option<Entry>> {
+    let mut at = offset;
+    let length: u32 = section.gread_with(&mut at, LE)?;
+    if length == 0 {
+        return Ok(None);
+    }
+    let length = if length == u32::MAX {
+        section.gread_with::<u64>(&mut at, LE)?
+    } else {
+        u64::from(length)
+    };
+    let end = usize::try_from(length)
+        .ok()
+        .and_then(|length| at.checked_add(length))
+        .filter(|&end| end <= section.len())
+        .ok_or_else(|| {
+            error::Error::Malformed(format!("eh_frame entry at {:#x} is too long", offset))
+        })?;
+    let id_at = at;
+    let id = section.gread_with(&mut at, LE)?;
+    Ok(Some(Entry {
+        id_at,
+        id,
+        start: at,
+        end,
+    }))
+}
+
+/// Read a pointer in the `DW_EH_PE_*` `encoding` at `at` in `section`, which is at `address`.
+/// Indirect pointers aren't followed, only personality routines use them
+fn read_pointer(section: &[u8], at: &mut usize, encoding: u8, address: u64) -> error::Result<u64> {
+    let position = address.wrapping_add(*at as u64);
+    let value = match encoding & 0x0f {
+        0x00 | 0x04 => section.gread_with::<u64>(at, LE)?,
+        0x01 => Uleb128::read(section, at)?,
+        0x02 => u64::from(section.gread_with::<u16>(at, LE)?),
+        0x03 => u64::from(section.gread_with::<u32>(at, LE)?),
+        0x09 => Sleb128::read(section, at)? as u64,
+        0x0a => section.gread_with::<i16>(at, LE)? as u64,
+        0x0b => section.gread_with::<i32>(at, LE)? as u64,
+        0x0c => section.gread_with::<i64>(at, LE)? as u64,
+        format => {
+            return Err(error::Error::Malformed(format!(
+                "Unknown pointer format {:#x}",
+                format
+            )))
+        }
+    };
+    match encoding & 0x70 {
+        0x00 => Ok(value),
+        0x10 => Ok(position.wrapping_add(value)),
+        application => Err(error::Error::Malformed(format!(
+            "Unsupported pointer application {:#x}",
+            application
+        ))),
+    }
+}
+
+/// A common information entry
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra: u64,
+    fde_encoding: u8,
+    augmented: bool,
+    instructions: (usize, usize),
+}
+
+fn parse_cie(section: &[u8], entry: &Entry, address: u64) -> error::Result<Cie> {
+    if entry.id != 0 {
+        return Err(error::Error::Malformed(format!(
+            "FDE at {:#x} doesn't point at a CIE",
+            entry.id_at
+        )));
+    }
+    let mut at = entry.start;
+    let version: u8 = section.gread(&mut at)?;
+    let augmentation: &str = section.gread(&mut at)?;
+    if augmentation.contains("eh") {
+        return Err(error::Error::Malformed(format!(
+            "Unsupported CIE augmentation {:?}",
+            augmentation
+        )));
+    }
+    let code_align = Uleb128::read(section, &mut at)?;
+    let data_align = Sleb128::read(section, &mut at)?;
+    let ra = if version == 1 {
+        u64::from(section.gread::<u8>(&mut at)?)
+    } else {
+        Uleb128::read(section, &mut at)?
+    };
+    let mut fde_encoding = 0;
+    let augmented = augmentation.starts_with('z');
+    if augmented {
+        let length = Uleb128::read(section, &mut at)?;
+        let data_end = usize::try_from(length)
+            .ok()
+            .and_then(|length| at.checked_add(length))
+            .filter(|&end| end <= entry.end)
+            .ok_or_else(|| error::Error::Malformed("CIE augmentation is too long".into()))?;
+        for augmentation in augmentation.chars().skip(1) {
+            match augmentation {
+                'R' => fde_encoding = section.gread(&mut at)?,
+                'P' => {
+                    let encoding: u8 = section.gread(&mut at)?;
+                    read_pointer(section, &mut at, encoding & 0x7f, address)?;
+                }
+                'L' => at += 1,
+                _ => break,
+            }
+        }
+        at = data_end;
+    }
+    Ok(Cie {
+        code_align,
+        data_align,
+        ra,
+        fde_encoding,
+        augmented,
+        instructions: (at, entry.end),
+    })
+}
+
+/// The CIE of the FDE `entry`, the range of addresses the FDE covers and its instructions
+fn parse_fde(
+    section: &[u8],
+    entry: &Entry,
+    address: u64,
+) -> error::Result<(Cie, u64, u64, (usize, usize))> {
+    let cie = entry
+        .id_at
+        .checked_sub(entry.id as usize)
+        .map(|offset| read_entry(section, offset))
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| error::Error::Malformed(format!("FDE at {:#x} has no CIE", entry.id_at)))?;
+    let cie = parse_cie(section, &cie, address)?;
+    let mut at = entry.start;
+    let begin = read_pointer(section, &mut at, cie.fde_encoding, address)?;
+    let length = read_pointer(section, &mut at, cie.fde_encoding & 0x0f, address)?;
+    if cie.augmented {
+        let length = Uleb128::read(section, &mut at)?;
+        at = usize::try_from(length)
+            .ok()
+            .and_then(|length| at.checked_add(length))
+            .filter(|&at| at <= entry.end)
+            .ok_or_else(|| error::Error::Malformed("FDE augmentation is too long".into()))?;
+    }
+    Ok((cie, begin, begin.saturating_add(length), (at, entry.end)))
+}
+
+/// The (begin, end, offset) of every FDE in `section`, sorted by address. FDEs which can't be
+/// parsed are left out, their code is unwound by frame pointer
+fn index_fdes(section: &[u8], address: u64) -> error::Result<Vec<(u64, u64, usize)>> {
+    let mut fdes = Vec::new();
+    let mut offset = 0;
+    while offset < section.len() {
+        let entry = match read_entry(section, offset)? {
+            Some(entry) => entry,
+            None => break,
+        };
+        if entry.id != 0 {
+            if let Ok((_, begin, end, _)) = parse_fde(section, &entry, address) {
+                fdes.push((begin, end, offset));
+            }
+        }
+        offset = entry.end;
+    }
+    fdes.sort_unstable();
+    Ok(fdes)
+}
+
+/// How to recover a register of the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    SameValue,
+    /// Saved at CFA + n
+    Offset(i64),
+    /// Is CFA + n
+    ValOffset(i64),
+    /// Saved in another register
+    Register(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cfa {
+    /// Register + offset
+    Register(u16, i64),
+    /// A DWARF expression, which isn't evaluated
+    Expression,
+}
+
+/// A row of the CFI table: how to compute the CFA and each register of the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Row {
+    cfa: Cfa,
+    regs: [Rule; RA + 1],
+}
+
+impl Default for Row {
+    fn default() -> Self {
+        let mut regs = [Rule::SameValue; RA + 1];
+        regs[RA] = Rule::Undefined;
+        Row {
+            cfa: Cfa::Expression,
+            regs,
+        }
+    }
+}
+
+impl Row {
+    fn set(&mut self, reg: u64, rule: Rule) {
+        if let Some(slot) = usize::try_from(reg)
+            .ok()
+            .and_then(|reg| self.regs.get_mut(reg))
+        {
+            *slot = rule;
+        }
+    }
+
+    fn restore(&mut self, reg: u64, initial: &Row) {
+        if let Ok(reg) = usize::try_from(reg) {
+            if reg <= RA {
+                self.regs[reg] = initial.regs[reg];
+            }
+        }
+    }
+
+    /// The registers of the caller of `state`
+    fn apply<M: StackMemory + ?Sized>(&self, state: &FrameState, memory: &M) -> Option<FrameState> {
+        let old = |reg: usize| state.regs.get(reg).copied().flatten();
+        let cfa = match self.cfa {
+            Cfa::Register(reg, offset) => old(reg as usize)?.checked_add_signed(offset)?,
+            Cfa::Expression => return None,
+        };
+        let value = |reg: usize| match self.regs[reg] {
+            Rule::Undefined => None,
+            Rule::SameValue => old(reg),
+            Rule::Offset(offset) => memory.read_u64(cfa.checked_add_signed(offset)?),
+            Rule::ValOffset(offset) => cfa.checked_add_signed(offset),
+            Rule::Register(other) => old(other as usize),
+        };
+        let mut regs = [None; REGISTERS];
+        for (reg, slot) in regs.iter_mut().enumerate() {
+            *slot = value(reg);
+        }
+        // the CFA is the stack pointer of the caller, unless the CFI says otherwise
+        if self.regs[RSP] == Rule::SameValue {
+            regs[RSP] = Some(cfa);
+        }
+        Some(FrameState {
+            pc: value(RA)?,
+            regs,
+        })
+    }
+}
+
+/// The CFI row of `pc` (a link time address) from the FDE at `offset`
+fn cfi_row(section: &[u8], address: u64, offset: usize, pc: u64) -> error::Result<Row> {
+    let entry = read_entry(section, offset)?
+        .ok_or_else(|| error::Error::Malformed(format!("No FDE at {:#x}", offset)))?;
+    let (cie, begin, _, instructions) = parse_fde(section, &entry, address)?;
+    if cie.ra != RA as u64 {
+        return Err(error::Error::Malformed(format!(
+            "Unsupported return address column {}",
+            cie.ra
+        )));
+    }
+    let mut initial = Row::default();
+    let program = Program {
+        section,
+        address,
+        cie: &cie,
+    };
+    program.execute(
+        cie.instructions,
+        &mut initial,
+        &Row::default(),
+        begin,
+        u64::MAX,
+    )?;
+    let mut row = initial;
+    program.execute(instructions, &mut row, &initial, begin, pc)?;
+    Ok(row)
+}
+
+struct Program<'s> {
+    section: &'s [u8],
+    address: u64,
+    cie: &'s Cie,
+}
+
+impl Program<'_> {
+    /// Run the CFA instructions in `range` from `loc` until the row for `target` is complete
+    fn execute(
+        &self,
+        (mut at, end): (usize, usize),
+        row: &mut Row,
+        initial: &Row,
+        mut loc: u64,
+        target: u64,
+    ) -> error::Result<()> {
+        let section = self
+            .section
+            .get(..end)
+            .ok_or(scroll::Error::BadOffset(end))?;
+        let cie = self.cie;
+        let uleb = |at: &mut usize| Uleb128::read(section, at);
+        let factored = |at: &mut usize| -> error::Result<Rule> {
+            Ok(Rule::Offset(
+                (Uleb128::read(section, at)? as i64).wrapping_mul(cie.data_align),
+            ))
+        };
+        let factored_sf = |at: &mut usize| -> error::Result<i64> {
+            Ok(Sleb128::read(section, at)?.wrapping_mul(cie.data_align))
+        };
+        let mut states = Vec::new();
+        while at < end {
+            let op: u8 = section.gread(&mut at)?;
+            let low = u64::from(op & 0x3f);
+            let advance = match op & 0xc0 {
+                0x40 => Some(low),
+                0x80 => {
+                    let rule = factored(&mut at)?;
+                    row.set(low, rule);
+                    continue;
+                }
+                0xc0 => {
+                    row.restore(low, initial);
+                    continue;
+                }
+                _ => match op {
+                    // DW_CFA_advance_loc1, 2 and 4
+                    0x02 => Some(u64::from(section.gread::<u8>(&mut at)?)),
+                    0x03 => Some(u64::from(section.gread_with::<u16>(&mut at, LE)?)),
+                    0x04 => Some(u64::from(section.gread_with::<u32>(&mut at, LE)?)),
+                    _ => None,
+                },
+            };
+            if let Some(delta) = advance {
+                loc = loc.saturating_add(delta.saturating_mul(cie.code_align));
+                if loc > target {
+                    return Ok(());
+                }
+                continue;
+            }
+            match op {
+                // DW_CFA_nop
+                0x00 => {}
+                // DW_CFA_set_loc
+                0x01 => {
+                    loc = read_pointer(section, &mut at, cie.fde_encoding, self.address)?;
+                    if loc > target {
+                        return Ok(());
+                    }
+                }
+                // DW_CFA_offset_extended
+                0x05 => {
+                    let reg = uleb(&mut at)?;
+                    let rule = factored(&mut at)?;
+                    row.set(reg, rule);
+                }
+                // DW_CFA_restore_extended
+                0x06 => row.restore(uleb(&mut at)?, initial),
+                // DW_CFA_undefined
+                0x07 => row.set(uleb(&mut at)?, Rule::Undefined),
+                // DW_CFA_same_value
+                0x08 => row.set(uleb(&mut at)?, Rule::SameValue),
+                // DW_CFA_register
+                0x09 => {
+                    let reg = uleb(&mut at)?;
+                    let other = uleb(&mut at)?;
+                    row.set(reg, Rule::Register(other as u16));
+                }
+                // DW_CFA_remember_state
+                0x0a => {
+                    if states.len() == MAX_STATES {
+                        return Err(error::Error::Malformed(
+                            "CFA state stack is too deep".into(),
+                        ));
+                    }
+                    states.push(*row);
+                }
+                // DW_CFA_restore_state keeps the CFA rule
+                0x0b => {
+                    let cfa = row.cfa;
+                    *row = states.pop().ok_or_else(|| {
+                        error::Error::Malformed("CFA state stack is empty".into())
+                    })?;
+                    row.cfa = cfa;
+                }
+                // DW_CFA_def_cfa
+                0x0c => {
+                    let reg = uleb(&mut at)?;
+                    row.cfa = Cfa::Register(reg as u16, uleb(&mut at)? as i64);
+                }
+                // DW_CFA_def_cfa_register
+                0x0d => {
+                    let reg = uleb(&mut at)? as u16;
+                    if let Cfa::Register(_, offset) = row.cfa {
+                        row.cfa = Cfa::Register(reg, offset);
+                    }
+                }
+                // DW_CFA_def_cfa_offset
+                0x0e => {
+                    let offset = uleb(&mut at)? as i64;
+                    if let Cfa::Register(reg, _) = row.cfa {
+                        row.cfa = Cfa::Register(reg, offset);
+                    }
+                }
+                // DW_CFA_def_cfa_expression
+                0x0f => {
+                    let length = uleb(&mut at)?;
+                    at = at.saturating_add(length as usize);
+                    row.cfa = Cfa::Expression;
+                }
+                // DW_CFA_expression and DW_CFA_val_expression
+                0x10 | 0x16 => {
+                    let reg = uleb(&mut at)?;
+                    let length = uleb(&mut at)?;
+                    at = at.saturating_add(length as usize);
+                    row.set(reg, Rule::Undefined);
+                }
+                // DW_CFA_offset_extended_sf
+                0x11 => {
+                    let reg = uleb(&mut at)?;
+                    let offset = factored_sf(&mut at)?;
+                    row.set(reg, Rule::Offset(offset));
+                }
+                // DW_CFA_def_cfa_sf
+                0x12 => {
+                    let reg = uleb(&mut at)? as u16;
+                    row.cfa = Cfa::Register(reg, factored_sf(&mut at)?);
+                }
+                // DW_CFA_def_cfa_offset_sf
+                0x13 => {
+                    let offset = factored_sf(&mut at)?;
+                    if let Cfa::Register(reg, _) = row.cfa {
+                        row.cfa = Cfa::Register(reg, offset);
+                    }
+                }
+                // DW_CFA_val_offset
+                0x14 => {
+                    let reg = uleb(&mut at)?;
+                    let offset = (uleb(&mut at)? as i64).wrapping_mul(cie.data_align);
+                    row.set(reg, Rule::ValOffset(offset));
+                }
+                // DW_CFA_val_offset_sf
+                0x15 => {
+                    let reg = uleb(&mut at)?;
+                    let offset = factored_sf(&mut at)?;
+                    row.set(reg, Rule::ValOffset(offset));
+                }
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    uleb(&mut at)?;
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = uleb(&mut at)?;
+                    let offset = (uleb(&mut at)? as i64).wrapping_mul(cie.data_align);
+                    row.set(reg, Rule::Offset(offset.wrapping_neg()));
+                }
+                op => {
+                    return Err(error::Error::Malformed(format!(
+                        "Unknown CFA instruction {:#x}",
+                        op
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Follow the frame pointer: the caller's frame pointer is saved at it, the return address
+/// above that
+fn frame_pointer<M: StackMemory + ?Sized>(memory: &M, state: &FrameState) -> Option<FrameState> {
+    let fp = state.regs[RBP].filter(|&fp| fp != 0)?;
+    let mut regs = [None; REGISTERS];
+    regs[RBP] = Some(memory.read_u64(fp)?);
+    regs[RSP] = Some(fp.checked_add(16)?);
+    Some(FrameState {
+        pc: memory.read_u64(fp.checked_add(8)?)?,
+        regs,
+    })
+}
+
+/// Undo the prolog of the function at `pc` with its unwind codes, then pop the return address.
+/// Functions without a `RUNTIME_FUNCTION` are leaves, whose return address is on top of the stack
+fn pdata_step<M: StackMemory + ?Sized>(
+    pe: &PE,
+    base: u64,
+    pc: u64,
+    state: &FrameState,
+    memory: &M,
+) -> Option<FrameState> {
+    let exception = pe.exception_data.as_ref()?;
+    let rva = u32::try_from(pc.checked_sub(base)?).ok()?;
+    let mut regs = state.regs;
+    let mut rsp = regs[RSP]?;
+    let mut function = exception.find_function(rva).ok()?;
+    // codes of the function's own unwind info only apply once its prolog has run them
+    let mut in_prolog = function.filter(|function| function.unwind_info_address.is_multiple_of(2));
+    for _ in 0..MAX_CHAIN {
+        let current = match function {
+            Some(current) => current,
+            None => break,
+        };
+        let info = exception.get_unwind_info(current, &pe.sections).ok()?;
+        let offset = in_prolog.map(|function| rva - function.begin_address);
+        let frame = WINDOWS_TO_DWARF[info.frame_register.0 as usize & 0xf];
+        let frame_base = |regs: &[Option<u64>; REGISTERS]| {
+            regs[frame]?.checked_sub(u64::from(info.frame_register_offset))
+        };
+        for code in info.unwind_codes() {
+            let code = code.ok()?;
+            if offset.is_some_and(|offset| u32::from(code.code_offset) > offset) {
+                continue;
+            }
+            match code.operation {
+                UnwindOperation::PushNonVolatile(reg) => {
+                    regs[WINDOWS_TO_DWARF[reg.0 as usize & 0xf]] = Some(memory.read_u64(rsp)?);
+                    rsp = rsp.checked_add(8)?;
+                }
+                UnwindOperation::Alloc(size) => rsp = rsp.checked_add(u64::from(size))?,
+                UnwindOperation::SetFPRegister => rsp = frame_base(&regs)?,
+                UnwindOperation::SaveNonVolatile(reg, offset) => {
+                    let at = match offset {
+                        StackFrameOffset::RSP(offset) => rsp.checked_add(u64::from(offset))?,
+                        StackFrameOffset::FP(offset) => {
+                            frame_base(&regs)?.checked_add(u64::from(offset))?
+                        }
+                    };
+                    regs[WINDOWS_TO_DWARF[reg.0 as usize & 0xf]] = Some(memory.read_u64(at)?);
+                }
+                UnwindOperation::PushMachineFrame(error_code) => {
+                    // the interrupted pc and stack pointer are in the machine frame
+                    if error_code {
+                        rsp = rsp.checked_add(8)?;
+                    }
+                    regs[RSP] = Some(memory.read_u64(rsp.checked_add(24)?)?);
+                    return Some(FrameState {
+                        pc: memory.read_u64(rsp)?,
+                        regs,
+                    });
+                }
+                _ => {}
+            }
+        }
+        function = info.chained_info;
+        in_prolog = None;
+    }
+    let pc = memory.read_u64(rsp)?;
+    regs[RSP] = Some(rsp.checked_add(8)?);
+    Some(FrameState { pc, regs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use scroll::Pwrite;
+
+    const STACK: u64 = 0x7ffd_0000;
+
+    /// A stack of little endian words
+    struct Stack(BTreeMap<u64, [u8; 8]>);
+
+    impl Stack {
+        fn new(words: &[(u64, u64)]) -> Self {
+            Stack(
+                words
+                    .iter()
+                    .map(|&(va, value)| (va, value.to_le_bytes()))
+                    .collect(),
+            )
+        }
+    }
+
+    impl StackMemory for Stack {
+        fn read_memory(&self, va: u64, len: usize) -> Option<&[u8]> {
+            self.0.get(&va).map(|word| &word[..len.min(8)])
+        }
+    }
+
+    fn state(pc: u64, rsp: u64, rbp: u64) -> FrameState {
+        let mut regs = [None; REGISTERS];
+        regs[RSP] = Some(rsp);
+        regs[RBP] = Some(rbp);
+        FrameState { pc, regs }
+    }
+
+    /// An `.eh_frame` at 0x2000 with the usual CIE and one FDE for the function at 0x1000..0x1040:
+    /// `push rbp` at 0x1000, `mov rbp, rsp` at 0x1001, then a body which doesn't save rbp
+    fn eh_frame() -> Vec<u8> {
+        let mut cie = vec![0u8; 4];
+        cie.extend_from_slice(&[1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x1b]);
+        // DW_CFA_def_cfa rsp+8, DW_CFA_offset ra at cfa-8
+        cie.extend_from_slice(&[0x0c, 7, 8, 0x80 | 16, 1, 0, 0]);
+        let mut section = Vec::new();
+        section.extend_from_slice(&(cie.len() as u32).to_le_bytes());
+        section.extend_from_slice(&cie);
+
+        let fde_at = section.len();
+        let mut fde = Vec::new();
+        fde.extend_from_slice(&((fde_at + 4) as u32).to_le_bytes());
+        // pc begin, pcrel sdata4
+        let begin_at = 0x2000 + fde_at as i64 + 8;
+        fde.extend_from_slice(&((0x1000 - begin_at) as i32).to_le_bytes());
+        fde.extend_from_slice(&0x40u32.to_le_bytes());
+        fde.push(0);
+        // advance 1: cfa rsp+16, rbp at cfa-16; advance 3: cfa is rbp+16
+        fde.extend_from_slice(&[0x41, 0x0e, 16, 0x80 | 6, 2, 0x43, 0x0d, 6]);
+        fde.resize(fde.len().div_ceil(4) * 4, 0);
+        section.extend_from_slice(&(fde.len() as u32).to_le_bytes());
+        section.extend_from_slice(&fde);
+        section.extend_from_slice(&[0; 4]);
+        section
+    }
+
+    #[test]
+    fn cfi() {
+        let section = eh_frame();
+        let mut tables = UnwindTables::new();
+        // loaded 0x400000 above its link address
+        tables
+            .add_eh_frame(&[(0x401000, 0x403000)], 0x400000, &section, 0x2000)
+            .unwrap();
+        let stack = Stack::new(&[
+            (STACK, 0x401234),
+            (STACK + 0x10, STACK + 0x80),
+            (STACK + 0x18, 0x405678),
+        ]);
+
+        // at the entry the return address is on top of the stack, rbp is the caller's
+        let next = tables
+            .step(&stack, &state(0x401000, STACK, 0x1111), true)
+            .unwrap();
+        assert_eq!(next.pc, 0x401234);
+        assert_eq!(next.regs[RSP], Some(STACK + 8));
+        assert_eq!(next.regs[RBP], Some(0x1111));
+
+        // in the body the CFA is rbp + 16, the caller's rbp is saved below the return address
+        let next = tables
+            .step(&stack, &state(0x401020, STACK, STACK + 0x10), true)
+            .unwrap();
+        assert_eq!(next.pc, 0x405678);
+        assert_eq!(next.regs[RSP], Some(STACK + 0x20));
+        assert_eq!(next.regs[RBP], Some(STACK + 0x80));
+
+        // the frame pointer chain is followed outside the FDE, and ends at a null frame pointer
+        let next = tables
+            .step(&stack, &state(0x402000, STACK, STACK + 0x10), true)
+            .unwrap();
+        assert_eq!(next.pc, 0x405678);
+        assert_eq!(tables.step(&stack, &state(0x402000, STACK, 0), true), None);
+    }
+
+    #[test]
+    fn cfi_bad_input() {
+        let mut section = eh_frame();
+        // an FDE length past the end of the section
+        section[0x18..0x1c].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(index_fdes(&section, 0x2000).is_err());
+        // an FDE whose CIE pointer points before the section is skipped
+        let mut section = eh_frame();
+        section[0x1c..0x20].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(index_fdes(&section, 0x2000).unwrap(), []);
+        // a frame pointer at the top of the address space ends the unwind
+        let stack = Stack::new(&[]);
+        assert_eq!(frame_pointer(&stack, &state(0, STACK, u64::MAX - 4)), None);
+    }
+
+    /// A PE32+ image based at 0x140000000 with a `.text` and a `.pdata` section, both mapped at
+    /// the file offset equal to their RVA. The function at 0x1000 is `push rbx; sub rsp, 0x20`
+    /// and the one at 0x1100 is `push rbp; mov rbp, rsp` (frame pointer offset 0)
+    fn pe() -> Vec<u8> {
+        let mut out = vec![0u8; 0x3000];
+        out[0..2].copy_from_slice(b"MZ");
+        out.pwrite_with(0x40u32, 0x3c, LE).unwrap();
+        out[0x40..0x44].copy_from_slice(b"PE\0\0");
+        out.pwrite_with(0x8664u16, 0x44, LE).unwrap();
+        out.pwrite_with(2u16, 0x46, LE).unwrap();
+        out.pwrite_with(240u16, 0x54, LE).unwrap();
+        out.pwrite_with(0x22u16, 0x56, LE).unwrap();
+        let optional = 0x58;
+        out.pwrite_with(0x20bu16, optional, LE).unwrap();
+        out.pwrite_with(0x1000u32, optional + 16, LE).unwrap();
+        out.pwrite_with(0x1_4000_0000u64, optional + 24, LE)
+            .unwrap();
+        out.pwrite_with(0x1000u32, optional + 32, LE).unwrap();
+        out.pwrite_with(0x200u32, optional + 36, LE).unwrap();
+        out.pwrite_with(6u16, optional + 48, LE).unwrap();
+        out.pwrite_with(0x3000u32, optional + 56, LE).unwrap();
+        out.pwrite_with(0x400u32, optional + 60, LE).unwrap();
+        out.pwrite_with(16u32, optional + 108, LE).unwrap();
+        // the exception directory
+        out.pwrite_with(0x2000u32, optional + 112 + 3 * 8, LE)
+            .unwrap();
+        out.pwrite_with(24u32, optional + 112 + 3 * 8 + 4, LE)
+            .unwrap();
+        let sections = optional + 240;
+        for (i, name) in [&b".text\0\0\0"[..], b".pdata\0\0"].into_iter().enumerate() {
+            let header = sections + i * 40;
+            let rva = 0x1000 * (i as u32 + 1);
+            out[header..header + 8].copy_from_slice(name);
+            out.pwrite_with(0x1000u32, header + 8, LE).unwrap();
+            out.pwrite_with(rva, header + 12, LE).unwrap();
+            out.pwrite_with(0x1000u32, header + 16, LE).unwrap();
+            out.pwrite_with(rva, header + 20, LE).unwrap();
+            out.pwrite_with(0x4000_0040u32, header + 36, LE).unwrap();
+        }
+        for (i, function) in [[0x1000u32, 0x1040, 0x2100], [0x1100, 0x1140, 0x2110]]
+            .into_iter()
+            .enumerate()
+        {
+            for (j, value) in function.into_iter().enumerate() {
+                out.pwrite_with(value, 0x2000 + i * 12 + j * 4, LE).unwrap();
+            }
+        }
+        // version 1, 5 byte prolog, codes: alloc 0x20 at 5 (UWOP_ALLOC_SMALL, 3), push rbx at 1
+        out[0x2100..0x2108].copy_from_slice(&[1, 5, 2, 0, 5, 0x32, 1, 0x30]);
+        // 4 byte prolog, rbp frame at offset 0: set fp at 4, push rbp at 1
+        out[0x2110..0x2118].copy_from_slice(&[1, 4, 2, 0x05, 4, 0x03, 1, 0x50]);
+        out
+    }
+
+    #[test]
+    fn pdata() {
+        let bytes = pe();
+        let base = 0x1_4000_0000;
+        let mut tables = UnwindTables::new();
+        tables
+            .add_pe(&[(base, base + 0x3000)], base, &bytes)
+            .unwrap();
+        let stack = Stack::new(&[
+            (STACK, 0xaaaa),
+            (STACK + 0x20, 0xbbbb),
+            (STACK + 0x28, 0x1111),
+            (STACK + 0x30, 0x2222),
+        ]);
+
+        // after the prolog, rsp is 0x20 below the saved rbx
+        let next = tables
+            .step(&stack, &state(base + 0x1010, STACK, 0), true)
+            .unwrap();
+        assert_eq!(next.pc, 0x1111);
+        assert_eq!(next.regs[RSP], Some(STACK + 0x30));
+        assert_eq!(next.regs[3], Some(0xbbbb));
+
+        // inside the prolog only the codes it has run are undone
+        let next = tables
+            .step(&stack, &state(base + 0x1001, STACK + 0x20, 0), true)
+            .unwrap();
+        assert_eq!((next.pc, next.regs[3]), (0x1111, Some(0xbbbb)));
+
+        // with a frame pointer, rsp is recovered from rbp wherever the body moved it
+        let next = tables
+            .step(&stack, &state(base + 0x1120, STACK, STACK + 0x20), true)
+            .unwrap();
+        assert_eq!(next.pc, 0x1111);
+        assert_eq!(next.regs[RBP], Some(0xbbbb));
+        assert_eq!(next.regs[RSP], Some(STACK + 0x30));
+
+        // leaf functions have no RUNTIME_FUNCTION, their return address is on top of the stack
+        let next = tables.step(&stack, &state(base + 0x1200, STACK, 0), true);
+        assert_eq!(next.map(|next| next.pc), Some(0xaaaa));
+    }
+}
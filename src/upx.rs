@@ -0,0 +1,718 @@
+//! Static unpacking of UPX.
+//!
+//! UPX is by far the most common packer, and its format is documented by its source: there's
+//! no need to emulate the stub (see [`crate::unpack`]) to get the code back. A packed binary
+//! ends with a [`PackHeader`], `UPX!` and the method and sizes of the packing.
+//!
+//! * ELF and Mach-O binaries are packed as a chain of blocks after the stub, each with the
+//!   method and filter it was packed with. [`unpack_blocks`] decompresses them back into the
+//!   original file, for the loaders to take from there.
+//! * PE binaries are packed as a whole: the image from the first section on is compressed into
+//!   the start of the second, `UPX1`, and decompressed into the first, `UPX0`, which is empty on
+//!   disk. [`unpack_pe`] decompresses it, and finds the original entry point from the jump the
+//!   stub ends with. The imports stay in UPX's own format; [`crate::unpack`] rebuilds them.
+//!
+//! The NRV2B, NRV2D and NRV2E methods are decoded here, in each of their bit buffer widths;
+//! LZMA needs the `xz` feature. Of the filters, which make the calls of x86 code absolute
+//! so they compress better, [`unfilter`] undoes the `call`/`jmp` ones.
+
+use crate::{pe::PE, workspace::VivWorkspace};
+
+pub const MAGIC: &[u8; 4] = b"UPX!";
+
+/// How large an unpacked file may get, so a forged header can't take the process down
+pub const DEFAULT_LIMIT: usize = 256 << 20;
+
+/// The width of the bit buffer of an NRV method
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bits {
+    Byte,
+    Le16,
+    Le32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Nrv2b(Bits),
+    Nrv2d(Bits),
+    Nrv2e(Bits),
+    Lzma,
+}
+
+impl Method {
+    /// The method of a UPX method number
+    pub fn from_id(id: u8) -> Option<Method> {
+        Some(match id {
+            2 => Method::Nrv2b(Bits::Le32),
+            3 => Method::Nrv2b(Bits::Byte),
+            4 => Method::Nrv2b(Bits::Le16),
+            5 => Method::Nrv2d(Bits::Le32),
+            6 => Method::Nrv2d(Bits::Byte),
+            7 => Method::Nrv2d(Bits::Le16),
+            8 => Method::Nrv2e(Bits::Le32),
+            9 => Method::Nrv2e(Bits::Byte),
+            10 => Method::Nrv2e(Bits::Le16),
+            14 => Method::Lzma,
+            _ => return None,
+        })
+    }
+}
+
+/// The header UPX leaves at the end of a packed binary
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PackHeader {
+    /// The file offset of the `UPX!` it starts with
+    pub offset: usize,
+    pub version: u8,
+    /// The format of the binary, `UPX_F_*`; 128 and up are big endian
+    pub format: u8,
+    pub method: u8,
+    pub level: u8,
+    pub u_adler: u32,
+    pub c_adler: u32,
+    pub u_len: u32,
+    pub c_len: u32,
+    pub u_file_size: u32,
+    pub filter: u8,
+    pub filter_cto: u8,
+}
+
+/// The size of a pack header of version 10 and up
+const PACK_HEADER_SIZE: usize = 32;
+
+fn get32(bytes: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let word: [u8; 4] = bytes.get(at..at.checked_add(4)?)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(word)
+    } else {
+        u32::from_le_bytes(word)
+    })
+}
+
+impl PackHeader {
+    /// Parse the pack header at `offset`
+    pub fn parse(bytes: &[u8], offset: usize) -> Option<PackHeader> {
+        let header = bytes.get(offset..offset.checked_add(PACK_HEADER_SIZE)?)?;
+        if &header[..4] != MAGIC || header[4] < 10 {
+            return None;
+        }
+        let big_endian = header[5] >= 128;
+        let word = |at| get32(header, at, big_endian);
+        Some(PackHeader {
+            offset,
+            version: header[4],
+            format: header[5],
+            method: header[6],
+            level: header[7],
+            u_adler: word(8)?,
+            c_adler: word(12)?,
+            u_len: word(16)?,
+            c_len: word(20)?,
+            u_file_size: word(24)?,
+            filter: header[28],
+            filter_cto: header[29],
+        })
+    }
+
+    /// The pack header of a packed binary: the last one in its trailing kilobyte, or for PE,
+    /// which keeps it in the headers, the first one in its leading four
+    pub fn find(bytes: &[u8]) -> Option<PackHeader> {
+        let tail = bytes.len().saturating_sub(0x400);
+        let found = (tail..bytes.len())
+            .rev()
+            .find_map(|at| PackHeader::parse(bytes, at));
+        found.or_else(|| (0..bytes.len().min(0x1000)).find_map(|at| PackHeader::parse(bytes, at)))
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.format >= 128
+    }
+
+    /// Whether the binary is a PE, packed as a whole
+    pub fn is_pe(&self) -> bool {
+        // UPX_F_WIN32_PE, UPX_F_WINCE_ARM_PE, UPX_F_WIN64_PEP
+        matches!(self.format, 9 | 21 | 36)
+    }
+}
+
+/// Reads the bits of an NRV stream, most significant first, and the bytes between them
+struct BitReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+    bits: Bits,
+    word: u32,
+    left: u32,
+}
+
+impl BitReader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .src
+            .get(self.pos)
+            .ok_or_else(|| "compressed data ends early".to_string())?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bit(&mut self) -> Result<u32, String> {
+        if self.left == 0 {
+            (self.word, self.left) = match self.bits {
+                Bits::Byte => (self.byte()? as u32, 8),
+                Bits::Le16 => (u16::from_le_bytes([self.byte()?, self.byte()?]) as u32, 16),
+                Bits::Le32 => {
+                    let word = [self.byte()?, self.byte()?, self.byte()?, self.byte()?];
+                    (u32::from_le_bytes(word), 32)
+                }
+            };
+        }
+        self.left -= 1;
+        Ok((self.word >> self.left) & 1)
+    }
+
+    /// A number in the gamma code of NRV2B, starting from `value`
+    fn gamma(&mut self, mut value: u32) -> Result<u32, String> {
+        loop {
+            value = value
+                .checked_mul(2)
+                .ok_or_else(|| "bad gamma code".to_string())?
+                + self.bit()?;
+            if self.bit()? == 1 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+/// Decode an NRV2B, NRV2D or NRV2E stream of `u_len` bytes
+fn nrv(method: Method, src: &[u8], u_len: usize) -> Result<Vec<u8>, String> {
+    let bits = match method {
+        Method::Nrv2b(bits) | Method::Nrv2d(bits) | Method::Nrv2e(bits) => bits,
+        Method::Lzma => unreachable!("not an NRV method"),
+    };
+    let mut r = BitReader {
+        src,
+        pos: 0,
+        bits,
+        word: 0,
+        left: 0,
+    };
+    let mut out: Vec<u8> = Vec::with_capacity(u_len);
+    let mut last_off = 1u32;
+    loop {
+        while r.bit()? == 1 {
+            out.push(r.byte()?);
+        }
+        let mut off = match method {
+            Method::Nrv2b(_) => r.gamma(1)?,
+            _ => {
+                let mut off = 1u32;
+                loop {
+                    off = off.checked_mul(2).ok_or_else(|| "bad offset".to_string())? + r.bit()?;
+                    if r.bit()? == 1 {
+                        break off;
+                    }
+                    off = (off - 1)
+                        .checked_mul(2)
+                        .ok_or_else(|| "bad offset".to_string())?
+                        + r.bit()?;
+                }
+            }
+        };
+        let mut len = 0;
+        if off == 2 {
+            off = last_off;
+            if !matches!(method, Method::Nrv2b(_)) {
+                len = r.bit()?;
+            }
+        } else {
+            off = (off - 3)
+                .checked_mul(256)
+                .ok_or_else(|| "bad offset".to_string())?
+                | r.byte()? as u32;
+            if off == u32::MAX {
+                break;
+            }
+            if !matches!(method, Method::Nrv2b(_)) {
+                len = (off ^ u32::MAX) & 1;
+                off >>= 1;
+            }
+            off += 1;
+            last_off = off;
+        }
+        let long = |r: &mut BitReader, add: u32| -> Result<u32, String> { Ok(r.gamma(1)? + add) };
+        len = match method {
+            Method::Nrv2b(_) => match r.bit()? * 2 + r.bit()? {
+                0 => long(&mut r, 2)?,
+                len => len,
+            },
+            Method::Nrv2d(_) => match len * 2 + r.bit()? {
+                0 => long(&mut r, 2)?,
+                len => len,
+            },
+            _ if len != 0 => 1 + r.bit()?,
+            _ if r.bit()? == 1 => 3 + r.bit()?,
+            _ => long(&mut r, 3)?,
+        };
+        let far = match method {
+            Method::Nrv2b(_) => 0xd00,
+            _ => 0x500,
+        };
+        len += (off > far) as u32;
+        let from = out
+            .len()
+            .checked_sub(off as usize)
+            .ok_or_else(|| format!("match {:#x} back before the start", off))?;
+        if out.len() + len as usize + 1 > u_len {
+            return Err("decompressed data overruns".to_string());
+        }
+        for i in 0..=len as usize {
+            out.push(out[from + i]);
+        }
+    }
+    if out.len() != u_len {
+        return Err(format!(
+            "decompressed {:#x} bytes, not {:#x}",
+            out.len(),
+            u_len
+        ));
+    }
+    Ok(out)
+}
+
+/// UPX's LZMA: two bytes of properties, then a raw stream
+#[cfg(feature = "xz")]
+fn lzma(src: &[u8], u_len: usize) -> Result<Vec<u8>, String> {
+    use lzma_rs::decompress::{Options, UnpackedSize};
+
+    let [first, second, stream @ ..] = src else {
+        return Err("no LZMA properties".to_string());
+    };
+    let (pb, lp, lc) = (first & 7, (first >> 3) & 7, second & 15);
+    if pb > 4 || lp > 4 || lc > 8 {
+        return Err("bad LZMA properties".to_string());
+    }
+    let mut input = vec![(pb * 5 + lp) * 9 + lc];
+    input.extend_from_slice(&(u_len.max(0x1000) as u32).to_le_bytes());
+    input.extend_from_slice(stream);
+    let options = Options {
+        unpacked_size: UnpackedSize::UseProvided(Some(u_len as u64)),
+        ..Default::default()
+    };
+    let mut out = Vec::with_capacity(u_len);
+    lzma_rs::lzma_decompress_with_options(&mut input.as_slice(), &mut out, &options)
+        .map_err(|err| err.to_string())?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "xz"))]
+fn lzma(_: &[u8], _: usize) -> Result<Vec<u8>, String> {
+    Err("LZMA needs the xz feature".to_string())
+}
+
+/// Decompress `src`, packed with the UPX method numbered `method`, into `u_len` bytes
+pub fn decompress(method: u8, src: &[u8], u_len: usize) -> Result<Vec<u8>, String> {
+    if u_len > DEFAULT_LIMIT {
+        return Err(format!("{:#x} bytes is too large", u_len));
+    }
+    match Method::from_id(method) {
+        Some(Method::Lzma) => lzma(src, u_len),
+        Some(method) => nrv(method, src, u_len),
+        None => Err(format!("unknown method {}", method)),
+    }
+}
+
+/// Undo the filter numbered `ftid` on `buf`, code which was at `addvalue` from the start of
+/// what was filtered. The filters supported make the targets of `call` (`e8`) and `jmp`
+/// (`e9`), and with 0x49 of `jcc` (`0f 8x`), absolute, and where they have a `cto` tag it
+/// takes the place of the top byte of the target, which is kept big endian.
+pub fn unfilter(ftid: u8, cto: u8, buf: &mut [u8], addvalue: u32) -> Result<(), String> {
+    if ftid == 0 {
+        return Ok(());
+    }
+    let (calls, jumps, tagged, bswap) = match ftid {
+        0x11..=0x16 => (
+            matches!(ftid & 0xf, 1 | 3 | 4 | 6),
+            matches!(ftid & 0xf, 2 | 3 | 5 | 6),
+            false,
+            ftid & 0xf >= 4,
+        ),
+        0x24..=0x26 => (ftid != 0x25, ftid != 0x24, true, true),
+        0x46 | 0x49 => (true, true, true, true),
+        _ => return Err(format!("unsupported filter {:#x}", ftid)),
+    };
+    let jcc = ftid == 0x49;
+    let mut lastcall = 0;
+    let mut ic = 0;
+    while ic + 5 <= buf.len() {
+        let op = buf[ic];
+        let branch = (calls && op == 0xe8)
+            || (jumps && op == 0xe9)
+            || (jcc && ic > 0 && lastcall != ic && buf[ic - 1] == 0x0f && op & 0xf0 == 0x80);
+        if !branch || (tagged && buf[ic + 1] != cto) {
+            ic += 1;
+            continue;
+        }
+        let field = get32(buf, ic + 1, bswap).unwrap_or_default();
+        let target = if tagged {
+            field.wrapping_sub((cto as u32) << 24)
+        } else {
+            field
+        };
+        let rel = target.wrapping_sub(ic as u32 + 1).wrapping_sub(addvalue);
+        buf[ic + 1..ic + 5].copy_from_slice(&rel.to_le_bytes());
+        ic += 5;
+        lastcall = ic;
+    }
+    Ok(())
+}
+
+/// Unpack an ELF or Mach-O binary packed by UPX into the original file. After the stub come
+/// `l_info` and `p_info`, the latter at the offset following the pack header, then blocks,
+/// each a `b_info` with its compressed data, up to one of no size.
+pub fn unpack_blocks(bytes: &[u8], header: &PackHeader) -> Result<Vec<u8>, String> {
+    let big_endian = header.is_big_endian();
+    let word = |at| get32(bytes, at, big_endian).ok_or_else(|| format!("truncated at {:#x}", at));
+    let p_info = word(header.offset + PACK_HEADER_SIZE)? as usize;
+    let file_size = word(p_info + 4)? as usize;
+    if file_size > DEFAULT_LIMIT {
+        return Err(format!("{:#x} bytes is too large", file_size));
+    }
+    let mut out = Vec::with_capacity(file_size);
+    let mut at = p_info + 12;
+    loop {
+        let (sz_unc, sz_cpr) = (word(at)? as usize, word(at + 4)? as usize);
+        if sz_unc == 0 {
+            break;
+        }
+        let info = bytes
+            .get(at + 8..at + 12)
+            .ok_or_else(|| format!("truncated at {:#x}", at))?;
+        let (method, ftid, cto) = (info[0], info[1], info[2]);
+        let data = bytes
+            .get(at + 12..at + 12 + sz_cpr)
+            .ok_or_else(|| format!("block at {:#x} is truncated", at))?;
+        let mut block = if sz_cpr < sz_unc {
+            decompress(method, data, sz_unc)?
+        } else {
+            data.to_vec()
+        };
+        unfilter(ftid, cto, &mut block, 0)?;
+        out.extend_from_slice(&block);
+        if out.len() > file_size {
+            return Err("blocks overrun the file".to_string());
+        }
+        // blocks start on four byte boundaries
+        at = (at + 12 + sz_cpr).next_multiple_of(4);
+    }
+    Ok(out)
+}
+
+/// The image of a PE packed by UPX, decompressed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeImage {
+    /// Where the image decompresses to: the start of `UPX0`
+    pub va: u64,
+    pub bytes: Vec<u8>,
+    /// The original entry point, where the stub jumps to at the end
+    pub oep: Option<u64>,
+}
+
+impl PeImage {
+    /// Put the image in a workspace the packed binary was loaded into, the OEP as its entry
+    /// point. False if the image isn't all in one map.
+    pub fn apply(&self, workspace: &mut VivWorkspace) -> bool {
+        if !workspace.patch_bytes(self.va as i32, &self.bytes) {
+            return false;
+        }
+        if let Some(oep) = self.oep {
+            workspace.add_entry_point(oep as i32);
+        }
+        true
+    }
+}
+
+/// Unpack a PE packed by UPX: decompress the start of the second section into the first
+pub fn unpack_pe(bytes: &[u8], header: &PackHeader) -> Result<PeImage, String> {
+    let pe = PE::parse(bytes).map_err(|err| err.to_string())?;
+    let [upx0, upx1, ..] = pe.sections.as_slice() else {
+        return Err("fewer than two sections".to_string());
+    };
+    let start = upx1.pointer_to_raw_data as usize;
+    let packed = bytes
+        .get(start..start + header.c_len as usize)
+        .ok_or_else(|| "compressed data past the end of the file".to_string())?;
+    let mut image = decompress(header.method, packed, header.u_len as usize)?;
+    unfilter(header.filter, header.filter_cto, &mut image, 0)?;
+    let base = pe.image_base as u64;
+    let va = base + upx0.virtual_address as u64;
+    let size = upx0.virtual_size.max(image.len() as u32) as u64;
+    // the stub ends with a jmp back into UPX0
+    let stub = bytes
+        .get(start + packed.len()..start + upx1.size_of_raw_data as usize)
+        .unwrap_or_default();
+    let stub_va = base + upx1.virtual_address as u64 + packed.len() as u64;
+    let oep = (0..stub.len().saturating_sub(4))
+        .rev()
+        .filter(|at| stub[*at] == 0xe9)
+        .map(|at| {
+            let rel = i32::from_le_bytes(stub[at + 1..at + 5].try_into().unwrap());
+            (stub_va + at as u64 + 5).wrapping_add(rel as i64 as u64)
+        })
+        .find(|target| (va..va + size).contains(target));
+    Ok(PeImage {
+        va,
+        bytes: image,
+        oep,
+    })
+}
+
+/// What [`unpack`] gives back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Unpacked {
+    /// The original file, from an ELF or Mach-O binary
+    File(Vec<u8>),
+    /// The image, from a PE
+    Image(PeImage),
+}
+
+/// Unpack a binary packed by UPX. None if it isn't.
+pub fn unpack(bytes: &[u8]) -> Option<Result<Unpacked, String>> {
+    let header = PackHeader::find(bytes)?;
+    Some(if header.is_pe() {
+        unpack_pe(bytes, &header).map(Unpacked::Image)
+    } else {
+        unpack_blocks(bytes, &header).map(Unpacked::File)
+    })
+}
+
+/// Whether the workspace holds a PE packed by UPX, by its section names
+pub fn is_packed(workspace: &VivWorkspace) -> bool {
+    let segments = workspace.get_segments();
+    ["UPX0", "UPX1"]
+        .iter()
+        .all(|name| segments.iter().any(|(_, _, sname, _)| sname == name))
+}
+
+/// Unpack the PE a workspace was loaded from in place, as [`PeImage::apply`] does
+pub fn unpack_workspace(workspace: &mut VivWorkspace, bytes: &[u8]) -> Result<PeImage, String> {
+    let header = PackHeader::find(bytes).ok_or_else(|| "not packed by UPX".to_string())?;
+    let image = unpack_pe(bytes, &header)?;
+    if !image.apply(workspace) {
+        return Err(format!("{:#x}: image not in a memory map", image.va));
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an NRV bit stream, the bits in words of the width reserved where they start
+    struct BitWriter {
+        out: Vec<u8>,
+        bits: Bits,
+        at: usize,
+        left: u32,
+    }
+
+    impl BitWriter {
+        fn bit(&mut self, bit: u32) {
+            let width = match self.bits {
+                Bits::Byte => 8,
+                Bits::Le16 => 16,
+                Bits::Le32 => 32,
+            };
+            if self.left == 0 {
+                self.at = self.out.len();
+                self.out.resize(self.at + width / 8, 0);
+                self.left = width as u32;
+            }
+            self.left -= 1;
+            let byte = match self.bits {
+                Bits::Byte => 0,
+                _ => self.left as usize / 8,
+            };
+            self.out[self.at + byte] |= (bit << (self.left % 8)) as u8;
+        }
+
+        /// The gamma code of `value`, 2 or more, which NRV2B uses for offsets and every method
+        /// for long lengths
+        fn gamma(&mut self, value: u32) {
+            let n = 31 - value.leading_zeros();
+            for i in (0..n).rev() {
+                self.bit((value >> i) & 1);
+                self.bit((i == 0) as u32);
+            }
+        }
+
+        /// The code NRV2D and NRV2E use for offsets, of `value`, 2 or more: two bits more of
+        /// the value between each continue bit
+        fn gamma2(&mut self, value: u32) {
+            let mut steps = Vec::new();
+            let mut at = value >> 1;
+            while at > 1 {
+                let prev = (at + 2) >> 2;
+                steps.push(at - (4 * prev - 2));
+                at = prev;
+            }
+            for step in steps.into_iter().rev() {
+                self.bit(step >> 1);
+                self.bit(0);
+                self.bit(step & 1);
+            }
+            self.bit(value & 1);
+            self.bit(1);
+        }
+    }
+
+    /// "abc" as literals, a match of 60 bytes 3 back, one of 3 bytes at the same offset, the
+    /// end, as `method` packs them
+    fn nrv(method: Method) -> Vec<u8> {
+        let bits = match method {
+            Method::Nrv2b(bits) | Method::Nrv2d(bits) | Method::Nrv2e(bits) => bits,
+            Method::Lzma => unreachable!(),
+        };
+        let mut w = BitWriter {
+            out: Vec::new(),
+            bits,
+            at: 0,
+            left: 0,
+        };
+        for byte in b"abc" {
+            w.bit(1);
+            w.out.push(*byte);
+        }
+        w.bit(0);
+        match method {
+            Method::Nrv2b(_) => {
+                w.gamma(3);
+                w.out.push(2);
+                w.bit(0);
+                w.bit(0);
+                w.gamma(57);
+            }
+            Method::Nrv2d(_) => {
+                // the low bit of the offset byte clear for a long length
+                w.gamma2(3);
+                w.out.push(5);
+                w.bit(0);
+                w.gamma(57);
+            }
+            _ => {
+                w.gamma2(3);
+                w.out.push(5);
+                w.bit(0);
+                w.gamma(56);
+            }
+        }
+        w.bit(0);
+        match method {
+            Method::Nrv2b(_) => {
+                w.gamma(2);
+                w.bit(1);
+                w.bit(0);
+            }
+            Method::Nrv2d(_) => {
+                w.gamma2(2);
+                w.bit(1);
+                w.bit(0);
+            }
+            _ => {
+                w.gamma2(2);
+                w.bit(1);
+                w.bit(1);
+            }
+        }
+        w.bit(0);
+        match method {
+            Method::Nrv2b(_) => w.gamma(0x100_0002),
+            _ => w.gamma2(0x100_0002),
+        }
+        w.out.push(0xff);
+        w.out
+    }
+
+    #[test]
+    fn unpacks() {
+        for id in 2..=10 {
+            let method = Method::from_id(id).unwrap();
+            assert_eq!(decompress(id, &nrv(method), 66), Ok(b"abc".repeat(22)));
+            assert!(decompress(id, &nrv(method), 65).is_err());
+        }
+        #[cfg(feature = "xz")]
+        {
+            let mut packed = Vec::new();
+            lzma_rs::lzma_compress(&mut &b"abc".repeat(21)[..], &mut packed).unwrap();
+            // lc 3, lp 0, pb 2 in UPX's two bytes, in place of the 13 of the .lzma header
+            let mut upx = vec![2, 3];
+            upx.extend_from_slice(&packed[13..]);
+            assert_eq!(decompress(14, &upx, 63), Ok(b"abc".repeat(21)));
+        }
+
+        // call 0x1005, tagged 0x11 and made absolute
+        let mut code = [0xe8, 0x11, 0x00, 0x10, 0x05, 0x90];
+        unfilter(0x26, 0x11, &mut code, 0).unwrap();
+        assert_eq!(code, [0xe8, 0x04, 0x10, 0x00, 0x00, 0x90]);
+
+        // a stub, l_info, p_info, a compressed and a stored block, the end, the header
+        let mut file = vec![0xcc; 0x10];
+        file.extend_from_slice(&[0, 0, 0, 0]);
+        file.extend_from_slice(MAGIC);
+        file.extend_from_slice(&[0x0c, 0, 14, 12]);
+        let p_info = file.len() as u32;
+        for word in [0, 70, 0x1000] {
+            file.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        let packed = nrv(Method::Nrv2b(Bits::Le32));
+        file.extend_from_slice(&66u32.to_le_bytes());
+        file.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+        file.extend_from_slice(&[2, 0, 0, 0]);
+        file.extend_from_slice(&packed);
+        file.resize(file.len().next_multiple_of(4), 0);
+        file.extend_from_slice(&[4, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(b"\x7fELF");
+        file.extend_from_slice(&[0; 8]);
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&[13, 22, 2, 8]);
+        header.resize(PACK_HEADER_SIZE, 0);
+        file.extend_from_slice(&header);
+        file.extend_from_slice(&p_info.to_le_bytes());
+        let mut original = b"abc".repeat(22);
+        original.extend_from_slice(b"\x7fELF");
+        assert_eq!(unpack(&file), Some(Ok(Unpacked::File(original))));
+        assert_eq!(unpack(&[0; 0x40]), None);
+    }
+
+    /// Text with a match past the offsets where a match gets a byte longer, one at the last
+    /// offset after a literal, and one near. Each stream is in the layout UCL's compressors
+    /// write with a 32 bit bit buffer, and decodes the same with UCL's reference decompressors
+    #[test]
+    fn known_answers() {
+        let text = b"The quick brown fox jumps over the lazy dog";
+        let mut original = text.to_vec();
+        original.resize(text.len() + 0xe00, 0);
+        original.extend_from_slice(b"The Quick brown fox jumps over the lazy dog");
+        original.extend_from_slice(&text[..20]);
+        let packed: [(u8, &[u8]); 3] = [
+            (
+                2,
+                b"\xff\xff\xff\xffThe quick brown fox jumps over tU\x91\xfdw\x1elazy dog\x00\x00\x00\xe9\x80P*Q\x00\x02\x18\x90U\x00\x00\x00\x00\x00\x00\x00\x09\xff",
+            ),
+            (
+                5,
+                b"\xff\xff\xff\xffThe quick brown fox jumps over tUE\xfbo<lazy dog\x00\x01\x04H\x87BTQ$!\xc0\xa1\xab@%I\x92\xff",
+            ),
+            (
+                8,
+                b"\xff\xff\xff\xffThe quick brown fox jumps over tUE\xfb\x7f<lazy dog\x00\x01\x01\xc8\x87\x16TQ\x92\x84\xd5\xa1\xab\x00\x95$I\xff",
+            ),
+        ];
+        for (id, packed) in packed {
+            assert_eq!(
+                decompress(id, packed, original.len()).as_ref(),
+                Ok(&original)
+            );
+            assert!(decompress(id, &packed[..packed.len() - 1], original.len()).is_err());
+        }
+    }
+}
@@ -1,7 +1,11 @@
 #![allow(dead_code, unused)]
 
+use crate::constants::ENDIAN_MSB;
 use std::path::Path;
 
+/// The `size` byte number at `offset` in `bytes`, in the byte order `endianness` (one of the
+/// `ENDIAN_*` constants), sign extended if `sign`. Numbers wider than 4 bytes are truncated to
+/// their low 32 bits. None if the bytes aren't all there.
 pub fn parse_bytes(
     bytes: Option<Vec<u8>>,
     offset: i32,
@@ -12,10 +16,26 @@ pub fn parse_bytes(
     if size > 8 {
         return slow_parse_bytes(bytes, offset, size, sign, endianness);
     }
-    // if BIGEN
-    None
+    let bytes = bytes?;
+    let start = usize::try_from(offset).ok()?;
+    let size = usize::try_from(size).ok().filter(|size| *size > 0)?;
+    let field = bytes.get(start..start.checked_add(size)?)?;
+    let mut value = [0u8; 8];
+    let value = if endianness == ENDIAN_MSB {
+        value[8 - size..].copy_from_slice(field);
+        u64::from_be_bytes(value)
+    } else {
+        value[..size].copy_from_slice(field);
+        u64::from_le_bytes(value)
+    };
+    let value = match sign && size < 8 {
+        true => ((value << (64 - size * 8)) as i64 >> (64 - size * 8)) as u64,
+        false => value,
+    };
+    Some(value as i32)
 }
 
+/// [`parse_bytes`] for numbers wider than 8 bytes, of which only the low 32 bits are kept
 pub fn slow_parse_bytes(
     bytes: Option<Vec<u8>>,
     offset: i32,
@@ -23,7 +43,25 @@ pub fn slow_parse_bytes(
     sign: bool,
     endianness: i32,
 ) -> Option<i32> {
-    None
+    let bytes = bytes?;
+    let start = usize::try_from(offset).ok()?;
+    let field = bytes.get(start..start.checked_add(usize::try_from(size).ok()?)?)?;
+    let low = if endianness == ENDIAN_MSB {
+        &field[field.len() - 4..]
+    } else {
+        &field[..4]
+    };
+    parse_bytes(Some(low.to_vec()), 0, 4, sign, endianness)
+}
+
+/// `value` as `size` bytes in the byte order `endianness`, its high bytes dropped
+pub fn build_bytes(value: u64, size: usize, endianness: i32) -> Vec<u8> {
+    let size = size.min(8);
+    if endianness == ENDIAN_MSB {
+        value.to_be_bytes()[8 - size..].to_vec()
+    } else {
+        value.to_le_bytes()[..size].to_vec()
+    }
 }
 
 pub fn align(orig_size: usize, alignment: usize) -> usize {
@@ -35,6 +73,99 @@ pub fn align(orig_size: usize, alignment: usize) -> usize {
     }
 }
 
+/// A string as a JSON string literal, quotes included
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// The SHA-256 digest of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+        for (a, b) in h.iter_mut().zip(v) {
+            *a = a.wrapping_add(b);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Bytes as lowercase hex
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn guess_format_filename(filename: &str) -> String {
     String::new()
 }
@@ -0,0 +1,475 @@
+//! Indirect jump targets by value-set analysis.
+//!
+//! Where [`crate::switches`] recognizes the shapes compilers give jump tables, this works out
+//! the set of values each register may hold at the start of every block, and evaluates the
+//! target of an indirect jump over them. Handler tables, computed gotos and dispatch through
+//! tables of function pointers get resolved whenever the values are few enough to list: from
+//! constants, from loads out of memory which can't change, and from bounds checks, which limit
+//! an unknown index on the edge they guard.
+//!
+//! A set of more than [`MAX_VALUES`] values is unknown, and so is a set still growing after a
+//! few trips round a loop. Operations are folded by [`Expr::binary`], so the values agree with
+//! the symbolic engine. Calls aren't part of the IL; [`value_before`] evaluates the target of
+//! an indirect call, given as an expression, at its address.
+
+use crate::{
+    constants::{BR_COND, REF_CODE},
+    envi::{
+        flags::{self, Condition, Flags},
+        registers::RegId,
+        Arch,
+    },
+    pic::AddressSpace,
+    symbolic::{BinOp, Block, Expr, FlagOp, Function, State, Stmt, Terminator},
+    workspace::VivWorkspace,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The most values a set lists before it is taken as unknown
+pub const MAX_VALUES: usize = 256;
+/// How many times a block is gone through before the registers still changing are unknown
+const MAX_VISITS: usize = 8;
+
+/// The values an expression may have
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSet {
+    Values(BTreeSet<u64>),
+    Unknown,
+}
+
+impl ValueSet {
+    pub fn from_values<I: IntoIterator<Item = u64>>(values: I) -> Self {
+        let values: BTreeSet<u64> = values.into_iter().collect();
+        if values.len() > MAX_VALUES {
+            ValueSet::Unknown
+        } else {
+            ValueSet::Values(values)
+        }
+    }
+
+    pub fn values(&self) -> Option<&BTreeSet<u64>> {
+        match self {
+            ValueSet::Values(values) => Some(values),
+            ValueSet::Unknown => None,
+        }
+    }
+
+    fn join(&self, other: &ValueSet) -> ValueSet {
+        match (self, other) {
+            (ValueSet::Values(a), ValueSet::Values(b)) => ValueSet::from_values(a | b),
+            _ => ValueSet::Unknown,
+        }
+    }
+
+    fn map2(&self, other: &ValueSet, f: impl Fn(u64, u64) -> Option<u64>) -> ValueSet {
+        let (Some(a), Some(b)) = (self.values(), other.values()) else {
+            return ValueSet::Unknown;
+        };
+        if a.len() * b.len() > MAX_VALUES * 4 {
+            return ValueSet::Unknown;
+        }
+        let mut values = BTreeSet::new();
+        for x in a {
+            for y in b {
+                match f(*x, *y) {
+                    Some(value) => values.insert(value),
+                    None => return ValueSet::Unknown,
+                };
+            }
+        }
+        ValueSet::from_values(values)
+    }
+}
+
+/// The value sets of the registers; a register missing is unknown
+pub type Env = BTreeMap<RegId, ValueSet>;
+
+fn lookup(env: &Env, reg: RegId) -> ValueSet {
+    env.get(&reg).cloned().unwrap_or(ValueSet::Unknown)
+}
+
+fn join_env(a: &Env, b: &Env) -> Env {
+    a.iter()
+        .filter_map(|(reg, values)| match values.join(&lookup(b, *reg)) {
+            ValueSet::Unknown => None,
+            joined => Some((*reg, joined)),
+        })
+        .collect()
+}
+
+/// The values of `expr`, its registers as of the start of the block having the values of
+/// `env`. Loads read memory which can't change, at the register width.
+pub fn eval(expr: &Expr, env: &Env, space: &AddressSpace<'_>, width: u32) -> ValueSet {
+    match expr {
+        Expr::Const(c) => ValueSet::from_values([*c]),
+        Expr::Reg(reg) => lookup(env, *reg),
+        Expr::Load(addr) => {
+            let ValueSet::Values(addrs) = eval(addr, env, space, width) else {
+                return ValueSet::Unknown;
+            };
+            let loaded: Option<Vec<u64>> = addrs
+                .iter()
+                .map(|addr| space.read_constant(*addr, width as usize / 8))
+                .collect();
+            loaded.map_or(ValueSet::Unknown, ValueSet::from_values)
+        }
+        Expr::Unary(op, a) => {
+            let ValueSet::Values(values) = eval(a, env, space, width) else {
+                return ValueSet::Unknown;
+            };
+            let folded: Option<Vec<u64>> = values
+                .iter()
+                .map(|v| Expr::unary(*op, Expr::Const(*v), width).as_const())
+                .collect();
+            folded.map_or(ValueSet::Unknown, ValueSet::from_values)
+        }
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (eval(a, env, space, width), eval(b, env, space, width));
+            if *op == BinOp::And {
+                // Masking an unknown value leaves the values of the bits of the mask
+                match (a.values(), b.values()) {
+                    (Some(mask), None) | (None, Some(mask)) if mask.len() == 1 => {
+                        return submasks(*mask.first().unwrap_or(&0));
+                    }
+                    _ => {}
+                }
+            }
+            a.map2(&b, |x, y| {
+                Expr::binary(*op, Expr::Const(x), Expr::Const(y), width).as_const()
+            })
+        }
+    }
+}
+
+/// Every value made of some of the bits of `mask`
+fn submasks(mask: u64) -> ValueSet {
+    if 1usize
+        .checked_shl(mask.count_ones())
+        .is_none_or(|n| n > MAX_VALUES)
+    {
+        return ValueSet::Unknown;
+    }
+    let mut values = vec![0];
+    let mut sub = mask;
+    while sub != 0 {
+        values.push(sub);
+        sub = (sub - 1) & mask;
+    }
+    ValueSet::from_values(values)
+}
+
+/// The registers a block sets, and its state from the last statement it doesn't model on
+fn block_effects(arch: Arch, block: &Block) -> (State, BTreeSet<RegId>, bool) {
+    let stmts: Vec<&Stmt> = block.insns.iter().flat_map(|insn| &insn.stmts).collect();
+    let start = stmts
+        .iter()
+        .rposition(|stmt| **stmt == Stmt::Unknown)
+        .map_or(0, |i| i + 1);
+    let mut state = State::new(arch);
+    let mut written = BTreeSet::new();
+    for stmt in &stmts[start..] {
+        state.exec(stmt);
+        if let Stmt::Set(reg, _) = stmt {
+            written.insert(*reg);
+        }
+    }
+    (state, written, start > 0)
+}
+
+/// Whether a compare of `value` against `bound` takes the branch with `cond` the way `taken`
+fn passes(arch: Arch, width: u32, cond: Condition, taken: bool, value: u64, bound: u64) -> bool {
+    let mut state = Flags::unknown();
+    state.apply(&flags::sub(arch, value, bound, width));
+    cond.eval(&state).is_some_and(|holds| holds == taken)
+}
+
+/// Narrow `values` to those getting along the edge of a bounds check, if the check bounds them
+fn refine(
+    arch: Arch,
+    width: u32,
+    cond: Condition,
+    taken: bool,
+    bound: u64,
+    values: &ValueSet,
+) -> ValueSet {
+    let passes = |value| passes(arch, width, cond, taken, value, bound);
+    match values {
+        ValueSet::Values(values) => ValueSet::Values(
+            values
+                .iter()
+                .copied()
+                .filter(|value| passes(*value))
+                .collect(),
+        ),
+        ValueSet::Unknown => {
+            // Only an upper bound check lets no large or negative value through
+            let top = u64::MAX >> (64 - width);
+            let probes = [MAX_VALUES as u64, top, top >> 1, (top >> 1) + 1];
+            if probes.iter().any(|probe| passes(*probe)) {
+                return ValueSet::Unknown;
+            }
+            ValueSet::from_values((0..MAX_VALUES as u64).filter(|value| passes(*value)))
+        }
+    }
+}
+
+/// The value sets of the registers at the start of each reachable block. The registers hold
+/// what `entry` says at the entry of the function.
+pub fn block_envs(func: &Function, space: &AddressSpace<'_>, entry: &Env) -> BTreeMap<u64, Env> {
+    let width = func.arch.pointer_size() as u32 * 8;
+    let order = func.reverse_postorder();
+    let effects: BTreeMap<u64, _> = order
+        .iter()
+        .map(|va| (*va, block_effects(func.arch, &func.blocks[va])))
+        .collect();
+    let mut envs: BTreeMap<u64, Env> = BTreeMap::from([(func.entry, entry.clone())]);
+    let mut visits: BTreeMap<u64, usize> = BTreeMap::new();
+    let mut work: Vec<u64> = vec![func.entry];
+    while let Some(va) = work.pop() {
+        let Some((state, written, unknown)) = effects.get(&va) else {
+            continue;
+        };
+        let base = exit_base(&envs[&va], *unknown);
+        let mut exit = base.clone();
+        for reg in written {
+            match eval(&state.reg(*reg), &base, space, width) {
+                ValueSet::Unknown => exit.remove(reg),
+                values => exit.insert(*reg, values),
+            };
+        }
+        let block = &func.blocks[&va];
+        for succ in func.successors(va) {
+            let mut out = exit.clone();
+            if let Terminator::Branch {
+                cond,
+                taken,
+                fallthrough,
+            } = block.end
+            {
+                if let Some((FlagOp::Sub, compared, Expr::Const(bound))) = state.flag_source() {
+                    let regs: Vec<RegId> = written
+                        .iter()
+                        .copied()
+                        .chain(match compared {
+                            Expr::Reg(reg) => Some(*reg),
+                            _ => None,
+                        })
+                        .filter(|reg| state.reg(*reg) == *compared)
+                        .collect();
+                    for reg in regs {
+                        let edge = if succ == taken {
+                            Some(true)
+                        } else if succ == fallthrough {
+                            Some(false)
+                        } else {
+                            None
+                        };
+                        if let Some(edge) = edge {
+                            let values =
+                                refine(func.arch, width, cond, edge, *bound, &lookup(&out, reg));
+                            out.insert(reg, values);
+                        }
+                    }
+                }
+            }
+            let merged = match envs.get(&succ) {
+                Some(old) => join_env(old, &out),
+                None => out,
+            };
+            if envs.get(&succ) == Some(&merged) {
+                continue;
+            }
+            let count = visits.entry(succ).or_default();
+            *count += 1;
+            let merged = match (envs.get(&succ), *count > MAX_VISITS) {
+                // Give up on whatever is still changing
+                (Some(old), true) => merged
+                    .into_iter()
+                    .filter(|(reg, values)| old.get(reg) == Some(values))
+                    .collect(),
+                _ => merged,
+            };
+            envs.insert(succ, merged);
+            if !work.contains(&succ) {
+                work.push(succ);
+            }
+        }
+    }
+    envs
+}
+
+/// The registers the statements after the last unmodelled one read, which are unknown
+fn exit_base(start: &Env, unknown: bool) -> Env {
+    if unknown {
+        Env::new()
+    } else {
+        start.clone()
+    }
+}
+
+/// The targets of an indirect jump resolved by value sets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub block: u64,
+    /// The address of the jump
+    pub jump: u64,
+    pub targets: Vec<u64>,
+}
+
+/// Resolve the indirect jumps of a function which aren't switches already, and add their
+/// targets to it. Only jumps all of whose possible targets are mapped count.
+pub fn resolve(func: &mut Function, space: &AddressSpace<'_>) -> Vec<Resolved> {
+    let width = func.arch.pointer_size() as u32 * 8;
+    let envs = block_envs(func, space, &Env::new());
+    let mut resolved = Vec::new();
+    for (va, block) in func.blocks.iter() {
+        let Terminator::Indirect(target) = &block.end else {
+            continue;
+        };
+        if func.switches.contains_key(va) {
+            continue;
+        }
+        let Some(env) = envs.get(va) else {
+            continue;
+        };
+        let (state, _, unknown) = block_effects(func.arch, block);
+        let values = eval(&state.eval(target), &exit_base(env, unknown), space, width);
+        let Some(targets) = values.values() else {
+            continue;
+        };
+        if targets.is_empty() || !targets.iter().all(|target| space.is_mapped(*target)) {
+            continue;
+        }
+        resolved.push(Resolved {
+            block: *va,
+            jump: block.end_va,
+            targets: targets.iter().copied().collect(),
+        });
+    }
+    for jump in resolved.iter() {
+        func.add_targets(jump.block, jump.targets.clone());
+    }
+    resolved
+}
+
+/// The values of `expr` right before the instruction at `va`, such as the target of an
+/// indirect call there
+pub fn value_before(func: &Function, space: &AddressSpace<'_>, va: u64, expr: &Expr) -> ValueSet {
+    let width = func.arch.pointer_size() as u32 * 8;
+    let Some((_, block)) = func.blocks.range(..=va).next_back() else {
+        return ValueSet::Unknown;
+    };
+    let envs = block_envs(func, space, &Env::new());
+    let Some(env) = envs.get(&block.va) else {
+        return ValueSet::Unknown;
+    };
+    let before = Block {
+        insns: block
+            .insns
+            .iter()
+            .take_while(|insn| insn.va < va)
+            .cloned()
+            .collect(),
+        ..block.clone()
+    };
+    let (state, _, unknown) = block_effects(func.arch, &before);
+    eval(&state.eval(expr), &exit_base(env, unknown), space, width)
+}
+
+/// Record resolved targets in the workspace: code xrefs from the jump to each, and a comment
+pub fn annotate(workspace: &mut VivWorkspace, resolved: &Resolved) {
+    let jump = resolved.jump as i32;
+    for target in resolved.targets.iter() {
+        workspace.add_xref(jump, *target as i32, REF_CODE, BR_COND);
+    }
+    let comment = format!("indirect jump: {} targets", resolved.targets.len());
+    workspace.set_comment(jump, &comment, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{envi::registers::RegisterModel, symbolic::Insn};
+
+    #[test]
+    fn handler_table() {
+        // and eax, 3; jmp [0x2000 + eax*4], a table of four handlers with no bounds check
+        let model = RegisterModel::new(Arch::I386);
+        let (eax, ecx) = (model.by_name("eax").unwrap(), model.by_name("ecx").unwrap());
+        let table: Vec<u8> = [0x1100u32, 0x1200, 0x1100, 0x1300]
+            .iter()
+            .flat_map(|target| target.to_le_bytes())
+            .collect();
+        let code = [0u8; 0x400];
+        let mut space = AddressSpace::new();
+        space.add_map(0x1000, &code, false);
+        space.add_map(0x2000, &table, false);
+
+        let slot = Expr::binary(
+            BinOp::Add,
+            Expr::binary(BinOp::Mul, Expr::Reg(eax), Expr::Const(4), 32),
+            Expr::Const(0x2000),
+            32,
+        );
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![Insn {
+                va: 0x1000,
+                stmts: vec![Stmt::Set(
+                    eax,
+                    Expr::binary(BinOp::And, Expr::Reg(eax), Expr::Const(3), 32),
+                )],
+            }],
+            end_va: 0x1003,
+            end: Terminator::Indirect(Expr::Load(Box::new(slot))),
+        });
+        for va in [0x1100, 0x1200, 0x1300] {
+            func.add_block(Block {
+                va,
+                insns: vec![],
+                end_va: va,
+                end: Terminator::Return,
+            });
+        }
+
+        let resolved = resolve(&mut func, &space);
+        assert_eq!(
+            resolved,
+            [Resolved {
+                block: 0x1000,
+                jump: 0x1003,
+                targets: vec![0x1100, 0x1200, 0x1300],
+            }]
+        );
+        assert_eq!(func.successors(0x1000), [0x1100, 0x1200, 0x1300]);
+
+        // cmp ecx, 2; ja out; ... the index is 0, 1 or 2 past the check
+        let mut func = Function::new(Arch::I386, 0x1000);
+        func.add_block(Block {
+            va: 0x1000,
+            insns: vec![Insn {
+                va: 0x1000,
+                stmts: vec![Stmt::Flags(FlagOp::Sub, Expr::Reg(ecx), Expr::Const(2))],
+            }],
+            end_va: 0x1003,
+            end: Terminator::Branch {
+                cond: Condition::NoCarryNoZero,
+                taken: 0x1100,
+                fallthrough: 0x1010,
+            },
+        });
+        for va in [0x1010, 0x1100] {
+            func.add_block(Block {
+                va,
+                insns: vec![],
+                end_va: va,
+                end: Terminator::Return,
+            });
+        }
+        let index = value_before(&func, &space, 0x1010, &Expr::Reg(ecx));
+        assert_eq!(index, ValueSet::from_values([0, 1, 2]));
+        let index = value_before(&func, &space, 0x1100, &Expr::Reg(ecx));
+        assert_eq!(index, ValueSet::Unknown);
+    }
+}
@@ -0,0 +1,225 @@
+//! A JS API for binary viewers running in the browser.
+//!
+//! Built for `wasm32-unknown-unknown` with the `wasm` feature, the crate exports a `Workspace`
+//! class a web page loads files into from their bytes and queries, without a server to do the
+//! parsing. Everything runs on the thread it is called from, and nothing touches a file system:
+//! the workspace has no configuration directory, so no symbol cache.
+//!
+//! Lists of plain numbers come back as typed arrays; lists of records, imports or segments,
+//! come back as JSON for `JSON.parse`.
+
+use crate::{
+    constants::MM_READ,
+    listing::{write_listing, Style},
+    memory::Memory,
+    utils::json_string,
+    workspace::VivWorkspace,
+};
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(js_name = Workspace)]
+pub struct WasmWorkspace {
+    workspace: VivWorkspace,
+}
+
+#[wasm_bindgen(js_class = Workspace)]
+impl WasmWorkspace {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        WasmWorkspace {
+            workspace: VivWorkspace::new("", false),
+        }
+    }
+
+    /// Load a file from its bytes. Returns the name it was loaded under, or throws if the name
+    /// has no file name in it or a file was loaded under it already.
+    pub fn load(&mut self, name: &str, bytes: &[u8]) -> Result<String, JsError> {
+        self.try_load(name, bytes).map_err(|err| JsError::new(&err))
+    }
+
+    /// The `Format`, `Platform` or `Architecture` of what was loaded, and the like
+    pub fn meta(&self, name: &str) -> Option<String> {
+        self.workspace.get_meta(name)
+    }
+
+    pub fn files(&self) -> Vec<String> {
+        self.workspace.get_files()
+    }
+
+    #[wasm_bindgen(js_name = entryPoints)]
+    pub fn entry_points(&self) -> Vec<i32> {
+        self.workspace.get_entry_points()
+    }
+
+    pub fn functions(&self) -> Vec<i32> {
+        self.workspace.get_functions()
+    }
+
+    /// `[{"va", "size", "name", "file"}]`
+    pub fn segments(&self) -> String {
+        json_list(self.workspace.get_segments().iter().map(|(va, size, name, file)| {
+            format!(
+                "{{\"va\": {}, \"size\": {}, \"name\": {}, \"file\": {}}}",
+                va,
+                size,
+                json_string(name),
+                json_string(file)
+            )
+        }))
+    }
+
+    /// `[{"va", "name"}]`
+    pub fn imports(&self) -> String {
+        json_names(self.workspace.get_imports())
+    }
+
+    /// `[{"va", "name"}]`
+    pub fn exports(&self) -> String {
+        json_names(self.workspace.get_exports())
+    }
+
+    /// `[{"va", "name"}]`
+    pub fn names(&self) -> String {
+        json_names(self.workspace.get_names())
+    }
+
+    pub fn name(&self, va: i32) -> Option<String> {
+        self.workspace.get_name(va, true)
+    }
+
+    /// `[{"from", "to", "type", "flags"}]`
+    #[wasm_bindgen(js_name = xrefsTo)]
+    pub fn xrefs_to(&self, va: i32) -> String {
+        json_xrefs(self.workspace.get_xrefs_to(va, None))
+    }
+
+    /// `[{"from", "to", "type", "flags"}]`
+    #[wasm_bindgen(js_name = xrefsFrom)]
+    pub fn xrefs_from(&self, va: i32) -> String {
+        json_xrefs(self.workspace.get_xrefs_from(va, None))
+    }
+
+    /// The `size` bytes at `va`, if they are all mapped readable
+    pub fn read(&mut self, va: i32, size: i32) -> Option<Vec<u8>> {
+        let end = va.checked_add(size).filter(|_| size >= 0)?;
+        let mut at = va;
+        while at < end {
+            let (map_va, map_size, perms, _) = self.workspace.get_memory_map(at)?;
+            if perms & MM_READ == 0 {
+                return None;
+            }
+            at = map_va.saturating_add(map_size);
+        }
+        self.workspace.read_memory(va, size)
+    }
+
+    /// The listing of everything loaded, in the layout of `objdump -D`
+    pub fn listing(&self) -> String {
+        let mut out = Vec::new();
+        write_listing(&self.workspace, Style::Objdump, &mut out)
+            .expect("writing to memory doesn't fail");
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+impl WasmWorkspace {
+    fn try_load(&mut self, name: &str, bytes: &[u8]) -> Result<String, String> {
+        // the workspace goes by the stem of the name, and takes each only once
+        if Path::new(name).file_stem().is_none() {
+            return Err(format!("No file name in {:?}", name));
+        }
+        let fname = self.workspace.norm_filename(name);
+        if self.workspace.get_files().contains(&fname) {
+            return Err(format!("A file was loaded as {} already", fname));
+        }
+        Ok(self.workspace.load_from_bytes(name, bytes, None))
+    }
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+fn json_names(names: Vec<(i32, String)>) -> String {
+    json_list(
+        names
+            .iter()
+            .map(|(va, name)| format!("{{\"va\": {}, \"name\": {}}}", va, json_string(name))),
+    )
+}
+
+fn json_xrefs(xrefs: Vec<(i32, i32, i32, i32)>) -> String {
+    json_list(xrefs.iter().map(|(from, to, rtype, rflags)| {
+        format!(
+            "{{\"from\": {}, \"to\": {}, \"type\": {}, \"flags\": {}}}",
+            from, to, rtype, rflags
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::MM_WRITE;
+
+    /// An amd64 ELF executable with one segment at 0x400000, `xor eax, eax; ret` at its entry
+    fn tiny_elf() -> Vec<u8> {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(16, 0);
+        elf.extend(2u16.to_le_bytes()); // ET_EXEC
+        elf.extend(62u16.to_le_bytes()); // EM_X86_64
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(0x400078u64.to_le_bytes()); // entry
+        elf.extend(64u64.to_le_bytes()); // program headers
+        elf.extend(0u64.to_le_bytes()); // no section headers
+        elf.extend(0u32.to_le_bytes());
+        for half in [64u16, 56, 1, 64, 0, 0] {
+            elf.extend(half.to_le_bytes());
+        }
+        elf.extend(1u32.to_le_bytes()); // PT_LOAD
+        elf.extend(5u32.to_le_bytes()); // r-x
+        for word in [0u64, 0x400000, 0x400000, 0x7b, 0x7b, 0x1000] {
+            elf.extend(word.to_le_bytes());
+        }
+        elf.extend([0x31, 0xc0, 0xc3]);
+        elf
+    }
+
+    #[test]
+    fn loads_from_bytes() {
+        let mut ws = WasmWorkspace::new();
+        let fname = ws.try_load("/nowhere/tiny", &tiny_elf()).unwrap();
+        assert_eq!(fname, "tiny");
+        assert_eq!(ws.files(), [fname]);
+        assert_eq!(ws.meta("Format").as_deref(), Some("elf"));
+        assert_eq!(ws.entry_points(), [0x400078]);
+        assert_eq!(ws.read(0x400078, 3), Some(vec![0x31, 0xc0, 0xc3]));
+        assert_eq!(ws.read(0x400078, -1), None);
+        assert_eq!(ws.read(0x400078, 0x1000), None);
+        ws.workspace
+            .add_memory_map(0x1000, MM_WRITE, "tiny", vec![0; 0x10], None);
+        assert_eq!(ws.read(0x1000, 4), None);
+        assert_eq!(
+            ws.segments(),
+            r#"[{"va": 4194304, "size": 123, "name": "PHDR0", "file": "tiny"}]"#
+        );
+        assert_eq!(ws.imports(), "[]");
+        assert!(ws.listing().contains("  400077:\t00 31 c0 c3"));
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        let mut ws = WasmWorkspace::new();
+        for name in ["", "..", "/nowhere/.."] {
+            assert!(ws.try_load(name, &tiny_elf()).is_err(), "{:?}", name);
+        }
+        assert!(ws.files().is_empty());
+        assert_eq!(ws.try_load("tiny", &tiny_elf()).as_deref(), Ok("tiny"));
+        // the same file twice, or another under the same name
+        assert!(ws.try_load("tiny", &tiny_elf()).is_err());
+        assert!(ws.try_load("/elsewhere/tiny.elf", &tiny_elf()).is_err());
+        assert_eq!(ws.files(), ["tiny"]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Watching ranges of addresses for changes.
+//!
+//! A viewer showing part of a binary only needs to hear about what changes there.
+//! `VivWorkspace::watch` subscribes to a range of VAs and hands back the receiving end of a
+//! channel, down which every change touching the range comes as the journal [`Event`] for it:
+//! a function, location, name, comment or tag made in it, an xref from or into it, its bytes
+//! patched. `VivWorkspace::move_watch` follows the view as it scrolls, and
+//! `VivWorkspace::unwatch`, or dropping the receiver, ends the subscription.
+//!
+//! Analysis passes run on the workspace itself, so what they make is sent as they make it, from
+//! the thread they run on. A copy of the workspace starts out with no watches: what changes in
+//! it isn't what the viewer shows.
+
+use crate::journal::Event;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchId(u64);
+
+#[derive(Debug)]
+struct Watch {
+    id: WatchId,
+    start: i64,
+    end: i64,
+    sender: Sender<Event>,
+}
+
+/// The watches of a workspace
+#[derive(Debug, Default)]
+pub struct Watchers {
+    next: u64,
+    watches: Vec<Watch>,
+}
+
+impl Clone for Watchers {
+    /// None of the watches, which are of the workspace copied
+    fn clone(&self) -> Self {
+        Watchers {
+            next: self.next,
+            watches: Vec::new(),
+        }
+    }
+}
+
+impl Watchers {
+    pub fn new() -> Self {
+        Watchers::default()
+    }
+
+    /// Watch the `size` bytes at `va`
+    pub fn add(&mut self, va: i32, size: i32) -> (WatchId, Receiver<Event>) {
+        let (sender, receiver) = channel();
+        let id = WatchId(self.next);
+        self.next += 1;
+        self.watches.push(Watch {
+            id,
+            start: va as i64,
+            end: va as i64 + size as i64,
+            sender,
+        });
+        (id, receiver)
+    }
+
+    /// Watch another range instead. Returns whether the watch is still there.
+    pub fn set_range(&mut self, id: WatchId, va: i32, size: i32) -> bool {
+        match self.watches.iter_mut().find(|watch| watch.id == id) {
+            Some(watch) => {
+                watch.start = va as i64;
+                watch.end = va as i64 + size as i64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: WatchId) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|watch| watch.id != id);
+        self.watches.len() != before
+    }
+
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Send `event` to the watches of the ranges it touches, and drop those nobody receives
+    /// from anymore
+    pub fn notify(&mut self, event: &Event) {
+        let spans = spans(event);
+        if spans.is_empty() {
+            return;
+        }
+        self.watches.retain(|watch| {
+            let touched = spans
+                .iter()
+                .any(|(start, end)| *start < watch.end && watch.start < *end);
+            !touched || watch.sender.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// The [start, end) ranges of VAs an event touches
+fn spans(event: &Event) -> Vec<(i64, i64)> {
+    let span = |va: i32, size: usize| (va as i64, va as i64 + size.max(1) as i64);
+    match event {
+        Event::AddMemoryMap { va, bytes, .. } | Event::PatchMemory { va, bytes } => {
+            vec![span(*va, bytes.len())]
+        }
+        Event::AddSegment { va, size, .. }
+        | Event::AddLocation { va, size, .. }
+        | Event::AddRelocation { va, size, .. }
+        | Event::AddCodeBlock { va, size, .. }
+        | Event::AddRegionOverride { va, size, .. } => vec![span(*va, *size as usize)],
+        Event::AddXref { from, to, .. } => vec![span(*from, 1), span(*to, 1)],
+        Event::SetName { va, .. }
+        | Event::SetComment { va, .. }
+        | Event::SetType { va, .. }
+        | Event::AddTag { va, .. }
+        | Event::DelTag { va, .. }
+        | Event::AddImport { va, .. }
+        | Event::AddExport { va, .. }
+        | Event::DelCodeBlock { va } => vec![span(*va, 1)],
+        Event::AddFunction { fva, ranges } | Event::SetFunctionBounds { fva, ranges } => {
+            std::iter::once(span(*fva, 1))
+                .chain(ranges.iter().map(|(va, size)| span(*va, *size as usize)))
+                .collect()
+        }
+        Event::AddFunctionChunk { fva, va, size } => vec![span(*fva, 1), span(*va, *size as usize)],
+        Event::DelFunctionChunk { fva, va } | Event::AddFunctionEntry { fva, va } => {
+            vec![span(*fva, 1), span(*va, 1)]
+        }
+        Event::DelFunction { fva } | Event::SetFunctionMeta { fva, .. } => vec![span(*fva, 1)],
+        Event::Rename { va, comments, .. } => std::iter::once(va)
+            .chain(comments.iter())
+            .map(|va| span(*va, 1))
+            .collect(),
+        Event::AddFile { .. }
+        | Event::SetFileMeta { .. }
+        | Event::SetMeta { .. }
+        | Event::SetVaSetRows { .. }
+        | Event::SetArchitecture(_)
+        | Event::SetPointerSize(_)
+        | Event::Pass(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        analysis::Analyzer,
+        constants::{LOC_NUMBER, REF_CODE},
+        journal::Event,
+        workspace::VivWorkspace,
+    };
+    use std::sync::Arc;
+
+    struct Callee;
+
+    impl Analyzer for Callee {
+        fn analyze(&self, workspace: &mut VivWorkspace) {
+            workspace.add_function(0x2000, vec![(0x2000, 0x10)]);
+            workspace.add_xref(0x5000, 0x2008, REF_CODE, 0);
+        }
+    }
+
+    #[test]
+    fn watches_ranges() {
+        let mut ws = VivWorkspace::new("", false);
+        let (id, changes) = ws.watch(0x2000, 0x100);
+        ws.add_location(0x1000, 4, LOC_NUMBER, Some(vec![]));
+        ws.add_location(0x20fe, 4, LOC_NUMBER, Some(vec![]));
+        ws.add_analyzer(Arc::new(Callee));
+        ws.run_analyzers();
+        let got: Vec<_> = changes.try_iter().collect();
+        assert_eq!(got.len(), 3);
+        // everything heard of is there to be looked at
+        for event in got.iter() {
+            match event {
+                Event::AddLocation { va, .. } => assert!(ws.get_location(*va).is_some()),
+                Event::AddFunction { fva, .. } => assert!(ws.is_function(*fva)),
+                Event::AddXref {
+                    from,
+                    to,
+                    rtype,
+                    rflags,
+                } => assert_eq!(
+                    ws.get_xrefs_from(*from, None),
+                    [(*from, *to, *rtype, *rflags)]
+                ),
+                _ => panic!("unexpected {:?}", event),
+            }
+        }
+        assert!(matches!(got[0], Event::AddLocation { va: 0x20fe, .. }));
+        assert!(matches!(got[1], Event::AddFunction { fva: 0x2000, .. }));
+        assert!(matches!(got[2], Event::AddXref { to: 0x2008, .. }));
+
+        // a copy is changed apart from what is watched
+        let mut copy = ws.clone();
+        copy.add_location(0x2010, 4, LOC_NUMBER, Some(vec![]));
+        assert!(changes.try_recv().is_err());
+
+        // the view scrolls away
+        assert!(ws.move_watch(id, 0x3000, 0x100));
+        ws.make_name(0x2000, "main".to_string(), true, false);
+        ws.set_comment(0x3010, "here", false);
+        assert_eq!(changes.try_iter().count(), 1);
+
+        assert!(ws.unwatch(id));
+        ws.set_comment(0x3010, "gone", false);
+        assert!(changes.try_recv().is_err());
+    }
+}
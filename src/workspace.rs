@@ -1,72 +1,150 @@
 #![allow(dead_code, unused, clippy::type_complexity)]
 
+#[cfg(feature = "assembler")]
+use crate::assemble::IcedAssembler;
 use crate::{
-    analysis::{analyze_function, AnalysisModTracker, Analyzer},
+    analysis::{analyze_function, AnalysisModTracker, AnalysisStats, Analyzer},
+    assemble::Assembler,
+    auditlog::{AuditLog, AuditRecord},
     constants::{
-        ARCH_DEFAULT, CB_FUNCVA, ENDIAN_LSB, LOC_IMPORT, LOC_NUMBER, LOC_OP, LOC_POINTER,
-        LOC_STRING, LOC_UNI, LOC_VFTABLE, L_LTYPE, L_SIZE, L_TINFO, L_VA, MM_EXEC, MM_READ,
-        MM_WRITE, REBASE_TYPES, REF_PTR, SEG_FNAME, VASET_ADDRESS, VASET_COMPLEX, VASET_INTEGER,
-        VASET_STRING, VTE_MASK, VWE_ADDFREF, VWE_ADDMMAP, VWE_ADDRELOC, VWE_ADDVASET,
-        VWE_AUTOANALFIN, VWE_COMMENT, VWE_DELRELOC, VWE_SETVASETROW, XR_RTYPE,
+        ARCH_DEFAULT, BR_PROC, CB_FUNCVA, ENDIAN_LSB, ENDIAN_MSB, LOC_IMPORT, LOC_NUMBER, LOC_OP,
+        LOC_POINTER, LOC_STRING, LOC_UNI, LOC_VFTABLE, L_LTYPE, L_SIZE, L_TINFO, L_VA, MM_EXEC,
+        MM_READ, MM_WRITE, REBASE_TYPES, REF_CODE, REF_CONST, REF_PTR, SEG_FNAME, VASET_ADDRESS,
+        VASET_COMPLEX, VASET_INTEGER, VASET_STRING, VTE_MASK, VWE_ADDFREF, VWE_ADDMMAP,
+        VWE_ADDRELOC, VWE_ADDVASET, VWE_AUTOANALFIN, VWE_COMMENT, VWE_DELRELOC, VWE_SETVASETROW,
+        XR_RTYPE,
     },
     context::VivCodeFlowContext,
+    devirt::{self, Devirtualized},
+    driver::DriverInfo,
     emulator::{Emulator, GenericEmulator, ImmedOper, OpCode, RegisterOper},
+    envi::Arch,
+    immediates::{self, ImmediateUse, Watchlist},
+    journal::{Event, Journal},
+    loader::Loader,
+    locations::{LocationStore, MemoryLocations},
     memory::Memory,
+    merge::{merge_annotations, MergeConflict},
+    naming::{auto_name, parse_auto_name, replace_token, AutoKind, Renamed, NAME_META_KEYS},
+    noreturn::{self, Boundary},
+    ordinals::OrdinalNames,
+    origins::{Artifact, Origin, Origins},
+    osprofile::{OsProfile, META_LIBC, META_OS_PROFILE},
+    overrides::{Overrides, RegionKind},
     page_lookup::MapLookUp,
-    parser::parse_file,
-    utils::{align, guess_format_filename},
+    parser::{parse_contents, parse_file},
+    persistent::PersistentMap,
+    profile::{Profile, META_PROFILE},
+    regstate::{self, RegState, Value},
+    resolve::{Resolved, SymbolIndex},
+    slicing::{self, Operand},
+    snapshot::{Snapshot, Snapshots, Stores},
+    storage::Annotations,
+    structs::{self, FieldType, Structure},
+    symbolic::Function,
+    symcache::{rebase, SymbolCache, FUNC_META_CACHED},
+    utils::{align, guess_format_filename, parse_bytes},
+    watch::{WatchId, Watchers},
     Object,
 };
 use chrono::Local;
 use log::{debug, error, info, warn};
-use std::{collections::HashMap, fmt::format, fs, path::Path, rc::Rc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::format,
+    fs,
+    path::Path,
+    rc::Rc,
+    sync::{mpsc::Receiver, Arc, OnceLock},
+};
+
+/// The code of a function which isn't one contiguous range: chunks the compiler moved away
+/// (`.cold` sections), tails shared with other functions, stack check preambles, and entry
+/// points besides the function VA.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionChunks {
+    /// (va, size) of each range of code, sorted by va.
+    pub ranges: Vec<(i32, i32)>,
+    /// Entry points other than the function VA.
+    pub entries: Vec<i32>,
+}
+
+impl FunctionChunks {
+    pub fn contains(&self, va: i32) -> bool {
+        self.ranges
+            .iter()
+            .any(|(rva, size)| va >= *rva && va - *rva < *size)
+    }
+}
 
 /// VivWorkspace is the heart of vivisect_rs's binary analysis. Most APIs accept a VivWorkspace
 /// as their first parameter, and the workspace is responsible for all the user facing functions
 /// of getters/adders, running analysis passes, making the various locations, loading files, and
 /// more.
+///
+/// The lists the getters hand out are sorted, so they come out the same from one run to the
+/// next and whatever order analysis found things in: addresses and locations by VA, xrefs by
+/// `(from, to, type, flags)`, code blocks by VA, and imports and exports by VA. VAs sort as the
+/// unsigned addresses they are, so the high half of the address space comes last.
 #[derive(Clone, Debug)]
 pub struct VivWorkspace {
     pub sample_path: String,
     analysis_tracker: AnalysisModTracker,
+    /// A record of each analysis run
+    audit_log: AuditLog,
+    /// The loaders offered files before the built in formats
+    loaders: Vec<Arc<dyn Loader>>,
+    /// The names of the exports DLLs make by ordinal only
+    ordinal_names: OrdinalNames,
     viv_home: String,
     // pub object: Object,
-    loclist: Vec<(i32, i32, i32, Vec<(i32, i32)>)>,
+    locations: Box<dyn LocationStore>,
     pub arch: u32,
     segments: Vec<(i32, i32, String, String)>,
-    pub locmap: MapLookUp,
     pub blockmap: MapLookUp,
     pub library_functions: Vec<(String, i32)>,
     pub strings: Vec<(String, i32)>,
     exports: Vec<i32>,
     imports: Vec<i32>,
+    import_stubs: HashMap<i32, i32>, // Import slot by the va of the trampoline jumping through it,
+    overrides: Overrides,            // User region overrides analysis honours,
+    origins: Origins,             // Where the functions, locations and xrefs made here came from,
+    confidence: u8,               // The confidence of what is made from now on,
+    watchers: Watchers,           // The watches of this workspace, which copies don't take,
+    driver_info: Option<DriverInfo>, // What driver analysis found, for a kernel driver
     codeblocks: Vec<(i32, i32, i32, Vec<(i32, i32)>)>,
     relocations: Vec<(String, i32, i32, Vec<u8>, i32)>,
     pub _dead_data: Vec<(String, i32)>,
     _map_defs: Vec<(i32, i32, (i32, i32, i32, String), Vec<u8>)>,
     iscode: HashMap<String, String>,
-    xrefs: Vec<(i32, i32, i32, i32)>,
-    xrefs_by_to: HashMap<i32, Vec<(i32, i32, i32, i32)>>,
-    xrefs_by_from: HashMap<i32, Vec<(i32, i32, i32, i32)>>, // XXX - make config option,
+    xrefs_by_to: PersistentMap<Vec<(i32, i32, i32, i32)>>,
+    xrefs_by_from: PersistentMap<Vec<(i32, i32, i32, i32)>>, // XXX - make config option,
     greedycode: i32,
     metadata: HashMap<String, Option<String>>,
-    comments: HashMap<i32, String>, // Comment by VA.,
+    comments: HashMap<i32, String>,       // Comment by VA.,
+    tags: HashMap<i32, BTreeSet<String>>, // Tags by VA.,
+    types: HashMap<i32, String>,          // Type name by VA.,
     symhints: HashMap<String, String>,
     filemeta: HashMap<String, HashMap<String, i32>>, // Metadata Dicts stored by filename,
     transmeta: HashMap<String, String>,              // Metadata that is *not* saved/evented,
     // cfctx : VivCodeFlowContext,
     va_by_name: HashMap<String, i32>,
-    name_by_va: HashMap<i32, String>,
+    name_by_va: PersistentMap<String>,
+    auto_names: HashSet<i32>, // VAs whose name was made by analysis rather than given,
     codeblocks_by_funcva: HashMap<i32, Vec<(i32, i32, i32, Vec<(i32, i32)>)>>,
     exports_by_va: HashMap<String, String>,
     colormaps: HashMap<String, String>,
     vasetdefs: HashMap<String, String>,
     // Virtual address sets, Holds the name, and a tuple of definitions and rows.
     vasets: HashMap<String, (Option<Vec<(String, i32)>>, Vec<i32>)>,
-    reloc_by_va: HashMap<i32, i32>,
-    func_args: HashMap<String, String>,
-    funcmeta: HashMap<i32, HashMap<String, i32>>, // Function metadata stored in the workspace,
-    frefs: HashMap<(i32, i32), String>,           // Extended analysis modules,
+    reloc_by_va: HashMap<i32, (i32, i32)>,
+    func_args: HashMap<i32, Vec<(String, String)>>, // (type, name) of the arguments by function va,
+    funcmeta: PersistentMap<HashMap<String, i32>>, // Function metadata stored in the workspace,
+    func_chunks: PersistentMap<FunctionChunks>, // Disjoint code and extra entries by function va,
+    func_il: HashMap<i32, Function>,           // The IL of functions, as the caller lifted them,
+    reg_states: HashMap<i32, BTreeMap<u64, RegState>>, // Built on the first get_reg_state() in a function,
+    structures: BTreeMap<String, Structure>, // Structure types by name, inferred or given,
+    frefs: HashMap<(i32, i32), String>,        // Extended analysis modules,
     amods: HashMap<String, String>,
     amodlist: Vec<String>,
     // Extended *function* analysis modules,
@@ -81,6 +159,11 @@ pub struct VivWorkspace {
     _op_cache: HashMap<(i32, u32, Vec<u8>), Option<u32>>,
     p_size: i32,
     endianess: i32,
+    module_ids: HashMap<String, Vec<u8>>, // UUID/build-id/PDB GUID by filename,
+    content_ids: HashMap<String, Vec<u8>>, // SHA-256 of the contents by filename,
+    symbol_index: OnceLock<SymbolIndex>,  // Built on the first resolve(), dropped on changes,
+    journal: Option<Journal>,             // The changes made to this workspace, in order,
+    snapshots: Snapshots,                 // The change count and the last snapshot_view(),
 }
 
 impl VivWorkspace {
@@ -90,35 +173,45 @@ impl VivWorkspace {
     pub fn new(confdir: &str, autosave: bool) -> Self {
         let mut workspace = VivWorkspace {
             sample_path: String::new(),
-            viv_home: "".to_string(),
-            loclist: Vec::new(),
+            viv_home: confdir.to_string(),
+            locations: Box::new(MemoryLocations::new()),
             // object: Object::Unknown(0),
             // cfctx: VivCodeFlowContext::new()
             analysis_tracker: AnalysisModTracker::new(),
+            audit_log: AuditLog::new(),
+            loaders: Vec::new(),
+            ordinal_names: OrdinalNames::known(),
             arch: ARCH_DEFAULT,
-            locmap: MapLookUp::new(),
             blockmap: MapLookUp::new(),
             library_functions: Vec::new(),
             segments: Vec::new(),
             exports: Vec::new(),
             imports: Vec::new(),
+            import_stubs: Default::default(),
+            overrides: Default::default(),
+            origins: Default::default(),
+            watchers: Default::default(),
+            confidence: Origin::CERTAIN,
+            driver_info: None,
             codeblocks: Vec::new(),
             relocations: Vec::new(),
             _dead_data: Vec::new(),
             _map_defs: Vec::new(),
             iscode: Default::default(),
-            xrefs: Vec::new(),
             xrefs_by_to: Default::default(),
             xrefs_by_from: Default::default(),
             greedycode: 0,
             metadata: Default::default(),
             comments: Default::default(),
+            tags: Default::default(),
+            types: Default::default(),
             symhints: Default::default(),
             filemeta: Default::default(),
             transmeta: Default::default(),
             // cfctx: (),
             va_by_name: Default::default(),
             name_by_va: Default::default(),
+            auto_names: Default::default(),
             codeblocks_by_funcva: Default::default(),
             exports_by_va: Default::default(),
             colormaps: Default::default(),
@@ -127,6 +220,10 @@ impl VivWorkspace {
             reloc_by_va: Default::default(),
             func_args: Default::default(),
             funcmeta: Default::default(),
+            func_chunks: Default::default(),
+            func_il: Default::default(),
+            reg_states: Default::default(),
+            structures: Default::default(),
             frefs: Default::default(),
             amods: Default::default(),
             amodlist: Vec::new(),
@@ -141,6 +238,11 @@ impl VivWorkspace {
             p_size: 0,
             endianess: ENDIAN_LSB,
             strings: Vec::new(),
+            module_ids: Default::default(),
+            content_ids: Default::default(),
+            symbol_index: OnceLock::new(),
+            journal: None,
+            snapshots: Default::default(),
         };
         // Some core meta types that exist
         workspace.set_meta("NoReturnApis", None);
@@ -154,6 +256,10 @@ impl VivWorkspace {
         // A dd some vasets to use in analysis
         workspace.add_vaset("EntryPoints", vec![("va", VASET_ADDRESS)]);
         workspace.add_vaset("NoReturnCalls", vec![("va", VASET_ADDRESS)]);
+        workspace.add_vaset("IRelativeSlots", vec![("va", VASET_ADDRESS)]);
+        workspace.add_vaset("PseudoImports", vec![("va", VASET_ADDRESS)]);
+        workspace.add_vaset("DynamicCode", vec![("va", VASET_ADDRESS)]);
+        workspace.add_vaset("Coverage", vec![("va", VASET_ADDRESS)]);
         workspace.add_vaset(
             "Emulation Anomalies",
             vec![("va", VASET_ADDRESS), ("Message", VASET_STRING)],
@@ -204,6 +310,11 @@ impl VivWorkspace {
         self.p_size
     }
 
+    pub fn set_pointer_size(&mut self, size: i32) {
+        self.record(|| Event::SetPointerSize(size));
+        self.p_size = size;
+    }
+
     /// Return the GUID for this workspace.  Every newly created VivWorkspace
     /// should have a unique GUID, for identifying a particular workspace for
     /// a given binary/process-space versus another created at a different
@@ -238,7 +349,26 @@ impl VivWorkspace {
         None
     }
 
+    /// Every metadata entry with a value
+    pub fn get_meta_dict(&self) -> BTreeMap<String, String> {
+        self.metadata
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+            .collect()
+    }
+
     pub fn set_meta(&mut self, meta_name: &str, meta_value: Option<String>) {
+        self.record(|| Event::SetMeta {
+            key: meta_name.to_string(),
+            value: meta_value.clone(),
+        });
+        // The byte order of the target goes with the memory, also when replaying events
+        if meta_name == "bigend" {
+            self.endianess = match meta_value.as_deref() {
+                Some("true") => ENDIAN_MSB,
+                _ => ENDIAN_LSB,
+            };
+        }
         self.metadata.insert(
             meta_name.to_string(),
             if meta_value.is_some() {
@@ -282,8 +412,64 @@ impl VivWorkspace {
         if check && self.comments.get(&va).is_some() {
             return;
         }
-        todo!();
-        // self.fire_event(VWE_COMMENT, (va, comment));
+        self.record(|| Event::SetComment {
+            va,
+            comment: comment.to_string(),
+        });
+        if comment.is_empty() {
+            self.comments.remove(&va);
+        } else {
+            self.comments.insert(va, comment.to_string());
+        }
+    }
+
+    /// Tag an address, usually a function, with a capability such as "network" or
+    /// "crypto/aes". Tags are paths, a tag holding the tags below it (see [`crate::tags`]).
+    pub fn add_tag(&mut self, va: i32, tag: &str) {
+        if self.tags.get(&va).is_some_and(|tags| tags.contains(tag)) {
+            return;
+        }
+        self.record(|| Event::AddTag {
+            va,
+            tag: tag.to_string(),
+        });
+        self.tags.entry(va).or_default().insert(tag.to_string());
+    }
+
+    pub fn del_tag(&mut self, va: i32, tag: &str) {
+        let Some(tags) = self.tags.get_mut(&va) else {
+            return;
+        };
+        if !tags.remove(tag) {
+            return;
+        }
+        if tags.is_empty() {
+            self.tags.remove(&va);
+        }
+        self.record(|| Event::DelTag {
+            va,
+            tag: tag.to_string(),
+        });
+    }
+
+    /// The tags of an address, sorted.
+    pub fn get_tags(&self, va: i32) -> Vec<String> {
+        self.tags
+            .get(&va)
+            .map(|tags| tags.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Every address with `tag` or a tag below it, sorted.
+    pub fn get_tagged(&self, tag: &str) -> Vec<i32> {
+        let mut ret = self
+            .tags
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|t| crate::tags::is_under(t, tag)))
+            .map(|(va, _)| *va)
+            .collect::<Vec<_>>();
+        ret.sort_unstable();
+        ret
     }
 
     /// Returns the comment string (or None) for a given
@@ -304,6 +490,150 @@ impl VivWorkspace {
         self.comments.clone()
     }
 
+    /// Set the name of the type which lives at the given virtual address.
+    /// An empty type name removes the annotation.
+    pub fn set_type(&mut self, va: i32, tname: &str) {
+        self.record(|| Event::SetType {
+            va,
+            tname: tname.to_string(),
+        });
+        if tname.is_empty() {
+            self.types.remove(&va);
+        } else {
+            self.types.insert(va, tname.to_string());
+        }
+    }
+
+    /// Returns the type name (or None) for a given virtual address.
+    pub fn get_type(&self, va: i32) -> Option<String> {
+        self.types.get(&va).cloned()
+    }
+
+    /// Add a structure type, replacing any of its name
+    pub fn add_structure(&mut self, structure: Structure) {
+        self.structures.insert(structure.name.clone(), structure);
+    }
+
+    pub fn get_structure(&self, name: &str) -> Option<&Structure> {
+        self.structures.get(name)
+    }
+
+    /// The structure types, by name
+    pub fn get_structures(&self) -> Vec<&Structure> {
+        self.structures.values().collect()
+    }
+
+    /// Rename a structure type, and the pointers to it in the others and the types set at VAs
+    pub fn rename_structure(&mut self, name: &str, new_name: &str) -> Result<(), String> {
+        if self.structures.contains_key(new_name) {
+            return Err(format!("There is already a structure named {}", new_name));
+        }
+        let Some(mut structure) = self.structures.remove(name) else {
+            return Err(format!("No structure named {}", name));
+        };
+        structure.name = new_name.to_string();
+        self.structures.insert(new_name.to_string(), structure);
+        for structure in self.structures.values_mut() {
+            for field in structure.fields.iter_mut() {
+                if field.ty == FieldType::Pointer(Some(name.to_string())) {
+                    field.ty = FieldType::Pointer(Some(new_name.to_string()));
+                }
+            }
+        }
+        let typed: Vec<i32> = self
+            .types
+            .iter()
+            .filter(|(_, tname)| tname.as_str() == name)
+            .map(|(va, _)| *va)
+            .collect();
+        for va in typed {
+            self.set_type(va, new_name);
+        }
+        Ok(())
+    }
+
+    /// Infer the structures the functions with IL access (see [`crate::structs`]) and add them,
+    /// returning their names
+    pub fn infer_structures(&mut self) -> Vec<String> {
+        let mut names = Vec::new();
+        for structure in structs::infer(self) {
+            names.push(structure.name.clone());
+            self.add_structure(structure);
+        }
+        names
+    }
+
+    /// End the functions with IL at their calls to functions which never return (see
+    /// [`crate::noreturn`]), recording the calls in the `NoReturnCalls` VA set and adding the
+    /// functions found after them as entry points. Returns where each function was ended.
+    pub fn split_at_no_return_calls(&mut self) -> Vec<Boundary> {
+        let no_return = noreturn::no_return_functions(self);
+        let mut boundaries = Vec::new();
+        for fva in self.get_functions_with_il() {
+            let mut func = self.func_il[&fva].clone();
+            let found = noreturn::split(self, fva as u32 as u64, &mut func, &no_return);
+            if found.is_empty() {
+                continue;
+            }
+            self.set_function_il(fva, func);
+            boundaries.extend(found);
+        }
+        let mut calls = self.get_va_set_rows("NoReturnCalls").unwrap_or_default();
+        for boundary in boundaries.iter() {
+            if !calls.contains(&(boundary.call as i32)) {
+                calls.push(boundary.call as i32);
+            }
+            if let Some(next) = boundary.next.filter(|next| !self.is_function(*next as i32)) {
+                self.add_entry_point(next as i32);
+            }
+        }
+        self.set_va_set_row("NoReturnCalls", calls);
+        boundaries
+    }
+
+    /// Resolve the indirect calls of `calls` going through a vtable of a known class (see
+    /// [`crate::devirt`]), adding code xrefs to the functions they may go to and a comment
+    pub fn devirtualize_calls(&mut self, calls: &devirt::Calls) -> Vec<Devirtualized> {
+        let found = devirt::devirtualize(self, calls);
+        for call in found.iter() {
+            for target in call.targets.iter() {
+                self.add_xref(call.call as i32, *target as i32, REF_CODE, BR_PROC);
+            }
+            let class = match (call.class.as_deref(), call.exact) {
+                (Some(class), true) => class.to_string(),
+                (Some(class), false) => format!("{} or derived", class),
+                (None, _) => "unknown class".to_string(),
+            };
+            let targets: Vec<String> = call.targets.iter().map(|t| format!("{:#x}", t)).collect();
+            let comment = format!(
+                "virtual call to {} slot {:#x}: {}",
+                class,
+                call.slot,
+                targets.join(", ")
+            );
+            self.set_comment(call.call as i32, &comment, true);
+        }
+        found
+    }
+
+    /// Add a `REF_CONST` xref from each instruction of the lifted functions using a constant of
+    /// `watchlist` to the value (see [`crate::immediates`])
+    pub fn add_immediate_xrefs(&mut self, watchlist: &Watchlist) -> Vec<ImmediateUse> {
+        let uses = immediates::find(self, watchlist);
+        for found in uses.iter() {
+            self.add_xref(found.va as i32, found.value as i32, REF_CONST, 0);
+        }
+        uses
+    }
+
+    /// The instructions using the constant `value`, as far as `add_immediate_xrefs` found
+    pub fn get_immediate_uses(&self, value: u64) -> Vec<i32> {
+        self.get_xrefs_to(value as i32, Some(REF_CONST))
+            .into_iter()
+            .map(|(from, _, _, _)| from)
+            .collect()
+    }
+
     /// Add a relocation entry for tracking.
     /// Expects data to have whatever is necessary for the reloc type. eg. addend
     pub fn add_relocation(
@@ -327,7 +657,13 @@ impl VivWorkspace {
         }
         let imgbase = self.get_file_meta(fname.as_str(), "imagebase");
         let rva = imgbase + offset;
-        self.reloc_by_va.insert(rva, r_type);
+        self.record(|| Event::AddRelocation {
+            va,
+            rtype: r_type,
+            data: ext.clone(),
+            size: size.unwrap(),
+        });
+        self.reloc_by_va.insert(rva, (r_type, size.unwrap()));
         self.relocations.push((
             fname,
             offset,
@@ -337,8 +673,17 @@ impl VivWorkspace {
         ));
         // FIXME Should be careful with this because if we add more REBASE_TYPES we break unless we add the added check. We could possibly just make REBASE_TYPES a vector and check if the vec contains the r_type.
         if REBASE_TYPES.0 == r_type || REBASE_TYPES.1 == r_type {
-            let ptr = imgbase + ext.len() as i32;
-            if ptr != (ptr & size.as_ref().cloned().unwrap()) {
+            // The addend, in the byte order of the target
+            let addend = parse_bytes(
+                Some(ext.clone()),
+                0,
+                ext.len() as i32,
+                false,
+                self.endianess,
+            );
+            let ptr = imgbase.wrapping_add(addend.unwrap_or(0));
+            let bits = size.unwrap() * 8;
+            if bits < 32 && ptr as u32 >> bits != 0 {
                 warn!("Relocations calculated a bad pointer: {:#0x} (imgbase: {:#0x}) (Relocation: {})", ptr, imgbase, r_type);
             }
             let mem_val = self.read_mem_value(rva, size.as_ref().cloned().unwrap());
@@ -378,7 +723,12 @@ impl VivWorkspace {
     /// VA or None if there isn't a relocation entry for
     /// the address.
     pub fn get_relocation(&self, va: i32) -> Option<i32> {
-        self.reloc_by_va.get(&va).cloned()
+        self.reloc_by_va.get(&va).map(|(r_type, _)| *r_type)
+    }
+
+    /// Return the number of bytes the relocation at the specified VA patches.
+    pub fn get_relocation_size(&self, va: i32) -> Option<i32> {
+        self.reloc_by_va.get(&va).map(|(_, size)| *size)
     }
 
     pub fn is_location(&self, va: i32) -> bool {
@@ -391,6 +741,10 @@ impl VivWorkspace {
         mut tova: Option<i32>,
         follow: bool,
     ) -> Option<(i32, i32, i32, Vec<(i32, i32)>)> {
+        if !self.overrides.allows_data(va) {
+            debug!("{:#0x} is overridden as code, not making a pointer", va);
+            return None;
+        }
         let loctup = self.get_location(va);
         if loctup.is_some() {
             if loctup.as_ref().cloned().unwrap().2 != LOC_POINTER
@@ -401,11 +755,11 @@ impl VivWorkspace {
             return None;
         }
         let p_size = self.p_size;
-        self.add_xref(va, tova.as_ref().cloned().unwrap(), REF_PTR, 0);
-        let ploc = self.add_location(va, p_size, LOC_POINTER, None);
         if tova.is_none() {
             tova = self.cast_pointer(va);
         }
+        self.add_xref(va, tova.as_ref().cloned()?, REF_PTR, 0);
+        let ploc = self.add_location(va, p_size, LOC_POINTER, Some(vec![]));
         if follow && self.is_valid_pointer(tova.as_ref().cloned().unwrap()) {
             self.follow_pointer(tova.as_ref().cloned().unwrap());
         }
@@ -414,26 +768,19 @@ impl VivWorkspace {
 
     pub fn add_xref(&mut self, from_va: i32, to_va: i32, ref_type: i32, r_flags: i32) {
         let reference = (from_va, to_va, ref_type, r_flags);
-        if self.get_xrefs_from(from_va, None).contains(&reference) {
+        let xr_from = self.xrefs_by_from.entry(from_va).or_default();
+        if xr_from.contains(&reference) {
             return;
         }
-        let mut xr_to = self.xrefs_by_to.get(&to_va).cloned();
-        let mut xr_from = self.xrefs_by_from.get(&from_va).cloned();
-        if xr_to.is_none() {
-            xr_to = Some(Vec::new());
-            self.xrefs_by_to
-                .insert(to_va, xr_to.as_ref().cloned().unwrap());
-        }
-        if xr_from.is_none() {
-            xr_from = Some(Vec::new());
-            self.xrefs_by_from
-                .insert(from_va, xr_from.as_ref().cloned().unwrap());
-        }
-        if !xr_to.as_ref().cloned().unwrap().contains(&reference) {
-            xr_to.unwrap().push(reference);
-            xr_from.unwrap().push(reference);
-            self.xrefs.push(reference);
-        }
+        xr_from.push(reference);
+        self.xrefs_by_to.entry(to_va).or_default().push(reference);
+        self.note_origin(Artifact::Xref(from_va, to_va, ref_type));
+        self.record(|| Event::AddXref {
+            from: from_va,
+            to: to_va,
+            rtype: ref_type,
+            rflags: r_flags,
+        });
     }
 
     pub fn add_location(
@@ -444,13 +791,30 @@ impl VivWorkspace {
         tinfo: Option<Vec<(i32, i32)>>,
     ) -> (i32, i32, i32, Vec<(i32, i32)>) {
         let ltup = (va, size, ltype, tinfo.as_ref().cloned().unwrap());
-        self.locmap.set_map_lookup(va, size, Some(ltup.clone()));
-        self.loclist.push(ltup.clone());
+        self.locations.add(ltup.clone());
+        self.note_origin(Artifact::Location(va));
+        self.record(|| Event::AddLocation {
+            va,
+            size,
+            ltype,
+            tinfo: ltup.3.clone(),
+        });
         ltup
     }
 
+    /// Move the locations of this workspace into `store`, and keep them there from now on. For
+    /// huge targets, a [`crate::locations::DiskLocations`] keeps them out of memory.
+    pub fn set_location_store(&mut self, mut store: Box<dyn LocationStore>) {
+        for loc in self.locations.iter() {
+            store.add(loc);
+        }
+        self.locations = store;
+    }
+
+    /// The pointer stored at `va`, read in the byte order of the target
     pub fn cast_pointer(&self, va: i32) -> Option<i32> {
-        todo!()
+        let bytes = self.read_memory(va, self.p_size);
+        parse_bytes(bytes, 0, self.p_size, false, self.endianess)
     }
 
     pub fn follow_pointer(&self, va: i32) {
@@ -461,8 +825,277 @@ impl VivWorkspace {
     //     self.event_list.clone()
     // }
 
-    pub fn save_workspace(&self) {
-        todo!()
+    /// Save the workspace annotations (names, comments, types and function
+    /// boundaries) to the file named by the "StorageName" meta.
+    pub fn save_workspace(&self) -> std::io::Result<()> {
+        let path = self.get_meta("StorageName").ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "No StorageName set")
+        })?;
+        self.get_annotations().save(path)
+    }
+
+    /// Take a snapshot of the user facing annotations in this workspace.
+    pub fn get_annotations(&self) -> Annotations {
+        let mut ann = Annotations::new();
+        ann.names
+            .extend(self.name_by_va.iter().map(|(va, n)| (*va, n.clone())));
+        ann.comments
+            .extend(self.comments.iter().map(|(va, c)| (*va, c.clone())));
+        ann.types
+            .extend(self.types.iter().map(|(va, t)| (*va, t.clone())));
+        ann.tags
+            .extend(self.tags.iter().map(|(va, t)| (*va, t.clone())));
+        for (fva, meta) in self.funcmeta.iter() {
+            ann.functions
+                .insert(*fva, meta.get("Size").copied().unwrap_or(0));
+        }
+        ann.regions.extend(
+            self.overrides
+                .iter()
+                .map(|(va, size, kind)| (va, (size, kind))),
+        );
+        for fva in self.funcmeta.keys() {
+            if let Some(ranges) = self.get_function_bounds(*fva) {
+                ann.bounds.insert(*fva, ranges);
+            }
+        }
+        ann.audit = self.audit_log.records.clone();
+        ann
+    }
+
+    /// Replace the annotations of this workspace with the given snapshot.
+    /// Functions missing from the snapshot are removed, the others take
+    /// their recorded size, and their bounds where it has them.
+    pub fn apply_annotations(&mut self, ann: &Annotations) {
+        self.invalidate_symbols();
+        self.snapshots.changed();
+        self.name_by_va.clear();
+        self.va_by_name.clear();
+        self.auto_names.clear();
+        for (va, name) in ann.names.iter() {
+            self.name_by_va.insert(*va, name.clone());
+            self.va_by_name.insert(name.clone(), *va);
+            if parse_auto_name(name).is_some_and(|(_, auto_va)| auto_va == *va) {
+                self.auto_names.insert(*va);
+            }
+        }
+        self.comments = ann
+            .comments
+            .iter()
+            .map(|(va, c)| (*va, c.clone()))
+            .collect();
+        self.types = ann.types.iter().map(|(va, t)| (*va, t.clone())).collect();
+        self.tags = ann.tags.iter().map(|(va, t)| (*va, t.clone())).collect();
+        let dropped: Vec<i32> = self
+            .funcmeta
+            .keys()
+            .filter(|fva| !ann.functions.contains_key(fva))
+            .copied()
+            .collect();
+        for fva in dropped {
+            self.drop_function(fva);
+        }
+        for (fva, size) in ann.functions.iter() {
+            let ranges = ann.bounds.get(fva).cloned().unwrap_or_default();
+            if ranges.is_empty() {
+                // none in the snapshot, so none kept from before it
+                if let Some(chunks) = self.func_chunks.get_mut(fva) {
+                    chunks.ranges.clear();
+                }
+            }
+            self.put_function(*fva, *size, ranges);
+        }
+        self.overrides = ann
+            .regions
+            .iter()
+            .map(|(va, (size, kind))| (*va, *size, *kind))
+            .collect();
+        self.audit_log.records = ann.audit.clone();
+    }
+
+    /// Put down the running pass and the current confidence as the origin of `artifact`.
+    fn note_origin(&mut self, artifact: Artifact) {
+        let pass = self
+            .analysis_tracker
+            .current_pass()
+            .unwrap_or_else(|| AnalysisStats::OTHER.to_string());
+        let origin = Origin::new(&pass, self.confidence);
+        self.origins.add(artifact, origin);
+    }
+
+    /// How sure analysis is of the functions, locations and xrefs it makes from now on, as a
+    /// percentage. See [`crate::origins`].
+    pub fn set_analysis_confidence(&mut self, confidence: u8) {
+        self.confidence = confidence.min(Origin::CERTAIN);
+    }
+
+    pub fn get_analysis_confidence(&self) -> u8 {
+        self.confidence
+    }
+
+    /// The pass and the confidence a function, location or xref was made with.
+    pub fn get_origin(&self, artifact: &Artifact) -> Option<Origin> {
+        self.origins.get(artifact).cloned()
+    }
+
+    /// Replace the origin of a function, location or xref, as a pass revising its guess does.
+    pub fn set_origin(&mut self, artifact: Artifact, origin: Origin) {
+        self.origins.set(artifact, origin);
+    }
+
+    /// The origins of everything made so far, by hand, by loaders and by the passes run on it.
+    pub fn get_origins(&self) -> Origins {
+        self.origins.clone()
+    }
+
+    /// Keep a journal of the changes made to this workspace from now on, by hand or by the
+    /// analysis passes run on it. A copy of the workspace goes on with a copy of the journal.
+    /// See [`crate::journal`].
+    pub fn start_journal(&mut self) {
+        if self.journal.is_none() {
+            self.journal = Some(Journal::new());
+        }
+    }
+
+    /// The journal kept so far, if one is kept.
+    pub fn journal(&self) -> Option<Journal> {
+        self.journal.clone()
+    }
+
+    /// Put the event down in the journal, if one is kept, and send it to the watches of what it
+    /// changes. The event is only made then.
+    pub(crate) fn record(&mut self, event: impl FnOnce() -> Event) {
+        self.snapshots.changed();
+        if self.journal.is_none() && self.watchers.is_empty() {
+            return;
+        }
+        let event = event();
+        self.watchers.notify(&event);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(event);
+        }
+    }
+
+    /// Hear of the changes made to the `size` bytes at `va` from now on, by hand or by the
+    /// analysis passes run on the workspace. See [`crate::watch`].
+    pub fn watch(&mut self, va: i32, size: i32) -> (WatchId, Receiver<Event>) {
+        self.watchers.add(va, size)
+    }
+
+    /// Watch the `size` bytes at `va` instead. Returns whether the watch was still there.
+    pub fn move_watch(&mut self, id: WatchId, va: i32, size: i32) -> bool {
+        self.watchers.set_range(id, va, size)
+    }
+
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        self.watchers.remove(id)
+    }
+
+    /// Make the change a journal event records.
+    pub fn apply_event(&mut self, event: &Event) {
+        match event {
+            Event::AddFile { name, imagebase } => {
+                // The name is normalized already, and the file may not be around anymore
+                let mut meta = HashMap::new();
+                meta.insert("imagebase".to_string(), *imagebase);
+                self.filemeta.insert(name.clone(), meta);
+                self.record(|| event.clone());
+            }
+            Event::AddMemoryMap {
+                va,
+                perms,
+                fname,
+                bytes,
+            } => {
+                self.add_memory_map(*va, *perms, fname, bytes.clone(), None);
+            }
+            Event::AddSegment {
+                va,
+                size,
+                name,
+                fname,
+            } => self.add_segment(*va, *size, name, fname.clone()),
+            Event::SetFileMeta { fname, key, value } => {
+                self.set_file_meta(fname.clone(), key.clone(), *value)
+            }
+            Event::AddImport {
+                va,
+                libname,
+                impname,
+            } => self.make_import(*va, libname, impname),
+            Event::AddExport { va, name, fname } => self.add_export(*va, name, fname),
+            Event::AddRelocation {
+                va,
+                rtype,
+                data,
+                size,
+            } => {
+                self.add_relocation(*va, *rtype, Some(data.clone()), Some(*size));
+            }
+            Event::AddLocation {
+                va,
+                size,
+                ltype,
+                tinfo,
+            } => {
+                self.add_location(*va, *size, *ltype, Some(tinfo.clone()));
+            }
+            Event::AddXref {
+                from,
+                to,
+                rtype,
+                rflags,
+            } => self.add_xref(*from, *to, *rtype, *rflags),
+            Event::SetName { va, name } => {
+                self.make_name(*va, name.clone(), false, true);
+            }
+            Event::Rename { va, name, .. } => {
+                if let Err(err) = self.rename(*va, name) {
+                    warn!("{}", err);
+                }
+            }
+            Event::SetComment { va, comment } => self.set_comment(*va, comment, false),
+            Event::SetType { va, tname } => self.set_type(*va, tname),
+            Event::SetMeta { key, value } => self.set_meta(key, value.clone()),
+            Event::SetVaSetRows { name, rows } => self.set_va_set_row(name, rows.clone()),
+            Event::AddFunction { fva, ranges } => {
+                self.add_function(*fva, ranges.clone());
+            }
+            Event::DelFunction { fva } => self.del_function(*fva),
+            Event::SetFunctionMeta { fva, key, value } => self.set_function_meta(*fva, key, *value),
+            Event::SetFunctionBounds { fva, ranges } => {
+                self.set_function_bounds(*fva, ranges.clone())
+            }
+            Event::AddFunctionChunk { fva, va, size } => self.add_function_chunk(*fva, *va, *size),
+            Event::DelFunctionChunk { fva, va } => self.del_function_chunk(*fva, *va),
+            Event::AddFunctionEntry { fva, va } => self.add_function_entry(*fva, *va),
+            Event::AddCodeBlock { va, size, fva } => self.add_code_block(*va, *size, *fva),
+            Event::DelCodeBlock { va } => self.del_code_block(*va),
+            Event::AddRegionOverride { va, size, kind } => {
+                self.add_region_override(*va, *size, *kind)
+            }
+            Event::AddTag { va, tag } => self.add_tag(*va, tag),
+            Event::DelTag { va, tag } => self.del_tag(*va, tag),
+            Event::SetArchitecture(arch) => self.set_mem_architecture(*arch),
+            Event::SetPointerSize(size) => self.set_pointer_size(*size),
+            Event::PatchMemory { va, bytes } => {
+                self.patch_bytes(*va, bytes);
+            }
+            Event::Pass(_) => self.record(|| event.clone()),
+        }
+    }
+
+    /// Three-way merge another analyst's annotations into this workspace.
+    /// `base` is the snapshot both sides started from.  Conflicting
+    /// changes keep the local value and are returned to the caller.
+    pub fn merge_annotations(
+        &mut self,
+        base: &Annotations,
+        theirs: &Annotations,
+    ) -> Vec<MergeConflict> {
+        let result = merge_annotations(base, &self.get_annotations(), theirs);
+        self.apply_annotations(&result.merged);
+        result.conflicts
     }
 
     /// Return ana event, event info tuple.
@@ -535,12 +1168,96 @@ impl VivWorkspace {
             if self.is_function(eva) {
                 continue;
             }
-            if !self.probe_memory(eva, 1, MM_EXEC) {
+            // code entered part way into a function is another way into it, not a function
+            let owners = self.get_functions_containing(eva);
+            if !owners.is_empty() {
+                for fva in owners {
+                    self.add_function_entry(fva, eva);
+                }
+                continue;
+            }
+            if !self.probe_memory(eva, 1, MM_EXEC) || !self.overrides.allows_code(eva) {
                 continue;
             }
             debug!("processEntryPoint: {:#0X}", eva);
             self.make_function(eva, None, ARCH_DEFAULT as i32);
         }
+        self.link_function_parts();
+    }
+
+    /// Whether the block at `va` of `size` bytes calls a stack probe or split stack routine
+    /// (`__chkstk`, Go's `runtime.morestack`, ...)
+    fn calls_stack_check(&self, va: i32, size: i32) -> bool {
+        (va..va.saturating_add(size)).any(|from| {
+            self.get_xrefs_from(from, Some(REF_CODE))
+                .iter()
+                .filter(|(.., rflags)| rflags & BR_PROC != 0)
+                .filter_map(|(_, to, ..)| self.get_name(*to, false))
+                .any(|name| {
+                    let name = name.rsplit('.').next().unwrap_or(&name).trim_start_matches('_');
+                    name.starts_with("morestack")
+                        || ["chkstk", "chkstk_ms", "alloca_probe", "probestack", "rust_probestack"]
+                            .contains(&name)
+                })
+        })
+    }
+
+    /// Give functions the parts of their code found apart from their body: the split stack
+    /// preambles which grow the stack and jump back to the entry, and the tails several
+    /// functions jump to. Returns how many chunks were added.
+    pub fn link_function_parts(&mut self) -> usize {
+        let mut preambles = Vec::new();
+        let mut tails: BTreeMap<(i32, i32), BTreeSet<i32>> = BTreeMap::new();
+        for (from, to, _, rflags) in self.get_xrefs(Some(REF_CODE)) {
+            if rflags & BR_PROC != 0 {
+                continue;
+            }
+            let Some((bva, bsize, owner, _)) = self.get_code_block(from) else {
+                continue;
+            };
+            if self.is_function(to) {
+                if owner != to
+                    && !self.get_functions_containing(from).contains(&to)
+                    && self.calls_stack_check(bva, bsize)
+                {
+                    preambles.push((to, bva, bsize));
+                }
+                continue;
+            }
+            let Some((tva, tsize, towner, _)) = self.get_code_block(to) else {
+                continue;
+            };
+            let Some(fva) = self.get_function(from) else {
+                continue;
+            };
+            if tva == to && towner != fva {
+                let sharing = tails.entry((tva, tsize)).or_default();
+                sharing.insert(fva);
+                sharing.insert(towner);
+            }
+        }
+        let shared = tails
+            .into_iter()
+            .filter(|(_, fvas)| fvas.len() > 1)
+            .flat_map(|((va, size), fvas)| fvas.into_iter().map(move |fva| (fva, va, size)));
+        let parts: Vec<(i32, i32, i32)> = preambles.into_iter().chain(shared).collect();
+        let mut added = 0;
+        for (fva, va, size) in parts {
+            if !self.is_function(fva) {
+                continue;
+            }
+            // the chunks of a function are all of its code once it has any
+            if self.get_function_bounds(fva).is_none() {
+                for (bva, bsize, ..) in self.get_function_blocks(fva) {
+                    self.add_function_chunk(fva, bva, bsize);
+                }
+            }
+            if !self.get_functions_containing(va).contains(&fva) {
+                self.add_function_chunk(fva, va, size);
+                added += 1;
+            }
+        }
+        added
     }
 
     /// Add an entry point to the definition for the given file.  This
@@ -548,12 +1265,24 @@ impl VivWorkspace {
     /// is run.
     /// NOTE: No analysis is triggered by this function.
     pub fn add_entry_point(&mut self, va: i32) {
-        self.set_va_set_row("EntryPoints", vec![va]);
+        if !self.overrides.allows_code(va) {
+            debug!("{:#0x} is overridden as not code. Skipping.", va);
+            return;
+        }
+        let mut rows = self.get_va_set_rows("EntryPoints").unwrap_or_default();
+        if !rows.contains(&va) {
+            rows.push(va);
+        }
+        self.set_va_set_row("EntryPoints", rows);
     }
 
     /// Use this API to update the row data for a particular
     /// entry in the VA set.
     pub fn set_va_set_row(&mut self, name: &str, row_tup: Vec<i32>) {
+        self.record(|| Event::SetVaSetRows {
+            name: name.to_string(),
+            rows: row_tup.clone(),
+        });
         if let Some(defs_rows_tuple) = self.vasets.get(&name.to_string()) {
             self.vasets
                 .insert(name.to_string(), (defs_rows_tuple.clone().0, row_tup));
@@ -580,15 +1309,42 @@ impl VivWorkspace {
         x.map(|(defs, rows)| rows.clone())
     }
 
-    pub fn add_analyzer(&mut self, analyzer: Rc<dyn Analyzer>) {
+    pub fn add_analyzer(&mut self, analyzer: Arc<dyn Analyzer>) {
         self.analysis_tracker.register_analyzer(analyzer);
     }
 
+    /// Offer the files loaded from now on to `loader`, after the loaders added before it
+    pub fn add_loader(&mut self, loader: Arc<dyn Loader>) {
+        self.loaders.push(loader);
+    }
+
+    pub fn get_loaders(&self) -> Vec<Arc<dyn Loader>> {
+        self.loaders.clone()
+    }
+
+    /// Name the export `ordinal` of `dll` in the PE files loaded from now on, their imports of it
+    /// and the export itself when `dll` is loaded (see [`crate::ordinals`])
+    pub fn add_ordinal_name(&mut self, dll: &str, ordinal: u16, name: &str) {
+        self.ordinal_names.add(dll, ordinal, name);
+    }
+
+    /// Name the exports by ordinal of the module-definition file `def` in the PE files loaded from
+    /// now on. Returns how many there were.
+    pub fn add_ordinal_names(&mut self, def: &str) -> Result<usize, String> {
+        self.ordinal_names.add_def(def)
+    }
+
+    pub fn get_ordinal_name(&self, dll: &str, ordinal: u16) -> Option<String> {
+        self.ordinal_names.get(dll, ordinal).map(str::to_string)
+    }
+
     /// Call this to ask any available analysis module.
     pub fn analyze(&mut self, filename: &str) {
         // let  buf = buffer.as_slice();
         // Object::parse(buffer).unwrap();
         let mut buffer = &fs::read(filename).unwrap();
+        let record = AuditRecord::new(filename, buffer);
+        let before = self.get_analysis_stats();
         // Save the analysis.
         match Object::parse(buffer).unwrap() {
             Object::Elf(elf) => {
@@ -606,6 +1362,8 @@ impl VivWorkspace {
                     meta.insert("InstructionCount".to_string(), 0);
                     meta.insert("BlockCount".to_string(), 0);
                     self.funcmeta.insert(import.rva as i32, meta);
+                    self.invalidate_symbols();
+                    self.snapshots.changed();
                     // iter().map(|x| x.rva as i32).collect::<Vec<_>>()
                 }
                 // println!("pe: {:#?}", &pe);
@@ -622,15 +1380,93 @@ impl VivWorkspace {
         }
 
         // let start_time = Local::now();
-        // // TODO: Call the analysis modules.
-        self.analysis_tracker.start_analysis(self.clone());
+        self.run_analyzers();
         // let end_time = Local::now();
         // info!("... analysis complete!  ({} sec) ", (end_time - start_time).num_seconds());
         // self.print_discovered_stats();
+        let record = record.finish(self, &before);
+        self.audit_log.push(record);
+    }
+
+    /// How much the workspace does with the files it loads (see [`crate::profile`])
+    pub fn get_profile(&self) -> Profile {
+        self.get_meta(META_PROFILE)
+            .and_then(|profile| profile.parse().ok())
+            .unwrap_or_default()
     }
 
-    pub fn analyze_function(&self, fva: i32) {
-        analyze_function(self.clone(), fva);
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.set_meta(META_PROFILE, Some(profile.to_string()));
+    }
+
+    /// The runtime the workspace's files were built for, chosen by hand or else inferred from
+    /// the loaded file (see [`crate::osprofile`])
+    pub fn get_os_profile(&self) -> Option<OsProfile> {
+        if let Some(profile) = self.get_meta(META_OS_PROFILE) {
+            return profile.parse().ok();
+        }
+        OsProfile::infer(
+            &self.get_meta("Format").unwrap_or_default(),
+            &self.get_meta("Platform").unwrap_or_default(),
+            self.get_meta(META_LIBC).as_deref(),
+        )
+    }
+
+    /// Choose the runtime profile, or go back to inferring it with None
+    pub fn set_os_profile(&mut self, profile: Option<OsProfile>) {
+        self.set_meta(META_OS_PROFILE, profile.map(|profile| profile.to_string()));
+    }
+
+    /// The record of each run of [`VivWorkspace::analyze`] (see [`crate::auditlog`])
+    pub fn get_audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    /// The names of the analysis passes, in the order they run
+    pub fn get_analyzer_names(&self) -> Vec<String> {
+        self.analysis_tracker.names()
+    }
+
+    /// Run the registered analysis passes, in the order they were added.
+    pub fn run_analyzers(&mut self) {
+        if !self.get_profile().runs_analyzers() {
+            debug!("Not running the analysis passes of the {} profile", self.get_profile());
+            return;
+        }
+        let tracker = self.analysis_tracker.clone();
+        tracker.start_analysis(self);
+        debug!("Analysis passes:\n{}", self.get_analysis_stats());
+    }
+
+    /// Run the registered analysis pass at `index`, in the order of
+    /// [`VivWorkspace::get_analyzer_names`]. Returns false if there is no such pass.
+    pub fn run_analyzer(&mut self, index: usize) -> bool {
+        let tracker = self.analysis_tracker.clone();
+        tracker.run_pass(index, self)
+    }
+
+    /// The time spent and the work done by each analysis pass so far.
+    pub fn get_analysis_stats(&self) -> AnalysisStats {
+        self.analysis_tracker.stats()
+    }
+
+    /// Count functions analyzed and instructions decoded towards the running analysis pass.
+    pub fn record_analysis(&self, functions: u64, instructions: u64) {
+        self.analysis_tracker.record(functions, instructions);
+    }
+
+    /// Find the code blocks of the function at `fva`. Functions whose blocks came back from the
+    /// symbol cache are left as they are.
+    pub fn analyze_function(&mut self, fva: i32) {
+        if self
+            .funcmeta
+            .get(&fva)
+            .is_some_and(|meta| meta.contains_key(FUNC_META_CACHED))
+        {
+            debug!("{:#0x} was restored from the symbol cache. Skipping.", fva);
+            return;
+        }
+        analyze_function(self, fva);
     }
 
     pub fn get_stats(&self) -> HashMap<&str, i32> {
@@ -734,20 +1570,21 @@ impl VivWorkspace {
     }
 
     pub fn get_xrefs(&self, r_type: Option<i32>) -> Vec<(i32, i32, i32, i32)> {
-        if let Some(r_type) = r_type {
-            return self
-                .xrefs
-                .iter()
-                .filter(|x| x.2 == r_type)
-                .copied()
-                .collect::<Vec<_>>();
-        }
-        self.xrefs.clone()
+        let mut ret = self
+            .xrefs_by_from
+            .values()
+            .flatten()
+            .filter(|x| r_type.is_none_or(|r_type| x.2 == r_type))
+            .copied()
+            .collect::<Vec<_>>();
+        ret.sort_unstable_by_key(xref_order);
+        ret
     }
 
-    pub fn get_functions(&mut self) -> Vec<i32> {
-        // Vec::new()
-        self.funcmeta.iter().map(|x| *x.0).collect::<Vec<_>>()
+    pub fn get_functions(&self) -> Vec<i32> {
+        let mut ret = self.funcmeta.keys().copied().collect::<Vec<_>>();
+        ret.sort_unstable_by_key(|va| *va as u32);
+        ret
     }
 
     pub fn get_function_meta_dict(&self, va: i32) -> HashMap<String, i32> {
@@ -755,7 +1592,9 @@ impl VivWorkspace {
     }
 
     pub fn get_code_blocks(&self) -> Vec<(i32, i32, i32, Vec<(i32, i32)>)> {
-        self.codeblocks.clone()
+        let mut ret = self.codeblocks.clone();
+        ret.sort_by(block_order);
+        ret
     }
 
     /// Return a list of location objects from the workspace
@@ -766,23 +1605,21 @@ impl VivWorkspace {
         linfo: Option<Vec<(i32, i32)>>,
     ) -> Vec<(i32, i32, i32, Vec<(i32, i32)>)> {
         if ltype.is_none() {
-            return self.loclist.clone();
+            return self.locations.iter().collect();
         }
         if linfo.is_none() {
             return self
-                .loclist
+                .locations
                 .iter()
                 .filter(|loc| loc.2 == ltype.as_ref().cloned().unwrap())
-                .cloned()
                 .collect::<Vec<_>>();
         }
-        self.loclist
+        self.locations
             .iter()
             .filter(|loc| {
                 loc.2 == ltype.as_ref().cloned().unwrap()
                     && loc.3 == linfo.as_ref().cloned().unwrap()
             })
-            .cloned()
             .collect::<Vec<_>>()
     }
 
@@ -792,33 +1629,29 @@ impl VivWorkspace {
 
     pub fn snap_in_analysis_modules(&self) {}
 
-    pub fn get_function_blocks(&mut self, func_va: i32) -> Vec<(i32, i32, i32, Vec<(i32, i32)>)> {
-        let mut ret = self.codeblocks_by_funcva.get(&func_va).cloned();
-        if ret.is_none() {
-            ret = Some(Vec::new());
-        }
-        ret.unwrap()
+    pub fn get_function_blocks(&self, func_va: i32) -> Vec<(i32, i32, i32, Vec<(i32, i32)>)> {
+        let mut ret = self
+            .codeblocks_by_funcva
+            .get(&func_va)
+            .cloned()
+            .unwrap_or_default();
+        ret.sort_by(block_order);
+        ret
     }
 
     /// Get a list of xrefs which point to the given va. Optionally,
     /// specify an rtype to get only xrefs of that type.
     pub fn get_xrefs_to(&self, va: i32, r_type: Option<i32>) -> Vec<(i32, i32, i32, i32)> {
-        let mut ret = Vec::new();
-        let xrefs = self.xrefs_by_to.get(&va);
-        if xrefs.is_none() {
-            return ret;
-        }
-        if r_type.is_none() {
-            return xrefs.as_ref().cloned().unwrap().clone();
-        }
-        xrefs
-            .as_ref()
-            .cloned()
-            .unwrap()
-            .iter()
-            .filter(|x_tup| x_tup.2 == r_type.as_ref().cloned().unwrap())
+        let mut ret = self
+            .xrefs_by_to
+            .get(&va)
+            .into_iter()
+            .flatten()
+            .filter(|x_tup| r_type.is_none_or(|r_type| x_tup.2 == r_type))
             .copied()
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>();
+        ret.sort_unstable_by_key(xref_order);
+        ret
     }
 
     /// Return a list of tuples for the xrefs whose origin is the
@@ -828,72 +1661,64 @@ impl VivWorkspace {
     /// for fromva, tova, rtype, rflags in vw.getXrefsFrom(0x41414141):
     /// dostuff(tova)
     pub fn get_xrefs_from(&self, va: i32, r_type: Option<i32>) -> Vec<(i32, i32, i32, i32)> {
-        let mut ret = Vec::new();
-        let xrefs = self.xrefs_by_from.get(&va);
-        if xrefs.is_none() {
-            return ret;
-        }
-        if r_type.is_none() {
-            return xrefs.as_ref().cloned().unwrap().clone();
-        }
-        xrefs
-            .as_ref()
-            .cloned()
-            .unwrap()
-            .iter()
-            .filter(|x_tup| x_tup.2 == r_type.as_ref().cloned().unwrap())
+        let mut ret = self
+            .xrefs_by_from
+            .get(&va)
+            .into_iter()
+            .flatten()
+            .filter(|x_tup| r_type.is_none_or(|r_type| x_tup.2 == r_type))
             .copied()
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>();
+        ret.sort_unstable_by_key(xref_order);
+        ret
     }
 
+    /// The code block containing `va`
     pub fn get_code_block(&self, va: i32) -> Option<(i32, i32, i32, Vec<(i32, i32)>)> {
-        self.blockmap.get_map_lookup(va)
+        self.codeblocks
+            .iter()
+            .find(|(cbva, size, ..)| *cbva <= va && (va as i64) < *cbva as i64 + *size as i64)
+            .cloned()
     }
 
-    pub fn add_code_block(&self, p0: i32, p1: &i32, p2: i32) {
-        todo!()
+    pub fn add_code_block(&mut self, va: i32, size: i32, funcva: i32) {
+        self.record(|| Event::AddCodeBlock {
+            va,
+            size,
+            fva: funcva,
+        });
+        let cb = (va, size, funcva, Vec::new());
+        self.codeblocks.push(cb.clone());
+        self.codeblocks_by_funcva
+            .entry(funcva)
+            .or_default()
+            .push(cb);
     }
 
     pub fn del_code_block(&mut self, va: i32) {
-        let cb = self.get_code_block(va);
-        if cb.is_none() {
+        let Some(cb) = self.get_code_block(va) else {
             panic!("Unknown code block: {:#0x}", va);
+        };
+        self.codeblocks.retain(|x| *x != cb);
+        if let Some(blocks) = self.codeblocks_by_funcva.get_mut(&cb.2) {
+            blocks.retain(|x| *x != cb);
         }
-        self.codeblocks.remove(
-            self.codeblocks
-                .iter()
-                .position(|x| *x == cb.as_ref().cloned().unwrap())
-                .unwrap(),
-        );
-        let cb_index = self
-            .codeblocks_by_funcva
-            .get(&cb.as_ref().cloned().unwrap().0)
-            .unwrap()
-            .iter()
-            .position(|x| x.clone() == cb.as_ref().cloned().unwrap())
-            .expect("Code block not present.");
-        self.codeblocks_by_funcva
-            .get_mut(&cb.as_ref().cloned().unwrap().0)
-            .unwrap()
-            .remove(cb_index);
-        self.blockmap
-            .set_map_lookup(va, cb.as_ref().cloned().unwrap().1, None);
+        self.record(|| Event::DelCodeBlock { va });
     }
 
     pub fn set_function_meta(&mut self, funcva: i32, key: &str, val: i32) {
         if !self.is_function(funcva) {
             panic!("Invalid function: {}", funcva);
         }
-        let mut m = self.funcmeta.get(&funcva);
-        if m.is_some() {
-            let mut copy = m.as_ref().cloned().unwrap().clone();
-            copy.insert(key.to_string(), val);
-            m = Some(&copy);
+        if let Some(meta) = self.funcmeta.get_mut(&funcva) {
+            meta.insert(key.to_string(), val);
         }
-        let mcbname = format!(
-            "_fmcb_{}",
-            key.split(':').collect::<Vec<_>>().first().unwrap()
-        );
+        self.invalidate_symbols();
+        self.record(|| Event::SetFunctionMeta {
+            fva: funcva,
+            key: key.to_string(),
+            value: val,
+        });
     }
 
     /// Parse an opcode from the specified virtual address.
@@ -1036,7 +1861,7 @@ impl VivWorkspace {
     }
 
     pub fn get_location(&self, va: i32) -> Option<(i32, i32, i32, Vec<(i32, i32)>)> {
-        let loc = self.locmap.get_map_lookup(va);
+        let loc = self.locations.get(va);
         // if loc.is_none() {
         //     return None;
         // }
@@ -1068,11 +1893,19 @@ impl VivWorkspace {
     }
 
     pub fn get_entry_points(&self) -> Vec<i32> {
-        let entry_points = self.get_va_set_rows("EntryPoints").unwrap();
+        let mut entry_points = self.get_va_set_rows("EntryPoints").unwrap_or_default();
+        entry_points.sort_unstable_by_key(|va| *va as u32);
         info!("Entry points {:?}", entry_points);
         entry_points
     }
 
+    /// The normalized names of the files loaded, in order
+    pub fn get_files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.filemeta.keys().cloned().collect();
+        files.sort_unstable();
+        files
+    }
+
     pub fn get_file_meta(&self, filename: &str, key: &str) -> i32 {
         let d = self.filemeta.get(filename);
         if d.is_none() {
@@ -1085,8 +1918,14 @@ impl VivWorkspace {
         if !self.filemeta.contains_key(&fname) {
             panic!("Invalid file: {}", fname);
         }
+        self.record(|| Event::SetFileMeta {
+            fname: fname.clone(),
+            key: key.clone(),
+            value: val,
+        });
         let mut f = self.filemeta.get_mut(&fname).unwrap();
         f.insert(key, val);
+        self.invalidate_symbols();
     }
 
     pub fn get_file_meta_dict(&self, filename: &str) -> HashMap<String, i32> {
@@ -1103,6 +1942,10 @@ impl VivWorkspace {
             debug!("{:#0x} is already a function. Skipping.", va);
             return;
         }
+        if !self.overrides.allows_code(va) {
+            debug!("{:#0x} is overridden as not code. Skipping.", va);
+            return;
+        }
         if !self.is_valid_pointer(va) {
             panic!("Invalid location provided. {:#0x}", va);
         }
@@ -1121,6 +1964,139 @@ impl VivWorkspace {
         }
     }
 
+    /// Record a function known from elsewhere, such as another tool's project, with the (va,
+    /// size) ranges of its code if they are known. Returns false if an override rules out code
+    /// at `fva`.
+    pub fn add_function(&mut self, fva: i32, ranges: Vec<(i32, i32)>) -> bool {
+        if !self.overrides.allows_code(fva) {
+            debug!("{:#0x} is overridden as not code. Skipping.", fva);
+            return false;
+        }
+        self.record(|| Event::AddFunction {
+            fva,
+            ranges: ranges.clone(),
+        });
+        let size = ranges.iter().map(|(_, size)| *size).sum();
+        self.note_origin(Artifact::Function(fva));
+        self.put_function(fva, size, ranges);
+        true
+    }
+
+    /// Keep the function at `fva` of `size` bytes, made up of `ranges` if they're known
+    fn put_function(&mut self, fva: i32, size: i32, ranges: Vec<(i32, i32)>) {
+        self.funcmeta
+            .entry(fva)
+            .or_default()
+            .insert("Size".to_string(), size);
+        if !ranges.is_empty() {
+            self.put_function_bounds(fva, ranges);
+        }
+        self.invalidate_symbols();
+    }
+
+    /// Forget what is kept of the function at `fva`. False if there's no function there.
+    fn drop_function(&mut self, fva: i32) -> bool {
+        if self.funcmeta.remove(&fva).is_none() {
+            return false;
+        }
+        self.origins.remove(&Artifact::Function(fva));
+        self.func_chunks.remove(&fva);
+        self.func_args.remove(&fva);
+        self.func_il.remove(&fva);
+        self.reg_states.remove(&fva);
+        self.invalidate_symbols();
+        self.snapshots.changed();
+        true
+    }
+
+    /// Set the (va, size) ranges of code making up a function, from a source which knows them
+    /// for sure such as the exception directory. Every address in the ranges belongs to the
+    /// function, whatever code flow analysis finds.
+    pub fn set_function_bounds(&mut self, fva: i32, ranges: Vec<(i32, i32)>) {
+        self.record(|| Event::SetFunctionBounds {
+            fva,
+            ranges: ranges.clone(),
+        });
+        self.put_function_bounds(fva, ranges);
+    }
+
+    fn put_function_bounds(&mut self, fva: i32, mut ranges: Vec<(i32, i32)>) {
+        ranges.sort_unstable();
+        self.func_chunks.entry(fva).or_default().ranges = ranges;
+        self.invalidate_symbols();
+        self.snapshots.changed();
+    }
+
+    pub fn get_function_bounds(&self, fva: i32) -> Option<Vec<(i32, i32)>> {
+        self.func_chunks
+            .get(&fva)
+            .filter(|chunks| !chunks.ranges.is_empty())
+            .map(|chunks| chunks.ranges.clone())
+    }
+
+    /// Add a range of code to a function, apart from its body. A range may belong to several
+    /// functions, as tails shared between functions do.
+    pub fn add_function_chunk(&mut self, fva: i32, va: i32, size: i32) {
+        self.record(|| Event::AddFunctionChunk { fva, va, size });
+        let ranges = &mut self.func_chunks.entry(fva).or_default().ranges;
+        if !ranges.contains(&(va, size)) {
+            ranges.push((va, size));
+            ranges.sort_unstable();
+        }
+        self.invalidate_symbols();
+    }
+
+    pub fn del_function_chunk(&mut self, fva: i32, va: i32) {
+        self.record(|| Event::DelFunctionChunk { fva, va });
+        if let Some(chunks) = self.func_chunks.get_mut(&fva) {
+            chunks.ranges.retain(|(rva, _)| *rva != va);
+        }
+        self.invalidate_symbols();
+    }
+
+    /// Add an entry point to a function besides its VA, for code entered part way in.
+    pub fn add_function_entry(&mut self, fva: i32, va: i32) {
+        self.record(|| Event::AddFunctionEntry { fva, va });
+        let entries = &mut self.func_chunks.entry(fva).or_default().entries;
+        if va != fva && !entries.contains(&va) {
+            entries.push(va);
+            entries.sort_unstable_by_key(|va| *va as u32);
+        }
+        self.invalidate_symbols();
+    }
+
+    /// The entry points of a function other than its VA.
+    pub fn get_function_entries(&self, fva: i32) -> Vec<i32> {
+        self.func_chunks
+            .get(&fva)
+            .map(|chunks| chunks.entries.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_function_chunks(&self, fva: i32) -> Option<FunctionChunks> {
+        self.func_chunks.get(&fva).cloned()
+    }
+
+    /// Every function whose chunks or entries hold `va`, sorted. Shared tails belong to more
+    /// than one.
+    pub fn get_functions_containing(&self, va: i32) -> Vec<i32> {
+        let mut fvas: Vec<i32> = self
+            .func_chunks
+            .iter()
+            .filter(|(_, chunks)| chunks.contains(va) || chunks.entries.contains(&va))
+            .map(|(fva, _)| *fva)
+            .collect();
+        fvas.sort_unstable();
+        fvas
+    }
+
+    /// Forget the function at `fva`: its metadata, bounds and arguments. Its code stays.
+    pub fn del_function(&mut self, fva: i32) {
+        if self.drop_function(fva) {
+            self.record(|| Event::DelFunction { fva });
+        }
+    }
+
     pub fn is_function(&self, func_va: i32) -> bool {
         self.funcmeta.get(&func_va).is_some()
     }
@@ -1137,7 +2113,7 @@ impl VivWorkspace {
         let mut base_va = self.get_function(va).unwrap();
         let mut base_name = self.name_by_va.get(&base_va).cloned();
         if self.is_function(va) {
-            base_name = Some(format!("sub_{:#0x}", va));
+            base_name = Some(auto_name(AutoKind::Function, va));
         }
         if base_name.is_none() {
             base_name = self.get_file_by_va(va);
@@ -1153,6 +2129,67 @@ impl VivWorkspace {
         name
     }
 
+    /// Resolve a VA to the function or nearest preceding name it falls in, as `name+offset`
+    /// (see `SymbolIndex::lookup`). The index over all loaded modules is built on first use and
+    /// rebuilt after names, functions or segments change.
+    pub fn resolve(&self, va: i32) -> Option<Resolved> {
+        self.symbol_index
+            .get_or_init(|| self.build_symbol_index())
+            .lookup(va as u32 as u64)
+    }
+
+    /// Drop the symbol index, for the next [`VivWorkspace::resolve`] to build again. Whatever
+    /// changes the names, functions, segments or image bases calls this.
+    fn invalidate_symbols(&mut self) {
+        self.symbol_index = OnceLock::new();
+    }
+
+    /// Build an index of the current functions, names and segments, e.g. to resolve addresses
+    /// without holding on to the workspace.
+    pub fn build_symbol_index(&self) -> SymbolIndex {
+        let zext = |va: i32| va as u32 as u64;
+        let mut index = SymbolIndex::new();
+        for (va, size, _, fname) in self.segments.iter() {
+            let imagebase = self
+                .filemeta
+                .get(fname)
+                .and_then(|meta| meta.get("imagebase"))
+                .copied()
+                .unwrap_or(*va);
+            index.add_range(zext(*va), zext(*size), fname, zext(imagebase));
+        }
+        for (va, name) in self.name_by_va.iter() {
+            index.add_name(zext(*va), name);
+        }
+        for (fva, meta) in self.funcmeta.iter() {
+            let name = match self.name_by_va.get(fva) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = auto_name(AutoKind::Function, *fva);
+                    index.add_name(zext(*fva), &name);
+                    name
+                }
+            };
+            // a function made of chunks is indexed by each of them, and holds its VA anyway
+            match self.func_chunks.get(fva).filter(|c| !c.ranges.is_empty()) {
+                Some(chunks) => {
+                    for (va, size) in chunks.ranges.iter() {
+                        index.add_function_chunk(zext(*fva), zext(*va), zext(*size), &name);
+                    }
+                    if !chunks.contains(*fva) {
+                        index.add_function(zext(*fva), 0, &name);
+                    }
+                }
+                None => {
+                    let size = zext(meta.get("Size").copied().unwrap_or(0));
+                    index.add_function(zext(*fva), size, &name);
+                }
+            }
+        }
+        index.finish();
+        index
+    }
+
     /// Set a readable name for the given location by va. There
     /// *must* be a Location defined for the VA before you may name
     /// it.  You may set a location's name to None to remove a name.
@@ -1182,7 +2219,7 @@ impl VivWorkspace {
             }
         }
         let old_va: Option<i32> = self.va_by_name(name.clone());
-        if old_va.as_ref().cloned().unwrap() == va {
+        if old_va == Some(va) {
             return None;
         }
         if old_va.is_some() {
@@ -1226,12 +2263,177 @@ impl VivWorkspace {
         }
         self.va_by_name.insert(name.clone(), va);
         self.name_by_va.insert(va, name.clone());
+        self.auto_names.remove(&va);
+        self.invalidate_symbols();
+        self.record(|| Event::SetName {
+            va,
+            name: name.clone(),
+        });
         if self.is_function(va) {
             // Handle if its a function by modifying the call graph
         }
         Some(name)
     }
 
+    /// Give `va` the automatic name for `kind` (see [`crate::naming`]), unless it already has a
+    /// name which isn't automatic. Returns the name `va` ends up with.
+    pub fn make_auto_name(&mut self, va: i32, kind: AutoKind) -> Option<String> {
+        let auto = auto_name(kind, va);
+        if let Some(name) = self.name_by_va.get(&va) {
+            if !self.auto_names.contains(&va) || *name == auto {
+                return Some(name.clone());
+            }
+        }
+        let name = self.make_name(va, auto, false, true)?;
+        self.auto_names.insert(va);
+        Some(name)
+    }
+
+    /// Rename `va` to `name`, taken as is, and rewrite the references to its old name along
+    /// with it: the comments naming it (see [`crate::naming::replace_token`]), its entry in the
+    /// export table and the metadata values of [`NAME_META_KEYS`]. Where both names
+    /// have the file prefix of `va`, comments naming it without the prefix are rewritten too.
+    /// The whole change is one [`Event::Rename`]. Fails, changing nothing, if another VA has
+    /// the name.
+    pub fn rename(&mut self, va: i32, name: &str) -> Result<Renamed, String> {
+        if name.is_empty() {
+            return Err(format!("Empty name for {:#x}", va as u32));
+        }
+        if let Some(other) = self.va_by_name.get(name).filter(|other| **other != va) {
+            return Err(format!("{} is already the name of {:#x}", name, *other as u32));
+        }
+        let old = self.name_by_va.get(&va).cloned();
+        let mut renamed = Renamed {
+            old: old.clone(),
+            ..Default::default()
+        };
+        if old.as_deref() == Some(name) {
+            return Ok(renamed);
+        }
+        if let Some(old) = old.as_ref() {
+            self.va_by_name.remove(old);
+        }
+        self.va_by_name.insert(name.to_string(), va);
+        self.name_by_va.insert(va, name.to_string());
+        self.auto_names.remove(&va);
+        self.invalidate_symbols();
+        if let Some(old) = old {
+            let mut pairs = vec![(old.clone(), name.to_string())];
+            if let Some(fname) = self.get_file_by_va(va) {
+                let prefix = format!("{}.", fname);
+                if let (Some(old), Some(new)) = (old.strip_prefix(&prefix), name.strip_prefix(&prefix))
+                {
+                    pairs.push((old.to_string(), new.to_string()));
+                }
+            }
+            // exports are keyed by their bare name, the workspace name less the file prefix
+            if self.exports.contains(&va) {
+                let entry = self
+                    .exports_by_va
+                    .iter()
+                    .find(|(bare, fname)| old == format!("{}.{}", fname, bare))
+                    .map(|(bare, fname)| (bare.clone(), fname.clone()));
+                if let Some((bare, fname)) = entry {
+                    let new = name.strip_prefix(&format!("{}.", fname)).unwrap_or(name);
+                    self.exports_by_va.remove(&bare);
+                    self.exports_by_va.insert(new.to_string(), fname);
+                    renamed.export = true;
+                }
+            }
+            let rewrite = |text: &str| {
+                pairs.iter().fold(None, |done: Option<String>, (old, new)| {
+                    let text = done.as_deref().unwrap_or(text);
+                    replace_token(text, old, new).or(done)
+                })
+            };
+            for (cva, comment) in self.comments.iter_mut() {
+                if let Some(text) = rewrite(comment) {
+                    *comment = text;
+                    renamed.comments.push(*cva);
+                }
+            }
+            for (key, value) in self.metadata.iter_mut() {
+                if !NAME_META_KEYS.contains(&key.as_str()) {
+                    continue;
+                }
+                if let Some(text) = value.as_deref().and_then(rewrite) {
+                    *value = Some(text);
+                    renamed.meta.push(key.clone());
+                }
+            }
+            renamed.comments.sort_unstable();
+            renamed.meta.sort();
+        }
+        self.record(|| Event::Rename {
+            va,
+            name: name.to_string(),
+            comments: renamed.comments.clone(),
+        });
+        Ok(renamed)
+    }
+
+    /// Whether the name of `va` was made by analysis rather than given by a user or a file.
+    pub fn is_auto_name(&self, va: i32) -> bool {
+        self.auto_names.contains(&va)
+    }
+
+    fn del_name(&mut self, va: i32) {
+        if let Some(name) = self.name_by_va.remove(&va) {
+            self.va_by_name.remove(&name);
+        }
+        self.auto_names.remove(&va);
+        self.invalidate_symbols();
+        self.snapshots.changed();
+    }
+
+    /// Bring the automatic names up to date with the analysis: name every function, branch
+    /// target within a function, string, vtable and pointer which has no name, rename those
+    /// whose automatic name no longer fits and drop the automatic names of what is gone. Names
+    /// given by users or files are left alone. Returns the number of names made or dropped.
+    pub fn apply_auto_names(&mut self) -> usize {
+        let mut wanted: BTreeMap<i32, AutoKind> = BTreeMap::new();
+        let mut want = |va: i32, kind: AutoKind| {
+            let entry = wanted.entry(va).or_insert(kind);
+            if kind.precedence() > entry.precedence() {
+                *entry = kind;
+            }
+        };
+        for (va, _, ltype, _) in self.locations.iter() {
+            if let Some(kind) = AutoKind::for_location(ltype) {
+                want(va, kind);
+            }
+        }
+        for (_, to, rtype, rflags) in self.xrefs_by_from.values().flatten() {
+            if *rtype == REF_CODE && rflags & BR_PROC == 0 && self.get_function(*to).is_some() {
+                want(*to, AutoKind::Label);
+            }
+        }
+        for fva in self.funcmeta.keys() {
+            want(*fva, AutoKind::Function);
+        }
+
+        let mut changed = 0;
+        let mut stale: Vec<i32> = self
+            .auto_names
+            .iter()
+            .filter(|va| !wanted.contains_key(va))
+            .copied()
+            .collect();
+        stale.sort_unstable();
+        for va in stale {
+            self.del_name(va);
+            changed += 1;
+        }
+        for (va, kind) in wanted {
+            let before = self.name_by_va.get(&va).cloned();
+            let after = self.make_auto_name(va, kind);
+            if after != before {
+                changed += 1;
+            }
+        }
+        changed
+    }
+
     pub fn detect_string(&self, va: i32) -> i32 {
         todo!()
     }
@@ -1246,6 +2448,10 @@ impl VivWorkspace {
         if self.funcmeta.get(&va).is_some() {
             return Some(va);
         }
+        // Known chunks win over whatever code flow analysis glued together.
+        if let Some(fva) = self.get_functions_containing(va).first() {
+            return Some(*fva);
+        }
         let cbtup = self.get_code_block(va);
         if let Some(cbtup_val) = cbtup {
             return Some(cbtup_val.2);
@@ -1325,6 +2531,35 @@ impl VivWorkspace {
         (va, size, tinfo)
     }
 
+    /// Every named address with its name, sorted by address.
+    pub fn get_names(&self) -> Vec<(i32, String)> {
+        let mut ret = self
+            .name_by_va
+            .iter()
+            .map(|(va, name)| (*va, name.clone()))
+            .collect::<Vec<_>>();
+        ret.sort_unstable();
+        ret
+    }
+
+    /// A view of the locations, xrefs, functions and names as they are now, which analysis
+    /// going on doesn't change. See [`crate::snapshot`].
+    pub fn snapshot_view(&self) -> Snapshot {
+        self.snapshots.get_or_take(|generation| {
+            Snapshot::new(
+                generation,
+                Stores {
+                    locations: self.locations.persistent(),
+                    xrefs_by_from: self.xrefs_by_from.clone(),
+                    xrefs_by_to: self.xrefs_by_to.clone(),
+                    functions: self.funcmeta.clone(),
+                    chunks: self.func_chunks.clone(),
+                    names: self.name_by_va.clone(),
+                },
+            )
+        })
+    }
+
     pub fn va_by_name(&self, name: String) -> Option<i32> {
         self.va_by_name.get(&name).copied()
     }
@@ -1354,12 +2589,95 @@ impl VivWorkspace {
         None
     }
 
+    /// What driver analysis found, if the workspace is of a kernel driver
+    pub fn get_driver_info(&self) -> Option<&DriverInfo> {
+        self.driver_info.as_ref()
+    }
+
+    pub fn set_driver_info(&mut self, info: Option<DriverInfo>) {
+        self.driver_info = info;
+    }
+
+    pub fn get_segments(&self) -> Vec<(i32, i32, String, String)> {
+        self.segments.clone()
+    }
+
     pub fn add_segment(&mut self, va: i32, size: i32, name: &str, filename: String) {
+        self.record(|| Event::AddSegment {
+            va,
+            size,
+            name: name.to_string(),
+            fname: filename.clone(),
+        });
         self.segments.push((va, size, name.to_string(), filename));
+        self.invalidate_symbols();
+    }
+
+    /// The (type, name) of each argument of the function at `va`, as debug info or a user
+    /// gave them; the type is empty where it isn't known.
+    pub fn get_function_args(&self, va: i32) -> Vec<(String, String)> {
+        self.func_args.get(&va).cloned().unwrap_or_default()
+    }
+
+    pub fn set_function_args(&mut self, va: i32, args: Vec<(String, String)>) {
+        self.func_args.insert(va, args);
+    }
+
+    /// The IL of the function at `fva`, if it was given one with `set_function_il`
+    pub fn get_function_il(&self, fva: i32) -> Option<&Function> {
+        self.func_il.get(&fva)
+    }
+
+    /// The functions given IL with `set_function_il`, sorted
+    pub fn get_functions_with_il(&self) -> Vec<i32> {
+        let mut fvas: Vec<i32> = self.func_il.keys().copied().collect();
+        fvas.sort_unstable();
+        fvas
+    }
+
+    /// Give the function at `fva` the IL a lifter made of it, for the queries working on the IL
+    /// (see `get_reg_state`). The workspace has no lifter of its own.
+    pub fn set_function_il(&mut self, fva: i32, func: Function) {
+        self.func_il.insert(fva, func);
+        self.reg_states.remove(&fva);
+    }
+
+    /// The value of each register before the instruction at `va`, as a constant, an offset
+    /// from the stack pointer at the function entry, or the address of a name; a register
+    /// missing is unknown (see [`crate::regstate`]). None if `va` isn't an instruction of a
+    /// function with IL. The registers of the whole function are worked out on the first query
+    /// in it, and kept until its IL changes.
+    pub fn get_reg_state(&mut self, va: i32) -> Option<RegState> {
+        let fva = self.get_function(va)?;
+        if !self.reg_states.contains_key(&fva) {
+            let states = regstate::analyze(self.func_il.get(&fva)?);
+            self.reg_states.insert(fva, states);
+        }
+        let mut state = self.reg_states[&fva].get(&(va as u32 as u64))?.clone();
+        for value in state.values_mut() {
+            if let Value::Const(c) = *value {
+                let name = u32::try_from(c)
+                    .ok()
+                    .and_then(|c| self.get_name(c as i32, false));
+                if let Some(name) = name {
+                    *value = Value::Symbol { va: c, name };
+                }
+            }
+        }
+        Some(state)
     }
 
-    pub fn get_function_args(&self, va: i32) -> HashMap<String, String> {
-        self.func_args.clone()
+    /// The instructions of functions with IL the value of `operand` as the instruction at `va`
+    /// reads it depends on, and if `interprocedural` those in the callers passing the
+    /// parameters it depends on (see [`crate::slicing`])
+    pub fn slice_backward(&self, va: i32, operand: Operand, interprocedural: bool) -> BTreeSet<u64> {
+        slicing::backward(self, va as u32 as u64, operand, interprocedural)
+    }
+
+    /// The instructions of functions with IL depending on `operand` at `va`, and if
+    /// `interprocedural` those in the callees it is passed to (see [`crate::slicing`])
+    pub fn slice_forward(&self, va: i32, operand: Operand, interprocedural: bool) -> BTreeSet<u64> {
+        slicing::forward(self, va as u32 as u64, operand, interprocedural)
     }
 
     pub fn add_vaset(&mut self, name: &str, defs: Vec<(&str, i32)>) {
@@ -1395,9 +2713,22 @@ impl VivWorkspace {
         //     self.load_workspace(filename);
         //     return self.norm_filename(filename);
         // }
-        // let fname = parse_file(self.clone(), filename, base_addr);
-        // fname
-        parse_file(self.clone(), filename, base_addr)
+        let fname = parse_file(self, filename, base_addr);
+        self.apply_symbol_cache(&fname);
+        fname
+    }
+
+    /// Load a file from its contents rather than from the file system, as a browser or a
+    /// caller holding the file in memory has it. `filename` is the name it is loaded under.
+    pub fn load_from_bytes(
+        &mut self,
+        filename: &str,
+        bytes: &[u8],
+        base_addr: Option<i32>,
+    ) -> String {
+        let fname = parse_contents(self, filename, bytes, base_addr);
+        self.apply_symbol_cache(&fname);
+        fname
     }
 
     pub fn add_file(&mut self, filename: &str, imagebase: i32, bytes: Vec<u8>) -> String {
@@ -1408,17 +2739,457 @@ impl VivWorkspace {
         let mut meta = HashMap::new();
         meta.insert("imagebase".to_string(), imagebase);
         // self.set_file_meta(nname.clone(), "OrigName", filename);
-        self.filemeta.insert(filename.to_string(), meta);
+        self.filemeta.insert(nname.clone(), meta);
+        self.record(|| Event::AddFile {
+            name: nname.clone(),
+            imagebase,
+        });
         nname
     }
 
+    /// Record the identity (UUID, build-id or PDB GUID) of a loaded file.
+    pub fn set_module_id(&mut self, fname: &str, module_id: Vec<u8>) {
+        self.module_ids.insert(fname.to_string(), module_id);
+    }
+
+    pub fn get_module_id(&self, fname: &str) -> Option<Vec<u8>> {
+        self.module_ids.get(fname).cloned()
+    }
+
+    /// Record the content id (see [`crate::symcache::content_id`]) of a loaded file.
+    pub fn set_content_id(&mut self, fname: &str, content_id: Vec<u8>) {
+        self.content_ids.insert(fname.to_string(), content_id);
+    }
+
+    pub fn get_content_id(&self, fname: &str) -> Option<Vec<u8>> {
+        self.content_ids.get(fname).cloned()
+    }
+
+    /// The symbol cache keys of a file: its content id, then its module id.
+    fn symbol_cache_keys(&self, fname: &str) -> Vec<Vec<u8>> {
+        [self.get_content_id(fname), self.get_module_id(fname)]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Create an import location named "<libname>.<impname>" at the
+    /// given va (typically the IAT/GOT slot).
+    pub fn make_import(&mut self, va: i32, libname: &str, impname: &str) {
+        self.record(|| Event::AddImport {
+            va,
+            libname: libname.to_string(),
+            impname: impname.to_string(),
+        });
+        if !self.imports.contains(&va) {
+            self.imports.push(va);
+        }
+        if self.name_by_va.contains_key(&va) {
+            return;
+        }
+        self.make_name(va, format!("{}.{}", libname, impname), false, true);
+    }
+
+    pub fn get_imports(&self) -> Vec<(i32, String)> {
+        let mut ret = self
+            .imports
+            .iter()
+            .map(|va| (*va, self.name_by_va.get(va).cloned().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        ret.sort_unstable_by_key(|(va, _)| *va as u32);
+        ret
+    }
+
+    /// Record the trampoline at `va` (a PLT entry, a Mach-O stub or an import thunk) which jumps
+    /// through the import slot `slot`.
+    pub fn add_import_stub(&mut self, va: i32, slot: i32) {
+        if self.overrides.allows_code(va) {
+            self.import_stubs.insert(va, slot);
+        }
+    }
+
+    /// Override what analysis may make of `size` bytes from `va` (see
+    /// [`crate::overrides`]). Functions, entry points and import trampolines the override rules
+    /// out are dropped right away, and the start of a forced code region becomes an entry point.
+    pub fn add_region_override(&mut self, va: i32, size: i32, kind: RegionKind) {
+        self.record(|| Event::AddRegionOverride { va, size, kind });
+        self.overrides.add(va, size, kind);
+        let overrides = &self.overrides;
+        let rows = self.get_va_set_rows("EntryPoints").unwrap_or_default();
+        let rows = rows.into_iter().filter(|ep| overrides.allows_code(*ep));
+        self.set_va_set_row("EntryPoints", rows.collect());
+        let overrides = &self.overrides;
+        let dropped: Vec<i32> = self
+            .funcmeta
+            .keys()
+            .filter(|fva| !overrides.allows_code(**fva))
+            .copied()
+            .collect();
+        for fva in dropped {
+            debug!("Dropping function {:#0x}, overridden as not code", fva);
+            self.del_function(fva);
+        }
+        let overrides = &self.overrides;
+        self.import_stubs
+            .retain(|stub, _| overrides.allows_code(*stub));
+        if kind == RegionKind::ForceCode {
+            self.add_entry_point(va);
+        }
+    }
+
+    pub fn del_region_override(&mut self, va: i32) {
+        self.overrides.remove(va);
+    }
+
+    /// The override in effect at `va`, if any.
+    pub fn get_region_override(&self, va: i32) -> Option<RegionKind> {
+        self.overrides.kind_at(va)
+    }
+
+    /// The (va, size, kind) of every region override, by VA.
+    pub fn get_region_overrides(&self) -> Vec<(i32, i32, RegionKind)> {
+        self.overrides.iter().collect()
+    }
+
+    /// The (trampoline va, import slot) of every known import trampoline.
+    pub fn get_import_stubs(&self) -> Vec<(i32, i32)> {
+        let mut ret = self
+            .import_stubs
+            .iter()
+            .map(|(va, slot)| (*va, *slot))
+            .collect::<Vec<_>>();
+        ret.sort_unstable();
+        ret
+    }
+
+    /// The call sites of the import `name`, either the bare name ("memcpy") or the import name
+    /// ("libc.memcpy"): the calls to its trampolines and the calls through its slots, sorted.
+    pub fn get_callers_of_import(&self, name: &str) -> Vec<i32> {
+        let slots = self
+            .get_imports()
+            .into_iter()
+            .filter(|(_, impname)| {
+                impname == name
+                    || impname
+                        .split_once('.')
+                        .is_some_and(|(_, impname)| impname == name)
+            })
+            .map(|(va, _)| va)
+            .collect::<Vec<_>>();
+        let stubs = self
+            .import_stubs
+            .iter()
+            .filter(|(_, slot)| slots.contains(slot))
+            .map(|(va, _)| *va);
+        let mut ret = slots
+            .iter()
+            .copied()
+            .chain(stubs)
+            .flat_map(|va| self.get_xrefs_to(va, Some(REF_CODE)))
+            .map(|xref| xref.0)
+            .filter(|va| !self.import_stubs.contains_key(va))
+            .collect::<Vec<_>>();
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    /// The VA, permissions and bytes of every memory map, by VA.
+    pub fn get_maps(&self) -> Vec<(i32, i32, &[u8])> {
+        let mut maps = self
+            ._map_defs
+            .iter()
+            .map(|(va, _, map, bytes)| (*va, map.2, bytes.as_slice()))
+            .collect::<Vec<_>>();
+        maps.sort_by_key(|(va, _, _)| *va);
+        maps
+    }
+
+    /// The va and the bytes of every executable memory map.
+    pub fn get_executable_maps(&self) -> Vec<(i32, &[u8])> {
+        self._map_defs
+            .iter()
+            .filter(|(_, _, map, _)| map.2 & MM_EXEC != 0)
+            .map(|(va, _, _, bytes)| (*va, bytes.as_slice()))
+            .collect()
+    }
+
+    /// Overwrite the bytes at `va`, whatever the permissions of the memory map holding them.
+    /// Nothing changes, and false is returned, unless a single memory map holds all of them.
+    pub fn patch_bytes(&mut self, va: i32, bytes: &[u8]) -> bool {
+        let Some((mva, _, _, mbytes)) = self
+            ._map_defs
+            .iter_mut()
+            .find(|(mva, mmaxva, _, _)| *mva <= va && va < *mmaxva)
+        else {
+            return false;
+        };
+        let offset = (va - *mva) as usize;
+        let Some(dest) = mbytes.get_mut(offset..offset + bytes.len()) else {
+            return false;
+        };
+        dest.copy_from_slice(bytes);
+        self.record(|| Event::PatchMemory {
+            va,
+            bytes: bytes.to_vec(),
+        });
+        true
+    }
+
+    /// Take in code an emulation wrote at runtime: patch `bytes` in at `va` and list `va` in the
+    /// `DynamicCode` va set, for analysis to go over. False if no memory map holds it all.
+    pub fn add_dynamic_code(&mut self, va: i32, bytes: &[u8]) -> bool {
+        if !self.patch_bytes(va, bytes) {
+            return false;
+        }
+        let mut rows = self.get_va_set_rows("DynamicCode").unwrap_or_default();
+        if !rows.contains(&va) {
+            rows.push(va);
+        }
+        self.set_va_set_row("DynamicCode", rows);
+        true
+    }
+
+    /// Assemble `text` at `va` with `assembler` and patch the bytes in, returning how many
+    /// there are.
+    pub fn patch_with(
+        &mut self,
+        assembler: &dyn Assembler,
+        va: i32,
+        text: &str,
+    ) -> std::io::Result<usize> {
+        let arch = Arch::from_envi(self.arch).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Unsupported, "unknown architecture")
+        })?;
+        let bytes = assembler.assemble(arch, va as u32 as u64, text)?;
+        if !self.patch_bytes(va, &bytes) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:#x}: {:#x} bytes not in a memory map", va, bytes.len()),
+            ));
+        }
+        Ok(bytes.len())
+    }
+
+    /// Patch the instructions in `text` in at `va`, e.g. `patch_asm(va, "jmp 0x401000")`,
+    /// assembling them with [`IcedAssembler`].
+    #[cfg(feature = "assembler")]
+    pub fn patch_asm(&mut self, va: i32, text: &str) -> std::io::Result<usize> {
+        self.patch_with(&IcedAssembler, va, text)
+    }
+
+    /// The ROP and JOP gadgets of the executable memory, up to `max_insns` instructions before
+    /// the return or jump ending each; see [`crate::gadgets`].
+    #[cfg(feature = "gadgets")]
+    pub fn find_gadgets(&self, max_insns: usize) -> Vec<crate::gadgets::Gadget> {
+        crate::gadgets::find(self, max_insns)
+    }
+
+    /// The stack pivots and other instructions of exploitation interest in the executable
+    /// memory; see [`crate::gadgets::find_transfers`].
+    #[cfg(feature = "gadgets")]
+    pub fn find_transfers(&self) -> Vec<crate::gadgets::TransferSite> {
+        crate::gadgets::find_transfers(self)
+    }
+
+    /// Add an exported symbol of the given file.
+    pub fn add_export(&mut self, va: i32, name: &str, fname: &str) {
+        self.record(|| Event::AddExport {
+            va,
+            name: name.to_string(),
+            fname: fname.to_string(),
+        });
+        if !self.exports.contains(&va) {
+            self.exports.push(va);
+        }
+        self.exports_by_va
+            .insert(name.to_string(), fname.to_string());
+        if !self.name_by_va.contains_key(&va) {
+            self.make_name(va, format!("{}.{}", fname, name), false, true);
+        }
+    }
+
+    pub fn get_exports(&self) -> Vec<(i32, String)> {
+        let mut ret = self
+            .exports
+            .iter()
+            .map(|va| (*va, self.name_by_va.get(va).cloned().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        ret.sort_unstable_by_key(|(va, _)| *va as u32);
+        ret
+    }
+
+    /// The symbol cache lives in <viv_home>/symcache. Without a config
+    /// directory there is no cache.
+    pub fn get_symbol_cache(&self) -> Option<SymbolCache> {
+        if self.viv_home.is_empty() {
+            return None;
+        }
+        Some(SymbolCache::new(Path::new(&self.viv_home).join("symcache")))
+    }
+
+    /// Apply previously recovered names and function boundaries for the
+    /// given file from the symbol cache, found by the file's contents or
+    /// else its module id. Existing names are left alone, as are functions
+    /// the region overrides rule out. Functions whose code blocks were cached
+    /// get them back, with the cross references from them, and aren't
+    /// analyzed again (see [`VivWorkspace::analyze_function`]).
+    /// Returns the number of cache entries applied.
+    pub fn apply_symbol_cache(&mut self, fname: &str) -> usize {
+        let Some(cache) = self.get_symbol_cache() else {
+            return 0;
+        };
+        let mut found = None;
+        for key in self.symbol_cache_keys(fname) {
+            match cache.lookup(&key) {
+                Ok(Some(cached)) => {
+                    found = Some(cached);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to read the symbol cache for {}: {}", fname, e);
+                    return 0;
+                }
+            }
+        }
+        let Some(cached) = found else {
+            return 0;
+        };
+        let cached = rebase(&cached, self.get_file_meta(fname, "imagebase"));
+        let mut applied = 0;
+        // the code blocks of the functions brought back, whose cross references come with them
+        let mut restored = Vec::new();
+        for (va, name) in cached.names.iter() {
+            if self.name_by_va.contains_key(va) || self.va_by_name.contains_key(name) {
+                continue;
+            }
+            self.make_name(*va, name.clone(), false, false);
+            applied += 1;
+        }
+        for (fva, size) in cached.functions.iter() {
+            if self.is_function(*fva) {
+                continue;
+            }
+            let ranges = cached.bounds.get(fva).cloned().unwrap_or_default();
+            if !self.add_function(*fva, ranges) {
+                continue;
+            }
+            if self.funcmeta.get(fva).and_then(|meta| meta.get("Size")) != Some(size) {
+                self.set_function_meta(*fva, "Size", *size);
+            }
+            if let Some(blocks) = cached.blocks.get(fva) {
+                for (va, size) in blocks.iter() {
+                    self.add_code_block(*va, *size, *fva);
+                }
+                restored.extend(blocks.iter().copied());
+                self.set_function_meta(*fva, FUNC_META_CACHED, 1);
+            }
+            applied += 1;
+        }
+        for (from, to, rtype, rflags) in cached.xrefs.iter() {
+            if restored
+                .iter()
+                .any(|(va, size)| (*va..va.wrapping_add(*size)).contains(from))
+            {
+                self.add_xref(*from, *to, *rtype, *rflags);
+            }
+        }
+        info!("Applied {} symbol cache entries to {}", applied, fname);
+        applied
+    }
+
+    /// Store the names and function boundaries of every loaded file in the
+    /// symbol cache, under its content id and its module id.
+    pub fn save_symbol_cache(&self) -> std::io::Result<()> {
+        let cache = match self.get_symbol_cache() {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let mut fnames: Vec<&String> = self
+            .content_ids
+            .keys()
+            .chain(self.module_ids.keys())
+            .collect();
+        fnames.sort_unstable();
+        fnames.dedup();
+        for fname in fnames {
+            let entry = self.file_symbols(fname);
+            for key in self.symbol_cache_keys(fname) {
+                cache.store(&key, &entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The names, function sizes and function bounds within the given file, relative to its
+    /// image base.
+    pub fn file_symbols(&self, fname: &str) -> Annotations {
+        let ann = self.get_annotations();
+        let mut entry = Annotations::new();
+        let in_file = |va: &i32| self.get_file_by_va(*va).as_deref() == Some(fname);
+        entry.names = ann
+            .names
+            .iter()
+            .filter(|(va, _)| in_file(va))
+            .map(|(va, n)| (*va, n.clone()))
+            .collect();
+        entry.functions = ann
+            .functions
+            .iter()
+            .filter(|(va, _)| in_file(va))
+            .map(|(va, size)| (*va, *size))
+            .collect();
+        entry.bounds = entry
+            .functions
+            .keys()
+            .filter_map(|fva| Some((*fva, self.get_function_bounds(*fva)?)))
+            .collect();
+        entry.blocks = entry
+            .functions
+            .keys()
+            .filter_map(|fva| {
+                let blocks = self.codeblocks_by_funcva.get(fva)?;
+                let mut blocks: Vec<_> = blocks.iter().map(|(va, size, ..)| (*va, *size)).collect();
+                blocks.sort_unstable();
+                Some((*fva, blocks))
+            })
+            .collect();
+        entry.xrefs = entry
+            .blocks
+            .values()
+            .flatten()
+            .flat_map(|(va, size)| {
+                (*va..va.wrapping_add(*size)).filter_map(|from| self.xrefs_by_from.get(&from))
+            })
+            .flatten()
+            .filter(|(_, to, ..)| in_file(to))
+            .copied()
+            .collect();
+        let imagebase = self.get_file_meta(fname, "imagebase");
+        rebase(&entry, imagebase.wrapping_neg())
+    }
+
     pub fn norm_filename(&self, filename: &str) -> String {
         let mut normname = Path::new(filename).to_path_buf();
-        normname = normname.canonicalize().unwrap();
+        // Files loaded from their bytes needn't be on disk
+        normname = normname.canonicalize().unwrap_or(normname);
         normname.file_stem().unwrap().to_str().unwrap().to_string()
     }
 }
 
+/// Sort xrefs by their VAs as unsigned addresses, then by type and flags
+fn xref_order(&(from, to, rtype, rflags): &(i32, i32, i32, i32)) -> (u32, u32, i32, i32) {
+    (from as u32, to as u32, rtype, rflags)
+}
+
+type CodeBlock = (i32, i32, i32, Vec<(i32, i32)>);
+
+/// Sort code blocks by their VA as an unsigned address, then by the rest
+fn block_order(a: &CodeBlock, b: &CodeBlock) -> std::cmp::Ordering {
+    (a.0 as u32, a.1, a.2 as u32, &a.3).cmp(&(b.0 as u32, b.1, b.2 as u32, &b.3))
+}
+
 impl Memory for VivWorkspace {
     fn get_endian(&mut self) -> i32 {
         self.endianess
@@ -1429,6 +3200,7 @@ impl Memory for VivWorkspace {
     }
 
     fn set_mem_architecture(&mut self, arch: u32) {
+        self.record(|| Event::SetArchitecture(arch));
         self.arch = arch;
     }
 
@@ -1442,9 +3214,9 @@ impl Memory for VivWorkspace {
     /// original va (not the size).
     /// _origva is an internal field and should not be used.
     fn read_memory(&self, va: i32, size: i32) -> Option<Vec<u8>> {
-        for (m_va, m_max_va, m_map, m_bytes) in self._map_defs.clone() {
-            if m_va <= va && va < m_max_va {
-                let (m_va, m_size, m_perms, m_fname) = m_map;
+        for (m_va, m_max_va, m_map, m_bytes) in self._map_defs.iter() {
+            if *m_va <= va && va < *m_max_va {
+                let (m_va, m_size, m_perms, _) = m_map;
                 if m_perms & MM_READ == 0 {
                     panic!(
                         "Bad Memory Read (no read permission): {:#0x}: {:#0x} ",
@@ -1454,96 +3226,44 @@ impl Memory for VivWorkspace {
                 let offset = va - m_va;
                 let max_read_len = m_size - offset;
                 if size > max_read_len {
-                    let mut new_bytes = Vec::new();
-                    new_bytes.append(
-                        &mut m_bytes[offset as usize..].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                    new_bytes.append(
-                        &mut self
-                            .read_memory(m_va + m_size, size - max_read_len)
-                            .unwrap(),
-                    );
+                    let mut new_bytes = m_bytes[offset as usize..].to_vec();
+                    new_bytes.append(&mut self.read_memory(m_va + m_size, size - max_read_len)?);
                     return Some(new_bytes);
                 }
+                return Some(m_bytes[offset as usize..(offset + size) as usize].to_vec());
             }
         }
-        panic!(
+        debug!(
             "Bad memory read (invalid memory address): {:#0x}: {:#0x}",
             va, size
         );
+        None
     }
 
-    /// Write memory to maps stored in memory maps.
-    /// If the write crosses memory maps and fails on a later map, the exception
-    /// will show the details of the last map/failure, but should include the
-    /// original va (but not the original size).
-    /// In this scenario, writes to the first map will succeed, up until the address of the exception.
     fn write_memory(&mut self, va: i32, bytes: Vec<u8>) {
         let bytes_len = bytes.len() as i32;
-        for mut mapdef in self._map_defs.clone() {
+        for mapdef in self._map_defs.iter_mut() {
             let (mva, mmaxva, mmap, mbytes) = mapdef;
-            if mva <= va && va < mmaxva {
-                let (mva, msize, mperms, mfname) = mmap;
+            if *mva <= va && va < *mmaxva {
+                let (mva, msize, mperms, _) = *mmap;
                 if mperms & MM_WRITE == 0 {
                     panic!(
-                        "Bad Memory Write (no read permission): {:#0x}: {:#0x} ",
+                        "Bad Memory Write (no write permission): {:#0x}: {:#0x} ",
                         va, bytes_len
                     );
                 }
-                let offset = va - mva;
-                let max_write_len = msize - offset;
-                if bytes_len > max_write_len {
-                    let mut new_bytes = Vec::new();
-                    new_bytes.append(
-                        &mut mbytes[..offset as usize].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                    new_bytes.append(
-                        &mut bytes[..max_write_len as usize].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                    mapdef.3 = new_bytes;
-                    self.write_memory(
-                        mva + msize,
-                        bytes[max_write_len as usize..].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                } else {
-                    let mut new_bytes = Vec::new();
-                    new_bytes.append(
-                        &mut mbytes[..offset as usize].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                    new_bytes.append(&mut bytes.clone());
-                    new_bytes.append(
-                        &mut mbytes[(offset + bytes_len) as usize..].to_vec(),
-                        // .iter()
-                        // .copied()
-                        // // .map(|x| *x)
-                        // .collect::<Vec<_>>(),
-                    );
-                    mapdef.3 = new_bytes;
+                let offset = (va - mva) as usize;
+                let max_write_len = (msize as usize - offset).min(bytes.len());
+                mbytes[offset..offset + max_write_len].copy_from_slice(&bytes[..max_write_len]);
+                // Whatever runs past the end of this map goes to the next one
+                if max_write_len < bytes.len() {
+                    self.write_memory(mva + msize, bytes[max_write_len..].to_vec());
                 }
+                return;
             }
         }
         panic!(
-            "Bad memory read (invalid memory address): {:#0x}: {:#0x}",
+            "Bad memory write (invalid memory address): {:#0x}: {:#0x}",
             va, bytes_len
         );
     }
@@ -1581,6 +3301,12 @@ impl Memory for VivWorkspace {
             let delta = new_len - cur_len;
             bytes.append(&mut vec![0x00; delta]);
         }
+        self.record(|| Event::AddMemoryMap {
+            va: map_va,
+            perms,
+            fname: fname.to_string(),
+            bytes: bytes.clone(),
+        });
         let msize = bytes.len() as i32;
         let mmap = (map_va, msize, perms, fname.to_string());
         let hlpr = (map_va, map_va + msize as i32, mmap, bytes);
@@ -1601,3 +3327,280 @@ impl Memory for VivWorkspace {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::REF_DATA;
+
+    #[test]
+    fn deterministic_order() {
+        let xrefs = [
+            (0x1010, 0x2000, REF_CODE, 0),
+            (0x1000, 0x3000, REF_DATA, 0),
+            (0x1000, 0x2000, REF_CODE, 0),
+            (0x1020, 0x2000, REF_DATA, 0),
+        ];
+        let mut ann = Annotations::new();
+        ann.functions
+            .extend((0..64).map(|i| (0x1000 + i * 0x40, 0x40)));
+        let build = |order: &[usize]| {
+            let mut ws = VivWorkspace::new("", false);
+            ws.apply_annotations(&ann);
+            for &i in order {
+                let (from, to, rtype, rflags) = xrefs[i];
+                ws.add_xref(from, to, rtype, rflags);
+                ws.add_xref(from, to, rtype, rflags);
+                ws.add_entry_point(from);
+                ws.add_function_entry(0x1000, from);
+            }
+            ws
+        };
+        let mut a = build(&[0, 1, 2, 3]);
+        let mut b = build(&[3, 2, 1, 0]);
+        assert_eq!(a.get_xrefs(None), b.get_xrefs(None));
+        assert_eq!(a.get_xrefs(None).len(), 4);
+        assert_eq!(a.get_xrefs_to(0x2000, None), b.get_xrefs_to(0x2000, None));
+        assert_eq!(
+            a.get_xrefs_from(0x1000, Some(REF_CODE)),
+            [(0x1000, 0x2000, REF_CODE, 0)]
+        );
+        assert_eq!(a.get_entry_points(), [0x1000, 0x1010, 0x1020]);
+        assert_eq!(
+            a.get_function_entries(0x1000),
+            b.get_function_entries(0x1000)
+        );
+        let functions = a.get_functions();
+        assert_eq!(functions, b.get_functions());
+        assert!(functions.windows(2).all(|w| w[0] < w[1]));
+
+        // addresses in the high half of the address space come last
+        let high = 0x8000_1000_u32 as i32;
+        a.add_entry_point(high);
+        a.add_xref(high, 0x2000, REF_CODE, 0);
+        a.add_code_block(0x1000, 0x10, 0x1000);
+        a.add_code_block(high, 0x10, 0x1000);
+        assert_eq!(a.get_entry_points(), [0x1000, 0x1010, 0x1020, high]);
+        assert_eq!(a.get_xrefs_to(0x2000, None).last().unwrap().0, high);
+        let blocks = a.get_function_blocks(0x1000);
+        assert_eq!(
+            blocks.iter().map(|cb| cb.0).collect::<Vec<_>>(),
+            [0x1000, high]
+        );
+    }
+
+    #[test]
+    fn resolves_function_chunks() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_segment(0x1000, 0x8000, ".text", "app".to_string());
+        ws.add_function(0x1000, vec![(0x1000, 0x10)]);
+        ws.add_function(0x2000, vec![]);
+        ws.make_name(0x2008, "label".to_string(), false, false);
+        assert_eq!(ws.resolve(0x1004).unwrap().to_string(), "sub_1000+0x4");
+        assert_eq!(ws.resolve(0x2010).unwrap().to_string(), "label+0x8");
+
+        // a cold chunk away from the function resolves to it, and each change is seen at once
+        let snapshot = ws.snapshot_view();
+        ws.add_function_chunk(0x1000, 0x5000, 0x10);
+        assert_eq!(ws.get_functions_containing(0x5004), [0x1000]);
+        assert_eq!(ws.resolve(0x5004).unwrap().to_string(), "sub_1000+0x4004");
+        ws.del_function_chunk(0x1000, 0x5000);
+        assert_eq!(ws.resolve(0x5004).unwrap().to_string(), "label+0x2ffc");
+        ws.set_function_meta(0x2000, "Size", 0x20);
+        assert_eq!(ws.resolve(0x2010).unwrap().to_string(), "sub_2000+0x10");
+        assert!(!ws.snapshot_view().ptr_eq(&snapshot));
+
+        let snapshot = ws.snapshot_view();
+        ws.add_function_entry(0x1000, 0x1008);
+        assert!(!ws.snapshot_view().ptr_eq(&snapshot));
+    }
+
+    #[test]
+    fn resolves_names_added_later() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_segment(0x1000, 0x8000, ".text", "app".to_string());
+        ws.make_name(0x1000, "start".to_string(), false, false);
+        assert_eq!(ws.resolve(0x3004).unwrap().to_string(), "start+0x2004");
+        ws.make_name(0x3000, "later".to_string(), false, false);
+        assert_eq!(ws.resolve(0x3004).unwrap().to_string(), "later+0x4");
+        ws.rename(0x3000, "renamed").unwrap();
+        assert_eq!(ws.resolve(0x3004).unwrap().to_string(), "renamed+0x4");
+    }
+
+    #[test]
+    fn deterministic_analysis() {
+        use crate::pe::import::{ImportTable, ImportedFunction};
+
+        let mut imports = ImportTable::default();
+        for function in ["CreateFileA", "ReadFile", "CloseHandle", "ExitProcess"] {
+            imports.add_function("KERNEL32.dll", ImportedFunction::by_name(function));
+        }
+        let (pe, idata) = imports.tiny_image();
+        let slots: Vec<i32> = idata
+            .slots
+            .iter()
+            .map(|slot| 0x400000 + slot.2 as i32)
+            .collect();
+        // each run has its own maps, so its own hash seeds, and runs on a thread of its own
+        let analyze = |pe: Vec<u8>, slots: Vec<i32>| {
+            let mut ws = VivWorkspace::new("", false);
+            ws.load_from_bytes("tiny.exe", &pe, None);
+            let code = 0x1000_0000;
+            ws.add_memory_map(code, MM_READ | MM_EXEC, "code", vec![0xc3; 0x400], None);
+            ws.add_segment(code, 0x400, ".text", "code".to_string());
+            for i in 0..16 {
+                let fva = code + i * 0x40;
+                ws.add_entry_point(fva);
+                ws.add_code_block(fva, 0x20, fva);
+                ws.add_xref(fva + 4, slots[i as usize % slots.len()], REF_CODE, BR_PROC);
+                ws.add_xref(fva + 8, code + (i * 7 % 16) * 0x40, REF_CODE, BR_PROC);
+                ws.add_xref(fva + 0xc, fva + 0x10, REF_CODE, 0);
+                ws.add_location(fva + 0x20, 8, LOC_STRING, Some(vec![]));
+            }
+            ws.process_entry_points();
+            ws.apply_auto_names();
+            (
+                ws.get_locations(None, None),
+                ws.get_xrefs(None),
+                ws.get_names(),
+                ws.get_functions(),
+            )
+        };
+        let first = analyze(pe.clone(), slots.clone());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (pe, slots) = (pe.clone(), slots.clone());
+                std::thread::spawn(move || analyze(pe, slots))
+            })
+            .collect();
+        assert!(!first.0.is_empty() && !first.1.is_empty() && !first.2.is_empty());
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), first);
+        }
+    }
+
+    /// Load a 32 bit big endian executable for `machine`, one segment holding a pointer at
+    /// 0x400054 to 0x400010
+    fn load_elf32_be(machine: u16, name: &str) -> VivWorkspace {
+        let mut elf = b"\x7fELF\x01\x02\x01".to_vec();
+        elf.resize(16, 0);
+        for half in [2u16, machine] {
+            elf.extend(half.to_be_bytes());
+        }
+        for word in [1u32, 0x400054, 52, 0, 0] {
+            elf.extend(word.to_be_bytes());
+        }
+        for half in [52u16, 32, 1, 40, 0, 0] {
+            elf.extend(half.to_be_bytes());
+        }
+        for word in [1u32, 0, 0x400000, 0x400000, 88, 88, 7, 0x1000] {
+            elf.extend(word.to_be_bytes());
+        }
+        elf.extend(0x0040_0010u32.to_be_bytes());
+        let dir = std::env::temp_dir().join(format!("vivisect-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, &elf).unwrap();
+        let mut ws = VivWorkspace::new("", false);
+        ws.load_from_file(&path.to_string_lossy(), None, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        ws
+    }
+
+    #[test]
+    fn big_endian_target() {
+        // MIPS and PPC32
+        for (machine, name) in [(8, "mipsbe"), (20, "ppcbe")] {
+            let mut ws = load_elf32_be(machine, name);
+            assert_eq!(ws.get_endian(), ENDIAN_MSB, "{}", name);
+            assert_eq!(ws.get_meta("bigend"), Some("true".to_string()));
+            assert_eq!(ws.get_meta("Format"), Some("elf".to_string()));
+            assert_eq!(ws.get_pointer_size(), 4);
+            assert_eq!(ws.cast_pointer(0x400054), Some(0x400010));
+            assert_eq!(ws.read_mem_value(0x400054, 2), Some(0x40));
+
+            assert!(ws.make_pointer(0x400054, None, false).is_some());
+            let xref = (0x400054, 0x400010, REF_PTR, 0);
+            assert_eq!(ws.get_xrefs_from(0x400054, Some(REF_PTR)), [xref]);
+            assert_eq!(ws.get_xrefs_to(0x400010, None), [xref]);
+            ws.write_mem_value(0x400054, 0x400020, 4);
+            assert_eq!(ws.read_memory(0x400054, 4), Some(vec![0, 0x40, 0, 0x20]));
+            assert_eq!(ws.cast_pointer(0x400054), Some(0x400020));
+        }
+    }
+
+    #[test]
+    fn annotations_round_trip() {
+        let mut ws = VivWorkspace::new("", false);
+        ws.add_function(0x1000, vec![(0x1000, 0x10), (0x3000, 8)]);
+        ws.add_function(0x2000, vec![]);
+        ws.make_name(0x1000, "main".to_string(), true, false);
+        let dir = std::env::temp_dir().join(format!("vivisect-ann-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved.viv");
+        ws.set_meta("StorageName", Some(path.to_string_lossy().to_string()));
+        ws.save_workspace().unwrap();
+        let ann = Annotations::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let same = |loaded: &VivWorkspace| {
+            assert_eq!(loaded.get_functions(), ws.get_functions());
+            for fva in ws.get_functions() {
+                assert_eq!(
+                    loaded.get_function_meta_dict(fva),
+                    ws.get_function_meta_dict(fva)
+                );
+                assert_eq!(loaded.get_function_bounds(fva), ws.get_function_bounds(fva));
+            }
+            assert_eq!(loaded.get_function(0x3004), Some(0x1000));
+            assert_eq!(loaded.get_name(0x1000, false), Some("main".to_string()));
+        };
+        let mut fresh = VivWorkspace::new("", false);
+        fresh.apply_annotations(&ann);
+        same(&fresh);
+
+        // functions of its own which aren't in the snapshot, or have other bounds, go
+        let mut dirty = VivWorkspace::new("", false);
+        dirty.add_function(0x4000, vec![(0x4000, 0x20)]);
+        dirty.add_function(0x2000, vec![(0x2000, 4)]);
+        dirty.apply_annotations(&ann);
+        same(&dirty);
+        assert_eq!(dirty.get_function(0x4010), None);
+        assert_eq!(dirty.snapshot_view().get_function_bounds(0x4000), None);
+    }
+
+    #[test]
+    fn function_parts() {
+        let mut ws = VivWorkspace::new("", false);
+        // code entered part way in
+        ws.add_function(0x1000, vec![(0x1000, 0x40)]);
+        ws.add_entry_point(0x1010);
+
+        // a Go function whose split stack preamble after it calls morestack and starts over
+        ws.add_function(0x2000, vec![]);
+        ws.add_code_block(0x2000, 0x20, 0x2000);
+        ws.add_code_block(0x2040, 0x10, 0x2040);
+        ws.make_name(0x3000, "runtime.morestack_noctxt".to_string(), false, false);
+        ws.add_xref(0x2045, 0x3000, REF_CODE, BR_PROC);
+        ws.add_xref(0x204a, 0x2000, REF_CODE, 0);
+
+        // a tail analysis gave to the first function which the second jumps to as well
+        for fva in [0x4000, 0x5000] {
+            ws.add_function(fva, vec![]);
+            ws.add_code_block(fva, 0x10, fva);
+            ws.add_xref(fva + 0xc, 0x6000, REF_CODE, 0);
+        }
+        ws.add_code_block(0x6000, 8, 0x4000);
+
+        ws.process_entry_points();
+        assert_eq!(ws.get_function_entries(0x1000), [0x1010]);
+        assert_eq!(ws.get_function(0x1010), Some(0x1000));
+        assert!(!ws.is_function(0x1010));
+        assert_eq!(
+            ws.get_function_bounds(0x2000),
+            Some(vec![(0x2000, 0x20), (0x2040, 0x10)])
+        );
+        assert_eq!(ws.get_functions_containing(0x6004), [0x4000, 0x5000]);
+        assert_eq!(ws.link_function_parts(), 0);
+    }
+}